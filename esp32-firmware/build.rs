@@ -1,9 +1,50 @@
+use std::collections::HashMap;
+use std::{env, fs, path::PathBuf};
+
+#[path = "src/partitions.rs"]
+mod partitions;
+
+const PARTITION_TABLE: &str = "partitions.csv";
+
 fn main() {
+    let mut dotenv = HashMap::new();
     if std::path::Path::new(".env").exists() {
         for item in dotenvy::dotenv_iter().unwrap() {
             let (key, value) = item.unwrap();
             println!("cargo:rustc-env={}={}", key, value);
+            dotenv.insert(key, value);
         }
     }
+    // Host builds of the library have no image to check
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        size_check(dotenv.remove("FIRMWARE_SIZE_BUDGET"));
+    }
+    #[cfg(feature = "esp")]
     embuild::espidf::sysenv::output();
 }
+
+/// Fails the link when the image outgrows the smallest app partition, or
+/// `FIRMWARE_SIZE_BUDGET` (bytes, `K` or `M`) from the environment or .env.
+fn size_check(dotenv_budget: Option<String>) {
+    println!("cargo:rerun-if-changed={}", PARTITION_TABLE);
+    println!("cargo:rerun-if-env-changed=FIRMWARE_SIZE_BUDGET");
+    let (budget, source) = match env::var("FIRMWARE_SIZE_BUDGET").ok().or(dotenv_budget) {
+        Some(raw) => (
+            partitions::parse_size(&raw).unwrap_or_else(|e| panic!("FIRMWARE_SIZE_BUDGET: {}", e)),
+            "FIRMWARE_SIZE_BUDGET".to_string(),
+        ),
+        None => {
+            let csv = fs::read_to_string(PARTITION_TABLE)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", PARTITION_TABLE, e));
+            let table = partitions::parse(&csv)
+                .unwrap_or_else(|e| panic!("Invalid {}: {}", PARTITION_TABLE, e));
+            let slot = partitions::app_budget(&table)
+                .unwrap_or_else(|| panic!("{} has no app partition", PARTITION_TABLE));
+            (slot.size, format!("{} in {}", slot.name, PARTITION_TABLE))
+        }
+    };
+    let script = PathBuf::from(env::var("OUT_DIR").unwrap()).join("size_check.ld");
+    fs::write(&script, partitions::size_check_script(budget, &source)).unwrap();
+    // A script given as an input file adds to the ESP-IDF ones instead of replacing them
+    println!("cargo:rustc-link-arg={}", script.display());
+}
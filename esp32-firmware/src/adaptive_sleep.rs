@@ -0,0 +1,209 @@
+//! How long the firmware sleeps in adaptive mode.
+//!
+//! With adaptive mode on (`set_adaptive_mode`), the device compares each
+//! CO2 reading with the one from its previous wake and picks the next sleep
+//! from an `AdaptiveSleep` table: the faster CO2 moves, the sooner it wakes
+//! again. The table's choice is kept within its bounds, and the deep sleep
+//! time set with `set_deep_sleep_time` stays the device's schedule: of the
+//! two, whichever is shorter wins. Adaptive mode can wake the device more
+//! often than its schedule, never less.
+//!
+//! Builds set the table with `ADAPTIVE_SLEEP`, e.g. `150:120,50:300,900`:
+//! sleep 120 s after more than 150 ppm of change, 300 s after more than 50,
+//! 900 s otherwise. `ADAPTIVE_SLEEP_BOUNDS`, e.g. `60-3600`, sets the bounds.
+
+use core::str::FromStr;
+
+/// Sleep `seconds` after more than `above_ppm` of change since the previous
+/// wake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub above_ppm: u16,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdaptiveSleep {
+    /// Largest change first
+    pub steps: Vec<Step>,
+    /// When CO2 moved less than any step asks for, or there is no previous
+    /// reading to compare with
+    pub otherwise_seconds: u64,
+    pub min_seconds: u64,
+    pub max_seconds: u64,
+}
+
+impl Default for AdaptiveSleep {
+    fn default() -> Self {
+        Self {
+            steps: vec![
+                Step {
+                    above_ppm: 150,
+                    seconds: 120,
+                },
+                Step {
+                    above_ppm: 50,
+                    seconds: 300,
+                },
+            ],
+            otherwise_seconds: 900,
+            min_seconds: 60,
+            max_seconds: 3600,
+        }
+    }
+}
+
+/// Change between two readings, if there are both
+pub fn delta(previous: Option<u16>, current: Option<u16>) -> Option<u16> {
+    Some(previous?.abs_diff(current?))
+}
+
+impl AdaptiveSleep {
+    /// What the table asks for after a change of `delta_ppm`, within the
+    /// bounds
+    pub fn interval(&self, delta_ppm: Option<u16>) -> u64 {
+        let seconds = delta_ppm
+            .and_then(|delta| self.steps.iter().find(|step| delta > step.above_ppm))
+            .map_or(self.otherwise_seconds, |step| step.seconds);
+        seconds.clamp(self.min_seconds, self.max_seconds)
+    }
+
+    /// How long to sleep in adaptive mode, given the deep sleep time the
+    /// device is scheduled for
+    pub fn next_sleep(&self, delta_ppm: Option<u16>, scheduled_seconds: u64) -> u64 {
+        self.interval(delta_ppm).min(scheduled_seconds)
+    }
+
+    /// Replaces the bounds with `MIN-MAX` seconds.
+    pub fn with_bounds(mut self, bounds: &str) -> Result<Self, &'static str> {
+        let (min, max) = bounds.split_once('-').ok_or("expected MIN-MAX")?;
+        let min: u64 = min.trim().parse().map_err(|_| "invalid minimum")?;
+        let max: u64 = max.trim().parse().map_err(|_| "invalid maximum")?;
+        if min == 0 || min > max {
+            return Err("bounds must be 1 second or more, minimum first");
+        }
+        self.min_seconds = min;
+        self.max_seconds = max;
+        Ok(self)
+    }
+}
+
+/// `PPM:SECONDS` steps and one bare `SECONDS` for everything else, comma
+/// separated, in any order. The bounds stay the defaults.
+impl FromStr for AdaptiveSleep {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = |value: &str| -> Result<u64, Self::Err> {
+            match value.trim().parse() {
+                Ok(0) | Err(_) => Err("intervals must be whole seconds, at least 1"),
+                Ok(seconds) => Ok(seconds),
+            }
+        };
+        let mut steps = Vec::new();
+        let mut otherwise_seconds = None;
+        for item in s.split(',') {
+            match item.split_once(':') {
+                Some((above, value)) => steps.push(Step {
+                    above_ppm: above.trim().parse().map_err(|_| "invalid ppm threshold")?,
+                    seconds: seconds(value)?,
+                }),
+                None if otherwise_seconds.is_none() => otherwise_seconds = Some(seconds(item)?),
+                None => return Err("only one interval may go without a threshold"),
+            }
+        }
+        steps.sort_by_key(|step| core::cmp::Reverse(step.above_ppm));
+        if steps
+            .windows(2)
+            .any(|pair| pair[0].above_ppm == pair[1].above_ppm)
+        {
+            return Err("a threshold is listed twice");
+        }
+        Ok(Self {
+            steps,
+            otherwise_seconds: otherwise_seconds
+                .ok_or("expected an interval without a threshold for slow change")?,
+            ..Self::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faster_change_wakes_sooner() {
+        let table = AdaptiveSleep::default();
+        assert_eq!(table.interval(Some(400)), 120);
+        assert_eq!(table.interval(Some(151)), 120);
+        // Thresholds are exclusive
+        assert_eq!(table.interval(Some(150)), 300);
+        assert_eq!(table.interval(Some(51)), 300);
+        assert_eq!(table.interval(Some(50)), 900);
+        assert_eq!(table.interval(Some(0)), 900);
+    }
+
+    #[test]
+    fn without_a_previous_reading_the_slow_interval_applies() {
+        let table = AdaptiveSleep::default();
+        assert_eq!(delta(None, Some(612)), None);
+        assert_eq!(delta(Some(612), None), None);
+        assert_eq!(table.interval(delta(None, Some(612))), 900);
+    }
+
+    #[test]
+    fn delta_is_absolute() {
+        assert_eq!(delta(Some(900), Some(600)), Some(300));
+        assert_eq!(delta(Some(600), Some(900)), Some(300));
+    }
+
+    #[test]
+    fn bounds_clamp_the_table() {
+        let table = AdaptiveSleep::default().with_bounds("200-600").unwrap();
+        assert_eq!(table.interval(Some(400)), 200);
+        assert_eq!(table.interval(Some(100)), 300);
+        assert_eq!(table.interval(Some(0)), 600);
+
+        assert!(AdaptiveSleep::default().with_bounds("600-200").is_err());
+        assert!(AdaptiveSleep::default().with_bounds("0-600").is_err());
+        assert!(AdaptiveSleep::default().with_bounds("600").is_err());
+    }
+
+    #[test]
+    fn shorter_of_table_and_schedule_wins() {
+        let table = AdaptiveSleep::default();
+        // A meeting starts: the table wakes the device sooner than scheduled
+        assert_eq!(table.next_sleep(Some(200), 300), 120);
+        // Flat overnight: the table would sleep longer, the schedule keeps it
+        assert_eq!(table.next_sleep(Some(10), 300), 300);
+        assert_eq!(table.next_sleep(Some(10), 1800), 900);
+        // A schedule shorter than the minimum bound still applies
+        assert_eq!(table.next_sleep(Some(200), 30), 30);
+    }
+
+    #[test]
+    fn parses_the_build_setting() {
+        let table: AdaptiveSleep = "50:300, 900,150:120".parse().unwrap();
+        assert_eq!(table, AdaptiveSleep::default());
+
+        let flat: AdaptiveSleep = "600".parse().unwrap();
+        assert!(flat.steps.is_empty());
+        assert_eq!(flat.interval(Some(1000)), 600);
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        for bad in [
+            "",
+            "150:120",
+            "150:120,300,900",
+            "150:120,150:60,900",
+            "lots:120,900",
+            "150:0,900",
+            "150:soon,900",
+        ] {
+            assert!(bad.parse::<AdaptiveSleep>().is_err(), "{:?}", bad);
+        }
+    }
+}
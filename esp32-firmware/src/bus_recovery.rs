@@ -0,0 +1,209 @@
+//! When the firmware should try to free a stuck I2C bus.
+//!
+//! After a brownout the SCD40 can be left mid-byte, holding SDA low until it
+//! is clocked past the rest of that byte. Every transaction then fails the
+//! same way until a power cycle. The firmware tracks I2C results in a
+//! `BusHealth` and, when it asks for it, tears down the driver, clocks SCL by
+//! hand (`clock_out`), sends a STOP and brings the driver back.
+//!
+//! Only failures that look like the bus itself is blocked count towards a
+//! recovery. A CRC error or a sensor-level error means bytes made it across,
+//! so the bus is fine and the streak starts over.
+
+/// Why an I2C transaction failed, as far as the driver can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusFault {
+    /// No answer in time, typically because the bus never went idle
+    Timeout,
+    /// The controller couldn't get the bus (SDA low at START)
+    BusBusy,
+    /// The address or a data byte wasn't acknowledged
+    Nack,
+    /// Data arrived but failed its checksum
+    Crc,
+    /// The sensor answered with an error of its own
+    Other,
+}
+
+impl BusFault {
+    pub fn suggests_stuck_bus(self) -> bool {
+        match self {
+            BusFault::Timeout | BusFault::BusBusy | BusFault::Nack => true,
+            BusFault::Crc | BusFault::Other => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// Stuck-looking failures in a row before recovering
+    pub failures_before_recovery: u8,
+    /// Recoveries per wake; after that the device reports and sleeps
+    pub max_attempts_per_wake: u8,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            failures_before_recovery: 3,
+            max_attempts_per_wake: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryDecision {
+    /// Try the transaction again as is
+    Retry,
+    /// Run the bus recovery, then retry
+    Recover,
+    /// Out of attempts for this wake
+    GiveUp,
+}
+
+/// I2C results seen during one wake
+#[derive(Debug, Clone, Default)]
+pub struct BusHealth {
+    policy: RecoveryPolicy,
+    streak: u8,
+    attempts: u8,
+}
+
+impl BusHealth {
+    pub fn new(policy: RecoveryPolicy) -> Self {
+        Self {
+            policy,
+            streak: 0,
+            attempts: 0,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.streak = 0;
+    }
+
+    pub fn record_failure(&mut self, fault: BusFault) {
+        if fault.suggests_stuck_bus() {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 0;
+        }
+    }
+
+    pub fn decide(&self) -> RecoveryDecision {
+        if self.streak < self.policy.failures_before_recovery {
+            RecoveryDecision::Retry
+        } else if self.attempts < self.policy.max_attempts_per_wake {
+            RecoveryDecision::Recover
+        } else {
+            RecoveryDecision::GiveUp
+        }
+    }
+
+    /// Counts a recovery about to run and returns its number, starting at 1.
+    pub fn start_recovery(&mut self) -> u8 {
+        self.attempts += 1;
+        self.streak = 0;
+        self.attempts
+    }
+
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+}
+
+/// A byte and its ACK bit: clocking this many times releases any target
+pub const MAX_RECOVERY_PULSES: u8 = 9;
+
+/// Pulses SCL until SDA reads high, at most `MAX_RECOVERY_PULSES` times.
+///
+/// Returns the number of pulses it took, 0 if SDA was already free, or
+/// `None` if SDA is still held low.
+pub fn clock_out(mut sda_is_high: impl FnMut() -> bool, mut pulse_scl: impl FnMut()) -> Option<u8> {
+    for pulses in 0..=MAX_RECOVERY_PULSES {
+        if sda_is_high() {
+            return Some(pulses);
+        }
+        if pulses < MAX_RECOVERY_PULSES {
+            pulse_scl();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn health() -> BusHealth {
+        BusHealth::new(RecoveryPolicy::default())
+    }
+
+    #[test]
+    fn recovers_only_after_a_streak_of_stuck_failures() {
+        let mut h = health();
+        h.record_failure(BusFault::Timeout);
+        h.record_failure(BusFault::BusBusy);
+        assert_eq!(h.decide(), RecoveryDecision::Retry);
+        h.record_failure(BusFault::Nack);
+        assert_eq!(h.decide(), RecoveryDecision::Recover);
+    }
+
+    #[test]
+    fn crc_errors_and_successes_reset_the_streak() {
+        let mut h = health();
+        h.record_failure(BusFault::Timeout);
+        h.record_failure(BusFault::Timeout);
+        h.record_failure(BusFault::Crc);
+        h.record_failure(BusFault::Timeout);
+        assert_eq!(h.decide(), RecoveryDecision::Retry);
+        h.record_success();
+        h.record_failure(BusFault::Timeout);
+        h.record_failure(BusFault::Other);
+        assert_eq!(h.decide(), RecoveryDecision::Retry);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts_per_wake() {
+        let mut h = health();
+        for attempt in 1..=2 {
+            for _ in 0..3 {
+                h.record_failure(BusFault::BusBusy);
+            }
+            assert_eq!(h.decide(), RecoveryDecision::Recover);
+            assert_eq!(h.start_recovery(), attempt);
+            assert_eq!(h.decide(), RecoveryDecision::Retry);
+        }
+        for _ in 0..3 {
+            h.record_failure(BusFault::BusBusy);
+        }
+        assert_eq!(h.decide(), RecoveryDecision::GiveUp);
+        assert_eq!(h.attempts(), 2);
+    }
+
+    #[test]
+    fn clock_out_stops_as_soon_as_sda_is_released() {
+        let pulses = Cell::new(0);
+        let released = clock_out(|| pulses.get() >= 4, || pulses.set(pulses.get() + 1));
+        assert_eq!(released, Some(4));
+        assert_eq!(pulses.get(), 4);
+
+        let mut free = 0;
+        assert_eq!(clock_out(|| true, || free += 1), Some(0));
+        assert_eq!(free, 0);
+    }
+
+    #[test]
+    fn clock_out_gives_up_after_nine_pulses() {
+        let mut pulses = 0;
+        assert_eq!(clock_out(|| false, || pulses += 1), None);
+        assert_eq!(pulses, MAX_RECOVERY_PULSES);
+
+        let pulses = Cell::new(0);
+        assert_eq!(
+            clock_out(|| pulses.get() == 9, || pulses.set(pulses.get() + 1)),
+            Some(9)
+        );
+    }
+}
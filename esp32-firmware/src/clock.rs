@@ -0,0 +1,43 @@
+//! Reading the RTC, which counts from power-on until SNTP sets it and keeps
+//! the time through deep sleep after that.
+
+use std::time::Duration;
+
+/// Earlier readings (before November 2023) mean the clock was never set
+pub const SYNCED_AFTER_SECONDS: u64 = 1_700_000_000;
+
+/// Milliseconds since the epoch, `None` while the clock isn't set
+pub fn epoch_millis(since_epoch: Duration) -> Option<u64> {
+    (since_epoch.as_secs() >= SYNCED_AFTER_SECONDS).then_some(since_epoch.as_millis() as u64)
+}
+
+/// Hour of the day (0-23) at `utc_offset_hours` from UTC
+pub fn local_hour(epoch_seconds: u64, utc_offset_hours: i64) -> u8 {
+    let local = epoch_seconds as i64 + utc_offset_hours * 3600;
+    (local.rem_euclid(86_400) / 3600) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-01-15T12:00:00Z
+    const NOON: u64 = 1_736_942_400;
+
+    #[test]
+    fn unset_clock_has_no_epoch_time() {
+        assert_eq!(epoch_millis(Duration::from_secs(42)), None);
+        assert_eq!(
+            epoch_millis(Duration::from_millis(NOON * 1000 + 123)),
+            Some(NOON * 1000 + 123)
+        );
+    }
+
+    #[test]
+    fn local_hours_wrap_around_midnight() {
+        assert_eq!(local_hour(NOON, 0), 12);
+        assert_eq!(local_hour(NOON, 13), 1);
+        assert_eq!(local_hour(NOON, -12), 0);
+        assert_eq!(local_hour(NOON - 13 * 3600, -1), 22);
+    }
+}
@@ -0,0 +1,141 @@
+//! What happens to each command topic a wake received commands on.
+//!
+//! A device reads both the broadcast topic and its own. Commands deferred
+//! to the next wake are re-published, retained, on the topic they came in
+//! on: one sent to this device alone must not land on the broadcast topic,
+//! where every device in the fleet would run it. Every other topic is
+//! cleared, so nothing runs twice.
+
+use shared_types::command_schedule::deferred_batch;
+use shared_types::{CommandEnvelope, DeviceCommandBatch};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Retained {
+    /// A deferred batch per topic, replacing what is retained there
+    pub defer: Vec<(String, CommandEnvelope<DeviceCommandBatch>)>,
+    /// Deferred commands that didn't fit in their topic's batch, to answer
+    /// as rejected
+    pub turned_down: Vec<CommandEnvelope>,
+    /// Topics to clear
+    pub clear: Vec<String>,
+}
+
+/// Sorts the topics commands came in on into those that get the commands
+/// deferred from them back, and those that are cleared.
+pub fn retained_after(
+    received_on: &[String],
+    deferred: Vec<(String, CommandEnvelope)>,
+) -> Retained {
+    let mut by_topic: Vec<(String, Vec<CommandEnvelope>)> = Vec::new();
+    for (topic, command) in deferred {
+        match by_topic.iter_mut().find(|(t, _)| *t == topic) {
+            Some((_, commands)) => commands.push(command),
+            None => by_topic.push((topic, vec![command])),
+        }
+    }
+    let clear = received_on
+        .iter()
+        .filter(|topic| !by_topic.iter().any(|(t, _)| t == *topic))
+        .cloned()
+        .collect();
+    let mut retained = Retained {
+        clear,
+        ..Retained::default()
+    };
+    for (topic, commands) in by_topic {
+        let (batch, rest) = deferred_batch(commands);
+        retained.defer.push((topic, batch));
+        retained.turned_down.extend(rest);
+    }
+    retained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::command_schedule::schedule_tagged;
+    use shared_types::topics::{COMMAND_BROADCAST_TOPIC, command_topic};
+    use shared_types::{DeviceCommand, MAX_BATCH_COMMANDS};
+
+    fn offset(offset: f32) -> DeviceCommand {
+        DeviceCommand::SetTempOffset {
+            offset,
+            persist: true,
+        }
+    }
+
+    #[test]
+    fn device_commands_are_never_deferred_to_the_broadcast_topic() {
+        let own = command_topic("kitchen");
+        let broadcast = COMMAND_BROADCAST_TOPIC.to_string();
+        let received = vec![
+            (
+                broadcast.clone(),
+                DeviceCommand::StartFrc { target_ppm: 422 }.into(),
+            ),
+            (own.clone(), offset(4.0).into()),
+        ];
+        let schedule = schedule_tagged(received);
+        assert_eq!(schedule.deferred, vec![(own.clone(), offset(4.0).into())]);
+
+        let retained = retained_after(&[broadcast.clone(), own.clone()], schedule.deferred);
+        assert_eq!(retained.defer.len(), 1);
+        assert_eq!(retained.defer[0].0, own);
+        assert_eq!(retained.defer[0].1.command.commands, [offset(4.0)]);
+        assert!(retained.defer[0].1.command.deferred);
+        assert_eq!(retained.clear, vec![broadcast]);
+    }
+
+    #[test]
+    fn each_topic_gets_its_own_commands_back() {
+        let own = command_topic("kitchen");
+        let broadcast = COMMAND_BROADCAST_TOPIC.to_string();
+        let retained = retained_after(
+            &[own.clone(), broadcast.clone()],
+            vec![
+                (broadcast.clone(), offset(1.0).into()),
+                (own.clone(), offset(2.0).into()),
+                (broadcast.clone(), DeviceCommand::GetTempOffset.into()),
+            ],
+        );
+        let topics: Vec<_> = retained.defer.iter().map(|(t, _)| t.clone()).collect();
+        assert_eq!(topics, vec![broadcast, own]);
+        assert_eq!(
+            retained.defer[0].1.command.commands,
+            [offset(1.0), DeviceCommand::GetTempOffset]
+        );
+        assert!(retained.clear.is_empty());
+    }
+
+    #[test]
+    fn nothing_deferred_clears_every_topic() {
+        let topics = vec![
+            COMMAND_BROADCAST_TOPIC.to_string(),
+            command_topic("kitchen"),
+        ];
+        assert_eq!(
+            retained_after(&topics, vec![]),
+            Retained {
+                defer: vec![],
+                turned_down: vec![],
+                clear: topics,
+            }
+        );
+    }
+
+    #[test]
+    fn commands_past_a_full_batch_are_turned_down() {
+        let own = command_topic("kitchen");
+        let deferred = (0..MAX_BATCH_COMMANDS as u32 + 1)
+            .map(|id| (own.clone(), DeviceCommand::GetAsc.with_id(id)))
+            .collect();
+        let retained = retained_after(std::slice::from_ref(&own), deferred);
+        assert_eq!(retained.defer.len(), 1);
+        assert_eq!(
+            retained.defer[0].1.command.commands.len(),
+            MAX_BATCH_COMMANDS
+        );
+        assert_eq!(retained.turned_down, [DeviceCommand::GetAsc.with_id(8)]);
+        assert!(retained.clear.is_empty());
+    }
+}
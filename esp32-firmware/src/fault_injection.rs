@@ -0,0 +1,93 @@
+//! The fault an `inject_fault` armed, kept in RTC slow memory from the wake
+//! that received it to the next one, see `shared_types::fault_injection`.
+//!
+//! Only builds with the `fault-injection` feature keep one. The next wake
+//! takes it before doing anything else, so it happens once even when it is
+//! a panic, and a reset that doesn't keep RTC memory leaves nothing armed.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use shared_types::fault_injection::FaultKind;
+
+/// What the slot holds with no fault armed
+const NONE: u8 = 0;
+
+/// A fault waiting for the next wake
+pub struct FaultSlot(AtomicU8);
+
+impl FaultSlot {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(NONE))
+    }
+
+    /// Arms `kind` for the next wake, replacing whatever was armed
+    pub fn arm(&self, kind: FaultKind) {
+        self.0.store(code(kind), Ordering::Relaxed);
+    }
+
+    /// The fault for this wake, if one was armed, leaving the slot empty
+    pub fn take(&self) -> Option<FaultKind> {
+        from_code(self.0.swap(NONE, Ordering::Relaxed))
+    }
+}
+
+impl Default for FaultSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn code(kind: FaultKind) -> u8 {
+    match kind {
+        FaultKind::SensorTimeout => 1,
+        FaultKind::Co2Zero => 2,
+        FaultKind::Co2High => 3,
+        FaultKind::FlatHumidity => 4,
+        FaultKind::DelayedPublish => 5,
+        FaultKind::Panic => 6,
+    }
+}
+
+/// `None` for an empty slot, and for whatever a cold boot left in it
+fn from_code(value: u8) -> Option<FaultKind> {
+    FaultKind::ALL.into_iter().find(|kind| code(*kind) == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_fault_lasts_one_wake() {
+        let slot = FaultSlot::new();
+        assert_eq!(slot.take(), None);
+        slot.arm(FaultKind::Co2Zero);
+        assert_eq!(slot.take(), Some(FaultKind::Co2Zero));
+        assert_eq!(slot.take(), None);
+    }
+
+    #[test]
+    fn arming_again_replaces_the_fault() {
+        let slot = FaultSlot::new();
+        slot.arm(FaultKind::Panic);
+        slot.arm(FaultKind::FlatHumidity);
+        assert_eq!(slot.take(), Some(FaultKind::FlatHumidity));
+        assert_eq!(slot.take(), None);
+    }
+
+    #[test]
+    fn every_kind_survives_the_slot() {
+        let slot = FaultSlot::new();
+        for kind in FaultKind::ALL {
+            slot.arm(kind);
+            assert_eq!(slot.take(), Some(kind));
+        }
+    }
+
+    #[test]
+    fn leftover_memory_arms_nothing() {
+        let slot = FaultSlot(AtomicU8::new(0xA5));
+        assert_eq!(slot.take(), None);
+        assert_eq!(slot.0.load(Ordering::Relaxed), NONE);
+    }
+}
@@ -0,0 +1,245 @@
+//! Status indicator patterns for the firmware.
+//!
+//! The mapping from a pattern to what the LED actually does lives here rather
+//! than next to the LED drivers so it can be tested on the host. The plain
+//! GPIO LED only uses the flash count; an RGB pixel also gets the color and
+//! shape. When the indicator stays dark is `shared_types::quiet_hours`.
+
+use shared_types::ErrorCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlinkPattern {
+    Boot,
+    Connecting,
+    Connected,
+    FrcStarted,
+    Success,
+    Error(ErrorCode),
+    SafeMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+    pub const RED: Rgb = Rgb::new(255, 0, 0);
+    pub const GREEN: Rgb = Rgb::new(0, 255, 0);
+    pub const BLUE: Rgb = Rgb::new(0, 0, 255);
+    pub const CYAN: Rgb = Rgb::new(0, 255, 255);
+    pub const AMBER: Rgb = Rgb::new(255, 140, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scales every channel by `level / 255`.
+    pub fn scaled(self, level: u8) -> Self {
+        let scale = |c: u8| ((c as u16 * level as u16) / 255) as u8;
+        Self::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    /// `count` on/off flashes; the light is off afterwards.
+    Flash { count: u8, on_ms: u32, off_ms: u32 },
+    /// One fade in and out, then stays on dimly until the next pattern.
+    Pulse { period_ms: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Animation {
+    pub color: Rgb,
+    pub shape: Shape,
+}
+
+/// One frame of an animation: show `color` for `duration_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub color: Rgb,
+    pub duration_ms: u32,
+}
+
+const FLASH_MS: u32 = 200;
+const PULSE_STEPS: u32 = 8;
+
+const fn flash(color: Rgb, count: u8) -> Animation {
+    Animation {
+        color,
+        shape: Shape::Flash {
+            count,
+            on_ms: FLASH_MS,
+            off_ms: FLASH_MS,
+        },
+    }
+}
+
+const fn pulse(color: Rgb, period_ms: u32) -> Animation {
+    Animation {
+        color,
+        shape: Shape::Pulse { period_ms },
+    }
+}
+
+/// Number of red flashes per error class. The counts match what the plain LED
+/// has always blinked for these failures.
+pub const fn error_flashes(code: ErrorCode) -> u8 {
+    match code {
+        ErrorCode::SensorReadFailed => 2,
+        ErrorCode::SensorTimeout => 3,
+        ErrorCode::Other => 4,
+        ErrorCode::WifiError => 5,
+        ErrorCode::MqttError => 6,
+        ErrorCode::I2cBusStuck => 7,
+        ErrorCode::NvsError => 8,
+        ErrorCode::EncodingError => 9,
+        ErrorCode::I2cError => 10,
+    }
+}
+
+pub const fn animation(pattern: BlinkPattern) -> Animation {
+    match pattern {
+        BlinkPattern::Boot => flash(Rgb::WHITE, 1),
+        BlinkPattern::Connecting => pulse(Rgb::BLUE, 1_000),
+        BlinkPattern::Connected => flash(Rgb::GREEN, 2),
+        BlinkPattern::FrcStarted => flash(Rgb::CYAN, 3),
+        BlinkPattern::Success => flash(Rgb::GREEN, 5),
+        BlinkPattern::Error(code) => flash(Rgb::RED, error_flashes(code)),
+        BlinkPattern::SafeMode => pulse(Rgb::AMBER, 2_000),
+    }
+}
+
+impl Animation {
+    /// Blink count for a single-color LED, which can't show pulses.
+    pub fn plain_blinks(&self) -> u8 {
+        match self.shape {
+            Shape::Flash { count, .. } => count,
+            Shape::Pulse { .. } => 0,
+        }
+    }
+
+    /// What the light shows once the frames are done.
+    pub fn resting_color(&self) -> Rgb {
+        match self.shape {
+            Shape::Flash { .. } => Rgb::OFF,
+            Shape::Pulse { .. } => self.color.scaled(255 / PULSE_STEPS as u8),
+        }
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = Frame> + '_ {
+        let (count, steps) = match self.shape {
+            Shape::Flash { count, .. } => (count as u32 * 2, 0),
+            Shape::Pulse { .. } => (PULSE_STEPS * 2, PULSE_STEPS),
+        };
+        (0..count).map(move |i| match self.shape {
+            Shape::Flash { on_ms, off_ms, .. } => {
+                if i % 2 == 0 {
+                    Frame {
+                        color: self.color,
+                        duration_ms: on_ms,
+                    }
+                } else {
+                    Frame {
+                        color: Rgb::OFF,
+                        duration_ms: off_ms,
+                    }
+                }
+            }
+            Shape::Pulse { period_ms } => {
+                // Triangle: 1..=steps up, then steps..=1 down
+                let level = if i < steps { i + 1 } else { 2 * steps - i };
+                Frame {
+                    color: self.color.scaled((level * 255 / steps) as u8),
+                    duration_ms: period_ms / (2 * steps),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_CODES: [ErrorCode; 9] = [
+        ErrorCode::SensorTimeout,
+        ErrorCode::SensorReadFailed,
+        ErrorCode::I2cError,
+        ErrorCode::WifiError,
+        ErrorCode::MqttError,
+        ErrorCode::I2cBusStuck,
+        ErrorCode::NvsError,
+        ErrorCode::EncodingError,
+        ErrorCode::Other,
+    ];
+
+    #[test]
+    fn plain_led_keeps_historic_blink_counts() {
+        let blinks = |p| animation(p).plain_blinks();
+        assert_eq!(blinks(BlinkPattern::Boot), 1);
+        assert_eq!(blinks(BlinkPattern::Connected), 2);
+        assert_eq!(blinks(BlinkPattern::FrcStarted), 3);
+        assert_eq!(blinks(BlinkPattern::Success), 5);
+        assert_eq!(blinks(BlinkPattern::Error(ErrorCode::SensorTimeout)), 3);
+        assert_eq!(blinks(BlinkPattern::Error(ErrorCode::SensorReadFailed)), 2);
+        assert_eq!(blinks(BlinkPattern::Error(ErrorCode::WifiError)), 5);
+        assert_eq!(blinks(BlinkPattern::Error(ErrorCode::I2cError)), 10);
+    }
+
+    #[test]
+    fn errors_are_red_and_distinguishable() {
+        let mut seen = Vec::new();
+        for code in ALL_CODES {
+            let anim = animation(BlinkPattern::Error(code));
+            assert_eq!(anim.color, Rgb::RED);
+            assert!(!seen.contains(&anim.plain_blinks()), "{:?}", code);
+            seen.push(anim.plain_blinks());
+        }
+    }
+
+    #[test]
+    fn flash_frames_alternate_and_end_dark() {
+        let frames: Vec<_> = animation(BlinkPattern::Connected).frames().collect();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].color, Rgb::GREEN);
+        assert_eq!(frames[1].color, Rgb::OFF);
+        assert_eq!(frames[3].color, Rgb::OFF);
+        assert_eq!(animation(BlinkPattern::Connected).resting_color(), Rgb::OFF);
+    }
+
+    #[test]
+    fn pulse_ramps_up_and_down_within_period() {
+        let anim = animation(BlinkPattern::Connecting);
+        let frames: Vec<_> = anim.frames().collect();
+        let total: u32 = frames.iter().map(|f| f.duration_ms).sum();
+        // Integer division may drop a few ms off the period
+        assert!(1_000 - total < 2 * PULSE_STEPS);
+
+        let peak = frames.len() / 2 - 1;
+        assert_eq!(frames[peak].color, Rgb::BLUE);
+        for i in 0..peak {
+            assert!(frames[i].color.b < frames[i + 1].color.b);
+            assert_eq!(frames[i].color, frames[frames.len() - 1 - i].color);
+        }
+        assert_ne!(anim.resting_color(), Rgb::OFF);
+        assert_eq!(anim.plain_blinks(), 0);
+    }
+
+    #[test]
+    fn safe_mode_is_amber() {
+        assert_eq!(animation(BlinkPattern::SafeMode).color, Rgb::AMBER);
+    }
+
+    #[test]
+    fn brightness_scaling() {
+        assert_eq!(Rgb::AMBER.scaled(255), Rgb::AMBER);
+        assert_eq!(Rgb::AMBER.scaled(0), Rgb::OFF);
+        assert_eq!(Rgb::WHITE.scaled(51), Rgb::new(51, 51, 51));
+    }
+}
@@ -0,0 +1,20 @@
+//! The part of the firmware that doesn't need ESP-IDF.
+//!
+//! It builds for the host, so `cargo test -p esp32-firmware
+//! --no-default-features` runs its tests without the Xtensa toolchain. The
+//! protocol the device shares with the processor and commander lives in
+//! `shared-types`; the policies and state machines only the firmware runs,
+//! such as when to sleep, blink or recover the sensor bus, live here.
+
+pub mod adaptive_sleep;
+pub mod bus_recovery;
+pub mod clock;
+pub mod command_topics;
+pub mod fault_injection;
+pub mod indicator;
+pub mod outbox;
+pub mod partitions;
+pub mod persist_guard;
+pub mod supply_guard;
+pub mod wake_log;
+pub mod wake_split;
@@ -1,37 +1,118 @@
-use anyhow::{Result, bail};
+mod sensor_bus;
+mod status_led;
+mod supply;
+
+use anyhow::Result;
+use esp_idf_hal::cpu::Core;
 use esp_idf_hal::delay::FreeRtos;
-use esp_idf_hal::gpio::PinDriver;
-use esp_idf_hal::i2c::{self, I2cDriver};
+use esp_idf_hal::gpio::{Gpio21, Gpio22, PinDriver};
+use esp_idf_hal::i2c::{I2C0, I2cDriver};
 use esp_idf_hal::peripherals::Peripherals;
-use esp_idf_hal::units::Hertz;
+use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
 use esp_idf_svc::sys as esp_idf_sys;
 use log::info;
 
 use esp_idf_hal::delay::Ets;
 use scd4x::Scd4x;
+use scd4x::types::SensorData;
 
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::log::EspLogger;
 use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
 use esp_idf_svc::wifi::{BlockingWifi, ClientConfiguration, Configuration, EspWifi};
 
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::time::Duration;
-
-use shared_types::{DeviceCommand, DeviceMessage, DevicePayload};
+use std::time::{Duration, Instant};
+
+use esp32_firmware::adaptive_sleep::{self, AdaptiveSleep};
+use esp32_firmware::clock;
+use esp32_firmware::command_topics;
+#[cfg(feature = "fault-injection")]
+use esp32_firmware::fault_injection::FaultSlot;
+use esp32_firmware::indicator::BlinkPattern;
+use esp32_firmware::outbox::{BlobStore, Outbox};
+use esp32_firmware::persist_guard::{DEFAULT_PERSISTS_PER_DAY, PersistLog};
+use esp32_firmware::supply_guard;
+use esp32_firmware::wake_log::{self, WakeLog};
+use esp32_firmware::wake_split::{self, Joined, WakePlan, WakeTimings};
+use shared_types::command_schedule::{Schedule, schedule_tagged};
+use shared_types::config_trial::{ConfigChange, ConfigTrial, DEFAULT_CONFIRM_WAKES};
+use shared_types::device_config::{DeviceConfig, SensorMode};
+use shared_types::device_error::{Context, DeviceError, DeviceResult};
+use shared_types::factory_reset::FactoryReset;
+use shared_types::fault_injection::{DELAYED_PUBLISH_SECONDS, FaultKind};
+use shared_types::log_level::LogLevel;
+use shared_types::mqtt_policy::{MqttPolicy, PayloadClass, PublishPolicy};
+use shared_types::topics::{self, COMMAND_BROADCAST_TOPIC};
+use shared_types::{
+    CommandEnvelope, CommandMessage, Detail, DeviceCommand, DeviceCommandBatch, DeviceMessage,
+    DevicePayload, ErrorCode, MeasurementFlags, detail,
+};
+use status_led::StatusLed;
+use supply::Supply;
 
 const WIFI_SSID: &str = env!("WIFI_SSID");
 const WIFI_PASSWORD: &str = env!("WIFI_PASSWORD");
 
 const MQTT_BROKER_URL: &str = env!("MQTT_BROKER_URL");
-const MQTT_TOPIC_SENSOR: &str = "sensors/esp32/sensor";
-const MQTT_COMMAND_TOPIC: &str = "sensors/esp32/command";
 
 const DEVICE_NAME: &str = "esp32-scd40";
 
+/// Sent as `fw_version` with every message
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The room the device is set up in, e.g. "bedroom", sent as `location`
+/// with every message. Set through .env; left out when unset or empty.
+const DEVICE_LOCATION: Option<&str> = option_env!("DEVICE_LOCATION");
+
+/// GPIO of the WS2812 data line and its brightness (0-255), `neopixel` feature only
+#[cfg(feature = "neopixel")]
+const NEOPIXEL_GPIO: Option<&str> = option_env!("NEOPIXEL_GPIO");
+#[cfg(feature = "neopixel")]
+const NEOPIXEL_BRIGHTNESS: Option<&str> = option_env!("NEOPIXEL_BRIGHTNESS");
+/// Local hours to keep the pixel dark, e.g. "22-7", with the UTC offset in hours
+#[cfg(feature = "neopixel")]
+const QUIET_HOURS: Option<&str> = option_env!("QUIET_HOURS");
+#[cfg(feature = "neopixel")]
+const UTC_OFFSET_HOURS: Option<&str> = option_env!("UTC_OFFSET_HOURS");
+
+/// Compiled-in QoS/retain overrides, e.g. "measurement=1+retain,diagnostic=0"
+const MQTT_POLICY: Option<&str> = option_env!("MQTT_POLICY");
+
 const DEFAULT_DEEP_SLEEP_SECONDS: u64 = 300;
 const NVS_NAMESPACE: &str = "storage";
 const NVS_SLEEP_KEY: &str = "sleep_sec";
+const NVS_MQTT_POLICY_KEY: &str = "mqtt_policy";
+const NVS_PERSIST_COUNT_KEY: &str = "persist_cnt";
+const NVS_PERSIST_LAST_KEY: &str = "persist_last";
+const NVS_PERSIST_LOG_KEY: &str = "persist_log";
+const NVS_LOG_LEVEL_KEY: &str = "log_level";
+const NVS_ADAPTIVE_KEY: &str = "adaptive";
+const NVS_PRESSURE_KEY: &str = "pressure_pa";
+const NVS_TRIAL_KEY: &str = "config_trial";
+
+/// Adaptive sleep table and its MIN-MAX bounds, see `adaptive_sleep`
+const ADAPTIVE_SLEEP: Option<&str> = option_env!("ADAPTIVE_SLEEP");
+const ADAPTIVE_SLEEP_BOUNDS: Option<&str> = option_env!("ADAPTIVE_SLEEP_BOUNDS");
+
+/// SCD4x EEPROM writes allowed per day, `persist_guard::DEFAULT_PERSISTS_PER_DAY` if unset
+const PERSISTS_PER_DAY: Option<&str> = option_env!("PERSISTS_PER_DAY");
+
+/// Wakes a setting stays on trial without a confirmation,
+/// `config_trial::DEFAULT_CONFIRM_WAKES` if unset
+const CONFIRM_WAKES: Option<&str> = option_env!("CONFIRM_WAKES");
+/// How long a wake that put a setting on trial waits for a commander to
+/// confirm it, before leaving that to a later wake
+const CONFIRM_WAIT: Duration = Duration::from_secs(5);
+
+/// Stack of the sensor task, which logs with formatting on the way
+const SENSOR_TASK_STACK_SIZE: usize = 8 * 1024;
+/// From starting the sensor task; the probe, its recoveries and the 15 s wait
+/// for data fit well within it
+const SENSOR_TASK_TIMEOUT: Duration = Duration::from_secs(25);
 
 fn read_deep_sleep_from_nvs(nvs: &EspNvs<NvsDefault>) -> u64 {
     match nvs.get_u64(NVS_SLEEP_KEY) {
@@ -53,40 +134,496 @@ fn read_deep_sleep_from_nvs(nvs: &EspNvs<NvsDefault>) -> u64 {
     }
 }
 
-fn write_deep_sleep_to_nvs(nvs: &mut EspNvs<NvsDefault>, seconds: u64) -> Result<()> {
-    nvs.set_u64(NVS_SLEEP_KEY, seconds)?;
+fn write_deep_sleep_to_nvs(nvs: &mut EspNvs<NvsDefault>, seconds: u64) -> DeviceResult<()> {
+    nvs.set_u64(NVS_SLEEP_KEY, seconds)
+        .context(DeviceError::Nvs("saving deep sleep time"))?;
     info!("Saved deep sleep time to NVS: {} seconds", seconds);
     Ok(())
 }
 
-fn blink_led(
-    led: &mut PinDriver<'_, esp_idf_hal::gpio::Gpio2, esp_idf_hal::gpio::Output>,
-    times: u8,
+fn read_adaptive_from_nvs(nvs: &EspNvs<NvsDefault>) -> bool {
+    match nvs.get_u8(NVS_ADAPTIVE_KEY) {
+        Ok(Some(value)) => value != 0,
+        Ok(None) => false,
+        Err(e) => {
+            info!("Failed to read adaptive mode from NVS: {:?}, leaving it off", e);
+            false
+        }
+    }
+}
+
+fn write_adaptive_to_nvs(nvs: &mut EspNvs<NvsDefault>, enabled: bool) -> DeviceResult<()> {
+    nvs.set_u8(NVS_ADAPTIVE_KEY, enabled as u8)
+        .context(DeviceError::Nvs("saving adaptive mode"))?;
+    info!("Saved adaptive mode to NVS: {}", enabled);
+    Ok(())
+}
+
+/// The SCD4x forgets the ambient pressure when it powers down, so it is kept
+/// here and set again every wake.
+fn read_ambient_pressure_from_nvs(nvs: &EspNvs<NvsDefault>) -> Option<u32> {
+    match nvs.get_u32(NVS_PRESSURE_KEY) {
+        Ok(value) => value,
+        Err(e) => {
+            info!("Failed to read ambient pressure from NVS: {:?}, compensating for altitude", e);
+            None
+        }
+    }
+}
+
+fn write_ambient_pressure_to_nvs(nvs: &mut EspNvs<NvsDefault>, pascals: u32) -> DeviceResult<()> {
+    nvs.set_u32(NVS_PRESSURE_KEY, pascals)
+        .context(DeviceError::Nvs("saving ambient pressure"))?;
+    info!("Saved ambient pressure to NVS: {} Pa", pascals);
+    Ok(())
+}
+
+fn compiled_adaptive_sleep() -> AdaptiveSleep {
+    let table = match ADAPTIVE_SLEEP.map(str::parse::<AdaptiveSleep>) {
+        Some(Ok(table)) => table,
+        Some(Err(e)) => {
+            info!("Ignoring ADAPTIVE_SLEEP ({}), using the default table", e);
+            AdaptiveSleep::default()
+        }
+        None => AdaptiveSleep::default(),
+    };
+    match ADAPTIVE_SLEEP_BOUNDS.map(|bounds| table.clone().with_bounds(bounds)) {
+        Some(Ok(bounded)) => bounded,
+        Some(Err(e)) => {
+            info!("Ignoring ADAPTIVE_SLEEP_BOUNDS ({}), using the default bounds", e);
+            table
+        }
+        None => table,
+    }
+}
+
+fn read_persist_log(nvs: &EspNvs<NvsDefault>) -> PersistLog {
+    let mut buf = [0u8; 64];
+    match nvs.get_raw(NVS_PERSIST_LOG_KEY, &mut buf) {
+        Ok(Some(blob)) => match PersistLog::from_blob(blob) {
+            Ok(log) => return log,
+            // Counts as no writes yet, at worst a day's writes too many
+            Err(e) => info!("Ignoring the stored persist log: {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => info!("Couldn't read the persist log: {:?}", e),
+    }
+    // Builds before the versioned log kept two separate keys
+    let count = nvs.get_u16(NVS_PERSIST_COUNT_KEY).ok().flatten();
+    let last = nvs.get_u64(NVS_PERSIST_LAST_KEY).ok().flatten();
+    PersistLog {
+        count: count.unwrap_or(0),
+        last: last.unwrap_or(0),
+    }
+}
+
+fn write_persist_log(nvs: &mut EspNvs<NvsDefault>, log: &PersistLog) -> DeviceResult<()> {
+    nvs.set_raw(NVS_PERSIST_LOG_KEY, &log.to_blob())
+        .context(DeviceError::Nvs("saving persist log"))?;
+    Ok(())
+}
+
+/// Unreadable or torn, the trial is dropped and the saved settings apply
+fn read_config_trial(nvs: &EspNvs<NvsDefault>) -> ConfigTrial {
+    let mut buf = [0u8; 64];
+    match nvs.get_raw(NVS_TRIAL_KEY, &mut buf) {
+        Ok(Some(blob)) => match ConfigTrial::from_blob(blob) {
+            Ok(trial) => return trial,
+            Err(e) => info!("Ignoring the stored config trial: {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => info!("Couldn't read the config trial: {:?}", e),
+    }
+    ConfigTrial::default()
+}
+
+fn write_config_trial(nvs: &mut EspNvs<NvsDefault>, trial: &ConfigTrial) -> DeviceResult<()> {
+    nvs.set_raw(NVS_TRIAL_KEY, &trial.to_blob())
+        .context(DeviceError::Nvs("saving config trial"))?;
+    Ok(())
+}
+
+/// The outbox's view of NVS
+struct NvsBlobs<'a>(&'a mut EspNvs<NvsDefault>);
+
+impl BlobStore for NvsBlobs<'_> {
+    type Error = esp_idf_sys::EspError;
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        // A full outbox takes a couple of KiB
+        let mut buf = vec![0u8; 4096];
+        Ok(self.0.get_raw(key, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    fn store(&mut self, key: &str, blob: &[u8]) -> Result<(), Self::Error> {
+        self.0.set_raw(key, blob)?;
+        Ok(())
+    }
+}
+
+fn confirm_wakes() -> u8 {
+    CONFIRM_WAKES
+        .and_then(|wakes| wakes.parse().ok())
+        .unwrap_or(DEFAULT_CONFIRM_WAKES)
+}
+
+/// Uses `change` for this wake without saving it
+fn apply_change(change: ConfigChange, deep_sleep_seconds: &mut u64, mqtt_policy: &mut MqttPolicy) {
+    match change {
+        ConfigChange::DeepSleep { seconds } => *deep_sleep_seconds = seconds,
+        ConfigChange::MqttPolicy { class, qos, retain } => {
+            mqtt_policy.set(class, PublishPolicy::new(qos, retain))
+        }
+    }
+}
+
+/// Puts back the saved value of the setting `change` was trying out
+fn restore_saved(
+    nvs: &EspNvs<NvsDefault>,
+    change: ConfigChange,
+    deep_sleep_seconds: &mut u64,
+    mqtt_policy: &mut MqttPolicy,
 ) {
-    for _ in 0..times {
-        led.set_high().ok();
-        FreeRtos::delay_ms(200);
-        led.set_low().ok();
-        FreeRtos::delay_ms(200);
+    match change {
+        ConfigChange::DeepSleep { .. } => *deep_sleep_seconds = read_deep_sleep_from_nvs(nvs),
+        ConfigChange::MqttPolicy { class, .. } => {
+            mqtt_policy.set(class, read_mqtt_policy_from_nvs(nvs).get(class))
+        }
+    }
+}
+
+/// Saves a confirmed change on top of the other saved settings
+fn save_change(nvs: &mut EspNvs<NvsDefault>, change: ConfigChange) -> DeviceResult<()> {
+    match change {
+        ConfigChange::DeepSleep { seconds } => write_deep_sleep_to_nvs(nvs, seconds),
+        ConfigChange::MqttPolicy { class, qos, retain } => {
+            let mut saved = read_mqtt_policy_from_nvs(nvs);
+            saved.set(class, PublishPolicy::new(qos, retain));
+            write_mqtt_policy_to_nvs(nvs, &saved)
+        }
+    }
+}
+
+fn persists_per_day() -> u16 {
+    PERSISTS_PER_DAY
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_PERSISTS_PER_DAY)
+}
+
+/// Seconds on the RTC: since the epoch once SNTP has synced, since power-on before
+fn clock_seconds() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Milliseconds since the epoch, or `None` until SNTP has set the clock
+fn clock_millis() -> Option<u64> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    clock::epoch_millis(SystemTime::now().duration_since(UNIX_EPOCH).ok()?)
+}
+
+/// Measurements published since power-on. RTC slow memory keeps it through
+/// deep sleep, so the server can tell a lost or replayed message from a
+/// restart.
+#[unsafe(link_section = ".rtc.data")]
+static MEASUREMENT_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// CO2 of the previous wake's measurement for adaptive sleep, 0 if there was
+/// none. Kept in RTC slow memory like `MEASUREMENT_SEQ`.
+#[unsafe(link_section = ".rtc.data")]
+static PREVIOUS_CO2: AtomicU16 = AtomicU16::new(0);
+
+/// Whether the sensor has been read since power-on. Kept in RTC slow memory
+/// like `MEASUREMENT_SEQ`; the sensor stays powered through deep sleep.
+#[unsafe(link_section = ".rtc.data")]
+static SENSOR_READ_SINCE_BOOT: AtomicBool = AtomicBool::new(false);
+
+/// How long after power-on the sensor's readings take to settle
+const SENSOR_WARMUP_MS: i64 = 60_000;
+
+/// Wait for the sensor's next periodic result before reading again
+const READ_RETRY_DELAY_MS: u32 = 5_000;
+
+/// The id of the command being run; everything published while it runs
+/// answers it
+static ANSWERING: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Set by a `reboot`: the wake ends with a restart instead of deep sleep
+static REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Armed by `inject_fault` for the next wake. In RTC slow memory, which
+/// keeps it through deep sleep; see `fault_injection`.
+#[cfg(feature = "fault-injection")]
+#[unsafe(link_section = ".rtc.data")]
+static ARMED_FAULT: FaultSlot = FaultSlot::new();
+
+/// The fault this wake produces, taken from `ARMED_FAULT` at boot
+#[cfg(feature = "fault-injection")]
+static WAKE_FAULT: OnceLock<Option<FaultKind>> = OnceLock::new();
+
+#[cfg(feature = "fault-injection")]
+fn wake_fault() -> Option<FaultKind> {
+    WAKE_FAULT.get().copied().flatten()
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn wake_fault() -> Option<FaultKind> {
+    None
+}
+
+fn compiled_mqtt_policy() -> MqttPolicy {
+    match MQTT_POLICY.map(|p| MqttPolicy::DEFAULT.with_overrides(p)) {
+        Some(Ok(policy)) => policy,
+        Some(Err(e)) => {
+            info!("Invalid MQTT_POLICY ({}), using defaults", e);
+            MqttPolicy::DEFAULT
+        }
+        None => MqttPolicy::DEFAULT,
+    }
+}
+
+fn read_mqtt_policy_from_nvs(nvs: &EspNvs<NvsDefault>) -> MqttPolicy {
+    let defaults = compiled_mqtt_policy();
+    let mut buf = [0u8; 128];
+    match nvs.get_str(NVS_MQTT_POLICY_KEY, &mut buf) {
+        Ok(Some(stored)) => match defaults.with_overrides(stored) {
+            Ok(policy) => {
+                info!("Read MQTT policy from NVS: {}", policy);
+                policy
+            }
+            Err(e) => {
+                info!("Invalid MQTT policy in NVS ({}), using defaults", e);
+                defaults
+            }
+        },
+        Ok(None) => defaults,
+        Err(e) => {
+            info!("Failed to read MQTT policy from NVS: {:?}, using defaults", e);
+            defaults
+        }
+    }
+}
+
+fn write_mqtt_policy_to_nvs(nvs: &mut EspNvs<NvsDefault>, policy: &MqttPolicy) -> DeviceResult<()> {
+    nvs.set_str(NVS_MQTT_POLICY_KEY, &policy.to_string())
+        .context(DeviceError::Nvs("saving MQTT policy"))?;
+    info!("Saved MQTT policy to NVS: {}", policy);
+    Ok(())
+}
+
+/// Set once at the start of `main`
+static WAKE_LOG: OnceLock<WakeLog<EspLogger>> = OnceLock::new();
+
+/// Logs at the default level until `main` has read the level from NVS
+fn init_logging() {
+    let mut secrets = vec![WIFI_PASSWORD];
+    secrets.extend(wake_log::url_password(MQTT_BROKER_URL));
+    let logger =
+        WAKE_LOG.get_or_init(|| WakeLog::new(EspLogger::new(), LogLevel::default(), secrets));
+    if log::set_logger(logger).is_ok() {
+        apply_log_level(LogLevel::default());
+    }
+}
+
+fn log_level() -> LogLevel {
+    WAKE_LOG.get().map(WakeLog::level).unwrap_or_default()
+}
+
+/// Rust code logs through `WAKE_LOG`, the ESP-IDF components through their
+/// own logger, so both are set
+fn apply_log_level(level: LogLevel) {
+    if let Some(logger) = WAKE_LOG.get() {
+        logger.set_level(level);
+    }
+    log::set_max_level(wake_log::level_filter(level));
+    let esp_level = match level {
+        LogLevel::Error => esp_idf_sys::esp_log_level_t_ESP_LOG_ERROR,
+        LogLevel::Warn => esp_idf_sys::esp_log_level_t_ESP_LOG_WARN,
+        LogLevel::Info => esp_idf_sys::esp_log_level_t_ESP_LOG_INFO,
+        LogLevel::Debug => esp_idf_sys::esp_log_level_t_ESP_LOG_DEBUG,
+        LogLevel::Verbose => esp_idf_sys::esp_log_level_t_ESP_LOG_VERBOSE,
+    };
+    // SAFETY: "*" is a valid NUL-terminated tag for the whole call
+    unsafe {
+        esp_idf_sys::esp_log_level_set(c"*".as_ptr(), esp_level);
+    }
+}
+
+fn read_log_level_from_nvs(nvs: &EspNvs<NvsDefault>) -> LogLevel {
+    let mut buf = [0u8; 16];
+    match nvs.get_str(NVS_LOG_LEVEL_KEY, &mut buf) {
+        Ok(Some(stored)) => match stored.parse() {
+            Ok(level) => {
+                info!("Read log level from NVS: {}", level);
+                level
+            }
+            Err(e) => {
+                info!("Invalid log level in NVS ({}), using default", e);
+                LogLevel::default()
+            }
+        },
+        Ok(None) => LogLevel::default(),
+        Err(e) => {
+            info!("Failed to read log level from NVS: {:?}, using default", e);
+            LogLevel::default()
+        }
     }
 }
 
-fn publish_device_payload(client: &mut EspMqttClient, payload: DevicePayload) -> Result<()> {
-    let topic = MQTT_TOPIC_SENSOR;
-    let message = DeviceMessage {
-        device: DEVICE_NAME.to_string(),
-        payload: payload,
+fn write_log_level_to_nvs(nvs: &mut EspNvs<NvsDefault>, level: LogLevel) -> DeviceResult<()> {
+    nvs.set_str(NVS_LOG_LEVEL_KEY, level.as_str())
+        .context(DeviceError::Nvs("saving log level"))?;
+    info!("Saved log level to NVS: {}", level);
+    Ok(())
+}
+
+#[cfg(feature = "neopixel")]
+fn in_quiet_hours() -> bool {
+    use shared_types::quiet_hours::QuietHours;
+
+    let Some(quiet_hours) = QUIET_HOURS.and_then(|q| q.parse::<QuietHours>().ok()) else {
+        return false;
     };
-    let mqtt_payload = serde_json::to_vec(&message)?;
-    info!("MQTT Publish: {} bytes", mqtt_payload.len());
-    client.publish(topic, QoS::AtLeastOnce, false, &mqtt_payload)?;
+    // The RTC keeps time through deep sleep once SNTP has synced; before
+    // that the clock starts at 1970 and quiet hours can't be applied
+    let Some(now) = clock_millis() else {
+        return false;
+    };
+    let offset: i64 = UTC_OFFSET_HOURS.and_then(|o| o.parse().ok()).unwrap_or(0);
+    quiet_hours.contains(clock::local_hour(now / 1000, offset))
+}
+
+/// Measurement timestamps and quiet hours both need the time of day
+fn sync_time() {
+    use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+
+    match EspSntp::new_default() {
+        Ok(sntp) => {
+            for _ in 0..30 {
+                if sntp.get_sync_status() == SyncStatus::Completed {
+                    info!("SNTP time synchronized");
+                    return;
+                }
+                FreeRtos::delay_ms(100);
+            }
+            info!("SNTP sync not complete, keeping RTC time");
+        }
+        Err(e) => info!("Failed to start SNTP: {:?}", e),
+    }
+}
+
+fn to_qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+fn publish_device_payload(
+    client: &mut EspMqttClient,
+    policy: &MqttPolicy,
+    payload: DevicePayload,
+) -> DeviceResult<()> {
+    publish_message(client, policy, &device_message(payload))
+}
+
+fn device_message(payload: DevicePayload) -> DeviceMessage {
+    let mut message = DeviceMessage::new(DEVICE_NAME, payload).with_fw_version(FIRMWARE_VERSION);
+    if let Some(location) = DEVICE_LOCATION.filter(|location| !location.is_empty()) {
+        message = message.with_location(location);
+    }
+    if wake_fault().is_some() {
+        message = message.injected();
+    }
+    match *ANSWERING.lock().unwrap() {
+        Some(id) => message.replying_to(id),
+        None => message,
+    }
+}
+
+fn publish_message(
+    client: &mut EspMqttClient,
+    policy: &MqttPolicy,
+    message: &DeviceMessage,
+) -> DeviceResult<()> {
+    let PublishPolicy { qos, retain } = policy.for_payload(&message.payload);
+    send_message(client, qos, retain, message)?;
+    // The lines leading up to the error, its own log line included
+    if message.payload.class() == PayloadClass::Error && wake_log::sends_log_lines(log_level()) {
+        if let Some(logger) = WAKE_LOG.get() {
+            let lines = logger.recent();
+            publish_device_payload(client, policy, DevicePayload::LogLines { lines })?;
+        }
+    }
     Ok(())
 }
 
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+/// Publishes `message` as given, returning its packet id
+fn send_message(
+    client: &mut EspMqttClient,
+    qos: u8,
+    retain: bool,
+    message: &DeviceMessage,
+) -> DeviceResult<u32> {
+    let mqtt_payload = message.to_json_vec()?;
+    info!(
+        "MQTT Publish: {} bytes, QoS {}, retain {}",
+        mqtt_payload.len(),
+        qos,
+        retain
+    );
+    // Room for any device name worth typing, without allocating per publish
+    let mut topic = [0; 96];
+    let topic = topics::write_sensor_topic(DEVICE_NAME, &mut topic)
+        .context(DeviceError::Mqtt("building the sensor topic"))?;
+    client
+        .publish(topic, to_qos(qos), retain, &mqtt_payload)
+        .context(DeviceError::Mqtt("publishing payload"))
+}
+
+/// Publishes a measurement, keeping it in the outbox until the broker
+/// acknowledges it, see `outbox`
+fn publish_measurement(
+    client: &mut EspMqttClient,
+    policy: &MqttPolicy,
+    nvs: &mut EspNvs<NvsDefault>,
+    outbox: &mut Outbox,
+    message: &DeviceMessage,
+) -> DeviceResult<()> {
+    let PublishPolicy { qos, retain } = policy.for_payload(&message.payload);
+    if qos == 0 {
+        // Nothing acknowledges it, so there is nothing to wait for
+        return send_message(client, qos, retain, message).map(|_| ());
+    }
+    let id = outbox.add(message.clone());
+    if let Err(e) = outbox.save(&mut NvsBlobs(nvs)) {
+        // Still sent again if it's lost this wake and the next save works
+        info!("Failed to save the outbox: {:?}", e);
+    }
+    let packet_id = send_message(client, qos, retain, message)?;
+    outbox.published(id, packet_id);
+    Ok(())
+}
+
+/// Keeps a measurement the supply guard didn't let out in the outbox, so
+/// the first wake with the radio on again publishes it
+fn buffer_measurement(nvs: &mut EspNvs<NvsDefault>, outbox: &mut Outbox, message: DeviceMessage) {
+    outbox.add(message);
+    if let Err(e) = outbox.save(&mut NvsBlobs(nvs)) {
+        info!("Failed to save the outbox: {:?}", e);
+    }
+}
+
+fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> DeviceResult<()> {
     info!("Connecting to WiFi SSID: '{}'", WIFI_SSID);
     info!("Starting WiFi...");
-    wifi.start()?;
+    wifi.start().context(DeviceError::Wifi("starting WiFi"))?;
     const MAX_RETRIES: u8 = 3;
     for attempt in 1..=MAX_RETRIES {
         info!("Connection attempt {}/{}", attempt, MAX_RETRIES);
@@ -102,17 +639,22 @@ fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
                     FreeRtos::delay_ms(2000);
                     let _ = wifi.stop();
                     FreeRtos::delay_ms(500);
-                    wifi.start()?;
+                    wifi.start().context(DeviceError::Wifi("restarting WiFi"))?;
                     FreeRtos::delay_ms(500);
                 } else {
-                    return Err(e.into());
+                    return Err(DeviceError::Wifi("connecting to the access point"));
                 }
             }
         }
     }
     info!("Waiting for netacaork interface to come up...");
-    wifi.wait_netif_up()?;
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    wifi.wait_netif_up()
+        .context(DeviceError::Wifi("waiting for the network interface"))?;
+    let ip_info = wifi
+        .wifi()
+        .sta_netif()
+        .get_ip_info()
+        .context(DeviceError::Wifi("reading the IP address"))?;
     info!("WiFi connected");
     info!("  IP address: {:?}", ip_info.ip);
 
@@ -125,43 +667,80 @@ fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
     Ok(())
 }
 
-fn start_periodic_measurement(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> Result<()> {
+fn start_periodic_measurement(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<()> {
     info!("Starting periodic measurement...");
     match scd40.start_periodic_measurement() {
         Ok(_) => info!("Measurement started"),
-        Err(e) => bail!("Failed to start measurement: {:?}", e),
+        Err(e) => {
+            info!("Failed to start measurement: {:?}", e);
+            return Err(DeviceError::I2c("starting periodic measurement"));
+        }
     }
     Ok(())
 }
 
-fn stop_periodic_measurement(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> Result<()> {
+fn stop_periodic_measurement(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<()> {
     info!("Stopping periodic measurement...");
     match scd40.stop_periodic_measurement() {
         Ok(_) => info!("Measurement stopped"),
-        Err(e) => bail!("Failed to stop measurement: {:?}", e),
+        Err(e) => {
+            info!("Failed to stop measurement: {:?}", e);
+            return Err(DeviceError::I2c("stopping periodic measurement"));
+        }
     }
     info!("Waiting 600ms for stop command to complete...");
     FreeRtos::delay_ms(600);
     Ok(())
 }
 
-fn clear_retained_command(client: &mut EspMqttClient) -> Result<()> {
-    info!("Clearing retained command from broker...");
-    client.publish(
-        MQTT_COMMAND_TOPIC,
-        QoS::AtLeastOnce,
-        true, // RETAIN = true
-        "".as_bytes(),
-    )?;
+/// Commands for this device only, e.g. from `fleet ota` in the commander
+fn device_command_topic() -> String {
+    topics::command_topic(DEVICE_NAME)
+}
+
+fn clear_retained_command(client: &mut EspMqttClient, topic: &str) -> DeviceResult<()> {
+    info!("Clearing retained command on {} from broker...", topic);
+    client
+        .publish(
+            topic,
+            QoS::AtLeastOnce,
+            true, // RETAIN = true
+            "".as_bytes(),
+        )
+        .context(DeviceError::Mqtt("clearing retained command"))?;
     Ok(())
 }
 
-fn perform_measurement(
-    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
-    led: &mut PinDriver<'_, esp_idf_hal::gpio::Gpio2, esp_idf_hal::gpio::Output>,
-) -> Result<DevicePayload> {
-    let mut failure_reason: u8 = 0;
+/// Re-publishes commands held back by the FRC interlock as a retained batch
+/// on `topic`, the one they arrived on.
+fn defer_commands(
+    client: &mut EspMqttClient,
+    topic: &str,
+    batch: &CommandEnvelope<DeviceCommandBatch>,
+) -> DeviceResult<()> {
+    client
+        .publish(topic, QoS::AtLeastOnce, true, &batch.to_json_vec()?)
+        .context(DeviceError::Mqtt("deferring commands"))?;
+    Ok(())
+}
+
+/// A reading and the conditions it was taken under
+type Reading = (SensorData, MeasurementFlags);
+
+/// Whether the sensor was powered on less than `SENSOR_WARMUP_MS` ago. It
+/// is only powered down with the chip, never in deep sleep.
+fn sensor_warming_up() -> bool {
+    let cold_boot = unsafe { esp_idf_sys::esp_reset_reason() }
+        != esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP;
+    let uptime_ms = unsafe { esp_idf_sys::esp_timer_get_time() } / 1000;
+    cold_boot && uptime_ms < SENSOR_WARMUP_MS
+}
+
+/// Starts periodic measurement, waits for the first result and stops again.
+/// A failed read is retried once, on the next result.
+fn measure(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<Reading> {
     start_periodic_measurement(scd40)?;
+    let mut flags = MeasurementFlags::default();
 
     let mut attempts = 0;
     const MAX_ATTEMPTS: u8 = 15;
@@ -175,223 +754,200 @@ fn perform_measurement(
     }
 
     let data = if attempts >= MAX_ATTEMPTS {
-        blink_led(led, 3);
         info!("Timeout waiting for sensor data");
-        failure_reason = 1;
-        None
+        Err(DeviceError::SensorTimeout("Measurement timed out"))
     } else {
         info!("Reading measurement data...");
-        match scd40.measurement() {
-            Ok(data) => {
-                info!("CO2: {} ppm, Temperature: {:.2} °C, Humidity: {:.2} %", data.co2, data.temperature, data.humidity);
-                Some(data)
-            }
-            Err(e) => {
-                blink_led(led, 2);
+        scd40.measurement().or_else(|e| {
+            info!("Failed to read measurement, retrying: {:?}", e);
+            flags.retried_read = true;
+            FreeRtos::delay_ms(READ_RETRY_DELAY_MS);
+            scd40.measurement().map_err(|e| {
                 info!("Failed to read measurement: {:?}", e);
-                failure_reason = 2;
-                None
-            }
-        }
+                DeviceError::Sensor("Failed to read measurement")
+            })
+        })
     };
 
     stop_periodic_measurement(scd40)?;
+    let data = data?;
+    flags.first_after_boot = !SENSOR_READ_SINCE_BOOT.swap(true, Ordering::Relaxed);
+    flags.sensor_warmup_incomplete = sensor_warming_up();
+    Ok((data, flags))
+}
 
-    let final_mqtt_message = if let Some(sensor_data) = data {
-        DevicePayload::MeasurementSuccess {
-            co2: sensor_data.co2,
-            temperature: sensor_data.temperature,
-            humidity: sensor_data.humidity,
-        }
-    } else {
-        if failure_reason == 1 {
-            DevicePayload::Error {
-                detail: "Measurement timed out".to_string(),
+/// `resting_mv` is the supply before WiFi started, if the build samples
+/// it, see `supply`
+fn measurement_payload(
+    data: DeviceResult<Reading>,
+    resting_mv: Option<u16>,
+    led: &mut dyn StatusLed,
+) -> DevicePayload {
+    match data {
+        Ok((sensor_data, flags)) => {
+            info!("CO2: {} ppm, Temperature: {:.2} °C, Humidity: {:.2} %", sensor_data.co2, sensor_data.temperature, sensor_data.humidity);
+            let payload = match resting_mv {
+                Some(mv) => DevicePayload::measurement_with_battery(
+                    sensor_data.co2,
+                    sensor_data.temperature,
+                    sensor_data.humidity,
+                    mv,
+                    supply_guard::battery_percent(mv),
+                ),
+                None => DevicePayload::measurement(
+                    sensor_data.co2,
+                    sensor_data.temperature,
+                    sensor_data.humidity,
+                ),
             }
-        } else {
+            .flagged(flags);
+            // A glitching sensor reads 0 ppm and the like; the processor
+            // would only drop it, so report the glitch instead
+            match DeviceMessage::new(DEVICE_NAME, payload.clone()).validate() {
+                Ok(()) => payload,
+                // An injected glitch goes out as it is, for the processor to catch
+                Err(_) if wake_fault().is_some() => payload,
+                Err(e) => {
+                    let error = DeviceError::Sensor("Sensor reported an impossible reading");
+                    led.show(BlinkPattern::Error(error.code()));
+                    DevicePayload::Error {
+                        code: error.code(),
+                        detail: detail!("{}: {}", error.context(), e),
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            led.show(BlinkPattern::Error(e.code()));
             DevicePayload::Error {
-                detail: "Failed to read measurement".to_string(),
+                code: e.code(),
+                detail: e.context().into(),
             }
         }
-    };
-    Ok(final_mqtt_message)
+    }
 }
 
-// Forced recalibration
-fn perform_frc(
+fn perform_measurement(
     scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
-    led: &mut PinDriver<'_, esp_idf_hal::gpio::Gpio2, esp_idf_hal::gpio::Output>,
-    target_ppm: u16,
-    mqtt_client: &mut EspMqttClient,
-) -> Result<DevicePayload> {
-    publish_device_payload(mqtt_client, DevicePayload::FrcStart { target_ppm });
-    info!(
-        "Starting calibration procedure with target {} ppm.",
-        target_ppm
-    );
-    blink_led(led, 3);
-
-    start_periodic_measurement(scd40)?;
-
-    info!("Sensor warming up for 3 minutes...");
-
-    FreeRtos::delay_ms(180_000);
-
-    publish_device_payload(
-        mqtt_client,
-        DevicePayload::FrcWarmupComplete {
-            detail: "Took 3 minutes".to_string(),
-        },
-    );
+    resting_mv: Option<u16>,
+    led: &mut dyn StatusLed,
+) -> DevicePayload {
+    measurement_payload(with_injected_fault(measure(scd40)), resting_mv, led)
+}
 
-    info!("Warmup complete. Stopping sensor.");
+/// The measurement as this wake's injected fault has it, see `fault_injection`
+fn with_injected_fault(measurement: DeviceResult<Reading>) -> DeviceResult<Reading> {
+    match wake_fault() {
+        Some(FaultKind::SensorTimeout) => {
+            info!("Injected fault: sensor timeout");
+            Err(DeviceError::SensorTimeout(
+                "Measurement timed out (injected)",
+            ))
+        }
+        Some(kind) => measurement.map(|(data, flags)| {
+            let (co2, temperature, humidity) =
+                kind.garble(data.co2, data.temperature, data.humidity);
+            let data = SensorData {
+                co2,
+                temperature,
+                humidity,
+            };
+            (data, flags)
+        }),
+        None => measurement,
+    }
+}
 
-    stop_periodic_measurement(scd40)?;
+/// What the sensor task hands back to the main task.
+struct SensorHalf {
+    scd40: sensor_bus::Sensor,
+    bus_recoveries: Vec<DevicePayload>,
+    /// Taken at boot, published by the first `noop`
+    measurement: Option<DeviceResult<Reading>>,
+    /// When `measurement` was taken, see `clock_millis`
+    taken_at: Option<u64>,
+}
 
-    info!("Performing FRC with target {} ppm...", target_ppm);
-    publish_device_payload(mqtt_client, DevicePayload::FrcCalibrating { target_ppm });
-    let frc_result = scd40.forced_recalibration(target_ppm);
-    FreeRtos::delay_ms(400);
+/// Runs on the app core while the main task brings up the network: opens
+/// and probes the sensor, sets the saved ambient pressure, then takes the
+/// wake's measurement.
+fn sensor_half(
+    i2c: I2C0,
+    sda: Gpio21,
+    scl: Gpio22,
+    ambient_pressure: Option<u32>,
+) -> DeviceResult<SensorHalf> {
+    info!("Initializing I2C on GPIO21 (SDA) and GPIO22 (SCL)...");
+    let scd40 = sensor_bus::open_sensor(i2c, sda, scl)
+        .context(DeviceError::I2c("opening the I2C driver"))?;
+    info!("Waiting 1.1 seconds for sensor to enter idle state...");
+    FreeRtos::delay_ms(1100);
 
-    let final_payload = match frc_result {
-        Ok(correction) => {
-            info!("FRC successful, correction: {} ppm", correction);
-            blink_led(led, 5);
-            DevicePayload::FrcSuccess { correction }
-        }
-        Err(e) => {
-            let error = format!("{:?}", e);
-            info!("FRC failed: {}", error);
-            blink_led(led, 10);
-            DevicePayload::FrcError { detail: error }
+    // A brownout can leave the sensor holding SDA low; probe and recover
+    let (mut scd40, sensor_ok, bus_recoveries) =
+        sensor_bus::probe(scd40).context(DeviceError::I2c("probing the sensor"))?;
+    if let (true, Some(pascals)) = (sensor_ok, ambient_pressure) {
+        // A failure only costs this wake's compensation
+        if let Err(e) = scd40.set_ambient_pressure((pascals / 100) as u16) {
+            info!("Failed to set ambient pressure: {:?}", e);
         }
+    }
+    let measurement = if sensor_ok {
+        with_injected_fault(measure(&mut scd40))
+    } else {
+        Err(DeviceError::I2cBusStuck)
     };
-    Ok(final_payload)
+    Ok(SensorHalf {
+        scd40,
+        bus_recoveries,
+        measurement: Some(measurement),
+        taken_at: clock_millis(),
+    })
 }
 
-fn perform_set_temp_offset(
-    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
-    offset: f32,
-) -> Result<DevicePayload> {
-    let final_device_payload = match scd40.set_temperature_offset(offset) {
-        Ok(_) => {
-            info!("Temperature offset set to {}. Persisting...", offset);
-            // save to eeprom
-            match scd40.persist_settings() {
-                Ok(_) => {
-                    FreeRtos::delay_ms(800); // Poczekaj na zapis (wg datasheet 800ms)
-                    info!("Temperature offset persisted to EEPROM");
-                    DevicePayload::SetOffsetSuccess { offset }
-                }
-                Err(e) => {
-                    info!("Failed to persist offset: {:?}", e);
-                    DevicePayload::SetOffsetError {
-                        detail: format!("failed_to_persist: {:?}", e),
-                    }
-                }
-            }
-        }
-        Err(e) => {
-            info!("Failed to set temperature offset: {:?}", e);
-            DevicePayload::SetOffsetError {
-                detail: format!("failed_to_set: {:?}", e),
-            }
-        }
-    };
-    Ok(final_device_payload)
+/// Connected MQTT client and the commands retained for this device, each
+/// with the topic it arrived on.
+struct Network {
+    client: EspMqttClient<'static>,
+    commands: Vec<(String, CommandEnvelope)>,
+    /// Commands published while the device is awake
+    incoming: Receiver<(String, CommandEnvelope)>,
+    /// Packet ids the broker acknowledged
+    acks: Receiver<u32>,
 }
 
-fn perform_get_temp_offset(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> Result<DevicePayload> {
-    let final_device_payload = match scd40.temperature_offset() {
-        Ok(offset) => {
-            info!("Current temperature offset: {}", offset);
-            DevicePayload::GetOffsetSuccess { offset }
-        }
-        Err(e) => {
-            info!("Failed to get temperature offset: {:?}", e);
-            DevicePayload::GetOffsetError {
-                detail: format!("failed_to_get: {:?}", e),
-            }
-        }
-    };
-    Ok(final_device_payload)
-}
-
-fn main() -> Result<()> {
-    esp_idf_sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
-
-    info!("ESP32-S NodeMCU + SCD40 starting...");
-
-    let peripherals = Peripherals::take().unwrap();
-    let mut led = PinDriver::output(peripherals.pins.gpio2)?;
-    led.set_high()?;
-    info!("LED initialized on GPIO2");
-    blink_led(&mut led, 1);
-
-    // Setup I2C
-    let i2c_config = i2c::config::Config::new().baudrate(Hertz(100_000));
-    info!("Initializing I2C on GPIO21 (SDA) and GPIO22 (SCL)...");
-    let i2c_driver = I2cDriver::new(
-        peripherals.i2c0,
-        peripherals.pins.gpio21,
-        peripherals.pins.gpio22,
-        &i2c_config,
-    )?;
-    let delay = Ets;
-
-    // Setup SCD40
-    info!("Initializing SCD40 sensor driver...");
-    let mut scd40 = Scd4x::new(i2c_driver, delay);
-    info!("Waiting 1.1 seconds for sensor to enter idle state...");
-    FreeRtos::delay_ms(1100);
-
-    // NVS initialization
-    info!("Initializing NVS...");
-    let nvs_default = EspDefaultNvsPartition::take()?;
-    let mut nvs = EspNvs::new(nvs_default.clone(), NVS_NAMESPACE, true)?;
-
-    // Read deep sleep time from NVS or use default
-    let mut deep_sleep_seconds = read_deep_sleep_from_nvs(&nvs);
-
-    // Network initialization
-    info!("Initializing WiFi...");
-    let sys_loop = EspSystemEventLoop::take()?;
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs_default))?,
-        sys_loop,
-    )?;
-
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: WIFI_SSID.try_into().unwrap(),
-        password: WIFI_PASSWORD.try_into().unwrap(),
-        auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
-        ..Default::default()
-    }))?;
-
-    match connect_wifi(&mut wifi) {
-        Ok(_) => {
-            info!("Connected to WiFi");
-            blink_led(&mut led, 2);
-        }
-        Err(err) => {
-            blink_led(&mut led, 5);
-            bail!("Failed to connect to WiFi: {:?}", err);
-        }
-    }
+fn bring_up_network(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    led: &mut dyn StatusLed,
+) -> DeviceResult<Network> {
+    led.show(BlinkPattern::Connecting);
+    connect_wifi(wifi)?;
+    info!("Connected to WiFi");
+    sync_time();
+    led.show(BlinkPattern::Connected);
 
     // MQTT initialization
     info!("Initializing MQTT client...");
     let mqtt_config = MqttClientConfiguration::default();
-    let (mut mqtt_client, mut mqtt_conn) = EspMqttClient::new(MQTT_BROKER_URL, &mqtt_config)?;
+    let (mut mqtt_client, mut mqtt_conn) = EspMqttClient::new(MQTT_BROKER_URL, &mqtt_config)
+        .context(DeviceError::Mqtt("creating the client"))?;
 
     // Channel for communication between the MQTT thread and the main thread
-    let (cmd_tx, cmd_rx): (Sender<DeviceCommand>, Receiver<DeviceCommand>) = mpsc::channel();
+    // Commands come with the topic they arrived on, so that topic gets cleared
+    let (cmd_tx, cmd_rx): (
+        Sender<(String, CommandEnvelope)>,
+        Receiver<(String, CommandEnvelope)>,
+    ) = mpsc::channel();
+    let own_command_topic = device_command_topic();
 
     // Channel for connected status
     let (connected_tx, connected_rx): (Sender<bool>, Receiver<bool>) = mpsc::channel();
 
+    // Channel for PUBACKs, they take measurements out of the outbox
+    let (ack_tx, ack_rx): (Sender<u32>, Receiver<u32>) = mpsc::channel();
+
     // MQTT thread
+    let own_topic = own_command_topic.clone();
     std::thread::spawn(move || {
         while let Ok(event) = mqtt_conn.next() {
             match event.payload() {
@@ -403,15 +959,23 @@ fn main() -> Result<()> {
                 EventPayload::Disconnected => {
                     info!("MQTT disconnected");
                 }
+                EventPayload::Published(packet_id) => {
+                    let _ = ack_tx.send(packet_id);
+                }
                 EventPayload::Received { data, topic, .. } => {
-                    if topic == Some(MQTT_COMMAND_TOPIC) && !data.is_empty() {
+                    let is_command_topic =
+                        topic == Some(COMMAND_BROADCAST_TOPIC) || topic == Some(own_topic.as_str());
+                    if is_command_topic && !data.is_empty() {
                         info!("Received command payload: {:?}", std::str::from_utf8(data));
-                        match serde_json::from_slice::<DeviceCommand>(data) {
-                            Ok(command) => {
-                                info!("Parsed command: {:?}", command);
-                                // Wyślij komendę do głównego wątku
-                                if let Err(e) = cmd_tx.send(command) {
-                                    info!("Failed to send command to main thread: {:?}", e);
+                        match CommandMessage::from_json_slice(data) {
+                            Ok(message) => {
+                                info!("Parsed command: {:?}", message);
+                                // Wyślij komendy do głównego wątku, batch jako pojedyncze
+                                let topic = topic.unwrap_or(COMMAND_BROADCAST_TOPIC).to_string();
+                                for command in message.into_envelopes() {
+                                    if let Err(e) = cmd_tx.send((topic.clone(), command)) {
+                                        info!("Failed to send command to main thread: {:?}", e);
+                                    }
                                 }
                             }
                             Err(e) => {
@@ -430,8 +994,14 @@ fn main() -> Result<()> {
         Ok(_) => {
             info!("MQTT connection established");
             // Now it's safe to subscribe
-            info!("Subscribing to command topic: {}", MQTT_COMMAND_TOPIC);
-            mqtt_client.subscribe(MQTT_COMMAND_TOPIC, QoS::AtLeastOnce)?;
+            info!("Subscribing to command topic: {}", COMMAND_BROADCAST_TOPIC);
+            mqtt_client
+                .subscribe(COMMAND_BROADCAST_TOPIC, QoS::AtLeastOnce)
+                .context(DeviceError::Mqtt("subscribing to commands"))?;
+            info!("Subscribing to command topic: {}", own_command_topic);
+            mqtt_client
+                .subscribe(&own_command_topic, QoS::AtLeastOnce)
+                .context(DeviceError::Mqtt("subscribing to commands"))?;
             info!("Subscribed successfully");
         }
         Err(_) => {
@@ -439,62 +1009,1149 @@ fn main() -> Result<()> {
             // Try to subscribe anyway, it might work
             info!(
                 "Attempting to subscribe to command topic: {}",
-                MQTT_COMMAND_TOPIC
+                COMMAND_BROADCAST_TOPIC
             );
-            let _ = mqtt_client.subscribe(MQTT_COMMAND_TOPIC, QoS::AtLeastOnce);
+            let _ = mqtt_client.subscribe(COMMAND_BROADCAST_TOPIC, QoS::AtLeastOnce);
+            let _ = mqtt_client.subscribe(&own_command_topic, QoS::AtLeastOnce);
         }
     }
 
     info!("Waiting max 1s for a command from MQTT...");
     // commands are retained so we don't need to wait long
-    let received_cmd = cmd_rx.recv_timeout(Duration::from_secs(1));
-
-    let command = match received_cmd {
-        Ok(cmd) => {
-            info!("Received command: {:?}", cmd);
-            cmd
+    let mut commands = Vec::new();
+    match cmd_rx.recv_timeout(Duration::from_secs(1)) {
+        Ok(first) => {
+            // drain whatever else arrived in this wake
+            commands.extend(std::iter::once(first).chain(cmd_rx.try_iter()));
         }
         Err(_) => {
             info!("No command received, proceeding with normal measurement.");
-            DeviceCommand::NoOp
         }
-    };
+    }
 
-    // main logic
+    Ok(Network {
+        client: mqtt_client,
+        commands,
+        incoming: cmd_rx,
+        acks: ack_rx,
+    })
+}
+
+/// Runs the received commands, or the measurement without any, and
+/// publishes the results.
+fn run_commands(
+    network: &mut Network,
+    sensor: &mut SensorHalf,
+    led: &mut dyn StatusLed,
+    nvs: &mut EspNvs<NvsDefault>,
+    outbox: &mut Outbox,
+    mqtt_policy: &mut MqttPolicy,
+    deep_sleep_seconds: &mut u64,
+    adaptive: &mut bool,
+    trial: &mut ConfigTrial,
+    supply: &mut Supply,
+) -> DeviceResult<()> {
+    let mqtt_client = &mut network.client;
+    let scd40 = &mut sensor.scd40;
+
+    // Measurements earlier wakes got no PUBACK for, or kept off the radio,
+    // go before this one's. A sag on the way leaves the rest for later.
+    for entry in outbox.redeliveries() {
+        if !supply.allows_radio("redelivering a measurement") {
+            break;
+        }
+        let PublishPolicy { qos, .. } = mqtt_policy.for_payload(&entry.message.payload);
+        // Not retained, the retained measurement is newer by now. At least
+        // once even if the policy changed, or nothing would take it out.
+        match send_message(mqtt_client, qos.max(1), false, &entry.message) {
+            Ok(packet_id) => outbox.published(entry.id, packet_id),
+            Err(e) => info!("Failed to redeliver measurement {:?}: {}", entry.message.seq, e),
+        }
+    }
+
+    if let Some(report) = supply.state.report(&supply.guard)
+        && supply.allows_radio("reporting skipped wakes")
+        && publish_device_payload(mqtt_client, mqtt_policy, report).is_ok()
+    {
+        supply.state.reported();
+    }
+
+    // reported once the broker is reachable
+    for report in sensor.bus_recoveries.drain(..) {
+        let _ = publish_device_payload(mqtt_client, mqtt_policy, report);
+    }
+    if let Some(rolled_back) = trial.rolled_back
+        && publish_device_payload(mqtt_client, mqtt_policy, rolled_back.rollback()).is_ok()
+    {
+        trial.take_rolled_back();
+        if let Err(e) = write_config_trial(nvs, trial) {
+            info!("Failed to save config trial to NVS: {:?}", e);
+        }
+    }
+
+    let mut received = Vec::new();
+    let mut received_on: Vec<String> = Vec::new();
+    // Until SNTP has synced the clock can't tell, and commands run
+    let now_unix = clock_millis().map(|millis| millis / 1000);
+    for (topic, cmd) in network.commands.drain(..) {
+        info!("Received command on {}: {:?}", topic, cmd);
+        if !received_on.contains(&topic) {
+            received_on.push(topic.clone());
+        }
+        // Retained for too long, e.g. while the device was off; still cleared below
+        if let Some(now) = now_unix
+            && let Some(expired_at) = cmd.expires_at_unix().filter(|_| cmd.is_expired(now))
+        {
+            info!("Skipping expired command {:?}", cmd);
+            *ANSWERING.lock().unwrap() = cmd.id;
+            let _ = publish_device_payload(
+                mqtt_client,
+                mqtt_policy,
+                DevicePayload::error(detail!(
+                    "skipped {}: expired {} s ago",
+                    cmd.command.name(),
+                    now - expired_at
+                )),
+            );
+            continue;
+        }
+        received.push((topic, cmd));
+    }
+    *ANSWERING.lock().unwrap() = None;
+
+    let Schedule { run, deferred } = schedule_tagged(received);
+    let commands = if run.is_empty() {
+        vec![CommandEnvelope::from(DeviceCommand::NoOp)]
+    } else {
+        run.into_iter().map(|(_, cmd)| cmd).collect()
+    };
 
-    // always clear retained command before proceeding
-    if !matches!(command, DeviceCommand::NoOp) {
-        match clear_retained_command(&mut mqtt_client) {
+    // A deferred batch replaces the retained command, so the rest is
+    // picked up next wake; every other topic is cleared before proceeding
+    let retained = command_topics::retained_after(&received_on, deferred);
+    for (topic, batch) in &retained.defer {
+        match defer_commands(mqtt_client, topic, batch) {
+            Ok(_) => info!("Deferred command(s) on {} to the next wake", topic),
+            Err(e) => info!("Failed to defer commands: {:?}", e),
+        }
+    }
+    for topic in &retained.clear {
+        match clear_retained_command(mqtt_client, topic) {
             Ok(_) => info!("Retained command cleared"),
             Err(e) => info!("Failed to clear retained command: {:?}", e),
         }
     }
+    for cmd in &retained.turned_down {
+        info!("No room to defer {:?}", cmd);
+        *ANSWERING.lock().unwrap() = cmd.id;
+        let _ = publish_device_payload(
+            mqtt_client,
+            mqtt_policy,
+            DevicePayload::command_rejected(&cmd.command, "too many commands to defer"),
+        );
+    }
+    *ANSWERING.lock().unwrap() = None;
+    if !retained.defer.is_empty() {
+        let _ = publish_device_payload(
+            mqtt_client,
+            mqtt_policy,
+            DevicePayload::CommandsDeferred {
+                running: commands[0].command.name().to_string(),
+                deferred: retained
+                    .defer
+                    .iter()
+                    .flat_map(|(_, batch)| batch.command.commands.iter().cloned())
+                    .collect(),
+            },
+        );
+    }
 
-    let final_device_payload = match command {
-        DeviceCommand::NoOp => perform_measurement(&mut scd40, &mut led)?,
-        DeviceCommand::StartFrc { target_ppm } => {
-            perform_frc(&mut scd40, &mut led, target_ppm, &mut mqtt_client)?
+    for CommandEnvelope { id, command, .. } in commands {
+        *ANSWERING.lock().unwrap() = id;
+        // Acked before it runs, so an FRC isn't minutes of silence
+        let ack = DevicePayload::command_ack(&command);
+        let accepted = matches!(ack, DevicePayload::CommandAck { accepted: true, .. });
+        if supply.radio_off {
+            info!("Supply too low, not publishing {:?}", ack);
+        } else {
+            let _ = publish_device_payload(mqtt_client, mqtt_policy, ack);
         }
-        DeviceCommand::SetTempOffset { offset } => perform_set_temp_offset(&mut scd40, offset)?,
-        DeviceCommand::GetTempOffset => perform_get_temp_offset(&mut scd40)?,
-        DeviceCommand::SetDeepSleepTime { seconds } => {
-            deep_sleep_seconds = seconds;
-            match write_deep_sleep_to_nvs(&mut nvs, seconds) {
-                Ok(_) => DevicePayload::SetDeepSleepTimeSuccess { seconds },
-                Err(e) => {
-                    info!("Failed to save deep sleep time to NVS: {:?}", e);
-                    DevicePayload::SetDeepSleepTimeSuccess { seconds } // Still apply it for this cycle
+        if !accepted {
+            info!("Rejected {}", command.name());
+            continue;
+        }
+        let mut taken_at = None;
+        let device_payload = match command {
+            DeviceCommand::NoOp => match sensor.measurement.take() {
+                Some(data) => {
+                    taken_at = sensor.taken_at;
+                    measurement_payload(data, supply.resting_mv, led)
+                }
+                None => {
+                    let payload = perform_measurement(scd40, supply.resting_mv, led);
+                    taken_at = clock_millis();
+                    payload
+                }
+            },
+            DeviceCommand::StartFrc { target_ppm } => {
+                perform_frc(scd40, led, target_ppm, mqtt_client, mqtt_policy)?
+            }
+            DeviceCommand::SetTempOffset { offset, persist } => {
+                perform_set_temp_offset(scd40, nvs, offset, persist)?
+            }
+            DeviceCommand::GetTempOffset => perform_get_temp_offset(scd40)?,
+            DeviceCommand::SetDeepSleepTime { seconds } => stage_change(
+                mqtt_client,
+                nvs,
+                trial,
+                id,
+                ConfigChange::DeepSleep { seconds },
+                deep_sleep_seconds,
+                mqtt_policy,
+            ),
+            DeviceCommand::GetDeepSleepTime => DevicePayload::GetDeepSleepTimeSuccess {
+                seconds: *deep_sleep_seconds,
+            },
+            DeviceCommand::SetMqttPolicy { class, qos, retain } => {
+                if qos > 2 {
+                    DevicePayload::SetMqttPolicyError {
+                        code: ErrorCode::Other,
+                        detail: detail!("invalid QoS {}", qos),
+                    }
+                } else {
+                    // Applied right away, so the answer itself uses the new policy
+                    stage_change(
+                        mqtt_client,
+                        nvs,
+                        trial,
+                        id,
+                        ConfigChange::MqttPolicy { class, qos, retain },
+                        deep_sleep_seconds,
+                        mqtt_policy,
+                    )
+                }
+            }
+            DeviceCommand::GetConfig => perform_get_config(scd40, nvs, *deep_sleep_seconds, mqtt_policy),
+            DeviceCommand::Ota { url } => {
+                info!("OTA requested from {}, not supported by this build", url);
+                DevicePayload::OtaError {
+                    code: ErrorCode::Other,
+                    detail: "OTA is not supported by this firmware".into(),
+                }
+            }
+            DeviceCommand::SetLogLevel { level } => {
+                // Applied right away, so the rest of this wake logs at it too
+                apply_log_level(level);
+                match write_log_level_to_nvs(nvs, level) {
+                    Ok(_) => DevicePayload::SetLogLevelSuccess { level },
+                    Err(e) => DevicePayload::SetLogLevelError {
+                        code: ErrorCode::NvsError,
+                        detail: detail!("failed_to_persist: {:?}", e),
+                    },
+                }
+            }
+            DeviceCommand::GetLogLevel => DevicePayload::GetLogLevelSuccess { level: log_level() },
+            DeviceCommand::SetAdaptiveMode { enabled } => {
+                // Applied right away, this wake's sleep already follows it
+                *adaptive = enabled;
+                match write_adaptive_to_nvs(nvs, enabled) {
+                    Ok(_) => DevicePayload::SetAdaptiveModeSuccess { enabled },
+                    Err(e) => DevicePayload::SetAdaptiveModeError {
+                        code: ErrorCode::NvsError,
+                        detail: detail!("failed_to_persist: {:?}", e),
+                    },
+                }
+            }
+            DeviceCommand::SetAsc { enabled } => perform_set_asc(scd40, nvs, enabled)?,
+            DeviceCommand::GetAsc => perform_get_asc(scd40)?,
+            DeviceCommand::SetAltitude { meters } => perform_set_altitude(scd40, nvs, meters)?,
+            DeviceCommand::GetAltitude => perform_get_altitude(scd40)?,
+            DeviceCommand::SetAmbientPressure { pascals } => {
+                perform_set_ambient_pressure(scd40, nvs, pascals)?
+            }
+            DeviceCommand::ConfirmConfig { pending_id } => confirm_change(nvs, trial, pending_id),
+            DeviceCommand::SelfTest => perform_self_test(scd40)?,
+            DeviceCommand::FactoryReset { confirm } => perform_factory_reset(scd40, &confirm)?,
+            DeviceCommand::GetSerialNumber => perform_get_serial_number(scd40)?,
+            DeviceCommand::GetFirmwareInfo => firmware_info(),
+            DeviceCommand::Reboot => {
+                // Answered now, the restart waits until the wake has
+                // published everything and powered down like before a sleep
+                info!("Reboot requested");
+                REBOOT_REQUESTED.store(true, Ordering::Relaxed);
+                DevicePayload::Rebooting {
+                    detail: "restarting instead of sleeping".into(),
                 }
             }
+            DeviceCommand::InjectFault { kind } => inject_fault(kind),
+        };
+
+        let mut message = device_message(device_payload);
+        if matches!(message.payload, DevicePayload::MeasurementSuccess { .. }) {
+            message = message.stamped(taken_at, MEASUREMENT_SEQ.fetch_add(1, Ordering::Relaxed));
+            if wake_fault() == Some(FaultKind::DelayedPublish) {
+                info!(
+                    "Injected fault: holding the measurement back {} s",
+                    DELAYED_PUBLISH_SECONDS
+                );
+                FreeRtos::delay_ms(DELAYED_PUBLISH_SECONDS * 1000);
+            }
+            if supply.allows_radio("publishing the measurement") {
+                let _ = publish_measurement(mqtt_client, mqtt_policy, nvs, outbox, &message);
+            } else {
+                buffer_measurement(nvs, outbox, message);
+                supply.state.skipped_wake();
+            }
+        } else if supply.radio_off {
+            info!("Supply too low, not publishing {:?}", message.payload);
+        } else {
+            let _ = publish_message(mqtt_client, mqtt_policy, &message);
         }
-        DeviceCommand::GetDeepSleepTime => DevicePayload::GetDeepSleepTimeSuccess {
-            seconds: deep_sleep_seconds,
+    }
+
+    // A commander that is waiting confirms within moments; anything else
+    // that arrives meanwhile stays retained for the next wake
+    if let Some(pending) = trial.pending.filter(|pending| pending.wakes == 0) {
+        info!("Waiting {:?} for confirm_config {}...", CONFIRM_WAIT, pending.id);
+        let deadline = Instant::now() + CONFIRM_WAIT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            let Ok((topic, envelope)) = network.incoming.recv_timeout(left) else {
+                break;
+            };
+            let DeviceCommand::ConfirmConfig { pending_id } = envelope.command else {
+                continue;
+            };
+            *ANSWERING.lock().unwrap() = envelope.id;
+            let answer = confirm_change(nvs, trial, pending_id);
+            let _ = publish_device_payload(mqtt_client, mqtt_policy, answer);
+            if let Err(e) = clear_retained_command(mqtt_client, &topic) {
+                info!("Failed to clear retained command: {:?}", e);
+            }
+            break;
+        }
+    }
+    // the wake profile and anything else after this answers nothing
+    *ANSWERING.lock().unwrap() = None;
+    Ok(())
+}
+
+/// Puts `change` on trial, see `config_trial`: used from now on, saved once
+/// a `confirm_config` for it arrives.
+fn stage_change(
+    mqtt_client: &mut EspMqttClient,
+    nvs: &mut EspNvs<NvsDefault>,
+    trial: &mut ConfigTrial,
+    id: Option<u32>,
+    change: ConfigChange,
+    deep_sleep_seconds: &mut u64,
+    mqtt_policy: &mut MqttPolicy,
+) -> DevicePayload {
+    // The announcement carries the id, so any will do for a command without one
+    let id = id.unwrap_or(clock_seconds() as u32);
+    if let Some(replaced) = trial.stage(id, change) {
+        info!("Trial of {} replaced by {}", replaced.id, id);
+        if !replaced.change.same_setting(&change) {
+            restore_saved(nvs, replaced.change, deep_sleep_seconds, mqtt_policy);
+        }
+        let _ = publish_device_payload(mqtt_client, mqtt_policy, replaced.rollback());
+    }
+    apply_change(change, deep_sleep_seconds, mqtt_policy);
+    if let Err(e) = write_config_trial(nvs, trial) {
+        // Still applied for this wake; the next one runs on the saved settings
+        info!("Failed to save config trial to NVS: {:?}", e);
+    }
+    info!("Trying {:?} until confirmed", change);
+    trial
+        .pending
+        .expect("staged above")
+        .announcement(confirm_wakes())
+}
+
+/// Saves the change on trial under `pending_id`, answering with the usual
+/// success payload of the command that staged it
+fn confirm_change(nvs: &mut EspNvs<NvsDefault>, trial: &mut ConfigTrial, pending_id: u32) -> DevicePayload {
+    let change = match trial.confirm(pending_id) {
+        Ok(change) => change,
+        Err(e) => {
+            info!("Nothing to confirm under {}: {:?}", pending_id, e);
+            return DevicePayload::ConfirmConfigError {
+                code: ErrorCode::Other,
+                detail: e.detail(pending_id),
+            };
+        }
+    };
+    if let Err(e) = save_change(nvs, change) {
+        // Still in use for this wake, rolled back from the next
+        info!("Failed to save confirmed {:?}: {:?}", change, e);
+        return DevicePayload::ConfirmConfigError {
+            code: ErrorCode::NvsError,
+            detail: detail!("failed_to_persist: {:?}", e),
+        };
+    }
+    if let Err(e) = write_config_trial(nvs, trial) {
+        // Saved already; the stale trial only reapplies the same value
+        info!("Failed to save config trial to NVS: {:?}", e);
+    }
+    info!("Confirmed and saved {:?}", change);
+    change.saved()
+}
+
+// Forced recalibration
+fn perform_frc(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    led: &mut dyn StatusLed,
+    target_ppm: u16,
+    mqtt_client: &mut EspMqttClient,
+    mqtt_policy: &MqttPolicy,
+) -> DeviceResult<DevicePayload> {
+    publish_device_payload(mqtt_client, mqtt_policy, DevicePayload::FrcStart { target_ppm });
+    info!(
+        "Starting calibration procedure with target {} ppm.",
+        target_ppm
+    );
+    led.show(BlinkPattern::FrcStarted);
+
+    start_periodic_measurement(scd40)?;
+
+    info!("Sensor warming up for 3 minutes...");
+
+    FreeRtos::delay_ms(180_000);
+
+    publish_device_payload(
+        mqtt_client,
+        mqtt_policy,
+        DevicePayload::FrcWarmupComplete {
+            detail: "Took 3 minutes".into(),
         },
+    );
+
+    info!("Warmup complete. Stopping sensor.");
+
+    stop_periodic_measurement(scd40)?;
+
+    info!("Performing FRC with target {} ppm...", target_ppm);
+    publish_device_payload(
+        mqtt_client,
+        mqtt_policy,
+        DevicePayload::FrcCalibrating { target_ppm },
+    );
+    let frc_result = scd40.forced_recalibration(target_ppm);
+    FreeRtos::delay_ms(400);
+
+    let final_payload = match frc_result {
+        Ok(correction) => {
+            info!("FRC successful, correction: {} ppm", correction);
+            led.show(BlinkPattern::Success);
+            DevicePayload::FrcSuccess { correction }
+        }
+        Err(e) => {
+            let error = detail!("{:?}", e);
+            info!("FRC failed: {}", error);
+            led.show(BlinkPattern::Error(ErrorCode::I2cError));
+            DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: error,
+            }
+        }
     };
+    Ok(final_payload)
+}
 
-    publish_device_payload(&mut mqtt_client, final_device_payload);
+fn perform_set_temp_offset(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    nvs: &mut EspNvs<NvsDefault>,
+    offset: f32,
+    persist: bool,
+) -> DeviceResult<DevicePayload> {
+    let now = clock_seconds();
+    let mut persist_log = read_persist_log(nvs);
+    let final_device_payload = match scd40.set_temperature_offset(offset) {
+        Ok(_) if !persist => {
+            info!(
+                "Temperature offset set to {} until the sensor loses power",
+                offset
+            );
+            DevicePayload::SetOffsetSuccess {
+                offset,
+                persisted: false,
+            }
+        }
+        Ok(_) => match persist_log.check(now, persists_per_day()) {
+            Err(limited) => {
+                info!(
+                    "Temperature offset set to {} but not persisted, EEPROM already written {} times today",
+                    offset, limited.limit
+                );
+                DevicePayload::SetOffsetRateLimited {
+                    offset,
+                    limit: limited.limit,
+                    retry_after_seconds: limited.retry_after_seconds,
+                }
+            }
+            Ok(()) => {
+                info!("Temperature offset set to {}. Persisting...", offset);
+                // save to eeprom
+                let result = scd40.persist_settings();
+                // a failed command may still have reached the EEPROM, so it counts
+                persist_log.record(now);
+                if let Err(e) = write_persist_log(nvs, &persist_log) {
+                    info!("Failed to save persist count to NVS: {:?}", e);
+                }
+                match result {
+                    Ok(_) => {
+                        FreeRtos::delay_ms(800); // Poczekaj na zapis (wg datasheet 800ms)
+                        info!("Temperature offset persisted to EEPROM");
+                        DevicePayload::SetOffsetSuccess {
+                            offset,
+                            persisted: true,
+                        }
+                    }
+                    Err(e) => {
+                        info!("Failed to persist offset: {:?}", e);
+                        DevicePayload::SetOffsetError {
+                            code: ErrorCode::NvsError,
+                            detail: detail!("failed_to_persist: {:?}", e),
+                        }
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            info!("Failed to set temperature offset: {:?}", e);
+            DevicePayload::SetOffsetError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_set: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
 
-    FreeRtos::delay_ms(2000); // Time to send
+fn perform_get_temp_offset(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<DevicePayload> {
+    let final_device_payload = match scd40.temperature_offset() {
+        Ok(offset) => {
+            info!("Current temperature offset: {}", offset);
+            DevicePayload::GetOffsetSuccess { offset }
+        }
+        Err(e) => {
+            info!("Failed to get temperature offset: {:?}", e);
+            DevicePayload::GetOffsetError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_get: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// Saving to the EEPROM shares the daily persist limit with the temperature
+/// offset; over it the setting only lasts until the sensor loses power.
+fn perform_set_asc(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    nvs: &mut EspNvs<NvsDefault>,
+    enabled: bool,
+) -> DeviceResult<DevicePayload> {
+    let now = clock_seconds();
+    let mut persist_log = read_persist_log(nvs);
+    let final_device_payload = match scd40.set_automatic_self_calibration(enabled) {
+        Ok(_) => match persist_log.check(now, persists_per_day()) {
+            Err(limited) => {
+                info!(
+                    "Self-calibration set to {} but not persisted, EEPROM already written {} times today",
+                    enabled, limited.limit
+                );
+                DevicePayload::PersistRateLimited {
+                    command: "set_asc".to_string(),
+                    limit: limited.limit,
+                    retry_after_seconds: limited.retry_after_seconds,
+                }
+            }
+            Ok(()) => {
+                let result = scd40.persist_settings();
+                // a failed command may still have reached the EEPROM, so it counts
+                persist_log.record(now);
+                if let Err(e) = write_persist_log(nvs, &persist_log) {
+                    info!("Failed to save persist count to NVS: {:?}", e);
+                }
+                match result {
+                    Ok(_) => {
+                        FreeRtos::delay_ms(800);
+                        info!("Self-calibration set to {} and persisted", enabled);
+                        DevicePayload::AscSetSuccess { enabled }
+                    }
+                    Err(e) => {
+                        info!("Failed to persist self-calibration: {:?}", e);
+                        DevicePayload::AscError {
+                            code: ErrorCode::NvsError,
+                            detail: detail!("failed_to_persist: {:?}", e),
+                        }
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            info!("Failed to set self-calibration: {:?}", e);
+            DevicePayload::AscError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_set: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+fn perform_get_asc(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<DevicePayload> {
+    let final_device_payload = match scd40.automatic_self_calibration() {
+        Ok(enabled) => {
+            info!("Self-calibration: {}", enabled);
+            DevicePayload::AscGetSuccess { enabled }
+        }
+        Err(e) => {
+            info!("Failed to get self-calibration state: {:?}", e);
+            DevicePayload::AscError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_get: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// Like the self-calibration switch, saving to the EEPROM counts against the
+/// daily persist limit.
+fn perform_set_altitude(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    nvs: &mut EspNvs<NvsDefault>,
+    meters: u16,
+) -> DeviceResult<DevicePayload> {
+    let now = clock_seconds();
+    let mut persist_log = read_persist_log(nvs);
+    let final_device_payload = match scd40.set_altitude(meters) {
+        Ok(_) => match persist_log.check(now, persists_per_day()) {
+            Err(limited) => {
+                info!(
+                    "Altitude set to {} m but not persisted, EEPROM already written {} times today",
+                    meters, limited.limit
+                );
+                DevicePayload::PersistRateLimited {
+                    command: "set_altitude".to_string(),
+                    limit: limited.limit,
+                    retry_after_seconds: limited.retry_after_seconds,
+                }
+            }
+            Ok(()) => {
+                let result = scd40.persist_settings();
+                // a failed command may still have reached the EEPROM, so it counts
+                persist_log.record(now);
+                if let Err(e) = write_persist_log(nvs, &persist_log) {
+                    info!("Failed to save persist count to NVS: {:?}", e);
+                }
+                match result {
+                    Ok(_) => {
+                        FreeRtos::delay_ms(800);
+                        info!("Altitude set to {} m and persisted", meters);
+                        DevicePayload::AltitudeSetSuccess { meters }
+                    }
+                    Err(e) => {
+                        info!("Failed to persist altitude: {:?}", e);
+                        DevicePayload::AltitudeError {
+                            code: ErrorCode::NvsError,
+                            detail: detail!("failed_to_persist: {:?}", e),
+                        }
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            info!("Failed to set altitude: {:?}", e);
+            DevicePayload::AltitudeError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_set: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+fn perform_get_altitude(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<DevicePayload> {
+    let final_device_payload = match scd40.altitude() {
+        Ok(meters) => {
+            info!("Altitude: {} m", meters);
+            DevicePayload::AltitudeGetSuccess { meters }
+        }
+        Err(e) => {
+            info!("Failed to get altitude: {:?}", e);
+            DevicePayload::AltitudeError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_get: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// Blocks for the ~10 s the sensor takes; it is idle between commands, as
+/// the self test requires.
+fn perform_self_test(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<DevicePayload> {
+    info!("Running sensor self test...");
+    let final_device_payload = match scd40.self_test_is_ok() {
+        Ok(true) => {
+            info!("Self test passed");
+            DevicePayload::SelfTestResult {
+                passed: true,
+                detail: Detail::default(),
+            }
+        }
+        Ok(false) => {
+            info!("Self test failed, the sensor reported a malfunction");
+            DevicePayload::SelfTestResult {
+                passed: false,
+                detail: "malfunction: the sensor reported a fault".into(),
+            }
+        }
+        Err(e) => {
+            info!("Failed to run self test: {:?}", e);
+            DevicePayload::SelfTestResult {
+                passed: false,
+                detail: detail!("failed_to_run: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// The crate version, with the build date and ESP-IDF version from the app
+/// description ESP-IDF puts in the image
+fn firmware_info() -> DevicePayload {
+    // SAFETY: the description is a static in the running image
+    let description = unsafe { &*esp_idf_sys::esp_app_get_description() };
+    let text = |field: &[std::ffi::c_char]| {
+        // SAFETY: ESP-IDF fills these fields with NUL-terminated strings
+        unsafe { std::ffi::CStr::from_ptr(field.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    DevicePayload::FirmwareInfo {
+        version: FIRMWARE_VERSION.to_string(),
+        build_time: format!("{} {}", text(&description.date), text(&description.time)),
+        idf_version: text(&description.idf_ver),
+    }
+}
+
+/// Arms `kind` for the next wake, see `fault_injection`
+#[cfg(feature = "fault-injection")]
+fn inject_fault(kind: FaultKind) -> DevicePayload {
+    info!("Injecting a {} fault at the next wake", kind);
+    ARMED_FAULT.arm(kind);
+    DevicePayload::FaultArmed { kind }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn inject_fault(kind: FaultKind) -> DevicePayload {
+    info!("Fault injection ({}) requested, not built in", kind);
+    DevicePayload::error("fault injection is not built into this firmware")
+}
+
+/// There is no error payload of its own, so a failed read answers `error`.
+fn perform_get_serial_number(scd40: &mut Scd4x<I2cDriver<'_>, Ets>) -> DeviceResult<DevicePayload> {
+    let final_device_payload = match scd40.serial_number() {
+        Ok(serial) => {
+            info!("Sensor serial number: 0x{:012X}", serial);
+            DevicePayload::SerialNumber { serial }
+        }
+        Err(e) => {
+            info!("Failed to read serial number: {:?}", e);
+            DevicePayload::coded_error(
+                ErrorCode::I2cError,
+                detail!("failed_to_get_serial_number: {:?}", e),
+            )
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// Only for a command naming this device, so a `factory_reset` retained on
+/// the shared topic for another device is refused.
+fn perform_factory_reset(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    confirm: &str,
+) -> DeviceResult<DevicePayload> {
+    if !(FactoryReset { confirm }).is_confirmed(DEVICE_NAME) {
+        info!("Factory reset for '{}' refused, this is {}", confirm, DEVICE_NAME);
+        return Ok(DevicePayload::FactoryResetError {
+            code: ErrorCode::Other,
+            detail: detail!("not_confirmed: {} is not this device", confirm),
+        });
+    }
+    info!("Resetting sensor to factory settings...");
+    let final_device_payload = match scd40.factory_reset() {
+        Ok(_) => {
+            // The sensor needs 1.2 s before it takes commands again
+            FreeRtos::delay_ms(1200);
+            info!("Sensor reset to factory settings");
+            DevicePayload::FactoryResetSuccess
+        }
+        Err(e) => {
+            info!("Failed to reset sensor: {:?}", e);
+            DevicePayload::FactoryResetError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_reset: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// The sensor keeps the ambient pressure only while powered, so it lives in
+/// NVS instead of the EEPROM and `sensor_half` sets it again every wake.
+fn perform_set_ambient_pressure(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    nvs: &mut EspNvs<NvsDefault>,
+    pascals: u32,
+) -> DeviceResult<DevicePayload> {
+    let final_device_payload = match scd40.set_ambient_pressure((pascals / 100) as u16) {
+        Ok(_) => match write_ambient_pressure_to_nvs(nvs, pascals) {
+            Ok(_) => {
+                info!("Ambient pressure set to {} Pa", pascals);
+                DevicePayload::AmbientPressureSetSuccess { pascals }
+            }
+            Err(e) => DevicePayload::AmbientPressureError {
+                code: ErrorCode::NvsError,
+                detail: detail!("failed_to_persist: {:?}", e),
+            },
+        },
+        Err(e) => {
+            info!("Failed to set ambient pressure: {:?}", e);
+            DevicePayload::AmbientPressureError {
+                code: ErrorCode::I2cError,
+                detail: detail!("failed_to_set: {:?}", e),
+            }
+        }
+    };
+    Ok(final_device_payload)
+}
+
+/// Sensor values that can't be read are left out rather than failing the
+/// whole answer.
+fn perform_get_config(
+    scd40: &mut Scd4x<I2cDriver<'_>, Ets>,
+    nvs: &EspNvs<NvsDefault>,
+    deep_sleep_seconds: u64,
+    mqtt_policy: &MqttPolicy,
+) -> DevicePayload {
+    let temperature_offset = scd40
+        .temperature_offset()
+        .inspect_err(|e| info!("Failed to get temperature offset: {:?}", e))
+        .ok();
+    let altitude_m = scd40
+        .altitude()
+        .inspect_err(|e| info!("Failed to get altitude: {:?}", e))
+        .ok();
+    let asc_enabled = scd40
+        .automatic_self_calibration()
+        .inspect_err(|e| info!("Failed to get self-calibration state: {:?}", e))
+        .ok();
+
+    #[cfg(feature = "neopixel")]
+    let (quiet_hours, utc_offset_hours) = (
+        QUIET_HOURS.map(str::to_string),
+        UTC_OFFSET_HOURS.and_then(|o| o.parse().ok()).unwrap_or(0),
+    );
+    #[cfg(not(feature = "neopixel"))]
+    let (quiet_hours, utc_offset_hours) = (None, 0);
+
+    DevicePayload::Config(DeviceConfig {
+        firmware_version: FIRMWARE_VERSION.to_string(),
+        sleep_seconds: deep_sleep_seconds,
+        quiet_hours,
+        utc_offset_hours,
+        sensor_mode: SensorMode::Periodic,
+        temperature_offset,
+        altitude_m,
+        // the driver can only set the ambient pressure, so report the saved one
+        ambient_pressure_hpa: read_ambient_pressure_from_nvs(nvs).map(|pascals| (pascals / 100) as u16),
+        asc_enabled,
+        alarm_threshold_ppm: None,
+        mqtt_policy: mqtt_policy.to_string(),
+        wifi_ssid: WIFI_SSID.to_string(),
+        safe_mode: false,
+    })
+}
+
+fn main() -> Result<()> {
+    esp_idf_sys::link_patches();
+    init_logging();
+    let boot = Instant::now();
+    // Taken before anything can fail, so a fault happens once even if it
+    // is a panic
+    #[cfg(feature = "fault-injection")]
+    if let Some(kind) = *WAKE_FAULT.get_or_init(|| ARMED_FAULT.take()) {
+        info!("Injecting a {} fault this wake", kind);
+    }
+
+    info!("ESP32-S NodeMCU + SCD40 starting...");
+
+    let peripherals = Peripherals::take().unwrap();
+    #[cfg(not(feature = "neopixel"))]
+    let mut led = {
+        let mut pin = PinDriver::output(peripherals.pins.gpio2)?;
+        pin.set_high()?;
+        info!("LED initialized on GPIO2");
+        status_led::PlainLed::new(pin)
+    };
+    #[cfg(feature = "neopixel")]
+    let mut led = {
+        let gpio: i32 = NEOPIXEL_GPIO.and_then(|g| g.parse().ok()).unwrap_or(2);
+        let brightness: u8 = NEOPIXEL_BRIGHTNESS
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(64);
+        // SAFETY: the pin number comes from the build configuration and no
+        // other driver is created for it
+        let pin = unsafe { esp_idf_hal::gpio::AnyOutputPin::new(gpio) };
+        let mut pixel = status_led::NeoPixel::new(peripherals.rmt.channel0, pin, brightness)?;
+        pixel.set_quiet(in_quiet_hours());
+        info!("NeoPixel initialized on GPIO{}", gpio);
+        pixel
+    };
+    led.show(BlinkPattern::Boot);
+
+    // NVS initialization
+    info!("Initializing NVS...");
+    let nvs_default = EspDefaultNvsPartition::take()?;
+    let mut nvs = EspNvs::new(nvs_default.clone(), NVS_NAMESPACE, true)?;
+    apply_log_level(read_log_level_from_nvs(&nvs));
+    let mut outbox = Outbox::load(&NvsBlobs(&mut nvs));
+    if !outbox.is_empty() {
+        info!("{} measurement(s) in the outbox", outbox.len());
+    }
+    #[cfg(feature = "supply-guard")]
+    let rail = Some(supply::Rail::new(peripherals.adc1, peripherals.pins.gpio34)?);
+    #[cfg(not(feature = "supply-guard"))]
+    let rail = None;
+    let mut supply = Supply::load(rail);
+
+    // The sensor half runs pinned to the app core while this task, on the
+    // protocol core next to the WiFi driver, brings up the network
+    let ambient_pressure = read_ambient_pressure_from_nvs(&nvs);
+    let (i2c, sda, scl) = (
+        peripherals.i2c0,
+        peripherals.pins.gpio21,
+        peripherals.pins.gpio22,
+    );
+    let (sensor_job, sensor_pending) = wake_split::split(move || sensor_half(i2c, sda, scl, ambient_pressure));
+    let split_started = Instant::now();
+    ThreadSpawnConfiguration {
+        name: Some(b"sensor\0"),
+        pin_to_core: Some(Core::Core1),
+        ..Default::default()
+    }
+    .set()?;
+    std::thread::Builder::new()
+        .stack_size(SENSOR_TASK_STACK_SIZE)
+        .spawn(sensor_job)?;
+    ThreadSpawnConfiguration::default().set()?;
+
+    // Read deep sleep time from NVS or use default
+    let mut deep_sleep_seconds = read_deep_sleep_from_nvs(&nvs);
+    let mut mqtt_policy = read_mqtt_policy_from_nvs(&nvs);
+    let mut adaptive = read_adaptive_from_nvs(&nvs);
+    let mut trial = read_config_trial(&nvs);
+    if trial.pending.is_some() {
+        match trial.begin_wake(confirm_wakes()) {
+            Some(change) => {
+                info!("Still trying {:?}, not confirmed yet", change);
+                apply_change(change, &mut deep_sleep_seconds, &mut mqtt_policy);
+            }
+            None => info!("Rolling back an unconfirmed setting"),
+        }
+        if let Err(e) = write_config_trial(&mut nvs, &trial) {
+            info!("Failed to save config trial to NVS: {:?}", e);
+        }
+    }
+
+    // Network initialization
+    info!("Initializing WiFi...");
+    let sys_loop = EspSystemEventLoop::take()?;
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs_default))?,
+        sys_loop,
+    )?;
+
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: WIFI_SSID.try_into().unwrap(),
+        password: WIFI_PASSWORD.try_into().unwrap(),
+        auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
+        ..Default::default()
+    }))?;
+
+    // Sampled with the radio still off; a sagging supply skips the whole
+    // network half, see `supply_guard`
+    let network = if supply.allows_radio("starting WiFi") {
+        bring_up_network(&mut wifi, &mut led)
+    } else {
+        Err(DeviceError::Wifi("supply too low to start WiFi"))
+    };
+    let network_elapsed = split_started.elapsed();
+    #[cfg(feature = "neopixel")]
+    if network.is_ok() {
+        led.set_quiet(in_quiet_hours());
+    }
+    if let Err(e) = &network {
+        info!("Network unavailable: {}", e);
+        led.show(BlinkPattern::Error(e.code()));
+    }
+
+    info!("Waiting for the sensor task...");
+    let sensor = sensor_pending.join(SENSOR_TASK_TIMEOUT.saturating_sub(split_started.elapsed()));
+    let timings = WakeTimings {
+        sensor: match &sensor {
+            Joined::Finished { elapsed, .. } => *elapsed,
+            _ => split_started.elapsed(),
+        },
+        network: network_elapsed,
+        overlapped: split_started.elapsed(),
+    };
+    info!(
+        "Sensor took {:?}, network {:?}, overlap saved {:?}",
+        timings.sensor,
+        timings.network,
+        timings.saved()
+    );
+
+    let plan = wake_split::plan(&sensor, network.is_ok());
+    let mut network = network.ok();
+    let (mut sensor, sensor_failure) = match sensor {
+        Joined::Finished {
+            value: Ok(half), ..
+        } => (Some(half), None),
+        Joined::Finished { value: Err(e), .. } => (None, Some(e)),
+        Joined::TimedOut => (
+            None,
+            Some(DeviceError::SensorTimeout("Sensor task did not finish")),
+        ),
+        Joined::Lost => (
+            None,
+            Some(DeviceError::Sensor("Sensor task ended without a result")),
+        ),
+    };
+    if let Some(e) = sensor_failure {
+        info!("Sensor unavailable this wake: {}", e);
+        led.show(BlinkPattern::Error(e.code()));
+    }
+
+    // run_commands takes the measurement, adaptive sleep needs its CO2 after
+    let co2 = sensor
+        .as_ref()
+        .and_then(|half| half.measurement.as_ref())
+        .and_then(|data| data.as_ref().ok())
+        .map(|data| data.co2);
+
+    match (plan, network.as_mut(), sensor.as_mut()) {
+        (WakePlan::Publish, Some(network), Some(half)) => run_commands(
+            network,
+            half,
+            &mut led,
+            &mut nvs,
+            &mut outbox,
+            &mut mqtt_policy,
+            &mut deep_sleep_seconds,
+            &mut adaptive,
+            &mut trial,
+            &mut supply,
+        )?,
+        (WakePlan::ReportSensorFailure, Some(network), _) => {
+            // Commands stay retained for a wake with a working sensor
+            let e = sensor_failure.unwrap_or(DeviceError::Sensor("Sensor unavailable"));
+            let _ = publish_device_payload(
+                &mut network.client,
+                &mqtt_policy,
+                DevicePayload::Error {
+                    code: e.code(),
+                    detail: e.context().into(),
+                },
+            );
+        }
+        (_, None, Some(half)) if supply.radio_off => {
+            if let Some(data) = half.measurement.take() {
+                let payload = measurement_payload(data, supply.resting_mv, &mut led);
+                if matches!(payload, DevicePayload::MeasurementSuccess { .. }) {
+                    let message = device_message(payload)
+                        .stamped(half.taken_at, MEASUREMENT_SEQ.fetch_add(1, Ordering::Relaxed));
+                    buffer_measurement(&mut nvs, &mut outbox, message);
+                    supply.state.skipped_wake();
+                }
+            }
+        }
+        _ => {
+            // Without a network for other reasons the measurement is only
+            // logged
+            if let Some(Some(data)) = sensor.as_ref().map(|half| &half.measurement) {
+                info!("No network, measurement not sent: {:?}", data);
+            }
+        }
+    }
+
+    // The schedule stays the deep sleep time; adaptive mode can only shorten it
+    let previous_co2 = Some(PREVIOUS_CO2.load(Ordering::Relaxed)).filter(|&ppm| ppm != 0);
+    let delta_ppm = adaptive_sleep::delta(previous_co2, co2);
+    let sleep_seconds = if adaptive {
+        compiled_adaptive_sleep().next_sleep(delta_ppm, deep_sleep_seconds)
+    } else {
+        deep_sleep_seconds
+    };
+    // A failed reading keeps the last good one to compare with
+    if let Some(co2) = co2 {
+        PREVIOUS_CO2.store(co2, Ordering::Relaxed);
+    }
+    // A sagging supply gets longer to recover
+    let sleep_seconds = supply.guard.sleep_seconds(&supply.state, sleep_seconds);
+    supply.save();
+
+    if let Some(network) = network.as_mut() {
+        if !supply.radio_off {
+            let _ = publish_device_payload(
+                &mut network.client,
+                &mqtt_policy,
+                DevicePayload::NextWake {
+                    sleep_seconds,
+                    adaptive,
+                    delta_ppm: delta_ppm.filter(|_| adaptive),
+                },
+            );
+            let _ = publish_device_payload(
+                &mut network.client,
+                &mqtt_policy,
+                timings.to_payload(boot.elapsed()),
+            );
+            // Last, so the server hears nothing more until the next wake
+            if !REBOOT_REQUESTED.load(Ordering::Relaxed) {
+                let _ = publish_device_payload(
+                    &mut network.client,
+                    &mqtt_policy,
+                    DevicePayload::entering_sleep(
+                        sleep_seconds,
+                        clock_millis().map(|millis| millis / 1000),
+                    ),
+                );
+            }
+        }
+        FreeRtos::delay_ms(2000); // Time to send
+
+        // Whatever is still unacknowledged goes out again next wake
+        let acknowledged = network
+            .acks
+            .try_iter()
+            .filter_map(|packet_id| outbox.acknowledged(packet_id))
+            .count();
+        if acknowledged > 0
+            && let Err(e) = outbox.save(&mut NvsBlobs(&mut nvs))
+        {
+            info!("Failed to save the outbox: {:?}", e);
+        }
+        if !outbox.is_empty() {
+            info!("{} measurement(s) waiting for a PUBACK", outbox.len());
+        }
+    }
 
     info!("Cycle complete");
 
@@ -502,14 +2159,16 @@ fn main() -> Result<()> {
     info!("Shutting down peripherals...");
 
     // Turn off LED
-    let _ = led.set_low();
+    led.off();
 
     // Stop SCD40 periodic measurement to save power
-    let _ = scd40.stop_periodic_measurement();
-    FreeRtos::delay_ms(500);
+    if let Some(half) = sensor.as_mut() {
+        let _ = half.scd40.stop_periodic_measurement();
+        FreeRtos::delay_ms(500);
+    }
 
     // Disconnect MQTT
-    drop(mqtt_client);
+    drop(network);
 
     // Disconnect and stop WiFi
     info!("Disconnecting WiFi...");
@@ -520,12 +2179,20 @@ fn main() -> Result<()> {
 
     info!("All peripherals powered down.");
 
+    if wake_fault() == Some(FaultKind::Panic) {
+        panic!("Injected fault: panicking instead of sleeping");
+    }
+
+    if REBOOT_REQUESTED.load(Ordering::Relaxed) {
+        info!("Restarting...\n");
+        unsafe {
+            esp_idf_sys::esp_restart();
+        }
+    }
+
     // Enter deep sleep
-    let sleep_duration_us: u64 = deep_sleep_seconds * 1000 * 1000;
-    info!(
-        "Entering deep sleep for {} seconds...\n",
-        deep_sleep_seconds
-    );
+    let sleep_duration_us: u64 = sleep_seconds * 1000 * 1000;
+    info!("Entering deep sleep for {} seconds...\n", sleep_seconds);
     unsafe {
         esp_idf_sys::esp_deep_sleep(sleep_duration_us);
     }
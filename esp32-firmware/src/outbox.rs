@@ -0,0 +1,330 @@
+//! Measurements published but not yet acknowledged by the broker.
+//!
+//! The device goes to sleep right after publishing, so a QoS 1 measurement
+//! whose PUBACK hadn't arrived may never have reached the broker. Every
+//! measurement therefore gets a local id and is saved to the outbox in NVS
+//! before it is published, and the PUBACK for its packet id takes it out
+//! again. Whatever is still there on the next wake is published again,
+//! marked `redelivered`, before that wake's measurement. The copy keeps its
+//! `seq`, so the processor drops it if the first one arrived after all.
+//!
+//! A wake the supply guard keeps off the radio (see `supply_guard`) puts
+//! its measurement here without publishing it, and it goes out the same
+//! way once the supply has recovered.
+//!
+//! NVS keeps either the old or the new value of a key when power is cut in
+//! the middle of a write, so a power cut can at worst make a measurement go
+//! out twice. The outbox is one versioned blob (see `versioned`); one that
+//! can't be read counts as empty.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use shared_types::DeviceMessage;
+use shared_types::versioned::{BlobError, Migrations};
+
+/// The NVS key of the outbox
+pub const NVS_KEY: &str = "outbox";
+
+/// Measurements kept; the oldest is dropped to make room. Covers a few
+/// wakes without acknowledgments and keeps the blob to a couple of KiB.
+pub const CAPACITY: usize = 8;
+
+/// Version 1: JSON of `Saved`
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+/// Keeps blobs between wakes, NVS on the device
+pub trait BlobStore {
+    type Error: fmt::Debug;
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn store(&mut self, key: &str, blob: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A measurement waiting for its PUBACK
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Assigned by the outbox, unique across wakes and power cuts
+    pub id: u32,
+    pub message: DeviceMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    next_id: u32,
+    entries: VecDeque<Entry>,
+}
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    next_id: u32,
+    entries: VecDeque<Entry>,
+    /// `(packet id, local id)` of what was published this wake. Not saved:
+    /// packet ids start over with every connection.
+    in_flight: Vec<(u32, u32)>,
+}
+
+impl Outbox {
+    /// The outbox saved in `store`, empty if there is none or it can't be
+    /// read
+    pub fn load<S: BlobStore>(store: &S) -> Self {
+        match store.load(NVS_KEY) {
+            Ok(Some(blob)) => match Self::from_blob(&blob) {
+                Ok(outbox) => return outbox,
+                Err(e) => log::info!("Ignoring the stored outbox: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => log::info!("Couldn't read the outbox: {:?}", e),
+        }
+        Self::default()
+    }
+
+    pub fn save<S: BlobStore>(&self, store: &mut S) -> Result<(), S::Error> {
+        store.store(NVS_KEY, &self.to_blob())
+    }
+
+    /// Adds a measurement about to be published, returning its id. Save
+    /// the outbox before publishing it.
+    pub fn add(&mut self, message: DeviceMessage) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry { id, message });
+        id
+    }
+
+    /// Copies of the measurements still waiting, marked `redelivered`,
+    /// oldest first
+    pub fn redeliveries(&self) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| !self.in_flight.iter().any(|(_, id)| *id == entry.id))
+            .map(|entry| Entry {
+                id: entry.id,
+                message: entry.message.clone().redelivered(),
+            })
+            .collect()
+    }
+
+    /// Notes the packet id the entry `id` was published with
+    pub fn published(&mut self, id: u32, packet_id: u32) {
+        self.in_flight.retain(|(_, in_flight)| *in_flight != id);
+        self.in_flight.push((packet_id, id));
+    }
+
+    /// Takes out the entry published as `packet_id`, returning its id. Save
+    /// the outbox afterwards.
+    pub fn acknowledged(&mut self, packet_id: u32) -> Option<u32> {
+        let index = self.in_flight.iter().position(|(p, _)| *p == packet_id)?;
+        let (_, id) = self.in_flight.remove(index);
+        self.entries.retain(|entry| entry.id != id);
+        Some(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let saved = Saved {
+            next_id: self.next_id,
+            entries: self.entries.clone(),
+        };
+        LAYOUTS.encode(&serde_json::to_vec(&saved).expect("an outbox serializes"))
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, BlobError> {
+        let (payload, _) = LAYOUTS.load(blob)?;
+        let saved: Saved = serde_json::from_slice(&payload).map_err(|_| BlobError::Migration {
+            from: LAYOUTS.current,
+            reason: "outbox payload isn't valid JSON",
+        })?;
+        Ok(Self {
+            next_id: saved.next_id,
+            entries: saved.entries,
+            in_flight: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::DevicePayload;
+    use std::collections::HashMap;
+
+    /// NVS as it behaves across power cuts: a write either happens whole or
+    /// not at all, and RAM is lost
+    #[derive(Default)]
+    struct Nvs {
+        blobs: HashMap<String, Vec<u8>>,
+        /// Writes that still happen before the power is cut
+        writes_left: Option<usize>,
+    }
+
+    impl BlobStore for Nvs {
+        type Error = &'static str;
+
+        fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.blobs.get(key).cloned())
+        }
+
+        fn store(&mut self, key: &str, blob: &[u8]) -> Result<(), Self::Error> {
+            match &mut self.writes_left {
+                Some(0) => return Err("power cut"),
+                Some(left) => *left -= 1,
+                None => {}
+            }
+            self.blobs.insert(key.to_string(), blob.to_vec());
+            Ok(())
+        }
+    }
+
+    impl Nvs {
+        fn cut_power_after(&mut self, writes: usize) {
+            self.writes_left = Some(writes);
+        }
+
+        fn restore_power(&mut self) {
+            self.writes_left = None;
+        }
+    }
+
+    fn measurement(seq: u32) -> DeviceMessage {
+        DeviceMessage::new("esp32-scd40", DevicePayload::measurement(612, 22.4, 41.3))
+            .stamped(Some(1_736_942_400_000), seq)
+    }
+
+    /// One wake: send what is waiting, then `seq`, with packet ids from 1.
+    /// `acks` are the packet ids acknowledged before the power goes.
+    fn wake(nvs: &mut Nvs, seq: u32, acks: &[u32]) -> Vec<DeviceMessage> {
+        let mut outbox = Outbox::load(nvs);
+        let mut sent = Vec::new();
+        let mut packet_id = 0;
+        for entry in outbox.redeliveries() {
+            packet_id += 1;
+            outbox.published(entry.id, packet_id);
+            sent.push(entry.message);
+        }
+        let id = outbox.add(measurement(seq));
+        if outbox.save(nvs).is_err() {
+            // Never published
+            return sent;
+        }
+        packet_id += 1;
+        outbox.published(id, packet_id);
+        sent.push(measurement(seq));
+        for ack in acks {
+            outbox.acknowledged(*ack);
+            if outbox.save(nvs).is_err() {
+                break;
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn acknowledged_measurements_are_not_sent_again() {
+        let mut nvs = Nvs::default();
+        assert_eq!(wake(&mut nvs, 0, &[1]), [measurement(0)]);
+        assert_eq!(wake(&mut nvs, 1, &[1]), [measurement(1)]);
+        assert!(Outbox::load(&nvs).is_empty());
+    }
+
+    #[test]
+    fn an_unacknowledged_measurement_goes_first_next_wake() {
+        let mut nvs = Nvs::default();
+        // Asleep before the PUBACK came
+        assert_eq!(wake(&mut nvs, 0, &[]), [measurement(0)]);
+        assert_eq!(
+            wake(&mut nvs, 1, &[2]),
+            [measurement(0).redelivered(), measurement(1)]
+        );
+        // The redelivery wasn't acknowledged either
+        assert_eq!(
+            wake(&mut nvs, 2, &[1, 2]),
+            [measurement(0).redelivered(), measurement(2)]
+        );
+        assert!(Outbox::load(&nvs).is_empty());
+    }
+
+    #[test]
+    fn power_cuts_never_lose_a_published_measurement() {
+        let mut nvs = Nvs::default();
+        // Cut while saving the new measurement: it wasn't published either
+        nvs.cut_power_after(0);
+        assert_eq!(wake(&mut nvs, 0, &[1]), []);
+        nvs.restore_power();
+        assert!(Outbox::load(&nvs).is_empty());
+
+        // Published and acknowledged, but cut before that was saved
+        nvs.cut_power_after(1);
+        assert_eq!(wake(&mut nvs, 1, &[1]), [measurement(1)]);
+        nvs.restore_power();
+        // So it goes out again, for the processor to drop
+        assert_eq!(
+            wake(&mut nvs, 2, &[1, 2]),
+            [measurement(1).redelivered(), measurement(2)]
+        );
+        assert!(Outbox::load(&nvs).is_empty());
+    }
+
+    #[test]
+    fn ids_stay_unique_across_wakes() {
+        let mut nvs = Nvs::default();
+        for seq in 0..3 {
+            wake(&mut nvs, seq, &[]);
+        }
+        let ids: Vec<u32> = Outbox::load(&nvs)
+            .redeliveries()
+            .iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, [0, 1, 2]);
+    }
+
+    #[test]
+    fn the_oldest_measurement_makes_room() {
+        let mut outbox = Outbox::default();
+        for seq in 0..CAPACITY as u32 + 2 {
+            outbox.add(measurement(seq));
+        }
+        let waiting = outbox.redeliveries();
+        assert_eq!(waiting.len(), CAPACITY);
+        assert_eq!(waiting[0].message.seq, Some(2));
+    }
+
+    #[test]
+    fn acknowledgments_match_packet_ids_of_this_wake_only() {
+        let mut outbox = Outbox::default();
+        let id = outbox.add(measurement(0));
+        assert_eq!(outbox.acknowledged(1), None);
+        outbox.published(id, 7);
+        assert!(outbox.redeliveries().is_empty());
+        assert_eq!(outbox.acknowledged(7), Some(id));
+        assert_eq!(outbox.acknowledged(7), None);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn unreadable_outboxes_count_as_empty() {
+        let mut outbox = Outbox::default();
+        outbox.add(measurement(0));
+        let blob = outbox.to_blob();
+        assert_eq!(Outbox::from_blob(&blob).unwrap().len(), 1);
+        assert_eq!(
+            Outbox::from_blob(&blob[..blob.len() - 1]).unwrap_err(),
+            BlobError::Truncated
+        );
+        let mut nvs = Nvs::default();
+        nvs.blobs.insert(NVS_KEY.to_string(), blob[..10].to_vec());
+        assert!(Outbox::load(&nvs).is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! The app size budget, from the partition table.
+//!
+//! `build.rs` includes this file to make the link fail when the image would
+//! no longer fit the app partitions of `partitions.csv`. The image isn't
+//! known until the linker has laid it out, so the check is an `ASSERT` in a
+//! generated linker script rather than code in the build script.
+
+/// What the image takes beyond its sections: segment headers, the checksum
+/// and the padding that aligns flash segments to 64 KiB MMU pages
+pub const IMAGE_OVERHEAD: u32 = 0x10000;
+
+/// Output sections that end up in the app image
+const IMAGE_SECTIONS: [&str; 8] = [
+    ".flash.appdesc",
+    ".flash.rodata",
+    ".flash.text",
+    ".iram0.vectors",
+    ".iram0.text",
+    ".dram0.data",
+    ".rtc.text",
+    ".rtc.data",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    /// `app` or `data`
+    pub kind: String,
+    pub size: u32,
+}
+
+/// Parses ESP-IDF's partition table CSV. Only names, types and sizes are
+/// kept; offsets don't matter for the budget.
+pub fn parse(csv: &str) -> Result<Vec<Partition>, String> {
+    let mut partitions = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() < 5 {
+            return Err(format!(
+                "line {}: expected name, type, subtype, offset, size",
+                index + 1
+            ));
+        }
+        partitions.push(Partition {
+            name: fields[0].to_string(),
+            kind: fields[1].to_string(),
+            size: parse_size(fields[4]).map_err(|e| format!("line {}: {}", index + 1, e))?,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Sizes as the partition table writes them: `0x1E0000`, `1966080`, `1920K`
+/// or `2M`
+pub fn parse_size(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let (digits, unit) = if let Some(digits) = s.strip_suffix(['K', 'k']) {
+        (digits, 1024)
+    } else if let Some(digits) = s.strip_suffix(['M', 'm']) {
+        (digits, 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("invalid size '{}'", s))?;
+    value
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size '{}' doesn't fit 32 bits", s))
+}
+
+/// The smallest app partition, since an OTA update can land in any of them
+pub fn app_budget(partitions: &[Partition]) -> Option<&Partition> {
+    partitions
+        .iter()
+        .filter(|p| p.kind == "app")
+        .min_by_key(|p| p.size)
+}
+
+/// Linker script that fails the link when the image exceeds `budget` bytes.
+/// `source` says where the budget came from, for the error message.
+pub fn size_check_script(budget: u32, source: &str) -> String {
+    let sections: Vec<String> = IMAGE_SECTIONS
+        .iter()
+        .map(|s| format!("SIZEOF({})", s))
+        .collect();
+    format!(
+        "/* Generated by build.rs */\n\
+         ASSERT({} + {:#x} <= {:#x},\n       \
+         \"firmware image exceeds the {} KiB budget of {}; turn off features or raise the budget\")\n",
+        sections.join(" + "),
+        IMAGE_OVERHEAD,
+        budget,
+        budget / 1024,
+        source
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+# Name,   Type, SubType, Offset,   Size, Flags
+nvs,      data, nvs,     0x9000,   0x6000,
+otadata,  data, ota,     0xf000,   0x2000,
+phy_init, data, phy,     0x11000,  0x1000,
+ota_0,    app,  ota_0,   0x20000,  0x1E0000,
+ota_1,    app,  ota_1,   ,         1920K,
+";
+
+    #[test]
+    fn reads_the_partition_table() {
+        let partitions = parse(TABLE).unwrap();
+        assert_eq!(partitions.len(), 5);
+        assert_eq!(
+            partitions[0],
+            Partition {
+                name: "nvs".to_string(),
+                kind: "data".to_string(),
+                size: 0x6000,
+            }
+        );
+        assert_eq!(partitions[4].size, 0x1E0000);
+        assert_eq!(app_budget(&partitions).unwrap().name, "ota_0");
+    }
+
+    #[test]
+    fn the_smallest_app_slot_sets_the_budget() {
+        let table = "factory, app, factory, 0x10000, 1M\nota_0, app, ota_0, , 0x80000\n";
+        let budget = app_budget(&parse(table).unwrap()).unwrap().clone();
+        assert_eq!(budget.name, "ota_0");
+        assert_eq!(budget.size, 512 * 1024);
+        assert_eq!(app_budget(&parse("nvs, data, nvs, , 24K").unwrap()), None);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0x1E0000"), Ok(0x1E0000));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("24K"), Ok(24 * 1024));
+        assert_eq!(parse_size("2M"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("8192M").is_err());
+        assert!(parse("nvs, data, nvs").is_err());
+    }
+
+    #[test]
+    fn script_compares_the_image_with_the_budget() {
+        let script = size_check_script(0x1E0000, "ota_0 in partitions.csv");
+        assert!(script.contains("SIZEOF(.flash.text) + SIZEOF(.iram0.vectors)"));
+        assert!(script.contains("+ 0x10000 <= 0x1e0000,"));
+        assert!(script.contains("exceeds the 1920 KiB budget of ota_0 in partitions.csv"));
+    }
+}
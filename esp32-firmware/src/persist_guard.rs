@@ -0,0 +1,174 @@
+//! How often the firmware may write the SCD4x settings to its EEPROM.
+//!
+//! `persist_settings` copies the sensor's settings to an EEPROM rated for a
+//! limited number of write cycles, so an automation resending
+//! `set_temp_offset` in a loop could wear it out. The firmware keeps a
+//! `PersistLog` in NVS and asks it before every write; past the limit the
+//! setting is still applied, just not saved.
+//!
+//! Days are counted from the device clock: UTC days once SNTP has synced,
+//! days since power-on before that. Clock resets can only start a new day
+//! early, so at worst a power cycle allows one more day's worth of writes.
+//!
+//! The log is stored as one versioned blob (see `shared_types::versioned`), so a torn
+//! write reads as no writes yet rather than as a bogus count.
+
+use shared_types::versioned::{BlobError, Migrations};
+
+/// Writes per day when the build doesn't set `PERSISTS_PER_DAY`
+pub const DEFAULT_PERSISTS_PER_DAY: u16 = 4;
+
+const DAY_SECONDS: u64 = 86_400;
+
+/// Version 1: `count` as u16, then `last` as u64, little endian
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+/// Refused because `limit` writes were already made today
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PersistLimited {
+    pub limit: u16,
+    /// Until the next day starts
+    pub retry_after_seconds: u64,
+}
+
+/// Writes made on the day of the last one; times are seconds on the device
+/// clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PersistLog {
+    pub count: u16,
+    pub last: u64,
+}
+
+impl PersistLog {
+    /// Writes made on the same day as `now`
+    pub fn count_today(&self, now: u64) -> u16 {
+        if self.count > 0 && self.last / DAY_SECONDS == now / DAY_SECONDS {
+            self.count
+        } else {
+            0
+        }
+    }
+
+    pub fn check(&self, now: u64, limit: u16) -> Result<(), PersistLimited> {
+        if self.count_today(now) < limit {
+            Ok(())
+        } else {
+            Err(PersistLimited {
+                limit,
+                retry_after_seconds: DAY_SECONDS - now % DAY_SECONDS,
+            })
+        }
+    }
+
+    /// Counts a write made at `now`.
+    pub fn record(&mut self, now: u64) {
+        self.count = self.count_today(now).saturating_add(1);
+        self.last = now;
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut payload = self.count.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.last.to_le_bytes());
+        LAYOUTS.encode(&payload)
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, BlobError> {
+        let (payload, _) = LAYOUTS.load(blob)?;
+        let [c0, c1, l0, l1, l2, l3, l4, l5, l6, l7] = payload[..] else {
+            return Err(BlobError::Migration {
+                from: LAYOUTS.current,
+                reason: "persist log payload has the wrong size",
+            });
+        };
+        Ok(Self {
+            count: u16::from_le_bytes([c0, c1]),
+            last: u64::from_le_bytes([l0, l1, l2, l3, l4, l5, l6, l7]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = DAY_SECONDS;
+    /// 2025-01-15T00:00:00Z
+    const JAN_15: u64 = 1_736_899_200;
+
+    #[test]
+    fn allows_the_limit_then_refuses_until_the_next_day() {
+        let mut log = PersistLog::default();
+        let mut now = JAN_15 + 8 * 3600;
+        for _ in 0..4 {
+            log.check(now, 4).unwrap();
+            log.record(now);
+            now += 60;
+        }
+        assert_eq!(log.count_today(now), 4);
+        assert_eq!(
+            log.check(now, 4),
+            Err(PersistLimited {
+                limit: 4,
+                retry_after_seconds: DAY - (8 * 3600 + 240),
+            })
+        );
+        // Just before midnight, then just after
+        assert!(log.check(JAN_15 + DAY - 1, 4).is_err());
+        log.check(JAN_15 + DAY, 4).unwrap();
+        log.record(JAN_15 + DAY);
+        assert_eq!(
+            log,
+            PersistLog {
+                count: 1,
+                last: JAN_15 + DAY
+            }
+        );
+    }
+
+    #[test]
+    fn a_clock_reset_starts_over_rather_than_locking_out() {
+        let mut log = PersistLog::default();
+        for _ in 0..4 {
+            log.record(JAN_15);
+        }
+        // After a power cycle without SNTP the clock restarts at 1970
+        assert_eq!(log.count_today(30), 0);
+        log.check(30, 4).unwrap();
+        // Uptime within the first day keeps counting
+        log.record(30);
+        log.record(90);
+        assert_eq!(log.count_today(3600), 2);
+    }
+
+    #[test]
+    fn a_zero_limit_refuses_everything() {
+        assert_eq!(
+            PersistLog::default().check(JAN_15, 0),
+            Err(PersistLimited {
+                limit: 0,
+                retry_after_seconds: DAY,
+            })
+        );
+    }
+
+    #[test]
+    fn the_log_round_trips_through_nvs_and_torn_writes_read_as_errors() {
+        let log = PersistLog {
+            count: 3,
+            last: JAN_15 + 60,
+        };
+        let blob = log.to_blob();
+        assert_eq!(PersistLog::from_blob(&blob), Ok(log));
+        assert_eq!(
+            PersistLog::from_blob(&blob[..blob.len() - 1]),
+            Err(BlobError::Truncated)
+        );
+        let mut corrupted = blob.clone();
+        corrupted[11] ^= 0x01;
+        assert_eq!(PersistLog::from_blob(&corrupted), Err(BlobError::Checksum));
+        // Framed correctly but not a persist log
+        assert!(
+            PersistLog::from_blob(&shared_types::versioned::encode_versioned(1, &[0; 3])).is_err()
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Frees the sensor I2C bus when the SCD40 holds SDA low after a brownout.
+//!
+//! When to recover is decided by `esp32_firmware::bus_recovery::BusHealth`;
+//! this module only does the pin work: drop the driver, clock SCL by hand
+//! until SDA is released, send a STOP and bring the driver back.
+
+use anyhow::Result;
+use embedded_hal::i2c::{Error as _, ErrorKind};
+use esp_idf_hal::delay::{Ets, FreeRtos};
+use esp_idf_hal::gpio::{Gpio21, Gpio22, PinDriver};
+use esp_idf_hal::i2c::{self, I2C0, I2cDriver, I2cError};
+use esp_idf_hal::units::Hertz;
+use log::info;
+use scd4x::Scd4x;
+
+use esp32_firmware::bus_recovery::{
+    BusFault, BusHealth, RecoveryDecision, RecoveryPolicy, clock_out,
+};
+use shared_types::DevicePayload;
+
+pub type Sensor = Scd4x<I2cDriver<'static>, Ets>;
+
+/// Half an SCL period at the bus's 100 kHz
+const HALF_PERIOD_US: u32 = 5;
+
+pub fn open_sensor(i2c: I2C0, sda: Gpio21, scl: Gpio22) -> Result<Sensor> {
+    let config = i2c::config::Config::new().baudrate(Hertz(100_000));
+    let driver = I2cDriver::new(i2c, sda, scl, &config)?;
+    Ok(Scd4x::new(driver, Ets))
+}
+
+fn fault(error: &scd4x::Error<I2cError>) -> BusFault {
+    match error {
+        scd4x::Error::I2c(e) => match e.kind() {
+            ErrorKind::NoAcknowledge(_) => BusFault::Nack,
+            ErrorKind::ArbitrationLoss | ErrorKind::Bus => BusFault::BusBusy,
+            // esp-idf reports a bus that never goes idle as a timeout
+            _ => BusFault::Timeout,
+        },
+        scd4x::Error::Crc => BusFault::Crc,
+        _ => BusFault::Other,
+    }
+}
+
+/// Clocks SDA free and sends a STOP. Returns the pulses it took, `None` if
+/// SDA stayed low.
+fn release_bus(sda: Gpio21, scl: Gpio22) -> Result<Option<u8>> {
+    let mut sda = PinDriver::input_output_od(sda)?;
+    let mut scl = PinDriver::input_output_od(scl)?;
+    sda.set_high()?;
+    scl.set_high()?;
+    Ets::delay_us(HALF_PERIOD_US);
+
+    let pulses = clock_out(
+        || sda.is_high(),
+        || {
+            let _ = scl.set_low();
+            Ets::delay_us(HALF_PERIOD_US);
+            let _ = scl.set_high();
+            Ets::delay_us(HALF_PERIOD_US);
+        },
+    );
+
+    // STOP: SDA rises while SCL is high
+    sda.set_low()?;
+    Ets::delay_us(HALF_PERIOD_US);
+    scl.set_high()?;
+    Ets::delay_us(HALF_PERIOD_US);
+    sda.set_high()?;
+    Ets::delay_us(HALF_PERIOD_US);
+    Ok(pulses)
+}
+
+fn recover(sensor: Sensor) -> Result<(Sensor, Option<u8>)> {
+    drop(sensor.destroy());
+    // SAFETY: the driver that owned these was dropped above, and the pin
+    // drivers in release_bus are gone before the new I2C driver is created
+    let (i2c, sda, scl) = unsafe { (I2C0::new(), Gpio21::new(), Gpio22::new()) };
+    let pulses = release_bus(sda, scl)?;
+    // SAFETY: as above
+    let (sda, scl) = unsafe { (Gpio21::new(), Gpio22::new()) };
+    Ok((open_sensor(i2c, sda, scl)?, pulses))
+}
+
+/// Reads the serial number until it answers, recovering the bus when the
+/// policy says so. Returns the sensor, whether it answered and a report for
+/// every recovery attempt.
+pub fn probe(mut sensor: Sensor) -> Result<(Sensor, bool, Vec<DevicePayload>)> {
+    let mut health = BusHealth::new(RecoveryPolicy::default());
+    let mut reports = Vec::new();
+    loop {
+        let error = match sensor.serial_number() {
+            Ok(serial) => {
+                info!("SCD40 answered, serial {:#x}", serial);
+                return Ok((sensor, true, reports));
+            }
+            Err(e) => e,
+        };
+        info!("SCD40 probe failed: {:?}", error);
+        health.record_failure(fault(&error));
+
+        match health.decide() {
+            RecoveryDecision::Retry => FreeRtos::delay_ms(100),
+            RecoveryDecision::Recover => {
+                let attempt = health.start_recovery();
+                info!("Attempting I2C bus recovery #{}", attempt);
+                let (recovered_sensor, pulses) = recover(sensor)?;
+                sensor = recovered_sensor;
+                FreeRtos::delay_ms(20);
+                let recovered = sensor.serial_number().is_ok();
+                info!(
+                    "Bus recovery #{}: SCL pulses {:?}, sensor {}",
+                    attempt,
+                    pulses,
+                    if recovered { "answers" } else { "still silent" }
+                );
+                reports.push(DevicePayload::BusRecovery {
+                    attempt,
+                    pulses,
+                    recovered,
+                });
+                if recovered {
+                    return Ok((sensor, true, reports));
+                }
+            }
+            RecoveryDecision::GiveUp => return Ok((sensor, false, reports)),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{Gpio2, Output, PinDriver};
+use esp32_firmware::indicator::{BlinkPattern, animation};
+
+pub trait StatusLed {
+    fn show(&mut self, pattern: BlinkPattern);
+    fn off(&mut self);
+}
+
+/// The bare LED on GPIO2; only flash counts are shown.
+pub struct PlainLed<'d> {
+    pin: PinDriver<'d, Gpio2, Output>,
+}
+
+impl<'d> PlainLed<'d> {
+    pub fn new(pin: PinDriver<'d, Gpio2, Output>) -> Self {
+        Self { pin }
+    }
+}
+
+impl StatusLed for PlainLed<'_> {
+    fn show(&mut self, pattern: BlinkPattern) {
+        for _ in 0..animation(pattern).plain_blinks() {
+            self.pin.set_high().ok();
+            FreeRtos::delay_ms(200);
+            self.pin.set_low().ok();
+            FreeRtos::delay_ms(200);
+        }
+    }
+
+    fn off(&mut self) {
+        let _ = self.pin.set_low();
+    }
+}
+
+#[cfg(feature = "neopixel")]
+pub use neopixel::NeoPixel;
+
+#[cfg(feature = "neopixel")]
+mod neopixel {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use esp_idf_hal::delay::FreeRtos;
+    use esp_idf_hal::gpio::OutputPin;
+    use esp_idf_hal::peripheral::Peripheral;
+    use esp_idf_hal::rmt::config::TransmitConfig;
+    use esp_idf_hal::rmt::{FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver};
+    use log::info;
+    use esp32_firmware::indicator::{BlinkPattern, Rgb, animation};
+
+    use super::StatusLed;
+
+    /// A single WS2812 pixel driven through the RMT peripheral.
+    pub struct NeoPixel<'d> {
+        tx: TxRmtDriver<'d>,
+        brightness: u8,
+        quiet: bool,
+    }
+
+    impl<'d> NeoPixel<'d> {
+        pub fn new<C: RmtChannel>(
+            channel: impl Peripheral<P = C> + 'd,
+            pin: impl Peripheral<P = impl OutputPin> + 'd,
+            brightness: u8,
+        ) -> Result<Self> {
+            let config = TransmitConfig::new().clock_divider(1);
+            let tx = TxRmtDriver::new(channel, pin, &config)?;
+            Ok(Self {
+                tx,
+                brightness,
+                quiet: false,
+            })
+        }
+
+        /// While quiet, patterns are skipped and the pixel stays dark.
+        pub fn set_quiet(&mut self, quiet: bool) {
+            if quiet != self.quiet {
+                info!("Status LED quiet hours: {}", quiet);
+            }
+            self.quiet = quiet;
+            if quiet {
+                self.off();
+            }
+        }
+
+        fn write(&mut self, color: Rgb) -> Result<()> {
+            let color = color.scaled(self.brightness);
+            let ticks_hz = self.tx.counter_clock()?;
+            let t0h =
+                Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(350))?;
+            let t0l =
+                Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(800))?;
+            let t1h =
+                Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(700))?;
+            let t1l =
+                Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(600))?;
+
+            // WS2812 expects GRB, most significant bit first
+            let grb = ((color.g as u32) << 16) | ((color.r as u32) << 8) | color.b as u32;
+            let mut signal = FixedLengthSignal::<24>::new();
+            for i in 0..24 {
+                let bit = grb & (1 << (23 - i)) != 0;
+                let pulses = if bit { (t1h, t1l) } else { (t0h, t0l) };
+                signal.set(i, &pulses)?;
+            }
+            self.tx.start_blocking(&signal)?;
+            Ok(())
+        }
+    }
+
+    impl StatusLed for NeoPixel<'_> {
+        fn show(&mut self, pattern: BlinkPattern) {
+            if self.quiet {
+                return;
+            }
+            let animation = animation(pattern);
+            for frame in animation.frames() {
+                self.write(frame.color).ok();
+                FreeRtos::delay_ms(frame.duration_ms);
+            }
+            self.write(animation.resting_color()).ok();
+        }
+
+        fn off(&mut self) {
+            self.write(Rgb::OFF).ok();
+        }
+    }
+}
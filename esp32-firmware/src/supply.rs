@@ -0,0 +1,159 @@
+//! The supply rail, sampled for the supply guard (see
+//! `esp32_firmware::supply_guard`).
+//!
+//! Builds with the `supply-guard` feature read the rail on GPIO34 (ADC1)
+//! through a divider of `SUPPLY_DIVIDER`, e.g. `2` for two equal resistors.
+//! Builds without it have no rail to read, so the guard never keeps the
+//! radio off and measurements carry no battery level.
+
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+
+use log::info;
+use esp32_firmware::supply_guard::{SupplyGuard, SupplyState};
+
+/// Guard settings, see `supply_guard`
+const SUPPLY_GUARD: Option<&str> = option_env!("SUPPLY_GUARD");
+
+/// `SupplyState` between wakes, in RTC slow memory like `MEASUREMENT_SEQ`
+#[unsafe(link_section = ".rtc.data")]
+static SAGGING: AtomicBool = AtomicBool::new(false);
+#[unsafe(link_section = ".rtc.data")]
+static SKIPPED_WAKES: AtomicU32 = AtomicU32::new(0);
+#[unsafe(link_section = ".rtc.data")]
+static LOWEST_MV: AtomicU16 = AtomicU16::new(0);
+
+fn compiled_guard() -> SupplyGuard {
+    match SUPPLY_GUARD.map(str::parse::<SupplyGuard>) {
+        Some(Ok(guard)) => guard,
+        Some(Err(e)) => {
+            info!("Ignoring SUPPLY_GUARD ({}), using the default guard", e);
+            SupplyGuard::default()
+        }
+        None => SupplyGuard::default(),
+    }
+}
+
+pub struct Supply {
+    rail: Option<Rail>,
+    pub guard: SupplyGuard,
+    pub state: SupplyState,
+    /// The wake's first sample, taken before WiFi starts: the battery level
+    /// measurements carry
+    pub resting_mv: Option<u16>,
+    /// A sample kept the radio off; nothing more goes out this wake
+    pub radio_off: bool,
+}
+
+impl Supply {
+    /// Picks up the state the previous wake left
+    pub fn load(rail: Option<Rail>) -> Self {
+        Self {
+            rail,
+            guard: compiled_guard(),
+            state: SupplyState {
+                sagging: SAGGING.load(Ordering::Relaxed),
+                skipped_wakes: SKIPPED_WAKES.load(Ordering::Relaxed),
+                lowest_mv: LOWEST_MV.load(Ordering::Relaxed),
+            },
+            resting_mv: None,
+            radio_off: false,
+        }
+    }
+
+    /// Keeps the state for the next wake
+    pub fn save(&self) {
+        SAGGING.store(self.state.sagging, Ordering::Relaxed);
+        SKIPPED_WAKES.store(self.state.skipped_wakes, Ordering::Relaxed);
+        LOWEST_MV.store(self.state.lowest_mv, Ordering::Relaxed);
+    }
+
+    /// Samples the rail right before `what` and asks the guard. Always
+    /// `true` without a rail, and `false` for the rest of the wake once a
+    /// sample sagged.
+    pub fn allows_radio(&mut self, what: &str) -> bool {
+        if self.radio_off {
+            return false;
+        }
+        let Some(mv) = self.rail.as_mut().and_then(Rail::sample_mv) else {
+            return true;
+        };
+        self.resting_mv.get_or_insert(mv);
+        if !self.guard.allows_radio(&mut self.state, mv) {
+            info!("Supply at {} mV before {}, keeping the radio off", mv, what);
+            self.radio_off = true;
+        }
+        !self.radio_off
+    }
+}
+
+#[cfg(feature = "supply-guard")]
+pub use adc::Rail;
+
+/// Stands in for the ADC in builds without a divider on the supply
+#[cfg(not(feature = "supply-guard"))]
+pub enum Rail {}
+
+#[cfg(not(feature = "supply-guard"))]
+impl Rail {
+    fn sample_mv(&mut self) -> Option<u16> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "supply-guard")]
+mod adc {
+    use anyhow::Result;
+    use esp_idf_hal::adc::ADC1;
+    use esp_idf_hal::adc::attenuation::DB_12;
+    use esp_idf_hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
+    use esp_idf_hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+    use esp_idf_hal::gpio::Gpio34;
+    use log::info;
+
+    /// Ratio of the divider between the supply and GPIO34
+    const SUPPLY_DIVIDER: Option<&str> = option_env!("SUPPLY_DIVIDER");
+
+    /// Readings averaged into one sample; a single one is noisy
+    const READINGS: u32 = 8;
+
+    pub struct Rail {
+        channel: AdcChannelDriver<'static, Gpio34, AdcDriver<'static, ADC1>>,
+        divider: f32,
+    }
+
+    impl Rail {
+        pub fn new(adc: ADC1, pin: Gpio34) -> Result<Self> {
+            let config = AdcChannelConfig {
+                attenuation: DB_12,
+                calibration: Calibration::Line,
+                ..Default::default()
+            };
+            let channel = AdcChannelDriver::new(AdcDriver::new(adc)?, pin, &config)?;
+            let divider = SUPPLY_DIVIDER
+                .and_then(|ratio| ratio.parse().ok())
+                .filter(|ratio: &f32| *ratio >= 1.0)
+                .unwrap_or(2.0);
+            info!(
+                "Sampling the supply on GPIO34 through a 1:{} divider",
+                divider
+            );
+            Ok(Self { channel, divider })
+        }
+
+        /// The supply in mV, `None` if the ADC can't be read
+        pub(super) fn sample_mv(&mut self) -> Option<u16> {
+            let mut total = 0;
+            for _ in 0..READINGS {
+                match self.channel.read() {
+                    Ok(mv) => total += u32::from(mv),
+                    Err(e) => {
+                        info!("Failed to read the supply: {:?}", e);
+                        return None;
+                    }
+                }
+            }
+            let mv = (total / READINGS) as f32 * self.divider;
+            Some(mv.min(f32::from(u16::MAX)) as u16)
+        }
+    }
+}
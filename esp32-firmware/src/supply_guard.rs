@@ -0,0 +1,326 @@
+//! Keeping the radio off while a battery supply sags.
+//!
+//! The rail drops when WiFi starts transmitting, and on a cell near empty
+//! it drops below what the ESP32 runs on: the chip browns out mid-publish,
+//! boots again and tries the same. Builds with a divider on the supply
+//! sample the rail before starting WiFi and before each measurement they
+//! publish. Below `skip_below_mv` the wake keeps its radio off: its
+//! measurement goes to the outbox, the skip is counted and the device
+//! sleeps `sleep_multiplier` times as long. The supply only counts as recovered at `resume_at_mv`, so
+//! a rail hovering around the threshold doesn't flip the radio on and off.
+//!
+//! The first wake back on the broker publishes the buffered measurements,
+//! sampling before each, and a `radio_skipped` report. A sag on the way
+//! ends the catch-up and leaves the rest buffered for a later wake.
+//!
+//! The battery level measurements carry comes from the sample taken before
+//! WiFi starts. The one before a publish is taken with the radio on, and
+//! would report every sag as an empty battery.
+//!
+//! Builds set the guard with `SUPPLY_GUARD`, e.g.
+//! `skip_below=3500,resume_at=3650,sleep_multiplier=3,max_sleep=3600`.
+
+use core::str::FromStr;
+
+use shared_types::DevicePayload;
+
+/// A cell this low is reported as empty, one this high as full
+pub const EMPTY_MV: u16 = 3300;
+pub const FULL_MV: u16 = 4200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupplyGuard {
+    pub skip_below_mv: u16,
+    /// Where a sagging supply counts as recovered, at or above
+    /// `skip_below_mv`
+    pub resume_at_mv: u16,
+    pub sleep_multiplier: u64,
+    /// Longest a lengthened sleep gets; a scheduled sleep longer than this
+    /// stays as it is
+    pub max_sleep_seconds: u64,
+}
+
+impl Default for SupplyGuard {
+    fn default() -> Self {
+        Self {
+            skip_below_mv: 3500,
+            resume_at_mv: 3650,
+            sleep_multiplier: 3,
+            max_sleep_seconds: 3600,
+        }
+    }
+}
+
+/// What the guard keeps between wakes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SupplyState {
+    /// Fell below `skip_below_mv` and hasn't reached `resume_at_mv` since
+    pub sagging: bool,
+    /// Wakes that kept their measurement off the radio since the last
+    /// report
+    pub skipped_wakes: u32,
+    /// Lowest sample that kept the radio off since the last report, 0 if
+    /// there was none
+    pub lowest_mv: u16,
+}
+
+impl SupplyGuard {
+    /// Whether the radio may be used with the rail at `mv`. Samples are
+    /// taken right before starting WiFi or publishing, and a `false` ends
+    /// the wake's radio use.
+    pub fn allows_radio(&self, state: &mut SupplyState, mv: u16) -> bool {
+        let threshold = if state.sagging {
+            self.resume_at_mv
+        } else {
+            self.skip_below_mv
+        };
+        state.sagging = mv < threshold;
+        if state.sagging {
+            state.lowest_mv = match state.lowest_mv {
+                0 => mv,
+                lowest => lowest.min(mv),
+            };
+        }
+        !state.sagging
+    }
+
+    /// How long to sleep instead of `seconds`: longer while the supply
+    /// sags, to give the cell time to recover
+    pub fn sleep_seconds(&self, state: &SupplyState, seconds: u64) -> u64 {
+        if !state.sagging {
+            return seconds;
+        }
+        seconds
+            .saturating_mul(self.sleep_multiplier)
+            .min(self.max_sleep_seconds)
+            .max(seconds)
+    }
+}
+
+impl SupplyState {
+    /// Counts a wake whose measurement went to the outbox instead of the
+    /// broker
+    pub fn skipped_wake(&mut self) {
+        self.skipped_wakes = self.skipped_wakes.saturating_add(1);
+    }
+
+    /// The `radio_skipped` report for the skips since the last one, if
+    /// there were any
+    pub fn report(&self, guard: &SupplyGuard) -> Option<DevicePayload> {
+        (self.skipped_wakes > 0).then_some(DevicePayload::RadioSkipped {
+            skipped_wakes: self.skipped_wakes,
+            lowest_mv: self.lowest_mv,
+            threshold_mv: guard.skip_below_mv,
+        })
+    }
+
+    /// Starts counting again once the report is out
+    pub fn reported(&mut self) {
+        self.skipped_wakes = 0;
+        self.lowest_mv = 0;
+    }
+}
+
+/// Charge left in a cell resting at `mv`, straight-line between `EMPTY_MV`
+/// and `FULL_MV`
+pub fn battery_percent(mv: u16) -> u8 {
+    let span = u32::from(FULL_MV - EMPTY_MV);
+    let above_empty = u32::from(mv.clamp(EMPTY_MV, FULL_MV) - EMPTY_MV);
+    (above_empty * 100 / span) as u8
+}
+
+/// `name=value` pairs, comma separated: `skip_below` and `resume_at` in
+/// mV, `sleep_multiplier`, and `max_sleep` in seconds. Any left out keep
+/// their default.
+impl FromStr for SupplyGuard {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut guard = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').ok_or("expected name=value")?;
+            let value = value.trim();
+            match name.trim() {
+                "skip_below" => {
+                    guard.skip_below_mv = value.parse().map_err(|_| "invalid skip_below")?
+                }
+                "resume_at" => {
+                    guard.resume_at_mv = value.parse().map_err(|_| "invalid resume_at")?
+                }
+                "sleep_multiplier" => {
+                    guard.sleep_multiplier =
+                        value.parse().map_err(|_| "invalid sleep_multiplier")?
+                }
+                "max_sleep" => {
+                    guard.max_sleep_seconds = value.parse().map_err(|_| "invalid max_sleep")?
+                }
+                _ => {
+                    return Err(
+                        "unknown setting, expected skip_below, resume_at, sleep_multiplier or max_sleep",
+                    );
+                }
+            }
+        }
+        if guard.resume_at_mv < guard.skip_below_mv {
+            return Err("resume_at must be at least skip_below");
+        }
+        if guard.sleep_multiplier == 0 {
+            return Err("sleep_multiplier must be at least 1");
+        }
+        Ok(guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs samples through the guard, one wake each
+    fn wakes(guard: &SupplyGuard, state: &mut SupplyState, samples: &[u16]) -> Vec<bool> {
+        samples
+            .iter()
+            .map(|&mv| guard.allows_radio(state, mv))
+            .collect()
+    }
+
+    #[test]
+    fn a_sag_keeps_the_radio_off_until_the_supply_recovers() {
+        let guard = SupplyGuard::default();
+        let mut state = SupplyState::default();
+        assert_eq!(
+            wakes(
+                &guard,
+                &mut state,
+                &[3900, 3500, 3499, 3600, 3649, 3650, 3520]
+            ),
+            [true, true, false, false, false, true, true]
+        );
+        assert!(!state.sagging);
+        assert_eq!(state.lowest_mv, 3499);
+    }
+
+    #[test]
+    fn equal_thresholds_have_no_hysteresis() {
+        let guard = SupplyGuard {
+            resume_at_mv: 3500,
+            ..SupplyGuard::default()
+        };
+        let mut state = SupplyState::default();
+        assert_eq!(
+            wakes(&guard, &mut state, &[3499, 3500, 3499]),
+            [false, true, false]
+        );
+    }
+
+    #[test]
+    fn sleep_lengthens_while_sagging() {
+        let guard = SupplyGuard::default();
+        let mut state = SupplyState::default();
+        assert_eq!(guard.sleep_seconds(&state, 300), 300);
+        guard.allows_radio(&mut state, 3400);
+        assert_eq!(guard.sleep_seconds(&state, 300), 900);
+        assert_eq!(guard.sleep_seconds(&state, 1800), 3600);
+        // Never shorter than scheduled
+        assert_eq!(guard.sleep_seconds(&state, 7200), 7200);
+        guard.allows_radio(&mut state, 3700);
+        assert_eq!(guard.sleep_seconds(&state, 300), 300);
+    }
+
+    #[test]
+    fn skips_are_reported_once_and_counted_again_after() {
+        let guard = SupplyGuard::default();
+        let mut state = SupplyState::default();
+        assert_eq!(state.report(&guard), None);
+        for mv in [3450, 3380] {
+            guard.allows_radio(&mut state, mv);
+            state.skipped_wake();
+        }
+        guard.allows_radio(&mut state, 3700);
+        assert_eq!(
+            state.report(&guard),
+            Some(DevicePayload::RadioSkipped {
+                skipped_wakes: 2,
+                lowest_mv: 3380,
+                threshold_mv: 3500,
+            })
+        );
+        state.reported();
+        assert_eq!(state.report(&guard), None);
+        guard.allows_radio(&mut state, 3420);
+        state.skipped_wake();
+        assert_eq!(
+            state.report(&guard),
+            Some(DevicePayload::RadioSkipped {
+                skipped_wakes: 1,
+                lowest_mv: 3420,
+                threshold_mv: 3500,
+            })
+        );
+    }
+
+    #[test]
+    fn catch_up_stops_at_the_first_sag() {
+        let guard = SupplyGuard::default();
+        let mut state = SupplyState {
+            sagging: true,
+            skipped_wakes: 3,
+            lowest_mv: 3410,
+        };
+        assert!(
+            guard.allows_radio(&mut state, 3660),
+            "recovered before WiFi starts"
+        );
+        // Then one sample before each of four buffered measurements and the
+        // report
+        let sent = [3610, 3580, 3540, 3480, 3700]
+            .iter()
+            .take_while(|&&mv| guard.allows_radio(&mut state, mv))
+            .count();
+        assert_eq!(sent, 3);
+        assert!(state.sagging);
+        assert_eq!(state.lowest_mv, 3410);
+        // The next wake needs a recovered supply again, not just one above
+        // the skip threshold
+        assert!(!guard.allows_radio(&mut state, 3600));
+        assert!(guard.allows_radio(&mut state, 3700));
+    }
+
+    #[test]
+    fn battery_level_is_a_straight_line_between_empty_and_full() {
+        assert_eq!(battery_percent(3000), 0);
+        assert_eq!(battery_percent(EMPTY_MV), 0);
+        assert_eq!(battery_percent(3750), 50);
+        assert_eq!(battery_percent(FULL_MV), 100);
+        assert_eq!(battery_percent(4350), 100);
+    }
+
+    #[test]
+    fn settings_override_the_defaults() {
+        assert_eq!("".parse::<SupplyGuard>(), Ok(SupplyGuard::default()));
+        assert_eq!(
+            "skip_below=3400, resume_at=3600,max_sleep=1800".parse::<SupplyGuard>(),
+            Ok(SupplyGuard {
+                skip_below_mv: 3400,
+                resume_at_mv: 3600,
+                sleep_multiplier: 3,
+                max_sleep_seconds: 1800,
+            })
+        );
+        assert_eq!(
+            "skip_below=3700".parse::<SupplyGuard>(),
+            Err("resume_at must be at least skip_below")
+        );
+        assert_eq!(
+            "sleep_multiplier=0".parse::<SupplyGuard>(),
+            Err("sleep_multiplier must be at least 1")
+        );
+        assert_eq!(
+            "threshold=3500".parse::<SupplyGuard>().unwrap_err(),
+            "unknown setting, expected skip_below, resume_at, sleep_multiplier or max_sleep"
+        );
+        assert_eq!(
+            "skip_below".parse::<SupplyGuard>(),
+            Err("expected name=value")
+        );
+    }
+}
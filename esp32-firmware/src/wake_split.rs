@@ -0,0 +1,213 @@
+//! The two halves of a wake, run side by side.
+//!
+//! At boot the firmware starts the sensor half (bus probe, periodic
+//! measurement and the ~5 s wait for data) on the app core, while the
+//! network half (WiFi, MQTT and the retained commands) runs on the protocol
+//! core. `split` wraps the sensor half so it can be spawned on any thread,
+//! and `Pending::join` waits for it with a deadline. `plan` then picks what
+//! the rest of the wake does from how each half ended, and `WakeTimings`
+//! reports how much awake time the overlap saved.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use shared_types::DevicePayload;
+
+#[derive(Debug, PartialEq)]
+pub enum Joined<T> {
+    Finished {
+        value: T,
+        /// How long the task itself ran
+        elapsed: Duration,
+    },
+    /// Still running at the deadline
+    TimedOut,
+    /// The task ended without a result, e.g. it panicked
+    Lost,
+}
+
+/// The receiving end of a task started with `split`.
+pub struct Pending<T> {
+    rx: Receiver<(T, Duration)>,
+}
+
+/// Wraps `task` into a job for whatever thread the caller spawns, and the
+/// handle to wait for its result.
+pub fn split<T: Send + 'static>(
+    task: impl FnOnce() -> T + Send + 'static,
+) -> (impl FnOnce() + Send + 'static, Pending<T>) {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let job = move || {
+        let started = Instant::now();
+        let value = task();
+        // Nobody waits any more if the join already timed out
+        let _ = tx.send((value, started.elapsed()));
+    };
+    (job, Pending { rx })
+}
+
+impl<T> Pending<T> {
+    /// Waits up to `timeout`; a result that is already there is returned
+    /// even with a zero timeout.
+    pub fn join(self, timeout: Duration) -> Joined<T> {
+        match self.rx.recv_timeout(timeout) {
+            Ok((value, elapsed)) => Joined::Finished { value, elapsed },
+            Err(RecvTimeoutError::Timeout) => Joined::TimedOut,
+            Err(RecvTimeoutError::Disconnected) => Joined::Lost,
+        }
+    }
+}
+
+/// What the rest of the wake does, given how each half ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakePlan {
+    /// Run the commands and publish. A failed measurement still counts:
+    /// its error is what gets published.
+    Publish,
+    /// The sensor never came back, so it can't be used for commands. Only
+    /// the failure is published and the commands stay retained.
+    ReportSensorFailure,
+    /// Nothing can be published; the measurement is only logged
+    Offline,
+}
+
+pub fn plan<T, E>(sensor: &Joined<Result<T, E>>, network_up: bool) -> WakePlan {
+    match (network_up, sensor) {
+        (false, _) => WakePlan::Offline,
+        (true, Joined::Finished { value: Ok(_), .. }) => WakePlan::Publish,
+        (true, _) => WakePlan::ReportSensorFailure,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeTimings {
+    pub sensor: Duration,
+    pub network: Duration,
+    /// From starting both halves until both were done
+    pub overlapped: Duration,
+}
+
+impl WakeTimings {
+    /// Awake time running the halves one after the other would have added.
+    pub fn saved(&self) -> Duration {
+        (self.sensor + self.network).saturating_sub(self.overlapped)
+    }
+
+    /// The `wake_profile` diagnostic; `awake` is the time since boot.
+    pub fn to_payload(&self, awake: Duration) -> DevicePayload {
+        let ms = |d: Duration| u32::try_from(d.as_millis()).unwrap_or(u32::MAX);
+        DevicePayload::WakeProfile {
+            awake_ms: ms(awake),
+            sensor_ms: ms(self.sensor),
+            network_ms: ms(self.network),
+            saved_ms: ms(self.saved()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn finished_task_reports_its_value_and_runtime() {
+        let (job, pending) = split(|| {
+            std::thread::sleep(ms(20));
+            42
+        });
+        std::thread::spawn(job);
+        match pending.join(Duration::from_secs(5)) {
+            Joined::Finished { value, elapsed } => {
+                assert_eq!(value, 42);
+                assert!(elapsed >= ms(20), "{:?}", elapsed);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn result_ready_before_the_join_needs_no_wait() {
+        let (job, pending) = split(|| "measured");
+        // The sensor finished while the network was still connecting
+        std::thread::spawn(job).join().unwrap();
+        assert!(matches!(
+            pending.join(Duration::ZERO),
+            Joined::Finished {
+                value: "measured",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn slow_task_times_out() {
+        let (release, wait) = mpsc::channel::<()>();
+        let (job, pending) = split(move || wait.recv().is_ok());
+        std::thread::spawn(job);
+        assert_eq!(pending.join(ms(20)), Joined::TimedOut);
+        // The task finishing later has nobody to report to and must not panic
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn panicked_task_is_lost() {
+        let (job, pending) = split(|| -> u8 { panic!("sensor task died") });
+        let _ = std::thread::spawn(job).join();
+        assert_eq!(pending.join(Duration::from_secs(5)), Joined::Lost);
+    }
+
+    #[test]
+    fn plan_covers_either_half_failing() {
+        let measured: Joined<Result<u16, &str>> = Joined::Finished {
+            value: Ok(612),
+            elapsed: ms(5000),
+        };
+        let sensor_failed: Joined<Result<u16, &str>> = Joined::Finished {
+            value: Err("I2C bus stuck"),
+            elapsed: ms(900),
+        };
+        assert_eq!(plan(&measured, true), WakePlan::Publish);
+        assert_eq!(plan(&sensor_failed, true), WakePlan::ReportSensorFailure);
+        assert_eq!(
+            plan(&Joined::TimedOut::<Result<u16, &str>>, true),
+            WakePlan::ReportSensorFailure
+        );
+        assert_eq!(
+            plan(&Joined::Lost::<Result<u16, &str>>, true),
+            WakePlan::ReportSensorFailure
+        );
+        // Without a network the sensor result doesn't matter
+        assert_eq!(plan(&measured, false), WakePlan::Offline);
+        assert_eq!(plan(&sensor_failed, false), WakePlan::Offline);
+    }
+
+    #[test]
+    fn saving_is_the_overlap() {
+        let timings = WakeTimings {
+            sensor: ms(6200),
+            network: ms(3100),
+            overlapped: ms(6300),
+        };
+        assert_eq!(timings.saved(), ms(3000));
+        assert_eq!(
+            timings.to_payload(ms(9800)),
+            DevicePayload::WakeProfile {
+                awake_ms: 9800,
+                sensor_ms: 6200,
+                network_ms: 3100,
+                saved_ms: 3000,
+            }
+        );
+
+        // A join that waited past both halves saved nothing
+        let slow = WakeTimings {
+            overlapped: ms(10_000),
+            ..timings
+        };
+        assert_eq!(slow.saved(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,124 @@
+//! How long ago something happened, for the text output. "4 m ago" is
+//! quicker to read than a full timestamp, up to the age where the date
+//! matters more; past that, times are shown in full.
+
+use chrono::{DateTime, Duration, FixedOffset};
+
+/// Older times are shown as local time instead of an age
+pub const DEFAULT_ABSOLUTE_AFTER: Duration = Duration::hours(12);
+
+/// A device clock this far ahead is just SNTP jitter
+const SKEW_TOLERANCE: Duration = Duration::seconds(2);
+
+/// A duration in its largest whole unit, e.g. "4 m"
+pub fn span(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    match seconds {
+        0..60 => format!("{} s", seconds),
+        60..3600 => format!("{} m", seconds / 60),
+        3600..86_400 => format!("{} h", seconds / 3600),
+        _ => format!("{} d", seconds / 86_400),
+    }
+}
+
+/// "12 s ago", or "12 s ahead" for a time in the future, which means a
+/// skewed clock somewhere.
+pub fn relative(at: DateTime<FixedOffset>, now: DateTime<FixedOffset>) -> String {
+    let age = now - at;
+    if age < -SKEW_TOLERANCE {
+        format!("{} ahead", span(-age))
+    } else if age < Duration::seconds(1) {
+        "just now".to_string()
+    } else {
+        format!("{} ago", span(age))
+    }
+}
+
+/// Relative up to `absolute_after`, then `at` in `now`'s time zone.
+pub fn display(
+    at: DateTime<FixedOffset>,
+    now: DateTime<FixedOffset>,
+    absolute_after: Duration,
+    format: &str,
+) -> String {
+    if now - at > absolute_after {
+        at.with_timezone(now.offset()).format(format).to_string()
+    } else {
+        relative(at, now)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// Silent for more than two sleep intervals
+    Stale,
+    /// The sleep interval isn't known
+    Unknown,
+}
+
+pub fn freshness(
+    last: DateTime<FixedOffset>,
+    now: DateTime<FixedOffset>,
+    sleep_seconds: Option<u64>,
+) -> Freshness {
+    match sleep_seconds {
+        None => Freshness::Unknown,
+        Some(seconds) if now - last > Duration::seconds(2 * seconds as i64) => Freshness::Stale,
+        Some(_) => Freshness::Fresh,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2025-01-15T14:05:09+01:00").unwrap()
+    }
+
+    fn ago(seconds: i64) -> DateTime<FixedOffset> {
+        now() - Duration::seconds(seconds)
+    }
+
+    #[test]
+    fn units_switch_at_their_boundaries() {
+        assert_eq!(relative(ago(0), now()), "just now");
+        assert_eq!(relative(ago(1), now()), "1 s ago");
+        assert_eq!(relative(ago(59), now()), "59 s ago");
+        assert_eq!(relative(ago(60), now()), "1 m ago");
+        assert_eq!(relative(ago(3599), now()), "59 m ago");
+        assert_eq!(relative(ago(3600), now()), "1 h ago");
+        assert_eq!(relative(ago(86_399), now()), "23 h ago");
+        assert_eq!(relative(ago(86_400), now()), "1 d ago");
+        // Sub-second ages round down
+        assert_eq!(
+            relative(now() - Duration::milliseconds(59_999), now()),
+            "59 s ago"
+        );
+    }
+
+    #[test]
+    fn future_times_are_skew() {
+        assert_eq!(relative(ago(-2), now()), "just now");
+        assert_eq!(relative(ago(-3), now()), "3 s ahead");
+        assert_eq!(relative(ago(-600), now()), "10 m ahead");
+        assert_eq!(freshness(ago(-600), now(), Some(300)), Freshness::Fresh);
+    }
+
+    #[test]
+    fn old_times_are_absolute_in_local_time() {
+        let limit = Duration::hours(1);
+        assert_eq!(display(ago(3600), now(), limit, "%H:%M:%S"), "1 h ago");
+        assert_eq!(display(ago(3601), now(), limit, "%H:%M:%S"), "13:05:08");
+        let utc = DateTime::parse_from_rfc3339("2025-01-15T08:00:00Z").unwrap();
+        assert_eq!(display(utc, now(), limit, "%H:%M"), "09:00");
+    }
+
+    #[test]
+    fn stale_after_two_sleep_intervals() {
+        assert_eq!(freshness(ago(600), now(), Some(300)), Freshness::Fresh);
+        assert_eq!(freshness(ago(601), now(), Some(300)), Freshness::Stale);
+        assert_eq!(freshness(ago(86_400), now(), None), Freshness::Unknown);
+    }
+}
@@ -0,0 +1,541 @@
+//! A small in-process MQTT 3.1.1 broker for bench testing.
+//!
+//! `rpi-commander --embedded-broker` runs it so a device on the desk and
+//! the commander can talk with nothing else installed. It covers what the
+//! two of them use: QoS 0 and 1 (QoS 2 is accepted and delivered as 1),
+//! retained messages, last wills and pings. There is no authentication,
+//! TLS or persistent session; every connection starts clean. Retained
+//! messages are saved to a file, so a command queued for a sleeping device
+//! survives restarting the commander.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use log::{debug, warn};
+use rumqttc::mqttbytes::{self, matches, valid_filter, valid_topic};
+use rumqttc::{
+    ConnAck, ConnectReturnCode, Packet, PubAck, PubComp, PubRec, Publish, QoS, SubAck,
+    SubscribeFilter, SubscribeReasonCode, UnsubAck,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Well above anything the devices or the commander publish
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// Where retained messages are kept between runs, next to the config file.
+pub fn retained_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("embedded-broker.json")
+}
+
+/// A retained message as saved; payloads that aren't UTF-8 aren't saved.
+#[derive(Serialize, Deserialize)]
+struct SavedMessage {
+    topic: String,
+    payload: String,
+    qos: u8,
+}
+
+fn load_retained(path: &Path) -> anyhow::Result<BTreeMap<String, Publish>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    };
+    let saved: Vec<SavedMessage> =
+        serde_json::from_str(&text).with_context(|| format!("cannot parse {}", path.display()))?;
+    Ok(saved
+        .into_iter()
+        .map(|message| {
+            let qos = mqttbytes::qos(message.qos).unwrap_or(QoS::AtMostOnce);
+            let mut publish = Publish::new(&message.topic, qos, message.payload);
+            publish.retain = true;
+            (message.topic, publish)
+        })
+        .collect())
+}
+
+fn save_retained(path: &Path, retained: &BTreeMap<String, Publish>) -> anyhow::Result<()> {
+    let saved: Vec<SavedMessage> = retained
+        .values()
+        .filter_map(|publish| {
+            Some(SavedMessage {
+                topic: publish.topic.clone(),
+                payload: String::from_utf8(publish.payload.to_vec()).ok()?,
+                qos: publish.qos as u8,
+            })
+        })
+        .collect();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&saved)?)?;
+    Ok(())
+}
+
+fn lower(a: QoS, b: QoS) -> QoS {
+    if a < b { a } else { b }
+}
+
+struct Session {
+    filters: Vec<(String, QoS)>,
+    outgoing: mpsc::UnboundedSender<Packet>,
+}
+
+#[derive(Default)]
+struct State {
+    next_session: u64,
+    sessions: HashMap<u64, Session>,
+    retained: BTreeMap<String, Publish>,
+    retained_file: Option<PathBuf>,
+}
+
+impl State {
+    fn connect(&mut self, outgoing: mpsc::UnboundedSender<Packet>) -> u64 {
+        self.next_session += 1;
+        self.sessions.insert(
+            self.next_session,
+            Session {
+                filters: Vec::new(),
+                outgoing,
+            },
+        );
+        self.next_session
+    }
+
+    /// Retains `publish` if asked to and hands it to every matching
+    /// subscription, at the lower of the two QoS levels.
+    fn route(&mut self, publish: &Publish) {
+        if publish.retain {
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic);
+            } else {
+                self.retained.insert(publish.topic.clone(), publish.clone());
+            }
+            if let Some(path) = &self.retained_file
+                && let Err(e) = save_retained(path, &self.retained)
+            {
+                warn!("Broker: cannot save retained messages: {:#}", e);
+            }
+        }
+
+        for session in self.sessions.values() {
+            let granted = session
+                .filters
+                .iter()
+                .filter(|(filter, _)| matches(&publish.topic, filter))
+                .map(|(_, qos)| *qos)
+                .reduce(|a, b| if a < b { b } else { a });
+            if let Some(granted) = granted {
+                let mut delivery = Publish::from_bytes(
+                    &publish.topic,
+                    lower(publish.qos, granted),
+                    publish.payload.clone(),
+                );
+                delivery.retain = false;
+                let _ = session.outgoing.send(Packet::Publish(delivery));
+            }
+        }
+    }
+
+    /// Adds the filters and returns the retained messages they match.
+    fn subscribe(&mut self, session: u64, filters: &[SubscribeFilter]) -> Vec<Publish> {
+        let Some(session) = self.sessions.get_mut(&session) else {
+            return Vec::new();
+        };
+        let mut retained = Vec::new();
+        for filter in filters.iter().filter(|f| valid_filter(&f.path)) {
+            let qos = lower(filter.qos, QoS::AtLeastOnce);
+            session.filters.retain(|(path, _)| *path != filter.path);
+            session.filters.push((filter.path.clone(), qos));
+            retained.extend(
+                self.retained
+                    .values()
+                    .filter(|publish| matches(&publish.topic, &filter.path))
+                    .map(|publish| {
+                        let mut delivery = publish.clone();
+                        delivery.qos = lower(publish.qos, qos);
+                        delivery
+                    }),
+            );
+        }
+        retained
+    }
+
+    fn unsubscribe(&mut self, session: u64, topics: &[String]) {
+        if let Some(session) = self.sessions.get_mut(&session) {
+            session.filters.retain(|(path, _)| !topics.contains(path));
+        }
+    }
+}
+
+async fn read_packet(
+    reader: &mut OwnedReadHalf,
+    buffer: &mut BytesMut,
+) -> anyhow::Result<Option<Packet>> {
+    loop {
+        match Packet::read(buffer, MAX_PACKET_SIZE) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => bail!("malformed packet: {:?}", e),
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+fn handle(
+    state: &Mutex<State>,
+    session: u64,
+    packet: Packet,
+    outgoing: &mpsc::UnboundedSender<Packet>,
+) {
+    let reply = |packet| {
+        let _ = outgoing.send(packet);
+    };
+    match packet {
+        Packet::Publish(publish) => {
+            match publish.qos {
+                QoS::AtMostOnce => {}
+                QoS::AtLeastOnce => reply(Packet::PubAck(PubAck::new(publish.pkid))),
+                QoS::ExactlyOnce => reply(Packet::PubRec(PubRec::new(publish.pkid))),
+            }
+            if valid_topic(&publish.topic) {
+                state.lock().unwrap().route(&publish);
+            }
+        }
+        Packet::PubRel(release) => reply(Packet::PubComp(PubComp::new(release.pkid))),
+        Packet::Subscribe(subscribe) => {
+            let retained = state.lock().unwrap().subscribe(session, &subscribe.filters);
+            let codes = subscribe
+                .filters
+                .iter()
+                .map(|f| match valid_filter(&f.path) {
+                    true => SubscribeReasonCode::Success(lower(f.qos, QoS::AtLeastOnce)),
+                    false => SubscribeReasonCode::Failure,
+                })
+                .collect();
+            reply(Packet::SubAck(SubAck::new(subscribe.pkid, codes)));
+            for publish in retained {
+                reply(Packet::Publish(publish));
+            }
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            state
+                .lock()
+                .unwrap()
+                .unsubscribe(session, &unsubscribe.topics);
+            reply(Packet::UnsubAck(UnsubAck::new(unsubscribe.pkid)));
+        }
+        Packet::PingReq => reply(Packet::PingResp),
+        // Acknowledgements of what was sent; delivery is best effort
+        _ => {}
+    }
+}
+
+/// Writes queued packets, numbering publishes that need an answer.
+async fn write_packets(
+    mut writer: tokio::net::tcp::OwnedWriteHalf,
+    mut outgoing: mpsc::UnboundedReceiver<Packet>,
+) -> anyhow::Result<()> {
+    let mut next_pkid: u16 = 0;
+    let mut buffer = BytesMut::new();
+    while let Some(mut packet) = outgoing.recv().await {
+        if let Packet::Publish(publish) = &mut packet
+            && publish.qos != QoS::AtMostOnce
+        {
+            next_pkid = next_pkid.checked_add(1).unwrap_or(1);
+            publish.pkid = next_pkid;
+        }
+        buffer.clear();
+        packet
+            .write(&mut buffer, MAX_PACKET_SIZE)
+            .map_err(|e| anyhow::anyhow!("cannot encode {:?}: {:?}", packet, e))?;
+        writer.write_all(&buffer).await?;
+    }
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    state: Arc<Mutex<State>>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    let mut buffer = BytesMut::with_capacity(4096);
+    let connect = match read_packet(&mut reader, &mut buffer).await? {
+        Some(Packet::Connect(connect)) => connect,
+        Some(packet) => bail!("expected CONNECT, got {:?}", packet),
+        None => return Ok(()),
+    };
+    debug!("Broker: '{}' connected", connect.client_id);
+
+    let (outgoing, queued) = mpsc::unbounded_channel();
+    let session = state.lock().unwrap().connect(outgoing.clone());
+    let _ = outgoing.send(Packet::ConnAck(ConnAck::new(
+        ConnectReturnCode::Success,
+        false,
+    )));
+    let writer = tokio::spawn(write_packets(writer, queued));
+
+    let mut will = connect.last_will;
+    let result = loop {
+        let packet = tokio::select! {
+            _ = shutdown.cancelled() => {
+                will = None;
+                break Ok(());
+            }
+            packet = read_packet(&mut reader, &mut buffer) => packet,
+        };
+        match packet {
+            Ok(Some(Packet::Disconnect)) => {
+                will = None;
+                break Ok(());
+            }
+            Ok(Some(packet)) => handle(&state, session, packet, &outgoing),
+            // Gone without a DISCONNECT, so the will goes out
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    debug!("Broker: '{}' disconnected", connect.client_id);
+
+    {
+        let mut state = state.lock().unwrap();
+        state.sessions.remove(&session);
+        if let Some(will) = will {
+            let mut publish = Publish::from_bytes(will.topic, will.qos, will.message);
+            publish.retain = will.retain;
+            state.route(&publish);
+        }
+    }
+    // The writer stops once everything queued is sent
+    drop(outgoing);
+    let _ = writer.await;
+    result
+}
+
+pub struct EmbeddedBroker {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl EmbeddedBroker {
+    /// Listens on `addr` until `shutdown`. With `retained_file`, retained
+    /// messages are loaded from it and saved to it whenever they change.
+    pub async fn start(addr: SocketAddr, retained_file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("cannot listen on {}", addr))?;
+        let local_addr = listener.local_addr()?;
+        let retained = match &retained_file {
+            Some(path) => load_retained(path)?,
+            None => BTreeMap::new(),
+        };
+        let state = Arc::new(Mutex::new(State {
+            retained,
+            retained_file,
+            ..Default::default()
+        }));
+
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let task = tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => {
+                            let (state, token) = (state.clone(), token.clone());
+                            connections.spawn(async move {
+                                if let Err(e) = serve(stream, state, token).await {
+                                    warn!("Broker: connection from {} failed: {:#}", peer, e);
+                                }
+                            });
+                        }
+                        Err(e) => warn!("Broker: accept failed: {}", e),
+                    },
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                }
+            }
+            // Connections see the same token and close
+            while connections.join_next().await.is_some() {}
+        });
+
+        Ok(Self {
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Closes every connection and waits for them to finish.
+    pub async fn shutdown(self) {
+        self.shutdown.cancel();
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions};
+    use std::time::Duration;
+
+    async fn client(broker: &EmbeddedBroker, id: &str) -> (AsyncClient, EventLoop) {
+        let options = MqttOptions::new(id, "127.0.0.1", broker.local_addr().port());
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        loop {
+            if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await.unwrap() {
+                return (client, eventloop);
+            }
+        }
+    }
+
+    async fn next_publish(eventloop: &mut EventLoop) -> Publish {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    return publish;
+                }
+            }
+        })
+        .await
+        .expect("no publish within 5 s")
+    }
+
+    /// Polls until `client`'s queued requests are out.
+    async fn flush(eventloop: &mut EventLoop) {
+        let _ = tokio::time::timeout(Duration::from_millis(200), async {
+            loop {
+                eventloop.poll().await.unwrap();
+            }
+        })
+        .await;
+    }
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retained_commands_reach_late_subscribers_and_can_be_cleared() {
+        let broker = EmbeddedBroker::start(local(), None).await.unwrap();
+        let (commander, mut commander_loop) = client(&broker, "commander").await;
+        commander
+            .publish(
+                "sensors/esp32/command",
+                QoS::AtLeastOnce,
+                true,
+                "{\"command\":\"noop\"}",
+            )
+            .await
+            .unwrap();
+        flush(&mut commander_loop).await;
+
+        let (device, mut device_loop) = client(&broker, "device").await;
+        device
+            .subscribe("sensors/esp32/command/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let publish = next_publish(&mut device_loop).await;
+        assert_eq!(publish.topic, "sensors/esp32/command");
+        assert_eq!(&publish.payload[..], b"{\"command\":\"noop\"}");
+        assert!(publish.retain);
+
+        // Cleared the way the device does it after running the command
+        device
+            .publish("sensors/esp32/command", QoS::AtLeastOnce, true, Vec::new())
+            .await
+            .unwrap();
+        let cleared = next_publish(&mut device_loop).await;
+        assert!(cleared.payload.is_empty());
+        let (late, mut late_loop) = client(&broker, "late").await;
+        late.subscribe("sensors/esp32/command", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let nothing =
+            tokio::time::timeout(Duration::from_millis(300), next_publish(&mut late_loop)).await;
+        assert!(nothing.is_err());
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn retained_messages_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("commander-broker-{}", std::process::id()));
+        let path = retained_path(&dir.join("commander.env"));
+
+        let broker = EmbeddedBroker::start(local(), Some(path.clone()))
+            .await
+            .unwrap();
+        let (commander, mut commander_loop) = client(&broker, "commander").await;
+        commander
+            .publish(
+                "sensors/esp32/command/kitchen",
+                QoS::AtLeastOnce,
+                true,
+                "{}",
+            )
+            .await
+            .unwrap();
+        flush(&mut commander_loop).await;
+        broker.shutdown().await;
+
+        let broker = EmbeddedBroker::start(local(), Some(path)).await.unwrap();
+        let (device, mut device_loop) = client(&broker, "kitchen").await;
+        device
+            .subscribe("sensors/esp32/command/kitchen", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        assert_eq!(&next_publish(&mut device_loop).await.payload[..], b"{}");
+        broker.shutdown().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn last_will_goes_out_when_a_client_vanishes() {
+        let broker = EmbeddedBroker::start(local(), None).await.unwrap();
+        let (watcher, mut watcher_loop) = client(&broker, "watcher").await;
+        watcher
+            .subscribe("sensors/+/status", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        flush(&mut watcher_loop).await;
+
+        let mut options = MqttOptions::new("device", "127.0.0.1", broker.local_addr().port());
+        options.set_last_will(rumqttc::LastWill::new(
+            "sensors/device/status",
+            "offline",
+            QoS::AtMostOnce,
+            false,
+        ));
+        let (device, mut device_loop) = AsyncClient::new(options, 10);
+        flush(&mut device_loop).await;
+        // Dropped without a DISCONNECT
+        drop((device, device_loop));
+
+        let will = next_publish(&mut watcher_loop).await;
+        assert_eq!(will.topic, "sensors/device/status");
+        assert_eq!(&will.payload[..], b"offline");
+        broker.shutdown().await;
+    }
+}
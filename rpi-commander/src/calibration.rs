@@ -0,0 +1,437 @@
+//! `calibrate`: a forced recalibration walked through step by step, ending
+//! in a record of what was done.
+//!
+//! Every step that touches the device runs the console's own commands
+//! through `command_line::execute`, so it publishes, checks pending commands
+//! and prints exactly like the typed command: `get-offset` and `last` show
+//! where the device stands, the reference is checked like `frc`'s argument,
+//! and with InfluxDB configured the last minutes of CO2 are checked for air
+//! that hasn't settled. Once `start_frc` is sent the calibration is appended
+//! to `calibrations.json` next to the config, and a summary is printed for
+//! the operator's notes. Cancelling a prompt stops before the FRC is sent.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Local};
+use serde::{Deserialize, Serialize};
+use shared_types::{DeviceCommand, DevicePayload};
+
+use crate::command_line::{self, CommandContext, DEFAULT_FRC_PPM, ParseError, ParsedCommand};
+use crate::setup::{self, InfluxSettings};
+
+const MEASUREMENT_TABLE: &str = "scd40_data";
+
+/// How far back the stability check looks
+pub const STABILITY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Fewer readings than this in the window say nothing about the air
+const MIN_SAMPLES: usize = 3;
+
+/// Above this the air is still changing, and an FRC would calibrate to a
+/// level that is already gone
+const MAX_STD_DEV_PPM: f64 = 20.0;
+
+/// How CO2 moved over `STABILITY_WINDOW`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stability {
+    pub samples: usize,
+    pub mean_ppm: f64,
+    pub std_dev_ppm: f64,
+    pub min_ppm: f64,
+    pub max_ppm: f64,
+}
+
+impl Stability {
+    /// `None` for fewer than `MIN_SAMPLES` readings
+    pub fn of(co2: &[f64]) -> Option<Self> {
+        if co2.len() < MIN_SAMPLES {
+            return None;
+        }
+        let samples = co2.len();
+        let mean_ppm = co2.iter().sum::<f64>() / samples as f64;
+        let variance = co2.iter().map(|ppm| (ppm - mean_ppm).powi(2)).sum::<f64>() / samples as f64;
+        Some(Self {
+            samples,
+            mean_ppm,
+            std_dev_ppm: variance.sqrt(),
+            min_ppm: co2.iter().copied().fold(f64::INFINITY, f64::min),
+            max_ppm: co2.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        })
+    }
+
+    pub fn is_unstable(&self) -> bool {
+        self.std_dev_ppm > MAX_STD_DEV_PPM
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{:.1} ppm standard deviation over {} readings, {:.0} to {:.0} ppm",
+            self.std_dev_ppm, self.samples, self.min_ppm, self.max_ppm
+        )
+    }
+}
+
+/// One calibration as saved in `calibrations.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationRecord {
+    pub device: String,
+    /// When `start_frc` was sent
+    pub at: DateTime<FixedOffset>,
+    pub reference_ppm: u16,
+    /// The newest reading heard from the device before the FRC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_co2_ppm: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_measured_at: Option<DateTime<FixedOffset>>,
+    /// `None` when it couldn't be checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stability: Option<Stability>,
+    /// The operator went ahead although the air hadn't settled
+    #[serde(default)]
+    pub unstable_ignored: bool,
+}
+
+impl CalibrationRecord {
+    /// A markdown list for pasting into notes
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "Calibrated {} on {}",
+                self.device,
+                self.at.format("%Y-%m-%d %H:%M %:z")
+            ),
+            format!(
+                "- Reference: {} ppm, forced recalibration",
+                self.reference_ppm
+            ),
+        ];
+        match (self.last_co2_ppm, self.last_measured_at) {
+            (Some(co2), Some(at)) => lines.push(format!(
+                "- Last reading before: {} ppm at {}",
+                co2,
+                at.format("%H:%M:%S")
+            )),
+            _ => lines.push("- Last reading before: none heard".to_string()),
+        }
+        match &self.stability {
+            Some(stability) => lines.push(format!(
+                "- Air over the last {} min: {}",
+                STABILITY_WINDOW.as_secs() / 60,
+                stability.describe()
+            )),
+            None => lines.push("- Air stability: not checked".to_string()),
+        }
+        if self.unstable_ignored {
+            lines.push("- Calibrated although the air hadn't settled".to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+/// `calibrations.json` next to the config file
+pub fn log_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("calibrations.json")
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Vec<CalibrationRecord>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    };
+    serde_json::from_str(&text).with_context(|| format!("cannot parse {}", path.display()))
+}
+
+/// Adds `record` after the calibrations already saved in `path`
+pub fn append(path: &Path, record: &CalibrationRecord) -> anyhow::Result<()> {
+    let mut records = load(path)?;
+    records.push(record.clone());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&records)?)
+        .with_context(|| format!("cannot write {}", path.display()))?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Co2Row {
+    co2_ppm: f64,
+}
+
+/// The CO2 readings `device` has stored from the last `window`, oldest first
+pub async fn recent_co2(
+    reqwest_client: &reqwest::Client,
+    settings: &InfluxSettings,
+    device: &str,
+    window: Duration,
+) -> anyhow::Result<Vec<f64>> {
+    let sql = format!(
+        "SELECT co2_ppm FROM {} WHERE device = {} AND time > now() - INTERVAL '{} seconds' \
+         ORDER BY time",
+        MEASUREMENT_TABLE,
+        setup::sql_string(device),
+        window.as_secs()
+    );
+    let rows = setup::query_influx::<Co2Row>(reqwest_client, settings, &sql).await?;
+    Ok(rows.iter().map(|row| row.co2_ppm).collect())
+}
+
+/// Runs `command` like a typed line, ending in `--takeover` if the
+/// `calibrate` line did
+fn send(
+    ctx: &mut impl CommandContext,
+    command: ParsedCommand,
+    takeover: bool,
+) -> anyhow::Result<()> {
+    let command = if takeover {
+        ParsedCommand::Takeover(Box::new(command))
+    } else {
+        command
+    };
+    command_line::execute(command, ctx)?;
+    Ok(())
+}
+
+fn confirmed(ctx: &mut impl CommandContext, prompt: &str) -> bool {
+    ctx.ask(prompt)
+        .is_some_and(|answer| matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Asks until the answer is a target `frc` accepts; `None` once cancelled
+fn ask_reference(ctx: &mut impl CommandContext) -> Option<u16> {
+    let prompt = format!(
+        "Outdoor CO2 to calibrate to, in ppm [{}]: ",
+        DEFAULT_FRC_PPM
+    );
+    loop {
+        let answer = ctx.ask(&prompt)?;
+        match command_line::parse_command(&format!("frc {}", answer.trim())) {
+            Ok(ParsedCommand::Send(DeviceCommand::StartFrc { target_ppm })) => {
+                return Some(target_ppm);
+            }
+            Ok(_) | Err(ParseError::Usage(_)) => ctx.print("Give the reference as one number\n"),
+            Err(e) => ctx.print(&format!("{}\n", e)),
+        }
+    }
+}
+
+/// Runs the workflow for the current device. Returns once it's done or
+/// cancelled; errors from sending stop it where they happen.
+pub fn run(ctx: &mut impl CommandContext, takeover: bool) -> anyhow::Result<()> {
+    let device = ctx.device().to_string();
+    ctx.print(&format!(
+        "Calibrating '{}'. Cancel any prompt to stop before the sensor is calibrated.\n",
+        device
+    ));
+
+    ctx.print("Current state:");
+    send(
+        ctx,
+        ParsedCommand::Send(DeviceCommand::GetTempOffset),
+        takeover,
+    )?;
+    command_line::execute(ParsedCommand::Last, ctx)?;
+    let last = ctx.last_measurement().and_then(|(message, at)| {
+        let DevicePayload::MeasurementSuccess { co2, .. } = message.payload else {
+            return None;
+        };
+        Some((co2, at))
+    });
+
+    let Some(reference_ppm) = ask_reference(ctx) else {
+        ctx.print("Calibration cancelled\n");
+        return Ok(());
+    };
+
+    let stability = match ctx.recent_co2(STABILITY_WINDOW) {
+        Ok(None) => {
+            ctx.print("InfluxDB isn't configured, so the air's stability isn't checked\n");
+            None
+        }
+        Ok(Some(co2)) => {
+            let stability = Stability::of(&co2);
+            if stability.is_none() {
+                ctx.print(&format!(
+                    "Only {} readings in the last {} min, too few to tell whether the air is stable\n",
+                    co2.len(),
+                    STABILITY_WINDOW.as_secs() / 60
+                ));
+            }
+            stability
+        }
+        Err(e) => {
+            ctx.print(&format!("Couldn't check the air's stability: {:#}\n", e));
+            None
+        }
+    };
+    let unstable_ignored = match stability {
+        Some(stability) if stability.is_unstable() => {
+            let warning = format!(
+                "CO2 is still changing: {}. Calibrating now sets the sensor to air that's about to change.",
+                stability.describe()
+            );
+            ctx.print(&ctx.prefs().text_renderer().warning(&warning));
+            if !confirmed(ctx, "Calibrate anyway? [y/N] ") {
+                ctx.print("Calibration cancelled\n");
+                return Ok(());
+            }
+            true
+        }
+        Some(stability) => {
+            ctx.print(&format!("CO2 has settled: {}\n", stability.describe()));
+            false
+        }
+        None => false,
+    };
+
+    let prompt = format!(
+        "Calibrate '{}' to {} ppm? It must have been in that air for 3 minutes or more. [y/N] ",
+        device, reference_ppm
+    );
+    if !confirmed(ctx, &prompt) {
+        ctx.print("Calibration cancelled\n");
+        return Ok(());
+    }
+    let frc = DeviceCommand::start_frc(reference_ppm)
+        .map_err(|_| anyhow::anyhow!("{} ppm isn't a valid reference", reference_ppm))?;
+    send(ctx, ParsedCommand::Send(frc), takeover)?;
+
+    let record = CalibrationRecord {
+        device,
+        at: Local::now().fixed_offset(),
+        reference_ppm,
+        last_co2_ppm: last.map(|(co2, _)| co2),
+        last_measured_at: last.map(|(_, at)| at),
+        stability,
+        unstable_ignored,
+    };
+    let path = ctx.save_calibration(&record)?;
+    ctx.print(&format!(
+        "\n{}\n\nSaved to {}. The device reports the correction on its next wake.\n",
+        record.summary(),
+        path.display()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn record() -> CalibrationRecord {
+        CalibrationRecord {
+            device: "kitchen".to_string(),
+            at: DateTime::parse_from_rfc3339("2025-01-15T14:05:09+01:00").unwrap(),
+            reference_ppm: 420,
+            last_co2_ppm: Some(455),
+            last_measured_at: Some(
+                DateTime::parse_from_rfc3339("2025-01-15T14:01:30+01:00").unwrap(),
+            ),
+            stability: Stability::of(&[418.0, 422.0, 420.0, 424.0]),
+            unstable_ignored: false,
+        }
+    }
+
+    #[test]
+    fn stability_needs_a_few_readings() {
+        assert_eq!(Stability::of(&[420.0, 421.0]), None);
+        let settled = Stability::of(&[418.0, 422.0, 420.0, 424.0]).unwrap();
+        assert_eq!(settled.samples, 4);
+        assert_eq!(settled.mean_ppm, 421.0);
+        assert_eq!((settled.min_ppm, settled.max_ppm), (418.0, 424.0));
+        assert!(!settled.is_unstable());
+        // A window opened mid-measurement
+        assert!(
+            Stability::of(&[900.0, 700.0, 550.0, 460.0])
+                .unwrap()
+                .is_unstable()
+        );
+    }
+
+    #[test]
+    fn summary_reads_as_notes() {
+        assert_eq!(
+            record().summary(),
+            "Calibrated kitchen on 2025-01-15 14:05 +01:00\n\
+             - Reference: 420 ppm, forced recalibration\n\
+             - Last reading before: 455 ppm at 14:01:30\n\
+             - Air over the last 10 min: 2.2 ppm standard deviation over 4 readings, 418 to 424 ppm"
+        );
+        let unchecked = CalibrationRecord {
+            last_co2_ppm: None,
+            last_measured_at: None,
+            stability: None,
+            unstable_ignored: true,
+            ..record()
+        };
+        assert!(unchecked.summary().ends_with(
+            "- Last reading before: none heard\n\
+             - Air stability: not checked\n\
+             - Calibrated although the air hadn't settled"
+        ));
+    }
+
+    #[test]
+    fn records_are_appended() {
+        let dir =
+            std::env::temp_dir().join(format!("commander-calibration-{}", std::process::id()));
+        let path = log_path(&dir.join("commander.env"));
+        assert_eq!(load(&path).unwrap(), []);
+
+        let second = CalibrationRecord {
+            reference_ppm: 425,
+            stability: None,
+            ..record()
+        };
+        append(&path, &record()).unwrap();
+        append(&path, &second).unwrap();
+        assert_eq!(load(&path).unwrap(), [record(), second]);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(append(&path, &record()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recent_co2_asks_for_the_device_window() {
+        use axum::{Json, Router, routing::post};
+        use std::sync::{Arc, Mutex};
+
+        let asked = Arc::new(Mutex::new(String::new()));
+        let app = Router::new().route(
+            "/api/v3/query_sql",
+            post({
+                let asked = asked.clone();
+                move |Json(body): Json<serde_json::Value>| async move {
+                    *asked.lock().unwrap() = body["q"].as_str().unwrap().to_string();
+                    r#"[{"co2_ppm":431.0},{"co2_ppm":428}]"#
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let settings = InfluxSettings {
+            url: format!("http://{}", addr),
+            token: "token".to_string(),
+            database: "air_quality".to_string(),
+        };
+
+        let co2 = recent_co2(
+            &reqwest::Client::new(),
+            &settings,
+            "kid's room",
+            STABILITY_WINDOW,
+        )
+        .await
+        .unwrap();
+        assert_eq!(co2, [431.0, 428.0]);
+        let sql = asked.lock().unwrap().clone();
+        assert!(sql.contains("device = 'kid''s room'"), "{}", sql);
+        assert!(sql.contains("INTERVAL '600 seconds'"), "{}", sql);
+    }
+}
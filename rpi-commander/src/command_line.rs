@@ -0,0 +1,2353 @@
+//! The console's commands: parsing a typed line into a `ParsedCommand`,
+//! running it against a `CommandContext`, and the `help` listing.
+//!
+//! All three come from `COMMANDS`, one row per command word, so a new
+//! command is parsed, listed and documented in one place. The bounds that
+//! `help <command>` shows for an argument are the ones its parser checks. Parsing
+//! has no side effects; everything the commander does goes through
+//! `CommandContext`, which tests replace with a mock.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+use shared_types::fault_injection::FaultKind;
+use shared_types::log_level::LogLevel;
+use shared_types::mqtt_policy::PayloadClass;
+use shared_types::{
+    DeviceCommand, DeviceCommandBatch, DeviceMessage, MAX_ALTITUDE_M, MAX_AMBIENT_PRESSURE_PA,
+    MAX_BATCH_COMMANDS, MAX_DEEP_SLEEP_SECONDS, MAX_FRC_TARGET_PPM, MAX_TEMP_OFFSET_C,
+    MIN_ALTITUDE_M, MIN_AMBIENT_PRESSURE_PA, MIN_DEEP_SLEEP_SECONDS, MIN_FRC_TARGET_PPM,
+    MIN_TEMP_OFFSET_C,
+};
+
+use crate::calibration::{self, CalibrationRecord};
+use crate::fleet::{self, FleetOperation};
+use crate::render::{DisplayPrefs, OutputMode, UnitSystem};
+
+/// `frc` without a target
+pub const DEFAULT_FRC_PPM: u16 = 422;
+/// `devices watch` without an interval
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Usage lines fit before the description up to this width
+const USAGE_WIDTH: usize = 31;
+
+/// Ends a line that may replace another operator's pending command
+pub const TAKEOVER_FLAG: &str = "--takeover";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParsedCommand {
+    /// An empty line
+    Blank,
+    /// The listing, or one command's details by its first name
+    Help(Option<String>),
+    Exit,
+    Status,
+    /// `None` shows the current setting
+    Units(Option<UnitSystem>),
+    Output(Option<OutputMode>),
+    /// `None` asks which of the devices heard from
+    Device(Option<String>),
+    Devices,
+    /// Redraws the device table until Ctrl-C, which only the prompt loop
+    /// can wait for
+    DevicesWatch(Duration),
+    /// The current device's newest measurement heard in this session
+    Last,
+    /// A command for the current device
+    Send(DeviceCommand),
+    /// `factory_reset` for the current device, once its name is typed
+    FactoryReset,
+    /// `reboot` for the current device, once confirmed
+    Reboot,
+    /// The guided forced recalibration, see `calibration`
+    Calibrate,
+    /// Collect the device commands typed next instead of sending them
+    BatchStart,
+    /// The commands collected so far
+    BatchShow,
+    /// Send the collected commands together, for one wake
+    BatchSend,
+    BatchCancel,
+    FleetOta {
+        url: String,
+        group: Option<String>,
+    },
+    FleetStatus,
+    /// The commands retained for the devices and who sent them
+    Pending,
+    /// A command that sends, allowed to replace a command another
+    /// operator left pending (see `pending`)
+    Takeover(Box<ParsedCommand>),
+    TranscriptStart(PathBuf),
+    TranscriptStop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Unknown(String),
+    /// Missing or extra arguments, with the command's usage
+    Usage(String),
+    /// An argument that doesn't parse, with why
+    Invalid(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Unknown(word) => write!(
+                f,
+                "Unknown command: '{}'. Type 'help' for available commands.",
+                word
+            ),
+            ParseError::Usage(usage) => write!(f, "Usage: {}", usage),
+            ParseError::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Where `help` lists a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Sent to the current device
+    Device,
+    Fleet,
+    /// Choosing and watching devices
+    Devices,
+    /// The commander itself
+    Console,
+}
+
+impl Category {
+    pub const ALL: [Category; 4] = [
+        Category::Device,
+        Category::Fleet,
+        Category::Devices,
+        Category::Console,
+    ];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Category::Device => "Device commands (sent to the current device)",
+            Category::Fleet => "Fleet",
+            Category::Devices => "Devices",
+            Category::Console => "Console",
+        }
+    }
+}
+
+/// What an argument accepts. The parsers validate with it, so `help`
+/// shows the bounds that actually apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Values {
+    /// A whole number from `min` to `max`
+    Integer {
+        min: u64,
+        max: u64,
+    },
+    Decimal {
+        min: f32,
+        max: f32,
+    },
+    /// One of these words
+    OneOf(&'static [&'static str]),
+    /// Any single word, e.g. a name or a URL
+    Word,
+}
+
+impl fmt::Display for Values {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Values::Integer { min, max: u64::MAX } => write!(f, "a whole number, {} or more", min),
+            Values::Integer { min, max } => write!(f, "a whole number from {} to {}", min, max),
+            Values::Decimal { min, max } => write!(f, "a decimal number from {} to {}", min, max),
+            Values::OneOf(words) => write!(f, "one of {}", words.join(", ")),
+            Values::Word => f.write_str("any word"),
+        }
+    }
+}
+
+pub struct Arg {
+    /// As written in the usage, without the brackets
+    pub name: &'static str,
+    pub values: Values,
+    /// What a left-out argument stands for
+    pub default: Option<&'static str>,
+}
+
+impl Arg {
+    fn invalid(&self) -> ParseError {
+        ParseError::Invalid(format!("Invalid {}. Must be {}.", self.name, self.values))
+    }
+
+    /// `word` as a whole number within the bounds
+    fn integer<T: TryFrom<u64>>(&self, word: &str) -> Result<T, ParseError> {
+        let Values::Integer { min, max } = self.values else {
+            unreachable!("'{}' isn't a whole number", self.name);
+        };
+        word.parse::<u64>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .and_then(|value| T::try_from(value).ok())
+            .ok_or_else(|| self.invalid())
+    }
+
+    /// `word` as a number within the bounds
+    fn decimal(&self, word: &str) -> Result<f32, ParseError> {
+        let Values::Decimal { min, max } = self.values else {
+            unreachable!("'{}' isn't a decimal number", self.name);
+        };
+        word.parse::<f32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| self.invalid())
+    }
+}
+
+/// One way of typing a command, as `help` lists it
+pub struct Form {
+    pub usage: &'static str,
+    /// The first line is the summary `help` lists, the rest are details
+    /// for `help <command>`
+    pub description: &'static [&'static str],
+}
+
+pub struct CommandSpec {
+    /// The word that starts the command, then its aliases
+    pub names: &'static [&'static str],
+    pub category: Category,
+    pub forms: &'static [Form],
+    /// Every argument named in the forms
+    pub args: &'static [Arg],
+    /// Lines that parse, shown by `help <command>`
+    pub examples: &'static [&'static str],
+    /// Parses the words after the command's name
+    parse: fn(&CommandSpec, &[&str]) -> Result<ParsedCommand, ParseError>,
+}
+
+impl CommandSpec {
+    /// All forms, for usage errors
+    pub fn usage(&self) -> String {
+        self.forms
+            .iter()
+            .map(|form| form.usage)
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    fn usage_error(&self) -> ParseError {
+        ParseError::Usage(self.usage())
+    }
+
+    /// For commands without arguments
+    fn exactly(&self, args: &[&str], command: ParsedCommand) -> Result<ParsedCommand, ParseError> {
+        if args.is_empty() {
+            Ok(command)
+        } else {
+            Err(self.usage_error())
+        }
+    }
+
+    fn arg(&self, name: &str) -> &Arg {
+        self.args
+            .iter()
+            .find(|arg| arg.name == name)
+            .unwrap_or_else(|| panic!("'{}' has no argument '{}'", self.names[0], name))
+    }
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        names: &["noop"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "noop",
+            description: &["Send a no-op command (testing)"],
+        }],
+        args: &[],
+        examples: &["noop"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::NoOp)),
+    },
+    CommandSpec {
+        names: &["frc"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "frc [ppm]",
+            description: &[
+                "Start forced recalibration",
+                "Run it after a few minutes in fresh air, giving",
+                "the outdoor CO2 level as the target",
+            ],
+        }],
+        args: &[Arg {
+            name: "ppm",
+            values: Values::Integer {
+                min: MIN_FRC_TARGET_PPM as u64,
+                max: MAX_FRC_TARGET_PPM as u64,
+            },
+            default: Some("422"),
+        }],
+        examples: &["frc", "frc 420"],
+        parse: |spec, args| {
+            let target_ppm = match args {
+                [] => DEFAULT_FRC_PPM,
+                [ppm] => spec.arg("ppm").integer(ppm)?,
+                _ => return Err(spec.usage_error()),
+            };
+            DeviceCommand::start_frc(target_ppm)
+                .map(ParsedCommand::Send)
+                .map_err(|_| spec.arg("ppm").invalid())
+        },
+    },
+    CommandSpec {
+        names: &["calibrate"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "calibrate",
+            description: &[
+                "Walk through a forced recalibration",
+                "Shows the offset and last reading, asks for the",
+                "reference, checks the air has settled, sends the",
+                "FRC and saves a record next to the config",
+            ],
+        }],
+        args: &[],
+        examples: &["calibrate"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Calibrate),
+    },
+    CommandSpec {
+        names: &["set-offset"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "set-offset <value> [--volatile]",
+            description: &[
+                "Set temperature offset in °C",
+                "--volatile doesn't save it to the sensor's EEPROM",
+            ],
+        }],
+        args: &[Arg {
+            name: "value",
+            values: Values::Decimal {
+                min: MIN_TEMP_OFFSET_C,
+                max: MAX_TEMP_OFFSET_C,
+            },
+            default: None,
+        }],
+        examples: &["set-offset 1.5", "set-offset 2 --volatile"],
+        parse: |spec, args| {
+            let (value, persist) = match args {
+                [value] => (value, true),
+                [value, "--volatile"] => (value, false),
+                _ => return Err(spec.usage_error()),
+            };
+            let offset = spec.arg("value").decimal(value)?;
+            let mut command =
+                DeviceCommand::set_temp_offset(offset).map_err(|_| spec.arg("value").invalid())?;
+            if let DeviceCommand::SetTempOffset { persist: saved, .. } = &mut command {
+                *saved = persist;
+            }
+            Ok(ParsedCommand::Send(command))
+        },
+    },
+    CommandSpec {
+        names: &["get-offset"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "get-offset",
+            description: &["Get current temperature offset"],
+        }],
+        args: &[],
+        examples: &["get-offset"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetTempOffset)),
+    },
+    CommandSpec {
+        names: &["set-sleep"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "set-sleep <seconds>",
+            description: &[
+                "Set deep sleep time",
+                "Saved once confirmed, which this session does as soon",
+                "as the device answers with the new time in use",
+            ],
+        }],
+        args: &[Arg {
+            name: "seconds",
+            values: Values::Integer {
+                min: MIN_DEEP_SLEEP_SECONDS,
+                max: MAX_DEEP_SLEEP_SECONDS,
+            },
+            default: None,
+        }],
+        examples: &["set-sleep 600"],
+        parse: |spec, args| {
+            let [seconds] = args else {
+                return Err(spec.usage_error());
+            };
+            let seconds = spec.arg("seconds").integer(seconds)?;
+            DeviceCommand::set_deep_sleep_time(seconds)
+                .map(ParsedCommand::Send)
+                .map_err(|_| spec.arg("seconds").invalid())
+        },
+    },
+    CommandSpec {
+        names: &["get-sleep"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "get-sleep",
+            description: &["Get deep sleep time"],
+        }],
+        args: &[],
+        examples: &["get-sleep"],
+        parse: |spec, args| {
+            spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetDeepSleepTime))
+        },
+    },
+    CommandSpec {
+        names: &["config"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "config",
+            description: &["Show the device's configuration"],
+        }],
+        args: &[],
+        examples: &["config"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetConfig)),
+    },
+    CommandSpec {
+        names: &["set-mqtt-policy"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "set-mqtt-policy <class> <qos> [retain]",
+            description: &[
+                "Set publish QoS/retain for a payload class",
+                "retain keeps the class's last message on the broker",
+                "Saved once confirmed, like set-sleep",
+            ],
+        }],
+        args: &[
+            Arg {
+                name: "class",
+                values: Values::OneOf(&[
+                    "measurement",
+                    "error",
+                    "calibration",
+                    "command_response",
+                    "diagnostic",
+                ]),
+                default: None,
+            },
+            Arg {
+                name: "qos",
+                values: Values::Integer { min: 0, max: 2 },
+                default: None,
+            },
+        ],
+        examples: &[
+            "set-mqtt-policy measurement 1",
+            "set-mqtt-policy error 2 retain",
+        ],
+        parse: |spec, args| {
+            let (class, qos, retain) = match args {
+                [class, qos] => (class, qos, false),
+                [class, qos, "retain"] => (class, qos, true),
+                _ => return Err(spec.usage_error()),
+            };
+            let class = class
+                .parse::<PayloadClass>()
+                .map_err(|_| spec.arg("class").invalid())?;
+            let qos = spec.arg("qos").integer(qos)?;
+            Ok(ParsedCommand::Send(DeviceCommand::SetMqttPolicy {
+                class,
+                qos,
+                retain,
+            }))
+        },
+    },
+    CommandSpec {
+        names: &["confirm-config"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "confirm-config <id>",
+            description: &[
+                "Save a setting the device is trying out",
+                "For trials of commands sent from elsewhere; without",
+                "a confirmation the device rolls the setting back",
+            ],
+        }],
+        args: &[Arg {
+            name: "id",
+            values: Values::Integer {
+                min: 0,
+                max: u32::MAX as u64,
+            },
+            default: None,
+        }],
+        examples: &["confirm-config 41"],
+        parse: |spec, args| {
+            let [id] = args else {
+                return Err(spec.usage_error());
+            };
+            let pending_id = spec.arg("id").integer(id)?;
+            Ok(ParsedCommand::Send(DeviceCommand::ConfirmConfig {
+                pending_id,
+            }))
+        },
+    },
+    CommandSpec {
+        names: &["log-level"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "log-level [level]",
+            description: &[
+                "Show or set the firmware log level",
+                "At debug and up errors come with recent log lines",
+            ],
+        }],
+        args: &[Arg {
+            name: "level",
+            values: Values::OneOf(&["error", "warn", "info", "debug", "verbose"]),
+            default: None,
+        }],
+        examples: &["log-level", "log-level debug"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Send(DeviceCommand::GetLogLevel)),
+            [level] => match level.parse::<LogLevel>() {
+                Ok(level) => Ok(ParsedCommand::Send(DeviceCommand::SetLogLevel { level })),
+                Err(_) => Err(spec.arg("level").invalid()),
+            },
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["adaptive"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "adaptive <on|off>",
+            description: &[
+                "Wake sooner while CO2 changes quickly",
+                "Never later than the deep sleep time",
+            ],
+        }],
+        args: &[],
+        examples: &["adaptive on", "adaptive off"],
+        parse: |spec, args| match args {
+            [state] => match *state {
+                "on" => Ok(ParsedCommand::Send(DeviceCommand::SetAdaptiveMode {
+                    enabled: true,
+                })),
+                "off" => Ok(ParsedCommand::Send(DeviceCommand::SetAdaptiveMode {
+                    enabled: false,
+                })),
+                _ => Err(spec.usage_error()),
+            },
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["asc"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "asc <on|off>",
+            description: &[
+                "Switch the sensor's automatic self-calibration",
+                "Turn it off in rooms that never reach 400 ppm,",
+                "where it fights forced recalibration",
+            ],
+        }],
+        args: &[],
+        examples: &["asc off", "asc on"],
+        parse: |spec, args| match args {
+            [state] => match *state {
+                "on" => Ok(ParsedCommand::Send(DeviceCommand::SetAsc { enabled: true })),
+                "off" => Ok(ParsedCommand::Send(DeviceCommand::SetAsc {
+                    enabled: false,
+                })),
+                _ => Err(spec.usage_error()),
+            },
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["get-asc"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "get-asc",
+            description: &["Get whether automatic self-calibration is on"],
+        }],
+        args: &[],
+        examples: &["get-asc"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetAsc)),
+    },
+    CommandSpec {
+        names: &["set-altitude"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "set-altitude <meters>",
+            description: &[
+                "Set the altitude the sensor compensates CO2 for",
+                "Kept across wakes; ignored while an ambient pressure is set",
+            ],
+        }],
+        args: &[Arg {
+            name: "meters",
+            values: Values::Integer {
+                min: MIN_ALTITUDE_M as u64,
+                max: MAX_ALTITUDE_M as u64,
+            },
+            default: None,
+        }],
+        examples: &["set-altitude 600"],
+        parse: |spec, args| {
+            let [meters] = args else {
+                return Err(spec.usage_error());
+            };
+            let meters = spec.arg("meters").integer(meters)?;
+            DeviceCommand::set_altitude(meters)
+                .map(ParsedCommand::Send)
+                .map_err(|_| spec.arg("meters").invalid())
+        },
+    },
+    CommandSpec {
+        names: &["get-altitude"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "get-altitude",
+            description: &["Get the altitude the sensor compensates for"],
+        }],
+        args: &[],
+        examples: &["get-altitude"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetAltitude)),
+    },
+    CommandSpec {
+        names: &["set-pressure"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "set-pressure <pascals>",
+            description: &[
+                "Set the ambient pressure the sensor compensates CO2 for",
+                "Takes precedence over the altitude; kept across wakes",
+            ],
+        }],
+        args: &[Arg {
+            name: "pascals",
+            values: Values::Integer {
+                min: MIN_AMBIENT_PRESSURE_PA as u64,
+                max: MAX_AMBIENT_PRESSURE_PA as u64,
+            },
+            default: None,
+        }],
+        examples: &["set-pressure 94200"],
+        parse: |spec, args| {
+            let [pascals] = args else {
+                return Err(spec.usage_error());
+            };
+            let pascals = spec.arg("pascals").integer(pascals)?;
+            DeviceCommand::set_ambient_pressure(pascals)
+                .map(ParsedCommand::Send)
+                .map_err(|_| spec.arg("pascals").invalid())
+        },
+    },
+    CommandSpec {
+        names: &["self-test"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "self-test",
+            description: &[
+                "Run the sensor's built-in self test",
+                "Takes about 10 seconds; the result is stored",
+            ],
+        }],
+        args: &[],
+        examples: &["self-test"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::SelfTest)),
+    },
+    CommandSpec {
+        names: &["serial"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "serial",
+            description: &[
+                "Get the sensor's serial number",
+                "Identifies the physical module, for inventory",
+            ],
+        }],
+        args: &[],
+        examples: &["serial"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetSerialNumber)),
+    },
+    CommandSpec {
+        names: &["firmware"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "firmware",
+            description: &[
+                "Get the firmware version the device runs",
+                "With its build time and ESP-IDF version",
+            ],
+        }],
+        args: &[],
+        examples: &["firmware"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Send(DeviceCommand::GetFirmwareInfo)),
+    },
+    CommandSpec {
+        names: &["inject-fault"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "inject-fault <kind>",
+            description: &[
+                "Make the next wake fail on purpose, once",
+                "Only debug firmware builds act on it; what",
+                "the wake sends is marked injected",
+            ],
+        }],
+        args: &[Arg {
+            name: "kind",
+            values: Values::OneOf(&[
+                "sensor_timeout",
+                "co2_zero",
+                "co2_high",
+                "flat_humidity",
+                "delayed_publish",
+                "panic",
+            ]),
+            default: None,
+        }],
+        examples: &["inject-fault co2_zero", "inject-fault panic"],
+        parse: |spec, args| {
+            let [kind] = args else {
+                return Err(spec.usage_error());
+            };
+            match kind.parse::<FaultKind>() {
+                Ok(kind) => Ok(ParsedCommand::Send(DeviceCommand::InjectFault { kind })),
+                Err(_) => Err(spec.arg("kind").invalid()),
+            }
+        },
+    },
+    CommandSpec {
+        names: &["factory-reset"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "factory-reset",
+            description: &[
+                "Reset the sensor to its factory settings",
+                "Drops its calibration; asks for the device name",
+                "first, and only that device runs it",
+            ],
+        }],
+        args: &[],
+        examples: &["factory-reset"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::FactoryReset),
+    },
+    CommandSpec {
+        names: &["reboot"],
+        category: Category::Device,
+        forms: &[Form {
+            usage: "reboot",
+            description: &[
+                "Restart the device instead of letting it sleep",
+                "Clears a stuck I2C bus like a power cycle; asks",
+                "for confirmation first",
+            ],
+        }],
+        args: &[],
+        examples: &["reboot"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Reboot),
+    },
+    CommandSpec {
+        names: &["batch"],
+        category: Category::Device,
+        forms: &[
+            Form {
+                usage: "batch start",
+                description: &[
+                    "Collect device commands to send together",
+                    "The device runs them in order in one wake instead",
+                    "of one per wake; commands that must run alone, like",
+                    "frc, still leave the rest for the next wake",
+                ],
+            },
+            Form {
+                usage: "batch",
+                description: &["Show the commands collected"],
+            },
+            Form {
+                usage: "batch send",
+                description: &["Send the collected commands"],
+            },
+            Form {
+                usage: "batch cancel",
+                description: &["Drop the collected commands"],
+            },
+        ],
+        args: &[],
+        examples: &["batch start", "batch", "batch send", "batch cancel"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::BatchShow),
+            ["start"] => Ok(ParsedCommand::BatchStart),
+            ["send"] => Ok(ParsedCommand::BatchSend),
+            ["cancel"] => Ok(ParsedCommand::BatchCancel),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["fleet"],
+        category: Category::Fleet,
+        forms: &[
+            Form {
+                usage: "fleet ota <url> [--group <name>]",
+                description: &[
+                    "Update the current device or a DEVICE_GROUPS group",
+                    "Each device installs the update on its next wake",
+                ],
+            },
+            Form {
+                usage: "fleet status",
+                description: &["Show the progress of the last fleet update"],
+            },
+        ],
+        args: &[
+            Arg {
+                name: "url",
+                values: Values::Word,
+                default: None,
+            },
+            Arg {
+                name: "name",
+                values: Values::Word,
+                default: None,
+            },
+        ],
+        examples: &[
+            "fleet ota https://example.com/fw.bin --group bedrooms",
+            "fleet status",
+        ],
+        parse: |spec, args| match args {
+            ["status"] => Ok(ParsedCommand::FleetStatus),
+            ["ota", url] => Ok(ParsedCommand::FleetOta {
+                url: url.to_string(),
+                group: None,
+            }),
+            ["ota", url, "--group", group] => Ok(ParsedCommand::FleetOta {
+                url: url.to_string(),
+                group: Some(group.to_string()),
+            }),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["transcript"],
+        category: Category::Console,
+        forms: &[
+            Form {
+                usage: "transcript start <file>",
+                description: &["Append a markdown transcript of this session"],
+            },
+            Form {
+                usage: "transcript stop",
+                description: &["Stop writing the transcript"],
+            },
+        ],
+        args: &[Arg {
+            name: "file",
+            values: Values::Word,
+            default: None,
+        }],
+        examples: &["transcript start session.md", "transcript stop"],
+        parse: |spec, args| match args {
+            ["start", path] => Ok(ParsedCommand::TranscriptStart(PathBuf::from(path))),
+            ["stop"] => Ok(ParsedCommand::TranscriptStop),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["device"],
+        category: Category::Devices,
+        forms: &[Form {
+            usage: "device [name]",
+            description: &[
+                "Change target device",
+                "Without a name, pick one of the devices heard from",
+            ],
+        }],
+        args: &[Arg {
+            name: "name",
+            values: Values::Word,
+            default: None,
+        }],
+        examples: &["device kitchen", "device"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Device(None)),
+            [device] if *device == shared_types::HOME_DEVICE => Err(ParseError::Invalid(format!(
+                "'{}' is a virtual device and takes no commands.",
+                device
+            ))),
+            [device] => Ok(ParsedCommand::Device(Some(device.to_string()))),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["units"],
+        category: Category::Console,
+        forms: &[Form {
+            usage: "units [metric|imperial]",
+            description: &["Show or change display units"],
+        }],
+        args: &[],
+        examples: &["units", "units imperial"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Units(None)),
+            [units] => units
+                .parse()
+                .map(|units| ParsedCommand::Units(Some(units)))
+                .map_err(|e| ParseError::Invalid(e.to_string())),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["output"],
+        category: Category::Console,
+        forms: &[Form {
+            usage: "output [text|json]",
+            description: &[
+                "Show or change message output format",
+                "json prints one object per message, metric and UTC",
+            ],
+        }],
+        args: &[],
+        examples: &["output json"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Output(None)),
+            [output] => output
+                .parse()
+                .map(|output| ParsedCommand::Output(Some(output)))
+                .map_err(|e| ParseError::Invalid(e.to_string())),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["devices"],
+        category: Category::Devices,
+        forms: &[
+            Form {
+                usage: "devices",
+                description: &["Show the devices heard from, flagging stale ones"],
+            },
+            Form {
+                usage: "devices watch [seconds]",
+                description: &["Redraw that every few seconds until Ctrl-C"],
+            },
+        ],
+        args: &[Arg {
+            name: "seconds",
+            values: Values::Integer {
+                min: 1,
+                max: u64::MAX,
+            },
+            default: Some("5"),
+        }],
+        examples: &["devices", "devices watch 10"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Devices),
+            ["watch"] => Ok(ParsedCommand::DevicesWatch(DEFAULT_WATCH_INTERVAL)),
+            ["watch", seconds] => spec
+                .arg("seconds")
+                .integer(seconds)
+                .map(|s| ParsedCommand::DevicesWatch(Duration::from_secs(s))),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["last"],
+        category: Category::Devices,
+        forms: &[Form {
+            usage: "last",
+            description: &["Show the current device's latest measurement"],
+        }],
+        args: &[],
+        examples: &["last"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Last),
+    },
+    CommandSpec {
+        names: &["pending"],
+        category: Category::Devices,
+        forms: &[Form {
+            usage: "pending",
+            description: &[
+                "Show the commands waiting for a device, and who sent them",
+                "Replacing another operator's needs --takeover at the",
+                "end of the line, e.g. 'reboot --takeover'",
+            ],
+        }],
+        args: &[],
+        examples: &["pending"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Pending),
+    },
+    CommandSpec {
+        names: &["status"],
+        category: Category::Devices,
+        forms: &[Form {
+            usage: "status",
+            description: &["Show current device"],
+        }],
+        args: &[],
+        examples: &["status"],
+        parse: |spec, args| spec.exactly(args, ParsedCommand::Status),
+    },
+    CommandSpec {
+        names: &["help", "h", "?"],
+        category: Category::Console,
+        forms: &[Form {
+            usage: "help [command]",
+            description: &["Show this help message, or the details of a command"],
+        }],
+        args: &[Arg {
+            name: "command",
+            values: Values::Word,
+            default: None,
+        }],
+        examples: &["help", "help frc"],
+        parse: |spec, args| match args {
+            [] => Ok(ParsedCommand::Help(None)),
+            [name] => find(name).map(|spec| ParsedCommand::Help(Some(spec.names[0].to_string()))),
+            _ => Err(spec.usage_error()),
+        },
+    },
+    CommandSpec {
+        names: &["exit", "quit", "q"],
+        category: Category::Console,
+        forms: &[Form {
+            usage: "exit, quit",
+            description: &["Exit the program"],
+        }],
+        args: &[],
+        examples: &["exit"],
+        parse: |_, _| Ok(ParsedCommand::Exit),
+    },
+];
+
+fn find(name: &str) -> Result<&'static CommandSpec, ParseError> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.names.contains(&name))
+        .ok_or_else(|| ParseError::Unknown(name.to_string()))
+}
+
+pub fn parse_command(line: &str) -> Result<ParsedCommand, ParseError> {
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+    let takeover = words.len() > 1 && words.last() == Some(&TAKEOVER_FLAG);
+    if takeover {
+        words.pop();
+    }
+    let Some((name, args)) = words.split_first() else {
+        return Ok(ParsedCommand::Blank);
+    };
+    let spec = find(name)?;
+    let command = (spec.parse)(spec, args)?;
+    if !takeover {
+        return Ok(command);
+    }
+    match command {
+        ParsedCommand::Send(_)
+        | ParsedCommand::FactoryReset
+        | ParsedCommand::Reboot
+        | ParsedCommand::Calibrate
+        | ParsedCommand::BatchSend
+        | ParsedCommand::FleetOta { .. } => Ok(ParsedCommand::Takeover(Box::new(command))),
+        _ => Err(ParseError::Invalid(format!(
+            "{} only applies to commands sent to devices.",
+            TAKEOVER_FLAG
+        ))),
+    }
+}
+
+/// The `help` listing, without the final newline
+pub fn help_text() -> String {
+    let mut text = String::new();
+    for category in Category::ALL {
+        text.push_str(&format!("\n{}:\n", category.title()));
+        for form in COMMANDS
+            .iter()
+            .filter(|spec| spec.category == category)
+            .flat_map(|spec| spec.forms)
+        {
+            let summary = form.description[0];
+            if form.usage.len() < USAGE_WIDTH {
+                text.push_str(&format!(
+                    "  {:<width$}- {}\n",
+                    form.usage,
+                    summary,
+                    width = USAGE_WIDTH
+                ));
+            } else {
+                text.push_str(&format!(
+                    "  {}\n{:width$}- {}\n",
+                    form.usage,
+                    "",
+                    summary,
+                    width = USAGE_WIDTH + 2
+                ));
+            }
+        }
+    }
+    text.push_str("\nType 'help <command>' for its arguments and examples.\n");
+    text.push_str(&format!(
+        "End a device command with {} to replace one another operator left pending.\n",
+        TAKEOVER_FLAG
+    ));
+    text.push_str("Run 'rpi-commander setup' to change the broker and default device.\n");
+    text
+}
+
+/// What `help <name>` shows: every form, the arguments with their bounds
+/// and defaults, examples, and the device command it sends
+pub fn command_help(name: &str) -> Option<String> {
+    let spec = find(name).ok()?;
+    let mut text = String::new();
+    for form in spec.forms {
+        text.push_str(&format!("\n{}\n", form.usage));
+        for line in form.description {
+            text.push_str(&format!("  {}\n", line));
+        }
+    }
+    if let [_, aliases @ ..] = spec.names
+        && !aliases.is_empty()
+    {
+        text.push_str(&format!("\nAlso: {}\n", aliases.join(", ")));
+    }
+    if !spec.args.is_empty() {
+        text.push_str("\nArguments:\n");
+        let width = spec
+            .args
+            .iter()
+            .map(|arg| arg.name.len())
+            .max()
+            .unwrap_or(0);
+        for arg in spec.args {
+            text.push_str(&format!("  {:<width$}  {}", arg.name, arg.values));
+            if let Some(default) = arg.default {
+                text.push_str(&format!(", default {}", default));
+            }
+            text.push('\n');
+        }
+    }
+    text.push_str("\nExamples:\n");
+    for example in spec.examples {
+        text.push_str(&format!("  {}\n", example));
+    }
+    let mut sends: Vec<&str> = spec
+        .examples
+        .iter()
+        .filter_map(|line| sends(line))
+        .collect();
+    sends.dedup();
+    if !sends.is_empty() {
+        text.push_str(&format!("\nSends: {}\n", sends.join(", ")));
+    }
+    Some(text)
+}
+
+/// The device command `line` sends, if it sends one
+fn sends(line: &str) -> Option<&'static str> {
+    match parse_command(line).ok()? {
+        ParsedCommand::Send(command) => Some(command.name()),
+        ParsedCommand::FactoryReset => Some("factory_reset"),
+        ParsedCommand::Reboot => Some(DeviceCommand::Reboot.name()),
+        ParsedCommand::FleetOta { url, .. } => Some(DeviceCommand::Ota { url }.name()),
+        _ => None,
+    }
+}
+
+/// What running a command needs from the commander
+pub trait CommandContext {
+    /// Shows `text` on its own line
+    fn print(&mut self, text: &str);
+    /// A line typed in answer to `prompt`, `None` if the user cancelled
+    fn ask(&mut self, prompt: &str) -> Option<String>;
+    /// The index of the option picked from `options`, `None` if the user
+    /// cancelled
+    fn choose(&mut self, prompt: &str, options: &[String]) -> Option<usize>;
+    /// Retains `command` for the current device. Fails if that replaces a
+    /// command another operator left pending, unless `takeover`.
+    fn publish(&mut self, command: DeviceCommand, takeover: bool) -> anyhow::Result<()>;
+    /// Retains `batch` for the current device, checked like `publish`
+    fn publish_batch(&mut self, batch: DeviceCommandBatch, takeover: bool) -> anyhow::Result<()>;
+    /// Retains the operation's command for every member and follows it,
+    /// checking each member like `publish`
+    fn publish_fleet(&mut self, fleet: FleetOperation, takeover: bool) -> anyhow::Result<()>;
+    fn device(&self) -> &str;
+    fn set_device(&mut self, device: String);
+    fn prefs(&self) -> DisplayPrefs;
+    fn set_units(&mut self, units: UnitSystem);
+    fn set_output(&mut self, output: OutputMode);
+    /// The table of devices heard from
+    fn devices(&self) -> String;
+    /// Names of the devices heard from
+    fn known_devices(&self) -> Vec<String>;
+    /// The last fleet operation's table and summary, if there was one
+    fn fleet_status(&self) -> Option<String>;
+    /// The listing of commands retained for the devices
+    fn pending(&self) -> String;
+    /// The current device's newest measurement heard in this session, and
+    /// when it was received
+    fn last_measurement(&self) -> Option<(DeviceMessage, DateTime<FixedOffset>)>;
+    /// The current device's CO2 readings stored over the last `window`,
+    /// oldest first; `None` without InfluxDB configured
+    fn recent_co2(&mut self, window: Duration) -> anyhow::Result<Option<Vec<f64>>>;
+    /// The commands `batch start` is collecting, `None` when it isn't
+    fn batch(&mut self) -> &mut Option<Vec<DeviceCommand>>;
+    /// Saves a calibration `calibrate` finished, returning where
+    fn save_calibration(&mut self, record: &CalibrationRecord) -> anyhow::Result<PathBuf>;
+    fn start_transcript(&mut self, path: &Path) -> anyhow::Result<()>;
+    fn stop_transcript(&mut self);
+}
+
+/// Publishes `command`, or adds it to the batch being collected
+fn send(
+    ctx: &mut impl CommandContext,
+    command: DeviceCommand,
+    takeover: bool,
+) -> anyhow::Result<()> {
+    let Some(batch) = ctx.batch() else {
+        return ctx.publish(command, takeover);
+    };
+    if batch.len() == MAX_BATCH_COMMANDS {
+        let text = format!(
+            "The batch is full at {} commands; 'batch send' it first\n",
+            MAX_BATCH_COMMANDS
+        );
+        ctx.print(&text);
+        return Ok(());
+    }
+    let text = format!("{} (#{} in the batch)\n", command, batch.len() + 1);
+    batch.push(command);
+    ctx.print(&text);
+    Ok(())
+}
+
+/// Runs `command`. Returns `false` once the user asked to leave.
+pub fn execute(command: ParsedCommand, ctx: &mut impl CommandContext) -> anyhow::Result<bool> {
+    run(command, ctx, false)
+}
+
+fn run(
+    command: ParsedCommand,
+    ctx: &mut impl CommandContext,
+    takeover: bool,
+) -> anyhow::Result<bool> {
+    match command {
+        ParsedCommand::Blank => {}
+        ParsedCommand::Help(None) => ctx.print(&help_text()),
+        ParsedCommand::Help(Some(name)) => {
+            ctx.print(&command_help(&name).expect("parsed help names a command"))
+        }
+        ParsedCommand::Exit => {
+            ctx.print("Goodbye!");
+            return Ok(false);
+        }
+        ParsedCommand::Status => {
+            let prefs = ctx.prefs();
+            let status = format!(
+                "Current device: {}\nUnits: {:?}, output: {:?}\n",
+                ctx.device(),
+                prefs.units,
+                prefs.output
+            );
+            ctx.print(&status);
+        }
+        ParsedCommand::Units(None) => {
+            let units = ctx.prefs().units;
+            ctx.print(&format!("Units: {:?}\n", units));
+        }
+        ParsedCommand::Units(Some(units)) => {
+            ctx.set_units(units);
+            ctx.print(&format!("Units: {:?}\n", units));
+        }
+        ParsedCommand::Output(None) => {
+            let output = ctx.prefs().output;
+            ctx.print(&format!("Output mode: {:?}\n", output));
+        }
+        ParsedCommand::Output(Some(output)) => {
+            ctx.set_output(output);
+            ctx.print(&format!("Output mode: {:?}\n", output));
+        }
+        ParsedCommand::Device(Some(device)) => {
+            ctx.print(&format!("Now targeting device: {}\n", device));
+            ctx.set_device(device);
+        }
+        ParsedCommand::Device(None) => {
+            let devices = ctx.known_devices();
+            if devices.is_empty() {
+                ctx.print("No devices heard from yet, name one: device <name>\n");
+            } else if let Some(index) = ctx.choose("Device:", &devices) {
+                let device = devices[index].clone();
+                ctx.print(&format!("Now targeting device: {}\n", device));
+                ctx.set_device(device);
+            }
+        }
+        ParsedCommand::Devices => {
+            let devices = ctx.devices();
+            ctx.print(&format!("{}\n", devices));
+        }
+        // The prompt loop runs it; anywhere else it only redraws once
+        ParsedCommand::DevicesWatch(_) => {
+            let devices = ctx.devices();
+            ctx.print(&format!("{}\n", devices));
+        }
+        ParsedCommand::Last => match ctx.last_measurement() {
+            Some((message, received_at)) => {
+                let text = ctx.prefs().render(&message, received_at);
+                ctx.print(&format!("{}\n", text));
+            }
+            None => {
+                let text = format!("No measurement from '{}' in this session\n", ctx.device());
+                ctx.print(&text);
+            }
+        },
+        ParsedCommand::Send(command) => send(ctx, command, takeover)?,
+        ParsedCommand::FactoryReset => {
+            let device = ctx.device().to_string();
+            ctx.print(&format!(
+                "This resets the sensor of '{}' to its factory settings, calibration included.",
+                device
+            ));
+            match ctx.ask("Type the device name to confirm: ") {
+                Some(answer) if answer.trim() == device => send(
+                    ctx,
+                    DeviceCommand::FactoryReset { confirm: device },
+                    takeover,
+                )?,
+                _ => ctx.print("Factory reset cancelled\n"),
+            }
+        }
+        ParsedCommand::Reboot => {
+            let prompt = format!("Reboot '{}' at its next wake? [y/N] ", ctx.device());
+            match ctx.ask(&prompt) {
+                Some(answer) if matches!(answer.trim(), "y" | "Y" | "yes") => {
+                    send(ctx, DeviceCommand::Reboot, takeover)?
+                }
+                _ => ctx.print("Reboot cancelled\n"),
+            }
+        }
+        ParsedCommand::Calibrate => calibration::run(ctx, takeover)?,
+        ParsedCommand::BatchStart => match ctx.batch() {
+            Some(batch) => {
+                let text = format!("Already collecting a batch of {}\n", batch.len());
+                ctx.print(&text);
+            }
+            None => {
+                *ctx.batch() = Some(Vec::new());
+                ctx.print("Collecting device commands; 'batch send' sends them, 'batch cancel' drops them\n");
+            }
+        },
+        ParsedCommand::BatchShow => match ctx.batch().clone() {
+            Some(batch) if !batch.is_empty() => {
+                let mut text = String::new();
+                for (i, command) in batch.iter().enumerate() {
+                    text.push_str(&format!("  {}. {}\n", i + 1, command));
+                }
+                ctx.print(&text);
+            }
+            Some(_) => ctx.print("The batch is empty\n"),
+            None => ctx.print("No batch started, see 'batch start'\n"),
+        },
+        ParsedCommand::BatchSend => match ctx.batch().take() {
+            // A lone command goes out as itself
+            Some(mut batch) if batch.len() == 1 => ctx.publish(batch.remove(0), takeover)?,
+            Some(batch) if !batch.is_empty() => {
+                ctx.publish_batch(DeviceCommandBatch::new(batch)?, takeover)?
+            }
+            Some(_) => ctx.print("The batch is empty, nothing sent\n"),
+            None => ctx.print("No batch started, see 'batch start'\n"),
+        },
+        ParsedCommand::BatchCancel => match ctx.batch().take() {
+            Some(batch) => {
+                let text = format!("Dropped a batch of {}\n", batch.len());
+                ctx.print(&text);
+            }
+            None => ctx.print("No batch started, see 'batch start'\n"),
+        },
+        ParsedCommand::FleetOta { url, group } => {
+            let fleet = fleet::operation(&url, group.as_deref(), ctx.device())?;
+            ctx.publish_fleet(fleet, takeover)?;
+        }
+        ParsedCommand::FleetStatus => match ctx.fleet_status() {
+            Some(status) => ctx.print(&status),
+            None => ctx.print("No fleet operation in this session\n"),
+        },
+        ParsedCommand::Pending => {
+            let pending = ctx.pending();
+            ctx.print(&format!("{}\n", pending));
+        }
+        ParsedCommand::Takeover(command) => return run(*command, ctx, true),
+        ParsedCommand::TranscriptStart(path) => ctx.start_transcript(&path)?,
+        ParsedCommand::TranscriptStop => ctx.stop_transcript(),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::DevicePayload;
+
+    /// Typed lines, each followed in `snapshots/commands.txt` by what it
+    /// parses to
+    const CORPUS: &[&str] = &[
+        "",
+        "   ",
+        "noop",
+        "noop now",
+        "frc",
+        "frc 450",
+        "  frc    450  ",
+        "frc lots",
+        "frc 70000",
+        "frc 0",
+        "frc 2001",
+        "frc 450 500",
+        "calibrate",
+        "calibrate 420",
+        "calibrate --takeover",
+        "set-offset 1.5",
+        "set-offset 2 --volatile",
+        "set-offset -2 --volatile",
+        "set-offset 25",
+        "set-offset",
+        "set-offset warm",
+        "set-offset 1.5 --volatil",
+        "get-offset",
+        "get-ofset",
+        "set-sleep 600",
+        "set-sleep",
+        "set-sleep -1",
+        "set-sleep 0",
+        "set-sleep 29",
+        "set-sleep 86401",
+        "set-sleep 10m",
+        "get-sleep",
+        "config",
+        "config kitchen",
+        "set-mqtt-policy measurement 1",
+        "set-mqtt-policy error 2 retain",
+        "set-mqtt-policy error 2 keep",
+        "set-mqtt-policy measurements 1",
+        "set-mqtt-policy measurement 3",
+        "set-mqtt-policy measurement",
+        "confirm-config 41",
+        "confirm-config",
+        "confirm-config latest",
+        "confirm-config 4294967296",
+        "log-level",
+        "log-level debug",
+        "log-level loud",
+        "log-level debug verbose",
+        "adaptive on",
+        "adaptive off",
+        "adaptive",
+        "adaptive sometimes",
+        "asc off",
+        "asc on",
+        "asc",
+        "asc disabled",
+        "get-asc",
+        "get-asc now",
+        "set-altitude 600",
+        "set-altitude 0",
+        "set-altitude 3001",
+        "set-altitude high",
+        "set-altitude",
+        "get-altitude",
+        "get-altitude 600",
+        "set-pressure 94200",
+        "set-pressure 101325",
+        "set-pressure 942",
+        "set-pressure 94200 Pa",
+        "set-pressure",
+        "self-test",
+        "self-test now",
+        "serial",
+        "serial 1",
+        "firmware",
+        "firmware 1",
+        "inject-fault co2_zero",
+        "inject-fault",
+        "inject-fault brownout",
+        "factory-reset",
+        "factory-reset esp32-scd40",
+        "reboot",
+        "reboot now",
+        "fleet status",
+        "fleet ota https://example.com/fw.bin",
+        "fleet ota https://example.com/fw.bin --group bedrooms",
+        "fleet ota",
+        "fleet",
+        "fleet upgrade https://example.com/fw.bin",
+        "transcript start session.md",
+        "transcript stop",
+        "transcript start",
+        "transcript",
+        "device kitchen",
+        "device",
+        "device living room",
+        "device home",
+        "units",
+        "units imperial",
+        "units Metric",
+        "units kelvin",
+        "output json",
+        "output",
+        "output yaml",
+        "devices",
+        "devices watch",
+        "devices watch 10",
+        "devices watch 0",
+        "devices watch soon",
+        "devices list",
+        "status",
+        "status now",
+        "last",
+        "last 2",
+        "pending",
+        "pending all",
+        "batch start",
+        "batch",
+        "batch send",
+        "batch cancel",
+        "batch send now",
+        "batch send --takeover",
+        "reboot --takeover",
+        "set-sleep 600 --takeover",
+        "fleet ota https://example.com/fw.bin --takeover",
+        "status --takeover",
+        "--takeover",
+        "set-sleep --takeover 600",
+        "help",
+        "h",
+        "?",
+        "help frc",
+        "h devices",
+        "help ?",
+        "help calibrate",
+        "help selftest",
+        "help frc now",
+        "exit",
+        "quit",
+        "q",
+        "Status",
+        "sudo frc",
+    ];
+
+    fn golden() -> String {
+        CORPUS
+            .iter()
+            .map(|line| {
+                let result = match parse_command(line) {
+                    Ok(command) => format!("{:?}", command),
+                    Err(e) => format!("error: {}", e),
+                };
+                format!("> {:?}\n{}\n", line, result)
+            })
+            .collect()
+    }
+
+    /// Compared against `snapshots/commands.txt`; regenerate with
+    /// `UPDATE_SNAPSHOTS=1 cargo test -p rpi-commander command_line`.
+    #[test]
+    fn parses_the_corpus_like_the_snapshot() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots/commands.txt");
+        let rendered = golden();
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, &rendered).unwrap();
+        }
+        assert_eq!(
+            rendered,
+            std::fs::read_to_string(&path).unwrap(),
+            "regenerate with UPDATE_SNAPSHOTS=1 cargo test -p rpi-commander command_line"
+        );
+    }
+
+    /// Compared against `snapshots/help.txt`, likewise
+    #[test]
+    fn help_matches_the_snapshot() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots/help.txt");
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, help_text()).unwrap();
+        }
+        assert_eq!(
+            help_text(),
+            std::fs::read_to_string(&path).unwrap(),
+            "regenerate with UPDATE_SNAPSHOTS=1 cargo test -p rpi-commander command_line"
+        );
+    }
+
+    #[test]
+    fn every_name_and_form_is_reachable() {
+        let mut names: Vec<&str> = COMMANDS
+            .iter()
+            .flat_map(|spec| spec.names)
+            .copied()
+            .collect();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count, "a name is used twice");
+        for spec in COMMANDS {
+            for name in spec.names {
+                // Every name at least gets as far as its own parser
+                assert!(
+                    !matches!(parse_command(name), Err(ParseError::Unknown(_))),
+                    "{}",
+                    name
+                );
+            }
+            for form in spec.forms {
+                assert!(!form.description.is_empty(), "{}", form.usage);
+                let word = form.usage.split([' ', ',']).next().unwrap();
+                assert!(spec.names.contains(&word), "{}", form.usage);
+            }
+        }
+    }
+
+    #[test]
+    fn every_command_has_detailed_help() {
+        for spec in COMMANDS {
+            let help = command_help(spec.names[0]).unwrap();
+            for form in spec.forms {
+                assert!(help.contains(form.usage), "{}", spec.names[0]);
+            }
+            assert!(
+                !spec.examples.is_empty(),
+                "{} has no examples",
+                spec.names[0]
+            );
+            for example in spec.examples {
+                assert!(parse_command(example).is_ok(), "{}", example);
+                assert_eq!(example.split(' ').next(), Some(spec.names[0]));
+            }
+        }
+        assert_eq!(command_help("h"), command_help("help"));
+        assert_eq!(command_help("selftest"), None);
+    }
+
+    /// Words a form takes literally, and the arguments at the other places.
+    /// `<x>` is always an argument, `[x]` only when documented as one, like
+    /// `[ppm]` but not `[retain]`.
+    fn form_words<'a>(spec: &CommandSpec, form: &'a Form) -> Vec<Result<&'a str, &'a str>> {
+        form.usage
+            .split(' ')
+            .map(|word| {
+                let bare = word.trim_matches(['[', ']', '<', '>']);
+                if word.starts_with('<') || spec.args.iter().any(|arg| arg.name == bare) {
+                    Err(bare)
+                } else {
+                    Ok(bare)
+                }
+            })
+            .collect()
+    }
+
+    /// Puts `value` in place of the word at `at` in every example of `form`
+    /// that gets that far
+    fn substituted(spec: &CommandSpec, form: &Form, at: usize, value: &str) -> Vec<String> {
+        let words = form_words(spec, form);
+        spec.examples
+            .iter()
+            .map(|example| example.split(' ').collect::<Vec<_>>())
+            .filter(|example| {
+                example.len() > at
+                    && words[..at]
+                        .iter()
+                        .zip(example)
+                        .all(|(word, typed)| word.is_err() || word == &Ok(*typed))
+            })
+            .map(|mut example| {
+                example[at] = value;
+                example.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn documented_bounds_are_the_parsers_bounds() {
+        for spec in COMMANDS {
+            for form in spec.forms {
+                for (at, word) in form_words(spec, form).into_iter().enumerate() {
+                    let Err(name) = word else {
+                        continue;
+                    };
+                    if name.contains('|') {
+                        continue;
+                    }
+                    let arg = spec
+                        .args
+                        .iter()
+                        .find(|arg| arg.name == name)
+                        .unwrap_or_else(|| panic!("'{}' isn't documented", form.usage));
+                    let (valid, invalid): (Vec<String>, Vec<String>) = match arg.values {
+                        Values::Integer { min, max } => (
+                            vec![min.to_string(), max.to_string()],
+                            [min.checked_sub(1).map(|n| n.to_string())]
+                                .into_iter()
+                                .flatten()
+                                .chain([(max as u128 + 1).to_string(), "many".to_string()])
+                                .collect(),
+                        ),
+                        Values::Decimal { min, max } => (
+                            vec![min.to_string(), max.to_string()],
+                            vec![
+                                (min - 1.0).to_string(),
+                                (max + 1.0).to_string(),
+                                "warm".to_string(),
+                            ],
+                        ),
+                        Values::OneOf(words) => (
+                            words.iter().map(|word| word.to_string()).collect(),
+                            vec!["bogus".to_string()],
+                        ),
+                        Values::Word => continue,
+                    };
+                    assert!(
+                        !substituted(spec, form, at, &valid[0]).is_empty(),
+                        "no example of '{}' in '{}'",
+                        name,
+                        form.usage
+                    );
+                    for value in &valid {
+                        for line in substituted(spec, form, at, value) {
+                            assert!(parse_command(&line).is_ok(), "{}", line);
+                        }
+                    }
+                    for value in &invalid {
+                        for line in substituted(spec, form, at, value) {
+                            assert!(parse_command(&line).is_err(), "{}", line);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Nothing the parsers accept is left out of the lists
+        let words = |name: &str, arg: &str| match find(name).unwrap().arg(arg).values {
+            Values::OneOf(words) => words.to_vec(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            words("set-mqtt-policy", "class"),
+            PayloadClass::ALL.map(PayloadClass::as_str)
+        );
+        assert_eq!(
+            words("log-level", "level"),
+            LogLevel::ALL.map(LogLevel::as_str)
+        );
+        assert_eq!(
+            words("inject-fault", "kind"),
+            FaultKind::ALL.map(FaultKind::as_str)
+        );
+    }
+
+    #[test]
+    fn detailed_help_shows_bounds_defaults_and_what_is_sent() {
+        assert_eq!(
+            command_help("frc").unwrap(),
+            "\nfrc [ppm]\n  \
+             Start forced recalibration\n  \
+             Run it after a few minutes in fresh air, giving\n  \
+             the outdoor CO2 level as the target\n\
+             \nArguments:\n  \
+             ppm  a whole number from 400 to 2000, default 422\n\
+             \nExamples:\n  \
+             frc\n  \
+             frc 420\n\
+             \nSends: start_frc\n"
+        );
+        let sleep = command_help("set-sleep").unwrap();
+        assert!(
+            sleep.contains("seconds  a whole number from 30 to 86400\n"),
+            "{}",
+            sleep
+        );
+        assert!(
+            command_help("log-level")
+                .unwrap()
+                .ends_with("Sends: get_log_level, set_log_level\n")
+        );
+        assert!(!command_help("status").unwrap().contains("Sends"));
+        assert!(command_help("help").unwrap().contains("Also: h, ?"));
+    }
+
+    /// Records what the executor asks of the commander
+    #[derive(Default)]
+    struct MockContext {
+        device: String,
+        prefs: DisplayPrefs,
+        printed: Vec<String>,
+        /// Typed at the next prompts, in order
+        answers: Vec<String>,
+        /// The prompts shown, in order
+        asked: Vec<String>,
+        known: Vec<String>,
+        published: Vec<DeviceCommand>,
+        published_batches: Vec<DeviceCommandBatch>,
+        /// Whether each publish may take over, in order
+        takeovers: Vec<bool>,
+        fleets: Vec<FleetOperation>,
+        transcript: Option<PathBuf>,
+        measurement: Option<(DeviceMessage, DateTime<FixedOffset>)>,
+        /// What InfluxDB has stored, `None` when it isn't configured
+        stored_co2: Option<Vec<f64>>,
+        calibrations: Vec<CalibrationRecord>,
+        batch: Option<Vec<DeviceCommand>>,
+    }
+
+    impl CommandContext for MockContext {
+        fn print(&mut self, text: &str) {
+            self.printed.push(text.to_string());
+        }
+
+        fn ask(&mut self, prompt: &str) -> Option<String> {
+            self.asked.push(prompt.to_string());
+            (!self.answers.is_empty()).then(|| self.answers.remove(0))
+        }
+
+        fn choose(&mut self, prompt: &str, options: &[String]) -> Option<usize> {
+            let answer = self.ask(prompt)?;
+            options.iter().position(|option| *option == answer)
+        }
+
+        fn publish(&mut self, command: DeviceCommand, takeover: bool) -> anyhow::Result<()> {
+            self.published.push(command);
+            self.takeovers.push(takeover);
+            Ok(())
+        }
+
+        fn publish_batch(
+            &mut self,
+            batch: DeviceCommandBatch,
+            takeover: bool,
+        ) -> anyhow::Result<()> {
+            self.published_batches.push(batch);
+            self.takeovers.push(takeover);
+            Ok(())
+        }
+
+        fn publish_fleet(&mut self, fleet: FleetOperation, takeover: bool) -> anyhow::Result<()> {
+            self.fleets.push(fleet);
+            self.takeovers.push(takeover);
+            Ok(())
+        }
+
+        fn device(&self) -> &str {
+            &self.device
+        }
+
+        fn set_device(&mut self, device: String) {
+            self.device = device;
+        }
+
+        fn prefs(&self) -> DisplayPrefs {
+            self.prefs
+        }
+
+        fn set_units(&mut self, units: UnitSystem) {
+            self.prefs.units = units;
+        }
+
+        fn set_output(&mut self, output: OutputMode) {
+            self.prefs.output = output;
+        }
+
+        fn devices(&self) -> String {
+            "kitchen  2 min ago".to_string()
+        }
+
+        fn known_devices(&self) -> Vec<String> {
+            self.known.clone()
+        }
+
+        fn fleet_status(&self) -> Option<String> {
+            self.fleets.last().map(|fleet| fleet.summary())
+        }
+
+        fn pending(&self) -> String {
+            "No commands pending".to_string()
+        }
+
+        fn last_measurement(&self) -> Option<(DeviceMessage, DateTime<FixedOffset>)> {
+            self.measurement.clone()
+        }
+
+        fn recent_co2(&mut self, _window: Duration) -> anyhow::Result<Option<Vec<f64>>> {
+            Ok(self.stored_co2.clone())
+        }
+
+        fn batch(&mut self) -> &mut Option<Vec<DeviceCommand>> {
+            &mut self.batch
+        }
+
+        fn save_calibration(&mut self, record: &CalibrationRecord) -> anyhow::Result<PathBuf> {
+            self.calibrations.push(record.clone());
+            Ok(PathBuf::from("calibrations.json"))
+        }
+
+        fn start_transcript(&mut self, path: &Path) -> anyhow::Result<()> {
+            self.transcript = Some(path.to_path_buf());
+            Ok(())
+        }
+
+        fn stop_transcript(&mut self) {
+            self.transcript = None;
+        }
+    }
+
+    fn run(ctx: &mut MockContext, line: &str) -> bool {
+        execute(parse_command(line).unwrap(), ctx).unwrap()
+    }
+
+    #[test]
+    fn executes_against_the_context() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "set-offset 1.5 --volatile"));
+        assert!(run(&mut ctx, "device bedroom"));
+        assert!(run(&mut ctx, "units imperial"));
+        assert!(run(&mut ctx, "status"));
+        assert!(run(&mut ctx, "fleet status"));
+        assert!(run(&mut ctx, "fleet ota https://example.com/fw.bin"));
+        assert!(run(&mut ctx, "transcript start session.md"));
+        assert!(!run(&mut ctx, "quit"));
+
+        assert_eq!(
+            ctx.published,
+            [DeviceCommand::SetTempOffset {
+                offset: 1.5,
+                persist: false
+            }]
+        );
+        assert_eq!(ctx.device, "bedroom");
+        assert_eq!(ctx.prefs.units, UnitSystem::Imperial);
+        assert_eq!(ctx.fleets.len(), 1);
+        assert_eq!(ctx.fleets[0].members().collect::<Vec<_>>(), ["bedroom"]);
+        assert_eq!(ctx.transcript, Some(PathBuf::from("session.md")));
+        assert_eq!(
+            ctx.printed,
+            [
+                "Now targeting device: bedroom\n",
+                "Units: Imperial\n",
+                "Current device: bedroom\nUnits: Imperial, output: Text\n",
+                "No fleet operation in this session\n",
+                "Goodbye!",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_commands_keep_the_session_going() {
+        struct Offline(MockContext);
+
+        impl CommandContext for Offline {
+            fn print(&mut self, text: &str) {
+                self.0.print(text)
+            }
+            fn ask(&mut self, prompt: &str) -> Option<String> {
+                self.0.ask(prompt)
+            }
+            fn choose(&mut self, prompt: &str, options: &[String]) -> Option<usize> {
+                self.0.choose(prompt, options)
+            }
+            fn publish(&mut self, _: DeviceCommand, _: bool) -> anyhow::Result<()> {
+                anyhow::bail!("not connected")
+            }
+            fn publish_batch(&mut self, _: DeviceCommandBatch, _: bool) -> anyhow::Result<()> {
+                anyhow::bail!("not connected")
+            }
+            fn publish_fleet(&mut self, _: FleetOperation, _: bool) -> anyhow::Result<()> {
+                anyhow::bail!("not connected")
+            }
+            fn device(&self) -> &str {
+                self.0.device()
+            }
+            fn set_device(&mut self, device: String) {
+                self.0.set_device(device)
+            }
+            fn prefs(&self) -> DisplayPrefs {
+                self.0.prefs()
+            }
+            fn set_units(&mut self, units: UnitSystem) {
+                self.0.set_units(units)
+            }
+            fn set_output(&mut self, output: OutputMode) {
+                self.0.set_output(output)
+            }
+            fn devices(&self) -> String {
+                self.0.devices()
+            }
+            fn known_devices(&self) -> Vec<String> {
+                self.0.known_devices()
+            }
+            fn fleet_status(&self) -> Option<String> {
+                self.0.fleet_status()
+            }
+            fn pending(&self) -> String {
+                self.0.pending()
+            }
+            fn last_measurement(&self) -> Option<(DeviceMessage, DateTime<FixedOffset>)> {
+                self.0.last_measurement()
+            }
+            fn recent_co2(&mut self, window: Duration) -> anyhow::Result<Option<Vec<f64>>> {
+                self.0.recent_co2(window)
+            }
+            fn batch(&mut self) -> &mut Option<Vec<DeviceCommand>> {
+                self.0.batch()
+            }
+            fn save_calibration(&mut self, record: &CalibrationRecord) -> anyhow::Result<PathBuf> {
+                self.0.save_calibration(record)
+            }
+            fn start_transcript(&mut self, path: &Path) -> anyhow::Result<()> {
+                self.0.start_transcript(path)
+            }
+            fn stop_transcript(&mut self) {
+                self.0.stop_transcript()
+            }
+        }
+
+        let mut ctx = Offline(MockContext::default());
+        let error = execute(parse_command("noop").unwrap(), &mut ctx).unwrap_err();
+        assert_eq!(error.to_string(), "not connected");
+        assert!(execute(parse_command("devices").unwrap(), &mut ctx).unwrap());
+        assert_eq!(ctx.0.printed, ["kitchen  2 min ago\n"]);
+
+        // A calibration stops at the first command it can't send
+        ctx.0.answers = vec!["420".to_string(), "y".to_string()];
+        assert!(execute(parse_command("calibrate").unwrap(), &mut ctx).is_err());
+        assert!(ctx.0.asked.is_empty());
+        assert!(ctx.0.calibrations.is_empty());
+    }
+
+    #[test]
+    fn factory_reset_needs_the_device_name_typed() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            answers: vec![
+                "bedroom".to_string(),
+                "yes".to_string(),
+                " kitchen ".to_string(),
+            ],
+            ..Default::default()
+        };
+        // Wrong name, a plain yes, then the name; cancelling sends nothing
+        for _ in 0..4 {
+            assert!(run(&mut ctx, "factory-reset"));
+        }
+        assert_eq!(
+            ctx.published,
+            [DeviceCommand::FactoryReset {
+                confirm: "kitchen".to_string()
+            }]
+        );
+        assert_eq!(
+            ctx.printed
+                .iter()
+                .filter(|line| *line == "Factory reset cancelled\n")
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn device_without_a_name_picks_one_heard_from() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "device"));
+        assert_eq!(
+            ctx.printed,
+            ["No devices heard from yet, name one: device <name>\n"]
+        );
+
+        ctx.known = vec!["bedroom".to_string(), "office".to_string()];
+        ctx.answers = vec!["office".to_string()];
+        // The second time the picker is cancelled
+        assert!(run(&mut ctx, "device"));
+        assert!(run(&mut ctx, "device"));
+        assert_eq!(ctx.device, "office");
+        assert_eq!(ctx.printed[1..], ["Now targeting device: office\n"]);
+    }
+
+    #[test]
+    fn takeover_is_passed_on_for_one_line() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            answers: vec!["y".to_string()],
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "get-sleep"));
+        assert!(run(&mut ctx, "set-sleep 600 --takeover"));
+        assert!(run(&mut ctx, "reboot --takeover"));
+        assert!(run(
+            &mut ctx,
+            "fleet ota https://example.com/fw.bin --takeover"
+        ));
+        assert!(run(&mut ctx, "noop"));
+        assert!(run(&mut ctx, "pending"));
+        assert_eq!(ctx.takeovers, [false, true, true, true, false]);
+        assert_eq!(ctx.printed, ["No commands pending\n"]);
+    }
+
+    #[test]
+    fn reboot_needs_a_yes() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            answers: vec!["".to_string(), "n".to_string(), " y ".to_string()],
+            ..Default::default()
+        };
+        // Enter, no, yes, then cancelled
+        for _ in 0..4 {
+            assert!(run(&mut ctx, "reboot"));
+        }
+        assert_eq!(ctx.published, [DeviceCommand::Reboot]);
+        assert_eq!(
+            ctx.printed
+                .iter()
+                .filter(|line| *line == "Reboot cancelled\n")
+                .count(),
+            3
+        );
+    }
+
+    fn reading(co2: u16) -> Option<(DeviceMessage, DateTime<FixedOffset>)> {
+        Some((
+            DeviceMessage::new("kitchen", DevicePayload::measurement(co2, 21.5, 40.0)),
+            DateTime::parse_from_rfc3339("2025-01-15T14:05:09+01:00").unwrap(),
+        ))
+    }
+
+    #[test]
+    fn a_batch_sends_its_commands_together() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            answers: vec!["y".to_string()],
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "batch start"));
+        assert!(run(&mut ctx, "set-offset 1.5"));
+        assert!(run(&mut ctx, "set-sleep 600"));
+        assert!(run(&mut ctx, "reboot"));
+        assert!(run(&mut ctx, "batch"));
+        assert!(ctx.published.is_empty());
+        assert!(run(&mut ctx, "batch send --takeover"));
+        assert!(ctx.published.is_empty());
+        assert_eq!(
+            ctx.published_batches,
+            [DeviceCommandBatch::new([
+                DeviceCommand::SetTempOffset {
+                    offset: 1.5,
+                    persist: true
+                },
+                DeviceCommand::SetDeepSleepTime { seconds: 600 },
+                DeviceCommand::Reboot,
+            ])
+            .unwrap()]
+        );
+        assert_eq!(ctx.takeovers, [true]);
+        assert_eq!(
+            ctx.printed[4],
+            "  1. Set temperature offset to 1.5 °C\n  2. Set deep sleep time to 600 s\n  3. Reboot\n"
+        );
+
+        // Sending ends the batch; what follows goes out right away
+        assert!(run(&mut ctx, "noop"));
+        assert_eq!(ctx.published, [DeviceCommand::NoOp]);
+        assert_eq!(ctx.batch, None);
+    }
+
+    #[test]
+    fn a_full_batch_takes_no_more_commands() {
+        let mut ctx = MockContext::default();
+        assert!(run(&mut ctx, "batch start"));
+        for _ in 0..MAX_BATCH_COMMANDS + 1 {
+            assert!(run(&mut ctx, "get-offset"));
+        }
+        assert_eq!(
+            ctx.printed.last().unwrap(),
+            "The batch is full at 8 commands; 'batch send' it first\n"
+        );
+        assert!(run(&mut ctx, "batch send"));
+        assert_eq!(ctx.published_batches[0].commands.len(), MAX_BATCH_COMMANDS);
+    }
+
+    #[test]
+    fn a_batch_of_one_is_sent_as_the_command() {
+        let mut ctx = MockContext::default();
+        assert!(run(&mut ctx, "batch send"));
+        assert!(run(&mut ctx, "batch start"));
+        assert!(run(&mut ctx, "batch send"));
+        assert!(run(&mut ctx, "batch start"));
+        assert!(run(&mut ctx, "get-offset"));
+        assert!(run(&mut ctx, "batch send"));
+        assert!(run(&mut ctx, "batch start"));
+        assert!(run(&mut ctx, "noop"));
+        assert!(run(&mut ctx, "batch cancel"));
+        assert_eq!(ctx.published, [DeviceCommand::GetTempOffset]);
+        assert_eq!(
+            ctx.printed,
+            [
+                "No batch started, see 'batch start'\n",
+                "Collecting device commands; 'batch send' sends them, 'batch cancel' drops them\n",
+                "The batch is empty, nothing sent\n",
+                "Collecting device commands; 'batch send' sends them, 'batch cancel' drops them\n",
+                "Get temperature offset (#1 in the batch)\n",
+                "Collecting device commands; 'batch send' sends them, 'batch cancel' drops them\n",
+                "No-op (#1 in the batch)\n",
+                "Dropped a batch of 1\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn last_shows_the_newest_measurement() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "last"));
+        ctx.measurement = reading(612);
+        ctx.prefs.output = OutputMode::Json;
+        assert!(run(&mut ctx, "last"));
+        assert_eq!(
+            ctx.printed[0],
+            "No measurement from 'kitchen' in this session\n"
+        );
+        assert!(ctx.printed[1].contains("\"co2\":612"), "{}", ctx.printed[1]);
+    }
+
+    #[test]
+    fn calibrate_runs_its_steps_in_order() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            measurement: reading(455),
+            stored_co2: Some(vec![420.0, 424.0, 418.0, 421.0]),
+            answers: vec![
+                "lots".to_string(),
+                "2500".to_string(),
+                "415 420".to_string(),
+                " 415 ".to_string(),
+                "y".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "calibrate"));
+
+        assert_eq!(
+            ctx.published,
+            [
+                DeviceCommand::GetTempOffset,
+                DeviceCommand::StartFrc { target_ppm: 415 }
+            ]
+        );
+        assert_eq!(ctx.takeovers, [false, false]);
+        assert_eq!(ctx.asked.len(), 5);
+        assert!(ctx.asked[4].starts_with("Calibrate 'kitchen' to 415 ppm?"));
+        let invalid = "Invalid ppm. Must be a whole number from 400 to 2000.\n";
+        assert_eq!(
+            ctx.printed.iter().filter(|line| *line == invalid).count(),
+            2
+        );
+        assert!(
+            ctx.printed
+                .iter()
+                .any(|line| line.starts_with("CO2 has settled"))
+        );
+
+        let [record] = &ctx.calibrations[..] else {
+            panic!("{:?}", ctx.calibrations);
+        };
+        assert_eq!(record.device, "kitchen");
+        assert_eq!(record.reference_ppm, 415);
+        assert_eq!(record.last_co2_ppm, Some(455));
+        assert_eq!(record.stability.map(|s| s.samples), Some(4));
+        assert!(!record.unstable_ignored);
+        let summary = ctx.printed.last().unwrap();
+        assert!(summary.contains(&record.summary()), "{}", summary);
+        assert!(
+            summary.contains("Saved to calibrations.json"),
+            "{}",
+            summary
+        );
+
+        // --takeover applies to everything it sends
+        ctx.answers = vec!["".to_string(), "yes".to_string()];
+        assert!(run(&mut ctx, "calibrate --takeover"));
+        assert_eq!(ctx.takeovers[2..], [true, true]);
+        assert_eq!(
+            ctx.published[3],
+            DeviceCommand::StartFrc {
+                target_ppm: DEFAULT_FRC_PPM
+            }
+        );
+    }
+
+    #[test]
+    fn calibrate_cancelled_at_any_prompt_sends_no_frc() {
+        let unsettled = Some(vec![420.0, 520.0, 610.0, 480.0]);
+        let cases: [(&[&str], Option<Vec<f64>>); 4] = [
+            // At the reference
+            (&[], None),
+            // At the warning about unsettled air
+            (&["420", "n"], unsettled.clone()),
+            (&["420"], unsettled.clone()),
+            // At the final confirmation
+            (&["420", ""], Some(vec![420.0, 421.0, 419.0])),
+        ];
+        for (answers, stored_co2) in cases {
+            let mut ctx = MockContext {
+                device: "kitchen".to_string(),
+                answers: answers.iter().map(|answer| answer.to_string()).collect(),
+                stored_co2,
+                ..Default::default()
+            };
+            assert!(run(&mut ctx, "calibrate"));
+            assert_eq!(
+                ctx.published,
+                [DeviceCommand::GetTempOffset],
+                "{:?}",
+                answers
+            );
+            assert!(ctx.calibrations.is_empty());
+            assert_eq!(ctx.printed.last().unwrap(), "Calibration cancelled\n");
+        }
+    }
+
+    #[test]
+    fn calibrate_can_go_ahead_despite_unsettled_air() {
+        let mut ctx = MockContext {
+            device: "kitchen".to_string(),
+            answers: vec!["420".to_string(), "y".to_string(), "y".to_string()],
+            stored_co2: Some(vec![420.0, 520.0, 610.0, 480.0]),
+            ..Default::default()
+        };
+        assert!(run(&mut ctx, "calibrate"));
+        assert!(
+            ctx.printed
+                .iter()
+                .any(|line| line.contains("CO2 is still changing"))
+        );
+        assert_eq!(ctx.calibrations.len(), 1);
+        assert!(ctx.calibrations[0].unstable_ignored);
+        assert_eq!(ctx.calibrations[0].last_co2_ppm, None);
+
+        // Without InfluxDB the check is skipped, not failed
+        ctx.stored_co2 = None;
+        ctx.answers = vec!["420".to_string(), "y".to_string()];
+        assert!(run(&mut ctx, "calibrate"));
+        assert_eq!(ctx.calibrations.len(), 2);
+        assert_eq!(ctx.calibrations[1].stability, None);
+    }
+}
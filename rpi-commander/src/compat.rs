@@ -0,0 +1,203 @@
+//! Notices devices that are ahead of this build.
+//!
+//! Devices updated to a newer shared-types send payloads this commander
+//! has no variant for, and may send a newer protocol version. Such a
+//! message still parses as JSON, so instead of dropping it with a decode
+//! error the commander shows it raw, and `Mismatches` prints a banner
+//! suggesting an upgrade, once per device for each unknown `status` or
+//! protocol version.
+
+use std::collections::HashSet;
+
+use serde_json::Value;
+use shared_types::{CURRENT_PROTOCOL_VERSION, CodecError, DeviceMessage, LEGACY_PROTOCOL_VERSION};
+
+/// A message whose `status` this build doesn't know
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownMessage {
+    pub device: String,
+    pub status: String,
+    pub version: u8,
+    /// The whole message as received
+    pub raw: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoded {
+    Known(DeviceMessage),
+    Unknown(UnknownMessage),
+}
+
+/// Reads a device's JSON message. Only a `status` this build has no
+/// variant for makes it unknown; a known one that doesn't parse is still
+/// an error.
+pub fn decode(json: &str) -> Result<Decoded, CodecError> {
+    let error = match DeviceMessage::from_json(json) {
+        Ok(message) => return Ok(Decoded::Known(message)),
+        Err(e) => e,
+    };
+    if !matches!(&error, CodecError::Json(e) if e.to_string().starts_with("unknown variant")) {
+        return Err(error);
+    }
+    let raw: Value = serde_json::from_str(json)?;
+    let (Some(device), Some(status)) = (raw["device"].as_str(), raw["status"].as_str()) else {
+        return Err(error);
+    };
+    Ok(Decoded::Unknown(UnknownMessage {
+        device: device.to_string(),
+        status: status.to_string(),
+        version: raw["v"]
+            .as_u64()
+            .and_then(|v| u8::try_from(v).ok())
+            .unwrap_or(LEGACY_PROTOCOL_VERSION),
+        raw,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Mismatch {
+    Version(u8),
+    Status(String),
+}
+
+/// The mismatches already reported, by device
+#[derive(Debug, Default)]
+pub struct Mismatches {
+    reported: HashSet<(String, Mismatch)>,
+}
+
+impl Mismatches {
+    /// The banner to show for `decoded`, if it shows a mismatch that
+    /// hasn't been reported for its device yet
+    pub fn banner(&mut self, decoded: &Decoded) -> Option<String> {
+        let (device, version, status) = match decoded {
+            Decoded::Known(message) => (&message.device, message.version, None),
+            Decoded::Unknown(unknown) => (&unknown.device, unknown.version, Some(&unknown.status)),
+        };
+        let mut lines = Vec::new();
+        if version > CURRENT_PROTOCOL_VERSION && self.first(device, Mismatch::Version(version)) {
+            lines.push(format!(
+                "{} speaks protocol version {}, this commander understands up to {}.",
+                device, version, CURRENT_PROTOCOL_VERSION
+            ));
+        }
+        if let Some(status) = status
+            && self.first(device, Mismatch::Status(status.clone()))
+        {
+            lines.push(format!(
+                "{} sent a '{}' payload, which this commander doesn't know; it is shown raw.",
+                device, status
+            ));
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        lines.push("The device is newer than this commander; upgrade the commander.".to_string());
+        Some(lines.join("\n"))
+    }
+
+    fn first(&mut self, device: &str, mismatch: Mismatch) -> bool {
+        self.reported.insert((device.to_string(), mismatch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::DevicePayload;
+
+    /// Runs a stream of received messages, returning the banners shown
+    fn banners(stream: &[&str]) -> Vec<String> {
+        let mut mismatches = Mismatches::default();
+        stream
+            .iter()
+            .filter_map(|json| mismatches.banner(&decode(json).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_payloads_are_kept_raw() {
+        let json = r#"{"device":"kitchen","status":"voc_reading","index":112,"v":3}"#;
+        assert_eq!(
+            decode(json).unwrap(),
+            Decoded::Unknown(UnknownMessage {
+                device: "kitchen".to_string(),
+                status: "voc_reading".to_string(),
+                version: 3,
+                raw: serde_json::from_str(json).unwrap(),
+            })
+        );
+        // Without a version it comes from before versions were sent
+        let Decoded::Unknown(unknown) =
+            decode(r#"{"device":"kitchen","status":"voc_reading"}"#).unwrap()
+        else {
+            panic!("known");
+        };
+        assert_eq!(unknown.version, LEGACY_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn broken_messages_are_still_errors() {
+        for json in [
+            "not json",
+            // A known status with a field missing
+            r#"{"device":"kitchen","status":"success","co2":612}"#,
+            // Nobody to warn about
+            r#"{"status":"voc_reading"}"#,
+            r#"{"device":"kitchen"}"#,
+        ] {
+            assert!(decode(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn each_mismatch_is_reported_once_per_device() {
+        let current = DeviceMessage::new("kitchen", DevicePayload::Alive { uptime_seconds: 5 })
+            .to_json()
+            .unwrap();
+        let shown = banners(&[
+            &current,
+            r#"{"device":"kitchen","status":"voc_reading","index":112,"v":2}"#,
+            r#"{"device":"kitchen","status":"voc_reading","index":118,"v":2}"#,
+            r#"{"device":"bedroom","status":"voc_reading","index":97,"v":2}"#,
+            r#"{"device":"kitchen","status":"noise_level","dba":41,"v":2}"#,
+            &current,
+        ]);
+        assert_eq!(
+            shown,
+            [
+                "kitchen sent a 'voc_reading' payload, which this commander doesn't know; \
+                 it is shown raw.\n\
+                 The device is newer than this commander; upgrade the commander.",
+                "bedroom sent a 'voc_reading' payload, which this commander doesn't know; \
+                 it is shown raw.\n\
+                 The device is newer than this commander; upgrade the commander.",
+                "kitchen sent a 'noise_level' payload, which this commander doesn't know; \
+                 it is shown raw.\n\
+                 The device is newer than this commander; upgrade the commander.",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_newer_protocol_is_reported_even_for_known_payloads() {
+        let shown = banners(&[
+            r#"{"device":"kitchen","status":"alive","uptime_seconds":5,"v":3}"#,
+            r#"{"device":"kitchen","status":"alive","uptime_seconds":65,"v":3}"#,
+            r#"{"device":"kitchen","status":"voc_reading","index":112,"v":3}"#,
+            r#"{"device":"kitchen","status":"alive","uptime_seconds":5,"v":4}"#,
+        ]);
+        assert_eq!(
+            shown,
+            [
+                "kitchen speaks protocol version 3, this commander understands up to 2.\n\
+                 The device is newer than this commander; upgrade the commander.",
+                "kitchen sent a 'voc_reading' payload, which this commander doesn't know; \
+                 it is shown raw.\n\
+                 The device is newer than this commander; upgrade the commander.",
+                "kitchen speaks protocol version 4, this commander understands up to 2.\n\
+                 The device is newer than this commander; upgrade the commander.",
+            ]
+        );
+    }
+}
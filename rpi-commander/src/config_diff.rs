@@ -0,0 +1,487 @@
+//! `config diff`: what changed in a device's configuration.
+//!
+//! The device is asked for its configuration with `get_config` (retained on
+//! its own command topic, so it answers on its next wake) and the answer is
+//! compared field by field with an earlier snapshot. Snapshots come from the
+//! processor's history (`PROCESSOR_URL`, the processor's web server) or from
+//! a JSON file, such as a saved history entry or `config` message.
+//! `--against` without a value offers the latest snapshot times stored in
+//! InfluxDB (`INFLUXDB_URL`) to pick from.
+//!
+//! The comparison works on the JSON form, so fields added to the config
+//! payload later show up without changes here.
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Local, Utc};
+use clap::{Args, Subcommand};
+use rumqttc::{Event, Packet, QoS};
+use serde_json::{Map, Value};
+use shared_types::{DeviceCommand, DeviceMessage, DevicePayload, topics};
+
+use crate::age;
+use crate::render::TextRenderer;
+use crate::select;
+use crate::setup;
+
+const DEFAULT_PROCESSOR_URL: &str = "http://localhost:8080";
+
+/// Where the processor stores configuration snapshots
+const SNAPSHOT_TABLE: &str = "device_config";
+
+/// Snapshot times offered by `--against` without a value
+const PICK_LIMIT: usize = 20;
+
+/// Keys of a snapshot or message that aren't configuration
+const ENVELOPE: [&str; 3] = ["device", "status", "time"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(Value),
+    Removed(Value),
+    Changed { before: Value, after: Value },
+}
+
+/// Field-by-field differences, by dotted path. Nested objects are compared
+/// field by field, anything else as a whole; a null counts as absent.
+pub fn diff(before: &Value, after: &Value) -> Vec<(String, Change)> {
+    let mut changes = Vec::new();
+    walk("", before, after, &mut changes);
+    changes
+}
+
+fn walk(path: &str, before: &Value, after: &Value, changes: &mut Vec<(String, Change)>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                walk(
+                    &path,
+                    before.get(key).unwrap_or(&Value::Null),
+                    after.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if before == after => {}
+        (Value::Null, after) => changes.push((path.to_string(), Change::Added(after.clone()))),
+        (before, Value::Null) => changes.push((path.to_string(), Change::Removed(before.clone()))),
+        (before, after) => changes.push((
+            path.to_string(),
+            Change::Changed {
+                before: before.clone(),
+                after: after.clone(),
+            },
+        )),
+    }
+}
+
+/// The configuration fields of a snapshot, history entry or `config`
+/// message.
+pub fn config_fields(value: Value) -> anyhow::Result<Value> {
+    let Value::Object(mut fields) = value else {
+        bail!("expected a JSON object");
+    };
+    if fields.get("status").is_some_and(|s| s != "config") {
+        bail!("not a config message (status {})", fields["status"]);
+    }
+    for key in ENVELOPE {
+        fields.remove(key);
+    }
+    Ok(Value::Object(fields))
+}
+
+/// One line per change, `+` added, `-` removed and `~` changed.
+pub fn render(changes: &[(String, Change)], renderer: &TextRenderer) -> String {
+    if changes.is_empty() {
+        return renderer.success("  No differences");
+    }
+    changes
+        .iter()
+        .map(|(path, change)| match change {
+            Change::Added(value) => renderer.success(&format!("  + {}: {}", path, value)),
+            Change::Removed(value) => renderer.error(&format!("  - {}: {}", path, value)),
+            Change::Changed { before, after } => {
+                renderer.warning(&format!("  ~ {}: {} -> {}", path, before, after))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// What the device's current configuration is compared with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Against {
+    /// The latest snapshot the processor stored before this run
+    Latest,
+    /// The snapshot in effect at this time
+    Time(DateTime<Utc>),
+    File(PathBuf),
+    /// One of the stored snapshots, picked interactively
+    Pick,
+}
+
+impl Against {
+    /// A timestamp if it parses as RFC3339, a file otherwise. An empty
+    /// value is `--against` given alone.
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            None => Against::Latest,
+            Some("") => Against::Pick,
+            Some(value) => match DateTime::parse_from_rfc3339(value) {
+                Ok(time) => Against::Time(time.with_timezone(&Utc)),
+                Err(_) => Against::File(PathBuf::from(value)),
+            },
+        }
+    }
+}
+
+/// The latest snapshot of `device` taken at or before `before`.
+pub async fn fetch_snapshot(
+    reqwest_client: &reqwest::Client,
+    processor_url: &str,
+    device: &str,
+    before: DateTime<Utc>,
+) -> anyhow::Result<(DateTime<Utc>, Value)> {
+    let url = format!(
+        "{}/api/devices/{}/config/history",
+        processor_url.trim_end_matches('/'),
+        device
+    );
+    let response = reqwest_client
+        .get(&url)
+        .query(&[("before", before.to_rfc3339()), ("limit", "1".to_string())])
+        .send()
+        .await
+        .with_context(|| format!("couldn't reach the processor at {}", processor_url))?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "processor answered {}: {}",
+            status,
+            response.text().await.unwrap_or_default().trim()
+        );
+    }
+    let snapshots: Vec<Map<String, Value>> = serde_json::from_str(&response.text().await?)?;
+    let snapshot = snapshots.into_iter().next().ok_or_else(|| {
+        anyhow!(
+            "no stored configuration for {} at or before {}",
+            device,
+            before.to_rfc3339()
+        )
+    })?;
+    let time = snapshot
+        .get("time")
+        .and_then(Value::as_str)
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .ok_or_else(|| anyhow!("snapshot without a valid time"))?
+        .with_timezone(&Utc);
+    Ok((time, config_fields(Value::Object(snapshot))?))
+}
+
+pub fn load_file(path: &std::path::Path) -> anyhow::Result<Value> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("couldn't read {}", path.display()))?;
+    let value: Value = serde_json::from_str(&text)
+        .with_context(|| format!("{} is not valid JSON", path.display()))?;
+    config_fields(value).with_context(|| format!("{} is not a configuration", path.display()))
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Ask a device for its configuration and show what changed since an
+    /// earlier snapshot
+    Diff {
+        device: String,
+
+        /// A time (RFC3339) to compare with the snapshot in effect then, or
+        /// a JSON file; alone, pick one of the stored snapshots [default:
+        /// the latest stored snapshot]
+        #[arg(long, value_name = "TIMESTAMP|FILE", num_args = 0..=1, default_missing_value = "")]
+        against: Option<String>,
+
+        /// Give up if the device hasn't answered after this long
+        #[arg(long, value_name = "SECONDS", default_value_t = 900)]
+        timeout: u64,
+    },
+}
+
+fn snapshot_label(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    format!(
+        "snapshot of {} ({})",
+        time.to_rfc3339(),
+        age::relative(time.fixed_offset(), now.fixed_offset())
+    )
+}
+
+/// The time of a stored snapshot of `device`, chosen by the user
+async fn pick_snapshot(
+    reqwest_client: &reqwest::Client,
+    device: &str,
+) -> anyhow::Result<DateTime<Utc>> {
+    let settings = setup::InfluxSettings::from_env()
+        .ok_or_else(|| anyhow!("picking a snapshot needs INFLUXDB_URL"))?;
+    let times = select::recent_times(
+        reqwest_client,
+        &settings,
+        SNAPSHOT_TABLE,
+        device,
+        PICK_LIMIT,
+    )
+    .await?;
+    if times.is_empty() {
+        bail!("no stored configuration for {}", device);
+    }
+    let labels = select::time_labels(&times, Local::now().fixed_offset());
+    let index = select::select("Snapshot:", &labels).ok_or_else(|| anyhow!("cancelled"))?;
+    Ok(times[index])
+}
+
+pub async fn run(args: &ConfigArgs, renderer: TextRenderer) -> anyhow::Result<()> {
+    let ConfigAction::Diff {
+        device,
+        against,
+        timeout,
+    } = &args.action;
+
+    // The earlier side first, so a missing snapshot fails before the wait
+    let processor_url =
+        std::env::var("PROCESSOR_URL").unwrap_or_else(|_| DEFAULT_PROCESSOR_URL.to_string());
+    let reqwest_client = reqwest::Client::new();
+    let (label, before) = match Against::parse(against.as_deref()) {
+        Against::Latest => {
+            let (time, config) =
+                fetch_snapshot(&reqwest_client, &processor_url, device, Utc::now()).await?;
+            (snapshot_label(time, Utc::now()), config)
+        }
+        Against::Time(at) => {
+            let (time, config) =
+                fetch_snapshot(&reqwest_client, &processor_url, device, at).await?;
+            (snapshot_label(time, Utc::now()), config)
+        }
+        Against::File(path) => (path.display().to_string(), load_file(&path)?),
+        Against::Pick => {
+            let at = pick_snapshot(&reqwest_client, device).await?;
+            let (time, config) =
+                fetch_snapshot(&reqwest_client, &processor_url, device, at).await?;
+            (snapshot_label(time, Utc::now()), config)
+        }
+    };
+
+    let settings = setup::BrokerSettings::from_env()?;
+    let (client, mut eventloop) = setup::connect(&settings, "rpi-commander-config").await?;
+    setup::subscribe_responses(&client, &mut eventloop).await?;
+    client
+        .publish(
+            topics::command_topic(device),
+            QoS::AtLeastOnce,
+            true,
+            DeviceCommand::GetConfig.to_json()?,
+        )
+        .await?;
+    println!(
+        "Asked {} for its configuration, waiting for it to wake",
+        device
+    );
+
+    let config = setup::wait_for(
+        &mut eventloop,
+        Duration::from_secs(*timeout),
+        |event| match event {
+            Event::Incoming(Packet::Publish(publish)) if !publish.retain => {
+                match serde_json::from_slice::<DeviceMessage>(&publish.payload) {
+                    Ok(DeviceMessage {
+                        device: from,
+                        payload: DevicePayload::Config(config),
+                        ..
+                    }) if from == *device => Some(Ok(config)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+    )
+    .await
+    .with_context(|| format!("{} didn't report its configuration", device))?;
+    let _ = client.disconnect().await;
+
+    let now = config_fields(serde_json::to_value(&config)?)?;
+    println!("{}: now against {}", device, label);
+    println!("{}", render(&diff(&before, &now), &renderer));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::UnitSystem;
+    use serde_json::json;
+
+    fn renderer() -> TextRenderer {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: crate::age::DEFAULT_ABSOLUTE_AFTER,
+        }
+    }
+
+    #[test]
+    fn added_removed_and_changed_fields() {
+        let before = json!({
+            "firmware_version": "0.1.0",
+            "sleep_seconds": 300,
+            "altitude_m": 120,
+            "quiet_hours": null,
+            "wifi_ssid": "home",
+        });
+        let after = json!({
+            "firmware_version": "0.2.0",
+            "sleep_seconds": 600,
+            "quiet_hours": "22-7",
+            "wifi_ssid": "home",
+        });
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("altitude_m".to_string(), Change::Removed(json!(120))),
+                (
+                    "firmware_version".to_string(),
+                    Change::Changed {
+                        before: json!("0.1.0"),
+                        after: json!("0.2.0"),
+                    }
+                ),
+                ("quiet_hours".to_string(), Change::Added(json!("22-7"))),
+                (
+                    "sleep_seconds".to_string(),
+                    Change::Changed {
+                        before: json!(300),
+                        after: json!(600),
+                    }
+                ),
+            ]
+        );
+        assert!(diff(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn nested_objects_are_compared_by_field_and_arrays_whole() {
+        let before = json!({ "sensor": { "mode": "periodic", "asc": true }, "hours": [22, 7] });
+        let after = json!({ "sensor": { "mode": "single_shot", "asc": true }, "hours": [23, 7] });
+        let paths: Vec<String> = diff(&before, &after).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, vec!["hours", "sensor.mode"]);
+        // A value becoming an object is one change
+        assert_eq!(
+            diff(&json!({ "a": 1 }), &json!({ "a": { "b": 1 } })),
+            vec![(
+                "a".to_string(),
+                Change::Changed {
+                    before: json!(1),
+                    after: json!({ "b": 1 }),
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn renders_one_marked_line_per_change() {
+        let changes = diff(
+            &json!({ "sleep_seconds": 300, "altitude_m": 120 }),
+            &json!({ "sleep_seconds": 600, "quiet_hours": "22-7" }),
+        );
+        assert_eq!(
+            render(&changes, &renderer()),
+            "  - altitude_m: 120\n  + quiet_hours: \"22-7\"\n  ~ sleep_seconds: 300 -> 600"
+        );
+        assert_eq!(render(&[], &renderer()), "  No differences");
+    }
+
+    #[test]
+    fn snapshots_and_messages_lose_their_envelope() {
+        assert_eq!(
+            config_fields(json!({
+                "device": "kitchen",
+                "status": "config",
+                "sleep_seconds": 300,
+            }))
+            .unwrap(),
+            json!({ "sleep_seconds": 300 })
+        );
+        assert_eq!(
+            config_fields(json!({ "time": "2025-01-15T10:00:00Z", "sleep_seconds": 300 })).unwrap(),
+            json!({ "sleep_seconds": 300 })
+        );
+        assert!(config_fields(json!({ "status": "measurement_success" })).is_err());
+        assert!(config_fields(json!([1, 2])).is_err());
+    }
+
+    #[test]
+    fn against_is_a_time_or_a_file() {
+        assert_eq!(Against::parse(None), Against::Latest);
+        assert_eq!(Against::parse(Some("")), Against::Pick);
+        assert_eq!(
+            Against::parse(Some("2025-01-15T10:00:00Z")),
+            Against::Time(
+                DateTime::parse_from_rfc3339("2025-01-15T10:00:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc)
+            )
+        );
+        assert_eq!(
+            Against::parse(Some("kitchen.json")),
+            Against::File(PathBuf::from("kitchen.json"))
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_the_snapshot_in_effect_from_the_processor() {
+        use axum::{Json, Router, extract::Query, routing::get};
+        use std::collections::HashMap;
+
+        async fn history(Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+            assert_eq!(query["before"], "2025-01-15T11:00:00+00:00");
+            assert_eq!(query["limit"], "1");
+            Json(json!([{
+                "time": "2025-01-15T10:00:00Z",
+                "firmware_version": "0.1.0",
+                "sleep_seconds": 300,
+            }]))
+        }
+        let app = Router::new().route("/api/devices/kitchen/config/history", get(history));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let at = DateTime::parse_from_rfc3339("2025-01-15T11:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let client = reqwest::Client::new();
+        let (time, config) = fetch_snapshot(&client, &format!("http://{}/", addr), "kitchen", at)
+            .await
+            .unwrap();
+        assert_eq!(time.to_rfc3339(), "2025-01-15T10:00:00+00:00");
+        assert_eq!(
+            config,
+            json!({ "firmware_version": "0.1.0", "sleep_seconds": 300 })
+        );
+
+        let error = fetch_snapshot(&client, &format!("http://{}", addr), "bedroom", at)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("404"), "{:#}", error);
+    }
+}
@@ -0,0 +1,113 @@
+//! Confirms settings a device is trying out.
+//!
+//! `set_deep_sleep_time` and `set_mqtt_policy` only stick once confirmed
+//! (see `shared_types::config_trial`); until then the device rolls them
+//! back after a few wakes. A live `pending_confirmation` answering one of
+//! this session's commands shows the device still reaches the broker with
+//! the new setting, which is all the verification there is to do, so the
+//! commander sends the confirmation right away.
+
+use std::collections::HashMap;
+
+use shared_types::config_trial::ConfigChange;
+use shared_types::{DeviceCommand, DeviceMessage, DevicePayload};
+
+/// Commands this session sent that go on trial, by command id
+#[derive(Debug, Default)]
+pub struct Confirmations {
+    sent: HashMap<u32, &'static str>,
+}
+
+impl Confirmations {
+    /// Remembers `command` if the device will ask to confirm it.
+    pub fn sent(&mut self, id: u32, command: &DeviceCommand) {
+        if ConfigChange::of(command).is_some() {
+            self.sent.insert(id, command.name());
+        }
+    }
+
+    /// The `confirm_config` to send for `message`, if it is a live
+    /// `pending_confirmation` for a command this session sent. Each command
+    /// is confirmed once.
+    pub fn verify(&mut self, message: &DeviceMessage, retained: bool) -> Option<DeviceCommand> {
+        let DevicePayload::PendingConfirmation { id, command, .. } = &message.payload else {
+            return None;
+        };
+        // A retained one is from an earlier wake, which says nothing about now
+        if retained || message.in_reply_to != Some(*id) {
+            return None;
+        }
+        if self.sent.get(id) != Some(&command.as_str()) {
+            return None;
+        }
+        self.sent.remove(id);
+        Some(DeviceCommand::ConfirmConfig { pending_id: *id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(id: u32, command: &str, in_reply_to: Option<u32>) -> DeviceMessage {
+        let message = DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::PendingConfirmation {
+                id,
+                command: command.to_string(),
+                wakes_left: 3,
+            },
+        );
+        match in_reply_to {
+            Some(id) => message.replying_to(id),
+            None => message,
+        }
+    }
+
+    #[test]
+    fn confirms_a_live_trial_of_a_command_it_sent_once() {
+        let mut confirmations = Confirmations::default();
+        confirmations.sent(41, &DeviceCommand::SetDeepSleepTime { seconds: 900 });
+        assert_eq!(
+            confirmations.verify(&pending(41, "set_deep_sleep_time", Some(41)), false),
+            Some(DeviceCommand::ConfirmConfig { pending_id: 41 })
+        );
+        assert_eq!(
+            confirmations.verify(&pending(41, "set_deep_sleep_time", Some(41)), false),
+            None
+        );
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        let mut confirmations = Confirmations::default();
+        confirmations.sent(41, &DeviceCommand::SetDeepSleepTime { seconds: 900 });
+        confirmations.sent(42, &DeviceCommand::GetDeepSleepTime);
+        for (message, retained) in [
+            // From before the session
+            (pending(41, "set_deep_sleep_time", Some(41)), true),
+            // Not an answer to the command
+            (pending(41, "set_deep_sleep_time", None), false),
+            (pending(41, "set_deep_sleep_time", Some(7)), false),
+            // Some other command under the same id
+            (pending(41, "set_mqtt_policy", Some(41)), false),
+            // Never on trial, or not sent from here
+            (pending(42, "get_deep_sleep_time", Some(42)), false),
+            (pending(43, "set_deep_sleep_time", Some(43)), false),
+            (
+                DeviceMessage::new(
+                    "esp32-scd40",
+                    DevicePayload::SetDeepSleepTimeSuccess { seconds: 900 },
+                ),
+                false,
+            ),
+        ] {
+            assert_eq!(
+                confirmations.verify(&message, retained),
+                None,
+                "{:?}",
+                message
+            );
+        }
+    }
+}
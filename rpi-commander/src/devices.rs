@@ -0,0 +1,238 @@
+//! The devices heard from in this session, for `devices` and `devices
+//! watch`: when each last published and whether that's longer ago than its
+//! sleep interval allows. `last` shows the newest measurement kept here.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset};
+use shared_types::{DeviceMessage, DevicePayload};
+
+use crate::age::{self, Freshness};
+use crate::render::TextRenderer;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Seen {
+    /// `None` when only a retained message without a timestamp was seen
+    last: Option<DateTime<FixedOffset>>,
+    /// From `config` and deep sleep answers
+    sleep_seconds: Option<u64>,
+    /// The newest measurement and when it was received. A retained one only
+    /// counts until a live one arrives.
+    measurement: Option<(DeviceMessage, DateTime<FixedOffset>, bool)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Devices {
+    seen: BTreeMap<String, Seen>,
+}
+
+impl Devices {
+    /// A retained message can be arbitrarily old, so only its device
+    /// timestamp says when the device was last heard from.
+    pub fn observe(
+        &mut self,
+        message: &DeviceMessage,
+        received_at: DateTime<FixedOffset>,
+        retained: bool,
+    ) {
+        let seen = self.seen.entry(message.device.clone()).or_default();
+        let sent = message
+            .ts
+            .and_then(|ts| DateTime::from_timestamp_millis(ts as i64))
+            .map(|sent| sent.with_timezone(received_at.offset()))
+            .or((!retained).then_some(received_at));
+        if sent > seen.last {
+            seen.last = sent;
+        }
+        let replaces = match &seen.measurement {
+            Some((_, _, was_retained)) => !retained || *was_retained,
+            None => true,
+        };
+        if replaces && matches!(message.payload, DevicePayload::MeasurementSuccess { .. }) {
+            seen.measurement = Some((message.clone(), received_at, retained));
+        }
+        match &message.payload {
+            DevicePayload::Config(config) => seen.sleep_seconds = Some(config.sleep_seconds),
+            DevicePayload::SetDeepSleepTimeSuccess { seconds }
+            | DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
+                seen.sleep_seconds = Some(*seconds)
+            }
+            _ => {}
+        }
+    }
+
+    /// Names of the devices heard from, sorted
+    pub fn names(&self) -> Vec<String> {
+        self.seen.keys().cloned().collect()
+    }
+
+    /// `device`'s newest measurement and when it was received
+    pub fn last_measurement(&self, device: &str) -> Option<(DeviceMessage, DateTime<FixedOffset>)> {
+        let (message, received_at, _) = self.seen.get(device)?.measurement.as_ref()?;
+        Some((message.clone(), *received_at))
+    }
+
+    pub fn render(&self, renderer: &TextRenderer, now: DateTime<FixedOffset>) -> String {
+        if self.seen.is_empty() {
+            return "No devices heard from in this session".to_string();
+        }
+        let rows: Vec<(&String, String, String, Freshness)> = self
+            .seen
+            .iter()
+            .map(|(device, seen)| {
+                let last = seen
+                    .last
+                    .map_or_else(|| "unknown".to_string(), |last| renderer.time(last, now));
+                let sleep = seen
+                    .sleep_seconds
+                    .map_or_else(|| "?".to_string(), |s| format!("{} s", s));
+                let freshness = match seen.last {
+                    Some(last) => age::freshness(last, now, seen.sleep_seconds),
+                    None => Freshness::Unknown,
+                };
+                (device, last, sleep, freshness)
+            })
+            .collect();
+        let device_width = rows
+            .iter()
+            .map(|r| r.0.len())
+            .chain(["Device".len()])
+            .max()
+            .unwrap_or(0);
+        let last_width = rows
+            .iter()
+            .map(|r| r.1.chars().count())
+            .chain(["Last message".len()])
+            .max()
+            .unwrap_or(0);
+        let mut lines = vec![format!(
+            "  {:<device_width$}  {:<last_width$}  Sleep",
+            "Device", "Last message"
+        )];
+        for (device, last, sleep, freshness) in &rows {
+            let mut line = format!(
+                "  {:<device_width$}  {:<last_width$}  {}",
+                device, last, sleep
+            );
+            if *freshness == Freshness::Stale {
+                line.push_str(&format!("  {}", renderer.warning("stale")));
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::UnitSystem;
+    use chrono::Duration;
+    use shared_types::DevicePayload;
+
+    fn now() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2025-01-15T14:05:09+01:00").unwrap()
+    }
+
+    fn renderer() -> TextRenderer {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: Duration::hours(1),
+        }
+    }
+
+    #[test]
+    fn table_flags_devices_silent_for_two_intervals() {
+        let mut devices = Devices::default();
+        let kitchen = DeviceMessage::new("kitchen", DevicePayload::measurement(612, 21.5, 40.0));
+        devices.observe(&kitchen, now() - Duration::seconds(700), false);
+        devices.observe(
+            &DeviceMessage::new(
+                "kitchen",
+                DevicePayload::GetDeepSleepTimeSuccess { seconds: 300 },
+            ),
+            now() - Duration::seconds(601),
+            false,
+        );
+        devices.observe(
+            &DeviceMessage::new("hall", DevicePayload::measurement(700, 20.0, 45.0)),
+            now() - Duration::seconds(12),
+            false,
+        );
+        assert_eq!(
+            devices.render(&renderer(), now()),
+            "  Device   Last message  Sleep\n  \
+             hall     12 s ago      ?\n  \
+             kitchen  10 m ago      300 s  stale"
+        );
+    }
+
+    #[test]
+    fn retained_messages_count_by_their_timestamp() {
+        let mut devices = Devices::default();
+        let old = now() - Duration::hours(3);
+        devices.observe(
+            &DeviceMessage::new("kitchen", DevicePayload::measurement(612, 21.5, 40.0))
+                .stamped(Some(old.timestamp_millis() as u64), 1),
+            now(),
+            true,
+        );
+        devices.observe(
+            &DeviceMessage::new("hall", DevicePayload::measurement(700, 20.0, 45.0)),
+            now(),
+            true,
+        );
+        assert_eq!(
+            devices.render(&renderer(), now()),
+            "  Device   Last message         Sleep\n  \
+             hall     unknown              ?\n  \
+             kitchen  2025-01-15 11:05:09  ?"
+        );
+        // An older retained message doesn't move the time back
+        devices.observe(
+            &DeviceMessage::new("hall", DevicePayload::measurement(700, 20.0, 45.0)),
+            now(),
+            false,
+        );
+        devices.observe(
+            &DeviceMessage::new("hall", DevicePayload::measurement(700, 20.0, 45.0))
+                .stamped(Some(old.timestamp_millis() as u64), 1),
+            now(),
+            true,
+        );
+        assert!(
+            devices
+                .render(&renderer(), now())
+                .contains("hall     just now")
+        );
+    }
+
+    #[test]
+    fn last_measurement_prefers_live_ones() {
+        let mut devices = Devices::default();
+        let reading =
+            |co2| DeviceMessage::new("kitchen", DevicePayload::measurement(co2, 21.5, 40.0));
+        devices.observe(&reading(612), now() - Duration::seconds(60), false);
+        devices.observe(
+            &DeviceMessage::new(
+                "kitchen",
+                DevicePayload::GetDeepSleepTimeSuccess { seconds: 300 },
+            ),
+            now() - Duration::seconds(30),
+            false,
+        );
+        // A retained one arriving later is older than the live one
+        devices.observe(&reading(480), now(), true);
+        assert_eq!(
+            devices.last_measurement("kitchen"),
+            Some((reading(612), now() - Duration::seconds(60)))
+        );
+        devices.observe(&reading(655), now(), false);
+        assert_eq!(
+            devices.last_measurement("kitchen"),
+            Some((reading(655), now()))
+        );
+        assert_eq!(devices.last_measurement("hall"), None);
+    }
+}
@@ -0,0 +1,506 @@
+//! Fleet operations: one OTA queued for every device in a group, with each
+//! device's progress followed through the response stream.
+//!
+//! Groups come from `DEVICE_GROUPS`, e.g.
+//! `upstairs=esp32-bedroom,esp32-office;downstairs=esp32-kitchen`. The
+//! command is retained on each member's own topic
+//! (`sensors/esp32/command/<device>`), so the first device to wake doesn't
+//! clear it for the others. Devices only pick it up on their next wake, so
+//! an update can take a few sleep intervals to reach the whole group.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use clap::{Args, Subcommand};
+use rumqttc::{Event, Packet, QoS};
+use shared_types::{DeviceCommand, DeviceMessage, DevicePayload, topics};
+
+use crate::render::TextRenderer;
+use crate::setup;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Groups(BTreeMap<String, Vec<String>>);
+
+impl Groups {
+    /// A missing `DEVICE_GROUPS` means no groups.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("DEVICE_GROUPS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|e| anyhow!("invalid DEVICE_GROUPS: {}", e)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn members(&self, group: &str) -> anyhow::Result<&[String]> {
+        self.0.get(group).map(Vec::as_slice).ok_or_else(|| {
+            if self.0.is_empty() {
+                anyhow!("unknown group '{}', DEVICE_GROUPS is not set", group)
+            } else {
+                anyhow!(
+                    "unknown group '{}', known groups: {}",
+                    group,
+                    self.0.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            }
+        })
+    }
+}
+
+impl FromStr for Groups {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut groups = BTreeMap::new();
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, devices) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not name=device,device", entry))?;
+            let devices: Vec<String> = devices
+                .split(',')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .map(str::to_string)
+                .collect();
+            if devices.is_empty() {
+                return Err(format!("group '{}' has no devices", name.trim()));
+            }
+            groups.insert(name.trim().to_string(), devices);
+        }
+        Ok(Self(groups))
+    }
+}
+
+/// Where one device is in the update. `Updated` and `Failed` are final.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberState {
+    /// Retained on the broker, the device hasn't woken up yet
+    Queued,
+    Downloading {
+        percent: u8,
+    },
+    Updated {
+        version: String,
+    },
+    Failed {
+        detail: String,
+    },
+}
+
+impl MemberState {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            MemberState::Updated { .. } | MemberState::Failed { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FleetOperation {
+    pub url: String,
+    /// The group name, or the device when a single one was targeted
+    pub target: String,
+    members: BTreeMap<String, MemberState>,
+}
+
+impl FleetOperation {
+    pub fn new(url: &str, target: &str, members: &[String]) -> Self {
+        Self {
+            url: url.to_string(),
+            target: target.to_string(),
+            members: members
+                .iter()
+                .map(|device| (device.clone(), MemberState::Queued))
+                .collect(),
+        }
+    }
+
+    pub fn command(&self) -> DeviceCommand {
+        DeviceCommand::Ota {
+            url: self.url.clone(),
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.keys().map(String::as_str)
+    }
+
+    /// Applies a response and returns whether anything changed. Retained
+    /// messages are from before the command was queued and must not be
+    /// passed in.
+    pub fn observe(&mut self, message: &DeviceMessage) -> bool {
+        let Some(state) = self.members.get_mut(&message.device) else {
+            return false;
+        };
+        if state.is_final() {
+            return false;
+        }
+        let next = match &message.payload {
+            DevicePayload::OtaProgress { percent } => MemberState::Downloading {
+                percent: (*percent).min(100),
+            },
+            DevicePayload::OtaSuccess { version } => MemberState::Updated {
+                version: version.clone(),
+            },
+            DevicePayload::OtaError { detail, .. } => MemberState::Failed {
+                detail: detail.clone(),
+            },
+            _ => return false,
+        };
+        if *state == next {
+            return false;
+        }
+        *state = next;
+        true
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.members.values().all(MemberState::is_final)
+    }
+
+    pub fn updated(&self) -> usize {
+        self.members
+            .values()
+            .filter(|s| matches!(s, MemberState::Updated { .. }))
+            .count()
+    }
+
+    pub fn failed(&self) -> Vec<(&str, &str)> {
+        self.members
+            .iter()
+            .filter_map(|(device, state)| match state {
+                MemberState::Failed { detail } => Some((device.as_str(), detail.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn pending(&self) -> Vec<&str> {
+        self.members
+            .iter()
+            .filter(|(_, state)| !state.is_final())
+            .map(|(device, _)| device.as_str())
+            .collect()
+    }
+
+    /// One row per member under a header with the totals.
+    pub fn render(&self, renderer: &TextRenderer) -> String {
+        let width = self.members.keys().map(String::len).max().unwrap_or(0);
+        let mut lines = vec![format!(
+            "OTA {} -> {}: {}/{} updated, {} failed, {} pending",
+            self.url,
+            self.target,
+            self.updated(),
+            self.members.len(),
+            self.failed().len(),
+            self.pending().len()
+        )];
+        for (device, state) in &self.members {
+            let status = match state {
+                MemberState::Queued => "queued".to_string(),
+                MemberState::Downloading { percent } => {
+                    format!("{} {:>3}%", progress_bar(*percent), percent)
+                }
+                MemberState::Updated { version } => {
+                    renderer.success(&format!("updated, running {}", version))
+                }
+                MemberState::Failed { detail } => renderer.error(&format!("failed: {}", detail)),
+            };
+            lines.push(format!("  {:<width$}  {}", device, status));
+        }
+        lines.join("\n")
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} of {} devices updated",
+            self.updated(),
+            self.members.len()
+        );
+        let failed = self.failed();
+        if !failed.is_empty() {
+            let failed: Vec<String> = failed
+                .iter()
+                .map(|(device, detail)| format!("{} ({})", device, detail))
+                .collect();
+            summary.push_str(&format!("; failed: {}", failed.join(", ")));
+        }
+        let pending = self.pending();
+        if !pending.is_empty() {
+            summary.push_str(&format!("; no result from: {}", pending.join(", ")));
+        }
+        summary
+    }
+}
+
+fn progress_bar(percent: u8) -> String {
+    const WIDTH: usize = 20;
+    let filled = usize::from(percent.min(100)) * WIDTH / 100;
+    format!("[{}{}]", "#".repeat(filled), ".".repeat(WIDTH - filled))
+}
+
+#[derive(Args, Debug)]
+pub struct FleetArgs {
+    #[command(subcommand)]
+    pub action: FleetAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FleetAction {
+    /// Queue a firmware update for a group and follow it until every
+    /// device has answered; exits non-zero if any device didn't update
+    Ota {
+        /// Firmware image URL the devices download
+        url: String,
+
+        /// Group from DEVICE_GROUPS [default: just DEFAULT_DEVICE]
+        #[arg(long)]
+        group: Option<String>,
+
+        /// Give up on devices that haven't answered after this long
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+        timeout: u64,
+    },
+}
+
+/// Resolves `--group`, or the default device without one.
+pub fn operation(url: &str, group: Option<&str>, device: &str) -> anyhow::Result<FleetOperation> {
+    match group {
+        Some(group) => Ok(FleetOperation::new(
+            url,
+            group,
+            Groups::from_env()?.members(group)?,
+        )),
+        None => Ok(FleetOperation::new(url, device, &[device.to_string()])),
+    }
+}
+
+/// One-shot mode: queues the update, prints the table on every change and
+/// fails unless every device updated.
+pub async fn run(args: &FleetArgs, renderer: TextRenderer) -> anyhow::Result<()> {
+    let FleetAction::Ota {
+        url,
+        group,
+        timeout,
+    } = &args.action;
+    let device = std::env::var("DEFAULT_DEVICE").unwrap_or_else(|_| "esp32-scd40".to_string());
+    let mut fleet = operation(url, group.as_deref(), &device)?;
+
+    let settings = setup::BrokerSettings::from_env()?;
+    let (client, mut eventloop) = setup::connect(&settings, "rpi-commander-fleet").await?;
+    setup::subscribe_responses(&client, &mut eventloop).await?;
+    let command = fleet.command().to_json()?;
+    for member in fleet.members() {
+        client
+            .publish(
+                topics::command_topic(member),
+                QoS::AtLeastOnce,
+                true,
+                command.clone(),
+            )
+            .await?;
+    }
+    println!("{}\n", fleet.render(&renderer));
+
+    let deadline = Instant::now() + Duration::from_secs(*timeout);
+    while !fleet.is_finished() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let message = setup::wait_for(&mut eventloop, remaining, |event| match event {
+            Event::Incoming(Packet::Publish(publish)) if !publish.retain => {
+                serde_json::from_slice::<DeviceMessage>(&publish.payload)
+                    .ok()
+                    .map(Ok)
+            }
+            _ => None,
+        })
+        .await;
+        match message {
+            Ok(message) => {
+                if fleet.observe(&message) {
+                    println!("{}\n", fleet.render(&renderer));
+                }
+            }
+            Err(e) if Instant::now() >= deadline => {
+                println!("{}", renderer.warning(&format!("Stopped waiting: {}", e)));
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let _ = client.disconnect().await;
+
+    println!("{}", fleet.summary());
+    if fleet.updated() < fleet.members.len() {
+        bail!(
+            "{} of {} devices did not update",
+            fleet.members.len() - fleet.updated(),
+            fleet.members.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::UnitSystem;
+    use shared_types::ErrorCode;
+
+    fn renderer() -> TextRenderer {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: crate::age::DEFAULT_ABSOLUTE_AFTER,
+        }
+    }
+
+    fn upstairs() -> FleetOperation {
+        FleetOperation::new(
+            "http://firmware.local/air-quality-0.4.0.bin",
+            "upstairs",
+            &["esp32-bedroom".to_string(), "esp32-office".to_string()],
+        )
+    }
+
+    fn progress(device: &str, percent: u8) -> DeviceMessage {
+        DeviceMessage::new(device, DevicePayload::OtaProgress { percent })
+    }
+
+    fn success(device: &str) -> DeviceMessage {
+        DeviceMessage::new(
+            device,
+            DevicePayload::OtaSuccess {
+                version: "0.4.0".to_string(),
+            },
+        )
+    }
+
+    fn failure(device: &str, detail: &str) -> DeviceMessage {
+        DeviceMessage::new(
+            device,
+            DevicePayload::OtaError {
+                code: ErrorCode::Other,
+                detail: detail.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn groups_parse() {
+        let groups: Groups = "upstairs=esp32-bedroom, esp32-office; downstairs=esp32-kitchen;"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            groups.members("upstairs").unwrap(),
+            ["esp32-bedroom", "esp32-office"]
+        );
+        assert_eq!(groups.members("downstairs").unwrap(), ["esp32-kitchen"]);
+        assert_eq!(
+            groups.members("attic").unwrap_err().to_string(),
+            "unknown group 'attic', known groups: downstairs, upstairs"
+        );
+
+        assert!("upstairs".parse::<Groups>().is_err());
+        assert!("upstairs=".parse::<Groups>().is_err());
+        assert_eq!("".parse::<Groups>().unwrap(), Groups::default());
+    }
+
+    #[test]
+    fn devices_move_through_download_to_a_result() {
+        let mut fleet = upstairs();
+        assert!(!fleet.is_finished());
+
+        assert!(fleet.observe(&progress("esp32-bedroom", 10)));
+        assert!(fleet.observe(&progress("esp32-bedroom", 60)));
+        // The same percentage twice is not a change
+        assert!(!fleet.observe(&progress("esp32-bedroom", 60)));
+        assert_eq!(
+            fleet.members.get("esp32-bedroom"),
+            Some(&MemberState::Downloading { percent: 60 })
+        );
+        assert_eq!(
+            fleet.members.get("esp32-office"),
+            Some(&MemberState::Queued)
+        );
+
+        assert!(fleet.observe(&success("esp32-bedroom")));
+        assert!(!fleet.is_finished());
+        assert!(fleet.observe(&failure("esp32-office", "download failed: HTTP 404")));
+        assert!(fleet.is_finished());
+        assert_eq!(fleet.updated(), 1);
+        assert_eq!(
+            fleet.failed(),
+            [("esp32-office", "download failed: HTTP 404")]
+        );
+    }
+
+    #[test]
+    fn results_are_final_and_other_messages_ignored() {
+        let mut fleet = upstairs();
+        assert!(fleet.observe(&failure("esp32-office", "OTA is not supported")));
+        // A late progress report or a retry's success doesn't undo a failure
+        assert!(!fleet.observe(&progress("esp32-office", 90)));
+        assert!(!fleet.observe(&success("esp32-office")));
+
+        // Measurements from members and anything from other devices
+        assert!(!fleet.observe(&DeviceMessage::new(
+            "esp32-bedroom",
+            DevicePayload::measurement(612, 22.4, 41.3)
+        )));
+        assert!(!fleet.observe(&success("esp32-kitchen")));
+        assert_eq!(
+            fleet.members.get("esp32-bedroom"),
+            Some(&MemberState::Queued)
+        );
+        assert_eq!(fleet.members.get("esp32-kitchen"), None);
+    }
+
+    #[test]
+    fn table_shows_every_member() {
+        let mut fleet = upstairs();
+        fleet.observe(&progress("esp32-office", 45));
+        assert_eq!(
+            fleet.render(&renderer()),
+            "OTA http://firmware.local/air-quality-0.4.0.bin -> upstairs: \
+             0/2 updated, 0 failed, 2 pending\n  \
+             esp32-bedroom  queued\n  \
+             esp32-office   [#########...........]  45%"
+        );
+
+        fleet.observe(&success("esp32-office"));
+        fleet.observe(&failure("esp32-bedroom", "download failed: HTTP 404"));
+        assert_eq!(
+            fleet.render(&renderer()),
+            "OTA http://firmware.local/air-quality-0.4.0.bin -> upstairs: \
+             1/2 updated, 1 failed, 0 pending\n  \
+             esp32-bedroom  failed: download failed: HTTP 404\n  \
+             esp32-office   updated, running 0.4.0"
+        );
+    }
+
+    #[test]
+    fn summary_names_failed_and_silent_devices() {
+        let mut fleet = upstairs();
+        fleet.observe(&success("esp32-bedroom"));
+        assert_eq!(
+            fleet.summary(),
+            "1 of 2 devices updated; no result from: esp32-office"
+        );
+        fleet.observe(&failure("esp32-office", "download failed: HTTP 404"));
+        assert_eq!(
+            fleet.summary(),
+            "1 of 2 devices updated; failed: esp32-office (download failed: HTTP 404)"
+        );
+
+        let mut single = FleetOperation::new(
+            "http://x.local/a.bin",
+            "esp32-scd40",
+            &["esp32-scd40".to_string()],
+        );
+        single.observe(&success("esp32-scd40"));
+        assert_eq!(single.summary(), "1 of 1 devices updated");
+    }
+}
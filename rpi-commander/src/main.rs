@@ -1,32 +1,185 @@
-use std::{env, sync::Arc, time::Duration};
-
-use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
-use shared_types::{DeviceCommand, DeviceMessage, DevicePayload};
+mod age;
+mod broker;
+mod calibration;
+mod command_line;
+mod compat;
+mod config_diff;
+mod confirm;
+mod devices;
+mod fleet;
+mod pending;
+mod probe;
+mod render;
+mod select;
+mod setup;
+mod transcript;
+
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, Local};
+use clap::{Parser, Subcommand};
+use rumqttc::{Client, Event, Packet, QoS};
+use shared_types::{
+    CommandEnvelope, CommandMessage, DeviceCommand, DeviceCommandBatch, DeviceMessage, topics,
+};
 use tokio::sync::Mutex;
 
-use log::{debug, error, info};
+use calibration::CalibrationRecord;
+use command_line::{CommandContext, ParsedCommand, execute, parse_command};
+use compat::{Decoded, Mismatches};
+use confirm::Confirmations;
+use devices::Devices;
+use fleet::FleetOperation;
+use pending::{Issuer, Pending};
+use render::{DisplayPrefs, OutputMode, TextRenderer, UnitSystem};
+use transcript::{SessionEvent, Transcript};
+
+use log::{debug, error, info, warn};
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
 
 struct Commander {
     client: Client,
     device: String,
+    prefs: Arc<std::sync::Mutex<DisplayPrefs>>,
+    /// The last fleet operation, updated by the MQTT event loop
+    fleet: Arc<std::sync::Mutex<Option<FleetOperation>>>,
+    /// Shared with the MQTT event loop, which records received messages
+    transcript: SharedTranscript,
+    /// Updated by the MQTT event loop
+    devices: Arc<std::sync::Mutex<Devices>>,
+    /// Filled here, confirmed by the MQTT event loop
+    confirmations: Arc<std::sync::Mutex<Confirmations>>,
+    /// What the commands sent from here are stamped with, see `pending`
+    issuer: Issuer,
+    /// Updated by the MQTT event loop and by every command sent
+    pending: Arc<std::sync::Mutex<Pending>>,
+    /// Collected by `batch start`, published by `batch send`
+    batch: Option<Vec<DeviceCommand>>,
+}
+
+type SharedTranscript = Arc<std::sync::Mutex<Option<Transcript>>>;
+
+/// Appends to the transcript if one is open, warning when it stops being
+/// writable.
+fn record(transcript: &SharedTranscript, event: SessionEvent, renderer: &TextRenderer) {
+    if let Some(transcript) = transcript.lock().unwrap().as_mut()
+        && let Some(e) = transcript.record(&event)
+    {
+        println!(
+            "{}\n",
+            renderer.warning(&format!(
+                "Transcript {} is no longer writable ({}); it stops here",
+                transcript.path().display(),
+                e
+            ))
+        );
+    }
 }
 
 impl Commander {
-    fn new(client: Client, device: String) -> Self {
-        Self { client, device }
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        client: Client,
+        device: String,
+        prefs: Arc<std::sync::Mutex<DisplayPrefs>>,
+        fleet: Arc<std::sync::Mutex<Option<FleetOperation>>>,
+        transcript: SharedTranscript,
+        devices: Arc<std::sync::Mutex<Devices>>,
+        confirmations: Arc<std::sync::Mutex<Confirmations>>,
+        issuer: Issuer,
+        pending: Arc<std::sync::Mutex<Pending>>,
+    ) -> Self {
+        Self {
+            client,
+            device,
+            prefs,
+            fleet,
+            transcript,
+            devices,
+            confirmations,
+            issuer,
+            pending,
+            batch: None,
+        }
+    }
+
+    fn record(&self, event: SessionEvent) {
+        record(&self.transcript, event, &self.prefs().text_renderer());
+    }
+
+    fn start_transcript(&self, path: &Path) -> anyhow::Result<()> {
+        let mut transcript = Transcript::create(path)?;
+        let started = SessionEvent::Started {
+            at: Local::now().fixed_offset(),
+            device: self.device.clone(),
+        };
+        if let Some(e) = transcript.record(&started) {
+            anyhow::bail!("can't write to {}: {}", path.display(), e);
+        }
+        if let Some(previous) = self.transcript.lock().unwrap().replace(transcript) {
+            println!("Stopped transcript {}", previous.path().display());
+        }
+        println!("Writing transcript to {}\n", path.display());
+        Ok(())
+    }
+
+    fn stop_transcript(&self) {
+        self.record(SessionEvent::Stopped {
+            at: Local::now().fixed_offset(),
+        });
+        match self.transcript.lock().unwrap().take() {
+            Some(transcript) => println!("Transcript saved to {}\n", transcript.path().display()),
+            None => println!("No transcript is being written\n"),
+        }
+    }
+
+    fn send_command(&self, command: DeviceCommand, takeover: bool) -> anyhow::Result<()> {
+        let id = command_id();
+        let envelope = self
+            .issuer
+            .stamp(command.clone().with_id(id), Local::now().fixed_offset());
+        self.send(envelope.into(), takeover)?;
+        self.confirmations.lock().unwrap().sent(id, &command);
+        Ok(())
     }
 
-    fn send_command(&self, command: DeviceCommand) -> anyhow::Result<()> {
-        let command_topic = "sensors/esp32/command";
+    fn send_batch(&self, batch: DeviceCommandBatch, takeover: bool) -> anyhow::Result<()> {
+        let envelope = self
+            .issuer
+            .stamp(batch.with_id(command_id()), Local::now().fixed_offset());
+        self.send(envelope.into(), takeover)
+    }
+
+    /// Retains a stamped command or batch on the broadcast topic, checked
+    /// against what is pending there.
+    fn send(&self, command: CommandMessage, takeover: bool) -> anyhow::Result<()> {
+        let command_topic = topics::COMMAND_BROADCAST_TOPIC;
+        let renderer = self.prefs().text_renderer();
+        let mut pending = self.pending.lock().unwrap();
+        match pending.check(command_topic, &self.issuer.operator, takeover) {
+            Ok(Some(warning)) => println!("{}", renderer.warning(&warning)),
+            Ok(None) => {}
+            Err(refusal) => anyhow::bail!(refusal),
+        }
         let command_json = command.to_json()?;
 
         println!(
-            "Sending to '{}' on topic '{}': {:?}",
-            self.device, command_topic, command
+            "Sending to '{}' on topic '{}' as command {}: {}",
+            self.device,
+            command_topic,
+            command.id().unwrap_or_default(),
+            command
         );
         debug!("Command JSON: {}", command_json);
+        pending.sent(command_topic, command.clone(), Local::now().fixed_offset());
+        drop(pending);
 
         self.client.publish(
             command_topic,
@@ -34,60 +187,375 @@ impl Commander {
             true,
             command_json.as_bytes(),
         )?;
+        self.record(SessionEvent::Published {
+            at: Local::now().fixed_offset(),
+            device: self.device.clone(),
+            topic: command_topic.to_string(),
+            command,
+        });
+
+        println!("Command sent");
+        println!(
+            "{}\n",
+            renderer.warning("Retained on the broker until the device wakes up and picks it up")
+        );
+        Ok(())
+    }
 
-        println!("Command sent\n");
+    /// Retains the command on each member's own topic and starts tracking
+    /// it, replacing any earlier fleet operation. Sends nothing if a member
+    /// has another operator's command pending, unless `takeover`.
+    fn start_fleet(&self, fleet: FleetOperation, takeover: bool) -> anyhow::Result<()> {
+        let renderer = self.prefs().text_renderer();
+        let mut pending = self.pending.lock().unwrap();
+        let mut refusals = Vec::new();
+        for member in fleet.members() {
+            match pending.check(
+                &topics::command_topic(member),
+                &self.issuer.operator,
+                takeover,
+            ) {
+                Ok(Some(warning)) => println!("{}", renderer.warning(&warning)),
+                Ok(None) => {}
+                Err(refusal) => refusals.push(refusal),
+            }
+        }
+        if !refusals.is_empty() {
+            anyhow::bail!(refusals.join("\n"));
+        }
+
+        let envelope = self.issuer.stamp(
+            CommandEnvelope::from(fleet.command()),
+            Local::now().fixed_offset(),
+        );
+        let command_json = envelope.to_json()?;
+        for member in fleet.members() {
+            let topic = topics::command_topic(member);
+            debug!("Queueing on '{}': {}", topic, command_json);
+            self.client
+                .publish(&topic, QoS::AtLeastOnce, true, command_json.as_bytes())?;
+            pending.sent(&topic, envelope.clone().into(), Local::now().fixed_offset());
+            self.record(SessionEvent::Published {
+                at: Local::now().fixed_offset(),
+                device: member.to_string(),
+                topic,
+                command: envelope.clone().into(),
+            });
+        }
+        drop(pending);
+
+        println!(
+            "{}
+",
+            fleet.render(&renderer)
+        );
+        println!(
+            "{}
+",
+            renderer.warning("Each device starts the update on its next wake; see 'fleet status'")
+        );
+        *self.fleet.lock().unwrap() = Some(fleet);
         Ok(())
     }
 
+    fn render_devices(&self) -> String {
+        self.devices
+            .lock()
+            .unwrap()
+            .render(&self.prefs().text_renderer(), Local::now().fixed_offset())
+    }
+}
+
+impl CommandContext for Commander {
+    fn print(&mut self, text: &str) {
+        println!("{}", text);
+    }
+
+    fn ask(&mut self, prompt: &str) -> Option<String> {
+        DefaultEditor::new().ok()?.readline(prompt).ok()
+    }
+
+    fn choose(&mut self, prompt: &str, options: &[String]) -> Option<usize> {
+        select::select(prompt, options)
+    }
+
+    fn publish(&mut self, command: DeviceCommand, takeover: bool) -> anyhow::Result<()> {
+        self.send_command(command, takeover)
+    }
+
+    fn publish_batch(&mut self, batch: DeviceCommandBatch, takeover: bool) -> anyhow::Result<()> {
+        self.send_batch(batch, takeover)
+    }
+
+    fn publish_fleet(&mut self, fleet: FleetOperation, takeover: bool) -> anyhow::Result<()> {
+        self.start_fleet(fleet, takeover)
+    }
+
+    fn device(&self) -> &str {
+        &self.device
+    }
+
     fn set_device(&mut self, device: String) {
         self.device = device;
-        println!("Now targeting device: {}\n", self.device);
     }
 
-    fn current_device(&self) -> &str {
-        &self.device
+    fn prefs(&self) -> DisplayPrefs {
+        *self.prefs.lock().unwrap()
+    }
+
+    fn set_units(&mut self, units: UnitSystem) {
+        self.prefs.lock().unwrap().units = units;
+    }
+
+    fn set_output(&mut self, output: OutputMode) {
+        self.prefs.lock().unwrap().output = output;
+    }
+
+    fn devices(&self) -> String {
+        self.render_devices()
+    }
+
+    fn known_devices(&self) -> Vec<String> {
+        self.devices.lock().unwrap().names()
+    }
+
+    fn fleet_status(&self) -> Option<String> {
+        self.fleet.lock().unwrap().as_ref().map(|fleet| {
+            format!(
+                "{}\n{}\n",
+                fleet.render(&self.prefs().text_renderer()),
+                fleet.summary()
+            )
+        })
+    }
+
+    fn pending(&self) -> String {
+        self.pending.lock().unwrap().render(
+            &self.issuer.operator,
+            &self.prefs().text_renderer(),
+            Local::now().fixed_offset(),
+        )
+    }
+
+    fn last_measurement(&self) -> Option<(DeviceMessage, DateTime<FixedOffset>)> {
+        self.devices.lock().unwrap().last_measurement(&self.device)
+    }
+
+    fn recent_co2(&mut self, window: Duration) -> anyhow::Result<Option<Vec<f64>>> {
+        let Some(settings) = setup::InfluxSettings::from_env() else {
+            return Ok(None);
+        };
+        let reqwest_client = reqwest::Client::new();
+        let query = calibration::recent_co2(&reqwest_client, &settings, &self.device, window);
+        // Commands run synchronously, on one of the runtime's threads
+        let co2 =
+            tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(query))?;
+        Ok(Some(co2))
+    }
+
+    fn batch(&mut self) -> &mut Option<Vec<DeviceCommand>> {
+        &mut self.batch
+    }
+
+    fn save_calibration(&mut self, record: &CalibrationRecord) -> anyhow::Result<PathBuf> {
+        let path = calibration::log_path(&setup::config_path());
+        calibration::append(&path, record)?;
+        Ok(path)
+    }
+
+    fn start_transcript(&mut self, path: &Path) -> anyhow::Result<()> {
+        Commander::start_transcript(self, path)
+    }
+
+    fn stop_transcript(&mut self) {
+        Commander::stop_transcript(self)
     }
 }
 
-fn create_mqtt_client(client_id: &str) -> anyhow::Result<(Client, rumqttc::Connection)> {
-    let mqtt_host = env::var("MQTT_BROKER_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let mqtt_port: u16 = env::var("MQTT_BROKER_PORT")
-        .unwrap_or_else(|_| "1883".to_string())
-        .parse()
-        .expect("MQTT_BROKER_PORT must be a valid u16");
+/// Sends the `confirm_config` for a setting the device is trying out, see
+/// `confirm`. Another operator's pending command is left alone; without the
+/// confirmation the device only rolls the setting back.
+fn confirm_trial(
+    client: &Client,
+    transcript: &SharedTranscript,
+    renderer: &TextRenderer,
+    issuer: &Issuer,
+    pending: &std::sync::Mutex<Pending>,
+    device: &str,
+    confirmation: DeviceCommand,
+) -> anyhow::Result<()> {
+    let command_topic = topics::COMMAND_BROADCAST_TOPIC;
+    let mut pending = pending.lock().unwrap();
+    if let Err(refusal) = pending.check(command_topic, &issuer.operator, false) {
+        anyhow::bail!("not confirming: {}", refusal);
+    }
+    let envelope = issuer.stamp(
+        confirmation.with_id(command_id()),
+        Local::now().fixed_offset(),
+    );
+    let command_json = envelope.to_json()?;
+    debug!("Confirming on '{}': {}", command_topic, command_json);
+    pending.sent(
+        command_topic,
+        envelope.clone().into(),
+        Local::now().fixed_offset(),
+    );
+    drop(pending);
+    client.publish(
+        command_topic,
+        QoS::AtLeastOnce,
+        true,
+        command_json.as_bytes(),
+    )?;
+    record(
+        transcript,
+        SessionEvent::Published {
+            at: Local::now().fixed_offset(),
+            device: device.to_string(),
+            topic: command_topic.to_string(),
+            command: envelope.into(),
+        },
+        renderer,
+    );
+    println!(
+        "{}\n",
+        renderer.warning("The device answered with the new setting; sent the confirmation")
+    );
+    Ok(())
+}
 
-    let mut mqttoptions = MqttOptions::new(client_id, &mqtt_host, mqtt_port);
-    mqttoptions.set_keep_alive(Duration::from_secs(30));
-    mqttoptions.set_clean_session(true);
+/// Tells this command's answers from those to earlier ones. The low bits
+/// of the clock in milliseconds are unique enough for commands typed by hand.
+fn command_id() -> u32 {
+    Local::now().timestamp_millis() as u32
+}
 
-    info!("Connecting to MQTT broker at {}:{}", &mqtt_host, mqtt_port);
-    let (client, connection) = Client::new(mqttoptions, 10);
+fn create_mqtt_client(
+    client_id: &str,
+    broker: &setup::BrokerSettings,
+) -> anyhow::Result<(Client, rumqttc::Connection)> {
+    info!(
+        "Connecting to MQTT broker at {}:{}",
+        &broker.host, broker.port
+    );
+    let (client, connection) = Client::new(broker.mqtt_options(client_id), 10);
 
     Ok((client, connection))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_mqtt_events(
     client: &Client,
     mut connection: rumqttc::Connection,
+    prefs: Arc<std::sync::Mutex<DisplayPrefs>>,
+    fleet: Arc<std::sync::Mutex<Option<FleetOperation>>>,
+    transcript: SharedTranscript,
+    devices: Arc<std::sync::Mutex<Devices>>,
+    confirmations: Arc<std::sync::Mutex<Confirmations>>,
+    issuer: Issuer,
+    pending: Arc<std::sync::Mutex<Pending>>,
 ) -> anyhow::Result<()> {
     // Subscribe to all device sensor topics
-    let response_topic = "sensors/+/sensor";
+    let response_topic = topics::sensor_wildcard();
     info!("Subscribing to responses on topic '{}'", response_topic);
     client.subscribe(response_topic, QoS::AtLeastOnce)?;
+    // And to the commands, to know what is pending and whose it is
+    for command_topic in pending::command_topics() {
+        client.subscribe(command_topic, QoS::AtLeastOnce)?;
+    }
+    let mut mismatches = Mismatches::default();
 
     loop {
         match connection.eventloop.poll().await {
             Ok(Event::Incoming(Packet::Publish(publish))) => {
                 let topic = &publish.topic;
                 let payload = &publish.payload;
+                if pending.lock().unwrap().observe(
+                    topic,
+                    payload,
+                    Local::now().fixed_offset(),
+                    publish.retain,
+                ) {
+                    continue;
+                }
 
                 match std::str::from_utf8(payload) {
                     Ok(str_message) => {
                         debug!("Received on '{}': {}", topic, str_message);
 
-                        match serde_json::from_str::<DeviceMessage>(str_message) {
-                            Ok(device_message) => {
-                                display_device_message(&device_message);
+                        let decoded = compat::decode(str_message);
+                        if let Ok(decoded) = &decoded
+                            && let Some(banner) = mismatches.banner(decoded)
+                        {
+                            // JSON output stays machine-readable, so the log gets it there
+                            let prefs = *prefs.lock().unwrap();
+                            match prefs.output {
+                                OutputMode::Text => {
+                                    println!("\n{}", prefs.text_renderer().warning(&banner))
+                                }
+                                OutputMode::Json => warn!("{}", banner),
+                            }
+                        }
+                        match decoded {
+                            Ok(Decoded::Unknown(unknown)) => {
+                                let prefs = *prefs.lock().unwrap();
+                                let received_at = Local::now().fixed_offset();
+                                println!("\n{}\n", prefs.render_unknown(&unknown, received_at));
+                            }
+                            Ok(Decoded::Known(device_message)) => {
+                                let prefs = *prefs.lock().unwrap();
+                                let received_at = Local::now().fixed_offset();
+                                devices.lock().unwrap().observe(
+                                    &device_message,
+                                    received_at,
+                                    publish.retain,
+                                );
+                                if publish.retain && prefs.output == OutputMode::Text {
+                                    println!(
+                                        "\n{}",
+                                        prefs.text_renderer().warning(
+                                            "Retained message from before this session, may be stale"
+                                        )
+                                    );
+                                }
+                                println!("\n{}\n", prefs.render(&device_message, received_at));
+                                record(
+                                    &transcript,
+                                    SessionEvent::Received {
+                                        at: received_at,
+                                        message: device_message.clone(),
+                                        retained: publish.retain,
+                                    },
+                                    &prefs.text_renderer(),
+                                );
+
+                                let confirmation = confirmations
+                                    .lock()
+                                    .unwrap()
+                                    .verify(&device_message, publish.retain);
+                                if let Some(confirmation) = confirmation
+                                    && let Err(e) = confirm_trial(
+                                        client,
+                                        &transcript,
+                                        &prefs.text_renderer(),
+                                        &issuer,
+                                        &pending,
+                                        &device_message.device,
+                                        confirmation,
+                                    )
+                                {
+                                    error!("Failed to confirm the new setting: {:?}", e);
+                                }
+
+                                if !publish.retain
+                                    && let Some(fleet) = fleet.lock().unwrap().as_mut()
+                                    && fleet.observe(&device_message)
+                                {
+                                    println!("{}\n", fleet.render(&prefs.text_renderer()));
+                                    if fleet.is_finished() {
+                                        println!("Fleet OTA finished: {}\n", fleet.summary());
+                                    }
+                                }
                             }
                             Err(e) => {
                                 error!("Failed to decode message: {:?}", e);
@@ -115,189 +583,176 @@ async fn handle_mqtt_events(
     }
 }
 
-fn display_device_message(msg: &DeviceMessage) {
-    let device = &msg.device;
-
-    println!("\n[Device: {}]", device);
-
-    match &msg.payload {
-        DevicePayload::MeasurementSuccess {
-            co2,
-            temperature,
-            humidity,
-        } => {
-            println!("  Measurement Success");
-            println!("  CO2: {} ppm", co2);
-            println!("  Temperature: {}°C", temperature);
-            println!("  Humidity: {:.1}%", humidity);
-        }
-        DevicePayload::Error { detail } => {
-            println!("  Error: {}", detail);
-        }
-        DevicePayload::FrcStart { target_ppm } => {
-            println!("  FRC Started, target: {} ppm", target_ppm);
-        }
-        DevicePayload::FrcWarmupComplete { detail } => {
-            println!("  FRC Warmup Complete: {}", detail);
-        }
-        DevicePayload::FrcCalibrating { target_ppm } => {
-            println!("  FRC Calibrating, target: {} ppm", target_ppm);
-        }
-        DevicePayload::FrcSuccess { correction } => {
-            println!("  FRC Success, correction: {} ppm", correction);
-        }
-        DevicePayload::FrcError { detail } => {
-            println!("  FRC Error: {}", detail);
-        }
-        DevicePayload::SetOffsetSuccess { offset } => {
-            println!("  Set Temperature Offset Success: {}°C", offset);
-        }
-        DevicePayload::SetOffsetError { detail } => {
-            println!("  Set Temperature Offset Error: {}", detail);
-        }
-        DevicePayload::GetOffsetSuccess { offset } => {
-            println!("  Get Temperature Offset: {}°C", offset);
-        }
-        DevicePayload::GetOffsetError { detail } => {
-            println!("  Get Temperature Offset Error: {}", detail);
-        }
-        DevicePayload::Alive { uptime_seconds } => {
-            let uptime_mins = uptime_seconds / 60;
-            let uptime_hours = uptime_mins / 60;
-            println!(
-                "  Device Alive, uptime: {}s ({}m / {}h)",
-                uptime_seconds, uptime_mins, uptime_hours
-            );
-        }
-        DevicePayload::SetDeepSleepTimeSuccess { seconds } => {
-            println!("  Set Deep Sleep Time Success: {}s", seconds);
-        }
-        DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
-            println!("  Get Deep Sleep Time: {}s", seconds);
+async fn watch_devices(commander: &Commander, interval: Duration) {
+    println!("Watching devices, Ctrl-C to stop\n");
+    loop {
+        println!("{}\n", commander.render_devices());
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => break,
         }
     }
     println!();
 }
 
-fn print_help() {
-    println!("\nAvailable Commands:");
-    println!("  noop                           - Send a no-op command (testing)");
-    println!("  frc [ppm]                      - Start forced recalibration (default: 422 ppm)");
-    println!("  set-offset <value>             - Set temperature offset in °C");
-    println!("  get-offset                     - Get current temperature offset");
-    println!("  set-sleep <seconds>            - Set deep sleep time");
-    println!("  get-sleep                      - Get deep sleep time");
-    println!("  device <name>                  - Change target device");
-    println!("  status                         - Show current device");
-    println!("  help                           - Show this help message");
-    println!("  exit, quit                     - Exit the program");
-    println!();
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Interactive console for the ESP32 air quality sensors"
+)]
+struct Cli {
+    /// Don't offer the setup wizard when no configuration exists
+    #[arg(long)]
+    skip_setup: bool,
+
+    /// Run an MQTT broker inside the commander and connect to it, for
+    /// devices on the bench pointed at this machine
+    #[arg(long)]
+    embedded_broker: bool,
+
+    /// Port for --embedded-broker, on all interfaces
+    #[arg(long, default_value_t = broker::DEFAULT_PORT, requires = "embedded_broker")]
+    broker_port: u16,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
 }
 
-fn parse_and_execute(line: &str, commander: &mut Commander) -> anyhow::Result<bool> {
-    let parts: Vec<&str> = line.trim().split_whitespace().collect();
-
-    if parts.is_empty() {
-        return Ok(true);
-    }
-
-    match parts[0] {
-        "help" | "h" | "?" => {
-            print_help();
-        }
-        "exit" | "quit" | "q" => {
-            println!("Goodbye!");
-            return Ok(false);
-        }
-        "status" => {
-            println!("Current device: {}\n", commander.current_device());
-        }
-        "device" => {
-            if parts.len() < 2 {
-                println!("Usage: device <device_name>\n");
-            } else {
-                commander.set_device(parts[1].to_string());
-            }
-        }
-        "noop" => {
-            commander.send_command(DeviceCommand::NoOp)?;
-        }
-        "frc" => {
-            let target_ppm = if parts.len() > 1 {
-                parts[1].parse::<u16>().unwrap_or(422)
-            } else {
-                422
-            };
-            commander.send_command(DeviceCommand::StartFrc { target_ppm })?;
-        }
-        "set-offset" => {
-            if parts.len() < 2 {
-                println!("Usage: set-offset <value>\n");
-            } else {
-                match parts[1].parse::<f32>() {
-                    Ok(offset) => {
-                        commander.send_command(DeviceCommand::SetTempOffset { offset })?;
-                    }
-                    Err(_) => {
-                        println!("Invalid offset value. Must be a number.\n");
-                    }
-                }
-            }
-        }
-        "get-offset" => {
-            commander.send_command(DeviceCommand::GetTempOffset)?;
-        }
-        "set-sleep" => {
-            if parts.len() < 2 {
-                println!("Usage: set-sleep <seconds>\n");
-            } else {
-                match parts[1].parse::<u64>() {
-                    Ok(seconds) => {
-                        commander.send_command(DeviceCommand::SetDeepSleepTime { seconds })?;
-                    }
-                    Err(_) => {
-                        println!("Invalid seconds value. Must be a number.\n");
-                    }
-                }
-            }
-        }
-        "get-sleep" => {
-            commander.send_command(DeviceCommand::GetDeepSleepTime)?;
-        }
-        "" => {}
-        _ => {
-            println!(
-                "Unknown command: '{}'. Type 'help' for available commands.\n",
-                parts[0]
-            );
-        }
-    }
-
-    Ok(true)
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Configure the broker, default device and InfluxDB, checking each one
+    Setup(setup::SetupArgs),
+    /// Update several devices at once and follow their progress
+    Fleet(fleet::FleetArgs),
+    /// Compare device configurations
+    Config(config_diff::ConfigArgs),
+    /// Check the broker, the device and InfluxDB, print one line and exit
+    /// 0, 1 or 2 for ok, degraded or failed
+    Probe(probe::ProbeArgs),
+    /// Run the probe's checks and explain each one
+    Doctor(probe::ProbeArgs),
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     dotenvy::dotenv().ok();
     env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Info)
         .init();
 
+    if let Some(CliCommand::Setup(args)) = &cli.command {
+        return setup::run(args).await;
+    }
+    if let Some(CliCommand::Fleet(args)) = &cli.command {
+        setup::load_config(&setup::config_path())?;
+        let renderer = DisplayPrefs::from_env()?.text_renderer();
+        return fleet::run(args, renderer).await;
+    }
+    if let Some(CliCommand::Config(args)) = &cli.command {
+        setup::load_config(&setup::config_path())?;
+        let renderer = DisplayPrefs::from_env()?.text_renderer();
+        return config_diff::run(args, renderer).await;
+    }
+    if let Some(CliCommand::Probe(args)) = &cli.command {
+        setup::load_config(&setup::config_path())?;
+        let report = probe::run(args).await?;
+        println!("{}", report.line());
+        std::process::exit(report.health().exit_code());
+    }
+    if let Some(CliCommand::Doctor(args)) = &cli.command {
+        setup::load_config(&setup::config_path())?;
+        let renderer = DisplayPrefs::from_env()?.text_renderer();
+        let report = probe::run(args).await?;
+        println!("{}", report.render(&renderer));
+        std::process::exit(report.health().exit_code());
+    }
+
+    let config_path = setup::config_path();
+    if !setup::load_config(&config_path)?
+        && !cli.skip_setup
+        && !cli.embedded_broker
+        && setup::can_prompt()
+        && setup::offer_first_run()?
+    {
+        match setup::run(&setup::SetupArgs {
+            wake_timeout: 360,
+            ..Default::default()
+        })
+        .await
+        {
+            Ok(()) => {
+                setup::load_config(&config_path)?;
+            }
+            Err(e) => println!("Setup not finished ({:#}), continuing with defaults\n", e),
+        }
+    }
+
     let client_id =
         env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "raspberry-pi-commander".to_string());
 
     let default_device = env::var("DEFAULT_DEVICE").unwrap_or_else(|_| "esp32-scd40".to_string());
 
-    let (client, connection) = create_mqtt_client(&client_id)?;
+    let prefs = Arc::new(std::sync::Mutex::new(DisplayPrefs::from_env()?));
+    let fleet = Arc::new(std::sync::Mutex::new(None));
+    let transcript = Arc::new(std::sync::Mutex::new(None));
+    let devices = Arc::new(std::sync::Mutex::new(Devices::default()));
+    let confirmations = Arc::new(std::sync::Mutex::new(Confirmations::default()));
+    let issuer = Issuer::from_env()?;
+    let pending = Arc::new(std::sync::Mutex::new(Pending::default()));
+
+    let embedded = if cli.embedded_broker {
+        let addr = SocketAddr::from(([0, 0, 0, 0], cli.broker_port));
+        let broker =
+            broker::EmbeddedBroker::start(addr, Some(broker::retained_path(&config_path))).await?;
+        println!(
+            "Embedded MQTT broker listening on {}; point devices at this machine's address",
+            broker.local_addr()
+        );
+        Some(broker)
+    } else {
+        None
+    };
+    let broker_settings = match &embedded {
+        Some(broker) => setup::BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port: broker.local_addr().port(),
+            username: None,
+            password: None,
+            tls: false,
+        },
+        None => setup::BrokerSettings::from_env()?,
+    };
+
+    let (client, connection) = create_mqtt_client(&client_id, &broker_settings)?;
 
     let commander = Arc::new(Mutex::new(Commander::new(
         client.clone(),
         default_device.clone(),
+        prefs.clone(),
+        fleet.clone(),
+        transcript.clone(),
+        devices.clone(),
+        confirmations.clone(),
+        issuer.clone(),
+        pending.clone(),
     )));
 
     // Spawn MQTT event loop in background
     let mqtt_handle = tokio::spawn(async move {
-        if let Err(e) = handle_mqtt_events(&client, connection).await {
+        if let Err(e) = handle_mqtt_events(
+            &client,
+            connection,
+            prefs,
+            fleet,
+            transcript,
+            devices,
+            confirmations,
+            issuer,
+            pending,
+        )
+        .await
+        {
             error!("MQTT error: {:?}", e);
         }
     });
@@ -320,7 +775,22 @@ async fn main() -> anyhow::Result<()> {
                     let _ = rl.add_history_entry(line.as_str());
 
                     let mut cmd = commander.lock().await;
-                    match parse_and_execute(&line, &mut cmd) {
+                    cmd.record(SessionEvent::Typed {
+                        at: Local::now().fixed_offset(),
+                        line: line.trim().to_string(),
+                    });
+                    let command = match parse_command(&line) {
+                        Ok(ParsedCommand::DevicesWatch(interval)) => {
+                            watch_devices(&cmd, interval).await;
+                            continue;
+                        }
+                        Ok(command) => command,
+                        Err(e) => {
+                            println!("{}\n", e);
+                            continue;
+                        }
+                    };
+                    match execute(command, &mut *cmd) {
                         Ok(true) => continue,
                         Ok(false) => break,
                         Err(e) => {
@@ -344,6 +814,192 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    let commander = commander.lock().await;
+    if commander.transcript.lock().unwrap().is_some() {
+        commander.stop_transcript();
+    }
+
     mqtt_handle.abort();
+    if let Some(broker) = embedded {
+        broker.shutdown().await;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{AsyncClient, MqttOptions};
+    use shared_types::{CommandEnvelope, DeviceMessage, DevicePayload};
+
+    #[tokio::test]
+    async fn event_loop_sees_devices_through_the_embedded_broker() {
+        let broker = broker::EmbeddedBroker::start("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let port = broker.local_addr().port();
+        let settings = setup::BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            tls: false,
+        };
+
+        let path = env::temp_dir().join(format!("commander-events-{}.md", std::process::id()));
+        let transcript: SharedTranscript = Arc::new(std::sync::Mutex::new(Some(
+            Transcript::create(&path).unwrap(),
+        )));
+        let (client, connection) = create_mqtt_client("commander-test", &settings).unwrap();
+        let events = {
+            let transcript = transcript.clone();
+            tokio::spawn(async move {
+                handle_mqtt_events(
+                    &client,
+                    connection,
+                    Arc::new(std::sync::Mutex::new(DisplayPrefs::default())),
+                    Arc::new(std::sync::Mutex::new(None)),
+                    transcript,
+                    Arc::new(std::sync::Mutex::new(Devices::default())),
+                    Arc::new(std::sync::Mutex::new(Confirmations::default())),
+                    Issuer {
+                        operator: "ola".to_string(),
+                        ttl_seconds: None,
+                    },
+                    Arc::new(std::sync::Mutex::new(Pending::default())),
+                )
+                .await
+            })
+        };
+
+        let (device, mut device_loop) =
+            AsyncClient::new(MqttOptions::new("esp32-bench", "127.0.0.1", port), 10);
+        let message =
+            DeviceMessage::new("esp32-bench", DevicePayload::measurement(612, 21.5, 40.0));
+        // Published until the commander has subscribed and recorded one
+        let seen = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                device
+                    .publish(
+                        "sensors/esp32-bench/sensor",
+                        QoS::AtLeastOnce,
+                        false,
+                        message.to_json().unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let _ = tokio::time::timeout(Duration::from_millis(200), async {
+                    loop {
+                        device_loop.poll().await.unwrap();
+                    }
+                })
+                .await;
+                let text = std::fs::read_to_string(&path).unwrap();
+                if text.contains("**`esp32-bench`**") {
+                    return text;
+                }
+            }
+        })
+        .await
+        .expect("the commander never saw the measurement");
+        assert!(seen.contains("unsolicited"), "{}", seen);
+
+        events.abort();
+        broker.shutdown().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_trial_of_a_command_sent_here_is_confirmed() {
+        let broker = broker::EmbeddedBroker::start("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let port = broker.local_addr().port();
+        let settings = setup::BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            tls: false,
+        };
+
+        let mut confirmations = Confirmations::default();
+        confirmations.sent(41, &DeviceCommand::SetDeepSleepTime { seconds: 900 });
+        let (client, connection) = create_mqtt_client("commander-confirm", &settings).unwrap();
+        let events = tokio::spawn(async move {
+            handle_mqtt_events(
+                &client,
+                connection,
+                Arc::new(std::sync::Mutex::new(DisplayPrefs::default())),
+                Arc::new(std::sync::Mutex::new(None)),
+                Arc::new(std::sync::Mutex::new(None)),
+                Arc::new(std::sync::Mutex::new(Devices::default())),
+                Arc::new(std::sync::Mutex::new(confirmations)),
+                Issuer {
+                    operator: "ola".to_string(),
+                    ttl_seconds: Some(600),
+                },
+                Arc::new(std::sync::Mutex::new(Pending::default())),
+            )
+            .await
+        });
+
+        let (device, mut device_loop) =
+            AsyncClient::new(MqttOptions::new("esp32-bench", "127.0.0.1", port), 10);
+        device
+            .subscribe(topics::COMMAND_BROADCAST_TOPIC, QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let pending = DeviceMessage::new(
+            "esp32-bench",
+            DevicePayload::PendingConfirmation {
+                id: 41,
+                command: "set_deep_sleep_time".to_string(),
+                wakes_left: 3,
+            },
+        )
+        .replying_to(41);
+        // Published until the commander has subscribed and answered
+        let confirmation = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                device
+                    .publish(
+                        "sensors/esp32-bench/sensor",
+                        QoS::AtLeastOnce,
+                        false,
+                        pending.to_json().unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let received = tokio::time::timeout(Duration::from_millis(200), async {
+                    loop {
+                        if let Event::Incoming(Packet::Publish(publish)) =
+                            device_loop.poll().await.unwrap()
+                        {
+                            return publish;
+                        }
+                    }
+                })
+                .await;
+                if let Ok(publish) = received {
+                    break publish;
+                }
+            }
+        })
+        .await
+        .expect("the commander never confirmed");
+        let envelope =
+            CommandEnvelope::from_json(std::str::from_utf8(&confirmation.payload).unwrap())
+                .unwrap();
+        assert_eq!(
+            envelope.command,
+            DeviceCommand::ConfirmConfig { pending_id: 41 }
+        );
+        assert_eq!(envelope.issued_by.as_deref(), Some("ola"));
+        assert_eq!(envelope.ttl_seconds, Some(600));
+        assert!(envelope.issued_at_unix.is_some());
+
+        events.abort();
+        broker.shutdown().await;
+    }
+}
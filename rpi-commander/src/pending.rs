@@ -0,0 +1,418 @@
+//! Commands retained on the broker that no device has picked up yet, and
+//! who sent them.
+//!
+//! A topic retains one command, so a second operator's command silently
+//! replaces the first one's. Every command the commander sends names its
+//! operator in `issued_by`: `COMMANDER_OPERATOR` from the config, or the
+//! login name. The commander follows the command topics, and refuses to
+//! replace a command another operator left pending unless the line ends in
+//! `--takeover`. The processor's relay sends as `relay`. Commands that name
+//! no operator, from an older commander, are replaced with a warning.
+//!
+//! Commands also carry when they were sent and how long they stay worth
+//! running, `COMMANDER_COMMAND_TTL` seconds (a day by default, 0 for
+//! ever), so a device that was off for a week doesn't act on them.
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset};
+use shared_types::topics::COMMAND_BROADCAST_TOPIC;
+use shared_types::{CommandEnvelope, CommandMessage};
+
+use crate::age;
+use crate::render::TextRenderer;
+
+pub const DEFAULT_COMMAND_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+/// Who this commander sends commands as
+pub fn operator() -> String {
+    ["COMMANDER_OPERATOR", "USER", "USERNAME"]
+        .into_iter()
+        .filter_map(|key| std::env::var(key).ok())
+        .find(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// What every command sent from here is stamped with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issuer {
+    pub operator: String,
+    /// `None` for commands that never expire
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Issuer {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let ttl_seconds = match std::env::var("COMMANDER_COMMAND_TTL") {
+            Ok(ttl) => ttl
+                .parse()
+                .context("COMMANDER_COMMAND_TTL must be a number of seconds")?,
+            Err(_) => DEFAULT_COMMAND_TTL_SECONDS,
+        };
+        Ok(Self {
+            operator: operator(),
+            ttl_seconds: Some(ttl_seconds).filter(|ttl| *ttl > 0),
+        })
+    }
+
+    /// Names the operator and, with a TTL, when the command was sent.
+    pub fn stamp<C>(
+        &self,
+        envelope: CommandEnvelope<C>,
+        now: DateTime<FixedOffset>,
+    ) -> CommandEnvelope<C> {
+        let envelope = envelope.issued_by(&self.operator);
+        match self.ttl_seconds {
+            Some(ttl) => envelope.expires(now.timestamp().max(0) as u64, ttl),
+            None => envelope,
+        }
+    }
+}
+
+/// The broadcast command topic and every device's own
+pub fn command_topics() -> [String; 2] {
+    [
+        COMMAND_BROADCAST_TOPIC.to_string(),
+        format!("{}/+", COMMAND_BROADCAST_TOPIC),
+    ]
+}
+
+fn is_command_topic(topic: &str) -> bool {
+    topic
+        .strip_prefix(COMMAND_BROADCAST_TOPIC)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingCommand {
+    pub command: CommandMessage,
+    /// When this session first saw it; retained ones may be much older
+    pub seen: DateTime<FixedOffset>,
+    pub retained: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Pending {
+    by_topic: BTreeMap<String, PendingCommand>,
+}
+
+impl Pending {
+    /// Follows a publish on a command topic. Returns `false` for other
+    /// topics. An empty payload is a device clearing what it picked up.
+    pub fn observe(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        at: DateTime<FixedOffset>,
+        retained: bool,
+    ) -> bool {
+        if !is_command_topic(topic) {
+            return false;
+        }
+        if payload.is_empty() {
+            self.by_topic.remove(topic);
+            return true;
+        }
+        let command = std::str::from_utf8(payload)
+            .ok()
+            .and_then(|json| CommandMessage::from_json(json).ok());
+        match command {
+            // The echo of a command sent from here keeps the time it was sent
+            Some(command)
+                if self
+                    .by_topic
+                    .get(topic)
+                    .is_some_and(|pending| pending.command == command) => {}
+            Some(command) => {
+                self.by_topic.insert(
+                    topic.to_string(),
+                    PendingCommand {
+                        command,
+                        seen: at,
+                        retained,
+                    },
+                );
+            }
+            None => log::debug!("Ignoring unreadable command on '{}'", topic),
+        }
+        true
+    }
+
+    /// Checks whether `operator` may replace what `topic` retains. `Ok`
+    /// carries a warning to show when replacing something anyway; `Err`
+    /// why the command wasn't sent.
+    pub fn check(
+        &self,
+        topic: &str,
+        operator: &str,
+        takeover: bool,
+    ) -> Result<Option<String>, String> {
+        let Some(pending) = self.by_topic.get(topic) else {
+            return Ok(None);
+        };
+        let name = pending.command.name();
+        match pending.command.issued_by() {
+            Some(issuer) if issuer == operator => Ok(None),
+            Some(issuer) if takeover => Ok(Some(format!(
+                "Replacing the {} {} left pending on '{}'",
+                name, issuer, topic
+            ))),
+            Some(issuer) => Err(format!(
+                "{} left a {} pending on '{}'; see 'pending', or add --takeover to replace it",
+                issuer, name, topic
+            )),
+            None => Ok(Some(format!(
+                "Replacing a pending {} on '{}' from an unknown sender",
+                name, topic
+            ))),
+        }
+    }
+
+    /// Remembers a command this session just sent, before the broker
+    /// echoes it back.
+    pub fn sent(&mut self, topic: &str, command: CommandMessage, at: DateTime<FixedOffset>) {
+        self.by_topic.insert(
+            topic.to_string(),
+            PendingCommand {
+                command,
+                seen: at,
+                retained: false,
+            },
+        );
+    }
+
+    pub fn render(
+        &self,
+        operator: &str,
+        renderer: &TextRenderer,
+        now: DateTime<FixedOffset>,
+    ) -> String {
+        if self.by_topic.is_empty() {
+            return "No commands pending".to_string();
+        }
+        let mut lines = Vec::new();
+        for (topic, pending) in &self.by_topic {
+            let issuer = pending.command.issued_by();
+            let when = if pending.retained {
+                "from before this session".to_string()
+            } else {
+                age::relative(pending.seen, now)
+            };
+            lines.push(format!(
+                "{}  {}  {}, {}",
+                topic,
+                pending.command.name(),
+                issuer.map_or("unknown sender".to_string(), |i| format!("by {}", i)),
+                when
+            ));
+            if let Some(issuer) = issuer.filter(|issuer| *issuer != operator) {
+                lines.push(renderer.warning(&format!(
+                    "  Issued by {}, not you ({}); sending to this topic needs --takeover",
+                    issuer, operator
+                )));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use shared_types::topics::command_topic;
+    use shared_types::{DeviceCommand, DeviceCommandBatch};
+
+    use crate::render::UnitSystem;
+
+    fn at(seconds: i64) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2025-01-15T12:00:00+01:00").unwrap()
+            + Duration::seconds(seconds)
+    }
+
+    fn renderer() -> TextRenderer {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: Duration::hours(1),
+        }
+    }
+
+    fn payload(operator: Option<&str>) -> Vec<u8> {
+        let envelope = DeviceCommand::GetTempOffset.with_id(7);
+        let envelope = match operator {
+            Some(operator) => envelope.issued_by(operator),
+            None => envelope,
+        };
+        envelope.to_json().unwrap().into_bytes()
+    }
+
+    #[test]
+    fn commands_are_stamped_with_the_operator_and_expiry() {
+        let issuer = Issuer {
+            operator: "ola".to_string(),
+            ttl_seconds: Some(3600),
+        };
+        let envelope = issuer.stamp(DeviceCommand::Reboot.with_id(9), at(0));
+        assert_eq!(envelope.id, Some(9));
+        assert_eq!(envelope.issued_by.as_deref(), Some("ola"));
+        assert_eq!(envelope.issued_at_unix, Some(1_736_938_800));
+        assert_eq!(envelope.ttl_seconds, Some(3600));
+        assert!(envelope.is_expired(1_736_938_800 + 3600));
+
+        let forever = Issuer {
+            ttl_seconds: None,
+            ..issuer
+        };
+        let envelope = forever.stamp(DeviceCommand::Reboot.into(), at(0));
+        assert_eq!(envelope.issued_by.as_deref(), Some("ola"));
+        assert_eq!(envelope.expires_at_unix(), None);
+    }
+
+    #[test]
+    fn own_pending_commands_are_replaced_quietly() {
+        let mut pending = Pending::default();
+        assert_eq!(
+            pending.check(COMMAND_BROADCAST_TOPIC, "ola", false),
+            Ok(None)
+        );
+        assert!(pending.observe(COMMAND_BROADCAST_TOPIC, &payload(Some("ola")), at(0), true));
+        assert_eq!(
+            pending.check(COMMAND_BROADCAST_TOPIC, "ola", false),
+            Ok(None)
+        );
+        assert_eq!(
+            pending.check(COMMAND_BROADCAST_TOPIC, "ola", true),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn foreign_pending_commands_need_a_takeover() {
+        let mut pending = Pending::default();
+        pending.observe(
+            COMMAND_BROADCAST_TOPIC,
+            &payload(Some("mateusz")),
+            at(0),
+            false,
+        );
+        let refused = pending
+            .check(COMMAND_BROADCAST_TOPIC, "ola", false)
+            .unwrap_err();
+        assert!(
+            refused.starts_with("mateusz left a get_temp_offset pending"),
+            "{}",
+            refused
+        );
+        assert!(refused.contains("--takeover"), "{}", refused);
+        let warning = pending.check(COMMAND_BROADCAST_TOPIC, "ola", true).unwrap();
+        assert!(warning.unwrap().contains("mateusz"));
+
+        // Other topics are someone else's business
+        let kitchen = command_topic("kitchen");
+        assert_eq!(pending.check(&kitchen, "ola", false), Ok(None));
+        pending.observe(&kitchen, &payload(Some("mateusz")), at(5), false);
+        assert!(pending.check(&kitchen, "ola", false).is_err());
+
+        // Once the device picks it up there is nothing left to clobber
+        assert!(pending.observe(COMMAND_BROADCAST_TOPIC, b"", at(10), false));
+        assert_eq!(
+            pending.check(COMMAND_BROADCAST_TOPIC, "ola", false),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn unattributed_pending_commands_are_replaced_with_a_warning() {
+        let mut pending = Pending::default();
+        pending.observe(COMMAND_BROADCAST_TOPIC, &payload(None), at(0), true);
+        let warning = pending
+            .check(COMMAND_BROADCAST_TOPIC, "ola", false)
+            .unwrap();
+        assert!(warning.unwrap().contains("unknown sender"));
+    }
+
+    #[test]
+    fn sending_replaces_what_is_pending() {
+        let mut pending = Pending::default();
+        pending.observe(
+            COMMAND_BROADCAST_TOPIC,
+            &payload(Some("mateusz")),
+            at(0),
+            false,
+        );
+        let envelope = DeviceCommand::NoOp.with_id(8).issued_by("ola");
+        pending.sent(COMMAND_BROADCAST_TOPIC, envelope.clone().into(), at(10));
+        assert_eq!(
+            pending.check(COMMAND_BROADCAST_TOPIC, "ola", false),
+            Ok(None)
+        );
+        // The broker's echo doesn't reset when it was sent
+        pending.observe(
+            COMMAND_BROADCAST_TOPIC,
+            envelope.to_json().unwrap().as_bytes(),
+            at(12),
+            false,
+        );
+        assert_eq!(pending.by_topic[COMMAND_BROADCAST_TOPIC].seen, at(10));
+    }
+
+    #[test]
+    fn batches_are_followed_too() {
+        let mut pending = Pending::default();
+        let batch = DeviceCommandBatch::new([DeviceCommand::GetAsc, DeviceCommand::GetAltitude])
+            .unwrap()
+            .with_id(5)
+            .issued_by("mateusz");
+        let json = batch.to_json().unwrap();
+        assert!(pending.observe(COMMAND_BROADCAST_TOPIC, json.as_bytes(), at(0), false));
+        let refused = pending
+            .check(COMMAND_BROADCAST_TOPIC, "ola", false)
+            .unwrap_err();
+        assert!(
+            refused.starts_with("mateusz left a batch pending"),
+            "{}",
+            refused
+        );
+    }
+
+    #[test]
+    fn only_command_topics_are_followed() {
+        let mut pending = Pending::default();
+        for topic in ["sensors/kitchen/sensor", "sensors/esp32/commander"] {
+            assert!(!pending.observe(topic, &payload(Some("ola")), at(0), false));
+        }
+        assert!(pending.observe(&command_topic("kitchen"), b"{not json", at(0), false));
+        assert!(pending.by_topic.is_empty());
+    }
+
+    #[test]
+    fn listing_flags_other_operators() {
+        let renderer = renderer();
+        let mut pending = Pending::default();
+        assert_eq!(
+            pending.render("ola", &renderer, at(0)),
+            "No commands pending"
+        );
+        pending.observe(
+            COMMAND_BROADCAST_TOPIC,
+            &payload(Some("mateusz")),
+            at(0),
+            true,
+        );
+        pending.observe(
+            &command_topic("kitchen"),
+            &payload(Some("ola")),
+            at(0),
+            false,
+        );
+        let listing = pending.render("ola", &renderer, at(120));
+        assert_eq!(
+            listing,
+            "sensors/esp32/command  get_temp_offset  by mateusz, from before this session\n\
+             \u{20} Issued by mateusz, not you (ola); sending to this topic needs --takeover\n\
+             sensors/esp32/command/kitchen  get_temp_offset  by ola, 2 m ago"
+        );
+    }
+}
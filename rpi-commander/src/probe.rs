@@ -0,0 +1,632 @@
+//! `probe` and `doctor`: whether the chain from a device through the broker
+//! to InfluxDB works, checked without the REPL.
+//!
+//! Both run the same steps in order: connect to the broker, look for a
+//! recent measurement from the device on its sensor topic, and with InfluxDB
+//! configured, check the newest point stored for it. `probe` prints a single
+//! logfmt line and exits 0, 1 or 2 for ok, degraded or failed, for cron jobs,
+//! systemd units or healthchecks; `doctor` explains each step.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::Args;
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use serde::Deserialize;
+use shared_types::duration::parse_duration;
+use shared_types::{DeviceMessage, DevicePayload, topics};
+
+use crate::age;
+use crate::render::TextRenderer;
+use crate::setup::{self, BrokerSettings, InfluxSettings};
+
+const MEASUREMENT_TABLE: &str = "scd40_data";
+
+#[derive(Args, Debug)]
+pub struct ProbeArgs {
+    /// The device to check [default: DEFAULT_DEVICE]
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// How old the newest measurement may be, such as 900s, 15m or 1h.
+    /// Without a recent retained measurement, this is also how long to wait
+    /// for the device to publish.
+    #[arg(long, value_name = "DURATION", value_parser = parse_max_age, default_value = "15m")]
+    pub max_age: Duration,
+}
+
+/// A duration as [`parse_duration`] takes it, at least a second long
+fn parse_max_age(value: &str) -> Result<Duration, String> {
+    match parse_duration(value)? {
+        Duration::ZERO => Err("the duration must be at least a second".to_string()),
+        max_age => Ok(max_age),
+    }
+}
+
+/// Ordered from best to worst, so a report is as bad as its worst step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    Ok,
+    /// The device is publishing, but storing its measurements is behind
+    Degraded,
+    /// The broker can't be reached or the device is silent
+    Failed,
+}
+
+impl Health {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Degraded => "degraded",
+            Health::Failed => "failed",
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Health::Ok => 0,
+            Health::Degraded => 1,
+            Health::Failed => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: &'static str,
+    pub health: Health,
+    /// When the newest measurement this step found was taken
+    pub newest: Option<DateTime<Utc>>,
+    pub detail: String,
+}
+
+impl Step {
+    fn new(name: &'static str, health: Health, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            health,
+            newest: None,
+            detail: detail.into(),
+        }
+    }
+
+    fn with_newest(self, newest: DateTime<Utc>) -> Self {
+        Self {
+            newest: Some(newest),
+            ..self
+        }
+    }
+}
+
+/// Connects the way the REPL does; the connection is kept for the next
+/// step.
+pub async fn check_broker(settings: &BrokerSettings) -> (Step, Option<(AsyncClient, EventLoop)>) {
+    match setup::connect(settings, "rpi-commander-probe").await {
+        Ok(connection) => (
+            Step::new(
+                "broker",
+                Health::Ok,
+                format!("connected to {}:{}", settings.host, settings.port),
+            ),
+            Some(connection),
+        ),
+        Err(e) => (
+            Step::new("broker", Health::Failed, format!("{:#}", e)),
+            None,
+        ),
+    }
+}
+
+/// A retained measurement from `device` whose device timestamp is within
+/// `max_age`, or failing that, anything the device publishes within
+/// `max_age`.
+pub async fn check_recent_message(
+    client: &AsyncClient,
+    eventloop: &mut EventLoop,
+    device: &str,
+    max_age: Duration,
+) -> Step {
+    let topic = topics::sensor_topic(device);
+    if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+        return Step::new(
+            "message",
+            Health::Failed,
+            format!("cannot subscribe to {}: {}", topic, e),
+        );
+    }
+    let limit = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+    let mut stale = None;
+    let found = setup::wait_for(eventloop, max_age, |event| {
+        let Event::Incoming(Packet::Publish(publish)) = event else {
+            return None;
+        };
+        let message = serde_json::from_slice::<DeviceMessage>(&publish.payload)
+            .ok()
+            .filter(|message| message.device == device)?;
+        if !publish.retain {
+            return Some(Ok((Utc::now(), "a message arrived")));
+        }
+        // A retained message can be arbitrarily old; only its timestamp counts
+        if !matches!(
+            message.payload,
+            DevicePayload::MeasurementSuccess { .. } | DevicePayload::MeasurementBatch { .. }
+        ) {
+            return None;
+        }
+        let sent = message
+            .ts
+            .and_then(|ts| DateTime::from_timestamp_millis(ts as i64))?;
+        if Utc::now() - sent > limit {
+            stale = Some(sent);
+            return None;
+        }
+        Some(Ok((sent, "retained measurement")))
+    })
+    .await;
+
+    match found {
+        Ok((at, what)) => {
+            Step::new("message", Health::Ok, format!("{} on {}", what, topic)).with_newest(at)
+        }
+        Err(e) => {
+            let step = Step::new(
+                "message",
+                Health::Failed,
+                format!("nothing recent from {} on {}: {:#}", device, topic, e),
+            );
+            match stale {
+                Some(at) => step.with_newest(at),
+                None => step,
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewestRow {
+    time: String,
+}
+
+/// The newest point stored for `device`. Missing or old points only
+/// degrade the result, since the device itself may still be fine.
+pub async fn check_stored_point(
+    reqwest_client: &reqwest::Client,
+    settings: &InfluxSettings,
+    device: &str,
+    max_age: Duration,
+    now: DateTime<Utc>,
+) -> Step {
+    let sql = format!(
+        "SELECT time FROM {} WHERE device = {} ORDER BY time DESC LIMIT 1",
+        MEASUREMENT_TABLE,
+        setup::sql_string(device)
+    );
+    let rows = match setup::query_influx::<NewestRow>(reqwest_client, settings, &sql).await {
+        Ok(rows) => rows,
+        Err(e) => return Step::new("influx", Health::Degraded, format!("{:#}", e)),
+    };
+    let Some(newest) = rows
+        .first()
+        .and_then(|row| setup::parse_influx_time(&row.time))
+    else {
+        return Step::new(
+            "influx",
+            Health::Degraded,
+            format!("no points stored for {} in {}", device, settings.database),
+        );
+    };
+    let limit = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+    let health = if now - newest > limit {
+        Health::Degraded
+    } else {
+        Health::Ok
+    };
+    Step::new(
+        "influx",
+        health,
+        format!("newest point in {}", settings.database),
+    )
+    .with_newest(newest)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub device: String,
+    pub max_age: Duration,
+    pub checked_at: DateTime<Utc>,
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    pub fn health(&self) -> Health {
+        self.steps
+            .iter()
+            .map(|step| step.health)
+            .max()
+            .unwrap_or(Health::Ok)
+    }
+
+    /// `status=<health> device=<name>`, then `<step>=<health>` for each step
+    /// with `<step>_age_s` when it found a measurement, and a quoted
+    /// `<step>_detail` when it isn't ok.
+    pub fn line(&self) -> String {
+        let mut fields = vec![
+            format!("status={}", self.health().as_str()),
+            format!("device={}", self.device),
+        ];
+        for step in &self.steps {
+            fields.push(format!("{}={}", step.name, step.health.as_str()));
+            if let Some(newest) = step.newest {
+                fields.push(format!(
+                    "{}_age_s={}",
+                    step.name,
+                    (self.checked_at - newest).num_seconds()
+                ));
+            }
+            if step.health != Health::Ok {
+                fields.push(format!("{}_detail={:?}", step.name, step.detail));
+            }
+        }
+        fields.join(" ")
+    }
+
+    /// One line per step, for `doctor`.
+    pub fn render(&self, renderer: &TextRenderer) -> String {
+        let mut lines = vec![format!(
+            "Checking {}, measurements may be up to {} s old",
+            self.device,
+            self.max_age.as_secs()
+        )];
+        for step in &self.steps {
+            let mut line = format!(
+                "  {}  {:<8} {}",
+                paint(renderer, step.health),
+                step.name,
+                step.detail
+            );
+            if let Some(newest) = step.newest {
+                line.push_str(&format!(
+                    ", taken {}",
+                    age::relative(newest.fixed_offset(), self.checked_at.fixed_offset())
+                ));
+            }
+            lines.push(line);
+        }
+        lines.push(format!("Overall: {}", paint(renderer, self.health())));
+        lines.join("\n")
+    }
+}
+
+fn paint(renderer: &TextRenderer, health: Health) -> String {
+    let text = format!("{:<8}", health.as_str());
+    match health {
+        Health::Ok => renderer.success(&text),
+        Health::Degraded => renderer.warning(&text),
+        Health::Failed => renderer.error(&text),
+    }
+}
+
+/// Runs every step in order; without the broker, nothing after it can be
+/// checked.
+pub async fn probe(
+    broker: &BrokerSettings,
+    influx: Option<&InfluxSettings>,
+    device: &str,
+    max_age: Duration,
+) -> Report {
+    let mut steps = Vec::new();
+    let (step, connection) = check_broker(broker).await;
+    steps.push(step);
+    if let Some((client, mut eventloop)) = connection {
+        steps.push(check_recent_message(&client, &mut eventloop, device, max_age).await);
+        let _ = client.disconnect().await;
+        if let Some(influx) = influx {
+            steps.push(
+                check_stored_point(&reqwest::Client::new(), influx, device, max_age, Utc::now())
+                    .await,
+            );
+        }
+    }
+    Report {
+        device: device.to_string(),
+        max_age,
+        checked_at: Utc::now(),
+        steps,
+    }
+}
+
+/// Probes with the broker and InfluxDB from the environment.
+pub async fn run(args: &ProbeArgs) -> anyhow::Result<Report> {
+    let device = args
+        .device
+        .clone()
+        .or_else(|| std::env::var("DEFAULT_DEVICE").ok())
+        .unwrap_or_else(|| setup::DEFAULT_DEVICE.to_string());
+    let broker = BrokerSettings::from_env()?;
+    let influx = InfluxSettings::from_env();
+    Ok(probe(&broker, influx.as_ref(), &device, args.max_age).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::EmbeddedBroker;
+    use crate::render::UnitSystem;
+    use shared_types::DevicePayload;
+    use tokio::net::TcpListener;
+
+    fn settings(broker: &EmbeddedBroker) -> BrokerSettings {
+        BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port: broker.local_addr().port(),
+            username: None,
+            password: None,
+            tls: false,
+        }
+    }
+
+    /// A measurement from `device`, taken `age` ago by the device's clock
+    fn measurement(device: &str, age: chrono::Duration) -> String {
+        let taken = (Utc::now() - age).timestamp_millis() as u64;
+        DeviceMessage::new(device, DevicePayload::measurement(612, 21.5, 40.0))
+            .stamped(Some(taken), 1)
+            .to_json()
+            .unwrap()
+    }
+
+    async fn retain(broker: &EmbeddedBroker, topic: &str, payload: String) {
+        let (client, mut eventloop) = setup::connect(&settings(broker), "device").await.unwrap();
+        client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await
+            .unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(200), async {
+            loop {
+                eventloop.poll().await.unwrap();
+            }
+        })
+        .await;
+    }
+
+    async fn start_broker() -> EmbeddedBroker {
+        EmbeddedBroker::start("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_recent_retained_measurement_is_enough() {
+        let broker = start_broker().await;
+        retain(
+            &broker,
+            "sensors/esp32-test/sensor",
+            measurement("esp32-test", chrono::Duration::seconds(30)),
+        )
+        .await;
+
+        let report = probe(
+            &settings(&broker),
+            None,
+            "esp32-test",
+            Duration::from_secs(600),
+        )
+        .await;
+        assert_eq!(report.health(), Health::Ok, "{:?}", report);
+        assert_eq!(report.steps.len(), 2);
+        let age = report.checked_at - report.steps[1].newest.unwrap();
+        assert!((29..60).contains(&age.num_seconds()), "{}", age);
+        assert!(
+            report
+                .line()
+                .starts_with("status=ok device=esp32-test broker=ok message=ok")
+        );
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn an_old_retained_measurement_fails_after_waiting() {
+        let broker = start_broker().await;
+        retain(
+            &broker,
+            "sensors/esp32-test/sensor",
+            measurement("esp32-test", chrono::Duration::hours(2)),
+        )
+        .await;
+        // Someone else's fresh measurement doesn't count either
+        retain(
+            &broker,
+            "sensors/esp32-other/sensor",
+            measurement("esp32-other", chrono::Duration::zero()),
+        )
+        .await;
+
+        let (client, mut eventloop) = setup::connect(&settings(&broker), "probe").await.unwrap();
+        let step = check_recent_message(
+            &client,
+            &mut eventloop,
+            "esp32-test",
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(step.health, Health::Failed);
+        assert!(
+            step.detail.contains("no answer within 1s"),
+            "{}",
+            step.detail
+        );
+        let age = Utc::now() - step.newest.unwrap();
+        assert!(age >= chrono::Duration::hours(2), "{}", age);
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn a_live_message_counts_whatever_it_is() {
+        let broker = start_broker().await;
+        let (device, mut device_loop) = setup::connect(&settings(&broker), "device").await.unwrap();
+        let publisher = tokio::spawn(async move {
+            let alive =
+                DeviceMessage::new("esp32-test", DevicePayload::Alive { uptime_seconds: 5 });
+            loop {
+                device
+                    .publish(
+                        "sensors/esp32-test/sensor",
+                        QoS::AtLeastOnce,
+                        false,
+                        alive.to_json().unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let _ = tokio::time::timeout(Duration::from_millis(100), async {
+                    loop {
+                        device_loop.poll().await.unwrap();
+                    }
+                })
+                .await;
+            }
+        });
+
+        let (client, mut eventloop) = setup::connect(&settings(&broker), "probe").await.unwrap();
+        let step = check_recent_message(
+            &client,
+            &mut eventloop,
+            "esp32-test",
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(step.health, Health::Ok, "{}", step.detail);
+        assert!(
+            step.detail.starts_with("a message arrived"),
+            "{}",
+            step.detail
+        );
+        publisher.abort();
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_broker_stops_the_probe() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let broker = BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            tls: false,
+        };
+        let influx = InfluxSettings {
+            url: "http://127.0.0.1:1".to_string(),
+            token: String::new(),
+            database: "air_quality".to_string(),
+        };
+        let report = probe(
+            &broker,
+            Some(&influx),
+            "esp32-test",
+            Duration::from_secs(60),
+        )
+        .await;
+        assert_eq!(report.steps.len(), 1);
+        assert_eq!(report.health().exit_code(), 2);
+        assert!(
+            report.line().starts_with(
+                "status=failed device=esp32-test broker=failed broker_detail=\"cannot reach"
+            ),
+            "{}",
+            report.line()
+        );
+    }
+
+    async fn fake_influx(status: u16, body: &'static str) -> InfluxSettings {
+        use axum::{Router, http::StatusCode, routing::post};
+        let app = Router::new().route(
+            "/api/v3/query_sql",
+            post(move || async move { (StatusCode::from_u16(status).unwrap(), body) }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        InfluxSettings {
+            url: format!("http://{}", addr),
+            token: "token".to_string(),
+            database: "air_quality".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_points_older_than_max_age_degrade() {
+        let client = reqwest::Client::new();
+        let now = DateTime::parse_from_rfc3339("2025-01-15T13:05:09Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let max_age = Duration::from_secs(900);
+
+        let recent = fake_influx(200, r#"[{"time":"2025-01-15T13:00:00"}]"#).await;
+        let step = check_stored_point(&client, &recent, "esp32-test", max_age, now).await;
+        assert_eq!(step.health, Health::Ok);
+        assert_eq!(step.newest, Some(now - chrono::Duration::seconds(309)));
+
+        let old = fake_influx(200, r#"[{"time":"2025-01-15T10:00:00"}]"#).await;
+        let step = check_stored_point(&client, &old, "esp32-test", max_age, now).await;
+        assert_eq!(step.health, Health::Degraded);
+
+        let empty = fake_influx(200, "[]").await;
+        let step = check_stored_point(&client, &empty, "esp32-test", max_age, now).await;
+        assert_eq!(step.health, Health::Degraded);
+        assert!(step.detail.contains("no points"), "{}", step.detail);
+
+        let refused = fake_influx(401, "bad token").await;
+        let step = check_stored_point(&client, &refused, "esp32-test", max_age, now).await;
+        assert_eq!(step.health, Health::Degraded);
+        assert!(step.detail.contains("401"), "{}", step.detail);
+    }
+
+    #[test]
+    fn the_line_and_the_doctor_output_agree() {
+        let checked_at = Utc::now();
+        let report = Report {
+            device: "esp32-test".to_string(),
+            max_age: Duration::from_secs(900),
+            checked_at,
+            steps: vec![
+                Step::new("broker", Health::Ok, "connected to localhost:1883"),
+                Step::new("message", Health::Ok, "retained measurement")
+                    .with_newest(checked_at - chrono::Duration::seconds(42)),
+                Step::new("influx", Health::Degraded, "newest point in air_quality")
+                    .with_newest(checked_at - chrono::Duration::hours(3)),
+            ],
+        };
+        assert_eq!(report.health().exit_code(), 1);
+        assert_eq!(
+            report.line(),
+            "status=degraded device=esp32-test broker=ok message=ok message_age_s=42 \
+             influx=degraded influx_age_s=10800 influx_detail=\"newest point in air_quality\""
+        );
+
+        let renderer = TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        };
+        assert_eq!(
+            report.render(&renderer),
+            "Checking esp32-test, measurements may be up to 900 s old\n\
+             \x20 ok        broker   connected to localhost:1883\n\
+             \x20 ok        message  retained measurement, taken 42 s ago\n\
+             \x20 degraded  influx   newest point in air_quality, taken 3 h ago\n\
+             Overall: degraded"
+        );
+    }
+
+    #[test]
+    fn durations_take_a_unit() {
+        assert_eq!(parse_max_age("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_max_age("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_max_age("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_max_age("0s").is_err());
+        assert!(parse_max_age("5d").is_err());
+        assert!(parse_max_age("m").is_err());
+    }
+}
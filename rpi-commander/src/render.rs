@@ -0,0 +1,1313 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use owo_colors::{OwoColorize, Style};
+use serde::Serialize;
+use shared_types::{Celsius, DeviceMessage, DevicePayload};
+
+use crate::age;
+use crate::compat::UnknownMessage;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnitSystem {
+    #[default]
+    Metric,
+    /// Fahrenheit and US-style 12-hour timestamps.
+    Imperial,
+}
+
+impl UnitSystem {
+    fn timestamp_format(self) -> &'static str {
+        match self {
+            UnitSystem::Metric => "%Y-%m-%d %H:%M:%S",
+            UnitSystem::Imperial => "%m/%d/%Y %I:%M:%S %p",
+        }
+    }
+
+    fn clock_format(self) -> &'static str {
+        match self {
+            UnitSystem::Metric => "%H:%M",
+            UnitSystem::Imperial => "%I:%M %p",
+        }
+    }
+}
+
+impl FromStr for UnitSystem {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "metric" => Ok(UnitSystem::Metric),
+            "imperial" => Ok(UnitSystem::Imperial),
+            other => anyhow::bail!("unknown unit system '{}' (expected metric|imperial)", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    #[default]
+    Text,
+    /// One canonical JSON object per message, always metric and UTC.
+    Json,
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(OutputMode::Text),
+            "json" => Ok(OutputMode::Json),
+            other => anyhow::bail!("unknown output mode '{}' (expected text|json)", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayPrefs {
+    pub units: UnitSystem,
+    pub output: OutputMode,
+    /// ANSI colors in text output
+    pub color: bool,
+    /// Times older than this are shown in full rather than as an age
+    pub absolute_after: Duration,
+}
+
+impl Default for DisplayPrefs {
+    fn default() -> Self {
+        Self {
+            units: UnitSystem::default(),
+            output: OutputMode::default(),
+            color: false,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        }
+    }
+}
+
+/// Colors only make sense on a terminal, and https://no-color.org asks for
+/// none whenever `NO_COLOR` is set to a non-empty value.
+pub fn color_enabled(stdout_is_tty: bool, no_color: Option<&str>) -> bool {
+    stdout_is_tty && no_color.is_none_or(str::is_empty)
+}
+
+impl DisplayPrefs {
+    /// Reads `UNITS`, `OUTPUT` and `ABSOLUTE_TIME_AFTER` (seconds), falling
+    /// back to metric text output with ages up to 12 hours.
+    pub fn from_env() -> anyhow::Result<Self> {
+        use std::io::IsTerminal;
+
+        let mut prefs = Self {
+            color: color_enabled(
+                std::io::stdout().is_terminal(),
+                std::env::var("NO_COLOR").ok().as_deref(),
+            ),
+            ..Self::default()
+        };
+        if let Ok(units) = std::env::var("UNITS") {
+            prefs.units = units.parse()?;
+        }
+        if let Ok(output) = std::env::var("OUTPUT") {
+            prefs.output = output.parse()?;
+        }
+        if let Ok(seconds) = std::env::var("ABSOLUTE_TIME_AFTER") {
+            let seconds: i64 = seconds
+                .parse()
+                .map_err(|_| anyhow::anyhow!("ABSOLUTE_TIME_AFTER must be a number of seconds"))?;
+            prefs.absolute_after = Duration::seconds(seconds);
+        }
+        Ok(prefs)
+    }
+
+    pub fn render(&self, msg: &DeviceMessage, received_at: DateTime<FixedOffset>) -> String {
+        match self.output {
+            OutputMode::Text => self.text_renderer().render(msg, received_at),
+            OutputMode::Json => JsonRenderer.render(msg, received_at),
+        }
+    }
+
+    /// A message with a payload this build doesn't know, as received
+    pub fn render_unknown(
+        &self,
+        unknown: &UnknownMessage,
+        received_at: DateTime<FixedOffset>,
+    ) -> String {
+        match self.output {
+            OutputMode::Text => self.text_renderer().render_unknown(unknown, received_at),
+            OutputMode::Json => JsonRenderer.render_unknown(unknown, received_at),
+        }
+    }
+
+    pub fn text_renderer(&self) -> TextRenderer {
+        TextRenderer {
+            units: self.units,
+            color: self.color,
+            absolute_after: self.absolute_after,
+        }
+    }
+}
+
+pub trait Renderer {
+    fn render(&self, msg: &DeviceMessage, received_at: DateTime<FixedOffset>) -> String;
+}
+
+pub struct TextRenderer {
+    pub units: UnitSystem,
+    pub color: bool,
+    pub absolute_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tone {
+    Success,
+    Error,
+    Warning,
+    /// CO2 band by indoor air quality
+    IaqGood,
+    IaqModerate,
+    IaqPoor,
+    IaqBad,
+}
+
+impl Tone {
+    /// Payloads shown as their one-line text are colored by outcome
+    fn for_payload(payload: &DevicePayload) -> Option<Self> {
+        match payload {
+            DevicePayload::FrcSuccess { .. }
+            | DevicePayload::SetDeepSleepTimeSuccess { .. }
+            | DevicePayload::SetMqttPolicySuccess { .. }
+            | DevicePayload::OtaSuccess { .. }
+            | DevicePayload::SetLogLevelSuccess { .. }
+            | DevicePayload::SetAdaptiveModeSuccess { .. }
+            | DevicePayload::AscSetSuccess { .. }
+            | DevicePayload::AltitudeSetSuccess { .. }
+            | DevicePayload::AmbientPressureSetSuccess { .. }
+            | DevicePayload::SelfTestResult { passed: true, .. }
+            | DevicePayload::FactoryResetSuccess => Some(Tone::Success),
+            DevicePayload::Error { .. }
+            | DevicePayload::FrcError { .. }
+            | DevicePayload::SetOffsetError { .. }
+            | DevicePayload::GetOffsetError { .. }
+            | DevicePayload::SetDeepSleepTimeError { .. }
+            | DevicePayload::GetDeepSleepTimeError { .. }
+            | DevicePayload::SetMqttPolicyError { .. }
+            | DevicePayload::OtaError { .. }
+            | DevicePayload::SetLogLevelError { .. }
+            | DevicePayload::SetAdaptiveModeError { .. }
+            | DevicePayload::AscError { .. }
+            | DevicePayload::AltitudeError { .. }
+            | DevicePayload::AmbientPressureError { .. }
+            | DevicePayload::ConfigRolledBack { .. }
+            | DevicePayload::ConfirmConfigError { .. }
+            | DevicePayload::SelfTestResult { passed: false, .. }
+            | DevicePayload::FactoryResetError { .. }
+            | DevicePayload::CommandAck {
+                accepted: false, ..
+            }
+            | DevicePayload::BusRecovery {
+                recovered: false, ..
+            } => Some(Tone::Error),
+            DevicePayload::CommandsDeferred { .. }
+            | DevicePayload::BusRecovery {
+                recovered: true, ..
+            }
+            | DevicePayload::PendingConfirmation { .. }
+            | DevicePayload::Rebooting { .. }
+            | DevicePayload::FaultArmed { .. }
+            | DevicePayload::RadioSkipped { .. } => Some(Tone::Warning),
+            _ => None,
+        }
+    }
+
+    fn for_co2(co2: u16) -> Self {
+        match co2 {
+            0..=800 => Tone::IaqGood,
+            801..=1200 => Tone::IaqModerate,
+            1201..=2000 => Tone::IaqPoor,
+            _ => Tone::IaqBad,
+        }
+    }
+
+    fn style(self) -> Style {
+        match self {
+            Tone::Success | Tone::IaqGood => Style::new().green(),
+            Tone::Warning | Tone::IaqModerate => Style::new().yellow(),
+            Tone::IaqPoor => Style::new().bright_red(),
+            Tone::Error | Tone::IaqBad => Style::new().red().bold(),
+        }
+    }
+}
+
+impl TextRenderer {
+    fn paint(&self, text: impl std::fmt::Display, tone: Tone) -> String {
+        if self.color {
+            text.style(tone.style()).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Highlights a line that isn't a device message, e.g. stale data.
+    pub fn warning(&self, text: &str) -> String {
+        self.paint(text, Tone::Warning)
+    }
+
+    pub fn success(&self, text: &str) -> String {
+        self.paint(text, Tone::Success)
+    }
+
+    pub fn error(&self, text: &str) -> String {
+        self.paint(text, Tone::Error)
+    }
+
+    /// An age, or the full time once it's older than `absolute_after`
+    pub fn time(&self, at: DateTime<FixedOffset>, now: DateTime<FixedOffset>) -> String {
+        age::display(at, now, self.absolute_after, self.units.timestamp_format())
+    }
+
+    fn temperature(&self, celsius: f32) -> String {
+        match self.units {
+            UnitSystem::Metric => format!("{}°C", celsius),
+            UnitSystem::Imperial => format!("{:.1}°F", Celsius(celsius).to_fahrenheit()),
+        }
+    }
+
+    fn temperature_offset(&self, celsius: f32) -> String {
+        match self.units {
+            UnitSystem::Metric => format!("{}°C", celsius),
+            UnitSystem::Imperial => format!("{:.1}°F", Celsius(celsius).delta_to_fahrenheit()),
+        }
+    }
+}
+
+impl TextRenderer {
+    /// The raw JSON under the usual header
+    pub fn render_unknown(
+        &self,
+        unknown: &UnknownMessage,
+        received_at: DateTime<FixedOffset>,
+    ) -> String {
+        [
+            format!(
+                "[Device: {}] {}",
+                unknown.device,
+                received_at.format(self.units.timestamp_format())
+            ),
+            self.paint(
+                format!("  Unknown Payload '{}'", unknown.status),
+                Tone::Warning,
+            ),
+            format!("  {}", unknown.raw),
+        ]
+        .join("\n")
+    }
+}
+
+impl Renderer for TextRenderer {
+    fn render(&self, msg: &DeviceMessage, received_at: DateTime<FixedOffset>) -> String {
+        let mut header = format!(
+            "[Device: {}] {}",
+            msg.device,
+            received_at.format(self.units.timestamp_format())
+        );
+        if let Some(location) = &msg.location {
+            header.push_str(&format!(", in {}", location));
+        }
+        // Differs from the receive time for retained and delayed messages
+        if let Some(sent) = msg
+            .ts
+            .and_then(|ts| DateTime::from_timestamp_millis(ts as i64))
+        {
+            header.push_str(&format!(
+                ", sent {}",
+                self.time(sent.fixed_offset(), received_at)
+            ));
+        }
+        if let Some(id) = msg.in_reply_to {
+            header.push_str(&format!(", answering command {}", id));
+        }
+        if let Some(version) = &msg.fw_version {
+            header.push_str(&format!(", firmware {}", version));
+        }
+        let mut lines = vec![header];
+        if msg.injected {
+            lines.push(self.paint("  INJECTED FAULT, not real data", Tone::Warning));
+        }
+
+        match &msg.payload {
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags,
+            } => {
+                lines.push(self.paint("  Measurement Success", Tone::Success));
+                lines.push(format!(
+                    "  CO2: {}",
+                    self.paint(format!("{} ppm", co2), Tone::for_co2(*co2))
+                ));
+                lines.push(format!("  Temperature: {}", self.temperature(*temperature)));
+                lines.push(format!("  Humidity: {:.1}%", humidity));
+                match (battery_mv, battery_percent) {
+                    (Some(mv), Some(percent)) => {
+                        lines.push(format!("  Battery: {}% ({} mV)", percent, mv))
+                    }
+                    (Some(mv), None) => lines.push(format!("  Battery: {} mV", mv)),
+                    (None, Some(percent)) => lines.push(format!("  Battery: {}%", percent)),
+                    (None, None) => {}
+                }
+                if let Some(flags) = flags.filter(|flags| flags.any()) {
+                    lines.push(self.paint(format!("  Flags: {}", flags), Tone::Warning));
+                }
+            }
+            DevicePayload::MeasurementBatch { readings } => {
+                lines.push(self.paint(
+                    format!("  Measurement Batch: {} reading(s)", readings.len()),
+                    Tone::Success,
+                ));
+                for reading in readings {
+                    let age = age::span(Duration::seconds(reading.age_seconds.into()));
+                    lines.push(format!(
+                        "    {:>6} ago  {}  {:>7}  {:>5.1}%",
+                        age,
+                        self.paint(
+                            format!("{:>4} ppm", reading.co2),
+                            Tone::for_co2(reading.co2)
+                        ),
+                        self.temperature(reading.temperature),
+                        reading.humidity
+                    ));
+                }
+            }
+            DevicePayload::SetOffsetSuccess { offset, persisted } => {
+                lines.push(self.paint(
+                    format!(
+                        "  Temperature offset set to {}{}",
+                        self.temperature_offset(*offset),
+                        if *persisted {
+                            ""
+                        } else {
+                            " (not saved, lost when the sensor loses power)"
+                        }
+                    ),
+                    Tone::Success,
+                ));
+            }
+            DevicePayload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            } => {
+                lines.push(self.paint(
+                    format!(
+                        "  Temperature offset {} applied but not saved, the sensor's \
+                         EEPROM was already written {} times today; saving works again in {}h {}m",
+                        self.temperature_offset(*offset),
+                        limit,
+                        retry_after_seconds / 3600,
+                        retry_after_seconds % 3600 / 60
+                    ),
+                    Tone::Warning,
+                ));
+            }
+            DevicePayload::PersistRateLimited {
+                command,
+                limit,
+                retry_after_seconds,
+            } => {
+                lines.push(self.paint(
+                    format!(
+                        "  {} applied but not saved, the sensor's EEPROM was already \
+                         written {} times today; saving works again in {}h {}m",
+                        command,
+                        limit,
+                        retry_after_seconds / 3600,
+                        retry_after_seconds % 3600 / 60
+                    ),
+                    Tone::Warning,
+                ));
+            }
+            DevicePayload::GetOffsetSuccess { offset } => {
+                lines.push(format!(
+                    "  Temperature offset: {}",
+                    self.temperature_offset(*offset)
+                ));
+            }
+            DevicePayload::Config(config) => {
+                let unknown = || "-".to_string();
+                let rows = [
+                    ("Firmware", config.firmware_version.clone()),
+                    ("Sleep", format!("{} s", config.sleep_seconds)),
+                    (
+                        "Quiet hours",
+                        config
+                            .quiet_hours
+                            .clone()
+                            .unwrap_or_else(|| "off".to_string()),
+                    ),
+                    ("UTC offset", format!("{:+} h", config.utc_offset_hours)),
+                    ("Sensor mode", config.sensor_mode.as_str().to_string()),
+                    (
+                        "Temp offset",
+                        config
+                            .temperature_offset
+                            .map_or_else(unknown, |o| self.temperature_offset(o)),
+                    ),
+                    (
+                        "Altitude",
+                        config
+                            .altitude_m
+                            .map_or_else(unknown, |m| format!("{} m", m)),
+                    ),
+                    (
+                        "Pressure",
+                        config
+                            .ambient_pressure_hpa
+                            .map_or_else(unknown, |p| format!("{} hPa", p)),
+                    ),
+                    (
+                        "Self-calib",
+                        config
+                            .asc_enabled
+                            .map_or_else(unknown, |on| if on { "on" } else { "off" }.to_string()),
+                    ),
+                    (
+                        "CO2 alarm",
+                        config
+                            .alarm_threshold_ppm
+                            .map_or_else(|| "off".to_string(), |ppm| format!("{} ppm", ppm)),
+                    ),
+                    ("MQTT policy", config.mqtt_policy.clone()),
+                    ("Wi-Fi", config.wifi_ssid.clone()),
+                ];
+                lines.push("  Configuration".to_string());
+                for (label, value) in rows {
+                    lines.push(format!("    {:<12} {}", label, value));
+                }
+                if config.safe_mode {
+                    lines.push(self.paint("    Safe mode is on", Tone::Warning));
+                }
+            }
+            DevicePayload::LogLines { lines: logged } => {
+                lines.push(format!("  Log lines: last {} line(s)", logged.len()));
+                for line in logged {
+                    lines.push(format!("    {}", line));
+                }
+            }
+            DevicePayload::Diagnostics {
+                rssi_dbm,
+                free_heap_bytes,
+                boot_count,
+                reset_reason,
+            } => {
+                lines.push("  Diagnostics:".to_string());
+                let signal = format!(
+                    "    Signal: {} dBm ({})",
+                    rssi_dbm,
+                    signal_quality(*rssi_dbm)
+                );
+                // Below -80 dBm publishes start failing
+                lines.push(if *rssi_dbm < -80 {
+                    self.paint(signal, Tone::Warning)
+                } else {
+                    signal
+                });
+                lines.push(format!(
+                    "    Free heap: {:.1} KiB",
+                    *free_heap_bytes as f64 / 1024.0
+                ));
+                lines.push(format!("    Boot count: {}", boot_count));
+                lines.push(format!("    Last reset: {}", reset_reason));
+            }
+            DevicePayload::EnteringSleep {
+                sleep_seconds,
+                next_wake_unix,
+            } => {
+                // Without a synced clock the device can't say, so count from
+                // when the message came in
+                let wake = next_wake_unix
+                    .and_then(|unix| DateTime::from_timestamp(unix as i64, 0))
+                    .map(|wake| wake.with_timezone(received_at.offset()))
+                    .unwrap_or(received_at + Duration::seconds(*sleep_seconds as i64));
+                lines.push(format!(
+                    "  Sleeping until {} ({} s)",
+                    wake.format(self.units.clock_format()),
+                    sleep_seconds
+                ));
+            }
+            DevicePayload::FirmwareInfo {
+                version,
+                build_time,
+                idf_version,
+            } => {
+                lines.push(format!("  Firmware: {}", version));
+                lines.push(format!("    Built: {}", build_time));
+                lines.push(format!("    ESP-IDF: {}", idf_version));
+            }
+            payload => {
+                let line = format!("  {}", payload);
+                lines.push(match Tone::for_payload(payload) {
+                    Some(tone) => self.paint(line, tone),
+                    None => line,
+                });
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// The usual Wi-Fi rule of thumb for an RSSI
+fn signal_quality(rssi_dbm: i8) -> &'static str {
+    match rssi_dbm {
+        -60.. => "strong",
+        -70..=-61 => "good",
+        -80..=-71 => "weak",
+        _ => "poor",
+    }
+}
+
+#[derive(Serialize)]
+struct StampedMessage<'a> {
+    #[serde(flatten)]
+    message: &'a DeviceMessage,
+    received_at: DateTime<Utc>,
+}
+
+/// Ignores the unit preference on purpose so scripts get stable output.
+pub struct JsonRenderer;
+
+impl Renderer for JsonRenderer {
+    fn render(&self, msg: &DeviceMessage, received_at: DateTime<FixedOffset>) -> String {
+        let stamped = StampedMessage {
+            message: msg,
+            received_at: received_at.with_timezone(&Utc),
+        };
+        serde_json::to_string(&stamped).unwrap_or_default()
+    }
+}
+
+impl JsonRenderer {
+    /// The message as received, stamped like the known ones
+    pub fn render_unknown(
+        &self,
+        unknown: &UnknownMessage,
+        received_at: DateTime<FixedOffset>,
+    ) -> String {
+        let mut raw = unknown.raw.clone();
+        if let Some(fields) = raw.as_object_mut() {
+            fields.insert(
+                "received_at".to_string(),
+                serde_json::json!(received_at.with_timezone(&Utc)),
+            );
+        }
+        raw.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::{BatchedReading, DeviceCommand, ErrorCode, MeasurementFlags};
+
+    fn received_at() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2025-01-15T14:05:09+01:00").unwrap()
+    }
+
+    fn text_message(message: DeviceMessage) -> String {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        }
+        .render(&message, received_at())
+    }
+
+    fn text(units: UnitSystem, payload: DevicePayload) -> String {
+        TextRenderer {
+            units,
+            color: false,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        }
+        .render(&DeviceMessage::new("esp32-scd40", payload), received_at())
+    }
+
+    #[test]
+    fn measurement_metric() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::measurement(612, 22.4, 41.3)
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Measurement Success\n  \
+             CO2: 612 ppm\n  \
+             Temperature: 22.4°C\n  \
+             Humidity: 41.3%"
+        );
+    }
+
+    #[test]
+    fn measurement_imperial() {
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::measurement(612, 22.4, 41.3)
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  \
+             Measurement Success\n  \
+             CO2: 612 ppm\n  \
+             Temperature: 72.3°F\n  \
+             Humidity: 41.3%"
+        );
+    }
+
+    #[test]
+    fn measurement_with_battery() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::measurement_with_battery(612, 22.4, 41.3, 3870, 72)
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Measurement Success\n  \
+             CO2: 612 ppm\n  \
+             Temperature: 22.4°C\n  \
+             Humidity: 41.3%\n  \
+             Battery: 72% (3870 mV)"
+        );
+    }
+
+    #[test]
+    fn measurement_flags_are_a_warning() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::measurement(612, 22.4, 41.3).flagged(MeasurementFlags {
+                    sensor_warmup_incomplete: true,
+                    ..Default::default()
+                })
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Measurement Success\n  \
+             CO2: 612 ppm\n  \
+             Temperature: 22.4°C\n  \
+             Humidity: 41.3%\n  \
+             Flags: sensor warming up"
+        );
+    }
+
+    #[test]
+    fn stamped_messages_show_when_they_were_sent() {
+        let received = received_at().timestamp_millis() as u64;
+        let stamped = |ts: u64| {
+            text_message(
+                DeviceMessage::new("esp32-scd40", DevicePayload::error("Measurement timed out"))
+                    .stamped(Some(ts), 7),
+            )
+        };
+        assert_eq!(
+            stamped(received - 12_000),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09, sent 12 s ago\n  \
+             Error: Measurement timed out"
+        );
+        // Retained from two days ago
+        assert!(
+            stamped(received - 2 * 86_400_000).starts_with(
+                "[Device: esp32-scd40] 2025-01-15 14:05:09, sent 2025-01-13 14:05:09\n"
+            )
+        );
+    }
+
+    #[test]
+    fn answers_show_the_command_id() {
+        assert_eq!(
+            text_message(
+                DeviceMessage::new(
+                    "esp32-scd40",
+                    DevicePayload::GetOffsetSuccess { offset: 4.0 }
+                )
+                .replying_to(7)
+            )
+            .lines()
+            .next(),
+            Some("[Device: esp32-scd40] 2025-01-15 14:05:09, answering command 7")
+        );
+    }
+
+    #[test]
+    fn headers_show_the_firmware_version() {
+        let firmware = DevicePayload::FirmwareInfo {
+            version: "0.4.0".to_string(),
+            build_time: "2025-01-15T12:00:00Z".to_string(),
+            idf_version: "v5.3.2".to_string(),
+        };
+        assert_eq!(
+            text_message(DeviceMessage::new("esp32-scd40", firmware).with_fw_version("0.4.0")),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09, firmware 0.4.0\n  \
+             Firmware: 0.4.0\n    \
+             Built: 2025-01-15T12:00:00Z\n    \
+             ESP-IDF: v5.3.2"
+        );
+    }
+
+    #[test]
+    fn headers_show_the_location() {
+        let message = DeviceMessage::new("esp32-scd40", DevicePayload::Alive { uptime_seconds: 5 })
+            .with_fw_version("0.4.0")
+            .with_location("bedroom");
+        assert_eq!(
+            text_message(message).lines().next(),
+            Some("[Device: esp32-scd40] 2025-01-15 14:05:09, in bedroom, firmware 0.4.0")
+        );
+    }
+
+    #[test]
+    fn batches_render_one_row_per_reading() {
+        let reading = |co2, age_seconds| BatchedReading {
+            co2,
+            temperature: 22.4,
+            humidity: 41.3,
+            age_seconds,
+        };
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::MeasurementBatch {
+                    readings: vec![reading(1240, 7200), reading(640, 600), reading(612, 0)],
+                }
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Measurement Batch: 3 reading(s)\n       \
+             2 h ago  1240 ppm   22.4°C   41.3%\n      \
+             10 m ago   640 ppm   22.4°C   41.3%\n       \
+             0 s ago   612 ppm   22.4°C   41.3%"
+        );
+    }
+
+    #[test]
+    fn radio_skips_name_the_supply_voltages() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::RadioSkipped {
+                    skipped_wakes: 3,
+                    lowest_mv: 3410,
+                    threshold_mv: 3500,
+                }
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09
+  \
+             Radio skipped: 3 wake(s) with the supply below 3500 mV, lowest 3410 mV"
+        );
+    }
+
+    #[test]
+    fn log_lines_are_listed() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::LogLines {
+                    lines: vec![
+                        "I (812) sensor: Waiting for data... (attempt 1/15)".to_string(),
+                        "I (15830) sensor: Timeout waiting for sensor data".to_string(),
+                    ]
+                }
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Log lines: last 2 line(s)\n    \
+             I (812) sensor: Waiting for data... (attempt 1/15)\n    \
+             I (15830) sensor: Timeout waiting for sensor data"
+        );
+    }
+
+    #[test]
+    fn next_wake_explains_adaptive_sleep() {
+        let next_wake = |adaptive, delta_ppm| {
+            text(
+                UnitSystem::Metric,
+                DevicePayload::NextWake {
+                    sleep_seconds: 120,
+                    adaptive,
+                    delta_ppm,
+                },
+            )
+        };
+        assert!(
+            next_wake(true, Some(180))
+                .ends_with("Next wake in 120 s (adaptive, CO2 moved 180 ppm)")
+        );
+        assert!(
+            next_wake(true, None).ends_with("Next wake in 120 s (adaptive, no previous reading)")
+        );
+        assert!(next_wake(false, None).ends_with("Next wake in 120 s"));
+    }
+
+    #[test]
+    fn sleep_shows_the_wake_time() {
+        // 14:10:00 at the receiver's +01:00
+        let synced = DevicePayload::EnteringSleep {
+            sleep_seconds: 300,
+            next_wake_unix: Some(1_736_946_600),
+        };
+        assert!(text(UnitSystem::Metric, synced.clone()).ends_with("Sleeping until 14:10 (300 s)"));
+        assert!(text(UnitSystem::Imperial, synced).ends_with("Sleeping until 02:10 PM (300 s)"));
+        // Counted from 14:05:09 instead
+        let unsynced = DevicePayload::EnteringSleep {
+            sleep_seconds: 300,
+            next_wake_unix: None,
+        };
+        assert!(text(UnitSystem::Metric, unsynced).ends_with("Sleeping until 14:10 (300 s)"));
+    }
+
+    #[test]
+    fn command_acks() {
+        let frc = DeviceCommand::StartFrc { target_ppm: 422 };
+        assert!(
+            text(UnitSystem::Metric, DevicePayload::command_accepted(&frc))
+                .ends_with("\n  start_frc accepted")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::command_rejected(&frc, "FRC is already running")
+            )
+            .ends_with("\n  start_frc rejected: FRC is already running")
+        );
+    }
+
+    #[test]
+    fn asc_answers() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::AscSetSuccess { enabled: false }
+            )
+            .ends_with("Automatic self-calibration set to off")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::AscGetSuccess { enabled: true }
+            )
+            .ends_with("Automatic self-calibration: on")
+        );
+    }
+
+    #[test]
+    fn compensation_answers() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::AltitudeSetSuccess { meters: 600 }
+            )
+            .ends_with("Altitude set to 600 m")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::AltitudeGetSuccess { meters: 600 }
+            )
+            .ends_with("Altitude: 600 m")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::AmbientPressureSetSuccess { pascals: 94200 }
+            )
+            .ends_with("Ambient pressure set to 94200 Pa")
+        );
+    }
+
+    #[test]
+    fn config_trials() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::PendingConfirmation {
+                    id: 41,
+                    command: "set_deep_sleep_time".to_string(),
+                    wakes_left: 3,
+                }
+            )
+            .ends_with(
+                "Trying set_deep_sleep_time (#41): confirm within 3 wake(s) or it rolls back"
+            )
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::ConfigRolledBack {
+                    id: 41,
+                    command: "set_mqtt_policy".to_string(),
+                }
+            )
+            .ends_with("Rolled back set_mqtt_policy (#41): never confirmed")
+        );
+    }
+
+    #[test]
+    fn self_test_results() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::SelfTestResult {
+                    passed: true,
+                    detail: String::new(),
+                }
+            )
+            .ends_with("Self test passed")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::SelfTestResult {
+                    passed: false,
+                    detail: "malfunction: the sensor reported a fault".to_string(),
+                }
+            )
+            .ends_with("Self test failed: malfunction: the sensor reported a fault")
+        );
+    }
+
+    #[test]
+    fn serial_number_in_hex_and_decimal() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::SerialNumber {
+                    serial: 273_325_796_834_238
+                }
+            )
+            .ends_with("Serial number: 0xF8969F073BBE (273325796834238)")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::SerialNumber { serial: 42 }
+            )
+            .ends_with("Serial number: 0x00000000002A (42)")
+        );
+    }
+
+    #[test]
+    fn rebooting() {
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::Rebooting {
+                    detail: "restarting instead of sleeping".to_string(),
+                }
+            )
+            .ends_with("Rebooting: restarting instead of sleeping")
+        );
+    }
+
+    #[test]
+    fn factory_reset_answers() {
+        assert!(
+            text(UnitSystem::Metric, DevicePayload::FactoryResetSuccess)
+                .ends_with("Factory reset done")
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::FactoryResetError {
+                    code: ErrorCode::Other,
+                    detail: "not_confirmed: esp32-kitchen is not this device".to_string(),
+                }
+            )
+            .ends_with("Factory reset failed: not_confirmed: esp32-kitchen is not this device")
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep")
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  \
+             Diagnostics:\n    \
+             Signal: -67 dBm (good)\n    \
+             Free heap: 178.1 KiB\n    \
+             Boot count: 41\n    \
+             Last reset: deep_sleep"
+        );
+        assert!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::diagnostics(-85, 182344, 41, "brownout")
+            )
+            .contains("Signal: -85 dBm (poor)")
+        );
+    }
+
+    #[test]
+    fn offsets_metric() {
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::SetOffsetSuccess {
+                    offset: 4.0,
+                    persisted: true
+                }
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  Temperature offset set to 4°C"
+        );
+        assert_eq!(
+            text(
+                UnitSystem::Metric,
+                DevicePayload::GetOffsetSuccess { offset: 2.5 }
+            ),
+            "[Device: esp32-scd40] 2025-01-15 14:05:09\n  Temperature offset: 2.5°C"
+        );
+    }
+
+    #[test]
+    fn offsets_imperial() {
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::SetOffsetSuccess {
+                    offset: 4.0,
+                    persisted: true
+                }
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  Temperature offset set to 7.2°F"
+        );
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::SetOffsetSuccess {
+                    offset: 4.0,
+                    persisted: false
+                }
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  \
+             Temperature offset set to 7.2°F (not saved, lost when the sensor loses power)"
+        );
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::SetOffsetRateLimited {
+                    offset: 4.0,
+                    limit: 4,
+                    retry_after_seconds: 5 * 3600 + 30 * 60
+                }
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  \
+             Temperature offset 7.2°F applied but not saved, the sensor's EEPROM was \
+             already written 4 times today; saving works again in 5h 30m"
+        );
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::PersistRateLimited {
+                    command: "set_asc".to_string(),
+                    limit: 4,
+                    retry_after_seconds: 5 * 3600 + 30 * 60
+                }
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  \
+             set_asc applied but not saved, the sensor's EEPROM was already written 4 \
+             times today; saving works again in 5h 30m"
+        );
+        assert_eq!(
+            text(
+                UnitSystem::Imperial,
+                DevicePayload::GetOffsetSuccess { offset: 2.5 }
+            ),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  Temperature offset: 4.5°F"
+        );
+    }
+
+    #[test]
+    fn json_ignores_units() {
+        let msg = DeviceMessage::new("esp32-scd40", DevicePayload::measurement(612, 22.4, 41.3));
+        let prefs = DisplayPrefs {
+            units: UnitSystem::Imperial,
+            output: OutputMode::Json,
+            color: true,
+            ..DisplayPrefs::default()
+        };
+        assert_eq!(
+            prefs.render(&msg, received_at()),
+            r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"v":2,"received_at":"2025-01-15T13:05:09Z"}"#
+        );
+    }
+
+    #[test]
+    fn unknown_payloads_are_shown_raw() {
+        let json = r#"{"device":"kitchen","index":112,"status":"voc_reading","v":3}"#;
+        let crate::compat::Decoded::Unknown(unknown) = crate::compat::decode(json).unwrap() else {
+            panic!("known");
+        };
+        let prefs = DisplayPrefs::default();
+        assert_eq!(
+            prefs.render_unknown(&unknown, received_at()),
+            format!(
+                "[Device: kitchen] 2025-01-15 14:05:09\n  Unknown Payload 'voc_reading'\n  {}",
+                json
+            )
+        );
+        let prefs = DisplayPrefs {
+            output: OutputMode::Json,
+            ..prefs
+        };
+        assert_eq!(
+            prefs.render_unknown(&unknown, received_at()),
+            r#"{"device":"kitchen","index":112,"received_at":"2025-01-15T13:05:09Z","status":"voc_reading","v":3}"#
+        );
+    }
+
+    #[test]
+    fn config_is_a_table() {
+        let config = shared_types::device_config::DeviceConfig {
+            firmware_version: "0.1.0".to_string(),
+            sleep_seconds: 300,
+            quiet_hours: Some("22-7".to_string()),
+            utc_offset_hours: 1,
+            temperature_offset: Some(4.0),
+            altitude_m: Some(120),
+            asc_enabled: Some(true),
+            mqtt_policy: "measurement=1+retain".to_string(),
+            wifi_ssid: "home".to_string(),
+            safe_mode: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            text(UnitSystem::Imperial, DevicePayload::Config(config)),
+            "[Device: esp32-scd40] 01/15/2025 02:05:09 PM\n  \
+             Configuration\n    \
+             Firmware     0.1.0\n    \
+             Sleep        300 s\n    \
+             Quiet hours  22-7\n    \
+             UTC offset   +1 h\n    \
+             Sensor mode  periodic\n    \
+             Temp offset  7.2°F\n    \
+             Altitude     120 m\n    \
+             Pressure     -\n    \
+             Self-calib   on\n    \
+             CO2 alarm    off\n    \
+             MQTT policy  measurement=1+retain\n    \
+             Wi-Fi        home\n    \
+             Safe mode is on"
+        );
+    }
+
+    fn colored(payload: DevicePayload) -> String {
+        TextRenderer {
+            units: UnitSystem::Metric,
+            color: true,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        }
+        .render(&DeviceMessage::new("esp32-scd40", payload), received_at())
+    }
+
+    #[test]
+    fn plain_output_has_no_escape_codes() {
+        for payload in [
+            DevicePayload::measurement(2500, 22.4, 41.3),
+            DevicePayload::error("Measurement timed out"),
+            DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: "I2C(Timeout)".to_string(),
+            },
+            DevicePayload::SetOffsetSuccess {
+                offset: 4.0,
+                persisted: true,
+            },
+        ] {
+            let out = text(UnitSystem::Metric, payload);
+            assert!(!out.contains('\x1b'), "{:?}", out);
+        }
+        let renderer = TextRenderer {
+            units: UnitSystem::Metric,
+            color: false,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        };
+        assert_eq!(renderer.warning("stale"), "stale");
+    }
+
+    #[test]
+    fn colored_output_highlights_severity() {
+        let out = colored(DevicePayload::error("Measurement timed out"));
+        assert!(
+            out.contains(
+                &"  Error: Measurement timed out"
+                    .style(Style::new().red().bold())
+                    .to_string()
+            )
+        );
+
+        let out = colored(DevicePayload::SetOffsetError {
+            code: ErrorCode::Other,
+            detail: "failed_to_set".to_string(),
+        });
+        assert!(
+            out.contains(
+                &"  Set temperature offset error: failed_to_set"
+                    .style(Style::new().red().bold())
+                    .to_string()
+            )
+        );
+
+        let out = colored(DevicePayload::frc_success(12));
+        assert!(
+            out.contains(
+                &"  FRC success, correction 12 ppm"
+                    .style(Style::new().green())
+                    .to_string()
+            )
+        );
+
+        let renderer = TextRenderer {
+            units: UnitSystem::Metric,
+            color: true,
+            absolute_after: age::DEFAULT_ABSOLUTE_AFTER,
+        };
+        assert_eq!(
+            renderer.warning("stale"),
+            "stale".style(Style::new().yellow()).to_string()
+        );
+    }
+
+    #[test]
+    fn co2_is_colored_by_iaq_band() {
+        let cases = [
+            (600, "600 ppm".style(Style::new().green()).to_string()),
+            (1000, "1000 ppm".style(Style::new().yellow()).to_string()),
+            (
+                1500,
+                "1500 ppm".style(Style::new().bright_red()).to_string(),
+            ),
+            (
+                2500,
+                "2500 ppm".style(Style::new().red().bold()).to_string(),
+            ),
+        ];
+        for (co2, expected) in cases {
+            let out = colored(DevicePayload::measurement(co2, 21.0, 40.0));
+            assert!(out.contains(&format!("  CO2: {}", expected)), "{:?}", out);
+            assert!(
+                out.contains(
+                    &"  Measurement Success"
+                        .style(Style::new().green())
+                        .to_string()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn color_respects_tty_and_no_color() {
+        assert!(color_enabled(true, None));
+        assert!(color_enabled(true, Some("")));
+        assert!(!color_enabled(true, Some("1")));
+        assert!(!color_enabled(false, None));
+    }
+}
@@ -0,0 +1,498 @@
+//! Picking one of several values instead of typing it.
+//!
+//! Commands that need a device or a time can be run without one; the
+//! commander then offers the candidates it knows of. On a terminal that
+//! supports raw mode the choice is made inline: typing narrows the list to
+//! the options containing the typed letters in order, the arrow keys move
+//! the cursor and Enter picks. Anywhere else the options are printed as a
+//! numbered list and a number or name is read back.
+//!
+//! `Selector` holds the filtering and navigation and knows nothing of the
+//! terminal, so it is tested with synthetic keys.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use crossterm::{
+    cursor::{MoveToColumn, MoveUp},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal::{self, Clear, ClearType},
+};
+use serde::Deserialize;
+
+use crate::age;
+use crate::setup::{self, InfluxSettings};
+
+/// Options shown at once; the list scrolls with the cursor
+const VISIBLE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    Enter,
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pending,
+    /// The index of the chosen option
+    Picked(usize),
+    Cancelled,
+}
+
+/// The filter typed so far and the options it leaves, best match first
+#[derive(Debug)]
+pub struct Selector<'a> {
+    options: &'a [String],
+    filter: String,
+    /// Indexes into `options`
+    matches: Vec<usize>,
+    /// Position in `matches`
+    cursor: usize,
+}
+
+impl<'a> Selector<'a> {
+    pub fn new(options: &'a [String]) -> Self {
+        let mut selector = Self {
+            options,
+            filter: String::new(),
+            matches: Vec::new(),
+            cursor: 0,
+        };
+        selector.refilter();
+        selector
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn matches(&self) -> &[usize] {
+        &self.matches
+    }
+
+    /// The option under the cursor, if anything matches
+    pub fn current(&self) -> Option<usize> {
+        self.matches.get(self.cursor).copied()
+    }
+
+    pub fn handle(&mut self, key: Key) -> Outcome {
+        match key {
+            Key::Char(c) => {
+                self.filter.push(c);
+                self.refilter();
+            }
+            Key::Backspace => {
+                if self.filter.pop().is_some() {
+                    self.refilter();
+                }
+            }
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => {
+                if self.cursor + 1 < self.matches.len() {
+                    self.cursor += 1;
+                }
+            }
+            Key::Enter => {
+                if let Some(index) = self.current() {
+                    return Outcome::Picked(index);
+                }
+            }
+            Key::Cancel => return Outcome::Cancelled,
+        }
+        Outcome::Pending
+    }
+
+    fn refilter(&mut self) {
+        let mut scored: Vec<(usize, usize)> = self
+            .options
+            .iter()
+            .enumerate()
+            .filter_map(|(index, option)| score(&self.filter, option).map(|s| (s, index)))
+            .collect();
+        // Stable, so equally good matches keep their order
+        scored.sort_by_key(|(score, _)| *score);
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.cursor = 0;
+    }
+}
+
+/// How well `option` matches `filter`, lower is better: `None` unless the
+/// filter's characters appear in it in order, ignoring case. Matches that
+/// start early and stay together rank first.
+pub fn score(filter: &str, option: &str) -> Option<usize> {
+    let option: Vec<char> = option.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut next = 0;
+    for (n, wanted) in filter.to_lowercase().chars().enumerate() {
+        let found = next + option[next..].iter().position(|c| *c == wanted)?;
+        // The first match counts its position, later ones their gap
+        score += if n == 0 { found } else { found - next };
+        next = found + 1;
+    }
+    Some(score)
+}
+
+/// The lines of the inline selector: the prompt with the filter, then the
+/// window of matches around the cursor.
+pub fn view(prompt: &str, selector: &Selector) -> Vec<String> {
+    let mut lines = vec![format!("{} {}", prompt, selector.filter())];
+    let matches = selector.matches();
+    if matches.is_empty() {
+        lines.push("  no match".to_string());
+        return lines;
+    }
+    let start = selector.cursor.saturating_sub(VISIBLE - 1);
+    let end = (start + VISIBLE).min(matches.len());
+    for (position, index) in matches[start..end].iter().enumerate() {
+        let marker = if start + position == selector.cursor {
+            ">"
+        } else {
+            " "
+        };
+        lines.push(format!("{} {}", marker, selector.options[*index]));
+    }
+    if end < matches.len() {
+        lines.push(format!("  … {} more", matches.len() - end));
+    }
+    lines
+}
+
+/// The numbered-list answer: empty cancels, a number picks that entry and
+/// anything else must match exactly one option.
+pub fn parse_choice(answer: &str, options: &[String]) -> Result<Option<usize>, String> {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(number) = answer.parse::<usize>() {
+        return match number {
+            1.. if number <= options.len() => Ok(Some(number - 1)),
+            _ => Err(format!("Pick a number from 1 to {}", options.len())),
+        };
+    }
+    if let Some(index) = options.iter().position(|option| option == answer) {
+        return Ok(Some(index));
+    }
+    let matching: Vec<usize> = (0..options.len())
+        .filter(|index| score(answer, &options[*index]).is_some())
+        .collect();
+    match matching[..] {
+        [index] => Ok(Some(index)),
+        [] => Err(format!("Nothing matches '{}'", answer)),
+        _ => Err(format!("'{}' matches {} options", answer, matching.len())),
+    }
+}
+
+/// Asks for one of `options`, returning its index, `None` if the user
+/// cancelled or there was nothing to choose from.
+pub fn select(prompt: &str, options: &[String]) -> Option<usize> {
+    if options.is_empty() {
+        return None;
+    }
+    if io::stdin().is_terminal() && terminal::enable_raw_mode().is_ok() {
+        let _raw = RawMode;
+        match select_inline(prompt, options) {
+            Ok(picked) => return picked,
+            Err(e) => log::debug!("Inline selection failed: {}", e),
+        }
+    }
+    select_numbered(prompt, options)
+}
+
+/// Leaves raw mode when dropped, however the selection ends
+struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn select_inline(prompt: &str, options: &[String]) -> io::Result<Option<usize>> {
+    let mut stdout = io::stdout();
+    let mut selector = Selector::new(options);
+    let mut drawn = 0;
+    loop {
+        // Back to the prompt line and draw over what was there
+        if drawn > 1 {
+            crossterm::queue!(stdout, MoveUp(drawn as u16 - 1))?;
+        }
+        crossterm::queue!(stdout, MoveToColumn(0), Clear(ClearType::FromCursorDown))?;
+        let lines = view(prompt, &selector);
+        write!(stdout, "{}", lines.join("\r\n"))?;
+        drawn = lines.len();
+        stdout.flush()?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        let Some(key) = key_of(key) else {
+            continue;
+        };
+        let outcome = selector.handle(key);
+        if outcome == Outcome::Pending {
+            continue;
+        }
+        if drawn > 1 {
+            crossterm::queue!(stdout, MoveUp(drawn as u16 - 1))?;
+        }
+        crossterm::queue!(stdout, MoveToColumn(0), Clear(ClearType::FromCursorDown))?;
+        stdout.flush()?;
+        return Ok(match outcome {
+            Outcome::Picked(index) => Some(index),
+            _ => None,
+        });
+    }
+}
+
+/// Key presses the selector acts on; releases and repeats of other keys
+/// are ignored.
+fn key_of(event: KeyEvent) -> Option<Key> {
+    if event.kind != KeyEventKind::Press {
+        return None;
+    }
+    match event.code {
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Some(Key::Cancel),
+        KeyCode::Char(c) => Some(Key::Char(c)),
+        KeyCode::Backspace => Some(Key::Backspace),
+        KeyCode::Up => Some(Key::Up),
+        KeyCode::Down => Some(Key::Down),
+        KeyCode::Enter => Some(Key::Enter),
+        KeyCode::Esc => Some(Key::Cancel),
+        _ => None,
+    }
+}
+
+fn select_numbered(prompt: &str, options: &[String]) -> Option<usize> {
+    println!("{}", prompt);
+    for (n, option) in options.iter().enumerate() {
+        println!("  {:>2}. {}", n + 1, option);
+    }
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("Number or name (empty to cancel): ");
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if stdin.read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        match parse_choice(&answer, options) {
+            Ok(picked) => return picked,
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TimeRow {
+    time: String,
+}
+
+/// The times of the latest `limit` points `device` has in `table`, newest
+/// first.
+pub async fn recent_times(
+    reqwest_client: &reqwest::Client,
+    settings: &InfluxSettings,
+    table: &str,
+    device: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<DateTime<Utc>>> {
+    let sql = format!(
+        "SELECT time FROM {} WHERE device = {} ORDER BY time DESC LIMIT {}",
+        table,
+        setup::sql_string(device),
+        limit
+    );
+    let rows = setup::query_influx::<TimeRow>(reqwest_client, settings, &sql).await?;
+    Ok(rows
+        .iter()
+        .filter_map(|row| setup::parse_influx_time(&row.time))
+        .collect())
+}
+
+/// Options for picking one of `times`: local time, then how long ago.
+pub fn time_labels(times: &[DateTime<Utc>], now: DateTime<FixedOffset>) -> Vec<String> {
+    times
+        .iter()
+        .map(|time| {
+            let local = time.with_timezone(now.offset());
+            format!(
+                "{}  ({})",
+                local.format("%Y-%m-%d %H:%M:%S"),
+                age::relative(local, now)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn options(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn typed(selector: &mut Selector, text: &str) {
+        for c in text.chars() {
+            assert_eq!(selector.handle(Key::Char(c)), Outcome::Pending);
+        }
+    }
+
+    #[test]
+    fn typing_narrows_to_ordered_letters_best_first() {
+        let devices = options(&["esp32-kitchen", "bedroom", "esp32-bedroom", "Kids-room"]);
+        let mut selector = Selector::new(&devices);
+        assert_eq!(selector.matches(), [0, 1, 2, 3]);
+
+        typed(&mut selector, "bed");
+        assert_eq!(selector.matches(), [1, 2]);
+        typed(&mut selector, "x");
+        assert!(selector.matches().is_empty());
+        assert_eq!(selector.handle(Key::Enter), Outcome::Pending);
+
+        selector.handle(Key::Backspace);
+        selector.handle(Key::Backspace);
+        selector.handle(Key::Backspace);
+        assert_eq!(selector.filter(), "b");
+        // Case is ignored
+        let mut selector = Selector::new(&devices);
+        typed(&mut selector, "KI");
+        assert_eq!(selector.matches(), [3, 0]);
+    }
+
+    #[test]
+    fn scores_prefer_early_and_tight_matches() {
+        assert_eq!(score("", "kitchen"), Some(0));
+        assert_eq!(score("kit", "kitchen"), Some(0));
+        assert_eq!(score("kit", "esp32-kitchen"), Some(6));
+        assert_eq!(score("kn", "kitchen"), Some(5));
+        assert_eq!(score("nk", "kitchen"), None);
+    }
+
+    #[test]
+    fn arrows_stay_within_the_matches() {
+        let devices = options(&["kitchen", "bedroom", "office"]);
+        let mut selector = Selector::new(&devices);
+        selector.handle(Key::Up);
+        assert_eq!(selector.current(), Some(0));
+        for _ in 0..5 {
+            selector.handle(Key::Down);
+        }
+        assert_eq!(selector.handle(Key::Enter), Outcome::Picked(2));
+
+        // A new filter starts from the top again
+        typed(&mut selector, "o");
+        assert_eq!(selector.current(), Some(2));
+        selector.handle(Key::Down);
+        assert_eq!(selector.handle(Key::Enter), Outcome::Picked(1));
+        assert_eq!(selector.handle(Key::Cancel), Outcome::Cancelled);
+    }
+
+    #[test]
+    fn the_view_scrolls_with_the_cursor() {
+        let many: Vec<String> = (1..=14).map(|n| format!("device-{}", n)).collect();
+        let mut selector = Selector::new(&many);
+        let lines = view("Device:", &selector);
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[0], "Device: ");
+        assert_eq!(lines[1], "> device-1");
+        assert_eq!(lines[11], "  … 4 more");
+
+        for _ in 0..11 {
+            selector.handle(Key::Down);
+        }
+        let lines = view("Device:", &selector);
+        assert_eq!(lines[1], "  device-3");
+        assert_eq!(lines[10], "> device-12");
+        assert_eq!(lines[11], "  … 2 more");
+
+        typed(&mut selector, "zz");
+        assert_eq!(view("Device:", &selector), ["Device: zz", "  no match"]);
+    }
+
+    #[test]
+    fn numbered_answers() {
+        let devices = options(&["kitchen", "bedroom", "bedroom-2"]);
+        assert_eq!(parse_choice("", &devices), Ok(None));
+        assert_eq!(parse_choice(" 2\n", &devices), Ok(Some(1)));
+        assert_eq!(parse_choice("bedroom", &devices), Ok(Some(1)));
+        assert_eq!(parse_choice("kit", &devices), Ok(Some(0)));
+        assert_eq!(
+            parse_choice("0", &devices),
+            Err("Pick a number from 1 to 3".to_string())
+        );
+        assert_eq!(
+            parse_choice("4", &devices),
+            Err("Pick a number from 1 to 3".to_string())
+        );
+        assert_eq!(
+            parse_choice("bed", &devices),
+            Err("'bed' matches 2 options".to_string())
+        );
+        assert_eq!(
+            parse_choice("attic", &devices),
+            Err("Nothing matches 'attic'".to_string())
+        );
+    }
+
+    #[test]
+    fn only_key_presses_count() {
+        let press = KeyEvent::new(KeyCode::Char('k'), KeyModifiers::NONE);
+        assert_eq!(key_of(press), Some(Key::Char('k')));
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(key_of(ctrl_c), Some(Key::Cancel));
+        let mut release = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        release.kind = KeyEventKind::Release;
+        assert_eq!(key_of(release), None);
+    }
+
+    #[test]
+    fn times_are_labelled_in_local_time() {
+        let now = DateTime::parse_from_rfc3339("2025-01-15T14:00:00+01:00").unwrap();
+        let times = [DateTime::parse_from_rfc3339("2025-01-15T12:55:00Z")
+            .unwrap()
+            .with_timezone(&Utc)];
+        assert_eq!(time_labels(&times, now), ["2025-01-15 13:55:00  (5 m ago)"]);
+    }
+
+    #[tokio::test]
+    async fn recent_times_come_newest_first_from_influx() {
+        use axum::{Router, routing::post};
+        let app = Router::new().route(
+            "/api/v3/query_sql",
+            post(|| async { r#"[{"time":"2025-01-15T12:55:00"},{"time":"2025-01-15T12:50:00"}]"# }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let settings = InfluxSettings {
+            url: format!("http://{}", addr),
+            token: "token".to_string(),
+            database: "air_quality".to_string(),
+        };
+
+        let times = recent_times(
+            &reqwest::Client::new(),
+            &settings,
+            "device_config",
+            "kitchen",
+            10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            times,
+            [
+                "2025-01-15T12:55:00Z".parse::<DateTime<Utc>>().unwrap(),
+                "2025-01-15T12:50:00Z".parse::<DateTime<Utc>>().unwrap(),
+            ]
+        );
+    }
+}
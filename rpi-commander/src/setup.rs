@@ -0,0 +1,903 @@
+//! First-run setup: asks for the broker, default device and optional
+//! InfluxDB settings, checks each one against the real service and writes
+//! them to the commander config file.
+//!
+//! The config file uses the same `KEY=VALUE` names as `.env`, and is loaded
+//! after it, so variables from the environment or `.env` still win. Every
+//! question has a flag, and with `--non-interactive` nothing is asked at all,
+//! for provisioning scripts.
+
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Packet, QoS, SubscribeReasonCode,
+    Transport,
+};
+use rustyline::DefaultEditor;
+use shared_types::topics::{self, COMMAND_BROADCAST_TOPIC};
+use shared_types::{DeviceCommand, DeviceMessage};
+
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_DEVICE: &str = "esp32-scd40";
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Args, Debug, Default)]
+pub struct SetupArgs {
+    /// Never prompt; everything not given as a flag keeps its default
+    #[arg(long)]
+    pub non_interactive: bool,
+
+    /// Write the configuration without connecting to anything
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Where to write the configuration [default: see COMMANDER_CONFIG]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long)]
+    pub broker_host: Option<String>,
+
+    #[arg(long)]
+    pub broker_port: Option<u16>,
+
+    #[arg(long)]
+    pub username: Option<String>,
+
+    #[arg(long)]
+    pub password: Option<String>,
+
+    /// Connect to the broker over TLS
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Device targeted at startup
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Name on the commands sent from here [default: the login name]
+    #[arg(long)]
+    pub operator: Option<String>,
+
+    /// How long commands stay worth running [default: a day; 0 for never]
+    #[arg(long, value_name = "SECONDS")]
+    pub command_ttl: Option<u64>,
+
+    /// InfluxDB URL; InfluxDB is left unconfigured without it
+    #[arg(long)]
+    pub influx_url: Option<String>,
+
+    #[arg(long)]
+    pub influx_token: Option<String>,
+
+    #[arg(long)]
+    pub influx_database: Option<String>,
+
+    /// Send a noop to the device and wait for it to answer
+    #[arg(long)]
+    pub confirm_device: bool,
+
+    /// How long to wait for the device, at least one deep sleep interval
+    #[arg(long, value_name = "SECONDS", default_value_t = 360)]
+    pub wake_timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+}
+
+impl BrokerSettings {
+    /// Reads `MQTT_BROKER_*`, `MQTT_USERNAME`, `MQTT_PASSWORD` and `MQTT_TLS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let port = match std::env::var("MQTT_BROKER_PORT") {
+            Ok(port) => port
+                .parse()
+                .context("MQTT_BROKER_PORT must be a valid u16")?,
+            Err(_) => DEFAULT_PORT,
+        };
+        Ok(Self {
+            host: std::env::var("MQTT_BROKER_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string()),
+            port,
+            username: std::env::var("MQTT_USERNAME").ok(),
+            password: std::env::var("MQTT_PASSWORD").ok(),
+            tls: std::env::var("MQTT_TLS").is_ok_and(|v| v == "1" || v == "true"),
+        })
+    }
+
+    pub fn mqtt_options(&self, client_id: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_clean_session(true);
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or(""));
+        }
+        if self.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxSettings {
+    pub url: String,
+    pub token: String,
+    pub database: String,
+}
+
+impl InfluxSettings {
+    /// Reads `INFLUXDB_URL`, `INFLUXDB_TOKEN` and `INFLUXDB_DATABASE`;
+    /// `None` without a URL.
+    pub fn from_env() -> Option<Self> {
+        std::env::var("INFLUXDB_URL").ok().map(|url| Self {
+            url,
+            token: std::env::var("INFLUXDB_TOKEN").unwrap_or_default(),
+            database: std::env::var("INFLUXDB_DATABASE").unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommanderConfig {
+    pub broker: BrokerSettings,
+    pub default_device: String,
+    /// Left out to use the login name, see `pending::operator`
+    pub operator: Option<String>,
+    /// Left out for a day, see `pending::Issuer`
+    pub command_ttl: Option<u64>,
+    pub influx: Option<InfluxSettings>,
+}
+
+impl CommanderConfig {
+    pub fn to_env_file(&self) -> String {
+        let mut lines = vec![
+            "# Written by `rpi-commander setup`".to_string(),
+            env_line("MQTT_BROKER_HOST", &self.broker.host),
+            env_line("MQTT_BROKER_PORT", &self.broker.port.to_string()),
+        ];
+        if let Some(username) = &self.broker.username {
+            lines.push(env_line("MQTT_USERNAME", username));
+        }
+        if let Some(password) = &self.broker.password {
+            lines.push(env_line("MQTT_PASSWORD", password));
+        }
+        if self.broker.tls {
+            lines.push(env_line("MQTT_TLS", "true"));
+        }
+        lines.push(env_line("DEFAULT_DEVICE", &self.default_device));
+        if let Some(operator) = &self.operator {
+            lines.push(env_line("COMMANDER_OPERATOR", operator));
+        }
+        if let Some(ttl) = self.command_ttl {
+            lines.push(env_line("COMMANDER_COMMAND_TTL", &ttl.to_string()));
+        }
+        if let Some(influx) = &self.influx {
+            lines.push(env_line("INFLUXDB_URL", &influx.url));
+            lines.push(env_line("INFLUXDB_TOKEN", &influx.token));
+            lines.push(env_line("INFLUXDB_DATABASE", &influx.database));
+        }
+        lines.join("\n") + "\n"
+    }
+
+    /// Readable only by the owner, since it can hold passwords.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        file.write_all(self.to_env_file().as_bytes())?;
+        Ok(())
+    }
+}
+
+fn env_line(key: &str, value: &str) -> String {
+    format!(
+        "{}=\"{}\"",
+        key,
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// `COMMANDER_CONFIG`, or `commander.env` in the user's config directory.
+pub fn config_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("COMMANDER_CONFIG") {
+        return PathBuf::from(path);
+    }
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+    base.join("air-quality").join("commander.env")
+}
+
+/// Loads the config file into the environment, without overriding variables
+/// that are already set. Returns whether the commander counts as configured.
+pub fn load_config(path: &Path) -> anyhow::Result<bool> {
+    if path.exists() {
+        dotenvy::from_path(path).with_context(|| format!("cannot read {}", path.display()))?;
+        return Ok(true);
+    }
+    Ok(std::env::var_os("MQTT_BROKER_HOST").is_some())
+}
+
+/// Polls until `wanted` matches an event, failing on errors and after `timeout`.
+pub async fn wait_for<T>(
+    eventloop: &mut EventLoop,
+    timeout: Duration,
+    mut wanted: impl FnMut(&Event) -> Option<anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, async {
+        loop {
+            let event = eventloop.poll().await.map_err(describe_connection_error)?;
+            if let Some(result) = wanted(&event) {
+                return result;
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("no answer within {}s", timeout.as_secs()))?
+}
+
+fn describe_connection_error(e: ConnectionError) -> anyhow::Error {
+    match e {
+        ConnectionError::ConnectionRefused(code) => {
+            anyhow!("broker refused the connection: {:?}", code)
+        }
+        ConnectionError::Io(e) => anyhow!("cannot reach the broker: {}", e),
+        other => anyhow!("MQTT error: {}", other),
+    }
+}
+
+pub async fn connect(
+    settings: &BrokerSettings,
+    client_id: &str,
+) -> anyhow::Result<(AsyncClient, EventLoop)> {
+    let (client, mut eventloop) = AsyncClient::new(settings.mqtt_options(client_id), 10);
+    wait_for(&mut eventloop, CHECK_TIMEOUT, |event| match event {
+        Event::Incoming(Packet::ConnAck(_)) => Some(Ok(())),
+        _ => None,
+    })
+    .await?;
+    Ok((client, eventloop))
+}
+
+pub async fn subscribe_responses(
+    client: &AsyncClient,
+    eventloop: &mut EventLoop,
+) -> anyhow::Result<()> {
+    client
+        .subscribe(topics::sensor_wildcard(), QoS::AtLeastOnce)
+        .await?;
+    wait_for(eventloop, CHECK_TIMEOUT, |event| match event {
+        Event::Incoming(Packet::SubAck(ack)) => Some(
+            if ack
+                .return_codes
+                .iter()
+                .all(|code| matches!(code, SubscribeReasonCode::Success(_)))
+            {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "broker refused the subscription to {}",
+                    topics::sensor_wildcard()
+                ))
+            },
+        ),
+        _ => None,
+    })
+    .await
+}
+
+/// Connects and subscribes to the device responses like the REPL does.
+pub async fn check_broker(settings: &BrokerSettings) -> anyhow::Result<()> {
+    let (client, mut eventloop) = connect(settings, "rpi-commander-setup").await?;
+    subscribe_responses(&client, &mut eventloop).await?;
+    let _ = client.disconnect().await;
+    Ok(())
+}
+
+pub fn sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// InfluxDB leaves the zone off UTC times.
+pub fn parse_influx_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = if value.ends_with('Z') || value.contains('+') {
+        value.to_string()
+    } else {
+        format!("{}Z", value)
+    };
+    DateTime::parse_from_rfc3339(&value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Runs `sql` and returns its rows.
+pub async fn query_influx<T: serde::de::DeserializeOwned>(
+    reqwest_client: &reqwest::Client,
+    settings: &InfluxSettings,
+    sql: &str,
+) -> anyhow::Result<Vec<T>> {
+    let response = reqwest_client
+        .post(format!(
+            "{}/api/v3/query_sql?db={}",
+            settings.url.trim_end_matches('/'),
+            settings.database
+        ))
+        .bearer_auth(&settings.token)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&serde_json::json!({
+            "db": settings.database,
+            "q": sql
+        }))?)
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("cannot reach InfluxDB at {}", settings.url))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        bail!("InfluxDB answered {}: {}", status, text.trim());
+    }
+    let text = response.text().await?;
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_str(&text).context("InfluxDB answered with something other than rows")
+}
+
+/// Runs a query that fails on a wrong token or a missing database.
+pub async fn check_influx(
+    reqwest_client: &reqwest::Client,
+    settings: &InfluxSettings,
+) -> anyhow::Result<()> {
+    query_influx::<serde_json::Value>(reqwest_client, settings, "SHOW TABLES").await?;
+    Ok(())
+}
+
+/// Sends a noop to `device` and waits for the message it publishes on its
+/// next wake. Retained messages are from an earlier wake and don't count.
+pub async fn confirm_device(
+    settings: &BrokerSettings,
+    device: &str,
+    timeout: Duration,
+) -> anyhow::Result<DeviceMessage> {
+    let (client, mut eventloop) = connect(settings, "rpi-commander-setup").await?;
+    subscribe_responses(&client, &mut eventloop).await?;
+    client
+        .publish(
+            COMMAND_BROADCAST_TOPIC,
+            QoS::AtLeastOnce,
+            true,
+            DeviceCommand::NoOp.to_json()?,
+        )
+        .await?;
+
+    let message = wait_for(&mut eventloop, timeout, |event| match event {
+        Event::Incoming(Packet::Publish(publish)) if !publish.retain => {
+            serde_json::from_slice::<DeviceMessage>(&publish.payload)
+                .ok()
+                .filter(|m| m.device == device)
+                .map(Ok)
+        }
+        _ => None,
+    })
+    .await
+    .with_context(|| format!("{} did not answer", device))?;
+    let _ = client.disconnect().await;
+    Ok(message)
+}
+
+/// Line-based questions with a default shown in brackets.
+struct Prompt {
+    editor: DefaultEditor,
+}
+
+impl Prompt {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            editor: DefaultEditor::new()?,
+        })
+    }
+
+    /// Ctrl-C or Ctrl-D abandons the setup.
+    fn ask(&mut self, question: &str, default: &str) -> anyhow::Result<String> {
+        let prompt = if default.is_empty() {
+            format!("{}: ", question)
+        } else {
+            format!("{} [{}]: ", question, default)
+        };
+        let answer = self.editor.readline(&prompt).context("setup cancelled")?;
+        let answer = answer.trim();
+        Ok(if answer.is_empty() {
+            default.to_string()
+        } else {
+            answer.to_string()
+        })
+    }
+
+    fn ask_optional(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let answer = self.ask(question, default.unwrap_or(""))?;
+        Ok((!answer.is_empty()).then_some(answer))
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> anyhow::Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            match self
+                .ask(&format!("{} ({})", question, hint), "")?
+                .to_lowercase()
+                .as_str()
+            {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => println!("Please answer y or n"),
+            }
+        }
+    }
+}
+
+/// Whether the first-run question makes sense: there is someone to answer it.
+pub fn can_prompt() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Asks whether to run the wizard now. Declining leaves the defaults.
+pub fn offer_first_run() -> anyhow::Result<bool> {
+    println!(
+        "No commander configuration found at {}",
+        config_path().display()
+    );
+    Prompt::new()?.confirm("Run setup now?", true)
+}
+
+fn broker_from_args(args: &SetupArgs, current: BrokerSettings) -> BrokerSettings {
+    BrokerSettings {
+        host: args.broker_host.clone().unwrap_or(current.host),
+        port: args.broker_port.unwrap_or(current.port),
+        username: args.username.clone().or(current.username),
+        password: args.password.clone().or(current.password),
+        tls: args.tls || current.tls,
+    }
+}
+
+fn ask_broker(prompt: &mut Prompt, current: &BrokerSettings) -> anyhow::Result<BrokerSettings> {
+    let host = prompt.ask("MQTT broker host", &current.host)?;
+    let port = loop {
+        match prompt
+            .ask("MQTT broker port", &current.port.to_string())?
+            .parse()
+        {
+            Ok(port) => break port,
+            Err(_) => println!("Not a valid port"),
+        }
+    };
+    let username = prompt.ask_optional("Username (empty for none)", current.username.as_deref())?;
+    let password = match &username {
+        Some(_) => prompt.ask_optional("Password", current.password.as_deref())?,
+        None => None,
+    };
+    let tls = prompt.confirm("Use TLS?", current.tls)?;
+    Ok(BrokerSettings {
+        host,
+        port,
+        username,
+        password,
+        tls,
+    })
+}
+
+fn ask_influx(
+    prompt: &mut Prompt,
+    current: Option<&InfluxSettings>,
+) -> anyhow::Result<Option<InfluxSettings>> {
+    if !prompt.confirm("Configure InfluxDB?", current.is_some())? {
+        return Ok(None);
+    }
+    Ok(Some(InfluxSettings {
+        url: prompt.ask(
+            "InfluxDB URL",
+            current.map_or("http://localhost:8181", |c| c.url.as_str()),
+        )?,
+        token: prompt.ask("InfluxDB token", current.map_or("", |c| c.token.as_str()))?,
+        database: prompt.ask(
+            "InfluxDB database",
+            current.map_or("air_quality", |c| c.database.as_str()),
+        )?,
+    }))
+}
+
+/// After a failed check: non-interactively that's the end, otherwise the
+/// user may re-enter the settings or keep them anyway.
+fn after_failure(
+    prompt: Option<&mut Prompt>,
+    what: &str,
+    error: anyhow::Error,
+) -> anyhow::Result<bool> {
+    println!("{} check failed: {:#}", what, error);
+    match prompt {
+        None => Err(error.context(format!("{} check failed", what))),
+        Some(prompt) => prompt.confirm(&format!("Re-enter the {} settings?", what), true),
+    }
+}
+
+pub async fn run(args: &SetupArgs) -> anyhow::Result<()> {
+    let path = args.config.clone().unwrap_or_else(config_path);
+    let mut prompt = if args.non_interactive {
+        None
+    } else {
+        Some(Prompt::new()?)
+    };
+
+    let mut broker = broker_from_args(args, BrokerSettings::from_env()?);
+    loop {
+        if let Some(prompt) = prompt.as_mut() {
+            broker = ask_broker(prompt, &broker)?;
+        }
+        if args.no_verify {
+            break;
+        }
+        println!("Connecting to {}:{}...", broker.host, broker.port);
+        match check_broker(&broker).await {
+            Ok(()) => {
+                println!("Broker OK, subscribed to {}", topics::sensor_wildcard());
+                break;
+            }
+            Err(e) => {
+                if !after_failure(prompt.as_mut(), "broker", e)? {
+                    break;
+                }
+            }
+        }
+    }
+
+    let default_device = args
+        .device
+        .clone()
+        .or_else(|| std::env::var("DEFAULT_DEVICE").ok())
+        .unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    let default_device = match prompt.as_mut() {
+        Some(prompt) => prompt.ask("Default device", &default_device)?,
+        None => default_device,
+    };
+
+    let mut influx = match &args.influx_url {
+        Some(url) => Some(InfluxSettings {
+            url: url.clone(),
+            token: args.influx_token.clone().unwrap_or_default(),
+            database: args
+                .influx_database
+                .clone()
+                .unwrap_or_else(|| "air_quality".to_string()),
+        }),
+        None => InfluxSettings::from_env(),
+    };
+    let reqwest_client = reqwest::Client::new();
+    loop {
+        if let Some(prompt) = prompt.as_mut() {
+            influx = ask_influx(prompt, influx.as_ref())?;
+        }
+        let Some(settings) = &influx else { break };
+        if args.no_verify {
+            break;
+        }
+        match check_influx(&reqwest_client, settings).await {
+            Ok(()) => {
+                println!("InfluxDB OK");
+                break;
+            }
+            Err(e) => {
+                if !after_failure(prompt.as_mut(), "InfluxDB", e)? {
+                    break;
+                }
+            }
+        }
+    }
+
+    let operator = args
+        .operator
+        .clone()
+        .or_else(|| std::env::var("COMMANDER_OPERATOR").ok());
+    let command_ttl = match args.command_ttl {
+        Some(ttl) => Some(ttl),
+        None => match std::env::var("COMMANDER_COMMAND_TTL") {
+            Ok(ttl) => Some(
+                ttl.parse()
+                    .context("COMMANDER_COMMAND_TTL must be a number of seconds")?,
+            ),
+            Err(_) => None,
+        },
+    };
+    let config = CommanderConfig {
+        broker,
+        default_device,
+        operator,
+        command_ttl,
+        influx,
+    };
+    config.write(&path)?;
+    println!("Configuration written to {}", path.display());
+
+    let confirm = match prompt.as_mut() {
+        Some(prompt) => prompt.confirm(
+            &format!(
+                "Send a noop to {} and wait up to {}s for it to wake up?",
+                config.default_device, args.wake_timeout
+            ),
+            false,
+        )?,
+        None => args.confirm_device,
+    };
+    if confirm {
+        println!("Waiting for {}...", config.default_device);
+        let message = confirm_device(
+            &config.broker,
+            &config.default_device,
+            Duration::from_secs(args.wake_timeout),
+        )
+        .await?;
+        println!(
+            "{} answered ({:?}), everything is connected",
+            message.device, message.payload
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn broker_at(port: u16) -> BrokerSettings {
+        BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            tls: false,
+        }
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+        let mut length = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic.as_bytes());
+        body.extend_from_slice(payload);
+        let mut packet = vec![0x30];
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            packet.push(if length > 0 { byte | 0x80 } else { byte });
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend(body);
+        packet
+    }
+
+    /// A single-connection MQTT 3.1.1 broker: accepts with `connack_code`,
+    /// grants subscriptions and, when a command is published, answers with
+    /// `answer` on the device's sensor topic.
+    async fn fake_broker(connack_code: u8, answer: Option<DeviceMessage>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_packet(&mut stream).await.unwrap();
+            stream
+                .write_all(&[0x20, 0x02, 0x00, connack_code])
+                .await
+                .unwrap();
+            while let Some((header, body)) = read_packet(&mut stream).await {
+                match header >> 4 {
+                    8 => {
+                        stream
+                            .write_all(&[0x90, 0x03, body[0], body[1], 0x01])
+                            .await
+                            .unwrap();
+                    }
+                    3 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let id = &body[2 + topic_len..4 + topic_len];
+                        stream.write_all(&[0x40, 0x02, id[0], id[1]]).await.unwrap();
+                        if let Some(answer) = &answer {
+                            let topic = topics::sensor_topic(&answer.device);
+                            let payload = answer.to_json().unwrap();
+                            stream
+                                .write_all(&publish_packet(&topic, payload.as_bytes()))
+                                .await
+                                .unwrap();
+                        }
+                    }
+                    12 => stream.write_all(&[0xd0, 0x00]).await.unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn broker_check_connects_and_subscribes() {
+        let port = fake_broker(0, None).await;
+        check_broker(&broker_at(port)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn broker_check_reports_refused_credentials() {
+        // 5: not authorized
+        let port = fake_broker(5, None).await;
+        let error = check_broker(&broker_at(port)).await.unwrap_err();
+        assert!(error.to_string().contains("refused"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn broker_check_fails_when_nothing_listens() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let error = check_broker(&broker_at(port)).await.unwrap_err();
+        assert!(error.to_string().contains("cannot reach"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn device_confirmation_waits_for_the_answer() {
+        let answer = DeviceMessage::new(
+            "esp32-test",
+            shared_types::DevicePayload::measurement(612, 21.5, 40.0),
+        );
+        let port = fake_broker(0, Some(answer.clone())).await;
+        let message = confirm_device(&broker_at(port), "esp32-test", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(message, answer);
+    }
+
+    #[tokio::test]
+    async fn device_confirmation_ignores_other_devices() {
+        let answer = DeviceMessage::new(
+            "esp32-other",
+            shared_types::DevicePayload::Alive { uptime_seconds: 1 },
+        );
+        let port = fake_broker(0, Some(answer)).await;
+        let result =
+            confirm_device(&broker_at(port), "esp32-test", Duration::from_millis(500)).await;
+        assert!(result.is_err());
+    }
+
+    async fn fake_influx(status: u16) -> String {
+        use axum::{Router, http::StatusCode, routing::post};
+        let app = Router::new().route(
+            "/api/v3/query_sql",
+            post(move || async move { (StatusCode::from_u16(status).unwrap(), "[]") }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn influx_check_accepts_a_working_query_and_rejects_bad_tokens() {
+        let client = reqwest::Client::new();
+        let settings = |url: String| InfluxSettings {
+            url,
+            token: "token".to_string(),
+            database: "air_quality".to_string(),
+        };
+        check_influx(&client, &settings(fake_influx(200).await))
+            .await
+            .unwrap();
+        let error = check_influx(&client, &settings(fake_influx(401).await))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("401"), "{}", error);
+    }
+
+    #[test]
+    fn config_file_reads_back_through_dotenv() {
+        let config = CommanderConfig {
+            broker: BrokerSettings {
+                host: "broker.lan".to_string(),
+                port: 8883,
+                username: Some("pi".to_string()),
+                password: Some(r#"p"a\ss word"#.to_string()),
+                tls: true,
+            },
+            default_device: "esp32-kitchen".to_string(),
+            operator: Some("ola".to_string()),
+            command_ttl: Some(3600),
+            influx: Some(InfluxSettings {
+                url: "http://localhost:8181".to_string(),
+                token: "apiv3_abc".to_string(),
+                database: "air_quality".to_string(),
+            }),
+        };
+        let dir = std::env::temp_dir().join(format!("commander-setup-{}", std::process::id()));
+        let path = dir.join("commander.env");
+        config.write(&path).unwrap();
+
+        let values: std::collections::HashMap<String, String> = dotenvy::from_path_iter(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(values["MQTT_BROKER_HOST"], "broker.lan");
+        assert_eq!(values["MQTT_BROKER_PORT"], "8883");
+        assert_eq!(values["MQTT_PASSWORD"], r#"p"a\ss word"#);
+        assert_eq!(values["MQTT_TLS"], "true");
+        assert_eq!(values["DEFAULT_DEVICE"], "esp32-kitchen");
+        assert_eq!(values["COMMANDER_OPERATOR"], "ola");
+        assert_eq!(values["COMMANDER_COMMAND_TTL"], "3600");
+        assert_eq!(values["INFLUXDB_TOKEN"], "apiv3_abc");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flags_override_the_environment() {
+        let args = SetupArgs {
+            broker_host: Some("mqtt.example".to_string()),
+            tls: true,
+            ..Default::default()
+        };
+        let settings = broker_from_args(&args, broker_at(1883));
+        assert_eq!(settings.host, "mqtt.example");
+        assert_eq!(settings.port, 1883);
+        assert!(settings.tls);
+    }
+}
@@ -0,0 +1,481 @@
+//! Markdown transcript of a commander session, meant for pasting into issue
+//! reports: what was typed, the exact command JSON published for it, and
+//! every device message, with answers tied to the command they answer.
+//!
+//! Events are appended as they happen. If the file stops accepting writes
+//! the session carries on; the transcript just ends there.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset};
+use shared_types::{CommandMessage, DeviceMessage, DevicePayload};
+
+use crate::render::{Renderer, TextRenderer, UnitSystem};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %:z";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Started {
+        at: DateTime<FixedOffset>,
+        device: String,
+    },
+    /// A line entered at the prompt
+    Typed {
+        at: DateTime<FixedOffset>,
+        line: String,
+    },
+    Published {
+        at: DateTime<FixedOffset>,
+        device: String,
+        topic: String,
+        /// With the id answers carry back and the operator it names
+        command: CommandMessage,
+    },
+    Received {
+        at: DateTime<FixedOffset>,
+        message: DeviceMessage,
+        /// Retained on the broker from before the session
+        retained: bool,
+    },
+    Stopped {
+        at: DateTime<FixedOffset>,
+    },
+}
+
+/// The command name a payload answers, if it answers one at all.
+/// Measurements and errors are published every wake and answer nothing.
+fn answered_command(payload: &DevicePayload) -> Option<&str> {
+    match payload {
+        DevicePayload::CommandAck { cmd, .. }
+        | DevicePayload::PersistRateLimited { command: cmd, .. } => Some(cmd),
+        DevicePayload::FrcStart { .. }
+        | DevicePayload::FrcWarmupComplete { .. }
+        | DevicePayload::FrcCalibrating { .. }
+        | DevicePayload::FrcSuccess { .. }
+        | DevicePayload::FrcError { .. } => Some("start_frc"),
+        DevicePayload::SetOffsetSuccess { .. }
+        | DevicePayload::SetOffsetRateLimited { .. }
+        | DevicePayload::SetOffsetError { .. } => Some("set_temp_offset"),
+        DevicePayload::GetOffsetSuccess { .. } | DevicePayload::GetOffsetError { .. } => {
+            Some("get_temp_offset")
+        }
+        DevicePayload::SetDeepSleepTimeSuccess { .. }
+        | DevicePayload::SetDeepSleepTimeError { .. } => Some("set_deep_sleep_time"),
+        DevicePayload::GetDeepSleepTimeSuccess { .. }
+        | DevicePayload::GetDeepSleepTimeError { .. } => Some("get_deep_sleep_time"),
+        DevicePayload::SetMqttPolicySuccess { .. } | DevicePayload::SetMqttPolicyError { .. } => {
+            Some("set_mqtt_policy")
+        }
+        DevicePayload::OtaProgress { .. }
+        | DevicePayload::OtaSuccess { .. }
+        | DevicePayload::OtaError { .. } => Some("ota"),
+        DevicePayload::Config(_) => Some("get_config"),
+        DevicePayload::SetLogLevelSuccess { .. } | DevicePayload::SetLogLevelError { .. } => {
+            Some("set_log_level")
+        }
+        DevicePayload::GetLogLevelSuccess { .. } => Some("get_log_level"),
+        DevicePayload::SetAdaptiveModeSuccess { .. }
+        | DevicePayload::SetAdaptiveModeError { .. } => Some("set_adaptive_mode"),
+        DevicePayload::AscSetSuccess { .. } => Some("set_asc"),
+        DevicePayload::AscGetSuccess { .. } => Some("get_asc"),
+        DevicePayload::AltitudeSetSuccess { .. } => Some("set_altitude"),
+        DevicePayload::AltitudeGetSuccess { .. } => Some("get_altitude"),
+        DevicePayload::AmbientPressureSetSuccess { .. }
+        | DevicePayload::AmbientPressureError { .. } => Some("set_ambient_pressure"),
+        DevicePayload::ConfirmConfigError { .. } => Some("confirm_config"),
+        DevicePayload::SelfTestResult { .. } => Some("self_test"),
+        DevicePayload::FactoryResetSuccess | DevicePayload::FactoryResetError { .. } => {
+            Some("factory_reset")
+        }
+        DevicePayload::SerialNumber { .. } => Some("get_serial_number"),
+        DevicePayload::Rebooting { .. } => Some("reboot"),
+        DevicePayload::FirmwareInfo { .. } => Some("get_firmware_info"),
+        DevicePayload::FaultArmed { .. } => Some("inject_fault"),
+        DevicePayload::CommandsDeferred { .. } => Some("batch"),
+        DevicePayload::MeasurementSuccess { .. }
+        | DevicePayload::MeasurementBatch { .. }
+        | DevicePayload::Error { .. }
+        | DevicePayload::Alive { .. }
+        | DevicePayload::BusRecovery { .. }
+        | DevicePayload::WakeProfile { .. }
+        | DevicePayload::LogLines { .. }
+        | DevicePayload::NextWake { .. }
+        | DevicePayload::Diagnostics { .. }
+        | DevicePayload::RadioSkipped { .. }
+        | DevicePayload::EnteringSleep { .. }
+        // Could be either ASC command's
+        | DevicePayload::AscError { .. }
+        // Same for altitude
+        | DevicePayload::AltitudeError { .. }
+        // Could be either command that goes on trial; the id tells
+        | DevicePayload::PendingConfirmation { .. }
+        | DevicePayload::ConfigRolledBack { .. } => None,
+    }
+}
+
+fn command_names(command: &CommandMessage) -> Vec<&'static str> {
+    match command {
+        CommandMessage::Single(envelope) => vec![envelope.command.name()],
+        CommandMessage::Batch(envelope) => std::iter::once("batch")
+            .chain(envelope.command.commands.iter().map(|c| c.name()))
+            .collect(),
+    }
+}
+
+struct Sent {
+    number: usize,
+    id: Option<u32>,
+    device: String,
+    names: Vec<&'static str>,
+    typed: Option<String>,
+}
+
+/// Turns session events into markdown, remembering what was sent so later
+/// answers can refer back to it.
+pub struct TranscriptFormatter {
+    sent: Vec<Sent>,
+    last_typed: Option<String>,
+    renderer: TextRenderer,
+}
+
+impl Default for TranscriptFormatter {
+    fn default() -> Self {
+        Self {
+            sent: Vec::new(),
+            last_typed: None,
+            // Metric and uncolored so transcripts read the same for everyone
+            renderer: TextRenderer {
+                units: UnitSystem::Metric,
+                color: false,
+                absolute_after: crate::age::DEFAULT_ABSOLUTE_AFTER,
+            },
+        }
+    }
+}
+
+impl TranscriptFormatter {
+    /// Answers carrying a command id go to the command sent with it.
+    /// Others go to the latest command of that kind sent to the device;
+    /// an FRC or OTA answers several times, so earlier answers don't
+    /// consume it.
+    fn answering(&self, message: &DeviceMessage) -> Option<&Sent> {
+        if let Some(id) = message.in_reply_to {
+            return self
+                .sent
+                .iter()
+                .rev()
+                .find(|sent| sent.device == message.device && sent.id == Some(id));
+        }
+        let name = answered_command(&message.payload)?;
+        self.sent
+            .iter()
+            .rev()
+            .find(|sent| sent.device == message.device && sent.names.contains(&name))
+    }
+
+    pub fn format(&mut self, event: &SessionEvent) -> String {
+        match event {
+            SessionEvent::Started { at, device } => format!(
+                "# Commander session\n\nStarted {}, targeting `{}`\n",
+                at.format(TIME_FORMAT),
+                device
+            ),
+            SessionEvent::Typed { at, line } => {
+                self.last_typed = Some(line.clone());
+                format!("\n## `{}`\n\n_{}_\n", line, at.format(TIME_FORMAT))
+            }
+            SessionEvent::Published {
+                at,
+                device,
+                topic,
+                command,
+            } => {
+                let number = self.sent.len() + 1;
+                self.sent.push(Sent {
+                    number,
+                    id: command.id(),
+                    device: device.clone(),
+                    names: command_names(command),
+                    typed: self.last_typed.clone(),
+                });
+                let json = command
+                    .to_json()
+                    .unwrap_or_else(|e| format!("<unserializable: {}>", e));
+                format!(
+                    "\n**#{}** published for `{}` on `{}` at {}\n\n```json\n{}\n```\n",
+                    number,
+                    device,
+                    topic,
+                    at.format(TIME_FORMAT),
+                    json
+                )
+            }
+            SessionEvent::Received {
+                at,
+                message,
+                retained,
+            } => {
+                let source = match (retained, self.answering(message)) {
+                    (true, _) => "retained from before the session".to_string(),
+                    (false, Some(sent)) => match &sent.typed {
+                        Some(typed) => format!("answering #{} (`{}`)", sent.number, typed),
+                        None => format!("answering #{}", sent.number),
+                    },
+                    (false, None) => {
+                        match (message.in_reply_to, answered_command(&message.payload)) {
+                            (Some(id), _) => {
+                                format!("answering command {} not sent in this session", id)
+                            }
+                            (None, Some(name)) => {
+                                format!("answering a `{}` not sent in this session", name)
+                            }
+                            (None, None) => "unsolicited".to_string(),
+                        }
+                    }
+                };
+                format!(
+                    "\n**`{}`** at {}, {}\n\n```text\n{}\n```\n",
+                    message.device,
+                    at.format(TIME_FORMAT),
+                    source,
+                    self.renderer.render(message, *at)
+                )
+            }
+            SessionEvent::Stopped { at } => {
+                format!("\n---\n\nStopped {}\n", at.format(TIME_FORMAT))
+            }
+        }
+    }
+}
+
+/// An open transcript. Write failures are reported once by `record`, after
+/// which the transcript stops writing.
+pub struct Transcript {
+    path: PathBuf,
+    out: Option<Box<dyn Write + Send>>,
+    formatter: TranscriptFormatter,
+}
+
+impl Transcript {
+    /// Appends to `path`, creating it if needed.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::from_writer(path.to_path_buf(), Box::new(file)))
+    }
+
+    pub fn from_writer(path: PathBuf, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            path,
+            out: Some(out),
+            formatter: TranscriptFormatter::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the event. Returns the error only for the write that failed;
+    /// later events are dropped silently.
+    pub fn record(&mut self, event: &SessionEvent) -> Option<std::io::Error> {
+        let text = self.formatter.format(event);
+        let out = self.out.as_mut()?;
+        match out.write_all(text.as_bytes()).and_then(|()| out.flush()) {
+            Ok(()) => None,
+            Err(e) => {
+                self.out = None;
+                Some(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::{CommandEnvelope, DeviceCommand, DeviceCommandBatch};
+    use std::sync::{Arc, Mutex};
+
+    fn at(minute: u32, second: u32) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2025-01-15T10:{:02}:{:02}+01:00", minute, second))
+            .unwrap()
+    }
+
+    fn session() -> Vec<SessionEvent> {
+        let device = "esp32-scd40";
+        let message = |payload| DeviceMessage::new(device, payload);
+        vec![
+            SessionEvent::Started {
+                at: at(0, 0),
+                device: device.to_string(),
+            },
+            SessionEvent::Received {
+                at: at(0, 1),
+                message: message(DevicePayload::GetDeepSleepTimeSuccess { seconds: 240 }),
+                retained: true,
+            },
+            SessionEvent::Typed {
+                at: at(0, 10),
+                line: "frc 450".to_string(),
+            },
+            SessionEvent::Published {
+                at: at(0, 10),
+                device: device.to_string(),
+                topic: "sensors/commands".to_string(),
+                command: CommandEnvelope::from(DeviceCommand::StartFrc { target_ppm: 450 }).into(),
+            },
+            SessionEvent::Typed {
+                at: at(0, 20),
+                line: "set-offset 1.5".to_string(),
+            },
+            SessionEvent::Published {
+                at: at(0, 20),
+                device: device.to_string(),
+                topic: "sensors/commands".to_string(),
+                command: CommandEnvelope::from(DeviceCommand::SetTempOffset {
+                    offset: 1.5,
+                    persist: true,
+                })
+                .into(),
+            },
+            SessionEvent::Received {
+                at: at(4, 0),
+                message: message(DevicePayload::measurement(612, 21.5, 45.0)),
+                retained: false,
+            },
+            SessionEvent::Received {
+                at: at(4, 2),
+                message: message(DevicePayload::FrcStart { target_ppm: 450 }),
+                retained: false,
+            },
+            SessionEvent::Received {
+                at: at(7, 5),
+                message: message(DevicePayload::FrcSuccess { correction: 32768 }),
+                retained: false,
+            },
+            SessionEvent::Received {
+                at: at(7, 6),
+                message: message(DevicePayload::SetOffsetSuccess {
+                    offset: 1.5,
+                    persisted: true,
+                }),
+                retained: false,
+            },
+            SessionEvent::Received {
+                at: at(7, 7),
+                message: message(DevicePayload::GetOffsetSuccess { offset: 1.5 }),
+                retained: false,
+            },
+            SessionEvent::Stopped { at: at(8, 0) },
+        ]
+    }
+
+    /// Compared against `snapshots/transcript.md`; regenerate with
+    /// `UPDATE_SNAPSHOTS=1 cargo test -p rpi-commander transcript`.
+    #[test]
+    fn session_transcript_matches_snapshot() {
+        let mut formatter = TranscriptFormatter::default();
+        let rendered: String = session().iter().map(|e| formatter.format(e)).collect();
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots/transcript.md");
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, &rendered).unwrap();
+        }
+        assert_eq!(
+            rendered,
+            std::fs::read_to_string(&path).unwrap(),
+            "regenerate with UPDATE_SNAPSHOTS=1 cargo test -p rpi-commander transcript"
+        );
+    }
+
+    #[test]
+    fn batch_members_are_answered_by_the_batch() {
+        let mut formatter = TranscriptFormatter::default();
+        formatter.format(&SessionEvent::Published {
+            at: at(0, 0),
+            device: "kitchen".to_string(),
+            topic: "sensors/kitchen/commands".to_string(),
+            command: CommandEnvelope::from(
+                DeviceCommandBatch::new([DeviceCommand::GetTempOffset, DeviceCommand::NoOp])
+                    .unwrap(),
+            )
+            .into(),
+        });
+        let answer = |device: &str| {
+            DeviceMessage::new(device, DevicePayload::GetOffsetSuccess { offset: 0.0 })
+        };
+        assert_eq!(formatter.answering(&answer("kitchen")).unwrap().number, 1);
+        // Another device's answer isn't attributed to it
+        assert!(formatter.answering(&answer("bedroom")).is_none());
+    }
+
+    #[test]
+    fn answers_with_an_id_go_to_that_command() {
+        let mut formatter = TranscriptFormatter::default();
+        for id in [7, 8] {
+            formatter.format(&SessionEvent::Published {
+                at: at(0, 0),
+                device: "kitchen".to_string(),
+                topic: "sensors/kitchen/commands".to_string(),
+                command: DeviceCommand::GetTempOffset.with_id(id).into(),
+            });
+        }
+        let answer = DeviceMessage::new("kitchen", DevicePayload::GetOffsetSuccess { offset: 0.0 });
+        assert_eq!(formatter.answering(&answer).unwrap().number, 2);
+        assert_eq!(
+            formatter
+                .answering(&answer.clone().replying_to(7))
+                .unwrap()
+                .number,
+            1
+        );
+        // Answering a command from another session
+        assert!(formatter.answering(&answer.replying_to(3)).is_none());
+    }
+
+    /// Accepts `budget` bytes, then fails like a full disk.
+    struct FailingWriter {
+        written: Arc<Mutex<Vec<u8>>>,
+        budget: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut written = self.written.lock().unwrap();
+            if written.len() + buf.len() > self.budget {
+                return Err(std::io::Error::other("No space left on device"));
+            }
+            written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_failure_is_reported_once() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut transcript = Transcript::from_writer(
+            PathBuf::from("session.md"),
+            Box::new(FailingWriter {
+                written: written.clone(),
+                budget: 100,
+            }),
+        );
+        let errors: Vec<_> = session()
+            .iter()
+            .filter_map(|e| transcript.record(e))
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            String::from_utf8(written.lock().unwrap().clone())
+                .unwrap()
+                .starts_with("# Commander session")
+        );
+    }
+}
@@ -0,0 +1,1315 @@
+//! Alerts from the live receiver, with severities, quiet hours and
+//! escalation.
+//!
+//! Rules are the live anomaly flags (`co2_spike`, `humidity_spike`,
+//! `temperature_spike`, `possible_sunlight`), `device_error` and
+//! `config_drift`. Each rule has a severity and each severity its own
+//! destinations, configured in the environment:
+//!
+//! - `ALERT_RULES`: e.g. `co2_spike=critical,possible_sunlight=info`.
+//!   Unlisted rules keep their default; `off` silences one.
+//! - `ALERT_INFO`, `ALERT_WARNING`, `ALERT_CRITICAL`: comma separated
+//!   destinations, `ntfy:<url>`, `ntfy:<priority>:<url>` or `webhook:<url>`
+//! - `ALERT_ESCALATION`: destinations for unacknowledged critical alerts,
+//!   after `ALERT_ESCALATE_AFTER_MINUTES` (default 15)
+//! - `ALERT_QUIET_HOURS`: e.g. `22-7`, in `ALERT_UTC_OFFSET_HOURS` local time
+//! - `ALERT_REPEAT_MINUTES`: how long an unacknowledged alert silences the
+//!   same rule on the same device (default 60)
+//! - `ALERT_CATCH_UP_MINUTES`: events this much older than the processor's
+//!   start go into one catch-up digest instead of alerts (default 30)
+//!
+//! Non-critical alerts raised during quiet hours are held and go out as one
+//! batch per severity once quiet hours end. A critical alert that isn't
+//! acknowledged with `POST /api/alerts/{id}/ack` in time is sent once more,
+//! to the escalation destinations. After downtime, the events that queued up
+//! while the processor was away are told apart by their device timestamps;
+//! they are counted into a single "while I was away" digest, sent when the
+//! first live event comes in. Alerts are kept in a state file
+//! (`ALERT_STATE_FILE`, default `alerts.json`, see `state_file`) shared by
+//! the receiver and the web server, like the maintenance windows.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use shared_types::DeviceMessage;
+use shared_types::quiet_hours::QuietHours;
+use shared_types::versioned::Migrations;
+
+use crate::anomalies::{AnomalyDetector, AnomalyFlags};
+use crate::latency::SKEW_TOLERANCE;
+use crate::options::parse_kv_options;
+use crate::state_file;
+use crate::types::MeasurementWithTime;
+
+pub const DEFAULT_STATE_FILE: &str = "alerts.json";
+/// Version 1: JSON of `AlertBook`
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+pub const DEFAULT_ESCALATE_AFTER: Duration = Duration::minutes(15);
+pub const DEFAULT_REPEAT_AFTER: Duration = Duration::minutes(60);
+pub const DEFAULT_CATCH_UP_AFTER: Duration = Duration::minutes(30);
+
+/// Alerts no longer waiting for anything are dropped after this long
+pub const RETENTION: Duration = Duration::days(7);
+
+/// How often held and escalated deliveries are checked
+const TICK: std::time::Duration = std::time::Duration::from_secs(60);
+
+const NTFY_PRIORITIES: [&str; 5] = ["min", "low", "default", "high", "urgent"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 3] = [Severity::Info, Severity::Warning, Severity::Critical];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Severity::ALL
+            .into_iter()
+            .find(|severity| severity.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown severity '{}', expected info, warning or critical",
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// ntfy topic URL with the message priority, `min` to `urgent`
+    Ntfy { url: String, priority: String },
+    /// Receives `{"ids", "severity", "escalation", "title", "body"}` as JSON
+    Webhook { url: String },
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("ntfy:") {
+            return Ok(match rest.split_once(':') {
+                Some((priority, url)) if NTFY_PRIORITIES.contains(&priority) => Destination::Ntfy {
+                    url: url.to_string(),
+                    priority: priority.to_string(),
+                },
+                _ => Destination::Ntfy {
+                    url: rest.to_string(),
+                    priority: "default".to_string(),
+                },
+            });
+        }
+        if let Some(url) = s.strip_prefix("webhook:") {
+            return Ok(Destination::Webhook {
+                url: url.to_string(),
+            });
+        }
+        Err(format!(
+            "invalid destination '{}', expected ntfy:[priority:]<url> or webhook:<url>",
+            s
+        ))
+    }
+}
+
+impl Destination {
+    pub fn url(&self) -> &str {
+        match self {
+            Destination::Ntfy { url, .. } | Destination::Webhook { url } => url,
+        }
+    }
+}
+
+fn parse_destinations(s: &str) -> Result<Vec<Destination>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertPolicy {
+    /// Severity per rule; rules without one aren't alerted
+    pub rules: BTreeMap<String, Severity>,
+    pub destinations: BTreeMap<Severity, Vec<Destination>>,
+    pub escalation: Vec<Destination>,
+    pub escalate_after: Duration,
+    pub repeat_after: Duration,
+    /// Events older than this at startup go into the catch-up digest
+    pub catch_up_after: Duration,
+    pub quiet_hours: Option<QuietHours>,
+    pub utc_offset_hours: i64,
+}
+
+impl Default for AlertPolicy {
+    fn default() -> Self {
+        Self {
+            rules: [
+                ("co2_spike", Severity::Warning),
+                ("humidity_spike", Severity::Info),
+                ("temperature_spike", Severity::Info),
+                ("device_error", Severity::Warning),
+                ("config_drift", Severity::Warning),
+            ]
+            .into_iter()
+            .map(|(rule, severity)| (rule.to_string(), severity))
+            .collect(),
+            destinations: BTreeMap::new(),
+            escalation: Vec::new(),
+            escalate_after: DEFAULT_ESCALATE_AFTER,
+            repeat_after: DEFAULT_REPEAT_AFTER,
+            catch_up_after: DEFAULT_CATCH_UP_AFTER,
+            quiet_hours: None,
+            utc_offset_hours: 0,
+        }
+    }
+}
+
+impl AlertPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        let minutes = |key: &str, default: Duration| match var(key) {
+            Some(value) => value
+                .parse()
+                .map(Duration::minutes)
+                .map_err(|_| format!("{} must be a number of minutes", key)),
+            None => Ok(default),
+        };
+
+        let mut policy = Self::default();
+        if let Some(rules) = var("ALERT_RULES") {
+            policy.set_rules(&rules)?;
+        }
+        for severity in Severity::ALL {
+            let key = format!("ALERT_{}", severity.as_str().to_uppercase());
+            if let Some(destinations) = var(&key) {
+                policy
+                    .destinations
+                    .insert(severity, parse_destinations(&destinations)?);
+            }
+        }
+        if let Some(escalation) = var("ALERT_ESCALATION") {
+            policy.escalation = parse_destinations(&escalation)?;
+        }
+        policy.escalate_after = minutes("ALERT_ESCALATE_AFTER_MINUTES", DEFAULT_ESCALATE_AFTER)?;
+        policy.repeat_after = minutes("ALERT_REPEAT_MINUTES", DEFAULT_REPEAT_AFTER)?;
+        policy.catch_up_after = minutes("ALERT_CATCH_UP_MINUTES", DEFAULT_CATCH_UP_AFTER)?;
+        if let Some(quiet_hours) = var("ALERT_QUIET_HOURS") {
+            policy.quiet_hours = Some(
+                quiet_hours
+                    .parse()
+                    .map_err(|e| format!("ALERT_QUIET_HOURS: {}", e))?,
+            );
+        }
+        if let Some(offset) = var("ALERT_UTC_OFFSET_HOURS") {
+            policy.utc_offset_hours = offset
+                .parse()
+                .map_err(|_| "ALERT_UTC_OFFSET_HOURS must be a whole number".to_string())?;
+        }
+        Ok(policy)
+    }
+
+    /// Applies `rule=severity` pairs over the defaults.
+    pub fn set_rules(&mut self, s: &str) -> Result<(), String> {
+        for (rule, severity) in parse_kv_options(s)? {
+            let rule = rule.to_string();
+            match severity {
+                "off" => {
+                    self.rules.remove(&rule);
+                }
+                severity => {
+                    self.rules.insert(rule, severity.parse()?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Without any destination nothing could be delivered.
+    pub fn is_enabled(&self) -> bool {
+        self.destinations.values().any(|d| !d.is_empty())
+    }
+
+    pub fn severity(&self, rule: &str) -> Option<Severity> {
+        self.rules.get(rule).copied()
+    }
+
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        self.quiet_hours.is_some_and(|quiet| {
+            let local = now + Duration::hours(self.utc_offset_hours);
+            quiet.contains(local.hour() as u8)
+        })
+    }
+
+    /// Every destination with the variable it came from, e.g. `ALERT_CRITICAL`
+    pub fn named_destinations(&self) -> Vec<(String, &Destination)> {
+        let by_severity = self
+            .destinations
+            .iter()
+            .flat_map(|(severity, destinations)| {
+                let source = format!("ALERT_{}", severity.as_str().to_uppercase());
+                destinations.iter().map(move |d| (source.clone(), d))
+            });
+        let escalation = self
+            .escalation
+            .iter()
+            .map(|d| ("ALERT_ESCALATION".to_string(), d));
+        by_severity.chain(escalation).collect()
+    }
+
+    fn destinations_for(&self, delivery: &Delivery) -> &[Destination] {
+        if delivery.escalation {
+            &self.escalation
+        } else {
+            self.destinations
+                .get(&delivery.severity)
+                .map_or(&[], Vec::as_slice)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    /// Raised during quiet hours, waiting for the morning batch
+    Held,
+    Sent,
+    /// Critical and unacknowledged, sent again to the escalation destinations
+    Escalated,
+    Acknowledged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alert {
+    pub id: u64,
+    pub rule: String,
+    pub device: String,
+    pub severity: Severity,
+    pub message: String,
+    pub raised_at: DateTime<Utc>,
+    pub state: AlertState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalated_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+impl Alert {
+    fn line(&self) -> String {
+        format!(
+            "#{} {} {} on {}: {}",
+            self.id,
+            self.raised_at.format("%H:%M"),
+            self.rule,
+            self.device,
+            self.message
+        )
+    }
+}
+
+/// One message to the destinations of `severity`, or to the escalation
+/// destinations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub severity: Severity,
+    pub escalation: bool,
+    pub alert_ids: Vec<u64>,
+    pub title: String,
+    pub body: String,
+}
+
+impl Delivery {
+    fn single(alert: &Alert, escalation: bool) -> Self {
+        let title = format!(
+            "{}{} {} on {}",
+            if escalation { "Unacknowledged: " } else { "" },
+            alert.severity.as_str(),
+            alert.rule,
+            alert.device
+        );
+        Self {
+            severity: alert.severity,
+            escalation,
+            alert_ids: vec![alert.id],
+            title,
+            body: format!(
+                "{}\nAcknowledge with POST /api/alerts/{}/ack",
+                alert.line(),
+                alert.id
+            ),
+        }
+    }
+
+    fn batch(severity: Severity, alerts: &[&Alert]) -> Self {
+        Self {
+            severity,
+            escalation: false,
+            alert_ids: alerts.iter().map(|a| a.id).collect(),
+            title: format!(
+                "{} {} alert(s) from quiet hours",
+                alerts.len(),
+                severity.as_str()
+            ),
+            body: alerts
+                .iter()
+                .map(|a| a.line())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckError {
+    NotFound,
+    AlreadyAcknowledged,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::NotFound => f.write_str("no such alert"),
+            AckError::AlreadyAcknowledged => f.write_str("alert is already acknowledged"),
+        }
+    }
+}
+
+/// Every alert still kept, by id. Time always comes from the caller, so the
+/// state machine runs the same in tests as on the clock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlertBook {
+    next_id: u64,
+    alerts: BTreeMap<u64, Alert>,
+}
+
+impl AlertBook {
+    /// Newest first
+    pub fn list(&self) -> Vec<Alert> {
+        self.alerts.values().rev().cloned().collect()
+    }
+
+    /// Records an alert for `rule` on `device`. Returns what to send right
+    /// away: nothing for a held, repeated or unconfigured alert.
+    pub fn raise(
+        &mut self,
+        policy: &AlertPolicy,
+        rule: &str,
+        device: &str,
+        message: String,
+        now: DateTime<Utc>,
+    ) -> Option<Delivery> {
+        let severity = policy.severity(rule)?;
+        let repeated = self.alerts.values().any(|a| {
+            a.rule == rule
+                && a.device == device
+                && a.state != AlertState::Acknowledged
+                && now - a.raised_at < policy.repeat_after
+        });
+        if repeated {
+            return None;
+        }
+
+        self.next_id += 1;
+        let held = severity != Severity::Critical && policy.is_quiet(now);
+        let alert = Alert {
+            id: self.next_id,
+            rule: rule.to_string(),
+            device: device.to_string(),
+            severity,
+            message,
+            raised_at: now,
+            state: if held {
+                AlertState::Held
+            } else {
+                AlertState::Sent
+            },
+            sent_at: (!held).then_some(now),
+            escalated_at: None,
+            acknowledged_at: None,
+        };
+        let delivery = (!held).then(|| Delivery::single(&alert, false));
+        self.alerts.insert(alert.id, alert);
+        delivery
+    }
+
+    /// Held alerts are acknowledged too, and then never sent.
+    pub fn acknowledge(&mut self, id: u64, now: DateTime<Utc>) -> Result<&Alert, AckError> {
+        let alert = self.alerts.get_mut(&id).ok_or(AckError::NotFound)?;
+        if alert.state == AlertState::Acknowledged {
+            return Err(AckError::AlreadyAcknowledged);
+        }
+        alert.state = AlertState::Acknowledged;
+        alert.acknowledged_at = Some(now);
+        Ok(alert)
+    }
+
+    /// Releases the held alerts once quiet hours are over, one batch per
+    /// severity, and escalates overdue critical alerts.
+    pub fn due(&mut self, policy: &AlertPolicy, now: DateTime<Utc>) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+
+        if !policy.is_quiet(now) {
+            for severity in Severity::ALL {
+                let held: Vec<&Alert> = self
+                    .alerts
+                    .values()
+                    .filter(|a| a.state == AlertState::Held && a.severity == severity)
+                    .collect();
+                if !held.is_empty() {
+                    deliveries.push(Delivery::batch(severity, &held));
+                }
+            }
+            for alert in self
+                .alerts
+                .values_mut()
+                .filter(|a| a.state == AlertState::Held)
+            {
+                alert.state = AlertState::Sent;
+                alert.sent_at = Some(now);
+            }
+        }
+
+        if !policy.escalation.is_empty() {
+            for alert in self.alerts.values_mut() {
+                let overdue = alert
+                    .sent_at
+                    .is_some_and(|sent| now - sent >= policy.escalate_after);
+                if alert.severity == Severity::Critical
+                    && alert.state == AlertState::Sent
+                    && overdue
+                {
+                    alert.state = AlertState::Escalated;
+                    alert.escalated_at = Some(now);
+                    deliveries.push(Delivery::single(alert, true));
+                }
+            }
+        }
+
+        deliveries
+    }
+
+    /// Drops old alerts that aren't held or waiting for escalation.
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        self.alerts.retain(|_, a| {
+            let waiting = a.state == AlertState::Held
+                || (a.severity == Severity::Critical && a.state == AlertState::Sent);
+            waiting || now - a.raised_at < RETENTION
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertStore {
+    path: PathBuf,
+}
+
+impl AlertStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("ALERT_STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()),
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A missing file is an empty book.
+    pub fn load(&self) -> Result<AlertBook, Box<dyn Error>> {
+        Ok(state_file::load(&self.path, &LAYOUTS)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, book: &AlertBook) -> Result<(), Box<dyn Error>> {
+        state_file::save(&self.path, &LAYOUTS, book)
+    }
+
+    /// Loads, applies `f` and saves if anything changed.
+    pub fn update<R>(&self, f: impl FnOnce(&mut AlertBook) -> R) -> Result<R, Box<dyn Error>> {
+        let mut book = self.load()?;
+        let before = book.clone();
+        let result = f(&mut book);
+        if book != before {
+            self.save(&book)?;
+        }
+        Ok(result)
+    }
+}
+
+/// When the event behind a message happened: the device's timestamp, unless
+/// there is none or it's ahead of `received`.
+pub fn event_time(message: &DeviceMessage, received: DateTime<Utc>) -> DateTime<Utc> {
+    message
+        .timestamp()
+        .filter(|sent| *sent - received <= SKEW_TOLERANCE)
+        .unwrap_or(received)
+}
+
+/// An alert whose event is from before the catch-up cutoff, counted in the
+/// digest instead of sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissedAlert {
+    pub rule: String,
+    pub device: String,
+    pub severity: Severity,
+    pub at: DateTime<Utc>,
+}
+
+/// Splits events into the ones that queued up while the processor was away
+/// and live ones, by their own time: anything before `cutoff`, the start
+/// less `catch_up_after`, is missed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatchUp {
+    cutoff: DateTime<Utc>,
+    missed: Vec<MissedAlert>,
+    /// The highest CO2 of the missed measurements, with device and time
+    worst_co2: Option<(u16, String, DateTime<Utc>)>,
+}
+
+impl CatchUp {
+    pub fn new(started_at: DateTime<Utc>, catch_up_after: Duration) -> Self {
+        Self {
+            cutoff: started_at - catch_up_after,
+            missed: Vec::new(),
+            worst_co2: None,
+        }
+    }
+
+    pub fn is_missed(&self, at: DateTime<Utc>) -> bool {
+        at < self.cutoff
+    }
+
+    /// Keeps `rules` for the digest if their event is missed. Returns whether
+    /// it was; live events are raised as usual.
+    pub fn hold(
+        &mut self,
+        policy: &AlertPolicy,
+        device: &str,
+        rules: &[(&'static str, String)],
+        at: DateTime<Utc>,
+    ) -> bool {
+        if !self.is_missed(at) {
+            return false;
+        }
+        for (rule, _) in rules {
+            if let Some(severity) = policy.severity(rule) {
+                self.missed.push(MissedAlert {
+                    rule: rule.to_string(),
+                    device: device.to_string(),
+                    severity,
+                    at,
+                });
+            }
+        }
+        true
+    }
+
+    /// Tracks the worst CO2 of missed measurements, flagged or not.
+    pub fn observe(&mut self, measurement: &MeasurementWithTime) {
+        if self.is_missed(measurement.time)
+            && self
+                .worst_co2
+                .as_ref()
+                .is_none_or(|(co2, _, _)| measurement.co2 > *co2)
+        {
+            self.worst_co2 = Some((
+                measurement.co2,
+                measurement.device.clone(),
+                measurement.time,
+            ));
+        }
+    }
+
+    /// The digest of the missed alerts, once; `None` without any. It goes to
+    /// the destinations of the worst severity in it.
+    pub fn take_digest(&mut self) -> Option<Delivery> {
+        let missed = std::mem::take(&mut self.missed);
+        let worst_co2 = self.worst_co2.take();
+        let severity = missed.iter().map(|a| a.severity).max()?;
+        let first = missed.iter().map(|a| a.at).min()?;
+        let last = missed.iter().map(|a| a.at).max()?;
+
+        let mut per_device: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+        for alert in &missed {
+            *per_device
+                .entry(&alert.device)
+                .or_default()
+                .entry(&alert.rule)
+                .or_default() += 1;
+        }
+        let mut lines = vec![format!(
+            "Events from {} to {} UTC",
+            first.format("%Y-%m-%d %H:%M"),
+            last.format("%Y-%m-%d %H:%M")
+        )];
+        lines.push(
+            Severity::ALL
+                .into_iter()
+                .rev()
+                .map(|severity| {
+                    let count = missed.iter().filter(|a| a.severity == severity).count();
+                    format!("{}: {}", severity.as_str(), count)
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        for (device, rules) in &per_device {
+            lines.push(format!(
+                "{}: {} ({})",
+                device,
+                rules.values().sum::<usize>(),
+                rules
+                    .iter()
+                    .map(|(rule, count)| format!("{} {}", rule, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        if let Some((co2, device, at)) = worst_co2 {
+            lines.push(format!(
+                "Worst CO2: {} ppm on {} at {} UTC",
+                co2,
+                device,
+                at.format("%Y-%m-%d %H:%M")
+            ));
+        }
+        Some(Delivery {
+            severity,
+            escalation: false,
+            alert_ids: Vec::new(),
+            title: format!(
+                "While I was away: {} alert(s) from {} device(s)",
+                missed.len(),
+                per_device.len()
+            ),
+            body: lines.join("\n"),
+        })
+    }
+}
+
+/// Rules raised by one measurement's anomaly flags, with their messages.
+pub fn anomaly_rules(
+    flags: &AnomalyFlags,
+    measurement: &MeasurementWithTime,
+) -> Vec<(&'static str, String)> {
+    let mut rules = Vec::new();
+    if flags.co2_spike {
+        rules.push(("co2_spike", format!("CO2 at {} ppm", measurement.co2)));
+    }
+    if flags.humidity_spike {
+        rules.push((
+            "humidity_spike",
+            format!("humidity at {:.1}%", measurement.humidity),
+        ));
+    }
+    if flags.temperature_spike {
+        rules.push((
+            "temperature_spike",
+            format!("temperature at {:.1}°C", measurement.temperature),
+        ));
+    }
+    if flags.possible_sunlight {
+        rules.push((
+            "possible_sunlight",
+            format!(
+                "temperature at {:.1}°C, possibly sunlight",
+                measurement.temperature
+            ),
+        ));
+    }
+    rules
+}
+
+async fn send(
+    reqwest_client: &reqwest::Client,
+    destination: &Destination,
+    delivery: &Delivery,
+) -> Result<(), Box<dyn Error>> {
+    match destination {
+        Destination::Ntfy { url, priority } => {
+            reqwest_client
+                .post(url)
+                .header("Title", &delivery.title)
+                .header("Priority", priority)
+                .body(delivery.body.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Destination::Webhook { url } => {
+            reqwest_client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&json!({
+                    "ids": delivery.alert_ids,
+                    "severity": delivery.severity,
+                    "escalation": delivery.escalation,
+                    "title": delivery.title,
+                    "body": delivery.body,
+                }))?)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends to every destination; a failing one doesn't stop the others.
+pub async fn deliver(reqwest_client: &reqwest::Client, policy: &AlertPolicy, delivery: &Delivery) {
+    for destination in policy.destinations_for(delivery) {
+        match send(reqwest_client, destination, delivery).await {
+            Ok(()) => log::info!("Alert sent: {}", delivery.title),
+            Err(e) => log::error!("Failed to send alert '{}': {}", delivery.title, e),
+        }
+    }
+}
+
+/// Sends held batches and escalations as they fall due.
+pub async fn run_scheduler(
+    store: AlertStore,
+    policy: AlertPolicy,
+    reqwest_client: reqwest::Client,
+) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let deliveries = match store.update(|book| {
+            book.prune(now);
+            book.due(&policy, now)
+        }) {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                log::error!("Failed to update alerts {}: {}", store.path().display(), e);
+                continue;
+            }
+        };
+        for delivery in &deliveries {
+            deliver(&reqwest_client, &policy, delivery).await;
+        }
+    }
+}
+
+/// The receiver's side: runs a detector per device over live measurements
+/// and raises alerts for its flags and for device errors. Times passed in
+/// are when the event happened, see `event_time`.
+pub struct Alerter {
+    pub store: AlertStore,
+    pub policy: AlertPolicy,
+    detectors: HashMap<String, AnomalyDetector>,
+    catch_up: CatchUp,
+}
+
+impl Alerter {
+    pub fn new(store: AlertStore, policy: AlertPolicy, started_at: DateTime<Utc>) -> Self {
+        let catch_up = CatchUp::new(started_at, policy.catch_up_after);
+        Self {
+            store,
+            policy,
+            detectors: HashMap::new(),
+            catch_up,
+        }
+    }
+
+    pub async fn measurement(
+        &mut self,
+        reqwest_client: &reqwest::Client,
+        measurement: &MeasurementWithTime,
+    ) {
+        let flags = self
+            .detectors
+            .entry(measurement.device.clone())
+            .or_default()
+            .analyze(measurement, false);
+        self.catch_up.observe(measurement);
+        let rules = anomaly_rules(&flags, measurement);
+        self.raise(reqwest_client, &measurement.device, rules, measurement.time)
+            .await;
+    }
+
+    /// Replaces the device's detector with one that has seen `history`,
+    /// without raising anything for it.
+    pub fn warm_up(&mut self, device: &str, history: &[MeasurementWithTime]) {
+        let mut detector = AnomalyDetector::default();
+        for measurement in history {
+            detector.analyze(measurement, false);
+        }
+        self.detectors.insert(device.to_string(), detector);
+    }
+
+    pub async fn device_error(
+        &mut self,
+        reqwest_client: &reqwest::Client,
+        device: &str,
+        detail: &str,
+        now: DateTime<Utc>,
+    ) {
+        self.raise(
+            reqwest_client,
+            device,
+            vec![("device_error", detail.to_string())],
+            now,
+        )
+        .await;
+    }
+
+    /// A configuration change no command asked for, see `config_drift`
+    pub async fn config_drift(
+        &mut self,
+        reqwest_client: &reqwest::Client,
+        device: &str,
+        detail: &str,
+        now: DateTime<Utc>,
+    ) {
+        self.raise(
+            reqwest_client,
+            device,
+            vec![("config_drift", detail.to_string())],
+            now,
+        )
+        .await;
+    }
+
+    /// Missed events only go into the catch-up digest, which goes out
+    /// ahead of the first live event.
+    async fn raise(
+        &mut self,
+        reqwest_client: &reqwest::Client,
+        device: &str,
+        rules: Vec<(&'static str, String)>,
+        now: DateTime<Utc>,
+    ) {
+        if self.catch_up.hold(&self.policy, device, &rules, now) {
+            return;
+        }
+        if let Some(digest) = self.catch_up.take_digest() {
+            deliver(reqwest_client, &self.policy, &digest).await;
+        }
+        if rules.is_empty() {
+            return;
+        }
+        let deliveries = match self.store.update(|book| {
+            rules
+                .into_iter()
+                .filter_map(|(rule, message)| book.raise(&self.policy, rule, device, message, now))
+                .collect::<Vec<_>>()
+        }) {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                log::error!(
+                    "Failed to record alert in {}: {}",
+                    self.store.path().display(),
+                    e
+                );
+                return;
+            }
+        };
+        for delivery in &deliveries {
+            deliver(reqwest_client, &self.policy, delivery).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-01-15 20:00 UTC plus `minutes`
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T20:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::minutes(minutes)
+    }
+
+    fn policy() -> AlertPolicy {
+        let mut policy = AlertPolicy {
+            quiet_hours: Some("22-7".parse().unwrap()),
+            utc_offset_hours: 1,
+            escalation: vec!["webhook:http://pager.local/hook".parse().unwrap()],
+            ..Default::default()
+        };
+        policy.set_rules("co2_spike=critical").unwrap();
+        policy
+    }
+
+    fn raise(book: &mut AlertBook, rule: &str, now: DateTime<Utc>) -> Option<Delivery> {
+        book.raise(&policy(), rule, "esp32-scd40", "test".to_string(), now)
+    }
+
+    #[test]
+    fn outside_quiet_hours_alerts_go_out_right_away() {
+        let mut book = AlertBook::default();
+        // 20:00 UTC is 21:00 local, before quiet hours
+        let delivery = raise(&mut book, "humidity_spike", at(0)).unwrap();
+        assert_eq!(delivery.severity, Severity::Info);
+        assert_eq!(delivery.alert_ids, vec![1]);
+        assert!(!delivery.escalation);
+        assert_eq!(book.alerts[&1].state, AlertState::Sent);
+        assert_eq!(book.alerts[&1].sent_at, Some(at(0)));
+
+        assert!(raise(&mut book, "not_a_rule", at(0)).is_none());
+        assert!(!book.alerts.contains_key(&2));
+    }
+
+    #[test]
+    fn quiet_hours_hold_non_critical_alerts_for_a_morning_batch() {
+        let mut book = AlertBook::default();
+        // 03:00 local
+        let night = at(6 * 60);
+        assert!(raise(&mut book, "humidity_spike", night).is_none());
+        assert!(raise(&mut book, "temperature_spike", night + Duration::minutes(5)).is_none());
+        assert!(raise(&mut book, "device_error", night + Duration::minutes(10)).is_none());
+        assert_eq!(book.alerts[&1].state, AlertState::Held);
+
+        // Critical ones aren't held
+        let critical = raise(&mut book, "co2_spike", night).unwrap();
+        assert_eq!(critical.severity, Severity::Critical);
+
+        // Still quiet at 06:59 local, only the critical alert escalates
+        assert!(
+            book.due(&policy(), at(9 * 60 + 59))
+                .iter()
+                .all(|d| d.escalation)
+        );
+
+        let morning = at(10 * 60);
+        let deliveries: Vec<_> = book
+            .due(&policy(), morning)
+            .into_iter()
+            .filter(|d| !d.escalation)
+            .collect();
+        assert_eq!(
+            deliveries
+                .iter()
+                .map(|d| (d.severity, d.alert_ids.clone()))
+                .collect::<Vec<_>>(),
+            vec![(Severity::Info, vec![1, 2]), (Severity::Warning, vec![3])]
+        );
+        assert!(deliveries[0].title.starts_with("2 info alert(s)"));
+        assert_eq!(book.alerts[&2].state, AlertState::Sent);
+        assert_eq!(book.alerts[&2].sent_at, Some(morning));
+
+        // Released once only
+        assert!(
+            book.due(&policy(), morning + Duration::minutes(1))
+                .iter()
+                .all(|d| d.escalation)
+        );
+    }
+
+    #[test]
+    fn unacknowledged_critical_alerts_escalate_once() {
+        let mut book = AlertBook::default();
+        raise(&mut book, "co2_spike", at(0)).unwrap();
+
+        assert!(book.due(&policy(), at(14)).is_empty());
+        let deliveries = book.due(&policy(), at(15));
+        assert_eq!(deliveries.len(), 1);
+        assert!(deliveries[0].escalation);
+        assert!(deliveries[0].title.starts_with("Unacknowledged: critical"));
+        assert_eq!(
+            policy().destinations_for(&deliveries[0]),
+            policy().escalation.as_slice()
+        );
+        assert_eq!(book.alerts[&1].state, AlertState::Escalated);
+        assert_eq!(book.alerts[&1].escalated_at, Some(at(15)));
+
+        assert!(book.due(&policy(), at(60)).is_empty());
+        // Acknowledging after the escalation still closes it
+        assert!(book.acknowledge(1, at(61)).is_ok());
+    }
+
+    #[test]
+    fn acknowledged_critical_alerts_dont_escalate() {
+        let mut book = AlertBook::default();
+        raise(&mut book, "co2_spike", at(0)).unwrap();
+        let alert = book.acknowledge(1, at(5)).unwrap();
+        assert_eq!(alert.state, AlertState::Acknowledged);
+        assert_eq!(alert.acknowledged_at, Some(at(5)));
+
+        assert!(book.due(&policy(), at(30)).is_empty());
+        assert_eq!(
+            book.acknowledge(1, at(31)).unwrap_err(),
+            AckError::AlreadyAcknowledged
+        );
+        assert_eq!(book.acknowledge(7, at(31)).unwrap_err(), AckError::NotFound);
+    }
+
+    #[test]
+    fn acknowledged_held_alerts_are_never_sent() {
+        let mut book = AlertBook::default();
+        assert!(raise(&mut book, "humidity_spike", at(6 * 60)).is_none());
+        book.acknowledge(1, at(6 * 60 + 1)).unwrap();
+        assert!(book.due(&policy(), at(10 * 60)).is_empty());
+        assert_eq!(book.alerts[&1].sent_at, None);
+    }
+
+    #[test]
+    fn no_escalation_without_escalation_destinations() {
+        let mut policy = policy();
+        policy.escalation.clear();
+        let mut book = AlertBook::default();
+        book.raise(
+            &policy,
+            "co2_spike",
+            "esp32-scd40",
+            "test".to_string(),
+            at(0),
+        )
+        .unwrap();
+        assert!(book.due(&policy, at(60)).is_empty());
+        assert_eq!(book.alerts[&1].state, AlertState::Sent);
+    }
+
+    #[test]
+    fn repeats_are_silenced_until_acknowledged_or_expired() {
+        let mut book = AlertBook::default();
+        assert!(raise(&mut book, "humidity_spike", at(-120)).is_some());
+        assert!(raise(&mut book, "humidity_spike", at(-90)).is_none());
+        // Another device or rule isn't a repeat
+        assert!(raise(&mut book, "device_error", at(-90)).is_some());
+        assert!(
+            book.raise(
+                &policy(),
+                "humidity_spike",
+                "esp32-bedroom",
+                "test".to_string(),
+                at(-90)
+            )
+            .is_some()
+        );
+        assert!(raise(&mut book, "humidity_spike", at(-60)).is_some());
+
+        book.acknowledge(4, at(-59)).unwrap();
+        assert!(raise(&mut book, "humidity_spike", at(-58)).is_some());
+    }
+
+    #[test]
+    fn prune_keeps_alerts_still_waiting() {
+        let mut book = AlertBook::default();
+        raise(&mut book, "humidity_spike", at(0)).unwrap();
+        raise(&mut book, "co2_spike", at(0)).unwrap();
+        assert!(raise(&mut book, "device_error", at(6 * 60)).is_none());
+
+        book.prune(at(0) + RETENTION + Duration::minutes(1));
+        assert!(!book.alerts.contains_key(&1));
+        // Unacknowledged critical and held alerts stay
+        assert!(book.alerts.contains_key(&2));
+        assert!(book.alerts.contains_key(&3));
+    }
+
+    #[test]
+    fn rules_and_destinations_parse() {
+        let mut policy = AlertPolicy::default();
+        policy
+            .set_rules("humidity_spike=off, possible_sunlight=info")
+            .unwrap();
+        assert_eq!(policy.severity("humidity_spike"), None);
+        assert_eq!(policy.severity("possible_sunlight"), Some(Severity::Info));
+        assert_eq!(policy.severity("co2_spike"), Some(Severity::Warning));
+        assert!(policy.set_rules("co2_spike=loud").is_err());
+        assert!(policy.set_rules("co2_spike").is_err());
+
+        assert_eq!(
+            parse_destinations("ntfy:high:https://ntfy.sh/aq, ntfy:https://ntfy.sh/aq").unwrap(),
+            vec![
+                Destination::Ntfy {
+                    url: "https://ntfy.sh/aq".to_string(),
+                    priority: "high".to_string(),
+                },
+                Destination::Ntfy {
+                    url: "https://ntfy.sh/aq".to_string(),
+                    priority: "default".to_string(),
+                },
+            ]
+        );
+        assert!(parse_destinations("mailto:me@example.com").is_err());
+        assert!(!policy.is_enabled());
+    }
+
+    fn missed(device: &str, co2: u16, minutes: i64) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 21.5,
+            humidity: 40.0,
+            time: at(minutes),
+            device: device.to_string(),
+        }
+    }
+
+    #[test]
+    fn catch_up_splits_a_backlog_at_the_cutoff() {
+        // Started at 20:00, so events before 19:30 were missed
+        let mut catch_up = CatchUp::new(at(0), Duration::minutes(30));
+        let rules = [("co2_spike", "CO2 at 2100 ppm".to_string())];
+        let held: Vec<bool> = [-3 * 24 * 60, -31, -30, -29, 0, 5]
+            .into_iter()
+            .map(|minutes| catch_up.hold(&policy(), "kitchen", &rules, at(minutes)))
+            .collect();
+        assert_eq!(held, vec![true, true, false, false, false, false]);
+        assert_eq!(catch_up.missed.len(), 2);
+
+        // Missed, but not a configured rule, so not counted either
+        assert!(catch_up.hold(
+            &policy(),
+            "kitchen",
+            &[("possible_sunlight", String::new())],
+            at(-60)
+        ));
+        assert_eq!(catch_up.missed.len(), 2);
+    }
+
+    #[test]
+    fn catch_up_digest_counts_by_severity_and_device() {
+        let policy = policy();
+        let mut catch_up = CatchUp::new(at(0), Duration::minutes(30));
+        let spike = [("co2_spike", String::new())];
+        catch_up.hold(&policy, "kitchen", &spike, at(-2 * 24 * 60));
+        catch_up.hold(&policy, "kitchen", &spike, at(-24 * 60));
+        catch_up.hold(
+            &policy,
+            "kitchen",
+            &[("device_error", String::new())],
+            at(-90),
+        );
+        catch_up.hold(
+            &policy,
+            "bedroom",
+            &[("humidity_spike", String::new())],
+            at(-45),
+        );
+        for measurement in [
+            missed("kitchen", 1800, -24 * 60),
+            missed("bedroom", 2100, -6 * 60),
+            // Live, so not part of what was missed
+            missed("bedroom", 5000, 0),
+        ] {
+            catch_up.observe(&measurement);
+        }
+
+        let digest = catch_up.take_digest().unwrap();
+        assert_eq!(digest.severity, Severity::Critical);
+        assert!(!digest.escalation);
+        assert!(digest.alert_ids.is_empty());
+        assert_eq!(
+            digest.title,
+            "While I was away: 4 alert(s) from 2 device(s)"
+        );
+        assert_eq!(
+            digest.body,
+            "Events from 2025-01-13 20:00 to 2025-01-15 19:15 UTC\n\
+             critical: 2, warning: 1, info: 1\n\
+             bedroom: 1 (humidity_spike 1)\n\
+             kitchen: 3 (co2_spike 2, device_error 1)\n\
+             Worst CO2: 2100 ppm on bedroom at 2025-01-15 14:00 UTC"
+        );
+        // Sent once
+        assert_eq!(catch_up.take_digest(), None);
+        // Missed measurements alone make no digest
+        catch_up.observe(&missed("kitchen", 3000, -60));
+        assert_eq!(catch_up.take_digest(), None);
+    }
+
+    #[tokio::test]
+    async fn missed_events_stay_out_of_the_book() {
+        let store = AlertStore::new(std::env::temp_dir().join(format!(
+            "rpi-processor-catch-up-{}.json",
+            std::process::id()
+        )));
+        let _ = std::fs::remove_file(store.path());
+        let mut alerter = Alerter::new(store.clone(), AlertPolicy::default(), at(0));
+        let client = reqwest::Client::new();
+
+        alerter
+            .device_error(&client, "kitchen", "I2C(Nack)", at(-600))
+            .await;
+        assert_eq!(store.load().unwrap(), AlertBook::default());
+        assert_eq!(alerter.catch_up.missed.len(), 1);
+
+        // The first live event sends the digest and is raised as usual
+        alerter
+            .device_error(&client, "kitchen", "I2C(Nack)", at(1))
+            .await;
+        assert!(alerter.catch_up.missed.is_empty());
+        let alerts = store.load().unwrap().list();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].raised_at, at(1));
+        let _ = std::fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn events_are_timed_by_the_device_clock() {
+        let received = at(0);
+        let message = DeviceMessage::new(
+            "kitchen",
+            shared_types::DevicePayload::measurement(612, 21.5, 40.0),
+        );
+        assert_eq!(event_time(&message, received), received);
+        let stamped = |time: DateTime<Utc>| {
+            message
+                .clone()
+                .stamped(Some(time.timestamp_millis() as u64), 1)
+        };
+        assert_eq!(event_time(&stamped(at(-3 * 60)), received), at(-3 * 60));
+        // A clock ahead of the receiver isn't trusted
+        assert_eq!(event_time(&stamped(at(5)), received), received);
+    }
+
+    #[test]
+    fn book_survives_the_store() {
+        let store = AlertStore::new(
+            std::env::temp_dir().join(format!("rpi-processor-alerts-{}.json", std::process::id())),
+        );
+        let _ = std::fs::remove_file(store.path());
+        assert_eq!(store.load().unwrap(), AlertBook::default());
+
+        let delivery = store
+            .update(|book| raise(book, "co2_spike", at(0)))
+            .unwrap();
+        assert!(delivery.is_some());
+        let acknowledged = store
+            .update(|book| book.acknowledge(1, at(1)).map(|a| a.state))
+            .unwrap();
+        assert_eq!(acknowledged, Ok(AlertState::Acknowledged));
+        assert_eq!(
+            store.load().unwrap().alerts[&1].acknowledged_at,
+            Some(at(1))
+        );
+        // Ids keep counting after a reload
+        store
+            .update(|book| raise(book, "device_error", at(2)))
+            .unwrap();
+        assert!(store.load().unwrap().alerts.contains_key(&2));
+        let _ = std::fs::remove_file(store.path());
+    }
+}
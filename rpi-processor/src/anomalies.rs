@@ -1,6 +1,7 @@
 use std::fmt::Display;
 
 use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::Serialize;
 
 use crate::types::MeasurementWithTime;
 
@@ -43,7 +44,7 @@ impl Default for AnomalyConfig {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct AnomalyFlags {
     pub temperature_spike: bool,
     pub humidity_spike: bool,
@@ -59,18 +60,20 @@ impl AnomalyFlags {
     pub fn is_any_true(&self) -> bool {
         self.temperature_spike || self.humidity_spike || self.co2_spike || self.possible_sunlight
     }
-}
 
-impl Default for AnomalyFlags {
-    fn default() -> Self {
-        Self {
-            temperature_spike: false,
-            humidity_spike: false,
-            co2_spike: false,
-            possible_sunlight: false,
-            physical_constraint_temp_violation: false,
-            physical_constraint_humidity_violation: false,
-            physical_constraint_co2_violation: false,
+    /// Every flag set in either
+    pub fn merged(&self, other: &AnomalyFlags) -> AnomalyFlags {
+        AnomalyFlags {
+            temperature_spike: self.temperature_spike || other.temperature_spike,
+            humidity_spike: self.humidity_spike || other.humidity_spike,
+            co2_spike: self.co2_spike || other.co2_spike,
+            possible_sunlight: self.possible_sunlight || other.possible_sunlight,
+            physical_constraint_temp_violation: self.physical_constraint_temp_violation
+                || other.physical_constraint_temp_violation,
+            physical_constraint_humidity_violation: self.physical_constraint_humidity_violation
+                || other.physical_constraint_humidity_violation,
+            physical_constraint_co2_violation: self.physical_constraint_co2_violation
+                || other.physical_constraint_co2_violation,
         }
     }
 }
@@ -313,23 +316,23 @@ impl AnomalyDetector {
             }
         }
 
-        if !flags.temperature_spike {
-            if let Some(baseline) = self.get_pre_sunlight_baseline(measurement.time) {
-                let temp_rise = temp - baseline;
-
-                if temp_rise >= self.config.temp_above_daily_min
-                    && temp >= self.config.temp_absolute_min_for_spike
-                    && is_daylight_hours
-                {
-                    flags.temperature_spike = true;
-                    if debug {
-                        log::debug!(
-                            "Temperature spike: {:.1}°C (+{:.1}°C from baseline {:.1}°C)",
-                            temp,
-                            temp_rise,
-                            baseline
-                        );
-                    }
+        if !flags.temperature_spike
+            && let Some(baseline) = self.get_pre_sunlight_baseline(measurement.time)
+        {
+            let temp_rise = temp - baseline;
+
+            if temp_rise >= self.config.temp_above_daily_min
+                && temp >= self.config.temp_absolute_min_for_spike
+                && is_daylight_hours
+            {
+                flags.temperature_spike = true;
+                if debug {
+                    log::debug!(
+                        "Temperature spike: {:.1}°C (+{:.1}°C from baseline {:.1}°C)",
+                        temp,
+                        temp_rise,
+                        baseline
+                    );
                 }
             }
         }
@@ -0,0 +1,483 @@
+//! Admission control for anomaly markings.
+//!
+//! Overlapping marking runs used to write the same point twice. Before a
+//! batch is written, `fetch_existing` reads the detector's markings already
+//! stored for the batch's devices and time range with one query, and
+//! `admit` drops the points stored with the same flags. A point whose flags
+//! differ is merged (every flag set in either) and written again, which
+//! overwrites the stored `status=auto` marking. Reviews are rows of their
+//! own and are left alone.
+//!
+//! `--dedupe-anomalies` cleans up what was written before: markings from
+//! before the status tag existed, next to a `status=auto` marking for the
+//! same device and time. Both are merged into the `status=auto` point and
+//! the untagged row is deleted.
+//!
+//! Markings carry the `location` tag of the measurement they were made
+//! from, see `fetch_locations`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use shared_types::line_protocol::escape_tag;
+
+use crate::anomalies::AnomalyFlags;
+use crate::anomaly_review::{AnomalyRow, parse_time};
+use crate::bulk_write::{InfluxStore, PointStore};
+use crate::fetcher::{Identifier, Sql, query_rows};
+
+/// Device and time of a marking, as written by `save_anomalies_batch`
+pub type Marking = (DateTime<Utc>, AnomalyFlags, String);
+
+type Key = (DateTime<Utc>, String);
+
+/// The room each device reported at each time it measured
+pub type Locations = HashMap<Key, String>;
+
+/// Untagged rows deleted per query
+const DELETE_BATCH: usize = 100;
+
+pub fn marking_line(
+    table: &str,
+    (time, flags, device): &Marking,
+    location: Option<&str>,
+) -> String {
+    format!(
+        "{},device={}{},status=auto temperature_spike={},humidity_spike={},co2_spike={},physical_constraint_temp_violation={},physical_constraint_humidity_violation={},physical_constraint_co2_violation={},possible_sunlight={} {}",
+        table,
+        escape_tag(device),
+        location
+            .map(|location| format!(",location={}", escape_tag(location)))
+            .unwrap_or_default(),
+        flags.temperature_spike,
+        flags.humidity_spike,
+        flags.co2_spike,
+        flags.physical_constraint_temp_violation,
+        flags.physical_constraint_humidity_violation,
+        flags.physical_constraint_co2_violation,
+        flags.possible_sunlight,
+        time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+#[derive(Deserialize)]
+struct LocationRow {
+    time: String,
+    device: String,
+    location: String,
+}
+
+fn locations(rows: Vec<LocationRow>) -> Locations {
+    rows.into_iter()
+        .filter_map(|row| Some(((parse_time(&row.time)?, row.device), row.location)))
+        .collect()
+}
+
+/// Where the measurements behind `batch` were taken, for those that carry
+/// a `location` tag. Before any device sent one the column doesn't exist,
+/// and nothing is tagged.
+pub async fn fetch_locations(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    batch: &[Marking],
+) -> Locations {
+    let Some(sql) = locations_query(batch) else {
+        return Locations::new();
+    };
+    match query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql,
+    )
+    .await
+    {
+        Ok(rows) => locations(rows),
+        Err(e) => {
+            log::warn!("Couldn't fetch device locations, marking without: {}", e);
+            Locations::new()
+        }
+    }
+}
+
+/// Completes `sql` with a range covering every point of `batch`. Devices
+/// whose names can't be queried are left out.
+fn covering(sql: Sql, batch: &[Marking]) -> Option<Sql> {
+    let devices: BTreeSet<Identifier> = batch
+        .iter()
+        .filter_map(|(_, _, d)| Identifier::parse(d).ok())
+        .collect();
+    if devices.is_empty() {
+        return None;
+    }
+    let from = batch.iter().map(|(time, _, _)| *time).min()?;
+    let to = batch.iter().map(|(time, _, _)| *time).max()?;
+    Some(
+        sql.push("time >= ")
+            .time(from)
+            .push(" AND time <= ")
+            .time(to)
+            .push(" AND device IN (")
+            .identifiers(&devices)
+            .push(")"),
+    )
+}
+
+/// One range query covering every point of `batch`. Devices whose names
+/// can't be queried are left out, so their points are all admitted.
+pub fn existence_query(table: &Identifier, batch: &[Marking]) -> Option<Sql> {
+    covering(
+        Sql::new("SELECT * FROM ").table(table).push(" WHERE "),
+        batch,
+    )
+}
+
+/// The measurement locations for `batch`, over the same range as
+/// `existence_query`
+pub fn locations_query(batch: &[Marking]) -> Option<Sql> {
+    covering(
+        Sql::new("SELECT time, device, location FROM scd40_data WHERE location IS NOT NULL AND "),
+        batch,
+    )
+}
+
+fn is_detector_row(row: &AnomalyRow) -> bool {
+    row.status.as_deref().is_none_or(|s| s == "auto")
+}
+
+/// The detector's flags per device and time, merged when stored twice.
+pub fn existing_markings(rows: Vec<AnomalyRow>) -> HashMap<Key, AnomalyFlags> {
+    let mut existing: HashMap<Key, AnomalyFlags> = HashMap::new();
+    for record in rows
+        .into_iter()
+        .filter(is_detector_row)
+        .filter_map(AnomalyRow::into_record)
+    {
+        existing
+            .entry((record.time, record.device))
+            .and_modify(|flags| *flags = flags.merged(&record.flags))
+            .or_insert(record.flags);
+    }
+    existing
+}
+
+pub async fn fetch_existing(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    table: &Identifier,
+    batch: &[Marking],
+) -> Result<HashMap<Key, AnomalyFlags>, Box<dyn Error>> {
+    let Some(sql) = existence_query(table, batch) else {
+        return Ok(HashMap::new());
+    };
+    let rows: Vec<AnomalyRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql,
+    )
+    .await?;
+    Ok(existing_markings(rows))
+}
+
+/// What is left of `batch` to write: new points as they are, changed ones
+/// merged with what is stored, and nothing already stored. Points repeated
+/// within the batch are merged first.
+pub fn admit(batch: &[Marking], existing: &HashMap<Key, AnomalyFlags>) -> Vec<Marking> {
+    let mut merged: BTreeMap<Key, AnomalyFlags> = BTreeMap::new();
+    for (time, flags, device) in batch {
+        merged
+            .entry((*time, device.clone()))
+            .and_modify(|f| *f = f.merged(flags))
+            .or_insert_with(|| flags.clone());
+    }
+    merged
+        .into_iter()
+        .filter_map(|(key, flags)| {
+            let flags = match existing.get(&key) {
+                None => flags,
+                Some(stored) => {
+                    let flags = stored.merged(&flags);
+                    if flags == *stored {
+                        return None;
+                    }
+                    flags
+                }
+            };
+            Some((key.0, flags, key.1))
+        })
+        .collect()
+}
+
+/// Device and time of every untagged marking stored next to a
+/// `status=auto` one, with the flags of both merged.
+pub fn find_duplicates(rows: Vec<AnomalyRow>) -> Vec<Marking> {
+    #[derive(Default)]
+    struct Group {
+        tagged: bool,
+        untagged: bool,
+        flags: AnomalyFlags,
+    }
+
+    let mut groups: BTreeMap<Key, Group> = BTreeMap::new();
+    for row in rows.into_iter().filter(is_detector_row) {
+        let tagged = row.status.is_some();
+        let Some(record) = row.into_record() else {
+            continue;
+        };
+        let group = groups.entry((record.time, record.device)).or_default();
+        group.tagged |= tagged;
+        group.untagged |= !tagged;
+        group.flags = group.flags.merged(&record.flags);
+    }
+    groups
+        .into_iter()
+        .filter(|(_, g)| g.tagged && g.untagged)
+        .map(|((time, device), g)| (time, g.flags, device))
+        .collect()
+}
+
+/// `duplicates` must hold at least one device name that parses; the others
+/// are left out.
+pub fn delete_untagged_query(table: &Identifier, duplicates: &[Marking]) -> Sql {
+    let mut sql = Sql::new("DELETE FROM ")
+        .table(table)
+        .push(" WHERE status IS NULL AND (");
+    let devices = duplicates
+        .iter()
+        .filter_map(|(time, _, device)| Some((time, Identifier::parse(device).ok()?)));
+    for (i, (time, device)) in devices.enumerate() {
+        if i > 0 {
+            sql = sql.push(" OR ");
+        }
+        sql = sql
+            .push("(time = ")
+            .time(*time)
+            .push(" AND device = ")
+            .identifier(&device)
+            .push(")");
+    }
+    sql.push(")")
+}
+
+/// Merges and removes the duplicates in `table`. Returns how many there
+/// were.
+pub async fn dedupe_table(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    table: &Identifier,
+) -> Result<usize, Box<dyn Error>> {
+    let rows: Vec<AnomalyRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT * FROM ").table(table),
+    )
+    .await?;
+    let mut duplicates = find_duplicates(rows);
+    // Rows of devices whose names can't be queried can't be deleted either
+    duplicates.retain(|(_, _, device)| Identifier::parse(device).is_ok());
+    if duplicates.is_empty() {
+        return Ok(0);
+    }
+
+    let store = InfluxStore {
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    };
+    // Merged points first, so a failed delete leaves duplicates rather than
+    // gaps. Untagged markings predate locations, like the points next to them.
+    let lines: Vec<String> = duplicates
+        .iter()
+        .map(|m| marking_line(table.as_str(), m, None))
+        .collect();
+    for chunk in lines.chunks(500) {
+        store.write(chunk).await?;
+    }
+    for chunk in duplicates.chunks(DELETE_BATCH) {
+        query_rows::<serde_json::Value>(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &delete_untagged_query(table, chunk),
+        )
+        .await?;
+    }
+    Ok(duplicates.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at_minute as at;
+
+    fn co2() -> AnomalyFlags {
+        AnomalyFlags {
+            co2_spike: true,
+            ..Default::default()
+        }
+    }
+
+    fn humidity() -> AnomalyFlags {
+        AnomalyFlags {
+            humidity_spike: true,
+            ..Default::default()
+        }
+    }
+
+    fn row(json: &str) -> AnomalyRow {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn identical_points_are_dropped_and_different_ones_merged() {
+        let existing: HashMap<Key, AnomalyFlags> = [
+            ((at(0), "kitchen".to_string()), co2()),
+            ((at(5), "kitchen".to_string()), co2()),
+            ((at(10), "kitchen".to_string()), co2().merged(&humidity())),
+        ]
+        .into();
+        let batch = vec![
+            (at(0), co2(), "kitchen".to_string()),
+            (at(5), humidity(), "kitchen".to_string()),
+            // Fewer flags than stored: nothing to add
+            (at(10), humidity(), "kitchen".to_string()),
+            (at(0), humidity(), "bedroom".to_string()),
+        ];
+        assert_eq!(
+            admit(&batch, &existing),
+            vec![
+                (at(0), humidity(), "bedroom".to_string()),
+                (at(5), co2().merged(&humidity()), "kitchen".to_string()),
+            ]
+        );
+        assert_eq!(admit(&batch, &HashMap::new()).len(), 4);
+    }
+
+    #[test]
+    fn repeats_within_a_batch_are_merged() {
+        let batch = vec![
+            (at(0), co2(), "kitchen".to_string()),
+            (at(0), humidity(), "kitchen".to_string()),
+        ];
+        assert_eq!(
+            admit(&batch, &HashMap::new()),
+            vec![(at(0), co2().merged(&humidity()), "kitchen".to_string())]
+        );
+    }
+
+    #[test]
+    fn markings_are_tagged_with_the_measurement_location() {
+        let rows: Vec<LocationRow> = serde_json::from_str(
+            r#"[
+                {"time": "2025-01-15T12:00:00", "device": "bedroom-1", "location": "bedroom"},
+                {"time": "2025-01-15T12:05:00", "device": "bedroom-1", "location": "guest room"}
+            ]"#,
+        )
+        .unwrap();
+        let locations = locations(rows);
+        let tag = |minute, device: &str| {
+            let location = locations.get(&(at(minute), device.to_string()));
+            marking_line(
+                "anomalies",
+                &(at(minute), co2(), device.to_string()),
+                location.map(String::as_str),
+            )
+        };
+        assert!(tag(0, "bedroom-1").starts_with(
+            "anomalies,device=bedroom-1,location=bedroom,status=auto temperature_spike=false,"
+        ));
+        assert!(
+            tag(5, "bedroom-1")
+                .starts_with("anomalies,device=bedroom-1,location=guest\\ room,status=auto ")
+        );
+        assert!(tag(5, "kitchen").starts_with("anomalies,device=kitchen,status=auto "));
+    }
+
+    #[test]
+    fn one_query_covers_the_batch() {
+        let batch = vec![
+            (at(10), co2(), "kitchen".to_string()),
+            (at(0), co2(), "bed'room".to_string()),
+            (at(5), co2(), "kitchen".to_string()),
+        ];
+        let table = Identifier::parse("anomalies").unwrap();
+        assert_eq!(
+            existence_query(&table, &batch).unwrap().as_str(),
+            "SELECT * FROM anomalies WHERE time >= '2025-01-15T12:00:00+00:00' \
+             AND time <= '2025-01-15T12:10:00+00:00' AND device IN ('kitchen')"
+        );
+        assert_eq!(existence_query(&table, &[]), None);
+        assert_eq!(existence_query(&table, &batch[1..2]), None);
+        assert_eq!(
+            locations_query(&batch).unwrap().as_str(),
+            "SELECT time, device, location FROM scd40_data WHERE location IS NOT NULL \
+             AND time >= '2025-01-15T12:00:00+00:00' AND time <= '2025-01-15T12:10:00+00:00' \
+             AND device IN ('kitchen')"
+        );
+        assert_eq!(locations_query(&[]), None);
+    }
+
+    #[test]
+    fn reviews_dont_count_as_stored_markings() {
+        let existing = existing_markings(vec![
+            row(r#"{"time": "2025-01-15T12:00:00", "device": "kitchen", "co2_spike": true}"#),
+            row(
+                r#"{"time": "2025-01-15T12:00:00", "device": "kitchen", "status": "auto", "humidity_spike": true}"#,
+            ),
+            row(
+                r#"{"time": "2025-01-15T12:05:00", "device": "kitchen", "status": "dismissed", "co2_spike": true}"#,
+            ),
+        ]);
+        assert_eq!(existing.len(), 1);
+        assert_eq!(
+            existing[&(at(0), "kitchen".to_string())],
+            co2().merged(&humidity())
+        );
+    }
+
+    #[test]
+    fn only_untagged_rows_beside_tagged_ones_are_duplicates() {
+        let duplicates = find_duplicates(vec![
+            row(r#"{"time": "2025-01-15T12:00:00", "device": "kitchen", "co2_spike": true}"#),
+            row(
+                r#"{"time": "2025-01-15T12:00:00", "device": "kitchen", "status": "auto", "humidity_spike": true}"#,
+            ),
+            // Alone, or next to a review only
+            row(r#"{"time": "2025-01-15T12:05:00", "device": "kitchen", "co2_spike": true}"#),
+            row(
+                r#"{"time": "2025-01-15T12:05:00", "device": "kitchen", "status": "confirmed", "co2_spike": true}"#,
+            ),
+        ]);
+        assert_eq!(
+            duplicates,
+            vec![(at(0), co2().merged(&humidity()), "kitchen".to_string())]
+        );
+        assert_eq!(
+            delete_untagged_query(&Identifier::parse("anomalies").unwrap(), &duplicates).as_str(),
+            "DELETE FROM anomalies WHERE status IS NULL AND \
+             ((time = '2025-01-15T12:00:00+00:00' AND device = 'kitchen'))"
+        );
+        assert_eq!(
+            marking_line("anomalies", &duplicates[0], None),
+            "anomalies,device=kitchen,status=auto temperature_spike=false,humidity_spike=true,\
+             co2_spike=true,physical_constraint_temp_violation=false,\
+             physical_constraint_humidity_violation=false,physical_constraint_co2_violation=false,\
+             possible_sunlight=false 1736942400000000000"
+        );
+    }
+}
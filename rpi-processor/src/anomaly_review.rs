@@ -0,0 +1,396 @@
+//! Review of anomaly markings.
+//!
+//! The detector writes markings tagged `status=auto`. Confirming or
+//! dismissing one writes the point again with the new status, a reviewer
+//! note and the review time. The status is a tag, so the rewrite is a row
+//! next to the original rather than a replacement; `resolve` picks the
+//! latest review for each device and time, and the detector's own record
+//! stays in the table.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::anomalies::AnomalyFlags;
+use crate::fetcher::{Identifier, Sql, query_rows};
+
+pub const MEASUREMENT: &str = "anomalies";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewStatus {
+    /// Marked by the detector and not reviewed yet
+    #[default]
+    Auto,
+    Confirmed,
+    /// A false alarm, e.g. a party really did raise the CO2
+    Dismissed,
+}
+
+impl ReviewStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReviewStatus::Auto => "auto",
+            ReviewStatus::Confirmed => "confirmed",
+            ReviewStatus::Dismissed => "dismissed",
+        }
+    }
+
+    /// Dismissed points are ordinary measurements again and go back into
+    /// training.
+    pub fn excluded_from_training(self) -> bool {
+        !matches!(self, ReviewStatus::Dismissed)
+    }
+
+    /// A reviewer may confirm or dismiss any marking and change their mind
+    /// later; only the detector marks points `auto`.
+    pub fn review(self, to: ReviewStatus) -> Result<ReviewStatus, String> {
+        match to {
+            ReviewStatus::Auto => Err(format!(
+                "a {} marking can't be set back to auto",
+                self.as_str()
+            )),
+            ReviewStatus::Confirmed | ReviewStatus::Dismissed => Ok(to),
+        }
+    }
+}
+
+impl FromStr for ReviewStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ReviewStatus::Auto),
+            "confirmed" => Ok(ReviewStatus::Confirmed),
+            "dismissed" => Ok(ReviewStatus::Dismissed),
+            other => Err(format!("unknown review status '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnomalyRecord {
+    pub time: DateTime<Utc>,
+    pub device: String,
+    /// The room the device reported when it took the measurement
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub status: ReviewStatus,
+    pub flags: AnomalyFlags,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reviewer_note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reviewed_at: Option<DateTime<Utc>>,
+}
+
+/// Keeps one record per device and time: the latest review, or the
+/// detector's marking if nobody reviewed it. Sorted by time.
+pub fn resolve(records: Vec<AnomalyRecord>) -> Vec<AnomalyRecord> {
+    let rank = |r: &AnomalyRecord| (r.status != ReviewStatus::Auto, r.reviewed_at);
+    let mut latest: BTreeMap<(DateTime<Utc>, String), AnomalyRecord> = BTreeMap::new();
+    for record in records {
+        let key = (record.time, record.device.clone());
+        match latest.get(&key) {
+            Some(current) if rank(current) >= rank(&record) => {}
+            _ => {
+                latest.insert(key, record);
+            }
+        }
+    }
+    latest.into_values().collect()
+}
+
+/// Times the predictor leaves out of training.
+pub fn training_exclusions(records: Vec<AnomalyRecord>) -> HashSet<DateTime<Utc>> {
+    resolve(records)
+        .into_iter()
+        .filter(|r| r.status.excluded_from_training())
+        .map(|r| r.time)
+        .collect()
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn escape_string_field(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The marking rewritten with the reviewer's verdict.
+pub fn review_line(
+    record: &AnomalyRecord,
+    status: ReviewStatus,
+    note: Option<&str>,
+    reviewed_at: DateTime<Utc>,
+) -> String {
+    let flags = &record.flags;
+    let mut fields = format!(
+        "temperature_spike={},humidity_spike={},co2_spike={},physical_constraint_temp_violation={},physical_constraint_humidity_violation={},physical_constraint_co2_violation={},possible_sunlight={},reviewed_at=\"{}\"",
+        flags.temperature_spike,
+        flags.humidity_spike,
+        flags.co2_spike,
+        flags.physical_constraint_temp_violation,
+        flags.physical_constraint_humidity_violation,
+        flags.physical_constraint_co2_violation,
+        flags.possible_sunlight,
+        reviewed_at.to_rfc3339()
+    );
+    if let Some(note) = note {
+        fields.push_str(&format!(",reviewer_note=\"{}\"", escape_string_field(note)));
+    }
+    format!(
+        "{},device={}{},status={} {} {}",
+        MEASUREMENT,
+        escape_tag(&record.device),
+        record
+            .location
+            .as_deref()
+            .map(|location| format!(",location={}", escape_tag(location)))
+            .unwrap_or_default(),
+        status.as_str(),
+        fields,
+        record.time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+/// Columns added later (status, review fields) are null on older rows.
+#[derive(Deserialize)]
+pub(crate) struct AnomalyRow {
+    time: String,
+    device: Option<String>,
+    location: Option<String>,
+    /// Absent on markings written before reviews existed
+    pub(crate) status: Option<String>,
+    temperature_spike: Option<bool>,
+    humidity_spike: Option<bool>,
+    co2_spike: Option<bool>,
+    possible_sunlight: Option<bool>,
+    physical_constraint_temp_violation: Option<bool>,
+    physical_constraint_humidity_violation: Option<bool>,
+    physical_constraint_co2_violation: Option<bool>,
+    reviewer_note: Option<String>,
+    reviewed_at: Option<String>,
+}
+
+pub(crate) fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = if value.ends_with('Z') || value.contains('+') {
+        value.to_string()
+    } else {
+        format!("{}Z", value)
+    };
+    DateTime::parse_from_rfc3339(&value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+impl AnomalyRow {
+    pub(crate) fn into_record(self) -> Option<AnomalyRecord> {
+        Some(AnomalyRecord {
+            time: parse_time(&self.time)?,
+            device: self.device.unwrap_or_default(),
+            location: self.location,
+            status: self.status.and_then(|s| s.parse().ok()).unwrap_or_default(),
+            flags: AnomalyFlags {
+                temperature_spike: self.temperature_spike.unwrap_or(false),
+                humidity_spike: self.humidity_spike.unwrap_or(false),
+                co2_spike: self.co2_spike.unwrap_or(false),
+                possible_sunlight: self.possible_sunlight.unwrap_or(false),
+                physical_constraint_temp_violation: self
+                    .physical_constraint_temp_violation
+                    .unwrap_or(false),
+                physical_constraint_humidity_violation: self
+                    .physical_constraint_humidity_violation
+                    .unwrap_or(false),
+                physical_constraint_co2_violation: self
+                    .physical_constraint_co2_violation
+                    .unwrap_or(false),
+            },
+            reviewer_note: self.reviewer_note,
+            reviewed_at: self.reviewed_at.as_deref().and_then(parse_time),
+        })
+    }
+}
+
+/// Every stored row between `from` and `to` (both inclusive), reviews and
+/// originals alike; pass the result through `resolve`.
+pub async fn fetch_records(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    device: Option<&Identifier>,
+) -> Result<Vec<AnomalyRecord>, Box<dyn Error>> {
+    let mut sql = Sql::new("SELECT * FROM ").push(MEASUREMENT);
+    let mut conjunction = " WHERE ";
+    if let Some((from, to)) = range {
+        sql = sql
+            .push(conjunction)
+            .push("time >= ")
+            .time(from)
+            .push(" AND time <= ")
+            .time(to);
+        conjunction = " AND ";
+    }
+    if let Some(device) = device {
+        sql = sql.push(conjunction).push("device = ").identifier(device);
+    }
+    let rows: Vec<AnomalyRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql.push(" ORDER BY time ASC"),
+    )
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(AnomalyRow::into_record)
+        .collect())
+}
+
+/// Anomalous times to leave out of training. Without an anomalies table
+/// nothing is filtered.
+pub async fn fetch_training_exclusions(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+) -> HashSet<DateTime<Utc>> {
+    match fetch_records(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        None,
+        None,
+    )
+    .await
+    {
+        Ok(records) => training_exclusions(records),
+        Err(e) => {
+            log::warn!("Failed to fetch anomalies or no anomalies found: {}", e);
+            HashSet::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at_minute as at;
+
+    fn record(minute: i64, status: ReviewStatus, reviewed_minute: Option<i64>) -> AnomalyRecord {
+        AnomalyRecord {
+            time: at(minute),
+            device: "esp32-scd40".to_string(),
+            location: None,
+            status,
+            flags: AnomalyFlags {
+                co2_spike: true,
+                ..Default::default()
+            },
+            reviewer_note: None,
+            reviewed_at: reviewed_minute.map(at),
+        }
+    }
+
+    #[test]
+    fn reviewers_can_confirm_dismiss_and_change_their_mind() {
+        use ReviewStatus::*;
+        assert_eq!(Auto.review(Confirmed), Ok(Confirmed));
+        assert_eq!(Auto.review(Dismissed), Ok(Dismissed));
+        assert_eq!(Confirmed.review(Dismissed), Ok(Dismissed));
+        assert_eq!(Dismissed.review(Confirmed), Ok(Confirmed));
+        // Repeating a verdict only updates the note
+        assert_eq!(Dismissed.review(Dismissed), Ok(Dismissed));
+        assert!(Confirmed.review(Auto).is_err());
+        assert!(Dismissed.review(Auto).is_err());
+    }
+
+    #[test]
+    fn only_dismissed_points_go_back_into_training() {
+        assert!(ReviewStatus::Auto.excluded_from_training());
+        assert!(ReviewStatus::Confirmed.excluded_from_training());
+        assert!(!ReviewStatus::Dismissed.excluded_from_training());
+
+        let records = vec![
+            record(0, ReviewStatus::Auto, None),
+            record(4, ReviewStatus::Auto, None),
+            record(4, ReviewStatus::Dismissed, Some(60)),
+            record(8, ReviewStatus::Auto, None),
+            record(8, ReviewStatus::Confirmed, Some(61)),
+        ];
+        assert_eq!(training_exclusions(records), HashSet::from([at(0), at(8)]));
+    }
+
+    #[test]
+    fn latest_review_wins_over_the_original_marking() {
+        let records = vec![
+            record(4, ReviewStatus::Dismissed, Some(60)),
+            record(4, ReviewStatus::Confirmed, Some(90)),
+            // Re-running the detector writes `auto` again after the reviews
+            record(4, ReviewStatus::Auto, None),
+        ];
+        let resolved = resolve(records);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].status, ReviewStatus::Confirmed);
+        assert_eq!(resolved[0].reviewed_at, Some(at(90)));
+
+        // Another device at the same time is a separate marking
+        let mut other = record(4, ReviewStatus::Auto, None);
+        other.device = "kitchen".to_string();
+        let resolved = resolve(vec![record(4, ReviewStatus::Dismissed, Some(60)), other]);
+        assert_eq!(resolved.len(), 2);
+    }
+
+    #[test]
+    fn review_line_keeps_flags_and_escapes_the_note() {
+        let line = review_line(
+            &record(0, ReviewStatus::Auto, None),
+            ReviewStatus::Dismissed,
+            Some("birthday \"party\""),
+            at(60),
+        );
+        assert_eq!(
+            line,
+            "anomalies,device=esp32-scd40,status=dismissed temperature_spike=false,\
+             humidity_spike=false,co2_spike=true,physical_constraint_temp_violation=false,\
+             physical_constraint_humidity_violation=false,physical_constraint_co2_violation=false,\
+             possible_sunlight=false,reviewed_at=\"2025-01-15T13:00:00+00:00\",\
+             reviewer_note=\"birthday \\\"party\\\"\" 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn review_line_keeps_the_location() {
+        let record = AnomalyRecord {
+            location: Some("living room".to_string()),
+            ..record(0, ReviewStatus::Auto, None)
+        };
+        let line = review_line(&record, ReviewStatus::Confirmed, None, at(60));
+        assert!(line.starts_with(
+            "anomalies,device=esp32-scd40,location=living\\ room,status=confirmed temperature_spike=false,"
+        ));
+    }
+
+    #[test]
+    fn rows_without_review_columns_are_auto() {
+        let row: AnomalyRow = serde_json::from_str(
+            r#"{"time": "2025-01-15T12:00:00", "device": "esp32-scd40", "co2_spike": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            row.into_record().unwrap(),
+            record(0, ReviewStatus::Auto, None)
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Read-only statistics for tuning the anomaly thresholds.
+//!
+//! Recomputes a detector's metric over stored measurements without writing
+//! any flags, so a threshold can be picked from the distribution and from
+//! how many points each alternative would have flagged.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::anomalies::AnomalyConfig;
+use crate::types::MeasurementWithTime;
+
+/// Longest range one tuning request may cover.
+pub const MAX_TUNING_RANGE_DAYS: i64 = 31;
+pub const DEFAULT_BUCKETS: usize = 20;
+pub const MAX_BUCKETS: usize = 200;
+pub const DEFAULT_SWEEP_STEPS: usize = 11;
+pub const MAX_SWEEP_STEPS: usize = 101;
+
+/// Detectors whose flag is a single comparison against one threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Detector {
+    /// CO2 ppm at or above `co2_spike_threshold`
+    Co2Spike,
+    /// Humidity at or below `humidity_definite_anomaly`
+    HumiditySpike,
+}
+
+impl FromStr for Detector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "co2_spike" => Ok(Detector::Co2Spike),
+            "humidity_spike" => Ok(Detector::HumiditySpike),
+            other => Err(format!(
+                "unknown detector '{}', expected co2_spike or humidity_spike",
+                other
+            )),
+        }
+    }
+}
+
+impl Detector {
+    pub fn metric(&self, m: &MeasurementWithTime) -> f32 {
+        match self {
+            Detector::Co2Spike => m.co2 as f32,
+            Detector::HumiditySpike => m.humidity,
+        }
+    }
+
+    pub fn threshold(&self, config: &AnomalyConfig) -> f32 {
+        match self {
+            Detector::Co2Spike => config.co2_spike_threshold,
+            Detector::HumiditySpike => config.humidity_definite_anomaly,
+        }
+    }
+
+    /// Same comparison as `AnomalyDetector::analyze`
+    pub fn flags(&self, value: f32, threshold: f32) -> bool {
+        match self {
+            Detector::Co2Spike => value >= threshold,
+            Detector::HumiditySpike => value <= threshold,
+        }
+    }
+}
+
+/// Rejects empty, inverted and over-long ranges before anything is queried.
+pub fn check_range(from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(), String> {
+    if to <= from {
+        return Err(format!("'to' ({}) must be after 'from' ({})", to, from));
+    }
+    if to - from > Duration::days(MAX_TUNING_RANGE_DAYS) {
+        return Err(format!(
+            "range of {} days exceeds the {} day limit",
+            (to - from).num_days(),
+            MAX_TUNING_RANGE_DAYS
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub lower: f32,
+    pub upper: f32,
+    pub count: usize,
+}
+
+/// Equal-width buckets spanning the observed values. The last bucket
+/// includes its upper bound; identical values all land in one bucket.
+pub fn histogram(values: &[f32], buckets: usize) -> Vec<Bucket> {
+    let (Some(min), Some(max)) = (
+        values.iter().copied().reduce(f32::min),
+        values.iter().copied().reduce(f32::max),
+    ) else {
+        return Vec::new();
+    };
+    if buckets == 0 {
+        return Vec::new();
+    }
+    if min == max {
+        return vec![Bucket {
+            lower: min,
+            upper: max,
+            count: values.len(),
+        }];
+    }
+
+    let width = (max - min) / buckets as f32;
+    let mut result: Vec<Bucket> = (0..buckets)
+        .map(|i| Bucket {
+            lower: min + width * i as f32,
+            upper: if i + 1 == buckets {
+                max
+            } else {
+                min + width * (i + 1) as f32
+            },
+            count: 0,
+        })
+        .collect();
+    for &value in values {
+        let index = (((value - min) / width) as usize).min(buckets - 1);
+        result[index].count += 1;
+    }
+    result
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepPoint {
+    pub threshold: f32,
+    pub flagged: usize,
+}
+
+/// `steps` thresholds evenly spread over the observed values, plus the
+/// current one, each with the number of values it would flag.
+pub fn sweep(detector: Detector, values: &[f32], current: f32, steps: usize) -> Vec<SweepPoint> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mut thresholds = vec![current];
+    if let (Some(&min), Some(&max)) = (sorted.first(), sorted.last()) {
+        match steps {
+            0 => {}
+            1 => thresholds.push(min),
+            _ => {
+                let step = (max - min) / (steps - 1) as f32;
+                thresholds.extend((0..steps).map(|i| min + step * i as f32));
+            }
+        }
+    }
+    thresholds.sort_by(|a, b| a.total_cmp(b));
+    thresholds.dedup();
+
+    thresholds
+        .into_iter()
+        .map(|threshold| {
+            let flagged = match detector {
+                Detector::Co2Spike => sorted.len() - sorted.partition_point(|&v| v < threshold),
+                Detector::HumiditySpike => sorted.partition_point(|&v| v <= threshold),
+            };
+            SweepPoint { threshold, flagged }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tuning {
+    pub detector: Detector,
+    pub device: Option<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub samples: usize,
+    pub current_threshold: f32,
+    /// Points the current threshold flags in this range
+    pub current_flagged: usize,
+    pub histogram: Vec<Bucket>,
+    pub sweep: Vec<SweepPoint>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn build_tuning(
+    detector: Detector,
+    config: &AnomalyConfig,
+    measurements: &[MeasurementWithTime],
+    device: Option<String>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    buckets: usize,
+    sweep_steps: usize,
+) -> Tuning {
+    let values: Vec<f32> = measurements.iter().map(|m| detector.metric(m)).collect();
+    let current_threshold = detector.threshold(config);
+    Tuning {
+        detector,
+        device,
+        from,
+        to,
+        samples: values.len(),
+        current_threshold,
+        current_flagged: values
+            .iter()
+            .filter(|&&v| detector.flags(v, current_threshold))
+            .count(),
+        histogram: histogram(&values, buckets),
+        sweep: sweep(detector, &values, current_threshold, sweep_steps),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn measurement(co2: u16, humidity: f32) -> MeasurementWithTime {
+        MeasurementWithTime {
+            time: Utc.with_ymd_and_hms(2025, 1, 15, 10, 0, 0).unwrap(),
+            co2,
+            temperature: 21.0,
+            humidity,
+            device: "esp32-scd40".to_string(),
+        }
+    }
+
+    #[test]
+    fn histogram_spreads_values_over_equal_buckets() {
+        let buckets = histogram(&[400.0, 450.0, 500.0, 550.0, 800.0], 4);
+        assert_eq!(buckets.len(), 4);
+        assert_eq!(buckets[0].lower, 400.0);
+        assert_eq!(buckets[0].upper, 500.0);
+        assert_eq!(
+            buckets.iter().map(|b| b.count).collect::<Vec<_>>(),
+            vec![2, 2, 0, 1]
+        );
+        // The maximum lands in the last bucket rather than past it
+        assert_eq!(buckets[3].upper, 800.0);
+    }
+
+    #[test]
+    fn histogram_edge_cases() {
+        assert!(histogram(&[], 10).is_empty());
+        assert!(histogram(&[1.0], 0).is_empty());
+        assert_eq!(
+            histogram(&[612.0, 612.0, 612.0], 10),
+            vec![Bucket {
+                lower: 612.0,
+                upper: 612.0,
+                count: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn sweep_counts_in_the_detector_direction() {
+        let co2 = [400.0, 600.0, 700.0, 900.0, 1000.0];
+        let points = sweep(Detector::Co2Spike, &co2, 700.0, 4);
+        // 400, 600, 700 (current), 800 and 1000
+        assert_eq!(
+            points
+                .iter()
+                .map(|p| (p.threshold, p.flagged))
+                .collect::<Vec<_>>(),
+            vec![(400.0, 5), (600.0, 4), (700.0, 3), (800.0, 2), (1000.0, 1)]
+        );
+
+        let humidity = [40.0, 50.0, 55.0, 70.0];
+        let points = sweep(Detector::HumiditySpike, &humidity, 55.0, 0);
+        assert_eq!(
+            points,
+            vec![SweepPoint {
+                threshold: 55.0,
+                flagged: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn tuning_matches_the_live_detector_at_the_current_threshold() {
+        let measurements = [
+            measurement(450, 60.0),
+            measurement(700, 54.0),
+            measurement(950, 70.0),
+        ];
+        let config = AnomalyConfig::default();
+        let from = Utc.with_ymd_and_hms(2025, 1, 15, 0, 0, 0).unwrap();
+        let to = from + Duration::days(1);
+        let tuning = build_tuning(
+            Detector::Co2Spike,
+            &config,
+            &measurements,
+            None,
+            from,
+            to,
+            DEFAULT_BUCKETS,
+            DEFAULT_SWEEP_STEPS,
+        );
+        assert_eq!(tuning.samples, 3);
+        assert_eq!(tuning.current_threshold, 700.0);
+        assert_eq!(tuning.current_flagged, 2);
+        let at_current = tuning.sweep.iter().find(|p| p.threshold == 700.0).unwrap();
+        assert_eq!(at_current.flagged, 2);
+
+        let mut detector = crate::anomalies::AnomalyDetector::with_config(config);
+        let live = measurements
+            .iter()
+            .filter(|m| detector.analyze(m, false).co2_spike)
+            .count();
+        assert_eq!(live, tuning.current_flagged);
+    }
+
+    #[test]
+    fn range_is_capped() {
+        let from = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        assert!(check_range(from, from + Duration::days(MAX_TUNING_RANGE_DAYS)).is_ok());
+        assert!(check_range(from, from + Duration::days(MAX_TUNING_RANGE_DAYS + 1)).is_err());
+        assert!(check_range(from, from).is_err());
+        assert!("co2_delta".parse::<Detector>().is_err());
+    }
+}
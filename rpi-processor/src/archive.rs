@@ -0,0 +1,848 @@
+//! Long-term archive of raw measurements as Parquet, one file per device and
+//! month, in a local directory or an S3-compatible bucket.
+//!
+//! Files are laid out as `scd40_data/device=<device>/<yyyy>-<mm>.parquet`,
+//! sorted by time. Archiving a month that already has a file merges into it,
+//! so running the archive again with a later cutoff is safe.
+//!
+//! Raw rows are only deleted after every written file has been read back and
+//! the row counts match the source, and only when the cutoff is old enough
+//! (`MIN_DELETE_AGE`). `restore` writes the archived rows back to
+//! `scd40_data` with their original timestamps.
+//!
+//! S3 targets (`s3://bucket/prefix`) are configured with the usual `AWS_*`
+//! variables; `AWS_ENDPOINT` and `AWS_ALLOW_HTTP` cover MinIO and friends.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, TimestampNanosecondType, UInt16Type};
+use arrow_array::{
+    ArrayRef, Float32Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt16Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use futures::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::local::LocalFileSystem;
+use object_store::path::{Path, PathPart};
+use object_store::prefix::PrefixStore;
+use object_store::{ObjectStore, PutPayload};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Deserialize;
+use shared_types::line_protocol::{self, MeasurementFields};
+
+use crate::bulk_write::{BulkWriter, InfluxStore, Point, log_progress};
+use crate::fetcher::{Sql, query_rows};
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+const TABLE: &str = "scd40_data";
+
+/// Raw rows younger than this are never deleted, whatever the cutoff
+pub const MIN_DELETE_AGE: Duration = Duration::days(30);
+
+/// Rows per line-protocol request when restoring
+const RESTORE_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Partition {
+    pub device: String,
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Partition {
+    pub fn of(m: &MeasurementWithTime) -> Self {
+        Self {
+            device: m.device.clone(),
+            year: m.time.year(),
+            month: m.time.month(),
+        }
+    }
+
+    /// Device names are percent-encoded where they aren't valid path parts.
+    pub fn path(&self) -> Path {
+        Path::from_iter([
+            PathPart::from(TABLE),
+            PathPart::from(format!("device={}", self.device)),
+            PathPart::from(format!("{:04}-{:02}.parquet", self.year, self.month)),
+        ])
+    }
+
+    /// The inverse of `path`, for files found when listing the archive.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let parts: Vec<PathPart> = path.parts().collect();
+        let [table, device, file] = parts.as_slice() else {
+            return None;
+        };
+        if table.as_ref() != TABLE {
+            return None;
+        }
+        let device = percent_decode(device.as_ref().strip_prefix("device=")?);
+        let (year, month) = file.as_ref().strip_suffix(".parquet")?.split_once('-')?;
+        Some(Self {
+            device,
+            year: year.parse().ok()?,
+            month: month.parse().ok()?,
+        })
+    }
+
+    fn start(&self) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(self.year, self.month, 1)
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Groups measurements by partition, each sorted by time.
+pub fn partition(
+    measurements: Vec<MeasurementWithTime>,
+) -> BTreeMap<Partition, Vec<MeasurementWithTime>> {
+    let mut partitions: BTreeMap<Partition, Vec<MeasurementWithTime>> = BTreeMap::new();
+    for m in measurements {
+        partitions.entry(Partition::of(&m)).or_default().push(m);
+    }
+    for rows in partitions.values_mut() {
+        rows.sort_by_key(|m| m.time);
+    }
+    partitions
+}
+
+fn schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("device", DataType::Utf8, false),
+        Field::new("co2_ppm", DataType::UInt16, false),
+        Field::new("temperature_c", DataType::Float32, false),
+        Field::new("humidity_percent", DataType::Float32, false),
+    ]))
+}
+
+pub fn to_parquet(rows: &[MeasurementWithTime]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(
+            TimestampNanosecondArray::from(
+                rows.iter()
+                    .map(|m| m.time.timestamp_nanos_opt().ok_or("time out of range"))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|m| m.device.as_str()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(rows.iter().map(|m| m.co2))),
+        Arc::new(Float32Array::from_iter_values(
+            rows.iter().map(|m| m.temperature),
+        )),
+        Arc::new(Float32Array::from_iter_values(
+            rows.iter().map(|m| m.humidity),
+        )),
+    ];
+    let batch = RecordBatch::try_new(schema(), columns)?;
+
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(buffer)
+}
+
+pub fn from_parquet(bytes: Bytes) -> Result<Vec<MeasurementWithTime>, Box<dyn Error>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?;
+    let mut rows = Vec::new();
+    for batch in reader {
+        let batch = batch?;
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| format!("archive file has no {} column", name))
+        };
+        let time = column("time")?.as_primitive::<TimestampNanosecondType>();
+        let device = column("device")?.as_string::<i32>();
+        let co2 = column("co2_ppm")?.as_primitive::<UInt16Type>();
+        let temperature = column("temperature_c")?.as_primitive::<Float32Type>();
+        let humidity = column("humidity_percent")?.as_primitive::<Float32Type>();
+        for i in 0..batch.num_rows() {
+            rows.push(MeasurementWithTime {
+                co2: co2.value(i),
+                temperature: temperature.value(i),
+                humidity: humidity.value(i),
+                time: DateTime::from_timestamp_nanos(time.value(i)),
+                device: device.value(i).to_string(),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// A directory, or `s3://bucket[/prefix]`.
+pub fn open_store(target: &str) -> Result<Arc<dyn ObjectStore>, Box<dyn Error>> {
+    if let Some(rest) = target.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let s3 = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+        let prefix = prefix.trim_matches('/');
+        return Ok(if prefix.is_empty() {
+            Arc::new(s3)
+        } else {
+            Arc::new(PrefixStore::new(s3, prefix))
+        });
+    }
+    std::fs::create_dir_all(target)?;
+    Ok(Arc::new(LocalFileSystem::new_with_prefix(target)?))
+}
+
+async fn read_partition(
+    store: &dyn ObjectStore,
+    path: &Path,
+) -> Result<Vec<MeasurementWithTime>, Box<dyn Error>> {
+    match store.get(path).await {
+        Ok(result) => from_parquet(result.bytes().await?),
+        Err(object_store::Error::NotFound { .. }) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds `rows` to what is already archived, dropping exact duplicates of a
+/// (time, device) pair.
+fn merge(
+    existing: Vec<MeasurementWithTime>,
+    rows: &[MeasurementWithTime],
+) -> Vec<MeasurementWithTime> {
+    let mut seen = BTreeSet::new();
+    let mut merged: Vec<MeasurementWithTime> = existing
+        .into_iter()
+        .chain(rows.iter().cloned())
+        .filter(|m| seen.insert((m.time, m.device.clone())))
+        .collect();
+    merged.sort_by_key(|m| m.time);
+    merged
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionReport {
+    pub path: String,
+    /// Source rows archived in this run
+    pub archived: usize,
+    /// Rows in the file after the run, earlier archives included
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveReport {
+    pub cutoff: DateTime<Utc>,
+    /// What the database reports for rows before the cutoff
+    pub source_rows: usize,
+    pub partitions: Vec<PartitionReport>,
+}
+
+impl ArchiveReport {
+    pub fn archived_rows(&self) -> usize {
+        self.partitions.iter().map(|p| p.archived).sum()
+    }
+}
+
+/// Writes the partitions and reads each one back. Fails if a file doesn't
+/// hold every row it should.
+pub async fn write_partitions(
+    store: &dyn ObjectStore,
+    partitions: &BTreeMap<Partition, Vec<MeasurementWithTime>>,
+) -> Result<Vec<PartitionReport>, Box<dyn Error>> {
+    let mut reports = Vec::new();
+    for (partition, rows) in partitions {
+        let path = partition.path();
+        let merged = merge(read_partition(store, &path).await?, rows);
+        store
+            .put(&path, PutPayload::from(to_parquet(&merged)?))
+            .await?;
+
+        let written = read_partition(store, &path).await?;
+        let keys: BTreeSet<_> = written.iter().map(|m| (m.time, &m.device)).collect();
+        if written.len() != merged.len()
+            || rows.iter().any(|m| !keys.contains(&(m.time, &m.device)))
+        {
+            return Err(format!(
+                "{} holds {} rows after writing, expected {}",
+                path,
+                written.len(),
+                merged.len()
+            )
+            .into());
+        }
+        reports.push(PartitionReport {
+            path: path.to_string(),
+            archived: rows.len(),
+            total: written.len(),
+        });
+    }
+    Ok(reports)
+}
+
+/// Deleting is refused unless the archive is complete and the cutoff is at
+/// least `MIN_DELETE_AGE` in the past.
+pub fn check_delete_allowed(report: &ArchiveReport, now: DateTime<Utc>) -> Result<(), String> {
+    if report.archived_rows() != report.source_rows {
+        return Err(format!(
+            "archived {} rows but the database has {} before {}",
+            report.archived_rows(),
+            report.source_rows,
+            report.cutoff.to_rfc3339()
+        ));
+    }
+    if report.cutoff > now - MIN_DELETE_AGE {
+        return Err(format!(
+            "cutoff {} is less than {} days ago",
+            report.cutoff.to_rfc3339(),
+            MIN_DELETE_AGE.num_days()
+        ));
+    }
+    Ok(())
+}
+
+async fn fetch_before(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    cutoff: DateTime<Utc>,
+) -> Result<(Vec<MeasurementWithTime>, usize), Box<dyn Error>> {
+    #[derive(Deserialize)]
+    struct CountRow {
+        rows: u64,
+    }
+
+    let count: Vec<CountRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT COUNT(*) AS rows FROM ")
+            .push(TABLE)
+            .push(" WHERE time < ")
+            .time(cutoff),
+    )
+    .await?;
+    let rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM ")
+            .push(TABLE)
+            .push(" WHERE time < ")
+            .time(cutoff)
+            .push(" ORDER BY time ASC"),
+    )
+    .await?;
+    let measurements = rows
+        .iter()
+        .map(InfluxMeasurementRow::to_measurement_with_time)
+        .collect::<Result<Vec<_>, _>>()?;
+    let source_rows = count.first().map_or(0, |c| c.rows as usize);
+    Ok((measurements, source_rows))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run_archive(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    store: &dyn ObjectStore,
+    cutoff: DateTime<Utc>,
+    delete: bool,
+    now: DateTime<Utc>,
+) -> Result<ArchiveReport, Box<dyn Error>> {
+    let (measurements, source_rows) = fetch_before(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        cutoff,
+    )
+    .await?;
+    log::info!(
+        "Archiving {} rows before {}",
+        measurements.len(),
+        cutoff.to_rfc3339()
+    );
+
+    let partitions = write_partitions(store, &partition(measurements)).await?;
+    let report = ArchiveReport {
+        cutoff,
+        source_rows,
+        partitions,
+    };
+    for p in &report.partitions {
+        log::info!("{}: +{} rows, {} total", p.path, p.archived, p.total);
+    }
+
+    if delete {
+        check_delete_allowed(&report, now).map_err(|e| format!("not deleting: {}", e))?;
+        query_rows::<serde_json::Value>(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &Sql::new("DELETE FROM ")
+                .push(TABLE)
+                .push(" WHERE time < ")
+                .time(cutoff),
+        )
+        .await
+        .map_err(|e| format!("archive verified, but deleting raw rows failed: {}", e))?;
+        log::info!("Deleted {} archived raw rows", report.source_rows);
+    }
+    Ok(report)
+}
+
+pub fn to_line_protocol(m: &MeasurementWithTime) -> String {
+    line_protocol::measurement_to_line(
+        &m.device,
+        &MeasurementFields {
+            co2: m.co2,
+            temperature: m.temperature,
+            humidity: m.humidity,
+            maintenance: false,
+            injected: false,
+            battery_mv: None,
+            battery_percent: None,
+            fw_version: None,
+            location: None,
+            flags: None,
+        },
+        Some(m.time.timestamp_nanos_opt().unwrap_or(0)),
+    )
+}
+
+/// Filters for `run_restore`; `None` means everything.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreFilter {
+    pub device: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl RestoreFilter {
+    /// Whether the month can hold rows in range, so other files aren't read.
+    fn wants_partition(&self, partition: &Partition) -> bool {
+        if self.device.as_ref().is_some_and(|d| *d != partition.device) {
+            return false;
+        }
+        let Some(start) = partition.start() else {
+            return false;
+        };
+        let start = start.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let end = start
+            .checked_add_months(chrono::Months::new(1))
+            .unwrap_or(start);
+        self.from.is_none_or(|from| from < end) && self.to.is_none_or(|to| to >= start)
+    }
+
+    fn wants(&self, m: &MeasurementWithTime) -> bool {
+        self.from.is_none_or(|from| m.time >= from) && self.to.is_none_or(|to| m.time <= to)
+    }
+}
+
+/// Writes archived rows back to `scd40_data`. Returns the number of rows
+/// written, not counting those that were still stored.
+pub async fn run_restore(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    store: &dyn ObjectStore,
+    filter: &RestoreFilter,
+) -> Result<usize, Box<dyn Error>> {
+    let mut partitions: Vec<(Partition, Path)> = store
+        .list(Some(&Path::from(TABLE)))
+        .try_filter_map(|meta| async move {
+            Ok(Partition::from_path(&meta.location).map(|p| (p, meta.location)))
+        })
+        .try_collect()
+        .await?;
+    partitions.sort();
+
+    // Already restored rows are skipped, so an interrupted restore can be rerun
+    let writer = BulkWriter {
+        chunk_size: RESTORE_BATCH,
+        idempotent: true,
+        ..BulkWriter::new(InfluxStore {
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+        })
+    };
+    let mut restored = 0;
+    for (partition, path) in partitions {
+        if !filter.wants_partition(&partition) {
+            continue;
+        }
+        let rows: Vec<MeasurementWithTime> = read_partition(store, &path)
+            .await?
+            .into_iter()
+            .filter(|m| filter.wants(m))
+            .collect();
+        let label = format!("Restore of {}", path);
+        let progress = writer
+            .write(
+                rows.iter().map(|m| Point {
+                    measurement: TABLE,
+                    series_tag: "device",
+                    series: m.device.clone(),
+                    time: m.time,
+                    line: to_line_protocol(m),
+                }),
+                log_progress(&label),
+            )
+            .await
+            .map_err(|e| format!("Failed to restore {} to InfluxDB: {}", path, e))?;
+        log::info!(
+            "Restored {} rows from {}, {} were already stored",
+            progress.written,
+            path,
+            progress.skipped
+        );
+        restored += progress.written;
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, extract::State, routing::post};
+
+    fn at(day: u32, month: u32, hour: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2025, month, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    fn m(device: &str, time: DateTime<Utc>, co2: u16) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 21.5,
+            humidity: 40.25,
+            time,
+            device: device.to_string(),
+        }
+    }
+
+    fn sample() -> Vec<MeasurementWithTime> {
+        vec![
+            m("kitchen", at(31, 1, 23), 700),
+            m("kitchen", at(2, 1, 8), 500),
+            m("kitchen", at(1, 2, 0), 800),
+            m("bed/room", at(15, 1, 12), 600),
+        ]
+    }
+
+    /// A fresh directory under the system temp dir, removed on drop
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rpi-processor-archive-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        fn store(&self) -> Arc<dyn ObjectStore> {
+            open_store(self.0.to_str().unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn partitions_by_device_and_month_sorted_by_time() {
+        let partitions = partition(sample());
+        let summary: Vec<(String, Vec<u16>)> = partitions
+            .iter()
+            .map(|(p, rows)| (p.path().to_string(), rows.iter().map(|m| m.co2).collect()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "scd40_data/device=bed%2Froom/2025-01.parquet".to_string(),
+                    vec![600]
+                ),
+                (
+                    "scd40_data/device=kitchen/2025-01.parquet".to_string(),
+                    vec![500, 700]
+                ),
+                (
+                    "scd40_data/device=kitchen/2025-02.parquet".to_string(),
+                    vec![800]
+                ),
+            ]
+        );
+        for p in partitions.keys() {
+            assert_eq!(Partition::from_path(&p.path()).as_ref(), Some(p));
+        }
+    }
+
+    #[test]
+    fn parquet_roundtrip_keeps_types_and_values() {
+        let rows = sample();
+        let bytes = to_parquet(&rows).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(bytes.clone())).unwrap();
+        assert_eq!(reader.schema().as_ref(), schema().as_ref());
+
+        let back = from_parquet(Bytes::from(bytes)).unwrap();
+        assert_eq!(back.len(), rows.len());
+        for (a, b) in back.iter().zip(&rows) {
+            assert_eq!(
+                (a.time, &a.device, a.co2, a.temperature, a.humidity),
+                (b.time, &b.device, b.co2, b.temperature, b.humidity)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn archiving_twice_merges_without_duplicates() {
+        let dir = TempDir::new("merge");
+        let store = dir.store();
+        let first = write_partitions(store.as_ref(), &partition(sample()[..2].to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(first[0].total, 2);
+
+        // Overlaps the first run by the two January kitchen rows
+        let second = write_partitions(store.as_ref(), &partition(sample()))
+            .await
+            .unwrap();
+        let kitchen_january = second
+            .iter()
+            .find(|p| p.path.ends_with("kitchen/2025-01.parquet"))
+            .unwrap();
+        assert_eq!((kitchen_january.archived, kitchen_january.total), (2, 2));
+        assert!(
+            dir.0
+                .join("scd40_data/device=kitchen/2025-02.parquet")
+                .exists()
+        );
+    }
+
+    #[test]
+    fn delete_needs_matching_counts_and_an_old_cutoff() {
+        let report = |source_rows, cutoff| ArchiveReport {
+            cutoff,
+            source_rows,
+            partitions: vec![PartitionReport {
+                path: "p".to_string(),
+                archived: 4,
+                total: 4,
+            }],
+        };
+        let now = at(1, 6, 0);
+        assert!(check_delete_allowed(&report(4, at(1, 3, 0)), now).is_ok());
+        assert!(
+            check_delete_allowed(&report(5, at(1, 3, 0)), now)
+                .unwrap_err()
+                .contains("archived 4 rows")
+        );
+        assert!(
+            check_delete_allowed(&report(4, at(20, 5, 0)), now)
+                .unwrap_err()
+                .contains("30 days")
+        );
+    }
+
+    #[test]
+    fn restore_filter_skips_months_out_of_range() {
+        let filter = RestoreFilter {
+            device: Some("kitchen".to_string()),
+            from: Some(at(15, 1, 0)),
+            to: Some(at(31, 1, 0)),
+        };
+        let p = |device: &str, month| Partition {
+            device: device.to_string(),
+            year: 2025,
+            month,
+        };
+        assert!(filter.wants_partition(&p("kitchen", 1)));
+        assert!(!filter.wants_partition(&p("kitchen", 2)));
+        assert!(!filter.wants_partition(&p("bed/room", 1)));
+        assert!(!filter.wants(&m("kitchen", at(2, 1, 8), 500)));
+        assert!(filter.wants(&m("kitchen", at(20, 1, 8), 500)));
+    }
+
+    /// Serves `rows` for the SELECT, `count` for COUNT(*) and records
+    /// DELETEs and line-protocol writes.
+    #[derive(Clone, Default)]
+    struct FakeInflux {
+        rows: Arc<std::sync::Mutex<Vec<MeasurementWithTime>>>,
+        count: Arc<std::sync::Mutex<usize>>,
+        deletes: Arc<std::sync::Mutex<Vec<String>>>,
+        written: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    async fn fake_query(
+        State(fake): State<FakeInflux>,
+        Json(body): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        let sql = body["q"].as_str().unwrap().to_string();
+        if sql.starts_with("DELETE") {
+            fake.deletes.lock().unwrap().push(sql);
+            return Json(serde_json::json!([]));
+        }
+        if sql.contains("COUNT(*)") {
+            let count = *fake.count.lock().unwrap();
+            return Json(serde_json::json!([{ "rows": count }]));
+        }
+        let rows = fake
+            .rows
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                serde_json::json!({
+                    "time": m.time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                    "co2_ppm": m.co2 as f64,
+                    "temperature_c": m.temperature as f64,
+                    "humidity_percent": m.humidity as f64,
+                    "device": m.device,
+                })
+            })
+            .collect();
+        Json(serde_json::Value::Array(rows))
+    }
+
+    async fn fake_write(State(fake): State<FakeInflux>, body: String) {
+        fake.written
+            .lock()
+            .unwrap()
+            .extend(body.lines().map(String::from));
+    }
+
+    async fn fake_influx(rows: Vec<MeasurementWithTime>, count: usize) -> (String, FakeInflux) {
+        let fake = FakeInflux::default();
+        *fake.rows.lock().unwrap() = rows;
+        *fake.count.lock().unwrap() = count;
+        let app = Router::new()
+            .route("/api/v3/query_sql", post(fake_query))
+            .route("/api/v3/write_lp", post(fake_write))
+            .with_state(fake.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), fake)
+    }
+
+    #[tokio::test]
+    async fn archive_verifies_then_deletes_and_restore_writes_it_back() {
+        let dir = TempDir::new("roundtrip");
+        let store = dir.store();
+        let (host, fake) = fake_influx(sample(), 4).await;
+        let client = reqwest::Client::new();
+
+        let report = run_archive(
+            &host,
+            "token",
+            "db",
+            &client,
+            store.as_ref(),
+            at(1, 3, 0),
+            true,
+            at(1, 6, 0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.archived_rows(), 4);
+        assert_eq!(report.partitions.len(), 3);
+        assert_eq!(
+            *fake.deletes.lock().unwrap(),
+            vec!["DELETE FROM scd40_data WHERE time < '2025-03-01T00:00:00+00:00'"]
+        );
+        fake.rows.lock().unwrap().clear();
+
+        let restored = run_restore(
+            &host,
+            "token",
+            "db",
+            &client,
+            store.as_ref(),
+            &RestoreFilter {
+                device: Some("kitchen".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(restored, 3);
+        assert_eq!(
+            fake.written.lock().unwrap()[0],
+            format!(
+                "scd40_data,device=kitchen co2_ppm=500,temperature_c=21.5,humidity_percent=40.25 {}",
+                at(2, 1, 8).timestamp_nanos_opt().unwrap()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn count_mismatch_keeps_the_raw_rows() {
+        let dir = TempDir::new("mismatch");
+        let (host, fake) = fake_influx(sample(), 5).await;
+        let error = run_archive(
+            &host,
+            "token",
+            "db",
+            &reqwest::Client::new(),
+            dir.store().as_ref(),
+            at(1, 3, 0),
+            true,
+            at(1, 6, 0),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().starts_with("not deleting"), "{}", error);
+        assert!(fake.deletes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+//! Rebuilds the receiver's per-device state from InfluxDB at startup.
+//!
+//! Without it every restart forgets when devices were last seen and the
+//! alert detectors start without context, so offline checks and baselines
+//! take hours to become useful again. Two queries bring it back: the last
+//! measurement time per device, and the last day of points, which gives the
+//! median time between measurements and the history the detectors need.
+//!
+//! The receiver waits only briefly for the queries. If the database is slow
+//! it connects to MQTT anyway and applies the result when it arrives; live
+//! measurements received in the meantime take precedence over stored ones.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::alerts::Alerter;
+use crate::fetcher::{Sql, query_rows};
+use crate::freshness::{self, LastSeen};
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+/// How far back the history goes
+pub const HISTORY: Duration = Duration::days(1);
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceHistory {
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Median time between consecutive points of the last day
+    pub median_interval: Option<Duration>,
+    /// The last day of points, oldest first
+    pub recent: Vec<MeasurementWithTime>,
+}
+
+pub type Bootstrap = BTreeMap<String, DeviceHistory>;
+
+pub fn history_query(now: DateTime<Utc>) -> Sql {
+    Sql::new(
+        "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+         WHERE time >= ",
+    )
+    .time(now - HISTORY)
+    .push(" ORDER BY time ASC")
+}
+
+/// `None` with fewer than two points. `times` must be sorted.
+pub fn median_interval(times: &[DateTime<Utc>]) -> Option<Duration> {
+    let mut gaps: Vec<Duration> = times.windows(2).map(|w| w[1] - w[0]).collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort();
+    let mid = gaps.len() / 2;
+    Some(if gaps.len().is_multiple_of(2) {
+        (gaps[mid - 1] + gaps[mid]) / 2
+    } else {
+        gaps[mid]
+    })
+}
+
+/// Groups the results of both queries by device.
+pub fn build(
+    last_seen: BTreeMap<String, DateTime<Utc>>,
+    measurements: Vec<MeasurementWithTime>,
+) -> Bootstrap {
+    let mut bootstrap = Bootstrap::new();
+    for (device, time) in last_seen {
+        bootstrap.entry(device).or_default().last_seen = Some(time);
+    }
+    for m in measurements {
+        bootstrap
+            .entry(m.device.clone())
+            .or_default()
+            .recent
+            .push(m);
+    }
+    for history in bootstrap.values_mut() {
+        let times: Vec<DateTime<Utc>> = history.recent.iter().map(|m| m.time).collect();
+        history.median_interval = median_interval(&times);
+    }
+    bootstrap
+}
+
+pub async fn fetch(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    now: DateTime<Utc>,
+) -> Result<Bootstrap, Box<dyn Error>> {
+    let last_seen =
+        freshness::fetch_last_seen(influx_host, influx_token, influx_database, reqwest_client)
+            .await?;
+    let rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &history_query(now),
+    )
+    .await?;
+    let measurements = rows
+        .iter()
+        .map(InfluxMeasurementRow::to_measurement_with_time)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(build(last_seen, measurements))
+}
+
+/// Stored history followed by the live points, with stored points at or
+/// after the first live one left out.
+pub fn merge_live(
+    history: &[MeasurementWithTime],
+    live: &[MeasurementWithTime],
+) -> Vec<MeasurementWithTime> {
+    let mut merged: Vec<MeasurementWithTime> = match live.first() {
+        Some(first) => history
+            .iter()
+            .filter(|m| m.time < first.time)
+            .cloned()
+            .collect(),
+        None => history.to_vec(),
+    };
+    merged.extend_from_slice(live);
+    merged
+}
+
+/// Takes the last measurement times from `bootstrap`, keeping later ones
+/// already recorded live.
+pub fn record_last_seen(bootstrap: &Bootstrap, last_seen: &LastSeen) {
+    for (device, history) in bootstrap {
+        if let Some(time) = history.last_seen {
+            last_seen.record(device, time);
+        }
+    }
+}
+
+/// Warms up the alert detectors with the restored history. `live` are the
+/// measurements received since startup, oldest first.
+pub fn warm_up(bootstrap: &Bootstrap, live: &[MeasurementWithTime], alerter: &mut Alerter) {
+    for (device, history) in bootstrap {
+        let live: Vec<MeasurementWithTime> = live
+            .iter()
+            .filter(|m| &m.device == device)
+            .cloned()
+            .collect();
+        alerter.warm_up(device, &merge_live(&history.recent, &live));
+    }
+}
+
+pub fn log_restored(bootstrap: &Bootstrap) {
+    for (device, history) in bootstrap {
+        log::info!(
+            "Restored {}: last seen {}, {} points in the last day, every {}",
+            device,
+            history
+                .last_seen
+                .map_or_else(|| "never".to_string(), |t| t.to_rfc3339()),
+            history.recent.len(),
+            history
+                .median_interval
+                .map_or_else(|| "?".to_string(), |d| format!("{}s", d.num_seconds()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use axum::{Json, Router, extract::State, routing::post};
+    use std::sync::{Arc, Mutex};
+
+    fn measurement(device: &str, seconds: i64, co2: u16) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 21.5,
+            humidity: 40.0,
+            time: at(seconds),
+            device: device.to_string(),
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeInflux {
+        queries: Arc<Mutex<Vec<String>>>,
+        delay: std::time::Duration,
+    }
+
+    async fn fake_query(
+        State(fake): State<FakeInflux>,
+        Json(body): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        tokio::time::sleep(fake.delay).await;
+        let sql = body["q"].as_str().unwrap().to_string();
+        fake.queries.lock().unwrap().push(sql.clone());
+        if sql == freshness::QUERY {
+            return Json(serde_json::json!([
+                { "device": "bedroom", "last_seen": "2025-01-14T20:00:00" },
+                { "device": "kitchen", "last_seen": "2025-01-15T11:50:00" },
+            ]));
+        }
+        Json(serde_json::json!([
+            { "time": "2025-01-15T11:40:00", "co2_ppm": 600.0, "temperature_c": 21.0, "humidity_percent": 40.0, "device": "kitchen" },
+            { "time": "2025-01-15T11:45:00", "co2_ppm": 610.0, "temperature_c": 21.0, "humidity_percent": 40.0, "device": "kitchen" },
+            { "time": "2025-01-15T11:50:00", "co2_ppm": 620.0, "temperature_c": 21.0, "humidity_percent": 40.0, "device": "kitchen" },
+        ]))
+    }
+
+    async fn serve(fake: FakeInflux) -> String {
+        let app = Router::new()
+            .route("/api/v3/query_sql", post(fake_query))
+            .with_state(fake);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn median_ignores_outlying_gaps() {
+        let times = [at(0), at(300), at(600), at(4200), at(4500)];
+        assert_eq!(median_interval(&times), Some(Duration::seconds(300)));
+        assert_eq!(
+            median_interval(&[at(0), at(240), at(540)]),
+            Some(Duration::seconds(270))
+        );
+        assert_eq!(median_interval(&[at(0)]), None);
+        assert_eq!(median_interval(&[]), None);
+    }
+
+    #[test]
+    fn live_points_replace_stored_ones_from_their_time_on() {
+        let history = [
+            measurement("kitchen", -600, 600),
+            measurement("kitchen", -300, 610),
+            measurement("kitchen", 0, 620),
+        ];
+        // The receiver already stored the last point itself
+        let live = [
+            measurement("kitchen", 0, 620),
+            measurement("kitchen", 300, 630),
+        ];
+        let merged: Vec<(i64, u16)> = merge_live(&history, &live)
+            .iter()
+            .map(|m| ((m.time - at(0)).num_seconds(), m.co2))
+            .collect();
+        assert_eq!(merged, vec![(-600, 600), (-300, 610), (0, 620), (300, 630)]);
+        assert_eq!(merge_live(&history, &[]).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reconstructs_devices_from_influx() {
+        let fake = FakeInflux::default();
+        let host = serve(fake.clone()).await;
+        let bootstrap = fetch(&host, "token", "db", &reqwest::Client::new(), at(0))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *fake.queries.lock().unwrap(),
+            vec![
+                freshness::QUERY.to_string(),
+                "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+                 WHERE time >= '2025-01-14T12:00:00+00:00' ORDER BY time ASC"
+                    .to_string(),
+            ]
+        );
+        assert_eq!(bootstrap.len(), 2);
+        let kitchen = &bootstrap["kitchen"];
+        assert_eq!(kitchen.last_seen, Some(at(-600)));
+        assert_eq!(kitchen.median_interval, Some(Duration::minutes(5)));
+        assert_eq!(kitchen.recent.len(), 3);
+        // Quiet for the whole day: known, but without history
+        let bedroom = &bootstrap["bedroom"];
+        assert_eq!(bedroom.last_seen, Some(at(-16 * 3600)));
+        assert_eq!(bedroom.median_interval, None);
+        assert!(bedroom.recent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn late_results_dont_override_live_updates() {
+        let fake = FakeInflux {
+            delay: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let host = serve(fake).await;
+        let client = reqwest::Client::new();
+        let fetch = fetch(&host, "token", "db", &client, at(0));
+        tokio::pin!(fetch);
+        // The receiver gives up waiting and carries on
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(20), &mut fetch)
+                .await
+                .is_err()
+        );
+        let last_seen = LastSeen::new();
+        last_seen.record("kitchen", measurement("kitchen", 60, 640).time);
+
+        let bootstrap = fetch.await.unwrap();
+        record_last_seen(&bootstrap, &last_seen);
+        let snapshot = last_seen.snapshot();
+        assert_eq!(snapshot["kitchen"], at(60));
+        assert_eq!(snapshot["bedroom"], at(-16 * 3600));
+    }
+}
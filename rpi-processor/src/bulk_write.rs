@@ -0,0 +1,516 @@
+//! Batched writes for the bulk paths: reference imports, archive restores
+//! and the hourly aggregates rebuilt at startup.
+//!
+//! `BulkWriter` takes points from an iterator, writes them in chunks with
+//! `RetryPolicy` and reports `Progress` after every chunk. With `idempotent`
+//! set, each chunk first asks the store which of its series and timestamps
+//! already exist, with one range query per measurement in the chunk, and
+//! only writes the rest, so an interrupted import or restore can simply be
+//! run again.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::fetcher::{Sql, query_rows};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 500;
+
+/// Series tag value and time, which identify a point within a measurement
+pub type PointKey = (String, DateTime<Utc>);
+
+/// One line-protocol point, with the tag and time that identify it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: &'static str,
+    /// The tag telling series apart, e.g. `device`
+    pub series_tag: &'static str,
+    pub series: String,
+    pub time: DateTime<Utc>,
+    pub line: String,
+}
+
+impl Point {
+    fn key(&self) -> PointKey {
+        (self.series.clone(), self.time)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    /// Worth another attempt: the connection failed, or the server was
+    /// overloaded or broken
+    Retryable(String),
+    Fatal(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Retryable(message) | WriteError::Fatal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for WriteError {}
+
+/// Where the points go; InfluxDB outside of tests.
+#[allow(async_fn_in_trait)]
+pub trait PointStore {
+    /// Series and times already stored in `measurement` between `from` and
+    /// `to`, both inclusive.
+    async fn existing(
+        &self,
+        measurement: &'static str,
+        series_tag: &'static str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<HashSet<PointKey>, Box<dyn Error>>;
+
+    async fn write(&self, lines: &[String]) -> Result<(), WriteError>;
+}
+
+#[derive(Clone, Copy)]
+pub struct InfluxStore<'a> {
+    pub influx_host: &'a str,
+    pub influx_token: &'a str,
+    pub influx_database: &'a str,
+    pub reqwest_client: &'a reqwest::Client,
+}
+
+impl PointStore for InfluxStore<'_> {
+    async fn existing(
+        &self,
+        measurement: &'static str,
+        series_tag: &'static str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<HashSet<PointKey>, Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct KeyRow {
+            time: String,
+            series: String,
+        }
+
+        let rows: Vec<KeyRow> = match query_rows(
+            self.influx_host,
+            self.influx_token,
+            self.influx_database,
+            self.reqwest_client,
+            &Sql::new("SELECT time, ")
+                .push(series_tag)
+                .push(" AS series FROM ")
+                .push(measurement)
+                .push(" WHERE time >= ")
+                .time(from)
+                .push(" AND time <= ")
+                .time(to),
+        )
+        .await
+        {
+            Ok(rows) => rows,
+            // The table doesn't exist before its first write
+            Err(e) if e.to_string().contains("not found") => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        rows.into_iter()
+            .map(|row| {
+                let time = if row.time.ends_with('Z') {
+                    row.time
+                } else {
+                    format!("{}Z", row.time)
+                };
+                Ok((
+                    row.series,
+                    DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+                ))
+            })
+            .collect()
+    }
+
+    async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+        let response = self
+            .reqwest_client
+            .post(format!(
+                "{}/api/v3/write_lp?db={}",
+                self.influx_host, self.influx_database
+            ))
+            .body(lines.join("\n"))
+            .bearer_auth(self.influx_token)
+            .send()
+            .await
+            .map_err(|e| WriteError::Retryable(e.to_string()))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            let message = format!("InfluxDB write failed: {} - {}", status, error_text);
+            return Err(
+                if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    WriteError::Retryable(message)
+                } else {
+                    WriteError::Fatal(message)
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// How often a chunk is tried, waiting `delay` before the first retry and
+/// twice as long before each later one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub chunks: usize,
+    /// Points taken from the input so far
+    pub seen: usize,
+    pub written: usize,
+    /// Already stored, only counted when idempotent
+    pub skipped: usize,
+    /// Known when the input iterator reports an exact length
+    pub total: Option<usize>,
+}
+
+/// A progress callback that logs one line per chunk.
+pub fn log_progress(label: &str) -> impl FnMut(&Progress) + '_ {
+    move |p| match p.total {
+        Some(total) => log::info!(
+            "{}: {}/{} points, {} written, {} already stored",
+            label,
+            p.seen,
+            total,
+            p.written,
+            p.skipped
+        ),
+        None => log::info!(
+            "{}: {} points, {} written, {} already stored",
+            label,
+            p.seen,
+            p.written,
+            p.skipped
+        ),
+    }
+}
+
+pub struct BulkWriter<S> {
+    pub store: S,
+    pub chunk_size: usize,
+    /// Skip points whose series and time are already stored
+    pub idempotent: bool,
+    pub retry: RetryPolicy,
+}
+
+impl<S: PointStore> BulkWriter<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            idempotent: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Writes everything and returns the final progress. Chunks before a
+    /// failed one stay written.
+    pub async fn write(
+        &self,
+        points: impl IntoIterator<Item = Point>,
+        mut report: impl FnMut(&Progress),
+    ) -> Result<Progress, Box<dyn Error>> {
+        let mut points = points.into_iter();
+        let (lower, upper) = points.size_hint();
+        let mut progress = Progress {
+            total: (Some(lower) == upper).then_some(lower),
+            ..Default::default()
+        };
+        loop {
+            let chunk: Vec<Point> = points.by_ref().take(self.chunk_size.max(1)).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            let chunk_len = chunk.len();
+            progress.chunks += 1;
+            progress.seen += chunk_len;
+
+            let lines = if self.idempotent {
+                self.unstored(chunk).await?
+            } else {
+                chunk.into_iter().map(|p| p.line).collect()
+            };
+            progress.skipped += chunk_len - lines.len();
+            if !lines.is_empty() {
+                self.write_with_retry(&lines).await?;
+                progress.written += lines.len();
+            }
+            report(&progress);
+        }
+        Ok(progress)
+    }
+
+    /// Lines of the points that aren't stored yet.
+    async fn unstored(&self, chunk: Vec<Point>) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut ranges = BTreeMap::new();
+        for point in &chunk {
+            let (from, to) = ranges
+                .entry((point.measurement, point.series_tag))
+                .or_insert((point.time, point.time));
+            *from = point.time.min(*from);
+            *to = point.time.max(*to);
+        }
+        let mut stored = BTreeMap::new();
+        for ((measurement, series_tag), (from, to)) in ranges {
+            let existing = self
+                .store
+                .existing(measurement, series_tag, from, to)
+                .await?;
+            stored.insert(measurement, existing);
+        }
+        Ok(chunk
+            .into_iter()
+            .filter(|p| !stored[p.measurement].contains(&p.key()))
+            .map(|p| p.line)
+            .collect())
+    }
+
+    async fn write_with_retry(&self, lines: &[String]) -> Result<(), WriteError> {
+        let mut delay = self.retry.delay;
+        let mut attempt = 1;
+        loop {
+            match self.store.write(lines).await {
+                Err(WriteError::Retryable(message)) if attempt < self.retry.attempts => {
+                    log::warn!(
+                        "Write of {} points failed (attempt {}/{}), retrying in {:?}: {}",
+                        lines.len(),
+                        attempt,
+                        self.retry.attempts,
+                        delay,
+                        message
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at_minute as at;
+    use std::cell::RefCell;
+
+    fn point(device: &str, minutes: i64) -> Point {
+        Point {
+            measurement: "scd40_data",
+            series_tag: "device",
+            series: device.to_string(),
+            time: at(minutes),
+            line: format!("scd40_data,device={} co2_ppm=500 {}", device, minutes),
+        }
+    }
+
+    /// Keeps written points in memory and fails the next `failures` writes.
+    #[derive(Default)]
+    struct MockStore {
+        stored: RefCell<HashSet<PointKey>>,
+        queries: RefCell<Vec<(DateTime<Utc>, DateTime<Utc>)>>,
+        writes: RefCell<Vec<Vec<String>>>,
+        failures: RefCell<Vec<WriteError>>,
+    }
+
+    impl MockStore {
+        fn with_stored(points: &[Point]) -> Self {
+            let store = Self::default();
+            store
+                .stored
+                .borrow_mut()
+                .extend(points.iter().map(Point::key));
+            store
+        }
+    }
+
+    impl PointStore for &MockStore {
+        async fn existing(
+            &self,
+            _measurement: &'static str,
+            _series_tag: &'static str,
+            from: DateTime<Utc>,
+            to: DateTime<Utc>,
+        ) -> Result<HashSet<PointKey>, Box<dyn Error>> {
+            self.queries.borrow_mut().push((from, to));
+            Ok(self
+                .stored
+                .borrow()
+                .iter()
+                .filter(|(_, time)| *time >= from && *time <= to)
+                .cloned()
+                .collect())
+        }
+
+        async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+            if let Some(error) = self.failures.borrow_mut().pop() {
+                return Err(error);
+            }
+            self.writes.borrow_mut().push(lines.to_vec());
+            Ok(())
+        }
+    }
+
+    fn writer(store: &MockStore, chunk_size: usize, idempotent: bool) -> BulkWriter<&MockStore> {
+        BulkWriter {
+            chunk_size,
+            idempotent,
+            retry: RetryPolicy {
+                attempts: 3,
+                delay: Duration::ZERO,
+            },
+            ..BulkWriter::new(store)
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_in_chunks_and_reports_each_one() {
+        let store = MockStore::default();
+        let points: Vec<Point> = (0..7).map(|i| point("kitchen", i)).collect();
+        let mut reports = Vec::new();
+        let done = writer(&store, 3, false)
+            .write(points, |p| reports.push(*p))
+            .await
+            .unwrap();
+
+        let sizes: Vec<usize> = store.writes.borrow().iter().map(Vec::len).collect();
+        assert_eq!(sizes, [3, 3, 1]);
+        assert!(store.queries.borrow().is_empty());
+        assert_eq!(
+            reports
+                .iter()
+                .map(|p| (p.chunks, p.seen, p.written))
+                .collect::<Vec<_>>(),
+            [(1, 3, 3), (2, 6, 6), (3, 7, 7)]
+        );
+        assert_eq!(
+            done,
+            Progress {
+                chunks: 3,
+                seen: 7,
+                written: 7,
+                skipped: 0,
+                total: Some(7),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn idempotent_writes_skip_stored_points_with_one_query_per_chunk() {
+        let store = MockStore::with_stored(&[
+            point("kitchen", 0),
+            point("kitchen", 1),
+            point("kitchen", 2),
+            point("kitchen", 4),
+            // Same time, other series
+            point("bedroom", 3),
+        ]);
+        let points: Vec<Point> = (0..6).map(|i| point("kitchen", i)).collect();
+        let mut reports = Vec::new();
+        let done = writer(&store, 3, true)
+            .write(points, |p| reports.push(*p))
+            .await
+            .unwrap();
+
+        assert_eq!(*store.queries.borrow(), [(at(0), at(2)), (at(3), at(5))]);
+        // The first chunk is all stored and not written at all
+        assert_eq!(
+            *store.writes.borrow(),
+            [vec![point("kitchen", 3).line, point("kitchen", 5).line]]
+        );
+        assert_eq!(
+            reports
+                .iter()
+                .map(|p| (p.written, p.skipped))
+                .collect::<Vec<_>>(),
+            [(0, 3), (2, 4)]
+        );
+        assert_eq!((done.written, done.skipped, done.seen), (2, 4, 6));
+    }
+
+    #[tokio::test]
+    async fn unknown_length_has_no_total() {
+        let store = MockStore::default();
+        let points = (0..4)
+            .map(|i| point("kitchen", i))
+            .filter(|p| p.time < at(3));
+        let done = writer(&store, 10, false)
+            .write(points, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(done.total, None);
+        assert_eq!(done.written, 3);
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_are_retried_up_to_the_limit() {
+        let store = MockStore::default();
+        *store.failures.borrow_mut() = vec![
+            WriteError::Retryable("503".to_string()),
+            WriteError::Retryable("connection reset".to_string()),
+        ];
+        let done = writer(&store, 10, false)
+            .write([point("kitchen", 0)], |_| {})
+            .await
+            .unwrap();
+        assert_eq!(done.written, 1);
+        assert_eq!(store.writes.borrow().len(), 1);
+
+        *store.failures.borrow_mut() = vec![WriteError::Retryable("503".to_string()); 3];
+        let error = writer(&store, 10, false)
+            .write([point("kitchen", 1)], |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "503");
+        assert_eq!(store.writes.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fatal_failure_stops_after_the_chunks_already_written() {
+        let store = MockStore::default();
+        let points: Vec<Point> = (0..4).map(|i| point("kitchen", i)).collect();
+        let mut reports = Vec::new();
+        // Only the second write fails
+        let writer = writer(&store, 2, false);
+        let result = writer
+            .write(points, |p| {
+                reports.push(*p);
+                store
+                    .failures
+                    .borrow_mut()
+                    .push(WriteError::Fatal("400 Bad Request".to_string()));
+            })
+            .await;
+        assert_eq!(result.unwrap_err().to_string(), "400 Bad Request");
+        assert_eq!(store.writes.borrow().len(), 1);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].written, 2);
+    }
+}
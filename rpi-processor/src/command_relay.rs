@@ -0,0 +1,964 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use rumqttc::{Client, QoS};
+use serde::Serialize;
+use shared_types::{CommandEnvelope, DeviceCommand, DeviceMessage, DevicePayload, topics};
+use tokio::sync::{Mutex, mpsc};
+
+/// Wake interval assumed for devices we haven't seen wake up twice yet
+/// (matches the firmware's default deep sleep time).
+const DEFAULT_WAKE_INTERVAL_SECONDS: i64 = 300;
+/// Number of recent arrivals used to learn a device's wake interval
+const ARRIVAL_HISTORY: usize = 8;
+/// `issued_by` of every relayed command, shown by the commander
+pub const RELAY_OPERATOR: &str = "relay";
+
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Wake cycles to wait for an answer before giving up on a command
+    pub timeout_cycles: i32,
+    /// Clear the device's retained command when a command times out and
+    /// nothing newer was submitted for it
+    pub clear_retained_on_timeout: bool,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            timeout_cycles: 2,
+            clear_retained_on_timeout: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CommandState {
+    /// Published, device hasn't answered yet
+    Pending,
+    /// Device started a long-running command (FRC)
+    InProgress,
+    Succeeded,
+    Failed {
+        detail: String,
+    },
+    /// A newer command replaced this one on the retained topic before it ran
+    Superseded,
+    TimedOut,
+}
+
+impl CommandState {
+    pub fn is_open(&self) -> bool {
+        matches!(self, CommandState::Pending | CommandState::InProgress)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RelayedCommand {
+    /// Also the command's `id` on the wire, so answers name it in
+    /// `in_reply_to`
+    pub id: u32,
+    pub device: String,
+    pub command: DeviceCommand,
+    pub submitted_at: DateTime<Utc>,
+    /// When the device is next expected to wake and pick the command up
+    pub expected_execution: Option<DateTime<Utc>>,
+    pub deadline: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub state: CommandState,
+}
+
+impl RelayedCommand {
+    /// What is published: the command with its id, the relay as operator,
+    /// and a TTL ending at the deadline, after which waiting for an answer
+    /// is given up and a device waking later should drop it.
+    pub fn envelope(&self) -> CommandEnvelope {
+        let ttl = (self.deadline - self.submitted_at).num_seconds().max(0) as u64;
+        self.command
+            .clone()
+            .with_id(self.id)
+            .issued_by(RELAY_OPERATOR)
+            .expires(self.submitted_at.timestamp().max(0) as u64, ttl)
+    }
+}
+
+/// API representation of a relayed command
+#[derive(Serialize)]
+pub struct RelayedCommandView {
+    pub id: u32,
+    pub device: String,
+    pub command: DeviceCommand,
+    pub submitted_at: String,
+    pub expected_execution: Option<String>,
+    pub deadline: String,
+    pub resolved_at: Option<String>,
+    #[serde(flatten)]
+    pub state: CommandState,
+}
+
+impl From<&RelayedCommand> for RelayedCommandView {
+    fn from(entry: &RelayedCommand) -> Self {
+        Self {
+            id: entry.id,
+            device: entry.device.clone(),
+            command: entry.command.clone(),
+            submitted_at: entry.submitted_at.to_rfc3339(),
+            expected_execution: entry.expected_execution.map(|t| t.to_rfc3339()),
+            deadline: entry.deadline.to_rfc3339(),
+            resolved_at: entry.resolved_at.map(|t| t.to_rfc3339()),
+            state: entry.state.clone(),
+        }
+    }
+}
+
+/// Outcome of matching a device payload against a command
+#[derive(Debug, PartialEq)]
+enum Answer {
+    Started,
+    Success,
+    Failure(String),
+}
+
+/// Whether `payload` answers `command`, and how.
+fn answer_for(command: &DeviceCommand, payload: &DevicePayload) -> Option<Answer> {
+    match (command, payload) {
+        // Acked before it runs, then answered as usual
+        (
+            _,
+            DevicePayload::CommandAck {
+                cmd,
+                accepted: true,
+                ..
+            },
+        ) if *cmd == command.name() => Some(Answer::Started),
+        (_, DevicePayload::CommandAck { cmd, detail, .. }) if *cmd == command.name() => {
+            Some(Answer::Failure(match detail {
+                Some(detail) => format!("rejected: {}", detail),
+                None => "rejected".to_string(),
+            }))
+        }
+        (DeviceCommand::NoOp, DevicePayload::MeasurementSuccess { .. }) => Some(Answer::Success),
+        (DeviceCommand::NoOp, DevicePayload::Error { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::StartFrc { .. }, DevicePayload::FrcStart { .. }) => Some(Answer::Started),
+        (DeviceCommand::StartFrc { .. }, DevicePayload::FrcSuccess { .. }) => Some(Answer::Success),
+        (DeviceCommand::StartFrc { .. }, DevicePayload::FrcError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::SetTempOffset { .. }, DevicePayload::SetOffsetSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (
+            DeviceCommand::SetTempOffset { .. },
+            DevicePayload::SetOffsetRateLimited { limit, .. },
+        ) => Some(Answer::Failure(format!(
+            "applied but not saved, EEPROM write limit of {} per day reached",
+            limit
+        ))),
+        (DeviceCommand::SetTempOffset { .. }, DevicePayload::SetOffsetError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::GetTempOffset, DevicePayload::GetOffsetSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::GetTempOffset, DevicePayload::GetOffsetError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::SetDeepSleepTime { .. }, DevicePayload::SetDeepSleepTimeSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (
+            DeviceCommand::SetDeepSleepTime { .. },
+            DevicePayload::SetDeepSleepTimeError { detail, .. },
+        ) => Some(Answer::Failure(detail.clone())),
+        (DeviceCommand::GetDeepSleepTime, DevicePayload::GetDeepSleepTimeSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::GetDeepSleepTime, DevicePayload::GetDeepSleepTimeError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::SetMqttPolicy { .. }, DevicePayload::SetMqttPolicySuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::SetMqttPolicy { .. }, DevicePayload::SetMqttPolicyError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        // On trial until confirmed, then answered as usual
+        (
+            DeviceCommand::SetDeepSleepTime { .. } | DeviceCommand::SetMqttPolicy { .. },
+            DevicePayload::PendingConfirmation { command: name, .. },
+        ) if *name == command.name() => Some(Answer::Started),
+        (
+            DeviceCommand::SetDeepSleepTime { .. } | DeviceCommand::SetMqttPolicy { .. },
+            DevicePayload::ConfigRolledBack { command: name, .. },
+        ) if *name == command.name() => Some(Answer::Failure(
+            "rolled back, the device never got a confirmation".to_string(),
+        )),
+        (
+            DeviceCommand::ConfirmConfig { .. },
+            DevicePayload::SetDeepSleepTimeSuccess { .. }
+            | DevicePayload::SetMqttPolicySuccess { .. },
+        ) => Some(Answer::Success),
+        (DeviceCommand::ConfirmConfig { .. }, DevicePayload::ConfirmConfigError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::SelfTest, DevicePayload::SelfTestResult { passed: true, .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::SelfTest, DevicePayload::SelfTestResult { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::FactoryReset { .. }, DevicePayload::FactoryResetSuccess) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::FactoryReset { .. }, DevicePayload::FactoryResetError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::GetSerialNumber, DevicePayload::SerialNumber { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::GetSerialNumber, DevicePayload::Error { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::Reboot, DevicePayload::Rebooting { .. }) => Some(Answer::Success),
+        (DeviceCommand::InjectFault { .. }, DevicePayload::FaultArmed { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::GetFirmwareInfo, DevicePayload::FirmwareInfo { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::SetLogLevel { .. }, DevicePayload::SetLogLevelSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::SetLogLevel { .. }, DevicePayload::SetLogLevelError { detail, .. }) => {
+            Some(Answer::Failure(detail.clone()))
+        }
+        (DeviceCommand::GetLogLevel, DevicePayload::GetLogLevelSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (DeviceCommand::SetAdaptiveMode { .. }, DevicePayload::SetAdaptiveModeSuccess { .. }) => {
+            Some(Answer::Success)
+        }
+        (
+            DeviceCommand::SetAdaptiveMode { .. },
+            DevicePayload::SetAdaptiveModeError { detail, .. },
+        ) => Some(Answer::Failure(detail.clone())),
+        (
+            DeviceCommand::SetAsc { .. } | DeviceCommand::SetAltitude { .. },
+            DevicePayload::PersistRateLimited {
+                command: name,
+                limit,
+                ..
+            },
+        ) if *name == command.name() => Some(Answer::Failure(format!(
+            "applied but not saved, EEPROM write limit of {} per day reached",
+            limit
+        ))),
+        (DeviceCommand::SetAsc { .. }, DevicePayload::AscSetSuccess { .. })
+        | (DeviceCommand::GetAsc, DevicePayload::AscGetSuccess { .. }) => Some(Answer::Success),
+        (
+            DeviceCommand::SetAsc { .. } | DeviceCommand::GetAsc,
+            DevicePayload::AscError { detail, .. },
+        ) => Some(Answer::Failure(detail.clone())),
+        (DeviceCommand::SetAltitude { .. }, DevicePayload::AltitudeSetSuccess { .. })
+        | (DeviceCommand::GetAltitude, DevicePayload::AltitudeGetSuccess { .. })
+        | (
+            DeviceCommand::SetAmbientPressure { .. },
+            DevicePayload::AmbientPressureSetSuccess { .. },
+        ) => Some(Answer::Success),
+        (
+            DeviceCommand::SetAltitude { .. } | DeviceCommand::GetAltitude,
+            DevicePayload::AltitudeError { detail, .. },
+        )
+        | (
+            DeviceCommand::SetAmbientPressure { .. },
+            DevicePayload::AmbientPressureError { detail, .. },
+        ) => Some(Answer::Failure(detail.clone())),
+        _ => None,
+    }
+}
+
+/// Learns when a device wakes up from the arrival times of its messages
+#[derive(Debug, Default)]
+struct WakeTracker {
+    arrivals: VecDeque<DateTime<Utc>>,
+    /// Interval the device reported itself (deep sleep time, or the sleep it
+    /// announced before the last one)
+    reported_interval: Option<Duration>,
+}
+
+impl WakeTracker {
+    fn record(&mut self, now: DateTime<Utc>) {
+        // Several messages from one wake (e.g. FRC progress) count as one arrival
+        if let Some(last) = self.arrivals.back()
+            && now - *last < Duration::seconds(60)
+        {
+            return;
+        }
+        if self.arrivals.len() == ARRIVAL_HISTORY {
+            self.arrivals.pop_front();
+        }
+        self.arrivals.push_back(now);
+    }
+
+    fn interval(&self) -> Duration {
+        if let Some(reported) = self.reported_interval {
+            return reported;
+        }
+        let mut gaps: Vec<Duration> = self
+            .arrivals
+            .iter()
+            .zip(self.arrivals.iter().skip(1))
+            .map(|(a, b)| *b - *a)
+            .collect();
+        if gaps.is_empty() {
+            return Duration::seconds(DEFAULT_WAKE_INTERVAL_SECONDS);
+        }
+        gaps.sort();
+        gaps[gaps.len() / 2]
+    }
+
+    fn next_wake(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let last = *self.arrivals.back()?;
+        let interval = self.interval();
+        if interval <= Duration::zero() {
+            return None;
+        }
+        let mut next = last + interval;
+        while next < now {
+            next += interval;
+        }
+        Some(next)
+    }
+}
+
+/// Tracks commands relayed to sleeping devices until they are answered.
+#[derive(Debug)]
+pub struct CommandRelay {
+    config: RelayConfig,
+    next_id: u32,
+    commands: Vec<RelayedCommand>,
+    wake_trackers: HashMap<String, WakeTracker>,
+}
+
+impl CommandRelay {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            next_id: 1,
+            commands: Vec::new(),
+            wake_trackers: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    /// Queue a command. Any open command for the same device is superseded,
+    /// since the retained topic only holds one command at a time.
+    pub fn submit(
+        &mut self,
+        device: &str,
+        command: DeviceCommand,
+        now: DateTime<Utc>,
+    ) -> RelayedCommand {
+        for entry in self
+            .commands
+            .iter_mut()
+            .filter(|e| e.device == device && e.state == CommandState::Pending)
+        {
+            entry.state = CommandState::Superseded;
+            entry.resolved_at = Some(now);
+        }
+
+        let tracker = self.wake_trackers.entry(device.to_string()).or_default();
+        let interval = tracker.interval();
+        let entry = RelayedCommand {
+            id: self.next_id,
+            device: device.to_string(),
+            command,
+            submitted_at: now,
+            expected_execution: tracker.next_wake(now),
+            deadline: now + interval * self.config.timeout_cycles,
+            resolved_at: None,
+            state: CommandState::Pending,
+        };
+        self.next_id += 1;
+        self.commands.push(entry.clone());
+        entry
+    }
+
+    /// Feed every message received from a device. Returns the ids of the
+    /// commands whose state changed.
+    ///
+    /// An answer naming a command in `in_reply_to` only resolves that
+    /// command, and one naming a command sent from elsewhere none. Answers
+    /// without an id, from firmware that predates them, go to the oldest
+    /// open command they fit.
+    pub fn observe(&mut self, message: &DeviceMessage, now: DateTime<Utc>) -> Vec<u32> {
+        let tracker = self
+            .wake_trackers
+            .entry(message.device.clone())
+            .or_default();
+        tracker.record(now);
+        if let DevicePayload::SetDeepSleepTimeSuccess { seconds }
+        | DevicePayload::GetDeepSleepTimeSuccess { seconds }
+        | DevicePayload::NextWake {
+            sleep_seconds: seconds,
+            ..
+        } = message.payload
+        {
+            tracker.reported_interval = Some(Duration::seconds(seconds as i64));
+        }
+
+        let mut changed = Vec::new();
+        // Oldest open command first: the device runs whatever was retained
+        if let Some((entry, answer)) = self
+            .commands
+            .iter_mut()
+            .filter(|e| e.device == message.device && e.state.is_open())
+            .filter(|e| message.in_reply_to.is_none_or(|id| id == e.id))
+            .find_map(|e| answer_for(&e.command, &message.payload).map(|a| (e, a)))
+        {
+            match answer {
+                Answer::Started => entry.state = CommandState::InProgress,
+                Answer::Success => {
+                    entry.state = CommandState::Succeeded;
+                    entry.resolved_at = Some(now);
+                }
+                Answer::Failure(detail) => {
+                    entry.state = CommandState::Failed { detail };
+                    entry.resolved_at = Some(now);
+                }
+            }
+            changed.push(entry.id);
+        }
+        changed
+    }
+
+    /// The `confirm_config` for `message` if it puts one of the commands in
+    /// `changed` on trial, see `shared_types::config_trial`. The answer
+    /// itself shows the device still reaches the broker with the setting.
+    pub fn confirmation_for(
+        &self,
+        message: &DeviceMessage,
+        changed: &[u32],
+    ) -> Option<DeviceCommand> {
+        let DevicePayload::PendingConfirmation { id, command, .. } = &message.payload else {
+            return None;
+        };
+        self.commands
+            .iter()
+            .any(|e| changed.contains(&e.id) && e.command.name() == command.as_str())
+            .then_some(DeviceCommand::ConfirmConfig { pending_id: *id })
+    }
+
+    /// Time out commands that went unanswered past their deadline.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<RelayedCommand> {
+        let mut expired = Vec::new();
+        for entry in self
+            .commands
+            .iter_mut()
+            .filter(|e| e.state.is_open() && e.deadline < now)
+        {
+            entry.state = CommandState::TimedOut;
+            entry.resolved_at = Some(now);
+            expired.push(entry.clone());
+        }
+        expired
+    }
+
+    /// Whether the retained command on `entry`'s device topic is still
+    /// `entry`'s own, i.e. nothing was submitted for the device since.
+    pub fn holds_retained(&self, entry: &RelayedCommand) -> bool {
+        !self
+            .commands
+            .iter()
+            .any(|e| e.device == entry.device && e.id > entry.id)
+    }
+
+    pub fn commands_for(&self, device: &str) -> Vec<&RelayedCommand> {
+        self.commands
+            .iter()
+            .filter(|e| e.device == device)
+            .collect()
+    }
+}
+
+/// A retained publish the MQTT side should perform on behalf of the relay
+#[derive(Debug)]
+pub struct OutgoingCommand {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Shared handle used by the web API (submit) and the live receiver (observe)
+#[derive(Clone)]
+pub struct RelayHandle {
+    pub relay: Arc<Mutex<CommandRelay>>,
+    pub outbox: mpsc::UnboundedSender<OutgoingCommand>,
+}
+
+impl RelayHandle {
+    pub fn new(config: RelayConfig) -> (Self, mpsc::UnboundedReceiver<OutgoingCommand>) {
+        let (outbox, outbox_rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                relay: Arc::new(Mutex::new(CommandRelay::new(config))),
+                outbox,
+            },
+            outbox_rx,
+        )
+    }
+
+    /// Queue `confirmation` for publishing without tracking it: the command
+    /// it confirms is resolved by the answer.
+    pub fn confirm(
+        &self,
+        confirmation: &DeviceCommand,
+        topic: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = confirmation.to_json()?.into_bytes();
+        self.outbox.send(OutgoingCommand { topic, payload })?;
+        Ok(())
+    }
+
+    /// Record the command and queue it for publishing on `device`'s own
+    /// command topic, so no other device picks it up
+    pub async fn submit(
+        &self,
+        device: &str,
+        command: DeviceCommand,
+    ) -> Result<RelayedCommand, Box<dyn std::error::Error>> {
+        let mut relay = self.relay.lock().await;
+        let entry = relay.submit(device, command, Utc::now());
+        let payload = entry.envelope().to_json()?.into_bytes();
+        self.outbox.send(OutgoingCommand {
+            topic: topics::command_topic(device),
+            payload,
+        })?;
+        Ok(entry)
+    }
+}
+
+/// Publish queued commands retained and time out unanswered ones.
+/// Runs alongside the live receiver's MQTT event loop.
+pub fn spawn_publisher(
+    client: Client,
+    handle: RelayHandle,
+    mut outbox_rx: mpsc::UnboundedReceiver<OutgoingCommand>,
+) {
+    tokio::spawn(async move {
+        let mut expiry_tick = tokio::time::interval(std::time::Duration::from_secs(30));
+        loop {
+            tokio::select! {
+                outgoing = outbox_rx.recv() => {
+                    let Some(outgoing) = outgoing else { break };
+                    log::info!("Relaying retained command to '{}'", outgoing.topic);
+                    if let Err(e) =
+                        client.try_publish(&outgoing.topic, QoS::AtLeastOnce, true, outgoing.payload)
+                    {
+                        log::error!("Failed to relay command: {:?}", e);
+                    }
+                }
+                _ = expiry_tick.tick() => {
+                    let mut relay = handle.relay.lock().await;
+                    for entry in relay.expire(Utc::now()) {
+                        log::warn!(
+                            "Command {} for '{}' timed out without an answer: {:?}",
+                            entry.id,
+                            entry.device,
+                            entry.command
+                        );
+                        // Unless a newer command for the device replaced it
+                        if relay.config().clear_retained_on_timeout
+                            && relay.holds_retained(&entry)
+                        {
+                            let topic = topics::command_topic(&entry.device);
+                            if let Err(e) =
+                                client.try_publish(topic, QoS::AtLeastOnce, true, Vec::new())
+                            {
+                                log::error!("Failed to clear retained command: {:?}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::ErrorCode;
+    use shared_types::mqtt_policy::PayloadClass;
+
+    fn t(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn msg(payload: DevicePayload) -> DeviceMessage {
+        DeviceMessage::new("dev", payload)
+    }
+
+    fn relay_with_wakes(wakes: &[i64]) -> CommandRelay {
+        let mut relay = CommandRelay::new(RelayConfig::default());
+        for &w in wakes {
+            relay.observe(&msg(DevicePayload::measurement(500, 21.0, 40.0)), t(w));
+        }
+        relay
+    }
+
+    #[test]
+    fn learns_interval_and_predicts_next_wake() {
+        let mut relay = relay_with_wakes(&[0, 600, 1200, 1800]);
+        let entry = relay.submit("dev", DeviceCommand::GetTempOffset, t(1900));
+        assert_eq!(entry.expected_execution, Some(t(2400)));
+        assert_eq!(entry.deadline, t(1900 + 1200));
+    }
+
+    #[test]
+    fn unknown_device_uses_default_interval() {
+        let mut relay = CommandRelay::new(RelayConfig::default());
+        let entry = relay.submit("dev", DeviceCommand::NoOp, t(0));
+        assert_eq!(entry.expected_execution, None);
+        assert_eq!(entry.deadline, t(2 * DEFAULT_WAKE_INTERVAL_SECONDS));
+    }
+
+    #[test]
+    fn reported_sleep_time_overrides_learned_interval() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        relay.observe(
+            &msg(DevicePayload::GetDeepSleepTimeSuccess { seconds: 900 }),
+            t(600),
+        );
+        let entry = relay.submit("dev", DeviceCommand::NoOp, t(700));
+        assert_eq!(entry.expected_execution, Some(t(1500)));
+    }
+
+    #[test]
+    fn announced_next_wake_replaces_the_sleep_time() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        relay.observe(
+            &msg(DevicePayload::GetDeepSleepTimeSuccess { seconds: 900 }),
+            t(600),
+        );
+        // Adaptive mode cut the next sleep short
+        relay.observe(
+            &msg(DevicePayload::NextWake {
+                sleep_seconds: 120,
+                adaptive: true,
+                delta_ppm: Some(180),
+            }),
+            t(600),
+        );
+        let entry = relay.submit("dev", DeviceCommand::NoOp, t(650));
+        assert_eq!(entry.expected_execution, Some(t(720)));
+    }
+
+    #[test]
+    fn matching_answer_resolves_command() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let entry = relay.submit(
+            "dev",
+            DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true,
+            },
+            t(350),
+        );
+
+        // A routine measurement doesn't answer an offset command
+        assert!(
+            relay
+                .observe(&msg(DevicePayload::measurement(500, 21.0, 40.0)), t(600))
+                .is_empty()
+        );
+        assert_eq!(
+            relay.observe(
+                &msg(DevicePayload::SetOffsetSuccess {
+                    offset: 4.0,
+                    persisted: true
+                }),
+                t(900)
+            ),
+            vec![entry.id]
+        );
+        let stored = relay.commands_for("dev")[0];
+        assert_eq!(stored.state, CommandState::Succeeded);
+        assert_eq!(stored.resolved_at, Some(t(900)));
+    }
+
+    #[test]
+    fn a_setting_on_trial_is_confirmed_then_resolved_by_the_saved_answer() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let entry = relay.submit(
+            "dev",
+            DeviceCommand::SetDeepSleepTime { seconds: 900 },
+            t(350),
+        );
+        let pending = msg(DevicePayload::PendingConfirmation {
+            id: 41,
+            command: "set_deep_sleep_time".to_string(),
+            wakes_left: 3,
+        });
+        let changed = relay.observe(&pending, t(600));
+        assert_eq!(changed, vec![entry.id]);
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::InProgress);
+        assert_eq!(
+            relay.confirmation_for(&pending, &changed),
+            Some(DeviceCommand::ConfirmConfig { pending_id: 41 })
+        );
+        // Not for trials of commands sent from elsewhere
+        assert_eq!(relay.confirmation_for(&pending, &[]), None);
+
+        relay.observe(
+            &msg(DevicePayload::SetDeepSleepTimeSuccess { seconds: 900 }),
+            t(1500),
+        );
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::Succeeded);
+    }
+
+    #[test]
+    fn an_ack_starts_a_command_and_a_rejection_fails_it() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let frc = DeviceCommand::StartFrc { target_ppm: 422 };
+        relay.submit("dev", frc.clone(), t(350));
+        // Not an ack of this command
+        assert!(
+            relay
+                .observe(
+                    &msg(DevicePayload::command_accepted(&DeviceCommand::GetAsc)),
+                    t(600)
+                )
+                .is_empty()
+        );
+        relay.observe(&msg(DevicePayload::command_accepted(&frc)), t(600));
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::InProgress);
+        relay.observe(&msg(DevicePayload::frc_success(12)), t(800));
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::Succeeded);
+
+        let out_of_range = DeviceCommand::StartFrc { target_ppm: 5000 };
+        relay.submit("dev", out_of_range.clone(), t(900));
+        relay.observe(
+            &msg(DevicePayload::command_rejected(
+                &out_of_range,
+                "FRC target 5000 ppm is outside 400 to 2000 ppm",
+            )),
+            t(1200),
+        );
+        assert_eq!(
+            relay.commands_for("dev")[1].state,
+            CommandState::Failed {
+                detail: "rejected: FRC target 5000 ppm is outside 400 to 2000 ppm".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn a_rolled_back_setting_fails_its_command() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        relay.submit(
+            "dev",
+            DeviceCommand::SetMqttPolicy {
+                class: PayloadClass::Measurement,
+                qos: 0,
+                retain: true,
+            },
+            t(350),
+        );
+        // A trial of the other risky command is not this one's
+        let other = msg(DevicePayload::PendingConfirmation {
+            id: 7,
+            command: "set_deep_sleep_time".to_string(),
+            wakes_left: 3,
+        });
+        assert!(relay.observe(&other, t(600)).is_empty());
+
+        relay.observe(
+            &msg(DevicePayload::ConfigRolledBack {
+                id: 41,
+                command: "set_mqtt_policy".to_string(),
+            }),
+            t(1500),
+        );
+        assert!(matches!(
+            relay.commands_for("dev")[0].state,
+            CommandState::Failed { .. }
+        ));
+    }
+
+    #[test]
+    fn a_rate_limited_save_fails_only_its_own_command() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        relay.submit("dev", DeviceCommand::SetAsc { enabled: false }, t(350));
+        let limited = |command: &str| {
+            msg(DevicePayload::PersistRateLimited {
+                command: command.to_string(),
+                limit: 4,
+                retry_after_seconds: 3600,
+            })
+        };
+        assert!(relay.observe(&limited("set_altitude"), t(600)).is_empty());
+        relay.observe(&limited("set_asc"), t(600));
+        assert_eq!(
+            relay.commands_for("dev")[0].state,
+            CommandState::Failed {
+                detail: "applied but not saved, EEPROM write limit of 4 per day reached"
+                    .to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn frc_goes_through_in_progress_to_failed() {
+        let mut relay = relay_with_wakes(&[0]);
+        relay.submit("dev", DeviceCommand::StartFrc { target_ppm: 422 }, t(10));
+        relay.observe(&msg(DevicePayload::frc_start(422)), t(300));
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::InProgress);
+        relay.observe(
+            &msg(DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: "nack".to_string(),
+            }),
+            t(500),
+        );
+        assert_eq!(
+            relay.commands_for("dev")[0].state,
+            CommandState::Failed {
+                detail: "nack".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn new_command_supersedes_pending_one() {
+        let mut relay = relay_with_wakes(&[0]);
+        relay.submit("dev", DeviceCommand::GetTempOffset, t(10));
+        relay.submit("dev", DeviceCommand::GetDeepSleepTime, t(20));
+        let states: Vec<_> = relay
+            .commands_for("dev")
+            .iter()
+            .map(|e| e.state.clone())
+            .collect();
+        assert_eq!(
+            states,
+            vec![CommandState::Superseded, CommandState::Pending]
+        );
+    }
+
+    #[test]
+    fn unanswered_command_times_out_after_two_cycles() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        relay.submit("dev", DeviceCommand::GetTempOffset, t(310));
+        assert!(relay.expire(t(900)).is_empty());
+        let expired = relay.expire(t(911));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::TimedOut);
+        // Late answers no longer touch a timed out command
+        assert!(
+            relay
+                .observe(
+                    &msg(DevicePayload::GetOffsetSuccess { offset: 1.0 }),
+                    t(950)
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn only_the_latest_command_holds_the_retained_topic() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let frc = relay.submit("dev", DeviceCommand::StartFrc { target_ppm: 422 }, t(310));
+        relay.observe(&msg(DevicePayload::frc_start(422)), t(600));
+        let other = relay.submit("other", DeviceCommand::GetTempOffset, t(610));
+        assert!(relay.holds_retained(&frc));
+
+        // Queued while the FRC was still running
+        let offset = relay.submit("dev", DeviceCommand::GetTempOffset, t(620));
+        let expired = relay.expire(t(1000));
+        assert_eq!(expired[0].id, frc.id);
+        assert!(!relay.holds_retained(&expired[0]));
+        assert!(relay.holds_retained(&offset));
+        assert!(relay.holds_retained(&other));
+    }
+
+    #[tokio::test]
+    async fn commands_go_to_the_device_s_own_topic() {
+        let (handle, mut outbox_rx) = RelayHandle::new(RelayConfig::default());
+        handle
+            .submit("kitchen", DeviceCommand::GetTempOffset)
+            .await
+            .unwrap();
+        let outgoing = outbox_rx.try_recv().unwrap();
+        assert_eq!(outgoing.topic, "sensors/esp32/command/kitchen");
+        assert_ne!(outgoing.topic, topics::COMMAND_BROADCAST_TOPIC);
+        let sent = CommandEnvelope::from_json(std::str::from_utf8(&outgoing.payload).unwrap());
+        assert_eq!(sent.unwrap().id, Some(1));
+    }
+
+    #[test]
+    fn commands_carry_their_id_and_expire_at_the_deadline() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let entry = relay.submit("dev", DeviceCommand::GetTempOffset, t(310));
+        let envelope = entry.envelope();
+        assert_eq!(envelope.id, Some(entry.id));
+        assert_eq!(envelope.issued_by.as_deref(), Some(RELAY_OPERATOR));
+        assert_eq!(
+            envelope.expires_at_unix(),
+            Some(entry.deadline.timestamp() as u64)
+        );
+        assert_eq!(envelope.command, DeviceCommand::GetTempOffset);
+    }
+
+    #[test]
+    fn answers_with_an_id_resolve_only_that_command() {
+        let mut relay = relay_with_wakes(&[0, 300]);
+        let frc = relay.submit("dev", DeviceCommand::StartFrc { target_ppm: 422 }, t(310));
+        relay.observe(&msg(DevicePayload::frc_start(422)), t(600));
+        let offset = relay.submit("dev", DeviceCommand::GetTempOffset, t(610));
+
+        // The same answer to a command the commander sent
+        let answer = DevicePayload::GetOffsetSuccess { offset: 1.0 };
+        assert!(
+            relay
+                .observe(&msg(answer.clone()).replying_to(offset.id + 1000), t(900))
+                .is_empty()
+        );
+        // An FRC answer naming the offset command doesn't touch the FRC
+        assert!(
+            relay
+                .observe(
+                    &msg(DevicePayload::frc_success(12)).replying_to(offset.id),
+                    t(900)
+                )
+                .is_empty()
+        );
+        assert_eq!(relay.commands_for("dev")[0].state, CommandState::InProgress);
+
+        assert_eq!(
+            relay.observe(&msg(answer).replying_to(offset.id), t(900)),
+            vec![offset.id]
+        );
+        assert_eq!(
+            relay.observe(
+                &msg(DevicePayload::frc_success(12)).replying_to(frc.id),
+                t(900)
+            ),
+            vec![frc.id]
+        );
+    }
+
+    #[test]
+    fn messages_from_other_devices_are_ignored() {
+        let mut relay = relay_with_wakes(&[0]);
+        relay.submit("dev", DeviceCommand::GetTempOffset, t(10));
+        let other = DeviceMessage::new("other", DevicePayload::GetOffsetSuccess { offset: 1.0 });
+        assert!(relay.observe(&other, t(300)).is_empty());
+    }
+}
@@ -0,0 +1,679 @@
+//! Alerts for configuration changes nobody asked for.
+//!
+//! The receiver keeps the temperature offset, sleep interval and firmware
+//! version it expects from every device, starting from the latest
+//! `device_config` snapshots. It also listens on the command topics, so
+//! every command sent to the devices, by the relay, the commander or anyone
+//! else on the broker, lands in an audit trail, with the operator who
+//! issued it when the command names one. Acknowledgements and commanded
+//! changes are logged with that operator.
+//!
+//! When a `config` answer or a command acknowledgement reports a different
+//! value and no command from the last `AUDIT_WINDOW` asked for it, the
+//! change is drift: NVS corruption, a firmware rollback, a reflash. Drift is
+//! written to `config_drift` and raised as the `config_drift` alert. Either
+//! way the reported value becomes the expected one.
+//!
+//! Commands sent while the processor was down never reach the trail, so for
+//! `STARTUP_GRACE` after startup a change to a value last confirmed before
+//! startup is adopted with a log line instead of an alert.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use shared_types::line_protocol::escape_tag;
+use shared_types::{CommandEnvelope, DeviceCommand, DevicePayload};
+
+use crate::bulk_write::PointStore;
+use crate::device_config::{ConfigSnapshot, escape_string_field};
+
+pub const MEASUREMENT: &str = "config_drift";
+/// How long a command explains a change; covers deferred commands and long sleeps
+pub const AUDIT_WINDOW: Duration = Duration::hours(24);
+pub const STARTUP_GRACE: Duration = Duration::hours(6);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    TemperatureOffset,
+    SleepSeconds,
+    FirmwareVersion,
+}
+
+impl Field {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Field::TemperatureOffset => "temperature_offset",
+            Field::SleepSeconds => "sleep_seconds",
+            Field::FirmwareVersion => "firmware_version",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+impl Value {
+    /// Offsets travel as `f32`, so numbers only need to agree to the hundredth
+    fn same(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => (a - b).abs() < 0.005,
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", (n * 100.0).round() / 100.0),
+            Value::Text(t) => f.write_str(t),
+        }
+    }
+}
+
+/// The watched values a payload reports
+pub fn reported(payload: &DevicePayload) -> Vec<(Field, Value)> {
+    match payload {
+        DevicePayload::Config(config) => {
+            let mut values = vec![
+                (
+                    Field::FirmwareVersion,
+                    Value::Text(config.firmware_version.clone()),
+                ),
+                (
+                    Field::SleepSeconds,
+                    Value::Number(config.sleep_seconds as f64),
+                ),
+            ];
+            if let Some(offset) = config.temperature_offset {
+                values.push((Field::TemperatureOffset, Value::Number(offset as f64)));
+            }
+            values
+        }
+        DevicePayload::SetOffsetSuccess { offset, .. }
+        | DevicePayload::SetOffsetRateLimited { offset, .. }
+        | DevicePayload::GetOffsetSuccess { offset } => {
+            vec![(Field::TemperatureOffset, Value::Number(*offset as f64))]
+        }
+        DevicePayload::SetDeepSleepTimeSuccess { seconds }
+        | DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
+            vec![(Field::SleepSeconds, Value::Number(*seconds as f64))]
+        }
+        DevicePayload::OtaSuccess { version } => {
+            vec![(Field::FirmwareVersion, Value::Text(version.clone()))]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Whether `command` asks for `field` to become `value`. An OTA update
+/// explains any firmware version.
+fn requests(command: &DeviceCommand, field: Field, value: &Value) -> bool {
+    match command {
+        DeviceCommand::SetTempOffset { offset, .. } => {
+            field == Field::TemperatureOffset && value.same(&Value::Number(*offset as f64))
+        }
+        DeviceCommand::SetDeepSleepTime { seconds } => {
+            field == Field::SleepSeconds && value.same(&Value::Number(*seconds as f64))
+        }
+        DeviceCommand::Ota { .. } => field == Field::FirmwareVersion,
+        _ => false,
+    }
+}
+
+/// The device a command topic addresses: `Some(None)` for the topic all
+/// devices read, `None` for topics that aren't command topics.
+pub fn command_target<'a>(topic: &'a str, command_topic: &str) -> Option<Option<&'a str>> {
+    let rest = topic.strip_prefix(command_topic)?;
+    if rest.is_empty() {
+        return Some(None);
+    }
+    rest.strip_prefix('/')
+        .filter(|device| !device.is_empty() && !device.contains('/'))
+        .map(Some)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// A command in the audit trail asked for it
+    Explained,
+    /// Possibly changed while the processor was down
+    Adopted,
+    Drift,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub field: Field,
+    pub expected: Value,
+    pub reported: Value,
+    pub verdict: Verdict,
+    /// Who issued the command that explains the change, if it says
+    pub issued_by: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Expected {
+    value: Value,
+    /// When the value was last reported
+    since: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct AuditEntry {
+    /// `None` for commands to every device
+    device: Option<String>,
+    id: Option<u32>,
+    issued_by: Option<String>,
+    command: DeviceCommand,
+    time: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct DriftDetector {
+    started: DateTime<Utc>,
+    expected: BTreeMap<String, BTreeMap<Field, Expected>>,
+    /// Oldest first
+    audit: VecDeque<AuditEntry>,
+}
+
+impl DriftDetector {
+    pub fn new(started: DateTime<Utc>) -> Self {
+        Self {
+            started,
+            expected: BTreeMap::new(),
+            audit: VecDeque::new(),
+        }
+    }
+
+    /// Takes the expected values from a stored snapshot. Values reported
+    /// live since then are kept.
+    pub fn restore(&mut self, device: &str, snapshot: &ConfigSnapshot) {
+        let expected = self.expected.entry(device.to_string()).or_default();
+        for (field, value) in reported(&DevicePayload::Config(snapshot.config.clone())) {
+            match expected.get(&field) {
+                Some(e) if e.since >= snapshot.time => {}
+                _ => {
+                    expected.insert(
+                        field,
+                        Expected {
+                            value,
+                            since: snapshot.time,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Records a command seen on a command topic.
+    pub fn command(
+        &mut self,
+        device: Option<&str>,
+        command: impl Into<CommandEnvelope>,
+        now: DateTime<Utc>,
+    ) {
+        self.prune(now);
+        let CommandEnvelope {
+            id,
+            issued_by,
+            command,
+            ..
+        } = command.into();
+        self.audit.push_back(AuditEntry {
+            device: device.map(str::to_string),
+            id,
+            issued_by,
+            command,
+            time: now,
+        });
+    }
+
+    /// Who issued the command `device` answered with `in_reply_to`.
+    /// `None` if the command isn't in the trail or names no one.
+    pub fn issuer(&self, device: &str, in_reply_to: u32) -> Option<&str> {
+        self.audit
+            .iter()
+            .rev()
+            .filter(|entry| entry.device.as_deref().is_none_or(|d| d == device))
+            .find(|entry| entry.id == Some(in_reply_to))
+            .and_then(|entry| entry.issued_by.as_deref())
+    }
+
+    /// Compares what `payload` reports with the expected values and adopts
+    /// the reported ones. Returns the values that changed.
+    pub fn observe(
+        &mut self,
+        device: &str,
+        payload: &DevicePayload,
+        now: DateTime<Utc>,
+    ) -> Vec<Change> {
+        self.prune(now);
+        let mut changes = Vec::new();
+        for (field, value) in reported(payload) {
+            let expected = self.expected.entry(device.to_string()).or_default();
+            let previous = expected.insert(
+                field,
+                Expected {
+                    value: value.clone(),
+                    since: now,
+                },
+            );
+            let Some(previous) = previous.filter(|p| !p.value.same(&value)) else {
+                continue;
+            };
+            let explanation = self.explanation(device, field, &value);
+            let verdict = if explanation.is_some() {
+                Verdict::Explained
+            } else if previous.since < self.started && now < self.started + STARTUP_GRACE {
+                Verdict::Adopted
+            } else {
+                Verdict::Drift
+            };
+            changes.push(Change {
+                field,
+                expected: previous.value,
+                reported: value,
+                verdict,
+                issued_by: explanation.and_then(|entry| entry.issued_by.clone()),
+            });
+        }
+        changes
+    }
+
+    /// The latest command in the trail asking for the change
+    fn explanation(&self, device: &str, field: Field, value: &Value) -> Option<&AuditEntry> {
+        self.audit.iter().rev().find(|entry| {
+            entry.device.as_deref().is_none_or(|d| d == device)
+                && requests(&entry.command, field, value)
+        })
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        while self
+            .audit
+            .front()
+            .is_some_and(|entry| now - entry.time > AUDIT_WINDOW)
+        {
+            self.audit.pop_front();
+        }
+    }
+}
+
+pub fn event_line(device: &str, change: &Change, time: DateTime<Utc>) -> String {
+    format!(
+        "{},device={},field={} expected=\"{}\",reported=\"{}\" {}",
+        MEASUREMENT,
+        escape_tag(device),
+        change.field.as_str(),
+        escape_string_field(&change.expected.to_string()),
+        escape_string_field(&change.reported.to_string()),
+        time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+/// Logs `changes` and stores the drift among them. Returns the detail of
+/// the one alert to raise for it, if there was any.
+pub async fn report(
+    store: &impl PointStore,
+    device: &str,
+    changes: &[Change],
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let mut drift = Vec::new();
+    for change in changes {
+        let description = format!(
+            "{} changed from {} to {}",
+            change.field.as_str(),
+            change.expected,
+            change.reported
+        );
+        match (change.verdict, &change.issued_by) {
+            (Verdict::Explained, Some(operator)) => {
+                log::info!("{}: {} as commanded by {}", device, description, operator)
+            }
+            (Verdict::Explained, None) => log::info!("{}: {} as commanded", device, description),
+            (Verdict::Adopted, _) => log::info!(
+                "{}: {}, possibly by a command sent while the processor was down",
+                device,
+                description
+            ),
+            (Verdict::Drift, _) => {
+                log::warn!("{}: {} without a command", device, description);
+                drift.push((change, description));
+            }
+        }
+    }
+    if drift.is_empty() {
+        return None;
+    }
+    let lines: Vec<String> = drift
+        .iter()
+        .map(|(change, _)| event_line(device, change, now))
+        .collect();
+    if let Err(e) = store.write(&lines).await {
+        log::error!("Failed to save configuration drift: {}", e);
+    }
+    let message = drift
+        .iter()
+        .map(|(_, description)| description.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(format!("{} without a command", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at_minute as at;
+    use shared_types::device_config::{DeviceConfig, SensorMode};
+
+    /// 2025-01-15 12:00 UTC plus `minutes`
+    fn config(version: &str, sleep_seconds: u64, offset: f32) -> DevicePayload {
+        DevicePayload::Config(DeviceConfig {
+            firmware_version: version.to_string(),
+            sleep_seconds,
+            quiet_hours: None,
+            utc_offset_hours: 0,
+            sensor_mode: SensorMode::Periodic,
+            temperature_offset: Some(offset),
+            altitude_m: None,
+            ambient_pressure_hpa: None,
+            asc_enabled: None,
+            alarm_threshold_ppm: None,
+            mqtt_policy: String::new(),
+            wifi_ssid: String::new(),
+            safe_mode: false,
+        })
+    }
+
+    fn snapshot(minutes: i64, payload: DevicePayload) -> ConfigSnapshot {
+        let DevicePayload::Config(config) = payload else {
+            unreachable!()
+        };
+        ConfigSnapshot {
+            time: at(minutes),
+            config,
+        }
+    }
+
+    fn verdicts(changes: &[Change]) -> Vec<(Field, Verdict)> {
+        changes.iter().map(|c| (c.field, c.verdict)).collect()
+    }
+
+    /// Started at `at(0)` with a snapshot from two days before
+    fn started() -> DriftDetector {
+        let mut detector = DriftDetector::new(at(0));
+        detector.restore(
+            "kitchen",
+            &snapshot(-2 * 24 * 60, config("0.3.0", 300, 4.0)),
+        );
+        detector
+    }
+
+    #[test]
+    fn unchanged_and_first_reports_are_quiet() {
+        let mut detector = started();
+        assert!(
+            detector
+                .observe("kitchen", &config("0.3.0", 300, 4.0), at(400))
+                .is_empty()
+        );
+        // Nothing known about this one yet
+        assert!(
+            detector
+                .observe("bedroom", &config("0.1.0", 60, 0.0), at(400))
+                .is_empty()
+        );
+        // Offsets are f32 on the wire
+        assert!(
+            detector
+                .observe(
+                    "kitchen",
+                    &DevicePayload::GetOffsetSuccess { offset: 4.001 },
+                    at(401)
+                )
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn commanded_changes_are_explained() {
+        let mut detector = started();
+        detector.command(
+            Some("kitchen"),
+            DeviceCommand::SetTempOffset {
+                offset: 5.5,
+                persist: true,
+            },
+            at(400),
+        );
+        // Sent to every device
+        detector.command(
+            None,
+            DeviceCommand::SetDeepSleepTime { seconds: 600 },
+            at(401),
+        );
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::SetOffsetSuccess {
+                offset: 5.5,
+                persisted: true,
+            },
+            at(405),
+        );
+        assert_eq!(
+            changes,
+            vec![Change {
+                field: Field::TemperatureOffset,
+                expected: Value::Number(4.0),
+                reported: Value::Number(5.5),
+                verdict: Verdict::Explained,
+                issued_by: None,
+            }]
+        );
+        let changes = detector.observe("kitchen", &config("0.3.0", 600, 5.5), at(410));
+        assert_eq!(
+            verdicts(&changes),
+            vec![(Field::SleepSeconds, Verdict::Explained)]
+        );
+    }
+
+    #[test]
+    fn unexplained_changes_are_drift() {
+        let mut detector = started();
+        detector.observe("kitchen", &config("0.3.0", 300, 4.0), at(400));
+        // A command for another device, and one asking for a different value
+        detector.command(
+            Some("bedroom"),
+            DeviceCommand::SetTempOffset {
+                offset: 0.0,
+                persist: true,
+            },
+            at(410),
+        );
+        detector.command(
+            Some("kitchen"),
+            DeviceCommand::SetDeepSleepTime { seconds: 900 },
+            at(410),
+        );
+        // NVS reset to defaults and an older firmware
+        let changes = detector.observe("kitchen", &config("0.2.9", 300, 0.0), at(420));
+        assert_eq!(
+            verdicts(&changes),
+            vec![
+                (Field::FirmwareVersion, Verdict::Drift),
+                (Field::TemperatureOffset, Verdict::Drift),
+            ]
+        );
+        // The new values are expected from now on
+        assert!(
+            detector
+                .observe("kitchen", &config("0.2.9", 300, 0.0), at(425))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn commands_only_explain_changes_within_the_window() {
+        let mut detector = started();
+        detector.command(
+            Some("kitchen"),
+            DeviceCommand::Ota {
+                url: "http://firmware.local/air-quality-0.4.0.bin".to_string(),
+            },
+            at(400),
+        );
+        let late = at(400) + AUDIT_WINDOW + Duration::minutes(1);
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::OtaSuccess {
+                version: "0.4.0".to_string(),
+            },
+            late,
+        );
+        assert_eq!(
+            verdicts(&changes),
+            vec![(Field::FirmwareVersion, Verdict::Drift)]
+        );
+    }
+
+    #[test]
+    fn changes_made_while_down_are_adopted_during_the_grace_period() {
+        let mut detector = started();
+        // The sleep interval changed while the processor was down
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::GetDeepSleepTimeSuccess { seconds: 900 },
+            at(30),
+        );
+        assert_eq!(
+            verdicts(&changes),
+            vec![(Field::SleepSeconds, Verdict::Adopted)]
+        );
+        // Confirmed live after startup, so no longer covered by the grace
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::GetDeepSleepTimeSuccess { seconds: 300 },
+            at(60),
+        );
+        assert_eq!(
+            verdicts(&changes),
+            vec![(Field::SleepSeconds, Verdict::Drift)]
+        );
+        // After the grace period a stale expectation alerts too
+        let after_grace = at(0) + STARTUP_GRACE + Duration::minutes(1);
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::GetOffsetSuccess { offset: 1.0 },
+            after_grace,
+        );
+        assert_eq!(
+            verdicts(&changes),
+            vec![(Field::TemperatureOffset, Verdict::Drift)]
+        );
+    }
+
+    #[test]
+    fn stored_snapshots_dont_override_live_reports() {
+        let mut detector = DriftDetector::new(at(0));
+        detector.observe("kitchen", &config("0.3.0", 300, 4.0), at(5));
+        // The bootstrap query came back late with an older snapshot
+        detector.restore("kitchen", &snapshot(-60, config("0.2.0", 60, 0.0)));
+        assert!(
+            detector
+                .observe("kitchen", &config("0.3.0", 300, 4.0), at(10))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn changes_and_answers_name_who_commanded_them() {
+        let mut detector = started();
+        detector.command(
+            None,
+            DeviceCommand::SetDeepSleepTime { seconds: 600 }
+                .with_id(41)
+                .issued_by("ola"),
+            at(400),
+        );
+        // Someone else asked for the same later, without naming themselves
+        detector.command(
+            Some("kitchen"),
+            DeviceCommand::SetDeepSleepTime { seconds: 600 }.with_id(42),
+            at(401),
+        );
+        detector.command(
+            Some("bedroom"),
+            DeviceCommand::GetTempOffset
+                .with_id(43)
+                .issued_by("mateusz"),
+            at(402),
+        );
+        assert_eq!(detector.issuer("kitchen", 41), Some("ola"));
+        assert_eq!(detector.issuer("kitchen", 42), None);
+        assert_eq!(detector.issuer("kitchen", 43), None, "sent to bedroom");
+        assert_eq!(detector.issuer("bedroom", 43), Some("mateusz"));
+
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::SetDeepSleepTimeSuccess { seconds: 600 },
+            at(405),
+        );
+        assert_eq!(changes[0].verdict, Verdict::Explained);
+        assert_eq!(changes[0].issued_by, None, "the latest command counts");
+
+        detector.command(
+            Some("kitchen"),
+            DeviceCommand::SetTempOffset {
+                offset: 5.0,
+                persist: true,
+            }
+            .with_id(44)
+            .issued_by("ola"),
+            at(410),
+        );
+        let changes = detector.observe(
+            "kitchen",
+            &DevicePayload::SetOffsetSuccess {
+                offset: 5.0,
+                persisted: true,
+            },
+            at(415),
+        );
+        assert_eq!(changes[0].issued_by.as_deref(), Some("ola"));
+    }
+
+    #[test]
+    fn command_topics() {
+        let base = "sensors/esp32/command";
+        assert_eq!(command_target(base, base), Some(None));
+        assert_eq!(
+            command_target("sensors/esp32/command/kitchen", base),
+            Some(Some("kitchen"))
+        );
+        assert_eq!(command_target("sensors/esp32/sensor", base), None);
+        assert_eq!(command_target("sensors/esp32/commander", base), None);
+        assert_eq!(command_target("sensors/esp32/command/a/b", base), None);
+    }
+
+    #[test]
+    fn drift_events() {
+        let change = Change {
+            field: Field::TemperatureOffset,
+            expected: Value::Number(4.0),
+            reported: Value::Number(0.1 + 0.2),
+            verdict: Verdict::Drift,
+            issued_by: None,
+        };
+        assert_eq!(
+            event_line("esp32 kitchen", &change, at(0)),
+            "config_drift,device=esp32\\ kitchen,field=temperature_offset \
+             expected=\"4\",reported=\"0.3\" 1736942400000000000"
+        );
+    }
+}
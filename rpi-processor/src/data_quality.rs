@@ -0,0 +1,597 @@
+//! Daily data-quality score per device.
+//!
+//! Each day gets a 0-100 score built from five components, each normalized
+//! to 0..=1 (1 = perfect) and combined as a weighted average:
+//!
+//! | component     | 1.0 when                  | 0.0 when                       | default weight |
+//! |---------------|---------------------------|--------------------------------|----------------|
+//! | completeness  | every expected sample     | no samples                     | 40             |
+//! | anomalies     | no anomalies              | >= 25% of samples anomalous    | 20             |
+//! | rejections    | every sample plausible    | >= 10% of samples implausible  | 15             |
+//! | flatline      | no stuck readings         | >= 4 hours of stuck readings   | 15             |
+//! | clock skew    | no incidents              | >= 5 incidents                 | 10             |
+//!
+//! Weights are relative, so `--quality-weights completeness=2,anomalies=1`
+//! (unlisted components keep their defaults) works just as well as numbers
+//! that add up to 100.
+//!
+//! External events overlapping the day (see `external_events`) don't change
+//! the score, but are logged and counted next to it, as they often explain
+//! a bad one.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::external_events::{self, ExternalEvent};
+use crate::fetcher::{Sql, query_rows};
+use crate::latency;
+use crate::options::parse_kv_options;
+use crate::types::MeasurementWithTime;
+
+/// Score below which a device is highlighted on the dashboard.
+pub const DEFAULT_ALERT_THRESHOLD: f64 = 70.0;
+
+const ANOMALY_RATE_FLOOR: f64 = 0.25;
+const REJECTION_RATE_FLOOR: f64 = 0.10;
+const FLATLINE_MINUTES_FLOOR: f64 = 240.0;
+const CLOCK_SKEW_FLOOR: f64 = 5.0;
+
+/// Identical consecutive readings only count as a flatline from this many samples on.
+const FLATLINE_MIN_SAMPLES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityWeights {
+    pub completeness: f64,
+    pub anomalies: f64,
+    pub rejections: f64,
+    pub flatline: f64,
+    pub clock_skew: f64,
+}
+
+impl Default for QualityWeights {
+    fn default() -> Self {
+        Self {
+            completeness: 40.0,
+            anomalies: 20.0,
+            rejections: 15.0,
+            flatline: 15.0,
+            clock_skew: 10.0,
+        }
+    }
+}
+
+impl FromStr for QualityWeights {
+    type Err = String;
+
+    /// Parses `name=weight` pairs separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Self::default();
+        for (name, value) in parse_kv_options(s)? {
+            let value: f64 = value
+                .parse()
+                .map_err(|_| format!("invalid weight '{}' for {}", value, name))?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("weight for {} must be a non-negative number", name));
+            }
+            match name {
+                "completeness" => weights.completeness = value,
+                "anomalies" => weights.anomalies = value,
+                "rejections" => weights.rejections = value,
+                "flatline" => weights.flatline = value,
+                "clock_skew" => weights.clock_skew = value,
+                other => return Err(format!("unknown quality component '{}'", other)),
+            }
+        }
+        if weights.total() <= 0.0 {
+            return Err("at least one weight must be positive".to_string());
+        }
+        Ok(weights)
+    }
+}
+
+impl QualityWeights {
+    fn total(&self) -> f64 {
+        self.completeness + self.anomalies + self.rejections + self.flatline + self.clock_skew
+    }
+}
+
+/// Raw counts for one device and one day.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DayInputs {
+    pub expected_samples: usize,
+    pub received_samples: usize,
+    pub anomalies: usize,
+    pub rejected_samples: usize,
+    pub flatline_minutes: f64,
+    pub clock_skew_incidents: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QualityScore {
+    pub score: f64,
+    pub completeness: f64,
+    pub anomaly_rate: f64,
+    pub rejection_rate: f64,
+    pub flatline_minutes: f64,
+    pub clock_skew_incidents: usize,
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+/// Linear penalty reaching zero at `floor`.
+fn penalty(value: f64, floor: f64) -> f64 {
+    (1.0 - value / floor).clamp(0.0, 1.0)
+}
+
+pub fn score_day(inputs: &DayInputs, weights: &QualityWeights) -> QualityScore {
+    let completeness = ratio(inputs.received_samples, inputs.expected_samples).min(1.0);
+    let anomaly_rate = ratio(inputs.anomalies, inputs.received_samples);
+    let rejection_rate = ratio(inputs.rejected_samples, inputs.received_samples);
+
+    let weighted = weights.completeness * completeness
+        + weights.anomalies * penalty(anomaly_rate, ANOMALY_RATE_FLOOR)
+        + weights.rejections * penalty(rejection_rate, REJECTION_RATE_FLOOR)
+        + weights.flatline * penalty(inputs.flatline_minutes, FLATLINE_MINUTES_FLOOR)
+        + weights.clock_skew * penalty(inputs.clock_skew_incidents as f64, CLOCK_SKEW_FLOOR);
+
+    // A day without any data can't earn points for "no anomalies"
+    let score = if inputs.received_samples == 0 {
+        0.0
+    } else {
+        100.0 * weighted / weights.total()
+    };
+
+    QualityScore {
+        score,
+        completeness,
+        anomaly_rate,
+        rejection_rate,
+        flatline_minutes: inputs.flatline_minutes,
+        clock_skew_incidents: inputs.clock_skew_incidents,
+    }
+}
+
+/// False for readings outside the SCD40's physical range, which count as rejected.
+pub fn is_plausible(m: &MeasurementWithTime) -> bool {
+    (250..=40_000).contains(&m.co2)
+        && (-10.0..=60.0).contains(&m.temperature)
+        && (0.0..=100.0).contains(&m.humidity)
+}
+
+/// Derives the counts for one device's day from its measurements, which must
+/// be in arrival order.
+///
+/// Clock-skew incidents are samples that arrive out of order or well before
+/// the wake interval allows (under a quarter of it), which means the device
+/// woke at the wrong time or its messages were replayed.
+pub fn day_inputs(
+    measurements: &[MeasurementWithTime],
+    anomalies: usize,
+    expected_interval: Duration,
+) -> DayInputs {
+    let expected_samples =
+        (Duration::days(1).num_seconds() / expected_interval.num_seconds().max(1)) as usize;
+
+    let rejected_samples = measurements.iter().filter(|m| !is_plausible(m)).count();
+
+    let skew_gap = expected_interval / 4;
+    let clock_skew_incidents = measurements
+        .windows(2)
+        .filter(|w| w[1].time - w[0].time < skew_gap)
+        .count();
+
+    let mut flatline_minutes = 0.0;
+    let mut run_start = 0;
+    for i in 1..=measurements.len() {
+        let continues =
+            i < measurements.len() && same_reading(&measurements[i - 1], &measurements[i]);
+        if !continues {
+            if i - run_start >= FLATLINE_MIN_SAMPLES {
+                let span = measurements[i - 1].time - measurements[run_start].time;
+                flatline_minutes += span.num_seconds() as f64 / 60.0;
+            }
+            run_start = i;
+        }
+    }
+
+    DayInputs {
+        expected_samples,
+        received_samples: measurements.len(),
+        anomalies,
+        rejected_samples,
+        flatline_minutes,
+        clock_skew_incidents,
+    }
+}
+
+fn same_reading(a: &MeasurementWithTime, b: &MeasurementWithTime) -> bool {
+    a.co2 == b.co2 && a.temperature == b.temperature && a.humidity == b.humidity
+}
+
+fn day_bounds(date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (start, start + Duration::days(1))
+}
+
+/// Measurements and anomaly counts of every device that reported on one day.
+#[derive(Debug, Default)]
+pub struct DayData {
+    pub measurements: BTreeMap<String, Vec<MeasurementWithTime>>,
+    pub anomalies: BTreeMap<String, usize>,
+}
+
+pub async fn fetch_day(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    date: NaiveDate,
+) -> Result<DayData, Box<dyn std::error::Error>> {
+    let (start, end) = day_bounds(date);
+
+    let rows: Vec<crate::types::InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             WHERE time >= ",
+        )
+        .time(start)
+        .push(" AND time < ")
+        .time(end)
+        .push(" ORDER BY time ASC"),
+    )
+    .await?;
+
+    let mut measurements: BTreeMap<String, Vec<MeasurementWithTime>> = BTreeMap::new();
+    for row in rows {
+        let m = row.to_measurement_with_time()?;
+        measurements.entry(m.device.clone()).or_default().push(m);
+    }
+
+    #[derive(serde::Deserialize)]
+    struct AnomalyCount {
+        device: String,
+        anomalies: u64,
+    }
+
+    // The anomalies table only exists once marking has run at least once
+    let anomalies: BTreeMap<String, usize> = match query_rows::<AnomalyCount>(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT device, COUNT(*) AS anomalies FROM anomalies WHERE time >= ")
+            .time(start)
+            .push(" AND time < ")
+            .time(end)
+            .push(" GROUP BY device"),
+    )
+    .await
+    {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|r| (r.device, r.anomalies as usize))
+            .collect(),
+        Err(e) => {
+            log::warn!("Could not count anomalies, assuming none: {}", e);
+            BTreeMap::new()
+        }
+    };
+
+    Ok(DayData {
+        measurements,
+        anomalies,
+    })
+}
+
+/// The external events of a device's day, as extra `data_quality` fields:
+/// how many there were and their kinds, in order. Nothing without events.
+fn event_fields(events: &[&ExternalEvent]) -> String {
+    if events.is_empty() {
+        return String::new();
+    }
+    let kinds: Vec<_> = events.iter().map(|e| e.kind.as_str()).collect();
+    format!(
+        ",external_events={}i,external_event_kinds=\"{}\"",
+        events.len(),
+        kinds.join(",")
+    )
+}
+
+/// Scores every device that reported on `date` and writes the results to the
+/// `data_quality` measurement, timestamped at the start of the day. Devices
+/// that stamp their measurements also get their ingest latency percentiles.
+pub async fn run_daily_report(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    date: NaiveDate,
+    expected_interval: Duration,
+    weights: &QualityWeights,
+) -> Result<Vec<(String, QualityScore)>, Box<dyn std::error::Error>> {
+    let (start, end) = day_bounds(date);
+    let day = fetch_day(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        date,
+    )
+    .await?;
+    // Like anomalies, ingest_latency only exists once the receiver wrote to it
+    let latencies = match latency::fetch_day(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        date,
+    )
+    .await
+    {
+        Ok(latencies) => latencies,
+        Err(e) => {
+            log::warn!("Could not read ingest latencies, leaving them out: {}", e);
+            BTreeMap::new()
+        }
+    };
+    let events = external_events::overlapping_or_none(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        None,
+        start,
+        end - Duration::nanoseconds(1),
+    )
+    .await;
+
+    let mut results = Vec::new();
+    let mut lines = Vec::new();
+    for (device, measurements) in day.measurements {
+        let anomalies = day.anomalies.get(&device).copied().unwrap_or(0);
+        let inputs = day_inputs(&measurements, anomalies, expected_interval);
+        let quality = score_day(&inputs, weights);
+        log::info!(
+            "{} on {}: quality {:.1} ({:?})",
+            device,
+            date,
+            quality.score,
+            inputs
+        );
+        let latency_fields = match latencies.get(&device) {
+            Some(summary) => {
+                log::info!(
+                    "{} on {}: ingest latency p50 {} ms, p95 {} ms over {} measurements",
+                    device,
+                    date,
+                    summary.p50_ms,
+                    summary.p95_ms,
+                    summary.count
+                );
+                format!(
+                    ",latency_p50_ms={}i,latency_p95_ms={}i",
+                    summary.p50_ms, summary.p95_ms
+                )
+            }
+            None => String::new(),
+        };
+        let device_events: Vec<_> = events.iter().filter(|e| e.device == device).collect();
+        for event in &device_events {
+            log::info!(
+                "{} on {}: {} from {} to {}{}",
+                device,
+                date,
+                event.kind,
+                event.from.to_rfc3339(),
+                event.to.to_rfc3339(),
+                event
+                    .note
+                    .as_deref()
+                    .map(|note| format!(" ({})", note))
+                    .unwrap_or_default()
+            );
+        }
+        lines.push(format!(
+            "data_quality,device={} score={},completeness={},anomaly_rate={},rejection_rate={},flatline_minutes={},clock_skew_incidents={}i{}{} {}",
+            device,
+            quality.score,
+            quality.completeness,
+            quality.anomaly_rate,
+            quality.rejection_rate,
+            quality.flatline_minutes,
+            quality.clock_skew_incidents,
+            latency_fields,
+            event_fields(&device_events),
+            start.timestamp_nanos_opt().unwrap_or(0)
+        ));
+        results.push((device, quality));
+    }
+
+    if lines.is_empty() {
+        log::info!("No measurements on {}, nothing to score", date);
+        return Ok(results);
+    }
+
+    let response = reqwest_client
+        .post(format!(
+            "{}/api/v3/write_lp?db={}",
+            influx_host, influx_database
+        ))
+        .body(lines.join("\n"))
+        .bearer_auth(influx_token)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await?;
+        return Err(format!(
+            "Failed to write data quality to InfluxDB: {} - {}",
+            status, error_text
+        )
+        .into());
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(minutes: i64, co2: u16, temperature: f32, humidity: f32) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature,
+            humidity,
+            time: DateTime::parse_from_rfc3339("2025-01-15T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc)
+                + Duration::minutes(minutes),
+            device: "esp32-scd40".to_string(),
+        }
+    }
+
+    /// A varying reading every five minutes for `count` slots.
+    fn regular_day(count: i64) -> Vec<MeasurementWithTime> {
+        (0..count)
+            .map(|i| {
+                sample(
+                    i * 5,
+                    600 + (i % 50) as u16,
+                    21.0 + (i % 7) as f32 * 0.1,
+                    45.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn good_day_scores_near_perfect() {
+        let inputs = day_inputs(&regular_day(288), 2, Duration::minutes(5));
+        assert_eq!(inputs.expected_samples, 288);
+        assert_eq!(inputs.rejected_samples, 0);
+        assert_eq!(inputs.flatline_minutes, 0.0);
+        assert_eq!(inputs.clock_skew_incidents, 0);
+
+        let quality = score_day(&inputs, &QualityWeights::default());
+        assert!(quality.score > 99.0, "{:?}", quality);
+    }
+
+    #[test]
+    fn mediocre_day_scores_in_the_middle() {
+        let inputs = DayInputs {
+            expected_samples: 288,
+            received_samples: 216,
+            anomalies: 11,
+            rejected_samples: 2,
+            flatline_minutes: 60.0,
+            clock_skew_incidents: 1,
+        };
+        let quality = score_day(&inputs, &QualityWeights::default());
+        assert!((55.0..85.0).contains(&quality.score), "{:?}", quality);
+    }
+
+    #[test]
+    fn bad_day_scores_low() {
+        let inputs = DayInputs {
+            expected_samples: 288,
+            received_samples: 80,
+            anomalies: 30,
+            rejected_samples: 10,
+            flatline_minutes: 360.0,
+            clock_skew_incidents: 12,
+        };
+        let quality = score_day(&inputs, &QualityWeights::default());
+        assert!(quality.score < 15.0, "{:?}", quality);
+    }
+
+    #[test]
+    fn empty_day_scores_zero() {
+        let inputs = day_inputs(&[], 0, Duration::minutes(5));
+        assert_eq!(score_day(&inputs, &QualityWeights::default()).score, 0.0);
+    }
+
+    #[test]
+    fn weights_change_the_score() {
+        let inputs = DayInputs {
+            expected_samples: 288,
+            received_samples: 144,
+            ..Default::default()
+        };
+        let only_completeness: QualityWeights =
+            "completeness=1,anomalies=0,rejections=0,flatline=0,clock_skew=0"
+                .parse()
+                .unwrap();
+        assert!((score_day(&inputs, &only_completeness).score - 50.0).abs() < 1e-9);
+        // Default: 40 * 0.5 + 60 * 1.0 out of 100
+        assert!((score_day(&inputs, &QualityWeights::default()).score - 80.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weights_parse_partial_and_reject_garbage() {
+        let weights: QualityWeights = "anomalies=50".parse().unwrap();
+        assert_eq!(weights.anomalies, 50.0);
+        assert_eq!(weights.completeness, 40.0);
+
+        assert!("bogus=1".parse::<QualityWeights>().is_err());
+        assert!("anomalies=-1".parse::<QualityWeights>().is_err());
+        assert!(
+            "completeness=0,anomalies=0,rejections=0,flatline=0,clock_skew=0"
+                .parse::<QualityWeights>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn flatline_and_skew_are_detected() {
+        let mut day = regular_day(12);
+        // Stuck sensor for 4 samples spanning 15 minutes
+        for (i, m) in day.iter_mut().enumerate().skip(4).take(4) {
+            *m = sample(i as i64 * 5, 500, 20.0, 50.0);
+        }
+        // A duplicate delivered a few seconds after the original
+        let mut replay = day[10].clone();
+        replay.time += Duration::seconds(3);
+        replay.co2 += 1;
+        day.insert(11, replay);
+        day.push(sample(70, 0, 20.0, 50.0));
+
+        let inputs = day_inputs(&day, 0, Duration::minutes(5));
+        assert_eq!(inputs.flatline_minutes, 15.0);
+        assert_eq!(inputs.clock_skew_incidents, 1);
+        assert_eq!(inputs.rejected_samples, 1);
+    }
+
+    #[test]
+    fn external_events_are_counted_next_to_the_score() {
+        let (start, _) = day_bounds(NaiveDate::from_ymd_opt(2025, 1, 14).unwrap());
+        let party = ExternalEvent::new(
+            "esp32-scd40",
+            "party",
+            start + Duration::hours(18),
+            start + Duration::hours(23),
+            Some("10 people"),
+        )
+        .unwrap();
+        let filter = ExternalEvent::new("esp32-scd40", "hvac_filter", start, start, None).unwrap();
+
+        assert_eq!(event_fields(&[]), "");
+        assert_eq!(
+            event_fields(&[&filter, &party]),
+            ",external_events=2i,external_event_kinds=\"hvac_filter,party\""
+        );
+    }
+}
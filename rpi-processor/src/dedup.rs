@@ -0,0 +1,95 @@
+//! Drops retained messages the receiver has already processed.
+//!
+//! Devices publish their latest measurement retained, so the broker hands it
+//! out again every time the receiver (re)subscribes. Measurements carry no
+//! device timestamp, so storing the replay would add a second point with the
+//! time of the reconnect. A retained delivery is skipped when it is
+//! byte-for-byte the last payload already seen on that topic.
+//!
+//! Right after startup nothing has been seen yet, so the first retained
+//! message is kept: it may be a measurement published while the receiver was
+//! down.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct RetainedDedup {
+    last_payload: HashMap<String, Vec<u8>>,
+}
+
+impl RetainedDedup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the publish should be processed. Remembers what it accepts.
+    pub fn accept(&mut self, topic: &str, payload: &[u8], retained: bool) -> bool {
+        if retained
+            && self
+                .last_payload
+                .get(topic)
+                .is_some_and(|last| last.as_slice() == payload)
+        {
+            return false;
+        }
+        self.last_payload
+            .insert(topic.to_string(), payload.to_vec());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::{DeviceMessage, DevicePayload};
+
+    const TOPIC: &str = "sensors/esp32/sensor";
+
+    fn measurement(co2: u16) -> Vec<u8> {
+        DeviceMessage::new("esp32-scd40", DevicePayload::measurement(co2, 21.5, 40.0))
+            .to_json()
+            .unwrap()
+            .into_bytes()
+    }
+
+    #[test]
+    fn retained_measurement_is_skipped_on_resubscribe() {
+        let mut dedup = RetainedDedup::new();
+        // Live delivery, then the same message retained after a reconnect
+        assert!(dedup.accept(TOPIC, &measurement(612), false));
+        assert!(!dedup.accept(TOPIC, &measurement(612), true));
+        // And again after another reconnect
+        assert!(!dedup.accept(TOPIC, &measurement(612), true));
+    }
+
+    #[test]
+    fn first_retained_message_after_startup_is_kept() {
+        let mut dedup = RetainedDedup::new();
+        assert!(dedup.accept(TOPIC, &measurement(612), true));
+        assert!(!dedup.accept(TOPIC, &measurement(612), true));
+    }
+
+    #[test]
+    fn newer_retained_message_is_kept() {
+        let mut dedup = RetainedDedup::new();
+        assert!(dedup.accept(TOPIC, &measurement(612), false));
+        // The device published again while the receiver was disconnected
+        assert!(dedup.accept(TOPIC, &measurement(640), true));
+        assert!(!dedup.accept(TOPIC, &measurement(640), true));
+    }
+
+    #[test]
+    fn live_repeats_are_never_dropped() {
+        let mut dedup = RetainedDedup::new();
+        assert!(dedup.accept(TOPIC, &measurement(612), false));
+        // A stable room can legitimately report the same values twice
+        assert!(dedup.accept(TOPIC, &measurement(612), false));
+    }
+
+    #[test]
+    fn topics_are_tracked_separately() {
+        let mut dedup = RetainedDedup::new();
+        assert!(dedup.accept(TOPIC, &measurement(612), false));
+        assert!(dedup.accept("sensors/other/sensor", &measurement(612), true));
+    }
+}
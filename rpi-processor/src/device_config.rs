@@ -0,0 +1,265 @@
+//! Snapshots of device configuration.
+//!
+//! Every `config` answer is written as one point in `device_config`, so the
+//! configuration a device ran with at any time can be looked up next to its
+//! measurements. Values the device left out are left out of the point too.
+//! `GET /api/devices/:device/config/history` serves them back, newest
+//! first, for comparing against what a device reports now.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use shared_types::device_config::DeviceConfig;
+
+use crate::bulk_write::{PointStore, WriteError};
+use crate::fetcher::{Identifier, Sql, query_rows};
+
+pub const MEASUREMENT: &str = "device_config";
+
+/// Snapshots returned when the request doesn't say
+pub const DEFAULT_HISTORY_LIMIT: usize = 20;
+pub const MAX_HISTORY_LIMIT: usize = 500;
+
+/// The latest snapshot of every device
+pub const LATEST_QUERY: &str =
+    "SELECT DISTINCT ON (device) * FROM device_config ORDER BY device, time DESC";
+
+/// A stored configuration and when it was reported
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ConfigSnapshot {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub config: DeviceConfig,
+}
+
+#[derive(Deserialize)]
+struct ConfigRow {
+    time: String,
+    #[serde(flatten)]
+    config: DeviceConfig,
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+pub fn escape_string_field(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn config_line(device: &str, config: &DeviceConfig, time: DateTime<Utc>) -> String {
+    let mut fields = vec![
+        format!(
+            "firmware_version=\"{}\"",
+            escape_string_field(&config.firmware_version)
+        ),
+        format!("sleep_seconds={}i", config.sleep_seconds),
+        format!("utc_offset_hours={}i", config.utc_offset_hours),
+        format!("sensor_mode=\"{}\"", config.sensor_mode.as_str()),
+    ];
+    if let Some(quiet_hours) = &config.quiet_hours {
+        fields.push(format!(
+            "quiet_hours=\"{}\"",
+            escape_string_field(quiet_hours)
+        ));
+    }
+    if let Some(offset) = config.temperature_offset {
+        fields.push(format!("temperature_offset={}", offset));
+    }
+    if let Some(altitude) = config.altitude_m {
+        fields.push(format!("altitude_m={}i", altitude));
+    }
+    if let Some(pressure) = config.ambient_pressure_hpa {
+        fields.push(format!("ambient_pressure_hpa={}i", pressure));
+    }
+    if let Some(asc) = config.asc_enabled {
+        fields.push(format!("asc_enabled={}", asc));
+    }
+    if let Some(threshold) = config.alarm_threshold_ppm {
+        fields.push(format!("alarm_threshold_ppm={}i", threshold));
+    }
+    fields.push(format!(
+        "mqtt_policy=\"{}\"",
+        escape_string_field(&config.mqtt_policy)
+    ));
+    fields.push(format!(
+        "wifi_ssid=\"{}\"",
+        escape_string_field(&config.wifi_ssid)
+    ));
+    fields.push(format!("safe_mode={}", config.safe_mode));
+
+    format!(
+        "{},device={} {} {}",
+        MEASUREMENT,
+        escape_tag(device),
+        fields.join(","),
+        time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+pub async fn save_snapshot(
+    store: &impl PointStore,
+    device: &str,
+    config: &DeviceConfig,
+    time: DateTime<Utc>,
+) -> Result<(), WriteError> {
+    store.write(&[config_line(device, config, time)]).await
+}
+
+/// The `limit` latest snapshots of `device` taken at or before `before`.
+pub fn history_query(device: &Identifier, before: Option<DateTime<Utc>>, limit: usize) -> Sql {
+    let mut sql = Sql::new("SELECT * FROM ")
+        .push(MEASUREMENT)
+        .push(" WHERE device = ")
+        .identifier(device);
+    if let Some(before) = before {
+        sql = sql.push(" AND time <= ").time(before);
+    }
+    sql.push(" ORDER BY time DESC LIMIT ").number(limit as u64)
+}
+
+pub async fn fetch_history(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: &Identifier,
+    before: Option<DateTime<Utc>>,
+    limit: usize,
+) -> Result<Vec<ConfigSnapshot>, Box<dyn std::error::Error>> {
+    let rows: Vec<ConfigRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &history_query(device, before, limit),
+    )
+    .await?;
+    rows.into_iter()
+        .map(|row| snapshot(row.time, row.config))
+        .collect()
+}
+
+pub async fn fetch_latest(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+) -> Result<BTreeMap<String, ConfigSnapshot>, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct LatestRow {
+        device: String,
+        #[serde(flatten)]
+        row: ConfigRow,
+    }
+
+    let rows: Vec<LatestRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(LATEST_QUERY),
+    )
+    .await?;
+    rows.into_iter()
+        .map(|latest| Ok((latest.device, snapshot(latest.row.time, latest.row.config)?)))
+        .collect()
+}
+
+fn snapshot(
+    time: String,
+    config: DeviceConfig,
+) -> Result<ConfigSnapshot, Box<dyn std::error::Error>> {
+    let time = if time.ends_with('Z') {
+        time
+    } else {
+        format!("{}Z", time)
+    };
+    Ok(ConfigSnapshot {
+        time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use shared_types::device_config::SensorMode;
+
+    #[test]
+    fn snapshot_line_skips_absent_values() {
+        let config = DeviceConfig {
+            firmware_version: "0.1.0".to_string(),
+            sleep_seconds: 300,
+            quiet_hours: None,
+            utc_offset_hours: -5,
+            sensor_mode: SensorMode::Periodic,
+            temperature_offset: Some(4.5),
+            altitude_m: Some(120),
+            ambient_pressure_hpa: None,
+            asc_enabled: Some(true),
+            alarm_threshold_ppm: None,
+            mqtt_policy: "measurement=1+retain,error=1".to_string(),
+            wifi_ssid: "my \"home\"".to_string(),
+            safe_mode: false,
+        };
+        let time = at(0);
+        assert_eq!(
+            config_line("esp32 kitchen", &config, time),
+            "device_config,device=esp32\\ kitchen firmware_version=\"0.1.0\",\
+             sleep_seconds=300i,utc_offset_hours=-5i,sensor_mode=\"periodic\",\
+             temperature_offset=4.5,altitude_m=120i,asc_enabled=true,\
+             mqtt_policy=\"measurement=1+retain,error=1\",\
+             wifi_ssid=\"my \\\"home\\\"\",safe_mode=false 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn history_is_newest_first_and_optionally_bounded() {
+        let kitchen = Identifier::parse("kitchen").unwrap();
+        assert_eq!(
+            history_query(&kitchen, None, 20).as_str(),
+            "SELECT * FROM device_config WHERE device = 'kitchen' ORDER BY time DESC LIMIT 20"
+        );
+        let before = at(0);
+        assert_eq!(
+            history_query(&kitchen, Some(before), 1).as_str(),
+            "SELECT * FROM device_config WHERE device = 'kitchen' \
+             AND time <= '2025-01-15T12:00:00+00:00' ORDER BY time DESC LIMIT 1"
+        );
+    }
+
+    #[test]
+    fn stored_rows_read_back_as_configs() {
+        // As InfluxDB returns them: nulls for fields a snapshot didn't have
+        let row: ConfigRow = serde_json::from_value(serde_json::json!({
+            "time": "2025-01-15T12:00:00",
+            "device": "kitchen",
+            "firmware_version": "0.1.0",
+            "sleep_seconds": 300,
+            "utc_offset_hours": -5,
+            "sensor_mode": "single_shot",
+            "quiet_hours": null,
+            "temperature_offset": 4.5,
+            "altitude_m": null,
+            "ambient_pressure_hpa": null,
+            "asc_enabled": true,
+            "alarm_threshold_ppm": null,
+            "mqtt_policy": "measurement=1+retain",
+            "wifi_ssid": "home",
+            "safe_mode": false
+        }))
+        .unwrap();
+        assert_eq!(row.time, "2025-01-15T12:00:00");
+        assert_eq!(row.config.sensor_mode, SensorMode::SingleShot);
+        assert_eq!(row.config.utc_offset_hours, -5);
+        assert_eq!(row.config.temperature_offset, Some(4.5));
+        assert_eq!(row.config.altitude_m, None);
+    }
+}
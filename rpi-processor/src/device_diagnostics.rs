@@ -0,0 +1,85 @@
+//! Device health reports.
+//!
+//! Every `diagnostics` message is written as one point in the
+//! `device_diagnostics` measurement: the Wi-Fi signal, free heap, boot count
+//! and last reset reason, so a flaky connection or a leak can be followed
+//! over time next to the measurements.
+
+use chrono::{DateTime, Utc};
+use shared_types::DevicePayload;
+use shared_types::line_protocol::escape_tag;
+
+use crate::bulk_write::{PointStore, WriteError};
+use crate::device_config::escape_string_field;
+
+pub const MEASUREMENT: &str = "device_diagnostics";
+
+/// The point for `payload`, if it is a diagnostics report
+pub fn diagnostics_line(
+    device: &str,
+    payload: &DevicePayload,
+    time: DateTime<Utc>,
+) -> Option<String> {
+    let DevicePayload::Diagnostics {
+        rssi_dbm,
+        free_heap_bytes,
+        boot_count,
+        reset_reason,
+    } = payload
+    else {
+        return None;
+    };
+    Some(format!(
+        "{},device={} rssi_dbm={}i,free_heap_bytes={}i,boot_count={}i,reset_reason=\"{}\" {}",
+        MEASUREMENT,
+        escape_tag(device),
+        rssi_dbm,
+        free_heap_bytes,
+        boot_count,
+        escape_string_field(reset_reason),
+        time.timestamp_nanos_opt().unwrap_or(0)
+    ))
+}
+
+/// Writes `payload` if it is a diagnostics report
+pub async fn save(
+    store: &impl PointStore,
+    device: &str,
+    payload: &DevicePayload,
+    time: DateTime<Utc>,
+) -> Result<(), WriteError> {
+    match diagnostics_line(device, payload, time) {
+        Some(line) => store.write(&[line]).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn diagnostics_become_one_point() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            diagnostics_line(
+                "living room",
+                &DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep"),
+                time
+            )
+            .unwrap(),
+            "device_diagnostics,device=living\\ room rssi_dbm=-67i,free_heap_bytes=182344i,\
+             boot_count=41i,reset_reason=\"deep_sleep\" 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn other_payloads_are_not_diagnostics() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            diagnostics_line("kitchen", &DevicePayload::Alive { uptime_seconds: 5 }, time),
+            None
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! One-off events on a device.
+//!
+//! Results that only come when asked for, like the sensor's self test, and
+//! the errors a device reports are written to the `device_events`
+//! measurement, one point each, tagged with the event so that a history of
+//! them can be queried per device. Errors, `error` as well as the failed
+//! commands' `*_error` payloads, are also tagged with their `code`,
+//! so an alert can watch one failure class, e.g. `code='i2c_error'`.
+
+use chrono::{DateTime, Utc};
+use shared_types::line_protocol::escape_tag;
+use shared_types::{DevicePayload, ErrorCode};
+
+use crate::bulk_write::{PointStore, WriteError};
+use crate::device_config::escape_string_field;
+
+pub const MEASUREMENT: &str = "device_events";
+
+/// The point for `payload`, if it is an event
+pub fn event_line(device: &str, payload: &DevicePayload, time: DateTime<Utc>) -> Option<String> {
+    let (event, code, fields) = match payload {
+        DevicePayload::SelfTestResult { passed, detail } => (
+            "self_test",
+            None,
+            format!(
+                "passed={},detail=\"{}\"",
+                passed,
+                escape_string_field(detail)
+            ),
+        ),
+        _ => {
+            let (event, code, detail) = error_event(payload)?;
+            (
+                event,
+                Some(code),
+                format!("detail=\"{}\"", escape_string_field(detail)),
+            )
+        }
+    };
+    let mut tags = format!("device={},event={}", escape_tag(device), event);
+    if let Some(code) = code {
+        tags.push_str(&format!(",code={}", code));
+    }
+    Some(format!(
+        "{},{} {} {}",
+        MEASUREMENT,
+        tags,
+        fields,
+        time.timestamp_nanos_opt().unwrap_or(0)
+    ))
+}
+
+/// The status, code and detail of `error` and the other `*_error` payloads
+fn error_event(payload: &DevicePayload) -> Option<(&'static str, ErrorCode, &str)> {
+    Some(match payload {
+        DevicePayload::Error { code, detail } => ("error", *code, detail.as_str()),
+        DevicePayload::FrcError { code, detail } => ("frc_error", *code, detail.as_str()),
+        DevicePayload::SetOffsetError { code, detail } => {
+            ("set_offset_error", *code, detail.as_str())
+        }
+        DevicePayload::SetDeepSleepTimeError { code, detail } => {
+            ("set_deep_sleep_time_error", *code, detail.as_str())
+        }
+        DevicePayload::GetDeepSleepTimeError { code, detail } => {
+            ("get_deep_sleep_time_error", *code, detail.as_str())
+        }
+        DevicePayload::GetOffsetError { code, detail } => {
+            ("get_offset_error", *code, detail.as_str())
+        }
+        DevicePayload::SetMqttPolicyError { code, detail } => {
+            ("set_mqtt_policy_error", *code, detail.as_str())
+        }
+        DevicePayload::OtaError { code, detail } => ("ota_error", *code, detail.as_str()),
+        DevicePayload::SetLogLevelError { code, detail } => {
+            ("set_log_level_error", *code, detail.as_str())
+        }
+        DevicePayload::SetAdaptiveModeError { code, detail } => {
+            ("set_adaptive_mode_error", *code, detail.as_str())
+        }
+        DevicePayload::AscError { code, detail } => ("asc_error", *code, detail.as_str()),
+        DevicePayload::AltitudeError { code, detail } => ("altitude_error", *code, detail.as_str()),
+        DevicePayload::AmbientPressureError { code, detail } => {
+            ("ambient_pressure_error", *code, detail.as_str())
+        }
+        DevicePayload::ConfirmConfigError { code, detail } => {
+            ("confirm_config_error", *code, detail.as_str())
+        }
+        DevicePayload::FactoryResetError { code, detail } => {
+            ("factory_reset_error", *code, detail.as_str())
+        }
+        _ => return None,
+    })
+}
+
+/// Writes `payload` if it is an event
+pub async fn save(
+    store: &impl PointStore,
+    device: &str,
+    payload: &DevicePayload,
+    time: DateTime<Utc>,
+) -> Result<(), WriteError> {
+    match event_line(device, payload, time) {
+        Some(line) => store.write(&[line]).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn a_self_test_becomes_one_point() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            event_line(
+                "living room",
+                &DevicePayload::SelfTestResult {
+                    passed: false,
+                    detail: "failed_to_run: I2c(\"timeout\")".to_string(),
+                },
+                time
+            )
+            .unwrap(),
+            "device_events,device=living\\ room,event=self_test passed=false,\
+             detail=\"failed_to_run: I2c(\\\"timeout\\\")\" 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn errors_are_tagged_with_their_code() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            event_line(
+                "kitchen",
+                &DevicePayload::coded_error(ErrorCode::SensorTimeout, "Measurement timed out"),
+                time
+            )
+            .unwrap(),
+            "device_events,device=kitchen,event=error,code=sensor_timeout \
+             detail=\"Measurement timed out\" 1736942400000000000"
+        );
+        // Older firmware sends no code
+        assert_eq!(
+            event_line(
+                "kitchen",
+                &DevicePayload::FrcError {
+                    code: ErrorCode::Other,
+                    detail: "I2C(Nack)".to_string(),
+                },
+                time
+            )
+            .unwrap(),
+            "device_events,device=kitchen,event=frc_error,code=other \
+             detail=\"I2C(Nack)\" 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn failed_commands_are_tagged_with_their_code() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            event_line(
+                "kitchen",
+                &DevicePayload::AltitudeError {
+                    code: ErrorCode::I2cError,
+                    detail: "failed_to_set: I2C(Nack)".to_string(),
+                },
+                time
+            )
+            .unwrap(),
+            "device_events,device=kitchen,event=altitude_error,code=i2c_error \
+             detail=\"failed_to_set: I2C(Nack)\" 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn other_payloads_are_not_events() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            event_line("kitchen", &DevicePayload::Alive { uptime_seconds: 5 }, time),
+            None
+        );
+    }
+}
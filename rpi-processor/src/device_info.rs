@@ -0,0 +1,67 @@
+//! Which physical sensor a device has.
+//!
+//! A `serial_number` answer is written to the `device_info` measurement,
+//! tagged only with the device, so device names can be mapped to SCD4x
+//! modules for inventory and warranty claims. `KnownSerials` remembers the
+//! serial stored for each device since startup: the same serial again is
+//! not written, a different one (a swapped module) is.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use shared_types::line_protocol::escape_tag;
+
+use crate::bulk_write::{PointStore, WriteError};
+
+pub const MEASUREMENT: &str = "device_info";
+
+/// The point recording `serial` for `device`. 48 bits fit a signed
+/// integer field.
+pub fn serial_line(device: &str, serial: u64, time: DateTime<Utc>) -> String {
+    format!(
+        "{},device={} serial_number={}i {}",
+        MEASUREMENT,
+        escape_tag(device),
+        serial,
+        time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+/// The serial last stored for each device
+#[derive(Debug, Default)]
+pub struct KnownSerials(HashMap<String, u64>);
+
+impl KnownSerials {
+    /// Writes `serial` unless it is already stored for `device`. Returns
+    /// whether it was new. A failed write is tried again with the next
+    /// answer.
+    pub async fn save(
+        &mut self,
+        store: &impl PointStore,
+        device: &str,
+        serial: u64,
+        time: DateTime<Utc>,
+    ) -> Result<bool, WriteError> {
+        if self.0.get(device) == Some(&serial) {
+            return Ok(false);
+        }
+        store.write(&[serial_line(device, serial, time)]).await?;
+        self.0.insert(device.to_string(), serial);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn a_serial_becomes_one_point() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            serial_line("living room", 273_325_796_834_238, time),
+            "device_info,device=living\\ room serial_number=273325796834238i 1736942400000000000"
+        );
+    }
+}
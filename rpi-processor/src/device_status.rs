@@ -0,0 +1,118 @@
+//! Whether a quiet device is asleep or gone.
+//!
+//! Every `entering_sleep` message is written as one point in the
+//! `device_status` measurement, with `expected_next_contact`: the unix time
+//! by which the device should be heard from again. A dashboard showing a
+//! device as late only once that has passed doesn't mistake a long sleep for
+//! a dead device.
+
+use chrono::{DateTime, Utc};
+use shared_types::DevicePayload;
+use shared_types::line_protocol::escape_tag;
+
+use crate::bulk_write::{PointStore, WriteError};
+
+pub const MEASUREMENT: &str = "device_status";
+
+/// From waking up to publishing: warming up the sensor, measuring and
+/// joining Wi-Fi and the broker
+const WAKE_TO_CONTACT_SECONDS: u64 = 30;
+
+/// When the device should be heard from again. Without a synced clock the
+/// device can't name its wake time, so the sleep counts from `time`.
+pub fn expected_next_contact(
+    sleep_seconds: u64,
+    next_wake_unix: Option<u64>,
+    time: DateTime<Utc>,
+) -> u64 {
+    let wake = next_wake_unix
+        .unwrap_or_else(|| (time.timestamp().max(0) as u64).saturating_add(sleep_seconds));
+    wake.saturating_add(WAKE_TO_CONTACT_SECONDS)
+}
+
+/// The point for `payload`, if the device is going to sleep
+pub fn status_line(device: &str, payload: &DevicePayload, time: DateTime<Utc>) -> Option<String> {
+    let DevicePayload::EnteringSleep {
+        sleep_seconds,
+        next_wake_unix,
+    } = *payload
+    else {
+        return None;
+    };
+    let mut fields = format!("state=\"sleeping\",sleep_seconds={}i", sleep_seconds);
+    if let Some(next_wake_unix) = next_wake_unix {
+        fields.push_str(&format!(",next_wake_unix={}i", next_wake_unix));
+    }
+    Some(format!(
+        "{},device={} {},expected_next_contact={}i {}",
+        MEASUREMENT,
+        escape_tag(device),
+        fields,
+        expected_next_contact(sleep_seconds, next_wake_unix, time),
+        time.timestamp_nanos_opt().unwrap_or(0)
+    ))
+}
+
+/// Writes `payload` if the device is going to sleep
+pub async fn save(
+    store: &impl PointStore,
+    device: &str,
+    payload: &DevicePayload,
+    time: DateTime<Utc>,
+) -> Result<(), WriteError> {
+    match status_line(device, payload, time) {
+        Some(line) => store.write(&[line]).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn a_synced_device_names_its_wake_time() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            status_line(
+                "living room",
+                &DevicePayload::EnteringSleep {
+                    sleep_seconds: 300,
+                    next_wake_unix: Some(1_736_942_702),
+                },
+                time
+            )
+            .unwrap(),
+            "device_status,device=living\\ room state=\"sleeping\",sleep_seconds=300i,\
+             next_wake_unix=1736942702i,expected_next_contact=1736942732i 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn without_a_clock_the_sleep_counts_from_the_message() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            status_line(
+                "kitchen",
+                &DevicePayload::EnteringSleep {
+                    sleep_seconds: 300,
+                    next_wake_unix: None,
+                },
+                time
+            )
+            .unwrap(),
+            "device_status,device=kitchen state=\"sleeping\",sleep_seconds=300i,\
+             expected_next_contact=1736942730i 1736942400000000000"
+        );
+    }
+
+    #[test]
+    fn other_payloads_are_not_statuses() {
+        let time = Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(
+            status_line("kitchen", &DevicePayload::Alive { uptime_seconds: 5 }, time),
+            None
+        );
+    }
+}
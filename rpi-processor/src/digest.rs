@@ -0,0 +1,624 @@
+//! End-of-day digest: one message per day summarizing every device.
+//!
+//! The numbers come from the same day data as the data-quality report, plus
+//! the current +1 h forecast. The digest goes to whichever channels are
+//! configured in the environment:
+//!
+//! - `DIGEST_NTFY_URL`: ntfy topic URL, e.g. `https://ntfy.sh/air-quality`
+//! - `DIGEST_WEBHOOK_URL`: receives `{"title", "body", "date"}` as JSON
+//! - `SMTP_HOST`, `SMTP_PORT`, `SMTP_USERNAME`, `SMTP_PASSWORD`,
+//!   `DIGEST_EMAIL_FROM`, `DIGEST_EMAIL_TO` (comma separated), with the
+//!   `email` feature
+//!
+//! With the `charts` feature each device also gets a CO2 sparkline PNG,
+//! attached to the email and uploaded to ntfy. The webhook only gets text.
+//! Without any channel the digest is printed to stdout.
+//!
+//! Each device also gets the ventilation advice from `ventilation`, learned
+//! from the days up to the end of the digest's day.
+//!
+//! The latest predictor retraining of the week up to the end of the day, see
+//! `model_training`, closes the digest.
+
+use std::error::Error;
+
+use chrono::{Duration, NaiveDate};
+use serde_json::json;
+
+use crate::data_quality::{self, QualityWeights};
+use crate::fetcher::Identifier;
+use crate::model_training::{self, TrainingSummary};
+use crate::predictor::{self, Forecast};
+use crate::types::MeasurementWithTime;
+use crate::ventilation::{self, RoomRegistry, VentilationConfig};
+
+/// CO2 level counted as "high" in the digest
+pub const CO2_HIGH_PPM: u16 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceDay {
+    pub device: String,
+    pub samples: usize,
+    pub co2_min: u16,
+    pub co2_mean: f64,
+    pub co2_max: u16,
+    pub hours_above_high: f64,
+    pub anomalies: usize,
+    pub quality_score: Option<f64>,
+    pub ventilation_advice: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digest {
+    pub title: String,
+    pub body: String,
+}
+
+/// A rendered chart ready to attach.
+#[derive(Debug, Clone)]
+pub struct Chart {
+    pub filename: String,
+    pub png: Vec<u8>,
+}
+
+/// Time spent above `threshold`. Each sample stands for the time until the
+/// next one, capped at two intervals so gaps in the data don't count; the
+/// last sample stands for one interval.
+pub fn hours_above(
+    measurements: &[MeasurementWithTime],
+    threshold: u16,
+    expected_interval: Duration,
+) -> f64 {
+    let max_gap = expected_interval * 2;
+    let seconds: i64 = measurements
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.co2 > threshold)
+        .map(|(i, m)| match measurements.get(i + 1) {
+            Some(next) => (next.time - m.time).clamp(Duration::zero(), max_gap),
+            None => expected_interval,
+        })
+        .map(|d| d.num_seconds())
+        .sum();
+    seconds as f64 / 3600.0
+}
+
+/// Summarizes one device's day; measurements must be in time order.
+pub fn summarize_device(
+    device: &str,
+    measurements: &[MeasurementWithTime],
+    anomalies: usize,
+    quality_score: Option<f64>,
+    expected_interval: Duration,
+) -> Option<DeviceDay> {
+    let co2 = measurements.iter().map(|m| m.co2);
+    let co2_min = co2.clone().min()?;
+    let co2_max = co2.clone().max()?;
+    let co2_mean = co2.map(f64::from).sum::<f64>() / measurements.len() as f64;
+    Some(DeviceDay {
+        device: device.to_string(),
+        samples: measurements.len(),
+        co2_min,
+        co2_mean,
+        co2_max,
+        hours_above_high: hours_above(measurements, CO2_HIGH_PPM, expected_interval),
+        anomalies,
+        quality_score,
+        ventilation_advice: None,
+    })
+}
+
+pub fn build_digest(
+    date: NaiveDate,
+    devices: &[DeviceDay],
+    forecast: Option<&Forecast>,
+    training: Option<&TrainingSummary>,
+) -> Digest {
+    let title = format!("Air quality digest for {}", date);
+    let mut lines = vec![title.clone(), String::new()];
+
+    if devices.is_empty() {
+        lines.push(format!("No measurements were received on {}.", date));
+    }
+    for day in devices {
+        lines.push(format!("{} ({} measurements)", day.device, day.samples));
+        lines.push(format!(
+            "  CO2 min/mean/max: {} / {:.0} / {} ppm",
+            day.co2_min, day.co2_mean, day.co2_max
+        ));
+        lines.push(format!(
+            "  Above {} ppm: {:.1} h",
+            CO2_HIGH_PPM, day.hours_above_high
+        ));
+        lines.push(format!("  Anomalies: {}", day.anomalies));
+        if let Some(score) = day.quality_score {
+            lines.push(format!("  Data quality: {:.0}/100", score));
+        }
+        if let Some(advice) = &day.ventilation_advice {
+            lines.push(format!("  Ventilation: {}", advice));
+        }
+        lines.push(String::new());
+    }
+
+    match forecast {
+        Some(f) => lines.push(format!(
+            "Forecast for {}: CO2 {:.0} ppm, {:.1} °C, {:.0} % RH",
+            f.target_time.format("%Y-%m-%d %H:%M UTC"),
+            f.co2,
+            f.temperature,
+            f.humidity
+        )),
+        None => lines.push("Forecast: not available".to_string()),
+    }
+    if let Some(training) = training {
+        lines.push(training.describe());
+    }
+
+    Digest {
+        title,
+        body: lines.join("\n"),
+    }
+}
+
+/// Renders a small CO2 line chart as PNG.
+#[cfg(feature = "charts")]
+pub fn sparkline_png(values: &[f64]) -> Result<Vec<u8>, Box<dyn Error>> {
+    use plotters::prelude::*;
+
+    const WIDTH: u32 = 240;
+    const HEIGHT: u32 = 48;
+
+    let mut rgb = vec![0u8; (WIDTH * HEIGHT * 3) as usize];
+    {
+        let root = BitMapBackend::with_buffer(&mut rgb, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE)?;
+
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        // Keep a flat line off the edges
+        let (low, high) = if min < max {
+            (min, max)
+        } else {
+            (min - 1.0, min + 1.0)
+        };
+
+        let mut chart = ChartBuilder::on(&root)
+            .margin(2)
+            .build_cartesian_2d(0..values.len().max(2) - 1, low..high)?;
+        chart.draw_series(LineSeries::new(values.iter().copied().enumerate(), &BLUE))?;
+        root.present()?;
+    }
+
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&rgb)?;
+    Ok(png_bytes)
+}
+
+#[cfg(feature = "email")]
+#[derive(Debug, Clone)]
+pub struct EmailConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DigestChannels {
+    pub ntfy_url: Option<String>,
+    pub webhook_url: Option<String>,
+    #[cfg(feature = "email")]
+    pub email: Option<EmailConfig>,
+}
+
+impl DigestChannels {
+    pub fn from_env() -> Self {
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        #[cfg(feature = "email")]
+        let email = match (
+            var("SMTP_HOST"),
+            var("DIGEST_EMAIL_FROM"),
+            var("DIGEST_EMAIL_TO"),
+        ) {
+            (Some(host), Some(from), Some(to)) => Some(EmailConfig {
+                host,
+                port: var("SMTP_PORT").and_then(|p| p.parse().ok()).unwrap_or(587),
+                username: var("SMTP_USERNAME"),
+                password: var("SMTP_PASSWORD"),
+                from,
+                to: to.split(',').map(|a| a.trim().to_string()).collect(),
+            }),
+            _ => None,
+        };
+        Self {
+            ntfy_url: var("DIGEST_NTFY_URL"),
+            webhook_url: var("DIGEST_WEBHOOK_URL"),
+            #[cfg(feature = "email")]
+            email,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        #[cfg(feature = "email")]
+        if self.email.is_some() {
+            return false;
+        }
+        self.ntfy_url.is_none() && self.webhook_url.is_none()
+    }
+}
+
+async fn send_ntfy(
+    reqwest_client: &reqwest::Client,
+    url: &str,
+    digest: &Digest,
+    charts: &[Chart],
+) -> Result<(), Box<dyn Error>> {
+    reqwest_client
+        .post(url)
+        .header("Title", &digest.title)
+        .body(digest.body.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    // ntfy takes one attachment per message
+    for chart in charts {
+        reqwest_client
+            .put(url)
+            .header("Filename", &chart.filename)
+            .header("Title", &digest.title)
+            .body(chart.png.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+async fn send_webhook(
+    reqwest_client: &reqwest::Client,
+    url: &str,
+    date: NaiveDate,
+    digest: &Digest,
+) -> Result<(), Box<dyn Error>> {
+    reqwest_client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&json!({
+            "title": digest.title,
+            "body": digest.body,
+            "date": date.to_string(),
+        }))?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(feature = "email")]
+async fn send_email(
+    config: &EmailConfig,
+    digest: &Digest,
+    charts: &[Chart],
+) -> Result<(), Box<dyn Error>> {
+    use lettre::message::header::ContentType;
+    use lettre::message::{Attachment, MultiPart, SinglePart};
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+    let mut builder = Message::builder()
+        .from(config.from.parse()?)
+        .subject(digest.title.clone());
+    for to in &config.to {
+        builder = builder.to(to.parse()?);
+    }
+
+    let mut parts = MultiPart::mixed().singlepart(SinglePart::plain(digest.body.clone()));
+    for chart in charts {
+        parts = parts.singlepart(
+            Attachment::new(chart.filename.clone())
+                .body(chart.png.clone(), ContentType::parse("image/png")?),
+        );
+    }
+    let message = builder.multipart(parts)?;
+
+    let mut transport =
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?.port(config.port);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport.build().send(message).await?;
+    Ok(())
+}
+
+/// Builds the digest for `date` and delivers it to every configured channel.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_digest(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    date: NaiveDate,
+    expected_interval: Duration,
+    weights: &QualityWeights,
+    rooms: &RoomRegistry,
+    ventilation_config: &VentilationConfig,
+    channels: &DigestChannels,
+    event_kinds: &[Identifier],
+) -> Result<Digest, Box<dyn Error>> {
+    let day = data_quality::fetch_day(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        date,
+    )
+    .await?;
+
+    let end_of_day = (date + Duration::days(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    let mut devices = Vec::new();
+    #[cfg_attr(not(feature = "charts"), allow(unused_mut))]
+    let mut charts: Vec<Chart> = Vec::new();
+    for (device, measurements) in &day.measurements {
+        let anomalies = day.anomalies.get(device).copied().unwrap_or(0);
+        let inputs = data_quality::day_inputs(measurements, anomalies, expected_interval);
+        let quality = data_quality::score_day(&inputs, weights);
+        let Some(mut summary) = summarize_device(
+            device,
+            measurements,
+            anomalies,
+            Some(quality.score),
+            expected_interval,
+        ) else {
+            continue;
+        };
+        let recommendation = match Identifier::parse(device) {
+            Ok(name) => {
+                ventilation::fetch_recommendation(
+                    influx_host,
+                    influx_token,
+                    influx_database,
+                    reqwest_client,
+                    &name,
+                    end_of_day,
+                    rooms,
+                    ventilation_config,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        match recommendation {
+            Ok(recommendation) => summary.ventilation_advice = Some(recommendation.advice),
+            Err(e) => log::warn!("Ventilation advice for {} failed: {}", device, e),
+        }
+        devices.push(summary);
+
+        #[cfg(feature = "charts")]
+        {
+            let values: Vec<f64> = measurements.iter().map(|m| m.co2 as f64).collect();
+            match sparkline_png(&values) {
+                Ok(png) => charts.push(Chart {
+                    filename: format!("{}-{}.png", device, date),
+                    png,
+                }),
+                Err(e) => log::warn!("Failed to render chart for {}: {}", device, e),
+            }
+        }
+    }
+
+    let forecast = match model_training::champion_cutoff() {
+        Ok(training_until) => {
+            predictor::predict_weather(
+                influx_host,
+                influx_token,
+                influx_database,
+                reqwest_client,
+                None,
+                training_until,
+                event_kinds,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let forecast = match forecast {
+        Ok(forecast) => forecast,
+        Err(e) => {
+            log::warn!("Forecast for the digest failed: {}", e);
+            None
+        }
+    };
+
+    let training = match model_training::fetch_latest(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        end_of_day - Duration::days(7),
+        end_of_day,
+    )
+    .await
+    {
+        Ok(training) => training,
+        Err(e) => {
+            log::warn!("Could not read the latest retraining: {}", e);
+            None
+        }
+    };
+
+    let digest = build_digest(date, &devices, forecast.as_ref(), training.as_ref());
+
+    if channels.is_empty() {
+        println!("{}", digest.body);
+        return Ok(digest);
+    }
+    if let Some(url) = &channels.ntfy_url {
+        match send_ntfy(reqwest_client, url, &digest, &charts).await {
+            Ok(()) => log::info!("Digest sent to ntfy"),
+            Err(e) => log::error!("Failed to send digest to ntfy: {}", e),
+        }
+    }
+    if let Some(url) = &channels.webhook_url {
+        match send_webhook(reqwest_client, url, date, &digest).await {
+            Ok(()) => log::info!("Digest sent to webhook"),
+            Err(e) => log::error!("Failed to send digest to webhook: {}", e),
+        }
+    }
+    #[cfg(feature = "email")]
+    if let Some(email) = &channels.email {
+        match send_email(email, &digest, &charts).await {
+            Ok(()) => log::info!("Digest emailed to {}", email.to.join(", ")),
+            Err(e) => log::error!("Failed to email digest: {}", e),
+        }
+    }
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::minutes(minutes)
+    }
+
+    fn sample(minutes: i64, co2: u16) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 21.0,
+            humidity: 40.0,
+            time: at(minutes),
+            device: "esp32-scd40".to_string(),
+        }
+    }
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 1, 15).unwrap()
+    }
+
+    #[test]
+    fn hours_above_counts_until_next_sample_and_skips_gaps() {
+        let interval = Duration::minutes(5);
+        let day = vec![
+            sample(0, 900),
+            sample(5, 1100),  // 5 min
+            sample(10, 1200), // 5 min
+            sample(15, 800),
+            sample(20, 1300),  // gap to next capped at 10 min
+            sample(200, 1050), // last sample: one interval
+        ];
+        let hours = hours_above(&day, CO2_HIGH_PPM, interval);
+        assert!((hours - 25.0 / 60.0).abs() < 1e-9, "{}", hours);
+    }
+
+    #[test]
+    fn device_summary() {
+        let day = vec![sample(0, 600), sample(5, 1200), sample(10, 900)];
+        let summary =
+            summarize_device("esp32-scd40", &day, 2, Some(88.0), Duration::minutes(5)).unwrap();
+        assert_eq!(summary.samples, 3);
+        assert_eq!(summary.co2_min, 600);
+        assert_eq!(summary.co2_max, 1200);
+        assert_eq!(summary.co2_mean, 900.0);
+        assert!((summary.hours_above_high - 5.0 / 60.0).abs() < 1e-9);
+        assert_eq!(summary.anomalies, 2);
+
+        assert!(summarize_device("esp32-scd40", &[], 0, None, Duration::minutes(5)).is_none());
+    }
+
+    #[test]
+    fn digest_body() {
+        let devices = vec![DeviceDay {
+            device: "esp32-scd40".to_string(),
+            samples: 280,
+            co2_min: 420,
+            co2_mean: 734.6,
+            co2_max: 1450,
+            hours_above_high: 2.34,
+            anomalies: 3,
+            quality_score: Some(91.7),
+            ventilation_advice: Some(
+                "Ventilate the office ~10 minutes every 2 hours during occupancy \
+                 to stay under 1000 ppm."
+                    .to_string(),
+            ),
+        }];
+        let forecast = Forecast {
+            based_on: at(21 * 60 + 55),
+            target_time: at(22 * 60 + 55),
+            co2: 812.4,
+            temperature: 21.43,
+            humidity: 45.2,
+        };
+        let digest = build_digest(date(), &devices, Some(&forecast), None);
+        assert_eq!(digest.title, "Air quality digest for 2025-01-15");
+        assert_eq!(
+            digest.body,
+            "Air quality digest for 2025-01-15\n\
+             \n\
+             esp32-scd40 (280 measurements)\n  \
+             CO2 min/mean/max: 420 / 735 / 1450 ppm\n  \
+             Above 1000 ppm: 2.3 h\n  \
+             Anomalies: 3\n  \
+             Data quality: 92/100\n  \
+             Ventilation: Ventilate the office ~10 minutes every 2 hours during occupancy \
+             to stay under 1000 ppm.\n\
+             \n\
+             Forecast for 2025-01-15 22:55 UTC: CO2 812 ppm, 21.4 °C, 45 % RH"
+        );
+    }
+
+    #[test]
+    fn empty_day_digest() {
+        let digest = build_digest(date(), &[], None, None);
+        assert!(
+            digest
+                .body
+                .contains("No measurements were received on 2025-01-15.")
+        );
+        assert!(digest.body.ends_with("Forecast: not available"));
+    }
+
+    #[test]
+    fn digest_ends_with_the_latest_retraining() {
+        let training = TrainingSummary {
+            at: at(-3 * 24 * 60),
+            verdict: model_training::Verdict::Worse,
+            holdout_samples: 1900,
+            tolerance_percent: 2.0,
+            challenger_co2_mae: Some(47.25),
+            champion_co2_mae: Some(41.0),
+        };
+        let digest = build_digest(date(), &[], None, Some(&training));
+        assert!(
+            digest.body.ends_with(
+                "Forecast: not available\n\
+                 Model retraining on 2025-01-12: previous model kept, \
+                 CO2 error 47.2 ppm vs 41.0 ppm"
+            ),
+            "{}",
+            digest.body
+        );
+    }
+
+    #[cfg(feature = "charts")]
+    #[test]
+    fn sparkline_is_png() {
+        let png = sparkline_png(&[600.0, 800.0, 750.0, 1200.0]).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // A flat day renders too
+        assert!(sparkline_png(&[500.0; 10]).is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+//! Stopping a web request's work when its client goes away.
+//!
+//! When a connection closes before the response is written, axum drops the
+//! handler's future, and with it whatever InfluxDB query it was waiting on.
+//! `cancel_on_disconnect` makes that explicit: every request gets a
+//! `CancellationToken` in its extensions, which is cancelled if the request
+//! is dropped unfinished, and each query made for the request races it, so
+//! no query outlives the client that asked for it. Dropped requests are
+//! counted for `/metrics`.
+//!
+//! Nothing a handler starts runs on a task of its own. A prediction is
+//! computed inside the request that asked for it first; if that one goes
+//! away, a request waiting for the same prediction takes over. Training data
+//! is loaded at startup, outside any request.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio_util::sync::CancellationToken;
+
+const METRIC: &str = "web_requests_cancelled";
+
+/// Requests whose client disconnected before they were answered
+#[derive(Debug, Default)]
+pub struct Disconnects {
+    cancelled: AtomicU64,
+}
+
+impl Disconnects {
+    pub fn cancelled(&self) -> u64 {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn write_openmetrics(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} counter", METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Web requests dropped because the client disconnected.",
+            METRIC
+        );
+        let _ = writeln!(out, "{}_total {}", METRIC, self.cancelled());
+    }
+}
+
+/// Cancels the token, and counts the request, unless `finish` is called
+/// first
+struct Pending {
+    token: CancellationToken,
+    disconnects: Arc<Disconnects>,
+    path: String,
+    finished: bool,
+}
+
+impl Pending {
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.token.cancel();
+        self.disconnects.cancelled.fetch_add(1, Ordering::Relaxed);
+        log::info!("Client went away, cancelled {}", self.path);
+    }
+}
+
+/// Middleware giving each request the token its queries stop on
+pub async fn cancel_on_disconnect(
+    State(disconnects): State<Arc<Disconnects>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = CancellationToken::new();
+    request.extensions_mut().insert(token.clone());
+    let pending = Pending {
+        token,
+        disconnects,
+        path: request.uri().path().to_string(),
+        finished: false,
+    };
+    let response = next.run(request).await;
+    pending.finish();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unfinished_requests_are_cancelled() {
+        let disconnects = Arc::new(Disconnects::default());
+        let pending = |token: &CancellationToken| Pending {
+            token: token.clone(),
+            disconnects: disconnects.clone(),
+            path: "/api/predict".to_string(),
+            finished: false,
+        };
+
+        let answered = CancellationToken::new();
+        pending(&answered).finish();
+        assert!(!answered.is_cancelled());
+
+        let dropped = CancellationToken::new();
+        drop(pending(&dropped));
+        assert!(dropped.is_cancelled());
+
+        let mut out = String::new();
+        disconnects.write_openmetrics(&mut out);
+        assert_eq!(
+            out,
+            "# TYPE web_requests_cancelled counter\n\
+             # HELP web_requests_cancelled Web requests dropped because the client disconnected.\n\
+             web_requests_cancelled_total 1\n"
+        );
+    }
+}
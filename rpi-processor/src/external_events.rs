@@ -0,0 +1,403 @@
+//! Things that happened around a device that its readings can't tell: a
+//! party, a window left open, a new HVAC filter.
+//!
+//! Events are added with `POST /api/events` or `--add-event` and stored in
+//! the `external_events` measurement, one point per event at its start,
+//! tagged with the device and kind, with the end and an optional note as
+//! fields. An event covers `from` to `to`, both inclusive, so one that took
+//! no time at all (a filter change) has them equal. The anomaly context
+//! endpoint and the daily report show the events overlapping what they
+//! cover, and the predictor can learn from them, see `EventFeature`.
+//!
+//! Two events of the same kind starting at the same time on one device are
+//! the same point; the later one replaces the earlier.
+
+use std::error::Error;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use shared_types::line_protocol::escape_tag;
+
+use crate::device_config::escape_string_field;
+use crate::fetcher::{Identifier, Sql, query_rows};
+
+pub const MEASUREMENT: &str = "external_events";
+
+/// Longest event accepted; it bounds how far back an overlap query looks
+pub const MAX_SPAN_DAYS: i64 = 31;
+
+pub const MAX_NOTE_LEN: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExternalEvent {
+    pub device: String,
+    pub kind: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// An event as posted to `/api/events`
+#[derive(Debug, Deserialize)]
+pub struct EventRequest {
+    pub device: String,
+    pub kind: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub note: Option<String>,
+}
+
+impl ExternalEvent {
+    /// Checks the names, the range and the note. A blank note is no note.
+    pub fn new(
+        device: &str,
+        kind: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        note: Option<&str>,
+    ) -> Result<Self, String> {
+        let device = Identifier::parse(device)?;
+        let kind = Identifier::parse(kind).map_err(|e| format!("kind: {}", e))?;
+        if to < from {
+            return Err(format!("'to' ({}) is before 'from' ({})", to, from));
+        }
+        if to - from > Duration::days(MAX_SPAN_DAYS) {
+            return Err(format!(
+                "events last at most {} days, this one {}",
+                MAX_SPAN_DAYS,
+                (to - from).num_days()
+            ));
+        }
+        let note = note.map(str::trim).filter(|note| !note.is_empty());
+        if let Some(note) = note
+            && note.chars().count() > MAX_NOTE_LEN
+        {
+            return Err(format!("notes are at most {} characters", MAX_NOTE_LEN));
+        }
+        Ok(Self {
+            device: device.to_string(),
+            kind: kind.to_string(),
+            from,
+            to,
+            note: note.map(str::to_string),
+        })
+    }
+
+    /// Whether the event covers any of `from` to `to`, both inclusive
+    pub fn overlaps(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        self.from <= to && self.to >= from
+    }
+
+    pub fn line(&self) -> String {
+        let mut fields = format!("until=\"{}\"", self.to.to_rfc3339());
+        if let Some(note) = &self.note {
+            fields.push_str(&format!(",note=\"{}\"", escape_string_field(note)));
+        }
+        format!(
+            "{},device={},kind={} {} {}",
+            MEASUREMENT,
+            escape_tag(&self.device),
+            escape_tag(&self.kind),
+            fields,
+            self.from.timestamp_nanos_opt().unwrap_or(0)
+        )
+    }
+}
+
+impl TryFrom<EventRequest> for ExternalEvent {
+    type Error = String;
+
+    fn try_from(request: EventRequest) -> Result<Self, Self::Error> {
+        Self::new(
+            &request.device,
+            &request.kind,
+            request.from,
+            request.to,
+            request.note.as_deref(),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct EventRow {
+    time: String,
+    device: Option<String>,
+    kind: Option<String>,
+    until: Option<String>,
+    note: Option<String>,
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = if value.ends_with('Z') || value.contains('+') {
+        value.to_string()
+    } else {
+        format!("{}Z", value)
+    };
+    DateTime::parse_from_rfc3339(&value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+impl EventRow {
+    fn into_event(self) -> Option<ExternalEvent> {
+        let from = parse_time(&self.time)?;
+        Some(ExternalEvent {
+            device: self.device?,
+            kind: self.kind?,
+            from,
+            to: self.until.as_deref().and_then(parse_time).unwrap_or(from),
+            note: self.note,
+        })
+    }
+}
+
+/// Events overlapping `from` to `to`, of one device or all of them, by start
+pub async fn fetch_overlapping(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: Option<&Identifier>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<ExternalEvent>, Box<dyn Error>> {
+    // An event overlapping `from` started at most MAX_SPAN_DAYS before it
+    let mut sql = Sql::new("SELECT * FROM ")
+        .push(MEASUREMENT)
+        .push(" WHERE time >= ")
+        .time(from - Duration::days(MAX_SPAN_DAYS))
+        .push(" AND time <= ")
+        .time(to);
+    if let Some(device) = device {
+        sql = sql.push(" AND device = ").identifier(device);
+    }
+    let rows: Vec<EventRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql.push(" ORDER BY time ASC"),
+    )
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(EventRow::into_event)
+        .filter(|event| event.overlaps(from, to))
+        .collect())
+}
+
+/// Like `fetch_overlapping`, but a missing table or a failed query is no
+/// events, which is what the reports need: the table only exists once the
+/// first event was added.
+pub async fn overlapping_or_none(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: Option<&Identifier>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<ExternalEvent> {
+    match fetch_overlapping(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        device,
+        from,
+        to,
+    )
+    .await
+    {
+        Ok(events) => events,
+        Err(e) => {
+            log::warn!("Could not read external events, leaving them out: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// The predictor's "external event in progress" feature: 1 for a
+/// measurement covered by an event of one of the chosen kinds on its
+/// device, 0 otherwise. Turned on with `--predictor-event-kinds`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFeature {
+    events: Vec<ExternalEvent>,
+}
+
+impl EventFeature {
+    /// Keeps the events of `kinds`
+    pub fn new(events: Vec<ExternalEvent>, kinds: &[Identifier]) -> Self {
+        Self {
+            events: events
+                .into_iter()
+                .filter(|event| kinds.iter().any(|kind| kind.as_str() == event.kind))
+                .collect(),
+        }
+    }
+
+    pub fn value(&self, device: &str, time: DateTime<Utc>) -> f64 {
+        let covered = self
+            .events
+            .iter()
+            .any(|event| event.device == device && event.overlaps(time, time));
+        if covered { 1.0 } else { 0.0 }
+    }
+
+    /// `None` without any kinds, so the feature is left out. Otherwise every
+    /// stored event of those kinds; if they can't be read, the feature is
+    /// there but never set.
+    pub async fn fetch(
+        influx_host: &str,
+        influx_token: &str,
+        influx_database: &str,
+        reqwest_client: &reqwest::Client,
+        kinds: &[Identifier],
+    ) -> Option<Self> {
+        if kinds.is_empty() {
+            return None;
+        }
+        let sql = Sql::new("SELECT * FROM ")
+            .push(MEASUREMENT)
+            .push(" WHERE kind IN (")
+            .identifiers(kinds)
+            .push(") ORDER BY time ASC");
+        let events = match query_rows::<EventRow>(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &sql,
+        )
+        .await
+        {
+            Ok(rows) => rows.into_iter().filter_map(EventRow::into_event).collect(),
+            Err(e) => {
+                log::warn!("Could not read external events for the predictor: {}", e);
+                Vec::new()
+            }
+        };
+        Some(Self::new(events, kinds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 14, hour, 0, 0).unwrap()
+    }
+
+    fn party() -> ExternalEvent {
+        ExternalEvent::new(
+            "living-room",
+            "party",
+            at(18),
+            at(23),
+            Some("  10 people  "),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn events_are_validated() {
+        assert_eq!(party().note.as_deref(), Some("10 people"));
+        // A filter change takes no time
+        let filter = ExternalEvent::new("kitchen", "hvac_filter", at(9), at(9), Some(" ")).unwrap();
+        assert_eq!(filter.note, None);
+
+        for (device, kind, from, to, note) in [
+            ("living room", "party", at(18), at(23), None),
+            ("living-room", "", at(18), at(23), None),
+            ("living-room", "big party", at(18), at(23), None),
+            ("living-room", "party", at(23), at(18), None),
+            (
+                "living-room",
+                "party",
+                at(18),
+                at(18) + Duration::days(MAX_SPAN_DAYS + 1),
+                None,
+            ),
+            (
+                "living-room",
+                "party",
+                at(18),
+                at(23),
+                Some("x".repeat(MAX_NOTE_LEN + 1)),
+            ),
+        ] {
+            assert!(
+                ExternalEvent::new(device, kind, from, to, note.as_deref()).is_err(),
+                "{} {} {} {} {:?}",
+                device,
+                kind,
+                from,
+                to,
+                note
+            );
+        }
+    }
+
+    #[test]
+    fn overlap_includes_both_ends() {
+        let party = party();
+        assert!(party.overlaps(at(12), at(18)));
+        assert!(party.overlaps(at(20), at(20)));
+        assert!(party.overlaps(at(23), at(23) + Duration::hours(1)));
+        assert!(party.overlaps(at(0), at(23) + Duration::hours(1)));
+        assert!(!party.overlaps(at(12), at(17)));
+        assert!(!party.overlaps(at(23) + Duration::seconds(1), at(23) + Duration::hours(1)));
+    }
+
+    #[test]
+    fn an_event_is_one_point_at_its_start() {
+        let mut party = party();
+        party.note = Some("10 \"guests\"".to_string());
+        assert_eq!(
+            party.line(),
+            "external_events,device=living-room,kind=party \
+             until=\"2025-01-14T23:00:00+00:00\",note=\"10 \\\"guests\\\"\" 1736877600000000000"
+        );
+
+        let row: EventRow = serde_json::from_str(
+            r#"{"time": "2025-01-14T18:00:00", "device": "living-room", "kind": "party",
+                "until": "2025-01-14T23:00:00+00:00", "note": "10 \"guests\""}"#,
+        )
+        .unwrap();
+        assert_eq!(row.into_event(), Some(party));
+    }
+
+    #[test]
+    fn the_feature_is_set_while_an_event_of_a_chosen_kind_runs() {
+        let filter = ExternalEvent::new("living-room", "hvac_filter", at(9), at(9), None).unwrap();
+        let kinds = [Identifier::parse("party").unwrap()];
+        let feature = EventFeature::new(vec![party(), filter], &kinds);
+
+        assert_eq!(feature.value("living-room", at(20)), 1.0);
+        assert_eq!(feature.value("living-room", at(23)), 1.0);
+        assert_eq!(feature.value("living-room", at(17)), 0.0);
+        // Another device, or a kind that wasn't chosen
+        assert_eq!(feature.value("kitchen", at(20)), 0.0);
+        assert_eq!(feature.value("living-room", at(9)), 0.0);
+    }
+
+    #[tokio::test]
+    async fn the_feature_is_only_there_with_kinds() {
+        // Nothing listens here, so every query fails
+        let host = "http://127.0.0.1:9";
+        let client = reqwest::Client::new();
+        assert_eq!(
+            EventFeature::fetch(host, "token", "db", &client, &[]).await,
+            None
+        );
+        // Unreadable events leave the feature in place, never set
+        let kinds = [Identifier::parse("party").unwrap()];
+        assert_eq!(
+            EventFeature::fetch(host, "token", "db", &client, &kinds).await,
+            Some(EventFeature::default())
+        );
+    }
+}
@@ -0,0 +1,927 @@
+//! Leader election for running the receiver on two machines.
+//!
+//! Both instances receive live data, but only the leader runs every ingest
+//! stage: it writes points, raises alerts and runs the alert scheduler. The
+//! follower runs `pipeline::FOLLOWER_STAGES`, which keep `/freshness` and
+//! the like current without writing to InfluxDB, refuses changes through the
+//! web API, and restores the rest from InfluxDB when it takes over.
+//!
+//! The leader holds a lease, retained on `LEASE_TOPIC`: its instance id and
+//! when it last renewed the lease, which it does every third of
+//! `ElectionConfig::lease`. A follower takes over once the lease has run out
+//! by both clocks: the heartbeat must be older than the lease plus
+//! `skew_margin`, and no renewal may have arrived for a whole lease. The
+//! first check covers a leader whose clock runs behind, the second one whose
+//! renewals stopped arriving before they got old.
+//!
+//! Taking over is a claim: the follower publishes its own lease and only
+//! leads once the broker hands it back. When both instances claim at once,
+//! or a leader cut off from the broker comes back, each sees the other's
+//! live lease; the instance whose id sorts first keeps it and the other
+//! steps down. A leader that hasn't seen its own renewals come back for a
+//! whole lease steps down by itself, since the follower is about to take
+//! over. Nothing is claimed or renewed while the broker is unreachable, and
+//! after reconnecting an instance waits for the retained lease again before
+//! judging it, so coming back from an outage doesn't unseat the instance
+//! that took over meanwhile.
+//!
+//! Points the receiver writes carry an `instance` tag (`InstanceTagged`),
+//! so if both instances ever write, the duplicates show up as two series
+//! instead of silently overwriting each other.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use shared_types::line_protocol::escape_tag;
+
+use crate::bulk_write::{PointKey, PointStore, WriteError};
+
+pub const LEASE_TOPIC: &str = "air-quality/processor/leader";
+pub const DEFAULT_LEASE_SECONDS: i64 = 30;
+pub const DEFAULT_SKEW_SECONDS: i64 = 5;
+pub const INSTANCE_TAG: &str = "instance";
+
+const LEADER_METRIC: &str = "air_quality_failover_leader";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub instance: String,
+    pub heartbeat: DateTime<Utc>,
+}
+
+impl Lease {
+    /// Reads what was retained on `LEASE_TOPIC`; nothing means the lease was
+    /// cleared.
+    pub fn from_payload(payload: &[u8]) -> Result<Option<Self>, serde_json::Error> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_slice(payload).map(Some)
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("a lease serializes")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElectionConfig {
+    /// How long a lease lasts without being renewed
+    pub lease: Duration,
+    /// How far apart the instances' clocks may be
+    pub skew_margin: Duration,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Self {
+            lease: Duration::seconds(DEFAULT_LEASE_SECONDS),
+            skew_margin: Duration::seconds(DEFAULT_SKEW_SECONDS),
+        }
+    }
+}
+
+impl ElectionConfig {
+    fn renewal(&self) -> Duration {
+        self.lease / 3
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Role {
+    #[default]
+    Follower,
+    /// Claimed the lease and waiting for the broker to hand the claim back
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+/// What the receiver has to do for the election
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Retain this lease on `LEASE_TOPIC`
+    Publish(Lease),
+    /// Start running every stage
+    Lead,
+    /// Go back to the follower's stages
+    Follow,
+}
+
+#[derive(Debug)]
+pub struct Election {
+    instance: String,
+    config: ElectionConfig,
+    role: Role,
+    started: DateTime<Utc>,
+    /// The lease last seen on the topic, and when it arrived by this clock
+    current: Option<(Lease, DateTime<Utc>)>,
+    /// When this instance last published its lease
+    published: Option<DateTime<Utc>>,
+    /// When one of this instance's leases last came back from the broker
+    echoed: Option<DateTime<Utc>>,
+    connected: bool,
+}
+
+impl Election {
+    pub fn new(instance: impl Into<String>, config: ElectionConfig, now: DateTime<Utc>) -> Self {
+        Self {
+            instance: instance.into(),
+            config,
+            role: Role::Follower,
+            started: now,
+            current: None,
+            published: None,
+            echoed: None,
+            connected: false,
+        }
+    }
+
+    /// Call on connecting to the broker and on losing the connection.
+    pub fn set_connected(&mut self, connected: bool, now: DateTime<Utc>) {
+        self.connected = connected;
+        match self.role {
+            Role::Leader => {}
+            _ if connected => {
+                // Whatever was seen before is out of date; the retained
+                // lease comes with the subscription
+                self.current = None;
+                self.started = now;
+            }
+            _ => self.role = Role::Follower,
+        }
+    }
+
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Who holds a lease that hasn't run out
+    pub fn leader(&self, now: DateTime<Utc>) -> Option<&str> {
+        match &self.current {
+            Some((lease, arrived)) if !self.expired(lease, *arrived, now) => Some(&lease.instance),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> Status {
+        Status {
+            role: self.role,
+            leader: self.leader(now).map(str::to_string),
+        }
+    }
+
+    /// Takes in what arrived on `LEASE_TOPIC`, `None` for a cleared lease.
+    pub fn observe(&mut self, lease: Option<Lease>, now: DateTime<Utc>) -> Vec<Action> {
+        let Some(lease) = lease else {
+            self.current = None;
+            return Vec::new();
+        };
+        if lease.heartbeat - now > self.config.skew_margin {
+            log::warn!(
+                "Lease from {} is {}s ahead of this clock, more than the {}s skew margin",
+                lease.instance,
+                (lease.heartbeat - now).num_seconds(),
+                self.config.skew_margin.num_seconds()
+            );
+        }
+        if lease.instance == self.instance {
+            self.current = Some((lease, now));
+            self.echoed = Some(now);
+            if self.role == Role::Candidate {
+                self.role = Role::Leader;
+                return vec![Action::Lead];
+            }
+            return Vec::new();
+        }
+
+        let live = now - lease.heartbeat <= self.config.lease + self.config.skew_margin;
+        let yields = lease.instance < self.instance;
+        self.current = Some((lease, now));
+        match self.role {
+            Role::Follower => Vec::new(),
+            // An old lease delivered late isn't a rival
+            _ if !live => Vec::new(),
+            Role::Candidate if yields => {
+                self.role = Role::Follower;
+                Vec::new()
+            }
+            Role::Leader if yields => {
+                self.role = Role::Follower;
+                vec![Action::Follow]
+            }
+            // Tell the other instance to step down
+            _ => {
+                self.published = Some(now);
+                vec![Action::Publish(self.lease(now))]
+            }
+        }
+    }
+
+    /// Claims, renews or gives up the lease as time passes. Call it every
+    /// second or so.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<Action> {
+        match self.role {
+            Role::Follower if !self.connected => Vec::new(),
+            Role::Follower => {
+                let vacant = match &self.current {
+                    Some((lease, arrived)) => self.expired(lease, *arrived, now),
+                    // Give a retained lease time to arrive
+                    None => now - self.started > self.config.lease + self.config.skew_margin,
+                };
+                if !vacant {
+                    return Vec::new();
+                }
+                self.role = Role::Candidate;
+                self.published = Some(now);
+                vec![Action::Publish(self.lease(now))]
+            }
+            Role::Candidate => {
+                // The claim never came back; try again once the lease is
+                // still vacant
+                if self
+                    .published
+                    .is_none_or(|published| now - published > self.config.lease)
+                {
+                    self.role = Role::Follower;
+                }
+                Vec::new()
+            }
+            Role::Leader => {
+                if self
+                    .echoed
+                    .is_none_or(|echoed| now - echoed > self.config.lease)
+                {
+                    self.role = Role::Follower;
+                    return vec![Action::Follow];
+                }
+                if self.connected
+                    && self
+                        .published
+                        .is_none_or(|published| now - published >= self.config.renewal())
+                {
+                    self.published = Some(now);
+                    return vec![Action::Publish(self.lease(now))];
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    fn lease(&self, now: DateTime<Utc>) -> Lease {
+        Lease {
+            instance: self.instance.clone(),
+            heartbeat: now,
+        }
+    }
+
+    /// Run out by the holder's clock, with the skew margin, and by the time
+    /// it arrived here
+    fn expired(&self, lease: &Lease, arrived: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - lease.heartbeat > self.config.lease + self.config.skew_margin
+            && now - arrived > self.config.lease
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    pub role: Role,
+    /// Who holds the lease, if anyone does
+    pub leader: Option<String>,
+}
+
+/// The election's status, shared between the receiver and the web server
+#[derive(Debug, Clone, Default)]
+pub struct LeaderStatus(Arc<Mutex<Status>>);
+
+impl LeaderStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, status: Status) {
+        *self.0.lock().unwrap() = status;
+    }
+
+    pub fn get(&self) -> Status {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// A gauge that is 1 while this instance leads
+    pub fn write_openmetrics(&self, out: &mut String) {
+        let leads = self.get().role == Role::Leader;
+        let _ = writeln!(out, "# TYPE {} gauge", LEADER_METRIC);
+        let _ = writeln!(out, "{} {}", LEADER_METRIC, u8::from(leads));
+    }
+}
+
+/// Writes to `store` with the `instance` tag added to every point, when
+/// there is an instance.
+#[derive(Clone, Copy)]
+pub struct InstanceTagged<'a, S> {
+    pub store: S,
+    pub instance: Option<&'a str>,
+}
+
+impl<S: PointStore> PointStore for InstanceTagged<'_, S> {
+    async fn existing(
+        &self,
+        measurement: &'static str,
+        series_tag: &'static str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<HashSet<PointKey>, Box<dyn Error>> {
+        self.store.existing(measurement, series_tag, from, to).await
+    }
+
+    async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+        let Some(instance) = self.instance else {
+            return self.store.write(lines).await;
+        };
+        let tagged: Vec<String> = lines
+            .iter()
+            .map(|line| with_tag(line, INSTANCE_TAG, instance))
+            .collect();
+        self.store.write(&tagged).await
+    }
+}
+
+/// Adds `key=value` to the tags of a line protocol `line`.
+pub fn with_tag(line: &str, key: &str, value: &str) -> String {
+    let end = series_end(line);
+    format!(
+        "{},{}={}{}",
+        &line[..end],
+        key,
+        escape_tag(value),
+        &line[end..]
+    )
+}
+
+/// Where the measurement and its tags end: the first unescaped space
+fn series_end(line: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            ' ' => return i,
+            _ => {}
+        }
+    }
+    line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use std::cell::RefCell;
+
+    fn lease(instance: &str, seconds: i64) -> Lease {
+        Lease {
+            instance: instance.to_string(),
+            heartbeat: at(seconds),
+        }
+    }
+
+    /// Connected since `at(0)`
+    fn election(instance: &str) -> Election {
+        let mut election = Election::new(instance, ElectionConfig::default(), at(0));
+        election.set_connected(true, at(0));
+        election
+    }
+
+    /// Ticks every second in `(from, to]`, returning what came out
+    fn run(election: &mut Election, from: i64, to: i64) -> Vec<(i64, Action)> {
+        (from + 1..=to)
+            .flat_map(|s| {
+                election
+                    .tick(at(s))
+                    .into_iter()
+                    .map(move |action| (s, action))
+            })
+            .collect()
+    }
+
+    /// Two instances on one broker. Published leases reach both after
+    /// `latency` seconds, in the order they were sent. `a` can be cut off
+    /// from the broker, and gets the retained lease when it reconnects.
+    struct Cluster {
+        a: Election,
+        b: Election,
+        latency: i64,
+        a_connected: bool,
+        in_flight: Vec<(i64, Lease)>,
+        retained: Option<Lease>,
+        /// When each instance started or stopped leading
+        leading: Vec<(i64, &'static str, bool)>,
+    }
+
+    impl Cluster {
+        fn new() -> Self {
+            Self {
+                a: election("a"),
+                b: election("b"),
+                latency: 0,
+                a_connected: true,
+                in_flight: Vec::new(),
+                retained: None,
+                leading: Vec::new(),
+            }
+        }
+
+        fn handle(&mut self, s: i64, from_a: bool, actions: Vec<Action>) {
+            let name = if from_a { "a" } else { "b" };
+            for action in actions {
+                match action {
+                    Action::Publish(lease) => {
+                        assert!(!from_a || self.a_connected, "published while offline");
+                        self.in_flight.push((s + self.latency, lease));
+                    }
+                    Action::Lead => self.leading.push((s, name, true)),
+                    Action::Follow => self.leading.push((s, name, false)),
+                }
+            }
+        }
+
+        fn disconnect_a(&mut self, s: i64) {
+            self.a_connected = false;
+            self.a.set_connected(false, at(s));
+        }
+
+        fn reconnect_a(&mut self, s: i64) {
+            self.a_connected = true;
+            self.a.set_connected(true, at(s));
+            let actions = self.a.observe(self.retained.clone(), at(s));
+            self.handle(s, true, actions);
+        }
+
+        fn step(&mut self, s: i64) {
+            let due: Vec<Lease> = self
+                .in_flight
+                .iter()
+                .filter(|(arrives, _)| *arrives <= s)
+                .map(|(_, lease)| lease.clone())
+                .collect();
+            self.in_flight.retain(|(arrives, _)| *arrives > s);
+            for lease in due {
+                self.retained = Some(lease.clone());
+                if self.a_connected {
+                    let actions = self.a.observe(Some(lease.clone()), at(s));
+                    self.handle(s, true, actions);
+                }
+                let actions = self.b.observe(Some(lease), at(s));
+                self.handle(s, false, actions);
+            }
+            let actions = self.a.tick(at(s));
+            self.handle(s, true, actions);
+            let actions = self.b.tick(at(s));
+            self.handle(s, false, actions);
+        }
+
+        fn run(&mut self, from: i64, to: i64) {
+            for s in from + 1..=to {
+                self.step(s);
+            }
+        }
+
+        fn leaders(&self) -> Vec<&str> {
+            [&self.a, &self.b]
+                .into_iter()
+                .filter(|e| e.role() == Role::Leader)
+                .map(|e| e.instance())
+                .collect()
+        }
+
+        /// Seconds in `(from, to]` in which both instances led
+        fn overlap(&self, from: i64, to: i64) -> Vec<i64> {
+            let leads = |name: &str, s: i64| {
+                self.leading
+                    .iter()
+                    .rfind(|(at, who, _)| *who == name && *at <= s)
+                    .is_some_and(|(_, _, leads)| *leads)
+            };
+            (from + 1..=to)
+                .filter(|s| leads("a", *s) && leads("b", *s))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn lease_round_trips_and_clears() {
+        let lease = lease("pi-1", 0);
+        assert_eq!(
+            String::from_utf8(lease.to_payload()).unwrap(),
+            r#"{"instance":"pi-1","heartbeat":"2025-01-15T12:00:00Z"}"#
+        );
+        assert_eq!(
+            Lease::from_payload(&lease.to_payload()).unwrap(),
+            Some(lease)
+        );
+        assert_eq!(Lease::from_payload(b"").unwrap(), None);
+        assert!(Lease::from_payload(b"leader").is_err());
+    }
+
+    #[test]
+    fn claims_a_vacant_lease_after_waiting_for_a_retained_one() {
+        let mut election = election("a");
+        assert!(run(&mut election, 0, 35).is_empty());
+        assert_eq!(
+            run(&mut election, 35, 36),
+            [(36, Action::Publish(lease("a", 36)))]
+        );
+        assert_eq!(election.role(), Role::Candidate);
+        assert_eq!(
+            election.observe(Some(lease("a", 36)), at(36)),
+            [Action::Lead]
+        );
+        assert_eq!(election.role(), Role::Leader);
+        assert_eq!(election.leader(at(36)), Some("a"));
+    }
+
+    #[test]
+    fn leader_renews_every_third_of_the_lease() {
+        let mut election = election("a");
+        run(&mut election, 0, 36);
+        election.observe(Some(lease("a", 36)), at(36));
+        let mut renewals = Vec::new();
+        for s in 37..=70 {
+            for action in election.tick(at(s)) {
+                let Action::Publish(lease) = action else {
+                    panic!("{:?}", action)
+                };
+                election.observe(Some(lease.clone()), at(s));
+                renewals.push(lease.heartbeat);
+            }
+        }
+        assert_eq!(renewals, [at(46), at(56), at(66)]);
+        assert_eq!(election.role(), Role::Leader);
+    }
+
+    #[test]
+    fn follows_a_live_leader() {
+        let mut election = election("b");
+        for s in (0..=120).step_by(10) {
+            election.observe(Some(lease("a", s)), at(s));
+            assert!(election.tick(at(s)).is_empty());
+        }
+        assert_eq!(election.role(), Role::Follower);
+        assert_eq!(election.leader(at(120)), Some("a"));
+    }
+
+    #[test]
+    fn takes_over_once_the_heartbeat_is_stale_by_both_clocks() {
+        let mut election = election("b");
+        election.observe(Some(lease("a", 0)), at(0));
+        // Lease plus skew margin after the heartbeat
+        assert!(run(&mut election, 0, 35).is_empty());
+        assert_eq!(election.leader(at(35)), Some("a"));
+        assert_eq!(
+            run(&mut election, 35, 36),
+            [(36, Action::Publish(lease("b", 36)))]
+        );
+        assert_eq!(election.leader(at(36)), None);
+    }
+
+    #[test]
+    fn leader_clock_behind_does_not_trigger_a_takeover() {
+        let mut election = election("b");
+        // The leader's clock is a minute behind, but its renewals arrive
+        for s in (0..=120).step_by(10) {
+            election.observe(Some(lease("a", s - 60)), at(s));
+            assert!(run(&mut election, s, s + 9).is_empty(), "{}", s);
+        }
+        // Until they stop
+        assert!(run(&mut election, 129, 150).is_empty());
+        assert_eq!(
+            run(&mut election, 150, 151),
+            [(151, Action::Publish(lease("b", 151)))]
+        );
+    }
+
+    #[test]
+    fn leader_clock_ahead_delays_the_takeover_by_its_skew() {
+        let mut election = election("b");
+        // Ahead by the skew margin and some
+        election.observe(Some(lease("a", 10)), at(0));
+        assert!(run(&mut election, 0, 45).is_empty());
+        assert_eq!(
+            run(&mut election, 45, 46),
+            [(46, Action::Publish(lease("b", 46)))]
+        );
+    }
+
+    #[test]
+    fn stale_retained_lease_from_a_dead_leader_is_taken_over() {
+        let mut election = election("b");
+        // Retained on the broker since long before this instance started
+        election.observe(Some(lease("a", -3600)), at(0));
+        assert_eq!(election.leader(at(0)), Some("a"));
+        assert!(run(&mut election, 0, 30).is_empty());
+        assert_eq!(
+            run(&mut election, 30, 31),
+            [(31, Action::Publish(lease("b", 31)))]
+        );
+    }
+
+    #[test]
+    fn cleared_lease_is_claimed_right_away() {
+        let mut election = election("b");
+        for s in (0..=60).step_by(10) {
+            election.observe(Some(lease("a", s)), at(s));
+        }
+        // The leader cleared it on the way out
+        election.observe(None, at(65));
+        assert_eq!(
+            run(&mut election, 65, 66),
+            [(66, Action::Publish(lease("b", 66)))]
+        );
+    }
+
+    #[test]
+    fn candidate_gives_up_a_claim_that_never_comes_back() {
+        let mut election = election("a");
+        run(&mut election, 0, 36);
+        assert_eq!(election.role(), Role::Candidate);
+        assert!(run(&mut election, 36, 66).is_empty());
+        assert_eq!(election.role(), Role::Candidate);
+        assert!(run(&mut election, 66, 67).is_empty());
+        assert_eq!(election.role(), Role::Follower);
+        // And claims again
+        assert_eq!(
+            run(&mut election, 67, 68),
+            [(68, Action::Publish(lease("a", 68)))]
+        );
+    }
+
+    #[test]
+    fn candidate_yields_to_a_rival_that_sorts_first() {
+        let mut election = election("b");
+        run(&mut election, 0, 36);
+        assert!(election.observe(Some(lease("a", 36)), at(36)).is_empty());
+        assert_eq!(election.role(), Role::Follower);
+        // Its own claim coming back afterwards changes nothing
+        assert!(election.observe(Some(lease("b", 36)), at(36)).is_empty());
+        assert_eq!(election.role(), Role::Follower);
+    }
+
+    #[test]
+    fn leader_answers_a_rival_that_sorts_last() {
+        let mut election = election("a");
+        run(&mut election, 0, 36);
+        election.observe(Some(lease("a", 36)), at(36));
+        assert_eq!(
+            election.observe(Some(lease("b", 37)), at(37)),
+            [Action::Publish(lease("a", 37))]
+        );
+        assert_eq!(election.role(), Role::Leader);
+    }
+
+    #[test]
+    fn leader_steps_down_for_a_rival_that_sorts_first() {
+        let mut election = election("b");
+        run(&mut election, 0, 36);
+        election.observe(Some(lease("b", 36)), at(36));
+        assert_eq!(
+            election.observe(Some(lease("a", 37)), at(37)),
+            [Action::Follow]
+        );
+        assert_eq!(election.role(), Role::Follower);
+        assert_eq!(election.leader(at(37)), Some("a"));
+    }
+
+    #[test]
+    fn old_rival_lease_delivered_late_is_ignored() {
+        let mut election = election("b");
+        run(&mut election, 0, 36);
+        election.observe(Some(lease("b", 36)), at(36));
+        assert!(election.observe(Some(lease("a", -60)), at(37)).is_empty());
+        assert_eq!(election.role(), Role::Leader);
+    }
+
+    #[test]
+    fn leader_cut_off_from_the_broker_steps_down() {
+        let mut election = election("a");
+        run(&mut election, 0, 36);
+        election.observe(Some(lease("a", 36)), at(36));
+        // Renewals go out but never come back
+        let actions = run(&mut election, 36, 66);
+        assert!(
+            actions
+                .iter()
+                .all(|(_, action)| matches!(action, Action::Publish(_)))
+        );
+        assert_eq!(run(&mut election, 66, 67), [(67, Action::Follow)]);
+        assert_eq!(election.role(), Role::Follower);
+    }
+
+    #[test]
+    fn exactly_one_instance_leads_after_a_simultaneous_start() {
+        let mut cluster = Cluster::new();
+        cluster.run(0, 120);
+        assert_eq!(cluster.leaders(), ["a"]);
+        assert_eq!(cluster.b.leader(at(120)), Some("a"));
+        // Claimed at 36, handed back a step later
+        assert_eq!(cluster.leading, [(37, "a", true)]);
+    }
+
+    #[test]
+    fn racing_claims_over_a_slow_broker_settle_on_one_leader() {
+        let mut cluster = Cluster::new();
+        cluster.latency = 2;
+        cluster.run(0, 120);
+        assert_eq!(cluster.leaders(), ["a"]);
+        assert_eq!(cluster.leading, [(38, "a", true)]);
+    }
+
+    #[test]
+    fn claim_from_the_instance_sorting_last_may_lead_for_one_delivery() {
+        let mut cluster = Cluster::new();
+        cluster.latency = 2;
+        // b's clock has it claim a second earlier
+        cluster.b = Election::new("b", ElectionConfig::default(), at(-1));
+        cluster.b.set_connected(true, at(-1));
+        cluster.run(0, 120);
+        assert_eq!(cluster.leaders(), ["a"]);
+        assert_eq!(
+            cluster.leading,
+            [(37, "b", true), (38, "a", true), (38, "b", false)]
+        );
+        assert!(cluster.overlap(0, 120).is_empty());
+    }
+
+    #[test]
+    fn follower_takes_over_from_a_leader_cut_off() {
+        let mut cluster = Cluster::new();
+        cluster.run(0, 60);
+        assert_eq!(cluster.leaders(), ["a"]);
+
+        cluster.disconnect_a(60);
+        cluster.run(60, 200);
+        assert_eq!(cluster.leaders(), ["b"]);
+        // a gave up the lease before b claimed it
+        assert_eq!(
+            cluster.leading,
+            [(37, "a", true), (89, "a", false), (94, "b", true)]
+        );
+        assert!(cluster.overlap(0, 200).is_empty());
+    }
+
+    #[test]
+    fn reconnecting_instance_follows_whoever_took_over() {
+        let mut cluster = Cluster::new();
+        cluster.run(0, 60);
+        cluster.disconnect_a(60);
+        cluster.run(60, 200);
+
+        cluster.reconnect_a(200);
+        cluster.run(200, 400);
+        // Even though a sorts first
+        assert_eq!(cluster.leaders(), ["b"]);
+        assert_eq!(cluster.a.role(), Role::Follower);
+        assert_eq!(cluster.a.leader(at(400)), Some("b"));
+        assert_eq!(cluster.leading.len(), 3);
+    }
+
+    #[test]
+    fn short_outage_leaves_the_leader_in_place() {
+        let mut cluster = Cluster::new();
+        cluster.run(0, 60);
+        cluster.disconnect_a(60);
+        cluster.run(60, 75);
+        cluster.reconnect_a(75);
+        cluster.run(75, 200);
+        assert_eq!(cluster.leaders(), ["a"]);
+        assert_eq!(cluster.leading, [(37, "a", true)]);
+    }
+
+    #[test]
+    fn follower_stays_put_while_offline() {
+        let mut election = election("b");
+        election.observe(Some(lease("a", 0)), at(0));
+        election.set_connected(false, at(10));
+        assert!(run(&mut election, 10, 300).is_empty());
+        assert_eq!(election.role(), Role::Follower);
+
+        // After reconnecting it waits for the retained lease again
+        election.set_connected(true, at(300));
+        assert!(run(&mut election, 300, 335).is_empty());
+        assert_eq!(
+            run(&mut election, 335, 336),
+            [(336, Action::Publish(lease("b", 336)))]
+        );
+    }
+
+    #[test]
+    fn candidate_drops_its_claim_when_the_connection_goes() {
+        let mut election = election("a");
+        run(&mut election, 0, 36);
+        assert_eq!(election.role(), Role::Candidate);
+        election.set_connected(false, at(37));
+        assert_eq!(election.role(), Role::Follower);
+        // A claim echoed after all doesn't make it lead
+        assert!(election.observe(Some(lease("a", 36)), at(38)).is_empty());
+    }
+
+    #[test]
+    fn status_reports_role_and_leader() {
+        let mut election = election("b");
+        let status = LeaderStatus::new();
+        assert_eq!(status.get(), Status::default());
+        election.observe(Some(lease("a", 0)), at(0));
+        status.set(election.status(at(0)));
+        assert_eq!(
+            status.get(),
+            Status {
+                role: Role::Follower,
+                leader: Some("a".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn tags_go_before_the_fields() {
+        assert_eq!(
+            with_tag(
+                "scd40_data,device=kitchen co2_ppm=600 1736942400000000000",
+                INSTANCE_TAG,
+                "pi 1"
+            ),
+            "scd40_data,device=kitchen,instance=pi\\ 1 co2_ppm=600 1736942400000000000"
+        );
+        assert_eq!(
+            with_tag(
+                "scd40_data,device=living\\ room co2_ppm=600",
+                INSTANCE_TAG,
+                "pi-1"
+            ),
+            "scd40_data,device=living\\ room,instance=pi-1 co2_ppm=600"
+        );
+    }
+
+    #[derive(Default)]
+    struct MockStore {
+        lines: RefCell<Vec<String>>,
+    }
+
+    impl PointStore for &MockStore {
+        async fn existing(
+            &self,
+            _: &'static str,
+            _: &'static str,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+        ) -> Result<HashSet<PointKey>, Box<dyn Error>> {
+            Ok(HashSet::new())
+        }
+
+        async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+            self.lines.borrow_mut().extend_from_slice(lines);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn tagged_store_tags_only_with_an_instance() {
+        let store = MockStore::default();
+        let line = "ingest_latency,device=kitchen transit_ms=20i".to_string();
+        let untagged = InstanceTagged {
+            store: &store,
+            instance: None,
+        };
+        untagged.write(std::slice::from_ref(&line)).await.unwrap();
+        let tagged = InstanceTagged {
+            store: &store,
+            instance: Some("pi-2"),
+        };
+        tagged.write(&[line]).await.unwrap();
+        assert_eq!(
+            *store.lines.borrow(),
+            [
+                "ingest_latency,device=kitchen transit_ms=20i",
+                "ingest_latency,device=kitchen,instance=pi-2 transit_ms=20i"
+            ]
+        );
+    }
+}
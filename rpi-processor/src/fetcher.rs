@@ -9,28 +9,43 @@ pub async fn fetch_measurement_at(
     reqwest_client: &reqwest::Client,
     target_time: DateTime<Utc>,
 ) -> Result<Option<MeasurementWithTime>, Box<dyn Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-
     // Look for a measurement within +/- 5 minutes of the target time
     let start_window = target_time - chrono::Duration::minutes(5);
     let end_window = target_time + chrono::Duration::minutes(5);
 
-    let sql_query = format!(
-        r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent,
-            device
-        FROM scd40_data
-        WHERE time >= '{}' AND time <= '{}'
-        ORDER BY time ASC
-        LIMIT 1
-    "#,
-        start_window.to_rfc3339(),
-        end_window.to_rfc3339()
-    );
+    let sql = Sql::new(
+        "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+         WHERE time >= ",
+    )
+    .time(start_window)
+    .push(" AND time <= ")
+    .time(end_window)
+    .push(" ORDER BY time ASC LIMIT 1");
+
+    let influx_rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql,
+    )
+    .await?;
+    if let Some(row) = influx_rows.first() {
+        Ok(Some(row.to_measurement_with_time()?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Runs a SQL query and deserializes every returned row.
+pub async fn query_rows<T: serde::de::DeserializeOwned>(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    sql: &Sql,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
 
     let response = reqwest_client
         .post(&query_url)
@@ -38,24 +53,189 @@ pub async fn fetch_measurement_at(
         .header("Content-Type", "application/json")
         .body(serde_json::to_string(&serde_json::json!({
             "db": influx_database,
-            "q": sql_query
+            "q": sql.as_str()
         }))?)
         .send()
         .await?;
 
     if !response.status().is_success() {
-        return Err(format!("InfluxDB query failed: {}", response.status()).into());
+        let status = response.status();
+        let error_text = response.text().await?;
+        return Err(format!(
+            "InfluxDB query failed with status {}: {}",
+            status, error_text
+        )
+        .into());
     }
 
     let response_text = response.text().await?;
     if response_text.is_empty() {
-        return Ok(None);
+        return Ok(Vec::new());
     }
+    Ok(serde_json::from_str(&response_text)?)
+}
 
-    let influx_rows: Vec<InfluxMeasurementRow> = serde_json::from_str(&response_text)?;
-    if let Some(row) = influx_rows.first() {
-        Ok(Some(row.to_measurement_with_time()?))
-    } else {
-        Ok(None)
+/// A device or reference source name that is safe to put in a query: 1 to
+/// 64 ASCII letters, digits, `-`, `_`, `.` or `:`. Names from requests, MQTT
+/// topics and the room registry all go through `parse`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Identifier(String);
+
+impl Identifier {
+    pub const MAX_LEN: usize = 64;
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':');
+        if value.is_empty() || value.len() > Self::MAX_LEN || !value.chars().all(allowed) {
+            return Err(format!(
+                "invalid name {:?}: expected 1 to {} letters, digits, '-', '_', '.' or ':'",
+                value,
+                Self::MAX_LEN
+            ));
+        }
+        Ok(Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A query for `query_rows`. Only `'static` text is taken as is; values are
+/// rendered here, from types that can't carry anything but what they say:
+/// checked identifiers, times and numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sql(String);
+
+impl Sql {
+    pub fn new(text: &'static str) -> Self {
+        Self(text.to_string())
+    }
+
+    pub fn push(mut self, text: &'static str) -> Self {
+        self.0.push_str(text);
+        self
+    }
+
+    /// A table name, quoted unless it is a plain word
+    pub fn table(mut self, table: &Identifier) -> Self {
+        let name = table.as_str();
+        if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.0.push_str(name);
+        } else {
+            self.0.push('"');
+            self.0.push_str(name);
+            self.0.push('"');
+        }
+        self
+    }
+
+    /// `identifier` as a string literal
+    pub fn identifier(mut self, identifier: &Identifier) -> Self {
+        self.0.push('\'');
+        self.0.push_str(identifier.as_str());
+        self.0.push('\'');
+        self
+    }
+
+    /// Comma separated string literals, for `IN (...)`
+    pub fn identifiers<'a>(
+        mut self,
+        identifiers: impl IntoIterator<Item = &'a Identifier>,
+    ) -> Self {
+        for (i, identifier) in identifiers.into_iter().enumerate() {
+            if i > 0 {
+                self.0.push_str(", ");
+            }
+            self = self.identifier(identifier);
+        }
+        self
+    }
+
+    /// `time` as an RFC 3339 string literal
+    pub fn time(mut self, time: DateTime<Utc>) -> Self {
+        self.0.push('\'');
+        self.0.push_str(&time.to_rfc3339());
+        self.0.push('\'');
+        self
+    }
+
+    pub fn number(mut self, number: u64) -> Self {
+        self.0.push_str(&number.to_string());
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Sql {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn identifiers_are_checked() {
+        for good in [
+            "esp32-scd40",
+            "kitchen",
+            "home",
+            "airly:1234",
+            "node_2.local",
+        ] {
+            assert_eq!(Identifier::parse(good).unwrap().as_str(), good);
+        }
+        for bad in [
+            "",
+            "x' OR '1'='1",
+            "kitchen's",
+            "living room",
+            "a;DROP TABLE scd40_data",
+            "back\\slash",
+            "quote\"",
+            "zażółć",
+            &"x".repeat(Identifier::MAX_LEN + 1),
+        ] {
+            assert!(Identifier::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn values_are_rendered_by_the_builder() {
+        let devices = [
+            Identifier::parse("kitchen").unwrap(),
+            Identifier::parse("bedroom").unwrap(),
+        ];
+        let sql = Sql::new("SELECT * FROM scd40_data WHERE time >= ")
+            .time(Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap())
+            .push(" AND device IN (")
+            .identifiers(&devices)
+            .push(") LIMIT ")
+            .number(10);
+        assert_eq!(
+            sql.as_str(),
+            "SELECT * FROM scd40_data WHERE time >= '2025-01-15T12:00:00+00:00' \
+             AND device IN ('kitchen', 'bedroom') LIMIT 10"
+        );
+
+        let table = |name| Sql::new("SELECT * FROM ").table(&Identifier::parse(name).unwrap());
+        assert_eq!(
+            table("anomalies_v3_hd1").as_str(),
+            "SELECT * FROM anomalies_v3_hd1"
+        );
+        assert_eq!(table("sweep-2").as_str(), "SELECT * FROM \"sweep-2\"");
     }
 }
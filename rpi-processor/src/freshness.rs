@@ -0,0 +1,300 @@
+//! Seconds since each device's last measurement, for external watchdogs.
+//!
+//! Served as plain text by `GET /freshness` and printed by `--freshness`,
+//! one `device seconds` pair per line, so a cron job or healthchecks-style
+//! monitor can alert on a stale device without parsing JSON. With
+//! `?format=openmetrics` the same numbers come as a gauge, next to the sleep
+//! each device announced in its last `next_wake`: in adaptive mode that
+//! changes from wake to wake, so a watchdog can compare the age against it
+//! instead of a fixed threshold.
+//!
+//! When the receiver runs in the same process it keeps `LastSeen` up to date
+//! and nothing is queried; otherwise the ages come from `scd40_data`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::fetcher::{Sql, query_rows};
+
+pub const QUERY: &str =
+    "SELECT device, MAX(time) AS last_seen FROM scd40_data GROUP BY device ORDER BY device";
+
+const METRIC: &str = "air_quality_seconds_since_last_measurement";
+const INTERVAL_METRIC: &str = "air_quality_expected_interval_seconds";
+
+/// Last measurement time per device, shared between the receiver and the web server
+#[derive(Debug, Clone, Default)]
+pub struct LastSeen {
+    times: Arc<Mutex<BTreeMap<String, DateTime<Utc>>>>,
+    /// Sleep announced by the device's last `next_wake`
+    intervals: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl LastSeen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the later of `time` and what is already known for `device`.
+    pub fn record(&self, device: &str, time: DateTime<Utc>) {
+        let mut map = self.times.lock().unwrap();
+        match map.get_mut(device) {
+            Some(last) if *last >= time => {}
+            Some(last) => *last = time,
+            None => {
+                map.insert(device.to_string(), time);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, DateTime<Utc>> {
+        self.times.lock().unwrap().clone()
+    }
+
+    /// Replaces what `device` said about its next wake.
+    pub fn record_interval(&self, device: &str, sleep_seconds: u64) {
+        self.intervals
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), sleep_seconds);
+    }
+
+    pub fn intervals(&self) -> BTreeMap<String, u64> {
+        self.intervals.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceAge {
+    pub device: String,
+    pub seconds: i64,
+}
+
+pub async fn fetch_last_seen(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+) -> Result<BTreeMap<String, DateTime<Utc>>, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct LastSeenRow {
+        device: String,
+        last_seen: String,
+    }
+
+    let rows: Vec<LastSeenRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(QUERY),
+    )
+    .await?;
+
+    let mut last_seen = BTreeMap::new();
+    for row in rows {
+        let time = if row.last_seen.ends_with('Z') {
+            row.last_seen
+        } else {
+            format!("{}Z", row.last_seen)
+        };
+        let time = DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc);
+        last_seen.insert(row.device, time);
+    }
+    Ok(last_seen)
+}
+
+/// Ages in whole seconds, by device name. A clock ahead of `now` counts as fresh.
+pub fn ages(last_seen: &BTreeMap<String, DateTime<Utc>>, now: DateTime<Utc>) -> Vec<DeviceAge> {
+    last_seen
+        .iter()
+        .map(|(device, time)| DeviceAge {
+            device: device.clone(),
+            seconds: (now - *time).num_seconds().max(0),
+        })
+        .collect()
+}
+
+/// Devices whose last measurement is more than `max_age_seconds` old
+pub fn stale(ages: &[DeviceAge], max_age_seconds: i64) -> Vec<&DeviceAge> {
+    ages.iter()
+        .filter(|a| a.seconds > max_age_seconds)
+        .collect()
+}
+
+pub fn render_plain(ages: &[DeviceAge]) -> String {
+    let mut out = String::new();
+    for age in ages {
+        let _ = writeln!(out, "{} {}", age.device, age.seconds);
+    }
+    out
+}
+
+fn label(device: &str) -> String {
+    device.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `intervals` only lists the devices that announced one
+pub fn render_openmetrics(ages: &[DeviceAge], intervals: &BTreeMap<String, u64>) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# TYPE {} gauge", METRIC);
+    let _ = writeln!(out, "# UNIT {} seconds", METRIC);
+    let _ = writeln!(
+        out,
+        "# HELP {} Seconds since the device's last stored measurement.",
+        METRIC
+    );
+    for age in ages {
+        let _ = writeln!(
+            out,
+            "{}{{device=\"{}\"}} {}",
+            METRIC,
+            label(&age.device),
+            age.seconds
+        );
+    }
+    if !intervals.is_empty() {
+        let _ = writeln!(out, "# TYPE {} gauge", INTERVAL_METRIC);
+        let _ = writeln!(out, "# UNIT {} seconds", INTERVAL_METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Sleep the device announced before its last deep sleep.",
+            INTERVAL_METRIC
+        );
+        for (device, seconds) in intervals {
+            let _ = writeln!(
+                out,
+                "{}{{device=\"{}\"}} {}",
+                INTERVAL_METRIC,
+                label(device),
+                seconds
+            );
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use axum::{Json, Router, routing::post};
+
+    fn sample_ages() -> Vec<DeviceAge> {
+        let mut last_seen = BTreeMap::new();
+        last_seen.insert("kitchen".to_string(), at(-90));
+        last_seen.insert("bedroom".to_string(), at(-4000));
+        ages(&last_seen, at(0))
+    }
+
+    #[test]
+    fn ages_are_sorted_by_device() {
+        assert_eq!(
+            sample_ages(),
+            vec![
+                DeviceAge {
+                    device: "bedroom".to_string(),
+                    seconds: 4000,
+                },
+                DeviceAge {
+                    device: "kitchen".to_string(),
+                    seconds: 90,
+                },
+            ]
+        );
+        assert_eq!(render_plain(&sample_ages()), "bedroom 4000\nkitchen 90\n");
+    }
+
+    #[test]
+    fn threshold_is_exclusive() {
+        let ages = sample_ages();
+        let names = |max| -> Vec<&str> {
+            stale(&ages, max)
+                .into_iter()
+                .map(|a| a.device.as_str())
+                .collect()
+        };
+        assert_eq!(names(60), vec!["bedroom", "kitchen"]);
+        assert_eq!(names(90), vec!["bedroom"]);
+        assert_eq!(names(4000), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn future_timestamps_count_as_fresh() {
+        let mut last_seen = BTreeMap::new();
+        last_seen.insert("skewed".to_string(), at(30));
+        assert_eq!(ages(&last_seen, at(0))[0].seconds, 0);
+    }
+
+    #[test]
+    fn last_seen_keeps_the_latest_time() {
+        let last_seen = LastSeen::new();
+        last_seen.record("kitchen", at(-10));
+        last_seen.record("kitchen", at(-100));
+        last_seen.record("bedroom", at(-5));
+        let snapshot = last_seen.snapshot();
+        assert_eq!(snapshot["kitchen"], at(-10));
+        assert_eq!(snapshot["bedroom"], at(-5));
+    }
+
+    #[test]
+    fn openmetrics_has_one_gauge_per_device() {
+        assert_eq!(
+            render_openmetrics(&sample_ages(), &BTreeMap::new()),
+            "# TYPE air_quality_seconds_since_last_measurement gauge\n\
+             # UNIT air_quality_seconds_since_last_measurement seconds\n\
+             # HELP air_quality_seconds_since_last_measurement Seconds since the device's last stored measurement.\n\
+             air_quality_seconds_since_last_measurement{device=\"bedroom\"} 4000\n\
+             air_quality_seconds_since_last_measurement{device=\"kitchen\"} 90\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn openmetrics_adds_announced_intervals() {
+        let last_seen = LastSeen::new();
+        last_seen.record_interval("kitchen", 900);
+        last_seen.record_interval("kitchen", 120);
+        let rendered = render_openmetrics(&sample_ages(), &last_seen.intervals());
+        assert!(rendered.ends_with(
+            "# TYPE air_quality_expected_interval_seconds gauge\n\
+             # UNIT air_quality_expected_interval_seconds seconds\n\
+             # HELP air_quality_expected_interval_seconds Sleep the device announced before its last deep sleep.\n\
+             air_quality_expected_interval_seconds{device=\"kitchen\"} 120\n\
+             # EOF\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetches_last_seen_from_influx() {
+        async fn fake_query(Json(body): Json<serde_json::Value>) -> Json<serde_json::Value> {
+            assert_eq!(body["q"], QUERY);
+            Json(serde_json::json!([
+                { "device": "bedroom", "last_seen": "2025-01-15T10:53:20" },
+                { "device": "kitchen", "last_seen": "2025-01-15T11:58:30Z" },
+            ]))
+        }
+        let app = Router::new().route("/api/v3/query_sql", post(fake_query));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let last_seen = fetch_last_seen(
+            &format!("http://{}", addr),
+            "token",
+            "db",
+            &reqwest::Client::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            render_plain(&ages(&last_seen, at(0))),
+            "bedroom 4000\nkitchen 90\n"
+        );
+    }
+}
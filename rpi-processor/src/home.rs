@@ -0,0 +1,407 @@
+//! The whole-home virtual sensor: a `home` device combined from the latest
+//! measurement of every real device, written to `scd40_data` like any other,
+//! so dashboards and the predictor can pick it like a room.
+//!
+//! On every stored measurement the receiver takes the latest reading of each
+//! device and leaves out devices in maintenance, devices not heard from
+//! within `max_age_minutes` and virtual devices. The rest are combined by
+//! `strategy`:
+//!
+//! - `mean`: the plain mean of CO2, temperature and humidity
+//! - `weighted`: the mean weighted by each room's `weight` in the registry,
+//!   1 where it has none
+//! - `max`: the highest CO2, with temperature and humidity averaged
+//!
+//! Each point also carries the highest CO2 as `max_co2_ppm`, the room it came
+//! from (its registry name, or the device) as `max_co2_room`, and how many
+//! devices went into it as `devices`.
+//!
+//! Enabled with `--home-aggregate`, e.g. `strategy=weighted,max_age_minutes=15`.
+//! `home` counts as virtual in the room registry and takes no commands.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use shared_types::HOME_DEVICE;
+use shared_types::line_protocol::{self, MeasurementFields};
+
+use crate::options::parse_kv_options;
+use crate::types::MeasurementWithTime;
+use crate::ventilation::RoomRegistry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Mean,
+    Weighted,
+    Max,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "weighted" => Ok(Self::Weighted),
+            "max" => Ok(Self::Max),
+            other => Err(format!(
+                "unknown strategy '{}' (expected mean|weighted|max)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomeConfig {
+    pub strategy: Strategy,
+    /// Devices whose latest measurement is older than this are left out
+    pub max_age_minutes: i64,
+}
+
+impl Default for HomeConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::Mean,
+            max_age_minutes: 15,
+        }
+    }
+}
+
+impl FromStr for HomeConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for pair in parse_kv_options(s)? {
+            match pair {
+                ("strategy", value) => config.strategy = value.parse()?,
+                ("max_age_minutes", value) => {
+                    config.max_age_minutes = value
+                        .parse()
+                        .ok()
+                        .filter(|minutes| *minutes > 0)
+                        .ok_or_else(|| "max_age_minutes must be a positive number".to_string())?
+                }
+                (other, _) => return Err(format!("unknown home setting '{}'", other)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// One point of the `home` device
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomeReading {
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub max_co2: u16,
+    pub max_co2_room: String,
+    /// How many devices went into it
+    pub devices: usize,
+}
+
+impl HomeReading {
+    pub fn to_line(&self, timestamp: Option<i64>) -> String {
+        let mut line = line_protocol::measurement_to_line(
+            HOME_DEVICE,
+            &MeasurementFields {
+                co2: self.co2,
+                temperature: self.temperature,
+                humidity: self.humidity,
+                maintenance: false,
+                injected: false,
+                battery_mv: None,
+                battery_percent: None,
+                fw_version: None,
+                location: None,
+                flags: None,
+            },
+            None,
+        );
+        let _ = write!(
+            line,
+            ",max_co2_ppm={},max_co2_room=\"{}\",devices={}",
+            self.max_co2,
+            self.max_co2_room.replace('\\', "\\\\").replace('"', "\\\""),
+            self.devices
+        );
+        if let Some(timestamp) = timestamp {
+            let _ = write!(line, " {}", timestamp);
+        }
+        line
+    }
+}
+
+/// Combines the latest measurement of each device at `now`. `None` when no
+/// device is left to combine.
+pub fn aggregate<'a>(
+    latest: impl IntoIterator<Item = &'a MeasurementWithTime>,
+    now: DateTime<Utc>,
+    config: &HomeConfig,
+    rooms: &RoomRegistry,
+    in_maintenance: impl Fn(&str) -> bool,
+) -> Option<HomeReading> {
+    let max_age = Duration::minutes(config.max_age_minutes);
+    let fresh: Vec<&MeasurementWithTime> = latest
+        .into_iter()
+        .filter(|m| !rooms.is_virtual(&m.device))
+        .filter(|m| now - m.time <= max_age)
+        .filter(|m| !in_maintenance(&m.device))
+        .collect();
+    // The first of equal maxima, so the room doesn't flip between them
+    let loudest = fresh
+        .iter()
+        .copied()
+        .reduce(|max, m| if m.co2 > max.co2 { m } else { max })?;
+
+    let weight = |m: &MeasurementWithTime| match config.strategy {
+        Strategy::Weighted => rooms
+            .get(&m.device)
+            .and_then(|room| room.weight)
+            .unwrap_or(1.0),
+        Strategy::Mean | Strategy::Max => 1.0,
+    };
+    let total: f64 = fresh.iter().map(|m| weight(m)).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mean = |value: fn(&MeasurementWithTime) -> f64| {
+        fresh.iter().map(|m| weight(m) * value(m)).sum::<f64>() / total
+    };
+    let co2 = match config.strategy {
+        Strategy::Max => loudest.co2,
+        Strategy::Mean | Strategy::Weighted => mean(|m| m.co2 as f64).round() as u16,
+    };
+    Some(HomeReading {
+        co2,
+        // Two decimals, like the sensors report
+        temperature: ((mean(|m| m.temperature as f64) * 100.0).round() / 100.0) as f32,
+        humidity: ((mean(|m| m.humidity as f64) * 100.0).round() / 100.0) as f32,
+        max_co2: loudest.co2,
+        max_co2_room: rooms
+            .get(&loudest.device)
+            .and_then(|room| room.name.clone())
+            .unwrap_or_else(|| loudest.device.clone()),
+        devices: fresh.len(),
+    })
+}
+
+/// The latest measurement of every real device, fed by the `home` stage
+#[derive(Debug)]
+pub struct HomeAggregator {
+    pub config: HomeConfig,
+    pub rooms: RoomRegistry,
+    latest: BTreeMap<String, MeasurementWithTime>,
+}
+
+impl HomeAggregator {
+    pub fn new(config: HomeConfig, rooms: RoomRegistry) -> Self {
+        Self {
+            config,
+            rooms,
+            latest: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps the later of `measurement` and what is known for its device.
+    /// Virtual devices are ignored. Returns whether it was kept.
+    pub fn observe(&mut self, measurement: &MeasurementWithTime) -> bool {
+        if self.rooms.is_virtual(&measurement.device) {
+            return false;
+        }
+        match self.latest.get(&measurement.device) {
+            Some(known) if known.time >= measurement.time => false,
+            _ => {
+                self.latest
+                    .insert(measurement.device.clone(), measurement.clone());
+                true
+            }
+        }
+    }
+
+    pub fn reading(
+        &self,
+        now: DateTime<Utc>,
+        in_maintenance: impl Fn(&str) -> bool,
+    ) -> Option<HomeReading> {
+        aggregate(
+            self.latest.values(),
+            now,
+            &self.config,
+            &self.rooms,
+            in_maintenance,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+
+    fn reading(device: &str, seconds: i64, co2: u16, temperature: f32) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature,
+            humidity: 40.0,
+            time: at(seconds),
+            device: device.to_string(),
+        }
+    }
+
+    fn rooms() -> RoomRegistry {
+        serde_json::from_str(
+            r#"{
+                "kitchen": { "name": "Kitchen", "weight": 1.0 },
+                "bedroom": { "name": "Bedroom", "weight": 3.0 },
+                "outdoor": { "virtual": true }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn house() -> Vec<MeasurementWithTime> {
+        vec![
+            reading("kitchen", -60, 1200, 22.0),
+            reading("bedroom", -120, 600, 20.0),
+            reading("office", -30, 900, 21.0),
+        ]
+    }
+
+    fn config(strategy: Strategy) -> HomeConfig {
+        HomeConfig {
+            strategy,
+            ..HomeConfig::default()
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let none_in_maintenance = |_: &str| false;
+        let mean = aggregate(
+            &house(),
+            at(0),
+            &config(Strategy::Mean),
+            &rooms(),
+            none_in_maintenance,
+        )
+        .unwrap();
+        assert_eq!(mean.co2, 900);
+        assert_eq!(mean.temperature, 21.0);
+        assert_eq!(mean.max_co2, 1200);
+        assert_eq!(mean.max_co2_room, "Kitchen");
+        assert_eq!(mean.devices, 3);
+
+        // The bedroom counts three times, the office once without a weight
+        let weighted = aggregate(
+            &house(),
+            at(0),
+            &config(Strategy::Weighted),
+            &rooms(),
+            none_in_maintenance,
+        )
+        .unwrap();
+        assert_eq!(weighted.co2, 780);
+        assert_eq!(weighted.temperature, 20.6);
+
+        let max = aggregate(
+            &house(),
+            at(0),
+            &config(Strategy::Max),
+            &rooms(),
+            none_in_maintenance,
+        )
+        .unwrap();
+        assert_eq!(max.co2, 1200);
+        assert_eq!(max.temperature, 21.0);
+    }
+
+    #[test]
+    fn stale_devices_are_left_out() {
+        let mut latest = house();
+        latest.push(reading("attic", -16 * 60, 2000, 15.0));
+        let home = aggregate(&latest, at(0), &HomeConfig::default(), &rooms(), |_| false).unwrap();
+        assert_eq!(home.devices, 3);
+        assert_eq!(home.max_co2, 1200);
+
+        // A longer limit takes it in
+        let patient = HomeConfig {
+            max_age_minutes: 20,
+            ..HomeConfig::default()
+        };
+        let home = aggregate(&latest, at(0), &patient, &rooms(), |_| false).unwrap();
+        assert_eq!(home.devices, 4);
+        assert_eq!(home.max_co2_room, "attic");
+
+        // Nothing fresh, nothing to write
+        assert_eq!(
+            aggregate(&latest, at(3600), &HomeConfig::default(), &rooms(), |_| {
+                false
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn maintenance_and_virtual_devices_are_left_out() {
+        let mut latest = house();
+        latest.push(reading("outdoor", -10, 420, 5.0));
+        latest.push(reading(HOME_DEVICE, -10, 900, 21.0));
+        let home = aggregate(&latest, at(0), &HomeConfig::default(), &rooms(), |device| {
+            device == "kitchen"
+        })
+        .unwrap();
+        assert_eq!(home.devices, 2);
+        assert_eq!(home.co2, 750);
+        assert_eq!(home.max_co2_room, "office");
+    }
+
+    #[test]
+    fn aggregator_keeps_the_latest_real_reading() {
+        let mut aggregator = HomeAggregator::new(HomeConfig::default(), rooms());
+        assert!(aggregator.observe(&reading("kitchen", -60, 1200, 22.0)));
+        assert!(!aggregator.observe(&reading("kitchen", -120, 500, 22.0)));
+        assert!(aggregator.observe(&reading("kitchen", 0, 800, 22.0)));
+        assert!(!aggregator.observe(&reading(HOME_DEVICE, 0, 800, 22.0)));
+        assert!(!aggregator.observe(&reading("outdoor", 0, 420, 5.0)));
+
+        let home = aggregator.reading(at(0), |_| false).unwrap();
+        assert_eq!((home.co2, home.devices), (800, 1));
+    }
+
+    #[test]
+    fn written_as_a_measurement_of_home() {
+        let home = aggregate(&house(), at(0), &HomeConfig::default(), &rooms(), |_| false).unwrap();
+        let line = home.to_line(Some(1_736_942_400_000_000_000));
+        assert_eq!(
+            line,
+            "scd40_data,device=home co2_ppm=900,temperature_c=21,humidity_percent=40,\
+             max_co2_ppm=1200,max_co2_room=\"Kitchen\",devices=3 1736942400000000000"
+        );
+        let parsed = line_protocol::line_to_measurement(&line).unwrap();
+        assert_eq!(parsed.device, HOME_DEVICE);
+        assert_eq!(parsed.fields.co2, 900);
+    }
+
+    #[test]
+    fn parses_the_setting() {
+        assert_eq!("".parse::<HomeConfig>().unwrap(), HomeConfig::default());
+        assert_eq!(
+            "strategy=weighted, max_age_minutes=30"
+                .parse::<HomeConfig>()
+                .unwrap(),
+            HomeConfig {
+                strategy: Strategy::Weighted,
+                max_age_minutes: 30,
+            }
+        );
+        assert!("strategy=median".parse::<HomeConfig>().is_err());
+        assert!("max_age_minutes=0".parse::<HomeConfig>().is_err());
+        assert!("rooms=3".parse::<HomeConfig>().is_err());
+        assert!("weighted".parse::<HomeConfig>().is_err());
+    }
+}
@@ -0,0 +1,1018 @@
+//! Hourly per-device aggregates maintained incrementally by the live receiver.
+//!
+//! Each device has an open bucket for the current hour. A measurement from a
+//! later hour closes it and the finished aggregate is written to
+//! `scd40_hourly`. The last closed bucket is kept so a measurement arriving
+//! just after the rollover still corrects it; anything older needs a rebuild
+//! from the raw data.
+//!
+//! Buckets written on shutdown are marked `partial=true`, and startup recovery
+//! rebuilds those from `scd40_data` before the receiver resumes.
+//!
+//! Besides min, max and mean, every hour keeps what a mean hides: when the
+//! CO2 maximum was measured, the minutes spent above each configured CO2
+//! threshold, and how many samples the anomaly detector flagged as sensor
+//! artifacts and left out. Rows written before these fields existed read
+//! back with them missing.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::Deserialize;
+
+use crate::anomalies::{AnomalyDetector, AnomalyFlags};
+use crate::bulk_write::{BulkWriter, InfluxStore, Point, log_progress};
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+pub const MEASUREMENT: &str = "scd40_hourly";
+/// Ventilation is recommended above 1000 ppm and the air is poor above 1400
+pub const DEFAULT_CO2_THRESHOLDS: [u16; 2] = [1000, 1400];
+/// Earlier data the anomaly detector looks back on
+const DETECTOR_CONTEXT: Duration = Duration::hours(3);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HourlyConfig {
+    /// CO2 levels, in ppm, to record the time spent above
+    pub co2_thresholds: Vec<u16>,
+    /// How long one sample stands for
+    pub sample_interval: Duration,
+}
+
+impl Default for HourlyConfig {
+    fn default() -> Self {
+        Self {
+            co2_thresholds: DEFAULT_CO2_THRESHOLDS.to_vec(),
+            sample_interval: Duration::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+}
+
+impl Stat {
+    const EMPTY: Stat = Stat {
+        min: f64::INFINITY,
+        max: f64::NEG_INFINITY,
+        sum: 0.0,
+    };
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+}
+
+/// Sunlight on the sensor and the humidity dips it causes aren't the room's
+/// air. CO2 spikes are, so they stay in.
+fn is_artifact(flags: &AnomalyFlags) -> bool {
+    flags.possible_sunlight || flags.temperature_spike || flags.humidity_spike
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HourlyAggregate {
+    pub device: String,
+    pub hour_start: DateTime<Utc>,
+    /// Samples in the statistics
+    pub count: u64,
+    pub co2: Stat,
+    pub temperature: Stat,
+    pub humidity: Stat,
+    /// When `co2.max` was measured; the earliest time if it was reached twice
+    pub co2_max_time: Option<DateTime<Utc>>,
+    /// Minutes above each configured CO2 threshold
+    pub minutes_above: Vec<(u16, f64)>,
+    /// Samples flagged as sensor artifacts and left out of the statistics
+    pub excluded: u64,
+    /// Written before the hour was over (on shutdown)
+    pub partial: bool,
+}
+
+impl HourlyAggregate {
+    fn empty(device: &str, hour_start: DateTime<Utc>, config: &HourlyConfig) -> Self {
+        Self {
+            device: device.to_string(),
+            hour_start,
+            count: 0,
+            co2: Stat::EMPTY,
+            temperature: Stat::EMPTY,
+            humidity: Stat::EMPTY,
+            co2_max_time: None,
+            minutes_above: config.co2_thresholds.iter().map(|&t| (t, 0.0)).collect(),
+            excluded: 0,
+            partial: false,
+        }
+    }
+
+    fn started(m: &MeasurementWithTime, artifact: bool, config: &HourlyConfig) -> Self {
+        let mut aggregate = Self::empty(&m.device, hour_of(m.time), config);
+        aggregate.add(m, artifact, config);
+        aggregate
+    }
+
+    fn add(&mut self, m: &MeasurementWithTime, artifact: bool, config: &HourlyConfig) {
+        if artifact {
+            self.excluded += 1;
+            return;
+        }
+        self.count += 1;
+        let co2 = m.co2 as f64;
+        // Ties go to the earliest time, so arrival order doesn't matter
+        if co2 > self.co2.max
+            || (co2 == self.co2.max && self.co2_max_time.is_none_or(|t| m.time < t))
+        {
+            self.co2_max_time = Some(m.time);
+        }
+        self.co2.add(co2);
+        self.temperature.add(m.temperature as f64);
+        self.humidity.add(m.humidity as f64);
+        let minutes = config.sample_interval.num_seconds() as f64 / 60.0;
+        for (threshold, total) in &mut self.minutes_above {
+            if m.co2 > *threshold {
+                *total += minutes;
+            }
+        }
+    }
+
+    pub fn to_line_protocol(&self) -> String {
+        let mut fields = Vec::new();
+        // An hour of nothing but artifacts has no statistics
+        if self.count > 0 {
+            for (name, s) in [
+                ("co2", &self.co2),
+                ("temperature", &self.temperature),
+                ("humidity", &self.humidity),
+            ] {
+                fields.push(format!(
+                    "{name}_min={},{name}_max={},{name}_mean={}",
+                    s.min,
+                    s.max,
+                    s.sum / self.count as f64
+                ));
+            }
+        }
+        if let Some(time) = self.co2_max_time {
+            fields.push(format!("co2_max_time_ms={}i", time.timestamp_millis()));
+        }
+        for (threshold, minutes) in &self.minutes_above {
+            fields.push(format!("minutes_above_{}={}", threshold, minutes));
+        }
+        fields.push(format!(
+            "count={}i,excluded={}i,partial={}",
+            self.count, self.excluded, self.partial
+        ));
+        format!(
+            "{},device={} {} {}",
+            MEASUREMENT,
+            self.device,
+            fields.join(","),
+            self.hour_start.timestamp_nanos_opt().unwrap_or(0)
+        )
+    }
+}
+
+pub fn hour_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::hours(1))
+        .expect("an hour always fits in a timestamp")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Write (or overwrite) this hour's point
+    Write(HourlyAggregate),
+    /// A measurement arrived for an hour no longer in memory
+    Rebuild {
+        device: String,
+        hour_start: DateTime<Utc>,
+    },
+}
+
+#[derive(Default)]
+struct DeviceBuckets {
+    current: Option<HourlyAggregate>,
+    previous: Option<HourlyAggregate>,
+    detector: AnomalyDetector,
+}
+
+pub struct HourlyAggregator {
+    config: HourlyConfig,
+    devices: HashMap<String, DeviceBuckets>,
+}
+
+impl HourlyAggregator {
+    pub fn new(config: HourlyConfig) -> Self {
+        Self {
+            config,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &HourlyConfig {
+        &self.config
+    }
+
+    pub fn fold(&mut self, m: &MeasurementWithTime) -> Vec<Action> {
+        let hour = hour_of(m.time);
+        let config = &self.config;
+        let buckets = self.devices.entry(m.device.clone()).or_default();
+        let artifact = is_artifact(&buckets.detector.analyze(m, false));
+
+        let Some(current) = &mut buckets.current else {
+            buckets.current = Some(HourlyAggregate::started(m, artifact, config));
+            return Vec::new();
+        };
+
+        if hour == current.hour_start {
+            current.add(m, artifact, config);
+            return Vec::new();
+        }
+
+        if hour > current.hour_start {
+            let finished =
+                std::mem::replace(current, HourlyAggregate::started(m, artifact, config));
+            buckets.previous = Some(finished.clone());
+            return vec![Action::Write(finished)];
+        }
+
+        // Late arrival for an hour that's already been written
+        match &mut buckets.previous {
+            Some(previous) if previous.hour_start == hour => {
+                previous.add(m, artifact, config);
+                vec![Action::Write(previous.clone())]
+            }
+            _ => vec![Action::Rebuild {
+                device: m.device.clone(),
+                hour_start: hour,
+            }],
+        }
+    }
+
+    /// Resumes a device's open hour from raw data after a restart.
+    /// `measurements` should reach `DETECTOR_CONTEXT` back before
+    /// `hour_start`, so the detector has seen what it would have live.
+    pub fn seed(
+        &mut self,
+        device: &str,
+        hour_start: DateTime<Utc>,
+        measurements: &[MeasurementWithTime],
+    ) -> Option<&HourlyAggregate> {
+        let config = &self.config;
+        let buckets = self.devices.entry(device.to_string()).or_default();
+        if buckets
+            .current
+            .as_ref()
+            .is_some_and(|current| current.hour_start >= hour_start)
+        {
+            return None;
+        }
+        buckets.current = aggregate_hour(
+            &mut buckets.detector,
+            device,
+            hour_start,
+            measurements,
+            config,
+        );
+        buckets.current.as_ref()
+    }
+
+    /// Returns the open hours, marked partial, for writing on shutdown.
+    pub fn flush(&mut self) -> Vec<HourlyAggregate> {
+        let mut open: Vec<_> = self
+            .devices
+            .values_mut()
+            .filter_map(|b| b.current.take())
+            .map(|mut aggregate| {
+                aggregate.partial = true;
+                aggregate
+            })
+            .collect();
+        open.sort_by(|a, b| a.device.cmp(&b.device));
+        open
+    }
+}
+
+/// Runs `detector` over the device's measurements up to the end of the hour,
+/// in time order, and aggregates the ones in the hour.
+fn aggregate_hour(
+    detector: &mut AnomalyDetector,
+    device: &str,
+    hour_start: DateTime<Utc>,
+    measurements: &[MeasurementWithTime],
+    config: &HourlyConfig,
+) -> Option<HourlyAggregate> {
+    let mut samples: Vec<_> = measurements
+        .iter()
+        .filter(|m| m.device == device && m.time < hour_start + Duration::hours(1))
+        .collect();
+    samples.sort_by_key(|m| m.time);
+    let mut aggregate = None;
+    for m in samples {
+        let artifact = is_artifact(&detector.analyze(m, false));
+        if hour_of(m.time) == hour_start {
+            aggregate
+                .get_or_insert_with(|| HourlyAggregate::empty(device, hour_start, config))
+                .add(m, artifact, config);
+        }
+    }
+    aggregate
+}
+
+/// Aggregates raw measurements of one device and hour from scratch. Earlier
+/// measurements are only context for the anomaly detector.
+pub fn rebuild(
+    device: &str,
+    hour_start: DateTime<Utc>,
+    measurements: &[MeasurementWithTime],
+    config: &HourlyConfig,
+) -> Option<HourlyAggregate> {
+    aggregate_hour(
+        &mut AnomalyDetector::default(),
+        device,
+        hour_start,
+        measurements,
+        config,
+    )
+}
+
+/// A stored `scd40_hourly` row. Rows written before the extremes were kept
+/// have no `co2_max_time_ms`, `minutes_above_*` or `excluded`, and an hour
+/// where every sample was excluded has no statistics.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HourlyRow {
+    pub time: String,
+    pub co2_min: Option<f64>,
+    pub co2_max: Option<f64>,
+    pub co2_mean: Option<f64>,
+    pub temperature_min: Option<f64>,
+    pub temperature_max: Option<f64>,
+    pub temperature_mean: Option<f64>,
+    pub humidity_min: Option<f64>,
+    pub humidity_max: Option<f64>,
+    pub humidity_mean: Option<f64>,
+    pub co2_max_time_ms: Option<i64>,
+    pub excluded: Option<i64>,
+    /// `minutes_above_*`, the device, the count and `partial`
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl HourlyRow {
+    /// The start of the hour; InfluxDB leaves out the `Z`
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        let time = if self.time.ends_with('Z') {
+            self.time.clone()
+        } else {
+            format!("{}Z", self.time)
+        };
+        DateTime::parse_from_rfc3339(&time)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    }
+
+    /// Samples the statistics come from, artifacts left out
+    pub fn count(&self) -> u64 {
+        self.other
+            .get("count")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0)
+    }
+
+    pub fn co2_max_time(&self) -> Option<DateTime<Utc>> {
+        self.co2_max_time_ms
+            .and_then(DateTime::from_timestamp_millis)
+    }
+
+    /// Minutes above each threshold recorded for the hour, which depend on
+    /// the thresholds configured when it was written
+    pub fn minutes_above(&self) -> BTreeMap<u16, f64> {
+        self.other
+            .iter()
+            .filter_map(|(name, value)| {
+                let threshold = name.strip_prefix("minutes_above_")?.parse().ok()?;
+                Some((threshold, value.as_f64()?))
+            })
+            .collect()
+    }
+}
+
+/// A device's stored hours in `[from, to)`, oldest first.
+pub fn device_range_query(device: &Identifier, from: DateTime<Utc>, to: DateTime<Utc>) -> Sql {
+    Sql::new("SELECT * FROM ")
+        .push(MEASUREMENT)
+        .push(" WHERE device = ")
+        .identifier(device)
+        .push(" AND time >= ")
+        .time(from)
+        .push(" AND time < ")
+        .time(to)
+        .push(" ORDER BY time ASC")
+}
+
+/// Stored hours in `[from, to]`, oldest first.
+pub fn range_query(from: DateTime<Utc>, to: DateTime<Utc>, limit: usize) -> Sql {
+    // `*`, because selecting a field no row has written yet is an error
+    Sql::new("SELECT * FROM ")
+        .push(MEASUREMENT)
+        .push(" WHERE time >= ")
+        .time(from)
+        .push(" AND time <= ")
+        .time(to)
+        .push(" ORDER BY time ASC LIMIT ")
+        .number(limit as u64)
+}
+
+pub async fn write_aggregates(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    aggregates: &[HourlyAggregate],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if aggregates.is_empty() {
+        return Ok(());
+    }
+    let body: Vec<String> = aggregates.iter().map(|a| a.to_line_protocol()).collect();
+
+    let response = reqwest_client
+        .post(format!(
+            "{}/api/v3/write_lp?db={}",
+            influx_host, influx_database
+        ))
+        .body(body.join("\n"))
+        .bearer_auth(influx_token)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await?;
+        return Err(format!(
+            "Failed to write hourly aggregates to InfluxDB: {} - {}",
+            status, error_text
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A device's raw measurements in `[from, to)`.
+async fn fetch_raw(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: &Identifier,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<MeasurementWithTime>, Box<dyn std::error::Error>> {
+    let rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             WHERE device = ",
+        )
+        .identifier(device)
+        .push(" AND time >= ")
+        .time(from)
+        .push(" AND time < ")
+        .time(to),
+    )
+    .await?;
+    rows.iter()
+        .map(|row| row.to_measurement_with_time())
+        .collect()
+}
+
+/// Rebuilds one hour from `scd40_data`.
+pub async fn rebuild_from_raw(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    config: &HourlyConfig,
+    device: &str,
+    hour_start: DateTime<Utc>,
+) -> Result<Option<HourlyAggregate>, Box<dyn std::error::Error>> {
+    let Ok(name) = Identifier::parse(device) else {
+        log::warn!(
+            "Not rebuilding hour {} for {:?}: the name can't be queried",
+            hour_start,
+            device
+        );
+        return Ok(None);
+    };
+    let measurements = fetch_raw(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &name,
+        hour_start - DETECTOR_CONTEXT,
+        hour_start + Duration::hours(1),
+    )
+    .await?;
+    Ok(rebuild(device, hour_start, &measurements, config))
+}
+
+/// Carries out what [`HourlyAggregator::fold`] asked for.
+pub async fn apply(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    config: &HourlyConfig,
+    actions: Vec<Action>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut aggregates = Vec::new();
+    for action in actions {
+        match action {
+            Action::Write(aggregate) => aggregates.push(aggregate),
+            Action::Rebuild { device, hour_start } => {
+                log::info!(
+                    "Late measurement for {} in hour {}, rebuilding from raw data",
+                    device,
+                    hour_start
+                );
+                if let Some(aggregate) = rebuild_from_raw(
+                    influx_host,
+                    influx_token,
+                    influx_database,
+                    reqwest_client,
+                    config,
+                    &device,
+                    hour_start,
+                )
+                .await?
+                {
+                    aggregates.push(aggregate);
+                }
+            }
+        }
+    }
+    write_aggregates(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &aggregates,
+    )
+    .await
+}
+
+/// Rebuilds hours left partial by an earlier shutdown and seeds the current
+/// hour of every device that already reported in it.
+pub async fn recover(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    aggregator: &mut HourlyAggregator,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct PartialRow {
+        time: String,
+        device: String,
+    }
+    #[derive(Deserialize)]
+    struct DeviceRow {
+        device: String,
+    }
+
+    let current_hour = hour_of(now);
+
+    // The table doesn't exist before the first aggregate is written
+    let partial: Vec<PartialRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT time, device FROM ")
+            .push(MEASUREMENT)
+            .push(" WHERE partial = true"),
+    )
+    .await
+    .unwrap_or_default();
+
+    let mut rebuilt = Vec::new();
+    for row in partial {
+        let time = if row.time.ends_with('Z') {
+            row.time
+        } else {
+            format!("{}Z", row.time)
+        };
+        let hour_start = DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc);
+        if hour_start >= current_hour {
+            continue;
+        }
+        if let Some(aggregate) = rebuild_from_raw(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            aggregator.config(),
+            &row.device,
+            hour_start,
+        )
+        .await?
+        {
+            log::info!(
+                "Rebuilt partial hour {} for {} from raw data",
+                hour_start,
+                row.device
+            );
+            rebuilt.push(aggregate);
+        }
+    }
+    // Rebuilt hours replace the partial ones, so nothing is skipped
+    BulkWriter::new(InfluxStore {
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    })
+    .write(
+        rebuilt.iter().map(|a| Point {
+            measurement: MEASUREMENT,
+            series_tag: "device",
+            series: a.device.clone(),
+            time: a.hour_start,
+            line: a.to_line_protocol(),
+        }),
+        log_progress("Hourly rebuild"),
+    )
+    .await?;
+
+    let devices: Vec<DeviceRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT DISTINCT device FROM scd40_data WHERE time >= ").time(current_hour),
+    )
+    .await?;
+    for DeviceRow { device } in devices {
+        let Ok(name) = Identifier::parse(&device) else {
+            log::warn!(
+                "Not resuming hour {} for {:?}: the name can't be queried",
+                current_hour,
+                device
+            );
+            continue;
+        };
+        let measurements = fetch_raw(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &name,
+            current_hour - DETECTOR_CONTEXT,
+            current_hour + Duration::hours(1),
+        )
+        .await?;
+        if let Some(aggregate) = aggregator.seed(&device, current_hour, &measurements) {
+            log::info!(
+                "Resuming hour {} for {} with {} measurements",
+                current_hour,
+                device,
+                aggregate.count + aggregate.excluded
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T10:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::minutes(minutes)
+    }
+
+    fn m(device: &str, minutes: i64, co2: u16) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 20.0 + co2 as f32 / 1000.0,
+            humidity: 70.0,
+            time: at(minutes),
+            device: device.to_string(),
+        }
+    }
+
+    fn aggregator() -> HourlyAggregator {
+        HourlyAggregator::new(HourlyConfig::default())
+    }
+
+    fn written(actions: Vec<Action>) -> Vec<HourlyAggregate> {
+        actions
+            .into_iter()
+            .map(|a| match a {
+                Action::Write(aggregate) => aggregate,
+                other => panic!("expected a write, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn folds_within_the_hour_without_writing() {
+        let mut agg = aggregator();
+        assert!(agg.fold(&m("a", 5, 600)).is_empty());
+        assert!(agg.fold(&m("a", 10, 800)).is_empty());
+        assert!(agg.fold(&m("a", 59, 700)).is_empty());
+
+        let open = agg.flush();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].count, 3);
+        assert_eq!(open[0].co2.min, 600.0);
+        assert_eq!(open[0].co2.max, 800.0);
+        assert_eq!(open[0].co2.sum, 2100.0);
+        assert!(open[0].partial);
+        assert_eq!(open[0].hour_start, at(0));
+    }
+
+    #[test]
+    fn rollover_writes_finished_hour_once() {
+        let mut agg = aggregator();
+        agg.fold(&m("a", 10, 600));
+        agg.fold(&m("a", 50, 620));
+
+        let done = written(agg.fold(&m("a", 65, 640)));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].hour_start, at(0));
+        assert_eq!(done[0].count, 2);
+        assert!(!done[0].partial);
+
+        assert!(agg.fold(&m("a", 70, 650)).is_empty());
+        let open = agg.flush();
+        assert_eq!(open[0].hour_start, at(60));
+        assert_eq!(open[0].count, 2);
+    }
+
+    #[test]
+    fn skipping_hours_writes_only_hours_with_data() {
+        let mut agg = aggregator();
+        agg.fold(&m("a", 10, 600));
+        let done = written(agg.fold(&m("a", 200, 600)));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].hour_start, at(0));
+    }
+
+    #[test]
+    fn out_of_order_within_the_hour_matches_in_order() {
+        let readings = [(5, 700), (40, 500), (20, 900), (55, 650)];
+
+        let mut in_order = aggregator();
+        let mut sorted = readings;
+        sorted.sort();
+        for (minute, co2) in sorted {
+            in_order.fold(&m("a", minute, co2));
+        }
+
+        let mut shuffled = aggregator();
+        for (minute, co2) in readings {
+            shuffled.fold(&m("a", minute, co2));
+        }
+
+        assert_eq!(in_order.flush(), shuffled.flush());
+    }
+
+    #[test]
+    fn late_arrival_after_rollover_corrects_previous_hour() {
+        let mut agg = aggregator();
+        agg.fold(&m("a", 30, 600));
+        agg.fold(&m("a", 61, 700));
+
+        let corrected = written(agg.fold(&m("a", 59, 1000)));
+        assert_eq!(corrected.len(), 1);
+        assert_eq!(corrected[0].hour_start, at(0));
+        assert_eq!(corrected[0].count, 2);
+        assert_eq!(corrected[0].co2.max, 1000.0);
+
+        // The open hour is untouched
+        let open = agg.flush();
+        assert_eq!(open[0].hour_start, at(60));
+        assert_eq!(open[0].count, 1);
+    }
+
+    #[test]
+    fn arrival_older_than_previous_hour_requests_rebuild() {
+        let mut agg = aggregator();
+        agg.fold(&m("a", 130, 600));
+        agg.fold(&m("a", 190, 600));
+
+        assert_eq!(
+            agg.fold(&m("a", 10, 600)),
+            vec![Action::Rebuild {
+                device: "a".to_string(),
+                hour_start: at(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn devices_are_independent() {
+        let mut agg = aggregator();
+        agg.fold(&m("a", 10, 600));
+        agg.fold(&m("b", 20, 800));
+        let done = written(agg.fold(&m("a", 70, 600)));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].device, "a");
+
+        let open = agg.flush();
+        assert_eq!(
+            open.iter()
+                .map(|a| (a.device.as_str(), a.hour_start))
+                .collect::<Vec<_>>(),
+            vec![("a", at(60)), ("b", at(0))]
+        );
+    }
+
+    #[test]
+    fn rebuild_matches_incremental_fold() {
+        let raw = vec![
+            m("a", 55, 640),
+            m("a", 5, 600),
+            m("b", 10, 900),
+            m("a", 30, 620),
+            m("a", 65, 700),
+        ];
+        let rebuilt = rebuild("a", at(0), &raw, &HourlyConfig::default()).unwrap();
+
+        let mut agg = aggregator();
+        for reading in raw.iter().filter(|r| r.device == "a" && r.time < at(60)) {
+            agg.fold(reading);
+        }
+        let mut incremental = agg.flush().remove(0);
+        incremental.partial = false;
+        assert_eq!(rebuilt, incremental);
+        assert_eq!(rebuilt.count, 3);
+
+        assert!(rebuild("a", at(120), &raw, &HourlyConfig::default()).is_none());
+    }
+
+    #[test]
+    fn seeding_resumes_the_open_hour() {
+        let raw = vec![m("a", 5, 600), m("a", 15, 640)];
+        let mut agg = aggregator();
+        assert_eq!(agg.seed("a", at(0), &raw).unwrap().count, 2);
+        // An hour already open isn't replaced
+        assert!(agg.seed("a", at(-60), &raw).is_none());
+
+        assert!(agg.fold(&m("a", 25, 700)).is_empty());
+        let done = written(agg.fold(&m("a", 61, 700)));
+        assert_eq!(done[0].count, 3);
+        assert_eq!(done[0].co2.sum, 1940.0);
+    }
+
+    #[test]
+    fn line_protocol() {
+        let mut first = m("a", 5, 600);
+        first.temperature = 20.5;
+        let mut second = m("a", 15, 800);
+        second.temperature = 21.5;
+        let aggregate = rebuild("a", at(0), &[first, second], &HourlyConfig::default()).unwrap();
+        assert_eq!(
+            aggregate.to_line_protocol(),
+            format!(
+                "scd40_hourly,device=a co2_min=600,co2_max=800,co2_mean=700,\
+                 temperature_min=20.5,temperature_max=21.5,temperature_mean=21,\
+                 humidity_min=70,humidity_max=70,humidity_mean=70,co2_max_time_ms={}i,\
+                 minutes_above_1000=0,minutes_above_1400=0,count=2i,excluded=0i,partial=false {}",
+                at(15).timestamp_millis(),
+                at(0).timestamp_nanos_opt().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn peaks_keep_their_time_and_duration() {
+        let mut agg = aggregator();
+        for (minute, co2) in [(0, 900), (5, 1200), (10, 1500), (15, 1500), (20, 1100)] {
+            agg.fold(&m("a", minute, co2));
+        }
+        let open = agg.flush().remove(0);
+        assert_eq!(open.co2.max, 1500.0);
+        // Reached twice, the first time counts
+        assert_eq!(open.co2_max_time, Some(at(10)));
+        assert_eq!(open.minutes_above, vec![(1000, 20.0), (1400, 10.0)]);
+
+        // Arrival order doesn't change it
+        let mut reversed = aggregator();
+        for (minute, co2) in [(20, 1100), (15, 1500), (10, 1500), (5, 1200), (0, 900)] {
+            reversed.fold(&m("a", minute, co2));
+        }
+        assert_eq!(reversed.flush().remove(0), open);
+    }
+
+    #[test]
+    fn sensor_artifacts_are_counted_but_left_out() {
+        let mut sunlit = m("a", 30, 640);
+        sunlit.humidity = 50.0;
+        sunlit.temperature = 31.0;
+        let mut spike = m("a", 40, 1800);
+        spike.temperature = 20.0;
+        let raw = vec![m("a", 10, 600), sunlit, spike];
+
+        let aggregate = rebuild("a", at(0), &raw, &HourlyConfig::default()).unwrap();
+        assert_eq!(aggregate.count, 2);
+        assert_eq!(aggregate.excluded, 1);
+        assert_eq!(aggregate.humidity.min, 70.0);
+        // The sunlit 31 °C is gone
+        assert_eq!(aggregate.temperature.max, 20.6_f32 as f64);
+        // A CO2 spike is real air
+        assert_eq!(aggregate.co2.max, 1800.0);
+        assert_eq!(aggregate.co2_max_time, Some(at(40)));
+
+        let mut agg = aggregator();
+        for reading in &raw {
+            agg.fold(reading);
+        }
+        let mut incremental = agg.flush().remove(0);
+        incremental.partial = false;
+        assert_eq!(incremental, aggregate);
+    }
+
+    #[test]
+    fn an_hour_of_artifacts_has_only_counts() {
+        let mut dip = m("a", 10, 600);
+        dip.humidity = 30.0;
+        let aggregate = rebuild("a", at(0), &[dip], &HourlyConfig::default()).unwrap();
+        assert_eq!(
+            aggregate.to_line_protocol(),
+            format!(
+                "scd40_hourly,device=a minutes_above_1000=0,minutes_above_1400=0,\
+                 count=0i,excluded=1i,partial=false {}",
+                at(0).timestamp_nanos_opt().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn earlier_measurements_are_only_detector_context() {
+        let raw = vec![m("a", -30, 2000), m("a", 10, 600)];
+        let aggregate = rebuild("a", at(0), &raw, &HourlyConfig::default()).unwrap();
+        assert_eq!(aggregate.count, 1);
+        assert_eq!(aggregate.co2.max, 600.0);
+    }
+
+    #[test]
+    fn rows_in_the_old_format_still_read() {
+        let rows: Vec<HourlyRow> = serde_json::from_str(
+            r#"[
+                {"time":"2025-01-15T10:00:00","device":"a","co2_min":600.0,"co2_max":800.0,
+                 "co2_mean":700.0,"temperature_min":20.5,"temperature_max":21.5,
+                 "temperature_mean":21.0,"humidity_min":40.0,"humidity_max":40.0,
+                 "humidity_mean":40.0,"count":2,"partial":false},
+                {"time":"2025-01-15T11:00:00","device":"a","co2_min":900.0,"co2_max":1500.0,
+                 "co2_mean":1200.0,"co2_max_time_ms":1736937000000,"minutes_above_1000":20.0,
+                 "minutes_above_1400":10.0,"count":5,"excluded":1,"partial":false},
+                {"time":"2025-01-15T12:00:00","device":"a","count":0,"excluded":3,
+                 "minutes_above_1000":0.0,"partial":false}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(rows[0].co2_max, Some(800.0));
+        assert_eq!(rows[0].co2_max_time(), None);
+        assert_eq!(rows[0].excluded, None);
+        assert!(rows[0].minutes_above().is_empty());
+        assert_eq!(rows[0].start(), Some(at(0)));
+        assert_eq!(rows[0].count(), 2);
+
+        assert_eq!(rows[1].co2_max_time(), Some(at(30)));
+        assert_eq!(
+            rows[1].minutes_above(),
+            BTreeMap::from([(1000, 20.0), (1400, 10.0)])
+        );
+        assert_eq!(rows[1].excluded, Some(1));
+
+        assert_eq!(rows[2].co2_mean, None);
+        assert_eq!(rows[2].excluded, Some(3));
+    }
+
+    #[test]
+    fn range_query_reads_every_field() {
+        let day = |d| {
+            DateTime::parse_from_rfc3339(&format!("2025-01-{:02}T00:00:00Z", d))
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        assert_eq!(
+            range_query(day(1), day(31), 100).as_str(),
+            "SELECT * FROM scd40_hourly WHERE time >= '2025-01-01T00:00:00+00:00' \
+             AND time <= '2025-01-31T00:00:00+00:00' ORDER BY time ASC LIMIT 100"
+        );
+    }
+}
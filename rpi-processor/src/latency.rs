@@ -0,0 +1,618 @@
+//! How long measurements take from the sensor to a queryable InfluxDB point.
+//!
+//! Devices stamp measurements with their clock (`ts`) once SNTP has synced,
+//! and number them (`seq`). The receiver notes when each message arrives and
+//! when InfluxDB acknowledges the write, which splits the latency into two
+//! stages: `transit`, from the reading to the receiver (network bring-up,
+//! broker), and `write`, from the receiver to the write ack.
+//!
+//! Only live, stamped measurements count. Left out are retained messages
+//! redelivered on reconnect, repeated sequence numbers (QoS 1 duplicates)
+//! and readings whose timestamp is more than `SKEW_TOLERANCE` ahead of the
+//! receiver or `MAX_TRANSIT` behind it, which means the device clock is off
+//! rather than the message slow.
+//!
+//! Histograms are served as OpenMetrics on `GET /metrics`, and every counted
+//! measurement is stored in `ingest_latency` for the daily report's
+//! percentiles.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Deserialize;
+use shared_types::DeviceMessage;
+
+use crate::fetcher::{Sql, query_rows};
+
+/// How far a device clock may run ahead of the receiver's
+pub const SKEW_TOLERANCE: Duration = Duration::seconds(2);
+/// Longer than a wake lasts, so an older timestamp means a clock that's behind
+pub const MAX_TRANSIT: Duration = Duration::minutes(10);
+/// Default for `--latency-warn-ms`
+pub const DEFAULT_WARN_MS: u64 = 10_000;
+
+/// Upper bounds of the histogram buckets, in milliseconds
+pub const BUCKETS_MS: [u64; 10] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000,
+];
+/// Totals kept per device for the percentiles
+const WINDOW: usize = 1_000;
+
+const METRIC: &str = "air_quality_ingest_latency_seconds";
+const EXCLUDED_METRIC: &str = "air_quality_ingest_latency_excluded";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Transit,
+    Write,
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Transit, Stage::Write, Stage::Total];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Transit => "transit",
+            Stage::Write => "write",
+            Stage::Total => "total",
+        }
+    }
+}
+
+/// One measurement's latency, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub transit_ms: u64,
+    pub write_ms: u64,
+}
+
+impl Sample {
+    pub fn total_ms(&self) -> u64 {
+        self.transit_ms + self.write_ms
+    }
+
+    fn stage_ms(&self, stage: Stage) -> u64 {
+        match stage {
+            Stage::Transit => self.transit_ms,
+            Stage::Write => self.write_ms,
+            Stage::Total => self.total_ms(),
+        }
+    }
+}
+
+/// Why a measurement was left out of the statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exclusion {
+    /// No device timestamp, the clock hasn't synced yet
+    Unstamped,
+    Retained,
+    Replayed,
+    Skewed,
+}
+
+impl Exclusion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exclusion::Unstamped => "unstamped",
+            Exclusion::Retained => "retained",
+            Exclusion::Replayed => "replayed",
+            Exclusion::Skewed => "skewed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observation {
+    Counted(Sample),
+    Excluded(Exclusion),
+}
+
+/// p50/p95 of a set of totals, nearest-rank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub count: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Summary {
+    /// `None` for an empty set
+    pub fn of(totals: impl IntoIterator<Item = u64>) -> Option<Self> {
+        let mut sorted: Vec<u64> = totals.into_iter().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        Some(Self {
+            count: sorted.len(),
+            p50_ms: percentile(&sorted, 50),
+            p95_ms: percentile(&sorted, 95),
+            max_ms: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// `sorted` must be non-empty
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Per bucket of `BUCKETS_MS`, then one for everything above
+    counts: [u64; BUCKETS_MS.len() + 1],
+    sum_ms: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, ms: u64) {
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.sum_ms += ms;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Counts at or below each bound of `BUCKETS_MS`, as OpenMetrics wants them
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.counts[..BUCKETS_MS.len()]
+            .iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DeviceLatency {
+    histograms: [Histogram; 3],
+    /// The last `WINDOW` totals, oldest first
+    recent: VecDeque<u64>,
+    excluded: BTreeMap<Exclusion, u64>,
+    last_seq: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    devices: BTreeMap<String, DeviceLatency>,
+}
+
+impl LatencyTracker {
+    /// Accounts for a measurement that arrived at `received` and whose write
+    /// InfluxDB acknowledged at `written`.
+    pub fn observe(
+        &mut self,
+        message: &DeviceMessage,
+        retained: bool,
+        received: DateTime<Utc>,
+        written: DateTime<Utc>,
+    ) -> Observation {
+        let state = self.devices.entry(message.device.clone()).or_default();
+        let observation = classify(state, message, retained, received, written);
+        match observation {
+            Observation::Counted(sample) => {
+                for (histogram, stage) in state.histograms.iter_mut().zip(Stage::ALL) {
+                    histogram.record(sample.stage_ms(stage));
+                }
+                if state.recent.len() == WINDOW {
+                    state.recent.pop_front();
+                }
+                state.recent.push_back(sample.total_ms());
+            }
+            Observation::Excluded(reason) => *state.excluded.entry(reason).or_default() += 1,
+        }
+        observation
+    }
+
+    /// Percentiles of the device's recent totals
+    pub fn summary(&self, device: &str) -> Option<Summary> {
+        Summary::of(self.devices.get(device)?.recent.iter().copied())
+    }
+
+    /// The histograms and exclusion counters, without the closing `# EOF`
+    pub fn write_openmetrics(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} histogram", METRIC);
+        let _ = writeln!(out, "# UNIT {} seconds", METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Time from a device taking a measurement to InfluxDB acknowledging it, by stage.",
+            METRIC
+        );
+        for (device, state) in &self.devices {
+            let device = escape_label(device);
+            for (histogram, stage) in state.histograms.iter().zip(Stage::ALL) {
+                let labels = format!("device=\"{}\",stage=\"{}\"", device, stage.as_str());
+                for (bound, count) in BUCKETS_MS.iter().zip(histogram.cumulative()) {
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{{{},le=\"{}\"}} {}",
+                        METRIC,
+                        labels,
+                        seconds(*bound),
+                        count
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"+Inf\"}} {}",
+                    METRIC,
+                    labels,
+                    histogram.count()
+                );
+                let _ = writeln!(out, "{}_count{{{}}} {}", METRIC, labels, histogram.count());
+                let _ = writeln!(
+                    out,
+                    "{}_sum{{{}}} {}",
+                    METRIC,
+                    labels,
+                    seconds(histogram.sum_ms)
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE {} counter", EXCLUDED_METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Measurements left out of the latency histogram, by reason.",
+            EXCLUDED_METRIC
+        );
+        for (device, state) in &self.devices {
+            let device = escape_label(device);
+            for (reason, count) in &state.excluded {
+                let _ = writeln!(
+                    out,
+                    "{}_total{{device=\"{}\",reason=\"{}\"}} {}",
+                    EXCLUDED_METRIC,
+                    device,
+                    reason.as_str(),
+                    count
+                );
+            }
+        }
+    }
+}
+
+fn classify(
+    state: &mut DeviceLatency,
+    message: &DeviceMessage,
+    retained: bool,
+    received: DateTime<Utc>,
+    written: DateTime<Utc>,
+) -> Observation {
+    if retained {
+        return Observation::Excluded(Exclusion::Retained);
+    }
+    if let Some(seq) = message.seq {
+        let repeated = state.last_seq == Some(seq);
+        // A lower number is a power cycle, which starts over at 0
+        state.last_seq = Some(seq);
+        if repeated {
+            return Observation::Excluded(Exclusion::Replayed);
+        }
+    }
+    let Some(taken) = message.timestamp() else {
+        return Observation::Excluded(Exclusion::Unstamped);
+    };
+    let transit = received - taken;
+    if transit < -SKEW_TOLERANCE || transit > MAX_TRANSIT {
+        return Observation::Excluded(Exclusion::Skewed);
+    }
+    Observation::Counted(Sample {
+        transit_ms: transit.num_milliseconds().max(0) as u64,
+        write_ms: (written - received).num_milliseconds().max(0) as u64,
+    })
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{}", ms as f64 / 1000.0)
+}
+
+/// Latency bookkeeping shared between the receiver and the web server
+#[derive(Debug, Clone, Default)]
+pub struct Latency(Arc<Mutex<LatencyTracker>>);
+
+impl Latency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(
+        &self,
+        message: &DeviceMessage,
+        retained: bool,
+        received: DateTime<Utc>,
+        written: DateTime<Utc>,
+    ) -> Observation {
+        self.0
+            .lock()
+            .unwrap()
+            .observe(message, retained, received, written)
+    }
+
+    pub fn summary(&self, device: &str) -> Option<Summary> {
+        self.0.lock().unwrap().summary(device)
+    }
+
+    pub fn write_openmetrics(&self, out: &mut String) {
+        self.0.lock().unwrap().write_openmetrics(out)
+    }
+}
+
+/// The `ingest_latency` point for a counted measurement
+pub fn to_line(device: &str, seq: Option<u32>, sample: &Sample, received: DateTime<Utc>) -> String {
+    let mut fields = format!(
+        "transit_ms={}i,write_ms={}i,total_ms={}i",
+        sample.transit_ms,
+        sample.write_ms,
+        sample.total_ms()
+    );
+    if let Some(seq) = seq {
+        let _ = write!(fields, ",seq={}i", seq);
+    }
+    format!(
+        "ingest_latency,device={} {} {}",
+        device,
+        fields,
+        received.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+pub fn day_query(start: DateTime<Utc>, end: DateTime<Utc>) -> Sql {
+    Sql::new("SELECT device, total_ms FROM ingest_latency WHERE time >= ")
+        .time(start)
+        .push(" AND time < ")
+        .time(end)
+}
+
+/// Percentiles of each device's stored latencies on `date`
+pub async fn fetch_day(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    date: NaiveDate,
+) -> Result<BTreeMap<String, Summary>, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct LatencyRow {
+        device: String,
+        total_ms: u64,
+    }
+
+    let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let rows: Vec<LatencyRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &day_query(start, start + Duration::days(1)),
+    )
+    .await?;
+
+    let mut totals: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for row in rows {
+        totals.entry(row.device).or_default().push(row.total_ms);
+    }
+    Ok(totals
+        .into_iter()
+        .filter_map(|(device, totals)| Summary::of(totals).map(|s| (device, s)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::DevicePayload;
+
+    const T0_MS: u64 = 1_736_942_400_000;
+
+    fn at_ms(ms: u64) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(ms as i64).unwrap()
+    }
+
+    fn openmetrics(tracker: &LatencyTracker) -> String {
+        let mut out = String::new();
+        tracker.write_openmetrics(&mut out);
+        out
+    }
+
+    fn stamped(device: &str, ts: u64, seq: u32) -> DeviceMessage {
+        DeviceMessage::new(device, DevicePayload::measurement(600, 21.0, 40.0))
+            .stamped(Some(ts), seq)
+    }
+
+    /// A live measurement taken at `ts` that spent `transit_ms` getting to
+    /// the receiver and `write_ms` being written
+    fn arrive(
+        tracker: &mut LatencyTracker,
+        message: &DeviceMessage,
+        transit_ms: u64,
+        write_ms: u64,
+    ) -> Observation {
+        let received = at_ms(message.ts.unwrap_or(T0_MS) + transit_ms);
+        tracker.observe(
+            message,
+            false,
+            received,
+            received + Duration::milliseconds(write_ms as i64),
+        )
+    }
+
+    #[test]
+    fn splits_latency_into_stages() {
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(
+            arrive(&mut tracker, &stamped("kitchen", T0_MS, 0), 1_200, 35),
+            Observation::Counted(Sample {
+                transit_ms: 1_200,
+                write_ms: 35
+            })
+        );
+        let summary = tracker.summary("kitchen").unwrap();
+        assert_eq!(summary.count, 1);
+        assert_eq!(summary.p50_ms, 1_235);
+    }
+
+    #[test]
+    fn percentiles_of_a_synthetic_stream() {
+        let mut tracker = LatencyTracker::default();
+        // 1..=100 seconds of transit, shuffled by stepping through with a stride
+        for i in 0..100u32 {
+            let transit = (i * 37 % 100 + 1) as u64 * 1_000;
+            arrive(
+                &mut tracker,
+                &stamped("kitchen", T0_MS + i as u64 * 300_000, i),
+                transit,
+                0,
+            );
+        }
+        assert_eq!(
+            tracker.summary("kitchen").unwrap(),
+            Summary {
+                count: 100,
+                p50_ms: 50_000,
+                p95_ms: 95_000,
+                max_ms: 100_000,
+            }
+        );
+        assert_eq!(Summary::of([]), None);
+        assert_eq!(Summary::of([7]).unwrap().p95_ms, 7);
+    }
+
+    #[test]
+    fn skewed_clocks_are_left_out() {
+        let mut tracker = LatencyTracker::default();
+        let taken = T0_MS;
+        // Slightly ahead is within tolerance and counts as no transit
+        let ahead = tracker.observe(
+            &stamped("kitchen", taken, 0),
+            false,
+            at_ms(taken - 1_500),
+            at_ms(taken - 1_400),
+        );
+        assert_eq!(
+            ahead,
+            Observation::Counted(Sample {
+                transit_ms: 0,
+                write_ms: 100
+            })
+        );
+        let far_ahead = tracker.observe(
+            &stamped("kitchen", taken, 1),
+            false,
+            at_ms(taken - 5_000),
+            at_ms(taken - 4_900),
+        );
+        assert_eq!(far_ahead, Observation::Excluded(Exclusion::Skewed));
+        assert_eq!(
+            arrive(&mut tracker, &stamped("kitchen", taken, 2), 3_600_000, 10),
+            Observation::Excluded(Exclusion::Skewed)
+        );
+        assert_eq!(tracker.summary("kitchen").unwrap().count, 1);
+        let metrics = openmetrics(&tracker);
+        assert!(metrics.contains(
+            "air_quality_ingest_latency_excluded_total{device=\"kitchen\",reason=\"skewed\"} 2"
+        ));
+    }
+
+    #[test]
+    fn replays_and_unstamped_messages_are_left_out() {
+        let mut tracker = LatencyTracker::default();
+        let first = stamped("kitchen", T0_MS, 7);
+        assert!(matches!(
+            arrive(&mut tracker, &first, 900, 20),
+            Observation::Counted(_)
+        ));
+        // A QoS 1 duplicate a little later
+        assert_eq!(
+            arrive(&mut tracker, &first, 1_900, 20),
+            Observation::Excluded(Exclusion::Replayed)
+        );
+        // Retained copy on reconnect
+        let received = at_ms(T0_MS + 600_000);
+        assert_eq!(
+            tracker.observe(&stamped("kitchen", T0_MS, 7), true, received, received),
+            Observation::Excluded(Exclusion::Retained)
+        );
+        // After a power cycle the sequence starts over and the clock isn't set
+        let restarted = DeviceMessage {
+            seq: Some(0),
+            ..DeviceMessage::new("kitchen", DevicePayload::measurement(600, 21.0, 40.0))
+        };
+        assert_eq!(
+            arrive(&mut tracker, &restarted, 0, 20),
+            Observation::Excluded(Exclusion::Unstamped)
+        );
+        assert!(matches!(
+            arrive(
+                &mut tracker,
+                &stamped("kitchen", T0_MS + 300_000, 1),
+                800,
+                20
+            ),
+            Observation::Counted(_)
+        ));
+        assert_eq!(tracker.summary("kitchen").unwrap().count, 2);
+    }
+
+    #[test]
+    fn renders_cumulative_histograms() {
+        let mut tracker = LatencyTracker::default();
+        for (seq, transit) in [40, 400, 4_000].into_iter().enumerate() {
+            arrive(
+                &mut tracker,
+                &stamped("kitchen", T0_MS + seq as u64 * 300_000, seq as u32),
+                transit,
+                10,
+            );
+        }
+        let metrics = openmetrics(&tracker);
+        for expected in [
+            "# TYPE air_quality_ingest_latency_seconds histogram",
+            "air_quality_ingest_latency_seconds_bucket{device=\"kitchen\",stage=\"total\",le=\"0.05\"} 1",
+            "air_quality_ingest_latency_seconds_bucket{device=\"kitchen\",stage=\"total\",le=\"0.5\"} 2",
+            "air_quality_ingest_latency_seconds_bucket{device=\"kitchen\",stage=\"total\",le=\"5\"} 3",
+            "air_quality_ingest_latency_seconds_bucket{device=\"kitchen\",stage=\"total\",le=\"+Inf\"} 3",
+            "air_quality_ingest_latency_seconds_count{device=\"kitchen\",stage=\"write\"} 3",
+            "air_quality_ingest_latency_seconds_sum{device=\"kitchen\",stage=\"transit\"} 4.44",
+        ] {
+            assert!(
+                metrics.contains(expected),
+                "{} missing from\n{}",
+                expected,
+                metrics
+            );
+        }
+    }
+
+    #[test]
+    fn stored_points() {
+        let sample = Sample {
+            transit_ms: 1_200,
+            write_ms: 35,
+        };
+        assert_eq!(
+            to_line("kitchen", Some(42), &sample, at_ms(T0_MS)),
+            "ingest_latency,device=kitchen transit_ms=1200i,write_ms=35i,total_ms=1235i,seq=42i 1736942400000000000"
+        );
+        assert_eq!(
+            day_query(at_ms(T0_MS), at_ms(T0_MS) + Duration::days(1)).as_str(),
+            "SELECT device, total_ms FROM ingest_latency \
+             WHERE time >= '2025-01-15T12:00:00+00:00' AND time < '2025-01-16T12:00:00+00:00'"
+        );
+    }
+}
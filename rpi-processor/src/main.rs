@@ -1,18 +1,57 @@
+mod alerts;
 mod anomalies;
+mod anomaly_dedupe;
+mod anomaly_review;
+mod anomaly_tuning;
+#[cfg(feature = "archive")]
+mod archive;
+mod bootstrap;
+mod bulk_write;
+mod command_relay;
+mod config_drift;
+mod data_quality;
+mod dedup;
+mod device_config;
+mod device_diagnostics;
+mod device_events;
+mod device_info;
+mod device_status;
+mod digest;
+mod disconnects;
+mod external_events;
+mod failover;
 mod fetcher;
+mod freshness;
+mod home;
+mod hourly;
+mod latency;
+mod maintenance;
+mod model_training;
+mod options;
+mod pipeline;
+mod prediction_cache;
 mod predictor;
 mod predictor_web;
+mod preflight;
+mod reference;
+mod share_export;
+mod state_file;
+mod stats;
+mod storage;
+#[cfg(test)]
+pub(crate) mod test_support;
 mod types;
+mod ventilation;
 
 use chrono::{DateTime, Utc};
-use circular_queue::CircularQueue;
 use rumqttc::{Client, Event, MqttOptions, Packet};
-use shared_types::{DeviceMessage, DevicePayload};
 use std::{env, time::Duration};
 
-use log::{self, debug, error, info};
+use log::{self, error, info, warn};
 
+use bulk_write::PointStore;
 use clap::Parser;
+use shared_types::topics;
 use types::{InfluxMeasurementRow, MeasurementWithTime};
 
 #[derive(Parser, Debug)]
@@ -26,20 +65,53 @@ struct Args {
     #[arg(short, long, default_value_t = false)]
     delete_old_markings: bool,
 
+    /// Merge anomaly markings stored twice for the same device and time
+    /// (untagged ones from before reviews next to status=auto ones)
+    #[arg(long, default_value_t = false)]
+    dedupe_anomalies: bool,
+
     /// Receive live data from MQTT broker and save it to influxDB
     #[arg(short, long, default_value_t = false)]
     receive_live_data: bool,
 
+    /// Maintain hourly per-device aggregates in scd40_hourly while receiving live data
+    #[arg(long, default_value_t = false)]
+    hourly_aggregates: bool,
+
+    /// CO2 levels, in ppm, whose exceedance the hourly aggregates record in minutes
+    #[arg(long, value_delimiter = ',', default_values_t = hourly::DEFAULT_CO2_THRESHOLDS)]
+    hourly_co2_thresholds: Vec<u16>,
+
     /// Predict weather (CO2, Temp, Humidity) based on historical data
     #[arg(short, long, default_value_t = false)]
     predict_weather: bool,
 
+    /// Give the predictor an "external event in progress" feature, set while
+    /// an event of one of these kinds covers a measurement (comma separated)
+    #[arg(long, value_delimiter = ',', value_name = "KINDS")]
+    predictor_event_kinds: Vec<String>,
+
     /// Timestamp to use as "now" for prediction (RFC3339 format).
     /// If provided, the model will be trained on data before this time,
     /// and predict the weather 1 hour after this time, comparing it with actual data.
     #[arg(long)]
     prediction_timestamp: Option<String>,
 
+    /// Train a challenger model and promote it over the current one unless
+    /// it does worse on the last week; see model_training.rs. Meant to run
+    /// weekly.
+    #[arg(long, default_value_t = false)]
+    retrain_model: bool,
+
+    /// Promote the challenger from --retrain-model whatever its error
+    #[arg(long, default_value_t = false, requires = "retrain_model")]
+    force_promote: bool,
+
+    /// Settings for --retrain-model, e.g.
+    /// "holdout_days=7,tolerance_percent=2,min_samples=100"
+    #[arg(long)]
+    retrain_config: Option<model_training::RetrainConfig>,
+
     /// Run a matrix of anomaly detection tests with different parameters
     #[arg(long, default_value_t = false)]
     mark_anomalies_test: bool,
@@ -55,6 +127,223 @@ struct Args {
     /// Base path for web server (e.g. "/air-predictor")
     #[arg(long, default_value = "/")]
     web_base_path: String,
+
+    /// Relay commands submitted through the web API to the devices.
+    /// Requires both --web-server and --receive-live-data.
+    #[arg(long, default_value_t = false)]
+    command_relay: bool,
+
+    /// Start without first checking InfluxDB, the MQTT broker, alert and
+    /// digest destinations and the state file directories
+    #[arg(long, default_value_t = false)]
+    no_preflight: bool,
+
+    /// Clear the retained command when a relayed command times out
+    #[arg(long, default_value_t = false)]
+    relay_clear_on_timeout: bool,
+
+    /// Compute the daily data-quality score for every device
+    #[arg(long, default_value_t = false)]
+    daily_report: bool,
+
+    /// Send the end-of-day digest to the channels configured in the environment
+    #[arg(long, default_value_t = false)]
+    daily_digest: bool,
+
+    /// Day to report on (YYYY-MM-DD, UTC). Defaults to yesterday for
+    /// --daily-report and to today for --daily-digest.
+    #[arg(long)]
+    report_date: Option<chrono::NaiveDate>,
+
+    /// How long the receiver waits for the startup bootstrap before it
+    /// connects to MQTT and lets the bootstrap finish in the background
+    #[arg(long, default_value_t = 5)]
+    bootstrap_timeout_seconds: u64,
+
+    /// Warn when a measurement takes longer than this from the device to
+    /// InfluxDB, in milliseconds
+    #[arg(long, default_value_t = latency::DEFAULT_WARN_MS)]
+    latency_warn_ms: u64,
+
+    /// Stages live data goes through, in order. Stages whose feature is off
+    /// (relay, hourly, freshness without the web server, alerts) are skipped.
+    #[arg(long, value_delimiter = ',', default_values = pipeline::STAGES)]
+    ingest_stages: Vec<String>,
+
+    /// Run as one of two receivers, under this id: only the instance holding
+    /// the lease on the MQTT broker writes and alerts, the other one stands by
+    #[arg(long, value_name = "ID", requires = "receive_live_data")]
+    failover_instance: Option<String>,
+
+    /// How long the failover lease lasts without being renewed
+    #[arg(long, default_value_t = failover::DEFAULT_LEASE_SECONDS)]
+    failover_lease_seconds: i64,
+
+    /// How far apart the clocks of the two receivers may be
+    #[arg(long, default_value_t = failover::DEFAULT_SKEW_SECONDS)]
+    failover_skew_seconds: i64,
+
+    /// Expected interval between measurements, used for completeness and for
+    /// the time above CO2 thresholds in the hourly aggregates
+    #[arg(long, default_value_t = 300)]
+    expected_interval_seconds: i64,
+
+    /// Data-quality weights, e.g. "completeness=40,anomalies=20,rejections=15,flatline=15,clock_skew=10".
+    /// Components that aren't listed keep their default weight.
+    #[arg(long)]
+    quality_weights: Option<data_quality::QualityWeights>,
+
+    /// Ventilation advice settings, e.g. "limit_ppm=1000,target_ppm=600,infiltration_rate=0.3".
+    /// Settings that aren't listed keep their default; see ventilation.rs for all of them.
+    #[arg(long)]
+    ventilation_config: Option<ventilation::VentilationConfig>,
+
+    /// Disk budget and retention for --storage-report and /api/storage, e.g.
+    /// "budget_mb=16000,retention_days=365"; see storage.rs for all settings
+    #[arg(long)]
+    storage_config: Option<storage::StorageConfig>,
+
+    /// Write a virtual "home" device combined from all the others, e.g.
+    /// "strategy=weighted,max_age_minutes=15"; see home.rs for the strategies
+    #[arg(long)]
+    home_aggregate: Option<home::HomeConfig>,
+
+    /// How long a prediction answers repeated requests for the same time,
+    /// unless a newer measurement arrives first
+    #[arg(long, default_value_t = prediction_cache::DEFAULT_TTL_SECONDS)]
+    prediction_cache_seconds: i64,
+
+    /// Devices scoring below this are highlighted on the dashboard
+    #[arg(long, default_value_t = data_quality::DEFAULT_ALERT_THRESHOLD)]
+    quality_alert_threshold: f64,
+
+    /// Import reference measurements (timestamp, co2, temperature, humidity, source) from a CSV file
+    #[arg(long, value_name = "CSV")]
+    import_reference: Option<std::path::PathBuf>,
+
+    /// Source name for imported rows without a source column (default "reference").
+    /// When comparing, only reference data from this source is used.
+    #[arg(long)]
+    reference_source: Option<String>,
+
+    /// Map reference CSV headers explicitly, e.g. "time=Logged at,co2=CO2 ppm".
+    /// Unlisted columns are detected from the header.
+    #[arg(long)]
+    reference_columns: Option<reference::ColumnOverrides>,
+
+    /// UTC offset of reference timestamps without a timezone, e.g. "+01:00"
+    #[arg(long, default_value = "+00:00")]
+    reference_utc_offset: chrono::FixedOffset,
+
+    /// Timezone the web server's heatmap is local to, as a POSIX TZ string,
+    /// e.g. "CET-1CEST,M3.5.0,M10.5.0/3"
+    #[arg(long, default_value = "UTC0")]
+    display_timezone: stats::DisplayZone,
+
+    /// Compare a device against stored reference data and recommend calibration
+    #[arg(long, default_value_t = false)]
+    compare_reference: bool,
+
+    /// Device to compare against the reference, to restore with --restore,
+    /// to limit --share-export to, or that an event added with --add-event
+    /// happened at
+    #[arg(long)]
+    device: Option<String>,
+
+    /// Start of the comparison window (RFC3339). Defaults to 7 days before --to.
+    /// With --restore, only rows from this time on are restored.
+    /// With --share-export, the first row exported (default 30 days before --to).
+    /// With --add-event, when the event started.
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+
+    /// End of the comparison window (RFC3339). Defaults to now.
+    /// With --restore, only rows up to this time are restored.
+    /// With --share-export, the last row exported.
+    /// With --add-event, when the event ended.
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+
+    /// Record an external event, like a party or a new HVAC filter, from
+    /// --from to --to on --device; see external_events.rs
+    #[arg(long, default_value_t = false, requires_all = ["device", "from", "to", "kind"])]
+    add_event: bool,
+
+    /// What kind of event --add-event records, e.g. "party" or "hvac_filter"
+    #[arg(long)]
+    kind: Option<String>,
+
+    /// Free text stored with the event from --add-event
+    #[arg(long)]
+    note: Option<String>,
+
+    /// Maximum time between a reference row and the device measurement it is paired with
+    #[arg(long, default_value_t = 300)]
+    reference_tolerance_seconds: i64,
+
+    /// Print "device seconds_since_last_measurement" for every device
+    #[arg(long, default_value_t = false)]
+    freshness: bool,
+
+    /// With --freshness, exit with status 1 if any device is older than this
+    #[arg(long, value_name = "SECONDS")]
+    freshness_max_age: Option<i64>,
+
+    /// Print the estimated InfluxDB footprint, its growth and how long the
+    /// disk budget lasts
+    #[arg(long, default_value_t = false)]
+    storage_report: bool,
+
+    /// Write measurements sanitized for publishing to this CSV, with a
+    /// manifest of what was done next to it; see share_export.rs
+    #[arg(long, value_name = "CSV")]
+    share_export: Option<std::path::PathBuf>,
+
+    /// Settings for --share-export, e.g.
+    /// "seed=42,resolution_minutes=15,max_shift_days=365,keep_time_of_day=true"
+    #[arg(long)]
+    share_config: Option<share_export::ShareConfig>,
+
+    /// Put a device in maintenance until --maintenance-until: its measurements
+    /// are stored tagged maintenance=true and skipped by anomaly marking,
+    /// quality alerts and predictor training
+    #[arg(long, value_name = "DEVICE", requires = "maintenance_until")]
+    maintenance: Option<String>,
+
+    /// End of the maintenance window (RFC3339)
+    #[arg(long)]
+    maintenance_until: Option<DateTime<Utc>>,
+
+    /// End a device's maintenance window now
+    #[arg(long, value_name = "DEVICE")]
+    end_maintenance: Option<String>,
+
+    /// Export raw measurements older than --archive-before to Parquet in --archive-target
+    #[cfg(feature = "archive")]
+    #[arg(long, default_value_t = false)]
+    archive: bool,
+
+    /// Archive cutoff (RFC3339); rows strictly before it are exported
+    #[cfg(feature = "archive")]
+    #[arg(long)]
+    archive_before: Option<DateTime<Utc>>,
+
+    /// Archive location: a directory or s3://bucket/prefix (configured with AWS_* variables)
+    #[cfg(feature = "archive")]
+    #[arg(long, value_name = "DIR|URL")]
+    archive_target: Option<String>,
+
+    /// After a verified archive, delete the archived raw rows from InfluxDB.
+    /// Refused for cutoffs less than 30 days ago.
+    #[cfg(feature = "archive")]
+    #[arg(long, default_value_t = false)]
+    archive_delete: bool,
+
+    /// Write archived rows from --archive-target back to InfluxDB,
+    /// optionally limited with --device, --from and --to
+    #[cfg(feature = "archive")]
+    #[arg(long, default_value_t = false)]
+    restore: bool,
 }
 
 pub async fn fetch_historical_measurements(
@@ -63,47 +352,18 @@ pub async fn fetch_historical_measurements(
     influx_database: &str,
     reqwest_client: &reqwest::Client,
 ) -> Result<Vec<MeasurementWithTime>, Box<dyn std::error::Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-    log::debug!("Query URL: {}", query_url);
-    // SQL query to get all measurements ordered by time
-    let sql_query = r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent,
-            device
-        FROM scd40_data
-        ORDER BY time ASC
-    "#;
-
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await?;
-        return Err(format!(
-            "InfluxDB query failed with status {}: {}",
-            status, error_text
-        )
-        .into());
-    }
-
-    let response_text = response.text().await?;
-    if response_text.is_empty() {
-        return Ok(Vec::new());
-    }
-
-    let influx_rows: Vec<InfluxMeasurementRow> = serde_json::from_str(&response_text)?;
+    // All measurements ordered by time
+    let influx_rows: Vec<InfluxMeasurementRow> = fetcher::query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &fetcher::Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             ORDER BY time ASC",
+        ),
+    )
+    .await?;
     let mut measurements = Vec::with_capacity(influx_rows.len());
 
     for (idx, row) in influx_rows.iter().enumerate() {
@@ -122,6 +382,32 @@ pub async fn fetch_historical_measurements(
     Ok(measurements)
 }
 
+/// Drops measurements stored during maintenance, which anomaly marking skips.
+async fn without_maintenance(
+    mut measurements: Vec<MeasurementWithTime>,
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+) -> Vec<MeasurementWithTime> {
+    let maintenance = maintenance::fetch_maintenance_times(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+    if !maintenance.is_empty() {
+        let before = measurements.len();
+        measurements.retain(|m| !maintenance.contains(&m.time));
+        log::info!(
+            "Skipping {} measurements taken during maintenance",
+            before - measurements.len()
+        );
+    }
+    measurements
+}
+
 pub async fn run_anomaly_test_matrix(
     influx_host: &str,
     influx_token: &str,
@@ -132,6 +418,14 @@ pub async fn run_anomaly_test_matrix(
     let measurements =
         fetch_historical_measurements(influx_host, influx_token, influx_database, reqwest_client)
             .await?;
+    let measurements = without_maintenance(
+        measurements,
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
     log::info!("Fetched {} measurements for testing", measurements.len());
 
     // Test different configuration combinations
@@ -218,6 +512,14 @@ pub async fn mark_historical_data(
             .await?;
 
     log::info!("Received {} measurements", measurements.len());
+    let measurements = without_maintenance(
+        measurements,
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
 
     // Use new multi-stage anomaly detection
     let result = anomalies::analyze_historical_data(&measurements, None);
@@ -264,40 +566,59 @@ async fn save_anomalies_batch(
     anomalies: &[(DateTime<Utc>, anomalies::AnomalyFlags, String)],
     measurement_name: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if anomalies.is_empty() {
+    // One range query per batch; points already stored with the same flags
+    // are dropped, the rest merged with what is stored
+    let table = fetcher::Identifier::parse(measurement_name)?;
+    let existing = match anomaly_dedupe::fetch_existing(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &table,
+        anomalies,
+    )
+    .await
+    {
+        Ok(existing) => existing,
+        Err(e) => {
+            // Also the case before the table's first point
+            log::warn!("Couldn't check for stored anomaly markings: {}", e);
+            Default::default()
+        }
+    };
+    let admitted = anomaly_dedupe::admit(anomalies, &existing);
+    if admitted.len() < anomalies.len() {
+        log::info!(
+            "Skipping {} anomaly markings already stored",
+            anomalies.len() - admitted.len()
+        );
+    }
+    if admitted.is_empty() {
         return Ok(());
     }
 
-    // Build line protocol for all anomalies
-    let mut line_protocol_lines = Vec::new();
-
-    for (timestamp, flags, device) in anomalies {
-        // Convert timestamp to Unix nanoseconds
-        let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-
-        // Build line protocol: measurement,tags fields timestamp
-        let line = format!(
-            "{},device={} temperature_spike={},humidity_spike={},co2_spike={},physical_constraint_temp_violation={},physical_constraint_humidity_violation={},physical_constraint_co2_violation={},possible_sunlight={} {}",
-            measurement_name,
-            device,
-            flags.temperature_spike,
-            flags.humidity_spike,
-            flags.co2_spike,
-            flags.physical_constraint_temp_violation,
-            flags.physical_constraint_humidity_violation,
-            flags.physical_constraint_co2_violation,
-            flags.possible_sunlight,
-            timestamp_nanos
-        );
-        line_protocol_lines.push(line);
-    }
+    let locations = anomaly_dedupe::fetch_locations(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &admitted,
+    )
+    .await;
+    let line_protocol_lines: Vec<String> = admitted
+        .iter()
+        .map(|marking @ (time, _, device)| {
+            let location = locations.get(&(*time, device.clone()));
+            anomaly_dedupe::marking_line(measurement_name, marking, location.map(String::as_str))
+        })
+        .collect();
 
     // Join all lines with newlines
     let batch_body = line_protocol_lines.join("\n");
 
     // Write to InfluxDB
     let response = reqwest_client
-        .post(&format!(
+        .post(format!(
             "{}/api/v3/write_lp?db={}",
             influx_host, influx_database
         ))
@@ -328,39 +649,21 @@ pub async fn delete_old_markings(
     log::info!("Deleting old anomaly markings from database...");
 
     // 1. List all tables to find ones starting with "anomalies"
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-    let sql_query = "SHOW TABLES";
-
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await?;
-        return Err(format!(
-            "InfluxDB show tables failed with status {}: {}",
-            status, error_text
-        )
-        .into());
-    }
-
-    let response_text = response.text().await?;
-    let tables: Vec<serde_json::Value> = serde_json::from_str(&response_text)?;
+    let tables: Vec<serde_json::Value> = fetcher::query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &fetcher::Sql::new("SHOW TABLES"),
+    )
+    .await?;
 
     let mut tables_to_delete = Vec::new();
     for table in tables {
-        if let Some(name) = table.get("table_name").and_then(|v| v.as_str()) {
-            if name.starts_with("anomalies") {
-                tables_to_delete.push(name.to_string());
-            }
+        if let Some(name) = table.get("table_name").and_then(|v| v.as_str())
+            && name.starts_with("anomalies")
+        {
+            tables_to_delete.push(name.to_string());
         }
     }
 
@@ -406,59 +709,158 @@ pub async fn delete_old_markings(
     Ok(())
 }
 
-pub async fn save_measurement_to_influx(
-    influx_host: &str,
-    influx_token: &str,
-    influx_database: &str,
-    device: &str,
-    co2: u16,
-    temperature: f32,
-    humidity: f32,
-    reqwest_client: &reqwest::Client,
-) {
-    let line_protocol = format!(
-        "scd40_data,device={} co2_ppm={},temperature_c={},humidity_percent={}",
-        device, co2, temperature, humidity
-    );
+/// The parts of the pipeline only the leader runs
+struct LeaderParts<'a> {
+    hourly: Option<(hourly::HourlyAggregator, bulk_write::InfluxStore<'a>)>,
+    alerter: Option<(alerts::Alerter, reqwest::Client)>,
+    /// The alert scheduler, running as long as the alerter is
+    scheduler: Option<tokio::task::JoinHandle<()>>,
+    drift: config_drift::DriftDetector,
+}
 
-    let response = reqwest_client
-        .post(&format!(
-            "{}/api/v3/write_lp?db={}",
-            influx_host, influx_database
-        ))
-        .body(line_protocol)
-        .bearer_auth(influx_token)
-        .send()
-        .await
-        .expect("Failed to send measurement to InfluxDB");
+impl LeaderParts<'_> {
+    /// What a failover follower runs with: none of it
+    fn follower() -> Self {
+        Self {
+            hourly: None,
+            alerter: None,
+            scheduler: None,
+            drift: config_drift::DriftDetector::new(Utc::now()),
+        }
+    }
+}
 
-    if !response.status().is_success() {
-        eprintln!(
-            "Failed to save measurement to InfluxDB: {} - {}",
-            response.status(),
-            response.text().await.expect("Failed to get response text")
-        );
+/// Recovers hourly aggregates and device configurations from InfluxDB and
+/// starts the alert scheduler
+async fn start_leading<'a>(
+    influx: bulk_write::InfluxStore<'a>,
+    hourly: Option<&hourly::HourlyConfig>,
+    bootstrap_timeout: Duration,
+) -> LeaderParts<'a> {
+    let bulk_write::InfluxStore {
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    } = influx;
+    let hourly = if let Some(config) = hourly {
+        let mut aggregator = hourly::HourlyAggregator::new(config.clone());
+        if let Err(e) = hourly::recover(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &mut aggregator,
+            Utc::now(),
+        )
+        .await
+        {
+            error!("Failed to recover hourly aggregates: {}", e);
+        }
+        Some((aggregator, influx))
     } else {
+        None
+    };
+
+    let (alerter, scheduler) = match alerts::AlertPolicy::from_env() {
+        Ok(policy) if policy.is_enabled() => {
+            let store = alerts::AlertStore::from_env();
+            let scheduler = tokio::spawn(alerts::run_scheduler(
+                store.clone(),
+                policy.clone(),
+                reqwest_client.clone(),
+            ));
+            (
+                Some((
+                    alerts::Alerter::new(store, policy, Utc::now()),
+                    reqwest_client.clone(),
+                )),
+                Some(scheduler),
+            )
+        }
+        Ok(_) => (None, None),
+        Err(e) => {
+            error!("Invalid alert configuration, alerts are off: {}", e);
+            (None, None)
+        }
+    };
+
+    let mut drift = config_drift::DriftDetector::new(Utc::now());
+    match tokio::time::timeout(
+        bootstrap_timeout,
+        device_config::fetch_latest(influx_host, influx_token, influx_database, reqwest_client),
+    )
+    .await
+    {
+        Ok(Ok(snapshots)) => {
+            for (device, snapshot) in &snapshots {
+                drift.restore(device, snapshot);
+            }
+        }
+        Ok(Err(e)) => error!("Failed to load device configurations: {}", e),
+        Err(_) => warn!(
+            "Loading device configurations takes more than {:?}, starting without them",
+            bootstrap_timeout
+        ),
+    }
+
+    LeaderParts {
+        hourly,
+        alerter,
+        scheduler,
+        drift,
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn receive_live_data(
     influx_host: &str,
     influx_token: &str,
     influx_database: &str,
     reqwest_client: &reqwest::Client,
+    relay: Option<(
+        command_relay::RelayHandle,
+        tokio::sync::mpsc::UnboundedReceiver<command_relay::OutgoingCommand>,
+    )>,
+    hourly: Option<hourly::HourlyConfig>,
+    last_seen: Option<freshness::LastSeen>,
+    bootstrap_timeout: Duration,
+    latency: latency::Latency,
+    latency_warn_ms: u64,
+    stages: &[String],
+    metrics: pipeline::Metrics,
+    failover: Option<(failover::Election, failover::LeaderStatus)>,
+    home: Option<home::HomeConfig>,
 ) {
-    let mut measurement_queue: CircularQueue<MeasurementWithTime> =
-        CircularQueue::with_capacity(300);
-
-    let mqtt_host = env::var("MQTT_BROKER_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let mqtt_port: u16 = env::var("MQTT_BROKER_PORT")
-        .unwrap_or_else(|_| "1883".to_string())
-        .parse()
-        .expect("MQTT_BROKER_PORT must be a valid u16");
-    let mqtt_client_id =
-        env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "raspberry-pi-receiver".to_string());
-    let mqtt_topic = env::var("MQTT_TOPIC").unwrap_or_else(|_| "sensors/esp32/sensor".to_string());
+    let influx = bulk_write::InfluxStore {
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    };
+    let (mut election, leader_status) = failover.unzip();
+    let instance = election.as_ref().map(|e| e.instance().to_string());
+    let store = failover::InstanceTagged {
+        store: influx,
+        instance: instance.as_deref(),
+    };
+    let follower_stages = pipeline::follower_stages(stages);
+    let maintenance = maintenance::MaintenanceStore::from_env();
+    let rooms = home.is_some().then(ventilation::RoomRegistry::from_env);
+
+    let preflight::BrokerSettings {
+        host: mqtt_host,
+        port: mqtt_port,
+        client_id: mqtt_client_id,
+        topic: mqtt_topic,
+    } = match preflight::BrokerSettings::from_env() {
+        Ok(broker) => broker,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let command_topic = topics::COMMAND_BROADCAST_TOPIC.to_string();
 
     let mut mqttoptions = MqttOptions::new(mqtt_client_id, &mqtt_host, mqtt_port);
     mqttoptions.set_keep_alive(Duration::from_secs(30));
@@ -468,144 +870,270 @@ pub async fn receive_live_data(
     let (client, mut connection) = Client::new(mqttoptions, 10);
     info!("Waiting for connection...\n");
 
+    let relay = relay.map(|(handle, outbox_rx)| {
+        command_relay::spawn_publisher(client.clone(), handle.clone(), outbox_rx);
+        handle
+    });
+
+    let assemble = |names: &[String],
+                    LeaderParts {
+                        hourly,
+                        alerter,
+                        drift,
+                        ..
+                    }| {
+        let parts = pipeline::Parts {
+            store,
+            command_topic: command_topic.clone(),
+            drift,
+            maintenance: maintenance.clone(),
+            latency: latency.clone(),
+            latency_warn_ms,
+            relay: relay.clone(),
+            hourly,
+            last_seen: last_seen.clone(),
+            alerter,
+            home: home
+                .clone()
+                .zip(rooms.clone())
+                .map(|(config, rooms)| home::HomeAggregator::new(config, rooms)),
+        };
+        pipeline::assemble(names, parts, metrics.clone())
+    };
+    let (pipeline, mut scheduler) = if election.is_some() {
+        // Only checked for the stage names, the leader's parts come later
+        if let Err(e) = assemble(stages, LeaderParts::follower()) {
+            error!("Invalid ingest stages: {}", e);
+            return;
+        }
+        info!("Following until this instance holds the lease");
+        let pipeline = assemble(&follower_stages, LeaderParts::follower());
+        (pipeline, None)
+    } else {
+        let mut leader = start_leading(influx, hourly.as_ref(), bootstrap_timeout).await;
+        let scheduler = leader.scheduler.take();
+        (assemble(stages, leader), scheduler)
+    };
+    let mut pipeline = match pipeline {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            error!("Invalid ingest stages: {}", e);
+            return;
+        }
+    };
+    info!("Ingest stages: {}", pipeline.names().join(", "));
+
+    let bootstrap = bootstrap::fetch(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        Utc::now(),
+    );
+    tokio::pin!(bootstrap);
+    let mut bootstrap_pending = pipeline.restores();
+    if bootstrap_pending {
+        match tokio::time::timeout(bootstrap_timeout, &mut bootstrap).await {
+            Ok(Ok(restored)) => {
+                pipeline.feed(pipeline::Event::Restored(restored)).await;
+                bootstrap_pending = false;
+            }
+            Ok(Err(e)) => {
+                error!("Failed to restore device state: {}", e);
+                bootstrap_pending = false;
+            }
+            Err(_) => warn!(
+                "Restoring device state takes more than {:?}, continuing in the background",
+                bootstrap_timeout
+            ),
+        }
+    }
+
+    let mut election_tick = tokio::time::interval(Duration::from_secs(1));
     loop {
-        match connection.eventloop.poll().await {
-            Ok(Event::Incoming(Packet::Publish(publish))) => {
-                let topic = &publish.topic;
-                let payload = &publish.payload;
-
-                match std::str::from_utf8(payload) {
-                    Ok(str_message) => {
-                        info!("Received message on topic '{}'", topic);
-                        debug!("Raw message content: {}", str_message);
-
-                        match serde_json::from_str::<DeviceMessage>(str_message) {
-                            Ok(device_message) => {
-                                let device = &device_message.device;
-                                debug!("Decoded message: {:?}", &device_message);
-                                match device_message.payload {
-                                    DevicePayload::MeasurementSuccess {
-                                        co2,
-                                        temperature,
-                                        humidity,
-                                    } => {
-                                        let now = chrono::Utc::now();
-                                        info!("Received measurement success");
-                                        info!("CO2: {}", co2);
-                                        info!("Temperature: {}", temperature);
-                                        info!("Humidity: {}", humidity);
-                                        measurement_queue.push(MeasurementWithTime {
-                                            co2,
-                                            temperature,
-                                            humidity,
-                                            time: now,
-                                            device: device.clone(),
-                                        });
-                                        save_measurement_to_influx(
-                                            &influx_host,
-                                            &influx_token,
-                                            &influx_database,
-                                            device,
-                                            co2,
-                                            temperature,
-                                            humidity,
-                                            &reqwest_client,
-                                        )
-                                        .await;
-                                        info!("Measurement saved to InfluxDB");
-                                    }
-                                    DevicePayload::Error { detail } => {
-                                        error!("Error: {}", detail);
-                                    }
-                                    DevicePayload::FrcStart { target_ppm } => {
-                                        info!(
-                                            "Force recalibration started with target ppm: {}",
-                                            target_ppm
-                                        );
-                                    }
-                                    DevicePayload::FrcWarmupComplete { detail } => {
-                                        info!("Force recalibration warmup complete: {}", detail);
-                                    }
-                                    DevicePayload::FrcCalibrating { target_ppm } => {
-                                        info!(
-                                            "Force recalibration calibrating to target ppm: {}",
-                                            target_ppm
-                                        );
-                                    }
-                                    DevicePayload::FrcSuccess { correction } => {
-                                        info!(
-                                            "Force recalibration successful with correction: {}",
-                                            correction
-                                        );
-                                    }
-                                    DevicePayload::FrcError { detail } => {
-                                        error!("Force recalibration error: {}", detail);
-                                    }
-                                    DevicePayload::SetOffsetSuccess { offset } => {
-                                        info!(
-                                            "Set temperature offset successful with offset: {}",
-                                            offset
-                                        );
-                                    }
-                                    DevicePayload::SetOffsetError { detail } => {
-                                        error!("Set temperature offset error: {}", detail);
-                                    }
-                                    DevicePayload::GetOffsetSuccess { offset } => {
-                                        info!(
-                                            "Get temperature offset successful with offset: {}",
-                                            offset
-                                        );
-                                    }
-                                    DevicePayload::GetOffsetError { detail } => {
-                                        error!("Get temperature offset error: {}", detail);
-                                    }
-                                    DevicePayload::Alive { uptime_seconds } => {
-                                        info!(
-                                            "Device is alive with uptime: {} seconds",
-                                            uptime_seconds
-                                        );
-                                    }
-                                    DevicePayload::SetDeepSleepTimeSuccess { seconds } => {
-                                        info!(
-                                            "Set deep sleep time successful with seconds: {}",
-                                            seconds
-                                        );
-                                    }
-                                    DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
-                                        info!(
-                                            "Get deep sleep time successful with seconds: {}",
-                                            seconds
-                                        );
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to decode message payload: {:?}", e);
-                            }
-                        }
+        let mut actions = Vec::new();
+        let event = tokio::select! {
+            event = connection.eventloop.poll() => Some(event),
+            result = &mut bootstrap, if bootstrap_pending => {
+                bootstrap_pending = false;
+                match result {
+                    Ok(restored) => {
+                        pipeline.feed(pipeline::Event::Restored(restored)).await;
                     }
-                    Err(e) => {
-                        error!("Failed to decode message payload: {:?}", e);
+                    Err(e) => error!("Failed to restore device state: {}", e),
+                }
+                continue;
+            }
+            _ = election_tick.tick(), if election.is_some() => {
+                if let Some(election) = &mut election {
+                    actions = election.tick(Utc::now());
+                }
+                None
+            }
+            _ = tokio::signal::ctrl_c() => {
+                if let Some(scheduler) = scheduler.take() {
+                    scheduler.abort();
+                }
+                pipeline.feed(pipeline::Event::Shutdown).await;
+                pipeline.log_metrics();
+                return;
+            }
+        };
+        match event {
+            Some(Ok(Event::Incoming(Packet::Publish(publish))))
+                if publish.topic == failover::LEASE_TOPIC =>
+            {
+                if let Some(election) = &mut election {
+                    match failover::Lease::from_payload(&publish.payload) {
+                        Ok(lease) => actions = election.observe(lease, Utc::now()),
+                        Err(e) => warn!("Ignoring an unreadable lease: {}", e),
                     }
                 }
             }
+            Some(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                pipeline
+                    .feed(pipeline::Event::Publish {
+                        topic: publish.topic,
+                        payload: publish.payload.to_vec(),
+                        retained: publish.retain,
+                        received: Utc::now(),
+                    })
+                    .await;
+            }
 
-            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+            Some(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
                 info!("Connected to MQTT broker");
                 info!("Subscribing to mqtt topic {}", mqtt_topic);
                 client
                     .subscribe(&mqtt_topic, rumqttc::QoS::AtLeastOnce)
                     .expect("Could not subscribe to the MQTT topic.");
+                for topic in [command_topic.clone(), format!("{}/+", command_topic)] {
+                    client
+                        .subscribe(&topic, rumqttc::QoS::AtLeastOnce)
+                        .expect("Could not subscribe to the MQTT command topics.");
+                }
+                if let Some(election) = &mut election {
+                    election.set_connected(true, Utc::now());
+                    client
+                        .subscribe(failover::LEASE_TOPIC, rumqttc::QoS::AtLeastOnce)
+                        .expect("Could not subscribe to the MQTT lease topic.");
+                }
             }
-            Ok(Event::Incoming(Packet::SubAck(_))) => info!("Subscription confirmed"),
-            Err(e) => {
+            Some(Ok(Event::Incoming(Packet::SubAck(_)))) => info!("Subscription confirmed"),
+            Some(Err(e)) => {
                 error!("Connection error: {:?}", e);
+                if let Some(election) = &mut election {
+                    election.set_connected(false, Utc::now());
+                }
                 error!("Retrying in 5 seconds...");
                 tokio::time::sleep(Duration::from_secs(5)).await;
             }
             _ => {} // Ignore other events
         }
+
+        for action in actions {
+            match action {
+                failover::Action::Publish(lease) => {
+                    if let Err(e) = client.try_publish(
+                        failover::LEASE_TOPIC,
+                        rumqttc::QoS::AtLeastOnce,
+                        true,
+                        lease.to_payload(),
+                    ) {
+                        error!("Failed to publish the lease: {}", e);
+                    }
+                }
+                failover::Action::Lead => {
+                    info!("This instance holds the lease, taking over ingestion");
+                    let mut leader =
+                        start_leading(influx, hourly.as_ref(), bootstrap_timeout).await;
+                    scheduler = leader.scheduler.take();
+                    pipeline = assemble(stages, leader).expect("stages were checked at startup");
+                    // Alerts pick up where the previous leader left them
+                    bootstrap.set(bootstrap::fetch(
+                        influx_host,
+                        influx_token,
+                        influx_database,
+                        reqwest_client,
+                        Utc::now(),
+                    ));
+                    bootstrap_pending = pipeline.restores();
+                }
+                failover::Action::Follow => {
+                    warn!("Another instance holds the lease, stepping down to follower");
+                    if let Some(scheduler) = scheduler.take() {
+                        scheduler.abort();
+                    }
+                    pipeline.feed(pipeline::Event::Shutdown).await;
+                    pipeline = assemble(&follower_stages, LeaderParts::follower())
+                        .expect("stages were checked at startup");
+                }
+            }
+        }
+        if let (Some(election), Some(status)) = (&election, &leader_status) {
+            status.set(election.status(Utc::now()));
+        }
+    }
+}
+
+/// InfluxDB is always checked, the broker only when receiving live data,
+/// and destinations only when they are configured.
+fn preflight_targets(
+    args: &Args,
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+) -> Result<preflight::Targets, String> {
+    let broker = if args.receive_live_data {
+        Some(preflight::BrokerSettings::from_env()?)
+    } else {
+        None
+    };
+    // An invalid policy is reported when the receiver loads it
+    let mut endpoints: Vec<(String, String)> = alerts::AlertPolicy::from_env()
+        .map(|policy| {
+            policy
+                .named_destinations()
+                .into_iter()
+                .map(|(source, destination)| (source, destination.url().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let digest = digest::DigestChannels::from_env();
+    if let Some(url) = &digest.ntfy_url {
+        endpoints.push(("DIGEST_NTFY_URL".to_string(), url.clone()));
+    }
+    if let Some(url) = &digest.webhook_url {
+        endpoints.push(("DIGEST_WEBHOOK_URL".to_string(), url.clone()));
     }
+    #[cfg(feature = "email")]
+    let smtp = digest.email.map(|email| (email.host, email.port));
+    #[cfg(not(feature = "email"))]
+    let smtp = None;
+    Ok(preflight::Targets {
+        influx_host: influx_host.to_string(),
+        influx_token: influx_token.to_string(),
+        influx_database: influx_database.to_string(),
+        broker,
+        endpoints,
+        smtp,
+        state_files: vec![
+            (
+                "MAINTENANCE_STATE_FILE".to_string(),
+                maintenance::MaintenanceStore::from_env()
+                    .path()
+                    .to_path_buf(),
+            ),
+            (
+                "ALERT_STATE_FILE".to_string(),
+                alerts::AlertStore::from_env().path().to_path_buf(),
+            ),
+            (
+                "MODEL_STATE_FILE".to_string(),
+                model_training::ModelStore::from_env().path().to_path_buf(),
+            ),
+        ],
+    })
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -621,6 +1149,43 @@ async fn main() {
 
     let reqwest_client = reqwest::Client::new();
 
+    let preflight = if args.no_preflight {
+        None
+    } else {
+        let targets = match preflight_targets(&args, &influx_host, &influx_token, &influx_database)
+        {
+            Ok(targets) => targets,
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        let report = preflight::run(&reqwest_client, &targets).await;
+        if report.hard_failures() > 0 {
+            log::error!("{}", report.describe());
+            log::error!("Refusing to start; --no-preflight skips these checks");
+            std::process::exit(1);
+        } else if report.soft_failures() > 0 {
+            log::warn!("{}", report.describe());
+        } else {
+            log::info!("{}", report.describe());
+        }
+        Some(report)
+    };
+
+    let event_kinds = match args
+        .predictor_event_kinds
+        .iter()
+        .map(|kind| fetcher::Identifier::parse(kind))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(kinds) => kinds,
+        Err(e) => {
+            log::error!("Invalid --predictor-event-kinds: {}", e);
+            return;
+        }
+    };
+
     if args.mark_historical_data {
         log::info!("Marking historical data");
         match mark_historical_data(
@@ -666,46 +1231,512 @@ async fn main() {
         }
     }
 
+    if args.dedupe_anomalies {
+        log::info!("Merging duplicate anomaly markings");
+        match anomaly_dedupe::dedupe_table(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+            &fetcher::Identifier::parse("anomalies").expect("a valid table name"),
+        )
+        .await
+        {
+            Ok(count) => log::info!("Merged {} duplicate anomaly markings", count),
+            Err(e) => log::error!("Failed to merge duplicate anomaly markings: {}", e),
+        }
+    }
+
     if args.predict_weather {
         log::info!("Predicting weather");
-        match predictor::predict_weather(
+        let result = match model_training::champion_cutoff() {
+            Ok(training_until) => {
+                predictor::predict_weather(
+                    &influx_host,
+                    &influx_token,
+                    &influx_database,
+                    &reqwest_client,
+                    args.prediction_timestamp,
+                    training_until,
+                    &event_kinds,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => log::info!("Weather prediction complete"),
+            Err(e) => log::error!("Failed to predict weather: {}", e),
+        }
+    }
+
+    if args.retrain_model {
+        log::info!("Retraining the predictor");
+        let store = model_training::ModelStore::from_env();
+        match model_training::run_retraining(
             &influx_host,
             &influx_token,
             &influx_database,
             &reqwest_client,
-            args.prediction_timestamp,
+            &store,
+            &args.retrain_config.clone().unwrap_or_default(),
+            &event_kinds,
+            args.force_promote,
+            Utc::now(),
         )
         .await
         {
-            Ok(()) => log::info!("Weather prediction complete"),
-            Err(e) => log::error!("Failed to predict weather: {}", e),
+            Ok(record) => log::info!("{}", record.summary().describe()),
+            Err(e) => {
+                log::error!("Failed to retrain the predictor: {}", e);
+                std::process::exit(1);
+            }
         }
     }
 
-    if args.web_server {
-        log::info!("Starting predictor web server on port {}", args.web_port);
-        match predictor_web::run_web_server(
-            influx_host.clone(),
-            influx_token.clone(),
-            influx_database.clone(),
-            args.web_port,
-            args.web_base_path,
+    if args.daily_report {
+        let date = args
+            .report_date
+            .unwrap_or_else(|| Utc::now().date_naive() - chrono::Duration::days(1));
+        log::info!("Computing data quality for {}", date);
+        match data_quality::run_daily_report(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+            date,
+            chrono::Duration::seconds(args.expected_interval_seconds),
+            &args.quality_weights.clone().unwrap_or_default(),
         )
         .await
         {
-            Ok(()) => log::info!("Web server stopped"),
-            Err(e) => log::error!("Web server failed: {}", e),
+            Ok(results) => log::info!("Data quality written for {} devices", results.len()),
+            Err(e) => log::error!("Failed to compute data quality: {}", e),
         }
     }
 
-    if args.receive_live_data {
-        log::info!("Receiving live data");
-        receive_live_data(
+    if args.daily_digest {
+        let date = args.report_date.unwrap_or_else(|| Utc::now().date_naive());
+        log::info!("Building digest for {}", date);
+        match digest::run_digest(
             &influx_host,
             &influx_token,
             &influx_database,
             &reqwest_client,
+            date,
+            chrono::Duration::seconds(args.expected_interval_seconds),
+            &args.quality_weights.clone().unwrap_or_default(),
+            &ventilation::RoomRegistry::from_env(),
+            &args.ventilation_config.clone().unwrap_or_default(),
+            &digest::DigestChannels::from_env(),
+            &event_kinds,
         )
-        .await;
+        .await
+        {
+            Ok(_) => log::info!("Digest for {} done", date),
+            Err(e) => log::error!("Failed to build digest: {}", e),
+        }
+    }
+
+    if let Some(path) = &args.import_reference {
+        log::info!("Importing reference data from {}", path.display());
+        let result = match std::fs::read_to_string(path) {
+            Ok(text) => reference::parse_reference_csv(
+                &text,
+                &args.reference_columns.clone().unwrap_or_default(),
+                args.reference_source.as_deref().unwrap_or("reference"),
+                args.reference_utc_offset,
+            ),
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(rows) => match reference::import_reference(
+                &influx_host,
+                &influx_token,
+                &influx_database,
+                &reqwest_client,
+                &rows,
+            )
+            .await
+            {
+                Ok(progress) => log::info!(
+                    "Imported {} of {} reference rows, {} were already stored",
+                    progress.written,
+                    rows.len(),
+                    progress.skipped
+                ),
+                Err(e) => log::error!("Failed to store reference data: {}", e),
+            },
+            Err(e) => log::error!("Failed to read reference CSV: {}", e),
+        }
+    }
+
+    if args.compare_reference {
+        let Some(device) = &args.device else {
+            log::error!("--compare-reference requires --device");
+            return;
+        };
+        let names = fetcher::Identifier::parse(device).and_then(|device| {
+            let source = args
+                .reference_source
+                .as_deref()
+                .map(fetcher::Identifier::parse)
+                .transpose()?;
+            Ok((device, source))
+        });
+        let (device, source) = match names {
+            Ok(names) => names,
+            Err(e) => {
+                log::error!("Can't compare with reference: {}", e);
+                return;
+            }
+        };
+        let to = args.to.unwrap_or_else(Utc::now);
+        let from = args.from.unwrap_or(to - chrono::Duration::days(7));
+        match reference::compare_reference(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+            &device,
+            source.as_ref(),
+            from,
+            to,
+            chrono::Duration::seconds(args.reference_tolerance_seconds),
+        )
+        .await
+        {
+            Ok(comparison) => match serde_json::to_string_pretty(&comparison) {
+                Ok(json) => println!("{}", json),
+                Err(e) => log::error!("Failed to format comparison: {}", e),
+            },
+            Err(e) => log::error!("Failed to compare with reference: {}", e),
+        }
+    }
+
+    if args.freshness {
+        match freshness::fetch_last_seen(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+        )
+        .await
+        {
+            Ok(last_seen) => {
+                let ages = freshness::ages(&last_seen, Utc::now());
+                print!("{}", freshness::render_plain(&ages));
+                if let Some(max_age) = args.freshness_max_age {
+                    let stale = freshness::stale(&ages, max_age);
+                    if !stale.is_empty() {
+                        for age in stale {
+                            log::error!(
+                                "{} has not reported for {}s (limit {}s)",
+                                age.device,
+                                age.seconds,
+                                max_age
+                            );
+                        }
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to query last measurement times: {}", e);
+                // A watchdog must not read a failed query as "all fresh"
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if args.storage_report {
+        let config = args.storage_config.clone().unwrap_or_default();
+        match storage::fetch_usage(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+            config.window_days,
+            Utc::now(),
+        )
+        .await
+        {
+            Ok(usage) => print!("{}", storage::render_plain(&storage::plan(&usage, &config))),
+            Err(e) => log::error!("Failed to count points: {}", e),
+        }
+    }
+
+    if let Some(path) = &args.share_export {
+        let device = match args.device.as_deref().map(fetcher::Identifier::parse) {
+            Some(Err(e)) => {
+                log::error!("Can't export: {}", e);
+                return;
+            }
+            device => device.and_then(Result::ok),
+        };
+        let config = args.share_config.clone().unwrap_or_default();
+        let seed = config.seed.unwrap_or_else(|| {
+            let seed = share_export::random_seed();
+            // Needed to repeat the export, and enough to undo its shift
+            log::info!("Share export seed {}; keep it private", seed);
+            seed
+        });
+        let to = args.to.unwrap_or_else(Utc::now);
+        let from = args.from.unwrap_or(to - chrono::Duration::days(30));
+        match share_export::export(
+            &influx_host,
+            &influx_token,
+            &influx_database,
+            &reqwest_client,
+            device.as_ref(),
+            from,
+            to,
+            &config,
+            seed,
+            path,
+        )
+        .await
+        {
+            Ok(rows) => log::info!(
+                "Exported {} rows to {} ({})",
+                rows,
+                path.display(),
+                share_export::manifest_path(path).display()
+            ),
+            Err(e) => log::error!("Failed to export: {}", e),
+        }
+    }
+
+    if let Some(device) = &args.maintenance {
+        // `requires` makes clap reject --maintenance without an end time
+        let until = args.maintenance_until.unwrap_or_else(Utc::now);
+        let store = maintenance::MaintenanceStore::from_env();
+        match store.update(Utc::now(), |state| {
+            state.set(device, until, maintenance::Reason::Manual)
+        }) {
+            Ok(()) => log::info!(
+                "{} is in maintenance until {} (saved in {})",
+                device,
+                until.to_rfc3339(),
+                store.path().display()
+            ),
+            Err(e) => log::error!("Failed to save maintenance state: {}", e),
+        }
+    }
+
+    if args.add_event {
+        // `requires_all` makes clap reject --add-event without these
+        let event = external_events::ExternalEvent::new(
+            args.device.as_deref().unwrap_or_default(),
+            args.kind.as_deref().unwrap_or_default(),
+            args.from.unwrap_or_else(Utc::now),
+            args.to.unwrap_or_else(Utc::now),
+            args.note.as_deref(),
+        );
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Invalid event: {}", e);
+                std::process::exit(2);
+            }
+        };
+        let store = bulk_write::InfluxStore {
+            influx_host: &influx_host,
+            influx_token: &influx_token,
+            influx_database: &influx_database,
+            reqwest_client: &reqwest_client,
+        };
+        match store.write(&[event.line()]).await {
+            Ok(()) => log::info!(
+                "Added {} event for {} from {} to {}",
+                event.kind,
+                event.device,
+                event.from.to_rfc3339(),
+                event.to.to_rfc3339()
+            ),
+            Err(e) => {
+                log::error!("Failed to store the event: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(device) = &args.end_maintenance {
+        let store = maintenance::MaintenanceStore::from_env();
+        match store.update(Utc::now(), |state| state.end(device)) {
+            Ok(true) => log::info!("Ended maintenance for {}", device),
+            Ok(false) => log::info!("{} was not in maintenance", device),
+            Err(e) => log::error!("Failed to save maintenance state: {}", e),
+        }
+    }
+
+    #[cfg(feature = "archive")]
+    if args.archive || args.restore {
+        let Some(target) = &args.archive_target else {
+            log::error!("--archive and --restore require --archive-target");
+            std::process::exit(2);
+        };
+        let store = match archive::open_store(target) {
+            Ok(store) => store,
+            Err(e) => {
+                log::error!("Failed to open archive target {}: {}", target, e);
+                std::process::exit(2);
+            }
+        };
+        if args.archive {
+            let Some(cutoff) = args.archive_before else {
+                log::error!("--archive requires --archive-before");
+                std::process::exit(2);
+            };
+            match archive::run_archive(
+                &influx_host,
+                &influx_token,
+                &influx_database,
+                &reqwest_client,
+                store.as_ref(),
+                cutoff,
+                args.archive_delete,
+                Utc::now(),
+            )
+            .await
+            {
+                Ok(report) => log::info!(
+                    "Archived {} rows into {} files",
+                    report.archived_rows(),
+                    report.partitions.len()
+                ),
+                Err(e) => {
+                    log::error!("Archive failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if args.restore {
+            let filter = archive::RestoreFilter {
+                device: args.device.clone(),
+                from: args.from,
+                to: args.to,
+            };
+            match archive::run_restore(
+                &influx_host,
+                &influx_token,
+                &influx_database,
+                &reqwest_client,
+                store.as_ref(),
+                &filter,
+            )
+            .await
+            {
+                Ok(rows) => log::info!("Restored {} rows", rows),
+                Err(e) => {
+                    log::error!("Restore failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    let relay = if args.command_relay {
+        if !(args.web_server && args.receive_live_data) {
+            log::error!("--command-relay requires --web-server and --receive-live-data");
+            return;
+        }
+        let config = command_relay::RelayConfig {
+            clear_retained_on_timeout: args.relay_clear_on_timeout,
+            ..Default::default()
+        };
+        Some(command_relay::RelayHandle::new(config))
+    } else {
+        None
+    };
+    let (web_relay, receiver_relay) = match relay {
+        Some((handle, outbox_rx)) => (Some(handle.clone()), Some((handle, outbox_rx))),
+        None => (None, None),
+    };
+    let failover = match &args.failover_instance {
+        Some(_) if args.failover_lease_seconds < 3 || args.failover_skew_seconds < 0 => {
+            log::error!(
+                "--failover-lease-seconds must be at least 3 and --failover-skew-seconds not negative"
+            );
+            return;
+        }
+        Some(instance) => {
+            let config = failover::ElectionConfig {
+                lease: chrono::Duration::seconds(args.failover_lease_seconds),
+                skew_margin: chrono::Duration::seconds(args.failover_skew_seconds),
+            };
+            let election = failover::Election::new(instance.as_str(), config, Utc::now());
+            Some((election, failover::LeaderStatus::new()))
+        }
+        None => None,
+    };
+    let in_process = args.web_server && args.receive_live_data;
+    let last_seen = in_process.then(freshness::LastSeen::new);
+    let latency = latency::Latency::new();
+    let ingest_metrics = pipeline::Metrics::new();
+    let leader_status = failover.as_ref().map(|(_, status)| status.clone());
+
+    let web_server = async {
+        if args.web_server {
+            log::info!("Starting predictor web server on port {}", args.web_port);
+            match predictor_web::run_web_server(
+                influx_host.clone(),
+                influx_token.clone(),
+                influx_database.clone(),
+                args.web_port,
+                args.web_base_path.clone(),
+                web_relay,
+                args.quality_alert_threshold,
+                last_seen.clone(),
+                in_process.then(|| latency.clone()),
+                in_process.then(|| ingest_metrics.clone()),
+                args.ventilation_config.clone().unwrap_or_default(),
+                args.storage_config.clone().unwrap_or_default(),
+                chrono::Duration::seconds(args.prediction_cache_seconds),
+                leader_status,
+                event_kinds.clone(),
+                args.display_timezone,
+                preflight.clone(),
+            )
+            .await
+            {
+                Ok(()) => log::info!("Web server stopped"),
+                Err(e) => log::error!("Web server failed: {}", e),
+            }
+        }
+    };
+
+    let live_data = async {
+        if args.receive_live_data {
+            log::info!("Receiving live data");
+            receive_live_data(
+                &influx_host,
+                &influx_token,
+                &influx_database,
+                &reqwest_client,
+                receiver_relay,
+                args.hourly_aggregates.then(|| hourly::HourlyConfig {
+                    co2_thresholds: args.hourly_co2_thresholds.clone(),
+                    sample_interval: chrono::Duration::seconds(args.expected_interval_seconds),
+                }),
+                last_seen.clone(),
+                Duration::from_secs(args.bootstrap_timeout_seconds),
+                latency.clone(),
+                args.latency_warn_ms,
+                &args.ingest_stages,
+                ingest_metrics.clone(),
+                failover,
+                args.home_aggregate.clone(),
+            )
+            .await;
+        }
+    };
+
+    if in_process {
+        // The relay, /freshness and /metrics share state with the receiver
+        tokio::join!(web_server, live_data);
+    } else {
+        web_server.await;
+        live_data.await;
     }
 }
@@ -0,0 +1,352 @@
+//! Per-device maintenance windows, e.g. while testing FRC with a CO2 canister.
+//!
+//! Measurements taken during a window are still stored, tagged
+//! `maintenance=true`, but anomaly marking, the predictor's training data and
+//! the dashboard's quality alert leave them out.
+//!
+//! A window is opened with `POST /api/devices/{device}/maintenance`,
+//! `--maintenance`, or automatically when a device reports `frc_start`; the
+//! automatic one ends with `frc_success`/`frc_error`, or after `FRC_WINDOW`
+//! if neither arrives. Windows are kept in a state file
+//! (`MAINTENANCE_STATE_FILE`, default `maintenance.json`, see `state_file`)
+//! shared by the receiver and the web server, and expire at their `until`
+//! time whether or not the process was running in between.
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use shared_types::DevicePayload;
+use shared_types::versioned::Migrations;
+
+use crate::fetcher::{Sql, query_rows};
+use crate::state_file;
+
+pub const DEFAULT_STATE_FILE: &str = "maintenance.json";
+/// Version 1: JSON of `MaintenanceState`
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+/// An FRC takes about 3.5 minutes; this only matters if its result is lost
+pub const FRC_WINDOW: Duration = Duration::minutes(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    Manual,
+    Frc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    pub until: DateTime<Utc>,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    devices: BTreeMap<String, Window>,
+}
+
+impl MaintenanceState {
+    pub fn active(&self, device: &str, now: DateTime<Utc>) -> Option<&Window> {
+        self.devices.get(device).filter(|w| w.until > now)
+    }
+
+    /// Opens or replaces the window; an `until` in the past ends it.
+    pub fn set(&mut self, device: &str, until: DateTime<Utc>, reason: Reason) {
+        self.devices
+            .insert(device.to_string(), Window { until, reason });
+    }
+
+    pub fn end(&mut self, device: &str) -> bool {
+        self.devices.remove(device).is_some()
+    }
+
+    /// Drops windows that are over.
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        self.devices.retain(|_, w| w.until > now);
+    }
+
+    /// Opens a window when an FRC starts and closes it when the FRC is done.
+    /// A longer manual window is left alone either way.
+    pub fn observe(&mut self, device: &str, payload: &DevicePayload, now: DateTime<Utc>) {
+        match payload {
+            DevicePayload::FrcStart { .. } => {
+                let until = now + FRC_WINDOW;
+                if self.active(device, now).is_none_or(|w| w.until < until) {
+                    self.set(device, until, Reason::Frc);
+                }
+            }
+            DevicePayload::FrcSuccess { .. } | DevicePayload::FrcError { .. }
+                if self
+                    .devices
+                    .get(device)
+                    .is_some_and(|w| w.reason == Reason::Frc) =>
+            {
+                self.end(device);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceStore {
+    path: PathBuf,
+}
+
+impl MaintenanceStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("MAINTENANCE_STATE_FILE")
+                .unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()),
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored windows without the expired ones. A missing file is an
+    /// empty state.
+    pub fn load(&self, now: DateTime<Utc>) -> Result<MaintenanceState, Box<dyn Error>> {
+        let mut state: MaintenanceState =
+            state_file::load(&self.path, &LAYOUTS)?.unwrap_or_default();
+        state.prune(now);
+        Ok(state)
+    }
+
+    pub fn save(&self, state: &MaintenanceState) -> Result<(), Box<dyn Error>> {
+        state_file::save(&self.path, &LAYOUTS, state)
+    }
+
+    /// Loads, applies `f` and saves if anything changed.
+    pub fn update<R>(
+        &self,
+        now: DateTime<Utc>,
+        f: impl FnOnce(&mut MaintenanceState) -> R,
+    ) -> Result<R, Box<dyn Error>> {
+        let mut state = self.load(now)?;
+        let before = state.clone();
+        let result = f(&mut state);
+        if state != before {
+            self.save(&state)?;
+        }
+        Ok(result)
+    }
+
+    /// An unreadable state file counts as no maintenance, so alerts keep working.
+    pub fn is_active(&self, device: &str, now: DateTime<Utc>) -> bool {
+        match self.load(now) {
+            Ok(state) => state.active(device, now).is_some(),
+            Err(e) => {
+                log::error!(
+                    "Failed to read maintenance state {}: {}",
+                    self.path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Times of measurements stored during maintenance. Empty until the first
+/// one is stored, since the `maintenance` tag doesn't exist before that.
+pub async fn fetch_maintenance_times(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+) -> HashSet<DateTime<Utc>> {
+    #[derive(Deserialize)]
+    struct TimeRow {
+        time: String,
+    }
+
+    let rows: Vec<TimeRow> = match query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SELECT time FROM scd40_data WHERE maintenance = 'true'"),
+    )
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::debug!("No maintenance-tagged measurements: {}", e);
+            return HashSet::new();
+        }
+    };
+    rows.into_iter()
+        .filter_map(|row| {
+            let time = if row.time.ends_with('Z') {
+                row.time
+            } else {
+                format!("{}Z", row.time)
+            };
+            DateTime::parse_from_rfc3339(&time)
+                .ok()
+                .map(|t| t.with_timezone(&Utc))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at_minute as at;
+    use shared_types::ErrorCode;
+
+    fn temp_store(name: &str) -> MaintenanceStore {
+        let path = std::env::temp_dir().join(format!(
+            "rpi-processor-maintenance-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        MaintenanceStore::new(path)
+    }
+
+    #[test]
+    fn windows_survive_a_restart_and_expire_on_time() {
+        let store = temp_store("restart");
+        store
+            .update(at(0), |s| s.set("kitchen", at(60), Reason::Manual))
+            .unwrap();
+
+        // A new process only has the file
+        let restarted = MaintenanceStore::new(&store.path);
+        assert!(restarted.is_active("kitchen", at(59)));
+        assert!(!restarted.is_active("bedroom", at(59)));
+        assert!(!restarted.is_active("kitchen", at(60)));
+
+        // The next write after expiry drops the window from the file
+        restarted
+            .update(at(61), |s| s.set("bedroom", at(90), Reason::Manual))
+            .unwrap();
+        let state = store.load(at(61)).unwrap();
+        assert!(!state.devices.contains_key("kitchen"), "{:?}", state);
+        let blob = std::fs::read(&store.path).unwrap();
+        assert!(!String::from_utf8_lossy(&blob).contains("kitchen"));
+        std::fs::remove_file(&store.path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_means_no_maintenance() {
+        let store = temp_store("missing");
+        assert_eq!(store.load(at(0)).unwrap(), MaintenanceState::default());
+        assert!(!store.is_active("kitchen", at(0)));
+        // Nothing changed, so nothing is written
+        store.update(at(0), |_| ()).unwrap();
+        assert!(!store.path.exists());
+    }
+
+    #[test]
+    fn frc_opens_and_closes_a_window() {
+        let mut state = MaintenanceState::default();
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcStart { target_ppm: 422 },
+            at(0),
+        );
+        assert_eq!(
+            state.active("kitchen", at(3)),
+            Some(&Window {
+                until: at(15),
+                reason: Reason::Frc,
+            })
+        );
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcCalibrating { target_ppm: 422 },
+            at(3),
+        );
+        assert!(state.active("kitchen", at(4)).is_some());
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcSuccess { correction: 3 },
+            at(4),
+        );
+        assert!(state.active("kitchen", at(4)).is_none());
+
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcStart { target_ppm: 422 },
+            at(10),
+        );
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: "I2C(Nack)".to_string(),
+            },
+            at(12),
+        );
+        assert!(state.active("kitchen", at(12)).is_none());
+    }
+
+    #[test]
+    fn lost_frc_result_expires_with_the_window() {
+        let mut state = MaintenanceState::default();
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcStart { target_ppm: 422 },
+            at(0),
+        );
+        assert!(state.active("kitchen", at(14)).is_some());
+        assert!(state.active("kitchen", at(15)).is_none());
+    }
+
+    #[test]
+    fn manual_window_outlives_an_frc() {
+        let mut state = MaintenanceState::default();
+        state.set("kitchen", at(120), Reason::Manual);
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcStart { target_ppm: 422 },
+            at(0),
+        );
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcSuccess { correction: 3 },
+            at(4),
+        );
+        assert_eq!(
+            state.active("kitchen", at(100)).unwrap().reason,
+            Reason::Manual
+        );
+
+        // A manual window ending sooner than the FRC is extended by it
+        state.set("kitchen", at(5), Reason::Manual);
+        state.observe(
+            "kitchen",
+            &DevicePayload::FrcStart { target_ppm: 422 },
+            at(0),
+        );
+        assert_eq!(state.active("kitchen", at(10)).unwrap().reason, Reason::Frc);
+    }
+
+    #[test]
+    fn frc_events_persist_through_the_store() {
+        let store = temp_store("frc");
+        let start = DevicePayload::FrcStart { target_ppm: 422 };
+        store
+            .update(at(0), |s| s.observe("kitchen", &start, at(0)))
+            .unwrap();
+        assert!(store.is_active("kitchen", at(1)));
+        let done = DevicePayload::FrcSuccess { correction: 3 };
+        store
+            .update(at(4), |s| s.observe("kitchen", &done, at(4)))
+            .unwrap();
+        assert!(!store.is_active("kitchen", at(5)));
+        std::fs::remove_file(&store.path).unwrap();
+    }
+}
@@ -0,0 +1,701 @@
+//! Champion/challenger retraining of the predictor.
+//!
+//! `--retrain-model`, meant to run weekly like `--daily-digest` runs daily,
+//! holds out the last `holdout_days` of measurements, trains a challenger
+//! on everything before them and evaluates it and the champion, the model
+//! promoted last time, on the held-out hours. The challenger takes over
+//! only if its CO2 mean absolute error is at most `tolerance_percent` worse
+//! than the champion's; `--force-promote` promotes it whatever the numbers.
+//!
+//! smartcore models can't be saved in this build, but training is
+//! deterministic, so a model is kept as the time its training data ends.
+//! The champion's is stored in a state file (`MODEL_STATE_FILE`, default
+//! `model.json`, see `state_file`), the champion is trained again from that data to be
+//! evaluated, and the forecast of `--predict-weather` and the digest learns
+//! from the same data. Before the first promotion the forecast learns from
+//! everything, as it always did. A champion trained past the start of the
+//! held-out window is evaluated as trained up to its start.
+//!
+//! Every decision, with both models' errors, goes to the
+//! `model_training_log` measurement, and the digest mentions the latest one
+//! of the past week.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use shared_types::versioned::Migrations;
+
+use crate::bulk_write::{InfluxStore, PointStore};
+use crate::external_events::EventFeature;
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::options::parse_kv_options;
+use crate::predictor::{self, Evaluation, Model, Samples};
+use crate::state_file;
+
+pub const MEASUREMENT: &str = "model_training_log";
+
+pub const DEFAULT_STATE_FILE: &str = "model.json";
+/// Version 1: JSON of `Champion`
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrainConfig {
+    /// The most recent days, which the challenger doesn't train on and
+    /// both models are evaluated on
+    pub holdout_days: i64,
+    /// How much higher the challenger's CO2 error may be than the
+    /// champion's, in percent of the champion's
+    pub tolerance_percent: f64,
+    /// Fewer held-out samples than this can't show the challenger is any good
+    pub min_samples: usize,
+}
+
+impl Default for RetrainConfig {
+    fn default() -> Self {
+        Self {
+            holdout_days: 7,
+            tolerance_percent: 2.0,
+            min_samples: 100,
+        }
+    }
+}
+
+impl FromStr for RetrainConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (name, value) in parse_kv_options(s)? {
+            let invalid = || format!("invalid value '{}' for {}", value, name);
+            match name {
+                "holdout_days" => {
+                    config.holdout_days = value.parse().map_err(|_| invalid())?;
+                    if config.holdout_days < 1 {
+                        return Err("holdout_days must be at least 1".to_string());
+                    }
+                }
+                "tolerance_percent" => {
+                    config.tolerance_percent = value.parse().map_err(|_| invalid())?;
+                    if !config.tolerance_percent.is_finite() || config.tolerance_percent < 0.0 {
+                        return Err("tolerance_percent must not be negative".to_string());
+                    }
+                }
+                "min_samples" => config.min_samples = value.parse().map_err(|_| invalid())?,
+                other => return Err(format!("unknown retraining setting '{}'", other)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// `--force-promote`
+    Forced,
+    /// Nothing to compare with: no champion yet, or it couldn't be trained
+    NoChampion,
+    Better,
+    /// Worse, but by no more than the tolerance
+    WithinTolerance,
+    Worse,
+    TooFewSamples,
+}
+
+impl Verdict {
+    pub fn promotes(self) -> bool {
+        !matches!(self, Verdict::Worse | Verdict::TooFewSamples)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Forced => "forced",
+            Verdict::NoChampion => "no_champion",
+            Verdict::Better => "better",
+            Verdict::WithinTolerance => "within_tolerance",
+            Verdict::Worse => "worse",
+            Verdict::TooFewSamples => "too_few_samples",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether the challenger replaces the champion. Both must have been
+/// evaluated on the same held-out samples; `champion` is `None` when there
+/// is none to evaluate.
+pub fn decide(
+    champion: Option<&Evaluation>,
+    challenger: &Evaluation,
+    config: &RetrainConfig,
+    force: bool,
+) -> Verdict {
+    if force {
+        return Verdict::Forced;
+    }
+    if challenger.samples < config.min_samples.max(1) {
+        return Verdict::TooFewSamples;
+    }
+    if !challenger.co2.is_finite() {
+        return Verdict::Worse;
+    }
+    let Some(champion) = champion.filter(|champion| champion.co2.is_finite()) else {
+        return Verdict::NoChampion;
+    };
+    if challenger.co2 < champion.co2 {
+        Verdict::Better
+    } else if challenger.co2 <= champion.co2 * (1.0 + config.tolerance_percent / 100.0) {
+        Verdict::WithinTolerance
+    } else {
+        Verdict::Worse
+    }
+}
+
+/// The promoted model, kept as the end of its training data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Champion {
+    pub trained_until: DateTime<Utc>,
+    pub promoted_at: DateTime<Utc>,
+    pub verdict: Verdict,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelStore {
+    path: PathBuf,
+}
+
+impl ModelStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("MODEL_STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()),
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `None` before the first promotion. A damaged file is a `BlobError`,
+    /// not a missing champion.
+    pub fn load(&self) -> Result<Option<Champion>, Box<dyn Error>> {
+        state_file::load(&self.path, &LAYOUTS)
+    }
+
+    pub fn save(&self, champion: &Champion) -> Result<(), Box<dyn Error>> {
+        state_file::save(&self.path, &LAYOUTS, champion)
+    }
+}
+
+/// Where the forecast's training data ends: the champion's cutoff, or
+/// `None` to learn from everything. An unreadable state file is an error
+/// rather than a reason to drop the promoted model.
+pub fn champion_cutoff() -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+    let store = ModelStore::from_env();
+    match store.load() {
+        Ok(champion) => Ok(champion.map(|champion| champion.trained_until)),
+        Err(e) => Err(format!(
+            "could not read the promoted model from {}: {}",
+            store.path().display(),
+            e
+        )
+        .into()),
+    }
+}
+
+/// One retraining: what was compared and what was decided
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingRecord {
+    pub at: DateTime<Utc>,
+    pub holdout_from: DateTime<Utc>,
+    pub tolerance_percent: f64,
+    pub challenger_trained_until: DateTime<Utc>,
+    pub challenger: Evaluation,
+    pub champion_trained_until: Option<DateTime<Utc>>,
+    pub champion: Option<Evaluation>,
+    pub verdict: Verdict,
+}
+
+/// Errors that aren't numbers, like those of an empty held-out window,
+/// are left out
+fn error_fields(prefix: &str, evaluation: &Evaluation) -> String {
+    [
+        ("co2", evaluation.co2),
+        ("temperature", evaluation.temperature),
+        ("humidity", evaluation.humidity),
+    ]
+    .into_iter()
+    .filter(|(_, mae)| mae.is_finite())
+    .map(|(name, mae)| format!(",{}_{}_mae={}", prefix, name, mae))
+    .collect()
+}
+
+impl TrainingRecord {
+    pub fn line(&self) -> String {
+        let mut fields = format!(
+            "promoted={},holdout_from=\"{}\",holdout_samples={}i,tolerance_percent={},challenger_trained_until=\"{}\"{}",
+            self.verdict.promotes(),
+            self.holdout_from.to_rfc3339(),
+            self.challenger.samples,
+            self.tolerance_percent,
+            self.challenger_trained_until.to_rfc3339(),
+            error_fields("challenger", &self.challenger)
+        );
+        if let Some(until) = self.champion_trained_until {
+            fields.push_str(&format!(
+                ",champion_trained_until=\"{}\"",
+                until.to_rfc3339()
+            ));
+        }
+        if let Some(champion) = &self.champion {
+            fields.push_str(&error_fields("champion", champion));
+        }
+        format!(
+            "{},verdict={} {} {}",
+            MEASUREMENT,
+            self.verdict,
+            fields,
+            self.at.timestamp_nanos_opt().unwrap_or(0)
+        )
+    }
+
+    pub fn summary(&self) -> TrainingSummary {
+        TrainingSummary {
+            at: self.at,
+            verdict: self.verdict,
+            holdout_samples: self.challenger.samples,
+            tolerance_percent: self.tolerance_percent,
+            challenger_co2_mae: Some(self.challenger.co2).filter(|mae| mae.is_finite()),
+            champion_co2_mae: self
+                .champion
+                .map(|champion| champion.co2)
+                .filter(|mae| mae.is_finite()),
+        }
+    }
+}
+
+/// What the digest says about a retraining
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingSummary {
+    pub at: DateTime<Utc>,
+    pub verdict: Verdict,
+    pub holdout_samples: usize,
+    pub tolerance_percent: f64,
+    pub challenger_co2_mae: Option<f64>,
+    pub champion_co2_mae: Option<f64>,
+}
+
+impl TrainingSummary {
+    pub fn describe(&self) -> String {
+        let errors = match (self.challenger_co2_mae, self.champion_co2_mae) {
+            (Some(new), Some(old)) => format!("CO2 error {:.1} ppm vs {:.1} ppm", new, old),
+            (Some(new), None) => format!("CO2 error {:.1} ppm", new),
+            _ => "CO2 error unknown".to_string(),
+        };
+        let outcome = match self.verdict {
+            Verdict::Forced => format!("new model promoted by hand, {}", errors),
+            Verdict::NoChampion => format!("new model promoted, {}, nothing to compare", errors),
+            Verdict::Better => format!("new model promoted, {}", errors),
+            Verdict::WithinTolerance => format!(
+                "new model promoted, {}, within the {}% tolerance",
+                errors, self.tolerance_percent
+            ),
+            Verdict::Worse => format!("previous model kept, {}", errors),
+            Verdict::TooFewSamples => format!(
+                "previous model kept, only {} recent samples to evaluate on",
+                self.holdout_samples
+            ),
+        };
+        format!(
+            "Model retraining on {}: {}",
+            self.at.format("%Y-%m-%d"),
+            outcome
+        )
+    }
+}
+
+/// The latest retraining from `from` to `to`, if any
+pub async fn fetch_latest(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Option<TrainingSummary>, Box<dyn Error>> {
+    #[derive(Deserialize)]
+    struct Row {
+        time: String,
+        verdict: Verdict,
+        holdout_samples: usize,
+        tolerance_percent: f64,
+        challenger_co2_mae: Option<f64>,
+        champion_co2_mae: Option<f64>,
+    }
+
+    let rows: Vec<Row> = match query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(
+            "SELECT time, verdict, holdout_samples, tolerance_percent, \
+             challenger_co2_mae, champion_co2_mae FROM ",
+        )
+        .push(MEASUREMENT)
+        .push(" WHERE time >= ")
+        .time(from)
+        .push(" AND time < ")
+        .time(to)
+        .push(" ORDER BY time DESC LIMIT 1"),
+    )
+    .await
+    {
+        Ok(rows) => rows,
+        // The table doesn't exist before the first retraining
+        Err(e) if e.to_string().contains("not found") => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let Some(row) = rows.into_iter().next() else {
+        return Ok(None);
+    };
+    let time = if row.time.ends_with('Z') {
+        row.time
+    } else {
+        format!("{}Z", row.time)
+    };
+    Ok(Some(TrainingSummary {
+        at: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+        verdict: row.verdict,
+        holdout_samples: row.holdout_samples,
+        tolerance_percent: row.tolerance_percent,
+        challenger_co2_mae: row.challenger_co2_mae,
+        champion_co2_mae: row.champion_co2_mae,
+    }))
+}
+
+/// Trains and evaluates the challenger and the champion, promotes the
+/// challenger if it earned it, and records the decision.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_retraining(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    store: &ModelStore,
+    config: &RetrainConfig,
+    event_kinds: &[Identifier],
+    force: bool,
+    now: DateTime<Utc>,
+) -> Result<TrainingRecord, Box<dyn Error>> {
+    let holdout_from = now - Duration::days(config.holdout_days);
+    let champion = store.load()?;
+
+    let event_feature = EventFeature::fetch(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        event_kinds,
+    )
+    .await;
+    let fetch = |until| {
+        predictor::fetch_clean_measurements(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            until,
+        )
+    };
+
+    let recent = fetch(None).await?;
+    let holdout = Samples::build(&recent, event_feature.as_ref()).after(holdout_from);
+    log::info!(
+        "Evaluating on {} samples since {}",
+        holdout.len(),
+        holdout_from.to_rfc3339()
+    );
+
+    let challenger_data = fetch(Some(holdout_from)).await?;
+    let challenger = Model::fit(&Samples::build(&challenger_data, event_feature.as_ref()))?
+        .evaluate(&holdout)?;
+    log::info!("Challenger CO2 error: {:.2} ppm", challenger.co2);
+
+    let champion_trained_until = champion
+        .as_ref()
+        .map(|champion| champion.trained_until.min(holdout_from));
+    let champion_evaluation = match champion_trained_until {
+        Some(until) => {
+            let evaluation = match fetch(Some(until)).await {
+                Ok(data) => Model::fit(&Samples::build(&data, event_feature.as_ref()))
+                    .and_then(|model| model.evaluate(&holdout)),
+                Err(e) => Err(e),
+            };
+            match evaluation {
+                Ok(evaluation) => {
+                    log::info!("Champion CO2 error: {:.2} ppm", evaluation.co2);
+                    Some(evaluation)
+                }
+                Err(e) => {
+                    log::warn!("Could not evaluate the champion: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let verdict = decide(champion_evaluation.as_ref(), &challenger, config, force);
+    let record = TrainingRecord {
+        at: now,
+        holdout_from,
+        tolerance_percent: config.tolerance_percent,
+        challenger_trained_until: holdout_from,
+        challenger,
+        champion_trained_until,
+        champion: champion_evaluation,
+        verdict,
+    };
+
+    if verdict.promotes() {
+        store.save(&Champion {
+            trained_until: holdout_from,
+            promoted_at: now,
+            verdict,
+        })?;
+    }
+
+    let influx = InfluxStore {
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    };
+    if let Err(e) = influx.write(&[record.line()]).await {
+        log::error!("Failed to record the retraining: {}", e);
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared_types::versioned::BlobError;
+
+    use super::*;
+
+    fn at(hours: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T03:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::hours(hours)
+    }
+
+    fn evaluation(co2: f64, samples: usize) -> Evaluation {
+        Evaluation {
+            co2,
+            temperature: 0.3,
+            humidity: 1.5,
+            samples,
+        }
+    }
+
+    #[test]
+    fn config_parses_and_checks_settings() {
+        assert_eq!("".parse::<RetrainConfig>(), Ok(RetrainConfig::default()));
+        assert_eq!(
+            "holdout_days=14, tolerance_percent=0, min_samples=500".parse(),
+            Ok(RetrainConfig {
+                holdout_days: 14,
+                tolerance_percent: 0.0,
+                min_samples: 500,
+            })
+        );
+        assert!("holdout_days=0".parse::<RetrainConfig>().is_err());
+        assert!("tolerance_percent=-1".parse::<RetrainConfig>().is_err());
+        assert!("tolerance_percent=NaN".parse::<RetrainConfig>().is_err());
+        assert!("min_samples=lots".parse::<RetrainConfig>().is_err());
+        assert!("epochs=3".parse::<RetrainConfig>().is_err());
+    }
+
+    #[test]
+    fn challenger_is_promoted_unless_clearly_worse() {
+        let config = RetrainConfig::default();
+        let champion = evaluation(40.0, 1000);
+        let decide = |co2| decide(Some(&champion), &evaluation(co2, 1000), &config, false);
+        assert_eq!(decide(38.5), Verdict::Better);
+        assert_eq!(decide(40.0), Verdict::WithinTolerance);
+        assert_eq!(decide(40.8), Verdict::WithinTolerance);
+        assert_eq!(decide(40.81), Verdict::Worse);
+        assert_eq!(decide(f64::NAN), Verdict::Worse);
+        assert!(!Verdict::Worse.promotes());
+
+        let strict = RetrainConfig {
+            tolerance_percent: 0.0,
+            ..config
+        };
+        assert_eq!(
+            super::decide(Some(&champion), &evaluation(40.0, 1000), &strict, false),
+            Verdict::WithinTolerance
+        );
+        assert_eq!(
+            super::decide(Some(&champion), &evaluation(40.01, 1000), &strict, false),
+            Verdict::Worse
+        );
+    }
+
+    #[test]
+    fn missing_champion_or_samples() {
+        let config = RetrainConfig::default();
+        let challenger = evaluation(55.0, 1000);
+        assert_eq!(
+            decide(None, &challenger, &config, false),
+            Verdict::NoChampion
+        );
+        // A champion that can't be scored is no champion
+        assert_eq!(
+            decide(
+                Some(&evaluation(f64::NAN, 1000)),
+                &challenger,
+                &config,
+                false
+            ),
+            Verdict::NoChampion
+        );
+
+        let few = evaluation(10.0, 99);
+        assert_eq!(
+            decide(Some(&evaluation(40.0, 99)), &few, &config, false),
+            Verdict::TooFewSamples
+        );
+        assert_eq!(decide(None, &few, &config, false), Verdict::TooFewSamples);
+        let any = RetrainConfig {
+            min_samples: 0,
+            ..config
+        };
+        assert_eq!(
+            decide(None, &evaluation(f64::NAN, 0), &any, false),
+            Verdict::TooFewSamples
+        );
+    }
+
+    #[test]
+    fn forcing_promotes_whatever_the_numbers() {
+        let config = RetrainConfig::default();
+        let champion = evaluation(40.0, 1000);
+        for challenger in [evaluation(90.0, 1000), evaluation(f64::NAN, 0)] {
+            let verdict = decide(Some(&champion), &challenger, &config, true);
+            assert_eq!(verdict, Verdict::Forced);
+            assert!(verdict.promotes());
+        }
+    }
+
+    #[test]
+    fn champion_state_round_trips() {
+        let path = std::env::temp_dir().join(format!("model-{}.json", std::process::id()));
+        let store = ModelStore::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+        let champion = Champion {
+            trained_until: at(0),
+            promoted_at: at(168),
+            verdict: Verdict::Better,
+        };
+        store.save(&champion).unwrap();
+        assert_eq!(store.load().unwrap(), Some(champion));
+
+        let mut blob = std::fs::read(&path).unwrap();
+        blob.truncate(blob.len() - 1);
+        std::fs::write(&path, &blob).unwrap();
+        let error = store.load().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlobError>(),
+            Some(&BlobError::Truncated)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn record(verdict: Verdict, champion: Option<Evaluation>) -> TrainingRecord {
+        TrainingRecord {
+            at: at(168),
+            holdout_from: at(0),
+            tolerance_percent: 2.0,
+            challenger_trained_until: at(0),
+            challenger: evaluation(40.5, 1900),
+            champion_trained_until: champion.map(|_| at(-168)),
+            champion,
+            verdict,
+        }
+    }
+
+    #[test]
+    fn decisions_are_logged_with_both_errors() {
+        let logged = record(Verdict::WithinTolerance, Some(evaluation(40.0, 1900)));
+        assert_eq!(
+            logged.line(),
+            format!(
+                "model_training_log,verdict=within_tolerance promoted=true,\
+                 holdout_from=\"2025-01-15T03:00:00+00:00\",holdout_samples=1900i,\
+                 tolerance_percent=2,challenger_trained_until=\"2025-01-15T03:00:00+00:00\",\
+                 challenger_co2_mae=40.5,challenger_temperature_mae=0.3,challenger_humidity_mae=1.5,\
+                 champion_trained_until=\"2025-01-08T03:00:00+00:00\",\
+                 champion_co2_mae=40,champion_temperature_mae=0.3,champion_humidity_mae=1.5 {}",
+                at(168).timestamp_nanos_opt().unwrap()
+            )
+        );
+
+        let mut empty = record(Verdict::TooFewSamples, None);
+        empty.challenger = evaluation(f64::NAN, 0);
+        let line = empty.line();
+        assert!(line.starts_with("model_training_log,verdict=too_few_samples promoted=false,"));
+        assert!(
+            !line.contains("NaN") && !line.contains("champion"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn summaries_say_what_happened() {
+        let champion = Some(evaluation(40.0, 1900));
+        let describe = |verdict, champion| record(verdict, champion).summary().describe();
+        assert_eq!(
+            describe(Verdict::Better, champion),
+            "Model retraining on 2025-01-22: new model promoted, CO2 error 40.5 ppm vs 40.0 ppm"
+        );
+        assert_eq!(
+            describe(Verdict::WithinTolerance, champion),
+            "Model retraining on 2025-01-22: new model promoted, \
+             CO2 error 40.5 ppm vs 40.0 ppm, within the 2% tolerance"
+        );
+        assert_eq!(
+            describe(Verdict::Worse, champion),
+            "Model retraining on 2025-01-22: previous model kept, CO2 error 40.5 ppm vs 40.0 ppm"
+        );
+        assert_eq!(
+            describe(Verdict::NoChampion, None),
+            "Model retraining on 2025-01-22: new model promoted, CO2 error 40.5 ppm, \
+             nothing to compare"
+        );
+        assert_eq!(
+            describe(Verdict::Forced, champion),
+            "Model retraining on 2025-01-22: new model promoted by hand, \
+             CO2 error 40.5 ppm vs 40.0 ppm"
+        );
+        assert_eq!(
+            describe(Verdict::TooFewSamples, champion),
+            "Model retraining on 2025-01-22: previous model kept, \
+             only 1900 recent samples to evaluate on"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! The `name=value,name=value` settings strings taken by the config flags,
+//! e.g. `--storage budget_mb=500,window_days=14`.
+//!
+//! Each config's `FromStr` gets the pairs from `parse_kv_options` and only
+//! decides what its names mean.
+
+/// The `name=value` pairs in `s`, separated by commas, in order. Whitespace
+/// around names and values is trimmed and empty entries are skipped, so
+/// `"a=1, ,b=2,"` is two pairs.
+pub fn parse_kv_options(s: &str) -> Result<Vec<(&str, &str)>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| format!("expected name=value, got '{}'", pair))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_are_trimmed_in_order() {
+        assert_eq!(
+            parse_kv_options(" budget_mb = 500 ,window_days=14"),
+            Ok(vec![("budget_mb", "500"), ("window_days", "14")])
+        );
+    }
+
+    #[test]
+    fn empty_entries_are_skipped() {
+        assert_eq!(parse_kv_options(""), Ok(vec![]));
+        assert_eq!(parse_kv_options(" , a=1,, "), Ok(vec![("a", "1")]));
+    }
+
+    #[test]
+    fn values_may_hold_spaces_and_equals_signs() {
+        assert_eq!(
+            parse_kv_options("time=Logged at,filter=a=b"),
+            Ok(vec![("time", "Logged at"), ("filter", "a=b")])
+        );
+        assert_eq!(parse_kv_options("seed="), Ok(vec![("seed", "")]));
+    }
+
+    #[test]
+    fn a_pair_without_equals_sign_is_an_error() {
+        assert_eq!(
+            parse_kv_options("a=1,budget"),
+            Err("expected name=value, got 'budget'".to_string())
+        );
+    }
+}
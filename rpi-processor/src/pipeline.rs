@@ -0,0 +1,2441 @@
+//! The live receiver's handling of what arrives over MQTT, as a chain of
+//! stages.
+//!
+//! The MQTT loop turns every publish into an `Event` and feeds it to the
+//! `Pipeline`, which hands it through the stages in order. A stage takes one
+//! event and returns what goes on to the next one: the same event, a changed
+//! one, several, or none to drop it. `Event::Failed` never goes on; the
+//! pipeline logs it and counts it against the stage that returned it.
+//!
+//! `--ingest-stages` picks the stages and their order, by default `STAGES`.
+//! Stages whose part of the receiver is off (the command relay, hourly
+//! aggregates, the in-process `/freshness`, alerts) are left out. A
+//! follower in a failover pair runs only those of them in `FOLLOWER_STAGES`.
+//!
+//! Every stage counts the events it processed, dropped and failed, and the
+//! time it took. The counts are logged at shutdown and served on
+//! `GET /metrics` when the web server runs in the same process.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use circular_queue::CircularQueue;
+use log::{Level, debug, error, info, log, warn};
+use shared_types::dedup_window::DedupWindow;
+use shared_types::line_protocol::{self, MeasurementFields};
+use shared_types::{
+    CommandEnvelope, CommandMessage, DeviceCommand, DeviceMessage, DevicePayload, topics,
+};
+
+use crate::alerts::{self, Alerter};
+use crate::bootstrap::{self, Bootstrap};
+use crate::bulk_write::{InfluxStore, PointStore, WriteError};
+use crate::command_relay::RelayHandle;
+use crate::config_drift::{self, DriftDetector};
+use crate::dedup::RetainedDedup;
+use crate::device_config;
+use crate::device_diagnostics;
+use crate::device_events;
+use crate::device_info::KnownSerials;
+use crate::device_status;
+use crate::freshness::LastSeen;
+use crate::home::HomeAggregator;
+use crate::hourly::{self, HourlyAggregator};
+use crate::latency::{self, Latency, Observation};
+use crate::maintenance::MaintenanceStore;
+use crate::types::MeasurementWithTime;
+
+/// Every stage, in the default order
+pub const STAGES: [&str; 19] = [
+    "dedup",
+    "decode",
+    "validate",
+    "seq_dedup",
+    "unbatch",
+    "config_drift",
+    "relay",
+    "log",
+    "maintenance",
+    "influx_write",
+    "home",
+    "hourly",
+    "freshness",
+    "alerts",
+    "device_config",
+    "device_diagnostics",
+    "device_events",
+    "device_info",
+    "device_status",
+];
+
+/// The stages a failover follower runs, see `failover`. They keep the
+/// in-process state current without writing to InfluxDB or notifying anyone.
+pub const FOLLOWER_STAGES: [&str; 9] = [
+    "dedup",
+    "decode",
+    "validate",
+    "seq_dedup",
+    "unbatch",
+    "relay",
+    "log",
+    "maintenance",
+    "freshness",
+];
+
+/// Live measurements kept for merging with a bootstrap that arrives late
+const LIVE_MEASUREMENTS: usize = 300;
+
+const EVENTS_METRIC: &str = "air_quality_ingest_stage_events";
+const DURATION_METRIC: &str = "air_quality_ingest_stage_duration_seconds";
+const REDELIVERED_METRIC: &str = "air_quality_redelivered_measurements";
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A message as the broker delivered it
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        retained: bool,
+        received: DateTime<Utc>,
+    },
+    /// A command seen on a command topic, `target` being `None` on the
+    /// topic all devices read
+    Command {
+        target: Option<String>,
+        command: DeviceCommand,
+        id: Option<u32>,
+        /// The operator the command names, see `CommandEnvelope`
+        issued_by: Option<String>,
+        received: DateTime<Utc>,
+    },
+    Message(Received),
+    /// A configuration change no command asked for, to raise an alert for
+    Drift {
+        device: String,
+        detail: String,
+        time: DateTime<Utc>,
+    },
+    /// Device state restored from InfluxDB, see `bootstrap`
+    Restored(Bootstrap),
+    /// The receiver is about to exit
+    Shutdown,
+    /// What went wrong in the stage that returned it
+    Failed(String),
+}
+
+/// A decoded device message
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub message: DeviceMessage,
+    pub retained: bool,
+    pub received: DateTime<Utc>,
+    /// Set by `maintenance` on measurements of a device in maintenance
+    pub in_maintenance: bool,
+    /// Set by `unbatch` on the measurements it took out of a batch, which
+    /// are back-dated
+    pub batched: bool,
+}
+
+impl Received {
+    /// The measurement the message carries, timed by the device clock
+    /// unless it's missing or ahead, see `alerts::event_time`
+    pub fn measurement(&self) -> Option<MeasurementWithTime> {
+        match self.message.payload {
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                ..
+            } => Some(MeasurementWithTime {
+                co2,
+                temperature,
+                humidity,
+                time: alerts::event_time(&self.message, self.received),
+                device: self.message.device.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Stage {
+    fn name(&self) -> &'static str;
+
+    /// The events for the next stage; an empty `Vec` drops `event`.
+    async fn process(&mut self, event: Event) -> Vec<Event>;
+}
+
+/// Skips retained messages already processed, see `dedup`
+#[derive(Debug, Default)]
+pub struct Dedup(RetainedDedup);
+
+impl Stage for Dedup {
+    fn name(&self) -> &'static str {
+        "dedup"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Publish {
+                ref topic,
+                ref payload,
+                retained,
+                ..
+            } if !self.0.accept(topic, payload, retained) => {
+                debug!("Skipping retained message already processed on '{}'", topic);
+                Vec::new()
+            }
+            event => vec![event],
+        }
+    }
+}
+
+/// Turns publishes into commands or decoded device messages
+pub struct Decode {
+    pub command_topic: String,
+}
+
+impl Stage for Decode {
+    fn name(&self) -> &'static str {
+        "decode"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let Event::Publish {
+            topic,
+            payload,
+            retained,
+            received,
+        } = event
+        else {
+            return vec![event];
+        };
+        if let Some(target) = config_drift::command_target(&topic, &self.command_topic) {
+            // Empty payloads clear retained commands
+            if payload.is_empty() {
+                return Vec::new();
+            }
+            // A batch is seen as its commands, each with the batch's id
+            return match CommandMessage::from_json_slice(&payload) {
+                Ok(message) => message
+                    .into_envelopes()
+                    .into_iter()
+                    .map(|envelope| Event::Command {
+                        target: target.map(str::to_string),
+                        command: envelope.command,
+                        id: envelope.id,
+                        issued_by: envelope.issued_by,
+                        received,
+                    })
+                    .collect(),
+                Err(e) => {
+                    debug!("Ignoring unreadable command on '{}': {}", topic, e);
+                    Vec::new()
+                }
+            };
+        }
+        match decode_device_message(&topic, &payload) {
+            Ok(message) => {
+                debug!("Decoded message: {:?}", &message);
+                // The message's own name is what gets stored
+                if let Some(device) = topics::parse_device_from_topic(&topic)
+                    && device != message.device
+                {
+                    warn!(
+                        "Message from '{}' arrived on '{}', the topic of '{}'",
+                        message.device, topic, device
+                    );
+                }
+                vec![Event::Message(Received {
+                    message,
+                    retained,
+                    received,
+                    in_maintenance: false,
+                    batched: false,
+                })]
+            }
+            Err(e) => vec![Event::Failed(format!(
+                "Failed to decode message payload: {}",
+                e
+            ))],
+        }
+    }
+}
+
+/// Devices send JSON, or postcard when built to save bytes. A JSON message
+/// always starts with `{`; anything else is taken for postcard, since short
+/// postcard messages are often valid UTF-8 as well.
+fn decode_device_message(topic: &str, payload: &[u8]) -> Result<DeviceMessage, String> {
+    if payload.trim_ascii_start().first() == Some(&b'{') {
+        info!("Received message on topic '{}'", topic);
+        debug!("Raw message content: {}", String::from_utf8_lossy(payload));
+        DeviceMessage::from_json_slice(payload).map_err(|e| format!("invalid {}", e))
+    } else {
+        info!("Received binary message on topic '{}'", topic);
+        debug!("Raw message content: {:02x?}", payload);
+        DeviceMessage::from_postcard(payload).map_err(|e| format!("invalid {}", e))
+    }
+}
+
+/// Drops messages of a protocol version this build doesn't understand and
+/// fails the ones that break `DeviceMessage::validate`, so an impossible
+/// reading ends up in the error log rather than in InfluxDB
+pub struct Validate;
+
+impl Stage for Validate {
+    fn name(&self) -> &'static str {
+        "validate"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Message(Received { ref message, .. }) if !message.is_compatible() => {
+                warn!(
+                    "Ignoring message from {} with protocol version {}, this build understands up to {}",
+                    message.device,
+                    message.version,
+                    shared_types::CURRENT_PROTOCOL_VERSION
+                );
+                Vec::new()
+            }
+            Event::Message(Received { ref message, .. }) => match message.validate() {
+                Ok(()) => vec![event],
+                Err(e) => vec![Event::Failed(format!(
+                    "Invalid message from {}: {}",
+                    message.device, e
+                ))],
+            },
+            event => vec![event],
+        }
+    }
+}
+
+/// Drops measurements the broker delivered twice, see `DedupWindow`, and
+/// counts what became of the ones devices sent again from their outbox
+#[derive(Default)]
+pub struct SeqDedup {
+    window: DedupWindow,
+    redelivered: Arc<Redelivered>,
+}
+
+impl SeqDedup {
+    pub fn new(redelivered: Arc<Redelivered>) -> Self {
+        Self {
+            window: DedupWindow::default(),
+            redelivered,
+        }
+    }
+}
+
+impl Stage for SeqDedup {
+    fn name(&self) -> &'static str {
+        "seq_dedup"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Message(Received { ref message, .. }) if !self.window.accept(message) => {
+                if message.redelivered {
+                    self.redelivered.repeated.fetch_add(1, Ordering::Relaxed);
+                }
+                debug!(
+                    "Skipping {}'s measurement {:?}, already processed",
+                    message.device, message.seq
+                );
+                Vec::new()
+            }
+            Event::Message(Received { ref message, .. }) if message.redelivered => {
+                self.redelivered.recovered.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "Recovered {}'s measurement {:?} from its outbox",
+                    message.device, message.seq
+                );
+                vec![event]
+            }
+            event => vec![event],
+        }
+    }
+}
+
+/// Turns a `measurement_batch` into one measurement per reading, see
+/// `unbatch`
+pub struct Unbatch;
+
+impl Stage for Unbatch {
+    fn name(&self) -> &'static str {
+        "unbatch"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Message(received)
+                if matches!(
+                    received.message.payload,
+                    DevicePayload::MeasurementBatch { .. }
+                ) =>
+            {
+                let measurements = unbatch(&received);
+                info!(
+                    "Received {} buffered measurement(s) from {}",
+                    measurements.len(),
+                    received.message.device
+                );
+                measurements.into_iter().map(Event::Message).collect()
+            }
+            event => vec![event],
+        }
+    }
+}
+
+/// The readings of a batch as measurements of their own, oldest first. Each
+/// is back-dated by its `age_seconds`, both its device timestamp and when
+/// it counts as received, so the stages after this one store and follow it
+/// as if it had arrived when it was taken.
+fn unbatch(received: &Received) -> Vec<Received> {
+    let DevicePayload::MeasurementBatch { readings } = &received.message.payload else {
+        return Vec::new();
+    };
+    let mut readings = readings.clone();
+    readings.sort_by_key(|reading| std::cmp::Reverse(reading.age_seconds));
+    readings
+        .iter()
+        .map(|reading| Received {
+            message: DeviceMessage {
+                payload: DevicePayload::measurement(
+                    reading.co2,
+                    reading.temperature,
+                    reading.humidity,
+                ),
+                ts: received
+                    .message
+                    .ts
+                    .map(|ts| ts.saturating_sub(u64::from(reading.age_seconds) * 1000)),
+                ..received.message.clone()
+            },
+            retained: received.retained,
+            received: received.received - chrono::Duration::seconds(reading.age_seconds.into()),
+            in_maintenance: false,
+            batched: true,
+        })
+        .collect()
+}
+
+/// Audits commands and turns configuration changes nobody asked for into
+/// `Event::Drift`, see `config_drift`
+pub struct ConfigDrift<S> {
+    pub detector: DriftDetector,
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for ConfigDrift<S> {
+    fn name(&self) -> &'static str {
+        "config_drift"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Command {
+                ref target,
+                ref command,
+                id,
+                ref issued_by,
+                received,
+            } => {
+                let envelope = CommandEnvelope {
+                    id,
+                    issued_by: issued_by.clone(),
+                    command: command.clone(),
+                    ..Default::default()
+                };
+                self.detector.command(target.as_deref(), envelope, received);
+                vec![event]
+            }
+            Event::Message(received) => {
+                let device = received.message.device.clone();
+                let time = received.received;
+                if let Some(id) = received.message.in_reply_to
+                    && let Some(operator) = self.detector.issuer(&device, id)
+                {
+                    info!("{}: answered command {} from {}", device, id, operator);
+                }
+                let changes = self
+                    .detector
+                    .observe(&device, &received.message.payload, time);
+                let mut events = vec![Event::Message(received)];
+                if !changes.is_empty()
+                    && let Some(detail) =
+                        config_drift::report(&self.store, &device, &changes, time).await
+                {
+                    events.push(Event::Drift {
+                        device,
+                        detail,
+                        time,
+                    });
+                }
+                events
+            }
+            event => vec![event],
+        }
+    }
+}
+
+/// Lets relayed commands follow the device answers, see `command_relay`
+pub struct Relay(pub RelayHandle);
+
+impl Stage for Relay {
+    fn name(&self) -> &'static str {
+        "relay"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        if let Event::Message(received) = &event {
+            let mut relay = self.0.relay.lock().await;
+            let changed = relay.observe(&received.message, received.received);
+            for id in &changed {
+                info!("Relayed command {} updated by device answer", id);
+            }
+            if !received.retained
+                && let Some(confirmation) = relay.confirmation_for(&received.message, &changed)
+            {
+                let topic = topics::command_topic(&received.message.device);
+                match self.0.confirm(&confirmation, topic) {
+                    Ok(()) => info!(
+                        "Confirming the setting '{}' is trying out",
+                        received.message.device
+                    ),
+                    Err(e) => error!("Failed to confirm a relayed setting: {}", e),
+                }
+            }
+        }
+        vec![event]
+    }
+}
+
+/// Logs what devices report and what the bootstrap restored
+pub struct Log;
+
+impl Stage for Log {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match &event {
+            Event::Message(received) => {
+                if received.message.injected {
+                    warn!(
+                        "{} is running an injected fault, the next message is made up",
+                        received.message.device
+                    );
+                }
+                log_payload(&received.message.device, &received.message.payload)
+            }
+            Event::Restored(restored) => bootstrap::log_restored(restored),
+            _ => {}
+        }
+        vec![event]
+    }
+}
+
+fn log_payload(device: &str, payload: &DevicePayload) {
+    match payload {
+        DevicePayload::LogLines { lines } => {
+            warn!("{} logged before the error:", device);
+            for line in lines {
+                warn!("  {}", line);
+            }
+        }
+        _ => log!(payload_level(payload), "{}: {}", device, payload),
+    }
+}
+
+/// Failures are errors; what the device worked around, put off or made up
+/// on purpose is a warning
+fn payload_level(payload: &DevicePayload) -> Level {
+    match payload {
+        DevicePayload::Error { .. }
+        | DevicePayload::FrcError { .. }
+        | DevicePayload::SetOffsetError { .. }
+        | DevicePayload::GetOffsetError { .. }
+        | DevicePayload::SetDeepSleepTimeError { .. }
+        | DevicePayload::GetDeepSleepTimeError { .. }
+        | DevicePayload::SetMqttPolicyError { .. }
+        | DevicePayload::ConfirmConfigError { .. }
+        | DevicePayload::OtaError { .. }
+        | DevicePayload::SetLogLevelError { .. }
+        | DevicePayload::SetAdaptiveModeError { .. }
+        | DevicePayload::AscError { .. }
+        | DevicePayload::AltitudeError { .. }
+        | DevicePayload::AmbientPressureError { .. }
+        | DevicePayload::FactoryResetError { .. }
+        | DevicePayload::SelfTestResult { passed: false, .. }
+        | DevicePayload::CommandAck {
+            accepted: false, ..
+        }
+        | DevicePayload::BusRecovery {
+            recovered: false, ..
+        } => Level::Error,
+        DevicePayload::SetOffsetRateLimited { .. }
+        | DevicePayload::PersistRateLimited { .. }
+        | DevicePayload::ConfigRolledBack { .. }
+        | DevicePayload::CommandsDeferred { .. }
+        | DevicePayload::BusRecovery {
+            recovered: true, ..
+        }
+        | DevicePayload::LogLines { .. }
+        | DevicePayload::RadioSkipped { .. }
+        | DevicePayload::FaultArmed { .. } => Level::Warn,
+        _ => Level::Info,
+    }
+}
+
+/// Follows FRC progress in the maintenance windows and marks measurements
+/// taken during one, see `maintenance`
+pub struct Maintenance(pub MaintenanceStore);
+
+impl Stage for Maintenance {
+    fn name(&self) -> &'static str {
+        "maintenance"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let Event::Message(mut received) = event else {
+            return vec![event];
+        };
+        let device = &received.message.device;
+        let now = received.received;
+        let mut failure = None;
+        match &received.message.payload {
+            payload @ (DevicePayload::FrcStart { .. }
+            | DevicePayload::FrcSuccess { .. }
+            | DevicePayload::FrcError { .. }) => {
+                if let Err(e) = self
+                    .0
+                    .update(now, |state| state.observe(device, payload, now))
+                {
+                    failure = Some(format!("Failed to update maintenance state: {}", e));
+                }
+            }
+            DevicePayload::MeasurementSuccess { .. } => {
+                received.in_maintenance = self.0.is_active(device, now);
+                if received.in_maintenance {
+                    info!("{} is in maintenance, tagging measurement", device);
+                }
+            }
+            _ => {}
+        }
+        let mut events = vec![Event::Message(received)];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Stores measurements in `scd40_data`, and their ingest latency, see
+/// `latency`
+pub struct InfluxWrite<S> {
+    pub store: S,
+    pub latency: Latency,
+    pub latency_warn_ms: u64,
+}
+
+impl<S: PointStore> InfluxWrite<S> {
+    /// Accounts for a stored measurement, warns when it took longer than
+    /// `latency_warn_ms` and stores the latency.
+    async fn record_latency(&self, received: &Received) -> Result<(), WriteError> {
+        let message = &received.message;
+        let sample =
+            match self
+                .latency
+                .observe(message, received.retained, received.received, Utc::now())
+            {
+                Observation::Counted(sample) => sample,
+                Observation::Excluded(reason) => {
+                    debug!(
+                        "Latency of {}'s measurement not counted: {}",
+                        message.device,
+                        reason.as_str()
+                    );
+                    return Ok(());
+                }
+            };
+        if sample.total_ms() > self.latency_warn_ms {
+            let p95 = self
+                .latency
+                .summary(&message.device)
+                .map_or_else(|| "?".to_string(), |s| s.p95_ms.to_string());
+            warn!(
+                "{}'s measurement took {} ms to reach InfluxDB ({} ms to the receiver, {} ms to write), recent p95 {} ms",
+                message.device,
+                sample.total_ms(),
+                sample.transit_ms,
+                sample.write_ms,
+                p95
+            );
+        }
+        let line = latency::to_line(&message.device, message.seq, &sample, received.received);
+        self.store.write(&[line]).await
+    }
+}
+
+impl<S: PointStore> Stage for InfluxWrite<S> {
+    fn name(&self) -> &'static str {
+        "influx_write"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let Event::Message(received) = event else {
+            return vec![event];
+        };
+        let DevicePayload::MeasurementSuccess {
+            co2,
+            temperature,
+            humidity,
+            battery_mv,
+            battery_percent,
+            flags,
+        } = received.message.payload
+        else {
+            return vec![Event::Message(received)];
+        };
+        let line = line_protocol::measurement_to_line(
+            &received.message.device,
+            &MeasurementFields {
+                co2,
+                temperature,
+                humidity,
+                maintenance: received.in_maintenance,
+                injected: received.message.injected,
+                battery_mv,
+                battery_percent,
+                fw_version: received.message.fw_version.clone(),
+                location: received.message.location.clone(),
+                flags,
+            },
+            alerts::event_time(&received.message, received.received).timestamp_nanos_opt(),
+        );
+        if let Err(e) = self.store.write(&[line]).await {
+            return vec![
+                Event::Message(received),
+                Event::Failed(format!("Failed to save measurement to InfluxDB: {}", e)),
+            ];
+        }
+        info!("Measurement saved to InfluxDB");
+        // A back-dated measurement's latency is mostly how long it was buffered
+        let failure = if received.batched {
+            None
+        } else {
+            self.record_latency(&received).await.err().map(|e| {
+                Event::Failed(format!("Failed to write ingest latency to InfluxDB: {}", e))
+            })
+        };
+        let mut events = vec![Event::Message(received)];
+        events.extend(failure);
+        events
+    }
+}
+
+/// Writes the whole-home virtual device, see `home`
+pub struct Home<S> {
+    pub aggregator: HomeAggregator,
+    pub maintenance: MaintenanceStore,
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for Home<S> {
+    fn name(&self) -> &'static str {
+        "home"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match &event {
+            Event::Message(received) => {
+                let Some(mut measurement) = received.measurement() else {
+                    return vec![event];
+                };
+                let time = alerts::event_time(&received.message, received.received);
+                measurement.time = time;
+                if !self.aggregator.observe(&measurement) {
+                    return vec![event];
+                }
+                let maintenance = &self.maintenance;
+                let Some(reading) = self
+                    .aggregator
+                    .reading(time, |device| maintenance.is_active(device, time))
+                else {
+                    return vec![event];
+                };
+                if let Err(e) = self
+                    .store
+                    .write(&[reading.to_line(time.timestamp_nanos_opt())])
+                    .await
+                {
+                    return vec![
+                        event,
+                        Event::Failed(format!(
+                            "Failed to save the home aggregate to InfluxDB: {}",
+                            e
+                        )),
+                    ];
+                }
+                debug!(
+                    "Home aggregate of {} device(s): {} ppm, highest {} ppm in {}",
+                    reading.devices, reading.co2, reading.max_co2, reading.max_co2_room
+                );
+            }
+            // Devices that stay quiet after a restart still count until they go stale
+            Event::Restored(restored) => {
+                for history in restored.values() {
+                    if let Some(last) = history.recent.last() {
+                        self.aggregator.observe(last);
+                    }
+                }
+            }
+            _ => {}
+        }
+        vec![event]
+    }
+}
+
+/// Keeps the hourly aggregates, see `hourly`
+pub struct Hourly<'a> {
+    pub aggregator: HourlyAggregator,
+    pub influx: InfluxStore<'a>,
+}
+
+impl Stage for Hourly<'_> {
+    fn name(&self) -> &'static str {
+        "hourly"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let result = match &event {
+            Event::Message(received) => match received.measurement() {
+                Some(measurement) => {
+                    let actions = self.aggregator.fold(&measurement);
+                    hourly::apply(
+                        self.influx.influx_host,
+                        self.influx.influx_token,
+                        self.influx.influx_database,
+                        self.influx.reqwest_client,
+                        self.aggregator.config(),
+                        actions,
+                    )
+                    .await
+                    .map_err(|e| format!("Failed to write hourly aggregate: {}", e))
+                }
+                None => Ok(()),
+            },
+            Event::Shutdown => {
+                info!("Writing open hourly aggregates before exiting");
+                hourly::write_aggregates(
+                    self.influx.influx_host,
+                    self.influx.influx_token,
+                    self.influx.influx_database,
+                    self.influx.reqwest_client,
+                    &self.aggregator.flush(),
+                )
+                .await
+                .map_err(|e| format!("Failed to write hourly aggregates: {}", e))
+            }
+            _ => Ok(()),
+        };
+        let mut events = vec![event];
+        events.extend(result.err().map(Event::Failed));
+        events
+    }
+}
+
+/// Keeps `LastSeen` for `/freshness`, see `freshness`
+pub struct Freshness(pub LastSeen);
+
+impl Stage for Freshness {
+    fn name(&self) -> &'static str {
+        "freshness"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match &event {
+            Event::Message(received) => {
+                if let Some(measurement) = received.measurement() {
+                    self.0.record(&measurement.device, measurement.time);
+                }
+                if let DevicePayload::NextWake { sleep_seconds, .. } = received.message.payload {
+                    self.0
+                        .record_interval(&received.message.device, sleep_seconds);
+                }
+            }
+            Event::Restored(restored) => bootstrap::record_last_seen(restored, &self.0),
+            _ => {}
+        }
+        vec![event]
+    }
+}
+
+/// Raises alerts for measurements outside maintenance, device errors and
+/// drift, see `alerts`
+pub struct Alerts {
+    pub alerter: Alerter,
+    pub reqwest_client: reqwest::Client,
+    /// Oldest first once read with `asc_iter`
+    live: CircularQueue<MeasurementWithTime>,
+}
+
+impl Alerts {
+    pub fn new(alerter: Alerter, reqwest_client: reqwest::Client) -> Self {
+        Self {
+            alerter,
+            reqwest_client,
+            live: CircularQueue::with_capacity(LIVE_MEASUREMENTS),
+        }
+    }
+}
+
+impl Stage for Alerts {
+    fn name(&self) -> &'static str {
+        "alerts"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match &event {
+            Event::Message(received) => {
+                let event_time = alerts::event_time(&received.message, received.received);
+                if let Some(measurement) = received.measurement() {
+                    if !received.in_maintenance {
+                        let event = MeasurementWithTime {
+                            time: event_time,
+                            ..measurement.clone()
+                        };
+                        self.alerter.measurement(&self.reqwest_client, &event).await;
+                    }
+                    self.live.push(measurement);
+                } else if let DevicePayload::Error { detail, .. } = &received.message.payload {
+                    self.alerter
+                        .device_error(
+                            &self.reqwest_client,
+                            &received.message.device,
+                            detail,
+                            event_time,
+                        )
+                        .await;
+                }
+            }
+            Event::Drift {
+                device,
+                detail,
+                time,
+            } => {
+                self.alerter
+                    .config_drift(&self.reqwest_client, device, detail, *time)
+                    .await;
+            }
+            Event::Restored(restored) => {
+                let live: Vec<MeasurementWithTime> = self.live.asc_iter().cloned().collect();
+                bootstrap::warm_up(restored, &live, &mut self.alerter);
+            }
+            _ => {}
+        }
+        vec![event]
+    }
+}
+
+/// Stores the configuration devices report, see `device_config`
+pub struct ConfigSnapshots<S> {
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for ConfigSnapshots<S> {
+    fn name(&self) -> &'static str {
+        "device_config"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut failure = None;
+        if let Event::Message(received) = &event
+            && let DevicePayload::Config(config) = &received.message.payload
+            && let Err(e) = device_config::save_snapshot(
+                &self.store,
+                &received.message.device,
+                config,
+                received.received,
+            )
+            .await
+        {
+            failure = Some(format!("Failed to save device configuration: {}", e));
+        }
+        let mut events = vec![event];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Stores the health devices report, see `device_diagnostics`
+pub struct Diagnostics<S> {
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for Diagnostics<S> {
+    fn name(&self) -> &'static str {
+        "device_diagnostics"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut failure = None;
+        if let Event::Message(received) = &event
+            && let Err(e) = device_diagnostics::save(
+                &self.store,
+                &received.message.device,
+                &received.message.payload,
+                alerts::event_time(&received.message, received.received),
+            )
+            .await
+        {
+            failure = Some(format!("Failed to save device diagnostics: {}", e));
+        }
+        let mut events = vec![event];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Stores one-off device events, see `device_events`
+pub struct Events<S> {
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for Events<S> {
+    fn name(&self) -> &'static str {
+        "device_events"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut failure = None;
+        if let Event::Message(received) = &event
+            && let Err(e) = device_events::save(
+                &self.store,
+                &received.message.device,
+                &received.message.payload,
+                alerts::event_time(&received.message, received.received),
+            )
+            .await
+        {
+            failure = Some(format!("Failed to save device event: {}", e));
+        }
+        let mut events = vec![event];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Stores a device's sensor serial number when it is new, see `device_info`
+pub struct Info<S> {
+    pub serials: KnownSerials,
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for Info<S> {
+    fn name(&self) -> &'static str {
+        "device_info"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut failure = None;
+        if let Event::Message(received) = &event
+            && let DevicePayload::SerialNumber { serial } = received.message.payload
+        {
+            let device = &received.message.device;
+            match self
+                .serials
+                .save(&self.store, device, serial, received.received)
+                .await
+            {
+                Ok(true) => info!("Stored sensor serial 0x{:012X} of {}", serial, device),
+                Ok(false) => {}
+                Err(e) => failure = Some(format!("Failed to save sensor serial: {}", e)),
+            }
+        }
+        let mut events = vec![event];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Stores when a sleeping device should wake, see `device_status`
+pub struct Status<S> {
+    pub store: S,
+}
+
+impl<S: PointStore> Stage for Status<S> {
+    fn name(&self) -> &'static str {
+        "device_status"
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut failure = None;
+        if let Event::Message(received) = &event
+            && let Err(e) = device_status::save(
+                &self.store,
+                &received.message.device,
+                &received.message.payload,
+                alerts::event_time(&received.message, received.received),
+            )
+            .await
+        {
+            failure = Some(format!("Failed to save device status: {}", e));
+        }
+        let mut events = vec![event];
+        events.extend(failure.map(Event::Failed));
+        events
+    }
+}
+
+/// Any of the stages above, so that one pipeline holds a mix of them
+pub enum IngestStage<'a, S> {
+    Dedup(Dedup),
+    Decode(Decode),
+    Validate(Validate),
+    SeqDedup(SeqDedup),
+    Unbatch(Unbatch),
+    ConfigDrift(ConfigDrift<S>),
+    Relay(Relay),
+    Log(Log),
+    Maintenance(Maintenance),
+    InfluxWrite(InfluxWrite<S>),
+    Home(Home<S>),
+    Hourly(Hourly<'a>),
+    Freshness(Freshness),
+    Alerts(Box<Alerts>),
+    DeviceConfig(ConfigSnapshots<S>),
+    DeviceDiagnostics(Diagnostics<S>),
+    DeviceEvents(Events<S>),
+    DeviceInfo(Info<S>),
+    DeviceStatus(Status<S>),
+}
+
+impl<S: PointStore> Stage for IngestStage<'_, S> {
+    fn name(&self) -> &'static str {
+        match self {
+            IngestStage::Dedup(stage) => stage.name(),
+            IngestStage::Decode(stage) => stage.name(),
+            IngestStage::Validate(stage) => stage.name(),
+            IngestStage::SeqDedup(stage) => stage.name(),
+            IngestStage::Unbatch(stage) => stage.name(),
+            IngestStage::ConfigDrift(stage) => stage.name(),
+            IngestStage::Relay(stage) => stage.name(),
+            IngestStage::Log(stage) => stage.name(),
+            IngestStage::Maintenance(stage) => stage.name(),
+            IngestStage::InfluxWrite(stage) => stage.name(),
+            IngestStage::Home(stage) => stage.name(),
+            IngestStage::Hourly(stage) => stage.name(),
+            IngestStage::Freshness(stage) => stage.name(),
+            IngestStage::Alerts(stage) => stage.name(),
+            IngestStage::DeviceConfig(stage) => stage.name(),
+            IngestStage::DeviceDiagnostics(stage) => stage.name(),
+            IngestStage::DeviceEvents(stage) => stage.name(),
+            IngestStage::DeviceInfo(stage) => stage.name(),
+            IngestStage::DeviceStatus(stage) => stage.name(),
+        }
+    }
+
+    async fn process(&mut self, event: Event) -> Vec<Event> {
+        match self {
+            IngestStage::Dedup(stage) => stage.process(event).await,
+            IngestStage::Decode(stage) => stage.process(event).await,
+            IngestStage::Validate(stage) => stage.process(event).await,
+            IngestStage::SeqDedup(stage) => stage.process(event).await,
+            IngestStage::Unbatch(stage) => stage.process(event).await,
+            IngestStage::ConfigDrift(stage) => stage.process(event).await,
+            IngestStage::Relay(stage) => stage.process(event).await,
+            IngestStage::Log(stage) => stage.process(event).await,
+            IngestStage::Maintenance(stage) => stage.process(event).await,
+            IngestStage::InfluxWrite(stage) => stage.process(event).await,
+            IngestStage::Home(stage) => stage.process(event).await,
+            IngestStage::Hourly(stage) => stage.process(event).await,
+            IngestStage::Freshness(stage) => stage.process(event).await,
+            IngestStage::Alerts(stage) => stage.process(event).await,
+            IngestStage::DeviceConfig(stage) => stage.process(event).await,
+            IngestStage::DeviceDiagnostics(stage) => stage.process(event).await,
+            IngestStage::DeviceEvents(stage) => stage.process(event).await,
+            IngestStage::DeviceInfo(stage) => stage.process(event).await,
+            IngestStage::DeviceStatus(stage) => stage.process(event).await,
+        }
+    }
+}
+
+/// What the stages are built from. A stage whose part is `None` is left
+/// out of the pipeline.
+pub struct Parts<'a, S> {
+    /// Where measurements, latency, drift, configurations, diagnostics,
+    /// device events, sensor serials and sleep statuses are written
+    pub store: S,
+    pub command_topic: String,
+    pub drift: DriftDetector,
+    pub maintenance: MaintenanceStore,
+    pub latency: Latency,
+    pub latency_warn_ms: u64,
+    pub relay: Option<RelayHandle>,
+    pub hourly: Option<(HourlyAggregator, InfluxStore<'a>)>,
+    pub last_seen: Option<LastSeen>,
+    pub alerter: Option<(Alerter, reqwest::Client)>,
+    pub home: Option<HomeAggregator>,
+}
+
+/// Those of `names` a follower runs, in the same order
+pub fn follower_stages(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| FOLLOWER_STAGES.contains(&name.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Builds the stages named in `names`, in that order.
+pub fn assemble<'a, S: PointStore + Clone>(
+    names: &[String],
+    parts: Parts<'a, S>,
+    metrics: Metrics,
+) -> Result<Pipeline<'a, S>, String> {
+    let Parts {
+        store,
+        command_topic,
+        drift,
+        maintenance,
+        latency,
+        latency_warn_ms,
+        mut relay,
+        mut hourly,
+        mut last_seen,
+        mut alerter,
+        home,
+    } = parts;
+    // The home stage checks maintenance windows itself
+    let mut home = home.map(|aggregator| (aggregator, maintenance.clone()));
+    let (mut drift, mut maintenance) = (Some(drift), Some(maintenance));
+    let mut stages = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return Err(format!("ingest stage '{}' is listed twice", name));
+        }
+        let stage = match name.as_str() {
+            "dedup" => IngestStage::Dedup(Dedup(RetainedDedup::new())),
+            "decode" => IngestStage::Decode(Decode {
+                command_topic: command_topic.clone(),
+            }),
+            "validate" => IngestStage::Validate(Validate),
+            "seq_dedup" => IngestStage::SeqDedup(SeqDedup::new(metrics.redelivered.clone())),
+            "unbatch" => IngestStage::Unbatch(Unbatch),
+            "config_drift" => {
+                let Some(detector) = drift.take() else {
+                    continue;
+                };
+                IngestStage::ConfigDrift(ConfigDrift {
+                    detector,
+                    store: store.clone(),
+                })
+            }
+            "relay" => {
+                let Some(handle) = relay.take() else {
+                    continue;
+                };
+                IngestStage::Relay(Relay(handle))
+            }
+            "log" => IngestStage::Log(Log),
+            "maintenance" => {
+                let Some(maintenance) = maintenance.take() else {
+                    continue;
+                };
+                IngestStage::Maintenance(Maintenance(maintenance))
+            }
+            "influx_write" => IngestStage::InfluxWrite(InfluxWrite {
+                store: store.clone(),
+                latency: latency.clone(),
+                latency_warn_ms,
+            }),
+            "home" => {
+                let Some((aggregator, maintenance)) = home.take() else {
+                    continue;
+                };
+                IngestStage::Home(Home {
+                    aggregator,
+                    maintenance,
+                    store: store.clone(),
+                })
+            }
+            "hourly" => {
+                let Some((aggregator, influx)) = hourly.take() else {
+                    continue;
+                };
+                IngestStage::Hourly(Hourly { aggregator, influx })
+            }
+            "freshness" => {
+                let Some(last_seen) = last_seen.take() else {
+                    continue;
+                };
+                IngestStage::Freshness(Freshness(last_seen))
+            }
+            "alerts" => {
+                let Some((alerter, reqwest_client)) = alerter.take() else {
+                    continue;
+                };
+                IngestStage::Alerts(Box::new(Alerts::new(alerter, reqwest_client)))
+            }
+            "device_config" => IngestStage::DeviceConfig(ConfigSnapshots {
+                store: store.clone(),
+            }),
+            "device_diagnostics" => IngestStage::DeviceDiagnostics(Diagnostics {
+                store: store.clone(),
+            }),
+            "device_events" => IngestStage::DeviceEvents(Events {
+                store: store.clone(),
+            }),
+            "device_info" => IngestStage::DeviceInfo(Info {
+                serials: KnownSerials::default(),
+                store: store.clone(),
+            }),
+            "device_status" => IngestStage::DeviceStatus(Status {
+                store: store.clone(),
+            }),
+            _ => {
+                return Err(format!(
+                    "unknown ingest stage '{}', expected one of {}",
+                    name,
+                    STAGES.join(", ")
+                ));
+            }
+        };
+        stages.push(stage);
+    }
+    Ok(Pipeline::new(stages, metrics))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StageMetrics {
+    pub processed: u64,
+    /// Events the stage passed nothing on for, without failing
+    pub dropped: u64,
+    /// `Event::Failed` the stage returned
+    pub errored: u64,
+    pub duration: Duration,
+}
+
+impl StageMetrics {
+    fn add(&mut self, other: &StageMetrics) {
+        self.processed += other.processed;
+        self.dropped += other.dropped;
+        self.errored += other.errored;
+        self.duration += other.duration;
+    }
+}
+
+/// Measurements marked `redelivered` that reached `SeqDedup`
+#[derive(Debug, Default)]
+pub struct Redelivered {
+    /// The first copy never arrived
+    recovered: AtomicU64,
+    /// The first copy had arrived after all
+    repeated: AtomicU64,
+}
+
+/// Stage metrics shared between the receiver and the web server, in
+/// pipeline order
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    stages: Arc<Mutex<Vec<(&'static str, StageMetrics)>>>,
+    redelivered: Arc<Redelivered>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, StageMetrics)> {
+        self.stages.lock().unwrap().clone()
+    }
+
+    fn start(&self, stages: impl Iterator<Item = &'static str>) {
+        *self.stages.lock().unwrap() = stages.map(|name| (name, StageMetrics::default())).collect();
+    }
+
+    fn add(&self, index: usize, metrics: &StageMetrics) {
+        if let Some((_, total)) = self.stages.lock().unwrap().get_mut(index) {
+            total.add(metrics);
+        }
+    }
+
+    pub fn write_openmetrics(&self, out: &mut String) {
+        let stages = self.snapshot();
+        let _ = writeln!(out, "# TYPE {} counter", EVENTS_METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Events through each ingest stage, by what became of them.",
+            EVENTS_METRIC
+        );
+        for (stage, metrics) in &stages {
+            for (outcome, count) in [
+                ("processed", metrics.processed),
+                ("dropped", metrics.dropped),
+                ("errored", metrics.errored),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "{}_total{{stage=\"{}\",outcome=\"{}\"}} {}",
+                    EVENTS_METRIC, stage, outcome, count
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE {} counter", DURATION_METRIC);
+        let _ = writeln!(out, "# UNIT {} seconds", DURATION_METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Time spent in each ingest stage.",
+            DURATION_METRIC
+        );
+        for (stage, metrics) in &stages {
+            let _ = writeln!(
+                out,
+                "{}_total{{stage=\"{}\"}} {}",
+                DURATION_METRIC,
+                stage,
+                metrics.duration.as_secs_f64()
+            );
+        }
+        let _ = writeln!(out, "# TYPE {} counter", REDELIVERED_METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Measurements devices sent again, by whether the first copy had arrived.",
+            REDELIVERED_METRIC
+        );
+        for (outcome, count) in [
+            ("recovered", &self.redelivered.recovered),
+            ("repeated", &self.redelivered.repeated),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_total{{outcome=\"{}\"}} {}",
+                REDELIVERED_METRIC,
+                outcome,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+pub struct Pipeline<'a, S> {
+    stages: Vec<IngestStage<'a, S>>,
+    metrics: Metrics,
+}
+
+impl<'a, S: PointStore> Pipeline<'a, S> {
+    pub fn new(stages: Vec<IngestStage<'a, S>>, metrics: Metrics) -> Self {
+        metrics.start(stages.iter().map(|stage| stage.name()));
+        Self { stages, metrics }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    /// Whether any stage takes `Event::Restored`, which makes the bootstrap
+    /// worth fetching
+    pub fn restores(&self) -> bool {
+        self.stages
+            .iter()
+            .any(|stage| matches!(stage, IngestStage::Freshness(_) | IngestStage::Alerts(_)))
+    }
+
+    /// Hands `event` through every stage. Returns what came out of the last
+    /// one.
+    pub async fn feed(&mut self, event: Event) -> Vec<Event> {
+        let mut events = vec![event];
+        for (index, stage) in self.stages.iter_mut().enumerate() {
+            let mut next = Vec::new();
+            for event in events {
+                let started = Instant::now();
+                let output = stage.process(event).await;
+                let mut metrics = StageMetrics {
+                    processed: 1,
+                    duration: started.elapsed(),
+                    ..Default::default()
+                };
+                let passed = next.len();
+                for event in output {
+                    match event {
+                        Event::Failed(reason) => {
+                            error!("{}", reason);
+                            metrics.errored += 1;
+                        }
+                        event => next.push(event),
+                    }
+                }
+                if next.len() == passed && metrics.errored == 0 {
+                    metrics.dropped = 1;
+                }
+                self.metrics.add(index, &metrics);
+            }
+            events = next;
+        }
+        events
+    }
+
+    pub fn log_metrics(&self) {
+        for (stage, metrics) in self.metrics.snapshot() {
+            info!(
+                "Stage {}: {} processed, {} dropped, {} errored in {:?}",
+                stage, metrics.processed, metrics.dropped, metrics.errored, metrics.duration
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{BTreeMap, HashSet};
+    use std::error::Error;
+
+    use shared_types::{BatchedReading, DeviceCommandBatch, MeasurementFlags};
+
+    use crate::bulk_write::PointKey;
+    use crate::ventilation::RoomRegistry;
+
+    const TOPIC: &str = "sensors/esp32/sensor";
+    const COMMAND_TOPIC: &str = "sensors/esp32/command";
+
+    /// Keeps written lines in memory, failing every write while `failing`
+    #[derive(Default)]
+    struct MockStore {
+        lines: RefCell<Vec<String>>,
+        failing: Cell<bool>,
+    }
+
+    impl PointStore for &MockStore {
+        async fn existing(
+            &self,
+            _measurement: &'static str,
+            _series_tag: &'static str,
+            _from: DateTime<Utc>,
+            _to: DateTime<Utc>,
+        ) -> Result<HashSet<PointKey>, Box<dyn Error>> {
+            Ok(HashSet::new())
+        }
+
+        async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+            if self.failing.get() {
+                return Err(WriteError::Retryable("connection refused".to_string()));
+            }
+            self.lines.borrow_mut().extend_from_slice(lines);
+            Ok(())
+        }
+    }
+
+    impl MockStore {
+        fn measurements(&self) -> Vec<String> {
+            self.lines
+                .borrow()
+                .iter()
+                .filter(|line| line.starts_with("scd40_data,"))
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn measurement(device: &str, co2: u16) -> DeviceMessage {
+        DeviceMessage::new(device, DevicePayload::measurement(co2, 21.5, 40.0))
+    }
+
+    fn publish(message: &DeviceMessage, retained: bool, seconds: i64) -> Event {
+        Event::Publish {
+            topic: TOPIC.to_string(),
+            payload: message.to_json().unwrap().into_bytes(),
+            retained,
+            received: at(seconds),
+        }
+    }
+
+    fn received(message: DeviceMessage, seconds: i64) -> Event {
+        Event::Message(Received {
+            message,
+            retained: false,
+            received: at(seconds),
+            in_maintenance: false,
+            batched: false,
+        })
+    }
+
+    fn parts<'a>(store: &'a MockStore, test: &str) -> Parts<'static, &'a MockStore> {
+        Parts {
+            store,
+            command_topic: COMMAND_TOPIC.to_string(),
+            drift: DriftDetector::new(at(0)),
+            maintenance: MaintenanceStore::new(std::env::temp_dir().join(format!(
+                "rpi-processor-pipeline-{}-{}.json",
+                std::process::id(),
+                test
+            ))),
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+            relay: None,
+            hourly: None,
+            last_seen: Some(LastSeen::new()),
+            alerter: None,
+            home: None,
+        }
+    }
+
+    fn default_stages() -> Vec<String> {
+        STAGES.map(str::to_string).to_vec()
+    }
+
+    #[tokio::test]
+    async fn dedup_drops_retained_replays_only() {
+        let mut stage = Dedup::default();
+        let message = measurement("kitchen", 600);
+        assert_eq!(stage.process(publish(&message, true, 0)).await.len(), 1);
+        assert!(stage.process(publish(&message, true, 5)).await.is_empty());
+        // Live repeats are QoS 1 duplicates or a steady room, not a replay
+        assert_eq!(stage.process(publish(&message, false, 10)).await.len(), 1);
+        assert_eq!(stage.process(publish(&message, false, 15)).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn decode_tells_commands_from_device_messages() {
+        let mut stage = Decode {
+            command_topic: COMMAND_TOPIC.to_string(),
+        };
+        let command = Event::Publish {
+            topic: format!("{}/kitchen", COMMAND_TOPIC),
+            payload: DeviceCommand::GetTempOffset.to_json().unwrap().into_bytes(),
+            retained: true,
+            received: at(0),
+        };
+        match stage.process(command).await.as_slice() {
+            [
+                Event::Command {
+                    target: Some(target),
+                    command: DeviceCommand::GetTempOffset,
+                    ..
+                },
+            ] => assert_eq!(target, "kitchen"),
+            other => panic!("{:?}", other),
+        }
+        let signed = Event::Publish {
+            topic: COMMAND_TOPIC.to_string(),
+            payload: DeviceCommand::GetTempOffset
+                .with_id(7)
+                .issued_by("ola")
+                .to_json()
+                .unwrap()
+                .into_bytes(),
+            retained: true,
+            received: at(0),
+        };
+        match stage.process(signed).await.as_slice() {
+            [
+                Event::Command {
+                    target: None,
+                    id: Some(7),
+                    issued_by: Some(operator),
+                    ..
+                },
+            ] => assert_eq!(operator, "ola"),
+            other => panic!("{:?}", other),
+        }
+        let batch = Event::Publish {
+            topic: COMMAND_TOPIC.to_string(),
+            payload: DeviceCommandBatch::new([
+                DeviceCommand::GetAsc,
+                DeviceCommand::SetDeepSleepTime { seconds: 600 },
+            ])
+            .unwrap()
+            .with_id(8)
+            .to_json()
+            .unwrap()
+            .into_bytes(),
+            retained: true,
+            received: at(0),
+        };
+        match stage.process(batch).await.as_slice() {
+            [
+                Event::Command {
+                    command: DeviceCommand::GetAsc,
+                    id: Some(8),
+                    ..
+                },
+                Event::Command {
+                    command: DeviceCommand::SetDeepSleepTime { seconds: 600 },
+                    id: Some(8),
+                    ..
+                },
+            ] => {}
+            other => panic!("{:?}", other),
+        }
+        let cleared = Event::Publish {
+            topic: COMMAND_TOPIC.to_string(),
+            payload: Vec::new(),
+            retained: true,
+            received: at(0),
+        };
+        assert!(stage.process(cleared).await.is_empty());
+
+        match stage
+            .process(publish(&measurement("kitchen", 600), true, 0))
+            .await
+            .as_slice()
+        {
+            [Event::Message(received)] => {
+                assert_eq!(received.message.device, "kitchen");
+                assert!(received.retained);
+                assert_eq!(received.received, at(0));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let garbage = Event::Publish {
+            topic: TOPIC.to_string(),
+            payload: b"{\"device\":".to_vec(),
+            retained: false,
+            received: at(0),
+        };
+        match stage.process(garbage).await.as_slice() {
+            [Event::Failed(reason)] => assert!(reason.contains("invalid JSON"), "{}", reason),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn seq_dedup_drops_redelivered_measurements() {
+        let mut stage = SeqDedup::default();
+        let first = measurement("kitchen", 600).stamped(None, 4);
+        assert_eq!(stage.process(received(first.clone(), 0)).await.len(), 1);
+        // The broker delivered it again after a reconnect
+        assert!(stage.process(received(first, 5)).await.is_empty());
+        let next = measurement("kitchen", 600).stamped(None, 5);
+        assert_eq!(stage.process(received(next, 300)).await.len(), 1);
+        // Without a seq there's nothing to tell repeats by
+        let unnumbered = measurement("kitchen", 600);
+        assert_eq!(
+            stage.process(received(unnumbered.clone(), 310)).await.len(),
+            1
+        );
+        assert_eq!(stage.process(received(unnumbered, 320)).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn seq_dedup_counts_what_outboxes_sent_again() {
+        let metrics = Metrics::new();
+        let mut stage = SeqDedup::new(metrics.redelivered.clone());
+        let arrived = measurement("kitchen", 600).stamped(None, 4);
+        assert_eq!(stage.process(received(arrived.clone(), 0)).await.len(), 1);
+        // Its PUBACK was lost, the first copy wasn't
+        assert!(
+            stage
+                .process(received(arrived.redelivered(), 300))
+                .await
+                .is_empty()
+        );
+        // Sent before a power cut and never seen
+        let lost = measurement("kitchen", 600).stamped(None, 3).redelivered();
+        assert_eq!(stage.process(received(lost, 301)).await.len(), 1);
+
+        let mut out = String::new();
+        metrics.write_openmetrics(&mut out);
+        assert!(
+            out.contains("air_quality_redelivered_measurements_total{outcome=\"recovered\"} 1\n")
+        );
+        assert!(
+            out.contains("air_quality_redelivered_measurements_total{outcome=\"repeated\"} 1\n")
+        );
+    }
+
+    fn batch(ages: &[u32]) -> DeviceMessage {
+        let readings = ages
+            .iter()
+            .map(|&age_seconds| BatchedReading {
+                co2: 600 + age_seconds as u16,
+                temperature: 21.5,
+                humidity: 40.0,
+                age_seconds,
+            })
+            .collect();
+        DeviceMessage::new("kitchen", DevicePayload::MeasurementBatch { readings })
+    }
+
+    #[tokio::test]
+    async fn unbatch_backdates_readings_oldest_first() {
+        let mut stage = Unbatch;
+        let sent = batch(&[0, 600, 300]).stamped(Some(at(900).timestamp_millis() as u64), 7);
+        let events = stage.process(received(sent, 905)).await;
+        let measurements: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                Event::Message(received) => (
+                    received.measurement().unwrap().co2,
+                    received.message.timestamp().unwrap(),
+                    received.received,
+                    received.batched,
+                ),
+                other => panic!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            measurements,
+            [
+                (1200, at(300), at(305), true),
+                (900, at(600), at(605), true),
+                (600, at(900), at(905), true),
+            ]
+        );
+
+        // Without a device timestamp only the arrival is moved back
+        let events = stage.process(received(batch(&[120]), 300)).await;
+        match events.as_slice() {
+            [Event::Message(received)] => {
+                assert_eq!(received.message.ts, None);
+                assert_eq!(received.received, at(180));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(stage.process(received(batch(&[]), 0)).await.is_empty());
+        assert_eq!(
+            stage
+                .process(received(measurement("kitchen", 600), 0))
+                .await
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_measurements_are_stored_when_they_were_taken() {
+        let store = MockStore::default();
+        let names = ["unbatch".to_string(), "influx_write".to_string()];
+        let mut pipeline = assemble(&names, parts(&store, "unbatch"), Metrics::new()).unwrap();
+        pipeline.feed(received(batch(&[300, 0]), 600)).await;
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen co2_ppm=900,temperature_c=21.5,humidity_percent=40 1736942700000000000",
+                "scd40_data,device=kitchen co2_ppm=600,temperature_c=21.5,humidity_percent=40 1736943000000000000"
+            ]
+        );
+        // Hours in a buffer aren't ingest latency
+        assert!(
+            !store
+                .lines
+                .borrow()
+                .iter()
+                .any(|line| line.starts_with("ingest_latency"))
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_drops_newer_protocol_versions() {
+        let mut stage = Validate;
+        let mut message = measurement("kitchen", 600);
+        assert_eq!(stage.process(received(message.clone(), 0)).await.len(), 1);
+        message.version = shared_types::CURRENT_PROTOCOL_VERSION + 1;
+        assert!(stage.process(received(message, 0)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn validate_fails_impossible_messages() {
+        let mut stage = Validate;
+        match &stage.process(received(measurement("kitchen", 0), 0)).await[..] {
+            [Event::Failed(reason)] => assert_eq!(
+                reason,
+                "Invalid message from kitchen: CO2 of 0 ppm is a sensor glitch"
+            ),
+            events => panic!("{:?}", events),
+        }
+        let frc = DeviceMessage::new("kitchen", DevicePayload::frc_start(70));
+        assert!(matches!(
+            &stage.process(received(frc, 1)).await[..],
+            [Event::Failed(reason)] if reason.contains("FRC target 70 ppm")
+        ));
+    }
+
+    #[tokio::test]
+    async fn influx_write_stores_measurements_with_their_tags() {
+        let store = MockStore::default();
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        let Event::Message(mut tagged) = received(measurement("kitchen", 600), 0) else {
+            unreachable!()
+        };
+        tagged.in_maintenance = true;
+        assert_eq!(stage.process(Event::Message(tagged)).await.len(), 1);
+        let alive = DeviceMessage::new("kitchen", DevicePayload::Alive { uptime_seconds: 5 });
+        assert_eq!(stage.process(received(alive, 1)).await.len(), 1);
+        let versioned = measurement("kitchen", 610).with_fw_version("0.4.0");
+        assert_eq!(stage.process(received(versioned, 2)).await.len(), 1);
+        let injected = measurement("kitchen", 0).injected();
+        assert_eq!(stage.process(received(injected, 3)).await.len(), 1);
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen,maintenance=true co2_ppm=600,temperature_c=21.5,humidity_percent=40 1736942400000000000",
+                "scd40_data,device=kitchen,fw_version=0.4.0 co2_ppm=610,temperature_c=21.5,humidity_percent=40 1736942402000000000",
+                "scd40_data,device=kitchen,injected=true co2_ppm=0,temperature_c=21.5,humidity_percent=40 1736942403000000000"
+            ]
+        );
+    }
+
+    #[test]
+    fn measurements_are_timed_by_the_device_clock() {
+        let time = |event| match event {
+            Event::Message(received) => received.measurement().unwrap().time,
+            other => panic!("{:?}", other),
+        };
+        let delayed = measurement("kitchen", 600).stamped(Some(at(0).timestamp_millis() as u64), 1);
+        assert_eq!(time(received(delayed, 60)), at(0));
+        let ahead = measurement("kitchen", 600).stamped(Some(at(600).timestamp_millis() as u64), 2);
+        assert_eq!(time(received(ahead, 120)), at(120));
+        assert_eq!(time(received(measurement("kitchen", 600), 60)), at(60));
+    }
+
+    #[tokio::test]
+    async fn influx_write_prefers_the_device_timestamp() {
+        let store = MockStore::default();
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        // Queued on the broker for a minute
+        let delayed = measurement("kitchen", 600).stamped(Some(at(0).timestamp_millis() as u64), 1);
+        stage.process(received(delayed, 60)).await;
+        // Its clock is ahead, so the arrival time is used
+        let ahead = measurement("kitchen", 610).stamped(Some(at(600).timestamp_millis() as u64), 2);
+        stage.process(received(ahead, 120)).await;
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen co2_ppm=600,temperature_c=21.5,humidity_percent=40 1736942400000000000",
+                "scd40_data,device=kitchen co2_ppm=610,temperature_c=21.5,humidity_percent=40 1736942520000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn influx_write_adds_battery_fields_when_sent() {
+        let store = MockStore::default();
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        let on_battery = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::measurement_with_battery(600, 21.5, 40.0, 3870, 72),
+        );
+        stage.process(received(on_battery, 0)).await;
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen co2_ppm=600,temperature_c=21.5,humidity_percent=40,battery_mv=3870,battery_percent=72 1736942400000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn influx_write_adds_quality_flags_when_sent() {
+        let store = MockStore::default();
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        let first = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::measurement(600, 21.5, 40.0).flagged(MeasurementFlags {
+                first_after_boot: true,
+                sensor_warmup_incomplete: true,
+                retried_read: false,
+            }),
+        );
+        stage.process(received(first, 0)).await;
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen co2_ppm=600,temperature_c=21.5,humidity_percent=40,first_after_boot=true,sensor_warmup_incomplete=true,retried_read=false 1736942400000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn influx_write_tags_the_location_when_sent() {
+        let store = MockStore::default();
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        let located = measurement("bedroom-1", 600).with_location("bedroom");
+        stage.process(received(located, 0)).await;
+        stage
+            .process(received(measurement("kitchen", 610), 1))
+            .await;
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=bedroom-1,location=bedroom co2_ppm=600,temperature_c=21.5,humidity_percent=40 1736942400000000000",
+                "scd40_data,device=kitchen co2_ppm=610,temperature_c=21.5,humidity_percent=40 1736942401000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn home_combines_the_latest_of_each_device() {
+        let store = MockStore::default();
+        let mut stage = Home {
+            aggregator: HomeAggregator::new(Default::default(), RoomRegistry::default()),
+            maintenance: parts(&store, "home").maintenance,
+            store: &store,
+        };
+        stage
+            .process(received(measurement("kitchen", 600), 0))
+            .await;
+        stage
+            .process(received(measurement("bedroom", 900), 60))
+            .await;
+        // The aggregate itself never feeds back in
+        stage
+            .process(received(measurement(shared_types::HOME_DEVICE, 2000), 90))
+            .await;
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=home co2_ppm=600,temperature_c=21.5,humidity_percent=40,max_co2_ppm=600,max_co2_room=\"kitchen\",devices=1 1736942400000000000",
+                "scd40_data,device=home co2_ppm=750,temperature_c=21.5,humidity_percent=40,max_co2_ppm=900,max_co2_room=\"bedroom\",devices=2 1736942460000000000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_diagnostics_are_stored() {
+        let store = MockStore::default();
+        let mut stage = Diagnostics { store: &store };
+        let report = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep"),
+        );
+        stage.process(received(report, 0)).await;
+        stage
+            .process(received(measurement("kitchen", 600), 60))
+            .await;
+
+        assert_eq!(
+            *store.lines.borrow(),
+            [
+                "device_diagnostics,device=kitchen rssi_dbm=-67i,free_heap_bytes=182344i,boot_count=41i,reset_reason=\"deep_sleep\" 1736942400000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn self_test_results_are_stored() {
+        let store = MockStore::default();
+        let mut stage = Events { store: &store };
+        let result = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::SelfTestResult {
+                passed: true,
+                detail: String::new(),
+            },
+        );
+        stage.process(received(result, 0)).await;
+        stage
+            .process(received(measurement("kitchen", 600), 60))
+            .await;
+
+        assert_eq!(
+            *store.lines.borrow(),
+            [
+                "device_events,device=kitchen,event=self_test passed=true,detail=\"\" 1736942400000000000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sensor_serials_are_stored_when_new() {
+        let store = MockStore::default();
+        let mut stage = Info {
+            serials: KnownSerials::default(),
+            store: &store,
+        };
+        let serial =
+            |device, serial| DeviceMessage::new(device, DevicePayload::SerialNumber { serial });
+        stage.process(received(serial("kitchen", 42), 0)).await;
+        stage.process(received(serial("kitchen", 42), 60)).await;
+        stage.process(received(serial("bedroom", 42), 120)).await;
+        // The module was swapped
+        stage.process(received(serial("kitchen", 43), 180)).await;
+
+        assert_eq!(
+            *store.lines.borrow(),
+            [
+                "device_info,device=kitchen serial_number=42i 1736942400000000000",
+                "device_info,device=bedroom serial_number=42i 1736942520000000000",
+                "device_info,device=kitchen serial_number=43i 1736942580000000000",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_serial_that_failed_to_store_is_stored_next_time() {
+        let store = MockStore::default();
+        let mut stage = Info {
+            serials: KnownSerials::default(),
+            store: &store,
+        };
+        let serial = DeviceMessage::new("kitchen", DevicePayload::SerialNumber { serial: 42 });
+        store.failing.set(true);
+        match stage.process(received(serial.clone(), 0)).await.as_slice() {
+            [Event::Message(_), Event::Failed(reason)] => {
+                assert_eq!(reason, "Failed to save sensor serial: connection refused")
+            }
+            other => panic!("{:?}", other),
+        }
+        store.failing.set(false);
+        stage.process(received(serial, 60)).await;
+        assert_eq!(store.lines.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn influx_write_failures_still_pass_the_measurement_on() {
+        let store = MockStore::default();
+        store.failing.set(true);
+        let mut stage = InfluxWrite {
+            store: &store,
+            latency: Latency::new(),
+            latency_warn_ms: latency::DEFAULT_WARN_MS,
+        };
+        match stage
+            .process(received(measurement("kitchen", 600), 0))
+            .await
+            .as_slice()
+        {
+            [Event::Message(_), Event::Failed(reason)] => {
+                assert_eq!(
+                    reason,
+                    "Failed to save measurement to InfluxDB: connection refused"
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn config_drift_raises_only_unexplained_changes() {
+        let store = MockStore::default();
+        let mut stage = ConfigDrift {
+            detector: DriftDetector::new(at(0)),
+            store: &store,
+        };
+        let sleep = |seconds| {
+            DeviceMessage::new(
+                "kitchen",
+                DevicePayload::GetDeepSleepTimeSuccess { seconds },
+            )
+        };
+        assert_eq!(stage.process(received(sleep(300), 60)).await.len(), 1);
+
+        let command = Event::Command {
+            target: Some("kitchen".to_string()),
+            command: DeviceCommand::SetDeepSleepTime { seconds: 600 },
+            id: None,
+            issued_by: None,
+            received: at(120),
+        };
+        assert_eq!(stage.process(command).await.len(), 1);
+        assert_eq!(stage.process(received(sleep(600), 180)).await.len(), 1);
+        assert!(store.lines.borrow().is_empty());
+
+        match stage.process(received(sleep(60), 240)).await.as_slice() {
+            [
+                Event::Message(_),
+                Event::Drift {
+                    device,
+                    detail,
+                    time,
+                },
+            ] => {
+                assert_eq!(device, "kitchen");
+                assert!(detail.ends_with("without a command"), "{}", detail);
+                assert_eq!(*time, at(240));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(store.lines.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn assembly_leaves_out_stages_whose_part_is_off() {
+        let store = MockStore::default();
+        let pipeline =
+            assemble(&default_stages(), parts(&store, "assembly"), Metrics::new()).unwrap();
+        assert_eq!(
+            pipeline.names(),
+            [
+                "dedup",
+                "decode",
+                "validate",
+                "seq_dedup",
+                "unbatch",
+                "config_drift",
+                "log",
+                "maintenance",
+                "influx_write",
+                "freshness",
+                "device_config",
+                "device_diagnostics",
+                "device_events",
+                "device_info",
+                "device_status"
+            ]
+        );
+        assert!(pipeline.restores());
+
+        let names = ["decode".to_string(), "influx_write".to_string()];
+        let pipeline = assemble(&names, parts(&store, "assembly"), Metrics::new()).unwrap();
+        assert_eq!(pipeline.names(), ["decode", "influx_write"]);
+        assert!(!pipeline.restores());
+
+        let twice = ["decode".to_string(), "decode".to_string()];
+        assert!(assemble(&twice, parts(&store, "assembly"), Metrics::new()).is_err());
+        let unknown = ["decode".to_string(), "enrich".to_string()];
+        let error = assemble(&unknown, parts(&store, "assembly"), Metrics::new())
+            .err()
+            .unwrap();
+        assert!(error.contains("'enrich'"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn follower_keeps_freshness_without_writing() {
+        let store = MockStore::default();
+        let mut parts = parts(&store, "follower");
+        let last_seen = LastSeen::new();
+        parts.last_seen = Some(last_seen.clone());
+        let mut pipeline =
+            assemble(&follower_stages(&default_stages()), parts, Metrics::new()).unwrap();
+        assert_eq!(
+            pipeline.names(),
+            [
+                "dedup",
+                "decode",
+                "validate",
+                "seq_dedup",
+                "unbatch",
+                "log",
+                "maintenance",
+                "freshness"
+            ]
+        );
+        assert!(pipeline.restores());
+
+        pipeline
+            .feed(publish(&measurement("kitchen", 600), false, 0))
+            .await;
+        assert!(store.lines.borrow().is_empty());
+        assert!(last_seen.snapshot().contains_key("kitchen"));
+    }
+
+    #[tokio::test]
+    async fn replays_a_session_through_every_stage() {
+        let store = MockStore::default();
+        let last_seen = LastSeen::new();
+        let metrics = Metrics::new();
+        let mut pipeline = assemble(
+            &default_stages(),
+            Parts {
+                last_seen: Some(last_seen.clone()),
+                ..parts(&store, "session")
+            },
+            metrics.clone(),
+        )
+        .unwrap();
+
+        let first = measurement("kitchen", 600);
+        let mut newer = measurement("kitchen", 610);
+        newer.version = shared_types::CURRENT_PROTOCOL_VERSION + 1;
+        let config = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::Config(shared_types::device_config::DeviceConfig {
+                firmware_version: "0.3.0".to_string(),
+                sleep_seconds: 300,
+                quiet_hours: None,
+                utc_offset_hours: 0,
+                sensor_mode: shared_types::device_config::SensorMode::Periodic,
+                temperature_offset: None,
+                altitude_m: None,
+                ambient_pressure_hpa: None,
+                asc_enabled: None,
+                alarm_threshold_ppm: None,
+                mqtt_policy: String::new(),
+                wifi_ssid: String::new(),
+                safe_mode: false,
+            }),
+        );
+        let script = [
+            // Retained on subscribing, then again after a reconnect
+            publish(&first, true, 0),
+            publish(&first, true, 30),
+            publish(&newer, false, 40),
+            Event::Publish {
+                topic: TOPIC.to_string(),
+                payload: b"{\"device\":".to_vec(),
+                retained: false,
+                received: at(50),
+            },
+            publish(&config, false, 60),
+            publish(&measurement("bedroom", 700), false, 300),
+        ];
+        for event in script {
+            pipeline.feed(event).await;
+        }
+        let out = pipeline.feed(Event::Shutdown).await;
+        assert!(matches!(out.as_slice(), [Event::Shutdown]));
+
+        assert_eq!(
+            store.measurements(),
+            [
+                "scd40_data,device=kitchen co2_ppm=600,temperature_c=21.5,humidity_percent=40 1736942400000000000",
+                "scd40_data,device=bedroom co2_ppm=700,temperature_c=21.5,humidity_percent=40 1736942700000000000"
+            ]
+        );
+        assert_eq!(
+            store
+                .lines
+                .borrow()
+                .iter()
+                .filter(|line| line.starts_with("device_config,"))
+                .count(),
+            1
+        );
+        let seen = last_seen.snapshot();
+        assert_eq!(seen["kitchen"], at(0));
+        assert_eq!(seen["bedroom"], at(300));
+
+        let metrics: BTreeMap<_, _> = metrics.snapshot().into_iter().collect();
+        let counts = |stage: &str| {
+            let m = metrics[stage];
+            (m.processed, m.dropped, m.errored)
+        };
+        assert_eq!(counts("dedup"), (7, 1, 0));
+        assert_eq!(counts("decode"), (6, 0, 1));
+        assert_eq!(counts("validate"), (5, 1, 0));
+        assert_eq!(counts("influx_write"), (4, 0, 0));
+        assert_eq!(counts("device_config"), (4, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn failures_are_counted_against_their_stage() {
+        let store = MockStore::default();
+        store.failing.set(true);
+        let metrics = Metrics::new();
+        let mut pipeline = assemble(
+            &default_stages(),
+            parts(&store, "failures"),
+            metrics.clone(),
+        )
+        .unwrap();
+        let out = pipeline
+            .feed(publish(&measurement("kitchen", 600), false, 0))
+            .await;
+        // The rest of the stages still see the measurement
+        assert!(matches!(out.as_slice(), [Event::Message(_)]));
+        let influx_write = metrics
+            .snapshot()
+            .into_iter()
+            .find(|(stage, _)| *stage == "influx_write")
+            .unwrap()
+            .1;
+        assert_eq!(
+            (
+                influx_write.processed,
+                influx_write.dropped,
+                influx_write.errored
+            ),
+            (1, 0, 1)
+        );
+    }
+
+    #[test]
+    fn failures_are_logged_louder_than_replies() {
+        assert_eq!(
+            payload_level(&DevicePayload::measurement(612, 22.4, 41.3)),
+            Level::Info
+        );
+        assert_eq!(
+            payload_level(&DevicePayload::error("sensor not ready")),
+            Level::Error
+        );
+        let recovery = |recovered| DevicePayload::BusRecovery {
+            attempt: 1,
+            pulses: Some(4),
+            recovered,
+        };
+        assert_eq!(payload_level(&recovery(true)), Level::Warn);
+        assert_eq!(payload_level(&recovery(false)), Level::Error);
+        assert_eq!(
+            payload_level(&DevicePayload::SelfTestResult {
+                passed: false,
+                detail: "malfunction".into(),
+            }),
+            Level::Error
+        );
+        let frc = DeviceCommand::StartFrc { target_ppm: 5000 };
+        assert_eq!(
+            payload_level(&DevicePayload::command_accepted(&frc)),
+            Level::Info
+        );
+        assert_eq!(
+            payload_level(&DevicePayload::command_rejected(&frc, "out of range")),
+            Level::Error
+        );
+    }
+
+    #[test]
+    fn metrics_render_as_openmetrics() {
+        let metrics = Metrics::new();
+        metrics.start(["dedup", "decode"].into_iter());
+        metrics.add(
+            1,
+            &StageMetrics {
+                processed: 3,
+                dropped: 1,
+                errored: 2,
+                duration: Duration::from_millis(250),
+            },
+        );
+        let mut out = String::new();
+        metrics.write_openmetrics(&mut out);
+        assert!(out.contains(
+            "air_quality_ingest_stage_events_total{stage=\"dedup\",outcome=\"processed\"} 0\n"
+        ));
+        assert!(out.contains(
+            "air_quality_ingest_stage_events_total{stage=\"decode\",outcome=\"errored\"} 2\n"
+        ));
+        assert!(
+            out.contains(
+                "air_quality_ingest_stage_duration_seconds_total{stage=\"decode\"} 0.25\n"
+            )
+        );
+        assert!(out.starts_with("# TYPE air_quality_ingest_stage_events counter\n"));
+    }
+}
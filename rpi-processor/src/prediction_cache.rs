@@ -0,0 +1,339 @@
+//! Sharing prediction work between dashboard clients.
+//!
+//! Every `/api/predict` request trains its own models, and dashboards open
+//! at the same time ask for the same prediction at the same moment.
+//! Requests for the same input time, to the minute, share one computation
+//! while it runs, and its result answers the same request for `ttl`
+//! afterwards unless a newer measurement has arrived since. A failure is
+//! handed to the requests that waited for it but not kept.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::sync::OnceCell;
+
+pub const DEFAULT_TTL_SECONDS: i64 = 60;
+const BUCKET_SECONDS: i64 = 60;
+const METRIC: &str = "prediction_requests";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PredictionKey {
+    bucket: i64,
+}
+
+impl PredictionKey {
+    pub fn new(input_time: DateTime<Utc>) -> Self {
+        Self {
+            bucket: input_time.timestamp().div_euclid(BUCKET_SECONDS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Computed,
+    /// Waited for a computation another request started
+    Coalesced,
+    CacheHit,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Computed => "computed",
+            Outcome::Coalesced => "coalesced",
+            Outcome::CacheHit => "cache_hit",
+        }
+    }
+}
+
+struct Slot<T> {
+    /// The newest measurement when the computation started
+    newest: Option<String>,
+    started: DateTime<Utc>,
+    result: OnceCell<Result<T, String>>,
+}
+
+pub struct PredictionCache<T> {
+    ttl: Duration,
+    slots: Mutex<HashMap<PredictionKey, Arc<Slot<T>>>>,
+    /// Indexed like `Outcome`
+    counts: [AtomicU64; 3],
+}
+
+impl<T: Clone> PredictionCache<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+            counts: Default::default(),
+        }
+    }
+
+    /// The result for `key`, from a running or recent computation when
+    /// there is one for the same `newest` measurement, otherwise from
+    /// `compute`.
+    pub async fn get_or_compute<F, Fut>(
+        &self,
+        key: PredictionKey,
+        newest: Option<String>,
+        now: DateTime<Utc>,
+        compute: F,
+    ) -> (Result<T, String>, Outcome)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, String>>,
+    {
+        let (slot, outcome) = {
+            let mut slots = self.slots.lock().unwrap();
+            // Failed and expired results go; running computations stay
+            slots.retain(|_, slot| match slot.result.get() {
+                None => true,
+                Some(Ok(_)) => now - slot.started <= self.ttl,
+                Some(Err(_)) => false,
+            });
+            match slots.get(&key) {
+                Some(slot) if slot.newest == newest => {
+                    let outcome = if slot.result.initialized() {
+                        Outcome::CacheHit
+                    } else {
+                        Outcome::Coalesced
+                    };
+                    (slot.clone(), outcome)
+                }
+                _ => {
+                    let slot = Arc::new(Slot {
+                        newest,
+                        started: now,
+                        result: OnceCell::new(),
+                    });
+                    slots.insert(key, slot.clone());
+                    (slot, Outcome::Computed)
+                }
+            }
+        };
+        self.counts[outcome as usize].fetch_add(1, Ordering::Relaxed);
+        // If the request computing it goes away, a waiting one takes over
+        let result = slot.result.get_or_init(compute).await.clone();
+        (result, outcome)
+    }
+
+    pub fn count(&self, outcome: Outcome) -> u64 {
+        self.counts[outcome as usize].load(Ordering::Relaxed)
+    }
+
+    /// The request counters, without the closing `# EOF`
+    pub fn write_openmetrics(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} counter", METRIC);
+        let _ = writeln!(
+            out,
+            "# HELP {} Prediction requests by how they were answered.",
+            METRIC
+        );
+        for outcome in [Outcome::Computed, Outcome::Coalesced, Outcome::CacheHit] {
+            let _ = writeln!(
+                out,
+                "{}_total{{outcome=\"{}\"}} {}",
+                METRIC,
+                outcome.as_str(),
+                self.count(outcome)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::sync::Notify;
+
+    /// 2025-01-15 12:00 UTC plus `seconds`
+    fn newest(time: &str) -> Option<String> {
+        Some(time.to_string())
+    }
+
+    /// Yields until `done`, so spawned requests get to register
+    async fn until(done: impl Fn() -> bool) {
+        while !done() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_computation() {
+        let cache = Arc::new(PredictionCache::new(Duration::seconds(60)));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let key = PredictionKey::new(at(0));
+
+        let requests: Vec<_> = (0..5)
+            .map(|i| {
+                let (cache, runs, release) = (cache.clone(), runs.clone(), release.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_or_compute(key, newest("11:55"), at(i), || async move {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            // A slow training run
+                            release.notified().await;
+                            Ok(42 + i as u32)
+                        })
+                        .await
+                })
+            })
+            .collect();
+        until(|| cache.count(Outcome::Computed) + cache.count(Outcome::Coalesced) == 5).await;
+        release.notify_one();
+
+        let mut results = Vec::new();
+        for request in requests {
+            results.push(request.await.unwrap());
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let value = results[0].0.clone();
+        assert!(results.iter().all(|(result, _)| *result == value));
+        assert_eq!(cache.count(Outcome::Computed), 1);
+        assert_eq!(cache.count(Outcome::Coalesced), 4);
+    }
+
+    #[tokio::test]
+    async fn results_are_cached_until_the_ttl_or_a_new_measurement() {
+        let cache = PredictionCache::new(Duration::seconds(60));
+        let runs = AtomicUsize::new(0);
+        let compute = |value: u32| {
+            let runs = &runs;
+            move || async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok(value)
+            }
+        };
+        let key = PredictionKey::new(at(0));
+
+        assert_eq!(
+            cache
+                .get_or_compute(key, newest("11:55"), at(0), compute(1))
+                .await,
+            (Ok(1), Outcome::Computed)
+        );
+        // Same minute, same data
+        let same = PredictionKey::new(at(59));
+        assert_eq!(same, key);
+        assert_eq!(
+            cache
+                .get_or_compute(same, newest("11:55"), at(60), compute(2))
+                .await,
+            (Ok(1), Outcome::CacheHit)
+        );
+        // A new measurement arrived
+        assert_eq!(
+            cache
+                .get_or_compute(key, newest("12:00"), at(61), compute(3))
+                .await,
+            (Ok(3), Outcome::Computed)
+        );
+        // Past the TTL
+        assert_eq!(
+            cache
+                .get_or_compute(key, newest("12:00"), at(122), compute(4))
+                .await,
+            (Ok(4), Outcome::Computed)
+        );
+        // Another minute is another prediction
+        assert_eq!(
+            cache
+                .get_or_compute(
+                    PredictionKey::new(at(180)),
+                    newest("12:00"),
+                    at(123),
+                    compute(5)
+                )
+                .await,
+            (Ok(5), Outcome::Computed)
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        assert_eq!(cache.count(Outcome::CacheHit), 1);
+    }
+
+    #[tokio::test]
+    async fn failures_reach_the_waiters_but_are_not_cached() {
+        let cache = Arc::new(PredictionCache::new(Duration::seconds(60)));
+        let release = Arc::new(Notify::new());
+        let key = PredictionKey::new(at(0));
+
+        let requests: Vec<_> = (0..3)
+            .map(|i| {
+                let (cache, release) = (cache.clone(), release.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_or_compute(key, newest("11:55"), at(i), || async move {
+                            release.notified().await;
+                            Err::<u32, _>("Training data not loaded yet".to_string())
+                        })
+                        .await
+                })
+            })
+            .collect();
+        until(|| cache.count(Outcome::Computed) + cache.count(Outcome::Coalesced) == 3).await;
+        release.notify_one();
+        for request in requests {
+            assert!(request.await.unwrap().0.is_err());
+        }
+        assert_eq!(cache.count(Outcome::Coalesced), 2);
+
+        let retried = cache
+            .get_or_compute(key, newest("11:55"), at(5), || async { Ok(7) })
+            .await;
+        assert_eq!(retried, (Ok(7), Outcome::Computed));
+    }
+
+    #[tokio::test]
+    async fn a_waiter_takes_over_when_the_computing_request_goes_away() {
+        let cache = Arc::new(PredictionCache::new(Duration::seconds(60)));
+        let key = PredictionKey::new(at(0));
+
+        let leader = {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_compute(key, newest("11:55"), at(0), || {
+                        std::future::pending::<Result<u32, String>>()
+                    })
+                    .await
+            })
+        };
+        until(|| cache.count(Outcome::Computed) == 1).await;
+        let waiter = {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_compute(key, newest("11:55"), at(1), || async { Ok(9) })
+                    .await
+            })
+        };
+        until(|| cache.count(Outcome::Coalesced) == 1).await;
+        // The client that started it disconnected
+        leader.abort();
+        assert_eq!(waiter.await.unwrap(), (Ok(9), Outcome::Coalesced));
+    }
+
+    #[test]
+    fn counters_render_as_openmetrics() {
+        let cache = PredictionCache::<u32>::new(Duration::seconds(60));
+        cache.counts[Outcome::CacheHit as usize].store(3, Ordering::Relaxed);
+        let mut out = String::new();
+        cache.write_openmetrics(&mut out);
+        assert_eq!(
+            out,
+            "# TYPE prediction_requests counter\n\
+             # HELP prediction_requests Prediction requests by how they were answered.\n\
+             prediction_requests_total{outcome=\"computed\"} 0\n\
+             prediction_requests_total{outcome=\"coalesced\"} 0\n\
+             prediction_requests_total{outcome=\"cache_hit\"} 3\n"
+        );
+    }
+}
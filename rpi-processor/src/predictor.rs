@@ -1,4 +1,5 @@
-use crate::fetcher::fetch_measurement_at;
+use crate::external_events::EventFeature;
+use crate::fetcher::{Identifier, Sql, fetch_measurement_at, query_rows};
 use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
 use chrono::{DateTime, Datelike, Timelike, Utc};
 use smartcore::linalg::basic::matrix::DenseMatrix;
@@ -6,16 +7,348 @@ use smartcore::xgboost::{
     XGRegressor as GradientBoostingRegressor,
     XGRegressorParameters as GradientBoostingRegressorParameters,
 };
-use std::collections::HashSet;
 use std::error::Error;
 
+type Regressor = GradientBoostingRegressor<f64, f64, DenseMatrix<f64>, Vec<f64>>;
+
+/// The +1 hour prediction made from the latest measurement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forecast {
+    pub based_on: DateTime<Utc>,
+    pub target_time: DateTime<Utc>,
+    pub co2: f64,
+    pub temperature: f64,
+    pub humidity: f64,
+}
+
+/// Mean absolute errors of a model's +1 hour predictions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Evaluation {
+    pub co2: f64,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub samples: usize,
+}
+
+/// Training or evaluation rows: the features at each time and what was
+/// measured an hour later.
+// Features: [Hour, Minute, Weekday, Current_CO2, Delta_15m_CO2, Delta_1h_CO2, Delta_3h_CO2, Current_Temp, Delta_15m_Temp, Delta_1h_Temp, Delta_3h_Temp, Current_Humidity, Delta_15m_Humidity, Delta_1h_Humidity, Delta_3h_Humidity]
+// and, with event kinds set, External_Event_In_Progress
+// Targets: [Future_CO2, Future_Temp, Future_Humidity] (1 hour later)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Samples {
+    pub times: Vec<DateTime<Utc>>,
+    pub features: Vec<Vec<f64>>,
+    pub co2: Vec<f64>,
+    pub temperature: Vec<f64>,
+    pub humidity: Vec<f64>,
+}
+
+impl Samples {
+    /// Every measurement with a full 3 h history and a measurement an hour
+    /// later; `measurements` must be in time order.
+    pub fn build(
+        measurements: &[MeasurementWithTime],
+        event_feature: Option<&EventFeature>,
+    ) -> Self {
+        let mut samples = Self::default();
+
+        // Find triplets (t-3h, t-1h, t-15m, t, t+1h)
+        for (i, m_current) in measurements.iter().enumerate() {
+            // 1. Find Future Target (t + 1h)
+            let target_time = m_current.time + chrono::Duration::hours(1);
+            let mut m_future_opt = None;
+
+            // Look forward
+            for m_next in measurements.iter().skip(i + 1) {
+                let diff = m_next.time.signed_duration_since(target_time);
+                if diff.num_minutes().abs() <= 5 {
+                    m_future_opt = Some(m_next);
+                    break;
+                } else if diff.num_minutes() > 5 {
+                    break;
+                }
+            }
+
+            if let Some(m_future) = m_future_opt
+                && let Some(features) = features_at(measurements, i, m_current.time, event_feature)
+            {
+                samples.times.push(m_current.time);
+                samples.features.push(features);
+                samples.co2.push(m_future.co2 as f64);
+                samples.temperature.push(m_future.temperature as f64);
+                samples.humidity.push(m_future.humidity as f64);
+            }
+        }
+        samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Only the rows for times after `from`
+    pub fn after(&self, from: DateTime<Utc>) -> Self {
+        let mut kept = Self::default();
+        for i in (0..self.len()).filter(|&i| self.times[i] > from) {
+            kept.times.push(self.times[i]);
+            kept.features.push(self.features[i].clone());
+            kept.co2.push(self.co2[i]);
+            kept.temperature.push(self.temperature[i]);
+            kept.humidity.push(self.humidity[i]);
+        }
+        kept
+    }
+}
+
+/// Helper to find past measurement
+fn find_past(
+    measurements: &[MeasurementWithTime],
+    target_time: DateTime<Utc>,
+    current_idx: usize,
+) -> Option<&MeasurementWithTime> {
+    let start_search = current_idx.saturating_sub(400);
+    for j in (start_search..current_idx).rev() {
+        let m = &measurements[j];
+        let diff = target_time
+            .signed_duration_since(m.time)
+            .num_minutes()
+            .abs();
+        if diff <= 10 {
+            return Some(m);
+        }
+        if m.time < target_time - chrono::Duration::minutes(20) {
+            return None;
+        }
+    }
+    None
+}
+
+/// The features of `measurements[i]`, with the time columns taken from
+/// `clock`. `None` without the 15 min, 1 h and 3 h history.
+fn features_at(
+    measurements: &[MeasurementWithTime],
+    i: usize,
+    clock: DateTime<Utc>,
+    event_feature: Option<&EventFeature>,
+) -> Option<Vec<f64>> {
+    let current = &measurements[i];
+    let m_15m = find_past(
+        measurements,
+        current.time - chrono::Duration::minutes(15),
+        i,
+    )?;
+    let m_1h = find_past(measurements, current.time - chrono::Duration::hours(1), i)?;
+    let m_3h = find_past(measurements, current.time - chrono::Duration::hours(3), i)?;
+
+    let mut features = vec![
+        clock.hour() as f64,
+        clock.minute() as f64,
+        clock.weekday().num_days_from_monday() as f64,
+        current.co2 as f64,
+        current.co2 as f64 - m_15m.co2 as f64,
+        current.co2 as f64 - m_1h.co2 as f64,
+        current.co2 as f64 - m_3h.co2 as f64,
+        current.temperature as f64,
+        current.temperature as f64 - m_15m.temperature as f64,
+        current.temperature as f64 - m_1h.temperature as f64,
+        current.temperature as f64 - m_3h.temperature as f64,
+        current.humidity as f64,
+        current.humidity as f64 - m_15m.humidity as f64,
+        current.humidity as f64 - m_1h.humidity as f64,
+        current.humidity as f64 - m_3h.humidity as f64,
+    ];
+    if let Some(event_feature) = event_feature {
+        features.push(event_feature.value(&current.device, current.time));
+    }
+    Some(features)
+}
+
+fn matrix(rows: &[Vec<f64>]) -> Result<DenseMatrix<f64>, Box<dyn Error>> {
+    DenseMatrix::from_2d_vec(&rows.to_vec()).map_err(|e| Box::new(e) as Box<dyn Error>)
+}
+
+fn with_column(rows: &[Vec<f64>], column: &[f64]) -> Vec<Vec<f64>> {
+    rows.iter()
+        .zip(column)
+        .map(|(row, value)| {
+            let mut row = row.clone();
+            row.push(*value);
+            row
+        })
+        .collect()
+}
+
+fn mean_absolute_error(predicted: &[f64], actual: &[f64]) -> f64 {
+    let total: f64 = predicted
+        .iter()
+        .zip(actual)
+        .map(|(predicted, actual)| (predicted - actual).abs())
+        .sum();
+    total / actual.len() as f64
+}
+
+/// One value per input row
+struct Predictions {
+    co2: Vec<f64>,
+    temperature: Vec<f64>,
+    humidity: Vec<f64>,
+}
+
+/// The three chained gradient boosting models. Training is deterministic,
+/// so the same samples always give the same model.
+pub struct Model {
+    co2: Regressor,
+    temperature: Regressor,
+    humidity: Regressor,
+}
+
+impl Model {
+    pub fn fit(samples: &Samples) -> Result<Self, Box<dyn Error>> {
+        if samples.is_empty() {
+            return Err("no training samples".into());
+        }
+        // Parameters for the Gradient Boosting Regressor itself
+        let gbm_params = GradientBoostingRegressorParameters::default()
+            .with_n_estimators(150)
+            .with_learning_rate(0.1)
+            .with_max_depth(3);
+
+        // Train CO2 Model
+        log::info!("Training CO2 Gradient Boosting model...");
+        let co2 = GradientBoostingRegressor::fit(
+            &matrix(&samples.features)?,
+            &samples.co2,
+            gbm_params.clone(),
+        )?;
+
+        // Train Temperature Model (using actual future CO2 as feature)
+        log::info!("Training Temperature Gradient Boosting model (chained)...");
+        let x_temp_data = with_column(&samples.features, &samples.co2);
+        let temperature = GradientBoostingRegressor::fit(
+            &matrix(&x_temp_data)?,
+            &samples.temperature,
+            gbm_params.clone(),
+        )?;
+
+        // Train Humidity Model (using actual future CO2 and Temp as features)
+        log::info!("Training Humidity Gradient Boosting model (chained)...");
+        let x_hum_data = with_column(&x_temp_data, &samples.temperature);
+        let humidity =
+            GradientBoostingRegressor::fit(&matrix(&x_hum_data)?, &samples.humidity, gbm_params)?;
+
+        Ok(Self {
+            co2,
+            temperature,
+            humidity,
+        })
+    }
+
+    /// Predicts CO2, temperature and humidity for every row, chaining the
+    /// predicted values rather than the measured ones.
+    fn predict_rows(&self, rows: &[Vec<f64>]) -> Result<Predictions, Box<dyn Error>> {
+        let co2 = self.co2.predict(&matrix(rows)?)?;
+        let rows = with_column(rows, &co2);
+        let temperature = self.temperature.predict(&matrix(&rows)?)?;
+        let rows = with_column(&rows, &temperature);
+        let humidity = self.humidity.predict(&matrix(&rows)?)?;
+        Ok(Predictions {
+            co2,
+            temperature,
+            humidity,
+        })
+    }
+
+    /// Without samples the errors are NaN
+    pub fn evaluate(&self, samples: &Samples) -> Result<Evaluation, Box<dyn Error>> {
+        if samples.is_empty() {
+            return Ok(Evaluation {
+                co2: f64::NAN,
+                temperature: f64::NAN,
+                humidity: f64::NAN,
+                samples: 0,
+            });
+        }
+        let predicted = self.predict_rows(&samples.features)?;
+        Ok(Evaluation {
+            co2: mean_absolute_error(&predicted.co2, &samples.co2),
+            temperature: mean_absolute_error(&predicted.temperature, &samples.temperature),
+            humidity: mean_absolute_error(&predicted.humidity, &samples.humidity),
+            samples: samples.len(),
+        })
+    }
+}
+
+/// Measurements up to `end_time` that are fit to train on, in time order:
+/// anomalies and maintenance windows are left out.
+pub async fn fetch_clean_measurements(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    end_time: Option<DateTime<Utc>>,
+) -> Result<Vec<MeasurementWithTime>, Box<dyn Error>> {
+    let mut measurements = fetch_training_data(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        end_time,
+    )
+    .await?;
+    if measurements.is_empty() {
+        return Ok(measurements);
+    }
+
+    // Fetch anomalies to filter
+    let anomalies = crate::anomaly_review::fetch_training_exclusions(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+    log::info!("Fetched {} anomalies for filtering", anomalies.len());
+
+    let maintenance = crate::maintenance::fetch_maintenance_times(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+
+    // Filter out anomalies and measurements taken during maintenance
+    let initial_len = measurements.len();
+    measurements.retain(|m| !anomalies.contains(&m.time) && !maintenance.contains(&m.time));
+    log::info!(
+        "Filtered {} anomalous or maintenance measurements. Remaining: {}",
+        initial_len - measurements.len(),
+        measurements.len()
+    );
+
+    // Sort by time ascending for time series processing
+    measurements.sort_by_key(|m| m.time);
+    Ok(measurements)
+}
+
+/// Predicts an hour past the latest measurement, or past
+/// `prediction_timestamp` when given. The models learn from the data up to
+/// that time, or only up to `training_until` when that is earlier: the
+/// promoted model's cutoff, see `model_training`.
 pub async fn predict_weather(
     influx_host: &str,
     influx_token: &str,
     influx_database: &str,
     reqwest_client: &reqwest::Client,
     prediction_timestamp_str: Option<String>,
-) -> Result<(), Box<dyn Error>> {
+    training_until: Option<DateTime<Utc>>,
+    event_kinds: &[Identifier],
+) -> Result<Option<Forecast>, Box<dyn Error>> {
     log::info!("Starting weather prediction...");
 
     let prediction_timestamp = if let Some(ts_str) = prediction_timestamp_str {
@@ -32,7 +365,7 @@ pub async fn predict_weather(
     };
 
     // 1. Fetch historical data
-    let mut measurements = fetch_training_data(
+    let measurements = fetch_clean_measurements(
         influx_host,
         influx_token,
         influx_database,
@@ -43,244 +376,91 @@ pub async fn predict_weather(
 
     if measurements.is_empty() {
         log::warn!("No data found for training.");
-        return Ok(());
+        return Ok(None);
     }
 
-    // Fetch anomalies to filter
-    let anomalies =
-        fetch_anomalies(influx_host, influx_token, influx_database, reqwest_client).await?;
-    log::info!("Fetched {} anomalies for filtering", anomalies.len());
-
-    // Filter out anomalies
-    let initial_len = measurements.len();
-    measurements.retain(|m| !anomalies.contains(&m.time));
-    log::info!(
-        "Filtered {} anomalous measurements. Remaining: {}",
-        initial_len - measurements.len(),
-        measurements.len()
-    );
+    let training_data = match training_until {
+        Some(until) if prediction_timestamp.is_none_or(|ts| until < ts) => {
+            log::info!("Training on data up to {}", until.to_rfc3339());
+            fetch_clean_measurements(
+                influx_host,
+                influx_token,
+                influx_database,
+                reqwest_client,
+                Some(until),
+            )
+            .await?
+        }
+        _ => measurements.clone(),
+    };
 
-    if measurements.len() < 100 {
+    if training_data.len() < 100 {
         log::warn!("Not enough data after filtering for training.");
-        return Ok(());
+        return Ok(None);
     }
 
-    // Sort by time ascending for time series processing
-    measurements.sort_by_key(|m| m.time);
-
-    // Parameters for the Gradient Boosting Regressor itself
-    let gbm_params = GradientBoostingRegressorParameters::default()
-        .with_n_estimators(150)
-        .with_learning_rate(0.1)
-        .with_max_depth(3);
+    let event_feature = EventFeature::fetch(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        event_kinds,
+    )
+    .await;
 
     // 2. Prepare data
-    // Features: [Hour, Minute, Weekday, Current_CO2, Delta_15m_CO2, Delta_1h_CO2, Delta_3h_CO2, Current_Temp, Delta_15m_Temp, Delta_1h_Temp, Delta_3h_Temp, Current_Humidity, Delta_15m_Humidity, Delta_1h_Humidity, Delta_3h_Humidity]
-    // Targets: [Future_CO2, Future_Temp, Future_Humidity] (1 hour later)
-
-    let mut x_base_data = Vec::new();
-    let mut y_co2 = Vec::new();
-    let mut y_temp = Vec::new();
-    let mut y_humidity = Vec::new();
-
-    // Helper to find past measurement
-    let find_past =
-        |target_time: DateTime<Utc>, current_idx: usize| -> Option<&MeasurementWithTime> {
-            let start_search = if current_idx > 400 {
-                current_idx - 400
-            } else {
-                0
-            };
-            for j in (start_search..current_idx).rev() {
-                let m = &measurements[j];
-                let diff = target_time
-                    .signed_duration_since(m.time)
-                    .num_minutes()
-                    .abs();
-                if diff <= 10 {
-                    return Some(m);
-                }
-                if m.time < target_time - chrono::Duration::minutes(20) {
-                    return None;
-                }
-            }
-            None
-        };
-
-    // Find triplets (t-3h, t-1h, t-15m, t, t+1h)
-    for (i, m_current) in measurements.iter().enumerate() {
-        // 1. Find Future Target (t + 1h)
-        let target_time = m_current.time + chrono::Duration::hours(1);
-        let mut m_future_opt = None;
-
-        // Look forward
-        for m_next in measurements.iter().skip(i + 1) {
-            let diff = m_next.time.signed_duration_since(target_time);
-            if diff.num_minutes().abs() <= 5 {
-                m_future_opt = Some(m_next);
-                break;
-            } else if diff.num_minutes() > 5 {
-                break;
-            }
-        }
-
-        if let Some(m_future) = m_future_opt {
-            // Find historical context
-            let m_15m = find_past(m_current.time - chrono::Duration::minutes(15), i);
-            let m_1h = find_past(m_current.time - chrono::Duration::hours(1), i);
-            let m_3h = find_past(m_current.time - chrono::Duration::hours(3), i);
-
-            if let (Some(m_15m), Some(m_1h), Some(m_3h)) = (m_15m, m_1h, m_3h) {
-                let hour = m_current.time.hour() as f64;
-                let minute = m_current.time.minute() as f64;
-                let weekday = m_current.time.weekday().num_days_from_monday() as f64;
-
-                x_base_data.push(vec![
-                    hour,
-                    minute,
-                    weekday,
-                    m_current.co2 as f64,
-                    m_current.co2 as f64 - m_15m.co2 as f64,
-                    m_current.co2 as f64 - m_1h.co2 as f64,
-                    m_current.co2 as f64 - m_3h.co2 as f64,
-                    m_current.temperature as f64,
-                    m_current.temperature as f64 - m_15m.temperature as f64,
-                    m_current.temperature as f64 - m_1h.temperature as f64,
-                    m_current.temperature as f64 - m_3h.temperature as f64,
-                    m_current.humidity as f64,
-                    m_current.humidity as f64 - m_15m.humidity as f64,
-                    m_current.humidity as f64 - m_1h.humidity as f64,
-                    m_current.humidity as f64 - m_3h.humidity as f64,
-                ]);
-
-                y_co2.push(m_future.co2 as f64);
-                y_temp.push(m_future.temperature as f64);
-                y_humidity.push(m_future.humidity as f64);
-            }
-        }
-    }
+    let samples = Samples::build(&training_data, event_feature.as_ref());
 
     log::info!(
         "Created {} training samples with full 3h context",
-        x_base_data.len()
+        samples.len()
     );
-    if x_base_data.is_empty() {
+    if samples.is_empty() {
         log::warn!("No training samples found (maybe gaps in data).");
-        return Ok(());
+        return Ok(None);
     }
 
     // 3. Train models (Chained Gradient Boosting)
-
-    // Train CO2 Model
-    log::info!("Training CO2 Gradient Boosting model...");
-    let x_co2_mat =
-        DenseMatrix::from_2d_vec(&x_base_data).map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let model_co2 = GradientBoostingRegressor::fit(&x_co2_mat, &y_co2, gbm_params.clone())?;
-
-    // Train Temperature Model (using actual future CO2 as feature)
-    log::info!("Training Temperature Gradient Boosting model (chained)...");
-    let mut x_temp_data = x_base_data.clone();
-    for (i, row) in x_temp_data.iter_mut().enumerate() {
-        row.push(y_co2[i]);
-    }
-    let x_temp_mat =
-        DenseMatrix::from_2d_vec(&x_temp_data).map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let model_temp = GradientBoostingRegressor::fit(&x_temp_mat, &y_temp, gbm_params.clone())?;
-
-    // Train Humidity Model (using actual future CO2 and Temp as features)
-    log::info!("Training Humidity Gradient Boosting model (chained)...");
-    let mut x_hum_data = x_temp_data.clone();
-    for (i, row) in x_hum_data.iter_mut().enumerate() {
-        row.push(y_temp[i]);
-    }
-    let x_hum_mat =
-        DenseMatrix::from_2d_vec(&x_hum_data).map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let model_humidity =
-        GradientBoostingRegressor::fit(&x_hum_mat, &y_humidity, gbm_params.clone())?;
+    let model = Model::fit(&samples)?;
 
     // 4. Predict for next hour using LATEST measurement
     // We need the latest measurement AND measurements from 15m, 1h, 3h ago.
 
     let latest_measurement = measurements.last().ok_or("No measurements available")?;
     let latest_idx = measurements.len() - 1;
+    let target_time = latest_measurement.time + chrono::Duration::hours(1);
 
-    // Find historical context for prediction
-    let p15 = find_past(
-        latest_measurement.time - chrono::Duration::minutes(15),
-        latest_idx,
-    );
-    let p1h = find_past(
-        latest_measurement.time - chrono::Duration::hours(1),
-        latest_idx,
-    );
-    let p3h = find_past(
-        latest_measurement.time - chrono::Duration::hours(3),
+    // Construct base input vector
+    let Some(input_vec) = features_at(
+        &measurements,
         latest_idx,
-    );
-
-    if p15.is_none() || p1h.is_none() || p3h.is_none() {
+        target_time,
+        event_feature.as_ref(),
+    ) else {
         log::warn!(
             "Could not find full historical context (15m, 1h, 3h) for latest measurement. Cannot predict."
         );
-        return Ok(());
-    }
-    let (p15, p1h, p3h) = (p15.unwrap(), p1h.unwrap(), p3h.unwrap());
+        return Ok(None);
+    };
 
     // If we are in "live" mode (no prediction_timestamp), check if data is recent
-    if prediction_timestamp.is_none() {
-        if Utc::now()
+    if prediction_timestamp.is_none()
+        && Utc::now()
             .signed_duration_since(latest_measurement.time)
             .num_minutes()
             > 30
-        {
-            log::warn!(
-                "Latest measurement is too old ({}), skipping prediction.",
-                latest_measurement.time
-            );
-            return Ok(());
-        }
+    {
+        log::warn!(
+            "Latest measurement is too old ({}), skipping prediction.",
+            latest_measurement.time
+        );
+        return Ok(None);
     }
 
-    let target_time = latest_measurement.time + chrono::Duration::hours(1);
-    let pred_hour = target_time.hour() as f64;
-    let pred_minute = target_time.minute() as f64;
-    let pred_weekday = target_time.weekday().num_days_from_monday() as f64;
-
-    // Construct base input vector
-    let mut input_vec = vec![
-        pred_hour,
-        pred_minute,
-        pred_weekday,
-        latest_measurement.co2 as f64,
-        latest_measurement.co2 as f64 - p15.co2 as f64,
-        latest_measurement.co2 as f64 - p1h.co2 as f64,
-        latest_measurement.co2 as f64 - p3h.co2 as f64,
-        latest_measurement.temperature as f64,
-        latest_measurement.temperature as f64 - p15.temperature as f64,
-        latest_measurement.temperature as f64 - p1h.temperature as f64,
-        latest_measurement.temperature as f64 - p3h.temperature as f64,
-        latest_measurement.humidity as f64,
-        latest_measurement.humidity as f64 - p15.humidity as f64,
-        latest_measurement.humidity as f64 - p1h.humidity as f64,
-        latest_measurement.humidity as f64 - p3h.humidity as f64,
-    ];
-
-    // Predict CO2
-    let x_pred_co2 = DenseMatrix::from_2d_vec(&vec![input_vec.clone()])
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let pred_co2_val = model_co2.predict(&x_pred_co2)?[0];
-
-    // Predict Temperature (chaining CO2)
-    input_vec.push(pred_co2_val);
-    let x_pred_temp = DenseMatrix::from_2d_vec(&vec![input_vec.clone()])
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let pred_temp_val = model_temp.predict(&x_pred_temp)?[0];
-
-    // Predict Humidity (chaining CO2 and Temp)
-    input_vec.push(pred_temp_val);
-    let x_pred_hum = DenseMatrix::from_2d_vec(&vec![input_vec.clone()])
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
-    let pred_humidity_val = model_humidity.predict(&x_pred_hum)?[0];
+    let predicted = model.predict_rows(&[input_vec])?;
+    let pred_co2_val = predicted.co2[0];
+    let pred_temp_val = predicted.temperature[0];
+    let pred_humidity_val = predicted.humidity[0];
 
     log::info!(
         "Input conditions at {}: CO2: {} ppm, Temp: {:.2} °C, Humidity: {:.2} %",
@@ -330,7 +510,13 @@ pub async fn predict_weather(
         }
     }
 
-    Ok(())
+    Ok(Some(Forecast {
+        based_on: latest_measurement.time,
+        target_time,
+        co2: pred_co2_val,
+        temperature: pred_temp_val,
+        humidity: pred_humidity_val,
+    }))
 }
 
 async fn fetch_training_data(
@@ -340,50 +526,19 @@ async fn fetch_training_data(
     reqwest_client: &reqwest::Client,
     end_time: Option<DateTime<Utc>>,
 ) -> Result<Vec<MeasurementWithTime>, Box<dyn Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-
-    let time_filter = if let Some(et) = end_time {
-        format!("WHERE time <= '{}'", et.to_rfc3339())
-    } else {
-        "".to_string()
-    };
-
-    let sql_query = format!(
-        r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent,
-            device
-        FROM scd40_data
-        {}
-        ORDER BY time DESC
-        LIMIT 10000
-    "#,
-        time_filter
-    );
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        return Err(format!("InfluxDB query failed: {}", response.status()).into());
-    }
-
-    let response_text = response.text().await?;
-    if response_text.is_empty() {
-        return Ok(Vec::new());
+    let mut sql =
+        Sql::new("SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data");
+    if let Some(end_time) = end_time {
+        sql = sql.push(" WHERE time <= ").time(end_time);
     }
-
-    let influx_rows: Vec<InfluxMeasurementRow> = serde_json::from_str(&response_text)?;
+    let influx_rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql.push(" ORDER BY time DESC LIMIT 10000"),
+    )
+    .await?;
 
     let mut measurements = Vec::with_capacity(influx_rows.len());
     for row in influx_rows {
@@ -394,59 +549,3 @@ async fn fetch_training_data(
 
     Ok(measurements)
 }
-
-async fn fetch_anomalies(
-    influx_host: &str,
-    influx_token: &str,
-    influx_database: &str,
-    reqwest_client: &reqwest::Client,
-) -> Result<HashSet<DateTime<Utc>>, Box<dyn Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-    let sql_query = "SELECT time FROM anomalies";
-
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        // If anomalies table doesn't exist or other error, just return empty set?
-        // Better to log and return empty if it's just "table not found" but hard to distinguish.
-        // For now, let's assume if it fails, we have no anomalies to filter.
-        log::warn!(
-            "Failed to fetch anomalies or no anomalies found: {}",
-            response.status()
-        );
-        return Ok(HashSet::new());
-    }
-
-    let response_text = response.text().await?;
-    if response_text.is_empty() {
-        return Ok(HashSet::new());
-    }
-
-    #[derive(serde::Deserialize)]
-    struct AnomalyRow {
-        time: String,
-    }
-
-    let rows: Vec<AnomalyRow> = serde_json::from_str(&response_text).unwrap_or_default();
-    let mut anomalies = HashSet::new();
-    for row in rows {
-        let time_with_timezone = if row.time.ends_with('Z') {
-            row.time
-        } else {
-            format!("{}Z", row.time)
-        };
-        if let Ok(dt) = DateTime::parse_from_rfc3339(&time_with_timezone) {
-            anomalies.insert(dt.with_timezone(&Utc));
-        }
-    }
-    Ok(anomalies)
-}
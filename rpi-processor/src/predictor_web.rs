@@ -1,16 +1,42 @@
+use crate::alerts::{AckError, Alert, AlertStore};
+use crate::anomalies::AnomalyConfig;
+use crate::anomaly_review::{self, AnomalyRecord, ReviewStatus};
+use crate::anomaly_tuning;
+use crate::bulk_write::{InfluxStore, PointStore};
+use crate::command_relay::{RelayHandle, RelayedCommandView};
+use crate::device_config::{self, ConfigSnapshot};
+use crate::disconnects::{self, Disconnects};
+use crate::external_events::{self, EventFeature, EventRequest, ExternalEvent};
+use crate::failover::{LeaderStatus, Role};
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::freshness::{self, LastSeen};
+use crate::hourly::{self, HourlyRow};
+use crate::latency::Latency;
+use crate::maintenance::{MaintenanceStore, Reason};
+use crate::pipeline;
+use crate::prediction_cache::{PredictionCache, PredictionKey};
+use crate::preflight::PreflightReport;
+use crate::stats::{self, DisplayZone, HourMean, Method, Statistic, Variable};
+use crate::storage::{self, StorageConfig, StorageReport};
 use crate::types::InfluxMeasurementRow;
+use crate::ventilation::{self, Recommendation, RoomRegistry, VentilationConfig};
 use axum::{
-    Json, Router,
-    extract::State,
-    http::StatusCode,
+    Extension, Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware,
     response::{Html, IntoResponse, Response},
     routing::{get, post},
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use shared_types::DeviceCommand;
+use shared_types::duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
 
 pub struct AppState {
@@ -20,29 +46,176 @@ pub struct AppState {
     pub reqwest_client: reqwest::Client,
     pub base_path: String,
     pub cached_training_data: Arc<Mutex<Option<Vec<crate::types::MeasurementWithTime>>>>,
+    /// Loaded with the training data when `--predictor-event-kinds` is set
+    pub event_feature: Option<EventFeature>,
+    pub command_relay: Option<RelayHandle>,
+    pub quality_alert_threshold: f64,
+    /// Kept by the receiver when it runs in this process
+    pub last_seen: Option<LastSeen>,
+    /// Likewise, for `/metrics`
+    pub latency: Option<Latency>,
+    pub ingest_metrics: Option<pipeline::Metrics>,
+    /// The receiver's role when it runs as one of a failover pair; only the
+    /// leader takes changes
+    pub failover: Option<LeaderStatus>,
+    pub predictions: PredictionCache<PredictionResponse>,
+    pub maintenance: MaintenanceStore,
+    pub alerts: AlertStore,
+    pub rooms: RoomRegistry,
+    pub ventilation: VentilationConfig,
+    pub storage: StorageConfig,
+    /// Bearer token for changing anything through the API; those endpoints
+    /// refuse every request while it's unset
+    pub api_token: Option<String>,
+    pub disconnects: Arc<Disconnects>,
+    /// Where the heatmap's weekdays and hours are local to
+    pub display_zone: DisplayZone,
+    /// The startup checks, unless `--no-preflight` skipped them
+    pub preflight: Option<PreflightReport>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Fields not requested through `fields` are left out of the JSON.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct AvailableTimestamp {
     pub time: String,
-    pub co2: f64,
-    pub temperature: f64,
-    pub humidity: f64,
-    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// The room the device reported, with `full` for devices that send one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampFields {
+    Time,
+    /// Time and CO2, enough for the dashboard
+    #[default]
+    Co2,
+    Full,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TimestampsQuery {
+    /// Only rows from the last `hours` hours
+    pub hours: Option<u32>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub fields: TimestampFields,
 }
 
+const DEFAULT_TIMESTAMP_LIMIT: usize = 500;
+const MAX_TIMESTAMP_LIMIT: usize = 5000;
+
+#[derive(Deserialize)]
+struct TimestampRow {
+    time: String,
+    co2_ppm: Option<f64>,
+    temperature_c: Option<f64>,
+    humidity_percent: Option<f64>,
+    device: Option<String>,
+    location: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FreshnessFormat {
+    /// `device seconds` per line
+    #[default]
+    Plain,
+    OpenMetrics,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FreshnessQuery {
+    #[serde(default)]
+    pub format: FreshnessFormat,
+}
+
+/// Longer ranges are charted from `scd40_hourly`, which keeps the extremes
+const HOURLY_AFTER: chrono::Duration = chrono::Duration::days(3);
+
 #[derive(Deserialize)]
 pub struct DateRangeRequest {
     pub start_date: String,
     pub end_date: String,
+    /// `raw` or `hourly`; when absent, hourly for ranges over `HOURLY_AFTER`
+    pub resolution: Option<String>,
+}
+
+impl DateRangeRequest {
+    fn hourly(&self) -> Result<bool, AppError> {
+        match self.resolution.as_deref() {
+            Some("raw") => Ok(false),
+            Some("hourly") => Ok(true),
+            Some(other) => Err(AppError::with_status(
+                StatusCode::BAD_REQUEST,
+                format!("unknown resolution '{}', expected raw or hourly", other),
+            )),
+            None => Ok(matches!(
+                (parse_query_time(&self.start_date), parse_query_time(&self.end_date)),
+                (Ok(start), Ok(end)) if end - start > HOURLY_AFTER
+            )),
+        }
+    }
 }
 
+/// On hourly points the values are means and `hourly` has the band around them.
 #[derive(Serialize)]
 pub struct DataPoint {
     pub time: String,
     pub co2: f64,
     pub temperature: f64,
     pub humidity: f64,
+    #[serde(flatten)]
+    pub hourly: Option<HourlyExtremes>,
+}
+
+#[derive(Serialize)]
+pub struct HourlyExtremes {
+    pub co2_min: f64,
+    pub co2_max: f64,
+    pub temperature_min: f64,
+    pub temperature_max: f64,
+    pub humidity_min: f64,
+    pub humidity_max: f64,
+    /// The rest is missing for hours stored before it was recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub co2_max_time: Option<DateTime<Utc>>,
+    /// Keyed by CO2 threshold in ppm
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub minutes_above: BTreeMap<u16, f64>,
+    /// Samples left out as sensor artifacts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excluded: Option<i64>,
+}
+
+/// `None` for hours without statistics, where every sample was excluded
+fn hourly_point(row: HourlyRow) -> Option<DataPoint> {
+    let hourly = HourlyExtremes {
+        co2_min: row.co2_min?,
+        co2_max: row.co2_max?,
+        temperature_min: row.temperature_min?,
+        temperature_max: row.temperature_max?,
+        humidity_min: row.humidity_min?,
+        humidity_max: row.humidity_max?,
+        co2_max_time: row.co2_max_time(),
+        minutes_above: row.minutes_above(),
+        excluded: row.excluded,
+    };
+    Some(DataPoint {
+        co2: row.co2_mean?,
+        temperature: row.temperature_mean?,
+        humidity: row.humidity_mean?,
+        time: row.time,
+        hourly: Some(hourly),
+    })
 }
 
 #[derive(Deserialize)]
@@ -53,12 +226,178 @@ struct SimpleInfluxRow {
     humidity_percent: f64,
 }
 
+#[derive(Serialize)]
+pub struct DeviceSummary {
+    pub device: String,
+    pub last_seen: Option<String>,
+    pub quality: Option<DeviceQuality>,
+    /// Latest quality score is below the configured threshold and the
+    /// device isn't in maintenance
+    pub quality_alert: bool,
+    pub maintenance_until: Option<DateTime<Utc>>,
+    /// Combined from other devices, like `home`; takes no commands
+    #[serde(rename = "virtual")]
+    pub is_virtual: bool,
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceRequest {
+    /// A time in the past ends the window
+    pub until: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MaintenanceView {
+    pub device: String,
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceQuality {
+    #[serde(rename(deserialize = "time"))]
+    pub date: String,
+    pub score: f64,
+    pub completeness: f64,
+    pub anomaly_rate: f64,
+    pub rejection_rate: f64,
+    pub flatline_minutes: f64,
+    pub clock_skew_incidents: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ReferenceCompareQuery {
+    pub device: String,
+    pub source: Option<String>,
+    pub from: String,
+    pub to: String,
+    pub tolerance_seconds: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigHistoryQuery {
+    /// Only snapshots taken at or before this time (RFC3339)
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ResampleQuery {
+    pub device: String,
+    pub from: String,
+    pub to: String,
+    /// Grid spacing such as `300s` or `5m`; 5 minutes when absent
+    pub step: Option<String>,
+    /// `linear` (the default) or `previous`
+    pub method: Option<String>,
+    /// How far the raw points may be from a grid time; one step when absent
+    pub tolerance: Option<String>,
+}
+
+/// Values are null where no raw point was within the tolerance.
+#[derive(Serialize)]
+pub struct ResampledPoint {
+    pub time: DateTime<Utc>,
+    pub co2_ppm: Option<f64>,
+    pub temperature_c: Option<f64>,
+    pub humidity_percent: Option<f64>,
+    /// Not a raw point at exactly this time
+    pub interpolated: bool,
+}
+
+#[derive(Serialize)]
+pub struct ResampledMeasurements {
+    pub device: String,
+    pub method: &'static str,
+    pub step_seconds: i64,
+    pub tolerance_seconds: i64,
+    pub points: Vec<ResampledPoint>,
+}
+
+/// Above this many weeks a heatmap request is refused
+const MAX_HEATMAP_WEEKS: u32 = 52;
+
+#[derive(Deserialize)]
+pub struct HeatmapQuery {
+    pub device: String,
+    /// `co2` (the default), `temperature` or `humidity`
+    pub variable: Option<String>,
+    /// Weeks back from the last full hour; 8 when absent
+    pub weeks: Option<u32>,
+    /// `mean` (the default) or `p95`
+    pub stat: Option<String>,
+}
+
+/// Matrices with a row per weekday from Monday and a column per local hour.
+/// Cells with few `samples` rest on little data; values are null without
+/// any.
+#[derive(Serialize, Debug)]
+pub struct HeatmapResponse {
+    pub device: String,
+    pub variable: &'static str,
+    pub stat: &'static str,
+    pub weeks: u32,
+    pub values: Vec<Vec<Option<f64>>>,
+    pub samples: Vec<Vec<u64>>,
+    /// Hours of data behind each cell
+    pub hours: Vec<Vec<u32>>,
+    /// Hours averaged from raw measurements for lack of a stored aggregate
+    pub raw_hours: usize,
+}
+
+#[derive(Deserialize)]
+pub struct AnomalyTuningQuery {
+    pub device: Option<String>,
+    pub from: String,
+    pub to: String,
+    pub detector: String,
+    pub buckets: Option<usize>,
+    pub steps: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct AnomalyListQuery {
+    pub device: Option<String>,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AnomalyContextQuery {
+    /// Needed when several devices were flagged at the same time
+    pub device: Option<String>,
+}
+
+/// A marking with the external events going on at its time
+#[derive(Serialize)]
+pub struct AnomalyContext {
+    pub anomaly: AnomalyRecord,
+    pub events: Vec<ExternalEvent>,
+}
+
+#[derive(Deserialize)]
+pub struct EventListQuery {
+    pub device: Option<String>,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AnomalyReviewRequest {
+    /// Needed when several devices were flagged at the same time
+    pub device: Option<String>,
+    pub note: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct PredictionRequest {
     pub timestamp: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct PredictionResponse {
     pub success: bool,
     pub input_time: String,
@@ -69,21 +408,21 @@ pub struct PredictionResponse {
     pub error: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct InputConditions {
     pub co2: f64,
     pub temperature: f64,
     pub humidity: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct PredictedValues {
     pub co2: f64,
     pub temperature: f64,
     pub humidity: f64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct ActualValues {
     pub co2: f64,
     pub temperature: f64,
@@ -93,12 +432,25 @@ pub struct ActualValues {
     pub humidity_diff: f64,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_web_server(
     influx_host: String,
     influx_token: String,
     influx_database: String,
     port: u16,
     base_path: String,
+    command_relay: Option<RelayHandle>,
+    quality_alert_threshold: f64,
+    last_seen: Option<LastSeen>,
+    latency: Option<Latency>,
+    ingest_metrics: Option<pipeline::Metrics>,
+    ventilation: VentilationConfig,
+    storage: StorageConfig,
+    prediction_cache_ttl: chrono::Duration,
+    failover: Option<LeaderStatus>,
+    event_kinds: Vec<Identifier>,
+    display_zone: DisplayZone,
+    preflight: Option<PreflightReport>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Ensure base path starts with / and doesn't end with / (unless it is just "/")
     let base_path = if !base_path.starts_with('/') {
@@ -130,6 +482,14 @@ pub async fn run_web_server(
         "Training data loaded successfully with {} data points!",
         training_data.len()
     );
+    let event_feature = EventFeature::fetch(
+        &influx_host,
+        &influx_token,
+        &influx_database,
+        &reqwest_client,
+        &event_kinds,
+    )
+    .await;
 
     let state = Arc::new(AppState {
         influx_host,
@@ -138,22 +498,36 @@ pub async fn run_web_server(
         reqwest_client,
         base_path: base_path.clone(),
         cached_training_data: Arc::new(Mutex::new(Some(training_data))),
+        event_feature,
+        command_relay,
+        quality_alert_threshold,
+        last_seen,
+        latency,
+        ingest_metrics,
+        failover,
+        predictions: PredictionCache::new(prediction_cache_ttl),
+        maintenance: MaintenanceStore::from_env(),
+        alerts: AlertStore::from_env(),
+        rooms: RoomRegistry::from_env(),
+        ventilation,
+        storage,
+        api_token: std::env::var("WEB_API_TOKEN")
+            .ok()
+            .filter(|t| !t.is_empty()),
+        disconnects: Arc::default(),
+        display_zone,
+        preflight,
     });
 
-    let api_router = Router::new()
-        .route("/", get(serve_index))
-        .route("/api/available-timestamps", get(get_available_timestamps))
-        .route("/api/data-range", post(get_data_range))
-        .route("/api/predict", post(perform_prediction))
-        .with_state(state);
-
+    let api_router = api_router(state);
     let app = if base_path == "/" {
-        api_router.layer(CorsLayer::permissive())
+        api_router
     } else {
-        Router::new()
-            .nest(&base_path, api_router)
-            .layer(CorsLayer::permissive())
+        Router::new().nest(&base_path, api_router)
     };
+    let app = app
+        .layer(CorsLayer::permissive())
+        .layer(CompressionLayer::new());
 
     let addr = format!("0.0.0.0:{}", port);
 
@@ -168,6 +542,51 @@ pub async fn run_web_server(
     Ok(())
 }
 
+fn api_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(serve_index))
+        .route("/api/available-timestamps", get(get_available_timestamps))
+        .route("/api/data-range", post(get_data_range))
+        .route("/api/predict", post(perform_prediction))
+        .route("/api/devices", get(list_devices))
+        .route("/api/reference/compare", get(compare_reference))
+        .route("/api/measurements/resampled", get(get_resampled))
+        .route("/api/heatmap", get(get_heatmap))
+        .route("/api/anomalies", get(list_anomalies))
+        .route("/api/anomalies/tuning", get(get_anomaly_tuning))
+        .route("/api/anomalies/:ts/confirm", post(confirm_anomaly))
+        .route("/api/anomalies/:ts/dismiss", post(dismiss_anomaly))
+        .route("/api/anomalies/:ts/context", get(get_anomaly_context))
+        .route("/api/events", get(list_events).post(add_event))
+        .route("/api/alerts", get(list_alerts))
+        .route("/api/alerts/:id/ack", post(acknowledge_alert))
+        .route("/api/storage", get(get_storage))
+        .route("/freshness", get(get_freshness))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_healthz))
+        .route(
+            "/api/devices/:device/maintenance",
+            get(get_maintenance).post(set_maintenance),
+        )
+        .route(
+            "/api/devices/:device/recommendation",
+            get(get_recommendation),
+        )
+        .route(
+            "/api/devices/:device/config/history",
+            get(get_config_history),
+        )
+        .route(
+            "/api/devices/:device/commands",
+            get(list_device_commands).post(submit_device_command),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.disconnects.clone(),
+            disconnects::cancel_on_disconnect,
+        ))
+        .with_state(state)
+}
+
 async fn serve_index(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let html = include_str!("predictor_web.html");
     // If base_path is "/", we want empty string for prefix to avoid "//api"
@@ -185,75 +604,100 @@ async fn serve_index(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     Html(html.replace("__API_BASE_PATH__", prefix))
 }
 
-async fn get_available_timestamps(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<AvailableTimestamp>>, AppError> {
-    let query_url = format!(
-        "{}/api/v3/query_sql?db={}",
-        state.influx_host, state.influx_database
-    );
+impl TimestampsQuery {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_TIMESTAMP_LIMIT)
+            .clamp(1, MAX_TIMESTAMP_LIMIT)
+    }
 
-    // Get all available measurements (no time filter to support old data)
-    let sql_query = r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent,
-            device
-        FROM scd40_data
-        ORDER BY time DESC
-        LIMIT 5000
-    "#;
+    fn time_filter(&self, sql: Sql) -> Sql {
+        match self.hours {
+            Some(hours) => sql
+                .push(" WHERE time >= now() - INTERVAL '")
+                .number(hours.into())
+                .push(" hours'"),
+            None => sql,
+        }
+    }
 
-    let response = state
-        .reqwest_client
-        .post(&query_url)
-        .bearer_auth(&state.influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": state.influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
+    fn sql(&self) -> Sql {
+        let columns = match self.fields {
+            TimestampFields::Time => "time",
+            TimestampFields::Co2 => "time, co2_ppm",
+            // `location` can't be named: the column only exists once a
+            // device has sent one
+            TimestampFields::Full => "*",
+        };
+        self.time_filter(Sql::new("SELECT ").push(columns).push(" FROM scd40_data"))
+            .push(" ORDER BY time DESC LIMIT ")
+            .number(self.limit() as u64)
+    }
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "<no text>".to_string());
-        log::error!("InfluxDB query failed: status={}, body={}", status, body);
-        return Err(AppError::influx_error(format!(
-            "Query failed: {} - {}",
-            status, body
-        )));
+    /// Changes whenever a new measurement arrives or the parameters differ.
+    /// With `hours` the window start is included too, to the minute, since
+    /// old rows drop out of the window without anything new arriving.
+    fn etag(&self, newest: &str, now: DateTime<Utc>) -> String {
+        let window = match self.hours {
+            Some(hours) => format!(
+                "-{}",
+                (now - chrono::Duration::hours(hours as i64)).format("%Y%m%d%H%M")
+            ),
+            None => String::new(),
+        };
+        format!(
+            "\"{}-{:?}-{}{}\"",
+            newest,
+            self.fields,
+            self.limit(),
+            window
+        )
     }
+}
 
-    let response_text = response.text().await?;
-    log::debug!("InfluxDB response text: {}", response_text);
+/// Whether an `If-None-Match` header value covers `etag`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+    })
+}
 
-    if response_text.is_empty() {
-        log::warn!("InfluxDB returned empty response");
-        return Ok(Json(Vec::new()));
+async fn get_available_timestamps(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+    Query(query): Query<TimestampsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    #[derive(Deserialize)]
+    struct Newest {
+        newest: Option<String>,
     }
 
-    let influx_rows: Vec<InfluxMeasurementRow> = match serde_json::from_str(&response_text) {
-        Ok(rows) => rows,
-        Err(e) => {
-            log::error!("Failed to parse InfluxDB response: {}", e);
-            log::error!("Response text was: {}", response_text);
-            return Err(AppError::from(e));
-        }
-    };
-
-    log::info!(
-        "Successfully parsed {} rows from InfluxDB",
-        influx_rows.len()
-    );
+    // Cheap query first, so polling clients with fresh data get a 304
+    // without the full rows being fetched
+    let newest: Vec<Newest> = query_influx(
+        &state,
+        &query.time_filter(Sql::new("SELECT MAX(time) AS newest FROM scd40_data")),
+        &cancel,
+    )
+    .await?;
+    let newest = newest
+        .into_iter()
+        .next()
+        .and_then(|n| n.newest)
+        .unwrap_or_default();
+    let etag = query.etag(&newest, Utc::now());
+
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        && etag_matches(if_none_match, &etag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
 
-    let timestamps: Vec<AvailableTimestamp> = influx_rows
+    let rows: Vec<TimestampRow> = query_influx(&state, &query.sql(), &cancel).await?;
+    let timestamps: Vec<AvailableTimestamp> = rows
         .into_iter()
         .map(|row| AvailableTimestamp {
             time: row.time,
@@ -261,37 +705,259 @@ async fn get_available_timestamps(
             temperature: row.temperature_c,
             humidity: row.humidity_percent,
             device: row.device,
+            location: row.location,
         })
         .collect();
 
     log::info!("Returning {} available timestamps", timestamps.len());
-    Ok(Json(timestamps))
+    Ok((
+        [
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Json(timestamps),
+    )
+        .into_response())
+}
+
+async fn get_freshness(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<FreshnessQuery>,
+) -> Result<Response, AppError> {
+    let last_seen = match &state.last_seen {
+        Some(live) => live.snapshot(),
+        None => freshness::fetch_last_seen(
+            &state.influx_host,
+            &state.influx_token,
+            &state.influx_database,
+            &state.reqwest_client,
+        )
+        .await
+        .map_err(|e| AppError::influx_error(e.to_string()))?,
+    };
+    let ages = freshness::ages(&last_seen, Utc::now());
+    // Only the receiver hears `next_wake`, nothing is stored to query
+    let intervals = state
+        .last_seen
+        .as_ref()
+        .map(|live| live.intervals())
+        .unwrap_or_default();
+
+    let (content_type, body) = match query.format {
+        FreshnessFormat::Plain => ("text/plain; charset=utf-8", freshness::render_plain(&ages)),
+        FreshnessFormat::OpenMetrics => (
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            freshness::render_openmetrics(&ages, &intervals),
+        ),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Prediction request counters, and ingest latency histograms and stage
+/// counters when the receiver runs in the same process, since only it sees
+/// the messages.
+#[derive(Deserialize)]
+pub struct HealthQuery {
+    /// `1` adds the startup checks
+    #[serde(default)]
+    pub verbose: u8,
+}
+
+/// Always 200 while the server runs: it only starts once the hard checks
+/// passed. `degraded` means a soft one failed.
+async fn get_healthz(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HealthQuery>,
+) -> Json<serde_json::Value> {
+    let degraded = state
+        .preflight
+        .as_ref()
+        .is_some_and(|report| report.soft_failures() > 0);
+    let mut body = serde_json::json!({ "status": if degraded { "degraded" } else { "ok" } });
+    if query.verbose != 0 {
+        body["preflight"] = serde_json::json!(state.preflight);
+    }
+    Json(body)
+}
+
+async fn get_metrics(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let mut body = String::new();
+    if let Some(latency) = &state.latency {
+        latency.write_openmetrics(&mut body);
+    }
+    if let Some(metrics) = &state.ingest_metrics {
+        metrics.write_openmetrics(&mut body);
+    }
+    if let Some(failover) = &state.failover {
+        failover.write_openmetrics(&mut body);
+    }
+    state.predictions.write_openmetrics(&mut body);
+    state.disconnects.write_openmetrics(&mut body);
+    body.push_str("# EOF\n");
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            ),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        body,
+    )
+        .into_response())
 }
 
 async fn get_data_range(
     State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
     Json(request): Json<DateRangeRequest>,
 ) -> Result<Json<Vec<DataPoint>>, AppError> {
+    let start = parse_query_time(&request.start_date)?;
+    let end = parse_query_time(&request.end_date)?;
+    if request.hourly()? {
+        let rows: Vec<HourlyRow> =
+            query_influx(&state, &hourly::range_query(start, end, 10_000), &cancel).await?;
+        let data_points: Vec<DataPoint> = rows.into_iter().filter_map(hourly_point).collect();
+        log::info!(
+            "Returning {} hourly points for range {} to {}",
+            data_points.len(),
+            request.start_date,
+            request.end_date
+        );
+        return Ok(Json(data_points));
+    }
+
+    let influx_rows: Vec<SimpleInfluxRow> = query_influx(
+        &state,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent FROM scd40_data \
+             WHERE time >= ",
+        )
+        .time(start)
+        .push(" AND time <= ")
+        .time(end)
+        .push(" ORDER BY time ASC LIMIT 10000"),
+        &cancel,
+    )
+    .await?;
+
+    let data_points: Vec<DataPoint> = influx_rows
+        .into_iter()
+        .map(|row| DataPoint {
+            time: row.time,
+            co2: row.co2_ppm,
+            temperature: row.temperature_c,
+            humidity: row.humidity_percent,
+            hourly: None,
+        })
+        .collect();
+
+    log::info!(
+        "Returning {} data points for range {} to {}",
+        data_points.len(),
+        request.start_date,
+        request.end_date
+    );
+    Ok(Json(data_points))
+}
+
+async fn perform_prediction(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+    Json(request): Json<PredictionRequest>,
+) -> Result<Json<PredictionResponse>, AppError> {
+    log::info!("Performing prediction for timestamp: {}", request.timestamp);
+
+    // Parse the timestamp
+    let prediction_timestamp = if let Ok(dt) = DateTime::parse_from_rfc3339(&request.timestamp) {
+        dt.with_timezone(&Utc)
+    } else {
+        let time_with_timezone = format!("{}Z", request.timestamp);
+        DateTime::parse_from_rfc3339(&time_with_timezone)?.with_timezone(&Utc)
+    };
+
+    // A new measurement makes earlier results for the same time stale
+    #[derive(Deserialize)]
+    struct Newest {
+        newest: Option<String>,
+    }
+    let newest: Vec<Newest> = query_influx(
+        &state,
+        &Sql::new("SELECT MAX(time) AS newest FROM scd40_data"),
+        &cancel,
+    )
+    .await?;
+    let newest = newest.into_iter().next().and_then(|n| n.newest);
+
+    // Use cached training data for faster prediction
+    let (result, outcome) = state
+        .predictions
+        .get_or_compute(
+            PredictionKey::new(prediction_timestamp),
+            newest,
+            Utc::now(),
+            || async {
+                predict_with_cached_data(&state, prediction_timestamp)
+                    .await
+                    .map_err(|e| e.to_string())
+            },
+        )
+        .await;
+    log::debug!("Prediction {}", outcome.as_str());
+
+    match result {
+        Ok(pred_result) => Ok(Json(pred_result)),
+        Err(e) => Ok(Json(PredictionResponse {
+            success: false,
+            input_time: request.timestamp.clone(),
+            prediction_time: (prediction_timestamp + chrono::Duration::hours(1)).to_rfc3339(),
+            input: InputConditions {
+                co2: 0.0,
+                temperature: 0.0,
+                humidity: 0.0,
+            },
+            predicted: PredictedValues {
+                co2: 0.0,
+                temperature: 0.0,
+                humidity: 0.0,
+            },
+            actual: None,
+            error: Some(e),
+        })),
+    }
+}
+
+/// Runs `sql`, giving up as soon as `cancel` fires
+async fn query_influx<T: serde::de::DeserializeOwned>(
+    state: &AppState,
+    sql: &Sql,
+    cancel: &CancellationToken,
+) -> Result<Vec<T>, AppError> {
+    tokio::select! {
+        rows = send_query(state, sql) => rows,
+        _ = cancel.cancelled() => Err(AppError::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query cancelled, the client went away",
+        )),
+    }
+}
+
+async fn send_query<T: serde::de::DeserializeOwned>(
+    state: &AppState,
+    sql: &Sql,
+) -> Result<Vec<T>, AppError> {
     let query_url = format!(
         "{}/api/v3/query_sql?db={}",
         state.influx_host, state.influx_database
     );
 
-    let sql_query = format!(
-        r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent
-        FROM scd40_data
-        WHERE time >= '{}' AND time <= '{}'
-        ORDER BY time ASC
-        LIMIT 10000
-    "#,
-        request.start_date, request.end_date
-    );
-
     let response = state
         .reqwest_client
         .post(&query_url)
@@ -299,7 +965,7 @@ async fn get_data_range(
         .header("Content-Type", "application/json")
         .body(serde_json::to_string(&serde_json::json!({
             "db": state.influx_database,
-            "q": sql_query
+            "q": sql.as_str()
         }))?)
         .send()
         .await?;
@@ -310,7 +976,6 @@ async fn get_data_range(
             .text()
             .await
             .unwrap_or_else(|_| "<no text>".to_string());
-        log::error!("InfluxDB query failed: status={}, body={}", status, body);
         return Err(AppError::influx_error(format!(
             "Query failed: {} - {}",
             status, body
@@ -319,74 +984,825 @@ async fn get_data_range(
 
     let response_text = response.text().await?;
     if response_text.is_empty() {
-        return Ok(Json(Vec::new()));
+        return Ok(Vec::new());
     }
+    Ok(serde_json::from_str(&response_text)?)
+}
 
-    let influx_rows: Vec<SimpleInfluxRow> = match serde_json::from_str(&response_text) {
-        Ok(rows) => rows,
-        Err(e) => {
-            log::error!("Failed to parse InfluxDB response: {}", e);
-            log::error!("Response text was: {}", response_text);
-            return Err(AppError::from(e));
-        }
-    };
+async fn list_devices(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+) -> Result<Json<Vec<DeviceSummary>>, AppError> {
+    #[derive(Deserialize)]
+    struct LastSeenRow {
+        device: String,
+        last_seen: String,
+    }
 
-    let data_points: Vec<DataPoint> = influx_rows
+    let last_seen: Vec<LastSeenRow> =
+        query_influx(&state, &Sql::new(freshness::QUERY), &cancel).await?;
+
+    #[derive(Deserialize)]
+    struct QualityRow {
+        device: String,
+        #[serde(flatten)]
+        quality: DeviceQuality,
+    }
+
+    // data_quality only exists after the first daily report
+    let quality_rows: Vec<QualityRow> = query_influx(
+        &state,
+        &Sql::new(
+            "SELECT time, device, score, completeness, anomaly_rate, rejection_rate, \
+             flatline_minutes, clock_skew_incidents FROM data_quality ORDER BY time DESC LIMIT 1000",
+        ), &cancel)
+    .await
+    .unwrap_or_else(|_| {
+        log::debug!("No data_quality measurement yet");
+        Vec::new()
+    });
+
+    let mut latest_quality: HashMap<String, DeviceQuality> = HashMap::new();
+    for row in quality_rows {
+        latest_quality.entry(row.device).or_insert(row.quality);
+    }
+
+    let now = Utc::now();
+    let maintenance = state.maintenance.load(now).unwrap_or_else(|e| {
+        log::error!("Failed to read maintenance state: {}", e);
+        Default::default()
+    });
+
+    let devices = last_seen
         .into_iter()
-        .map(|row| DataPoint {
-            time: row.time,
-            co2: row.co2_ppm,
-            temperature: row.temperature_c,
-            humidity: row.humidity_percent,
+        .map(|row| {
+            let quality = latest_quality.remove(&row.device);
+            let maintenance_until = maintenance.active(&row.device, now).map(|w| w.until);
+            DeviceSummary {
+                is_virtual: state.rooms.is_virtual(&row.device),
+                quality_alert: maintenance_until.is_none()
+                    && quality
+                        .as_ref()
+                        .is_some_and(|q| q.score < state.quality_alert_threshold),
+                device: row.device,
+                last_seen: Some(row.last_seen),
+                quality,
+                maintenance_until,
+            }
         })
         .collect();
 
+    Ok(Json(devices))
+}
+
+async fn compare_reference(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReferenceCompareQuery>,
+) -> Result<Json<crate::reference::Comparison>, AppError> {
+    let parse = |value: &str| {
+        DateTime::parse_from_rfc3339(value)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|e| {
+                AppError::with_status(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid timestamp '{}': {}", value, e),
+                )
+            })
+    };
+    let device = parse_device(&query.device)?;
+    let source = query.source.as_deref().map(parse_device).transpose()?;
+    let from = parse(&query.from)?;
+    let to = parse(&query.to)?;
+    let tolerance = chrono::Duration::seconds(query.tolerance_seconds.unwrap_or(300));
+
+    let comparison = crate::reference::compare_reference(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        &device,
+        source.as_ref(),
+        from,
+        to,
+        tolerance,
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    Ok(Json(comparison))
+}
+
+async fn get_resampled(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+    Query(query): Query<ResampleQuery>,
+) -> Result<Json<ResampledMeasurements>, AppError> {
+    let bad_request = |msg: String| AppError::with_status(StatusCode::BAD_REQUEST, msg);
+    let device = parse_device(&query.device)?;
+    let from = parse_query_time(&query.from)?;
+    let to = parse_query_time(&query.to)?;
+    let parse_duration = |value: &str| {
+        duration::parse_duration(value)
+            .and_then(|d| chrono::Duration::from_std(d).map_err(|e| e.to_string()))
+            .map_err(bad_request)
+    };
+    let step = match &query.step {
+        Some(step) => parse_duration(step)?,
+        None => chrono::Duration::minutes(5),
+    };
+    let tolerance = match &query.tolerance {
+        Some(tolerance) => parse_duration(tolerance)?,
+        None => step,
+    };
+    let method: Method = query
+        .method
+        .as_deref()
+        .unwrap_or("linear")
+        .parse()
+        .map_err(bad_request)?;
+    let grid = stats::grid(from, to, step).map_err(bad_request)?;
+
+    // Points just outside the range still count for its edges
+    let rows: Vec<InfluxMeasurementRow> = query_influx(
+        &state,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             WHERE device = ",
+        )
+        .identifier(&device)
+        .push(" AND time >= ")
+        .time(from - tolerance)
+        .push(" AND time <= ")
+        .time(to + tolerance)
+        .push(" ORDER BY time ASC"),
+        &cancel,
+    )
+    .await?;
+    let mut measurements = rows
+        .iter()
+        .map(|row| row.to_measurement_with_time())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AppError::influx_error(e.to_string()))?;
+    measurements.sort_by_key(|m| m.time);
+
+    let times: Vec<DateTime<Utc>> = measurements.iter().map(|m| m.time).collect();
+    let points = grid
+        .iter()
+        .zip(stats::resample(&times, &grid, method, tolerance))
+        .map(|(&time, sample)| ResampledPoint {
+            time,
+            co2_ppm: sample.value(|i| measurements[i].co2 as f64),
+            temperature_c: sample.value(|i| measurements[i].temperature as f64),
+            humidity_percent: sample.value(|i| measurements[i].humidity as f64),
+            interpolated: sample.is_interpolated(),
+        })
+        .collect();
+
+    Ok(Json(ResampledMeasurements {
+        device: query.device,
+        method: method.as_str(),
+        step_seconds: step.num_seconds(),
+        tolerance_seconds: tolerance.num_seconds(),
+        points,
+    }))
+}
+
+async fn get_heatmap(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+    Query(query): Query<HeatmapQuery>,
+) -> Result<Json<HeatmapResponse>, AppError> {
+    heatmap_until(&state, &cancel, query, hourly::hour_of(Utc::now())).await
+}
+
+/// The heatmap over the weeks before `to`, an hour boundary
+async fn heatmap_until(
+    state: &AppState,
+    cancel: &CancellationToken,
+    query: HeatmapQuery,
+    to: DateTime<Utc>,
+) -> Result<Json<HeatmapResponse>, AppError> {
+    let bad_request = |msg: String| AppError::with_status(StatusCode::BAD_REQUEST, msg);
+    let device = parse_device(&query.device)?;
+    let variable: Variable = query
+        .variable
+        .as_deref()
+        .unwrap_or("co2")
+        .parse()
+        .map_err(bad_request)?;
+    let statistic: Statistic = query
+        .stat
+        .as_deref()
+        .unwrap_or("mean")
+        .parse()
+        .map_err(bad_request)?;
+    let weeks = query.weeks.unwrap_or(8);
+    if !(1..=MAX_HEATMAP_WEEKS).contains(&weeks) {
+        return Err(bad_request(format!(
+            "weeks must be 1 to {}",
+            MAX_HEATMAP_WEEKS
+        )));
+    }
+    let from = to - chrono::Duration::weeks(i64::from(weeks));
+
+    let rows: Vec<HourlyRow> = query_influx(
+        state,
+        &hourly::device_range_query(&device, from, to),
+        cancel,
+    )
+    .await?;
+    let stored: HashSet<DateTime<Utc>> = rows.iter().filter_map(HourlyRow::start).collect();
+    let mut hours: Vec<HourMean> = rows
+        .iter()
+        .filter_map(|row| {
+            let mean = match variable {
+                Variable::Co2 => row.co2_mean,
+                Variable::Temperature => row.temperature_mean,
+                Variable::Humidity => row.humidity_mean,
+            };
+            Some(HourMean {
+                hour: row.start()?,
+                mean: mean?,
+                samples: row.count(),
+            })
+        })
+        .collect();
+
+    // Hours from before aggregates were enabled, or written while the
+    // receiver was down, come from the raw data
+    let missing: HashSet<DateTime<Utc>> = (0..i64::from(weeks) * 7 * 24)
+        .map(|i| from + chrono::Duration::hours(i))
+        .filter(|hour| !stored.contains(hour))
+        .collect();
+    let mut raw_hours = 0;
+    if let (Some(first), Some(last)) = (missing.iter().min(), missing.iter().max()) {
+        let raw: Vec<InfluxMeasurementRow> = query_influx(
+            state,
+            &Sql::new(
+                "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+                 WHERE device = ",
+            )
+            .identifier(&device)
+            .push(" AND time >= ")
+            .time(*first)
+            .push(" AND time < ")
+            .time(*last + chrono::Duration::hours(1))
+            .push(" ORDER BY time ASC"),
+            cancel,
+        )
+        .await?;
+        let points = raw
+            .iter()
+            .map(|row| row.to_measurement_with_time())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| AppError::influx_error(e.to_string()))?;
+        let filled: Vec<HourMean> = stats::hourly_means(points.iter().map(|m| {
+            let value = match variable {
+                Variable::Co2 => f64::from(m.co2),
+                Variable::Temperature => f64::from(m.temperature),
+                Variable::Humidity => f64::from(m.humidity),
+            };
+            (m.time, value)
+        }))
+        .into_iter()
+        .filter(|hour| missing.contains(&hour.hour))
+        .collect();
+        raw_hours = filled.len();
+        hours.extend(filled);
+    }
+
+    let heatmap = stats::heatmap(&hours, &state.display_zone, statistic);
+    Ok(Json(HeatmapResponse {
+        device: query.device,
+        variable: variable.as_str(),
+        stat: statistic.as_str(),
+        weeks,
+        values: matrix(&heatmap, |cell| cell.value),
+        samples: matrix(&heatmap, |cell| cell.samples),
+        hours: matrix(&heatmap, |cell| cell.hours),
+        raw_hours,
+    }))
+}
+
+fn matrix<T>(heatmap: &stats::Heatmap, field: impl Fn(&stats::Cell) -> T) -> Vec<Vec<T>> {
+    heatmap
+        .iter()
+        .map(|row| row.iter().map(&field).collect())
+        .collect()
+}
+
+async fn get_anomaly_tuning(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+    Query(query): Query<AnomalyTuningQuery>,
+) -> Result<Json<anomaly_tuning::Tuning>, AppError> {
+    let bad_request = |msg: String| AppError::with_status(StatusCode::BAD_REQUEST, msg);
+    let parse = |value: &str| {
+        DateTime::parse_from_rfc3339(value)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|e| bad_request(format!("invalid timestamp '{}': {}", value, e)))
+    };
+    let detector: anomaly_tuning::Detector = query.detector.parse().map_err(bad_request)?;
+    let device = query.device.as_deref().map(parse_device).transpose()?;
+    let from = parse(&query.from)?;
+    let to = parse(&query.to)?;
+    anomaly_tuning::check_range(from, to).map_err(bad_request)?;
+
+    let mut sql = Sql::new(
+        "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+         WHERE time >= ",
+    )
+    .time(from)
+    .push(" AND time <= ")
+    .time(to);
+    if let Some(device) = &device {
+        sql = sql.push(" AND device = ").identifier(device);
+    }
+    let rows: Vec<InfluxMeasurementRow> =
+        query_influx(&state, &sql.push(" ORDER BY time ASC"), &cancel).await?;
+    let measurements = rows
+        .iter()
+        .map(|row| row.to_measurement_with_time())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AppError::influx_error(e.to_string()))?;
+
+    Ok(Json(anomaly_tuning::build_tuning(
+        detector,
+        &AnomalyConfig::default(),
+        &measurements,
+        query.device,
+        from,
+        to,
+        query
+            .buckets
+            .unwrap_or(anomaly_tuning::DEFAULT_BUCKETS)
+            .clamp(1, anomaly_tuning::MAX_BUCKETS),
+        query
+            .steps
+            .unwrap_or(anomaly_tuning::DEFAULT_SWEEP_STEPS)
+            .min(anomaly_tuning::MAX_SWEEP_STEPS),
+    )))
+}
+
+/// Every route that changes something checks this first: reviews, events,
+/// alert acknowledgements, maintenance windows and device commands. Without
+/// `WEB_API_TOKEN` the API is read-only.
+fn require_api_token(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected) = state.api_token.as_deref() else {
+        return Err(AppError::with_status(
+            StatusCode::FORBIDDEN,
+            "Changes through the API are disabled; set WEB_API_TOKEN to enable them",
+        ));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given != Some(expected) {
+        return Err(AppError::with_status(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong API token",
+        ));
+    }
+    Ok(())
+}
+
+/// Refuses changes on a failover follower: the leader would not see them,
+/// and they would be lost when this instance takes over.
+fn require_leader(state: &AppState) -> Result<(), AppError> {
+    let Some(failover) = &state.failover else {
+        return Ok(());
+    };
+    let status = failover.get();
+    if status.role == Role::Leader {
+        return Ok(());
+    }
+    let leader = status.leader.as_deref().unwrap_or("no instance yet");
+    Err(AppError::with_status(
+        StatusCode::SERVICE_UNAVAILABLE,
+        format!(
+            "This instance is on standby, changes go to the leader ({})",
+            leader
+        ),
+    ))
+}
+
+fn parse_device(value: &str) -> Result<Identifier, AppError> {
+    Identifier::parse(value).map_err(|e| AppError::with_status(StatusCode::BAD_REQUEST, e))
+}
+
+fn parse_query_time(value: &str) -> Result<DateTime<Utc>, AppError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|e| {
+            AppError::with_status(
+                StatusCode::BAD_REQUEST,
+                format!("invalid timestamp '{}': {}", value, e),
+            )
+        })
+}
+
+async fn list_anomalies(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AnomalyListQuery>,
+) -> Result<Json<Vec<AnomalyRecord>>, AppError> {
+    let device = query.device.as_deref().map(parse_device).transpose()?;
+    let from = parse_query_time(&query.from)?;
+    let to = parse_query_time(&query.to)?;
+    anomaly_tuning::check_range(from, to)
+        .map_err(|e| AppError::with_status(StatusCode::BAD_REQUEST, e))?;
+
+    let records = anomaly_review::fetch_records(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        Some((from, to)),
+        device.as_ref(),
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    Ok(Json(anomaly_review::resolve(records)))
+}
+
+/// The one marking at `ts` among `records`
+fn single_record(records: Vec<AnomalyRecord>, ts: &str) -> Result<AnomalyRecord, AppError> {
+    let mut records = anomaly_review::resolve(records);
+    match records.len() {
+        0 => Err(AppError::with_status(
+            StatusCode::NOT_FOUND,
+            format!("No anomaly marked at {}", ts),
+        )),
+        1 => Ok(records.remove(0)),
+        _ => {
+            let devices: Vec<_> = records.iter().map(|r| r.device.as_str()).collect();
+            Err(AppError::with_status(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Several devices were flagged at {} ({}); pass one as 'device'",
+                    ts,
+                    devices.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+async fn review_anomaly(
+    state: &AppState,
+    headers: &HeaderMap,
+    ts: &str,
+    request: AnomalyReviewRequest,
+    to: ReviewStatus,
+) -> Result<Json<AnomalyRecord>, AppError> {
+    require_api_token(state, headers)?;
+    require_leader(state)?;
+    let time = parse_query_time(ts)?;
+    let device = request.device.as_deref().map(parse_device).transpose()?;
+
+    let records = anomaly_review::fetch_records(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        Some((time, time)),
+        device.as_ref(),
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    let record = single_record(records, ts)?;
+    let status = record
+        .status
+        .review(to)
+        .map_err(|e| AppError::with_status(StatusCode::CONFLICT, e))?;
+
+    let reviewed_at = Utc::now();
+    let line = anomaly_review::review_line(&record, status, request.note.as_deref(), reviewed_at);
+    InfluxStore {
+        influx_host: &state.influx_host,
+        influx_token: &state.influx_token,
+        influx_database: &state.influx_database,
+        reqwest_client: &state.reqwest_client,
+    }
+    .write(&[line])
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
     log::info!(
-        "Returning {} data points for range {} to {}",
-        data_points.len(),
-        request.start_date,
-        request.end_date
+        "Anomaly for '{}' at {} marked {}",
+        record.device,
+        record.time.to_rfc3339(),
+        status.as_str()
     );
-    Ok(Json(data_points))
+
+    Ok(Json(AnomalyRecord {
+        status,
+        reviewer_note: request.note,
+        reviewed_at: Some(reviewed_at),
+        ..record
+    }))
+}
+
+async fn confirm_anomaly(
+    State(state): State<Arc<AppState>>,
+    Path(ts): Path<String>,
+    headers: HeaderMap,
+    request: Option<Json<AnomalyReviewRequest>>,
+) -> Result<Json<AnomalyRecord>, AppError> {
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+    review_anomaly(&state, &headers, &ts, request, ReviewStatus::Confirmed).await
+}
+
+async fn dismiss_anomaly(
+    State(state): State<Arc<AppState>>,
+    Path(ts): Path<String>,
+    headers: HeaderMap,
+    request: Option<Json<AnomalyReviewRequest>>,
+) -> Result<Json<AnomalyRecord>, AppError> {
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+    review_anomaly(&state, &headers, &ts, request, ReviewStatus::Dismissed).await
+}
+
+async fn get_anomaly_context(
+    State(state): State<Arc<AppState>>,
+    Path(ts): Path<String>,
+    Query(query): Query<AnomalyContextQuery>,
+) -> Result<Json<AnomalyContext>, AppError> {
+    let time = parse_query_time(&ts)?;
+    let device = query.device.as_deref().map(parse_device).transpose()?;
+    let records = anomaly_review::fetch_records(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        Some((time, time)),
+        device.as_ref(),
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    let anomaly = single_record(records, &ts)?;
+    let device = parse_device(&anomaly.device)?;
+    let events = external_events::overlapping_or_none(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        Some(&device),
+        time,
+        time,
+    )
+    .await;
+    Ok(Json(AnomalyContext { anomaly, events }))
+}
+
+async fn list_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventListQuery>,
+) -> Result<Json<Vec<ExternalEvent>>, AppError> {
+    let device = query.device.as_deref().map(parse_device).transpose()?;
+    let from = parse_query_time(&query.from)?;
+    let to = parse_query_time(&query.to)?;
+    anomaly_tuning::check_range(from, to)
+        .map_err(|e| AppError::with_status(StatusCode::BAD_REQUEST, e))?;
+
+    let events = external_events::fetch_overlapping(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        device.as_ref(),
+        from,
+        to,
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    Ok(Json(events))
+}
+
+async fn add_event(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<EventRequest>,
+) -> Result<Json<ExternalEvent>, AppError> {
+    require_api_token(&state, &headers)?;
+    require_leader(&state)?;
+    let event = ExternalEvent::try_from(request)
+        .map_err(|e| AppError::with_status(StatusCode::BAD_REQUEST, e))?;
+    InfluxStore {
+        influx_host: &state.influx_host,
+        influx_token: &state.influx_token,
+        influx_database: &state.influx_database,
+        reqwest_client: &state.reqwest_client,
+    }
+    .write(&[event.line()])
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    log::info!(
+        "Added {} event for '{}' from {} to {}",
+        event.kind,
+        event.device,
+        event.from.to_rfc3339(),
+        event.to.to_rfc3339()
+    );
+    Ok(Json(event))
+}
+
+async fn list_alerts(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Alert>>, AppError> {
+    let book = state
+        .alerts
+        .load()
+        .map_err(|e| AppError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(book.list()))
+}
+
+async fn acknowledge_alert(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Json<Alert>, AppError> {
+    require_api_token(&state, &headers)?;
+    require_leader(&state)?;
+    let alert = state
+        .alerts
+        .update(|book| book.acknowledge(id, Utc::now()).cloned())
+        .map_err(|e| AppError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            let status = match e {
+                AckError::NotFound => StatusCode::NOT_FOUND,
+                AckError::AlreadyAcknowledged => StatusCode::CONFLICT,
+            };
+            AppError::with_status(status, format!("alert {}: {}", id, e))
+        })?;
+    log::info!(
+        "Alert {} ({} on {}) acknowledged",
+        id,
+        alert.rule,
+        alert.device
+    );
+    Ok(Json(alert))
+}
+
+fn maintenance_view(state: &AppState, device: String) -> Result<MaintenanceView, AppError> {
+    let now = Utc::now();
+    let window = state
+        .maintenance
+        .load(now)
+        .map_err(|e| AppError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .active(&device, now)
+        .cloned();
+    Ok(MaintenanceView {
+        device,
+        active: window.is_some(),
+        until: window.as_ref().map(|w| w.until),
+        reason: window.map(|w| w.reason),
+    })
+}
+
+async fn get_maintenance(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+) -> Result<Json<MaintenanceView>, AppError> {
+    Ok(Json(maintenance_view(&state, device)?))
+}
+
+async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<MaintenanceRequest>,
+) -> Result<Json<MaintenanceView>, AppError> {
+    require_api_token(&state, &headers)?;
+    require_leader(&state)?;
+    state
+        .maintenance
+        .update(Utc::now(), |maintenance| {
+            maintenance.set(&device, request.until, Reason::Manual)
+        })
+        .map_err(|e| AppError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    log::info!(
+        "Maintenance for '{}' until {}",
+        device,
+        request.until.to_rfc3339()
+    );
+    Ok(Json(maintenance_view(&state, device)?))
+}
+
+async fn get_recommendation(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+) -> Result<Json<Recommendation>, AppError> {
+    let device = parse_device(&device)?;
+    let recommendation = ventilation::fetch_recommendation(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        &device,
+        Utc::now(),
+        &state.rooms,
+        &state.ventilation,
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    Ok(Json(recommendation))
+}
+
+async fn get_storage(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel): Extension<CancellationToken>,
+) -> Result<Json<StorageReport>, AppError> {
+    let usage = tokio::select! {
+        usage = storage::fetch_usage(
+            &state.influx_host,
+            &state.influx_token,
+            &state.influx_database,
+            &state.reqwest_client,
+            state.storage.window_days,
+            Utc::now(),
+        ) => usage.map_err(|e| AppError::influx_error(e.to_string()))?,
+        _ = cancel.cancelled() => return Err(AppError::with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query cancelled, the client went away",
+        )),
+    };
+    Ok(Json(storage::plan(&usage, &state.storage)))
+}
+
+async fn get_config_history(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+    Query(query): Query<ConfigHistoryQuery>,
+) -> Result<Json<Vec<ConfigSnapshot>>, AppError> {
+    let device = parse_device(&device)?;
+    let before = query.before.as_deref().map(parse_query_time).transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(device_config::DEFAULT_HISTORY_LIMIT)
+        .clamp(1, device_config::MAX_HISTORY_LIMIT);
+    let snapshots = device_config::fetch_history(
+        &state.influx_host,
+        &state.influx_token,
+        &state.influx_database,
+        &state.reqwest_client,
+        &device,
+        before,
+        limit,
+    )
+    .await
+    .map_err(|e| AppError::influx_error(e.to_string()))?;
+    Ok(Json(snapshots))
 }
 
-async fn perform_prediction(
-    State(state): State<Arc<AppState>>,
-    Json(request): Json<PredictionRequest>,
-) -> Result<Json<PredictionResponse>, AppError> {
-    log::info!("Performing prediction for timestamp: {}", request.timestamp);
-
-    // Parse the timestamp
-    let prediction_timestamp = if let Ok(dt) = DateTime::parse_from_rfc3339(&request.timestamp) {
-        dt.with_timezone(&Utc)
-    } else {
-        let time_with_timezone = format!("{}Z", request.timestamp);
-        DateTime::parse_from_rfc3339(&time_with_timezone)?.with_timezone(&Utc)
-    };
+fn relay_handle(state: &AppState) -> Result<&RelayHandle, AppError> {
+    state.command_relay.as_ref().ok_or_else(|| {
+        AppError::with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Command relay is not enabled (start with --command-relay)",
+        )
+    })
+}
 
-    // Use cached training data for faster prediction
-    let result = predict_with_cached_data(&state, prediction_timestamp).await;
+async fn list_device_commands(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+) -> Result<Json<Vec<RelayedCommandView>>, AppError> {
+    let relay = relay_handle(&state)?.relay.lock().await;
+    Ok(Json(
+        relay
+            .commands_for(&device)
+            .into_iter()
+            .map(RelayedCommandView::from)
+            .collect(),
+    ))
+}
 
-    match result {
-        Ok(pred_result) => Ok(Json(pred_result)),
-        Err(e) => Ok(Json(PredictionResponse {
-            success: false,
-            input_time: request.timestamp.clone(),
-            prediction_time: (prediction_timestamp + chrono::Duration::hours(1)).to_rfc3339(),
-            input: InputConditions {
-                co2: 0.0,
-                temperature: 0.0,
-                humidity: 0.0,
-            },
-            predicted: PredictedValues {
-                co2: 0.0,
-                temperature: 0.0,
-                humidity: 0.0,
-            },
-            actual: None,
-            error: Some(e.to_string()),
-        })),
+async fn submit_device_command(
+    State(state): State<Arc<AppState>>,
+    Path(device): Path<String>,
+    headers: HeaderMap,
+    Json(command): Json<DeviceCommand>,
+) -> Result<Json<RelayedCommandView>, AppError> {
+    require_api_token(&state, &headers)?;
+    require_leader(&state)?;
+    if state.rooms.is_virtual(&device) {
+        return Err(AppError::with_status(
+            StatusCode::BAD_REQUEST,
+            format!("'{}' is a virtual device and takes no commands", device),
+        ));
     }
+    // The device would only reject it, a wake cycle or more from now
+    command
+        .check()
+        .map_err(|e| AppError::with_status(StatusCode::BAD_REQUEST, e.to_string()))?;
+    let entry = relay_handle(&state)?
+        .submit(&device, command)
+        .await
+        .map_err(|e| AppError::with_status(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    log::info!(
+        "Queued command {} for '{}', expected execution: {:?}",
+        entry.id,
+        entry.device,
+        entry.expected_execution
+    );
+    Ok(Json(RelayedCommandView::from(&entry)))
 }
 
 // Fast prediction using cached training data (no need to re-fetch from DB)
@@ -444,6 +1860,7 @@ async fn predict_with_cached_data(
 
     // Clone training data to avoid holding lock during model training
     let training_data_clone = training_data.clone();
+    let event_feature = state.event_feature.as_ref();
 
     // Release the lock before training
     drop(training_data_lock);
@@ -480,11 +1897,7 @@ async fn predict_with_cached_data(
 
         let find_past_for_training =
             |target_time: DateTime<Utc>, current_idx: usize| -> Option<&MeasurementWithTime> {
-                let start_search = if current_idx > 400 {
-                    current_idx - 400
-                } else {
-                    0
-                };
+                let start_search = current_idx.saturating_sub(400);
                 training_data_clone[start_search..current_idx]
                     .iter()
                     .rev()
@@ -507,7 +1920,7 @@ async fn predict_with_cached_data(
         let minute = m_current.time.minute() as f64;
         let weekday = m_current.time.weekday().num_days_from_monday() as f64;
 
-        let features = vec![
+        let mut features = vec![
             hour,
             minute,
             weekday,
@@ -525,6 +1938,9 @@ async fn predict_with_cached_data(
             m_current.humidity as f64 - p3h.humidity as f64,
         ];
 
+        if let Some(event_feature) = event_feature {
+            features.push(event_feature.value(&m_current.device, m_current.time));
+        }
         x_base_data.push(features);
         y_co2.push(m_future.co2 as f64);
         y_temp.push(m_future.temperature as f64);
@@ -559,7 +1975,7 @@ async fn predict_with_cached_data(
     let pred_minute = target_time.minute() as f64;
     let pred_weekday = target_time.weekday().num_days_from_monday() as f64;
 
-    let input_vec = vec![
+    let mut input_vec = vec![
         pred_hour,
         pred_minute,
         pred_weekday,
@@ -576,6 +1992,9 @@ async fn predict_with_cached_data(
         latest_measurement.humidity as f64 - p1h_data.humidity as f64,
         latest_measurement.humidity as f64 - p3h_data.humidity as f64,
     ];
+    if let Some(event_feature) = event_feature {
+        input_vec.push(event_feature.value(&latest_measurement.device, latest_measurement.time));
+    }
 
     let x_pred_co2 = DenseMatrix::from_2d_vec(&vec![input_vec.clone()])?;
     let pred_co2_val = model_co2.predict(&x_pred_co2)?[0];
@@ -649,10 +2068,21 @@ async fn fetch_and_prepare_training_data(
         return Err("No data found for training".into());
     }
 
-    let anomalies =
-        fetch_anomalies_internal(influx_host, influx_token, influx_database, reqwest_client)
-            .await?;
-    measurements.retain(|m| !anomalies.contains(&m.time));
+    let anomalies = crate::anomaly_review::fetch_training_exclusions(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+    let maintenance = crate::maintenance::fetch_maintenance_times(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+    measurements.retain(|m| !anomalies.contains(&m.time) && !maintenance.contains(&m.time));
 
     if measurements.len() < 100 {
         return Err("Not enough data after filtering for training".into());
@@ -670,135 +2100,1240 @@ async fn fetch_training_data_internal(
     reqwest_client: &reqwest::Client,
     end_time: Option<DateTime<Utc>>,
 ) -> Result<Vec<crate::types::MeasurementWithTime>, Box<dyn std::error::Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
+    let mut sql =
+        Sql::new("SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data");
+    if let Some(end_time) = end_time {
+        sql = sql.push(" WHERE time <= ").time(end_time);
+    }
+    let influx_rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql.push(" ORDER BY time DESC LIMIT 10000"),
+    )
+    .await?;
+    let mut measurements = Vec::with_capacity(influx_rows.len());
+    for row in influx_rows {
+        if let Ok(m) = row.to_measurement_with_time() {
+            measurements.push(m);
+        }
+    }
 
-    let time_filter = if let Some(et) = end_time {
-        format!("WHERE time <= '{}'", et.to_rfc3339())
-    } else {
-        "".to_string()
-    };
+    Ok(measurements)
+}
 
-    let sql_query = format!(
-        r#"
-        SELECT
-            time,
-            co2_ppm,
-            temperature_c,
-            humidity_percent,
-            device
-        FROM scd40_data
-        {}
-        ORDER BY time DESC
-        LIMIT 10000
-    "#,
-        time_filter
-    );
+// Error handling
+struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
 
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        log::error!("Web handler error: {}", self.error);
+        (self.status, format!("Error: {}", self.error)).into_response()
+    }
+}
 
-    if !response.status().is_success() {
-        return Err(format!("InfluxDB query failed: {}", response.status()).into());
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+        }
     }
+}
 
-    let response_text = response.text().await?;
-    if response_text.is_empty() {
-        return Ok(Vec::new());
+impl AppError {
+    fn influx_error(msg: String) -> Self {
+        Self::with_status(StatusCode::INTERNAL_SERVER_ERROR, msg)
     }
 
-    let influx_rows: Vec<InfluxMeasurementRow> = serde_json::from_str(&response_text)?;
-    let mut measurements = Vec::with_capacity(influx_rows.len());
-    for row in influx_rows {
-        if let Ok(m) = row.to_measurement_with_time() {
-            measurements.push(m);
+    fn with_status(status: StatusCode, msg: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: anyhow::anyhow!(msg.into()),
         }
     }
-
-    Ok(measurements)
 }
 
-async fn fetch_anomalies_internal(
-    influx_host: &str,
-    influx_token: &str,
-    influx_database: &str,
-    reqwest_client: &reqwest::Client,
-) -> Result<HashSet<DateTime<Utc>>, Box<dyn std::error::Error>> {
-    let query_url = format!("{}/api/v3/query_sql?db={}", influx_host, influx_database);
-    let sql_query = "SELECT time FROM anomalies";
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failover::Status;
+    use crate::test_support::at;
+    use axum::http::{HeaderValue, Uri};
+
+    /// Stands in for InfluxDB: answers `MAX(time)` with `newest`, after
+    /// `newest_delay`, and any other query with two rows holding just the
+    /// selected columns.
+    #[derive(Clone, Default)]
+    struct FakeInflux {
+        newest: Arc<std::sync::Mutex<String>>,
+        newest_delay: Arc<std::sync::Mutex<std::time::Duration>>,
+        queries: Arc<std::sync::Mutex<Vec<String>>>,
+    }
 
-    let response = reqwest_client
-        .post(&query_url)
-        .bearer_auth(influx_token)
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&serde_json::json!({
-            "db": influx_database,
-            "q": sql_query
-        }))?)
-        .send()
-        .await?;
+    async fn fake_query(
+        State(fake): State<FakeInflux>,
+        Json(body): Json<serde_json::Value>,
+    ) -> Json<serde_json::Value> {
+        let sql = body["q"].as_str().unwrap().to_string();
+        fake.queries.lock().unwrap().push(sql.clone());
+        if sql.contains("MAX(time)") {
+            let delay = *fake.newest_delay.lock().unwrap();
+            tokio::time::sleep(delay).await;
+            let newest = fake.newest.lock().unwrap().clone();
+            return Json(serde_json::json!([{ "newest": newest }]));
+        }
+        if sql.contains("FROM scd40_hourly") {
+            return Json(serde_json::json!([
+                {
+                    "time": "2025-01-15T09:00:00", "device": "esp32-scd40",
+                    "co2_min": 600.0, "co2_max": 800.0, "co2_mean": 700.0,
+                    "temperature_min": 20.5, "temperature_max": 21.5, "temperature_mean": 21.0,
+                    "humidity_min": 40.0, "humidity_max": 42.0, "humidity_mean": 41.0,
+                    "count": 12, "partial": false
+                },
+                {
+                    "time": "2025-01-15T10:00:00", "device": "esp32-scd40",
+                    "co2_min": 900.0, "co2_max": 1500.0, "co2_mean": 1200.0,
+                    "temperature_min": 21.0, "temperature_max": 22.0, "temperature_mean": 21.5,
+                    "humidity_min": 40.0, "humidity_max": 41.0, "humidity_mean": 40.5,
+                    "co2_max_time_ms": 1736936400000_i64, "minutes_above_1000": 35.0,
+                    "minutes_above_1400": 10.0, "count": 11, "excluded": 1, "partial": false
+                },
+                {
+                    "time": "2025-01-15T11:00:00", "device": "esp32-scd40",
+                    "minutes_above_1000": 0.0, "count": 0, "excluded": 12, "partial": false
+                }
+            ]));
+        }
+        if sql.contains("FROM external_events") {
+            return Json(serde_json::json!([
+                {
+                    "time": "2025-01-14T18:00:00", "device": "esp32-scd40", "kind": "party",
+                    "until": "2025-01-14T23:00:00+00:00", "note": "10 people"
+                },
+                {
+                    "time": "2025-01-15T09:00:00", "device": "esp32-scd40",
+                    "kind": "hvac_filter", "until": "2025-01-15T09:00:00+00:00"
+                }
+            ]));
+        }
+        if sql.contains("FROM anomalies") {
+            return Json(serde_json::json!([{
+                "time": "2025-01-14T20:00:00",
+                "device": "esp32-scd40",
+                "status": "auto",
+                "co2_spike": true
+            }]));
+        }
+        if sql.contains("FROM device_config") {
+            return Json(serde_json::json!([{
+                "time": "2025-01-15T10:00:00",
+                "device": "esp32-scd40",
+                "firmware_version": "0.1.0",
+                "sleep_seconds": 300,
+                "utc_offset_hours": 1,
+                "sensor_mode": "periodic",
+                "quiet_hours": null,
+                "temperature_offset": 4.0,
+                "mqtt_policy": "measurement=1+retain",
+                "wifi_ssid": "home",
+                "safe_mode": false
+            }]));
+        }
+        let columns = match &sql["SELECT ".len()..sql.find(" FROM").unwrap()] {
+            "*" => "time, co2_ppm, temperature_c, humidity_percent, device, location",
+            columns => columns,
+        }
+        .to_string();
+        let rows = ["2025-01-15T10:00:00", "2025-01-15T09:55:00"]
+            .iter()
+            .map(|time| {
+                let mut row = serde_json::Map::new();
+                for column in columns.split(", ") {
+                    let value = match column {
+                        "time" => serde_json::json!(time),
+                        "device" => serde_json::json!("esp32-scd40"),
+                        "location" => serde_json::json!("bedroom"),
+                        _ => serde_json::json!(612.0),
+                    };
+                    row.insert(column.to_string(), value);
+                }
+                serde_json::Value::Object(row)
+            })
+            .collect();
+        Json(serde_json::Value::Array(rows))
+    }
 
-    if !response.status().is_success() {
-        return Ok(HashSet::new());
+    async fn setup() -> (Arc<AppState>, FakeInflux) {
+        let fake = FakeInflux::default();
+        *fake.newest.lock().unwrap() = "2025-01-15T10:00:00".to_string();
+        let app = Router::new()
+            .route("/api/v3/query_sql", post(fake_query))
+            .with_state(fake.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let state = Arc::new(AppState {
+            influx_host: format!("http://{}", addr),
+            influx_token: "token".to_string(),
+            influx_database: "db".to_string(),
+            reqwest_client: reqwest::Client::new(),
+            base_path: "/".to_string(),
+            cached_training_data: Arc::new(Mutex::new(None)),
+            event_feature: None,
+            command_relay: None,
+            quality_alert_threshold: 70.0,
+            last_seen: None,
+            latency: None,
+            ingest_metrics: None,
+            failover: None,
+            predictions: PredictionCache::new(chrono::Duration::seconds(60)),
+            maintenance: MaintenanceStore::new(std::env::temp_dir().join(format!(
+                "rpi-processor-web-maintenance-{}-{}.json",
+                std::process::id(),
+                addr.port()
+            ))),
+            alerts: AlertStore::new(std::env::temp_dir().join(format!(
+                "rpi-processor-web-alerts-{}-{}.json",
+                std::process::id(),
+                addr.port()
+            ))),
+            rooms: RoomRegistry::default(),
+            ventilation: VentilationConfig::default(),
+            storage: StorageConfig::default(),
+            api_token: Some("secret".to_string()),
+            disconnects: Arc::default(),
+            display_zone: "CET-1CEST,M3.5.0,M10.5.0/3".parse().unwrap(),
+            preflight: None,
+        });
+        (state, fake)
     }
 
-    let response_text = response.text().await?;
-    if response_text.is_empty() {
-        return Ok(HashSet::new());
+    /// Headers carrying the token `setup` configures
+    fn authorized() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        headers
+    }
+
+    async fn get(
+        state: &Arc<AppState>,
+        query: &str,
+        if_none_match: Option<&str>,
+    ) -> (StatusCode, HeaderMap, Option<serde_json::Value>) {
+        let uri: Uri = format!("http://localhost/api/available-timestamps{}", query)
+            .parse()
+            .unwrap();
+        let Query(query) = Query::<TimestampsQuery>::try_from_uri(&uri).unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(value) = if_none_match {
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        }
+        let response = get_available_timestamps(
+            State(state.clone()),
+            Extension(CancellationToken::new()),
+            Query(query),
+            headers,
+        )
+        .await
+        .map_err(|e| e.error)
+        .unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json = (!body.is_empty()).then(|| serde_json::from_slice(&body).unwrap());
+        (status, headers, json)
+    }
+
+    fn last_query(fake: &FakeInflux) -> String {
+        fake.queries.lock().unwrap().last().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn a_client_going_away_stops_the_follow_up_queries() {
+        use tokio::io::AsyncWriteExt;
+
+        let (state, fake) = setup().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = api_router(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let body = r#"{"timestamp":"2025-01-15T10:00:00Z"}"#;
+
+        // A client that waits gets the measurement looked up after MAX(time)
+        reqwest::Client::new()
+            .post(format!("http://{}/api/predict", addr))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(fake.queries.lock().unwrap().len(), 2);
+        fake.queries.lock().unwrap().clear();
+
+        *fake.newest_delay.lock().unwrap() = std::time::Duration::from_millis(300);
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "POST /api/predict HTTP/1.1\r\nHost: localhost\r\n\
+                     Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        while fake.queries.lock().unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Gone while MAX(time) is still running
+        drop(client);
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+
+        assert_eq!(
+            *fake.queries.lock().unwrap(),
+            ["SELECT MAX(time) AS newest FROM scd40_data"]
+        );
+        assert_eq!(state.disconnects.cancelled(), 1);
+    }
+
+    #[tokio::test]
+    async fn defaults_to_time_and_co2() {
+        let (state, fake) = setup().await;
+        let (status, headers, body) = get(&state, "", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains_key(header::ETAG));
+        assert_eq!(
+            last_query(&fake),
+            "SELECT time, co2_ppm FROM scd40_data ORDER BY time DESC LIMIT 500"
+        );
+        assert_eq!(
+            body.unwrap()[0],
+            serde_json::json!({ "time": "2025-01-15T10:00:00", "co2": 612.0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn time_only_with_window_and_limit() {
+        let (state, fake) = setup().await;
+        let (_, _, body) = get(&state, "?fields=time&hours=4&limit=10", None).await;
+        assert_eq!(
+            last_query(&fake),
+            "SELECT time FROM scd40_data WHERE time >= now() - INTERVAL '4 hours' \
+             ORDER BY time DESC LIMIT 10"
+        );
+        assert_eq!(
+            body.unwrap()[1],
+            serde_json::json!({ "time": "2025-01-15T09:55:00" })
+        );
+    }
+
+    #[tokio::test]
+    async fn full_rows_on_request() {
+        let (state, fake) = setup().await;
+        let (_, _, body) = get(&state, "?fields=full", None).await;
+        assert_eq!(
+            last_query(&fake),
+            "SELECT * FROM scd40_data ORDER BY time DESC LIMIT 500"
+        );
+        let row = body.unwrap()[0].clone();
+        assert_eq!(row["device"], "esp32-scd40");
+        assert_eq!(row["location"], "bedroom");
+        assert_eq!(row["temperature"], 612.0);
+        assert_eq!(row["humidity"], 612.0);
+    }
+
+    #[tokio::test]
+    async fn limit_is_clamped() {
+        let (state, fake) = setup().await;
+        get(&state, "?limit=100000", None).await;
+        assert!(last_query(&fake).ends_with("LIMIT 5000"));
+        get(&state, "?limit=0", None).await;
+        assert!(last_query(&fake).ends_with("LIMIT 1"));
+    }
+
+    #[tokio::test]
+    async fn unchanged_data_returns_304_without_fetching_rows() {
+        let (state, fake) = setup().await;
+        let (_, headers, _) = get(&state, "", None).await;
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+
+        let queries_before = fake.queries.lock().unwrap().len();
+        let (status, headers, body) = get(&state, "", Some(&etag)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers[header::ETAG], etag.as_str());
+        assert!(body.is_none());
+        // Only the MAX(time) lookup ran
+        assert_eq!(fake.queries.lock().unwrap().len(), queries_before + 1);
+
+        let weak_in_list = format!("\"other\", W/{}", etag);
+        assert_eq!(
+            get(&state, "", Some(&weak_in_list)).await.0,
+            StatusCode::NOT_MODIFIED
+        );
+    }
+
+    #[tokio::test]
+    async fn new_data_or_other_parameters_change_the_etag() {
+        let (state, fake) = setup().await;
+        let (_, headers, _) = get(&state, "", None).await;
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+
+        // Same data, different shape
+        assert_eq!(
+            get(&state, "?fields=full", Some(&etag)).await.0,
+            StatusCode::OK
+        );
+
+        *fake.newest.lock().unwrap() = "2025-01-15T10:05:00".to_string();
+        let (status, headers, _) = get(&state, "", Some(&etag)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(headers[header::ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn maintenance_is_set_and_ended_through_the_api() {
+        let (state, _) = setup().await;
+        let until = Utc::now() + chrono::Duration::hours(1);
+        let set = |until| {
+            set_maintenance(
+                State(state.clone()),
+                Path("kitchen".to_string()),
+                authorized(),
+                Json(MaintenanceRequest { until }),
+            )
+        };
+
+        let Json(view) = set(until).await.map_err(|e| e.error).unwrap();
+        assert_eq!(
+            view,
+            MaintenanceView {
+                device: "kitchen".to_string(),
+                active: true,
+                until: Some(until),
+                reason: Some(Reason::Manual),
+            }
+        );
+        let Json(other) = get_maintenance(State(state.clone()), Path("bedroom".to_string()))
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert!(!other.active);
+
+        let Json(view) = set(Utc::now() - chrono::Duration::minutes(1))
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert!(!view.active);
+        let _ = std::fs::remove_file(state.maintenance.path());
     }
 
-    #[derive(serde::Deserialize)]
-    struct AnomalyRow {
-        time: String,
+    #[tokio::test]
+    async fn config_history_serves_stored_snapshots() {
+        let (state, fake) = setup().await;
+        let history = |query: &str| {
+            let uri: Uri = format!(
+                "http://localhost/api/devices/esp32-scd40/config/history?{}",
+                query
+            )
+            .parse()
+            .unwrap();
+            let Query(query) = Query::<ConfigHistoryQuery>::try_from_uri(&uri).unwrap();
+            get_config_history(
+                State(state.clone()),
+                Path("esp32-scd40".to_string()),
+                Query(query),
+            )
+        };
+
+        let Json(snapshots) = history("before=2025-01-15T11:00:00Z&limit=1")
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert_eq!(
+            last_query(&fake),
+            "SELECT * FROM device_config WHERE device = 'esp32-scd40' \
+             AND time <= '2025-01-15T11:00:00+00:00' ORDER BY time DESC LIMIT 1"
+        );
+        let json = serde_json::to_value(&snapshots).unwrap();
+        assert_eq!(json[0]["time"], "2025-01-15T10:00:00Z");
+        assert_eq!(json[0]["sleep_seconds"], 300);
+        assert_eq!(json[0]["temperature_offset"], 4.0);
+        assert!(json[0].get("quiet_hours").is_none());
+
+        let _ = history("limit=100000").await.map_err(|e| e.error).unwrap();
+        assert!(last_query(&fake).ends_with("ORDER BY time DESC LIMIT 500"));
+        let error = history("before=yesterday").await.err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
     }
 
-    let rows: Vec<AnomalyRow> = serde_json::from_str(&response_text).unwrap_or_default();
-    let mut anomalies = HashSet::new();
-    for row in rows {
-        let time_with_timezone = if row.time.ends_with('Z') {
-            row.time
-        } else {
-            format!("{}Z", row.time)
+    #[tokio::test]
+    async fn heatmap_buckets_stored_hours_in_local_time() {
+        let (state, fake) = setup().await;
+        let to = at(0);
+        let request = |query: &str| {
+            let uri: Uri = format!("http://localhost/api/heatmap?{}", query)
+                .parse()
+                .unwrap();
+            let Query(query) = Query::<HeatmapQuery>::try_from_uri(&uri).unwrap();
+            let state = state.clone();
+            async move { heatmap_until(&state, &CancellationToken::new(), query, to).await }
         };
-        if let Ok(dt) = DateTime::parse_from_rfc3339(&time_with_timezone) {
-            anomalies.insert(dt.with_timezone(&Utc));
+
+        let Json(heatmap) = request("device=esp32-scd40&weeks=1")
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        let queries = fake.queries.lock().unwrap().clone();
+        assert!(queries[0].contains(
+            "FROM scd40_hourly WHERE device = 'esp32-scd40' \
+             AND time >= '2025-01-08T12:00:00+00:00' AND time < '2025-01-15T12:00:00+00:00'"
+        ));
+        // Everything but the three stored hours is looked up in the raw data
+        assert!(queries[1].contains(
+            "FROM scd40_data WHERE device = 'esp32-scd40' \
+             AND time >= '2025-01-08T12:00:00+00:00' AND time < '2025-01-15T09:00:00+00:00'"
+        ));
+        assert_eq!((heatmap.variable, heatmap.stat), ("co2", "mean"));
+        // 09:00 UTC on a Wednesday is 10:00 in Central Europe
+        let wednesday = 2;
+        assert_eq!(heatmap.values[wednesday][10], Some(700.0));
+        assert_eq!(heatmap.samples[wednesday][10], 12);
+        assert_eq!(heatmap.values[wednesday][11], Some(1200.0));
+        // Stored, but every sample was an artifact
+        assert_eq!(heatmap.values[wednesday][12], None);
+        assert_eq!(heatmap.hours[wednesday][12], 0);
+        assert_eq!(heatmap.raw_hours, 0);
+        assert_eq!(heatmap.values.len(), 7);
+        assert!(heatmap.values.iter().all(|row| row.len() == 24));
+
+        let Json(heatmap) = request("device=esp32-scd40&weeks=1&variable=humidity&stat=p95")
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert_eq!(heatmap.values[wednesday][11], Some(40.5));
+
+        for query in [
+            "device=a&stat=median",
+            "device=a&variable=pm25",
+            "device=a&weeks=0",
+            "device=a&weeks=53",
+        ] {
+            let status = request(query).await.err().unwrap().status;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
         }
     }
-    Ok(anomalies)
-}
 
-// Error handling
-struct AppError(anyhow::Error);
+    #[tokio::test]
+    async fn resampled_measurements_fill_the_grid() {
+        let (state, fake) = setup().await;
+        let resample = |query: &str| {
+            let uri: Uri = format!("http://localhost/api/measurements/resampled?{}", query)
+                .parse()
+                .unwrap();
+            let Query(query) = Query::<ResampleQuery>::try_from_uri(&uri).unwrap();
+            get_resampled(
+                State(state.clone()),
+                Extension(CancellationToken::new()),
+                Query(query),
+            )
+        };
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        log::error!("Web handler error: {}", self.0);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error: {}", self.0),
+        let Json(resampled) = resample(
+            "device=esp32-scd40&from=2025-01-15T09:50:00Z&to=2025-01-15T10:05:00Z\
+             &step=5m&tolerance=60s",
         )
-            .into_response()
+        .await
+        .map_err(|e| e.error)
+        .unwrap();
+        assert!(last_query(&fake).contains(
+            "WHERE device = 'esp32-scd40' AND time >= '2025-01-15T09:49:00+00:00' \
+             AND time <= '2025-01-15T10:06:00+00:00'"
+        ));
+        assert_eq!(resampled.method, "linear");
+        assert_eq!(resampled.step_seconds, 300);
+        let values: Vec<_> = resampled
+            .points
+            .iter()
+            .map(|p| {
+                (
+                    p.time.format("%H:%M").to_string(),
+                    p.co2_ppm,
+                    p.interpolated,
+                )
+            })
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("09:50".to_string(), None, false),
+                ("09:55".to_string(), Some(612.0), false),
+                ("10:00".to_string(), Some(612.0), false),
+                ("10:05".to_string(), None, false),
+            ]
+        );
+
+        // The default tolerance of one step reaches the neighbouring points
+        let Json(resampled) = resample(
+            "device=esp32-scd40&from=2025-01-15T09:50:00Z&to=2025-01-15T10:05:00Z&method=previous",
+        )
+        .await
+        .map_err(|e| e.error)
+        .unwrap();
+        let held: Vec<_> = resampled
+            .points
+            .iter()
+            .map(|p| p.co2_ppm.is_some())
+            .collect();
+        assert_eq!(held, vec![false, true, true, true]);
+        assert!(resampled.points[3].interpolated);
+
+        for query in [
+            "device=a&from=2025-01-15T09:50:00Z&to=2025-01-15T10:05:00Z&method=cubic",
+            "device=a&from=2025-01-15T09:50:00Z&to=2025-01-15T10:05:00Z&step=0s",
+            "device=a&from=2025-01-15T10:50:00Z&to=2025-01-15T10:05:00Z",
+            "device=a&from=2025-01-01T00:00:00Z&to=2025-03-01T00:00:00Z&step=1m",
+        ] {
+            let status = resample(query).await.err().unwrap().status;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+        }
     }
-}
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+    #[tokio::test]
+    async fn long_ranges_are_charted_from_hourly_extremes() {
+        let (state, fake) = setup().await;
+        let range = |start: &str, end: &str, resolution: Option<&str>| DateRangeRequest {
+            start_date: start.to_string(),
+            end_date: end.to_string(),
+            resolution: resolution.map(str::to_string),
+        };
+
+        let Json(points) = get_data_range(
+            State(state.clone()),
+            Extension(CancellationToken::new()),
+            Json(range("2025-01-01T00:00:00Z", "2025-01-31T00:00:00Z", None)),
+        )
+        .await
+        .map_err(|e| e.error)
+        .unwrap();
+        assert!(last_query(&fake).starts_with("SELECT * FROM scd40_hourly"));
+        let json = serde_json::to_value(&points).unwrap();
+        // The hour of nothing but artifacts has no point
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(
+            json[0],
+            serde_json::json!({
+                "time": "2025-01-15T09:00:00",
+                "co2": 700.0, "temperature": 21.0, "humidity": 41.0,
+                "co2_min": 600.0, "co2_max": 800.0,
+                "temperature_min": 20.5, "temperature_max": 21.5,
+                "humidity_min": 40.0, "humidity_max": 42.0
+            })
+        );
+        assert_eq!(json[1]["co2_max_time"], "2025-01-15T10:20:00Z");
+        assert_eq!(
+            json[1]["minutes_above"],
+            serde_json::json!({ "1000": 35.0, "1400": 10.0 })
+        );
+        assert_eq!(json[1]["excluded"], 1);
+
+        // A day is raw unless asked otherwise; the fake can't answer that query
+        let _ = get_data_range(
+            State(state.clone()),
+            Extension(CancellationToken::new()),
+            Json(range("2025-01-15T00:00:00Z", "2025-01-16T00:00:00Z", None)),
+        )
+        .await;
+        assert!(last_query(&fake).contains("FROM scd40_data"));
+        let Json(points) = get_data_range(
+            State(state.clone()),
+            Extension(CancellationToken::new()),
+            Json(range(
+                "2025-01-15T00:00:00Z",
+                "2025-01-16T00:00:00Z",
+                Some("hourly"),
+            )),
+        )
+        .await
+        .map_err(|e| e.error)
+        .unwrap();
+        assert!(last_query(&fake).contains("FROM scd40_hourly"));
+        assert_eq!(points.len(), 2);
+
+        let status = get_data_range(
+            State(state),
+            Extension(CancellationToken::new()),
+            Json(range(
+                "2025-01-15T00:00:00Z",
+                "2025-01-16T00:00:00Z",
+                Some("daily"),
+            )),
+        )
+        .await
+        .err()
+        .unwrap()
+        .status;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
     }
-}
 
-impl AppError {
-    fn influx_error(msg: String) -> Self {
-        Self(anyhow::anyhow!(msg))
+    #[tokio::test]
+    async fn alerts_are_acknowledged_through_the_api() {
+        let (state, _) = setup().await;
+        let policy = crate::alerts::AlertPolicy {
+            rules: [("co2_spike".to_string(), crate::alerts::Severity::Critical)].into(),
+            ..Default::default()
+        };
+        state
+            .alerts
+            .update(|book| {
+                book.raise(
+                    &policy,
+                    "co2_spike",
+                    "kitchen",
+                    "CO2 at 2100 ppm".to_string(),
+                    Utc::now(),
+                )
+            })
+            .unwrap();
+        let ack = |id: u64, authorization: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_static(authorization),
+            );
+            acknowledge_alert(State(state.clone()), Path(id), headers)
+        };
+
+        assert_eq!(
+            ack(1, "Bearer wrong").await.err().unwrap().status,
+            StatusCode::UNAUTHORIZED
+        );
+        let Json(alert) = ack(1, "Bearer secret").await.map_err(|e| e.error).unwrap();
+        assert_eq!(alert.state, crate::alerts::AlertState::Acknowledged);
+        assert_eq!(
+            ack(1, "Bearer secret").await.err().unwrap().status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            ack(2, "Bearer secret").await.err().unwrap().status,
+            StatusCode::NOT_FOUND
+        );
+
+        let Json(alerts) = list_alerts(State(state.clone()))
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert_eq!(alerts, vec![alert]);
+        let _ = std::fs::remove_file(state.alerts.path());
+    }
+
+    #[tokio::test]
+    async fn anomaly_tuning_caps_the_range() {
+        let (state, fake) = setup().await;
+        let tuning = |from: &str, to: &str| {
+            get_anomaly_tuning(
+                State(state.clone()),
+                Extension(CancellationToken::new()),
+                Query(AnomalyTuningQuery {
+                    device: Some("esp32-scd40".to_string()),
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    detector: "co2_spike".to_string(),
+                    buckets: Some(5),
+                    steps: None,
+                }),
+            )
+        };
+
+        let err = tuning("2025-01-01T00:00:00Z", "2025-03-01T00:00:00Z")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(fake.queries.lock().unwrap().is_empty());
+
+        let Json(result) = tuning("2025-01-15T00:00:00Z", "2025-01-16T00:00:00Z")
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert!(last_query(&fake).contains("AND device = 'esp32-scd40'"));
+        assert_eq!(result.samples, 2);
+        assert_eq!(result.current_flagged, 0);
+        assert_eq!(result.histogram.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn hostile_names_and_times_never_reach_influx() {
+        let (state, fake) = setup().await;
+        let hostile = "x' OR '1'='1";
+        let day = ("2025-01-15T00:00:00Z", "2025-01-16T00:00:00Z");
+        let status = |result: Result<(), AppError>| result.err().unwrap().status;
+
+        let statuses = [
+            status(
+                get_config_history(
+                    State(state.clone()),
+                    Path(hostile.to_string()),
+                    Query(ConfigHistoryQuery {
+                        before: None,
+                        limit: None,
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                get_recommendation(State(state.clone()), Path(hostile.to_string()))
+                    .await
+                    .map(drop),
+            ),
+            status(
+                get_resampled(
+                    State(state.clone()),
+                    Extension(CancellationToken::new()),
+                    Query(ResampleQuery {
+                        device: hostile.to_string(),
+                        from: day.0.to_string(),
+                        to: day.1.to_string(),
+                        step: None,
+                        method: None,
+                        tolerance: None,
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                get_heatmap(
+                    State(state.clone()),
+                    Extension(CancellationToken::new()),
+                    Query(HeatmapQuery {
+                        device: hostile.to_string(),
+                        variable: None,
+                        weeks: None,
+                        stat: None,
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                compare_reference(
+                    State(state.clone()),
+                    Query(ReferenceCompareQuery {
+                        device: "esp32-scd40".to_string(),
+                        source: Some(hostile.to_string()),
+                        from: day.0.to_string(),
+                        to: day.1.to_string(),
+                        tolerance_seconds: None,
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                list_anomalies(
+                    State(state.clone()),
+                    Query(AnomalyListQuery {
+                        device: Some(hostile.to_string()),
+                        from: day.0.to_string(),
+                        to: day.1.to_string(),
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                list_events(
+                    State(state.clone()),
+                    Query(EventListQuery {
+                        device: Some(hostile.to_string()),
+                        from: day.0.to_string(),
+                        to: day.1.to_string(),
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                get_data_range(
+                    State(state.clone()),
+                    Extension(CancellationToken::new()),
+                    Json(DateRangeRequest {
+                        start_date: "2025-01-15' OR '1'='1".to_string(),
+                        end_date: day.1.to_string(),
+                        resolution: Some("raw".to_string()),
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+            status(
+                get_data_range(
+                    State(state.clone()),
+                    Extension(CancellationToken::new()),
+                    Json(DateRangeRequest {
+                        start_date: day.0.to_string(),
+                        end_date: "tomorrow".to_string(),
+                        resolution: Some("hourly".to_string()),
+                    }),
+                )
+                .await
+                .map(drop),
+            ),
+        ];
+        assert!(statuses.iter().all(|&s| s == StatusCode::BAD_REQUEST));
+        assert!(fake.queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_are_checked_before_they_are_stored() {
+        let (state, fake) = setup().await;
+        let time = |value| parse_query_time(value).ok().unwrap();
+        let add = |authorization: Option<&str>, to| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = authorization {
+                headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            }
+            add_event(
+                State(state.clone()),
+                headers,
+                Json(EventRequest {
+                    device: "esp32-scd40".to_string(),
+                    kind: "party".to_string(),
+                    from: time("2025-01-14T18:00:00Z"),
+                    to: time(to),
+                    note: Some("10 people".to_string()),
+                }),
+            )
+        };
+
+        assert_eq!(
+            add(None, "2025-01-14T23:00:00Z")
+                .await
+                .err()
+                .unwrap()
+                .status,
+            StatusCode::UNAUTHORIZED
+        );
+        let error = add(Some("Bearer secret"), "2025-01-14T17:00:00Z")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(
+            error.error.to_string().contains("before"),
+            "{}",
+            error.error
+        );
+        assert!(fake.queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_and_anomaly_context_show_what_overlaps() {
+        let (state, _) = setup().await;
+        let Json(events) = list_events(
+            State(state.clone()),
+            Query(EventListQuery {
+                device: Some("esp32-scd40".to_string()),
+                from: "2025-01-14T20:00:00Z".to_string(),
+                to: "2025-01-14T22:00:00Z".to_string(),
+            }),
+        )
+        .await
+        .ok()
+        .unwrap();
+        let kinds: Vec<_> = events.iter().map(|e| e.kind.as_str()).collect();
+        assert_eq!(kinds, ["party"]);
+
+        let Json(context) = get_anomaly_context(
+            State(state.clone()),
+            Path("2025-01-14T20:00:00Z".to_string()),
+            Query(AnomalyContextQuery::default()),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert_eq!(context.anomaly.device, "esp32-scd40");
+        assert_eq!(context.events, events);
+    }
+
+    #[tokio::test]
+    async fn anomaly_review_requires_the_api_token() {
+        let (state, fake) = setup().await;
+        let dismiss = |authorization: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = authorization {
+                headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            }
+            dismiss_anomaly(
+                State(state.clone()),
+                Path("2025-01-15T10:00:00Z".to_string()),
+                headers,
+                None,
+            )
+        };
+
+        assert_eq!(
+            dismiss(None).await.err().unwrap().status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            dismiss(Some("Bearer wrong")).await.err().unwrap().status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(fake.queries.lock().unwrap().is_empty());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(require_api_token(&state, &headers).is_ok());
+        // Without a configured token even the right header is refused
+        let mut unconfigured = Arc::try_unwrap(state).ok().unwrap();
+        unconfigured.api_token = None;
+        assert_eq!(
+            require_api_token(&unconfigured, &headers)
+                .err()
+                .unwrap()
+                .status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn failover_follower_refuses_changes() {
+        let (state, _) = setup().await;
+        let mut state = Arc::try_unwrap(state).ok().unwrap();
+        let failover = LeaderStatus::new();
+        failover.set(Status {
+            role: Role::Follower,
+            leader: Some("pi-a".to_string()),
+        });
+        state.failover = Some(failover.clone());
+        let state = Arc::new(state);
+        let set = || {
+            set_maintenance(
+                State(state.clone()),
+                Path("kitchen".to_string()),
+                authorized(),
+                Json(MaintenanceRequest {
+                    until: Utc::now() + chrono::Duration::hours(1),
+                }),
+            )
+        };
+
+        let error = set().await.err().unwrap();
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(error.error.to_string().contains("pi-a"), "{}", error.error);
+
+        failover.set(Status {
+            role: Role::Leader,
+            leader: Some("pi-b".to_string()),
+        });
+        assert!(set().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn every_change_requires_the_api_token() {
+        let (state, fake) = setup().await;
+        let ts = || Path("2025-01-15T10:00:00Z".to_string());
+        let device = || Path("kitchen".to_string());
+        let statuses = [
+            confirm_anomaly(State(state.clone()), ts(), HeaderMap::new(), None)
+                .await
+                .err()
+                .map(|e| e.status),
+            dismiss_anomaly(State(state.clone()), ts(), HeaderMap::new(), None)
+                .await
+                .err()
+                .map(|e| e.status),
+            add_event(
+                State(state.clone()),
+                HeaderMap::new(),
+                Json(EventRequest {
+                    device: "kitchen".to_string(),
+                    kind: "party".to_string(),
+                    from: Utc::now() - chrono::Duration::hours(2),
+                    to: Utc::now(),
+                    note: None,
+                }),
+            )
+            .await
+            .err()
+            .map(|e| e.status),
+            acknowledge_alert(State(state.clone()), Path(1), HeaderMap::new())
+                .await
+                .err()
+                .map(|e| e.status),
+            set_maintenance(
+                State(state.clone()),
+                device(),
+                HeaderMap::new(),
+                Json(MaintenanceRequest { until: Utc::now() }),
+            )
+            .await
+            .err()
+            .map(|e| e.status),
+            submit_device_command(
+                State(state.clone()),
+                device(),
+                HeaderMap::new(),
+                Json(DeviceCommand::Reboot),
+            )
+            .await
+            .err()
+            .map(|e| e.status),
+        ];
+        assert_eq!(statuses, [Some(StatusCode::UNAUTHORIZED); 6]);
+        assert!(fake.queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn maintenance_requires_the_api_token() {
+        let (state, _) = setup().await;
+        let error = set_maintenance(
+            State(state.clone()),
+            Path("kitchen".to_string()),
+            HeaderMap::new(),
+            Json(MaintenanceRequest {
+                until: Utc::now() + chrono::Duration::hours(1),
+            }),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.status, StatusCode::UNAUTHORIZED);
+        let view = get_maintenance(State(state), Path("kitchen".to_string()))
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert!(!view.0.active);
+    }
+
+    #[tokio::test]
+    async fn virtual_devices_take_no_commands() {
+        let (state, _) = setup().await;
+        let error = submit_device_command(
+            State(state.clone()),
+            Path(shared_types::HOME_DEVICE.to_string()),
+            authorized(),
+            Json(DeviceCommand::NoOp),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(
+            error.error.to_string().contains("virtual"),
+            "{}",
+            error.error
+        );
+
+        // A real device gets as far as the relay, which isn't running here
+        let error = submit_device_command(
+            State(state),
+            Path("kitchen".to_string()),
+            authorized(),
+            Json(DeviceCommand::NoOp),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_ne!(error.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn commands_need_the_api_token_and_valid_values() {
+        let (state, _) = setup().await;
+        let submit = |headers, command| {
+            submit_device_command(
+                State(state.clone()),
+                Path("kitchen".to_string()),
+                headers,
+                Json(command),
+            )
+        };
+        let error = submit(HeaderMap::new(), DeviceCommand::Reboot)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.status, StatusCode::UNAUTHORIZED);
+
+        let error = submit(authorized(), DeviceCommand::StartFrc { target_ppm: 5000 })
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(error.error.to_string().contains("5000"), "{}", error.error);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_predictions_without_the_receiver() {
+        let (state, _fake) = setup().await;
+        let response = get_metrics(State(state))
+            .await
+            .map_err(|e| e.error)
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("prediction_requests_total{outcome=\"coalesced\"} 0\n"));
+        assert!(!body.contains("ingest_latency"));
+        assert!(!body.contains("ingest_stage"));
+        assert!(body.ends_with("\n# EOF\n"));
+        assert_eq!(body.matches("# EOF").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn healthz_shows_the_startup_checks_when_verbose() {
+        let (mut state, _fake) = setup().await;
+        let healthz = |state: &Arc<AppState>, verbose| {
+            let state = state.clone();
+            async move {
+                get_healthz(State(state), Query(HealthQuery { verbose }))
+                    .await
+                    .0
+            }
+        };
+        assert_eq!(
+            healthz(&state, 1).await,
+            serde_json::json!({ "status": "ok", "preflight": null })
+        );
+
+        let missing = std::env::temp_dir().join("rpi-processor-web-healthz-missing/model.json");
+        Arc::get_mut(&mut state).unwrap().preflight = Some(PreflightReport {
+            checks: vec![crate::preflight::state_dir("MODEL_STATE_FILE", &missing)],
+        });
+        assert_eq!(
+            healthz(&state, 0).await,
+            serde_json::json!({ "status": "degraded" })
+        );
+        let verbose = healthz(&state, 1).await;
+        assert_eq!(verbose["status"], "degraded");
+        assert_eq!(
+            verbose["preflight"]["checks"][0]["name"],
+            "state dir MODEL_STATE_FILE"
+        );
+        assert_eq!(verbose["preflight"]["checks"][0]["status"], "failed");
     }
 }
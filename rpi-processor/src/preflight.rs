@@ -0,0 +1,788 @@
+//! Startup checks of everything the processor depends on outside itself,
+//! run before any mode starts unless `--no-preflight` is given.
+//!
+//! Every check runs even when an earlier one failed, so a fresh install
+//! learns about all of its mistakes at once. Failing InfluxDB or MQTT checks
+//! are hard: nothing works without them and the processor refuses to start.
+//! Alert and digest destinations and the directories of the state files are
+//! soft: the processor starts and logs a warning. The report is kept for
+//! `/healthz?verbose=1`.
+//!
+//! Destinations get a `HEAD` request, which reaches the endpoint without
+//! delivering anything. MQTT is only checked when receiving live data.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use rumqttc::{AsyncClient, ConnectionError, Event, MqttOptions, Packet, QoS, SubscribeReasonCode};
+use serde::Serialize;
+use shared_types::topics;
+
+/// How long each check waits for an answer
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The processor refuses to start
+    Hard,
+    /// The processor starts with a warning
+    Soft,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "message", rename_all = "snake_case")]
+pub enum Outcome {
+    Passed,
+    /// What is wrong and how to fix it
+    Failed(String),
+    /// Not tried because a check it depends on failed
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub severity: Severity,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, severity: Severity, outcome: Outcome) -> Self {
+        Self {
+            name: name.into(),
+            severity,
+            outcome,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_))
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.outcome, self.severity) {
+            (Outcome::Passed, _) => write!(f, "ok   {}", self.name),
+            (Outcome::Failed(message), Severity::Hard) => {
+                write!(f, "FAIL {}: {}", self.name, message)
+            }
+            (Outcome::Failed(message), Severity::Soft) => {
+                write!(f, "warn {}: {}", self.name, message)
+            }
+            (Outcome::Skipped(reason), _) => write!(f, "skip {}: {}", self.name, reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PreflightReport {
+    pub checks: Vec<Check>,
+}
+
+impl PreflightReport {
+    pub fn hard_failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|c| c.failed() && c.severity == Severity::Hard)
+            .count()
+    }
+
+    pub fn soft_failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|c| c.failed() && c.severity == Severity::Soft)
+            .count()
+    }
+
+    /// One line per check, failures first
+    pub fn describe(&self) -> String {
+        let mut checks: Vec<&Check> = self.checks.iter().collect();
+        checks.sort_by_key(|c| (!c.failed(), c.severity == Severity::Soft));
+        let mut text = format!(
+            "Preflight: {} checks, {} hard and {} soft failures",
+            self.checks.len(),
+            self.hard_failures(),
+            self.soft_failures()
+        );
+        for check in checks {
+            text.push_str(&format!("\n  {}", check));
+        }
+        text
+    }
+}
+
+/// The broker the receiver connects to, from `MQTT_BROKER_HOST` (default
+/// `localhost`), `MQTT_BROKER_PORT` (1883), `MQTT_CLIENT_ID` and
+/// `MQTT_TOPIC` (every sensor topic)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub topic: String,
+}
+
+impl BrokerSettings {
+    pub fn from_env() -> Result<Self, String> {
+        let var = |key: &str| std::env::var(key).ok();
+        Ok(Self {
+            host: var("MQTT_BROKER_HOST").unwrap_or_else(|| "localhost".to_string()),
+            port: match var("MQTT_BROKER_PORT") {
+                Some(port) => port
+                    .parse()
+                    .map_err(|_| "MQTT_BROKER_PORT must be a valid u16".to_string())?,
+                None => 1883,
+            },
+            client_id: var("MQTT_CLIENT_ID").unwrap_or_else(|| "raspberry-pi-receiver".to_string()),
+            topic: var("MQTT_TOPIC").unwrap_or_else(|| topics::sensor_wildcard().to_string()),
+        })
+    }
+}
+
+/// Everything `run` checks. Only InfluxDB is always there.
+#[derive(Debug, Clone, Default)]
+pub struct Targets {
+    pub influx_host: String,
+    pub influx_token: String,
+    pub influx_database: String,
+    pub broker: Option<BrokerSettings>,
+    /// Where the URL is configured, e.g. `ALERT_CRITICAL`, and the URL
+    pub endpoints: Vec<(String, String)>,
+    /// `SMTP_HOST` and `SMTP_PORT`
+    pub smtp: Option<(String, u16)>,
+    /// The variable naming a state file, and the file
+    pub state_files: Vec<(String, PathBuf)>,
+}
+
+/// Runs every check that applies to `targets`.
+pub async fn run(reqwest_client: &reqwest::Client, targets: &Targets) -> PreflightReport {
+    let mut checks = Vec::new();
+    let reachable = influx_reachable(reqwest_client, &targets.influx_host).await;
+    if reachable.failed() {
+        let reason = Outcome::Skipped("InfluxDB is unreachable".to_string());
+        checks.push(reachable);
+        checks.push(Check::new("influxdb token", Severity::Hard, reason.clone()));
+        checks.push(Check::new("influxdb database", Severity::Hard, reason));
+    } else {
+        checks.push(reachable);
+        let token = influx_token(
+            reqwest_client,
+            &targets.influx_host,
+            &targets.influx_token,
+            &targets.influx_database,
+        )
+        .await;
+        let database = if token.failed() {
+            Check::new(
+                "influxdb database",
+                Severity::Hard,
+                Outcome::Skipped("the token was rejected".to_string()),
+            )
+        } else {
+            influx_database(
+                reqwest_client,
+                &targets.influx_host,
+                &targets.influx_token,
+                &targets.influx_database,
+            )
+            .await
+        };
+        checks.push(token);
+        checks.push(database);
+    }
+    if let Some(broker) = &targets.broker {
+        checks.extend(mqtt(broker).await);
+    }
+    for (source, url) in &targets.endpoints {
+        checks.push(endpoint(reqwest_client, source, url).await);
+    }
+    if let Some((host, port)) = &targets.smtp {
+        checks.push(smtp(host, *port).await);
+    }
+    for (variable, path) in &targets.state_files {
+        checks.push(state_dir(variable, path));
+    }
+    PreflightReport { checks }
+}
+
+fn query(
+    reqwest_client: &reqwest::Client,
+    host: &str,
+    token: &str,
+    database: &str,
+) -> reqwest::RequestBuilder {
+    reqwest_client
+        .post(format!("{}/api/v3/query_sql?db={}", host, database))
+        .bearer_auth(token)
+        .timeout(CHECK_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .body(serde_json::json!({ "db": database, "q": "SELECT 1" }).to_string())
+}
+
+/// Any HTTP answer from `/ping` counts, even 401: it came from InfluxDB.
+pub async fn influx_reachable(reqwest_client: &reqwest::Client, host: &str) -> Check {
+    let outcome = match reqwest_client
+        .get(format!("{}/ping", host))
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(_) => Outcome::Passed,
+        Err(e) => Outcome::Failed(format!(
+            "cannot reach INFLUXDB_URL {}: {}; check the URL and that InfluxDB is running",
+            host, e
+        )),
+    };
+    Check::new("influxdb reachable", Severity::Hard, outcome)
+}
+
+pub async fn influx_token(
+    reqwest_client: &reqwest::Client,
+    host: &str,
+    token: &str,
+    database: &str,
+) -> Check {
+    let outcome = match query(reqwest_client, host, token, database).send().await {
+        Ok(response)
+            if matches!(
+                response.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) =>
+        {
+            Outcome::Failed(format!(
+                "InfluxDB rejected INFLUXDB_TOKEN ({}); create one with \
+                 `influxdb3 create token --admin` and set INFLUXDB_TOKEN",
+                response.status()
+            ))
+        }
+        Ok(_) => Outcome::Passed,
+        Err(e) => Outcome::Failed(format!("cannot query InfluxDB: {}", e)),
+    };
+    Check::new("influxdb token", Severity::Hard, outcome)
+}
+
+pub async fn influx_database(
+    reqwest_client: &reqwest::Client,
+    host: &str,
+    token: &str,
+    database: &str,
+) -> Check {
+    let outcome = match query(reqwest_client, host, token, database).send().await {
+        Ok(response) if response.status().is_success() => Outcome::Passed,
+        Ok(response) => {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            if status == StatusCode::NOT_FOUND || body.contains("not found") {
+                Outcome::Failed(format!(
+                    "INFLUXDB_DATABASE {} doesn't exist; create it with \
+                     `influxdb3 create database {}` or fix the name",
+                    database, database
+                ))
+            } else {
+                Outcome::Failed(format!(
+                    "test query failed with status {}: {}",
+                    status, body
+                ))
+            }
+        }
+        Err(e) => Outcome::Failed(format!("cannot query InfluxDB: {}", e)),
+    };
+    Check::new("influxdb database", Severity::Hard, outcome)
+}
+
+/// Connects with the receiver's client id plus `-preflight` and subscribes
+/// to its topic: a connect check and a subscribe check.
+pub async fn mqtt(broker: &BrokerSettings) -> Vec<Check> {
+    let address = format!("{}:{}", broker.host, broker.port);
+    let mut options = MqttOptions::new(
+        format!("{}-preflight", broker.client_id),
+        &broker.host,
+        broker.port,
+    );
+    options.set_clean_session(true);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    let connected = tokio::time::timeout(CHECK_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    })
+    .await;
+    let connect = match connected {
+        Ok(Ok(())) => Outcome::Passed,
+        Ok(Err(ConnectionError::ConnectionRefused(code))) => Outcome::Failed(format!(
+            "broker at {} refused the connection ({:?}); check the broker's credentials and \
+             that it allows client id {}",
+            address, code, broker.client_id
+        )),
+        Ok(Err(e)) => Outcome::Failed(format!(
+            "cannot reach the broker at {}: {}; check MQTT_BROKER_HOST and MQTT_BROKER_PORT",
+            address, e
+        )),
+        Err(_) => Outcome::Failed(format!(
+            "no answer from the broker at {} within {}s",
+            address,
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    if connect != Outcome::Passed {
+        return vec![
+            Check::new("mqtt connect", Severity::Hard, connect),
+            Check::new(
+                "mqtt subscribe",
+                Severity::Hard,
+                Outcome::Skipped("not connected".to_string()),
+            ),
+        ];
+    }
+
+    let subscribed = match client.subscribe(&broker.topic, QoS::AtLeastOnce).await {
+        Ok(()) => {
+            tokio::time::timeout(CHECK_TIMEOUT, async {
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::SubAck(ack))) => {
+                            return Ok(ack
+                                .return_codes
+                                .iter()
+                                .all(|code| matches!(code, SubscribeReasonCode::Success(_))));
+                        }
+                        Ok(_) => {}
+                        Err(e) => return Err(e.to_string()),
+                    }
+                }
+            })
+            .await
+        }
+        Err(e) => Ok(Err(e.to_string())),
+    };
+    let subscribe = match subscribed {
+        Ok(Ok(true)) => Outcome::Passed,
+        Ok(Ok(false)) => Outcome::Failed(format!(
+            "broker refused the subscription to {}; allow it in the broker's ACL or fix MQTT_TOPIC",
+            broker.topic
+        )),
+        Ok(Err(e)) => Outcome::Failed(format!("subscribing to {} failed: {}", broker.topic, e)),
+        Err(_) => Outcome::Failed(format!(
+            "no answer to the subscription to {} within {}s",
+            broker.topic,
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    let _ = client.disconnect().await;
+    vec![
+        Check::new("mqtt connect", Severity::Hard, Outcome::Passed),
+        Check::new("mqtt subscribe", Severity::Hard, subscribe),
+    ]
+}
+
+/// A `HEAD` request to an alert or digest destination. Endpoints that only
+/// take `POST` may answer 404 or 405; only refusals and server errors fail.
+pub async fn endpoint(reqwest_client: &reqwest::Client, source: &str, url: &str) -> Check {
+    let outcome = match reqwest_client.head(url).timeout(CHECK_TIMEOUT).send().await {
+        Ok(response)
+            if matches!(
+                response.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) =>
+        {
+            Outcome::Failed(format!(
+                "{} refuses requests without credentials ({}); deliveries will fail too",
+                url,
+                response.status()
+            ))
+        }
+        Ok(response) if response.status().is_server_error() => Outcome::Failed(format!(
+            "{} answered {}; deliveries may fail until it recovers",
+            url,
+            response.status()
+        )),
+        Ok(_) => Outcome::Passed,
+        Err(e) => Outcome::Failed(format!("cannot reach {}: {}; check {}", url, e, source)),
+    };
+    Check::new(format!("endpoint {}", source), Severity::Soft, outcome)
+}
+
+/// Connects and waits for the server's `220` greeting, without sending
+/// anything.
+pub async fn smtp(host: &str, port: u16) -> Check {
+    use tokio::io::AsyncBufReadExt;
+
+    let greeting = tokio::time::timeout(CHECK_TIMEOUT, async {
+        let stream = tokio::net::TcpStream::connect((host, port)).await?;
+        let mut line = String::new();
+        tokio::io::BufReader::new(stream)
+            .read_line(&mut line)
+            .await?;
+        Ok::<_, std::io::Error>(line)
+    })
+    .await;
+    let outcome = match greeting {
+        Ok(Ok(line)) if line.starts_with("220") => Outcome::Passed,
+        Ok(Ok(line)) => Outcome::Failed(format!(
+            "{}:{} is not ready for mail: {}",
+            host,
+            port,
+            line.trim_end()
+        )),
+        Ok(Err(e)) => Outcome::Failed(format!(
+            "cannot reach {}:{}: {}; check SMTP_HOST and SMTP_PORT",
+            host, port, e
+        )),
+        Err(_) => Outcome::Failed(format!(
+            "no greeting from {}:{} within {}s",
+            host,
+            port,
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    Check::new("smtp", Severity::Soft, outcome)
+}
+
+/// State files are replaced through a temporary file next to them, so their
+/// directory has to take new files.
+pub fn state_dir(variable: &str, path: &Path) -> Check {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".preflight-{}", std::process::id()));
+    let outcome = match std::fs::write(&probe, b"").and_then(|()| std::fs::remove_file(&probe)) {
+        Ok(()) => Outcome::Passed,
+        Err(e) => Outcome::Failed(format!(
+            "cannot write to {} ({}): {}; make it writable or point {} elsewhere",
+            dir.display(),
+            path.display(),
+            e,
+            variable
+        )),
+    };
+    Check::new(format!("state dir {}", variable), Severity::Soft, outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    /// Stands in for InfluxDB, knowing only the token "token" and the
+    /// database "db"
+    async fn fake_influx() -> String {
+        async fn fake_query(
+            headers: HeaderMap,
+            axum::extract::Json(body): axum::extract::Json<serde_json::Value>,
+        ) -> (StatusCode, String) {
+            if headers["authorization"] != "Bearer token" {
+                return (StatusCode::UNAUTHORIZED, "unauthorized".to_string());
+            }
+            if body["db"] != "db" {
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!("database not found: {}", body["db"]),
+                );
+            }
+            (StatusCode::OK, r#"[{"Int64(1)":1}]"#.to_string())
+        }
+        serve(
+            Router::new()
+                .route(
+                    "/ping",
+                    axum::routing::get(|| async { StatusCode::UNAUTHORIZED }),
+                )
+                .route("/api/v3/query_sql", post(fake_query)),
+        )
+        .await
+    }
+
+    async fn unused_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn targets(influx_host: String, token: &str, database: &str) -> Targets {
+        Targets {
+            influx_host,
+            influx_token: token.to_string(),
+            influx_database: database.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn outcomes(report: &PreflightReport) -> Vec<(&str, &Outcome)> {
+        report
+            .checks
+            .iter()
+            .map(|c| (c.name.as_str(), &c.outcome))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_healthy_influx_passes() {
+        let host = fake_influx().await;
+        let report = run(&reqwest::Client::new(), &targets(host, "token", "db")).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("influxdb reachable", &Outcome::Passed),
+                ("influxdb token", &Outcome::Passed),
+                ("influxdb database", &Outcome::Passed),
+            ]
+        );
+        assert_eq!((report.hard_failures(), report.soft_failures()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_token_skips_the_database() {
+        let host = fake_influx().await;
+        let report = run(&reqwest::Client::new(), &targets(host, "stale", "db")).await;
+        assert!(
+            matches!(&report.checks[1].outcome, Outcome::Failed(m) if m.contains("INFLUXDB_TOKEN")),
+            "{:?}",
+            report.checks[1]
+        );
+        assert!(matches!(report.checks[2].outcome, Outcome::Skipped(_)));
+        assert_eq!(report.hard_failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_missing_database_says_how_to_create_it() {
+        let host = fake_influx().await;
+        let client = reqwest::Client::new();
+        assert_eq!(
+            influx_token(&client, &host, "token", "air").await.outcome,
+            Outcome::Passed
+        );
+        let check = influx_database(&client, &host, "token", "air").await;
+        assert!(
+            matches!(&check.outcome, Outcome::Failed(m) if m.contains("influxdb3 create database air")),
+            "{:?}",
+            check
+        );
+    }
+
+    #[tokio::test]
+    async fn every_failure_is_reported_not_just_the_first() {
+        let port = unused_port().await;
+        let dir = std::env::temp_dir().join(format!("rpi-processor-preflight-{}", port));
+        let mut targets = targets(format!("http://127.0.0.1:{}", port), "token", "db");
+        targets.broker = Some(BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            client_id: "receiver".to_string(),
+            topic: "sensors/#".to_string(),
+        });
+        targets.endpoints = vec![(
+            "ALERT_CRITICAL".to_string(),
+            format!("http://127.0.0.1:{}/alerts", port),
+        )];
+        targets.state_files = vec![("MODEL_STATE_FILE".to_string(), dir.join("model.json"))];
+
+        let report = run(&reqwest::Client::new(), &targets).await;
+        let failed: Vec<&str> = report
+            .checks
+            .iter()
+            .filter(|c| c.failed())
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                "influxdb reachable",
+                "mqtt connect",
+                "endpoint ALERT_CRITICAL",
+                "state dir MODEL_STATE_FILE"
+            ]
+        );
+        assert_eq!((report.hard_failures(), report.soft_failures()), (2, 2));
+        let described = report.describe();
+        assert!(
+            described.starts_with("Preflight: 7 checks, 2 hard and 2 soft failures\n  FAIL influxdb reachable: cannot reach INFLUXDB_URL"),
+            "{}",
+            described
+        );
+        assert!(
+            described.ends_with("skip mqtt subscribe: not connected"),
+            "{}",
+            described
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoints_pass_unless_they_refuse() {
+        let host = serve(
+            Router::new()
+                .route("/hook", post(|| async { StatusCode::NO_CONTENT }))
+                .route(
+                    "/private",
+                    axum::routing::head(|| async { StatusCode::FORBIDDEN }),
+                ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        // 405 for HEAD on a POST-only route is still the endpoint answering
+        let hook = endpoint(&client, "DIGEST_WEBHOOK_URL", &format!("{}/hook", host)).await;
+        assert_eq!(hook.outcome, Outcome::Passed);
+        let private = endpoint(&client, "ALERT_INFO", &format!("{}/private", host)).await;
+        assert!(
+            matches!(&private.outcome, Outcome::Failed(m) if m.contains("403")),
+            "{:?}",
+            private
+        );
+        assert_eq!(private.severity, Severity::Soft);
+    }
+
+    #[tokio::test]
+    async fn smtp_needs_a_greeting() {
+        async fn server(greeting: &'static [u8]) -> u16 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(greeting).await.unwrap();
+            });
+            port
+        }
+        let ready = server(b"220 mail.example.com ESMTP\r\n").await;
+        assert_eq!(smtp("127.0.0.1", ready).await.outcome, Outcome::Passed);
+        let busy = server(b"554 no service\r\n").await;
+        assert!(
+            matches!(smtp("127.0.0.1", busy).await.outcome, Outcome::Failed(m) if m.contains("554")),
+        );
+    }
+
+    #[test]
+    fn state_dirs_must_take_new_files() {
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            state_dir("ALERT_STATE_FILE", &dir.join("alerts.json")).outcome,
+            Outcome::Passed
+        );
+        let check = state_dir(
+            "ALERT_STATE_FILE",
+            &dir.join("rpi-processor-preflight-missing/alerts.json"),
+        );
+        assert!(
+            matches!(&check.outcome, Outcome::Failed(m) if m.contains("point ALERT_STATE_FILE elsewhere")),
+            "{:?}",
+            check
+        );
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+        let mut length = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    /// A single-connection MQTT 3.1.1 broker accepting with `connack_code`
+    /// and answering subscriptions with `suback_code`
+    async fn fake_broker(connack_code: u8, suback_code: u8) -> BrokerSettings {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_packet(&mut stream).await.unwrap();
+            stream
+                .write_all(&[0x20, 0x02, 0x00, connack_code])
+                .await
+                .unwrap();
+            while let Some((header, body)) = read_packet(&mut stream).await {
+                if header >> 4 == 8 {
+                    stream
+                        .write_all(&[0x90, 0x03, body[0], body[1], suback_code])
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        BrokerSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            client_id: "receiver".to_string(),
+            topic: "sensors/#".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mqtt_checks_connecting_and_subscribing() {
+        let checks = mqtt(&fake_broker(0, 0x01).await).await;
+        assert!(
+            checks.iter().all(|c| c.outcome == Outcome::Passed),
+            "{:?}",
+            checks
+        );
+
+        // 0x80: the broker's ACL doesn't allow the topic
+        let checks = mqtt(&fake_broker(0, 0x80).await).await;
+        assert_eq!(checks[0].outcome, Outcome::Passed);
+        assert!(
+            matches!(&checks[1].outcome, Outcome::Failed(m) if m.contains("ACL")),
+            "{:?}",
+            checks
+        );
+
+        // 5: not authorized
+        let checks = mqtt(&fake_broker(5, 0x01).await).await;
+        assert!(
+            matches!(&checks[0].outcome, Outcome::Failed(m) if m.contains("refused")),
+            "{:?}",
+            checks
+        );
+        assert!(matches!(checks[1].outcome, Outcome::Skipped(_)));
+    }
+
+    #[test]
+    fn the_verbose_healthz_form_is_flat() {
+        let report = PreflightReport {
+            checks: vec![
+                Check::new("influxdb reachable", Severity::Hard, Outcome::Passed),
+                Check::new(
+                    "smtp",
+                    Severity::Soft,
+                    Outcome::Failed("cannot reach".to_string()),
+                ),
+            ],
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({ "checks": [
+                { "name": "influxdb reachable", "severity": "hard", "status": "passed" },
+                {
+                    "name": "smtp", "severity": "soft",
+                    "status": "failed", "message": "cannot reach"
+                }
+            ]})
+        );
+    }
+}
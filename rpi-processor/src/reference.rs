@@ -0,0 +1,714 @@
+//! External reference measurements (e.g. a borrowed Aranet4) and comparison
+//! against a device's own series.
+
+use std::{collections::HashMap, error::Error, str::FromStr};
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::bulk_write::{BulkWriter, InfluxStore, Point, Progress, log_progress};
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::options::parse_kv_options;
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+/// Below these the device is considered in agreement with the reference.
+const CO2_BIAS_THRESHOLD_PPM: f64 = 30.0;
+const TEMPERATURE_BIAS_THRESHOLD_C: f64 = 0.5;
+/// Fewer aligned pairs than this aren't enough to recommend anything.
+const MIN_PAIRS_FOR_RECOMMENDATION: usize = 6;
+
+const NAIVE_TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%d/%m/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M",
+    "%d.%m.%Y %H:%M:%S",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReferenceRow {
+    pub time: DateTime<Utc>,
+    pub source: String,
+    pub co2: Option<f64>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Column {
+    Time,
+    Co2,
+    Temperature,
+    Humidity,
+    Source,
+}
+
+impl Column {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "time" => Some(Column::Time),
+            "co2" => Some(Column::Co2),
+            "temperature" => Some(Column::Temperature),
+            "humidity" => Some(Column::Humidity),
+            "source" => Some(Column::Source),
+            _ => None,
+        }
+    }
+
+    /// Recognizes common export headers, e.g. Aranet4's
+    /// `Time(DD/MM/YYYY H:mm:ss)` or `Carbon dioxide(ppm)`.
+    fn detect(header: &str) -> Option<Self> {
+        let header = header.trim().to_lowercase();
+        if header.starts_with("time") || header.starts_with("date") {
+            Some(Column::Time)
+        } else if header.contains("co2") || header.contains("carbon dioxide") {
+            Some(Column::Co2)
+        } else if header.starts_with("temp") {
+            Some(Column::Temperature)
+        } else if header.contains("humidity") || header == "rh" || header.starts_with("rh ") {
+            Some(Column::Humidity)
+        } else if matches!(header.as_str(), "source" | "sensor" | "device") {
+            Some(Column::Source)
+        } else {
+            None
+        }
+    }
+}
+
+/// Explicit header names, e.g. `time=Logged at,co2=CO2 ppm`. Columns that
+/// aren't listed are detected from the header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnOverrides(HashMap<String, String>);
+
+impl FromStr for ColumnOverrides {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overrides = HashMap::new();
+        for (column, header) in parse_kv_options(s)? {
+            if Column::from_name(column).is_none() {
+                return Err(format!(
+                    "unknown column '{}' (expected time, co2, temperature, humidity or source)",
+                    column
+                ));
+            }
+            overrides.insert(column.to_string(), header.to_string());
+        }
+        Ok(Self(overrides))
+    }
+}
+
+fn resolve_columns(
+    headers: &csv::StringRecord,
+    overrides: &ColumnOverrides,
+) -> Result<HashMap<Column, usize>, String> {
+    let mut columns = HashMap::new();
+    for (idx, header) in headers.iter().enumerate() {
+        if let Some(column) = Column::detect(header) {
+            columns.entry(column).or_insert(idx);
+        }
+    }
+    for (name, header) in &overrides.0 {
+        let column = Column::from_name(name).expect("validated when parsing overrides");
+        let idx = headers
+            .iter()
+            .position(|h| h.trim() == header)
+            .ok_or_else(|| format!("column '{}' not found in CSV header", header))?;
+        columns.insert(column, idx);
+    }
+    if !columns.contains_key(&Column::Time) {
+        return Err("no time column found (use --reference-columns time=<header>)".to_string());
+    }
+    if ![Column::Co2, Column::Temperature, Column::Humidity]
+        .iter()
+        .any(|c| columns.contains_key(c))
+    {
+        return Err("no co2, temperature or humidity column found".to_string());
+    }
+    Ok(columns)
+}
+
+/// Parses RFC 3339, unix seconds, or one of the common naive formats, which
+/// are taken to be in `naive_offset`.
+pub fn parse_time(value: &str, naive_offset: FixedOffset) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        return DateTime::from_timestamp(value.parse().ok()?, 0);
+    }
+    NAIVE_TIME_FORMATS.iter().find_map(|format| {
+        let naive = NaiveDateTime::parse_from_str(value, format).ok()?;
+        naive_offset
+            .from_local_datetime(&naive)
+            .single()
+            .map(|dt| dt.with_timezone(&Utc))
+    })
+}
+
+fn parse_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        value.parse().ok()
+    }
+}
+
+pub fn parse_reference_csv(
+    text: &str,
+    overrides: &ColumnOverrides,
+    default_source: &str,
+    naive_offset: FixedOffset,
+) -> Result<Vec<ReferenceRow>, Box<dyn Error>> {
+    let header_line = text.lines().next().unwrap_or_default();
+    let delimiter = if header_line.matches(';').count() > header_line.matches(',').count() {
+        b';'
+    } else {
+        b','
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let columns = resolve_columns(reader.headers()?, overrides)?;
+    let field = |record: &csv::StringRecord, column| {
+        columns
+            .get(&column)
+            .and_then(|&idx| record.get(idx))
+            .unwrap_or("")
+            .to_string()
+    };
+
+    let mut rows = Vec::new();
+    for (idx, record) in reader.records().enumerate() {
+        let record = record?;
+        if record.iter().all(|f| f.is_empty()) {
+            continue;
+        }
+        let line = idx + 2;
+        let time_value = field(&record, Column::Time);
+        let time = parse_time(&time_value, naive_offset)
+            .ok_or_else(|| format!("line {}: unrecognized timestamp '{}'", line, time_value))?;
+        let source = field(&record, Column::Source);
+        rows.push(ReferenceRow {
+            time,
+            source: if source.is_empty() {
+                default_source.to_string()
+            } else {
+                source
+            },
+            co2: parse_value(&field(&record, Column::Co2)),
+            temperature: parse_value(&field(&record, Column::Temperature)),
+            humidity: parse_value(&field(&record, Column::Humidity)),
+        });
+    }
+    Ok(rows)
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+pub fn to_line_protocol(row: &ReferenceRow) -> Option<String> {
+    let fields: Vec<String> = [
+        ("co2_ppm", row.co2),
+        ("temperature_c", row.temperature),
+        ("humidity_percent", row.humidity),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|v| format!("{}={}", name, v)))
+    .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "reference_data,source={} {} {}",
+        escape_tag(&row.source),
+        fields.join(","),
+        row.time.timestamp_nanos_opt().unwrap_or(0)
+    ))
+}
+
+/// Pairs every reference row with the device measurement closest in time,
+/// dropping rows with no measurement within `tolerance`. `device` must be
+/// sorted by time.
+pub fn align<'a>(
+    reference: &'a [ReferenceRow],
+    device: &'a [MeasurementWithTime],
+    tolerance: Duration,
+) -> Vec<(&'a ReferenceRow, &'a MeasurementWithTime)> {
+    reference
+        .iter()
+        .filter_map(|r| {
+            let idx = device.partition_point(|m| m.time < r.time);
+            let before = idx.checked_sub(1).map(|i| &device[i]);
+            let after = device.get(idx);
+            let nearest = match (before, after) {
+                (Some(b), Some(a)) => {
+                    if r.time - b.time <= a.time - r.time {
+                        b
+                    } else {
+                        a
+                    }
+                }
+                (Some(m), None) | (None, Some(m)) => m,
+                (None, None) => return None,
+            };
+            ((nearest.time - r.time).abs() <= tolerance).then_some((r, nearest))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricComparison {
+    pub samples: usize,
+    /// Mean of device minus reference
+    pub mean_bias: f64,
+    pub rmse: f64,
+}
+
+pub fn compare_metric(pairs: impl Iterator<Item = (f64, f64)>) -> Option<MetricComparison> {
+    let (mut samples, mut sum, mut sum_sq) = (0usize, 0.0, 0.0);
+    for (device, reference) in pairs {
+        let diff = device - reference;
+        samples += 1;
+        sum += diff;
+        sum_sq += diff * diff;
+    }
+    (samples > 0).then(|| MetricComparison {
+        samples,
+        mean_bias: sum / samples as f64,
+        rmse: (sum_sq / samples as f64).sqrt(),
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Recommendation {
+    /// Run `frc <ppm>` with this target while the device sits next to the reference
+    pub frc_target_ppm: Option<u16>,
+    /// Add this to the device's current temperature offset
+    pub temperature_offset_change: Option<f64>,
+    pub notes: Vec<String>,
+}
+
+/// The SCD4x subtracts its temperature offset from the raw reading, so a
+/// device reading high needs a larger offset.
+pub fn recommend(
+    co2: Option<&MetricComparison>,
+    temperature: Option<&MetricComparison>,
+    latest_reference_co2: Option<f64>,
+) -> Recommendation {
+    let mut recommendation = Recommendation::default();
+
+    match co2 {
+        Some(c) if c.samples < MIN_PAIRS_FOR_RECOMMENDATION => recommendation
+            .notes
+            .push(format!("Only {} CO2 pairs, not enough to judge", c.samples)),
+        Some(c) if c.mean_bias.abs() > CO2_BIAS_THRESHOLD_PPM => {
+            recommendation.frc_target_ppm = latest_reference_co2.map(|ppm| ppm.round() as u16);
+            recommendation.notes.push(format!(
+                "CO2 reads {:.0} ppm {} than the reference; recalibrate with the reference's current reading",
+                c.mean_bias.abs(),
+                if c.mean_bias > 0.0 { "higher" } else { "lower" }
+            ));
+        }
+        Some(_) => recommendation
+            .notes
+            .push("CO2 agrees with the reference".to_string()),
+        None => {}
+    }
+
+    match temperature {
+        Some(t) if t.samples < MIN_PAIRS_FOR_RECOMMENDATION => recommendation.notes.push(format!(
+            "Only {} temperature pairs, not enough to judge",
+            t.samples
+        )),
+        Some(t) if t.mean_bias.abs() > TEMPERATURE_BIAS_THRESHOLD_C => {
+            let change = (t.mean_bias * 10.0).round() / 10.0;
+            recommendation.temperature_offset_change = Some(change);
+            recommendation.notes.push(format!(
+                "Temperature reads {:.1}°C {}; change the offset by {:+.1}°C (see get-offset)",
+                t.mean_bias.abs(),
+                if t.mean_bias > 0.0 { "high" } else { "low" },
+                change
+            ));
+        }
+        Some(_) => recommendation
+            .notes
+            .push("Temperature agrees with the reference".to_string()),
+        None => {}
+    }
+
+    recommendation
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub device: String,
+    pub source: Option<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub reference_samples: usize,
+    pub aligned_pairs: usize,
+    pub co2: Option<MetricComparison>,
+    pub temperature: Option<MetricComparison>,
+    pub humidity: Option<MetricComparison>,
+    pub recommendation: Recommendation,
+}
+
+pub fn build_comparison(
+    device_name: &str,
+    source: Option<&str>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    reference: &[ReferenceRow],
+    device: &[MeasurementWithTime],
+    tolerance: Duration,
+) -> Comparison {
+    let pairs = align(reference, device, tolerance);
+    let co2 = compare_metric(
+        pairs
+            .iter()
+            .filter_map(|(r, m)| r.co2.map(|c| (m.co2 as f64, c))),
+    );
+    let temperature = compare_metric(
+        pairs
+            .iter()
+            .filter_map(|(r, m)| r.temperature.map(|t| (m.temperature as f64, t))),
+    );
+    let humidity = compare_metric(
+        pairs
+            .iter()
+            .filter_map(|(r, m)| r.humidity.map(|h| (m.humidity as f64, h))),
+    );
+    let latest_reference_co2 = pairs.iter().rev().find_map(|(r, _)| r.co2);
+    let recommendation = recommend(co2.as_ref(), temperature.as_ref(), latest_reference_co2);
+
+    Comparison {
+        device: device_name.to_string(),
+        source: source.map(str::to_string),
+        from,
+        to,
+        reference_samples: reference.len(),
+        aligned_pairs: pairs.len(),
+        co2,
+        temperature,
+        humidity,
+        recommendation,
+    }
+}
+
+pub async fn import_reference(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    rows: &[ReferenceRow],
+) -> Result<Progress, Box<dyn Error>> {
+    let writer = BulkWriter {
+        idempotent: true,
+        ..BulkWriter::new(InfluxStore {
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+        })
+    };
+    let points: Vec<Point> = rows
+        .iter()
+        .filter_map(|row| {
+            Some(Point {
+                measurement: "reference_data",
+                series_tag: "source",
+                series: row.source.clone(),
+                time: row.time,
+                line: to_line_protocol(row)?,
+            })
+        })
+        .collect();
+    writer
+        .write(points, log_progress("Reference import"))
+        .await
+        .map_err(|e| format!("Failed to write reference data to InfluxDB: {}", e).into())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn compare_reference(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: &Identifier,
+    source: Option<&Identifier>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    tolerance: Duration,
+) -> Result<Comparison, Box<dyn Error>> {
+    #[derive(Deserialize)]
+    struct StoredReferenceRow {
+        time: String,
+        source: String,
+        co2_ppm: Option<f64>,
+        temperature_c: Option<f64>,
+        humidity_percent: Option<f64>,
+    }
+
+    let mut stored_query = Sql::new(
+        "SELECT time, source, co2_ppm, temperature_c, humidity_percent FROM reference_data \
+         WHERE time >= ",
+    )
+    .time(from)
+    .push(" AND time <= ")
+    .time(to);
+    if let Some(source) = source {
+        stored_query = stored_query.push(" AND source = ").identifier(source);
+    }
+    // The device series is widened by the tolerance so edge rows can still match
+    let stored: Vec<StoredReferenceRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &stored_query.push(" ORDER BY time ASC"),
+    )
+    .await?;
+    let measurements: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             WHERE device = ",
+        )
+        .identifier(device)
+        .push(" AND time >= ")
+        .time(from - tolerance)
+        .push(" AND time <= ")
+        .time(to + tolerance)
+        .push(" ORDER BY time ASC"),
+    )
+    .await?;
+
+    let reference: Vec<ReferenceRow> = stored
+        .into_iter()
+        .filter_map(|row| {
+            Some(ReferenceRow {
+                time: parse_time(&row.time, FixedOffset::east_opt(0)?)?,
+                source: row.source,
+                co2: row.co2_ppm,
+                temperature: row.temperature_c,
+                humidity: row.humidity_percent,
+            })
+        })
+        .collect();
+    let device_series = measurements
+        .iter()
+        .map(|row| row.to_measurement_with_time())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(build_comparison(
+        device.as_str(),
+        source.map(Identifier::as_str),
+        from,
+        to,
+        &reference,
+        &device_series,
+        tolerance,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc() -> FixedOffset {
+        FixedOffset::east_opt(0).unwrap()
+    }
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::minutes(minutes)
+    }
+
+    fn reference(minutes: i64, co2: f64, temperature: f64) -> ReferenceRow {
+        ReferenceRow {
+            time: at(minutes),
+            source: "aranet4".to_string(),
+            co2: Some(co2),
+            temperature: Some(temperature),
+            humidity: Some(40.0),
+        }
+    }
+
+    fn measurement(minutes: i64, co2: u16, temperature: f32) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature,
+            humidity: 42.0,
+            time: at(minutes),
+            device: "esp32-scd40".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_aranet_export() {
+        let csv = "Time(DD/MM/YYYY H:mm:ss),Carbon dioxide(ppm),Temperature(°C),Relative humidity(%),Atmospheric pressure(hPa)\n\
+                   01/03/2025 12:00:00,612,22.4,41,1013.2\n\
+                   01/03/2025 12:05:00,618,22.5,,1013.1\n";
+        let rows = parse_reference_csv(
+            csv,
+            &ColumnOverrides::default(),
+            "aranet4",
+            FixedOffset::east_opt(3600).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].time, at(-60));
+        assert_eq!(rows[0].source, "aranet4");
+        assert_eq!(rows[0].co2, Some(612.0));
+        assert_eq!(rows[0].temperature, Some(22.4));
+        assert_eq!(rows[1].humidity, None);
+    }
+
+    #[test]
+    fn parses_with_overrides_semicolons_and_source_column() {
+        let csv = "logged;ppm;sensor;celsius\n\
+                   2025-03-01T12:00:00Z;500;kitchen ref;21.0\n";
+        let overrides: ColumnOverrides = "time=logged,co2=ppm,temperature=celsius".parse().unwrap();
+        let rows = parse_reference_csv(csv, &overrides, "unused", utc()).unwrap();
+
+        assert_eq!(rows[0].time, at(0));
+        assert_eq!(rows[0].source, "kitchen ref");
+        assert_eq!(rows[0].co2, Some(500.0));
+        assert_eq!(rows[0].temperature, Some(21.0));
+        assert_eq!(rows[0].humidity, None);
+    }
+
+    #[test]
+    fn rejects_bad_csv() {
+        let no_time = "co2,temperature\n500,21\n";
+        assert!(parse_reference_csv(no_time, &ColumnOverrides::default(), "r", utc()).is_err());
+
+        let bad_time = "time,co2\nyesterday,500\n";
+        let err = parse_reference_csv(bad_time, &ColumnOverrides::default(), "r", utc())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2"), "{}", err);
+
+        assert!("weight=co2".parse::<ColumnOverrides>().is_err());
+        let missing: ColumnOverrides = "co2=nope".parse().unwrap();
+        assert!(parse_reference_csv("time,co2\n", &missing, "r", utc()).is_err());
+    }
+
+    #[test]
+    fn line_protocol_escapes_source_and_skips_missing_fields() {
+        let mut row = reference(0, 612.0, 22.4);
+        row.source = "Aranet4 Home".to_string();
+        row.humidity = None;
+        assert_eq!(
+            to_line_protocol(&row).unwrap(),
+            format!(
+                "reference_data,source=Aranet4\\ Home co2_ppm=612,temperature_c=22.4 {}",
+                at(0).timestamp_nanos_opt().unwrap()
+            )
+        );
+
+        row.co2 = None;
+        row.temperature = None;
+        assert!(to_line_protocol(&row).is_none());
+    }
+
+    #[test]
+    fn aligns_to_nearest_within_tolerance() {
+        let device = vec![
+            measurement(0, 600, 22.0),
+            measurement(5, 610, 22.0),
+            measurement(10, 620, 22.0),
+        ];
+        let reference = vec![
+            reference(-10, 590.0, 22.0), // too early
+            reference(2, 600.0, 22.0),   // closer to 0
+            reference(4, 600.0, 22.0),   // closer to 5
+            reference(11, 600.0, 22.0),  // after the last, within tolerance
+            reference(30, 600.0, 22.0),  // too late
+        ];
+
+        let pairs = align(&reference, &device, Duration::minutes(3));
+        let matched: Vec<_> = pairs.iter().map(|(r, m)| (r.time, m.co2)).collect();
+        assert_eq!(matched, vec![(at(2), 600), (at(4), 610), (at(11), 620)]);
+    }
+
+    #[test]
+    fn metric_comparison_math() {
+        let c = compare_metric([(10.0, 7.0), (10.0, 11.0), (10.0, 10.0)].into_iter()).unwrap();
+        assert_eq!(c.samples, 3);
+        assert!((c.mean_bias - 2.0 / 3.0).abs() < 1e-12);
+        assert!((c.rmse - (10.0f64 / 3.0).sqrt()).abs() < 1e-12);
+
+        assert!(compare_metric(std::iter::empty()).is_none());
+    }
+
+    #[test]
+    fn recommends_frc_and_offset_for_biased_device() {
+        let device: Vec<_> = (0..10).map(|i| measurement(i * 5, 700, 23.5)).collect();
+        let reference: Vec<_> = (0..10)
+            .map(|i| reference(i * 5, 640.0 + i as f64, 22.3))
+            .collect();
+
+        let comparison = build_comparison(
+            "esp32-scd40",
+            Some("aranet4"),
+            at(0),
+            at(50),
+            &reference,
+            &device,
+            Duration::minutes(2),
+        );
+        assert_eq!(comparison.aligned_pairs, 10);
+        let co2 = comparison.co2.as_ref().unwrap();
+        assert!((co2.mean_bias - 55.5).abs() < 1e-9);
+
+        let rec = &comparison.recommendation;
+        assert_eq!(rec.frc_target_ppm, Some(649));
+        assert_eq!(rec.temperature_offset_change, Some(1.2));
+    }
+
+    #[test]
+    fn no_recommendation_when_in_agreement_or_too_few_pairs() {
+        let agree = MetricComparison {
+            samples: 20,
+            mean_bias: 10.0,
+            rmse: 15.0,
+        };
+        let warm = MetricComparison {
+            samples: 20,
+            mean_bias: 0.2,
+            rmse: 0.3,
+        };
+        let rec = recommend(Some(&agree), Some(&warm), Some(600.0));
+        assert_eq!(rec.frc_target_ppm, None);
+        assert_eq!(rec.temperature_offset_change, None);
+
+        let few = MetricComparison {
+            samples: 2,
+            mean_bias: 200.0,
+            rmse: 200.0,
+        };
+        let rec = recommend(Some(&few), None, Some(600.0));
+        assert_eq!(rec.frc_target_ppm, None);
+        assert!(rec.notes[0].contains("not enough"));
+    }
+}
@@ -0,0 +1,504 @@
+//! `--share-export`: measurements sanitized for publishing.
+//!
+//! The CSV has only time, device, CO2, temperature and humidity, with:
+//!
+//! - device names replaced by `device-1`, `device-2`, ..., numbered in an
+//!   order drawn from the seed rather than by name;
+//! - every time moved by one secret offset of at least a day, earlier or
+//!   later, up to `max_shift_days`. With `keep_time_of_day` the offset is
+//!   whole days, so daily patterns stay true but dates don't;
+//! - times cut to whole seconds, or to `resolution_minutes` with the rows
+//!   of a device falling in the same bucket averaged.
+//!
+//! Tags and derived series that tell when someone is home (maintenance
+//! windows, anomaly markings, hourly threshold minutes, ventilation
+//! estimates, the combined home device) are never read. The CO2 curve
+//! itself still rises when a room is occupied; a coarse resolution blurs
+//! that.
+//!
+//! Everything random comes from the seed, so the same seed and data give
+//! the same export. The manifest written next to the CSV lists what was
+//! done but not the seed or the offset, which undo the shift.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::Serialize;
+use shared_types::HOME_DEVICE;
+
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::options::parse_kv_options;
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+pub const DEFAULT_MAX_SHIFT_DAYS: u32 = 365;
+
+const COLUMNS: [&str; 5] = [
+    "time",
+    "device",
+    "co2_ppm",
+    "temperature_c",
+    "humidity_percent",
+];
+
+/// What every export leaves out
+const EXCLUDED: [&str; 6] = [
+    "original device names",
+    "fw_version and maintenance tags",
+    "anomaly markings",
+    "hourly aggregates and ventilation estimates",
+    "the combined home device",
+    "sub-second timestamps",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareConfig {
+    /// Drives the pseudonyms and the shift; a random one when not given
+    pub seed: Option<u64>,
+    /// 0 keeps whole seconds
+    pub resolution_minutes: u32,
+    pub max_shift_days: u32,
+    pub keep_time_of_day: bool,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            resolution_minutes: 0,
+            max_shift_days: DEFAULT_MAX_SHIFT_DAYS,
+            keep_time_of_day: false,
+        }
+    }
+}
+
+impl FromStr for ShareConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (name, value) in parse_kv_options(s)? {
+            let invalid = || format!("invalid value '{}' for {}", value, name);
+            match name {
+                "seed" => config.seed = Some(value.parse().map_err(|_| invalid())?),
+                "resolution_minutes" => {
+                    config.resolution_minutes = value.parse().map_err(|_| invalid())?
+                }
+                "max_shift_days" => config.max_shift_days = value.parse().map_err(|_| invalid())?,
+                "keep_time_of_day" => {
+                    config.keep_time_of_day = value.parse().map_err(|_| invalid())?
+                }
+                other => return Err(format!("unknown share setting '{}'", other)),
+            }
+        }
+        if config.max_shift_days == 0 {
+            return Err("max_shift_days must be at least 1".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// A seed for an export run without one
+pub fn random_seed() -> u64 {
+    RandomState::new().hash_one(std::process::id())
+}
+
+/// SplitMix64: the same sequence for a seed on every platform and release
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `low..=high`
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+}
+
+/// One row of the CSV
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SharedRow {
+    pub time: String,
+    pub device: String,
+    pub co2_ppm: String,
+    pub temperature_c: String,
+    pub humidity_percent: String,
+}
+
+/// The rows of one device in one bucket
+#[derive(Debug, Default)]
+struct Sums {
+    co2: f64,
+    temperature: f64,
+    humidity: f64,
+    count: f64,
+}
+
+/// The transformations of one export, drawn from its seed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sanitizer {
+    pseudonyms: BTreeMap<String, String>,
+    shift: Duration,
+    /// Bucket length in seconds
+    resolution: i64,
+}
+
+impl Sanitizer {
+    pub fn new(config: &ShareConfig, seed: u64, devices: &BTreeSet<String>) -> Self {
+        let mut rng = Rng(seed);
+        let max_days = u64::from(config.max_shift_days);
+        let seconds = if config.keep_time_of_day {
+            rng.between(1, max_days) * 86_400
+        } else {
+            rng.between(86_400, max_days * 86_400)
+        } as i64;
+        let shift = if rng.next() & 1 == 0 {
+            Duration::seconds(seconds)
+        } else {
+            Duration::seconds(-seconds)
+        };
+
+        // Fisher-Yates, so the numbering says nothing about the names
+        let mut order: Vec<&String> = devices.iter().collect();
+        for i in (1..order.len()).rev() {
+            order.swap(i, rng.between(0, i as u64) as usize);
+        }
+        let pseudonyms = order
+            .into_iter()
+            .enumerate()
+            .map(|(n, device)| (device.clone(), format!("device-{}", n + 1)))
+            .collect();
+
+        Self {
+            pseudonyms,
+            shift,
+            resolution: (i64::from(config.resolution_minutes) * 60).max(1),
+        }
+    }
+
+    pub fn pseudonym(&self, device: &str) -> Option<&str> {
+        self.pseudonyms.get(device).map(String::as_str)
+    }
+
+    /// The shifted time, cut to the resolution
+    pub fn time(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = (time + self.shift).timestamp();
+        let bucket = seconds.div_euclid(self.resolution) * self.resolution;
+        DateTime::from_timestamp(bucket, 0).expect("shifted times stay in range")
+    }
+
+    /// The rows to publish, by time and then device. Devices without a
+    /// pseudonym are left out.
+    pub fn apply(&self, measurements: &[MeasurementWithTime]) -> Vec<SharedRow> {
+        let mut buckets: BTreeMap<(DateTime<Utc>, &str), Sums> = BTreeMap::new();
+        for m in measurements {
+            let Some(device) = self.pseudonym(&m.device) else {
+                continue;
+            };
+            let sums = buckets.entry((self.time(m.time), device)).or_default();
+            sums.co2 += f64::from(m.co2);
+            sums.temperature += f64::from(m.temperature);
+            sums.humidity += f64::from(m.humidity);
+            sums.count += 1.0;
+        }
+        buckets
+            .into_iter()
+            .map(|((time, device), sums)| {
+                let n = sums.count;
+                SharedRow {
+                    time: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    device: device.to_string(),
+                    co2_ppm: format!("{:.0}", sums.co2 / n),
+                    temperature_c: format!("{:.2}", sums.temperature / n),
+                    humidity_percent: format!("{:.2}", sums.humidity / n),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Transformation {
+    /// Names replaced by `device-N`, numbered in a random order
+    PseudonymizeDevices { devices: usize },
+    /// All times moved by the same secret offset
+    ShiftTime {
+        min_days: u32,
+        max_days: u32,
+        keep_time_of_day: bool,
+    },
+    /// Times cut to the resolution, rows in the same bucket averaged
+    TruncateTime { resolution_seconds: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    pub rows: usize,
+    pub columns: Vec<&'static str>,
+    pub transformations: Vec<Transformation>,
+    pub excluded: Vec<&'static str>,
+}
+
+impl Manifest {
+    pub fn new(config: &ShareConfig, sanitizer: &Sanitizer, rows: usize) -> Self {
+        Self {
+            rows,
+            columns: COLUMNS.to_vec(),
+            transformations: vec![
+                Transformation::PseudonymizeDevices {
+                    devices: sanitizer.pseudonyms.len(),
+                },
+                Transformation::ShiftTime {
+                    min_days: 1,
+                    max_days: config.max_shift_days,
+                    keep_time_of_day: config.keep_time_of_day,
+                },
+                Transformation::TruncateTime {
+                    resolution_seconds: sanitizer.resolution,
+                },
+            ],
+            excluded: EXCLUDED.to_vec(),
+        }
+    }
+}
+
+pub fn render_csv(rows: &[SharedRow]) -> Result<String, Box<dyn Error>> {
+    // The header goes first even when there are no rows
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(COLUMNS)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Where the manifest of `csv` goes: `shared.csv` -> `shared.manifest.json`
+pub fn manifest_path(csv: &Path) -> PathBuf {
+    csv.with_extension("manifest.json")
+}
+
+/// Reads the raw measurements between `from` and `to`, of `device` or of
+/// every real device, and writes the sanitized CSV to `path` with its
+/// manifest next to it. Returns the number of rows written.
+#[allow(clippy::too_many_arguments)]
+pub async fn export(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: Option<&Identifier>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    config: &ShareConfig,
+    seed: u64,
+    path: &Path,
+) -> Result<usize, Box<dyn Error>> {
+    let mut sql = Sql::new(
+        "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+         WHERE time >= ",
+    )
+    .time(from)
+    .push(" AND time <= ")
+    .time(to);
+    if let Some(device) = device {
+        sql = sql.push(" AND device = ").identifier(device);
+    }
+    let rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &sql.push(" ORDER BY time ASC"),
+    )
+    .await?;
+    let measurements = rows
+        .iter()
+        .filter(|row| row.device != HOME_DEVICE)
+        .map(|row| row.to_measurement_with_time())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let devices: BTreeSet<String> = measurements.iter().map(|m| m.device.clone()).collect();
+    let sanitizer = Sanitizer::new(config, seed, &devices);
+    let shared = sanitizer.apply(&measurements);
+    let manifest = Manifest::new(config, &sanitizer, shared.len());
+    std::fs::write(path, render_csv(&shared)?)?;
+    std::fs::write(
+        manifest_path(path),
+        serde_json::to_string_pretty(&manifest)? + "\n",
+    )?;
+    Ok(shared.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    fn measurement(device: &str, time: &str, co2: u16) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2,
+            temperature: 21.5,
+            humidity: 40.0,
+            time: at(time),
+            device: device.to_string(),
+        }
+    }
+
+    fn sample() -> Vec<MeasurementWithTime> {
+        vec![
+            measurement("kitchen", "2025-03-01T07:00:12.250Z", 600),
+            measurement("bedroom-anna", "2025-03-01T07:02:40Z", 1100),
+            measurement("kitchen", "2025-03-01T07:05:12Z", 800),
+            measurement("bedroom-anna", "2025-03-01T07:07:40Z", 1200),
+        ]
+    }
+
+    fn sanitizer(config: &ShareConfig, seed: u64) -> Sanitizer {
+        let devices = sample().into_iter().map(|m| m.device).collect();
+        Sanitizer::new(config, seed, &devices)
+    }
+
+    fn csv(config: &ShareConfig, seed: u64) -> String {
+        render_csv(&sanitizer(config, seed).apply(&sample())).unwrap()
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config: ShareConfig =
+            "seed=42, resolution_minutes=15,max_shift_days=30,keep_time_of_day=true"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config,
+            ShareConfig {
+                seed: Some(42),
+                resolution_minutes: 15,
+                max_shift_days: 30,
+                keep_time_of_day: true,
+            }
+        );
+        assert_eq!("".parse::<ShareConfig>().unwrap(), ShareConfig::default());
+        assert!("max_shift_days=0".parse::<ShareConfig>().is_err());
+        assert!("seed=-1".parse::<ShareConfig>().is_err());
+        assert!("salt=1".parse::<ShareConfig>().is_err());
+    }
+
+    #[test]
+    fn device_names_are_replaced() {
+        for seed in 0..20 {
+            let out = csv(&ShareConfig::default(), seed);
+            assert!(!out.contains("kitchen"), "{}", out);
+            assert!(!out.contains("anna"), "{}", out);
+            assert!(out.contains("device-1") && out.contains("device-2"));
+        }
+        // The numbering doesn't follow the names
+        let kitchen: BTreeSet<String> = (0..20)
+            .map(|seed| {
+                let sanitizer = sanitizer(&ShareConfig::default(), seed);
+                sanitizer.pseudonym("kitchen").unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(kitchen.len(), 2);
+    }
+
+    #[test]
+    fn times_are_shifted_by_at_least_a_day() {
+        let config = ShareConfig {
+            max_shift_days: 10,
+            ..Default::default()
+        };
+        for seed in 0..50 {
+            let sanitizer = sanitizer(&config, seed);
+            let out = render_csv(&sanitizer.apply(&sample())).unwrap();
+            for m in sample() {
+                assert!(!out.contains(&m.time.to_rfc3339_opts(SecondsFormat::Secs, true)));
+                let shift = (sanitizer.time(m.time) - m.time).num_seconds().abs();
+                assert!((86_399..=10 * 86_400).contains(&shift), "{}", shift);
+            }
+            assert!(!out.contains("2025-03-01"), "{}", out);
+        }
+    }
+
+    #[test]
+    fn whole_day_shifts_keep_the_time_of_day() {
+        let config = ShareConfig {
+            keep_time_of_day: true,
+            ..Default::default()
+        };
+        let sanitizer = sanitizer(&config, 7);
+        let original = at("2025-03-01T07:02:40Z");
+        let shifted = sanitizer.time(original);
+        assert_ne!(shifted.date_naive(), original.date_naive());
+        assert_eq!(shifted.time(), original.time());
+    }
+
+    #[test]
+    fn coarse_resolution_averages_each_bucket() {
+        let config = ShareConfig {
+            resolution_minutes: 15,
+            keep_time_of_day: true,
+            ..Default::default()
+        };
+        let rows = sanitizer(&config, 3).apply(&sample());
+        assert_eq!(rows.len(), 2);
+        for row in &rows {
+            assert!(row.time.ends_with(":00:00Z"), "{}", row.time);
+            assert!(!row.time.starts_with("2025-03-01"));
+        }
+        let co2: BTreeSet<&str> = rows.iter().map(|row| row.co2_ppm.as_str()).collect();
+        assert_eq!(co2, BTreeSet::from(["1150", "700"]));
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_export() {
+        let config = ShareConfig::default();
+        assert_eq!(csv(&config, 42), csv(&config, 42));
+        assert_ne!(csv(&config, 42), csv(&config, 43));
+        // Sub-second parts could match rows back to the originals
+        assert!(!csv(&config, 42).contains(".250"));
+        let out = csv(&config, 42);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0],
+            "time,device,co2_ppm,temperature_c,humidity_percent"
+        );
+        assert!(lines[1].ends_with(",600,21.50,40.00") || lines[1].ends_with(",1100,21.50,40.00"));
+    }
+
+    #[test]
+    fn the_manifest_keeps_the_offset_secret() {
+        let config = ShareConfig {
+            resolution_minutes: 5,
+            ..Default::default()
+        };
+        let sanitizer = sanitizer(&config, 42);
+        let manifest = Manifest::new(&config, &sanitizer, 4);
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(
+            json,
+            r#"{"rows":4,"columns":["time","device","co2_ppm","temperature_c","humidity_percent"],"transformations":[{"kind":"pseudonymize_devices","devices":2},{"kind":"shift_time","min_days":1,"max_days":365,"keep_time_of_day":false},{"kind":"truncate_time","resolution_seconds":300}],"excluded":["original device names","fw_version and maintenance tags","anomaly markings","hourly aggregates and ventilation estimates","the combined home device","sub-second timestamps"]}"#
+        );
+        assert!(!json.contains(&sanitizer.shift.num_seconds().abs().to_string()));
+        assert_eq!(
+            manifest_path(Path::new("out/shared.csv")),
+            Path::new("out/shared.manifest.json")
+        );
+    }
+}
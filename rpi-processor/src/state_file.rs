@@ -0,0 +1,142 @@
+//! The JSON state files shared by the receiver and the web server: the
+//! maintenance windows, the alert book and the model champion.
+//!
+//! Each file is one `shared_types::versioned` blob around the JSON, so a
+//! layout change or a corrupted write is reported instead of misread. Files
+//! written before the framing are bare JSON; they are read as version 1 and
+//! framed on the next save.
+
+use std::error::Error;
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use shared_types::versioned::{MAGIC, Migrations};
+
+/// The state in `path` in the current layout, `None` if there is no file.
+pub fn load<T: DeserializeOwned>(
+    path: &Path,
+    layouts: &Migrations,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let blob = match std::fs::read(path) {
+        Ok(blob) => blob,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let payload = if blob.starts_with(&MAGIC) {
+        layouts.load(&blob)?.0
+    } else {
+        layouts.upgrade(1, &blob)?
+    };
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+/// Written to a temporary file first, so the other process never reads
+/// half a state.
+pub fn save<T: Serialize>(
+    path: &Path,
+    layouts: &Migrations,
+    state: &T,
+) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, layouts.encode(&serde_json::to_vec_pretty(state)?))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use shared_types::versioned::BlobError;
+
+    use super::*;
+
+    type State = BTreeMap<String, u32>;
+
+    const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+    /// Version 2 renamed `count` to `total`
+    const RENAMED: Migrations<'static> = Migrations::new(2, &[(1, rename_count)]);
+
+    fn rename_count(payload: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let mut state: State = serde_json::from_slice(payload).map_err(|_| "not a state")?;
+        let count = state.remove("count").ok_or("no count")?;
+        state.insert("total".to_string(), count);
+        Ok(serde_json::to_vec(&state).unwrap())
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rpi-processor-state-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn state(key: &str, value: u32) -> State {
+        BTreeMap::from([(key.to_string(), value)])
+    }
+
+    #[test]
+    fn round_trips_and_migrates() {
+        let path = temp_path("round-trip");
+        assert_eq!(load::<State>(&path, &LAYOUTS).unwrap(), None);
+        save(&path, &LAYOUTS, &state("count", 3)).unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(b"AQvb"));
+        assert_eq!(
+            load::<State>(&path, &LAYOUTS).unwrap(),
+            Some(state("count", 3))
+        );
+        assert_eq!(
+            load::<State>(&path, &RENAMED).unwrap(),
+            Some(state("total", 3))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bare_json_reads_as_the_first_layout() {
+        let path = temp_path("bare");
+        std::fs::write(&path, r#"{"count": 4}"#).unwrap();
+        assert_eq!(
+            load::<State>(&path, &RENAMED).unwrap(),
+            Some(state("total", 4))
+        );
+        std::fs::write(&path, "{}").unwrap();
+        assert_eq!(load::<State>(&path, &LAYOUTS).unwrap(), Some(State::new()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damaged_and_newer_files_are_errors() {
+        let path = temp_path("damaged");
+        save(&path, &RENAMED, &state("total", 5)).unwrap();
+        let error = load::<State>(&path, &LAYOUTS).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlobError>(),
+            Some(&BlobError::UnknownVersion(2))
+        );
+
+        let mut blob = std::fs::read(&path).unwrap();
+        let last = blob.len() - 6;
+        blob[last] ^= 1;
+        std::fs::write(&path, &blob).unwrap();
+        let error = load::<State>(&path, &RENAMED).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlobError>(),
+            Some(&BlobError::Checksum)
+        );
+
+        std::fs::write(&path, &blob[..blob.len() - 1]).unwrap();
+        let error = load::<State>(&path, &RENAMED).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlobError>(),
+            Some(&BlobError::Truncated)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
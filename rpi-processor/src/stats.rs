@@ -0,0 +1,843 @@
+//! Resampling irregular measurements onto a regular time grid, and the
+//! weekday by hour-of-day heatmap.
+//!
+//! Devices wake on a timer with some jitter, so their points never land on
+//! exact boundaries. `grid` produces the boundaries and `resample` decides,
+//! for each of them, which raw points the value comes from. Both only look
+//! at times; the caller picks the values out of its own rows.
+//!
+//! `heatmap` puts hourly means into the local weekday and hour they fall
+//! on in the display timezone. Across a DST change a local hour is skipped
+//! or gets two UTC hours, so cells carry the hours and samples behind them.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, Timelike, Utc};
+
+use crate::hourly;
+
+/// Above this many grid points a request is refused
+pub const MAX_GRID_POINTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Between the raw points on either side
+    Linear,
+    /// The last raw point at or before the grid time
+    Previous,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Linear => "linear",
+            Method::Previous => "previous",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Method::Linear),
+            "previous" => Ok(Method::Previous),
+            _ => Err(format!(
+                "unknown method '{}', expected linear or previous",
+                s
+            )),
+        }
+    }
+}
+
+/// Every multiple of `step` since the epoch from `from` to `to`, both
+/// included.
+pub fn grid(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    step: Duration,
+) -> Result<Vec<DateTime<Utc>>, String> {
+    let step_ms = step.num_milliseconds();
+    if step_ms <= 0 {
+        return Err("step must be positive".to_string());
+    }
+    if to < from {
+        return Err(format!("'to' ({}) is before 'from' ({})", to, from));
+    }
+    let first = from.timestamp_millis().div_euclid(step_ms)
+        + i64::from(from.timestamp_millis().rem_euclid(step_ms) != 0);
+    let last = to.timestamp_millis().div_euclid(step_ms);
+    let count = (last - first + 1).max(0) as usize;
+    if count > MAX_GRID_POINTS {
+        return Err(format!(
+            "{} grid points exceed the limit of {}; use a larger step or a shorter range",
+            count, MAX_GRID_POINTS
+        ));
+    }
+    Ok((first..=last)
+        .filter_map(|n| DateTime::from_timestamp_millis(n * step_ms))
+        .collect())
+}
+
+/// Where the value at one grid time comes from, as indices into the raw
+/// points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sample {
+    /// A raw point is exactly on the grid time
+    Exact(usize),
+    /// `weight` of the way from `before` to `after`
+    Between {
+        before: usize,
+        after: usize,
+        weight: f64,
+    },
+    /// The value of a single raw point nearby
+    Held(usize),
+    /// No raw point within the tolerance
+    Missing,
+}
+
+impl Sample {
+    pub fn value(&self, values: impl Fn(usize) -> f64) -> Option<f64> {
+        match *self {
+            Sample::Exact(i) | Sample::Held(i) => Some(values(i)),
+            Sample::Between {
+                before,
+                after,
+                weight,
+            } => Some(values(before) + (values(after) - values(before)) * weight),
+            Sample::Missing => None,
+        }
+    }
+
+    /// Whether the value isn't a raw point taken exactly at the grid time
+    pub fn is_interpolated(&self) -> bool {
+        matches!(self, Sample::Between { .. } | Sample::Held(_))
+    }
+}
+
+/// Picks the raw points for each grid time. `times` must be sorted.
+///
+/// `Previous` holds the last point at or before the grid time if it is
+/// within `tolerance`. `Linear` interpolates between the points on either
+/// side when the nearer one is within `tolerance`; before the first and
+/// after the last point it holds the single point within reach instead.
+/// Anything else is `Missing`.
+pub fn resample(
+    times: &[DateTime<Utc>],
+    grid: &[DateTime<Utc>],
+    method: Method,
+    tolerance: Duration,
+) -> Vec<Sample> {
+    grid.iter()
+        .map(|&t| {
+            let i = times.partition_point(|time| *time < t);
+            if times.get(i) == Some(&t) {
+                return Sample::Exact(i);
+            }
+            let before = i.checked_sub(1).filter(|&b| t - times[b] <= tolerance);
+            let after = (i < times.len()).then_some(i);
+            match method {
+                Method::Previous => before.map_or(Sample::Missing, Sample::Held),
+                Method::Linear => match (i.checked_sub(1), after) {
+                    (Some(b), Some(a)) if (t - times[b]).min(times[a] - t) <= tolerance => {
+                        Sample::Between {
+                            before: b,
+                            after: a,
+                            weight: (t - times[b]).num_milliseconds() as f64
+                                / (times[a] - times[b]).num_milliseconds() as f64,
+                        }
+                    }
+                    (Some(_), None) => before.map_or(Sample::Missing, Sample::Held),
+                    (None, Some(a)) if times[a] - t <= tolerance => Sample::Held(a),
+                    _ => Sample::Missing,
+                },
+            }
+        })
+        .collect()
+}
+
+/// A POSIX TZ string such as `CET-1CEST,M3.5.0,M10.5.0/3`: the standard
+/// name and offset west of UTC, then optionally the DST name, its offset
+/// (an hour ahead of standard when left out) and the `Mm.w.d[/time]` rules
+/// for when DST starts and ends, in local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayZone {
+    standard: FixedOffset,
+    dst: Option<Dst>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dst {
+    offset: FixedOffset,
+    start: Transition,
+    end: Transition,
+}
+
+/// Day `weekday` (0 is Sunday) of week `week` of `month`, week 5 being
+/// the last, at `seconds` past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transition {
+    month: u32,
+    week: u32,
+    weekday: u32,
+    seconds: i32,
+}
+
+impl Default for DisplayZone {
+    fn default() -> Self {
+        Self {
+            standard: FixedOffset::east_opt(0).expect("UTC is a valid offset"),
+            dst: None,
+        }
+    }
+}
+
+impl Transition {
+    /// When it happens in `year`, with `offset` in effect until then
+    fn at(&self, year: i32, offset: FixedOffset) -> Option<DateTime<Utc>> {
+        let first = NaiveDate::from_ymd_opt(year, self.month, 1)?;
+        let first_weekday = first.weekday().num_days_from_sunday();
+        let mut day = 1 + (self.weekday + 7 - first_weekday) % 7 + (self.week - 1) * 7;
+        while NaiveDate::from_ymd_opt(year, self.month, day).is_none() {
+            day -= 7;
+        }
+        let local = NaiveDate::from_ymd_opt(year, self.month, day)?.and_hms_opt(0, 0, 0)?
+            + Duration::seconds(i64::from(self.seconds))
+            - Duration::seconds(i64::from(offset.local_minus_utc()));
+        Some(local.and_utc())
+    }
+}
+
+impl DisplayZone {
+    /// The UTC offset in effect at `time`
+    pub fn offset_at(&self, time: DateTime<Utc>) -> FixedOffset {
+        let Some(dst) = self.dst else {
+            return self.standard;
+        };
+        let year = (time + Duration::seconds(i64::from(self.standard.local_minus_utc()))).year();
+        let (Some(start), Some(end)) = (
+            dst.start.at(year, self.standard),
+            dst.end.at(year, dst.offset),
+        ) else {
+            return self.standard;
+        };
+        // South of the equator DST spans the new year
+        let in_dst = if start < end {
+            start <= time && time < end
+        } else {
+            time < end || start <= time
+        };
+        if in_dst { dst.offset } else { self.standard }
+    }
+
+    pub fn local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        time.with_timezone(&self.offset_at(time)).naive_local()
+    }
+}
+
+/// Reads what comes next in a TZ string
+struct TzCursor<'a>(&'a str);
+
+impl TzCursor<'_> {
+    fn eat(&mut self, c: char) -> bool {
+        match self.0.strip_prefix(c) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn name(&mut self) -> Result<(), String> {
+        let name = if self.eat('<') {
+            let end = self.0.find('>').ok_or("unterminated <name>")?;
+            let name = &self.0[..end];
+            self.0 = &self.0[end + 1..];
+            name
+        } else {
+            let end = self
+                .0
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.0.len());
+            let name = &self.0[..end];
+            self.0 = &self.0[end..];
+            name
+        };
+        if name.len() < 3 {
+            return Err(format!("zone name '{}' is shorter than 3 letters", name));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32, String> {
+        let end = self
+            .0
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.0.len());
+        let number = self.0[..end]
+            .parse()
+            .ok()
+            .filter(|n| *n <= max)
+            .ok_or_else(|| format!("expected a number up to {} at '{}'", max, self.0))?;
+        self.0 = &self.0[end..];
+        Ok(number)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds
+    fn time(&mut self, max_hours: u32) -> Result<i32, String> {
+        let sign = if self.eat('-') {
+            -1
+        } else {
+            self.eat('+');
+            1
+        };
+        let mut seconds = self.number(max_hours)? * 3600;
+        if self.eat(':') {
+            seconds += self.number(59)? * 60;
+            if self.eat(':') {
+                seconds += self.number(59)?;
+            }
+        }
+        Ok(sign * seconds as i32)
+    }
+
+    fn transition(&mut self) -> Result<Transition, String> {
+        if !self.eat('M') {
+            return Err(format!("expected an Mm.w.d rule at '{}'", self.0));
+        }
+        let month = self.number(12)?;
+        let week = self.eat('.').then(|| self.number(5)).transpose()?;
+        let weekday = self.eat('.').then(|| self.number(6)).transpose()?;
+        let (Some(week), Some(weekday)) = (week, weekday) else {
+            return Err("expected an Mm.w.d rule".to_string());
+        };
+        if month == 0 || week == 0 {
+            return Err("months and weeks count from 1".to_string());
+        }
+        let seconds = if self.eat('/') {
+            self.time(167)?
+        } else {
+            2 * 3600
+        };
+        Ok(Transition {
+            month,
+            week,
+            weekday,
+            seconds,
+        })
+    }
+}
+
+/// POSIX offsets count west of UTC, chrono's east
+fn east_of_utc(west: i32) -> Result<FixedOffset, String> {
+    FixedOffset::east_opt(-west).ok_or_else(|| format!("offset of {} s is too large", west))
+}
+
+impl FromStr for DisplayZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: String| format!("invalid timezone '{}': {}", s, e);
+        let mut cursor = TzCursor(s.trim());
+        cursor.name().map_err(invalid)?;
+        let standard = cursor.time(24).map_err(invalid)?;
+        if cursor.0.is_empty() {
+            return Ok(Self {
+                standard: east_of_utc(standard).map_err(invalid)?,
+                dst: None,
+            });
+        }
+        cursor.name().map_err(invalid)?;
+        let offset = if cursor.0.starts_with(',') {
+            standard - 3600
+        } else {
+            cursor.time(24).map_err(invalid)?
+        };
+        if !cursor.eat(',') {
+            return Err(invalid(
+                "expected the DST rules, e.g. ',M3.5.0,M10.5.0/3'".to_string(),
+            ));
+        }
+        let start = cursor.transition().map_err(invalid)?;
+        if !cursor.eat(',') {
+            return Err(invalid("expected the rule for when DST ends".to_string()));
+        }
+        let end = cursor.transition().map_err(invalid)?;
+        if !cursor.0.is_empty() {
+            return Err(invalid(format!("unexpected '{}'", cursor.0)));
+        }
+        Ok(Self {
+            standard: east_of_utc(standard).map_err(invalid)?,
+            dst: Some(Dst {
+                offset: east_of_utc(offset).map_err(invalid)?,
+                start,
+                end,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    Co2,
+    Temperature,
+    Humidity,
+}
+
+impl Variable {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Variable::Co2 => "co2",
+            Variable::Temperature => "temperature",
+            Variable::Humidity => "humidity",
+        }
+    }
+}
+
+impl FromStr for Variable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "co2" => Ok(Variable::Co2),
+            "temperature" => Ok(Variable::Temperature),
+            "humidity" => Ok(Variable::Humidity),
+            _ => Err(format!(
+                "unknown variable '{}', expected co2, temperature or humidity",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Statistic {
+    /// Of every sample in the cell
+    Mean,
+    /// Of the cell's hourly means, so a bad hour shows however few samples
+    /// it had
+    P95,
+}
+
+impl Statistic {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Statistic::Mean => "mean",
+            Statistic::P95 => "p95",
+        }
+    }
+}
+
+impl FromStr for Statistic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Statistic::Mean),
+            "p95" => Ok(Statistic::P95),
+            _ => Err(format!("unknown stat '{}', expected mean or p95", s)),
+        }
+    }
+}
+
+/// One UTC hour of a device's data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HourMean {
+    pub hour: DateTime<Utc>,
+    pub mean: f64,
+    pub samples: u64,
+}
+
+/// Hourly means of raw points, for hours without a stored aggregate
+pub fn hourly_means(points: impl IntoIterator<Item = (DateTime<Utc>, f64)>) -> Vec<HourMean> {
+    let mut hours: BTreeMap<DateTime<Utc>, (f64, u64)> = BTreeMap::new();
+    for (time, value) in points {
+        let (sum, samples) = hours.entry(hourly::hour_of(time)).or_default();
+        *sum += value;
+        *samples += 1;
+    }
+    hours
+        .into_iter()
+        .map(|(hour, (sum, samples))| HourMean {
+            hour,
+            mean: sum / samples as f64,
+            samples,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Cell {
+    /// `None` without any samples
+    pub value: Option<f64>,
+    pub samples: u64,
+    /// UTC hours behind the cell: one per week usually, none or two for the
+    /// hour a DST change skips or repeats
+    pub hours: u32,
+}
+
+/// Rows are weekdays from Monday, columns the local hour
+pub type Heatmap = [[Cell; 24]; 7];
+
+/// Puts each hour into the local weekday and hour it starts on in `zone`.
+/// Hours without samples are left out.
+pub fn heatmap(hours: &[HourMean], zone: &DisplayZone, statistic: Statistic) -> Heatmap {
+    let mut cells: Vec<Vec<Vec<&HourMean>>> = vec![vec![Vec::new(); 24]; 7];
+    for hour in hours
+        .iter()
+        .filter(|hour| hour.samples > 0 && hour.mean.is_finite())
+    {
+        let local = zone.local(hour.hour);
+        cells[local.weekday().num_days_from_monday() as usize][local.hour() as usize].push(hour);
+    }
+    let mut heatmap = [[Cell::default(); 24]; 7];
+    for (row, day) in heatmap.iter_mut().zip(cells) {
+        for (cell, mut hours) in row.iter_mut().zip(day) {
+            cell.hours = hours.len() as u32;
+            cell.samples = hours.iter().map(|hour| hour.samples).sum();
+            if hours.is_empty() {
+                continue;
+            }
+            cell.value = Some(match statistic {
+                Statistic::Mean => {
+                    hours
+                        .iter()
+                        .map(|hour| hour.mean * hour.samples as f64)
+                        .sum::<f64>()
+                        / cell.samples as f64
+                }
+                Statistic::P95 => {
+                    hours.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+                    // Nearest rank
+                    let rank = (hours.len() as f64 * 0.95).ceil() as usize;
+                    hours[rank.max(1) - 1].mean
+                }
+            });
+        }
+    }
+    heatmap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+
+    fn minutes(n: i64) -> Duration {
+        Duration::minutes(n)
+    }
+
+    #[test]
+    fn grid_lands_on_step_boundaries() {
+        assert_eq!(
+            grid(at(-10), at(610), minutes(5)).unwrap(),
+            vec![at(0), at(300), at(600)]
+        );
+        // Boundaries themselves are included
+        assert_eq!(
+            grid(at(0), at(600), minutes(5)).unwrap(),
+            vec![at(0), at(300), at(600)]
+        );
+        assert!(grid(at(10), at(20), minutes(5)).unwrap().is_empty());
+        assert_eq!(grid(at(300), at(300), minutes(5)).unwrap(), vec![at(300)]);
+    }
+
+    #[test]
+    fn grid_refuses_bad_input() {
+        assert!(grid(at(0), at(600), Duration::zero()).is_err());
+        assert!(grid(at(600), at(0), minutes(5)).is_err());
+        let error = grid(at(0), at(0) + Duration::days(365), minutes(1)).unwrap_err();
+        assert!(error.contains("limit"), "{}", error);
+    }
+
+    #[test]
+    fn methods_are_named() {
+        assert_eq!("linear".parse::<Method>(), Ok(Method::Linear));
+        assert_eq!("previous".parse::<Method>(), Ok(Method::Previous));
+        assert!("nearest".parse::<Method>().is_err());
+    }
+
+    #[test]
+    fn exact_points_are_not_interpolated() {
+        let times = [at(0), at(290), at(600)];
+        let samples = resample(&times, &[at(0), at(600)], Method::Linear, minutes(1));
+        assert_eq!(samples, vec![Sample::Exact(0), Sample::Exact(2)]);
+        assert!(!samples[0].is_interpolated());
+    }
+
+    #[test]
+    fn linear_interpolates_between_neighbours() {
+        let times = [at(290), at(320)];
+        let values = [400.0, 700.0];
+        let samples = resample(&times, &[at(300)], Method::Linear, minutes(1));
+        assert_eq!(
+            samples,
+            vec![Sample::Between {
+                before: 0,
+                after: 1,
+                weight: 1.0 / 3.0
+            }]
+        );
+        assert!(samples[0].is_interpolated());
+        let value = samples[0].value(|i| values[i]).unwrap();
+        assert!((value - 500.0).abs() < 1e-9, "{}", value);
+    }
+
+    #[test]
+    fn previous_holds_the_last_point() {
+        let times = [at(290), at(310)];
+        let samples = resample(&times, &[at(300)], Method::Previous, minutes(1));
+        assert_eq!(samples, vec![Sample::Held(0)]);
+        assert_eq!(samples[0].value(|i| [1.0, 2.0][i]), Some(1.0));
+    }
+
+    #[test]
+    fn points_beyond_the_tolerance_give_nulls() {
+        // A gap in the middle: the nearest points are 4 minutes away
+        let times = [at(0), at(560)];
+        let grid = [at(300)];
+        assert_eq!(
+            resample(&times, &grid, Method::Linear, minutes(1)),
+            vec![Sample::Missing]
+        );
+        assert_eq!(
+            resample(&times, &grid, Method::Previous, minutes(1)),
+            vec![Sample::Missing]
+        );
+        // Previous only looks back, however close the next point is
+        let times = [at(0), at(301)];
+        assert_eq!(
+            resample(&times, &grid, Method::Previous, minutes(1)),
+            vec![Sample::Missing]
+        );
+        assert!(matches!(
+            resample(&times, &grid, Method::Linear, minutes(1))[0],
+            Sample::Between { .. }
+        ));
+    }
+
+    #[test]
+    fn leading_and_trailing_gaps() {
+        let times = [at(320), at(590)];
+        let grid = [at(0), at(300), at(600), at(900)];
+        assert_eq!(
+            resample(&times, &grid, Method::Linear, minutes(1)),
+            vec![
+                Sample::Missing,
+                Sample::Held(0),
+                Sample::Held(1),
+                Sample::Missing
+            ]
+        );
+        assert_eq!(
+            resample(&times, &grid, Method::Previous, minutes(1)),
+            vec![
+                Sample::Missing,
+                Sample::Missing,
+                Sample::Held(1),
+                Sample::Missing
+            ]
+        );
+    }
+
+    #[test]
+    fn single_point_and_no_points() {
+        let grid = [at(0), at(300), at(600)];
+        assert_eq!(
+            resample(&[at(310)], &grid, Method::Linear, minutes(1)),
+            vec![Sample::Missing, Sample::Held(0), Sample::Missing]
+        );
+        assert_eq!(
+            resample(&[at(300)], &grid, Method::Previous, minutes(10)),
+            vec![Sample::Missing, Sample::Exact(0), Sample::Held(0)]
+        );
+        assert_eq!(
+            resample(&[], &grid, Method::Linear, minutes(10)),
+            vec![Sample::Missing; 3]
+        );
+        assert_eq!(Sample::Missing.value(|_| unreachable!()), None);
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    const CENTRAL_EUROPE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    fn hours_from(start: &str, count: i64) -> Vec<HourMean> {
+        (0..count)
+            .map(|i| HourMean {
+                hour: utc(start) + Duration::hours(i),
+                mean: 600.0,
+                samples: 12,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zones_follow_their_dst_rules() {
+        let zone: DisplayZone = CENTRAL_EUROPE.parse().unwrap();
+        let hours = |time| zone.offset_at(utc(time)).local_minus_utc() / 3600;
+        assert_eq!(hours("2025-01-15T12:00:00Z"), 1);
+        assert_eq!(hours("2025-07-15T12:00:00Z"), 2);
+        // 02:00 CET on the last Sunday of March, 03:00 CEST on the last
+        // Sunday of October
+        assert_eq!(hours("2025-03-30T00:59:59Z"), 1);
+        assert_eq!(hours("2025-03-30T01:00:00Z"), 2);
+        assert_eq!(hours("2025-10-26T00:59:59Z"), 2);
+        assert_eq!(hours("2025-10-26T01:00:00Z"), 1);
+
+        let sydney: DisplayZone = "AEST-10AEDT,M10.1.0,M4.1.0/3".parse().unwrap();
+        let hours = |time| sydney.offset_at(utc(time)).local_minus_utc() / 3600;
+        assert_eq!(hours("2025-01-15T12:00:00Z"), 11);
+        assert_eq!(hours("2025-07-15T12:00:00Z"), 10);
+
+        let fixed: DisplayZone = "<+0530>-5:30".parse().unwrap();
+        assert_eq!(
+            fixed
+                .offset_at(utc("2025-07-15T12:00:00Z"))
+                .local_minus_utc(),
+            5 * 3600 + 1800
+        );
+        assert_eq!("UTC0".parse::<DisplayZone>(), Ok(DisplayZone::default()));
+    }
+
+    #[test]
+    fn zones_refuse_what_they_cannot_follow() {
+        for zone in [
+            "",
+            "CET",
+            "C-1",
+            "CET-1CEST",
+            "CET-1CEST,J60,M10.5.0",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+            "CET-1CEST,M3.5.0,M10.5.0/3x",
+        ] {
+            assert!(zone.parse::<DisplayZone>().is_err(), "{}", zone);
+        }
+    }
+
+    #[test]
+    fn spring_forward_skips_an_hour() {
+        let zone: DisplayZone = CENTRAL_EUROPE.parse().unwrap();
+        // Monday 00:00 CET to the next Monday 00:00 CEST is 167 hours
+        let heatmap = heatmap(
+            &hours_from("2025-03-23T23:00:00Z", 167),
+            &zone,
+            Statistic::Mean,
+        );
+        for (day, row) in heatmap.iter().enumerate() {
+            for (hour, cell) in row.iter().enumerate() {
+                if (day, hour) == (6, 2) {
+                    assert_eq!(*cell, Cell::default());
+                } else {
+                    assert_eq!(cell.hours, 1, "day {} hour {}", day, hour);
+                    assert_eq!(cell.samples, 12);
+                    assert_eq!(cell.value, Some(600.0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fall_back_repeats_an_hour() {
+        let zone: DisplayZone = CENTRAL_EUROPE.parse().unwrap();
+        // Monday 00:00 CEST to the next Monday 00:00 CET is 169 hours
+        let mut hours = hours_from("2025-10-19T22:00:00Z", 169);
+        // The second 02:00 on Sunday
+        hours[24 * 6 + 3].mean = 900.0;
+        hours[24 * 6 + 3].samples = 24;
+        let heatmap = heatmap(&hours, &zone, Statistic::Mean);
+        let repeated = heatmap[6][2];
+        assert_eq!(repeated.hours, 2);
+        assert_eq!(repeated.samples, 36);
+        assert_eq!(repeated.value, Some(800.0));
+        assert_eq!(heatmap[6][3].hours, 1);
+        assert!(heatmap.iter().flatten().all(|cell| cell.hours >= 1));
+    }
+
+    #[test]
+    fn the_same_utc_hour_moves_with_dst() {
+        let zone: DisplayZone = CENTRAL_EUROPE.parse().unwrap();
+        let hours: Vec<HourMean> = ["2025-03-24T07:00:00Z", "2025-03-31T07:00:00Z"]
+            .iter()
+            .map(|time| HourMean {
+                hour: utc(time),
+                mean: 1000.0,
+                samples: 10,
+            })
+            .collect();
+        let heatmap = heatmap(&hours, &zone, Statistic::Mean);
+        assert_eq!(heatmap[0][8].hours, 1);
+        assert_eq!(heatmap[0][9].hours, 1);
+        assert_eq!(heatmap[0][7].hours, 0);
+    }
+
+    #[test]
+    fn p95_takes_the_nearest_rank_of_hourly_means() {
+        let mut hours = Vec::new();
+        for week in 0..20 {
+            hours.push(HourMean {
+                hour: utc("2025-01-13T08:00:00Z") + Duration::weeks(week),
+                mean: f64::from(week as u32 + 1) * 100.0,
+                samples: if week == 0 { 100 } else { 1 },
+            });
+        }
+        let zone = DisplayZone::default();
+        assert_eq!(
+            heatmap(&hours, &zone, Statistic::P95)[0][8].value,
+            Some(1900.0)
+        );
+        let mean = heatmap(&hours, &zone, Statistic::Mean)[0][8];
+        assert_eq!(mean.samples, 119);
+        assert!((mean.value.unwrap() - 30_900.0 / 119.0).abs() < 1e-9);
+        assert_eq!(
+            heatmap(&hours[..1], &zone, Statistic::P95)[0][8].value,
+            Some(100.0)
+        );
+        assert!("median".parse::<Statistic>().is_err());
+        assert!("pm25".parse::<Variable>().is_err());
+    }
+
+    #[test]
+    fn hours_without_samples_stay_empty() {
+        let hours = [HourMean {
+            hour: utc("2025-01-13T08:00:00Z"),
+            mean: f64::NAN,
+            samples: 0,
+        }];
+        let heatmap = heatmap(&hours, &DisplayZone::default(), Statistic::Mean);
+        assert_eq!(heatmap[0][8], Cell::default());
+    }
+
+    #[test]
+    fn raw_points_average_per_hour() {
+        let means = hourly_means([
+            (utc("2025-01-15T10:05:00Z"), 600.0),
+            (utc("2025-01-15T10:55:00Z"), 700.0),
+            (utc("2025-01-15T09:55:00Z"), 500.0),
+        ]);
+        assert_eq!(
+            means,
+            vec![
+                HourMean {
+                    hour: utc("2025-01-15T09:00:00Z"),
+                    mean: 500.0,
+                    samples: 1,
+                },
+                HourMean {
+                    hour: utc("2025-01-15T10:00:00Z"),
+                    mean: 650.0,
+                    samples: 2,
+                },
+            ]
+        );
+    }
+}
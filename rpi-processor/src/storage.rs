@@ -0,0 +1,721 @@
+//! Capacity planning for the InfluxDB database, printed by
+//! `--storage-report` and served as JSON by `GET /api/storage`.
+//!
+//! InfluxDB doesn't say how much disk a table takes, so sizes are estimated
+//! from points: each table's points are counted, all of them and those of
+//! the last `window_days`, and a sample of its newest rows gives the size of
+//! a point written as line protocol. Stored as Parquet the same points take
+//! less, so the footprint and the days until the budget runs out err on the
+//! safe side.
+//!
+//! `retention_days` is how much of `scd40_data` `--archive --archive-delete`
+//! would keep; the report shows what deleting the rest frees and how long
+//! the budget lasts then. The hourly aggregates in `scd40_hourly` are the
+//! downsampled copy that keeps the history once raw rows are gone.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::hourly;
+use crate::options::parse_kv_options;
+
+const RAW_TABLE: &str = "scd40_data";
+
+/// Rows sampled per table for the size of a point
+const SAMPLE_ROWS: u64 = 100;
+
+/// About the size of an `scd40_hourly` row with every field set, for
+/// estimates before the hourly aggregates have written any
+const HOURLY_LINE_BYTES: f64 = 360.0;
+
+/// The archive refuses to delete anything newer
+const MIN_RETENTION_DAYS: f64 = 30.0;
+
+/// Share of the budget a suggested retention leaves to raw measurements
+const RAW_BUDGET_SHARE: f64 = 0.5;
+
+/// Running out sooner than this is a warning
+const WARN_DAYS: f64 = 90.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageConfig {
+    /// Disk space InfluxDB may use, in megabytes
+    pub budget_mb: f64,
+    /// Days of raw measurements kept, if older ones are archived and deleted
+    pub retention_days: Option<f64>,
+    /// Days of recent points the growth rate is taken from
+    pub window_days: f64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            budget_mb: 8000.0,
+            retention_days: None,
+            window_days: 7.0,
+        }
+    }
+}
+
+impl FromStr for StorageConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (name, value) in parse_kv_options(s)? {
+            let number: f64 = value
+                .parse()
+                .map_err(|_| format!("invalid value '{}' for {}", value, name))?;
+            if !number.is_finite() || number <= 0.0 {
+                return Err(format!("{} must be a positive number", name));
+            }
+            match name {
+                "budget_mb" => config.budget_mb = number,
+                "retention_days" => config.retention_days = Some(number),
+                "window_days" => config.window_days = number,
+                other => return Err(format!("unknown storage setting '{}'", other)),
+            }
+        }
+        if config
+            .retention_days
+            .is_some_and(|days| days < MIN_RETENTION_DAYS)
+        {
+            return Err(format!(
+                "retention_days must be at least {}, the archive keeps newer rows",
+                MIN_RETENTION_DAYS
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// What was counted in one table
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableUsage {
+    pub table: String,
+    pub points: u64,
+    /// Points written in the window
+    pub recent_points: u64,
+    /// Days the window covers, fewer for a table younger than it
+    pub recent_days: f64,
+    /// Estimated size of a point as line protocol
+    pub line_bytes: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Usage {
+    pub tables: Vec<TableUsage>,
+    /// Devices that sent measurements in the window
+    pub devices: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableReport {
+    pub table: String,
+    pub points: u64,
+    pub points_per_day: f64,
+    pub line_bytes: f64,
+    pub bytes: f64,
+    pub bytes_per_day: f64,
+}
+
+impl From<&TableUsage> for TableReport {
+    fn from(usage: &TableUsage) -> Self {
+        let points_per_day = if usage.recent_days > 0.0 {
+            usage.recent_points as f64 / usage.recent_days
+        } else {
+            0.0
+        };
+        Self {
+            table: usage.table.clone(),
+            points: usage.points,
+            points_per_day,
+            line_bytes: usage.line_bytes,
+            bytes: usage.points as f64 * usage.line_bytes,
+            bytes_per_day: points_per_day * usage.line_bytes,
+        }
+    }
+}
+
+/// What keeping `retention_days` of raw measurements changes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetentionSavings {
+    pub retention_days: f64,
+    /// Raw measurements older than that, deleted now
+    pub freed_bytes: f64,
+    pub days_until_full: Option<f64>,
+}
+
+/// The hourly aggregates next to the raw measurements they summarize
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Downsampling {
+    /// Whether `scd40_hourly` got rows in the window; estimated otherwise
+    pub running: bool,
+    pub raw_bytes_per_day: f64,
+    pub hourly_bytes_per_day: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageReport {
+    pub budget_bytes: f64,
+    pub footprint_bytes: f64,
+    pub growth_bytes_per_day: f64,
+    /// `None` while nothing grows
+    pub days_until_full: Option<f64>,
+    pub devices: u64,
+    /// Average time between two measurements of one device
+    pub measurement_interval_seconds: Option<f64>,
+    /// Largest first
+    pub tables: Vec<TableReport>,
+    pub retention: Option<RetentionSavings>,
+    pub downsampling: Option<Downsampling>,
+    pub recommendations: Vec<String>,
+}
+
+pub async fn fetch_usage(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    window_days: f64,
+    now: DateTime<Utc>,
+) -> Result<Usage, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct TableRow {
+        table_schema: String,
+        table_name: String,
+    }
+    #[derive(Deserialize)]
+    struct CountRow {
+        points: u64,
+        first: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct DeviceRow {
+        devices: u64,
+    }
+
+    let since = now - Duration::seconds((window_days * 86_400.0) as i64);
+
+    let tables: Vec<TableRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new("SHOW TABLES"),
+    )
+    .await?;
+    let mut usage = Usage::default();
+    for row in tables.into_iter().filter(|row| row.table_schema == "iox") {
+        let Ok(table) = Identifier::parse(&row.table_name) else {
+            log::warn!(
+                "Skipping table {:?}, its name can't be queried",
+                row.table_name
+            );
+            continue;
+        };
+        let all: Vec<CountRow> = query_rows(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &Sql::new("SELECT COUNT(*) AS points, MIN(time) AS first FROM ").table(&table),
+        )
+        .await?;
+        let Some(all) = all.into_iter().next().filter(|all| all.points > 0) else {
+            continue;
+        };
+        let recent: Vec<CountRow> = query_rows(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &Sql::new("SELECT COUNT(*) AS points FROM ")
+                .table(&table)
+                .push(" WHERE time >= ")
+                .time(since),
+        )
+        .await?;
+        let sample: Vec<Map<String, Value>> = query_rows(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &Sql::new("SELECT * FROM ")
+                .table(&table)
+                .push(" ORDER BY time DESC LIMIT ")
+                .number(SAMPLE_ROWS),
+        )
+        .await?;
+        let first = all.first.as_deref().and_then(parse_time).unwrap_or(since);
+        usage.tables.push(TableUsage {
+            table: row.table_name,
+            points: all.points,
+            recent_points: recent.first().map_or(0, |recent| recent.points),
+            recent_days: window_days.min((now - first).num_seconds() as f64 / 86_400.0),
+            line_bytes: average_line_bytes(table.as_str(), &sample),
+        });
+    }
+    if usage.tables.iter().any(|t| t.table == RAW_TABLE) {
+        let devices: Vec<DeviceRow> = query_rows(
+            influx_host,
+            influx_token,
+            influx_database,
+            reqwest_client,
+            &Sql::new("SELECT COUNT(DISTINCT device) AS devices FROM scd40_data WHERE time >= ")
+                .time(since),
+        )
+        .await?;
+        usage.devices = devices.first().map_or(0, |row| row.devices);
+    }
+    Ok(usage)
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = if value.ends_with('Z') || value.contains('+') {
+        value.to_string()
+    } else {
+        format!("{}Z", value)
+    };
+    DateTime::parse_from_rfc3339(&value)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Length of `row` written as line protocol: the table, every non-null
+/// column as `name=value` with a separator, and a nanosecond timestamp.
+/// Tags and fields aren't told apart, so strings count their quotes.
+pub fn line_bytes(table: &str, row: &Map<String, Value>) -> usize {
+    const TIMESTAMP: usize = " 1736942400000000000".len();
+    let columns: usize = row
+        .iter()
+        .filter(|(name, _)| name.as_str() != "time")
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Null => return 0,
+                Value::String(text) => text.len() + 2,
+                other => other.to_string().len(),
+            };
+            1 + name.len() + 1 + value
+        })
+        .sum();
+    table.len() + columns + TIMESTAMP
+}
+
+pub fn average_line_bytes(table: &str, rows: &[Map<String, Value>]) -> f64 {
+    if rows.is_empty() {
+        return 0.0;
+    }
+    let total: usize = rows.iter().map(|row| line_bytes(table, row)).sum();
+    total as f64 / rows.len() as f64
+}
+
+/// Days until `footprint` growing by `growth` a day reaches `budget`
+fn days_until(budget: f64, footprint: f64, growth: f64) -> Option<f64> {
+    if footprint >= budget {
+        Some(0.0)
+    } else if growth > 0.0 {
+        Some((budget - footprint) / growth)
+    } else {
+        None
+    }
+}
+
+pub fn plan(usage: &Usage, config: &StorageConfig) -> StorageReport {
+    let budget_bytes = config.budget_mb * 1e6;
+    let mut tables: Vec<TableReport> = usage.tables.iter().map(TableReport::from).collect();
+    tables.sort_by(|a, b| b.bytes.total_cmp(&a.bytes));
+    let footprint_bytes: f64 = tables.iter().map(|t| t.bytes).sum();
+    let growth_bytes_per_day: f64 = tables.iter().map(|t| t.bytes_per_day).sum();
+    let raw = tables.iter().find(|t| t.table == RAW_TABLE);
+    let measurement_interval_seconds = raw
+        .filter(|raw| raw.points_per_day > 0.0 && usage.devices > 0)
+        .map(|raw| 86_400.0 * usage.devices as f64 / raw.points_per_day);
+
+    let retention = raw.zip(config.retention_days).map(|(raw, days)| {
+        let kept_points = raw.points_per_day * days;
+        let freed_bytes = (raw.points as f64 - kept_points).max(0.0) * raw.line_bytes;
+        let raw_now = raw.bytes - freed_bytes;
+        let raw_kept = kept_points * raw.line_bytes;
+        // Raw measurements grow until they hold `days`, then stay that size
+        let footprint = footprint_bytes - freed_bytes;
+        let other_growth = growth_bytes_per_day - raw.bytes_per_day;
+        let filling = if raw.bytes_per_day > 0.0 {
+            ((raw_kept - raw_now) / raw.bytes_per_day).max(0.0)
+        } else {
+            0.0
+        };
+        let days_until_full = match days_until(budget_bytes, footprint, growth_bytes_per_day) {
+            Some(full) if full <= filling => Some(full),
+            _ => {
+                let filled = footprint + growth_bytes_per_day * filling;
+                days_until(budget_bytes, filled, other_growth).map(|rest| filling + rest)
+            }
+        };
+        RetentionSavings {
+            retention_days: days,
+            freed_bytes,
+            days_until_full,
+        }
+    });
+
+    let downsampling = raw.filter(|raw| raw.bytes_per_day > 0.0).map(|raw| {
+        let hourly = tables
+            .iter()
+            .find(|t| t.table == hourly::MEASUREMENT && t.bytes_per_day > 0.0);
+        Downsampling {
+            running: hourly.is_some(),
+            raw_bytes_per_day: raw.bytes_per_day,
+            hourly_bytes_per_day: hourly
+                .map_or(usage.devices as f64 * 24.0 * HOURLY_LINE_BYTES, |hourly| {
+                    hourly.bytes_per_day
+                }),
+        }
+    });
+
+    let mut report = StorageReport {
+        budget_bytes,
+        footprint_bytes,
+        growth_bytes_per_day,
+        days_until_full: days_until(budget_bytes, footprint_bytes, growth_bytes_per_day),
+        devices: usage.devices,
+        measurement_interval_seconds,
+        tables,
+        retention,
+        downsampling,
+        recommendations: Vec::new(),
+    };
+    report.recommendations = recommendations(&report);
+    report
+}
+
+/// What to do about the projection, most urgent first
+pub fn recommendations(report: &StorageReport) -> Vec<String> {
+    let mut advice = Vec::new();
+    let raw = report.tables.iter().find(|t| t.table == RAW_TABLE);
+    match report.days_until_full {
+        Some(days) if days <= 0.0 => advice.push(format!(
+            "The database is estimated at {}, over the {} budget.",
+            bytes(report.footprint_bytes),
+            bytes(report.budget_bytes)
+        )),
+        Some(days) if days < WARN_DAYS => advice.push(format!(
+            "At {} a day the budget is used up in about {:.0} days.",
+            bytes(report.growth_bytes_per_day),
+            days
+        )),
+        Some(days) => advice.push(format!(
+            "At {} a day the budget lasts about {:.0} days.",
+            bytes(report.growth_bytes_per_day),
+            days
+        )),
+        None => advice.push("The database isn't growing.".to_string()),
+    }
+
+    match (&report.retention, raw) {
+        (Some(retention), _) => advice.push(format!(
+            "Keeping {:.0} days of raw measurements frees {} now; {}.",
+            retention.retention_days,
+            bytes(retention.freed_bytes),
+            match retention.days_until_full {
+                Some(days) => format!("the budget then lasts about {:.0} days", days),
+                None => "the budget then lasts".to_string(),
+            }
+        )),
+        (None, Some(raw))
+            if raw.bytes_per_day > 0.0
+                && report.days_until_full.is_some_and(|days| days < 365.0) =>
+        {
+            let days = (report.budget_bytes * RAW_BUDGET_SHARE / raw.bytes_per_day)
+                .floor()
+                .max(MIN_RETENTION_DAYS);
+            advice.push(format!(
+                "Keeping {:.0} days of raw measurements (--archive --archive-delete) \
+                 holds them to half the budget; try retention_days={:.0}.",
+                days, days
+            ));
+        }
+        _ => {}
+    }
+
+    if let Some(downsampling) = &report.downsampling
+        && !downsampling.running
+    {
+        advice.push(format!(
+            "Turn on --hourly-aggregates before deleting raw measurements: hourly rows \
+             take about {} a day instead of {}.",
+            bytes(downsampling.hourly_bytes_per_day),
+            bytes(downsampling.raw_bytes_per_day)
+        ));
+    }
+    advice
+}
+
+/// Decimal units, like disk sizes
+fn bytes(value: f64) -> String {
+    match value {
+        v if v >= 1e9 => format!("{:.1} GB", v / 1e9),
+        v if v >= 1e6 => format!("{:.1} MB", v / 1e6),
+        v if v >= 1e3 => format!("{:.1} kB", v / 1e3),
+        v => format!("{:.0} B", v),
+    }
+}
+
+pub fn render_plain(report: &StorageReport) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Estimated footprint: {} of {}",
+        bytes(report.footprint_bytes),
+        bytes(report.budget_bytes)
+    );
+    let _ = writeln!(out, "Growth: {} a day", bytes(report.growth_bytes_per_day));
+    if let Some(seconds) = report.measurement_interval_seconds {
+        let _ = writeln!(
+            out,
+            "Devices: {}, one measurement every {:.0}s each",
+            report.devices, seconds
+        );
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "{:<24} {:>12} {:>10} {:>8} {:>10} {:>10}",
+        "table", "points", "per day", "B/point", "size", "per day"
+    );
+    for table in &report.tables {
+        let _ = writeln!(
+            out,
+            "{:<24} {:>12} {:>10.0} {:>8.0} {:>10} {:>10}",
+            table.table,
+            table.points,
+            table.points_per_day,
+            table.line_bytes,
+            bytes(table.bytes),
+            bytes(table.bytes_per_day)
+        );
+    }
+    let _ = writeln!(out);
+    for line in &report.recommendations {
+        let _ = writeln!(out, "- {}", line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::at;
+    use axum::{Json, Router, routing::post};
+
+    fn table(name: &str, points: u64, per_day: u64, line_bytes: f64) -> TableUsage {
+        TableUsage {
+            table: name.to_string(),
+            points,
+            recent_points: per_day * 7,
+            recent_days: 7.0,
+            line_bytes,
+        }
+    }
+
+    /// Two devices every 5 minutes for 100 days, 100 bytes a point
+    fn usage() -> Usage {
+        Usage {
+            tables: vec![
+                table("scd40_data", 57_600, 576, 100.0),
+                table("anomalies", 1_000, 10, 50.0),
+            ],
+            devices: 2,
+        }
+    }
+
+    fn config(budget_mb: f64, retention_days: Option<f64>) -> StorageConfig {
+        StorageConfig {
+            budget_mb,
+            retention_days,
+            ..StorageConfig::default()
+        }
+    }
+
+    #[test]
+    fn config_parses_settings() {
+        assert_eq!(
+            "budget_mb=16000, retention_days=365".parse::<StorageConfig>(),
+            Ok(StorageConfig {
+                budget_mb: 16000.0,
+                retention_days: Some(365.0),
+                window_days: 7.0,
+            })
+        );
+        assert_eq!("".parse::<StorageConfig>(), Ok(StorageConfig::default()));
+        assert!("budget_mb=0".parse::<StorageConfig>().is_err());
+        assert!("retention_days=7".parse::<StorageConfig>().is_err());
+        assert!("budget_gb=16".parse::<StorageConfig>().is_err());
+    }
+
+    #[test]
+    fn line_size_counts_every_column() {
+        let row: Map<String, Value> = serde_json::from_str(
+            r#"{"time":"2025-01-15T12:00:00","device":"kitchen","co2_ppm":612,"maintenance":null}"#,
+        )
+        .unwrap();
+        // scd40_data,device="kitchen",co2_ppm=612 1736942400000000000
+        assert_eq!(line_bytes("scd40_data", &row), 10 + 17 + 12 + 20);
+        assert_eq!(average_line_bytes("scd40_data", &[]), 0.0);
+    }
+
+    #[test]
+    fn growth_projects_the_budget() {
+        let report = plan(&usage(), &config(10.0, None));
+        assert_eq!(report.footprint_bytes, 5_810_000.0);
+        assert_eq!(report.growth_bytes_per_day, 58_100.0);
+        // 4.19 MB left at 58.1 kB a day
+        assert_eq!(report.days_until_full.map(f64::round), Some(72.0));
+        assert_eq!(report.measurement_interval_seconds, Some(300.0));
+        assert_eq!(report.tables[0].table, "scd40_data");
+
+        assert_eq!(
+            plan(&usage(), &config(5.0, None)).days_until_full,
+            Some(0.0)
+        );
+        let idle = Usage {
+            tables: vec![table("scd40_data", 100, 0, 100.0)],
+            devices: 0,
+        };
+        assert_eq!(plan(&idle, &config(10.0, None)).days_until_full, None);
+    }
+
+    #[test]
+    fn retention_frees_old_rows_and_caps_raw_growth() {
+        let report = plan(&usage(), &config(10.0, Some(30.0)));
+        let retention = report.retention.unwrap();
+        // 100 days kept down to 30
+        assert_eq!(retention.freed_bytes, 70.0 * 576.0 * 100.0);
+        // Only the anomalies still grow: 10 MB - 1.778 MB at 500 B a day
+        assert_eq!(retention.days_until_full.map(f64::round), Some(16_444.0));
+
+        // Younger than the retention, raw rows grow for 50 more days first
+        let young = plan(&usage(), &config(10.0, Some(150.0)))
+            .retention
+            .unwrap();
+        assert_eq!(young.freed_bytes, 0.0);
+        let filled = 5_810_000.0 + 58_100.0 * 50.0;
+        assert_eq!(
+            young.days_until_full.map(f64::round),
+            Some((50.0_f64 + (10e6 - filled) / 500.0).round())
+        );
+        // Unless the budget runs out before they are 150 days old
+        let tight = plan(&usage(), &config(7.0, Some(150.0))).retention.unwrap();
+        assert_eq!(tight.days_until_full.map(f64::round), Some(20.0));
+    }
+
+    #[test]
+    fn recommendations_follow_the_projection() {
+        let report = plan(&usage(), &config(10.0, None));
+        assert_eq!(
+            report.recommendations,
+            [
+                "At 58.1 kB a day the budget is used up in about 72 days.",
+                "Keeping 86 days of raw measurements (--archive --archive-delete) \
+                 holds them to half the budget; try retention_days=86.",
+                "Turn on --hourly-aggregates before deleting raw measurements: hourly rows \
+                 take about 17.3 kB a day instead of 57.6 kB.",
+            ]
+        );
+
+        let mut with_hourly = usage();
+        with_hourly
+            .tables
+            .push(table("scd40_hourly", 4_800, 48, 300.0));
+        let report = plan(&with_hourly, &config(10.0, Some(30.0)));
+        assert_eq!(
+            report.recommendations,
+            [
+                "At 72.5 kB a day the budget is used up in about 38 days.",
+                "Keeping 30 days of raw measurements frees 4.0 MB now; \
+                 the budget then lasts about 455 days.",
+            ]
+        );
+        assert!(report.downsampling.unwrap().running);
+    }
+
+    #[test]
+    fn plain_report_lists_tables_by_size() {
+        let rendered = render_plain(&plan(&usage(), &config(10.0, None)));
+        assert!(rendered.starts_with(
+            "Estimated footprint: 5.8 MB of 10.0 MB\n\
+             Growth: 58.1 kB a day\n\
+             Devices: 2, one measurement every 300s each\n"
+        ));
+        let scd40 = rendered.find("\nscd40_data ").unwrap();
+        let anomalies = rendered.find("\nanomalies ").unwrap();
+        assert!(scd40 < anomalies);
+        assert!(rendered.ends_with("instead of 57.6 kB.\n"));
+    }
+
+    #[tokio::test]
+    async fn counts_points_in_influx() {
+        async fn fake_query(Json(body): Json<serde_json::Value>) -> Json<serde_json::Value> {
+            let sql = body["q"].as_str().unwrap();
+            Json(match sql {
+                "SHOW TABLES" => serde_json::json!([
+                    { "table_schema": "iox", "table_name": "scd40_data" },
+                    { "table_schema": "iox", "table_name": "empty" },
+                    { "table_schema": "system", "table_name": "queries" },
+                ]),
+                "SELECT COUNT(*) AS points, MIN(time) AS first FROM scd40_data" => {
+                    serde_json::json!([{ "points": 57_600, "first": "2024-10-07T12:00:00" }])
+                }
+                "SELECT COUNT(*) AS points, MIN(time) AS first FROM empty" => {
+                    serde_json::json!([{ "points": 0 }])
+                }
+                "SELECT COUNT(*) AS points FROM scd40_data \
+                 WHERE time >= '2025-01-08T12:00:00+00:00'" => {
+                    serde_json::json!([{ "points": 4_032 }])
+                }
+                "SELECT * FROM scd40_data ORDER BY time DESC LIMIT 100" => serde_json::json!([
+                    { "time": "2025-01-15T11:55:00", "device": "kitchen", "co2_ppm": 612 },
+                ]),
+                "SELECT COUNT(DISTINCT device) AS devices FROM scd40_data \
+                 WHERE time >= '2025-01-08T12:00:00+00:00'" => {
+                    serde_json::json!([{ "devices": 2 }])
+                }
+                other => panic!("unexpected query {:?}", other),
+            })
+        }
+        let app = Router::new().route("/api/v3/query_sql", post(fake_query));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let now = at(0);
+        let usage = fetch_usage(
+            &format!("http://{}", addr),
+            "token",
+            "db",
+            &reqwest::Client::new(),
+            7.0,
+            now,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                tables: vec![TableUsage {
+                    table: "scd40_data".to_string(),
+                    points: 57_600,
+                    recent_points: 4_032,
+                    recent_days: 7.0,
+                    line_bytes: 59.0,
+                }],
+                devices: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,16 @@
+//! Helpers shared by the unit tests.
+
+use chrono::{DateTime, Duration, Utc};
+
+/// `seconds` after 2025-01-15 12:00 UTC, the time most tests are set at
+pub(crate) fn at(seconds: i64) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339("2025-01-15T12:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc)
+        + Duration::seconds(seconds)
+}
+
+/// `at`, counted in minutes
+pub(crate) fn at_minute(minutes: i64) -> DateTime<Utc> {
+    at(minutes * 60)
+}
@@ -0,0 +1,754 @@
+//! Ventilation advice from how fast CO2 falls and builds up in a room.
+//!
+//! The room is treated as one well-mixed zone:
+//!
+//! ```text
+//! dC/dt = S - λ (C - C_out)
+//! ```
+//!
+//! with `C` the indoor CO2 (ppm), `C_out` the outdoor level, `λ` the air
+//! exchange rate (per hour) and `S` the CO2 the occupants add (ppm per hour,
+//! `G / V` for a generation rate `G` in a room of volume `V`).
+//!
+//! **Air exchange with the window open.** A ventilation event is a run of
+//! falling samples that drops at least `min_drop_ppm`. Ignoring `S`, the
+//! run follows `C(t) = C_out + (C_0 - C_out) e^(-λt)`, so `ln(C - C_out)` is a
+//! line with slope `-λ`, fitted by least squares. Fits worse than
+//! `min_fit_r2` or slower than `min_ventilation_rate` (a closed room slowly
+//! losing CO2) are dropped; the open-window rate `λ_o` is the median of the
+//! rest. A run during occupancy fits a slightly low `λ_o`, which only makes
+//! the advice more cautious.
+//!
+//! **Occupancy.** With the window closed the room exchanges air at
+//! `infiltration_rate` (`λ_i`). Every rise of at least `min_occupied_rise`
+//! ppm/h between two samples gives `S = dC/dt + λ_i (C - C_out)`; the
+//! estimate is the median. Given the room volume from the registry, that is
+//! `S V / 1000 / co2_per_person` people.
+//!
+//! **Recommendation.** With the window closed CO2 heads for
+//! `C_ss = C_out + S / λ_i`. If that stays under `limit_ppm` nothing needs
+//! doing. Otherwise the room goes from `target_ppm` to the limit in
+//!
+//! ```text
+//! t_closed = ln((C_ss - target) / (C_ss - limit)) / λ_i
+//! ```
+//!
+//! and an open window, heading for `C_open = C_out + S / λ_o`, brings it
+//! back down in
+//!
+//! ```text
+//! t_open = ln((limit - C_open) / (target - C_open)) / λ_o
+//! ```
+//!
+//! The advice is to ventilate `t_open` (rounded up to a minute) every
+//! `t_closed + t_open` (rounded down to 5 minutes). If `C_open` isn't below
+//! the target, airing in bursts can't keep up and the window should stay
+//! open while the room is in use.
+//!
+//! Room names and volumes come from the registry file `ROOM_REGISTRY_FILE`
+//! (default `rooms.json`), keyed by device:
+//!
+//! ```json
+//! { "esp32-scd40": { "name": "office", "volume_m3": 38.5 } }
+//! ```
+//!
+//! The same file gives each room its `weight` in the whole-home aggregate
+//! and can mark a device `"virtual": true`, see `home`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::fetcher::{Identifier, Sql, query_rows};
+use crate::maintenance;
+use crate::options::parse_kv_options;
+use crate::types::{InfluxMeasurementRow, MeasurementWithTime};
+
+pub const DEFAULT_REGISTRY_FILE: &str = "rooms.json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VentilationConfig {
+    /// Outdoor CO2 the room decays towards
+    pub outdoor_ppm: f64,
+    /// Level the advice keeps the room under
+    pub limit_ppm: f64,
+    /// Level to ventilate down to
+    pub target_ppm: f64,
+    /// Air exchanges per hour with windows closed
+    pub infiltration_rate: f64,
+    /// Smallest fall counted as a ventilation event
+    pub min_drop_ppm: f64,
+    /// Fewest samples in a ventilation event
+    pub min_points: usize,
+    /// Worst R² of the decay fit that is still trusted
+    pub min_fit_r2: f64,
+    /// Slowest decay (per hour) counted as ventilation
+    pub min_ventilation_rate: f64,
+    /// Slowest rise (ppm per hour) counted as occupancy
+    pub min_occupied_rise: f64,
+    /// CO2 one seated adult breathes out, in litres per hour
+    pub co2_per_person: f64,
+    /// Samples further apart than this aren't compared
+    pub max_gap_minutes: i64,
+    /// Days of history to learn from
+    pub lookback_days: i64,
+}
+
+impl Default for VentilationConfig {
+    fn default() -> Self {
+        Self {
+            outdoor_ppm: 420.0,
+            limit_ppm: 1000.0,
+            target_ppm: 600.0,
+            infiltration_rate: 0.3,
+            min_drop_ppm: 150.0,
+            min_points: 4,
+            min_fit_r2: 0.9,
+            min_ventilation_rate: 1.0,
+            min_occupied_rise: 50.0,
+            co2_per_person: 18.7,
+            max_gap_minutes: 15,
+            lookback_days: 7,
+        }
+    }
+}
+
+impl FromStr for VentilationConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (name, value) in parse_kv_options(s)? {
+            let number: f64 = value
+                .parse()
+                .map_err(|_| format!("invalid value '{}' for {}", value, name))?;
+            if !number.is_finite() || number < 0.0 {
+                return Err(format!("{} must be a non-negative number", name));
+            }
+            match name {
+                "outdoor_ppm" => config.outdoor_ppm = number,
+                "limit_ppm" => config.limit_ppm = number,
+                "target_ppm" => config.target_ppm = number,
+                "infiltration_rate" => config.infiltration_rate = number,
+                "min_drop_ppm" => config.min_drop_ppm = number,
+                "min_points" => config.min_points = number as usize,
+                "min_fit_r2" => config.min_fit_r2 = number,
+                "min_ventilation_rate" => config.min_ventilation_rate = number,
+                "min_occupied_rise" => config.min_occupied_rise = number,
+                "co2_per_person" => config.co2_per_person = number,
+                "max_gap_minutes" => config.max_gap_minutes = number as i64,
+                "lookback_days" => config.lookback_days = number as i64,
+                other => return Err(format!("unknown ventilation setting '{}'", other)),
+            }
+        }
+        if !(config.outdoor_ppm < config.target_ppm && config.target_ppm < config.limit_ppm) {
+            return Err("expected outdoor_ppm < target_ppm < limit_ppm".to_string());
+        }
+        if config.infiltration_rate <= 0.0 {
+            return Err("infiltration_rate must be above 0".to_string());
+        }
+        if config.min_points < 3 {
+            return Err("min_points must be at least 3".to_string());
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Room {
+    pub name: Option<String>,
+    pub volume_m3: Option<f64>,
+    /// Weight in the whole-home aggregate, 1 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+    /// Computed rather than measured; no commands and no part in the
+    /// whole-home aggregate
+    #[serde(
+        default,
+        rename = "virtual",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub is_virtual: bool,
+}
+
+/// Rooms by device id.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct RoomRegistry(BTreeMap<String, Room>);
+
+impl RoomRegistry {
+    /// A missing file is an empty registry.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(serde_json::from_str(&text)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// An unreadable registry is logged and treated as empty; advice still
+    /// works without room names and volumes.
+    pub fn from_env() -> Self {
+        let path = std::env::var("ROOM_REGISTRY_FILE")
+            .unwrap_or_else(|_| DEFAULT_REGISTRY_FILE.to_string());
+        Self::load(Path::new(&path)).unwrap_or_else(|e| {
+            log::error!("Failed to read room registry {}: {}", path, e);
+            Self::default()
+        })
+    }
+
+    pub fn get(&self, device: &str) -> Option<&Room> {
+        self.0.get(device)
+    }
+
+    /// `home` always is, see `home`
+    pub fn is_virtual(&self, device: &str) -> bool {
+        device == shared_types::HOME_DEVICE || self.get(device).is_some_and(|room| room.is_virtual)
+    }
+}
+
+/// An exponential fit to one falling run of CO2.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecayFit {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Air exchanges per hour
+    pub rate: f64,
+    pub r2: f64,
+    pub points: usize,
+}
+
+/// Fits `ln(C - outdoor) = a - rate * t`. `None` if a sample is at or below
+/// the outdoor level or there is nothing to fit.
+pub fn fit_decay(measurements: &[MeasurementWithTime], outdoor_ppm: f64) -> Option<DecayFit> {
+    let (first, last) = (measurements.first()?, measurements.last()?);
+    let points: Vec<(f64, f64)> = measurements
+        .iter()
+        .map(|m| {
+            let excess = f64::from(m.co2) - outdoor_ppm;
+            let hours = (m.time - first.time).num_seconds() as f64 / 3600.0;
+            (excess > 0.0).then(|| (hours, excess.ln()))
+        })
+        .collect::<Option<_>>()?;
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let stt: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+    let sty: f64 = points
+        .iter()
+        .map(|(t, y)| (t - mean_t) * (y - mean_y))
+        .sum();
+    let syy: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+    if stt == 0.0 || syy == 0.0 {
+        return None;
+    }
+    let slope = sty / stt;
+    Some(DecayFit {
+        start: first.time,
+        end: last.time,
+        rate: -slope,
+        r2: sty * sty / (stt * syy),
+        points: points.len(),
+    })
+}
+
+/// Splits time-ordered measurements into runs of falling CO2 and keeps the
+/// ones that look like a window being opened.
+pub fn find_ventilation_events(
+    measurements: &[MeasurementWithTime],
+    config: &VentilationConfig,
+) -> Vec<DecayFit> {
+    let max_gap = Duration::minutes(config.max_gap_minutes);
+    let mut events = Vec::new();
+    let mut start = 0;
+    for end in 1..=measurements.len() {
+        let falling = measurements.get(end).is_some_and(|m| {
+            let previous = &measurements[end - 1];
+            m.co2 < previous.co2 && m.time - previous.time <= max_gap
+        });
+        if falling {
+            continue;
+        }
+        let run = &measurements[start..end];
+        start = end;
+        if run.len() < config.min_points {
+            continue;
+        }
+        let drop = f64::from(run[0].co2) - f64::from(run[run.len() - 1].co2);
+        if drop < config.min_drop_ppm {
+            continue;
+        }
+        if let Some(fit) = fit_decay(run, config.outdoor_ppm)
+            && fit.r2 >= config.min_fit_r2
+            && fit.rate >= config.min_ventilation_rate
+        {
+            events.push(fit);
+        }
+    }
+    events
+}
+
+/// Median CO2 source strength `S` (ppm per hour) over rises fast enough to
+/// mean someone is in the room.
+pub fn estimate_source(
+    measurements: &[MeasurementWithTime],
+    config: &VentilationConfig,
+) -> Option<f64> {
+    let max_gap = Duration::minutes(config.max_gap_minutes);
+    let sources: Vec<f64> = measurements
+        .windows(2)
+        .filter_map(|pair| {
+            let (a, b) = (&pair[0], &pair[1]);
+            let elapsed = b.time - a.time;
+            if elapsed <= Duration::zero() || elapsed > max_gap {
+                return None;
+            }
+            let hours = elapsed.num_seconds() as f64 / 3600.0;
+            let rise = (f64::from(b.co2) - f64::from(a.co2)) / hours;
+            let mid = (f64::from(a.co2) + f64::from(b.co2)) / 2.0;
+            (rise >= config.min_occupied_rise)
+                .then_some(rise + config.infiltration_rate * (mid - config.outdoor_ppm))
+        })
+        .collect();
+    median(sources)
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+/// How long to air the room and how often.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Plan {
+    /// With the window closed CO2 settles under the limit
+    NotNeeded { steady_ppm: f64 },
+    Periodic {
+        ventilate_minutes: u32,
+        every_minutes: u32,
+    },
+    /// Even with the window open CO2 settles above the target
+    Continuous { open_steady_ppm: f64 },
+}
+
+/// Applies the recommendation formula from the module docs.
+pub fn plan(source: f64, open_rate: f64, config: &VentilationConfig) -> Plan {
+    let (outdoor, limit, target) = (config.outdoor_ppm, config.limit_ppm, config.target_ppm);
+    let closed_rate = config.infiltration_rate;
+
+    let steady = outdoor + source / closed_rate;
+    if steady <= limit {
+        return Plan::NotNeeded { steady_ppm: steady };
+    }
+    let open_steady = outdoor + source / open_rate;
+    if open_steady >= target {
+        return Plan::Continuous {
+            open_steady_ppm: open_steady,
+        };
+    }
+
+    let closed_hours = ((steady - target) / (steady - limit)).ln() / closed_rate;
+    let open_hours = ((limit - open_steady) / (target - open_steady)).ln() / open_rate;
+    let ventilate_minutes = (open_hours * 60.0).ceil().max(1.0) as u32;
+    let every_minutes = ((closed_hours + open_hours) * 60.0 / 5.0).floor() as u32 * 5;
+    Plan::Periodic {
+        ventilate_minutes,
+        every_minutes: every_minutes.max(ventilate_minutes.div_ceil(5) * 5),
+    }
+}
+
+fn format_interval(minutes: u32) -> String {
+    if minutes < 120 {
+        return format!("{} minutes", minutes);
+    }
+    // Whole or half hours, rounded down to err on the side of fresh air
+    let half_hours = minutes / 30;
+    if half_hours.is_multiple_of(2) {
+        format!("{} hours", half_hours / 2)
+    } else {
+        format!("{}.5 hours", half_hours / 2)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Recommendation {
+    pub device: String,
+    pub room: Option<String>,
+    pub volume_m3: Option<f64>,
+    pub ventilation_events: usize,
+    /// Median air exchanges per hour with the window open
+    pub air_exchange_rate: Option<f64>,
+    /// CO2 added while the room is occupied, in ppm per hour
+    pub co2_source_ppm_per_hour: Option<f64>,
+    pub estimated_occupants: Option<f64>,
+    pub ventilate_minutes: Option<u32>,
+    pub every_minutes: Option<u32>,
+    pub limit_ppm: f64,
+    pub advice: String,
+}
+
+/// Builds the recommendation from time-ordered measurements.
+pub fn recommend(
+    device: &str,
+    measurements: &[MeasurementWithTime],
+    room: Option<&Room>,
+    config: &VentilationConfig,
+) -> Recommendation {
+    let room_name = room.and_then(|r| r.name.clone());
+    let volume_m3 = room.and_then(|r| r.volume_m3);
+    let label = match &room_name {
+        Some(name) => format!("the {}", name),
+        None => device.to_string(),
+    };
+
+    let events = find_ventilation_events(measurements, config);
+    let open_rate = median(events.iter().map(|e| e.rate).collect());
+    let source = estimate_source(measurements, config);
+    let occupants = source
+        .zip(volume_m3)
+        .map(|(s, v)| s * v / 1000.0 / config.co2_per_person);
+
+    let limit = config.limit_ppm;
+    let mut recommendation = Recommendation {
+        device: device.to_string(),
+        room: room_name,
+        volume_m3,
+        ventilation_events: events.len(),
+        air_exchange_rate: open_rate,
+        co2_source_ppm_per_hour: source,
+        estimated_occupants: occupants,
+        ventilate_minutes: None,
+        every_minutes: None,
+        limit_ppm: limit,
+        advice: String::new(),
+    };
+
+    recommendation.advice = match (source, open_rate) {
+        (None, _) => format!(
+            "No occupancy detected for {}; nothing to recommend yet.",
+            label
+        ),
+        (Some(_), None) => format!(
+            "No ventilation seen for {} yet; open a window once so its air exchange can be measured.",
+            label
+        ),
+        (Some(source), Some(open_rate)) => match plan(source, open_rate, config) {
+            Plan::NotNeeded { steady_ppm } => format!(
+                "{} stays around {:.0} ppm with the windows closed; no scheduled ventilation needed.",
+                capitalize(&label),
+                steady_ppm
+            ),
+            Plan::Continuous { open_steady_ppm } => format!(
+                "Keep a window open in {} during occupancy; even then CO2 only falls to about {:.0} ppm.",
+                label, open_steady_ppm
+            ),
+            Plan::Periodic {
+                ventilate_minutes,
+                every_minutes,
+            } => {
+                recommendation.ventilate_minutes = Some(ventilate_minutes);
+                recommendation.every_minutes = Some(every_minutes);
+                format!(
+                    "Ventilate {} ~{} minutes every {} during occupancy to stay under {:.0} ppm.",
+                    label,
+                    ventilate_minutes,
+                    format_interval(every_minutes),
+                    limit
+                )
+            }
+        },
+    };
+    recommendation
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Learns from the `lookback_days` before `now`, leaving out measurements
+/// taken during maintenance.
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_recommendation(
+    influx_host: &str,
+    influx_token: &str,
+    influx_database: &str,
+    reqwest_client: &reqwest::Client,
+    device: &Identifier,
+    now: DateTime<Utc>,
+    rooms: &RoomRegistry,
+    config: &VentilationConfig,
+) -> Result<Recommendation, Box<dyn Error>> {
+    let rows: Vec<InfluxMeasurementRow> = query_rows(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+        &Sql::new(
+            "SELECT time, co2_ppm, temperature_c, humidity_percent, device FROM scd40_data \
+             WHERE device = ",
+        )
+        .identifier(device)
+        .push(" AND time >= ")
+        .time(now - Duration::days(config.lookback_days))
+        .push(" AND time < ")
+        .time(now)
+        .push(" ORDER BY time ASC"),
+    )
+    .await?;
+    let maintenance_times = maintenance::fetch_maintenance_times(
+        influx_host,
+        influx_token,
+        influx_database,
+        reqwest_client,
+    )
+    .await;
+    let measurements = rows
+        .iter()
+        .map(|row| row.to_measurement_with_time())
+        .filter(|m| {
+            m.as_ref()
+                .map_or(true, |m| !maintenance_times.contains(&m.time))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(recommend(
+        device.as_str(),
+        &measurements,
+        rooms.get(device.as_str()),
+        config,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: f64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T08:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::seconds((minutes * 60.0) as i64)
+    }
+
+    fn sample(minutes: f64, co2: f64) -> MeasurementWithTime {
+        MeasurementWithTime {
+            co2: co2.round() as u16,
+            temperature: 21.0,
+            humidity: 40.0,
+            time: at(minutes),
+            device: "esp32-scd40".to_string(),
+        }
+    }
+
+    /// Samples every 5 minutes of the single-zone model from the module docs.
+    fn simulate(
+        start_minutes: f64,
+        start_ppm: f64,
+        source: f64,
+        rate: f64,
+        samples: usize,
+    ) -> Vec<MeasurementWithTime> {
+        let outdoor = VentilationConfig::default().outdoor_ppm;
+        let steady = outdoor + source / rate;
+        (0..samples)
+            .map(|i| {
+                let minutes = i as f64 * 5.0;
+                let c = steady + (start_ppm - steady) * (-rate * minutes / 60.0).exp();
+                sample(start_minutes + minutes, c)
+            })
+            .collect()
+    }
+
+    /// Empty room, morning build-up, a 20 minute airing, more build-up.
+    fn office_day() -> Vec<MeasurementWithTime> {
+        let mut day = simulate(0.0, 450.0, 0.0, 0.3, 6);
+        day.extend(simulate(30.0, 450.0, 600.0, 0.3, 24));
+        let peak = f64::from(day.last().unwrap().co2);
+        day.extend(simulate(150.0, peak, 0.0, 4.0, 5));
+        let low = f64::from(day.last().unwrap().co2);
+        day.extend(simulate(175.0, low + 30.0, 600.0, 0.3, 24));
+        day
+    }
+
+    #[test]
+    fn decay_fit_recovers_the_exchange_rate() {
+        for rate in [0.5, 2.0, 6.0] {
+            let curve = simulate(0.0, 1400.0, 0.0, rate, 8);
+            let fit = fit_decay(&curve, 420.0).unwrap();
+            assert!(
+                (fit.rate - rate).abs() < 0.05 * rate,
+                "{} vs {}",
+                fit.rate,
+                rate
+            );
+            assert!(fit.r2 > 0.99, "{}", fit.r2);
+            assert_eq!(fit.points, 8);
+            assert_eq!(fit.end, at(35.0));
+        }
+        // Below outdoor the log isn't defined
+        assert!(fit_decay(&[sample(0.0, 500.0), sample(5.0, 400.0)], 420.0).is_none());
+        assert!(fit_decay(&[sample(0.0, 500.0)], 420.0).is_none());
+    }
+
+    #[test]
+    fn only_fast_clean_decays_count_as_ventilation() {
+        let config = VentilationConfig::default();
+        let events = find_ventilation_events(&office_day(), &config);
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0].start, at(150.0));
+        assert!((events[0].rate - 4.0).abs() < 0.4, "{}", events[0].rate);
+
+        // A closed room slowly losing CO2 overnight isn't ventilation
+        let night = simulate(0.0, 1200.0, 0.0, 0.3, 48);
+        assert!(find_ventilation_events(&night, &config).is_empty());
+
+        // A gap splits the run, leaving two that are too short
+        let mut gapped = simulate(0.0, 1400.0, 0.0, 4.0, 6);
+        for m in &mut gapped[3..] {
+            m.time += Duration::minutes(30);
+        }
+        assert!(find_ventilation_events(&gapped, &config).is_empty());
+    }
+
+    #[test]
+    fn source_strength_from_build_up() {
+        let config = VentilationConfig::default();
+        let source = estimate_source(&office_day(), &config).unwrap();
+        assert!((source - 600.0).abs() < 30.0, "{}", source);
+        assert!(estimate_source(&simulate(0.0, 900.0, 0.0, 0.3, 20), &config).is_none());
+    }
+
+    #[test]
+    fn plan_follows_the_formula() {
+        let config = VentilationConfig::default();
+        // Steady state 420 + 600 / 0.3 = 2420 ppm closed, 570 ppm open
+        let Plan::Periodic {
+            ventilate_minutes,
+            every_minutes,
+        } = plan(600.0, 4.0, &config)
+        else {
+            panic!("expected periodic ventilation");
+        };
+        let closed = ((2420.0f64 - 600.0) / (2420.0 - 1000.0)).ln() / 0.3;
+        let open = ((1000.0f64 - 570.0) / (600.0 - 570.0)).ln() / 4.0;
+        assert_eq!(ventilate_minutes, (open * 60.0).ceil() as u32);
+        assert_eq!(every_minutes, ((closed + open) * 12.0).floor() as u32 * 5);
+        assert_eq!((ventilate_minutes, every_minutes), (40, 85));
+
+        // A small source settles below the limit
+        assert_eq!(
+            plan(150.0, 4.0, &config),
+            Plan::NotNeeded { steady_ppm: 920.0 }
+        );
+        // A draughty window that can't beat the source
+        assert_eq!(
+            plan(600.0, 2.0, &config),
+            Plan::Continuous {
+                open_steady_ppm: 720.0
+            }
+        );
+    }
+
+    #[test]
+    fn recommendation_for_a_registered_room() {
+        let config = VentilationConfig::default();
+        let room = Room {
+            name: Some("office".to_string()),
+            volume_m3: Some(40.0),
+            ..Room::default()
+        };
+        let recommendation = recommend("esp32-scd40", &office_day(), Some(&room), &config);
+        assert_eq!(recommendation.ventilation_events, 1);
+        // 600 ppm/h in 40 m³ is 24 L/h, a bit more than one person
+        let occupants = recommendation.estimated_occupants.unwrap();
+        assert!((occupants - 1.28).abs() < 0.1, "{}", occupants);
+        let minutes = recommendation.ventilate_minutes.unwrap();
+        let every = recommendation.every_minutes.unwrap();
+        assert_eq!(
+            recommendation.advice,
+            format!(
+                "Ventilate the office ~{} minutes every {} during occupancy to stay under 1000 ppm.",
+                minutes,
+                format_interval(every)
+            )
+        );
+    }
+
+    #[test]
+    fn recommendation_without_enough_data() {
+        let config = VentilationConfig::default();
+        let build_up = simulate(0.0, 450.0, 600.0, 0.3, 24);
+        let recommendation = recommend("esp32-scd40", &build_up, None, &config);
+        assert_eq!(recommendation.ventilation_events, 0);
+        assert!(recommendation.estimated_occupants.is_none());
+        assert!(
+            recommendation
+                .advice
+                .starts_with("No ventilation seen for esp32-scd40 yet"),
+            "{}",
+            recommendation.advice
+        );
+        let empty = recommend("esp32-scd40", &[], None, &config);
+        assert!(empty.advice.starts_with("No occupancy detected"));
+    }
+
+    #[test]
+    fn interval_wording() {
+        assert_eq!(format_interval(85), "85 minutes");
+        assert_eq!(format_interval(120), "2 hours");
+        assert_eq!(format_interval(175), "2.5 hours");
+        assert_eq!(format_interval(239), "3.5 hours");
+    }
+
+    #[test]
+    fn config_parsing() {
+        let config: VentilationConfig = "limit_ppm=1200, infiltration_rate=0.5,min_points=5"
+            .parse()
+            .unwrap();
+        assert_eq!(config.limit_ppm, 1200.0);
+        assert_eq!(config.infiltration_rate, 0.5);
+        assert_eq!(config.min_points, 5);
+        assert_eq!(config.target_ppm, 600.0);
+
+        assert!("limit=1200".parse::<VentilationConfig>().is_err());
+        assert!("target_ppm=1100".parse::<VentilationConfig>().is_err());
+        assert!("infiltration_rate=0".parse::<VentilationConfig>().is_err());
+        assert!("limit_ppm=-5".parse::<VentilationConfig>().is_err());
+    }
+
+    #[test]
+    fn registry_file() {
+        let path =
+            std::env::temp_dir().join(format!("rpi-processor-rooms-{}.json", std::process::id()));
+        assert_eq!(RoomRegistry::load(&path).unwrap(), RoomRegistry::default());
+        std::fs::write(
+            &path,
+            r#"{"esp32-scd40": {"name": "office", "volume_m3": 38.5}, "hall": {}}"#,
+        )
+        .unwrap();
+        let rooms = RoomRegistry::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rooms.get("esp32-scd40").unwrap().volume_m3, Some(38.5));
+        assert_eq!(rooms.get("hall"), Some(&Room::default()));
+        assert!(rooms.get("bedroom").is_none());
+    }
+}
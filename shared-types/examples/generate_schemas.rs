@@ -0,0 +1,23 @@
+//! Writes `device_message.schema.json` and `device_command.schema.json`
+//! into the given directory, or the current one.
+//!
+//! ```text
+//! cargo run -p shared-types --features schema --example generate-schemas -- <dir>
+//! ```
+
+use std::path::PathBuf;
+
+use shared_types::schema;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| ".".to_string()));
+    for (file, schema) in [
+        (schema::DEVICE_MESSAGE_FILE, schema::device_message()),
+        (schema::DEVICE_COMMAND_FILE, schema::device_command()),
+    ] {
+        let path = dir.join(file);
+        std::fs::write(&path, serde_json::to_string_pretty(&schema)? + "\n")?;
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! Fixed-capacity strings for the `no_alloc` feature.
+//!
+//! With it, `DeviceName`, `Detail` and `Label` are `BoundedString`s
+//! instead of `String`s, so the firmware builds and publishes a message
+//! without a heap allocation per string. They serialize exactly like a
+//! `str`, in JSON, CBOR and postcard alike.
+//!
+//! That covers the strings a device puts in most messages: `device`,
+//! `detail`, `fw_version`, `location` and `reset_reason`. Out of scope, and
+//! still allocating, are `log_lines` and `measurement_batch`, whose lists
+//! kept inline would make every `DevicePayload` kilobytes long, and the
+//! strings of payloads sent once per command or update: command names,
+//! `commands_deferred`, `ota_success` and `firmware_info`.
+//!
+//! The feature changes public types, so it isn't additive: only enable it
+//! in builds where nothing else uses shared-types with `String`s.
+
+use core::fmt;
+use core::ops::Deref;
+
+use serde::{Deserialize, Serialize};
+
+/// At most `N` bytes of UTF-8, kept inline. Converting a longer `&str`
+/// keeps the longest prefix that fits on a character boundary; parsing one
+/// from a message fails instead.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BoundedString<const N: usize>(heapless::String<N>);
+
+impl<const N: usize> BoundedString<N> {
+    pub const CAPACITY: usize = N;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// `args` formatted in place, cut like a converted `&str`. Use it
+    /// through `detail!`.
+    pub fn from_args(args: fmt::Arguments<'_>) -> Self {
+        let mut text = Self::default();
+        // Only fails once the text is cut, which is what we want
+        let _ = fmt::Write::write_fmt(&mut text, args);
+        text
+    }
+}
+
+impl<const N: usize> From<&str> for BoundedString<N> {
+    fn from(s: &str) -> Self {
+        let mut text = Self::default();
+        let _ = fmt::Write::write_str(&mut text, s);
+        text
+    }
+}
+
+/// Appends what fits on a character boundary. Writing fails once something
+/// was cut, so formatting stops there and the text stays a prefix.
+impl<const N: usize> fmt::Write for BoundedString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(N - self.0.len());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0
+            .push_str(&s[..end])
+            .expect("a prefix no longer than the room left");
+        if end == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+impl<const N: usize> Deref for BoundedString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<const N: usize> AsRef<str> for BoundedString<N> {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<const N: usize> PartialEq<str> for BoundedString<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&str> for BoundedString<N> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl<const N: usize> fmt::Debug for BoundedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> fmt::Display for BoundedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+/// `maxLength` counts characters, not bytes, so text with multibyte
+/// characters can pass the schema and still be too long.
+#[cfg(feature = "schema")]
+impl<const N: usize> schemars::JsonSchema for BoundedString<N> {
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn schema_name() -> alloc::borrow::Cow<'static, str> {
+        alloc::format!("BoundedString{}", N).into()
+    }
+
+    fn json_schema(_generator: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({ "type": "string", "maxLength": N })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_text_is_cut_on_a_character_boundary() {
+        assert_eq!(BoundedString::<8>::from("kitchen"), "kitchen");
+        assert_eq!(BoundedString::<8>::from("living-room"), "living-r");
+        // "ż" takes two bytes, the second would be the 9th
+        assert_eq!(BoundedString::<8>::from("sypialnż"), "sypialn");
+    }
+
+    #[test]
+    fn formatting_stops_at_the_first_cut() {
+        assert_eq!(
+            BoundedString::<8>::from_args(format_args!("{}-{}", "ab", 12)),
+            "ab-12"
+        );
+        // The "ż" doesn't fit, so neither does the "a" after it
+        assert_eq!(
+            BoundedString::<8>::from_args(format_args!("{}{}", "sypialnż", "a")),
+            "sypialn"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn serializes_like_a_string() {
+        let bounded = BoundedString::<32>::from("esp32-\"scd40\"");
+        let string = String::from("esp32-\"scd40\"");
+        assert_eq!(
+            serde_json::to_string(&bounded).unwrap(),
+            serde_json::to_string(&string).unwrap()
+        );
+        assert_eq!(
+            serde_json::from_str::<BoundedString<32>>("\"kitchen\"").unwrap(),
+            "kitchen"
+        );
+        assert!(serde_json::from_str::<BoundedString<4>>("\"kitchen\"").is_err());
+    }
+}
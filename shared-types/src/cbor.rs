@@ -0,0 +1,144 @@
+//! CBOR encoding of messages and commands, for tooling that speaks CBOR.
+//!
+//! Unlike postcard, CBOR is self-describing: the serde attributes used for
+//! JSON apply unchanged, so a message is a map with a `status` key and a
+//! command a map with a `cmd` key, exactly like their JSON forms.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CborError {
+    /// The input ended in the middle of an item
+    Truncated,
+    /// More input follows the item
+    TrailingData,
+    /// Not well-formed CBOR; the byte offset where decoding stopped
+    Syntax(usize),
+    /// Well-formed CBOR that doesn't fit the type, e.g. a missing field
+    Semantic(Option<usize>, String),
+    /// Nested deeper than the decoder allows
+    RecursionLimitExceeded,
+    /// The value couldn't be represented in CBOR
+    Value(String),
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborError::Truncated => f.write_str("CBOR input ended early"),
+            CborError::TrailingData => f.write_str("CBOR input has data past its item"),
+            CborError::Syntax(offset) => write!(f, "invalid CBOR at byte {}", offset),
+            CborError::Semantic(Some(offset), reason) => {
+                write!(f, "unexpected CBOR at byte {}: {}", offset, reason)
+            }
+            CborError::Semantic(None, reason) => write!(f, "unexpected CBOR: {}", reason),
+            CborError::RecursionLimitExceeded => f.write_str("CBOR nested too deeply"),
+            CborError::Value(reason) => write!(f, "can't encode as CBOR: {}", reason),
+        }
+    }
+}
+
+impl core::error::Error for CborError {}
+
+pub(crate) fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, CborError> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes).map_err(|e| match e {
+        // Writing to a Vec only fails when out of memory, which aborts
+        ciborium::ser::Error::Io(_) => CborError::Value("write failed".into()),
+        ciborium::ser::Error::Value(reason) => CborError::Value(reason),
+    })?;
+    Ok(bytes)
+}
+
+pub(crate) fn from_slice<T: DeserializeOwned>(mut bytes: &[u8]) -> Result<T, CborError> {
+    let value = ciborium::from_reader(&mut bytes).map_err(|e| match e {
+        ciborium::de::Error::Io(_) => CborError::Truncated,
+        ciborium::de::Error::Syntax(offset) => CborError::Syntax(offset),
+        ciborium::de::Error::Semantic(offset, reason) => CborError::Semantic(offset, reason),
+        ciborium::de::Error::RecursionLimitExceeded => CborError::RecursionLimitExceeded,
+    })?;
+    if !bytes.is_empty() {
+        return Err(CborError::TrailingData);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodecError, DeviceCommand, DeviceMessage, DevicePayload};
+
+    fn message() -> DeviceMessage {
+        DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+        )
+        .stamped(Some(1_736_942_400_123), 42)
+    }
+
+    #[test]
+    fn same_map_as_json() {
+        let message = message();
+        let cbor: serde_json::Value = from_slice(&message.to_cbor().unwrap()).unwrap();
+        assert_eq!(cbor, serde_json::to_value(&message).unwrap());
+        assert_eq!(cbor["status"], "success");
+
+        let command = DeviceCommand::SetTempOffset {
+            offset: 4.0,
+            persist: false,
+        };
+        let cbor: serde_json::Value = from_slice(&command.to_cbor().unwrap()).unwrap();
+        assert_eq!(cbor, serde_json::to_value(&command).unwrap());
+        assert_eq!(cbor["cmd"], "set_temp_offset");
+    }
+
+    #[test]
+    fn smaller_than_json() {
+        let message = message();
+        assert!(message.to_cbor().unwrap().len() < message.to_json().unwrap().len());
+    }
+
+    #[test]
+    fn every_truncation_is_detected() {
+        let bytes = message().to_cbor().unwrap();
+        for len in 0..bytes.len() {
+            let error = DeviceMessage::from_cbor(&bytes[..len]).unwrap_err();
+            assert!(
+                matches!(error, CodecError::Cbor(CborError::Truncated)),
+                "cut to {} bytes: {:?}",
+                len,
+                error
+            );
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(
+            DeviceMessage::from_cbor(&longer),
+            Err(CodecError::Cbor(CborError::TrailingData))
+        ));
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        // Additional information 28 is reserved
+        assert!(matches!(
+            DeviceMessage::from_cbor(&[0x1c]),
+            Err(CodecError::Cbor(CborError::Syntax(0)))
+        ));
+        // An integer where a map is expected
+        assert!(matches!(
+            DeviceMessage::from_cbor(&[0x01]),
+            Err(CodecError::Cbor(CborError::Semantic(..)))
+        ));
+        // A map without the `cmd` tag
+        assert!(matches!(
+            DeviceCommand::from_cbor(&[0xa0]),
+            Err(CodecError::Cbor(CborError::Semantic(..)))
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+//! One error for every encoding and decoding helper.
+//!
+//! `to_json`, `to_cbor`, `to_postcard` and their `from_` counterparts all
+//! fail with `CodecError`, so callers handle one type whichever encoding a
+//! build uses, and a message that decodes but fails `validate` is reported
+//! the same way. JSON is always there; the CBOR and postcard variants only
+//! exist with their features. Only `core` is used here, so the type works
+//! without `std`.
+
+use core::fmt;
+
+#[cfg(feature = "cbor")]
+use crate::cbor::CborError;
+use crate::validation::ValidationError;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    #[cfg(feature = "cbor")]
+    Cbor(CborError),
+    #[cfg(feature = "postcard")]
+    Postcard(postcard::Error),
+    /// Decoded, but not a message worth acting on
+    Validation(ValidationError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "JSON: {}", e),
+            // Both already say what they are about
+            #[cfg(feature = "cbor")]
+            CodecError::Cbor(e) => write!(f, "{}", e),
+            #[cfg(feature = "postcard")]
+            CodecError::Postcard(e) => write!(f, "postcard: {}", e),
+            CodecError::Validation(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            CodecError::Json(e) => Some(e),
+            #[cfg(feature = "cbor")]
+            CodecError::Cbor(e) => Some(e),
+            #[cfg(feature = "postcard")]
+            CodecError::Postcard(_) => None,
+            CodecError::Validation(e) => Some(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(error: serde_json::Error) -> Self {
+        CodecError::Json(error)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<CborError> for CodecError {
+    fn from(error: CborError) -> Self {
+        CodecError::Cbor(error)
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl From<postcard::Error> for CodecError {
+    fn from(error: postcard::Error) -> Self {
+        CodecError::Postcard(error)
+    }
+}
+
+impl From<ValidationError> for CodecError {
+    fn from(error: ValidationError) -> Self {
+        CodecError::Validation(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::error::Error;
+
+    use crate::{DeviceCommand, DeviceMessage, DevicePayload};
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn json_errors_convert() {
+        let error = DeviceMessage::from_json(r#"{"device":"kitchen""#).unwrap_err();
+        assert!(
+            matches!(&error, CodecError::Json(e) if e.is_eof()),
+            "{:?}",
+            error
+        );
+        assert!(error.to_string().starts_with("JSON: EOF"), "{}", error);
+        assert!(error.source().is_some());
+
+        let error = CodecError::from(serde_json::from_str::<u8>("x").unwrap_err());
+        assert!(matches!(&error, CodecError::Json(e) if e.is_syntax()));
+        assert!(matches!(
+            DeviceCommand::from_json_slice(br#"{"cmd":"fly"}"#),
+            Err(CodecError::Json(_))
+        ));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_errors_convert() {
+        let error = DeviceMessage::from_cbor(&[]).unwrap_err();
+        assert!(matches!(error, CodecError::Cbor(CborError::Truncated)));
+        assert_eq!(error.to_string(), "CBOR input ended early");
+        assert!(matches!(
+            CodecError::from(CborError::TrailingData),
+            CodecError::Cbor(CborError::TrailingData)
+        ));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_errors_convert() {
+        let message = DeviceMessage::new(
+            "kitchen",
+            DevicePayload::try_measurement(600, 21.0, 40.0).unwrap(),
+        );
+        let mut small = [0; 4];
+        let error = message.to_postcard(&mut small).unwrap_err();
+        assert!(matches!(
+            error,
+            CodecError::Postcard(postcard::Error::SerializeBufferFull)
+        ));
+        assert!(error.to_string().starts_with("postcard: "), "{}", error);
+        assert!(matches!(
+            DeviceMessage::from_postcard(&[]),
+            Err(CodecError::Postcard(
+                postcard::Error::DeserializeUnexpectedEnd
+            ))
+        ));
+    }
+
+    #[test]
+    fn validation_errors_convert() {
+        let message = DeviceMessage::new(" ", DevicePayload::Alive { uptime_seconds: 60 });
+        let error = CodecError::from(message.validate().unwrap_err());
+        assert!(matches!(
+            error,
+            CodecError::Validation(ValidationError::EmptyDevice)
+        ));
+        assert_eq!(error.to_string(), "device name is empty");
+        assert!(error.source().is_some());
+    }
+}
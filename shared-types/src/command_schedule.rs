@@ -0,0 +1,459 @@
+//! Which of the commands received in one wake the device runs now.
+//!
+//! Some commands must not overlap with anything else: a temperature offset
+//! written halfway through forced recalibration corrupts the calibration,
+//! and an OTA update or a `reboot` ends the wake with a restart.
+//! Those exclusive commands run alone, and everything else that arrived in
+//! the same wake is deferred: the device re-publishes it as a retained
+//! `batch` with `deferred: true` and picks it up on the next wake.
+//!
+//! A `set_asc` or `set_altitude` saves all of the sensor's settings to its
+//! EEPROM, a temperature offset sent with `persist: false` included. Such
+//! offsets received after the last saved one move behind the last of those
+//! commands, so the EEPROM keeps the saved offset.
+//!
+//! Commands are scheduled with the id they were sent with, so each answer
+//! can carry it back. A batch is scheduled as its commands, see
+//! [`CommandEnvelope::into_envelopes`], each with the batch's id, sender
+//! and expiry. [`schedule_tagged`] keeps a tag with each command as well,
+//! e.g. the topic it arrived on, so deferred commands go back where they
+//! came from.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{CommandEnvelope, DeviceCommand, DeviceCommandBatch, MAX_BATCH_COMMANDS};
+
+impl DeviceCommand {
+    /// Whether the command has to run without any other command in the same wake.
+    pub fn is_exclusive(&self) -> bool {
+        match self {
+            DeviceCommand::StartFrc { .. }
+            | DeviceCommand::Ota { .. }
+            | DeviceCommand::FactoryReset { .. }
+            | DeviceCommand::Reboot => true,
+            DeviceCommand::NoOp
+            | DeviceCommand::SetTempOffset { .. }
+            | DeviceCommand::GetTempOffset
+            | DeviceCommand::SetDeepSleepTime { .. }
+            | DeviceCommand::GetDeepSleepTime
+            | DeviceCommand::SetMqttPolicy { .. }
+            | DeviceCommand::GetConfig
+            | DeviceCommand::SetLogLevel { .. }
+            | DeviceCommand::GetLogLevel
+            | DeviceCommand::SetAdaptiveMode { .. }
+            | DeviceCommand::SetAsc { .. }
+            | DeviceCommand::GetAsc
+            | DeviceCommand::SetAltitude { .. }
+            | DeviceCommand::GetAltitude
+            | DeviceCommand::SetAmbientPressure { .. }
+            | DeviceCommand::ConfirmConfig { .. }
+            | DeviceCommand::SelfTest
+            | DeviceCommand::GetSerialNumber
+            | DeviceCommand::GetFirmwareInfo
+            | DeviceCommand::InjectFault { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule<C = CommandEnvelope> {
+    /// In the order received
+    pub run: Vec<C>,
+    /// To re-publish for the next wake, in the order received
+    pub deferred: Vec<C>,
+}
+
+/// Splits one wake's commands into what runs now and what waits.
+///
+/// A no-op only asks for the regular measurement, so it is dropped when
+/// there are real commands. If any command is exclusive, the first one runs
+/// alone; otherwise everything runs in order.
+pub fn schedule(commands: Vec<CommandEnvelope>) -> Schedule {
+    let Schedule { run, deferred } =
+        schedule_tagged(commands.into_iter().map(|c| ((), c)).collect());
+    Schedule {
+        run: run.into_iter().map(|((), c)| c).collect(),
+        deferred: deferred.into_iter().map(|((), c)| c).collect(),
+    }
+}
+
+/// [`schedule`] for commands that each carry a tag, e.g. the topic they
+/// arrived on.
+pub fn schedule_tagged<T>(
+    mut commands: Vec<(T, CommandEnvelope)>,
+) -> Schedule<(T, CommandEnvelope)> {
+    if commands
+        .iter()
+        .any(|(_, c)| c.command != DeviceCommand::NoOp)
+    {
+        commands.retain(|(_, c)| c.command != DeviceCommand::NoOp);
+    }
+    save_before_volatile_offsets(&mut commands);
+
+    match commands.iter().position(|(_, c)| c.command.is_exclusive()) {
+        Some(index) => {
+            let exclusive = commands.remove(index);
+            Schedule {
+                run: vec![exclusive],
+                deferred: commands,
+            }
+        }
+        None => Schedule {
+            run: commands,
+            deferred: Vec::new(),
+        },
+    }
+}
+
+/// Moves the volatile offsets that the last `set_asc` or `set_altitude`
+/// would save behind it, keeping their order.
+fn save_before_volatile_offsets<T>(commands: &mut Vec<(T, CommandEnvelope)>) {
+    let Some(mut save) = commands.iter().rposition(|(_, c)| {
+        matches!(
+            c.command,
+            DeviceCommand::SetAsc { .. }
+                | DeviceCommand::SetAltitude { .. }
+                | DeviceCommand::SetTempOffset { persist: true, .. }
+        )
+    }) else {
+        return;
+    };
+    if matches!(
+        commands[save].1.command,
+        DeviceCommand::SetTempOffset { .. }
+    ) {
+        return;
+    }
+    let mut index = commands[..save]
+        .iter()
+        .rposition(|(_, c)| {
+            matches!(
+                c.command,
+                DeviceCommand::SetTempOffset { persist: true, .. }
+            )
+        })
+        .map_or(0, |saved| saved + 1);
+    let mut volatile = Vec::new();
+    while index < save {
+        if matches!(
+            commands[index].1.command,
+            DeviceCommand::SetTempOffset { .. }
+        ) {
+            volatile.push(commands.remove(index));
+            save -= 1;
+        } else {
+            index += 1;
+        }
+    }
+    commands.splice(save + 1..save + 1, volatile);
+}
+
+/// The retained batch that carries deferred commands to the next wake. A
+/// batch has a single id, issuer and expiry, so the commands keep their id
+/// and issuer only if they share them, and the batch expires with the first
+/// command to expire: none of them then runs later than it was meant to.
+///
+/// A batch holds [`MAX_BATCH_COMMANDS`]; the commands past that come back
+/// second, for the device to turn down.
+pub fn deferred_batch(
+    mut deferred: Vec<CommandEnvelope>,
+) -> (CommandEnvelope<DeviceCommandBatch>, Vec<CommandEnvelope>) {
+    let rest = deferred.split_off(deferred.len().min(MAX_BATCH_COMMANDS));
+    let id = deferred.first().and_then(|first| first.id);
+    let shared = deferred.iter().all(|c| c.id == id);
+    let issued_by = deferred.first().and_then(|first| first.issued_by.clone());
+    let same_issuer = deferred.iter().all(|c| c.issued_by == issued_by);
+    let (issued_at_unix, ttl_seconds) = deferred
+        .iter()
+        .filter(|c| c.expires_at_unix().is_some())
+        .min_by_key(|c| c.expires_at_unix())
+        .map(|soonest| (soonest.issued_at_unix, soonest.ttl_seconds))
+        .unwrap_or_default();
+    let batch = CommandEnvelope {
+        id: id.filter(|_| shared),
+        issued_by: issued_by.filter(|_| same_issuer),
+        issued_at_unix,
+        ttl_seconds,
+        command: DeviceCommandBatch {
+            commands: deferred.into_iter().map(|c| c.command).collect(),
+            deferred: true,
+        },
+    };
+    (batch, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frc() -> DeviceCommand {
+        DeviceCommand::StartFrc { target_ppm: 422 }
+    }
+
+    fn offset(offset: f32) -> DeviceCommand {
+        DeviceCommand::SetTempOffset {
+            offset,
+            persist: true,
+        }
+    }
+
+    fn batch(commands: Vec<DeviceCommand>, deferred: bool) -> DeviceCommandBatch {
+        DeviceCommandBatch {
+            deferred,
+            ..DeviceCommandBatch::new(commands).unwrap()
+        }
+    }
+
+    /// A deferred batch that had room for every command
+    fn batched(deferred: Vec<CommandEnvelope>) -> CommandEnvelope<DeviceCommandBatch> {
+        let (batch, rest) = deferred_batch(deferred);
+        assert!(rest.is_empty());
+        batch
+    }
+
+    /// As sent without ids
+    fn plain(commands: Vec<DeviceCommand>) -> Vec<CommandEnvelope> {
+        commands.into_iter().map(CommandEnvelope::from).collect()
+    }
+
+    #[test]
+    fn compatible_commands_all_run_in_order() {
+        let commands = vec![
+            offset(4.0),
+            DeviceCommand::GetTempOffset,
+            DeviceCommand::SetDeepSleepTime { seconds: 600 },
+        ];
+        assert_eq!(
+            schedule(plain(commands.clone())),
+            Schedule {
+                run: plain(commands),
+                deferred: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn a_volatile_offset_runs_after_the_commands_that_would_save_it() {
+        let volatile = |offset| DeviceCommand::SetTempOffset {
+            offset,
+            persist: false,
+        };
+        let asc = DeviceCommand::SetAsc { enabled: false };
+        let altitude = DeviceCommand::SetAltitude { meters: 600 };
+        assert_eq!(
+            schedule(plain(vec![volatile(4.0), asc.clone()])).run,
+            plain(vec![asc.clone(), volatile(4.0)])
+        );
+        assert_eq!(
+            schedule(plain(vec![
+                offset(3.0),
+                volatile(4.0),
+                DeviceCommand::GetTempOffset,
+                asc.clone(),
+                altitude.clone(),
+                volatile(5.0),
+            ]))
+            .run,
+            plain(vec![
+                offset(3.0),
+                DeviceCommand::GetTempOffset,
+                asc.clone(),
+                altitude,
+                volatile(4.0),
+                volatile(5.0),
+            ])
+        );
+        // A saved offset after them writes the EEPROM last anyway
+        let commands = vec![volatile(4.0), asc, offset(5.0)];
+        assert_eq!(schedule(plain(commands.clone())).run, plain(commands));
+    }
+
+    #[test]
+    fn frc_runs_alone_and_defers_the_rest() {
+        let s = schedule(plain(vec![
+            offset(4.0),
+            frc(),
+            DeviceCommand::GetDeepSleepTime,
+        ]));
+        assert_eq!(s.run, plain(vec![frc()]));
+        assert_eq!(
+            s.deferred,
+            plain(vec![offset(4.0), DeviceCommand::GetDeepSleepTime])
+        );
+    }
+
+    #[test]
+    fn second_exclusive_command_waits_too() {
+        let other = DeviceCommand::StartFrc { target_ppm: 450 };
+        let s = schedule(plain(vec![frc(), other.clone()]));
+        assert_eq!(s.run, plain(vec![frc()]));
+        assert_eq!(s.deferred, plain(vec![other]));
+
+        let ota = DeviceCommand::Ota {
+            url: "http://firmware.local/air.bin".to_string(),
+        };
+        let s = schedule(plain(vec![ota.clone(), offset(4.0)]));
+        assert_eq!(s.run, plain(vec![ota]));
+        assert_eq!(s.deferred, plain(vec![offset(4.0)]));
+    }
+
+    #[test]
+    fn noop_only_matters_alone() {
+        assert_eq!(schedule(vec![]).run, vec![]);
+        assert_eq!(
+            schedule(plain(vec![DeviceCommand::NoOp, DeviceCommand::NoOp])).run,
+            plain(vec![DeviceCommand::NoOp, DeviceCommand::NoOp])
+        );
+        let s = schedule(plain(vec![DeviceCommand::NoOp, frc(), DeviceCommand::NoOp]));
+        assert_eq!(s.run, plain(vec![frc()]));
+        assert!(s.deferred.is_empty());
+    }
+
+    #[test]
+    fn deferred_batch_from_last_wake_is_unpacked() {
+        // Last wake ran FRC and deferred the offset; a new command came in since
+        let mut commands: Vec<_> =
+            CommandEnvelope::from(batch(vec![offset(4.0), DeviceCommand::GetTempOffset], true))
+                .into_envelopes()
+                .collect();
+        commands.push(DeviceCommand::GetDeepSleepTime.into());
+        let s = schedule(commands);
+        assert_eq!(
+            s.run,
+            plain(vec![
+                offset(4.0),
+                DeviceCommand::GetTempOffset,
+                DeviceCommand::GetDeepSleepTime,
+            ])
+        );
+        assert!(s.deferred.is_empty());
+    }
+
+    #[test]
+    fn exclusive_inside_a_batch_is_found() {
+        let mut commands = plain(vec![offset(1.0)]);
+        commands
+            .extend(CommandEnvelope::from(batch(vec![frc(), offset(2.0)], false)).into_envelopes());
+        let s = schedule(commands);
+        assert_eq!(s.run, plain(vec![frc()]));
+        assert_eq!(s.deferred, plain(vec![offset(1.0), offset(2.0)]));
+    }
+
+    #[test]
+    fn batch_id_goes_to_each_command() {
+        let mut commands: Vec<_> = batch(vec![offset(4.0), frc()], false)
+            .with_id(7)
+            .into_envelopes()
+            .collect();
+        commands.push(DeviceCommand::GetTempOffset.with_id(8));
+        let s = schedule(commands);
+        assert_eq!(s.run, vec![frc().with_id(7)]);
+        assert_eq!(
+            s.deferred,
+            vec![
+                offset(4.0).with_id(7),
+                DeviceCommand::GetTempOffset.with_id(8)
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_follow_their_commands() {
+        let mut commands = vec![("broadcast", offset(4.0).into())];
+        commands.extend(
+            CommandEnvelope::from(batch(vec![frc(), DeviceCommand::GetTempOffset], false))
+                .into_envelopes()
+                .map(|c| ("kitchen", c)),
+        );
+        commands.push(("broadcast", DeviceCommand::NoOp.into()));
+        let s = schedule_tagged(commands);
+        assert_eq!(s.run, vec![("kitchen", frc().into())]);
+        assert_eq!(
+            s.deferred,
+            vec![
+                ("broadcast", offset(4.0).into()),
+                ("kitchen", DeviceCommand::GetTempOffset.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn deferred_batch_keeps_a_shared_id() {
+        let shared = batched(vec![
+            offset(4.0).with_id(7),
+            DeviceCommand::GetTempOffset.with_id(7),
+        ]);
+        assert_eq!(shared.id, Some(7));
+        assert_eq!(
+            shared.command,
+            batch(vec![offset(4.0), DeviceCommand::GetTempOffset], true)
+        );
+
+        let mixed = batched(vec![offset(4.0).with_id(7), frc().with_id(8)]);
+        assert_eq!(mixed.id, None);
+        let partly = batched(vec![offset(4.0).with_id(7), frc().into()]);
+        assert_eq!(partly.id, None);
+
+        let signed = batched(vec![
+            offset(4.0).with_id(7).issued_by("ola"),
+            CommandEnvelope::from(frc()).issued_by("ola"),
+        ]);
+        assert_eq!(signed.issued_by.as_deref(), Some("ola"));
+        let cosigned = batched(vec![
+            offset(4.0).with_id(7).issued_by("ola"),
+            frc().with_id(7).issued_by("mateusz"),
+        ]);
+        assert_eq!(cosigned.issued_by, None);
+
+        let expiring = batched(vec![
+            offset(4.0).with_id(7).expires(1_000, 60),
+            CommandEnvelope::from(frc()).expires(1_000, 60),
+        ]);
+        assert_eq!(expiring.expires_at_unix(), Some(1_060));
+        let staggered = batched(vec![
+            offset(4.0).with_id(7).expires(1_030, 60),
+            CommandEnvelope::from(frc()).expires(1_000, 120),
+            CommandEnvelope::from(DeviceCommand::GetTempOffset).expires(1_000, 60),
+        ]);
+        assert_eq!(staggered.expires_at_unix(), Some(1_060));
+        let partly_expiring = batched(vec![
+            offset(4.0).with_id(7),
+            CommandEnvelope::from(frc()).expires(1_030, 60),
+        ]);
+        assert_eq!(partly_expiring.expires_at_unix(), Some(1_090));
+        let lasting = batched(vec![offset(4.0).with_id(7), frc().into()]);
+        assert_eq!(lasting.expires_at_unix(), None);
+        assert_eq!(
+            schedule(expiring.clone().into_envelopes().collect()).run[0].expires_at_unix(),
+            Some(1_060)
+        );
+
+        // Scheduled again next wake, the id comes back
+        let s = schedule(shared.into_envelopes().collect());
+        assert_eq!(
+            s.run,
+            vec![
+                offset(4.0).with_id(7),
+                DeviceCommand::GetTempOffset.with_id(7)
+            ]
+        );
+    }
+
+    #[test]
+    fn deferred_batch_turns_back_what_doesnt_fit() {
+        let deferred = (0..10u32)
+            .map(|id| DeviceCommand::GetAsc.with_id(id))
+            .collect();
+        let (batch, rest) = deferred_batch(deferred);
+        assert_eq!(batch.command.commands.len(), MAX_BATCH_COMMANDS);
+        assert_eq!(batch.id, None);
+        assert_eq!(
+            rest,
+            vec![
+                DeviceCommand::GetAsc.with_id(8),
+                DeviceCommand::GetAsc.with_id(9)
+            ]
+        );
+    }
+}
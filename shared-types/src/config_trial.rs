@@ -0,0 +1,458 @@
+//! Apply-then-confirm for settings that can cut a device off.
+//!
+//! A deep sleep time of a week or an MQTT policy the broker rejects leaves
+//! a device that never comes back to read the command that would fix it.
+//! So the firmware puts `set_deep_sleep_time` and `set_mqtt_policy` on
+//! trial: the new value is used right away, but only saved as the
+//! `ConfigTrial`'s pending change, and the device answers
+//! `pending_confirmation`. A `confirm_config` with the same id, in the same
+//! wake or a later one, saves it for good.
+//!
+//! Until then every wake applies the pending change again on top of the
+//! saved settings. Once `confirm_wakes` more wakes have passed without a
+//! confirmation the change is dropped, which puts the saved settings back,
+//! and the rollback is kept until the device gets to report it.
+//!
+//! The trial is stored as one versioned blob (see `versioned`), so a torn
+//! write reads as no trial: the device falls back to its saved settings.
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::DeviceCommand;
+use crate::DevicePayload;
+use crate::mqtt_policy::PayloadClass;
+use crate::versioned::{BlobError, Migrations};
+use crate::{Detail, detail};
+
+/// Wakes after the one that staged a change that may still confirm it,
+/// when the build doesn't set `CONFIRM_WAKES`
+pub const DEFAULT_CONFIRM_WAKES: u8 = 3;
+
+/// Version 1: the pending change, then the unreported rollback, each a tag
+/// byte (0 for none) followed by the id (u32), the wakes (u8) and the
+/// change's fields, little endian
+const LAYOUTS: Migrations<'static> = Migrations::new(1, &[]);
+
+const TAG_NONE: u8 = 0;
+const TAG_DEEP_SLEEP: u8 = 1;
+const TAG_MQTT_POLICY: u8 = 2;
+
+/// A setting that goes through a trial before it is saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigChange {
+    DeepSleep {
+        seconds: u64,
+    },
+    MqttPolicy {
+        class: PayloadClass,
+        qos: u8,
+        retain: bool,
+    },
+}
+
+impl ConfigChange {
+    /// The change `command` makes, if it needs confirming
+    pub fn of(command: &DeviceCommand) -> Option<Self> {
+        match *command {
+            DeviceCommand::SetDeepSleepTime { seconds } => {
+                Some(ConfigChange::DeepSleep { seconds })
+            }
+            DeviceCommand::SetMqttPolicy { class, qos, retain } => {
+                Some(ConfigChange::MqttPolicy { class, qos, retain })
+            }
+            _ => None,
+        }
+    }
+
+    /// Name of the command that makes it
+    pub fn command_name(&self) -> &'static str {
+        match self {
+            ConfigChange::DeepSleep { .. } => "set_deep_sleep_time",
+            ConfigChange::MqttPolicy { .. } => "set_mqtt_policy",
+        }
+    }
+
+    /// The command's usual answer, sent once the change is saved
+    pub fn saved(&self) -> DevicePayload {
+        match *self {
+            ConfigChange::DeepSleep { seconds } => {
+                DevicePayload::SetDeepSleepTimeSuccess { seconds }
+            }
+            ConfigChange::MqttPolicy { class, qos, retain } => {
+                DevicePayload::SetMqttPolicySuccess { class, qos, retain }
+            }
+        }
+    }
+
+    /// Whether both change the same setting, so one replaces the other
+    /// without anything to put back
+    pub fn same_setting(&self, other: &ConfigChange) -> bool {
+        match (self, other) {
+            (ConfigChange::DeepSleep { .. }, ConfigChange::DeepSleep { .. }) => true,
+            (
+                ConfigChange::MqttPolicy { class, .. },
+                ConfigChange::MqttPolicy { class: other, .. },
+            ) => class == other,
+            _ => false,
+        }
+    }
+}
+
+/// A change on trial, confirmed by `id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingChange {
+    pub id: u32,
+    pub change: ConfigChange,
+    /// Wakes started since the one that staged it
+    pub wakes: u8,
+}
+
+impl PendingChange {
+    /// The `pending_confirmation` announcing it
+    pub fn announcement(&self, confirm_wakes: u8) -> DevicePayload {
+        DevicePayload::PendingConfirmation {
+            id: self.id,
+            command: self.change.command_name().to_string(),
+            wakes_left: confirm_wakes.saturating_sub(self.wakes),
+        }
+    }
+
+    /// The `config_rolled_back` reporting it was dropped
+    pub fn rollback(&self) -> DevicePayload {
+        DevicePayload::ConfigRolledBack {
+            id: self.id,
+            command: self.change.command_name().to_string(),
+        }
+    }
+}
+
+/// Why a `confirm_config` confirmed nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmError {
+    NothingPending,
+    /// A newer change replaced the one being confirmed, or it was rolled
+    /// back already
+    WrongId {
+        pending: u32,
+    },
+}
+
+impl ConfirmError {
+    /// Detail of the `confirm_config_error` answer
+    pub fn detail(&self, id: u32) -> Detail {
+        match self {
+            ConfirmError::NothingPending => detail!("nothing_pending: {} is not on trial", id),
+            ConfirmError::WrongId { pending } => {
+                detail!("wrong_id: {} is not on trial, {} is", id, pending)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConfigTrial {
+    pub pending: Option<PendingChange>,
+    /// Dropped without a confirmation and not reported yet
+    pub rolled_back: Option<PendingChange>,
+}
+
+impl ConfigTrial {
+    /// Starts a wake: counts it against the pending change, and drops the
+    /// change once it is out of wakes. Returns the change to apply on top
+    /// of the saved settings for this wake.
+    pub fn begin_wake(&mut self, confirm_wakes: u8) -> Option<ConfigChange> {
+        let mut pending = self.pending?;
+        pending.wakes = pending.wakes.saturating_add(1);
+        if pending.wakes > confirm_wakes {
+            self.pending = None;
+            self.rolled_back = Some(pending);
+            None
+        } else {
+            self.pending = Some(pending);
+            Some(pending.change)
+        }
+    }
+
+    /// Puts `change` on trial, replacing any pending change. The replaced
+    /// change is returned: it is no longer applied, and unless it changed
+    /// the same setting its saved value has to be put back.
+    pub fn stage(&mut self, id: u32, change: ConfigChange) -> Option<PendingChange> {
+        self.pending.replace(PendingChange {
+            id,
+            change,
+            wakes: 0,
+        })
+    }
+
+    /// Ends the trial of `id`, returning the change to save.
+    pub fn confirm(&mut self, id: u32) -> Result<ConfigChange, ConfirmError> {
+        match self.pending {
+            None => Err(ConfirmError::NothingPending),
+            Some(pending) if pending.id != id => Err(ConfirmError::WrongId {
+                pending: pending.id,
+            }),
+            Some(pending) => {
+                self.pending = None;
+                Ok(pending.change)
+            }
+        }
+    }
+
+    /// The rollback to report, if there is one; it is forgotten once taken.
+    pub fn take_rolled_back(&mut self) -> Option<PendingChange> {
+        self.rolled_back.take()
+    }
+
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        encode_change(&mut payload, self.pending.as_ref());
+        encode_change(&mut payload, self.rolled_back.as_ref());
+        LAYOUTS.encode(&payload)
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, BlobError> {
+        let (payload, _) = LAYOUTS.load(blob)?;
+        let mut reader = Reader(&payload);
+        let trial = Self {
+            pending: reader.change()?,
+            rolled_back: reader.change()?,
+        };
+        if !reader.0.is_empty() {
+            return Err(malformed("config trial payload has trailing bytes"));
+        }
+        Ok(trial)
+    }
+}
+
+fn encode_change(payload: &mut Vec<u8>, pending: Option<&PendingChange>) {
+    let Some(pending) = pending else {
+        payload.push(TAG_NONE);
+        return;
+    };
+    let tag = match pending.change {
+        ConfigChange::DeepSleep { .. } => TAG_DEEP_SLEEP,
+        ConfigChange::MqttPolicy { .. } => TAG_MQTT_POLICY,
+    };
+    payload.push(tag);
+    payload.extend_from_slice(&pending.id.to_le_bytes());
+    payload.push(pending.wakes);
+    match pending.change {
+        ConfigChange::DeepSleep { seconds } => payload.extend_from_slice(&seconds.to_le_bytes()),
+        ConfigChange::MqttPolicy { class, qos, retain } => {
+            let class = PayloadClass::ALL
+                .iter()
+                .position(|c| *c == class)
+                .unwrap_or_default() as u8;
+            payload.extend_from_slice(&[class, qos, retain as u8]);
+        }
+    }
+}
+
+fn malformed(reason: &'static str) -> BlobError {
+    BlobError::Migration {
+        from: LAYOUTS.current,
+        reason,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], BlobError> {
+        let (head, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or_else(|| malformed("config trial payload is too short"))?;
+        self.0 = rest;
+        Ok(*head)
+    }
+
+    fn change(&mut self) -> Result<Option<PendingChange>, BlobError> {
+        let [tag] = self.bytes()?;
+        if tag == TAG_NONE {
+            return Ok(None);
+        }
+        let id = u32::from_le_bytes(self.bytes()?);
+        let [wakes] = self.bytes()?;
+        let change = match tag {
+            TAG_DEEP_SLEEP => ConfigChange::DeepSleep {
+                seconds: u64::from_le_bytes(self.bytes()?),
+            },
+            TAG_MQTT_POLICY => {
+                let [class, qos, retain] = self.bytes()?;
+                ConfigChange::MqttPolicy {
+                    class: *PayloadClass::ALL
+                        .get(class as usize)
+                        .ok_or_else(|| malformed("unknown payload class"))?,
+                    qos,
+                    retain: retain != 0,
+                }
+            }
+            _ => return Err(malformed("unknown config change")),
+        };
+        Ok(Some(PendingChange { id, change, wakes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLEEP_WEEK: ConfigChange = ConfigChange::DeepSleep { seconds: 604_800 };
+    const QUIET_MEASUREMENTS: ConfigChange = ConfigChange::MqttPolicy {
+        class: PayloadClass::Measurement,
+        qos: 0,
+        retain: true,
+    };
+
+    #[test]
+    fn only_risky_commands_go_on_trial() {
+        assert_eq!(
+            ConfigChange::of(&DeviceCommand::SetDeepSleepTime { seconds: 604_800 }),
+            Some(SLEEP_WEEK)
+        );
+        assert_eq!(
+            ConfigChange::of(&DeviceCommand::SetMqttPolicy {
+                class: PayloadClass::Measurement,
+                qos: 0,
+                retain: true,
+            }),
+            Some(QUIET_MEASUREMENTS)
+        );
+        assert_eq!(ConfigChange::of(&DeviceCommand::GetDeepSleepTime), None);
+        assert_eq!(
+            ConfigChange::of(&DeviceCommand::SetAdaptiveMode { enabled: true }),
+            None
+        );
+    }
+
+    #[test]
+    fn a_confirmed_change_is_saved_and_the_trial_ends() {
+        let mut trial = ConfigTrial::default();
+        assert_eq!(trial.stage(41, SLEEP_WEEK), None);
+        assert_eq!(
+            trial.pending.unwrap().announcement(3),
+            DevicePayload::PendingConfirmation {
+                id: 41,
+                command: "set_deep_sleep_time".to_string(),
+                wakes_left: 3,
+            }
+        );
+        // Confirmed on the next wake
+        assert_eq!(trial.begin_wake(3), Some(SLEEP_WEEK));
+        assert_eq!(trial.confirm(41), Ok(SLEEP_WEEK));
+        assert_eq!(trial, ConfigTrial::default());
+        assert_eq!(
+            SLEEP_WEEK.saved(),
+            DevicePayload::SetDeepSleepTimeSuccess { seconds: 604_800 }
+        );
+        // Nothing left to apply or to confirm
+        assert_eq!(trial.begin_wake(3), None);
+        assert_eq!(trial.confirm(41), Err(ConfirmError::NothingPending));
+    }
+
+    #[test]
+    fn can_be_confirmed_in_the_wake_that_staged_it() {
+        let mut trial = ConfigTrial::default();
+        trial.stage(41, QUIET_MEASUREMENTS);
+        assert_eq!(trial.confirm(41), Ok(QUIET_MEASUREMENTS));
+    }
+
+    #[test]
+    fn an_unconfirmed_change_is_applied_for_its_wakes_then_rolled_back() {
+        let mut trial = ConfigTrial::default();
+        trial.stage(41, SLEEP_WEEK);
+        for wakes_left in [2, 1, 0] {
+            assert_eq!(trial.begin_wake(3), Some(SLEEP_WEEK));
+            assert_eq!(
+                trial.pending.unwrap().announcement(3),
+                DevicePayload::PendingConfirmation {
+                    id: 41,
+                    command: "set_deep_sleep_time".to_string(),
+                    wakes_left,
+                }
+            );
+        }
+        // The fourth wake runs on the saved settings again
+        assert_eq!(trial.begin_wake(3), None);
+        assert_eq!(trial.pending, None);
+        assert_eq!(trial.confirm(41), Err(ConfirmError::NothingPending));
+
+        let rolled_back = trial.take_rolled_back().unwrap();
+        assert_eq!(
+            rolled_back.rollback(),
+            DevicePayload::ConfigRolledBack {
+                id: 41,
+                command: "set_deep_sleep_time".to_string(),
+            }
+        );
+        // Reported once
+        assert_eq!(trial.take_rolled_back(), None);
+    }
+
+    #[test]
+    fn the_rollback_waits_for_a_wake_that_can_report_it() {
+        let mut trial = ConfigTrial::default();
+        trial.stage(41, SLEEP_WEEK);
+        assert_eq!(trial.begin_wake(0), None);
+        // Offline wakes carry it along
+        assert_eq!(trial.begin_wake(0), None);
+        assert_eq!(trial.take_rolled_back().map(|r| r.id), Some(41));
+    }
+
+    #[test]
+    fn a_newer_change_replaces_the_pending_one() {
+        let mut trial = ConfigTrial::default();
+        trial.stage(41, SLEEP_WEEK);
+        trial.begin_wake(3);
+        let replaced = trial.stage(42, QUIET_MEASUREMENTS).unwrap();
+        assert_eq!(replaced.id, 41);
+        assert!(!replaced.change.same_setting(&QUIET_MEASUREMENTS));
+        assert_eq!(
+            trial.confirm(41),
+            Err(ConfirmError::WrongId { pending: 42 })
+        );
+        // The replacement gets its own full set of wakes
+        assert_eq!(trial.pending.unwrap().wakes, 0);
+        assert_eq!(trial.confirm(42), Ok(QUIET_MEASUREMENTS));
+
+        let other_class = ConfigChange::MqttPolicy {
+            class: PayloadClass::Error,
+            qos: 1,
+            retain: false,
+        };
+        assert!(!QUIET_MEASUREMENTS.same_setting(&other_class));
+        assert!(SLEEP_WEEK.same_setting(&ConfigChange::DeepSleep { seconds: 60 }));
+    }
+
+    #[test]
+    fn the_trial_round_trips_through_nvs_and_torn_writes_read_as_errors() {
+        let mut trial = ConfigTrial::default();
+        assert_eq!(ConfigTrial::from_blob(&trial.to_blob()), Ok(trial));
+
+        trial.stage(41, SLEEP_WEEK);
+        trial.begin_wake(0);
+        trial.stage(0xDEAD_BEEF, QUIET_MEASUREMENTS);
+        trial.begin_wake(3);
+        let blob = trial.to_blob();
+        assert_eq!(ConfigTrial::from_blob(&blob), Ok(trial));
+        assert_eq!(
+            ConfigTrial::from_blob(&blob[..blob.len() - 1]),
+            Err(BlobError::Truncated)
+        );
+        let mut corrupted = blob.clone();
+        corrupted[12] ^= 0x01;
+        assert_eq!(ConfigTrial::from_blob(&corrupted), Err(BlobError::Checksum));
+        // Framed correctly but not a trial
+        for payload in [
+            &[][..],
+            &[9][..],
+            &[2, 0, 0, 0, 0, 0, 7, 0, 0, 0],
+            &[0, 0, 0],
+        ] {
+            let blob = crate::versioned::encode_versioned(1, payload);
+            assert!(ConfigTrial::from_blob(&blob).is_err(), "{:?}", payload);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Recognizes a measurement the broker delivered twice.
+//!
+//! QoS 1 is at-least-once: after a reconnect the broker may hand out a
+//! publish again that the receiver already got. Measurements carry the
+//! device's `seq`, so a `(device, seq)` pair seen recently is a repeat. Only
+//! the last `capacity` numbers of each device are kept; a redelivery comes
+//! long before that many newer measurements.
+//!
+//! `seq` starts over at 0 after a power loss. A 0 that isn't the latest
+//! number seen starts a new count and forgets the old one, so a device that
+//! loses power twice in a short while isn't taken for repeating itself.
+//!
+//! A device publishes a measurement again, marked `redelivered`, when it
+//! went to sleep before the broker acknowledged it. The copy keeps its
+//! `seq`: seen before, it is a repeat even as 0, and otherwise it is the
+//! lost measurement, older than anything that arrived since.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::DeviceMessage;
+
+/// Numbers kept per device
+pub const DEFAULT_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct DedupWindow {
+    capacity: usize,
+    seen: HashMap<String, VecDeque<u32>>,
+}
+
+impl Default for DedupWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl DedupWindow {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "a dedup window needs room for one number");
+        Self {
+            capacity,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether `message` is new. Messages without a `seq` always are.
+    pub fn accept(&mut self, message: &DeviceMessage) -> bool {
+        match message.seq {
+            Some(seq) if message.redelivered => self.accept_redelivered(&message.device, seq),
+            Some(seq) => self.accept_seq(&message.device, seq),
+            None => true,
+        }
+    }
+
+    /// Like `accept_seq`, but never starts a new count. A new number is
+    /// kept as the oldest, so it doesn't hide a live 0 that follows.
+    fn accept_redelivered(&mut self, device: &str, seq: u32) -> bool {
+        let seen = self.seen.entry(device.to_string()).or_default();
+        if seen.contains(&seq) {
+            return false;
+        }
+        if seen.len() == self.capacity {
+            seen.pop_front();
+        }
+        seen.push_front(seq);
+        true
+    }
+
+    /// Whether `seq` is new for `device`. Remembers it if so.
+    pub fn accept_seq(&mut self, device: &str, seq: u32) -> bool {
+        if !self.seen.contains_key(device) {
+            self.seen.insert(device.to_string(), VecDeque::new());
+        }
+        let seen = self.seen.get_mut(device).expect("inserted above");
+        if seen.contains(&seq) {
+            if seq != 0 || seen.back() == Some(&0) {
+                return false;
+            }
+            seen.clear();
+        }
+        if seen.len() == self.capacity {
+            seen.pop_front();
+        }
+        seen.push_back(seq);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DevicePayload;
+
+    fn measurement(device: &str, seq: u32) -> DeviceMessage {
+        DeviceMessage::new(
+            device,
+            DevicePayload::try_measurement(612, 21.5, 40.0).unwrap(),
+        )
+        .stamped(None, seq)
+    }
+
+    #[test]
+    fn repeated_seq_is_rejected() {
+        let mut window = DedupWindow::default();
+        assert!(window.accept(&measurement("kitchen", 7)));
+        assert!(!window.accept(&measurement("kitchen", 7)));
+        assert!(window.accept(&measurement("kitchen", 8)));
+        // Redelivered after a newer one
+        assert!(!window.accept(&measurement("kitchen", 7)));
+    }
+
+    #[test]
+    fn devices_are_tracked_separately() {
+        let mut window = DedupWindow::default();
+        assert!(window.accept(&measurement("kitchen", 7)));
+        assert!(window.accept(&measurement("bedroom", 7)));
+        assert!(!window.accept(&measurement("bedroom", 7)));
+    }
+
+    #[test]
+    fn unnumbered_messages_always_pass() {
+        let mut window = DedupWindow::default();
+        let alive = DeviceMessage::new("kitchen", DevicePayload::Alive { uptime_seconds: 5 });
+        assert!(window.accept(&alive));
+        assert!(window.accept(&alive));
+    }
+
+    #[test]
+    fn only_the_last_numbers_are_kept() {
+        let mut window = DedupWindow::new(2);
+        for seq in 1..=3 {
+            assert!(window.accept_seq("kitchen", seq));
+        }
+        assert!(window.accept_seq("kitchen", 1));
+        assert!(!window.accept_seq("kitchen", 3));
+    }
+
+    #[test]
+    fn redelivered_copies_are_repeats_even_as_zero() {
+        let mut window = DedupWindow::default();
+        assert!(window.accept(&measurement("kitchen", 0)));
+        assert!(window.accept(&measurement("kitchen", 1)));
+        // The acknowledgments got lost, so the outbox sends both again
+        assert!(!window.accept(&measurement("kitchen", 0).redelivered()));
+        assert!(!window.accept(&measurement("kitchen", 1).redelivered()));
+        assert!(window.accept(&measurement("kitchen", 2)));
+    }
+
+    #[test]
+    fn a_lost_measurement_is_kept_when_redelivered() {
+        let mut window = DedupWindow::default();
+        assert!(window.accept(&measurement("kitchen", 6)));
+        // 7 never reached the broker, and the device lost power since
+        assert!(window.accept(&measurement("kitchen", 7).redelivered()));
+        assert!(!window.accept(&measurement("kitchen", 7).redelivered()));
+        assert!(window.accept(&measurement("kitchen", 0)));
+        // A broker repeat of the live 0 is still one
+        assert!(!window.accept(&measurement("kitchen", 0)));
+    }
+
+    #[test]
+    fn power_loss_starts_a_new_count() {
+        let mut window = DedupWindow::default();
+        for seq in 0..3 {
+            assert!(window.accept_seq("kitchen", seq));
+        }
+        // Lost power soon after the last time
+        assert!(window.accept_seq("kitchen", 0));
+        assert!(!window.accept_seq("kitchen", 0));
+        assert!(window.accept_seq("kitchen", 1));
+        // The old count is forgotten
+        assert!(window.accept_seq("kitchen", 2));
+    }
+}
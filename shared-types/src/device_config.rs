@@ -0,0 +1,119 @@
+//! The answer to `get_config`: everything that decides what a device does
+//! on its next wake, whether it comes from the build environment, NVS or
+//! the sensor itself.
+
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+/// How the SCD4x takes its readings.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SensorMode {
+    /// Periodic measurement, started and stopped within each wake
+    #[default]
+    Periodic,
+    LowPowerPeriodic,
+    SingleShot,
+}
+
+impl SensorMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SensorMode::Periodic => "periodic",
+            SensorMode::LowPowerPeriodic => "low_power_periodic",
+            SensorMode::SingleShot => "single_shot",
+        }
+    }
+}
+
+/// Values read from the sensor are absent when the read failed; settings a
+/// build doesn't have are absent too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceConfig {
+    pub firmware_version: String,
+    pub sleep_seconds: u64,
+    /// Status LED quiet hours in local time, e.g. `22-7`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<String>,
+    #[serde(default)]
+    pub utc_offset_hours: i8,
+    pub sensor_mode: SensorMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_offset: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude_m: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ambient_pressure_hpa: Option<u16>,
+    /// Automatic self-calibration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asc_enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alarm_threshold_ppm: Option<u16>,
+    /// In the `MqttPolicy` text form, e.g. `measurement=1+retain,...`
+    pub mqtt_policy: String,
+    /// Network name only, never the password
+    pub wifi_ssid: String,
+    #[serde(default)]
+    pub safe_mode: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceMessage, DevicePayload};
+
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            firmware_version: "0.1.0".to_string(),
+            sleep_seconds: 300,
+            quiet_hours: Some("22-7".to_string()),
+            utc_offset_hours: 1,
+            sensor_mode: SensorMode::Periodic,
+            temperature_offset: Some(4.0),
+            altitude_m: Some(120),
+            ambient_pressure_hpa: None,
+            asc_enabled: Some(true),
+            alarm_threshold_ppm: None,
+            mqtt_policy: crate::mqtt_policy::MqttPolicy::DEFAULT.to_string(),
+            wifi_ssid: "home".to_string(),
+            safe_mode: false,
+        }
+    }
+
+    #[test]
+    fn config_payload_is_flat_beside_the_status() {
+        let message = DeviceMessage::new("esp32-scd40", DevicePayload::Config(config()));
+        let json: serde_json::Value = serde_json::from_str(&message.to_json().unwrap()).unwrap();
+        assert_eq!(json["status"], "config");
+        assert_eq!(json["device"], "esp32-scd40");
+        assert_eq!(json["sleep_seconds"], 300);
+        assert_eq!(json["sensor_mode"], "periodic");
+        // Unknown or unreadable values are left out rather than sent as null
+        assert!(json.get("ambient_pressure_hpa").is_none());
+        assert!(json.get("alarm_threshold_ppm").is_none());
+
+        assert_eq!(
+            DeviceMessage::from_json(&message.to_json().unwrap()).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn minimal_config_parses_with_defaults() {
+        let message = DeviceMessage::from_json(
+            r#"{"device":"esp32-scd40","status":"config","firmware_version":"0.1.0",
+                "sleep_seconds":300,"sensor_mode":"single_shot",
+                "mqtt_policy":"measurement=1+retain","wifi_ssid":"home"}"#,
+        )
+        .unwrap();
+        let DevicePayload::Config(config) = message.payload else {
+            panic!("{:?}", message.payload);
+        };
+        assert_eq!(config.sensor_mode, SensorMode::SingleShot);
+        assert_eq!(config.temperature_offset, None);
+        assert!(!config.safe_mode);
+    }
+}
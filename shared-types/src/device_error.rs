@@ -0,0 +1,215 @@
+//! Firmware errors that don't allocate.
+//!
+//! Most firmware failures only pick a blink pattern and maybe a short
+//! message, so `DeviceError` carries a static context string instead of a
+//! formatted chain. Only `core` is used here, so the module works without
+//! `std`. `main` keeps `anyhow` for esp-idf interop; `DeviceError` implements
+//! `Error`, so `?` converts it there.
+
+use core::fmt;
+
+use crate::{CodecError, ErrorCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The sensor never reported data ready
+    SensorTimeout(&'static str),
+    /// The sensor answered, but not with what was asked for
+    Sensor(&'static str),
+    /// The I2C transaction itself failed
+    I2c(&'static str),
+    /// Bus recovery ran and the bus is still unusable
+    I2cBusStuck,
+    Wifi(&'static str),
+    Mqtt(&'static str),
+    Nvs(&'static str),
+    /// A payload couldn't be serialized or parsed
+    Encoding(&'static str),
+}
+
+pub type DeviceResult<T> = Result<T, DeviceError>;
+
+impl DeviceError {
+    pub const fn code(&self) -> ErrorCode {
+        match self {
+            DeviceError::SensorTimeout(_) => ErrorCode::SensorTimeout,
+            DeviceError::Sensor(_) => ErrorCode::SensorReadFailed,
+            DeviceError::I2c(_) => ErrorCode::I2cError,
+            DeviceError::I2cBusStuck => ErrorCode::I2cBusStuck,
+            DeviceError::Wifi(_) => ErrorCode::WifiError,
+            DeviceError::Mqtt(_) => ErrorCode::MqttError,
+            DeviceError::Nvs(_) => ErrorCode::NvsError,
+            DeviceError::Encoding(_) => ErrorCode::EncodingError,
+        }
+    }
+
+    /// What was being done, e.g. "Measurement timed out"
+    pub const fn context(&self) -> &'static str {
+        match self {
+            DeviceError::SensorTimeout(context)
+            | DeviceError::Sensor(context)
+            | DeviceError::I2c(context)
+            | DeviceError::Wifi(context)
+            | DeviceError::Mqtt(context)
+            | DeviceError::Nvs(context)
+            | DeviceError::Encoding(context) => context,
+            DeviceError::I2cBusStuck => "I2C bus stuck after recovery",
+        }
+    }
+
+    const fn kind(&self) -> &'static str {
+        match self {
+            DeviceError::SensorTimeout(_) => "sensor timeout",
+            DeviceError::Sensor(_) => "sensor",
+            DeviceError::I2c(_) | DeviceError::I2cBusStuck => "i2c",
+            DeviceError::Wifi(_) => "wifi",
+            DeviceError::Mqtt(_) => "mqtt",
+            DeviceError::Nvs(_) => "nvs",
+            DeviceError::Encoding(_) => "encoding",
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.context())
+    }
+}
+
+impl core::error::Error for DeviceError {}
+
+impl From<DeviceError> for ErrorCode {
+    fn from(error: DeviceError) -> Self {
+        error.code()
+    }
+}
+
+impl From<fmt::Error> for DeviceError {
+    fn from(_: fmt::Error) -> Self {
+        DeviceError::Encoding("formatting failed")
+    }
+}
+
+impl From<serde_json::Error> for DeviceError {
+    fn from(error: serde_json::Error) -> Self {
+        if error.is_io() || error.is_eof() {
+            DeviceError::Encoding("JSON input ended early")
+        } else if error.is_syntax() || error.is_data() {
+            DeviceError::Encoding("invalid JSON")
+        } else {
+            DeviceError::Encoding("JSON serialization failed")
+        }
+    }
+}
+
+impl From<CodecError> for DeviceError {
+    fn from(error: CodecError) -> Self {
+        match error {
+            CodecError::Json(error) => error.into(),
+            #[cfg(feature = "cbor")]
+            CodecError::Cbor(_) => DeviceError::Encoding("invalid CBOR"),
+            #[cfg(feature = "postcard")]
+            CodecError::Postcard(postcard::Error::SerializeBufferFull) => {
+                DeviceError::Encoding("postcard buffer too small")
+            }
+            #[cfg(feature = "postcard")]
+            CodecError::Postcard(_) => DeviceError::Encoding("invalid postcard"),
+            CodecError::Validation(_) => DeviceError::Encoding("message failed validation"),
+        }
+    }
+}
+
+/// Replaces a foreign error with a `DeviceError`, for driver results whose
+/// error types this crate can't name.
+pub trait Context<T> {
+    fn context(self, error: DeviceError) -> DeviceResult<T>;
+}
+
+impl<T, E> Context<T> for Result<T, E> {
+    fn context(self, error: DeviceError) -> DeviceResult<T> {
+        self.map_err(|_| error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_maps_to_its_wire_code() {
+        let cases = [
+            (DeviceError::SensorTimeout("t"), ErrorCode::SensorTimeout),
+            (DeviceError::Sensor("s"), ErrorCode::SensorReadFailed),
+            (DeviceError::I2c("i"), ErrorCode::I2cError),
+            (DeviceError::I2cBusStuck, ErrorCode::I2cBusStuck),
+            (DeviceError::Wifi("w"), ErrorCode::WifiError),
+            (DeviceError::Mqtt("m"), ErrorCode::MqttError),
+            (DeviceError::Nvs("n"), ErrorCode::NvsError),
+            (DeviceError::Encoding("e"), ErrorCode::EncodingError),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code);
+            assert_eq!(ErrorCode::from(error), code);
+        }
+    }
+
+    #[test]
+    fn display_is_kind_and_context() {
+        assert_eq!(
+            DeviceError::SensorTimeout("Measurement timed out").to_string(),
+            "sensor timeout: Measurement timed out"
+        );
+        assert_eq!(
+            DeviceError::Nvs("saving deep sleep time").to_string(),
+            "nvs: saving deep sleep time"
+        );
+        assert_eq!(
+            DeviceError::I2cBusStuck.to_string(),
+            "i2c: I2C bus stuck after recovery"
+        );
+    }
+
+    #[test]
+    fn foreign_errors_convert() {
+        let json = serde_json::from_str::<crate::DeviceCommand>("{\"cmd\":");
+        assert_eq!(
+            DeviceError::from(json.unwrap_err()),
+            DeviceError::Encoding("JSON input ended early")
+        );
+        let json = serde_json::from_str::<crate::DeviceCommand>("{\"cmd\":\"self_destruct\"}");
+        assert_eq!(
+            DeviceError::from(json.unwrap_err()),
+            DeviceError::Encoding("invalid JSON")
+        );
+
+        // Through the shared helpers too
+        assert_eq!(
+            DeviceError::from(crate::DeviceCommand::from_json("{\"cmd\":").unwrap_err()),
+            DeviceError::Encoding("JSON input ended early")
+        );
+        let invalid = crate::validation::ValidationError::EmptyDevice;
+        assert_eq!(
+            DeviceError::from(CodecError::from(invalid)),
+            DeviceError::Encoding("message failed validation")
+        );
+
+        let nvs: Result<(), i32> = Err(-1);
+        assert_eq!(
+            nvs.context(DeviceError::Nvs("reading policy")),
+            Err(DeviceError::Nvs("reading policy"))
+        );
+        assert_eq!(Ok::<_, i32>(5).context(DeviceError::Wifi("x")), Ok(5));
+    }
+
+    #[test]
+    fn question_mark_reaches_boxed_errors() {
+        fn inner() -> DeviceResult<()> {
+            Err(DeviceError::Mqtt("publish failed"))
+        }
+        fn outer() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            inner()?;
+            Ok(())
+        }
+        assert_eq!(outer().unwrap_err().to_string(), "mqtt: publish failed");
+    }
+}
@@ -0,0 +1,1022 @@
+//! One-line human-readable forms of payloads and commands, for the
+//! processor's log and the commander's console.
+//!
+//! Temperatures are in °C. Whatever has more to show than fits on a line,
+//! like the log lines of `LogLines` or the readings of a batch, is left
+//! to the caller, which has the fields.
+
+use core::fmt;
+
+use crate::{
+    CommandMessage, DeviceCommand, DeviceCommandBatch, DevicePayload, ErrorCode, MeasurementFlags,
+    units,
+};
+
+/// ` (i2c_error)`, nothing for `Other`
+fn write_code(f: &mut fmt::Formatter<'_>, code: ErrorCode) -> fmt::Result {
+    match code {
+        ErrorCode::Other => Ok(()),
+        code => write!(f, " ({})", code),
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+impl fmt::Display for DevicePayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags,
+            } => {
+                let co2: u16 = units::plain(*co2);
+                let temperature: f32 = units::plain(*temperature);
+                let humidity: f32 = units::plain(*humidity);
+                write!(
+                    f,
+                    "Measurement: {} ppm CO2, {:.1} °C, {:.1} % RH",
+                    co2, temperature, humidity
+                )?;
+                match (battery_mv, battery_percent) {
+                    (Some(mv), Some(percent)) => write!(f, ", battery {} % ({} mV)", percent, mv),
+                    (Some(mv), None) => write!(f, ", battery {} mV", mv),
+                    (None, Some(percent)) => write!(f, ", battery {} %", percent),
+                    (None, None) => Ok(()),
+                }?;
+                match flags {
+                    Some(flags) if flags.any() => write!(f, " [{}]", flags),
+                    _ => Ok(()),
+                }
+            }
+            DevicePayload::MeasurementBatch { readings } => {
+                write!(f, "Measurement batch: {} reading(s)", readings.len())
+            }
+            DevicePayload::Error { code, detail } => {
+                f.write_str("Error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::FrcStart { target_ppm } => {
+                write!(f, "FRC started, target {} ppm", target_ppm)
+            }
+            DevicePayload::FrcWarmupComplete { detail } => {
+                write!(f, "FRC warmup complete: {}", detail)
+            }
+            DevicePayload::FrcCalibrating { target_ppm } => {
+                write!(f, "FRC calibrating, target {} ppm", target_ppm)
+            }
+            DevicePayload::FrcSuccess { correction } => {
+                write!(f, "FRC success, correction {} ppm", correction)
+            }
+            DevicePayload::FrcError { code, detail } => {
+                f.write_str("FRC error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::SetOffsetSuccess { offset, persisted } => {
+                write!(f, "Temperature offset set to {} °C", offset)?;
+                if !persisted {
+                    f.write_str(" (not saved, lost when the sensor loses power)")?;
+                }
+                Ok(())
+            }
+            DevicePayload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            } => write!(
+                f,
+                "Temperature offset {} °C applied but not saved, the sensor's EEPROM was \
+                 already written {} times today; saving works again in {}h {}m",
+                offset,
+                limit,
+                retry_after_seconds / 3600,
+                retry_after_seconds % 3600 / 60
+            ),
+            DevicePayload::SetOffsetError { code, detail } => {
+                f.write_str("Set temperature offset error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::GetOffsetSuccess { offset } => {
+                write!(f, "Temperature offset: {} °C", offset)
+            }
+            DevicePayload::GetOffsetError { code, detail } => {
+                f.write_str("Get temperature offset error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::Alive { uptime_seconds } => write!(
+                f,
+                "Alive, uptime {} s ({} m / {} h)",
+                uptime_seconds,
+                uptime_seconds / 60,
+                uptime_seconds / 3600
+            ),
+            DevicePayload::SetDeepSleepTimeSuccess { seconds } => {
+                write!(f, "Deep sleep time set to {} s", seconds)
+            }
+            DevicePayload::SetDeepSleepTimeError { code, detail } => {
+                f.write_str("Set deep sleep time error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
+                write!(f, "Deep sleep time: {} s", seconds)
+            }
+            DevicePayload::GetDeepSleepTimeError { code, detail } => {
+                f.write_str("Get deep sleep time error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::SetMqttPolicySuccess { class, qos, retain } => {
+                write!(f, "MQTT policy set: {} at QoS {}", class.as_str(), qos)?;
+                if *retain {
+                    f.write_str(", retained")?;
+                }
+                Ok(())
+            }
+            DevicePayload::SetMqttPolicyError { code, detail } => {
+                f.write_str("Set MQTT policy error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::CommandsDeferred { running, deferred } => {
+                write!(f, "{} must run alone, deferred to the next wake: ", running)?;
+                for (i, command) in deferred.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_str(command.name())?;
+                }
+                Ok(())
+            }
+            DevicePayload::BusRecovery {
+                attempt,
+                pulses,
+                recovered,
+            } => {
+                write!(f, "I2C bus recovery #{}: ", attempt)?;
+                match pulses {
+                    Some(pulses) => write!(f, "SDA released after {} SCL pulses", pulses)?,
+                    None => f.write_str("SDA still held low")?,
+                }
+                f.write_str(if *recovered {
+                    ", recovered"
+                } else {
+                    ", still stuck"
+                })
+            }
+            DevicePayload::OtaProgress { percent } => write!(f, "OTA downloading: {}%", percent),
+            DevicePayload::OtaSuccess { version } => write!(f, "OTA success, running {}", version),
+            DevicePayload::OtaError { code, detail } => {
+                f.write_str("OTA error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::WakeProfile {
+                awake_ms,
+                sensor_ms,
+                network_ms,
+                saved_ms,
+            } => write!(
+                f,
+                "Wake profile: awake {:.1} s (sensor {:.1} s, network {:.1} s, overlap saved {:.1} s)",
+                *awake_ms as f64 / 1000.0,
+                *sensor_ms as f64 / 1000.0,
+                *network_ms as f64 / 1000.0,
+                *saved_ms as f64 / 1000.0
+            ),
+            DevicePayload::Config(config) => write!(
+                f,
+                "Configuration: firmware {}, sleep {} s, MQTT policy {}",
+                config.firmware_version, config.sleep_seconds, config.mqtt_policy
+            ),
+            DevicePayload::SetLogLevelSuccess { level } => write!(f, "Log level set to {}", level),
+            DevicePayload::SetLogLevelError { code, detail } => {
+                f.write_str("Set log level error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::GetLogLevelSuccess { level } => write!(f, "Log level: {}", level),
+            DevicePayload::LogLines { lines } => {
+                write!(f, "Log lines: last {} line(s)", lines.len())
+            }
+            DevicePayload::SetAdaptiveModeSuccess { enabled } => {
+                write!(f, "Adaptive mode set to {}", on_off(*enabled))
+            }
+            DevicePayload::SetAdaptiveModeError { code, detail } => {
+                f.write_str("Set adaptive mode error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::NextWake {
+                sleep_seconds,
+                adaptive,
+                delta_ppm,
+            } => {
+                write!(f, "Next wake in {} s", sleep_seconds)?;
+                match (adaptive, delta_ppm) {
+                    (false, _) => Ok(()),
+                    (true, Some(delta)) => write!(f, " (adaptive, CO2 moved {} ppm)", delta),
+                    (true, None) => f.write_str(" (adaptive, no previous reading)"),
+                }
+            }
+            DevicePayload::Diagnostics {
+                rssi_dbm,
+                free_heap_bytes,
+                boot_count,
+                reset_reason,
+            } => write!(
+                f,
+                "Diagnostics: signal {} dBm, {:.1} KiB free heap, boot {}, last reset: {}",
+                rssi_dbm,
+                *free_heap_bytes as f64 / 1024.0,
+                boot_count,
+                reset_reason
+            ),
+            DevicePayload::AscSetSuccess { enabled } => {
+                write!(f, "Automatic self-calibration set to {}", on_off(*enabled))
+            }
+            DevicePayload::AscGetSuccess { enabled } => {
+                write!(f, "Automatic self-calibration: {}", on_off(*enabled))
+            }
+            DevicePayload::AscError { code, detail } => {
+                f.write_str("Automatic self-calibration error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::AltitudeSetSuccess { meters } => {
+                write!(f, "Altitude set to {} m", meters)
+            }
+            DevicePayload::AltitudeGetSuccess { meters } => write!(f, "Altitude: {} m", meters),
+            DevicePayload::AltitudeError { code, detail } => {
+                f.write_str("Altitude error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::AmbientPressureSetSuccess { pascals } => {
+                write!(f, "Ambient pressure set to {} Pa", pascals)
+            }
+            DevicePayload::AmbientPressureError { code, detail } => {
+                f.write_str("Ambient pressure error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::PendingConfirmation {
+                id,
+                command,
+                wakes_left,
+            } => write!(
+                f,
+                "Trying {} (#{}): confirm within {} wake(s) or it rolls back",
+                command, id, wakes_left
+            ),
+            DevicePayload::ConfigRolledBack { id, command } => {
+                write!(f, "Rolled back {} (#{}): never confirmed", command, id)
+            }
+            DevicePayload::ConfirmConfigError { code, detail } => {
+                f.write_str("Confirm config error")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            DevicePayload::SelfTestResult { passed: true, .. } => f.write_str("Self test passed"),
+            DevicePayload::SelfTestResult {
+                passed: false,
+                detail,
+            } => write!(f, "Self test failed: {}", detail),
+            DevicePayload::FactoryResetSuccess => f.write_str("Factory reset done"),
+            DevicePayload::FactoryResetError { code, detail } => {
+                f.write_str("Factory reset failed")?;
+                write_code(f, *code)?;
+                write!(f, ": {}", detail)
+            }
+            // Sensirion prints it in hex, as on the module's label
+            DevicePayload::SerialNumber { serial } => {
+                write!(f, "Serial number: 0x{:012X} ({})", serial, serial)
+            }
+            DevicePayload::Rebooting { detail } => write!(f, "Rebooting: {}", detail),
+            DevicePayload::FaultArmed { kind } => {
+                write!(f, "Fault armed: {} at the next wake", kind)
+            }
+            DevicePayload::FirmwareInfo {
+                version,
+                build_time,
+                idf_version,
+            } => write!(
+                f,
+                "Firmware {}, built {}, ESP-IDF {}",
+                version, build_time, idf_version
+            ),
+            DevicePayload::RadioSkipped {
+                skipped_wakes,
+                lowest_mv,
+                threshold_mv,
+            } => write!(
+                f,
+                "Radio skipped: {} wake(s) with the supply below {} mV, lowest {} mV",
+                skipped_wakes, threshold_mv, lowest_mv
+            ),
+            // The wake time is left to callers that know the time zone
+            DevicePayload::EnteringSleep { sleep_seconds, .. } => {
+                write!(f, "Entering sleep for {} s", sleep_seconds)
+            }
+            DevicePayload::CommandAck {
+                cmd,
+                accepted: true,
+                ..
+            } => write!(f, "{} accepted", cmd),
+            DevicePayload::CommandAck { cmd, detail, .. } => {
+                write!(f, "{} rejected", cmd)?;
+                match detail {
+                    Some(detail) => write!(f, ": {}", detail),
+                    None => Ok(()),
+                }
+            }
+            DevicePayload::PersistRateLimited {
+                command,
+                limit,
+                retry_after_seconds,
+            } => write!(
+                f,
+                "{} applied but not saved, the sensor's EEPROM was already written {} \
+                 times today; saving works again in {}h {}m",
+                command,
+                limit,
+                retry_after_seconds / 3600,
+                retry_after_seconds % 3600 / 60
+            ),
+        }
+    }
+}
+
+/// The set flags by name, comma-separated
+impl fmt::Display for MeasurementFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.first_after_boot, "first after boot"),
+            (self.sensor_warmup_incomplete, "sensor warming up"),
+            (self.retried_read, "retried read"),
+        ];
+        let mut separator = "";
+        for (_, name) in names.iter().filter(|(set, _)| *set) {
+            write!(f, "{}{}", separator, name)?;
+            separator = ", ";
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceCommand::NoOp => f.write_str("No-op"),
+            DeviceCommand::StartFrc { target_ppm } => {
+                write!(f, "Start FRC, target {} ppm", target_ppm)
+            }
+            DeviceCommand::SetTempOffset { offset, persist } => {
+                write!(f, "Set temperature offset to {} °C", offset)?;
+                if !persist {
+                    f.write_str(" (volatile)")?;
+                }
+                Ok(())
+            }
+            DeviceCommand::GetTempOffset => f.write_str("Get temperature offset"),
+            DeviceCommand::SetDeepSleepTime { seconds } => {
+                write!(f, "Set deep sleep time to {} s", seconds)
+            }
+            DeviceCommand::GetDeepSleepTime => f.write_str("Get deep sleep time"),
+            DeviceCommand::SetMqttPolicy { class, qos, retain } => {
+                write!(f, "Set MQTT policy: {} at QoS {}", class.as_str(), qos)?;
+                if *retain {
+                    f.write_str(", retained")?;
+                }
+                Ok(())
+            }
+            DeviceCommand::Ota { url } => write!(f, "OTA update from {}", url),
+            DeviceCommand::GetConfig => f.write_str("Get configuration"),
+            DeviceCommand::SetLogLevel { level } => write!(f, "Set log level to {}", level),
+            DeviceCommand::GetLogLevel => f.write_str("Get log level"),
+            DeviceCommand::SetAdaptiveMode { enabled } => {
+                write!(f, "Set adaptive mode {}", on_off(*enabled))
+            }
+            DeviceCommand::SetAsc { enabled } => {
+                write!(f, "Set automatic self-calibration {}", on_off(*enabled))
+            }
+            DeviceCommand::GetAsc => f.write_str("Get automatic self-calibration"),
+            DeviceCommand::SetAltitude { meters } => write!(f, "Set altitude to {} m", meters),
+            DeviceCommand::GetAltitude => f.write_str("Get altitude"),
+            DeviceCommand::SetAmbientPressure { pascals } => {
+                write!(f, "Set ambient pressure to {} Pa", pascals)
+            }
+            DeviceCommand::ConfirmConfig { pending_id } => {
+                write!(f, "Confirm pending setting #{}", pending_id)
+            }
+            DeviceCommand::SelfTest => f.write_str("Run the self test"),
+            DeviceCommand::FactoryReset { confirm } => write!(f, "Factory reset '{}'", confirm),
+            DeviceCommand::GetSerialNumber => f.write_str("Get serial number"),
+            DeviceCommand::Reboot => f.write_str("Reboot"),
+            DeviceCommand::GetFirmwareInfo => f.write_str("Get firmware info"),
+            DeviceCommand::InjectFault { kind } => write!(f, "Inject a {} fault", kind),
+        }
+    }
+}
+
+impl fmt::Display for DeviceCommandBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.deferred {
+            "Deferred batch: "
+        } else {
+            "Batch: "
+        })?;
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(command.name())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CommandMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandMessage::Single(envelope) => envelope.command.fmt(f),
+            CommandMessage::Batch(envelope) => envelope.command.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BatchedReading;
+    use crate::device_config::{DeviceConfig, SensorMode};
+    use crate::fault_injection::FaultKind;
+    use crate::log_level::LogLevel;
+    use crate::mqtt_policy::{MqttPolicy, PayloadClass};
+
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            firmware_version: "0.1.0".to_string(),
+            sleep_seconds: 300,
+            quiet_hours: None,
+            utc_offset_hours: 0,
+            sensor_mode: SensorMode::Periodic,
+            temperature_offset: None,
+            altitude_m: None,
+            ambient_pressure_hpa: None,
+            asc_enabled: None,
+            alarm_threshold_ppm: None,
+            mqtt_policy: MqttPolicy::DEFAULT.to_string(),
+            wifi_ssid: "home".to_string(),
+            safe_mode: false,
+        }
+    }
+
+    #[test]
+    fn every_payload_reads_as_one_line() {
+        let reading = BatchedReading {
+            co2: 612,
+            temperature: 22.4,
+            humidity: 41.3,
+            age_seconds: 300,
+        };
+        let cases = [
+            (
+                DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+                "Measurement: 612 ppm CO2, 22.4 °C, 41.3 % RH",
+            ),
+            (
+                DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3710, 64).unwrap(),
+                "Measurement: 612 ppm CO2, 22.4 °C, 41.3 % RH, battery 64 % (3710 mV)",
+            ),
+            (
+                DevicePayload::try_measurement(612, 22.4, 41.3)
+                    .unwrap()
+                    .flagged(MeasurementFlags {
+                        first_after_boot: true,
+                        retried_read: true,
+                        ..Default::default()
+                    }),
+                "Measurement: 612 ppm CO2, 22.4 °C, 41.3 % RH [first after boot, retried read]",
+            ),
+            (
+                DevicePayload::MeasurementBatch {
+                    readings: vec![reading, reading],
+                },
+                "Measurement batch: 2 reading(s)",
+            ),
+            (
+                DevicePayload::error("sensor not ready"),
+                "Error: sensor not ready",
+            ),
+            (
+                DevicePayload::coded_error(ErrorCode::SensorTimeout, "Measurement timed out"),
+                "Error (sensor_timeout): Measurement timed out",
+            ),
+            (
+                DevicePayload::FrcStart { target_ppm: 422 },
+                "FRC started, target 422 ppm",
+            ),
+            (
+                DevicePayload::FrcWarmupComplete {
+                    detail: "3 minutes".into(),
+                },
+                "FRC warmup complete: 3 minutes",
+            ),
+            (
+                DevicePayload::FrcCalibrating { target_ppm: 422 },
+                "FRC calibrating, target 422 ppm",
+            ),
+            (
+                DevicePayload::FrcSuccess { correction: 32780 },
+                "FRC success, correction 32780 ppm",
+            ),
+            (
+                DevicePayload::FrcError {
+                    code: ErrorCode::Other,
+                    detail: "CRC mismatch".into(),
+                },
+                "FRC error: CRC mismatch",
+            ),
+            (
+                DevicePayload::FrcError {
+                    code: ErrorCode::I2cError,
+                    detail: "Nack".into(),
+                },
+                "FRC error (i2c_error): Nack",
+            ),
+            (
+                DevicePayload::SetOffsetSuccess {
+                    offset: 4.5,
+                    persisted: true,
+                },
+                "Temperature offset set to 4.5 °C",
+            ),
+            (
+                DevicePayload::SetOffsetSuccess {
+                    offset: 4.5,
+                    persisted: false,
+                },
+                "Temperature offset set to 4.5 °C (not saved, lost when the sensor loses power)",
+            ),
+            (
+                DevicePayload::SetOffsetRateLimited {
+                    offset: 4.5,
+                    limit: 3,
+                    retry_after_seconds: 7500,
+                },
+                "Temperature offset 4.5 °C applied but not saved, the sensor's EEPROM was \
+                 already written 3 times today; saving works again in 2h 5m",
+            ),
+            (
+                DevicePayload::PersistRateLimited {
+                    command: "set_asc".to_string(),
+                    limit: 3,
+                    retry_after_seconds: 7500,
+                },
+                "set_asc applied but not saved, the sensor's EEPROM was already written 3 \
+                 times today; saving works again in 2h 5m",
+            ),
+            (
+                DevicePayload::SetOffsetError {
+                    code: ErrorCode::Other,
+                    detail: "out of range".into(),
+                },
+                "Set temperature offset error: out of range",
+            ),
+            (
+                DevicePayload::GetOffsetSuccess { offset: 4.0 },
+                "Temperature offset: 4 °C",
+            ),
+            (
+                DevicePayload::GetOffsetError {
+                    code: ErrorCode::Other,
+                    detail: "busy".into(),
+                },
+                "Get temperature offset error: busy",
+            ),
+            (
+                DevicePayload::Alive {
+                    uptime_seconds: 7260,
+                },
+                "Alive, uptime 7260 s (121 m / 2 h)",
+            ),
+            (
+                DevicePayload::SetDeepSleepTimeSuccess { seconds: 600 },
+                "Deep sleep time set to 600 s",
+            ),
+            (
+                DevicePayload::set_deep_sleep_time_error(ErrorCode::Other, "too short"),
+                "Set deep sleep time error: too short",
+            ),
+            (
+                DevicePayload::GetDeepSleepTimeSuccess { seconds: 600 },
+                "Deep sleep time: 600 s",
+            ),
+            (
+                DevicePayload::get_deep_sleep_time_error(ErrorCode::Other, "nvs"),
+                "Get deep sleep time error: nvs",
+            ),
+            (
+                DevicePayload::SetMqttPolicySuccess {
+                    class: PayloadClass::Measurement,
+                    qos: 1,
+                    retain: true,
+                },
+                "MQTT policy set: measurement at QoS 1, retained",
+            ),
+            (
+                DevicePayload::SetMqttPolicyError {
+                    code: ErrorCode::Other,
+                    detail: "qos 3".into(),
+                },
+                "Set MQTT policy error: qos 3",
+            ),
+            (
+                DevicePayload::CommandsDeferred {
+                    running: "ota".to_string(),
+                    deferred: vec![DeviceCommand::GetConfig, DeviceCommand::Reboot],
+                },
+                "ota must run alone, deferred to the next wake: get_config, reboot",
+            ),
+            (
+                DevicePayload::BusRecovery {
+                    attempt: 1,
+                    pulses: Some(4),
+                    recovered: true,
+                },
+                "I2C bus recovery #1: SDA released after 4 SCL pulses, recovered",
+            ),
+            (
+                DevicePayload::BusRecovery {
+                    attempt: 2,
+                    pulses: None,
+                    recovered: false,
+                },
+                "I2C bus recovery #2: SDA still held low, still stuck",
+            ),
+            (
+                DevicePayload::OtaProgress { percent: 40 },
+                "OTA downloading: 40%",
+            ),
+            (
+                DevicePayload::OtaSuccess {
+                    version: "0.2.0".to_string(),
+                },
+                "OTA success, running 0.2.0",
+            ),
+            (
+                DevicePayload::OtaError {
+                    code: ErrorCode::Other,
+                    detail: "404".into(),
+                },
+                "OTA error: 404",
+            ),
+            (
+                DevicePayload::WakeProfile {
+                    awake_ms: 6400,
+                    sensor_ms: 5000,
+                    network_ms: 3200,
+                    saved_ms: 1800,
+                },
+                "Wake profile: awake 6.4 s (sensor 5.0 s, network 3.2 s, overlap saved 1.8 s)",
+            ),
+            (
+                DevicePayload::Config(config()),
+                "Configuration: firmware 0.1.0, sleep 300 s, MQTT policy \
+                 measurement=1+retain,error=1,calibration=1,command_response=1,diagnostic=0",
+            ),
+            (
+                DevicePayload::SetLogLevelSuccess {
+                    level: LogLevel::Debug,
+                },
+                "Log level set to debug",
+            ),
+            (
+                DevicePayload::SetLogLevelError {
+                    code: ErrorCode::Other,
+                    detail: "loud".into(),
+                },
+                "Set log level error: loud",
+            ),
+            (
+                DevicePayload::GetLogLevelSuccess {
+                    level: LogLevel::Info,
+                },
+                "Log level: info",
+            ),
+            (
+                DevicePayload::LogLines {
+                    lines: vec!["boot".to_string(), "wifi up".to_string()],
+                },
+                "Log lines: last 2 line(s)",
+            ),
+            (
+                DevicePayload::SetAdaptiveModeSuccess { enabled: true },
+                "Adaptive mode set to on",
+            ),
+            (
+                DevicePayload::SetAdaptiveModeError {
+                    code: ErrorCode::Other,
+                    detail: "nvs".into(),
+                },
+                "Set adaptive mode error: nvs",
+            ),
+            (
+                DevicePayload::NextWake {
+                    sleep_seconds: 300,
+                    adaptive: false,
+                    delta_ppm: None,
+                },
+                "Next wake in 300 s",
+            ),
+            (
+                DevicePayload::NextWake {
+                    sleep_seconds: 120,
+                    adaptive: true,
+                    delta_ppm: Some(85),
+                },
+                "Next wake in 120 s (adaptive, CO2 moved 85 ppm)",
+            ),
+            (
+                DevicePayload::NextWake {
+                    sleep_seconds: 300,
+                    adaptive: true,
+                    delta_ppm: None,
+                },
+                "Next wake in 300 s (adaptive, no previous reading)",
+            ),
+            (
+                DevicePayload::diagnostics(-67, 180_224, 42, "deep sleep"),
+                "Diagnostics: signal -67 dBm, 176.0 KiB free heap, boot 42, \
+                 last reset: deep sleep",
+            ),
+            (
+                DevicePayload::AscSetSuccess { enabled: false },
+                "Automatic self-calibration set to off",
+            ),
+            (
+                DevicePayload::AscGetSuccess { enabled: true },
+                "Automatic self-calibration: on",
+            ),
+            (
+                DevicePayload::AscError {
+                    code: ErrorCode::Other,
+                    detail: "busy".into(),
+                },
+                "Automatic self-calibration error: busy",
+            ),
+            (
+                DevicePayload::AltitudeSetSuccess { meters: 120 },
+                "Altitude set to 120 m",
+            ),
+            (
+                DevicePayload::AltitudeGetSuccess { meters: 120 },
+                "Altitude: 120 m",
+            ),
+            (
+                DevicePayload::AltitudeError {
+                    code: ErrorCode::Other,
+                    detail: "busy".into(),
+                },
+                "Altitude error: busy",
+            ),
+            (
+                DevicePayload::AltitudeError {
+                    code: ErrorCode::I2cError,
+                    detail: "failed_to_set".into(),
+                },
+                "Altitude error (i2c_error): failed_to_set",
+            ),
+            (
+                DevicePayload::AmbientPressureSetSuccess { pascals: 101_300 },
+                "Ambient pressure set to 101300 Pa",
+            ),
+            (
+                DevicePayload::AmbientPressureError {
+                    code: ErrorCode::Other,
+                    detail: "busy".into(),
+                },
+                "Ambient pressure error: busy",
+            ),
+            (
+                DevicePayload::PendingConfirmation {
+                    id: 7,
+                    command: "set_deep_sleep_time".to_string(),
+                    wakes_left: 3,
+                },
+                "Trying set_deep_sleep_time (#7): confirm within 3 wake(s) or it rolls back",
+            ),
+            (
+                DevicePayload::ConfigRolledBack {
+                    id: 7,
+                    command: "set_deep_sleep_time".to_string(),
+                },
+                "Rolled back set_deep_sleep_time (#7): never confirmed",
+            ),
+            (
+                DevicePayload::ConfirmConfigError {
+                    code: ErrorCode::Other,
+                    detail: "nothing pending".into(),
+                },
+                "Confirm config error: nothing pending",
+            ),
+            (
+                DevicePayload::SelfTestResult {
+                    passed: true,
+                    detail: "".into(),
+                },
+                "Self test passed",
+            ),
+            (
+                DevicePayload::SelfTestResult {
+                    passed: false,
+                    detail: "malfunction 0x0001".into(),
+                },
+                "Self test failed: malfunction 0x0001",
+            ),
+            (DevicePayload::FactoryResetSuccess, "Factory reset done"),
+            (
+                DevicePayload::FactoryResetError {
+                    code: ErrorCode::Other,
+                    detail: "wrong word".into(),
+                },
+                "Factory reset failed: wrong word",
+            ),
+            (
+                DevicePayload::SerialNumber {
+                    serial: 0x1A2B_3C4D_5E6F,
+                },
+                "Serial number: 0x1A2B3C4D5E6F (28772997619311)",
+            ),
+            (
+                DevicePayload::Rebooting {
+                    detail: "requested".into(),
+                },
+                "Rebooting: requested",
+            ),
+            (
+                DevicePayload::FaultArmed {
+                    kind: FaultKind::Co2High,
+                },
+                "Fault armed: co2_high at the next wake",
+            ),
+            (
+                DevicePayload::FirmwareInfo {
+                    version: "0.1.0".to_string(),
+                    build_time: "2026-10-01 12:00".to_string(),
+                    idf_version: "v5.3".to_string(),
+                },
+                "Firmware 0.1.0, built 2026-10-01 12:00, ESP-IDF v5.3",
+            ),
+            (
+                DevicePayload::RadioSkipped {
+                    skipped_wakes: 4,
+                    lowest_mv: 3290,
+                    threshold_mv: 3350,
+                },
+                "Radio skipped: 4 wake(s) with the supply below 3350 mV, lowest 3290 mV",
+            ),
+            (
+                DevicePayload::EnteringSleep {
+                    sleep_seconds: 300,
+                    next_wake_unix: Some(1_736_942_700),
+                },
+                "Entering sleep for 300 s",
+            ),
+            (
+                DevicePayload::command_accepted(&DeviceCommand::StartFrc { target_ppm: 422 }),
+                "start_frc accepted",
+            ),
+            (
+                DevicePayload::command_rejected(
+                    &DeviceCommand::StartFrc { target_ppm: 5000 },
+                    "FRC target 5000 ppm is outside 400 to 2000 ppm",
+                ),
+                "start_frc rejected: FRC target 5000 ppm is outside 400 to 2000 ppm",
+            ),
+        ];
+        for (payload, expected) in cases {
+            assert_eq!(payload.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn every_command_reads_as_one_line() {
+        let cases = [
+            (DeviceCommand::NoOp, "No-op"),
+            (
+                DeviceCommand::StartFrc { target_ppm: 422 },
+                "Start FRC, target 422 ppm",
+            ),
+            (
+                DeviceCommand::SetTempOffset {
+                    offset: 1.5,
+                    persist: true,
+                },
+                "Set temperature offset to 1.5 °C",
+            ),
+            (
+                DeviceCommand::SetTempOffset {
+                    offset: 1.5,
+                    persist: false,
+                },
+                "Set temperature offset to 1.5 °C (volatile)",
+            ),
+            (DeviceCommand::GetTempOffset, "Get temperature offset"),
+            (
+                DeviceCommand::SetDeepSleepTime { seconds: 600 },
+                "Set deep sleep time to 600 s",
+            ),
+            (DeviceCommand::GetDeepSleepTime, "Get deep sleep time"),
+            (
+                DeviceCommand::SetMqttPolicy {
+                    class: PayloadClass::Diagnostic,
+                    qos: 0,
+                    retain: false,
+                },
+                "Set MQTT policy: diagnostic at QoS 0",
+            ),
+            (
+                DeviceCommand::Ota {
+                    url: "http://pi.local/fw.bin".to_string(),
+                },
+                "OTA update from http://pi.local/fw.bin",
+            ),
+            (DeviceCommand::GetConfig, "Get configuration"),
+            (
+                DeviceCommand::SetLogLevel {
+                    level: LogLevel::Warn,
+                },
+                "Set log level to warn",
+            ),
+            (DeviceCommand::GetLogLevel, "Get log level"),
+            (
+                DeviceCommand::SetAdaptiveMode { enabled: true },
+                "Set adaptive mode on",
+            ),
+            (
+                DeviceCommand::SetAsc { enabled: false },
+                "Set automatic self-calibration off",
+            ),
+            (DeviceCommand::GetAsc, "Get automatic self-calibration"),
+            (
+                DeviceCommand::SetAltitude { meters: 120 },
+                "Set altitude to 120 m",
+            ),
+            (DeviceCommand::GetAltitude, "Get altitude"),
+            (
+                DeviceCommand::SetAmbientPressure { pascals: 101_300 },
+                "Set ambient pressure to 101300 Pa",
+            ),
+            (
+                DeviceCommand::ConfirmConfig { pending_id: 7 },
+                "Confirm pending setting #7",
+            ),
+            (DeviceCommand::SelfTest, "Run the self test"),
+            (
+                DeviceCommand::FactoryReset {
+                    confirm: "reset".into(),
+                },
+                "Factory reset 'reset'",
+            ),
+            (DeviceCommand::GetSerialNumber, "Get serial number"),
+            (DeviceCommand::Reboot, "Reboot"),
+            (DeviceCommand::GetFirmwareInfo, "Get firmware info"),
+            (
+                DeviceCommand::InjectFault {
+                    kind: FaultKind::Panic,
+                },
+                "Inject a panic fault",
+            ),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(command.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn batches_list_their_commands() {
+        let batch =
+            DeviceCommandBatch::new([DeviceCommand::GetConfig, DeviceCommand::GetAsc]).unwrap();
+        assert_eq!(batch.to_string(), "Batch: get_config, get_asc");
+        let deferred = DeviceCommandBatch {
+            deferred: true,
+            ..DeviceCommandBatch::new([DeviceCommand::Reboot]).unwrap()
+        };
+        assert_eq!(deferred.to_string(), "Deferred batch: reboot");
+        assert_eq!(
+            CommandMessage::from(batch.with_id(3)).to_string(),
+            "Batch: get_config, get_asc"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Durations as the processor and commander take them on the command line
+//! and in query strings: `300s`, `5m`, `1h` or a bare number of seconds.
+
+use std::time::Duration;
+
+/// Parses a duration such as `300s`, `5m`, `1h` or a bare number of
+/// seconds. Zero is a duration too; callers that need a positive one check.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(3600),
+        _ => return Err(format!("invalid duration '{}', use s, m or h", value)),
+    };
+    seconds
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too long", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_and_bare_seconds() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("300s"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn other_units_and_missing_numbers_are_errors() {
+        for value in ["5d", "m", "", "-5m", "1.5h", " 5m"] {
+            assert!(parse_duration(value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            parse_duration("18446744073709551615h"),
+            Err("duration '18446744073709551615h' is too long".to_string())
+        );
+    }
+}
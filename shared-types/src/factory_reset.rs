@@ -0,0 +1,59 @@
+//! Confirmation for `factory_reset`.
+//!
+//! A factory reset drops the sensor's calibration and every setting saved
+//! to its EEPROM, so it must not run because of a stray command: the
+//! commander retains commands on the topic all devices share, and whichever
+//! device wakes first reads them. The command therefore names the device it
+//! is meant for in `confirm`, and every other device refuses it.
+
+use crate::DeviceCommand;
+
+/// A `factory_reset` command, for checking its confirmation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FactoryReset<'a> {
+    pub confirm: &'a str,
+}
+
+impl<'a> FactoryReset<'a> {
+    /// The reset `command` asks for, if it is a `factory_reset`
+    pub fn of(command: &'a DeviceCommand) -> Option<Self> {
+        match command {
+            DeviceCommand::FactoryReset { confirm } => Some(FactoryReset { confirm }),
+            _ => None,
+        }
+    }
+
+    /// Whether the reset was confirmed for `device`. The name has to match
+    /// exactly; an empty one confirms nothing.
+    pub fn is_confirmed(&self, device: &str) -> bool {
+        !device.is_empty() && self.confirm == device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_named_device_is_confirmed() {
+        let command = DeviceCommand::FactoryReset {
+            confirm: "esp32-scd40".to_string(),
+        };
+        let reset = FactoryReset::of(&command).unwrap();
+        assert!(reset.is_confirmed("esp32-scd40"));
+        for other in ["esp32-kitchen", "ESP32-SCD40", "esp32-scd40 ", "esp32", ""] {
+            assert!(!reset.is_confirmed(other), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn an_empty_confirmation_confirms_nothing() {
+        let reset = FactoryReset { confirm: "" };
+        assert!(!reset.is_confirmed(""));
+    }
+
+    #[test]
+    fn other_commands_are_no_reset() {
+        assert_eq!(FactoryReset::of(&DeviceCommand::SelfTest), None);
+    }
+}
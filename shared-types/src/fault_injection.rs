@@ -0,0 +1,132 @@
+//! Failures a debug firmware produces on purpose, set with `inject_fault`.
+//!
+//! They let the processor's validation, anomaly detection, alerts and
+//! safe-mode handling be tried end to end without abusing a sensor. Only
+//! builds with the firmware's `fault-injection` feature act on the command;
+//! the rest answer it with an error. A fault is one-shot: the next wake
+//! produces it and clears it, and every message that wake sends has
+//! `injected` set, so none of it is mistaken for real data.
+
+use core::fmt;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Humidity `flat_humidity` reports, the same every time so that injecting
+/// it a few wakes in a row reads as a stuck sensor
+pub const FLAT_HUMIDITY: f32 = 50.0;
+
+/// CO2 `co2_high` reports, far above anything the sensor can measure
+pub const GARBAGE_HIGH_PPM: u16 = 60_000;
+
+/// How late `delayed_publish` publishes the measurement
+pub const DELAYED_PUBLISH_SECONDS: u32 = 20;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// The measurement times out as if the sensor never had data ready
+    SensorTimeout,
+    /// The measurement reads 0 ppm
+    Co2Zero,
+    /// The measurement reads `GARBAGE_HIGH_PPM`
+    Co2High,
+    /// The measurement reads `FLAT_HUMIDITY`
+    FlatHumidity,
+    /// The measurement is published `DELAYED_PUBLISH_SECONDS` late
+    DelayedPublish,
+    /// The device panics once it has published the wake's messages
+    Panic,
+}
+
+impl FaultKind {
+    pub const ALL: [FaultKind; 6] = [
+        FaultKind::SensorTimeout,
+        FaultKind::Co2Zero,
+        FaultKind::Co2High,
+        FaultKind::FlatHumidity,
+        FaultKind::DelayedPublish,
+        FaultKind::Panic,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FaultKind::SensorTimeout => "sensor_timeout",
+            FaultKind::Co2Zero => "co2_zero",
+            FaultKind::Co2High => "co2_high",
+            FaultKind::FlatHumidity => "flat_humidity",
+            FaultKind::DelayedPublish => "delayed_publish",
+            FaultKind::Panic => "panic",
+        }
+    }
+
+    /// A reading with the fault applied, for the kinds that change one
+    pub fn garble(self, co2: u16, temperature: f32, humidity: f32) -> (u16, f32, f32) {
+        match self {
+            FaultKind::Co2Zero => (0, temperature, humidity),
+            FaultKind::Co2High => (GARBAGE_HIGH_PPM, temperature, humidity),
+            FaultKind::FlatHumidity => (co2, temperature, FLAT_HUMIDITY),
+            FaultKind::SensorTimeout | FaultKind::DelayedPublish | FaultKind::Panic => {
+                (co2, temperature, humidity)
+            }
+        }
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FaultKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FaultKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or(
+                "expected sensor_timeout, co2_zero, co2_high, flat_humidity, delayed_publish or panic",
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_form_round_trips() {
+        for kind in FaultKind::ALL {
+            assert_eq!(kind.as_str().parse(), Ok(kind));
+            assert_eq!(
+                serde_json::to_string(&kind).unwrap(),
+                format!("\"{}\"", kind)
+            );
+        }
+        assert!("brownout".parse::<FaultKind>().is_err());
+    }
+
+    #[test]
+    fn only_garbage_kinds_change_the_reading() {
+        let reading = (612, 22.4, 41.3);
+        assert_eq!(FaultKind::Co2Zero.garble(612, 22.4, 41.3), (0, 22.4, 41.3));
+        assert_eq!(
+            FaultKind::Co2High.garble(612, 22.4, 41.3),
+            (GARBAGE_HIGH_PPM, 22.4, 41.3)
+        );
+        assert_eq!(
+            FaultKind::FlatHumidity.garble(612, 22.4, 41.3),
+            (612, 22.4, FLAT_HUMIDITY)
+        );
+        for kind in [
+            FaultKind::SensorTimeout,
+            FaultKind::DelayedPublish,
+            FaultKind::Panic,
+        ] {
+            assert_eq!(kind.garble(612, 22.4, 41.3), reading);
+        }
+    }
+}
@@ -1,52 +1,315 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "no_alloc")]
+pub mod bounded_string;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod codec;
+pub mod command_schedule;
+pub mod config_trial;
+#[cfg(feature = "std")]
+pub mod dedup_window;
+pub mod device_config;
+pub mod device_error;
+mod display;
+#[cfg(feature = "std")]
+pub mod duration;
+pub mod factory_reset;
+pub mod fault_injection;
+#[cfg(feature = "std")]
+pub mod line_protocol;
+pub mod log_level;
+pub mod mqtt_policy;
+#[cfg(feature = "postcard")]
+mod postcard_wire;
+pub mod quiet_hours;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod topics;
+pub mod units;
+pub mod validation;
+pub mod versioned;
+
+use device_config::DeviceConfig;
+use fault_injection::FaultKind;
+use log_level::LogLevel;
+use mqtt_policy::PayloadClass;
+use units::{MeasuredCo2, MeasuredHumidity, MeasuredTemperature, OutOfRange};
+
+pub use codec::CodecError;
+pub use units::{Celsius, Co2Ppm, RelativeHumidity};
+
+/// Protocol version of the messages this build sends
+pub const CURRENT_PROTOCOL_VERSION: u8 = 2;
+/// Messages without a version come from firmware that predates it
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+/// The whole-home aggregate the processor writes next to the real devices.
+/// Nothing answers commands sent to it.
+pub const HOME_DEVICE: &str = "home";
+
+/// Altitudes the sensor can be set to, in meters
+pub const MIN_ALTITUDE_M: u16 = 0;
+pub const MAX_ALTITUDE_M: u16 = 3000;
+
+/// Ambient pressures the sensor can be set to, in pascals
+pub const MIN_AMBIENT_PRESSURE_PA: u32 = 70_000;
+pub const MAX_AMBIENT_PRESSURE_PA: u32 = 120_000;
+
+/// FRC targets the sensor accepts, in ppm
+pub const MIN_FRC_TARGET_PPM: u16 = 400;
+pub const MAX_FRC_TARGET_PPM: u16 = 2000;
+
+/// Temperature offsets worth setting, in °C. The sensor only subtracts
+/// its own heating, so an offset is never negative.
+pub const MIN_TEMP_OFFSET_C: f32 = 0.0;
+pub const MAX_TEMP_OFFSET_C: f32 = 20.0;
+
+/// Deep sleep times worth setting, in seconds: a wake takes about 30 s and
+/// a device quiet for over a day looks gone
+pub const MIN_DEEP_SLEEP_SECONDS: u64 = 30;
+pub const MAX_DEEP_SLEEP_SECONDS: u64 = 86_400;
+
+/// `device` in messages. A `String`, or with `no_alloc` up to 32 bytes kept
+/// inline, see `bounded_string`.
+#[cfg(not(feature = "no_alloc"))]
+pub type DeviceName = String;
+#[cfg(feature = "no_alloc")]
+pub type DeviceName = bounded_string::BoundedString<32>;
+
+/// `detail` in error and status payloads, likewise up to 96 bytes with
+/// `no_alloc`
+#[cfg(not(feature = "no_alloc"))]
+pub type Detail = String;
+#[cfg(feature = "no_alloc")]
+pub type Detail = bounded_string::BoundedString<96>;
+
+/// `fw_version`, `location` and `reset_reason`, up to 32 bytes with
+/// `no_alloc`
+#[cfg(not(feature = "no_alloc"))]
+pub type Label = String;
+#[cfg(feature = "no_alloc")]
+pub type Label = bounded_string::BoundedString<32>;
+
+/// Like `format!`, for a [`Detail`]. With `no_alloc` the text is written in
+/// place and cut to fit, without allocating.
+#[macro_export]
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        $crate::format_detail(format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn format_detail(args: core::fmt::Arguments<'_>) -> Detail {
+    #[cfg(not(feature = "no_alloc"))]
+    return alloc::fmt::format(args);
+    #[cfg(feature = "no_alloc")]
+    return Detail::from_args(args);
+}
+
+fn legacy_protocol_version() -> u8 {
+    LEGACY_PROTOCOL_VERSION
+}
+
 /// Main message envelope sent from ESP32 to server
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct DeviceMessage {
     /// Device identifier (e.g., "esp32-scd40")
-    pub device: String,
+    pub device: DeviceName,
     #[serde(flatten)]
     pub payload: DevicePayload,
+    /// When a measurement was taken, in milliseconds since the Unix epoch on
+    /// the device clock. Left out until the clock has been set over SNTP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    /// Counts the device's measurements; starts over at 0 after a power loss
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u32>,
+    /// The protocol version the message was built with. Sent as `v`, since
+    /// `ota_success` has a `version` of its own.
+    #[serde(rename = "v", default = "legacy_protocol_version")]
+    pub version: u8,
+    /// The `id` of the command this message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<u32>,
+    /// Published again from the device's outbox because no acknowledgment
+    /// for it arrived before the device went to sleep. It keeps its `ts`
+    /// and `seq`, so a copy the broker did deliver is recognized as one.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub redelivered: bool,
+    /// The firmware build that sent the message, left out by builds that
+    /// predate it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fw_version: Option<Label>,
+    /// The room the device was set up in, e.g. "bedroom", left out by
+    /// devices built without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Label>,
+    /// Sent during a wake with an injected fault, see `fault_injection`:
+    /// whatever the message says was made up on purpose
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub injected: bool,
 }
 
 impl DeviceMessage {
-    pub fn new(device: impl Into<String>, payload: DevicePayload) -> Self {
+    pub fn new(device: impl Into<DeviceName>, payload: DevicePayload) -> Self {
         Self {
             device: device.into(),
             payload,
+            ts: None,
+            seq: None,
+            version: CURRENT_PROTOCOL_VERSION,
+            in_reply_to: None,
+            redelivered: false,
+            fw_version: None,
+            location: None,
+            injected: false,
         }
     }
 
-    #[cfg(feature = "std")]
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string(self)
+    /// Whether this build understands the message's protocol version. Check
+    /// before acting on the payload: a newer protocol may have changed what
+    /// a payload that still parses means.
+    pub fn is_compatible(&self) -> bool {
+        (LEGACY_PROTOCOL_VERSION..=CURRENT_PROTOCOL_VERSION).contains(&self.version)
     }
 
+    /// Adds the device timestamp and sequence number.
+    pub fn stamped(mut self, ts: Option<u64>, seq: u32) -> Self {
+        self.ts = ts;
+        self.seq = Some(seq);
+        self
+    }
+
+    /// When the device took the message, from `ts`. `None` until the
+    /// device's clock has been set, or if `ts` is out of range.
     #[cfg(feature = "std")]
-    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+    pub fn timestamp(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        let ms = i64::try_from(self.ts?).ok()?;
+        chrono::DateTime::from_timestamp_millis(ms)
+    }
+
+    /// Marks the message as an answer to the command with this `id`.
+    pub fn replying_to(mut self, id: u32) -> Self {
+        self.in_reply_to = Some(id);
+        self
+    }
+
+    /// Marks the message as published again, see `redelivered`.
+    pub fn redelivered(mut self) -> Self {
+        self.redelivered = true;
+        self
+    }
+
+    /// Marks the message as part of a wake with an injected fault.
+    pub fn injected(mut self) -> Self {
+        self.injected = true;
+        self
+    }
+
+    /// Adds the version of the firmware sending the message.
+    pub fn with_fw_version(mut self, version: impl Into<Label>) -> Self {
+        self.fw_version = Some(version.into());
+        self
+    }
+
+    /// Adds the room the device was set up in.
+    pub fn with_location(mut self, location: impl Into<Label>) -> Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    pub fn to_json(&self) -> Result<String, CodecError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// The JSON as bytes, ready to publish
+    pub fn to_json_vec(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CodecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Reads a payload as received, without checking it is UTF-8 first
+    pub fn from_json_slice(json: &[u8]) -> Result<Self, CodecError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(cbor::to_vec(self)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(cbor::from_slice(bytes)?)
+    }
+
+    /// Encodes the message with postcard into `buf` and returns the part of
+    /// it that was used. Fails if `buf` is too small.
+    #[cfg(feature = "postcard")]
+    pub fn to_postcard<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], CodecError> {
+        Ok(postcard_wire::encode(self, buf)?)
+    }
+
+    #[cfg(feature = "postcard")]
+    pub fn from_postcard(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(postcard::from_bytes::<postcard_wire::Message>(bytes).map(Self::from)?)
     }
 }
 
 /// Payload variants for messages from device
+///
+/// New variants are safe to add: a receiver built before one fails to
+/// decode just the messages that carry its `status`, with serde's "unknown
+/// variant" error, and goes on with the rest. Nothing falls back to another
+/// variant, so such a message is never misread.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "status")]
 pub enum DevicePayload {
     #[serde(rename = "success")]
     MeasurementSuccess {
-        co2: u16,
-        temperature: f32,
-        humidity: f32,
+        co2: MeasuredCo2,
+        /// Whole degrees from older messages parse too
+        temperature: MeasuredTemperature,
+        humidity: MeasuredHumidity,
+        /// Only sent by battery-powered devices
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        battery_mv: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        battery_percent: Option<u8>,
+        /// Left out when none is set, and by older firmware
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flags: Option<MeasurementFlags>,
     },
 
+    /// Absent in messages from firmware older than `code`, which then reads
+    /// as `ErrorCode::Other`
     #[serde(rename = "error")]
-    Error { detail: String },
+    Error {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
 
     #[serde(rename = "frc_start")]
     FrcStart { target_ppm: u16 },
 
     #[serde(rename = "frc_warmup_complete")]
-    FrcWarmupComplete { detail: String },
+    FrcWarmupComplete { detail: Detail },
 
     #[serde(rename = "frc_calibrating")]
     FrcCalibrating { target_ppm: u16 },
@@ -55,13 +318,37 @@ pub enum DevicePayload {
     FrcSuccess { correction: u16 },
 
     #[serde(rename = "frc_error")]
-    FrcError { detail: String },
+    FrcError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
 
+    /// `persisted` is false when the offset was applied without saving it
+    /// to the sensor's EEPROM, as asked for
     #[serde(rename = "set_offset_success")]
-    SetOffsetSuccess { offset: f32 },
+    SetOffsetSuccess {
+        offset: f32,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        persisted: bool,
+    },
+
+    /// The offset was applied but not saved: the EEPROM had been written
+    /// `limit` times today already. Saving works again after
+    /// `retry_after_seconds`.
+    #[serde(rename = "set_offset_rate_limited")]
+    SetOffsetRateLimited {
+        offset: f32,
+        limit: u16,
+        retry_after_seconds: u64,
+    },
 
     #[serde(rename = "set_offset_error")]
-    SetOffsetError { detail: String },
+    SetOffsetError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
 
     #[serde(rename = "get_offset_success")]
     GetOffsetSuccess { offset: f32 },
@@ -69,19 +356,400 @@ pub enum DevicePayload {
     #[serde(rename = "set_deep_sleep_time_success")]
     SetDeepSleepTimeSuccess { seconds: u64 },
 
+    #[serde(rename = "set_deep_sleep_time_error")]
+    SetDeepSleepTimeError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
     #[serde(rename = "get_deep_sleep_time_success")]
     GetDeepSleepTimeSuccess { seconds: u64 },
 
+    #[serde(rename = "get_deep_sleep_time_error")]
+    GetDeepSleepTimeError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
     #[serde(rename = "get_offset_error")]
-    GetOffsetError { detail: String },
+    GetOffsetError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
 
     #[serde(rename = "alive")]
     Alive { uptime_seconds: u64 },
+
+    #[serde(rename = "set_mqtt_policy_success")]
+    SetMqttPolicySuccess {
+        class: PayloadClass,
+        qos: u8,
+        retain: bool,
+    },
+
+    #[serde(rename = "set_mqtt_policy_error")]
+    SetMqttPolicyError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Sent before running `running` alone; `deferred` waits for the next wake
+    #[serde(rename = "commands_deferred")]
+    CommandsDeferred {
+        running: String,
+        deferred: Vec<DeviceCommand>,
+    },
+
+    /// The device tried to free a stuck I2C bus. `pulses` is how many SCL
+    /// pulses it took to release SDA (absent if SDA stayed low) and
+    /// `recovered` whether the sensor answered afterwards.
+    #[serde(rename = "bus_recovery")]
+    BusRecovery {
+        attempt: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pulses: Option<u8>,
+        recovered: bool,
+    },
+
+    /// Share of the firmware image downloaded so far
+    #[serde(rename = "ota_progress")]
+    OtaProgress { percent: u8 },
+
+    /// The new image booted; `version` is what it reports
+    #[serde(rename = "ota_success")]
+    OtaSuccess { version: String },
+
+    #[serde(rename = "ota_error")]
+    OtaError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Sent last in a wake: how long the device had been awake by then and
+    /// how long its sensor and network halves took. `saved_ms` is what
+    /// running the halves one after the other would have added.
+    #[serde(rename = "wake_profile")]
+    WakeProfile {
+        awake_ms: u32,
+        sensor_ms: u32,
+        network_ms: u32,
+        saved_ms: u32,
+    },
+
+    /// Answer to `get_config`
+    #[serde(rename = "config")]
+    Config(DeviceConfig),
+
+    /// The level was applied right away and saved for later wakes
+    #[serde(rename = "set_log_level_success")]
+    SetLogLevelSuccess { level: LogLevel },
+
+    #[serde(rename = "set_log_level_error")]
+    SetLogLevelError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    #[serde(rename = "get_log_level_success")]
+    GetLogLevelSuccess { level: LogLevel },
+
+    /// Sent after an error at `debug` level and above: the last lines the
+    /// device logged in this wake, oldest first
+    #[serde(rename = "log_lines")]
+    LogLines { lines: Vec<String> },
+
+    /// Applied from the next sleep on and saved for later wakes
+    #[serde(rename = "set_adaptive_mode_success")]
+    SetAdaptiveModeSuccess { enabled: bool },
+
+    #[serde(rename = "set_adaptive_mode_error")]
+    SetAdaptiveModeError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Sent before the wake profile: how long the device sleeps now, and
+    /// whether adaptive mode chose it. `delta_ppm` is the CO2 change since
+    /// the previous wake it was chosen from, if there was one.
+    #[serde(rename = "next_wake")]
+    NextWake {
+        sleep_seconds: u64,
+        adaptive: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta_ppm: Option<u16>,
+    },
+
+    /// Connection and memory health, for debugging a device remotely.
+    /// `boot_count` counts boots since the last flash, `reset_reason` is why
+    /// the chip last reset, e.g. `deep_sleep` or `brownout`.
+    #[serde(rename = "diagnostics")]
+    Diagnostics {
+        rssi_dbm: i8,
+        free_heap_bytes: u32,
+        boot_count: u32,
+        reset_reason: Label,
+    },
+
+    /// Automatic self-calibration was switched and saved to the sensor
+    #[serde(rename = "asc_set_success")]
+    AscSetSuccess { enabled: bool },
+
+    #[serde(rename = "asc_get_success")]
+    AscGetSuccess { enabled: bool },
+
+    /// Answers either ASC command
+    #[serde(rename = "asc_error")]
+    AscError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Altitude compensation was set and saved to the sensor
+    #[serde(rename = "altitude_set_success")]
+    AltitudeSetSuccess { meters: u16 },
+
+    #[serde(rename = "altitude_get_success")]
+    AltitudeGetSuccess { meters: u16 },
+
+    /// Answers either altitude command
+    #[serde(rename = "altitude_error")]
+    AltitudeError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Applied to the sensor and saved on the device, which applies it
+    /// again every wake
+    #[serde(rename = "ambient_pressure_set_success")]
+    AmbientPressureSetSuccess { pascals: u32 },
+
+    #[serde(rename = "ambient_pressure_error")]
+    AmbientPressureError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// A `set_deep_sleep_time` or `set_mqtt_policy` is in use but not saved
+    /// until a `confirm_config` for `id` arrives, within `wakes_left` more
+    /// wakes; see `config_trial`
+    #[serde(rename = "pending_confirmation")]
+    PendingConfirmation {
+        id: u32,
+        command: String,
+        wakes_left: u8,
+    },
+
+    /// The trial of `command` ended without a confirmation and the saved
+    /// setting is back in use
+    #[serde(rename = "config_rolled_back")]
+    ConfigRolledBack { id: u32, command: String },
+
+    /// A `confirm_config` that matched no trial, or whose setting couldn't
+    /// be saved. A confirmed setting is answered with the command's usual
+    /// success payload.
+    #[serde(rename = "confirm_config_error")]
+    ConfirmConfigError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// Outcome of the sensor's self test. `detail` says what failed, or
+    /// why the test couldn't run.
+    #[serde(rename = "self_test_result")]
+    SelfTestResult { passed: bool, detail: Detail },
+
+    /// The sensor is back to its factory settings and calibration
+    #[serde(rename = "factory_reset_success")]
+    FactoryResetSuccess,
+
+    /// Not confirmed for this device, or the sensor refused the reset
+    #[serde(rename = "factory_reset_error")]
+    FactoryResetError {
+        #[serde(default, skip_serializing_if = "ErrorCode::is_other")]
+        code: ErrorCode,
+        detail: Detail,
+    },
+
+    /// The sensor's 48-bit serial number, which identifies the module
+    #[serde(rename = "serial_number")]
+    SerialNumber { serial: u64 },
+
+    /// Sent just before the device restarts for a `reboot`, in place of
+    /// going to deep sleep
+    #[serde(rename = "rebooting")]
+    Rebooting { detail: Detail },
+
+    /// An `inject_fault` was accepted; the next wake produces `kind`
+    #[serde(rename = "fault_armed")]
+    FaultArmed { kind: FaultKind },
+
+    /// The firmware build: its version, when it was built and the ESP-IDF
+    /// it was built against
+    #[serde(rename = "firmware_info")]
+    FirmwareInfo {
+        version: String,
+        build_time: String,
+        idf_version: String,
+    },
+
+    /// Measurements buffered while the device couldn't publish, sent
+    /// together once it could, oldest first
+    #[serde(rename = "measurement_batch")]
+    MeasurementBatch { readings: Vec<BatchedReading> },
+
+    /// Sent by the first wake back on the broker after the supply guard
+    /// kept the radio off, see the firmware's `supply_guard`: how many wakes
+    /// it did, the lowest supply voltage it saw and the threshold it went by
+    #[serde(rename = "radio_skipped")]
+    RadioSkipped {
+        skipped_wakes: u32,
+        lowest_mv: u16,
+        threshold_mv: u16,
+    },
+
+    /// The last message before deep sleep, so a device that is sleeping as
+    /// planned can be told apart from one that died. `next_wake_unix` is
+    /// left out while the device's clock has never been synced.
+    #[serde(rename = "entering_sleep")]
+    EnteringSleep {
+        sleep_seconds: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_wake_unix: Option<u64>,
+    },
+
+    /// Sent as soon as a command is parsed, before it runs, so a long one
+    /// like an FRC isn't minutes of silence. A rejected command isn't run;
+    /// `detail` says why.
+    #[serde(rename = "command_ack")]
+    CommandAck {
+        cmd: String,
+        accepted: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<Detail>,
+    },
+
+    /// A `set_asc` or `set_altitude` was applied but not saved, like
+    /// `set_offset_rate_limited`: the EEPROM had been written `limit` times
+    /// today already. `command` is the command's `cmd`.
+    #[serde(rename = "persist_rate_limited")]
+    PersistRateLimited {
+        command: String,
+        limit: u16,
+        retry_after_seconds: u64,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+/// One measurement of a `measurement_batch`. `age_seconds` is how long
+/// before the message was sent it was taken.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BatchedReading {
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub age_seconds: u32,
+}
+
+/// Conditions under which a measurement was taken that make it less
+/// trustworthy, for leaving it out of training data
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MeasurementFlags {
+    /// The first measurement since a cold boot, when the sensor had been
+    /// powered down
+    #[serde(default)]
+    pub first_after_boot: bool,
+    /// Taken before the sensor's warmup period had passed
+    #[serde(default)]
+    pub sensor_warmup_incomplete: bool,
+    /// Only read after an earlier attempt failed
+    #[serde(default)]
+    pub retried_read: bool,
+}
+
+impl MeasurementFlags {
+    /// Whether any flag is set
+    pub fn any(&self) -> bool {
+        self.first_after_boot || self.sensor_warmup_incomplete || self.retried_read
+    }
+}
+
+/// Coarse failure class of a device error, sent as the `code` of `error`,
+/// `frc_error` and the other `*_error` payloads so it can be acted on
+/// without parsing `detail`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    SensorTimeout,
+    SensorReadFailed,
+    I2cError,
+    WifiError,
+    MqttError,
+    /// I2C bus still unusable after bus recovery
+    I2cBusStuck,
+    /// Settings couldn't be read from or written to flash
+    NvsError,
+    /// A payload couldn't be serialized or parsed
+    EncodingError,
+    #[default]
+    Other,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 9] = [
+        ErrorCode::SensorTimeout,
+        ErrorCode::SensorReadFailed,
+        ErrorCode::I2cError,
+        ErrorCode::WifiError,
+        ErrorCode::MqttError,
+        ErrorCode::I2cBusStuck,
+        ErrorCode::NvsError,
+        ErrorCode::EncodingError,
+        ErrorCode::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::SensorTimeout => "sensor_timeout",
+            ErrorCode::SensorReadFailed => "sensor_read_failed",
+            ErrorCode::I2cError => "i2c_error",
+            ErrorCode::WifiError => "wifi_error",
+            ErrorCode::MqttError => "mqtt_error",
+            ErrorCode::I2cBusStuck => "i2c_bus_stuck",
+            ErrorCode::NvsError => "nvs_error",
+            ErrorCode::EncodingError => "encoding_error",
+            ErrorCode::Other => "other",
+        }
+    }
+
+    fn is_other(&self) -> bool {
+        *self == ErrorCode::Other
+    }
+}
+
+impl core::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "cmd")]
 pub enum DeviceCommand {
+    #[default]
     #[serde(rename = "noop")]
     NoOp,
 
@@ -92,8 +760,16 @@ pub enum DeviceCommand {
         target_ppm: u16,
     },
 
+    /// With `persist: false` the offset only lasts until the sensor loses
+    /// power, sparing its EEPROM a write. A `set_asc` or `set_altitude`
+    /// saves every setting the sensor holds, so in the same wake they run
+    /// first, see `command_schedule`; one in a later wake saves it too.
     #[serde(rename = "set_temp_offset")]
-    SetTempOffset { offset: f32 },
+    SetTempOffset {
+        offset: f32,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        persist: bool,
+    },
 
     #[serde(rename = "get_temp_offset")]
     GetTempOffset,
@@ -103,11 +779,443 @@ pub enum DeviceCommand {
 
     #[serde(rename = "get_deep_sleep_time")]
     GetDeepSleepTime,
+
+    /// Override QoS/retain for one payload class; persisted on the device
+    #[serde(rename = "set_mqtt_policy")]
+    SetMqttPolicy {
+        class: PayloadClass,
+        qos: u8,
+        #[serde(default)]
+        retain: bool,
+    },
+
+    /// Download firmware from `url`, install it and reboot. Answered with
+    /// `ota_progress` while downloading, then `ota_success` or `ota_error`.
+    #[serde(rename = "ota")]
+    Ota { url: String },
+
+    /// Report the device's configuration, answered with `config`
+    #[serde(rename = "get_config")]
+    GetConfig,
+
+    /// Change the firmware's log verbosity; persisted on the device
+    #[serde(rename = "set_log_level")]
+    SetLogLevel { level: LogLevel },
+
+    #[serde(rename = "get_log_level")]
+    GetLogLevel,
+
+    /// Choose the sleep from how fast CO2 changes, see the firmware's
+    /// `adaptive_sleep`; persisted on the device
+    #[serde(rename = "set_adaptive_mode")]
+    SetAdaptiveMode { enabled: bool },
+
+    /// Switch the sensor's automatic self-calibration, which drifts the
+    /// baseline in rooms that never see fresh air; saved to its EEPROM
+    #[serde(rename = "set_asc")]
+    SetAsc { enabled: bool },
+
+    #[serde(rename = "get_asc")]
+    GetAsc,
+
+    /// Height above sea level the sensor compensates CO2 for; saved to its
+    /// EEPROM. Build with [`DeviceCommand::set_altitude`].
+    #[serde(rename = "set_altitude")]
+    SetAltitude { meters: u16 },
+
+    #[serde(rename = "get_altitude")]
+    GetAltitude,
+
+    /// Ambient pressure the sensor compensates CO2 for, which overrides the
+    /// altitude while set. Build with [`DeviceCommand::set_ambient_pressure`].
+    #[serde(rename = "set_ambient_pressure")]
+    SetAmbientPressure { pascals: u32 },
+
+    /// Save the setting on trial under `pending_id`, see
+    /// `pending_confirmation`. Not `id`, which the envelope already uses.
+    #[serde(rename = "confirm_config")]
+    ConfirmConfig { pending_id: u32 },
+
+    /// Run the sensor's built-in self test, which takes about 10 seconds
+    #[serde(rename = "self_test")]
+    SelfTest,
+
+    /// Reset the sensor to its factory settings, dropping its calibration.
+    /// Only runs on the device named by `confirm`, see `factory_reset`.
+    #[serde(rename = "factory_reset")]
+    FactoryReset { confirm: String },
+
+    /// Read the sensor's serial number, answered with `serial_number`
+    #[serde(rename = "get_serial_number")]
+    GetSerialNumber,
+
+    /// Restart the device instead of going to deep sleep at the end of the
+    /// wake, like a power cycle. Answered with `rebooting`.
+    #[serde(rename = "reboot")]
+    Reboot,
+
+    /// Read which firmware the device runs, answered with `firmware_info`
+    #[serde(rename = "get_firmware_info")]
+    GetFirmwareInfo,
+
+    /// Make the next wake fail in the way `kind` says, see
+    /// `fault_injection`. Answered with `fault_armed` by debug builds only.
+    #[serde(rename = "inject_fault")]
+    InjectFault { kind: FaultKind },
+}
+
+/// A command together with the id its answers will carry, sent as the
+/// command's JSON with an extra `id` key. Without one it is exactly the
+/// plain command, and firmware that predates ids ignores the key. The same
+/// goes for the other keys, so a bare command from an older sender reads as
+/// an envelope with nothing set. A [`DeviceCommandBatch`] travels in one
+/// too, its keys covering every command in it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CommandEnvelope<C = DeviceCommand> {
+    /// Copied into `in_reply_to` of every message answering the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    /// The operator who sent the command, so the commander can tell whose
+    /// retained command it would replace. Devices ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_by: Option<String>,
+    /// When the command was sent, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_at_unix: Option<u64>,
+    /// How long after `issued_at_unix` the command is still worth running.
+    /// A retained command can wait on the broker for days; a device that
+    /// wakes after that drops it, see `is_expired`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    #[serde(flatten)]
+    pub command: C,
 }
 
-impl Default for DeviceCommand {
-    fn default() -> Self {
-        DeviceCommand::NoOp
+impl<C> CommandEnvelope<C> {
+    /// Names the operator sending the command.
+    pub fn issued_by(mut self, operator: impl Into<String>) -> Self {
+        self.issued_by = Some(operator.into());
+        self
+    }
+
+    /// Stamps the command as sent at `issued_at_unix`, to be dropped by a
+    /// device that only gets it more than `ttl_seconds` later.
+    pub fn expires(mut self, issued_at_unix: u64, ttl_seconds: u64) -> Self {
+        self.issued_at_unix = Some(issued_at_unix);
+        self.ttl_seconds = Some(ttl_seconds);
+        self
+    }
+
+    /// When the command stops being worth running, in seconds since the
+    /// epoch; `None` without both a send time and a TTL
+    pub fn expires_at_unix(&self) -> Option<u64> {
+        Some(self.issued_at_unix?.saturating_add(self.ttl_seconds?))
+    }
+
+    /// Whether a device whose clock reads `now_unix` should drop the
+    /// command. Commands without an expiry, like those from older senders,
+    /// never expire.
+    pub fn is_expired(&self, now_unix: u64) -> bool {
+        self.expires_at_unix().is_some_and(|at| now_unix >= at)
+    }
+}
+
+impl<C: Serialize> CommandEnvelope<C> {
+    pub fn to_json(&self) -> Result<String, CodecError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// See [`DeviceMessage::to_json_vec`].
+    pub fn to_json_vec(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+}
+
+/// A batch is read with [`CommandMessage::from_json`].
+impl CommandEnvelope {
+    pub fn from_json(json: &str) -> Result<Self, CodecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// See [`DeviceMessage::from_json_slice`].
+    pub fn from_json_slice(json: &[u8]) -> Result<Self, CodecError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+impl CommandEnvelope<DeviceCommandBatch> {
+    /// The batch's commands, each in an envelope with the batch's id,
+    /// sender and expiry.
+    pub fn into_envelopes(self) -> impl Iterator<Item = CommandEnvelope> {
+        let CommandEnvelope {
+            id,
+            issued_by,
+            issued_at_unix,
+            ttl_seconds,
+            command,
+        } = self;
+        command
+            .commands
+            .into_iter()
+            .map(move |command| CommandEnvelope {
+                id,
+                issued_by: issued_by.clone(),
+                issued_at_unix,
+                ttl_seconds,
+                command,
+            })
+    }
+}
+
+impl<C> From<C> for CommandEnvelope<C> {
+    fn from(command: C) -> Self {
+        Self {
+            id: None,
+            issued_by: None,
+            issued_at_unix: None,
+            ttl_seconds: None,
+            command,
+        }
+    }
+}
+
+/// Most commands a [`DeviceCommandBatch`] holds. Every build keeps to it,
+/// so a batch any sender builds fits a device built with `no_alloc`.
+pub const MAX_BATCH_COMMANDS: usize = 8;
+
+/// `commands` of a [`DeviceCommandBatch`]. A `Vec`, or with `no_alloc` up to
+/// [`MAX_BATCH_COMMANDS`] kept inline.
+#[cfg(not(feature = "no_alloc"))]
+pub type BatchCommands = Vec<DeviceCommand>;
+#[cfg(feature = "no_alloc")]
+pub type BatchCommands = heapless::Vec<DeviceCommand, MAX_BATCH_COMMANDS>;
+
+/// Several commands for one wake, run in order with an answer each. Sent
+/// as `{"cmd":"batch","commands":[...]}`, in a [`CommandEnvelope`] like a
+/// single command. Build one with [`DeviceCommandBatch::new`]; one with more
+/// than [`MAX_BATCH_COMMANDS`] doesn't decode.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "TaggedBatch", into = "TaggedBatch")]
+pub struct DeviceCommandBatch {
+    pub commands: BatchCommands,
+    /// Marks a batch the device re-published itself because an exclusive
+    /// command ran first, see `command_schedule`
+    pub deferred: bool,
+}
+
+impl DeviceCommandBatch {
+    /// A batch of `commands`, if there are no more than [`MAX_BATCH_COMMANDS`]
+    pub fn new(commands: impl IntoIterator<Item = DeviceCommand>) -> Result<Self, CommandError> {
+        let mut commands = commands.into_iter();
+        let batch = Self {
+            commands: commands.by_ref().take(MAX_BATCH_COMMANDS).collect(),
+            deferred: false,
+        };
+        match commands.count() {
+            0 => Ok(batch),
+            extra => Err(CommandError::BatchLength {
+                commands: MAX_BATCH_COMMANDS + extra,
+            }),
+        }
+    }
+
+    /// Wraps the batch with an id, which every command in it answers with.
+    pub fn with_id(self, id: u32) -> CommandEnvelope<Self> {
+        CommandEnvelope {
+            id: Some(id),
+            ..CommandEnvelope::from(self)
+        }
+    }
+
+    /// [`DeviceCommand::check`] for every command in the batch, and its length
+    pub fn check(&self) -> Result<(), CommandError> {
+        if self.commands.len() > MAX_BATCH_COMMANDS {
+            return Err(CommandError::BatchLength {
+                commands: self.commands.len(),
+            });
+        }
+        self.commands.iter().try_for_each(DeviceCommand::check)
+    }
+
+    pub fn to_json(&self) -> Result<String, CodecError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CodecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(cbor::to_vec(self)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(cbor::from_slice(bytes)?)
+    }
+
+    /// See [`DeviceMessage::to_postcard`].
+    #[cfg(feature = "postcard")]
+    pub fn to_postcard<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], CodecError> {
+        Ok(postcard::to_slice(
+            &postcard_wire::Command::from(self),
+            buf,
+        )?)
+    }
+
+    #[cfg(feature = "postcard")]
+    pub fn from_postcard(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(postcard::from_bytes::<postcard_wire::Command>(bytes).and_then(Self::try_from)?)
+    }
+}
+
+/// Gives [`DeviceCommandBatch`] its `cmd` tag, which serde only checks on
+/// enums
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd")]
+enum TaggedBatch {
+    #[serde(rename = "batch")]
+    Batch {
+        commands: BatchCommands,
+        #[serde(default, skip_serializing_if = "is_false")]
+        deferred: bool,
+    },
+}
+
+impl TryFrom<TaggedBatch> for DeviceCommandBatch {
+    type Error = CommandError;
+
+    fn try_from(
+        TaggedBatch::Batch { commands, deferred }: TaggedBatch,
+    ) -> Result<Self, Self::Error> {
+        let batch = Self::new(commands)?;
+        Ok(Self { deferred, ..batch })
+    }
+}
+
+impl From<DeviceCommandBatch> for TaggedBatch {
+    fn from(batch: DeviceCommandBatch) -> Self {
+        TaggedBatch::Batch {
+            commands: batch.commands,
+            deferred: batch.deferred,
+        }
+    }
+}
+
+/// Written out rather than derived from `TaggedBatch`, whose `commands` may
+/// be a `heapless::Vec`
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for DeviceCommandBatch {
+    fn schema_name() -> alloc::borrow::Cow<'static, str> {
+        "DeviceCommandBatch".into()
+    }
+
+    fn json_schema(generator: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "object",
+            "properties": {
+                "cmd": { "type": "string", "const": "batch" },
+                "commands": {
+                    "type": "array",
+                    "items": generator.subschema_for::<DeviceCommand>(),
+                    "maxItems": MAX_BATCH_COMMANDS
+                },
+                "deferred": { "type": "boolean" }
+            },
+            "required": ["cmd", "commands"]
+        })
+    }
+}
+
+/// What a command topic carries: a single command or a batch, each in its
+/// envelope. Told apart by the `cmd` tag, so a batch that doesn't decode is
+/// reported as a bad batch rather than as an unknown command.
+// Inline batches are the point of `no_alloc`; a message is read and then
+// split up, so its size doesn't matter
+#[cfg_attr(feature = "no_alloc", allow(clippy::large_enum_variant))]
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum CommandMessage {
+    Single(CommandEnvelope),
+    Batch(CommandEnvelope<DeviceCommandBatch>),
+}
+
+/// Only the `cmd` key of a [`CommandMessage`]
+#[derive(Deserialize)]
+struct CommandTag {
+    cmd: String,
+}
+
+impl CommandMessage {
+    /// The `cmd` tag: the command's, or `batch`
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandMessage::Single(envelope) => envelope.command.name(),
+            CommandMessage::Batch(_) => "batch",
+        }
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            CommandMessage::Single(envelope) => envelope.id,
+            CommandMessage::Batch(envelope) => envelope.id,
+        }
+    }
+
+    pub fn issued_by(&self) -> Option<&str> {
+        match self {
+            CommandMessage::Single(envelope) => envelope.issued_by.as_deref(),
+            CommandMessage::Batch(envelope) => envelope.issued_by.as_deref(),
+        }
+    }
+
+    /// The single command, or those in the batch
+    pub fn commands(&self) -> &[DeviceCommand] {
+        match self {
+            CommandMessage::Single(envelope) => core::slice::from_ref(&envelope.command),
+            CommandMessage::Batch(envelope) => &envelope.command.commands,
+        }
+    }
+
+    /// Each command in its own envelope, see
+    /// [`CommandEnvelope::into_envelopes`]
+    pub fn into_envelopes(self) -> Vec<CommandEnvelope> {
+        match self {
+            CommandMessage::Single(envelope) => vec![envelope],
+            CommandMessage::Batch(envelope) => envelope.into_envelopes().collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, CodecError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CodecError> {
+        Self::from_json_slice(json.as_bytes())
+    }
+
+    /// See [`DeviceMessage::from_json_slice`].
+    pub fn from_json_slice(json: &[u8]) -> Result<Self, CodecError> {
+        let CommandTag { cmd } = serde_json::from_slice(json)?;
+        Ok(match cmd.as_str() {
+            "batch" => CommandMessage::Batch(serde_json::from_slice(json)?),
+            _ => CommandMessage::Single(serde_json::from_slice(json)?),
+        })
+    }
+}
+
+impl From<CommandEnvelope> for CommandMessage {
+    fn from(envelope: CommandEnvelope) -> Self {
+        CommandMessage::Single(envelope)
+    }
+}
+
+impl From<CommandEnvelope<DeviceCommandBatch>> for CommandMessage {
+    fn from(envelope: CommandEnvelope<DeviceCommandBatch>) -> Self {
+        CommandMessage::Batch(envelope)
     }
 }
 
@@ -115,33 +1223,382 @@ fn default_frc_ppm() -> u16 {
     422
 }
 
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+/// Why a command builder refused its value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandError {
+    FrcTarget { target_ppm: u16 },
+    TempOffset { offset: f32 },
+    DeepSleepTime { seconds: u64 },
+    Altitude { meters: u16 },
+    AmbientPressure { pascals: u32 },
+    BatchLength { commands: usize },
+}
+
+impl core::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CommandError::FrcTarget { target_ppm } => write!(
+                f,
+                "FRC target {} ppm is outside {} to {} ppm",
+                target_ppm, MIN_FRC_TARGET_PPM, MAX_FRC_TARGET_PPM
+            ),
+            CommandError::TempOffset { offset } => write!(
+                f,
+                "temperature offset {} °C is outside {} to {} °C",
+                offset, MIN_TEMP_OFFSET_C, MAX_TEMP_OFFSET_C
+            ),
+            CommandError::DeepSleepTime { seconds } => write!(
+                f,
+                "deep sleep time {} s is outside {} to {} s",
+                seconds, MIN_DEEP_SLEEP_SECONDS, MAX_DEEP_SLEEP_SECONDS
+            ),
+            CommandError::Altitude { meters } => write!(
+                f,
+                "altitude {} m is outside {} to {} m",
+                meters, MIN_ALTITUDE_M, MAX_ALTITUDE_M
+            ),
+            CommandError::AmbientPressure { pascals } => write!(
+                f,
+                "ambient pressure {} Pa is outside {} to {} Pa",
+                pascals, MIN_AMBIENT_PRESSURE_PA, MAX_AMBIENT_PRESSURE_PA
+            ),
+            CommandError::BatchLength { commands } => write!(
+                f,
+                "a batch holds at most {} commands, not {}",
+                MAX_BATCH_COMMANDS, commands
+            ),
+        }
+    }
+}
+
+impl core::error::Error for CommandError {}
+
 impl DeviceCommand {
-    #[cfg(feature = "std")]
-    pub fn to_json(&self) -> Result<String, serde_json::Error> {
-        serde_json::to_string(self)
+    /// Wraps the command with an id for matching up its answers.
+    pub fn with_id(self, id: u32) -> CommandEnvelope {
+        CommandEnvelope {
+            id: Some(id),
+            issued_by: None,
+            issued_at_unix: None,
+            ttl_seconds: None,
+            command: self,
+        }
     }
 
-    #[cfg(feature = "std")]
-    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+    /// The `cmd` tag
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceCommand::NoOp => "noop",
+            DeviceCommand::StartFrc { .. } => "start_frc",
+            DeviceCommand::SetTempOffset { .. } => "set_temp_offset",
+            DeviceCommand::GetTempOffset => "get_temp_offset",
+            DeviceCommand::SetDeepSleepTime { .. } => "set_deep_sleep_time",
+            DeviceCommand::GetDeepSleepTime => "get_deep_sleep_time",
+            DeviceCommand::SetMqttPolicy { .. } => "set_mqtt_policy",
+            DeviceCommand::Ota { .. } => "ota",
+            DeviceCommand::GetConfig => "get_config",
+            DeviceCommand::SetLogLevel { .. } => "set_log_level",
+            DeviceCommand::GetLogLevel => "get_log_level",
+            DeviceCommand::SetAdaptiveMode { .. } => "set_adaptive_mode",
+            DeviceCommand::SetAsc { .. } => "set_asc",
+            DeviceCommand::GetAsc => "get_asc",
+            DeviceCommand::SetAltitude { .. } => "set_altitude",
+            DeviceCommand::GetAltitude => "get_altitude",
+            DeviceCommand::SetAmbientPressure { .. } => "set_ambient_pressure",
+            DeviceCommand::ConfirmConfig { .. } => "confirm_config",
+            DeviceCommand::SelfTest => "self_test",
+            DeviceCommand::FactoryReset { .. } => "factory_reset",
+            DeviceCommand::GetSerialNumber => "get_serial_number",
+            DeviceCommand::Reboot => "reboot",
+            DeviceCommand::GetFirmwareInfo => "get_firmware_info",
+            DeviceCommand::InjectFault { .. } => "inject_fault",
+        }
+    }
+
+    /// Whether the values of a command that came off the wire are in the
+    /// ranges its builder enforces. Every command is listed, so one added
+    /// with a builder doesn't go unchecked.
+    pub fn check(&self) -> Result<(), CommandError> {
+        match self {
+            DeviceCommand::StartFrc { target_ppm } => Self::start_frc(*target_ppm).map(drop),
+            DeviceCommand::SetTempOffset { offset, .. } => Self::set_temp_offset(*offset).map(drop),
+            DeviceCommand::SetDeepSleepTime { seconds } => {
+                Self::set_deep_sleep_time(*seconds).map(drop)
+            }
+            DeviceCommand::SetAltitude { meters } => Self::set_altitude(*meters).map(drop),
+            DeviceCommand::SetAmbientPressure { pascals } => {
+                Self::set_ambient_pressure(*pascals).map(drop)
+            }
+            DeviceCommand::NoOp
+            | DeviceCommand::GetTempOffset
+            | DeviceCommand::GetDeepSleepTime
+            | DeviceCommand::SetMqttPolicy { .. }
+            | DeviceCommand::Ota { .. }
+            | DeviceCommand::GetConfig
+            | DeviceCommand::SetLogLevel { .. }
+            | DeviceCommand::GetLogLevel
+            | DeviceCommand::SetAdaptiveMode { .. }
+            | DeviceCommand::SetAsc { .. }
+            | DeviceCommand::GetAsc
+            | DeviceCommand::GetAltitude
+            | DeviceCommand::ConfirmConfig { .. }
+            | DeviceCommand::SelfTest
+            | DeviceCommand::FactoryReset { .. }
+            | DeviceCommand::GetSerialNumber
+            | DeviceCommand::Reboot
+            | DeviceCommand::GetFirmwareInfo
+            | DeviceCommand::InjectFault { .. } => Ok(()),
+        }
+    }
+
+    /// `set_altitude`, if `meters` is one the sensor can be set to
+    pub fn set_altitude(meters: u16) -> Result<Self, CommandError> {
+        if !(MIN_ALTITUDE_M..=MAX_ALTITUDE_M).contains(&meters) {
+            return Err(CommandError::Altitude { meters });
+        }
+        Ok(Self::SetAltitude { meters })
+    }
+
+    /// `start_frc`, if `target_ppm` is one the sensor accepts. A wrong
+    /// target skews every measurement after the calibration.
+    pub fn start_frc(target_ppm: u16) -> Result<Self, CommandError> {
+        if !(MIN_FRC_TARGET_PPM..=MAX_FRC_TARGET_PPM).contains(&target_ppm) {
+            return Err(CommandError::FrcTarget { target_ppm });
+        }
+        Ok(Self::StartFrc { target_ppm })
+    }
+
+    /// A persisted `set_temp_offset`, if `offset` is within range
+    pub fn set_temp_offset(offset: f32) -> Result<Self, CommandError> {
+        // Written so that NaN is refused too
+        if !(MIN_TEMP_OFFSET_C..=MAX_TEMP_OFFSET_C).contains(&offset) {
+            return Err(CommandError::TempOffset { offset });
+        }
+        Ok(Self::SetTempOffset {
+            offset,
+            persist: true,
+        })
+    }
+
+    /// `set_deep_sleep_time`, if `seconds` is within range
+    pub fn set_deep_sleep_time(seconds: u64) -> Result<Self, CommandError> {
+        if !(MIN_DEEP_SLEEP_SECONDS..=MAX_DEEP_SLEEP_SECONDS).contains(&seconds) {
+            return Err(CommandError::DeepSleepTime { seconds });
+        }
+        Ok(Self::SetDeepSleepTime { seconds })
+    }
+
+    /// `set_ambient_pressure`, if `pascals` is one the sensor can be set to
+    pub fn set_ambient_pressure(pascals: u32) -> Result<Self, CommandError> {
+        if !(MIN_AMBIENT_PRESSURE_PA..=MAX_AMBIENT_PRESSURE_PA).contains(&pascals) {
+            return Err(CommandError::AmbientPressure { pascals });
+        }
+        Ok(Self::SetAmbientPressure { pascals })
+    }
+
+    pub fn to_json(&self) -> Result<String, CodecError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CodecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// See [`DeviceMessage::from_json_slice`].
+    pub fn from_json_slice(json: &[u8]) -> Result<Self, CodecError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(cbor::to_vec(self)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(cbor::from_slice(bytes)?)
+    }
+
+    /// See [`DeviceMessage::to_postcard`].
+    #[cfg(feature = "postcard")]
+    pub fn to_postcard<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], CodecError> {
+        Ok(postcard::to_slice(
+            &postcard_wire::Command::from(self),
+            buf,
+        )?)
+    }
+
+    #[cfg(feature = "postcard")]
+    pub fn from_postcard(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(postcard::from_bytes::<postcard_wire::Command>(bytes).and_then(Self::try_from)?)
     }
 }
 
+/// The payloads that carry an `ErrorCode`, binding it to `$code`. One list
+/// for `error_code` and `error_code_mut`.
+macro_rules! coded_payloads {
+    ($code:ident) => {
+        DevicePayload::Error { code: $code, .. }
+            | DevicePayload::FrcError { code: $code, .. }
+            | DevicePayload::SetOffsetError { code: $code, .. }
+            | DevicePayload::SetDeepSleepTimeError { code: $code, .. }
+            | DevicePayload::GetDeepSleepTimeError { code: $code, .. }
+            | DevicePayload::GetOffsetError { code: $code, .. }
+            | DevicePayload::SetMqttPolicyError { code: $code, .. }
+            | DevicePayload::OtaError { code: $code, .. }
+            | DevicePayload::SetLogLevelError { code: $code, .. }
+            | DevicePayload::SetAdaptiveModeError { code: $code, .. }
+            | DevicePayload::AscError { code: $code, .. }
+            | DevicePayload::AltitudeError { code: $code, .. }
+            | DevicePayload::AmbientPressureError { code: $code, .. }
+            | DevicePayload::ConfirmConfigError { code: $code, .. }
+            | DevicePayload::FactoryResetError { code: $code, .. }
+    };
+}
+
 impl DevicePayload {
+    /// Takes any reading, so only without the `validated` feature; see
+    /// `try_measurement`.
+    #[cfg(not(feature = "validated"))]
     pub fn measurement(co2: u16, temperature: f32, humidity: f32) -> Self {
         Self::MeasurementSuccess {
             co2,
             temperature,
             humidity,
+            battery_mv: None,
+            battery_percent: None,
+            flags: None,
         }
     }
 
-    pub fn error(detail: impl Into<String>) -> Self {
+    /// A measurement, unless a value is outside the sensor's range
+    pub fn try_measurement(co2: u16, temperature: f32, humidity: f32) -> Result<Self, OutOfRange> {
+        Ok(Self::MeasurementSuccess {
+            co2: units::measured(Co2Ppm::new(co2)?),
+            temperature: units::measured(Celsius::new(temperature)?),
+            humidity: units::measured(RelativeHumidity::new(humidity)?),
+            battery_mv: None,
+            battery_percent: None,
+            flags: None,
+        })
+    }
+
+    #[cfg(not(feature = "validated"))]
+    pub fn measurement_with_battery(
+        co2: u16,
+        temperature: f32,
+        humidity: f32,
+        battery_mv: u16,
+        battery_percent: u8,
+    ) -> Self {
+        Self::MeasurementSuccess {
+            co2,
+            temperature,
+            humidity,
+            battery_mv: Some(battery_mv),
+            battery_percent: Some(battery_percent),
+            flags: None,
+        }
+    }
+
+    pub fn try_measurement_with_battery(
+        co2: u16,
+        temperature: f32,
+        humidity: f32,
+        battery_mv: u16,
+        battery_percent: u8,
+    ) -> Result<Self, OutOfRange> {
+        Ok(Self::MeasurementSuccess {
+            co2: units::measured(Co2Ppm::new(co2)?),
+            temperature: units::measured(Celsius::new(temperature)?),
+            humidity: units::measured(RelativeHumidity::new(humidity)?),
+            battery_mv: Some(battery_mv),
+            battery_percent: Some(battery_percent),
+            flags: None,
+        })
+    }
+
+    /// A measurement with `flags`, which are left out when none is set.
+    /// Other payloads are returned as they are.
+    pub fn flagged(mut self, new_flags: MeasurementFlags) -> Self {
+        if let Self::MeasurementSuccess { flags, .. } = &mut self {
+            *flags = new_flags.any().then_some(new_flags);
+        }
+        self
+    }
+
+    pub fn diagnostics(
+        rssi_dbm: i8,
+        free_heap_bytes: u32,
+        boot_count: u32,
+        reset_reason: impl Into<Label>,
+    ) -> Self {
+        Self::Diagnostics {
+            rssi_dbm,
+            free_heap_bytes,
+            boot_count,
+            reset_reason: reset_reason.into(),
+        }
+    }
+
+    pub fn error(detail: impl Into<Detail>) -> Self {
+        Self::coded_error(ErrorCode::Other, detail)
+    }
+
+    pub fn coded_error(code: ErrorCode, detail: impl Into<Detail>) -> Self {
         Self::Error {
+            code,
             detail: detail.into(),
         }
     }
 
+    /// `command` was parsed and is about to run
+    pub fn command_accepted(command: &DeviceCommand) -> Self {
+        Self::CommandAck {
+            cmd: command.name().to_string(),
+            accepted: true,
+            detail: None,
+        }
+    }
+
+    /// `command` was parsed but won't run, because of `reason`
+    pub fn command_rejected(command: &DeviceCommand, reason: impl Into<Detail>) -> Self {
+        Self::CommandAck {
+            cmd: command.name().to_string(),
+            accepted: false,
+            detail: Some(reason.into()),
+        }
+    }
+
+    /// The ack for a received `command`: rejected with the reason when
+    /// `DeviceCommand::check` refuses its values, accepted otherwise
+    pub fn command_ack(command: &DeviceCommand) -> Self {
+        match command.check() {
+            Ok(()) => Self::command_accepted(command),
+            Err(e) => Self::command_rejected(command, detail!("{}", e)),
+        }
+    }
+
+    /// `now_unix` is `None` while the clock has never been synced
+    pub fn entering_sleep(sleep_seconds: u64, now_unix: Option<u64>) -> Self {
+        Self::EnteringSleep {
+            sleep_seconds,
+            next_wake_unix: now_unix.map(|now| now.saturating_add(sleep_seconds)),
+        }
+    }
+
     pub fn frc_start(target_ppm: u16) -> Self {
         Self::FrcStart { target_ppm }
     }
@@ -149,6 +1606,37 @@ impl DevicePayload {
     pub fn frc_success(correction: u16) -> Self {
         Self::FrcSuccess { correction }
     }
+
+    pub fn set_deep_sleep_time_error(code: ErrorCode, detail: impl Into<Detail>) -> Self {
+        Self::SetDeepSleepTimeError {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn get_deep_sleep_time_error(code: ErrorCode, detail: impl Into<Detail>) -> Self {
+        Self::GetDeepSleepTimeError {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    /// The failure class of `error`, `frc_error` and the other `*_error`
+    /// payloads; `None` for everything else
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            coded_payloads!(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// `error_code`, to be changed in place
+    pub fn error_code_mut(&mut self) -> Option<&mut ErrorCode> {
+        match self {
+            coded_payloads!(code) => Some(code),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -157,7 +1645,10 @@ mod tests {
 
     #[test]
     fn test_measurement_serialization() {
-        let msg = DeviceMessage::new("esp32-test", DevicePayload::measurement(450, 22.0, 45.3));
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement(450, 22.0, 45.3).unwrap(),
+        );
 
         let json = msg.to_json().unwrap();
         assert!(json.contains("\"status\":\"success\""));
@@ -167,6 +1658,79 @@ mod tests {
         assert_eq!(msg, deserialized);
     }
 
+    #[test]
+    fn test_integer_temperature() {
+        // As stored before fractional degrees were sent
+        let whole = r#"{"device":"esp32-test","status":"success","co2":450,"temperature":22,"humidity":45}"#;
+        let msg = DeviceMessage::from_json(whole).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::try_measurement(450, 22.0, 45.0).unwrap()
+        );
+
+        let fractional = r#"{"device":"esp32-test","status":"success","co2":450,"temperature":22.7,"humidity":45.3}"#;
+        let msg = DeviceMessage::from_json(fractional).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::try_measurement(450, 22.7, 45.3).unwrap()
+        );
+        assert!(msg.to_json().unwrap().contains("\"temperature\":22.7"));
+    }
+
+    #[test]
+    fn test_battery_fields() {
+        let without = r#"{"device":"esp32-test","status":"success","co2":450,"temperature":22.5,"humidity":45.0}"#;
+        let msg = DeviceMessage::from_json(without).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::try_measurement(450, 22.5, 45.0).unwrap()
+        );
+        assert!(!msg.to_json().unwrap().contains("battery"));
+
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement_with_battery(450, 22.5, 45.0, 3870, 72).unwrap(),
+        );
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("\"battery_mv\":3870"));
+        assert!(json.contains("\"battery_percent\":72"));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let json = r#"{"device":"esp32-test","status":"diagnostics","rssi_dbm":-67,"free_heap_bytes":182344,"boot_count":41,"reset_reason":"deep_sleep"}"#;
+        let msg = DeviceMessage::from_json(json).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep")
+        );
+        assert_eq!(
+            DeviceMessage::from_json(&msg.to_json().unwrap()).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn test_log_lines_and_diagnostics_tags() {
+        let lines = DevicePayload::LogLines {
+            lines: vec!["boot".to_string()],
+        };
+        let sent = DeviceMessage::new("esp32-test", lines).to_json().unwrap();
+        assert!(sent.contains(r#""status":"log_lines""#), "{}", sent);
+        let health = DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep");
+        let sent = DeviceMessage::new("esp32-test", health).to_json().unwrap();
+        assert!(sent.contains(r#""status":"diagnostics""#), "{}", sent);
+    }
+
+    #[test]
+    fn test_unknown_status_is_an_error() {
+        // What a receiver built before a new variant sees
+        let json = r#"{"device":"esp32-test","status":"from_the_future","rssi_dbm":-67}"#;
+        let error = DeviceMessage::from_json(json).unwrap_err();
+        assert!(error.to_string().contains("unknown variant"), "{}", error);
+    }
+
     #[test]
     fn test_command_deserialization() {
         let json = r#"{"cmd":"start_frc","target_ppm":420}"#;
@@ -175,6 +1739,269 @@ mod tests {
         assert_eq!(cmd, DeviceCommand::StartFrc { target_ppm: 420 });
     }
 
+    #[test]
+    fn test_compensation_commands_are_range_checked() {
+        assert_eq!(
+            DeviceCommand::set_altitude(600),
+            Ok(DeviceCommand::SetAltitude { meters: 600 })
+        );
+        assert!(DeviceCommand::set_altitude(MIN_ALTITUDE_M).is_ok());
+        assert!(DeviceCommand::set_altitude(MAX_ALTITUDE_M).is_ok());
+        assert_eq!(
+            DeviceCommand::set_altitude(MAX_ALTITUDE_M + 1),
+            Err(CommandError::Altitude { meters: 3001 })
+        );
+
+        assert_eq!(
+            DeviceCommand::set_ambient_pressure(94_200),
+            Ok(DeviceCommand::SetAmbientPressure { pascals: 94_200 })
+        );
+        assert!(DeviceCommand::set_ambient_pressure(MIN_AMBIENT_PRESSURE_PA).is_ok());
+        assert!(DeviceCommand::set_ambient_pressure(MAX_AMBIENT_PRESSURE_PA).is_ok());
+        assert!(DeviceCommand::set_ambient_pressure(MIN_AMBIENT_PRESSURE_PA - 1).is_err());
+        assert!(DeviceCommand::set_ambient_pressure(MAX_AMBIENT_PRESSURE_PA + 1).is_err());
+        // Hectopascals by mistake
+        assert_eq!(
+            DeviceCommand::set_ambient_pressure(942),
+            Err(CommandError::AmbientPressure { pascals: 942 })
+        );
+    }
+
+    #[test]
+    fn test_command_builders_check_their_range() {
+        assert_eq!(
+            DeviceCommand::start_frc(422),
+            Ok(DeviceCommand::StartFrc { target_ppm: 422 })
+        );
+        assert!(DeviceCommand::start_frc(MIN_FRC_TARGET_PPM).is_ok());
+        assert!(DeviceCommand::start_frc(MAX_FRC_TARGET_PPM).is_ok());
+        assert_eq!(
+            DeviceCommand::start_frc(0),
+            Err(CommandError::FrcTarget { target_ppm: 0 })
+        );
+        assert!(DeviceCommand::start_frc(MIN_FRC_TARGET_PPM - 1).is_err());
+        assert!(DeviceCommand::start_frc(MAX_FRC_TARGET_PPM + 1).is_err());
+
+        assert_eq!(
+            DeviceCommand::set_temp_offset(4.0),
+            Ok(DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true
+            })
+        );
+        assert!(DeviceCommand::set_temp_offset(MIN_TEMP_OFFSET_C).is_ok());
+        assert!(DeviceCommand::set_temp_offset(MAX_TEMP_OFFSET_C).is_ok());
+        assert!(DeviceCommand::set_temp_offset(-0.1).is_err());
+        assert!(DeviceCommand::set_temp_offset(20.1).is_err());
+        assert!(DeviceCommand::set_temp_offset(f32::NAN).is_err());
+
+        assert_eq!(
+            DeviceCommand::set_deep_sleep_time(600),
+            Ok(DeviceCommand::SetDeepSleepTime { seconds: 600 })
+        );
+        assert!(DeviceCommand::set_deep_sleep_time(MIN_DEEP_SLEEP_SECONDS).is_ok());
+        assert!(DeviceCommand::set_deep_sleep_time(MAX_DEEP_SLEEP_SECONDS).is_ok());
+        assert!(DeviceCommand::set_deep_sleep_time(MIN_DEEP_SLEEP_SECONDS - 1).is_err());
+        assert!(DeviceCommand::set_deep_sleep_time(MAX_DEEP_SLEEP_SECONDS + 1).is_err());
+        assert!(DeviceCommand::set_deep_sleep_time(0).is_err());
+    }
+
+    #[test]
+    fn mixed_batch_arrives_as_a_batch() {
+        let json = r#"{"cmd":"batch","commands":[{"cmd":"set_temp_offset","offset":4.0},{"cmd":"set_deep_sleep_time","seconds":600},{"cmd":"start_frc","target_ppm":422},{"cmd":"get_config"}]}"#;
+        let incoming = CommandMessage::from_json(json).unwrap();
+        let commands = [
+            DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true,
+            },
+            DeviceCommand::SetDeepSleepTime { seconds: 600 },
+            DeviceCommand::StartFrc { target_ppm: 422 },
+            DeviceCommand::GetConfig,
+        ];
+        let batch = DeviceCommandBatch::new(commands).unwrap();
+        assert_eq!(incoming, CommandMessage::Batch(batch.clone().into()));
+        assert_eq!(incoming.to_json().unwrap(), json);
+
+        // Run in order, with the FRC alone in its wake
+        let schedule = command_schedule::schedule(incoming.into_envelopes());
+        let names = |envelopes: &[CommandEnvelope]| {
+            envelopes.iter().map(|e| e.command.name()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&schedule.run), ["start_frc"]);
+        assert_eq!(
+            names(&schedule.deferred),
+            ["set_temp_offset", "set_deep_sleep_time", "get_config"]
+        );
+    }
+
+    #[test]
+    fn commands_are_told_apart_by_their_tag() {
+        assert_eq!(
+            CommandMessage::from_json(r#"{"cmd":"set_altitude","meters":250}"#).unwrap(),
+            CommandMessage::Single(DeviceCommand::SetAltitude { meters: 250 }.into())
+        );
+        assert_eq!(
+            CommandMessage::from_json(r#"{"id":3,"cmd":"get_config"}"#).unwrap(),
+            CommandMessage::Single(DeviceCommand::GetConfig.with_id(3))
+        );
+        // Envelope keys go with the batch, and to each command in it
+        let json = r#"{"id":9,"issued_by":"ola","cmd":"batch","commands":[{"cmd":"get_asc"},{"cmd":"get_altitude"}],"deferred":true}"#;
+        let message = CommandMessage::from_json(json).unwrap();
+        let batch = DeviceCommandBatch {
+            deferred: true,
+            ..DeviceCommandBatch::new([DeviceCommand::GetAsc, DeviceCommand::GetAltitude]).unwrap()
+        };
+        assert_eq!(
+            message,
+            CommandMessage::Batch(batch.with_id(9).issued_by("ola"))
+        );
+        assert_eq!(message.to_json().unwrap(), json);
+        assert_eq!(
+            message.into_envelopes(),
+            vec![
+                DeviceCommand::GetAsc.with_id(9).issued_by("ola"),
+                DeviceCommand::GetAltitude.with_id(9).issued_by("ola"),
+            ]
+        );
+        assert!(CommandMessage::from_json(r#"{"cmd":"from_the_future"}"#).is_err());
+        assert!(CommandMessage::from_json(r#"{"commands":[]}"#).is_err());
+    }
+
+    #[test]
+    fn an_over_long_batch_is_rejected() {
+        let commands = [r#"{"cmd":"get_asc"}"#; MAX_BATCH_COMMANDS + 1].join(",");
+        let json = format!(r#"{{"id":4,"cmd":"batch","commands":[{}]}}"#, commands);
+        let error = CommandMessage::from_json(&json).unwrap_err().to_string();
+        // Not mistaken for a single command nobody knows
+        assert!(!error.contains("unknown variant"), "{}", error);
+        // Inline commands run out of room before the length is checked
+        let expected = if cfg!(feature = "no_alloc") {
+            "invalid length 9"
+        } else {
+            "a batch holds at most 8 commands, not 9"
+        };
+        assert!(error.contains(expected), "{}", error);
+
+        let fits = [r#"{"cmd":"get_asc"}"#; MAX_BATCH_COMMANDS].join(",");
+        let json = format!(r#"{{"cmd":"batch","commands":[{}]}}"#, fits);
+        assert_eq!(
+            CommandMessage::from_json(&json).unwrap().commands().len(),
+            8
+        );
+
+        assert_eq!(
+            DeviceCommandBatch::new(vec![DeviceCommand::GetAsc; 9]),
+            Err(CommandError::BatchLength { commands: 9 })
+        );
+    }
+
+    #[test]
+    fn received_commands_are_checked_like_built_ones() {
+        assert_eq!(DeviceCommand::StartFrc { target_ppm: 422 }.check(), Ok(()));
+        assert_eq!(
+            DeviceCommand::StartFrc { target_ppm: 5000 }.check(),
+            Err(CommandError::FrcTarget { target_ppm: 5000 })
+        );
+        // Not saving the offset doesn't make it a different range
+        assert!(
+            DeviceCommand::SetTempOffset {
+                offset: 25.0,
+                persist: false
+            }
+            .check()
+            .is_err()
+        );
+        assert!(DeviceCommand::SetDeepSleepTime { seconds: 0 }.check().is_err());
+        assert_eq!(
+            DeviceCommand::SetAltitude { meters: 9000 }.check(),
+            Err(CommandError::Altitude { meters: 9000 })
+        );
+        assert_eq!(
+            DeviceCommand::SetAmbientPressure { pascals: 942 }.check(),
+            Err(CommandError::AmbientPressure { pascals: 942 })
+        );
+        assert_eq!(
+            DeviceCommand::SetAmbientPressure { pascals: 94_200 }.check(),
+            Ok(())
+        );
+        let batch = DeviceCommandBatch::new([
+            DeviceCommand::NoOp,
+            DeviceCommand::StartFrc { target_ppm: 100 },
+        ])
+        .unwrap();
+        assert_eq!(
+            batch.check(),
+            Err(CommandError::FrcTarget { target_ppm: 100 })
+        );
+        assert_eq!(DeviceCommand::GetConfig.check(), Ok(()));
+    }
+
+    #[test]
+    fn command_acks_say_whether_the_command_runs() {
+        let frc = DeviceCommand::StartFrc { target_ppm: 422 };
+        let msg = DeviceMessage::new("esp32-test", DevicePayload::command_accepted(&frc));
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""status":"command_ack","cmd":"start_frc","accepted":true"#));
+        assert!(!json.contains("detail"));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+
+        let json = r#"{"device":"esp32-test","status":"command_ack","cmd":"start_frc","accepted":false,"detail":"FRC target 5000 ppm is outside 400 to 2000 ppm"}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::command_rejected(&frc, "FRC target 5000 ppm is outside 400 to 2000 ppm")
+        );
+    }
+
+    #[test]
+    fn out_of_range_compensation_is_acked_as_rejected() {
+        assert_eq!(
+            DevicePayload::command_ack(&DeviceCommand::SetAltitude { meters: 9000 }),
+            DevicePayload::CommandAck {
+                cmd: "set_altitude".to_string(),
+                accepted: false,
+                detail: Some("altitude 9000 m is outside 0 to 3000 m".into()),
+            }
+        );
+        assert_eq!(
+            DevicePayload::command_ack(&DeviceCommand::SetAmbientPressure { pascals: 942 }),
+            DevicePayload::CommandAck {
+                cmd: "set_ambient_pressure".to_string(),
+                accepted: false,
+                detail: Some("ambient pressure 942 Pa is outside 70000 to 120000 Pa".into()),
+            }
+        );
+        let altitude = DeviceCommand::SetAltitude { meters: 250 };
+        assert_eq!(
+            DevicePayload::command_ack(&altitude),
+            DevicePayload::command_accepted(&altitude)
+        );
+    }
+
+    #[test]
+    fn test_command_errors_name_the_range() {
+        assert_eq!(
+            CommandError::FrcTarget { target_ppm: 70 }.to_string(),
+            "FRC target 70 ppm is outside 400 to 2000 ppm"
+        );
+        assert_eq!(
+            CommandError::TempOffset { offset: -2.0 }.to_string(),
+            "temperature offset -2 °C is outside 0 to 20 °C"
+        );
+        assert_eq!(
+            CommandError::DeepSleepTime { seconds: 5 }.to_string(),
+            "deep sleep time 5 s is outside 30 to 86400 s"
+        );
+        assert_eq!(
+            CommandError::Altitude { meters: 3001 }.to_string(),
+            "altitude 3001 m is outside 0 to 3000 m"
+        );
+        assert_eq!(
+            CommandError::AmbientPressure { pascals: 942 }.to_string(),
+            "ambient pressure 942 Pa is outside 70000 to 120000 Pa"
+        );
+    }
+
     #[test]
     fn test_error_message() {
         let msg = DeviceMessage::new("esp32-test", DevicePayload::error("Sensor timeout"));
@@ -184,4 +2011,347 @@ mod tests {
         assert!(json.contains("\"status\":\"error\""));
         assert!(json.contains("Sensor timeout"));
     }
+
+    #[test]
+    fn errors_from_older_firmware_have_no_code() {
+        let json = r#"{"device":"esp32-test","status":"error","detail":"Measurement timed out"}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::coded_error(ErrorCode::Other, "Measurement timed out")
+        );
+        let json = r#"{"device":"esp32-test","status":"frc_error","detail":"I2C(Nack)"}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::FrcError {
+                code: ErrorCode::Other,
+                detail: "I2C(Nack)".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn measurements_from_older_firmware_have_no_flags() {
+        let json = r#"{"device":"esp32-test","status":"success","co2":800,"temperature":21.5,"humidity":40.0}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::try_measurement(800, 21.5, 40.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn messages_from_devices_without_a_location_have_none() {
+        let json = r#"{"device":"esp32-test","status":"success","co2":800,"temperature":21.5,"humidity":40.0,"v":2}"#;
+        let msg = DeviceMessage::from_json(json).unwrap();
+        assert_eq!(msg.location, None);
+        assert_eq!(msg.to_json().unwrap(), json);
+    }
+
+    #[test]
+    fn location_is_sent_when_set() {
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement(800, 21.5, 40.0).unwrap(),
+        )
+        .with_location("bedroom");
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""location":"bedroom""#));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn measurement_flags_are_a_nested_object() {
+        let flags = MeasurementFlags {
+            first_after_boot: true,
+            retried_read: true,
+            ..Default::default()
+        };
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement(800, 21.5, 40.0)
+                .unwrap()
+                .flagged(flags),
+        );
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(
+            r#""flags":{"first_after_boot":true,"sensor_warmup_incomplete":false,"retried_read":true}"#
+        ));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+
+        // Flags a newer firmware adds are ignored, missing ones are unset
+        let json = r#"{"device":"esp32-test","status":"success","co2":800,"temperature":21.5,"humidity":40.0,"flags":{"retried_read":true,"low_power":true}}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::try_measurement(800, 21.5, 40.0)
+                .unwrap()
+                .flagged(MeasurementFlags {
+                    retried_read: true,
+                    ..Default::default()
+                })
+        );
+
+        // Nothing to tell is sent as older firmware sends it
+        let unflagged = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement(800, 21.5, 40.0)
+                .unwrap()
+                .flagged(MeasurementFlags::default()),
+        );
+        assert!(!unflagged.to_json().unwrap().contains("flags"));
+    }
+
+    #[test]
+    fn errors_carry_their_code() {
+        let json = r#"{"device":"esp32-test","status":"error","code":"sensor_timeout","detail":"Measurement timed out"}"#;
+        let msg = DeviceMessage::from_json(json).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::coded_error(ErrorCode::SensorTimeout, "Measurement timed out")
+        );
+        assert!(msg.to_json().unwrap().contains(r#""code":"sensor_timeout""#));
+
+        let json = r#"{"device":"esp32-test","status":"frc_error","code":"i2c_error","detail":"I2C(Nack)"}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: "I2C(Nack)".into(),
+            }
+        );
+
+        // Left out when there is nothing to tell, as older firmware sends it
+        let other = DeviceMessage::new("esp32-test", DevicePayload::error("Sensor timeout"));
+        assert!(!other.to_json().unwrap().contains("code"));
+
+        for code in ErrorCode::ALL {
+            assert_eq!(
+                serde_json::to_string(&code).unwrap(),
+                format!("\"{}\"", code)
+            );
+        }
+    }
+
+    #[test]
+    fn command_errors_carry_their_code_too() {
+        // Firmware from before the code
+        let json = r#"{"device":"esp32-test","status":"altitude_error","detail":"failed_to_set: I2C(Nack)"}"#;
+        let msg = DeviceMessage::from_json(json).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::AltitudeError {
+                code: ErrorCode::Other,
+                detail: "failed_to_set: I2C(Nack)".into(),
+            }
+        );
+        assert_eq!(msg.payload.error_code(), Some(ErrorCode::Other));
+        assert!(!msg.to_json().unwrap().contains("code"));
+
+        let json = r#"{"device":"esp32-test","status":"set_offset_error","code":"nvs_error","detail":"failed_to_save"}"#;
+        let msg = DeviceMessage::from_json(json).unwrap();
+        assert_eq!(
+            msg.payload,
+            DevicePayload::SetOffsetError {
+                code: ErrorCode::NvsError,
+                detail: "failed_to_save".into(),
+            }
+        );
+        assert_eq!(msg.payload.error_code(), Some(ErrorCode::NvsError));
+        assert!(msg.to_json().unwrap().contains(r#""code":"nvs_error","detail""#));
+
+        assert_eq!(DevicePayload::FactoryResetSuccess.error_code(), None);
+    }
+
+    #[test]
+    fn entering_sleep_names_the_wake_time_once_the_clock_is_synced() {
+        assert_eq!(
+            DevicePayload::entering_sleep(300, Some(1_736_942_400)),
+            DevicePayload::EnteringSleep {
+                sleep_seconds: 300,
+                next_wake_unix: Some(1_736_942_700),
+            }
+        );
+        let unsynced = DeviceMessage::new("esp32-test", DevicePayload::entering_sleep(300, None));
+        let json = unsynced.to_json().unwrap();
+        assert!(!json.contains("next_wake_unix"), "{}", json);
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), unsynced);
+    }
+
+    #[test]
+    fn test_deep_sleep_round_trip() {
+        let cmd = DeviceCommand::SetDeepSleepTime { seconds: 600 };
+        let json = cmd.to_json().unwrap();
+        assert_eq!(json, r#"{"cmd":"set_deep_sleep_time","seconds":600}"#);
+        assert_eq!(DeviceCommand::from_json(&json).unwrap(), cmd);
+
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::set_deep_sleep_time_error(
+                ErrorCode::Other,
+                "deep sleep time must be at least 1 second",
+            ),
+        );
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("\"status\":\"set_deep_sleep_time_error\""));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+
+        let json = r#"{"device":"esp32-test","status":"get_deep_sleep_time_error","detail":"failed_to_read: NVS"}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            DevicePayload::get_deep_sleep_time_error(ErrorCode::Other, "failed_to_read: NVS")
+        );
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        // Firmware from before the version was sent
+        let legacy = r#"{"device":"esp32-test","status":"alive","uptime_seconds":60}"#;
+        let msg = DeviceMessage::from_json(legacy).unwrap();
+        assert_eq!(msg.version, LEGACY_PROTOCOL_VERSION);
+        assert!(msg.is_compatible());
+
+        let msg = DeviceMessage::new("esp32-test", DevicePayload::Alive { uptime_seconds: 60 });
+        let json = msg.to_json().unwrap();
+        assert!(json.ends_with(&format!(r#","v":{}}}"#, CURRENT_PROTOCOL_VERSION)));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+
+        // Doesn't clash with the firmware version of an OTA answer
+        let ota = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::OtaSuccess {
+                version: "0.4.0".to_string(),
+            },
+        );
+        let parsed = DeviceMessage::from_json(&ota.to_json().unwrap()).unwrap();
+        assert_eq!(parsed, ota);
+
+        let newer = r#"{"device":"esp32-test","status":"alive","uptime_seconds":60,"v":3}"#;
+        assert!(!DeviceMessage::from_json(newer).unwrap().is_compatible());
+        let zero = r#"{"device":"esp32-test","status":"alive","uptime_seconds":60,"v":0}"#;
+        assert!(!DeviceMessage::from_json(zero).unwrap().is_compatible());
+    }
+
+    #[test]
+    fn test_device_timestamp() {
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::try_measurement(450, 22.0, 45.3).unwrap(),
+        );
+        let json = msg.to_json().unwrap();
+        assert!(!json.contains("\"ts\""));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap().timestamp(), None);
+
+        let msg = msg.stamped(Some(1_736_942_400_123), 1);
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""ts":1736942400123"#));
+        let parsed = DeviceMessage::from_json(&json).unwrap();
+        assert_eq!(parsed, msg);
+        assert_eq!(
+            parsed.timestamp().unwrap().timestamp_millis(),
+            1_736_942_400_123
+        );
+
+        // Beyond what chrono can represent
+        assert_eq!(msg.stamped(Some(u64::MAX), 2).timestamp(), None);
+    }
+
+    #[test]
+    fn test_command_id() {
+        let cmd = DeviceCommand::GetTempOffset.with_id(7);
+        let json = cmd.to_json().unwrap();
+        assert_eq!(json, r#"{"id":7,"cmd":"get_temp_offset"}"#);
+        assert_eq!(CommandEnvelope::from_json(&json).unwrap(), cmd);
+        // Firmware without ids reads the command and ignores the id
+        assert_eq!(
+            DeviceCommand::from_json(&json).unwrap(),
+            DeviceCommand::GetTempOffset
+        );
+
+        let plain = CommandEnvelope::from(DeviceCommand::GetTempOffset);
+        assert_eq!(plain.to_json().unwrap(), r#"{"cmd":"get_temp_offset"}"#);
+        assert_eq!(
+            CommandEnvelope::from_json(r#"{"cmd":"get_temp_offset"}"#).unwrap(),
+            plain
+        );
+
+        let signed = DeviceCommand::GetTempOffset.with_id(7).issued_by("ola");
+        let json = signed.to_json().unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":7,"issued_by":"ola","cmd":"get_temp_offset"}"#
+        );
+        assert_eq!(CommandEnvelope::from_json(&json).unwrap(), signed);
+        assert_eq!(
+            DeviceCommand::from_json(&json).unwrap(),
+            DeviceCommand::GetTempOffset
+        );
+
+        let expiring = DeviceCommand::GetTempOffset
+            .with_id(7)
+            .expires(1_736_935_200, 3600);
+        let json = expiring.to_json().unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":7,"issued_at_unix":1736935200,"ttl_seconds":3600,"cmd":"get_temp_offset"}"#
+        );
+        assert_eq!(CommandEnvelope::from_json(&json).unwrap(), expiring);
+        assert_eq!(
+            DeviceCommand::from_json(&json).unwrap(),
+            DeviceCommand::GetTempOffset
+        );
+
+        let msg = DeviceMessage::new(
+            "esp32-test",
+            DevicePayload::GetOffsetSuccess { offset: 4.0 },
+        )
+        .replying_to(7);
+        let json = msg.to_json().unwrap();
+        assert!(json.contains(r#""in_reply_to":7"#));
+        assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+        assert!(
+            !DeviceMessage::new(
+                "esp32-test",
+                DevicePayload::GetOffsetSuccess { offset: 4.0 }
+            )
+            .to_json()
+            .unwrap()
+            .contains("in_reply_to")
+        );
+    }
+
+    #[test]
+    fn test_command_expiry() {
+        let sent = 1_736_935_200;
+        let frc = CommandEnvelope::from(DeviceCommand::StartFrc { target_ppm: 422 })
+            .expires(sent, 86_400);
+        assert_eq!(frc.expires_at_unix(), Some(sent + 86_400));
+        assert!(!frc.is_expired(sent));
+        assert!(!frc.is_expired(sent + 86_399));
+        assert!(frc.is_expired(sent + 86_400));
+        assert!(frc.is_expired(sent + 7 * 86_400));
+        // A clock behind the sender's doesn't make it expire
+        assert!(!frc.is_expired(sent - 60));
+
+        let far = CommandEnvelope::from(DeviceCommand::NoOp).expires(u64::MAX - 10, 3600);
+        assert_eq!(far.expires_at_unix(), Some(u64::MAX));
+        assert!(!far.is_expired(u64::MAX - 1));
+
+        // Bare commands from older senders never expire
+        let legacy = CommandEnvelope::from_json(r#"{"cmd":"start_frc","target_ppm":422}"#).unwrap();
+        assert_eq!(
+            legacy,
+            CommandEnvelope::from(DeviceCommand::StartFrc { target_ppm: 422 })
+        );
+        assert_eq!(legacy.expires_at_unix(), None);
+        assert!(!legacy.is_expired(u64::MAX));
+        // Neither do half-stamped ones
+        let untimed = CommandEnvelope::from_json(r#"{"ttl_seconds":60,"cmd":"noop"}"#).unwrap();
+        assert_eq!(untimed.ttl_seconds, Some(60));
+        assert!(!untimed.is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn test_fahrenheit_conversion() {
+        assert_eq!(Celsius(0.0).to_fahrenheit(), 32.0);
+        assert_eq!(Celsius(-40.0).to_fahrenheit(), -40.0);
+        assert_eq!(Celsius(5.0).delta_to_fahrenheit(), 9.0);
+    }
 }
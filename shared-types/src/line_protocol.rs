@@ -0,0 +1,416 @@
+//! InfluxDB line protocol for measurements, the one schema the receiver
+//! writes to `scd40_data`:
+//!
+//! ```text
+//! scd40_data,device=<device>[,fw_version=<version>][,injected=true][,location=<room>][,maintenance=true] co2_ppm=<co2>,temperature_c=<t>,humidity_percent=<h>[,battery_mv=<mv>][,battery_percent=<pct>][,first_after_boot=<bool>,sensor_warmup_incomplete=<bool>,retried_read=<bool>][ <ns>]
+//! ```
+//!
+//! The battery fields are only written for devices that report them,
+//! `fw_version` for firmware that sends its version, `location` for devices
+//! built with the room they are in, and `injected` for readings a debug
+//! firmware made up, see `fault_injection`. The quality
+//! flags are written together, for measurements that carry any, so
+//! training queries can filter on `retried_read = false` and the like.
+//!
+//! Without a timestamp InfluxDB stamps the point on arrival, which is what
+//! the live receiver relies on; exports and spools carry one in
+//! nanoseconds. Tag values escape `\`, `,`, `=` and spaces with a
+//! backslash. Numbers use Rust's shortest round-tripping `Display`, so a
+//! formatted line parses back to the same values.
+
+use crate::MeasurementFlags;
+
+pub const MEASUREMENT: &str = "scd40_data";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementFields {
+    pub co2: u16,
+    pub temperature: f32,
+    pub humidity: f32,
+    /// Taken during a maintenance window; only written when true
+    pub maintenance: bool,
+    /// Made up by an injected fault; only written when true
+    pub injected: bool,
+    pub battery_mv: Option<u16>,
+    pub battery_percent: Option<u8>,
+    /// The firmware that took the measurement, written as a tag
+    pub fw_version: Option<String>,
+    /// The room the device reported, written as a tag
+    pub location: Option<String>,
+    pub flags: Option<MeasurementFlags>,
+}
+
+/// One parsed line; `timestamp` is in nanoseconds since the epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementLine {
+    pub device: String,
+    pub fields: MeasurementFields,
+    pub timestamp: Option<i64>,
+}
+
+pub fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+pub fn measurement_to_line(
+    device: &str,
+    fields: &MeasurementFields,
+    timestamp: Option<i64>,
+) -> String {
+    let mut line = format!(
+        "{},device={}{}{}{}{} co2_ppm={},temperature_c={},humidity_percent={}",
+        MEASUREMENT,
+        escape_tag(device),
+        fields
+            .fw_version
+            .as_deref()
+            .map(|version| format!(",fw_version={}", escape_tag(version)))
+            .unwrap_or_default(),
+        if fields.injected {
+            ",injected=true"
+        } else {
+            ""
+        },
+        fields
+            .location
+            .as_deref()
+            .map(|location| format!(",location={}", escape_tag(location)))
+            .unwrap_or_default(),
+        if fields.maintenance {
+            ",maintenance=true"
+        } else {
+            ""
+        },
+        fields.co2,
+        fields.temperature,
+        fields.humidity
+    );
+    if let Some(battery_mv) = fields.battery_mv {
+        line.push_str(&format!(",battery_mv={}", battery_mv));
+    }
+    if let Some(battery_percent) = fields.battery_percent {
+        line.push_str(&format!(",battery_percent={}", battery_percent));
+    }
+    if let Some(flags) = fields.flags {
+        line.push_str(&format!(
+            ",first_after_boot={},sensor_warmup_incomplete={},retried_read={}",
+            flags.first_after_boot, flags.sensor_warmup_incomplete, flags.retried_read
+        ));
+    }
+    if let Some(timestamp) = timestamp {
+        line.push(' ');
+        line.push_str(&timestamp.to_string());
+    }
+    line
+}
+
+/// Splits on `separator` where it isn't escaped, keeping the escapes.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        // a trailing backslash stays as it is
+        out.push(if c == '\\' {
+            chars.next().unwrap_or(c)
+        } else {
+            c
+        });
+    }
+    out
+}
+
+fn number<T: core::str::FromStr>(value: &str) -> Result<T, &'static str> {
+    // InfluxDB's integer suffix, for lines written by other tools
+    value
+        .strip_suffix('i')
+        .unwrap_or(value)
+        .parse()
+        .map_err(|_| "invalid field value")
+}
+
+fn boolean(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "true" | "t" | "T" | "True" | "TRUE" => Ok(true),
+        "false" | "f" | "F" | "False" | "FALSE" => Ok(false),
+        _ => Err("invalid field value"),
+    }
+}
+
+/// Parses a line written by `measurement_to_line`. Unknown tags and
+/// fields are ignored; the three measurement fields are required, the
+/// battery fields, `fw_version`, `location` and the flags optional.
+pub fn line_to_measurement(line: &str) -> Result<MeasurementLine, &'static str> {
+    let sections = split_unescaped(line.trim_end_matches(['\n', '\r']), ' ');
+    let (series, field_set, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (
+            *series,
+            *fields,
+            Some(timestamp.parse().map_err(|_| "invalid timestamp")?),
+        ),
+        _ => return Err("expected series, fields and an optional timestamp"),
+    };
+
+    let mut tags = split_unescaped(series, ',').into_iter();
+    if tags.next() != Some(MEASUREMENT) {
+        return Err("not a scd40_data line");
+    }
+    let mut device = None;
+    let mut maintenance = false;
+    let mut injected = false;
+    let (mut fw_version, mut location) = (None, None);
+    for tag in tags {
+        let [key, value] = split_unescaped(tag, '=')[..] else {
+            return Err("invalid tag");
+        };
+        match key {
+            "device" => device = Some(unescape(value)),
+            "maintenance" => maintenance = value == "true",
+            "injected" => injected = value == "true",
+            "fw_version" => fw_version = Some(unescape(value)),
+            "location" => location = Some(unescape(value)),
+            _ => {}
+        }
+    }
+
+    let (mut co2, mut temperature, mut humidity) = (None, None, None);
+    let (mut battery_mv, mut battery_percent) = (None, None);
+    let mut flags: Option<MeasurementFlags> = None;
+    for field in split_unescaped(field_set, ',') {
+        let Some((key, value)) = field.split_once('=') else {
+            return Err("invalid field");
+        };
+        match key {
+            "co2_ppm" => co2 = Some(number(value)?),
+            "temperature_c" => temperature = Some(number(value)?),
+            "humidity_percent" => humidity = Some(number(value)?),
+            "battery_mv" => battery_mv = Some(number(value)?),
+            "battery_percent" => battery_percent = Some(number(value)?),
+            "first_after_boot" => {
+                flags.get_or_insert_default().first_after_boot = boolean(value)?
+            }
+            "sensor_warmup_incomplete" => {
+                flags.get_or_insert_default().sensor_warmup_incomplete = boolean(value)?
+            }
+            "retried_read" => flags.get_or_insert_default().retried_read = boolean(value)?,
+            _ => {}
+        }
+    }
+
+    Ok(MeasurementLine {
+        device: device.ok_or("missing device tag")?,
+        fields: MeasurementFields {
+            co2: co2.ok_or("missing co2_ppm")?,
+            temperature: temperature.ok_or("missing temperature_c")?,
+            humidity: humidity.ok_or("missing humidity_percent")?,
+            maintenance,
+            injected,
+            battery_mv,
+            battery_percent,
+            fw_version,
+            location,
+            flags,
+        },
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(maintenance: bool) -> MeasurementFields {
+        MeasurementFields {
+            co2: 612,
+            temperature: 22.4,
+            humidity: 41.3,
+            maintenance,
+            injected: false,
+            battery_mv: None,
+            battery_percent: None,
+            fw_version: None,
+            location: None,
+            flags: None,
+        }
+    }
+
+    #[test]
+    fn matches_what_the_receiver_writes() {
+        assert_eq!(
+            measurement_to_line("esp32-scd40", &fields(false), None),
+            "scd40_data,device=esp32-scd40 co2_ppm=612,temperature_c=22.4,humidity_percent=41.3"
+        );
+        assert_eq!(
+            measurement_to_line("esp32-scd40", &fields(true), None),
+            "scd40_data,device=esp32-scd40,maintenance=true co2_ppm=612,temperature_c=22.4,humidity_percent=41.3"
+        );
+        // As restored from the archive
+        assert_eq!(
+            measurement_to_line(
+                "kitchen",
+                &MeasurementFields {
+                    co2: 500,
+                    temperature: 21.5,
+                    humidity: 40.25,
+                    maintenance: false,
+                    injected: false,
+                    battery_mv: None,
+                    battery_percent: None,
+                    fw_version: None,
+                    location: None,
+                    flags: None,
+                },
+                Some(1738368480000000000)
+            ),
+            "scd40_data,device=kitchen co2_ppm=500,temperature_c=21.5,humidity_percent=40.25 1738368480000000000"
+        );
+    }
+
+    #[test]
+    fn battery_fields_follow_the_measurement() {
+        let with_battery = MeasurementFields {
+            battery_mv: Some(3870),
+            battery_percent: Some(72),
+            ..fields(false)
+        };
+        let line = measurement_to_line("esp32-scd40", &with_battery, None);
+        assert_eq!(
+            line,
+            "scd40_data,device=esp32-scd40 co2_ppm=612,temperature_c=22.4,humidity_percent=41.3,battery_mv=3870,battery_percent=72"
+        );
+        assert_eq!(line_to_measurement(&line).unwrap().fields, with_battery);
+        // Lines without them, and from before them, parse to None
+        let plain = measurement_to_line("esp32-scd40", &fields(false), None);
+        let parsed = line_to_measurement(&plain).unwrap();
+        assert_eq!(parsed.fields.battery_mv, None);
+        assert_eq!(parsed.fields.battery_percent, None);
+    }
+
+    #[test]
+    fn flags_are_written_together() {
+        let flagged = MeasurementFields {
+            flags: Some(MeasurementFlags {
+                retried_read: true,
+                ..Default::default()
+            }),
+            ..fields(false)
+        };
+        let line = measurement_to_line("esp32-scd40", &flagged, None);
+        assert_eq!(
+            line,
+            "scd40_data,device=esp32-scd40 co2_ppm=612,temperature_c=22.4,humidity_percent=41.3,\
+             first_after_boot=false,sensor_warmup_incomplete=false,retried_read=true"
+        );
+        assert_eq!(line_to_measurement(&line).unwrap().fields, flagged);
+        // Lines from before them parse to None
+        let plain = measurement_to_line("esp32-scd40", &fields(false), None);
+        assert_eq!(line_to_measurement(&plain).unwrap().fields.flags, None);
+        assert!(
+            line_to_measurement(
+                "scd40_data,device=a co2_ppm=1,temperature_c=2,humidity_percent=3,retried_read=maybe"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn fw_version_is_a_tag() {
+        let tagged = MeasurementFields {
+            fw_version: Some("0.4.0 rc".to_string()),
+            ..fields(true)
+        };
+        let line = measurement_to_line("esp32-scd40", &tagged, None);
+        assert_eq!(
+            line,
+            "scd40_data,device=esp32-scd40,fw_version=0.4.0\\ rc,maintenance=true co2_ppm=612,temperature_c=22.4,humidity_percent=41.3"
+        );
+        assert_eq!(line_to_measurement(&line).unwrap().fields, tagged);
+    }
+
+    #[test]
+    fn location_is_a_tag() {
+        let tagged = MeasurementFields {
+            fw_version: Some("0.4.0".to_string()),
+            location: Some("living room".to_string()),
+            ..fields(true)
+        };
+        let line = measurement_to_line("esp32-scd40", &tagged, None);
+        assert_eq!(
+            line,
+            "scd40_data,device=esp32-scd40,fw_version=0.4.0,location=living\\ room,maintenance=true co2_ppm=612,temperature_c=22.4,humidity_percent=41.3"
+        );
+        assert_eq!(line_to_measurement(&line).unwrap().fields, tagged);
+    }
+
+    #[test]
+    fn injected_readings_are_tagged() {
+        let injected = MeasurementFields {
+            injected: true,
+            ..fields(true)
+        };
+        let line = measurement_to_line("esp32-scd40", &injected, None);
+        assert_eq!(
+            line,
+            "scd40_data,device=esp32-scd40,injected=true,maintenance=true co2_ppm=612,temperature_c=22.4,humidity_percent=41.3"
+        );
+        assert_eq!(line_to_measurement(&line).unwrap().fields, injected);
+    }
+
+    #[test]
+    fn device_names_are_escaped() {
+        let line = measurement_to_line("living room,north=1", &fields(false), Some(0));
+        assert!(line.starts_with("scd40_data,device=living\\ room\\,north\\=1 co2_ppm=612"));
+        let parsed = line_to_measurement(&line).unwrap();
+        assert_eq!(parsed.device, "living room,north=1");
+        assert_eq!(parsed.timestamp, Some(0));
+    }
+
+    #[test]
+    fn parser_accepts_other_writers_and_rejects_garbage() {
+        let parsed = line_to_measurement(
+            "scd40_data,device=a,room=b co2_ppm=700i,temperature_c=20,humidity_percent=50,extra=1 5\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.fields.co2, 700);
+        assert_eq!(parsed.fields.temperature, 20.0);
+        assert_eq!(parsed.timestamp, Some(5));
+
+        assert!(line_to_measurement("anomalies,device=a co2_spike=true").is_err());
+        assert!(line_to_measurement("scd40_data,device=a co2_ppm=1,temperature_c=2").is_err());
+        assert!(
+            line_to_measurement("scd40_data co2_ppm=1,temperature_c=2,humidity_percent=3").is_err()
+        );
+        assert!(
+            line_to_measurement("scd40_data,device=a co2_ppm=x,temperature_c=2,humidity_percent=3")
+                .is_err()
+        );
+        assert!(
+            line_to_measurement(
+                "scd40_data,device=a co2_ppm=1,temperature_c=2,humidity_percent=3 soon"
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Firmware log verbosity, set at runtime with `set_log_level`.
+//!
+//! The levels are ESP-IDF's, minus `none`: a device that logs nothing can't
+//! explain a failure. The device stores the level in NVS in the text form
+//! of `LogLevel::as_str`.
+
+use core::fmt;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Ordered from least to most verbose
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 5] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Verbose,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogLevel::ALL
+            .into_iter()
+            .find(|level| level.as_str() == s)
+            .ok_or("expected error, warn, info, debug or verbose")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_form_round_trips() {
+        for level in LogLevel::ALL {
+            assert_eq!(level.as_str().parse(), Ok(level));
+            assert_eq!(
+                serde_json::to_string(&level).unwrap(),
+                format!("\"{}\"", level)
+            );
+        }
+        assert!("none".parse::<LogLevel>().is_err());
+        assert!("INFO".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn ordered_by_verbosity() {
+        assert!(LogLevel::Error < LogLevel::Warn);
+        assert!(LogLevel::Debug > LogLevel::default());
+        assert!(LogLevel::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,291 @@
+//! QoS and retain settings for device publishes, per class of payload.
+//!
+//! The firmware looks up every outgoing payload here. Defaults are compiled
+//! in and can be overridden at runtime with `set_mqtt_policy`, which the
+//! device stores in NVS in the text form parsed by `MqttPolicy::from_str`,
+//! e.g. `measurement=1+retain,diagnostic=0`.
+
+use core::fmt;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::DevicePayload;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadClass {
+    Measurement,
+    Error,
+    Calibration,
+    CommandResponse,
+    Diagnostic,
+}
+
+impl PayloadClass {
+    pub const ALL: [PayloadClass; 5] = [
+        PayloadClass::Measurement,
+        PayloadClass::Error,
+        PayloadClass::Calibration,
+        PayloadClass::CommandResponse,
+        PayloadClass::Diagnostic,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PayloadClass::Measurement => "measurement",
+            PayloadClass::Error => "error",
+            PayloadClass::Calibration => "calibration",
+            PayloadClass::CommandResponse => "command_response",
+            PayloadClass::Diagnostic => "diagnostic",
+        }
+    }
+}
+
+impl FromStr for PayloadClass {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PayloadClass::ALL
+            .into_iter()
+            .find(|class| class.as_str() == s)
+            .ok_or("unknown payload class")
+    }
+}
+
+impl DevicePayload {
+    pub fn class(&self) -> PayloadClass {
+        match self {
+            DevicePayload::MeasurementSuccess { .. } | DevicePayload::MeasurementBatch { .. } => {
+                PayloadClass::Measurement
+            }
+            DevicePayload::Error { .. } => PayloadClass::Error,
+            DevicePayload::FrcStart { .. }
+            | DevicePayload::FrcWarmupComplete { .. }
+            | DevicePayload::FrcCalibrating { .. }
+            | DevicePayload::FrcSuccess { .. }
+            | DevicePayload::FrcError { .. } => PayloadClass::Calibration,
+            DevicePayload::SetOffsetSuccess { .. }
+            | DevicePayload::SetOffsetRateLimited { .. }
+            | DevicePayload::PersistRateLimited { .. }
+            | DevicePayload::SetOffsetError { .. }
+            | DevicePayload::GetOffsetSuccess { .. }
+            | DevicePayload::GetOffsetError { .. }
+            | DevicePayload::SetDeepSleepTimeSuccess { .. }
+            | DevicePayload::SetDeepSleepTimeError { .. }
+            | DevicePayload::GetDeepSleepTimeSuccess { .. }
+            | DevicePayload::GetDeepSleepTimeError { .. }
+            | DevicePayload::SetMqttPolicySuccess { .. }
+            | DevicePayload::SetMqttPolicyError { .. }
+            | DevicePayload::CommandsDeferred { .. }
+            | DevicePayload::OtaProgress { .. }
+            | DevicePayload::OtaSuccess { .. }
+            | DevicePayload::OtaError { .. }
+            | DevicePayload::Config(_)
+            | DevicePayload::SetLogLevelSuccess { .. }
+            | DevicePayload::SetLogLevelError { .. }
+            | DevicePayload::GetLogLevelSuccess { .. }
+            | DevicePayload::SetAdaptiveModeSuccess { .. }
+            | DevicePayload::SetAdaptiveModeError { .. }
+            | DevicePayload::AscSetSuccess { .. }
+            | DevicePayload::AscGetSuccess { .. }
+            | DevicePayload::AscError { .. }
+            | DevicePayload::AltitudeSetSuccess { .. }
+            | DevicePayload::AltitudeGetSuccess { .. }
+            | DevicePayload::AltitudeError { .. }
+            | DevicePayload::AmbientPressureSetSuccess { .. }
+            | DevicePayload::AmbientPressureError { .. }
+            | DevicePayload::PendingConfirmation { .. }
+            | DevicePayload::ConfigRolledBack { .. }
+            | DevicePayload::ConfirmConfigError { .. }
+            | DevicePayload::SelfTestResult { .. }
+            | DevicePayload::FactoryResetSuccess
+            | DevicePayload::FactoryResetError { .. }
+            | DevicePayload::SerialNumber { .. }
+            | DevicePayload::Rebooting { .. }
+            | DevicePayload::FirmwareInfo { .. }
+            | DevicePayload::FaultArmed { .. }
+            | DevicePayload::CommandAck { .. } => PayloadClass::CommandResponse,
+            DevicePayload::Alive { .. }
+            | DevicePayload::WakeProfile { .. }
+            | DevicePayload::LogLines { .. }
+            | DevicePayload::NextWake { .. }
+            | DevicePayload::Diagnostics { .. }
+            | DevicePayload::RadioSkipped { .. }
+            | DevicePayload::EnteringSleep { .. } => PayloadClass::Diagnostic,
+            DevicePayload::BusRecovery { recovered, .. } => {
+                if *recovered {
+                    PayloadClass::Diagnostic
+                } else {
+                    PayloadClass::Error
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishPolicy {
+    /// MQTT QoS level, 0-2
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl PublishPolicy {
+    pub const fn new(qos: u8, retain: bool) -> Self {
+        Self { qos, retain }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MqttPolicy {
+    pub measurement: PublishPolicy,
+    pub error: PublishPolicy,
+    pub calibration: PublishPolicy,
+    pub command_response: PublishPolicy,
+    pub diagnostic: PublishPolicy,
+}
+
+impl MqttPolicy {
+    /// The latest measurement is retained so new subscribers see it right
+    /// away; diagnostics are fire-and-forget.
+    pub const DEFAULT: MqttPolicy = MqttPolicy {
+        measurement: PublishPolicy::new(1, true),
+        error: PublishPolicy::new(1, false),
+        calibration: PublishPolicy::new(1, false),
+        command_response: PublishPolicy::new(1, false),
+        diagnostic: PublishPolicy::new(0, false),
+    };
+
+    pub fn get(&self, class: PayloadClass) -> PublishPolicy {
+        match class {
+            PayloadClass::Measurement => self.measurement,
+            PayloadClass::Error => self.error,
+            PayloadClass::Calibration => self.calibration,
+            PayloadClass::CommandResponse => self.command_response,
+            PayloadClass::Diagnostic => self.diagnostic,
+        }
+    }
+
+    pub fn set(&mut self, class: PayloadClass, policy: PublishPolicy) {
+        let slot = match class {
+            PayloadClass::Measurement => &mut self.measurement,
+            PayloadClass::Error => &mut self.error,
+            PayloadClass::Calibration => &mut self.calibration,
+            PayloadClass::CommandResponse => &mut self.command_response,
+            PayloadClass::Diagnostic => &mut self.diagnostic,
+        };
+        *slot = policy;
+    }
+
+    pub fn for_payload(&self, payload: &DevicePayload) -> PublishPolicy {
+        self.get(payload.class())
+    }
+
+    /// Applies `class=qos[+retain]` pairs on top of `self`.
+    pub fn with_overrides(mut self, s: &str) -> Result<Self, &'static str> {
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (class, value) = pair.split_once('=').ok_or("expected class=qos")?;
+            let class: PayloadClass = class.trim().parse()?;
+            let (qos, retain) = match value.trim().split_once('+') {
+                Some((qos, "retain")) => (qos, true),
+                Some(_) => return Err("only +retain may follow the QoS"),
+                None => (value.trim(), false),
+            };
+            let qos: u8 = qos.parse().map_err(|_| "invalid QoS")?;
+            if qos > 2 {
+                return Err("QoS must be 0, 1 or 2");
+            }
+            self.set(class, PublishPolicy::new(qos, retain));
+        }
+        Ok(self)
+    }
+}
+
+impl Default for MqttPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl FromStr for MqttPolicy {
+    type Err = &'static str;
+
+    /// Unlisted classes keep their default.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::DEFAULT.with_overrides(s)
+    }
+}
+
+impl fmt::Display for MqttPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, class) in PayloadClass::ALL.into_iter().enumerate() {
+            let policy = self.get(class);
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", class.as_str(), policy.qos)?;
+            if policy.retain {
+                f.write_str("+retain")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_table() {
+        let policy = MqttPolicy::default();
+        let lookup = |p: DevicePayload| policy.for_payload(&p);
+        assert_eq!(
+            lookup(DevicePayload::try_measurement(600, 21.0, 40.0).unwrap()),
+            PublishPolicy::new(1, true)
+        );
+        assert_eq!(
+            lookup(DevicePayload::error("timeout")),
+            PublishPolicy::new(1, false)
+        );
+        assert_eq!(
+            lookup(DevicePayload::frc_start(422)),
+            PublishPolicy::new(1, false)
+        );
+        assert_eq!(
+            lookup(DevicePayload::GetOffsetSuccess { offset: 4.0 }),
+            PublishPolicy::new(1, false)
+        );
+        assert_eq!(
+            lookup(DevicePayload::Alive { uptime_seconds: 5 }),
+            PublishPolicy::new(0, false)
+        );
+    }
+
+    #[test]
+    fn overrides_apply_on_top_of_defaults() {
+        let policy: MqttPolicy = "measurement=0, diagnostic=1+retain".parse().unwrap();
+        assert_eq!(policy.measurement, PublishPolicy::new(0, false));
+        assert_eq!(policy.diagnostic, PublishPolicy::new(1, true));
+        assert_eq!(policy.error, MqttPolicy::DEFAULT.error);
+
+        assert!("bogus=1".parse::<MqttPolicy>().is_err());
+        assert!("error=3".parse::<MqttPolicy>().is_err());
+        assert!("error=1+sticky".parse::<MqttPolicy>().is_err());
+        assert!("error".parse::<MqttPolicy>().is_err());
+    }
+
+    #[test]
+    fn text_form_roundtrips() {
+        let text = MqttPolicy::DEFAULT.to_string();
+        assert_eq!(
+            text,
+            "measurement=1+retain,error=1,calibration=1,command_response=1,diagnostic=0"
+        );
+        let mut custom = MqttPolicy::DEFAULT;
+        custom.set(PayloadClass::Calibration, PublishPolicy::new(2, true));
+        assert_eq!(custom.to_string().parse::<MqttPolicy>(), Ok(custom));
+    }
+}
@@ -0,0 +1,1403 @@
+//! Mirrors of the message types for postcard, the binary encoding.
+//!
+//! Postcard can't encode what the JSON form relies on: a flattened payload,
+//! internally tagged enums and fields that are left out when they hold their
+//! default. These mirrors carry the same data as plain structs and
+//! externally tagged enums, and convert to and from the real types.
+//!
+//! Postcard identifies a variant by its position and a field by its order,
+//! so variants are only ever appended and fields never reordered. A field
+//! added later goes into a new variant instead, used only when it is set, so
+//! readers that don't know it still decode everything else. A message's
+//! `redelivered` and `injected` markers, `fw_version` and `location` wrap
+//! its payload, in `Payload::Redelivered`, `Payload::Injected`,
+//! `Payload::FromFirmware` and `Payload::Located`, for the same reason.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::device_config::{DeviceConfig, SensorMode};
+use crate::fault_injection::FaultKind;
+use crate::log_level::LogLevel;
+use crate::mqtt_policy::PayloadClass;
+use crate::units::{MeasuredCo2, MeasuredHumidity, MeasuredTemperature};
+use crate::{
+    BatchedReading, Detail, DeviceCommand, DeviceCommandBatch, DeviceMessage, DeviceName,
+    DevicePayload, ErrorCode, Label, MeasurementFlags,
+};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Message<'a> {
+    device: Cow<'a, DeviceName>,
+    payload: Nested<'a>,
+    ts: Option<u64>,
+    seq: Option<u32>,
+    version: u8,
+    in_reply_to: Option<u32>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum Payload<'a> {
+    MeasurementSuccess {
+        co2: MeasuredCo2,
+        temperature: MeasuredTemperature,
+        humidity: MeasuredHumidity,
+    },
+    Error {
+        detail: Cow<'a, Detail>,
+    },
+    FrcStart {
+        target_ppm: u16,
+    },
+    FrcWarmupComplete {
+        detail: Cow<'a, Detail>,
+    },
+    FrcCalibrating {
+        target_ppm: u16,
+    },
+    FrcSuccess {
+        correction: u16,
+    },
+    FrcError {
+        detail: Cow<'a, Detail>,
+    },
+    SetOffsetSuccess {
+        offset: f32,
+        persisted: bool,
+    },
+    SetOffsetRateLimited {
+        offset: f32,
+        limit: u16,
+        retry_after_seconds: u64,
+    },
+    SetOffsetError {
+        detail: Cow<'a, Detail>,
+    },
+    GetOffsetSuccess {
+        offset: f32,
+    },
+    SetDeepSleepTimeSuccess {
+        seconds: u64,
+    },
+    SetDeepSleepTimeError {
+        detail: Cow<'a, Detail>,
+    },
+    GetDeepSleepTimeSuccess {
+        seconds: u64,
+    },
+    GetDeepSleepTimeError {
+        detail: Cow<'a, Detail>,
+    },
+    GetOffsetError {
+        detail: Cow<'a, Detail>,
+    },
+    Alive {
+        uptime_seconds: u64,
+    },
+    SetMqttPolicySuccess {
+        class: PayloadClass,
+        qos: u8,
+        retain: bool,
+    },
+    SetMqttPolicyError {
+        detail: Cow<'a, Detail>,
+    },
+    CommandsDeferred {
+        running: Cow<'a, str>,
+        deferred: Commands<'a>,
+    },
+    BusRecovery {
+        attempt: u8,
+        pulses: Option<u8>,
+        recovered: bool,
+    },
+    OtaProgress {
+        percent: u8,
+    },
+    OtaSuccess {
+        version: Cow<'a, str>,
+    },
+    OtaError {
+        detail: Cow<'a, Detail>,
+    },
+    WakeProfile {
+        awake_ms: u32,
+        sensor_ms: u32,
+        network_ms: u32,
+        saved_ms: u32,
+    },
+    Config(Config<'a>),
+    SetLogLevelSuccess {
+        level: LogLevel,
+    },
+    SetLogLevelError {
+        detail: Cow<'a, Detail>,
+    },
+    GetLogLevelSuccess {
+        level: LogLevel,
+    },
+    LogLines {
+        lines: Cow<'a, [String]>,
+    },
+    SetAdaptiveModeSuccess {
+        enabled: bool,
+    },
+    SetAdaptiveModeError {
+        detail: Cow<'a, Detail>,
+    },
+    NextWake {
+        sleep_seconds: u64,
+        adaptive: bool,
+        delta_ppm: Option<u16>,
+    },
+    /// `MeasurementSuccess` with battery fields
+    MeasurementWithBattery {
+        co2: MeasuredCo2,
+        temperature: MeasuredTemperature,
+        humidity: MeasuredHumidity,
+        battery_mv: Option<u16>,
+        battery_percent: Option<u8>,
+    },
+    Diagnostics {
+        rssi_dbm: i8,
+        free_heap_bytes: u32,
+        boot_count: u32,
+        reset_reason: Cow<'a, Label>,
+    },
+    AscSetSuccess {
+        enabled: bool,
+    },
+    AscGetSuccess {
+        enabled: bool,
+    },
+    AscError {
+        detail: Cow<'a, Detail>,
+    },
+    AltitudeSetSuccess {
+        meters: u16,
+    },
+    AltitudeGetSuccess {
+        meters: u16,
+    },
+    AltitudeError {
+        detail: Cow<'a, Detail>,
+    },
+    AmbientPressureSetSuccess {
+        pascals: u32,
+    },
+    AmbientPressureError {
+        detail: Cow<'a, Detail>,
+    },
+    PendingConfirmation {
+        id: u32,
+        command: Cow<'a, str>,
+        wakes_left: u8,
+    },
+    ConfigRolledBack {
+        id: u32,
+        command: Cow<'a, str>,
+    },
+    ConfirmConfigError {
+        detail: Cow<'a, Detail>,
+    },
+    SelfTestResult {
+        passed: bool,
+        detail: Cow<'a, Detail>,
+    },
+    FactoryResetSuccess,
+    FactoryResetError {
+        detail: Cow<'a, Detail>,
+    },
+    SerialNumber {
+        serial: u64,
+    },
+    Rebooting {
+        detail: Cow<'a, Detail>,
+    },
+    Redelivered(Nested<'a>),
+    FirmwareInfo {
+        version: Cow<'a, str>,
+        build_time: Cow<'a, str>,
+        idf_version: Cow<'a, str>,
+    },
+    FromFirmware {
+        fw_version: Cow<'a, Label>,
+        payload: Nested<'a>,
+    },
+    MeasurementBatch {
+        readings: Cow<'a, [BatchedReading]>,
+    },
+    RadioSkipped {
+        skipped_wakes: u32,
+        lowest_mv: u16,
+        threshold_mv: u16,
+    },
+    FaultArmed {
+        kind: FaultKind,
+    },
+    Injected(Nested<'a>),
+    CodedError {
+        code: ErrorCode,
+        detail: Cow<'a, Detail>,
+    },
+    CodedFrcError {
+        code: ErrorCode,
+        detail: Cow<'a, Detail>,
+    },
+    EnteringSleep {
+        sleep_seconds: u64,
+        next_wake_unix: Option<u64>,
+    },
+    /// `MeasurementSuccess` with quality flags
+    MeasurementWithFlags {
+        co2: MeasuredCo2,
+        temperature: MeasuredTemperature,
+        humidity: MeasuredHumidity,
+        battery_mv: Option<u16>,
+        battery_percent: Option<u8>,
+        flags: MeasurementFlags,
+    },
+    CommandAck {
+        cmd: Cow<'a, str>,
+        accepted: bool,
+        detail: Option<Cow<'a, Detail>>,
+    },
+    Located {
+        location: Cow<'a, Label>,
+        payload: Nested<'a>,
+    },
+    /// The `code` of an error payload other than `Error` and `FrcError`,
+    /// which have coded variants of their own
+    Coded {
+        code: ErrorCode,
+        payload: Nested<'a>,
+    },
+    PersistRateLimited {
+        command: Cow<'a, str>,
+        limit: u16,
+        retry_after_seconds: u64,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Command<'a> {
+    NoOp,
+    StartFrc {
+        target_ppm: u16,
+    },
+    SetTempOffset {
+        offset: f32,
+        persist: bool,
+    },
+    GetTempOffset,
+    SetDeepSleepTime {
+        seconds: u64,
+    },
+    GetDeepSleepTime,
+    SetMqttPolicy {
+        class: PayloadClass,
+        qos: u8,
+        retain: bool,
+    },
+    Ota {
+        url: Cow<'a, str>,
+    },
+    GetConfig,
+    /// A `DeviceCommandBatch`, never one of its commands
+    Batch {
+        commands: Commands<'a>,
+        deferred: bool,
+    },
+    SetLogLevel {
+        level: LogLevel,
+    },
+    GetLogLevel,
+    SetAdaptiveMode {
+        enabled: bool,
+    },
+    SetAsc {
+        enabled: bool,
+    },
+    GetAsc,
+    SetAltitude {
+        meters: u16,
+    },
+    GetAltitude,
+    SetAmbientPressure {
+        pascals: u32,
+    },
+    ConfirmConfig {
+        pending_id: u32,
+    },
+    SelfTest,
+    FactoryReset {
+        confirm: Cow<'a, str>,
+    },
+    GetSerialNumber,
+    Reboot,
+    GetFirmwareInfo,
+    InjectFault {
+        kind: FaultKind,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Config<'a> {
+    firmware_version: Cow<'a, str>,
+    sleep_seconds: u64,
+    quiet_hours: Option<Cow<'a, str>>,
+    utc_offset_hours: i8,
+    sensor_mode: SensorMode,
+    temperature_offset: Option<f32>,
+    altitude_m: Option<u16>,
+    ambient_pressure_hpa: Option<u16>,
+    asc_enabled: Option<bool>,
+    alarm_threshold_ppm: Option<u16>,
+    mqtt_policy: Cow<'a, str>,
+    wifi_ssid: Cow<'a, str>,
+    safe_mode: bool,
+}
+
+/// The payload inside `Message` and the variants that wrap one: borrowed
+/// while a message is encoded, boxed once one is decoded.
+#[derive(Clone)]
+enum Nested<'a> {
+    Borrowed(&'a Payload<'a>),
+    Boxed(Box<Payload<'a>>),
+}
+
+impl<'a> Nested<'a> {
+    fn into_payload(self) -> Payload<'a> {
+        match self {
+            Nested::Borrowed(payload) => payload.clone(),
+            Nested::Boxed(payload) => *payload,
+        }
+    }
+}
+
+impl Serialize for Nested<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Nested::Borrowed(payload) => payload.serialize(serializer),
+            Nested::Boxed(payload) => payload.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Nested<'_> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Box::deserialize(deserializer).map(Nested::Boxed)
+    }
+}
+
+/// A list of commands: mirrored one at a time from the real ones while
+/// encoding, collected once decoded.
+#[derive(Clone)]
+pub(crate) enum Commands<'a> {
+    Borrowed(&'a [DeviceCommand]),
+    Decoded(Vec<Command<'a>>),
+}
+
+impl<'a> Commands<'a> {
+    fn into_commands(self) -> Vec<Command<'a>> {
+        match self {
+            Commands::Borrowed(commands) => commands.iter().map(Command::from).collect(),
+            Commands::Decoded(commands) => commands,
+        }
+    }
+}
+
+impl Serialize for Commands<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Commands::Borrowed(commands) => {
+                serializer.collect_seq(commands.iter().map(Command::from))
+            }
+            Commands::Decoded(commands) => commands.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Commands<'_> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Commands::Decoded)
+    }
+}
+
+/// Encodes `message` into `buf`. Every layer borrows from `message` or
+/// from the layer inside it, so nothing is copied or allocated.
+pub(crate) fn encode<'b>(
+    message: &DeviceMessage,
+    buf: &'b mut [u8],
+) -> postcard::Result<&'b mut [u8]> {
+    let base = Payload::from(&message.payload);
+    let mut payload = &base;
+    let coded;
+    if let Some(code) = wrapping_code(&message.payload) {
+        coded = Payload::Coded {
+            code,
+            payload: Nested::Borrowed(payload),
+        };
+        payload = &coded;
+    }
+    let from_firmware;
+    if let Some(fw_version) = &message.fw_version {
+        from_firmware = Payload::FromFirmware {
+            fw_version: Cow::Borrowed(fw_version),
+            payload: Nested::Borrowed(payload),
+        };
+        payload = &from_firmware;
+    }
+    let located;
+    if let Some(location) = &message.location {
+        located = Payload::Located {
+            location: Cow::Borrowed(location),
+            payload: Nested::Borrowed(payload),
+        };
+        payload = &located;
+    }
+    let injected;
+    if message.injected {
+        injected = Payload::Injected(Nested::Borrowed(payload));
+        payload = &injected;
+    }
+    let redelivered;
+    if message.redelivered {
+        redelivered = Payload::Redelivered(Nested::Borrowed(payload));
+        payload = &redelivered;
+    }
+    let wire = Message {
+        device: Cow::Borrowed(&message.device),
+        payload: Nested::Borrowed(payload),
+        ts: message.ts,
+        seq: message.seq,
+        version: message.version,
+        in_reply_to: message.in_reply_to,
+    };
+    postcard::to_slice(&wire, buf)
+}
+
+impl From<Message<'_>> for DeviceMessage {
+    fn from(message: Message<'_>) -> Self {
+        let (mut payload, mut redelivered, mut fw_version) =
+            (message.payload.into_payload(), false, None);
+        let (mut injected, mut location) = (false, None);
+        loop {
+            match payload {
+                Payload::Redelivered(inner) => {
+                    redelivered = true;
+                    payload = inner.into_payload();
+                }
+                Payload::Injected(inner) => {
+                    injected = true;
+                    payload = inner.into_payload();
+                }
+                Payload::FromFirmware {
+                    fw_version: version,
+                    payload: inner,
+                } => {
+                    fw_version = Some(version.into_owned());
+                    payload = inner.into_payload();
+                }
+                Payload::Located {
+                    location: room,
+                    payload: inner,
+                } => {
+                    location = Some(room.into_owned());
+                    payload = inner.into_payload();
+                }
+                _ => break,
+            }
+        }
+        DeviceMessage {
+            device: message.device.into_owned(),
+            payload: payload.into(),
+            ts: message.ts,
+            seq: message.seq,
+            version: message.version,
+            in_reply_to: message.in_reply_to,
+            redelivered,
+            fw_version,
+            location,
+            injected,
+        }
+    }
+}
+
+impl<'a> From<&'a DevicePayload> for Payload<'a> {
+    fn from(payload: &'a DevicePayload) -> Self {
+        match payload {
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv: None,
+                battery_percent: None,
+                flags: None,
+            } => Payload::MeasurementSuccess {
+                co2: *co2,
+                temperature: *temperature,
+                humidity: *humidity,
+            },
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags: None,
+            } => Payload::MeasurementWithBattery {
+                co2: *co2,
+                temperature: *temperature,
+                humidity: *humidity,
+                battery_mv: *battery_mv,
+                battery_percent: *battery_percent,
+            },
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags: Some(flags),
+            } => Payload::MeasurementWithFlags {
+                co2: *co2,
+                temperature: *temperature,
+                humidity: *humidity,
+                battery_mv: *battery_mv,
+                battery_percent: *battery_percent,
+                flags: *flags,
+            },
+            DevicePayload::Error {
+                code: ErrorCode::Other,
+                detail,
+            } => Payload::Error {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::Error { code, detail } => Payload::CodedError {
+                code: *code,
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::FrcStart { target_ppm } => Payload::FrcStart {
+                target_ppm: *target_ppm,
+            },
+            DevicePayload::FrcWarmupComplete { detail } => Payload::FrcWarmupComplete {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::FrcCalibrating { target_ppm } => Payload::FrcCalibrating {
+                target_ppm: *target_ppm,
+            },
+            DevicePayload::FrcSuccess { correction } => Payload::FrcSuccess {
+                correction: *correction,
+            },
+            DevicePayload::FrcError {
+                code: ErrorCode::Other,
+                detail,
+            } => Payload::FrcError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::FrcError { code, detail } => Payload::CodedFrcError {
+                code: *code,
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::SetOffsetSuccess { offset, persisted } => Payload::SetOffsetSuccess {
+                offset: *offset,
+                persisted: *persisted,
+            },
+            DevicePayload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            } => Payload::SetOffsetRateLimited {
+                offset: *offset,
+                limit: *limit,
+                retry_after_seconds: *retry_after_seconds,
+            },
+            DevicePayload::SetOffsetError { detail, .. } => Payload::SetOffsetError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::GetOffsetSuccess { offset } => {
+                Payload::GetOffsetSuccess { offset: *offset }
+            }
+            DevicePayload::SetDeepSleepTimeSuccess { seconds } => {
+                Payload::SetDeepSleepTimeSuccess { seconds: *seconds }
+            }
+            DevicePayload::SetDeepSleepTimeError { detail, .. } => Payload::SetDeepSleepTimeError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::GetDeepSleepTimeSuccess { seconds } => {
+                Payload::GetDeepSleepTimeSuccess { seconds: *seconds }
+            }
+            DevicePayload::GetDeepSleepTimeError { detail, .. } => Payload::GetDeepSleepTimeError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::GetOffsetError { detail, .. } => Payload::GetOffsetError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::Alive { uptime_seconds } => Payload::Alive {
+                uptime_seconds: *uptime_seconds,
+            },
+            DevicePayload::SetMqttPolicySuccess { class, qos, retain } => {
+                Payload::SetMqttPolicySuccess {
+                    class: *class,
+                    qos: *qos,
+                    retain: *retain,
+                }
+            }
+            DevicePayload::SetMqttPolicyError { detail, .. } => Payload::SetMqttPolicyError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::CommandsDeferred { running, deferred } => Payload::CommandsDeferred {
+                running: Cow::Borrowed(running),
+                deferred: Commands::Borrowed(deferred),
+            },
+            DevicePayload::BusRecovery {
+                attempt,
+                pulses,
+                recovered,
+            } => Payload::BusRecovery {
+                attempt: *attempt,
+                pulses: *pulses,
+                recovered: *recovered,
+            },
+            DevicePayload::OtaProgress { percent } => Payload::OtaProgress { percent: *percent },
+            DevicePayload::OtaSuccess { version } => Payload::OtaSuccess {
+                version: Cow::Borrowed(version),
+            },
+            DevicePayload::OtaError { detail, .. } => Payload::OtaError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::WakeProfile {
+                awake_ms,
+                sensor_ms,
+                network_ms,
+                saved_ms,
+            } => Payload::WakeProfile {
+                awake_ms: *awake_ms,
+                sensor_ms: *sensor_ms,
+                network_ms: *network_ms,
+                saved_ms: *saved_ms,
+            },
+            DevicePayload::Config(config) => Payload::Config(config.into()),
+            DevicePayload::SetLogLevelSuccess { level } => {
+                Payload::SetLogLevelSuccess { level: *level }
+            }
+            DevicePayload::SetLogLevelError { detail, .. } => Payload::SetLogLevelError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::GetLogLevelSuccess { level } => {
+                Payload::GetLogLevelSuccess { level: *level }
+            }
+            DevicePayload::LogLines { lines } => Payload::LogLines {
+                lines: Cow::Borrowed(lines),
+            },
+            DevicePayload::SetAdaptiveModeSuccess { enabled } => {
+                Payload::SetAdaptiveModeSuccess { enabled: *enabled }
+            }
+            DevicePayload::SetAdaptiveModeError { detail, .. } => Payload::SetAdaptiveModeError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::NextWake {
+                sleep_seconds,
+                adaptive,
+                delta_ppm,
+            } => Payload::NextWake {
+                sleep_seconds: *sleep_seconds,
+                adaptive: *adaptive,
+                delta_ppm: *delta_ppm,
+            },
+            DevicePayload::Diagnostics {
+                rssi_dbm,
+                free_heap_bytes,
+                boot_count,
+                reset_reason,
+            } => Payload::Diagnostics {
+                rssi_dbm: *rssi_dbm,
+                free_heap_bytes: *free_heap_bytes,
+                boot_count: *boot_count,
+                reset_reason: Cow::Borrowed(reset_reason),
+            },
+            DevicePayload::AscSetSuccess { enabled } => {
+                Payload::AscSetSuccess { enabled: *enabled }
+            }
+            DevicePayload::AscGetSuccess { enabled } => {
+                Payload::AscGetSuccess { enabled: *enabled }
+            }
+            DevicePayload::AscError { detail, .. } => Payload::AscError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::AltitudeSetSuccess { meters } => {
+                Payload::AltitudeSetSuccess { meters: *meters }
+            }
+            DevicePayload::AltitudeGetSuccess { meters } => {
+                Payload::AltitudeGetSuccess { meters: *meters }
+            }
+            DevicePayload::AltitudeError { detail, .. } => Payload::AltitudeError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::AmbientPressureSetSuccess { pascals } => {
+                Payload::AmbientPressureSetSuccess { pascals: *pascals }
+            }
+            DevicePayload::AmbientPressureError { detail, .. } => Payload::AmbientPressureError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::PendingConfirmation {
+                id,
+                command,
+                wakes_left,
+            } => Payload::PendingConfirmation {
+                id: *id,
+                command: Cow::Borrowed(command),
+                wakes_left: *wakes_left,
+            },
+            DevicePayload::ConfigRolledBack { id, command } => Payload::ConfigRolledBack {
+                id: *id,
+                command: Cow::Borrowed(command),
+            },
+            DevicePayload::ConfirmConfigError { detail, .. } => Payload::ConfirmConfigError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::SelfTestResult { passed, detail } => Payload::SelfTestResult {
+                passed: *passed,
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::FactoryResetSuccess => Payload::FactoryResetSuccess,
+            DevicePayload::FactoryResetError { detail, .. } => Payload::FactoryResetError {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::SerialNumber { serial } => Payload::SerialNumber { serial: *serial },
+            DevicePayload::Rebooting { detail } => Payload::Rebooting {
+                detail: Cow::Borrowed(detail),
+            },
+            DevicePayload::FirmwareInfo {
+                version,
+                build_time,
+                idf_version,
+            } => Payload::FirmwareInfo {
+                version: Cow::Borrowed(version),
+                build_time: Cow::Borrowed(build_time),
+                idf_version: Cow::Borrowed(idf_version),
+            },
+            DevicePayload::MeasurementBatch { readings } => Payload::MeasurementBatch {
+                readings: Cow::Borrowed(readings),
+            },
+            DevicePayload::RadioSkipped {
+                skipped_wakes,
+                lowest_mv,
+                threshold_mv,
+            } => Payload::RadioSkipped {
+                skipped_wakes: *skipped_wakes,
+                lowest_mv: *lowest_mv,
+                threshold_mv: *threshold_mv,
+            },
+            DevicePayload::FaultArmed { kind } => Payload::FaultArmed { kind: *kind },
+            DevicePayload::EnteringSleep {
+                sleep_seconds,
+                next_wake_unix,
+            } => Payload::EnteringSleep {
+                sleep_seconds: *sleep_seconds,
+                next_wake_unix: *next_wake_unix,
+            },
+            DevicePayload::CommandAck {
+                cmd,
+                accepted,
+                detail,
+            } => Payload::CommandAck {
+                cmd: Cow::Borrowed(cmd),
+                accepted: *accepted,
+                detail: detail.as_ref().map(Cow::Borrowed),
+            },
+            DevicePayload::PersistRateLimited {
+                command,
+                limit,
+                retry_after_seconds,
+            } => Payload::PersistRateLimited {
+                command: Cow::Borrowed(command),
+                limit: *limit,
+                retry_after_seconds: *retry_after_seconds,
+            },
+        }
+    }
+}
+
+impl From<Payload<'_>> for DevicePayload {
+    fn from(payload: Payload<'_>) -> Self {
+        match payload {
+            Payload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+            } => DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv: None,
+                battery_percent: None,
+                flags: None,
+            },
+            Payload::Error { detail } => DevicePayload::Error {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::CodedError { code, detail } => DevicePayload::Error {
+                code,
+                detail: detail.into_owned(),
+            },
+            Payload::FrcStart { target_ppm } => DevicePayload::FrcStart { target_ppm },
+            Payload::FrcWarmupComplete { detail } => DevicePayload::FrcWarmupComplete {
+                detail: detail.into_owned(),
+            },
+            Payload::FrcCalibrating { target_ppm } => DevicePayload::FrcCalibrating { target_ppm },
+            Payload::FrcSuccess { correction } => DevicePayload::FrcSuccess { correction },
+            Payload::FrcError { detail } => DevicePayload::FrcError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::CodedFrcError { code, detail } => DevicePayload::FrcError {
+                code,
+                detail: detail.into_owned(),
+            },
+            Payload::SetOffsetSuccess { offset, persisted } => {
+                DevicePayload::SetOffsetSuccess { offset, persisted }
+            }
+            Payload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            } => DevicePayload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            },
+            Payload::SetOffsetError { detail } => DevicePayload::SetOffsetError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::GetOffsetSuccess { offset } => DevicePayload::GetOffsetSuccess { offset },
+            Payload::SetDeepSleepTimeSuccess { seconds } => {
+                DevicePayload::SetDeepSleepTimeSuccess { seconds }
+            }
+            Payload::SetDeepSleepTimeError { detail } => DevicePayload::SetDeepSleepTimeError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::GetDeepSleepTimeSuccess { seconds } => {
+                DevicePayload::GetDeepSleepTimeSuccess { seconds }
+            }
+            Payload::GetDeepSleepTimeError { detail } => DevicePayload::GetDeepSleepTimeError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::GetOffsetError { detail } => DevicePayload::GetOffsetError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::Alive { uptime_seconds } => DevicePayload::Alive { uptime_seconds },
+            Payload::SetMqttPolicySuccess { class, qos, retain } => {
+                DevicePayload::SetMqttPolicySuccess { class, qos, retain }
+            }
+            Payload::SetMqttPolicyError { detail } => DevicePayload::SetMqttPolicyError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::CommandsDeferred { running, deferred } => DevicePayload::CommandsDeferred {
+                running: running.into_owned(),
+                // A batch is never deferred inside another; skip one sent anyway
+                deferred: deferred
+                    .into_commands()
+                    .into_iter()
+                    .filter_map(|command| DeviceCommand::try_from(command).ok())
+                    .collect(),
+            },
+            Payload::BusRecovery {
+                attempt,
+                pulses,
+                recovered,
+            } => DevicePayload::BusRecovery {
+                attempt,
+                pulses,
+                recovered,
+            },
+            Payload::OtaProgress { percent } => DevicePayload::OtaProgress { percent },
+            Payload::OtaSuccess { version } => DevicePayload::OtaSuccess {
+                version: version.into_owned(),
+            },
+            Payload::OtaError { detail } => DevicePayload::OtaError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::WakeProfile {
+                awake_ms,
+                sensor_ms,
+                network_ms,
+                saved_ms,
+            } => DevicePayload::WakeProfile {
+                awake_ms,
+                sensor_ms,
+                network_ms,
+                saved_ms,
+            },
+            Payload::Config(config) => DevicePayload::Config(config.into()),
+            Payload::SetLogLevelSuccess { level } => DevicePayload::SetLogLevelSuccess { level },
+            Payload::SetLogLevelError { detail } => DevicePayload::SetLogLevelError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::GetLogLevelSuccess { level } => DevicePayload::GetLogLevelSuccess { level },
+            Payload::LogLines { lines } => DevicePayload::LogLines {
+                lines: lines.into_owned(),
+            },
+            Payload::SetAdaptiveModeSuccess { enabled } => {
+                DevicePayload::SetAdaptiveModeSuccess { enabled }
+            }
+            Payload::SetAdaptiveModeError { detail } => DevicePayload::SetAdaptiveModeError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::NextWake {
+                sleep_seconds,
+                adaptive,
+                delta_ppm,
+            } => DevicePayload::NextWake {
+                sleep_seconds,
+                adaptive,
+                delta_ppm,
+            },
+            Payload::MeasurementWithBattery {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+            } => DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags: None,
+            },
+            Payload::Diagnostics {
+                rssi_dbm,
+                free_heap_bytes,
+                boot_count,
+                reset_reason,
+            } => DevicePayload::Diagnostics {
+                rssi_dbm,
+                free_heap_bytes,
+                boot_count,
+                reset_reason: reset_reason.into_owned(),
+            },
+            Payload::AscSetSuccess { enabled } => DevicePayload::AscSetSuccess { enabled },
+            Payload::AscGetSuccess { enabled } => DevicePayload::AscGetSuccess { enabled },
+            Payload::AscError { detail } => DevicePayload::AscError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::AltitudeSetSuccess { meters } => DevicePayload::AltitudeSetSuccess { meters },
+            Payload::AltitudeGetSuccess { meters } => DevicePayload::AltitudeGetSuccess { meters },
+            Payload::AltitudeError { detail } => DevicePayload::AltitudeError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::AmbientPressureSetSuccess { pascals } => {
+                DevicePayload::AmbientPressureSetSuccess { pascals }
+            }
+            Payload::AmbientPressureError { detail } => DevicePayload::AmbientPressureError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::PendingConfirmation {
+                id,
+                command,
+                wakes_left,
+            } => DevicePayload::PendingConfirmation {
+                id,
+                command: command.into_owned(),
+                wakes_left,
+            },
+            Payload::ConfigRolledBack { id, command } => DevicePayload::ConfigRolledBack {
+                id,
+                command: command.into_owned(),
+            },
+            Payload::ConfirmConfigError { detail } => DevicePayload::ConfirmConfigError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::SelfTestResult { passed, detail } => DevicePayload::SelfTestResult {
+                passed,
+                detail: detail.into_owned(),
+            },
+            Payload::FactoryResetSuccess => DevicePayload::FactoryResetSuccess,
+            Payload::FactoryResetError { detail } => DevicePayload::FactoryResetError {
+                code: ErrorCode::Other,
+                detail: detail.into_owned(),
+            },
+            Payload::SerialNumber { serial } => DevicePayload::SerialNumber { serial },
+            Payload::Rebooting { detail } => DevicePayload::Rebooting {
+                detail: detail.into_owned(),
+            },
+            Payload::FirmwareInfo {
+                version,
+                build_time,
+                idf_version,
+            } => DevicePayload::FirmwareInfo {
+                version: version.into_owned(),
+                build_time: build_time.into_owned(),
+                idf_version: idf_version.into_owned(),
+            },
+            Payload::MeasurementBatch { readings } => DevicePayload::MeasurementBatch {
+                readings: readings.into_owned(),
+            },
+            Payload::RadioSkipped {
+                skipped_wakes,
+                lowest_mv,
+                threshold_mv,
+            } => DevicePayload::RadioSkipped {
+                skipped_wakes,
+                lowest_mv,
+                threshold_mv,
+            },
+            Payload::FaultArmed { kind } => DevicePayload::FaultArmed { kind },
+            Payload::EnteringSleep {
+                sleep_seconds,
+                next_wake_unix,
+            } => DevicePayload::EnteringSleep {
+                sleep_seconds,
+                next_wake_unix,
+            },
+            Payload::MeasurementWithFlags {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags,
+            } => DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_mv,
+                battery_percent,
+                flags: Some(flags),
+            },
+            Payload::CommandAck {
+                cmd,
+                accepted,
+                detail,
+            } => DevicePayload::CommandAck {
+                cmd: cmd.into_owned(),
+                accepted,
+                detail: detail.map(Cow::into_owned),
+            },
+            Payload::PersistRateLimited {
+                command,
+                limit,
+                retry_after_seconds,
+            } => DevicePayload::PersistRateLimited {
+                command: command.into_owned(),
+                limit,
+                retry_after_seconds,
+            },
+            Payload::Coded { code, payload } => {
+                let mut payload = DevicePayload::from(payload.into_payload());
+                if let Some(inner) = payload.error_code_mut() {
+                    *inner = code;
+                }
+                payload
+            }
+            Payload::Redelivered(payload)
+            | Payload::Injected(payload)
+            | Payload::FromFirmware { payload, .. }
+            | Payload::Located { payload, .. } => DevicePayload::from(payload.into_payload()),
+        }
+    }
+}
+
+/// The code to wrap `payload` in `Payload::Coded` with. Error payloads from
+/// before `code` only carry one other than `Other` there, so older readers
+/// still decode them; `Error` and `FrcError` have coded variants instead.
+fn wrapping_code(payload: &DevicePayload) -> Option<ErrorCode> {
+    match payload {
+        DevicePayload::Error { .. } | DevicePayload::FrcError { .. } => None,
+        payload => payload
+            .error_code()
+            .filter(|&code| code != ErrorCode::Other),
+    }
+}
+
+impl<'a> From<&'a DeviceCommand> for Command<'a> {
+    fn from(command: &'a DeviceCommand) -> Self {
+        match command {
+            DeviceCommand::NoOp => Command::NoOp,
+            DeviceCommand::StartFrc { target_ppm } => Command::StartFrc {
+                target_ppm: *target_ppm,
+            },
+            DeviceCommand::SetTempOffset { offset, persist } => Command::SetTempOffset {
+                offset: *offset,
+                persist: *persist,
+            },
+            DeviceCommand::GetTempOffset => Command::GetTempOffset,
+            DeviceCommand::SetDeepSleepTime { seconds } => {
+                Command::SetDeepSleepTime { seconds: *seconds }
+            }
+            DeviceCommand::GetDeepSleepTime => Command::GetDeepSleepTime,
+            DeviceCommand::SetMqttPolicy { class, qos, retain } => Command::SetMqttPolicy {
+                class: *class,
+                qos: *qos,
+                retain: *retain,
+            },
+            DeviceCommand::Ota { url } => Command::Ota {
+                url: Cow::Borrowed(url),
+            },
+            DeviceCommand::GetConfig => Command::GetConfig,
+            DeviceCommand::SetLogLevel { level } => Command::SetLogLevel { level: *level },
+            DeviceCommand::GetLogLevel => Command::GetLogLevel,
+            DeviceCommand::SetAdaptiveMode { enabled } => {
+                Command::SetAdaptiveMode { enabled: *enabled }
+            }
+            DeviceCommand::SetAsc { enabled } => Command::SetAsc { enabled: *enabled },
+            DeviceCommand::GetAsc => Command::GetAsc,
+            DeviceCommand::SetAltitude { meters } => Command::SetAltitude { meters: *meters },
+            DeviceCommand::GetAltitude => Command::GetAltitude,
+            DeviceCommand::SetAmbientPressure { pascals } => {
+                Command::SetAmbientPressure { pascals: *pascals }
+            }
+            DeviceCommand::ConfirmConfig { pending_id } => Command::ConfirmConfig {
+                pending_id: *pending_id,
+            },
+            DeviceCommand::SelfTest => Command::SelfTest,
+            DeviceCommand::FactoryReset { confirm } => Command::FactoryReset {
+                confirm: Cow::Borrowed(confirm),
+            },
+            DeviceCommand::GetSerialNumber => Command::GetSerialNumber,
+            DeviceCommand::Reboot => Command::Reboot,
+            DeviceCommand::GetFirmwareInfo => Command::GetFirmwareInfo,
+            DeviceCommand::InjectFault { kind } => Command::InjectFault { kind: *kind },
+        }
+    }
+}
+
+impl TryFrom<Command<'_>> for DeviceCommand {
+    type Error = postcard::Error;
+
+    fn try_from(command: Command<'_>) -> Result<Self, Self::Error> {
+        Ok(match command {
+            Command::NoOp => DeviceCommand::NoOp,
+            Command::StartFrc { target_ppm } => DeviceCommand::StartFrc { target_ppm },
+            Command::SetTempOffset { offset, persist } => {
+                DeviceCommand::SetTempOffset { offset, persist }
+            }
+            Command::GetTempOffset => DeviceCommand::GetTempOffset,
+            Command::SetDeepSleepTime { seconds } => DeviceCommand::SetDeepSleepTime { seconds },
+            Command::GetDeepSleepTime => DeviceCommand::GetDeepSleepTime,
+            Command::SetMqttPolicy { class, qos, retain } => {
+                DeviceCommand::SetMqttPolicy { class, qos, retain }
+            }
+            Command::Ota { url } => DeviceCommand::Ota {
+                url: url.into_owned(),
+            },
+            Command::GetConfig => DeviceCommand::GetConfig,
+            Command::Batch { .. } => return Err(postcard::Error::SerdeDeCustom),
+            Command::SetLogLevel { level } => DeviceCommand::SetLogLevel { level },
+            Command::GetLogLevel => DeviceCommand::GetLogLevel,
+            Command::SetAdaptiveMode { enabled } => DeviceCommand::SetAdaptiveMode { enabled },
+            Command::SetAsc { enabled } => DeviceCommand::SetAsc { enabled },
+            Command::GetAsc => DeviceCommand::GetAsc,
+            Command::SetAltitude { meters } => DeviceCommand::SetAltitude { meters },
+            Command::GetAltitude => DeviceCommand::GetAltitude,
+            Command::SetAmbientPressure { pascals } => {
+                DeviceCommand::SetAmbientPressure { pascals }
+            }
+            Command::ConfirmConfig { pending_id } => DeviceCommand::ConfirmConfig { pending_id },
+            Command::SelfTest => DeviceCommand::SelfTest,
+            Command::FactoryReset { confirm } => DeviceCommand::FactoryReset {
+                confirm: confirm.into_owned(),
+            },
+            Command::GetSerialNumber => DeviceCommand::GetSerialNumber,
+            Command::Reboot => DeviceCommand::Reboot,
+            Command::GetFirmwareInfo => DeviceCommand::GetFirmwareInfo,
+            Command::InjectFault { kind } => DeviceCommand::InjectFault { kind },
+        })
+    }
+}
+
+impl<'a> From<&'a DeviceCommandBatch> for Command<'a> {
+    fn from(batch: &'a DeviceCommandBatch) -> Self {
+        Command::Batch {
+            commands: Commands::Borrowed(&batch.commands),
+            deferred: batch.deferred,
+        }
+    }
+}
+
+impl TryFrom<Command<'_>> for DeviceCommandBatch {
+    type Error = postcard::Error;
+
+    fn try_from(command: Command<'_>) -> Result<Self, Self::Error> {
+        let Command::Batch { commands, deferred } = command else {
+            return Err(postcard::Error::SerdeDeCustom);
+        };
+        let commands = commands
+            .into_commands()
+            .into_iter()
+            .map(DeviceCommand::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let batch = Self::new(commands).map_err(|_| postcard::Error::SerdeDeCustom)?;
+        Ok(Self { deferred, ..batch })
+    }
+}
+
+impl<'a> From<&'a DeviceConfig> for Config<'a> {
+    fn from(config: &'a DeviceConfig) -> Self {
+        Config {
+            firmware_version: Cow::Borrowed(&config.firmware_version),
+            sleep_seconds: config.sleep_seconds,
+            quiet_hours: config.quiet_hours.as_deref().map(Cow::Borrowed),
+            utc_offset_hours: config.utc_offset_hours,
+            sensor_mode: config.sensor_mode,
+            temperature_offset: config.temperature_offset,
+            altitude_m: config.altitude_m,
+            ambient_pressure_hpa: config.ambient_pressure_hpa,
+            asc_enabled: config.asc_enabled,
+            alarm_threshold_ppm: config.alarm_threshold_ppm,
+            mqtt_policy: Cow::Borrowed(&config.mqtt_policy),
+            wifi_ssid: Cow::Borrowed(&config.wifi_ssid),
+            safe_mode: config.safe_mode,
+        }
+    }
+}
+
+impl From<Config<'_>> for DeviceConfig {
+    fn from(config: Config<'_>) -> Self {
+        DeviceConfig {
+            firmware_version: config.firmware_version.into_owned(),
+            sleep_seconds: config.sleep_seconds,
+            quiet_hours: config.quiet_hours.map(Cow::into_owned),
+            utc_offset_hours: config.utc_offset_hours,
+            sensor_mode: config.sensor_mode,
+            temperature_offset: config.temperature_offset,
+            altitude_m: config.altitude_m,
+            ambient_pressure_hpa: config.ambient_pressure_hpa,
+            asc_enabled: config.asc_enabled,
+            alarm_threshold_ppm: config.alarm_threshold_ppm,
+            mqtt_policy: config.mqtt_policy.into_owned(),
+            wifi_ssid: config.wifi_ssid.into_owned(),
+            safe_mode: config.safe_mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CodecError, DeviceCommand, DeviceMessage, DevicePayload, ErrorCode};
+
+    #[test]
+    fn smaller_than_json() {
+        let message = DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+        )
+        .stamped(Some(1_736_942_400_123), 42);
+        let mut buf = [0u8; 64];
+        let bytes = message.to_postcard(&mut buf).unwrap();
+        assert!(bytes.len() < message.to_json().unwrap().len() / 2);
+        assert_eq!(DeviceMessage::from_postcard(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn measurement_without_battery_keeps_its_encoding() {
+        let mut buf = [0u8; 64];
+        // The variant index follows the device name and its length
+        let variant = |payload: DevicePayload| {
+            let message = DeviceMessage::new("esp32-scd40", payload);
+            let mut buf = [0u8; 64];
+            message.to_postcard(&mut buf).unwrap()[1 + "esp32-scd40".len()]
+        };
+        assert_eq!(
+            variant(DevicePayload::try_measurement(612, 22.4, 41.3).unwrap()),
+            0
+        );
+        assert_ne!(
+            variant(
+                DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3870, 72).unwrap()
+            ),
+            0
+        );
+
+        let message = DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3870, 72).unwrap(),
+        );
+        let bytes = message.to_postcard(&mut buf).unwrap();
+        assert_eq!(DeviceMessage::from_postcard(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn location_wraps_the_payload() {
+        let message = DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+        )
+        .with_fw_version("0.4.0")
+        .with_location("bedroom");
+        let mut buf = [0u8; 64];
+        let bytes = message.to_postcard(&mut buf).unwrap();
+        assert_eq!(DeviceMessage::from_postcard(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn error_code_wraps_the_payload() {
+        let mut buf = [0u8; 64];
+        let encode = |code| {
+            let message = DeviceMessage::new(
+                "esp32-scd40",
+                DevicePayload::AltitudeError {
+                    code,
+                    detail: "failed_to_set: I2C(Nack)".into(),
+                },
+            );
+            let mut buf = [0u8; 64];
+            message.to_postcard(&mut buf).unwrap().to_vec()
+        };
+        // Without a code the encoding is what it was before `code`
+        let uncoded = encode(ErrorCode::Other);
+        let coded = encode(ErrorCode::I2cError);
+        assert_eq!(coded.len(), uncoded.len() + 2);
+        assert!(coded.ends_with(&uncoded[1 + "esp32-scd40".len()..]));
+
+        let message = DeviceMessage::new(
+            "esp32-scd40",
+            DevicePayload::OtaError {
+                code: ErrorCode::WifiError,
+                detail: "download_failed".into(),
+            },
+        );
+        let bytes = message.to_postcard(&mut buf).unwrap();
+        assert_eq!(DeviceMessage::from_postcard(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; 4];
+        assert!(DeviceCommand::GetConfig.to_postcard(&mut buf).is_ok());
+        let message =
+            DeviceMessage::new("esp32-scd40", DevicePayload::error("Measurement timed out"));
+        assert!(matches!(
+            message.to_postcard(&mut buf),
+            Err(CodecError::Postcard(postcard::Error::SerializeBufferFull))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let message = DeviceMessage::new("esp32-scd40", DevicePayload::frc_success(32791));
+        let mut buf = [0u8; 64];
+        let bytes = message.to_postcard(&mut buf).unwrap();
+        assert!(DeviceMessage::from_postcard(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
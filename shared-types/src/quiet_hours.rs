@@ -0,0 +1,61 @@
+//! Local hours during which something stays quiet, e.g. `22-7`: the
+//! firmware's indicator at night, and the processor's alerts.
+
+use core::str::FromStr;
+
+/// A span of local hours, `start_hour` up to but not including
+/// `end_hour`, wrapping past midnight when it starts later than it ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl QuietHours {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or("expected START-END")?;
+        let start_hour: u8 = start.trim().parse().map_err(|_| "invalid start hour")?;
+        let end_hour: u8 = end.trim().parse().map_err(|_| "invalid end hour")?;
+        if start_hour > 23 || end_hour > 23 {
+            return Err("hours must be 0-23");
+        }
+        Ok(Self {
+            start_hour,
+            end_hour,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours_wrap_midnight() {
+        let night: QuietHours = "22-7".parse().unwrap();
+        assert!(night.contains(23));
+        assert!(night.contains(0));
+        assert!(night.contains(6));
+        assert!(!night.contains(7));
+        assert!(!night.contains(12));
+
+        let lunch: QuietHours = "12-14".parse().unwrap();
+        assert!(lunch.contains(13));
+        assert!(!lunch.contains(14));
+
+        assert!("25-3".parse::<QuietHours>().is_err());
+        assert!("nope".parse::<QuietHours>().is_err());
+    }
+}
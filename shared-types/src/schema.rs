@@ -0,0 +1,25 @@
+//! JSON Schemas of what goes over MQTT, for clients that don't link these
+//! types, such as Node-RED flows. They come from the same serde attributes
+//! as the JSON itself, so they name exactly the fields and tags on the wire;
+//! `tests/fixtures.rs` checks every pinned fixture against them.
+//!
+//! `cargo run -p shared-types --features schema --example generate-schemas`
+//! writes them to files.
+
+use schemars::Schema;
+
+use crate::{CommandMessage, DeviceMessage};
+
+pub const DEVICE_MESSAGE_FILE: &str = "device_message.schema.json";
+pub const DEVICE_COMMAND_FILE: &str = "device_command.schema.json";
+
+/// A message published on `sensors/<device>/sensor`
+pub fn device_message() -> Schema {
+    schemars::schema_for!(DeviceMessage)
+}
+
+/// A command or a batch as published, with the envelope's optional `id`,
+/// `issued_by` and expiry next to `cmd`
+pub fn device_command() -> Schema {
+    schemars::schema_for!(CommandMessage)
+}
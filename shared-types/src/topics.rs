@@ -0,0 +1,180 @@
+//! MQTT topics, in one place so the firmware, processor and commander
+//! agree on them.
+//!
+//! Each device publishes its measurements and answers on
+//! `sensors/<device>/sensor`; the processor and commander subscribe to all
+//! of them with `sensors/+/sensor`. Firmware from before this published
+//! every device on `sensors/esp32/sensor`, which the wildcard still
+//! matches. That topic names no device, so the message's own `device` is
+//! what counts.
+//!
+//! Commands go to `sensors/esp32/command` for whichever device wakes first,
+//! or to `sensors/esp32/command/<device>` for one device only.
+//!
+//! `write_sensor_topic` and `write_command_topic` build the same topics
+//! into a caller's buffer, for builds that would rather not allocate.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+
+/// Shared by every device: retained commands are picked up by the first
+/// device to wake
+pub const COMMAND_BROADCAST_TOPIC: &str = "sensors/esp32/command";
+
+/// Where every device published before topics named the device
+pub const LEGACY_SENSOR_TOPIC: &str = "sensors/esp32/sensor";
+
+const ROOT: &str = "sensors";
+const SENSOR: &str = "sensor";
+/// The fixed segment of the legacy and command topics, never a device
+const FAMILY: &str = "esp32";
+const COMMAND: &str = "command";
+
+/// Where `device` publishes its measurements and answers
+pub fn sensor_topic(device: &str) -> String {
+    format!("{}/{}/{}", ROOT, device, SENSOR)
+}
+
+/// Commands for `device` only, retained until it wakes
+pub fn command_topic(device: &str) -> String {
+    format!("{}/{}", COMMAND_BROADCAST_TOPIC, device)
+}
+
+/// Every device's sensor topic, the legacy one included
+pub fn sensor_wildcard() -> &'static str {
+    "sensors/+/sensor"
+}
+
+/// The device a sensor or command topic belongs to. Segments before
+/// `sensors` and after the device's part are ignored, e.g. a bridge's
+/// prefix. `None` for the legacy and broadcast topics, which belong to no
+/// device in particular, and for wildcards.
+pub fn parse_device_from_topic(topic: &str) -> Option<&str> {
+    let mut segments = topic.split('/');
+    while let Some(segment) = segments.next() {
+        if segment != ROOT {
+            continue;
+        }
+        let mut rest = segments.clone();
+        let device = match (rest.next(), rest.next()) {
+            (Some(FAMILY), Some(COMMAND)) => rest.next(),
+            (Some(FAMILY), Some(SENSOR)) => None,
+            (device, Some(SENSOR)) => device,
+            _ => continue,
+        };
+        return device.filter(|device| !matches!(*device, "" | "+" | "#"));
+    }
+    None
+}
+
+/// The topic didn't fit the buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicTooLong;
+
+impl fmt::Display for TopicTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("topic is longer than the buffer")
+    }
+}
+
+impl core::error::Error for TopicTooLong {}
+
+fn write_segments<'a>(segments: &[&str], buf: &'a mut [u8]) -> Result<&'a str, TopicTooLong> {
+    let mut len = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let separator: &[u8] = if i == 0 { b"" } else { b"/" };
+        for part in [separator, segment.as_bytes()] {
+            buf.get_mut(len..len + part.len())
+                .ok_or(TopicTooLong)?
+                .copy_from_slice(part);
+            len += part.len();
+        }
+    }
+    // Only whole `str`s were copied in
+    Ok(core::str::from_utf8(&buf[..len]).expect("segments are UTF-8"))
+}
+
+/// [`sensor_topic`] into `buf`
+pub fn write_sensor_topic<'a>(device: &str, buf: &'a mut [u8]) -> Result<&'a str, TopicTooLong> {
+    write_segments(&[ROOT, device, SENSOR], buf)
+}
+
+/// [`command_topic`] into `buf`
+pub fn write_command_topic<'a>(device: &str, buf: &'a mut [u8]) -> Result<&'a str, TopicTooLong> {
+    write_segments(&[COMMAND_BROADCAST_TOPIC, device], buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_name_the_device() {
+        assert_eq!(sensor_topic("kitchen"), "sensors/kitchen/sensor");
+        assert_eq!(command_topic("kitchen"), "sensors/esp32/command/kitchen");
+        assert_eq!(
+            parse_device_from_topic(&sensor_topic("kitchen")),
+            Some("kitchen")
+        );
+        assert_eq!(
+            parse_device_from_topic(&command_topic("kitchen")),
+            Some("kitchen")
+        );
+    }
+
+    #[test]
+    fn shared_topics_belong_to_no_device() {
+        for topic in [
+            LEGACY_SENSOR_TOPIC,
+            COMMAND_BROADCAST_TOPIC,
+            sensor_wildcard(),
+            "sensors/esp32/command/+",
+            "sensors//sensor",
+            "sensors/kitchen",
+            "sensors/kitchen/status",
+            "kitchen/sensor",
+            "",
+        ] {
+            assert_eq!(parse_device_from_topic(topic), None, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn extra_segments_are_skipped() {
+        for (topic, device) in [
+            ("sensors/kitchen/sensor/json", "kitchen"),
+            ("site-a/sensors/kitchen/sensor", "kitchen"),
+            (
+                "home/site-a/sensors/esp32-scd40/sensor/v2/raw",
+                "esp32-scd40",
+            ),
+            ("sensors/esp32/command/kitchen/batch", "kitchen"),
+            ("bridge/sensors/esp32/command/bedroom", "bedroom"),
+            // The first `sensors` isn't followed by a device's topic
+            ("sensors/sensors/hall/sensor", "hall"),
+        ] {
+            assert_eq!(parse_device_from_topic(topic), Some(device), "{}", topic);
+        }
+    }
+
+    #[test]
+    fn buffers_get_the_same_topics() {
+        let mut buf = [0; 64];
+        assert_eq!(
+            write_sensor_topic("kitchen", &mut buf),
+            Ok("sensors/kitchen/sensor")
+        );
+        assert_eq!(
+            write_command_topic("kitchen", &mut buf),
+            Ok(command_topic("kitchen").as_str())
+        );
+        let mut small = [0; 21];
+        assert_eq!(write_sensor_topic("kitchen", &mut small), Err(TopicTooLong));
+        let mut exact = [0; 22];
+        assert_eq!(
+            write_sensor_topic("kitchen", &mut exact),
+            Ok("sensors/kitchen/sensor")
+        );
+    }
+}
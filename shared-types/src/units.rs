@@ -0,0 +1,333 @@
+//! Measured quantities, checked against what the SCD40 can report.
+//!
+//! The sensor's output covers 0–40000 ppm, -45–130 °C and 0–100 %RH, so a
+//! value outside that came from a broken sensor, a bad conversion or a
+//! hand-written message. `Co2Ppm::new` and friends refuse such values, and
+//! so does deserializing one; the wire form is the plain number either way.
+//!
+//! With the `validated` feature `MeasurementSuccess` carries these types,
+//! so an impossible reading fails to decode instead of reaching storage.
+//! Without it the fields stay plain numbers; the `Measured*` aliases name
+//! whichever is in use.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A reading outside the sensor's range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfRange {
+    pub quantity: &'static str,
+    pub value: f32,
+    pub min: f32,
+    pub max: f32,
+    pub unit: &'static str,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} is outside the sensor's range of {} to {} {}",
+            self.quantity, self.value, self.unit, self.min, self.max, self.unit
+        )
+    }
+}
+
+impl core::error::Error for OutOfRange {}
+
+/// CO2 concentration in ppm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(try_from = "u16", into = "u16")]
+pub struct Co2Ppm(u16);
+
+impl Co2Ppm {
+    pub const MAX: u16 = 40_000;
+
+    pub fn new(ppm: u16) -> Result<Self, OutOfRange> {
+        if ppm > Self::MAX {
+            return Err(OutOfRange {
+                quantity: "CO2",
+                value: f32::from(ppm),
+                min: 0.0,
+                max: f32::from(Self::MAX),
+                unit: "ppm",
+            });
+        }
+        Ok(Self(ppm))
+    }
+
+    pub fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<u16> for Co2Ppm {
+    type Error = OutOfRange;
+
+    fn try_from(ppm: u16) -> Result<Self, Self::Error> {
+        Self::new(ppm)
+    }
+}
+
+impl From<Co2Ppm> for u16 {
+    fn from(co2: Co2Ppm) -> Self {
+        co2.0
+    }
+}
+
+/// A temperature in degrees Celsius, the unit used on the wire.
+///
+/// `Celsius(value)` builds one unchecked, for converting temperature
+/// offsets and the like; `new` and deserializing check the sensor range.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(try_from = "f32", into = "f32")]
+pub struct Celsius(pub f32);
+
+impl Celsius {
+    pub const MIN: f32 = -45.0;
+    pub const MAX: f32 = 130.0;
+
+    pub fn new(celsius: f32) -> Result<Self, OutOfRange> {
+        if !(Self::MIN..=Self::MAX).contains(&celsius) {
+            return Err(OutOfRange {
+                quantity: "temperature",
+                value: celsius,
+                min: Self::MIN,
+                max: Self::MAX,
+                unit: "°C",
+            });
+        }
+        Ok(Self(celsius))
+    }
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+
+    pub fn to_fahrenheit(self) -> f32 {
+        self.0 * 9.0 / 5.0 + 32.0
+    }
+
+    /// Converts a temperature difference (e.g. the sensor's temperature
+    /// offset), which scales but doesn't shift.
+    pub fn delta_to_fahrenheit(self) -> f32 {
+        self.0 * 9.0 / 5.0
+    }
+}
+
+impl TryFrom<f32> for Celsius {
+    type Error = OutOfRange;
+
+    fn try_from(celsius: f32) -> Result<Self, Self::Error> {
+        Self::new(celsius)
+    }
+}
+
+impl From<Celsius> for f32 {
+    fn from(celsius: Celsius) -> Self {
+        celsius.0
+    }
+}
+
+/// Relative humidity in percent
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(try_from = "f32", into = "f32")]
+pub struct RelativeHumidity(f32);
+
+impl RelativeHumidity {
+    pub const MIN: f32 = 0.0;
+    pub const MAX: f32 = 100.0;
+
+    pub fn new(percent: f32) -> Result<Self, OutOfRange> {
+        if !(Self::MIN..=Self::MAX).contains(&percent) {
+            return Err(OutOfRange {
+                quantity: "humidity",
+                value: percent,
+                min: Self::MIN,
+                max: Self::MAX,
+                unit: "%",
+            });
+        }
+        Ok(Self(percent))
+    }
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+impl TryFrom<f32> for RelativeHumidity {
+    type Error = OutOfRange;
+
+    fn try_from(percent: f32) -> Result<Self, Self::Error> {
+        Self::new(percent)
+    }
+}
+
+impl From<RelativeHumidity> for f32 {
+    fn from(humidity: RelativeHumidity) -> Self {
+        humidity.0
+    }
+}
+
+#[cfg(feature = "validated")]
+pub type MeasuredCo2 = Co2Ppm;
+#[cfg(feature = "validated")]
+pub type MeasuredTemperature = Celsius;
+#[cfg(feature = "validated")]
+pub type MeasuredHumidity = RelativeHumidity;
+
+#[cfg(not(feature = "validated"))]
+pub type MeasuredCo2 = u16;
+#[cfg(not(feature = "validated"))]
+pub type MeasuredTemperature = f32;
+#[cfg(not(feature = "validated"))]
+pub type MeasuredHumidity = f32;
+
+/// A range-checked reading as the `MeasurementSuccess` field type: the
+/// newtype itself with the `validated` feature, the plain number without
+pub(crate) fn measured<V, T>(checked: V) -> T
+where
+    V: Into<T>,
+{
+    checked.into()
+}
+
+/// A `MeasurementSuccess` field as the plain number, whichever type it is
+pub(crate) fn plain<T, R>(field: T) -> R
+where
+    T: Into<R>,
+{
+    field.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundaries_are_inclusive() {
+        assert_eq!(Co2Ppm::new(0).map(Co2Ppm::get), Ok(0));
+        assert_eq!(Co2Ppm::new(40_000).map(Co2Ppm::get), Ok(40_000));
+        assert!(Co2Ppm::new(40_001).is_err());
+
+        assert_eq!(Celsius::new(-45.0).map(Celsius::get), Ok(-45.0));
+        assert_eq!(Celsius::new(130.0).map(Celsius::get), Ok(130.0));
+        assert!(Celsius::new(-45.01).is_err());
+        assert!(Celsius::new(130.01).is_err());
+
+        assert_eq!(
+            RelativeHumidity::new(0.0).map(RelativeHumidity::get),
+            Ok(0.0)
+        );
+        assert_eq!(
+            RelativeHumidity::new(100.0).map(RelativeHumidity::get),
+            Ok(100.0)
+        );
+        assert!(RelativeHumidity::new(-0.01).is_err());
+        assert!(RelativeHumidity::new(100.01).is_err());
+    }
+
+    #[test]
+    fn nan_is_out_of_range() {
+        assert!(Celsius::new(f32::NAN).is_err());
+        assert!(RelativeHumidity::new(f32::NAN).is_err());
+    }
+
+    #[test]
+    fn errors_say_what_and_by_how_much() {
+        assert_eq!(
+            RelativeHumidity::new(412.0).unwrap_err().to_string(),
+            "humidity 412 % is outside the sensor's range of 0 to 100 %"
+        );
+        assert_eq!(
+            Co2Ppm::new(65_535).unwrap_err().to_string(),
+            "CO2 65535 ppm is outside the sensor's range of 0 to 40000 ppm"
+        );
+        assert_eq!(
+            Celsius::new(-60.5).unwrap_err().to_string(),
+            "temperature -60.5 °C is outside the sensor's range of -45 to 130 °C"
+        );
+    }
+
+    #[test]
+    fn out_of_range_measurements_are_refused_not_built() {
+        use crate::DevicePayload;
+
+        assert_eq!(
+            DevicePayload::try_measurement(612, 22.4, 412.0)
+                .unwrap_err()
+                .to_string(),
+            "humidity 412 % is outside the sensor's range of 0 to 100 %"
+        );
+        assert!(DevicePayload::try_measurement_with_battery(40_001, 22.4, 41.3, 3870, 72).is_err());
+        assert!(DevicePayload::try_measurement(40_000, 130.0, 100.0).is_ok());
+    }
+
+    #[test]
+    fn the_wire_form_is_the_plain_number() {
+        assert_eq!(serde_json::to_string(&Co2Ppm(612)).unwrap(), "612");
+        assert_eq!(serde_json::to_string(&Celsius(22.4)).unwrap(), "22.4");
+        assert_eq!(
+            serde_json::from_str::<RelativeHumidity>("41.3").unwrap(),
+            RelativeHumidity(41.3)
+        );
+        // Whole degrees, as older firmware sent them
+        assert_eq!(
+            serde_json::from_str::<Celsius>("22").unwrap(),
+            Celsius(22.0)
+        );
+    }
+
+    #[test]
+    fn deserializing_checks_the_range() {
+        assert_eq!(
+            serde_json::from_str::<RelativeHumidity>("412")
+                .unwrap_err()
+                .to_string(),
+            "humidity 412 % is outside the sensor's range of 0 to 100 %"
+        );
+        assert_eq!(
+            serde_json::from_str::<Co2Ppm>("40001")
+                .unwrap_err()
+                .to_string(),
+            "CO2 40001 ppm is outside the sensor's range of 0 to 40000 ppm"
+        );
+        assert_eq!(
+            serde_json::from_str::<Co2Ppm>("-1")
+                .unwrap_err()
+                .to_string(),
+            "invalid value: integer `-1`, expected u16 at line 1 column 2"
+        );
+        assert_eq!(
+            serde_json::from_str::<Celsius>("\"warm\"")
+                .unwrap_err()
+                .to_string(),
+            "invalid type: string \"warm\", expected f32 at line 1 column 6"
+        );
+    }
+
+    #[cfg(feature = "validated")]
+    #[test]
+    fn impossible_measurements_fail_to_decode() {
+        use crate::DeviceMessage;
+
+        let json = r#"{"device":"esp32-test","status":"success","co2":612,"temperature":22.4,"humidity":412}"#;
+        let error = DeviceMessage::from_json(json).unwrap_err().to_string();
+        assert!(
+            error.starts_with("JSON: humidity 412 % is outside the sensor's range of 0 to 100 %"),
+            "{}",
+            error
+        );
+
+        let json = r#"{"device":"esp32-test","status":"success","co2":612,"temperature":22.4,"humidity":41.3}"#;
+        assert_eq!(
+            DeviceMessage::from_json(json).unwrap().payload,
+            crate::DevicePayload::try_measurement(612, 22.4, 41.3).unwrap()
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Invariants a message must hold to be worth storing or acting on.
+//!
+//! Decoding only checks the shape of a message. `DeviceMessage::validate`
+//! also checks what the values say: measurements within the sensor's range
+//! (see `units`) and not the 0 ppm a glitching sensor reports, a device
+//! name, a battery percentage of at most 100 and FRC targets the sensor
+//! accepts. The processor drops messages that fail it; the firmware checks
+//! its own measurements before publishing them. Only `core` is used, so
+//! the check works without `std`.
+
+use core::fmt;
+
+use crate::units::{self, Celsius, Co2Ppm, OutOfRange, RelativeHumidity};
+use crate::{DeviceMessage, DevicePayload, MAX_FRC_TARGET_PPM, MIN_FRC_TARGET_PPM};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationError {
+    EmptyDevice,
+    /// `reading` is the position in a `measurement_batch`, `None` for a
+    /// single measurement
+    OutOfRange {
+        reading: Option<usize>,
+        range: OutOfRange,
+    },
+    /// What the sensor reports after a glitch rather than for real air
+    ZeroCo2 {
+        reading: Option<usize>,
+    },
+    BatteryPercent {
+        percent: u8,
+    },
+    FrcTarget {
+        target_ppm: u16,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reading = |f: &mut fmt::Formatter<'_>, reading: &Option<usize>| match reading {
+            Some(index) => write!(f, "reading {}: ", index),
+            None => Ok(()),
+        };
+        match self {
+            ValidationError::EmptyDevice => f.write_str("device name is empty"),
+            ValidationError::OutOfRange { reading: at, range } => {
+                reading(f, at)?;
+                write!(f, "{}", range)
+            }
+            ValidationError::ZeroCo2 { reading: at } => {
+                reading(f, at)?;
+                f.write_str("CO2 of 0 ppm is a sensor glitch")
+            }
+            ValidationError::BatteryPercent { percent } => {
+                write!(f, "battery at {}% is over 100%", percent)
+            }
+            ValidationError::FrcTarget { target_ppm } => write!(
+                f,
+                "FRC target {} ppm is outside {} to {} ppm",
+                target_ppm, MIN_FRC_TARGET_PPM, MAX_FRC_TARGET_PPM
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+fn check_reading(
+    reading: Option<usize>,
+    co2: u16,
+    temperature: f32,
+    humidity: f32,
+) -> Result<(), ValidationError> {
+    let out_of_range = |range| ValidationError::OutOfRange { reading, range };
+    if co2 == 0 {
+        return Err(ValidationError::ZeroCo2 { reading });
+    }
+    Co2Ppm::new(co2).map_err(out_of_range)?;
+    Celsius::new(temperature).map_err(out_of_range)?;
+    RelativeHumidity::new(humidity).map_err(out_of_range)?;
+    Ok(())
+}
+
+fn check_frc_target(target_ppm: u16) -> Result<(), ValidationError> {
+    if !(MIN_FRC_TARGET_PPM..=MAX_FRC_TARGET_PPM).contains(&target_ppm) {
+        return Err(ValidationError::FrcTarget { target_ppm });
+    }
+    Ok(())
+}
+
+impl DeviceMessage {
+    /// The first invariant the message breaks, if any
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.device.trim().is_empty() {
+            return Err(ValidationError::EmptyDevice);
+        }
+        match &self.payload {
+            DevicePayload::MeasurementSuccess {
+                co2,
+                temperature,
+                humidity,
+                battery_percent,
+                ..
+            } => {
+                check_reading(
+                    None,
+                    units::plain(*co2),
+                    units::plain(*temperature),
+                    units::plain(*humidity),
+                )?;
+                if let Some(percent) = battery_percent.filter(|percent| *percent > 100) {
+                    return Err(ValidationError::BatteryPercent { percent });
+                }
+                Ok(())
+            }
+            DevicePayload::MeasurementBatch { readings } => {
+                for (index, reading) in readings.iter().enumerate() {
+                    check_reading(
+                        Some(index),
+                        reading.co2,
+                        reading.temperature,
+                        reading.humidity,
+                    )?;
+                }
+                Ok(())
+            }
+            DevicePayload::FrcStart { target_ppm }
+            | DevicePayload::FrcCalibrating { target_ppm } => check_frc_target(*target_ppm),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BatchedReading;
+
+    fn message(payload: DevicePayload) -> DeviceMessage {
+        DeviceMessage::new("esp32-test", payload)
+    }
+
+    fn error(payload: DevicePayload) -> ValidationError {
+        message(payload).validate().unwrap_err()
+    }
+
+    #[test]
+    fn ordinary_messages_pass() {
+        for payload in [
+            DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+            DevicePayload::try_measurement_with_battery(1, -45.0, 0.0, 3870, 100).unwrap(),
+            DevicePayload::try_measurement(40_000, 130.0, 100.0).unwrap(),
+            DevicePayload::frc_start(400),
+            DevicePayload::FrcCalibrating { target_ppm: 2000 },
+            DevicePayload::frc_success(0),
+            DevicePayload::error("Measurement timed out"),
+        ] {
+            assert_eq!(message(payload.clone()).validate(), Ok(()), "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn the_device_needs_a_name() {
+        for device in ["", "  "] {
+            let message = DeviceMessage::new(device, DevicePayload::frc_success(0));
+            assert_eq!(message.validate(), Err(ValidationError::EmptyDevice));
+        }
+    }
+
+    #[test]
+    fn zero_co2_is_a_glitch() {
+        assert_eq!(
+            error(DevicePayload::try_measurement(0, 22.4, 41.3).unwrap()),
+            ValidationError::ZeroCo2 { reading: None }
+        );
+        assert_eq!(
+            ValidationError::ZeroCo2 { reading: None }.to_string(),
+            "CO2 of 0 ppm is a sensor glitch"
+        );
+    }
+
+    // With `validated` such a measurement can't be built in the first place
+    #[cfg(not(feature = "validated"))]
+    #[test]
+    fn measurements_stay_within_the_sensor_range() {
+        let co2 = error(DevicePayload::measurement(40_001, 22.4, 41.3));
+        assert!(
+            matches!(co2, ValidationError::OutOfRange { reading: None, range } if range.quantity == "CO2")
+        );
+        let temperature = error(DevicePayload::measurement(612, 130.5, 41.3));
+        assert!(
+            matches!(temperature, ValidationError::OutOfRange { range, .. } if range.quantity == "temperature")
+        );
+        let humidity = error(DevicePayload::measurement(612, 22.4, 412.0));
+        assert_eq!(
+            humidity.to_string(),
+            "humidity 412 % is outside the sensor's range of 0 to 100 %"
+        );
+        assert!(
+            message(DevicePayload::measurement(612, 22.4, f32::NAN))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn battery_percent_is_at_most_100() {
+        assert_eq!(
+            error(DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3870, 101).unwrap()),
+            ValidationError::BatteryPercent { percent: 101 }
+        );
+    }
+
+    #[test]
+    fn batch_errors_name_the_reading() {
+        let reading = |co2, humidity| BatchedReading {
+            co2,
+            temperature: 22.0,
+            humidity,
+            age_seconds: 0,
+        };
+        let batch = |readings| DevicePayload::MeasurementBatch { readings };
+        assert_eq!(
+            message(batch(vec![reading(640, 42.0), reading(612, 41.3)])).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            error(batch(vec![reading(640, 42.0), reading(0, 41.3)])),
+            ValidationError::ZeroCo2 { reading: Some(1) }
+        );
+        assert_eq!(
+            error(batch(vec![reading(640, -3.0)])).to_string(),
+            "reading 0: humidity -3 % is outside the sensor's range of 0 to 100 %"
+        );
+    }
+
+    #[test]
+    fn frc_targets_are_400_to_2000_ppm() {
+        assert_eq!(
+            error(DevicePayload::frc_start(399)),
+            ValidationError::FrcTarget { target_ppm: 399 }
+        );
+        assert_eq!(
+            error(DevicePayload::FrcCalibrating { target_ppm: 2001 }).to_string(),
+            "FRC target 2001 ppm is outside 400 to 2000 ppm"
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Framing for blobs kept between runs, so a layout change or a corrupted
+//! write is detected instead of misread.
+//!
+//! A blob is `MAGIC`, the layout version (u16), the payload length (u32),
+//! the payload, and a CRC-32 of everything before it; integers are little
+//! endian. `Migrations` lists how each older layout upgrades to the next
+//! one, and `Migrations::load` chains them up to the current layout.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const MAGIC: [u8; 4] = *b"AQvb";
+const HEADER_LEN: usize = 10;
+const CRC_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobError {
+    /// Shorter than its header, or than the length the header declares
+    Truncated,
+    /// Longer than the length the header declares
+    TrailingData,
+    /// Not a versioned blob at all, e.g. an older raw layout
+    BadMagic,
+    /// Corrupted in storage or only partly written
+    Checksum,
+    /// Newer than this build, or an old layout no migration covers
+    UnknownVersion(u16),
+    /// A migration couldn't read the payload it was given
+    Migration { from: u16, reason: &'static str },
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Truncated => f.write_str("blob is truncated"),
+            BlobError::TrailingData => f.write_str("blob has data past its payload"),
+            BlobError::BadMagic => f.write_str("not a versioned blob"),
+            BlobError::Checksum => f.write_str("blob checksum doesn't match"),
+            BlobError::UnknownVersion(version) => {
+                write!(f, "unknown blob version {}", version)
+            }
+            BlobError::Migration { from, reason } => {
+                write!(f, "migrating from version {} failed: {}", from, reason)
+            }
+        }
+    }
+}
+
+impl core::error::Error for BlobError {}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE), as used by zlib and Ethernet
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+pub fn encode_versioned(version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+    blob.extend_from_slice(&MAGIC);
+    blob.extend_from_slice(&version.to_le_bytes());
+    blob.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    blob.extend_from_slice(payload);
+    let crc = crc32(&blob);
+    blob.extend_from_slice(&crc.to_le_bytes());
+    blob
+}
+
+/// The version and payload of `blob`, if it's intact and one of
+/// `expected_versions`.
+pub fn decode_versioned<'a>(
+    blob: &'a [u8],
+    expected_versions: &[u16],
+) -> Result<(u16, &'a [u8]), BlobError> {
+    if blob.len() < MAGIC.len() {
+        return Err(BlobError::Truncated);
+    }
+    if blob[..MAGIC.len()] != MAGIC {
+        return Err(BlobError::BadMagic);
+    }
+    if blob.len() < HEADER_LEN + CRC_LEN {
+        return Err(BlobError::Truncated);
+    }
+    let version = u16::from_le_bytes([blob[4], blob[5]]);
+    let len = u32::from_le_bytes([blob[6], blob[7], blob[8], blob[9]]) as usize;
+    let end = HEADER_LEN
+        .checked_add(len)
+        .and_then(|end| end.checked_add(CRC_LEN))
+        .ok_or(BlobError::Truncated)?;
+    if blob.len() < end {
+        return Err(BlobError::Truncated);
+    }
+    if blob.len() > end {
+        return Err(BlobError::TrailingData);
+    }
+    let (framed, crc) = blob.split_at(end - CRC_LEN);
+    if crc32(framed) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(BlobError::Checksum);
+    }
+    if !expected_versions.contains(&version) {
+        return Err(BlobError::UnknownVersion(version));
+    }
+    Ok((version, &framed[HEADER_LEN..]))
+}
+
+/// Turns a payload of one version into the next version's layout
+pub type Migration = fn(&[u8]) -> Result<Vec<u8>, &'static str>;
+
+/// The layouts of one kind of blob: the current version, and for each
+/// older one how it upgrades to the version after it.
+#[derive(Debug, Clone, Copy)]
+pub struct Migrations<'a> {
+    pub current: u16,
+    /// `(from, migration)` pairs, turning version `from` into `from + 1`
+    pub steps: &'a [(u16, Migration)],
+}
+
+impl<'a> Migrations<'a> {
+    pub const fn new(current: u16, steps: &'a [(u16, Migration)]) -> Self {
+        Self { current, steps }
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        encode_versioned(self.current, payload)
+    }
+
+    /// The current version and every older one with a chain of migrations
+    /// up to it
+    pub fn readable(&self) -> Vec<u16> {
+        let mut versions = vec![self.current];
+        let mut version = self.current;
+        while let Some(previous) = version.checked_sub(1)
+            && self.step(previous).is_some()
+        {
+            versions.push(previous);
+            version = previous;
+        }
+        versions
+    }
+
+    fn step(&self, from: u16) -> Option<Migration> {
+        self.steps
+            .iter()
+            .find(|(version, _)| *version == from)
+            .map(|(_, migration)| *migration)
+    }
+
+    /// Upgrades a `version` payload to the current layout.
+    pub fn upgrade(&self, version: u16, payload: &[u8]) -> Result<Vec<u8>, BlobError> {
+        if version > self.current {
+            return Err(BlobError::UnknownVersion(version));
+        }
+        let mut payload = payload.to_vec();
+        for from in version..self.current {
+            let migration = self.step(from).ok_or(BlobError::UnknownVersion(version))?;
+            payload =
+                migration(&payload).map_err(|reason| BlobError::Migration { from, reason })?;
+        }
+        Ok(payload)
+    }
+
+    /// The payload of `blob` in the current layout, and the version it was
+    /// stored as.
+    pub fn load(&self, blob: &[u8]) -> Result<(Vec<u8>, u16), BlobError> {
+        let (version, payload) = decode_versioned(blob, &self.readable())?;
+        Ok((self.upgrade(version, payload)?, version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trips() {
+        let blob = encode_versioned(3, b"payload");
+        assert_eq!(&blob[..4], b"AQvb");
+        assert_eq!(blob.len(), 10 + 7 + 4);
+        assert_eq!(decode_versioned(&blob, &[2, 3]), Ok((3, &b"payload"[..])));
+        let empty = encode_versioned(1, b"");
+        assert_eq!(decode_versioned(&empty, &[1]), Ok((1, &b""[..])));
+    }
+
+    #[test]
+    fn every_truncation_is_detected() {
+        let blob = encode_versioned(1, b"twelve bytes");
+        for len in 0..blob.len() {
+            let error = decode_versioned(&blob[..len], &[1]).unwrap_err();
+            assert_eq!(error, BlobError::Truncated, "cut to {} bytes", len);
+        }
+        let mut longer = blob.clone();
+        longer.push(0);
+        assert_eq!(
+            decode_versioned(&longer, &[1]),
+            Err(BlobError::TrailingData)
+        );
+    }
+
+    #[test]
+    fn every_flipped_bit_is_detected() {
+        let blob = encode_versioned(1, b"count and time");
+        for byte in 0..blob.len() {
+            for bit in 0..8 {
+                let mut corrupted = blob.clone();
+                corrupted[byte] ^= 1 << bit;
+                assert!(
+                    decode_versioned(&corrupted, &[1]).is_err(),
+                    "bit {} of byte {} flipped",
+                    bit,
+                    byte
+                );
+            }
+        }
+        // Past the header, it's the checksum that notices
+        let mut corrupted = blob.clone();
+        corrupted[12] ^= 0x20;
+        assert_eq!(decode_versioned(&corrupted, &[1]), Err(BlobError::Checksum));
+    }
+
+    #[test]
+    fn unexpected_versions_and_raw_data_are_refused() {
+        let blob = encode_versioned(4, b"from a newer build");
+        assert_eq!(
+            decode_versioned(&blob, &[1, 2, 3]),
+            Err(BlobError::UnknownVersion(4))
+        );
+        // The pre-framing layout of some blob
+        assert_eq!(
+            decode_versioned(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1]),
+            Err(BlobError::BadMagic)
+        );
+    }
+
+    /// v1: a u16 count. v2: adds a u64 time. v3: count widened to u32.
+    fn v1_to_v2(payload: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if payload.len() != 2 {
+            return Err("expected a u16");
+        }
+        let mut upgraded = payload.to_vec();
+        upgraded.extend_from_slice(&0u64.to_le_bytes());
+        Ok(upgraded)
+    }
+
+    fn v2_to_v3(payload: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if payload.len() != 10 {
+            return Err("expected a u16 and a u64");
+        }
+        let count = u16::from_le_bytes([payload[0], payload[1]]) as u32;
+        let mut upgraded = count.to_le_bytes().to_vec();
+        upgraded.extend_from_slice(&payload[2..]);
+        Ok(upgraded)
+    }
+
+    const LAYOUTS: Migrations<'static> = Migrations::new(3, &[(1, v1_to_v2), (2, v2_to_v3)]);
+
+    #[test]
+    fn migrations_chain_up_to_the_current_version() {
+        assert_eq!(LAYOUTS.readable(), vec![3, 2, 1]);
+
+        let v1 = encode_versioned(1, &7u16.to_le_bytes());
+        let mut expected = 7u32.to_le_bytes().to_vec();
+        expected.extend_from_slice(&0u64.to_le_bytes());
+        assert_eq!(LAYOUTS.load(&v1), Ok((expected.clone(), 1)));
+
+        let mut v2_payload = 7u16.to_le_bytes().to_vec();
+        v2_payload.extend_from_slice(&0u64.to_le_bytes());
+        let v2 = encode_versioned(2, &v2_payload);
+        assert_eq!(LAYOUTS.load(&v2), Ok((expected.clone(), 2)));
+
+        assert_eq!(LAYOUTS.load(&LAYOUTS.encode(&expected)), Ok((expected, 3)));
+    }
+
+    #[test]
+    fn gaps_and_failed_migrations_are_reported() {
+        // Nothing upgrades version 1 any more
+        let pruned = Migrations::new(3, &[(2, v2_to_v3)]);
+        assert_eq!(pruned.readable(), vec![3, 2]);
+        let v1 = encode_versioned(1, &7u16.to_le_bytes());
+        assert_eq!(pruned.load(&v1), Err(BlobError::UnknownVersion(1)));
+        assert_eq!(
+            pruned.upgrade(1, &7u16.to_le_bytes()),
+            Err(BlobError::UnknownVersion(1))
+        );
+
+        // Framed correctly, but the v1 payload has the wrong size
+        let odd = encode_versioned(1, &[1, 2, 3]);
+        assert_eq!(
+            LAYOUTS.load(&odd),
+            Err(BlobError::Migration {
+                from: 1,
+                reason: "expected a u16"
+            })
+        );
+        assert_eq!(LAYOUTS.upgrade(4, &[]), Err(BlobError::UnknownVersion(4)));
+    }
+}
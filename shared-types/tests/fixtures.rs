@@ -0,0 +1,897 @@
+//! Pinned wire fixtures.
+//!
+//! Every string here was produced by a released firmware, processor or
+//! commander. They must keep parsing into the listed values forever; add new
+//! fixtures when the protocol grows, never edit or remove existing ones.
+
+use shared_types::device_config::{DeviceConfig, SensorMode};
+use shared_types::fault_injection::FaultKind;
+use shared_types::log_level::LogLevel;
+use shared_types::mqtt_policy::PayloadClass;
+use shared_types::{
+    BatchedReading, CommandMessage, DeviceCommand, DeviceCommandBatch, DeviceMessage,
+    DevicePayload, ErrorCode, LEGACY_PROTOCOL_VERSION, MeasurementFlags,
+};
+
+const MESSAGE_FIXTURES: &[(&str, &str)] = &[
+    (
+        "measurement",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3}"#,
+    ),
+    (
+        "error",
+        r#"{"device":"esp32-scd40","status":"error","detail":"Measurement timed out"}"#,
+    ),
+    (
+        "frc_start",
+        r#"{"device":"esp32-scd40","status":"frc_start","target_ppm":422}"#,
+    ),
+    (
+        "frc_warmup_complete",
+        r#"{"device":"esp32-scd40","status":"frc_warmup_complete","detail":"Took 3 minutes"}"#,
+    ),
+    (
+        "frc_calibrating",
+        r#"{"device":"esp32-scd40","status":"frc_calibrating","target_ppm":422}"#,
+    ),
+    (
+        "frc_success",
+        r#"{"device":"esp32-scd40","status":"frc_success","correction":32791}"#,
+    ),
+    (
+        "frc_error",
+        r#"{"device":"esp32-scd40","status":"frc_error","detail":"I2C(Timeout)"}"#,
+    ),
+    (
+        "set_offset_success",
+        r#"{"device":"esp32-scd40","status":"set_offset_success","offset":4.0}"#,
+    ),
+    (
+        "set_offset_error",
+        r#"{"device":"esp32-scd40","status":"set_offset_error","detail":"failed_to_persist: I2C(Nack)"}"#,
+    ),
+    (
+        "get_offset_success",
+        r#"{"device":"esp32-scd40","status":"get_offset_success","offset":4.0}"#,
+    ),
+    (
+        "get_offset_error",
+        r#"{"device":"esp32-scd40","status":"get_offset_error","detail":"failed_to_get: I2C(Nack)"}"#,
+    ),
+    (
+        "set_deep_sleep_time_success",
+        r#"{"device":"esp32-scd40","status":"set_deep_sleep_time_success","seconds":600}"#,
+    ),
+    (
+        "get_deep_sleep_time_success",
+        r#"{"device":"esp32-scd40","status":"get_deep_sleep_time_success","seconds":300}"#,
+    ),
+    (
+        "alive",
+        r#"{"device":"esp32-scd40","status":"alive","uptime_seconds":3600}"#,
+    ),
+    (
+        "set_mqtt_policy_success",
+        r#"{"device":"esp32-scd40","status":"set_mqtt_policy_success","class":"diagnostic","qos":0,"retain":false}"#,
+    ),
+    (
+        "set_mqtt_policy_error",
+        r#"{"device":"esp32-scd40","status":"set_mqtt_policy_error","detail":"QoS must be 0, 1 or 2"}"#,
+    ),
+    (
+        "commands_deferred",
+        r#"{"device":"esp32-scd40","status":"commands_deferred","running":"start_frc","deferred":[{"cmd":"set_temp_offset","offset":4.0}]}"#,
+    ),
+    (
+        "bus_recovery",
+        r#"{"device":"esp32-scd40","status":"bus_recovery","attempt":1,"pulses":3,"recovered":true}"#,
+    ),
+    (
+        "bus_recovery_sda_stuck",
+        r#"{"device":"esp32-scd40","status":"bus_recovery","attempt":2,"recovered":false}"#,
+    ),
+    (
+        "ota_progress",
+        r#"{"device":"esp32-scd40","status":"ota_progress","percent":40}"#,
+    ),
+    (
+        "ota_success",
+        r#"{"device":"esp32-scd40","status":"ota_success","version":"0.4.0"}"#,
+    ),
+    (
+        "ota_error",
+        r#"{"device":"esp32-scd40","status":"ota_error","detail":"OTA is not supported by this firmware"}"#,
+    ),
+    (
+        "wake_profile",
+        r#"{"device":"esp32-scd40","status":"wake_profile","awake_ms":9800,"sensor_ms":6200,"network_ms":3100,"saved_ms":3000}"#,
+    ),
+    (
+        "config",
+        r#"{"device":"esp32-scd40","status":"config","firmware_version":"0.1.0","sleep_seconds":300,"quiet_hours":"22-7","utc_offset_hours":1,"sensor_mode":"periodic","temperature_offset":4.0,"altitude_m":0,"asc_enabled":true,"mqtt_policy":"measurement=1+retain,error=1,calibration=1,command_response=1,diagnostic=0","wifi_ssid":"home","safe_mode":false}"#,
+    ),
+    (
+        "set_offset_success_volatile",
+        r#"{"device":"esp32-scd40","status":"set_offset_success","offset":4.0,"persisted":false}"#,
+    ),
+    (
+        "set_offset_rate_limited",
+        r#"{"device":"esp32-scd40","status":"set_offset_rate_limited","offset":4.0,"limit":4,"retry_after_seconds":43200}"#,
+    ),
+    (
+        "measurement_stamped",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42}"#,
+    ),
+    (
+        "key_order",
+        r#"{"humidity":41.3,"co2":612,"status":"success","temperature":22.4,"device":"esp32-scd40"}"#,
+    ),
+    (
+        "set_deep_sleep_time_error",
+        r#"{"device":"esp32-scd40","status":"set_deep_sleep_time_error","detail":"deep sleep time must be at least 1 second"}"#,
+    ),
+    (
+        "get_deep_sleep_time_error",
+        r#"{"device":"esp32-scd40","status":"get_deep_sleep_time_error","detail":"failed_to_read: NVS"}"#,
+    ),
+    (
+        "measurement_versioned",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42,"v":2}"#,
+    ),
+    (
+        "measurement_redelivered",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42,"v":2,"redelivered":true}"#,
+    ),
+    (
+        "measurement_with_fw_version",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42,"v":2,"fw_version":"0.4.0"}"#,
+    ),
+    (
+        "measurement_with_location",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42,"v":2,"fw_version":"0.4.0","location":"bedroom"}"#,
+    ),
+    (
+        "set_log_level_success",
+        r#"{"device":"esp32-scd40","status":"set_log_level_success","level":"debug","v":2}"#,
+    ),
+    (
+        "get_log_level_success",
+        r#"{"device":"esp32-scd40","status":"get_log_level_success","level":"info","v":2}"#,
+    ),
+    (
+        "log_lines",
+        r#"{"device":"esp32-scd40","status":"log_lines","lines":["I (812) sensor: Waiting for data... (attempt 1/15)","I (15830) sensor: Timeout waiting for sensor data"],"v":2}"#,
+    ),
+    (
+        "measurement_with_battery",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"battery_mv":3870,"battery_percent":72,"v":2}"#,
+    ),
+    (
+        "get_offset_success_in_reply",
+        r#"{"device":"esp32-scd40","status":"get_offset_success","offset":4.0,"v":2,"in_reply_to":7}"#,
+    ),
+    (
+        "set_adaptive_mode_success",
+        r#"{"device":"esp32-scd40","status":"set_adaptive_mode_success","enabled":true,"v":2}"#,
+    ),
+    (
+        "next_wake",
+        r#"{"device":"esp32-scd40","status":"next_wake","sleep_seconds":120,"adaptive":true,"delta_ppm":180,"v":2}"#,
+    ),
+    (
+        "next_wake_fixed",
+        r#"{"device":"esp32-scd40","status":"next_wake","sleep_seconds":300,"adaptive":false,"v":2}"#,
+    ),
+    (
+        "asc_set_success",
+        r#"{"device":"esp32-scd40","status":"asc_set_success","enabled":false,"v":2}"#,
+    ),
+    (
+        "asc_get_success",
+        r#"{"device":"esp32-scd40","status":"asc_get_success","enabled":true,"v":2}"#,
+    ),
+    (
+        "asc_error",
+        r#"{"device":"esp32-scd40","status":"asc_error","detail":"failed_to_set: I2c(Timeout)","v":2}"#,
+    ),
+    (
+        "altitude_set_success",
+        r#"{"device":"esp32-scd40","status":"altitude_set_success","meters":600,"v":2}"#,
+    ),
+    (
+        "altitude_get_success",
+        r#"{"device":"esp32-scd40","status":"altitude_get_success","meters":600,"v":2}"#,
+    ),
+    (
+        "altitude_error",
+        r#"{"device":"esp32-scd40","status":"altitude_error","detail":"out_of_range: 4000 m","v":2}"#,
+    ),
+    (
+        "ambient_pressure_set_success",
+        r#"{"device":"esp32-scd40","status":"ambient_pressure_set_success","pascals":94200,"v":2}"#,
+    ),
+    (
+        "ambient_pressure_error",
+        r#"{"device":"esp32-scd40","status":"ambient_pressure_error","detail":"failed_to_set: I2c(Timeout)","v":2}"#,
+    ),
+    (
+        "pending_confirmation",
+        r#"{"device":"esp32-scd40","status":"pending_confirmation","id":41,"command":"set_deep_sleep_time","wakes_left":3,"v":2}"#,
+    ),
+    (
+        "config_rolled_back",
+        r#"{"device":"esp32-scd40","status":"config_rolled_back","id":41,"command":"set_deep_sleep_time","v":2}"#,
+    ),
+    (
+        "confirm_config_error",
+        r#"{"device":"esp32-scd40","status":"confirm_config_error","detail":"nothing_pending: 41 is not on trial","v":2}"#,
+    ),
+    (
+        "diagnostics",
+        r#"{"device":"esp32-scd40","status":"diagnostics","rssi_dbm":-67,"free_heap_bytes":182344,"boot_count":41,"reset_reason":"deep_sleep","v":2}"#,
+    ),
+    (
+        "self_test_result",
+        r#"{"device":"esp32-scd40","status":"self_test_result","passed":false,"detail":"malfunction: the sensor reported a fault","v":2}"#,
+    ),
+    (
+        "factory_reset_success",
+        r#"{"device":"esp32-scd40","status":"factory_reset_success","v":2}"#,
+    ),
+    (
+        "factory_reset_error",
+        r#"{"device":"esp32-scd40","status":"factory_reset_error","detail":"not_confirmed: esp32-kitchen is not this device","v":2}"#,
+    ),
+    (
+        "serial_number",
+        r#"{"device":"esp32-scd40","status":"serial_number","serial":273325796834238,"v":2}"#,
+    ),
+    (
+        "rebooting",
+        r#"{"device":"esp32-scd40","status":"rebooting","detail":"restarting instead of sleeping","v":2}"#,
+    ),
+    (
+        "firmware_info",
+        r#"{"device":"esp32-scd40","status":"firmware_info","version":"0.4.0","build_time":"2025-01-15T12:00:00Z","idf_version":"v5.3.2","v":2,"fw_version":"0.4.0"}"#,
+    ),
+    (
+        "measurement_batch",
+        r#"{"device":"esp32-scd40","status":"measurement_batch","readings":[{"co2":640,"temperature":22.1,"humidity":42.0,"age_seconds":600},{"co2":612,"temperature":22.4,"humidity":41.3,"age_seconds":0}],"ts":1736942400123,"seq":42,"v":2}"#,
+    ),
+    (
+        "radio_skipped",
+        r#"{"device":"esp32-scd40","status":"radio_skipped","skipped_wakes":3,"lowest_mv":3410,"threshold_mv":3500,"v":2}"#,
+    ),
+    (
+        "fault_armed",
+        r#"{"device":"esp32-scd40","status":"fault_armed","kind":"co2_zero","v":2}"#,
+    ),
+    (
+        "error_with_code",
+        r#"{"device":"esp32-scd40","status":"error","code":"sensor_timeout","detail":"Measurement timed out","v":2}"#,
+    ),
+    (
+        "frc_error_with_code",
+        r#"{"device":"esp32-scd40","status":"frc_error","code":"i2c_error","detail":"I2C(Timeout)","v":2}"#,
+    ),
+    (
+        "entering_sleep",
+        r#"{"device":"esp32-scd40","status":"entering_sleep","sleep_seconds":300,"next_wake_unix":1736942700,"v":2}"#,
+    ),
+    (
+        "entering_sleep_unsynced",
+        r#"{"device":"esp32-scd40","status":"entering_sleep","sleep_seconds":300,"v":2}"#,
+    ),
+    (
+        "measurement_injected",
+        r#"{"device":"esp32-scd40","status":"success","co2":0,"temperature":22.4,"humidity":41.3,"ts":1736942400123,"seq":42,"v":2,"injected":true}"#,
+    ),
+    (
+        "measurement_with_flags",
+        r#"{"device":"esp32-scd40","status":"success","co2":612,"temperature":22.4,"humidity":41.3,"flags":{"first_after_boot":true,"sensor_warmup_incomplete":false,"retried_read":true},"v":2}"#,
+    ),
+    (
+        "command_accepted",
+        r#"{"device":"esp32-scd40","status":"command_ack","cmd":"start_frc","accepted":true,"v":2}"#,
+    ),
+    (
+        "command_rejected",
+        r#"{"device":"esp32-scd40","status":"command_ack","cmd":"start_frc","accepted":false,"detail":"FRC target 5000 ppm is outside 400 to 2000 ppm","v":2}"#,
+    ),
+    (
+        "altitude_error_with_code",
+        r#"{"device":"esp32-scd40","status":"altitude_error","code":"i2c_error","detail":"failed_to_set: I2C(Nack)","v":2}"#,
+    ),
+    (
+        "persist_rate_limited",
+        r#"{"device":"esp32-scd40","status":"persist_rate_limited","command":"set_asc","limit":4,"retry_after_seconds":43200,"v":2}"#,
+    ),
+];
+
+const COMMAND_FIXTURES: &[(&str, &str)] = &[
+    ("noop", r#"{"cmd":"noop"}"#),
+    ("start_frc", r#"{"cmd":"start_frc","target_ppm":420}"#),
+    ("start_frc_default", r#"{"cmd":"start_frc"}"#),
+    (
+        "set_temp_offset",
+        r#"{"cmd":"set_temp_offset","offset":4.0}"#,
+    ),
+    ("get_temp_offset", r#"{"cmd":"get_temp_offset"}"#),
+    (
+        "set_deep_sleep_time",
+        r#"{"cmd":"set_deep_sleep_time","seconds":600}"#,
+    ),
+    ("get_deep_sleep_time", r#"{"cmd":"get_deep_sleep_time"}"#),
+    (
+        "set_mqtt_policy",
+        r#"{"cmd":"set_mqtt_policy","class":"measurement","qos":1,"retain":true}"#,
+    ),
+    (
+        "set_mqtt_policy_no_retain",
+        r#"{"cmd":"set_mqtt_policy","class":"error","qos":1}"#,
+    ),
+    (
+        "batch",
+        r#"{"cmd":"batch","commands":[{"cmd":"start_frc","target_ppm":420},{"cmd":"get_temp_offset"}]}"#,
+    ),
+    (
+        "batch_deferred",
+        r#"{"cmd":"batch","commands":[{"cmd":"set_temp_offset","offset":4.0}],"deferred":true}"#,
+    ),
+    (
+        "ota",
+        r#"{"cmd":"ota","url":"http://firmware.local/air-quality-0.4.0.bin"}"#,
+    ),
+    ("get_config", r#"{"cmd":"get_config"}"#),
+    (
+        "set_temp_offset_volatile",
+        r#"{"cmd":"set_temp_offset","offset":4.0,"persist":false}"#,
+    ),
+    (
+        "set_log_level",
+        r#"{"cmd":"set_log_level","level":"debug"}"#,
+    ),
+    ("get_log_level", r#"{"cmd":"get_log_level"}"#),
+    (
+        "set_adaptive_mode",
+        r#"{"cmd":"set_adaptive_mode","enabled":false}"#,
+    ),
+    ("set_asc", r#"{"cmd":"set_asc","enabled":false}"#),
+    ("get_asc", r#"{"cmd":"get_asc"}"#),
+    ("set_altitude", r#"{"cmd":"set_altitude","meters":600}"#),
+    ("get_altitude", r#"{"cmd":"get_altitude"}"#),
+    (
+        "set_ambient_pressure",
+        r#"{"cmd":"set_ambient_pressure","pascals":94200}"#,
+    ),
+    (
+        "confirm_config",
+        r#"{"cmd":"confirm_config","pending_id":41}"#,
+    ),
+    ("self_test", r#"{"cmd":"self_test"}"#),
+    (
+        "factory_reset",
+        r#"{"cmd":"factory_reset","confirm":"esp32-scd40"}"#,
+    ),
+    ("get_serial_number", r#"{"cmd":"get_serial_number"}"#),
+    ("reboot", r#"{"cmd":"reboot"}"#),
+    ("get_firmware_info", r#"{"cmd":"get_firmware_info"}"#),
+    (
+        "inject_fault",
+        r#"{"cmd":"inject_fault","kind":"sensor_timeout"}"#,
+    ),
+    (
+        "get_temp_offset_with_id",
+        r#"{"id":7,"cmd":"get_temp_offset"}"#,
+    ),
+];
+
+fn expected_message(name: &str) -> DeviceMessage {
+    let payload = match name {
+        "measurement"
+        | "measurement_stamped"
+        | "key_order"
+        | "measurement_versioned"
+        | "measurement_redelivered"
+        | "measurement_with_fw_version"
+        | "measurement_with_location" => DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+        "measurement_with_battery" => {
+            DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3870, 72).unwrap()
+        }
+        "error" => DevicePayload::error("Measurement timed out"),
+        "error_with_code" => {
+            DevicePayload::coded_error(ErrorCode::SensorTimeout, "Measurement timed out")
+        }
+        "frc_start" => DevicePayload::frc_start(422),
+        "frc_warmup_complete" => DevicePayload::FrcWarmupComplete {
+            detail: "Took 3 minutes".into(),
+        },
+        "frc_calibrating" => DevicePayload::FrcCalibrating { target_ppm: 422 },
+        "frc_success" => DevicePayload::frc_success(32791),
+        "frc_error" => DevicePayload::FrcError {
+            code: ErrorCode::Other,
+            detail: "I2C(Timeout)".into(),
+        },
+        "frc_error_with_code" => DevicePayload::FrcError {
+            code: ErrorCode::I2cError,
+            detail: "I2C(Timeout)".into(),
+        },
+        "set_offset_success" => DevicePayload::SetOffsetSuccess {
+            offset: 4.0,
+            persisted: true,
+        },
+        "set_offset_success_volatile" => DevicePayload::SetOffsetSuccess {
+            offset: 4.0,
+            persisted: false,
+        },
+        "set_offset_rate_limited" => DevicePayload::SetOffsetRateLimited {
+            offset: 4.0,
+            limit: 4,
+            retry_after_seconds: 43_200,
+        },
+        "set_offset_error" => DevicePayload::SetOffsetError {
+            code: ErrorCode::Other,
+            detail: "failed_to_persist: I2C(Nack)".into(),
+        },
+        "get_offset_success" => DevicePayload::GetOffsetSuccess { offset: 4.0 },
+        "get_offset_error" => DevicePayload::GetOffsetError {
+            code: ErrorCode::Other,
+            detail: "failed_to_get: I2C(Nack)".into(),
+        },
+        "set_deep_sleep_time_success" => DevicePayload::SetDeepSleepTimeSuccess { seconds: 600 },
+        "get_deep_sleep_time_success" => DevicePayload::GetDeepSleepTimeSuccess { seconds: 300 },
+        "alive" => DevicePayload::Alive {
+            uptime_seconds: 3600,
+        },
+        "set_mqtt_policy_success" => DevicePayload::SetMqttPolicySuccess {
+            class: PayloadClass::Diagnostic,
+            qos: 0,
+            retain: false,
+        },
+        "set_mqtt_policy_error" => DevicePayload::SetMqttPolicyError {
+            code: ErrorCode::Other,
+            detail: "QoS must be 0, 1 or 2".into(),
+        },
+        "commands_deferred" => DevicePayload::CommandsDeferred {
+            running: "start_frc".to_string(),
+            deferred: vec![DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true,
+            }],
+        },
+        "bus_recovery" => DevicePayload::BusRecovery {
+            attempt: 1,
+            pulses: Some(3),
+            recovered: true,
+        },
+        "bus_recovery_sda_stuck" => DevicePayload::BusRecovery {
+            attempt: 2,
+            pulses: None,
+            recovered: false,
+        },
+        "ota_progress" => DevicePayload::OtaProgress { percent: 40 },
+        "ota_success" => DevicePayload::OtaSuccess {
+            version: "0.4.0".to_string(),
+        },
+        "ota_error" => DevicePayload::OtaError {
+            code: ErrorCode::Other,
+            detail: "OTA is not supported by this firmware".into(),
+        },
+        "wake_profile" => DevicePayload::WakeProfile {
+            awake_ms: 9800,
+            sensor_ms: 6200,
+            network_ms: 3100,
+            saved_ms: 3000,
+        },
+        "config" => DevicePayload::Config(DeviceConfig {
+            firmware_version: "0.1.0".to_string(),
+            sleep_seconds: 300,
+            quiet_hours: Some("22-7".to_string()),
+            utc_offset_hours: 1,
+            sensor_mode: SensorMode::Periodic,
+            temperature_offset: Some(4.0),
+            altitude_m: Some(0),
+            ambient_pressure_hpa: None,
+            asc_enabled: Some(true),
+            alarm_threshold_ppm: None,
+            mqtt_policy:
+                "measurement=1+retain,error=1,calibration=1,command_response=1,diagnostic=0"
+                    .to_string(),
+            wifi_ssid: "home".to_string(),
+            safe_mode: false,
+        }),
+        "set_deep_sleep_time_error" => DevicePayload::SetDeepSleepTimeError {
+            code: ErrorCode::Other,
+            detail: "deep sleep time must be at least 1 second".into(),
+        },
+        "get_deep_sleep_time_error" => DevicePayload::GetDeepSleepTimeError {
+            code: ErrorCode::Other,
+            detail: "failed_to_read: NVS".into(),
+        },
+        "set_log_level_success" => DevicePayload::SetLogLevelSuccess {
+            level: LogLevel::Debug,
+        },
+        "get_log_level_success" => DevicePayload::GetLogLevelSuccess {
+            level: LogLevel::Info,
+        },
+        "get_offset_success_in_reply" => DevicePayload::GetOffsetSuccess { offset: 4.0 },
+        "log_lines" => DevicePayload::LogLines {
+            lines: vec![
+                "I (812) sensor: Waiting for data... (attempt 1/15)".to_string(),
+                "I (15830) sensor: Timeout waiting for sensor data".to_string(),
+            ],
+        },
+        "set_adaptive_mode_success" => DevicePayload::SetAdaptiveModeSuccess { enabled: true },
+        "next_wake" => DevicePayload::NextWake {
+            sleep_seconds: 120,
+            adaptive: true,
+            delta_ppm: Some(180),
+        },
+        "next_wake_fixed" => DevicePayload::NextWake {
+            sleep_seconds: 300,
+            adaptive: false,
+            delta_ppm: None,
+        },
+        "asc_set_success" => DevicePayload::AscSetSuccess { enabled: false },
+        "asc_get_success" => DevicePayload::AscGetSuccess { enabled: true },
+        "asc_error" => DevicePayload::AscError {
+            code: ErrorCode::Other,
+            detail: "failed_to_set: I2c(Timeout)".into(),
+        },
+        "altitude_set_success" => DevicePayload::AltitudeSetSuccess { meters: 600 },
+        "altitude_get_success" => DevicePayload::AltitudeGetSuccess { meters: 600 },
+        "altitude_error" => DevicePayload::AltitudeError {
+            code: ErrorCode::Other,
+            detail: "out_of_range: 4000 m".into(),
+        },
+        "ambient_pressure_set_success" => {
+            DevicePayload::AmbientPressureSetSuccess { pascals: 94200 }
+        }
+        "ambient_pressure_error" => DevicePayload::AmbientPressureError {
+            code: ErrorCode::Other,
+            detail: "failed_to_set: I2c(Timeout)".into(),
+        },
+        "pending_confirmation" => DevicePayload::PendingConfirmation {
+            id: 41,
+            command: "set_deep_sleep_time".to_string(),
+            wakes_left: 3,
+        },
+        "config_rolled_back" => DevicePayload::ConfigRolledBack {
+            id: 41,
+            command: "set_deep_sleep_time".to_string(),
+        },
+        "confirm_config_error" => DevicePayload::ConfirmConfigError {
+            code: ErrorCode::Other,
+            detail: "nothing_pending: 41 is not on trial".into(),
+        },
+        "diagnostics" => DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep"),
+        "self_test_result" => DevicePayload::SelfTestResult {
+            passed: false,
+            detail: "malfunction: the sensor reported a fault".into(),
+        },
+        "factory_reset_success" => DevicePayload::FactoryResetSuccess,
+        "factory_reset_error" => DevicePayload::FactoryResetError {
+            code: ErrorCode::Other,
+            detail: "not_confirmed: esp32-kitchen is not this device".into(),
+        },
+        "serial_number" => DevicePayload::SerialNumber {
+            serial: 273_325_796_834_238,
+        },
+        "rebooting" => DevicePayload::Rebooting {
+            detail: "restarting instead of sleeping".into(),
+        },
+        "firmware_info" => DevicePayload::FirmwareInfo {
+            version: "0.4.0".to_string(),
+            build_time: "2025-01-15T12:00:00Z".to_string(),
+            idf_version: "v5.3.2".to_string(),
+        },
+        "measurement_batch" => DevicePayload::MeasurementBatch {
+            readings: vec![
+                BatchedReading {
+                    co2: 640,
+                    temperature: 22.1,
+                    humidity: 42.0,
+                    age_seconds: 600,
+                },
+                BatchedReading {
+                    co2: 612,
+                    temperature: 22.4,
+                    humidity: 41.3,
+                    age_seconds: 0,
+                },
+            ],
+        },
+        "radio_skipped" => DevicePayload::RadioSkipped {
+            skipped_wakes: 3,
+            lowest_mv: 3410,
+            threshold_mv: 3500,
+        },
+        "fault_armed" => DevicePayload::FaultArmed {
+            kind: FaultKind::Co2Zero,
+        },
+        "entering_sleep" => DevicePayload::EnteringSleep {
+            sleep_seconds: 300,
+            next_wake_unix: Some(1_736_942_700),
+        },
+        "entering_sleep_unsynced" => DevicePayload::EnteringSleep {
+            sleep_seconds: 300,
+            next_wake_unix: None,
+        },
+        "measurement_injected" => DevicePayload::try_measurement(0, 22.4, 41.3).unwrap(),
+        "measurement_with_flags" => DevicePayload::try_measurement(612, 22.4, 41.3)
+            .unwrap()
+            .flagged(MeasurementFlags {
+                first_after_boot: true,
+                sensor_warmup_incomplete: false,
+                retried_read: true,
+            }),
+        "command_accepted" => DevicePayload::CommandAck {
+            cmd: "start_frc".to_string(),
+            accepted: true,
+            detail: None,
+        },
+        "command_rejected" => DevicePayload::CommandAck {
+            cmd: "start_frc".to_string(),
+            accepted: false,
+            detail: Some("FRC target 5000 ppm is outside 400 to 2000 ppm".into()),
+        },
+        "altitude_error_with_code" => DevicePayload::AltitudeError {
+            code: ErrorCode::I2cError,
+            detail: "failed_to_set: I2C(Nack)".into(),
+        },
+        "persist_rate_limited" => DevicePayload::PersistRateLimited {
+            command: "set_asc".to_string(),
+            limit: 4,
+            retry_after_seconds: 43_200,
+        },
+        other => panic!("no expectation for message fixture '{}'", other),
+    };
+    let message = DeviceMessage::new("esp32-scd40", payload);
+    match name {
+        "measurement_versioned" => message.stamped(Some(1_736_942_400_123), 42),
+        "measurement_redelivered" => message.stamped(Some(1_736_942_400_123), 42).redelivered(),
+        "measurement_with_fw_version" => message
+            .stamped(Some(1_736_942_400_123), 42)
+            .with_fw_version("0.4.0"),
+        "measurement_with_location" => message
+            .stamped(Some(1_736_942_400_123), 42)
+            .with_fw_version("0.4.0")
+            .with_location("bedroom"),
+        "firmware_info" => message.with_fw_version("0.4.0"),
+        "measurement_batch" => message.stamped(Some(1_736_942_400_123), 42),
+        "measurement_injected" => message.stamped(Some(1_736_942_400_123), 42).injected(),
+        "set_log_level_success"
+        | "get_log_level_success"
+        | "log_lines"
+        | "set_adaptive_mode_success"
+        | "measurement_with_battery"
+        | "next_wake"
+        | "next_wake_fixed"
+        | "diagnostics"
+        | "asc_set_success"
+        | "asc_get_success"
+        | "asc_error"
+        | "altitude_set_success"
+        | "altitude_get_success"
+        | "altitude_error"
+        | "ambient_pressure_set_success"
+        | "ambient_pressure_error"
+        | "pending_confirmation"
+        | "config_rolled_back"
+        | "confirm_config_error"
+        | "self_test_result"
+        | "factory_reset_success"
+        | "factory_reset_error"
+        | "serial_number"
+        | "rebooting"
+        | "radio_skipped"
+        | "fault_armed"
+        | "error_with_code"
+        | "frc_error_with_code"
+        | "entering_sleep"
+        | "entering_sleep_unsynced"
+        | "measurement_with_flags"
+        | "command_accepted"
+        | "command_rejected"
+        | "altitude_error_with_code"
+        | "persist_rate_limited" => message,
+        "get_offset_success_in_reply" => message.replying_to(7),
+        // Fixtures from before the protocol version was sent
+        "measurement_stamped" => DeviceMessage {
+            version: LEGACY_PROTOCOL_VERSION,
+            ..message.stamped(Some(1_736_942_400_123), 42)
+        },
+        _ => DeviceMessage {
+            version: LEGACY_PROTOCOL_VERSION,
+            ..message
+        },
+    }
+}
+
+fn expected_command(name: &str) -> DeviceCommand {
+    match name {
+        "noop" => DeviceCommand::NoOp,
+        "start_frc" => DeviceCommand::StartFrc { target_ppm: 420 },
+        "start_frc_default" => DeviceCommand::StartFrc { target_ppm: 422 },
+        "set_temp_offset" => DeviceCommand::SetTempOffset {
+            offset: 4.0,
+            persist: true,
+        },
+        "set_temp_offset_volatile" => DeviceCommand::SetTempOffset {
+            offset: 4.0,
+            persist: false,
+        },
+        "get_temp_offset" => DeviceCommand::GetTempOffset,
+        "set_deep_sleep_time" => DeviceCommand::SetDeepSleepTime { seconds: 600 },
+        "get_deep_sleep_time" => DeviceCommand::GetDeepSleepTime,
+        "set_mqtt_policy" => DeviceCommand::SetMqttPolicy {
+            class: PayloadClass::Measurement,
+            qos: 1,
+            retain: true,
+        },
+        "set_mqtt_policy_no_retain" => DeviceCommand::SetMqttPolicy {
+            class: PayloadClass::Error,
+            qos: 1,
+            retain: false,
+        },
+        "ota" => DeviceCommand::Ota {
+            url: "http://firmware.local/air-quality-0.4.0.bin".to_string(),
+        },
+        "get_config" => DeviceCommand::GetConfig,
+        "set_log_level" => DeviceCommand::SetLogLevel {
+            level: LogLevel::Debug,
+        },
+        "get_log_level" => DeviceCommand::GetLogLevel,
+        "set_adaptive_mode" => DeviceCommand::SetAdaptiveMode { enabled: false },
+        "set_asc" => DeviceCommand::SetAsc { enabled: false },
+        "get_asc" => DeviceCommand::GetAsc,
+        "set_altitude" => DeviceCommand::SetAltitude { meters: 600 },
+        "get_altitude" => DeviceCommand::GetAltitude,
+        "set_ambient_pressure" => DeviceCommand::SetAmbientPressure { pascals: 94200 },
+        "confirm_config" => DeviceCommand::ConfirmConfig { pending_id: 41 },
+        "self_test" => DeviceCommand::SelfTest,
+        "factory_reset" => DeviceCommand::FactoryReset {
+            confirm: "esp32-scd40".to_string(),
+        },
+        "get_serial_number" => DeviceCommand::GetSerialNumber,
+        "reboot" => DeviceCommand::Reboot,
+        "get_firmware_info" => DeviceCommand::GetFirmwareInfo,
+        "inject_fault" => DeviceCommand::InjectFault {
+            kind: FaultKind::SensorTimeout,
+        },
+        // Firmware from before command ids reads the command alone
+        "get_temp_offset_with_id" => DeviceCommand::GetTempOffset,
+        other => panic!("no expectation for command fixture '{}'", other),
+    }
+}
+
+/// The batch fixtures, which parse as a `DeviceCommandBatch` rather than a
+/// `DeviceCommand`
+fn expected_batch(name: &str) -> Option<DeviceCommandBatch> {
+    let (commands, deferred) = match name {
+        "batch" => (
+            vec![
+                DeviceCommand::StartFrc { target_ppm: 420 },
+                DeviceCommand::GetTempOffset,
+            ],
+            false,
+        ),
+        "batch_deferred" => (
+            vec![DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true,
+            }],
+            true,
+        ),
+        _ => return None,
+    };
+    Some(DeviceCommandBatch {
+        deferred,
+        ..DeviceCommandBatch::new(commands).unwrap()
+    })
+}
+
+#[test]
+fn message_fixtures_parse() {
+    for (name, json) in MESSAGE_FIXTURES {
+        let parsed = DeviceMessage::from_json(json)
+            .unwrap_or_else(|e| panic!("fixture '{}' no longer parses: {}", name, e));
+        assert_eq!(parsed, expected_message(name), "fixture '{}'", name);
+    }
+}
+
+#[test]
+fn command_fixtures_parse() {
+    for (name, json) in COMMAND_FIXTURES {
+        if let Some(batch) = expected_batch(name) {
+            let parsed = DeviceCommandBatch::from_json(json)
+                .unwrap_or_else(|e| panic!("fixture '{}' no longer parses: {}", name, e));
+            assert_eq!(parsed, batch, "fixture '{}'", name);
+            continue;
+        }
+        let parsed = DeviceCommand::from_json(json)
+            .unwrap_or_else(|e| panic!("fixture '{}' no longer parses: {}", name, e));
+        assert_eq!(parsed, expected_command(name), "fixture '{}'", name);
+    }
+}
+
+#[test]
+fn command_fixtures_parse_with_ids() {
+    for (name, json) in COMMAND_FIXTURES {
+        let parsed = CommandMessage::from_json(json)
+            .unwrap_or_else(|e| panic!("fixture '{}' no longer parses: {}", name, e));
+        let id = match *name {
+            "get_temp_offset_with_id" => Some(7),
+            _ => None,
+        };
+        assert_eq!(parsed.id(), id, "fixture '{}'", name);
+        match parsed {
+            CommandMessage::Batch(envelope) => {
+                assert_eq!(
+                    Some(envelope.command),
+                    expected_batch(name),
+                    "fixture '{}'",
+                    name
+                )
+            }
+            CommandMessage::Single(envelope) => {
+                assert_eq!(
+                    envelope.command,
+                    expected_command(name),
+                    "fixture '{}'",
+                    name
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn fixtures_match_the_json_schemas() {
+    use shared_types::schema;
+
+    let check = |schema: schemars::Schema, fixtures: &[(&str, &str)]| {
+        let validator = jsonschema::validator_for(schema.as_value()).unwrap();
+        for (name, json) in fixtures {
+            let instance: serde_json::Value = serde_json::from_str(json).unwrap();
+            let errors: Vec<String> = validator
+                .iter_errors(&instance)
+                .map(|e| e.to_string())
+                .collect();
+            assert!(errors.is_empty(), "fixture '{}': {:?}", name, errors);
+        }
+        validator
+    };
+    let messages = check(schema::device_message(), MESSAGE_FIXTURES);
+    let commands = check(schema::device_command(), COMMAND_FIXTURES);
+
+    // The schemas are strict enough to catch a guessed field name
+    for json in [
+        r#"{"device":"esp32-scd40","status":"success","co2_ppm":612,"temperature":22.4,"humidity":41.3}"#,
+        r#"{"device":"esp32-scd40","status":"measurement","co2":612,"temperature":22.4,"humidity":41.3}"#,
+        r#"{"status":"alive","uptime_seconds":60}"#,
+    ] {
+        assert!(
+            !messages.is_valid(&serde_json::from_str(json).unwrap()),
+            "{}",
+            json
+        );
+    }
+    for json in [
+        r#"{"command":"noop"}"#,
+        r#"{"cmd":"set_temp_offset","offset":"4"}"#,
+    ] {
+        assert!(
+            !commands.is_valid(&serde_json::from_str(json).unwrap()),
+            "{}",
+            json
+        );
+    }
+}
+
+#[test]
+fn missing_device_is_rejected() {
+    let json = r#"{"status":"alive","uptime_seconds":1}"#;
+    assert!(DeviceMessage::from_json(json).is_err());
+}
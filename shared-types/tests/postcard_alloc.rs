@@ -0,0 +1,96 @@
+//! Encoding to postcard must not touch the heap: the firmware encodes into
+//! a buffer on its stack, and a message that wraps its payload in
+//! `fw_version`, `location` and the other layers is encoded from borrowed
+//! mirrors rather than from a boxed copy.
+#![cfg(feature = "postcard")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use shared_types::{
+    BatchedReading, DeviceCommand, DeviceCommandBatch, DeviceMessage, DevicePayload, ErrorCode,
+};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// How many allocations `f` made on this thread
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn wrapped_messages_encode_without_allocating() {
+    let mut msg = DeviceMessage::new(
+        "esp32-scd40",
+        DevicePayload::coded_error(ErrorCode::SensorTimeout, "Measurement timed out"),
+    );
+    msg.fw_version = Some("1.4.0".into());
+    msg.location = Some("bedroom".into());
+    msg.injected = true;
+    msg.redelivered = true;
+
+    let mut buf = [0u8; 256];
+    assert_eq!(
+        allocations(|| {
+            msg.to_postcard(&mut buf).unwrap();
+        }),
+        0
+    );
+    assert_eq!(
+        DeviceMessage::from_postcard(msg.to_postcard(&mut buf).unwrap()).unwrap(),
+        msg
+    );
+}
+
+#[test]
+fn batches_encode_without_allocating() {
+    let reading = BatchedReading {
+        co2: 612,
+        temperature: 22.4,
+        humidity: 41.3,
+        age_seconds: 300,
+    };
+    let msg = DeviceMessage::new(
+        "esp32-scd40",
+        DevicePayload::MeasurementBatch {
+            readings: vec![reading; 3],
+        },
+    );
+    let batch = DeviceCommandBatch::new(vec![
+        DeviceCommand::GetAsc,
+        DeviceCommand::SetTempOffset {
+            offset: 4.0,
+            persist: true,
+        },
+    ])
+    .unwrap();
+
+    let mut buf = [0u8; 256];
+    assert_eq!(
+        allocations(|| {
+            msg.to_postcard(&mut buf).unwrap();
+            batch.to_postcard(&mut buf).unwrap();
+        }),
+        0
+    );
+}
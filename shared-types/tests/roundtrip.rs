@@ -0,0 +1,645 @@
+//! Property tests for the serde representation of the wire types.
+//!
+//! `DeviceMessage` flattens an internally tagged enum, which is the serde
+//! combination most likely to break silently, so every variant is generated
+//! and pushed through each available encoding. Measurements also go
+//! through the line protocol used for InfluxDB and the spools.
+
+use proptest::prelude::*;
+use shared_types::device_config::{DeviceConfig, SensorMode};
+use shared_types::fault_injection::FaultKind;
+use shared_types::line_protocol::{self, MeasurementFields};
+use shared_types::log_level::LogLevel;
+use shared_types::mqtt_policy::{MqttPolicy, PayloadClass};
+use shared_types::{
+    BatchedReading, CommandEnvelope, CommandMessage, Detail, DeviceCommand, DeviceCommandBatch,
+    DeviceMessage, DevicePayload, ErrorCode, Label, MAX_BATCH_COMMANDS, MeasurementFlags,
+};
+
+/// Floats are generated on a 0.01 grid so the JSON text form maps back to
+/// the exact same `f32`.
+fn hundredths(min: i32, max: i32) -> impl Strategy<Value = f32> {
+    (min..=max).prop_map(|v| v as f32 / 100.0)
+}
+
+fn detail() -> impl Strategy<Value = String> {
+    "\\PC{0,64}"
+}
+
+/// A `detail` field, cut to what fits with `no_alloc` before the round trip
+fn payload_detail() -> impl Strategy<Value = Detail> {
+    detail().prop_map(|detail| Detail::from(detail.as_str()))
+}
+
+/// A `fw_version`, `location` or `reset_reason`, likewise cut to fit
+fn label() -> impl Strategy<Value = Label> {
+    detail().prop_map(|label| Label::from(label.as_str()))
+}
+
+fn device_name() -> impl Strategy<Value = String> {
+    "[a-z0-9][a-z0-9-]{0,31}"
+}
+
+fn payload_class() -> impl Strategy<Value = PayloadClass> {
+    proptest::sample::select(PayloadClass::ALL.to_vec())
+}
+
+fn log_level() -> impl Strategy<Value = LogLevel> {
+    proptest::sample::select(LogLevel::ALL.to_vec())
+}
+
+fn error_code() -> impl Strategy<Value = ErrorCode> {
+    proptest::sample::select(ErrorCode::ALL.to_vec())
+}
+
+fn measurement_flags() -> impl Strategy<Value = MeasurementFlags> {
+    (any::<bool>(), any::<bool>(), any::<bool>()).prop_map(
+        |(first_after_boot, sensor_warmup_incomplete, retried_read)| MeasurementFlags {
+            first_after_boot,
+            sensor_warmup_incomplete,
+            retried_read,
+        },
+    )
+}
+
+fn fault_kind() -> impl Strategy<Value = FaultKind> {
+    proptest::sample::select(FaultKind::ALL.to_vec())
+}
+
+fn arb_config() -> impl Strategy<Value = DeviceConfig> {
+    let sensor_mode = proptest::sample::select(vec![
+        SensorMode::Periodic,
+        SensorMode::LowPowerPeriodic,
+        SensorMode::SingleShot,
+    ]);
+    (
+        (
+            "[0-9]{1,2}\\.[0-9]{1,2}\\.[0-9]{1,2}",
+            any::<u64>(),
+            proptest::option::of("[0-9]{1,2}-[0-9]{1,2}"),
+            -12i8..=14,
+            sensor_mode,
+        ),
+        (
+            proptest::option::of(hundredths(0, 2_000)),
+            proptest::option::of(any::<u16>()),
+            proptest::option::of(700u16..=1200),
+            proptest::option::of(any::<bool>()),
+            proptest::option::of(any::<u16>()),
+        ),
+        ("\\PC{0,32}", any::<bool>()),
+    )
+        .prop_map(
+            |(
+                (firmware_version, sleep_seconds, quiet_hours, utc_offset_hours, sensor_mode),
+                (
+                    temperature_offset,
+                    altitude_m,
+                    ambient_pressure_hpa,
+                    asc_enabled,
+                    alarm_threshold_ppm,
+                ),
+                (wifi_ssid, safe_mode),
+            )| DeviceConfig {
+                firmware_version,
+                sleep_seconds,
+                quiet_hours,
+                utc_offset_hours,
+                sensor_mode,
+                temperature_offset,
+                altitude_m,
+                ambient_pressure_hpa,
+                asc_enabled,
+                alarm_threshold_ppm,
+                mqtt_policy: MqttPolicy::DEFAULT.to_string(),
+                wifi_ssid,
+                safe_mode,
+            },
+        )
+}
+
+/// Through the checked constructor, which takes plain numbers with and
+/// without the `validated` feature
+fn measurement(
+    co2: u16,
+    temperature: f32,
+    humidity: f32,
+    battery_mv: Option<u16>,
+    battery_percent: Option<u8>,
+    flags: MeasurementFlags,
+) -> DevicePayload {
+    let mut payload = DevicePayload::try_measurement(co2, temperature, humidity).unwrap();
+    if let DevicePayload::MeasurementSuccess {
+        battery_mv: mv,
+        battery_percent: percent,
+        ..
+    } = &mut payload
+    {
+        *mv = battery_mv;
+        *percent = battery_percent;
+    }
+    payload.flagged(flags)
+}
+
+fn arb_payload() -> impl Strategy<Value = DevicePayload> {
+    prop_oneof![
+        (
+            0u16..=40_000,
+            hundredths(-4_500, 13_000),
+            hundredths(0, 10_000),
+            proptest::option::of(any::<u16>()),
+            proptest::option::of(0u8..=100),
+            measurement_flags()
+        )
+            .prop_map(
+                |(co2, temperature, humidity, battery_mv, battery_percent, flags)| {
+                    measurement(
+                        co2,
+                        temperature,
+                        humidity,
+                        battery_mv,
+                        battery_percent,
+                        flags,
+                    )
+                }
+            ),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::Error { code, detail }),
+        any::<u16>().prop_map(|target_ppm| DevicePayload::FrcStart { target_ppm }),
+        payload_detail().prop_map(|detail| DevicePayload::FrcWarmupComplete { detail }),
+        any::<u16>().prop_map(|target_ppm| DevicePayload::FrcCalibrating { target_ppm }),
+        any::<u16>().prop_map(|correction| DevicePayload::FrcSuccess { correction }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::FrcError { code, detail }),
+        (hundredths(0, 2_000), any::<bool>())
+            .prop_map(|(offset, persisted)| DevicePayload::SetOffsetSuccess { offset, persisted }),
+        (hundredths(0, 2_000), any::<u16>(), any::<u64>()).prop_map(
+            |(offset, limit, retry_after_seconds)| DevicePayload::SetOffsetRateLimited {
+                offset,
+                limit,
+                retry_after_seconds,
+            }
+        ),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::SetOffsetError { code, detail }),
+        hundredths(0, 2_000).prop_map(|offset| DevicePayload::GetOffsetSuccess { offset }),
+        any::<u64>().prop_map(|seconds| DevicePayload::SetDeepSleepTimeSuccess { seconds }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::SetDeepSleepTimeError { code, detail }),
+        any::<u64>().prop_map(|seconds| DevicePayload::GetDeepSleepTimeSuccess { seconds }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::GetDeepSleepTimeError { code, detail }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::GetOffsetError { code, detail }),
+        any::<u64>().prop_map(|uptime_seconds| DevicePayload::Alive { uptime_seconds }),
+        (payload_class(), 0u8..=2, any::<bool>()).prop_map(|(class, qos, retain)| {
+            DevicePayload::SetMqttPolicySuccess { class, qos, retain }
+        }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::SetMqttPolicyError { code, detail }),
+        (
+            "[a-z_]{1,24}",
+            proptest::collection::vec(arb_command(), 0..4)
+        )
+            .prop_map(|(running, deferred)| DevicePayload::CommandsDeferred { running, deferred }),
+        (any::<u8>(), proptest::option::of(0u8..=9), any::<bool>()).prop_map(
+            |(attempt, pulses, recovered)| DevicePayload::BusRecovery {
+                attempt,
+                pulses,
+                recovered,
+            }
+        ),
+        (0u8..=100).prop_map(|percent| DevicePayload::OtaProgress { percent }),
+        "[0-9]{1,2}\\.[0-9]{1,2}\\.[0-9]{1,2}"
+            .prop_map(|version| DevicePayload::OtaSuccess { version }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::OtaError { code, detail }),
+        (any::<u32>(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(
+            |(awake_ms, sensor_ms, network_ms, saved_ms)| DevicePayload::WakeProfile {
+                awake_ms,
+                sensor_ms,
+                network_ms,
+                saved_ms,
+            }
+        ),
+        arb_config().prop_map(DevicePayload::Config),
+        log_level().prop_map(|level| DevicePayload::SetLogLevelSuccess { level }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::SetLogLevelError { code, detail }),
+        log_level().prop_map(|level| DevicePayload::GetLogLevelSuccess { level }),
+        proptest::collection::vec(detail(), 0..8)
+            .prop_map(|lines| DevicePayload::LogLines { lines }),
+        any::<bool>().prop_map(|enabled| DevicePayload::SetAdaptiveModeSuccess { enabled }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::SetAdaptiveModeError { code, detail }),
+        (
+            any::<u64>(),
+            any::<bool>(),
+            proptest::option::of(any::<u16>())
+        )
+            .prop_map(
+                |(sleep_seconds, adaptive, delta_ppm)| DevicePayload::NextWake {
+                    sleep_seconds,
+                    adaptive,
+                    delta_ppm,
+                }
+            ),
+        (any::<i8>(), any::<u32>(), any::<u32>(), label()).prop_map(
+            |(rssi_dbm, free_heap_bytes, boot_count, reset_reason)| {
+                DevicePayload::Diagnostics {
+                    rssi_dbm,
+                    free_heap_bytes,
+                    boot_count,
+                    reset_reason,
+                }
+            }
+        ),
+        any::<bool>().prop_map(|enabled| DevicePayload::AscSetSuccess { enabled }),
+        any::<bool>().prop_map(|enabled| DevicePayload::AscGetSuccess { enabled }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::AscError { code, detail }),
+        any::<u16>().prop_map(|meters| DevicePayload::AltitudeSetSuccess { meters }),
+        any::<u16>().prop_map(|meters| DevicePayload::AltitudeGetSuccess { meters }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::AltitudeError { code, detail }),
+        any::<u32>().prop_map(|pascals| DevicePayload::AmbientPressureSetSuccess { pascals }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::AmbientPressureError { code, detail }),
+        (any::<u32>(), detail(), any::<u8>()).prop_map(|(id, command, wakes_left)| {
+            DevicePayload::PendingConfirmation {
+                id,
+                command,
+                wakes_left,
+            }
+        }),
+        (any::<u32>(), detail())
+            .prop_map(|(id, command)| DevicePayload::ConfigRolledBack { id, command }),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::ConfirmConfigError { code, detail }),
+        (any::<bool>(), payload_detail())
+            .prop_map(|(passed, detail)| DevicePayload::SelfTestResult { passed, detail }),
+        Just(DevicePayload::FactoryResetSuccess),
+        (error_code(), payload_detail())
+            .prop_map(|(code, detail)| DevicePayload::FactoryResetError { code, detail }),
+        (0u64..1 << 48).prop_map(|serial| DevicePayload::SerialNumber { serial }),
+        payload_detail().prop_map(|detail| DevicePayload::Rebooting { detail }),
+        (detail(), detail(), detail()).prop_map(|(version, build_time, idf_version)| {
+            DevicePayload::FirmwareInfo {
+                version,
+                build_time,
+                idf_version,
+            }
+        }),
+        proptest::collection::vec(
+            (
+                any::<u16>(),
+                hundredths(-4_500, 13_000),
+                hundredths(0, 10_000),
+                any::<u32>(),
+            )
+                .prop_map(|(co2, temperature, humidity, age_seconds)| BatchedReading {
+                    co2,
+                    temperature,
+                    humidity,
+                    age_seconds,
+                }),
+            0..16,
+        )
+        .prop_map(|readings| DevicePayload::MeasurementBatch { readings }),
+        (any::<u32>(), any::<u16>(), any::<u16>()).prop_map(
+            |(skipped_wakes, lowest_mv, threshold_mv)| DevicePayload::RadioSkipped {
+                skipped_wakes,
+                lowest_mv,
+                threshold_mv,
+            }
+        ),
+        fault_kind().prop_map(|kind| DevicePayload::FaultArmed { kind }),
+        (any::<u64>(), proptest::option::of(any::<u64>())).prop_map(
+            |(sleep_seconds, next_wake_unix)| DevicePayload::EnteringSleep {
+                sleep_seconds,
+                next_wake_unix,
+            }
+        ),
+        (
+            "[a-z_]{1,24}",
+            any::<bool>(),
+            proptest::option::of(payload_detail())
+        )
+            .prop_map(|(cmd, accepted, detail)| DevicePayload::CommandAck {
+                cmd,
+                accepted,
+                detail
+            }),
+        ("[a-z_]{1,24}", any::<u16>(), any::<u64>()).prop_map(
+            |(command, limit, retry_after_seconds)| DevicePayload::PersistRateLimited {
+                command,
+                limit,
+                retry_after_seconds,
+            }
+        ),
+    ]
+}
+
+fn arb_message() -> impl Strategy<Value = DeviceMessage> {
+    (
+        device_name(),
+        arb_payload(),
+        proptest::option::of(any::<u64>()),
+        proptest::option::of(any::<u32>()),
+        any::<u8>(),
+        proptest::option::of(any::<u32>()),
+        any::<bool>(),
+        proptest::option::of(label()),
+        proptest::option::of(label()),
+        any::<bool>(),
+    )
+        .prop_map(
+            |(
+                device,
+                payload,
+                ts,
+                seq,
+                version,
+                in_reply_to,
+                redelivered,
+                fw_version,
+                location,
+                injected,
+            )| {
+                DeviceMessage {
+                    ts,
+                    seq,
+                    version,
+                    in_reply_to,
+                    redelivered,
+                    fw_version,
+                    location,
+                    injected,
+                    ..DeviceMessage::new(device.as_str(), payload)
+                }
+            },
+        )
+}
+
+fn arb_command() -> impl Strategy<Value = DeviceCommand> {
+    prop_oneof![
+        Just(DeviceCommand::NoOp),
+        any::<u16>().prop_map(|target_ppm| DeviceCommand::StartFrc { target_ppm }),
+        (hundredths(0, 2_000), any::<bool>())
+            .prop_map(|(offset, persist)| DeviceCommand::SetTempOffset { offset, persist }),
+        Just(DeviceCommand::GetTempOffset),
+        any::<u64>().prop_map(|seconds| DeviceCommand::SetDeepSleepTime { seconds }),
+        Just(DeviceCommand::GetDeepSleepTime),
+        (payload_class(), 0u8..=2, any::<bool>())
+            .prop_map(|(class, qos, retain)| DeviceCommand::SetMqttPolicy { class, qos, retain }),
+        "https://[a-z]{1,12}\\.local/[a-z0-9_-]{1,16}\\.bin"
+            .prop_map(|url| DeviceCommand::Ota { url }),
+        Just(DeviceCommand::GetConfig),
+        log_level().prop_map(|level| DeviceCommand::SetLogLevel { level }),
+        Just(DeviceCommand::GetLogLevel),
+        any::<bool>().prop_map(|enabled| DeviceCommand::SetAdaptiveMode { enabled }),
+        any::<bool>().prop_map(|enabled| DeviceCommand::SetAsc { enabled }),
+        Just(DeviceCommand::GetAsc),
+        any::<u16>().prop_map(|meters| DeviceCommand::SetAltitude { meters }),
+        Just(DeviceCommand::GetAltitude),
+        any::<u32>().prop_map(|pascals| DeviceCommand::SetAmbientPressure { pascals }),
+        any::<u32>().prop_map(|pending_id| DeviceCommand::ConfirmConfig { pending_id }),
+        Just(DeviceCommand::SelfTest),
+        device_name().prop_map(|confirm| DeviceCommand::FactoryReset { confirm }),
+        Just(DeviceCommand::GetSerialNumber),
+        Just(DeviceCommand::Reboot),
+        Just(DeviceCommand::GetFirmwareInfo),
+        fault_kind().prop_map(|kind| DeviceCommand::InjectFault { kind }),
+    ]
+}
+
+fn arb_batch() -> impl Strategy<Value = DeviceCommandBatch> {
+    (
+        proptest::collection::vec(arb_command(), 0..=MAX_BATCH_COMMANDS),
+        any::<bool>(),
+    )
+        .prop_map(|(commands, deferred)| DeviceCommandBatch {
+            deferred,
+            ..DeviceCommandBatch::new(commands).unwrap()
+        })
+}
+
+/// Splices an extra key into a serialized JSON object.
+/// Any finite float: the line protocol writes the shortest text that
+/// parses back to the same `f32`, so no grid is needed here.
+fn finite_f32() -> impl Strategy<Value = f32> {
+    any::<f32>().prop_filter("finite", |v| v.is_finite())
+}
+
+fn arb_fields() -> impl Strategy<Value = MeasurementFields> {
+    (
+        (any::<u16>(), finite_f32(), finite_f32(), any::<bool>()),
+        proptest::option::of(any::<u16>()),
+        proptest::option::of(any::<u8>()),
+        proptest::option::of("\\PC{1,16}"),
+        proptest::option::of("\\PC{1,16}"),
+        any::<bool>(),
+        proptest::option::of(measurement_flags()),
+    )
+        .prop_map(
+            |(
+                (co2, temperature, humidity, maintenance),
+                battery_mv,
+                battery_percent,
+                fw_version,
+                location,
+                injected,
+                flags,
+            )| {
+                MeasurementFields {
+                    co2,
+                    temperature,
+                    humidity,
+                    maintenance,
+                    injected,
+                    battery_mv,
+                    battery_percent,
+                    fw_version,
+                    location,
+                    flags,
+                }
+            },
+        )
+}
+
+fn with_extra_field(json: &str) -> String {
+    let (head, tail) = json.split_at(json.len() - 1);
+    format!(
+        "{},\"unknown_future_field\":{{\"nested\":[1,2,3]}}{}",
+        head, tail
+    )
+}
+
+/// Fits the largest generated message: a config or a batch of commands
+/// with multi-byte text
+#[cfg(feature = "postcard")]
+const POSTCARD_BUF: usize = 4096;
+
+proptest! {
+    #[test]
+    fn message_json_roundtrip(msg in arb_message()) {
+        let json = msg.to_json().unwrap();
+        prop_assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn message_json_is_flat(msg in arb_message()) {
+        let value: serde_json::Value = serde_json::from_str(&msg.to_json().unwrap()).unwrap();
+        let object = value.as_object().unwrap();
+        prop_assert!(object.get("status").is_some_and(|s| s.is_string()));
+        prop_assert!(object.get("payload").is_none());
+    }
+
+    #[test]
+    fn message_tolerates_unknown_fields(msg in arb_message()) {
+        let json = with_extra_field(&msg.to_json().unwrap());
+        prop_assert_eq!(DeviceMessage::from_json(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn command_json_roundtrip(cmd in arb_command()) {
+        let json = cmd.to_json().unwrap();
+        prop_assert_eq!(DeviceCommand::from_json(&json).unwrap(), cmd);
+    }
+
+    #[test]
+    fn envelope_json_roundtrip(
+        cmd in arb_command(),
+        id in proptest::option::of(any::<u32>()),
+        issued_by in proptest::option::of("[a-z]{1,8}"),
+        issued_at_unix in proptest::option::of(any::<u64>()),
+        ttl_seconds in proptest::option::of(any::<u64>()),
+    ) {
+        let envelope = CommandEnvelope {
+            id,
+            issued_by,
+            issued_at_unix,
+            ttl_seconds,
+            command: cmd,
+        };
+        let json = envelope.to_json().unwrap();
+        prop_assert_eq!(&CommandEnvelope::from_json(&json).unwrap(), &envelope);
+        // The plain command is read from the same JSON
+        prop_assert_eq!(DeviceCommand::from_json(&json).unwrap(), envelope.command);
+    }
+
+    #[test]
+    fn batch_json_roundtrip(batch in arb_batch(), id in proptest::option::of(any::<u32>())) {
+        let envelope = CommandEnvelope { id, ..batch.into() };
+        let json = envelope.to_json().unwrap();
+        prop_assert_eq!(
+            CommandMessage::from_json(&json).unwrap(),
+            CommandMessage::Batch(envelope)
+        );
+    }
+
+    #[test]
+    fn command_tolerates_unknown_fields(cmd in arb_command()) {
+        let json = with_extra_field(&cmd.to_json().unwrap());
+        prop_assert_eq!(DeviceCommand::from_json(&json).unwrap(), cmd);
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics(input in "\\PC{0,128}") {
+        let _ = DeviceMessage::from_json(&input);
+        let _ = DeviceCommand::from_json(&input);
+        let _ = CommandMessage::from_json(&input);
+        let _ = line_protocol::line_to_measurement(&input);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn message_cbor_roundtrip(msg in arb_message()) {
+        let bytes = msg.to_cbor().unwrap();
+        prop_assert_eq!(DeviceMessage::from_cbor(&bytes).unwrap(), msg);
+    }
+
+    /// A message read from JSON and written as CBOR decodes to the same
+    /// message, and the other way around
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn message_json_cbor_cross(msg in arb_message()) {
+        let from_json = DeviceMessage::from_json(&msg.to_json().unwrap()).unwrap();
+        let from_cbor = DeviceMessage::from_cbor(&from_json.to_cbor().unwrap()).unwrap();
+        prop_assert_eq!(&from_cbor, &msg);
+        let back = DeviceMessage::from_json(&from_cbor.to_json().unwrap()).unwrap();
+        prop_assert_eq!(back, msg);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn command_cbor_roundtrip(cmd in arb_command()) {
+        let bytes = cmd.to_cbor().unwrap();
+        prop_assert_eq!(DeviceCommand::from_cbor(&bytes).unwrap(), cmd);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn batch_cbor_roundtrip(batch in arb_batch()) {
+        let bytes = batch.to_cbor().unwrap();
+        prop_assert_eq!(DeviceCommandBatch::from_cbor(&bytes).unwrap(), batch);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn arbitrary_cbor_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..128)) {
+        let _ = DeviceMessage::from_cbor(&bytes);
+        let _ = DeviceCommand::from_cbor(&bytes);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn message_postcard_roundtrip(msg in arb_message()) {
+        let mut buf = [0u8; POSTCARD_BUF];
+        let bytes = msg.to_postcard(&mut buf).unwrap();
+        prop_assert_eq!(DeviceMessage::from_postcard(bytes).unwrap(), msg);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn command_postcard_roundtrip(cmd in arb_command()) {
+        let mut buf = [0u8; POSTCARD_BUF];
+        let bytes = cmd.to_postcard(&mut buf).unwrap();
+        prop_assert_eq!(DeviceCommand::from_postcard(bytes).unwrap(), cmd);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn batch_postcard_roundtrip(batch in arb_batch()) {
+        let mut buf = [0u8; POSTCARD_BUF];
+        let bytes = batch.to_postcard(&mut buf).unwrap();
+        prop_assert_eq!(DeviceCommandBatch::from_postcard(bytes).unwrap(), batch);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..128)) {
+        let _ = DeviceMessage::from_postcard(&bytes);
+        let _ = DeviceCommand::from_postcard(&bytes);
+        let _ = DeviceCommandBatch::from_postcard(&bytes);
+    }
+
+    /// Device names include the characters that need escaping
+    #[test]
+    fn line_protocol_roundtrip(
+        device in "\\PC{1,32}",
+        fields in arb_fields(),
+        timestamp in proptest::option::of(any::<i64>()),
+    ) {
+        let line = line_protocol::measurement_to_line(&device, &fields, timestamp);
+        let parsed = line_protocol::line_to_measurement(&line).unwrap();
+        prop_assert_eq!(&parsed.device, &device);
+        prop_assert_eq!(&parsed.fields, &fields);
+        prop_assert_eq!(parsed.timestamp, timestamp);
+        // and back to the same bytes
+        prop_assert_eq!(
+            line_protocol::measurement_to_line(&parsed.device, &parsed.fields, parsed.timestamp),
+            line
+        );
+    }
+}
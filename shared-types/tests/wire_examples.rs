@@ -0,0 +1,1102 @@
+//! Generator and compatibility gate for the wire examples in `examples/`.
+//!
+//! `examples/*.json` holds one canonical example per `DevicePayload` and
+//! `DeviceCommand` variant and for `DeviceCommandBatch`, plus every form of
+//! optional fields, for clients
+//! that don't use these types (the MicroPython client, Node-RED flows).
+//!
+//! Regenerate after changing the protocol with
+//!
+//! ```text
+//! UPDATE_WIRE_EXAMPLES=1 cargo test -p shared-types --test wire_examples
+//! ```
+//!
+//! Files are only ever added or rewritten, never deleted, and every file in
+//! the directory must still parse into the current types. With the `cbor`
+//! and `postcard` features each one also goes through those encodings, and
+//! with `no_alloc` the files show that inline `device`, `detail` and
+//! `Label` strings write the same JSON as `String`s.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use shared_types::device_config::{DeviceConfig, SensorMode};
+use shared_types::fault_injection::FaultKind;
+use shared_types::log_level::LogLevel;
+use shared_types::mqtt_policy::PayloadClass;
+use shared_types::{
+    BatchedReading, CommandEnvelope, CommandMessage, DeviceCommand, DeviceCommandBatch,
+    DeviceMessage, DevicePayload, ErrorCode, MeasurementFlags,
+};
+
+const DEVICE: &str = "esp32-scd40";
+
+enum Example {
+    Message(DeviceMessage),
+    Command(DeviceCommand),
+    /// A command sent with an id
+    Envelope(CommandEnvelope),
+    Batch(DeviceCommandBatch),
+    /// Hand-written JSON for forms serialization never produces, such as an
+    /// omitted field that has a default
+    RawCommand(&'static str),
+}
+
+/// Exhaustive on purpose: a new variant doesn't compile until it is listed
+/// here and in `PAYLOAD_STATUSES`.
+fn payload_status(payload: &DevicePayload) -> &'static str {
+    match payload {
+        DevicePayload::MeasurementSuccess { .. } => "success",
+        DevicePayload::Error { .. } => "error",
+        DevicePayload::FrcStart { .. } => "frc_start",
+        DevicePayload::FrcWarmupComplete { .. } => "frc_warmup_complete",
+        DevicePayload::FrcCalibrating { .. } => "frc_calibrating",
+        DevicePayload::FrcSuccess { .. } => "frc_success",
+        DevicePayload::FrcError { .. } => "frc_error",
+        DevicePayload::SetOffsetSuccess { .. } => "set_offset_success",
+        DevicePayload::SetOffsetRateLimited { .. } => "set_offset_rate_limited",
+        DevicePayload::SetOffsetError { .. } => "set_offset_error",
+        DevicePayload::GetOffsetSuccess { .. } => "get_offset_success",
+        DevicePayload::SetDeepSleepTimeSuccess { .. } => "set_deep_sleep_time_success",
+        DevicePayload::SetDeepSleepTimeError { .. } => "set_deep_sleep_time_error",
+        DevicePayload::GetDeepSleepTimeSuccess { .. } => "get_deep_sleep_time_success",
+        DevicePayload::GetDeepSleepTimeError { .. } => "get_deep_sleep_time_error",
+        DevicePayload::GetOffsetError { .. } => "get_offset_error",
+        DevicePayload::Alive { .. } => "alive",
+        DevicePayload::SetMqttPolicySuccess { .. } => "set_mqtt_policy_success",
+        DevicePayload::SetMqttPolicyError { .. } => "set_mqtt_policy_error",
+        DevicePayload::CommandsDeferred { .. } => "commands_deferred",
+        DevicePayload::BusRecovery { .. } => "bus_recovery",
+        DevicePayload::OtaProgress { .. } => "ota_progress",
+        DevicePayload::OtaSuccess { .. } => "ota_success",
+        DevicePayload::OtaError { .. } => "ota_error",
+        DevicePayload::WakeProfile { .. } => "wake_profile",
+        DevicePayload::Config(_) => "config",
+        DevicePayload::SetLogLevelSuccess { .. } => "set_log_level_success",
+        DevicePayload::SetLogLevelError { .. } => "set_log_level_error",
+        DevicePayload::GetLogLevelSuccess { .. } => "get_log_level_success",
+        DevicePayload::LogLines { .. } => "log_lines",
+        DevicePayload::SetAdaptiveModeSuccess { .. } => "set_adaptive_mode_success",
+        DevicePayload::SetAdaptiveModeError { .. } => "set_adaptive_mode_error",
+        DevicePayload::NextWake { .. } => "next_wake",
+        DevicePayload::Diagnostics { .. } => "diagnostics",
+        DevicePayload::AscSetSuccess { .. } => "asc_set_success",
+        DevicePayload::AscGetSuccess { .. } => "asc_get_success",
+        DevicePayload::AscError { .. } => "asc_error",
+        DevicePayload::AltitudeSetSuccess { .. } => "altitude_set_success",
+        DevicePayload::AltitudeGetSuccess { .. } => "altitude_get_success",
+        DevicePayload::AltitudeError { .. } => "altitude_error",
+        DevicePayload::AmbientPressureSetSuccess { .. } => "ambient_pressure_set_success",
+        DevicePayload::AmbientPressureError { .. } => "ambient_pressure_error",
+        DevicePayload::PendingConfirmation { .. } => "pending_confirmation",
+        DevicePayload::ConfigRolledBack { .. } => "config_rolled_back",
+        DevicePayload::ConfirmConfigError { .. } => "confirm_config_error",
+        DevicePayload::SelfTestResult { .. } => "self_test_result",
+        DevicePayload::FactoryResetSuccess => "factory_reset_success",
+        DevicePayload::FactoryResetError { .. } => "factory_reset_error",
+        DevicePayload::SerialNumber { .. } => "serial_number",
+        DevicePayload::Rebooting { .. } => "rebooting",
+        DevicePayload::FirmwareInfo { .. } => "firmware_info",
+        DevicePayload::MeasurementBatch { .. } => "measurement_batch",
+        DevicePayload::RadioSkipped { .. } => "radio_skipped",
+        DevicePayload::FaultArmed { .. } => "fault_armed",
+        DevicePayload::EnteringSleep { .. } => "entering_sleep",
+        DevicePayload::CommandAck { .. } => "command_ack",
+        DevicePayload::PersistRateLimited { .. } => "persist_rate_limited",
+    }
+}
+
+const PAYLOAD_STATUSES: &[&str] = &[
+    "success",
+    "error",
+    "frc_start",
+    "frc_warmup_complete",
+    "frc_calibrating",
+    "frc_success",
+    "frc_error",
+    "set_offset_success",
+    "set_offset_rate_limited",
+    "set_offset_error",
+    "get_offset_success",
+    "set_deep_sleep_time_success",
+    "set_deep_sleep_time_error",
+    "get_deep_sleep_time_success",
+    "get_deep_sleep_time_error",
+    "get_offset_error",
+    "alive",
+    "set_mqtt_policy_success",
+    "set_mqtt_policy_error",
+    "commands_deferred",
+    "bus_recovery",
+    "ota_progress",
+    "ota_success",
+    "ota_error",
+    "wake_profile",
+    "config",
+    "set_log_level_success",
+    "set_log_level_error",
+    "get_log_level_success",
+    "log_lines",
+    "set_adaptive_mode_success",
+    "set_adaptive_mode_error",
+    "next_wake",
+    "diagnostics",
+    "asc_set_success",
+    "asc_get_success",
+    "asc_error",
+    "altitude_set_success",
+    "altitude_get_success",
+    "altitude_error",
+    "ambient_pressure_set_success",
+    "ambient_pressure_error",
+    "pending_confirmation",
+    "config_rolled_back",
+    "confirm_config_error",
+    "self_test_result",
+    "factory_reset_success",
+    "factory_reset_error",
+    "serial_number",
+    "rebooting",
+    "firmware_info",
+    "measurement_batch",
+    "radio_skipped",
+    "fault_armed",
+    "entering_sleep",
+    "command_ack",
+    "persist_rate_limited",
+];
+
+/// See `payload_status`.
+fn command_name(command: &DeviceCommand) -> &'static str {
+    match command {
+        DeviceCommand::NoOp => "noop",
+        DeviceCommand::StartFrc { .. } => "start_frc",
+        DeviceCommand::SetTempOffset { .. } => "set_temp_offset",
+        DeviceCommand::GetTempOffset => "get_temp_offset",
+        DeviceCommand::SetDeepSleepTime { .. } => "set_deep_sleep_time",
+        DeviceCommand::GetDeepSleepTime => "get_deep_sleep_time",
+        DeviceCommand::SetMqttPolicy { .. } => "set_mqtt_policy",
+        DeviceCommand::Ota { .. } => "ota",
+        DeviceCommand::GetConfig => "get_config",
+        DeviceCommand::SetLogLevel { .. } => "set_log_level",
+        DeviceCommand::GetLogLevel => "get_log_level",
+        DeviceCommand::SetAdaptiveMode { .. } => "set_adaptive_mode",
+        DeviceCommand::SetAsc { .. } => "set_asc",
+        DeviceCommand::GetAsc => "get_asc",
+        DeviceCommand::SetAltitude { .. } => "set_altitude",
+        DeviceCommand::GetAltitude => "get_altitude",
+        DeviceCommand::SetAmbientPressure { .. } => "set_ambient_pressure",
+        DeviceCommand::ConfirmConfig { .. } => "confirm_config",
+        DeviceCommand::SelfTest => "self_test",
+        DeviceCommand::FactoryReset { .. } => "factory_reset",
+        DeviceCommand::GetSerialNumber => "get_serial_number",
+        DeviceCommand::Reboot => "reboot",
+        DeviceCommand::GetFirmwareInfo => "get_firmware_info",
+        DeviceCommand::InjectFault { .. } => "inject_fault",
+    }
+}
+
+const COMMAND_NAMES: &[&str] = &[
+    "noop",
+    "start_frc",
+    "set_temp_offset",
+    "get_temp_offset",
+    "set_deep_sleep_time",
+    "get_deep_sleep_time",
+    "set_mqtt_policy",
+    "ota",
+    "get_config",
+    "set_log_level",
+    "get_log_level",
+    "set_adaptive_mode",
+    "set_asc",
+    "get_asc",
+    "set_altitude",
+    "get_altitude",
+    "set_ambient_pressure",
+    "confirm_config",
+    "self_test",
+    "factory_reset",
+    "get_serial_number",
+    "reboot",
+    "get_firmware_info",
+    "inject_fault",
+];
+
+fn message(payload: DevicePayload) -> Example {
+    Example::Message(DeviceMessage::new(DEVICE, payload))
+}
+
+/// The corpus, as (file stem, example). Stems are `message.<status>` and
+/// `command.<cmd>`, with a suffix for each optional-field form.
+fn corpus() -> Vec<(String, Example)> {
+    let messages = vec![
+        (
+            "",
+            message(DevicePayload::try_measurement(612, 22.4, 41.3).unwrap()),
+        ),
+        (
+            ".stamped",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+                )
+                .stamped(Some(1_736_942_400_123), 42),
+            ),
+        ),
+        (
+            ".redelivered",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+                )
+                .stamped(Some(1_736_942_400_123), 42)
+                .redelivered(),
+            ),
+        ),
+        (
+            ".injected",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::try_measurement(0, 22.4, 41.3).unwrap(),
+                )
+                .stamped(Some(1_736_942_400_123), 42)
+                .injected(),
+            ),
+        ),
+        (
+            ".with_fw_version",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+                )
+                .stamped(Some(1_736_942_400_123), 42)
+                .with_fw_version("0.4.0"),
+            ),
+        ),
+        (
+            ".with_location",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::try_measurement(612, 22.4, 41.3).unwrap(),
+                )
+                .stamped(Some(1_736_942_400_123), 42)
+                .with_fw_version("0.4.0")
+                .with_location("bedroom"),
+            ),
+        ),
+        (
+            ".with_battery",
+            message(
+                DevicePayload::try_measurement_with_battery(612, 22.4, 41.3, 3870, 72).unwrap(),
+            ),
+        ),
+        (
+            ".with_flags",
+            message(
+                DevicePayload::try_measurement(612, 22.4, 41.3)
+                    .unwrap()
+                    .flagged(MeasurementFlags {
+                        first_after_boot: true,
+                        ..Default::default()
+                    }),
+            ),
+        ),
+        (
+            ".in_reply_to",
+            Example::Message(
+                DeviceMessage::new(DEVICE, DevicePayload::GetOffsetSuccess { offset: 4.0 })
+                    .replying_to(7),
+            ),
+        ),
+        ("", message(DevicePayload::error("Measurement timed out"))),
+        (
+            ".with_code",
+            message(DevicePayload::coded_error(
+                ErrorCode::SensorTimeout,
+                "Measurement timed out",
+            )),
+        ),
+        ("", message(DevicePayload::frc_start(422))),
+        (
+            "",
+            message(DevicePayload::FrcWarmupComplete {
+                detail: "Took 3 minutes".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::FrcCalibrating { target_ppm: 422 }),
+        ),
+        ("", message(DevicePayload::frc_success(32791))),
+        (
+            "",
+            message(DevicePayload::FrcError {
+                code: ErrorCode::Other,
+                detail: "I2C(Timeout)".into(),
+            }),
+        ),
+        (
+            ".with_code",
+            message(DevicePayload::FrcError {
+                code: ErrorCode::I2cError,
+                detail: "I2C(Timeout)".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetOffsetSuccess {
+                offset: 4.0,
+                persisted: true,
+            }),
+        ),
+        (
+            ".volatile",
+            message(DevicePayload::SetOffsetSuccess {
+                offset: 4.0,
+                persisted: false,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetOffsetRateLimited {
+                offset: 4.0,
+                limit: 4,
+                retry_after_seconds: 43_200,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetOffsetError {
+                code: ErrorCode::Other,
+                detail: "failed_to_persist: I2C(Nack)".into(),
+            }),
+        ),
+        ("", message(DevicePayload::GetOffsetSuccess { offset: 4.0 })),
+        (
+            "",
+            message(DevicePayload::GetOffsetError {
+                code: ErrorCode::Other,
+                detail: "failed_to_get: I2C(Nack)".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetDeepSleepTimeSuccess { seconds: 600 }),
+        ),
+        (
+            "",
+            message(DevicePayload::set_deep_sleep_time_error(
+                ErrorCode::Other,
+                "deep sleep time must be at least 1 second",
+            )),
+        ),
+        (
+            "",
+            message(DevicePayload::GetDeepSleepTimeSuccess { seconds: 300 }),
+        ),
+        (
+            "",
+            message(DevicePayload::get_deep_sleep_time_error(
+                ErrorCode::Other,
+                "failed_to_read: NVS",
+            )),
+        ),
+        (
+            "",
+            message(DevicePayload::Alive {
+                uptime_seconds: 3600,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetMqttPolicySuccess {
+                class: PayloadClass::Measurement,
+                qos: 1,
+                retain: true,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetMqttPolicyError {
+                code: ErrorCode::Other,
+                detail: "failed_to_persist: ESP_ERR_NVS_NOT_ENOUGH_SPACE".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::CommandsDeferred {
+                running: "start_frc".to_string(),
+                deferred: vec![DeviceCommand::SetTempOffset {
+                    offset: 4.0,
+                    persist: true,
+                }],
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::BusRecovery {
+                attempt: 1,
+                pulses: Some(3),
+                recovered: true,
+            }),
+        ),
+        (
+            ".sda_stuck",
+            message(DevicePayload::BusRecovery {
+                attempt: 2,
+                pulses: None,
+                recovered: false,
+            }),
+        ),
+        ("", message(DevicePayload::OtaProgress { percent: 40 })),
+        (
+            "",
+            message(DevicePayload::OtaSuccess {
+                version: "0.4.0".to_string(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::OtaError {
+                code: ErrorCode::Other,
+                detail: "download failed: HTTP 404".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::WakeProfile {
+                awake_ms: 9800,
+                sensor_ms: 6200,
+                network_ms: 3100,
+                saved_ms: 3000,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::Config(DeviceConfig {
+                firmware_version: "0.1.0".to_string(),
+                sleep_seconds: 300,
+                quiet_hours: Some("22-7".to_string()),
+                utc_offset_hours: 1,
+                sensor_mode: SensorMode::Periodic,
+                temperature_offset: Some(4.0),
+                altitude_m: Some(0),
+                ambient_pressure_hpa: None,
+                asc_enabled: Some(true),
+                alarm_threshold_ppm: None,
+                mqtt_policy:
+                    "measurement=1+retain,error=1,calibration=1,command_response=1,diagnostic=0"
+                        .to_string(),
+                wifi_ssid: "home".to_string(),
+                safe_mode: false,
+            })),
+        ),
+        (
+            "",
+            message(DevicePayload::SetLogLevelSuccess {
+                level: LogLevel::Debug,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetLogLevelError {
+                code: ErrorCode::Other,
+                detail: "failed_to_persist: ESP_ERR_NVS_NOT_ENOUGH_SPACE".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::GetLogLevelSuccess {
+                level: LogLevel::Info,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::LogLines {
+                lines: vec![
+                    "I (812) sensor: Waiting for data... (attempt 1/15)".to_string(),
+                    "I (15830) sensor: Timeout waiting for sensor data".to_string(),
+                ],
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetAdaptiveModeSuccess { enabled: true }),
+        ),
+        (
+            "",
+            message(DevicePayload::SetAdaptiveModeError {
+                code: ErrorCode::Other,
+                detail: "failed_to_persist: ESP_ERR_NVS_NOT_ENOUGH_SPACE".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::NextWake {
+                sleep_seconds: 120,
+                adaptive: true,
+                delta_ppm: Some(180),
+            }),
+        ),
+        (
+            ".without_delta",
+            message(DevicePayload::NextWake {
+                sleep_seconds: 300,
+                adaptive: false,
+                delta_ppm: None,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::diagnostics(-67, 182344, 41, "deep_sleep")),
+        ),
+        ("", message(DevicePayload::AscSetSuccess { enabled: false })),
+        ("", message(DevicePayload::AscGetSuccess { enabled: true })),
+        (
+            "",
+            message(DevicePayload::AscError {
+                code: ErrorCode::Other,
+                detail: "failed_to_set: I2c(Timeout)".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::AltitudeSetSuccess { meters: 600 }),
+        ),
+        (
+            "",
+            message(DevicePayload::AltitudeGetSuccess { meters: 600 }),
+        ),
+        (
+            "",
+            message(DevicePayload::AltitudeError {
+                code: ErrorCode::Other,
+                detail: "out_of_range: 4000 m".into(),
+            }),
+        ),
+        (
+            ".with_code",
+            message(DevicePayload::AltitudeError {
+                code: ErrorCode::I2cError,
+                detail: "failed_to_set: I2C(Nack)".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::AmbientPressureSetSuccess { pascals: 94200 }),
+        ),
+        (
+            "",
+            message(DevicePayload::AmbientPressureError {
+                code: ErrorCode::Other,
+                detail: "failed_to_set: I2c(Timeout)".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::PendingConfirmation {
+                id: 41,
+                command: "set_deep_sleep_time".to_string(),
+                wakes_left: 3,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::ConfigRolledBack {
+                id: 41,
+                command: "set_deep_sleep_time".to_string(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::ConfirmConfigError {
+                code: ErrorCode::Other,
+                detail: "nothing_pending: 41 is not on trial".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SelfTestResult {
+                passed: false,
+                detail: "malfunction: the sensor reported a fault".into(),
+            }),
+        ),
+        ("", message(DevicePayload::FactoryResetSuccess)),
+        (
+            "",
+            message(DevicePayload::FactoryResetError {
+                code: ErrorCode::Other,
+                detail: "not_confirmed: esp32-kitchen is not this device".into(),
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::SerialNumber {
+                serial: 273_325_796_834_238,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::Rebooting {
+                detail: "restarting instead of sleeping".into(),
+            }),
+        ),
+        (
+            "",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::FirmwareInfo {
+                        version: "0.4.0".to_string(),
+                        build_time: "2025-01-15T12:00:00Z".to_string(),
+                        idf_version: "v5.3.2".to_string(),
+                    },
+                )
+                .with_fw_version("0.4.0"),
+            ),
+        ),
+        (
+            "",
+            Example::Message(
+                DeviceMessage::new(
+                    DEVICE,
+                    DevicePayload::MeasurementBatch {
+                        readings: vec![
+                            BatchedReading {
+                                co2: 640,
+                                temperature: 22.1,
+                                humidity: 42.0,
+                                age_seconds: 600,
+                            },
+                            BatchedReading {
+                                co2: 612,
+                                temperature: 22.4,
+                                humidity: 41.3,
+                                age_seconds: 0,
+                            },
+                        ],
+                    },
+                )
+                .stamped(Some(1_736_942_400_123), 42),
+            ),
+        ),
+        (
+            "",
+            message(DevicePayload::RadioSkipped {
+                skipped_wakes: 3,
+                lowest_mv: 3410,
+                threshold_mv: 3500,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::FaultArmed {
+                kind: FaultKind::Co2Zero,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::EnteringSleep {
+                sleep_seconds: 300,
+                next_wake_unix: Some(1_736_942_700),
+            }),
+        ),
+        (
+            ".unsynced",
+            message(DevicePayload::EnteringSleep {
+                sleep_seconds: 300,
+                next_wake_unix: None,
+            }),
+        ),
+        (
+            "",
+            message(DevicePayload::command_accepted(&DeviceCommand::StartFrc {
+                target_ppm: 422,
+            })),
+        ),
+        (
+            ".rejected",
+            message(DevicePayload::command_rejected(
+                &DeviceCommand::StartFrc { target_ppm: 5000 },
+                "FRC target 5000 ppm is outside 400 to 2000 ppm",
+            )),
+        ),
+        (
+            "",
+            message(DevicePayload::PersistRateLimited {
+                command: "set_asc".to_string(),
+                limit: 4,
+                retry_after_seconds: 43_200,
+            }),
+        ),
+    ];
+
+    let commands = vec![
+        ("", Example::Command(DeviceCommand::NoOp)),
+        (
+            "",
+            Example::Command(DeviceCommand::StartFrc { target_ppm: 422 }),
+        ),
+        (
+            ".without_target_ppm",
+            Example::RawCommand(
+                r#"{
+  "cmd": "start_frc"
+}"#,
+            ),
+        ),
+        (
+            "",
+            Example::Command(DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: true,
+            }),
+        ),
+        (
+            ".volatile",
+            Example::Command(DeviceCommand::SetTempOffset {
+                offset: 4.0,
+                persist: false,
+            }),
+        ),
+        ("", Example::Command(DeviceCommand::GetTempOffset)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetDeepSleepTime { seconds: 600 }),
+        ),
+        ("", Example::Command(DeviceCommand::GetDeepSleepTime)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetMqttPolicy {
+                class: PayloadClass::Measurement,
+                qos: 1,
+                retain: true,
+            }),
+        ),
+        (
+            ".without_retain",
+            Example::RawCommand(
+                r#"{
+  "cmd": "set_mqtt_policy",
+  "class": "diagnostic",
+  "qos": 0
+}"#,
+            ),
+        ),
+        (
+            "",
+            Example::Batch(
+                DeviceCommandBatch::new([
+                    DeviceCommand::StartFrc { target_ppm: 422 },
+                    DeviceCommand::SetTempOffset {
+                        offset: 4.0,
+                        persist: true,
+                    },
+                ])
+                .unwrap(),
+            ),
+        ),
+        (
+            ".deferred",
+            Example::Batch(DeviceCommandBatch {
+                deferred: true,
+                ..DeviceCommandBatch::new([DeviceCommand::SetTempOffset {
+                    offset: 4.0,
+                    persist: true,
+                }])
+                .unwrap()
+            }),
+        ),
+        (
+            "",
+            Example::Command(DeviceCommand::Ota {
+                url: "http://firmware.local/air-quality-0.4.0.bin".to_string(),
+            }),
+        ),
+        ("", Example::Command(DeviceCommand::GetConfig)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetLogLevel {
+                level: LogLevel::Debug,
+            }),
+        ),
+        ("", Example::Command(DeviceCommand::GetLogLevel)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetAdaptiveMode { enabled: true }),
+        ),
+        (
+            "",
+            Example::Command(DeviceCommand::SetAsc { enabled: false }),
+        ),
+        ("", Example::Command(DeviceCommand::GetAsc)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetAltitude { meters: 600 }),
+        ),
+        ("", Example::Command(DeviceCommand::GetAltitude)),
+        (
+            "",
+            Example::Command(DeviceCommand::SetAmbientPressure { pascals: 94200 }),
+        ),
+        (
+            "",
+            Example::Command(DeviceCommand::ConfirmConfig { pending_id: 41 }),
+        ),
+        ("", Example::Command(DeviceCommand::SelfTest)),
+        (
+            "",
+            Example::Command(DeviceCommand::FactoryReset {
+                confirm: DEVICE.to_string(),
+            }),
+        ),
+        ("", Example::Command(DeviceCommand::GetSerialNumber)),
+        ("", Example::Command(DeviceCommand::Reboot)),
+        ("", Example::Command(DeviceCommand::GetFirmwareInfo)),
+        (
+            "",
+            Example::Command(DeviceCommand::InjectFault {
+                kind: FaultKind::SensorTimeout,
+            }),
+        ),
+        (
+            ".with_id",
+            Example::Envelope(DeviceCommand::GetTempOffset.with_id(7)),
+        ),
+        (
+            ".issued_by",
+            Example::Envelope(DeviceCommand::GetTempOffset.with_id(7).issued_by("ola")),
+        ),
+        (
+            ".expires",
+            Example::Envelope(
+                DeviceCommand::StartFrc { target_ppm: 422 }
+                    .with_id(7)
+                    .issued_by("ola")
+                    .expires(1_736_935_200, 86_400),
+            ),
+        ),
+    ];
+
+    messages
+        .into_iter()
+        .chain(commands)
+        .map(|(suffix, example)| {
+            let stem = match &example {
+                Example::Message(m) => format!("message.{}", payload_status(&m.payload)),
+                Example::Command(c) => format!("command.{}", command_name(c)),
+                Example::Envelope(e) => format!("command.{}", command_name(&e.command)),
+                Example::Batch(_) => "command.batch".to_string(),
+                Example::RawCommand(json) => {
+                    let command = DeviceCommand::from_json(json).expect("raw example must parse");
+                    format!("command.{}", command_name(&command))
+                }
+            };
+            (format!("{}{}", stem, suffix), example)
+        })
+        .collect()
+}
+
+/// Pretty-printed with fields in declaration order. Going through
+/// `serde_json::Value` would sort the keys and widen floats.
+fn render(example: &Example) -> String {
+    let json = match example {
+        Example::Message(m) => serde_json::to_string_pretty(m).unwrap(),
+        Example::Command(c) => serde_json::to_string_pretty(c).unwrap(),
+        Example::Envelope(e) => serde_json::to_string_pretty(e).unwrap(),
+        Example::Batch(b) => serde_json::to_string_pretty(b).unwrap(),
+        Example::RawCommand(json) => json.to_string(),
+    };
+    json + "\n"
+}
+
+fn examples_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+#[test]
+fn examples_are_up_to_date() {
+    let dir = examples_dir();
+    let update = std::env::var_os("UPDATE_WIRE_EXAMPLES").is_some();
+    if update {
+        fs::create_dir_all(&dir).unwrap();
+    }
+
+    let mut stale = Vec::new();
+    for (stem, example) in corpus() {
+        let path = dir.join(format!("{}.json", stem));
+        let rendered = render(&example);
+        if update {
+            fs::write(&path, rendered).unwrap();
+        } else if fs::read_to_string(&path).ok().as_deref() != Some(rendered.as_str()) {
+            stale.push(stem);
+        }
+    }
+    assert!(
+        stale.is_empty(),
+        "wire examples out of date: {:?}\n\
+         regenerate with UPDATE_WIRE_EXAMPLES=1 cargo test -p shared-types --test wire_examples",
+        stale
+    );
+}
+
+#[test]
+fn every_example_file_still_parses() {
+    // Files are being rewritten by the other test
+    if std::env::var_os("UPDATE_WIRE_EXAMPLES").is_some() {
+        return;
+    }
+    let mut checked = 0;
+    for entry in fs::read_dir(examples_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let json = fs::read_to_string(&path).unwrap();
+        if name.starts_with("message.") {
+            let msg = DeviceMessage::from_json(&json)
+                .unwrap_or_else(|e| panic!("{} no longer parses: {}", name, e));
+            assert_eq!(
+                DeviceMessage::from_json(&msg.to_json().unwrap()).unwrap(),
+                msg,
+                "{}",
+                name
+            );
+            #[cfg(feature = "cbor")]
+            assert_eq!(
+                DeviceMessage::from_cbor(&msg.to_cbor().unwrap()).unwrap(),
+                msg,
+                "{}",
+                name
+            );
+            #[cfg(feature = "postcard")]
+            {
+                let mut buf = [0u8; 1024];
+                let bytes = msg.to_postcard(&mut buf).unwrap();
+                assert_eq!(
+                    DeviceMessage::from_postcard(bytes).unwrap(),
+                    msg,
+                    "{}",
+                    name
+                );
+            }
+        } else if name.starts_with("command.batch") {
+            let CommandMessage::Batch(envelope) = CommandMessage::from_json(&json)
+                .unwrap_or_else(|e| panic!("{} no longer parses: {}", name, e))
+            else {
+                panic!("{} no longer parses as a batch", name);
+            };
+            assert_eq!(
+                CommandMessage::from_json(&envelope.to_json().unwrap()).unwrap(),
+                CommandMessage::Batch(envelope.clone()),
+                "{}",
+                name
+            );
+            #[cfg(any(feature = "cbor", feature = "postcard"))]
+            let batch = envelope.command;
+            #[cfg(feature = "cbor")]
+            assert_eq!(
+                DeviceCommandBatch::from_cbor(&batch.to_cbor().unwrap()).unwrap(),
+                batch,
+                "{}",
+                name
+            );
+            #[cfg(feature = "postcard")]
+            {
+                let mut buf = [0u8; 1024];
+                let bytes = batch.to_postcard(&mut buf).unwrap();
+                assert_eq!(
+                    DeviceCommandBatch::from_postcard(bytes).unwrap(),
+                    batch,
+                    "{}",
+                    name
+                );
+            }
+        } else if name.starts_with("command.") {
+            let cmd = DeviceCommand::from_json(&json)
+                .unwrap_or_else(|e| panic!("{} no longer parses: {}", name, e));
+            assert_eq!(
+                DeviceCommand::from_json(&cmd.to_json().unwrap()).unwrap(),
+                cmd,
+                "{}",
+                name
+            );
+            let envelope = CommandEnvelope::from_json(&json)
+                .unwrap_or_else(|e| panic!("{} no longer parses with an id: {}", name, e));
+            assert_eq!(envelope.command, cmd, "{}", name);
+            assert_eq!(
+                CommandEnvelope::from_json(&envelope.to_json().unwrap()).unwrap(),
+                envelope,
+                "{}",
+                name
+            );
+            #[cfg(feature = "cbor")]
+            assert_eq!(
+                DeviceCommand::from_cbor(&cmd.to_cbor().unwrap()).unwrap(),
+                cmd,
+                "{}",
+                name
+            );
+            #[cfg(feature = "postcard")]
+            {
+                let mut buf = [0u8; 1024];
+                let bytes = cmd.to_postcard(&mut buf).unwrap();
+                assert_eq!(
+                    DeviceCommand::from_postcard(bytes).unwrap(),
+                    cmd,
+                    "{}",
+                    name
+                );
+            }
+        } else {
+            panic!("{} is neither a message nor a command example", name);
+        }
+        checked += 1;
+    }
+    assert!(checked > 0, "no wire examples found");
+}
+
+#[test]
+fn every_variant_has_an_example() {
+    let mut statuses = BTreeSet::new();
+    let mut commands = BTreeSet::new();
+    let mut batches = 0;
+    let mut stems = BTreeSet::new();
+    for (stem, example) in corpus() {
+        assert!(stems.insert(stem.clone()), "duplicate example {}", stem);
+        match example {
+            Example::Message(m) => {
+                let value = serde_json::to_value(&m).unwrap();
+                assert_eq!(value["status"], payload_status(&m.payload));
+                statuses.insert(payload_status(&m.payload));
+            }
+            Example::Command(c) => {
+                let value = serde_json::to_value(&c).unwrap();
+                assert_eq!(value["cmd"], command_name(&c));
+                commands.insert(command_name(&c));
+            }
+            Example::Envelope(e) => {
+                let value = serde_json::to_value(&e).unwrap();
+                assert_eq!(value["cmd"], command_name(&e.command));
+                assert_eq!(value["id"], e.id.unwrap());
+            }
+            Example::Batch(b) => {
+                let value = serde_json::to_value(&b).unwrap();
+                assert_eq!(value["cmd"], "batch");
+                batches += 1;
+            }
+            Example::RawCommand(_) => {}
+        }
+    }
+    assert!(batches > 0, "no batch example");
+    assert_eq!(statuses, PAYLOAD_STATUSES.iter().copied().collect());
+    assert_eq!(commands, COMMAND_NAMES.iter().copied().collect());
+}